@@ -0,0 +1,134 @@
+//! Alternates let an object directory borrow the objects of others, listed
+//! one per line in its `info/alternates` or in
+//! `GIT_ALTERNATE_OBJECT_DIRECTORIES`. Reading an object that isn't in the
+//! repository itself looks in them, while everything written stays in the
+//! repository, which is how `git clone --reference` and repositories
+//! sharing storage work.
+
+use crate::patch;
+#[cfg(test)]
+use crate::Repository;
+use bstr::ByteSlice;
+use std::{
+  env,
+  ffi::OsString,
+  fs,
+  path::{Path, PathBuf},
+};
+
+/// How many levels of alternates of alternates are followed, the same as
+/// git
+const MAX_DEPTH: usize = 5;
+
+/// Add the object directories `dirs` to `found` along with the ones they
+/// list as their alternates in turn. Relative paths are relative to `base`.
+/// Every directory is canonicalized and only added once, and never
+/// `objects` itself, so alternates that point back at each other don't
+/// loop. Directories that aren't there are skipped like git does after
+/// warning about them.
+pub(crate) fn resolve(objects: &Path, base: &Path, dirs: Vec<PathBuf>, found: &mut Vec<PathBuf>) {
+  let objects = fs::canonicalize(objects).unwrap_or_else(|_| objects.into());
+  link(&objects, base, dirs, 0, found);
+}
+
+fn link(objects: &Path, base: &Path, dirs: Vec<PathBuf>, depth: usize, found: &mut Vec<PathBuf>) {
+  for dir in dirs {
+    let dir = match fs::canonicalize(base.join(dir)) {
+      Ok(dir) if dir.is_dir() => dir,
+      _ => continue,
+    };
+    if dir == objects || found.contains(&dir) {
+      continue;
+    }
+    found.push(dir.clone());
+    if depth < MAX_DEPTH {
+      link(objects, &dir, listed(&dir), depth + 1, found);
+    }
+  }
+}
+
+/// The alternates listed in `info/alternates` of the object directory
+/// `objects`, skipping blank lines and comments. Paths can be quoted like
+/// git quotes paths with unusual characters.
+pub(crate) fn listed(objects: &Path) -> Vec<PathBuf> {
+  let bytes = match fs::read(objects.join("info/alternates")) {
+    Ok(bytes) => bytes,
+    Err(_) => return Vec::new(),
+  };
+  bytes
+    .lines()
+    .map(|line| line.trim_end())
+    .filter(|line| !line.is_empty() && !line.starts_with(b"#"))
+    .filter_map(|line| match patch::unquote_path(line) {
+      Some((path, _)) => path.to_path().ok().map(Path::to_path_buf),
+      None => line.to_path().ok().map(Path::to_path_buf),
+    })
+    .collect()
+}
+
+/// The object directories in `GIT_ALTERNATE_OBJECT_DIRECTORIES`, separated
+/// like `PATH` is
+pub(crate) fn from_env() -> Vec<PathBuf> {
+  env::var_os("GIT_ALTERNATE_OBJECT_DIRECTORIES")
+    .filter(|dirs| !dirs.is_empty())
+    .map(|dirs: OsString| env::split_paths(&dirs).collect())
+    .unwrap_or_default()
+}
+
+#[test]
+fn alternates() {
+  use crate::{Blob, Odb};
+  let tmp_dir = tempdir::TempDir::new("alternates_test").unwrap();
+  let shared = Repository::init_bare(tmp_dir.path().join("shared")).unwrap();
+  let blob = shared
+    .odb()
+    .write_blob(&Blob::new(b"shared\n".to_vec()))
+    .unwrap();
+  let mut repo = Repository::init(tmp_dir.path().join("repo")).unwrap();
+  assert!(!repo.odb().contains(&blob).unwrap());
+
+  repo.add_alternate(shared.odb().path()).unwrap();
+  repo.add_alternate(shared.odb().path()).unwrap();
+  assert_eq!(1, listed(repo.odb().path()).len());
+  assert!(repo.odb().contains(&blob).unwrap());
+  assert_eq!("shared\n", repo.odb().read_blob(&blob).unwrap().contents());
+  assert_eq!(blob, repo.odb().find_prefix(&blob.as_hex()[..7]).unwrap());
+  // Writing an object the alternate has leaves it there
+  assert_eq!(
+    blob,
+    repo
+      .odb()
+      .write_blob(&Blob::new(b"shared\n".to_vec()))
+      .unwrap()
+  );
+  assert!(repo.odb().oids().unwrap().is_empty());
+  let reopened = Repository::open(repo.work_dir().unwrap()).unwrap();
+  assert!(reopened.odb().contains(&blob).unwrap());
+
+  // Alternates pointing back at each other don't loop, and the chain of
+  // them is followed
+  fs::write(
+    shared.odb().path().join("info/alternates"),
+    format!("# comment\n\n{}\n", repo.odb().path().display()),
+  )
+  .unwrap();
+  let third = tmp_dir.path().join("third");
+  fs::create_dir_all(third.join("info")).unwrap();
+  fs::write(third.join("info/alternates"), "../repo/.git/objects\n").unwrap();
+  let odb = Odb::new(&third);
+  assert_eq!(2, odb.alternates().len());
+  assert_eq!("shared\n", odb.read_blob(&blob).unwrap().contents());
+
+  // Git reads the objects through the alternates too
+  if crate::transport::http::have_git() {
+    let output = std::process::Command::new("git")
+      .args(["cat-file", "-p", &blob.as_hex()])
+      .current_dir(repo.work_dir().unwrap())
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .output()
+      .unwrap();
+    assert!(output.status.success());
+    assert_eq!(b"shared\n", &output.stdout[..]);
+  }
+}
@@ -0,0 +1,1758 @@
+use crate::{
+  checkout::{entry_path, remove_existing, write_file, write_symlink},
+  delta::apply_delta,
+  diff::kind_of,
+  index::{hash_file, worktree_mode},
+  patch::unquote_path,
+  zlib, AttributesError, Blob, CheckoutError, CheckoutOptions, Config, ConfigError, DiffLine,
+  FileMode, FilterError, Hunk, Index, IndexEntry, IndexError, LineKind, Odb, OdbError, Repository,
+  StatData, Trace2, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  collections::HashMap,
+  convert::TryFrom,
+  fs, io,
+  path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// A patch for any number of files, as written by `git diff` and
+/// `git format-patch` or as a traditional unified diff
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Patch {
+  /// The patch for each file in the order they appear
+  pub files: Vec<FilePatch>,
+}
+
+/// The part of a [`Patch`] for a single file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilePatch {
+  /// The path of the file before the patch, `None` if the patch creates it
+  pub old_path: Option<BString>,
+  /// The path of the file after the patch, `None` if the patch deletes it
+  pub new_path: Option<BString>,
+  /// The mode of the file before the patch, if the patch says what it is
+  pub old_mode: Option<FileMode>,
+  /// The mode of the file after the patch, if the patch changes it
+  pub new_mode: Option<FileMode>,
+  /// Whether the file at [`FilePatch::old_path`] is kept when it differs
+  /// from [`FilePatch::new_path`], making the patch a copy instead of a
+  /// rename
+  pub copy: bool,
+  /// The hex digits of the object name of the file before the patch, as
+  /// given on the `index` line of a git diff
+  pub old_id: Option<String>,
+  /// The hex digits of the object name of the file after the patch
+  pub new_id: Option<String>,
+  /// The changes to the lines of a text file
+  pub hunks: Vec<Hunk>,
+  /// The changes to a binary file, if it is one
+  pub binary: Option<BinaryPatch>,
+}
+
+/// How a [`FilePatch`] for a binary file changes it, as written by
+/// `git diff --binary`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BinaryPatch {
+  /// How to make the new contents from the old ones. This is `None` for a
+  /// patch that only says `Binary files differ`, which can still be applied
+  /// if the new contents are already in the [`Odb`].
+  pub forward: Option<BinaryData>,
+  /// How to make the old contents from the new ones, used when the patch is
+  /// applied in reverse
+  pub reverse: Option<BinaryData>,
+}
+
+/// One direction of a [`BinaryPatch`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BinaryData {
+  /// The complete contents
+  Literal(Vec<u8>),
+  /// A delta against the contents on the other side, in the format packs
+  /// use
+  Delta(Vec<u8>),
+}
+
+/// Options controlling how a [`Patch`] is applied
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplyOptions {
+  /// How many lines of context before and after each change have to match
+  /// at least, like `git apply -C`. A hunk that doesn't apply is tried again
+  /// with fewer context lines down to this many. `None`, the default,
+  /// requires all of them to match.
+  pub min_context: Option<usize>,
+  /// Whether hunks without context are allowed to apply anywhere, as needed
+  /// for patches made with `git diff -U0`, like `git apply --unidiff-zero`.
+  /// Otherwise a hunk without context after its changes has to match at
+  /// the end of the file.
+  pub unidiff_zero: bool,
+  /// How files are read from and written to the working tree
+  pub checkout: CheckoutOptions,
+}
+
+impl ApplyOptions {
+  /// Create [`ApplyOptions`] from the `core.*` settings in a [`Config`]
+  /// that say how files are written
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    Ok(Self {
+      checkout: CheckoutOptions::from_config(config)?,
+      ..Self::default()
+    })
+  }
+}
+
+/// Where [`Repository::apply`] applies a [`Patch`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ApplyLocation {
+  /// Only the working tree, like `git apply`
+  #[default]
+  WorkDir,
+  /// Only the [`Index`], like `git apply --cached`
+  Index,
+  /// Both the working tree and the [`Index`], like `git apply --index`
+  Both,
+}
+
+impl Patch {
+  /// Parse a patch, dropping the first component of every path like
+  /// `git apply` does to remove the `a/` and `b/` prefixes. Anything that
+  /// isn't part of a patch, like the message of an email, is skipped.
+  ///
+  /// Like git, nothing is stripped from the paths of a traditional diff
+  /// between two files in the same directory, like `file.orig` and `file`.
+  pub fn parse(bytes: impl AsRef<[u8]>) -> Result<Self, ApplyError> {
+    Self::parse_lines(bytes.as_ref(), 1, false)
+  }
+
+  /// Parse a patch, dropping `strip` leading components of every path like
+  /// `git apply -p<strip>`
+  pub fn parse_with_strip(bytes: impl AsRef<[u8]>, strip: usize) -> Result<Self, ApplyError> {
+    Self::parse_lines(bytes.as_ref(), strip, true)
+  }
+
+  fn parse_lines(bytes: &[u8], strip: usize, strip_known: bool) -> Result<Self, ApplyError> {
+    let mut parser = Parser {
+      lines: bytes.lines_with_terminator().collect(),
+      pos: 0,
+      strip,
+      strip_known,
+    };
+    let mut files = Vec::new();
+    while let Some(line) = parser.peek(0) {
+      let starts = |n: usize, prefix: &[u8]| parser.peek(n).is_some_and(|l| l.starts_with(prefix));
+      if line.starts_with(b"diff --git ") {
+        files.push(parser.git_diff()?);
+      } else if starts(0, b"--- ") && starts(1, b"+++ ") && starts(2, b"@@ -") {
+        files.push(parser.traditional_diff()?);
+      } else if parse_hunk_header(trim_newline(line)).is_some() {
+        return Err(parser.error(parser.pos, "patch fragment without header"));
+      } else {
+        parser.pos += 1;
+      }
+    }
+    if files.is_empty() {
+      return Err(ApplyError::NoPatches);
+    }
+    Ok(Self { files })
+  }
+
+  /// The patch that undoes this one, like `git apply -R`. The files are
+  /// reversed in the opposite order, so a file deleted and then created
+  /// again by a type change is deleted first.
+  pub fn reverse(&self) -> Self {
+    Self {
+      files: self.files.iter().rev().map(FilePatch::reverse).collect(),
+    }
+  }
+}
+
+impl FilePatch {
+  /// The patch that undoes this one
+  pub fn reverse(&self) -> Self {
+    let hunks = self
+      .hunks
+      .iter()
+      .map(|hunk| {
+        let mut lines: Vec<_> = hunk
+          .lines
+          .iter()
+          .map(|line| DiffLine {
+            kind: match line.kind {
+              LineKind::Removed => LineKind::Added,
+              LineKind::Added => LineKind::Removed,
+              LineKind::Context => LineKind::Context,
+            },
+            content: line.content.clone(),
+          })
+          .collect();
+        // Keep removed lines before the added ones replacing them
+        for changes in lines.split_mut(|line| line.kind == LineKind::Context) {
+          changes.sort_by_key(|line| line.kind == LineKind::Added);
+        }
+        Hunk {
+          old_start: hunk.new_start,
+          old_len: hunk.new_len,
+          new_start: hunk.old_start,
+          new_len: hunk.old_len,
+          lines,
+        }
+      })
+      .collect();
+    // Created and deleted files only have the mode of the side they exist
+    // on, other patches only have a new mode if it changes
+    let (old_mode, new_mode) = match (&self.old_path, &self.new_path) {
+      (None, _) => (self.new_mode, None),
+      (_, None) => (None, self.old_mode),
+      _ => (
+        self.new_mode.or(self.old_mode),
+        self.new_mode.and(self.old_mode),
+      ),
+    };
+    Self {
+      old_path: self.new_path.clone(),
+      new_path: self.old_path.clone(),
+      old_mode,
+      new_mode,
+      copy: self.copy,
+      old_id: self.new_id.clone(),
+      new_id: self.old_id.clone(),
+      hunks,
+      binary: self.binary.as_ref().map(|binary| BinaryPatch {
+        forward: binary.reverse.clone(),
+        reverse: binary.forward.clone(),
+      }),
+    }
+  }
+}
+
+/// Apply a [`Patch`] to the [`Tree`][crate::Tree] with the given [`OID`]
+/// without a working tree, writing the new [`Blob`]s and
+/// [`Tree`][crate::Tree]s to the [`Odb`] and returning the [`OID`] of the
+/// new top one. See [`apply_to_index`].
+pub fn apply_to_tree(
+  odb: &Odb,
+  tree: &OID,
+  patch: &Patch,
+  options: &ApplyOptions,
+) -> Result<OID, ApplyError> {
+  let mut index = Index::from_tree(odb, tree)?;
+  apply_to_index(odb, &mut index, patch, options)?;
+  Ok(index.write_tree(odb)?)
+}
+
+/// Apply a [`Patch`] to the files in an [`Index`] without touching the
+/// working tree, like `git apply --cached`.
+///
+/// Every [`FilePatch`] applies to what the ones before it left behind. Each
+/// hunk is first looked for where its header says it goes and then further
+/// and further away from there, and if it isn't found with all of its
+/// context the context is cut down one line at a time to
+/// [`ApplyOptions::min_context`], the way `git apply` does. Nothing is
+/// changed unless the whole [`Patch`] applies.
+pub fn apply_to_index(
+  odb: &Odb,
+  index: &mut Index,
+  patch: &Patch,
+  options: &ApplyOptions,
+) -> Result<(), ApplyError> {
+  let _region = Trace2::region("apply", "apply_to_index");
+  let files: Vec<_> = patch.files.iter().collect();
+  let results = apply_files(odb, &files, &IndexSource { odb, index }, options)?;
+  for (path, file) in &results {
+    match file {
+      Some(file) => add_to_index(odb, index, path, file, StatData::default())?,
+      None => {
+        index.remove(path);
+      }
+    }
+  }
+  Trace2::data("apply", "files", results.len());
+  Ok(())
+}
+
+/// Apply a [`Patch`] to the files in `work_dir` like `git apply` does, see
+/// [`apply_to_index`] for how hunks are matched. Paths are never followed
+/// through symbolic links.
+///
+/// With an `index` the patch applies to the files in it instead, which have
+/// to match the ones in `work_dir`, and both are updated like
+/// `git apply --index` does. Without one changes to submodules are skipped,
+/// since there is nothing to record their new commit in.
+pub fn apply_to_work_dir(
+  odb: &Odb,
+  work_dir: impl AsRef<Path>,
+  index: Option<&mut Index>,
+  patch: &Patch,
+  options: &ApplyOptions,
+) -> Result<(), ApplyError> {
+  let _region = Trace2::region("apply", "apply_to_work_dir");
+  let work_dir = WorkDirSource {
+    work_dir: work_dir.as_ref(),
+    options,
+  };
+  let results = match &index {
+    Some(index) => {
+      let files: Vec<_> = patch.files.iter().collect();
+      let source = BothSource {
+        odb,
+        index,
+        work_dir: &work_dir,
+      };
+      apply_files(odb, &files, &source, options)?
+    }
+    None => {
+      let files: Vec<_> = patch
+        .files
+        .iter()
+        .filter(|file| {
+          file.old_mode != Some(FileMode::GitLink) && file.new_mode != Some(FileMode::GitLink)
+        })
+        .collect();
+      apply_files(odb, &files, &work_dir, options)?
+    }
+  };
+  write_work_dir(odb, work_dir.work_dir, &results, index, options)?;
+  Trace2::data("apply", "files", results.len());
+  Ok(())
+}
+
+impl Repository {
+  /// Apply a [`Patch`] to the repository using the [`ApplyOptions`] from its
+  /// [`Config`]. See [`apply_to_work_dir`] and [`apply_to_index`].
+  pub fn apply(&self, patch: &Patch, location: ApplyLocation) -> Result<(), ApplyError> {
+    let mut options = ApplyOptions::from_config(self.config())?;
+    options
+      .checkout
+      .set_attributes(self.attributes()?, self.work_dir());
+    let work_dir = || self.work_dir().ok_or(ApplyError::BareRepository);
+    match location {
+      ApplyLocation::WorkDir => apply_to_work_dir(self.odb(), work_dir()?, None, patch, &options),
+      ApplyLocation::Index => {
+        let mut index = self.index()?;
+        apply_to_index(self.odb(), &mut index, patch, &options)?;
+        Ok(index.write(self.index_path())?)
+      }
+      ApplyLocation::Both => {
+        let work_dir = work_dir()?;
+        let mut index = self.index()?;
+        apply_to_work_dir(self.odb(), work_dir, Some(&mut index), patch, &options)?;
+        Ok(index.write(self.index_path())?)
+      }
+    }
+  }
+}
+
+struct Parser<'a> {
+  lines: Vec<&'a [u8]>,
+  pos: usize,
+  strip: usize,
+  /// Whether `strip` was given instead of being a default that the first
+  /// traditional diff can change
+  strip_known: bool,
+}
+
+const NO_NAME: &str = "the patch lacks filename information";
+
+impl<'a> Parser<'a> {
+  fn peek(&self, n: usize) -> Option<&'a [u8]> {
+    self.lines.get(self.pos + n).copied()
+  }
+
+  /// An error for the line at `idx`, counting from 0
+  fn error(&self, idx: usize, reason: &'static str) -> ApplyError {
+    ApplyError::Malformed {
+      line: idx + 1,
+      reason,
+    }
+  }
+
+  /// Parse a `diff --git` header and what follows it
+  fn git_diff(&mut self) -> Result<FilePatch, ApplyError> {
+    let start = self.pos;
+    let header = trim_newline(self.lines[start]);
+    let default_name = self.git_header_name(&header[b"diff --git ".len()..]);
+    self.pos += 1;
+    let mut patch = FilePatch::default();
+    let (mut old_name, mut new_name) = (None, None);
+    let (mut created, mut deleted) = (false, false);
+    // Paths in rename and copy lines don't have the `a/` or `b/` prefix
+    let moved_strip = self.strip.saturating_sub(1);
+    while let Some(line) = self.peek(0) {
+      let idx = self.pos;
+      let text = trim_newline(line);
+      let path =
+        |text: &[u8], strip: usize| parse_path(text, strip).ok_or(self.error(idx, NO_NAME));
+      let mode =
+        |text: &[u8]| FileMode::from_bytes(text).map_err(|_| self.error(idx, "invalid mode"));
+      if let Some(rest) = text.strip_prefix(b"--- ") {
+        match path(rest, self.strip)? {
+          Some(name) => old_name = Some(name),
+          None => created = true,
+        }
+      } else if let Some(rest) = text.strip_prefix(b"+++ ") {
+        match path(rest, self.strip)? {
+          Some(name) => new_name = Some(name),
+          None => deleted = true,
+        }
+      } else if let Some(rest) = text.strip_prefix(b"old mode ") {
+        patch.old_mode = Some(mode(rest)?);
+      } else if let Some(rest) = text.strip_prefix(b"new mode ") {
+        patch.new_mode = Some(mode(rest)?);
+      } else if let Some(rest) = text.strip_prefix(b"deleted file mode ") {
+        patch.old_mode = Some(mode(rest)?);
+        deleted = true;
+      } else if let Some(rest) = text.strip_prefix(b"new file mode ") {
+        patch.new_mode = Some(mode(rest)?);
+        created = true;
+      } else if let Some(rest) = text.strip_prefix(b"copy from ") {
+        old_name = path(rest, moved_strip)?;
+        patch.copy = true;
+      } else if let Some(rest) = text.strip_prefix(b"copy to ") {
+        new_name = path(rest, moved_strip)?;
+        patch.copy = true;
+      } else if let Some(rest) = text
+        .strip_prefix(b"rename from ")
+        .or_else(|| text.strip_prefix(b"rename old "))
+      {
+        old_name = path(rest, moved_strip)?;
+      } else if let Some(rest) = text
+        .strip_prefix(b"rename to ")
+        .or_else(|| text.strip_prefix(b"rename new "))
+      {
+        new_name = path(rest, moved_strip)?;
+      } else if let Some(rest) = text.strip_prefix(b"index ") {
+        let (old_id, new_id, index_mode) =
+          parse_index(rest).ok_or_else(|| self.error(idx, "invalid index line"))?;
+        patch.old_id = Some(old_id);
+        patch.new_id = Some(new_id);
+        // The mode is only given when it stays the same
+        patch.old_mode = index_mode.or(patch.old_mode);
+      } else if !text.starts_with(b"similarity index ")
+        && !text.starts_with(b"dissimilarity index ")
+      {
+        break;
+      }
+      self.pos += 1;
+    }
+    if created && deleted {
+      return Err(self.error(start, "the patch both creates and deletes the file"));
+    }
+    let name = |name: Option<BString>| {
+      name
+        .or_else(|| default_name.clone())
+        .ok_or(self.error(start, NO_NAME))
+    };
+    patch.old_path = if created { None } else { Some(name(old_name)?) };
+    patch.new_path = if deleted { None } else { Some(name(new_name)?) };
+
+    match self.peek(0) {
+      Some(line) if line.starts_with(b"@@ -") => patch.hunks = self.hunks(created, deleted)?,
+      Some(b"GIT binary patch\n") => {
+        let idx = self.pos;
+        self.pos += 1;
+        let forward = self
+          .binary_data()?
+          .ok_or_else(|| self.error(idx, "the binary patch has no data"))?;
+        patch.binary = Some(BinaryPatch {
+          forward: Some(forward),
+          reverse: self.binary_data()?,
+        });
+      }
+      Some(line) if line.starts_with(b"Binary files ") && line.ends_with(b" differ\n") => {
+        patch.binary = Some(BinaryPatch::default());
+        self.pos += 1;
+      }
+      _ => {}
+    }
+    Ok(patch)
+  }
+
+  /// The name of a file that isn't renamed from the `diff --git` line,
+  /// where names with spaces can't be told apart from the separator unless
+  /// both are the same
+  fn git_header_name(&self, text: &[u8]) -> Option<BString> {
+    let same = |first: &[u8], second: &[u8]| {
+      let first = strip_path(first, self.strip)?;
+      (Some(&first) == strip_path(second, self.strip).as_ref()).then_some(first)
+    };
+    if text.starts_with(b"\"") {
+      let (first, len) = unquote_path(text)?;
+      let rest = text[len..].trim_start_with(|c| c == ' ');
+      return match unquote_path(rest) {
+        Some((second, _)) => same(&first, &second),
+        None => same(&first, rest),
+      };
+    }
+    if let Some(quote) = text.find(" \"").filter(|_| text.ends_with(b"\"")) {
+      let (second, _) = unquote_path(&text[quote + 1..])?;
+      return same(&text[..quote], &second);
+    }
+    text
+      .iter()
+      .enumerate()
+      .filter(|(_, c)| **c == b' ')
+      .find_map(|(idx, _)| same(&text[..idx], &text[idx + 1..]))
+  }
+
+  /// Parse a `---` and `+++` line and the hunks after them
+  fn traditional_diff(&mut self) -> Result<FilePatch, ApplyError> {
+    let start = self.pos;
+    if !self.strip_known {
+      // Like git's `guess_p_value`, names without a directory aren't
+      // stripped and then neither are the ones in the rest of the patch
+      let guess = |idx: usize| {
+        let name = parse_path(&trim_newline(self.lines[idx])[4..], 0)??;
+        (!name.contains(&b'/')).then_some(0)
+      };
+      let (old, new) = (guess(start), guess(start + 1));
+      if old.or(new).is_some() && old.or(new) == new {
+        self.strip = 0;
+        self.strip_known = true;
+      }
+    }
+    let path = |idx: usize| {
+      parse_path(&trim_newline(self.lines[idx])[4..], self.strip).ok_or(self.error(idx, NO_NAME))
+    };
+    let (old, new) = (path(start)?, path(start + 1)?);
+    self.pos += 2;
+    let mut patch = FilePatch::default();
+    match (old, new) {
+      (None, None) => return Err(self.error(start, NO_NAME)),
+      (old, None) => patch.old_path = old,
+      (None, new) => patch.new_path = new,
+      (Some(old), Some(new)) => {
+        // Like git, prefer the old name if it's the start of the new one as
+        // with `file` and `file.new`
+        let name = if old.len() < new.len() && new.starts_with(&old) {
+          old
+        } else {
+          new
+        };
+        patch.old_path = Some(name.clone());
+        patch.new_path = Some(name);
+      }
+    }
+    patch.hunks = self.hunks(patch.old_path.is_none(), patch.new_path.is_none())?;
+    Ok(patch)
+  }
+
+  fn hunks(&mut self, created: bool, deleted: bool) -> Result<Vec<Hunk>, ApplyError> {
+    let mut hunks = Vec::new();
+    while let Some(line) = self.peek(0).filter(|line| line.starts_with(b"@@ -")) {
+      let start = self.pos;
+      let (old_start, old_len, new_start, new_len) = parse_hunk_header(trim_newline(line))
+        .ok_or_else(|| self.error(start, "invalid hunk header"))?;
+      if created && old_len > 0 {
+        return Err(self.error(start, "new file depends on old contents"));
+      }
+      if deleted && new_len > 0 {
+        return Err(self.error(start, "deleted file still has contents"));
+      }
+      self.pos += 1;
+      let (mut old_left, mut new_left) = (old_len, new_len);
+      let mut lines: Vec<DiffLine> = Vec::new();
+      loop {
+        let idx = self.pos;
+        let line = match self.peek(0) {
+          Some(line) => line,
+          None if old_left == 0 && new_left == 0 => break,
+          None => return Err(self.error(idx, "the patch ends in the middle of a hunk")),
+        };
+        // A line missing its newline is followed by `\ No newline at end of
+        // file`, even after the last line the header counts
+        if line.starts_with(b"\\ ") {
+          let last = lines
+            .last_mut()
+            .ok_or_else(|| self.error(idx, "unexpected line in a hunk"))?;
+          if last.content.ends_with(b"\n") {
+            last.content.pop();
+          }
+          self.pos += 1;
+          continue;
+        }
+        if old_left == 0 && new_left == 0 {
+          break;
+        }
+        let (kind, content) = match line[0] {
+          b' ' => (LineKind::Context, &line[1..]),
+          // Some tools strip the space from empty context lines
+          b'\n' => (LineKind::Context, line),
+          b'-' => (LineKind::Removed, &line[1..]),
+          b'+' => (LineKind::Added, &line[1..]),
+          _ => return Err(self.error(idx, "unexpected line in a hunk")),
+        };
+        let (old, new) = match kind {
+          LineKind::Context => (1, 1),
+          LineKind::Removed => (1, 0),
+          LineKind::Added => (0, 1),
+        };
+        if old > old_left || new > new_left {
+          return Err(self.error(idx, "the hunk has more lines than its header says"));
+        }
+        old_left -= old;
+        new_left -= new;
+        lines.push(DiffLine {
+          kind,
+          content: content.into(),
+        });
+        self.pos += 1;
+      }
+      hunks.push(Hunk {
+        old_start,
+        old_len,
+        new_start,
+        new_len,
+        lines,
+      });
+    }
+    Ok(hunks)
+  }
+
+  /// Parse one `literal` or `delta` block of a binary patch and the empty
+  /// line after it, if there is one
+  fn binary_data(&mut self) -> Result<Option<BinaryData>, ApplyError> {
+    let start = self.pos;
+    let text = match self.peek(0) {
+      Some(line) => trim_newline(line),
+      None => return Ok(None),
+    };
+    let (literal, size) = if let Some(size) = text.strip_prefix(b"literal ") {
+      (true, size)
+    } else if let Some(size) = text.strip_prefix(b"delta ") {
+      (false, size)
+    } else {
+      return Ok(None);
+    };
+    let size = parse_number(size).ok_or_else(|| self.error(start, "invalid binary patch size"))?;
+    self.pos += 1;
+    let mut deflated = Vec::new();
+    loop {
+      let idx = self.pos;
+      let line = self
+        .peek(0)
+        .ok_or_else(|| self.error(idx, "the binary patch ends early"))?;
+      self.pos += 1;
+      let line = trim_newline(line);
+      if line.is_empty() {
+        break;
+      }
+      decode_base85(line, &mut deflated).ok_or_else(|| self.error(idx, "invalid base85 data"))?;
+    }
+    let data = zlib::decompress_with_limit(&deflated, size)
+      .ok()
+      .map(|(data, _)| data)
+      .filter(|data| data.len() == size)
+      .ok_or_else(|| self.error(start, "the binary patch data is corrupt"))?;
+    Ok(Some(if literal {
+      BinaryData::Literal(data)
+    } else {
+      BinaryData::Delta(data)
+    }))
+  }
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+  line.strip_suffix(b"\n").unwrap_or(line)
+}
+
+fn parse_number(text: &[u8]) -> Option<usize> {
+  if text.is_empty() || !text.iter().all(u8::is_ascii_digit) {
+    return None;
+  }
+  text.to_str().ok()?.parse().ok()
+}
+
+/// Parse `@@ -old_start,old_len +new_start,new_len @@`, where a missing
+/// length is 1
+fn parse_hunk_header(text: &[u8]) -> Option<(usize, usize, usize, usize)> {
+  let rest = text.strip_prefix(b"@@ -")?;
+  let ranges = &rest[..rest.find(" @@")?];
+  let split = ranges.find(" +")?;
+  let range = |range: &[u8]| match range.find_byte(b',') {
+    Some(comma) => Some((
+      parse_number(&range[..comma])?,
+      parse_number(&range[comma + 1..])?,
+    )),
+    None => Some((parse_number(range)?, 1)),
+  };
+  let (old_start, old_len) = range(&ranges[..split])?;
+  let (new_start, new_len) = range(&ranges[split + 2..])?;
+  Some((old_start, old_len, new_start, new_len))
+}
+
+/// Parse `old..new` and the optional mode after it on an `index` line
+fn parse_index(text: &[u8]) -> Option<(String, String, Option<FileMode>)> {
+  let (ids, mode) = match text.find_byte(b' ') {
+    Some(space) => (
+      &text[..space],
+      Some(FileMode::from_bytes(&text[space + 1..]).ok()?),
+    ),
+    None => (text, None),
+  };
+  let dots = ids.find("..")?;
+  let id = |id: &[u8]| {
+    (!id.is_empty() && id.iter().all(u8::is_ascii_hexdigit))
+      .then(|| id.to_str().unwrap().to_ascii_lowercase())
+  };
+  Some((id(&ids[..dots])?, id(&ids[dots + 2..])?, mode))
+}
+
+/// Parse a path that is either quoted or ends at a tab or the end of the
+/// line and drop `strip` leading components from it, where `/dev/null` is
+/// no path at all
+fn parse_path(text: &[u8], strip: usize) -> Option<Option<BString>> {
+  let path = match unquote_path(text) {
+    Some((path, _)) => path,
+    None => text[..text.find_byte(b'\t').unwrap_or(text.len())].to_vec(),
+  };
+  if path == b"/dev/null" {
+    return Some(None);
+  }
+  strip_path(&path, strip).map(Some)
+}
+
+fn strip_path(path: &[u8], strip: usize) -> Option<BString> {
+  let mut rest = path;
+  for _ in 0..strip {
+    rest = &rest[rest.find_byte(b'/')? + 1..];
+    rest = rest.trim_start_with(|c| c == '/');
+  }
+  (!rest.is_empty()).then(|| rest.into())
+}
+
+const BASE85: &[u8; 85] =
+  b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!#$%&()*+-;<=>?@^_`{|}~";
+
+/// Decode a line of a binary patch, which starts with a letter for how many
+/// bytes it holds followed by groups of 5 base85 digits for every 4 bytes
+fn decode_base85(line: &[u8], out: &mut Vec<u8>) -> Option<()> {
+  let mut len = match line.first()? {
+    c @ b'A'..=b'Z' => c - b'A' + 1,
+    c @ b'a'..=b'z' => c - b'a' + 27,
+    _ => return None,
+  } as usize;
+  let encoded = &line[1..];
+  if encoded.len() != len.div_ceil(4) * 5 {
+    return None;
+  }
+  for group in encoded.chunks(5) {
+    let value = group.iter().try_fold(0u32, |value, c| {
+      let digit = BASE85.iter().position(|d| d == c)? as u32;
+      value.checked_mul(85)?.checked_add(digit)
+    })?;
+    let bytes = len.min(4);
+    out.extend_from_slice(&value.to_be_bytes()[..bytes]);
+    len -= bytes;
+  }
+  Some(())
+}
+
+/// The contents and mode of a file a patch applies to. Submodules have the
+/// contents `git diff` shows for them.
+#[derive(Debug, Clone)]
+struct File {
+  mode: FileMode,
+  contents: Vec<u8>,
+}
+
+impl File {
+  fn from_object(odb: &Odb, mode: FileMode, oid: &OID) -> Result<Self, ApplyError> {
+    let contents = match mode {
+      FileMode::GitLink => format!("Subproject commit {}\n", oid.as_hex()).into_bytes(),
+      _ => odb.read_blob(oid)?.contents().to_vec(),
+    };
+    Ok(Self { mode, contents })
+  }
+}
+
+/// Where the files a patch applies to come from
+trait Source {
+  /// The file at `path` if there is one, where `mode` is what the patch
+  /// expects it to be
+  fn read(&self, path: &BStr, mode: Option<FileMode>) -> Result<Option<File>, ApplyError>;
+
+  /// Whether something is in the way of creating a file at `path`
+  fn exists(&self, path: &BStr) -> Result<bool, ApplyError> {
+    Ok(self.read(path, None)?.is_some())
+  }
+}
+
+struct IndexSource<'a> {
+  odb: &'a Odb,
+  index: &'a Index,
+}
+
+impl Source for IndexSource<'_> {
+  fn read(&self, path: &BStr, _: Option<FileMode>) -> Result<Option<File>, ApplyError> {
+    self
+      .index
+      .get(path)
+      .map(|entry| File::from_object(self.odb, entry.mode, &entry.oid))
+      .transpose()
+  }
+}
+
+struct WorkDirSource<'a> {
+  work_dir: &'a Path,
+  options: &'a ApplyOptions,
+}
+
+impl WorkDirSource<'_> {
+  /// The path of `path` in the working tree and its metadata, `None` if
+  /// nothing is there
+  fn metadata(&self, path: &BStr) -> Result<Option<(PathBuf, fs::Metadata)>, ApplyError> {
+    let mut full = self.work_dir.to_path_buf();
+    let names: Vec<_> = path.split_str("/").collect();
+    for (idx, name) in names.iter().enumerate() {
+      full.push(
+        name
+          .to_path()
+          .map_err(|_| ApplyError::InvalidPath(path.into()))?,
+      );
+      let metadata = match fs::symlink_metadata(&full) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+      };
+      if idx + 1 == names.len() {
+        return Ok(Some((full, metadata)));
+      }
+      if metadata.file_type().is_symlink() {
+        return Err(ApplyError::BeyondSymlink(path.into()));
+      }
+      if !metadata.is_dir() {
+        return Ok(None);
+      }
+    }
+    Ok(None)
+  }
+}
+
+impl Source for WorkDirSource<'_> {
+  fn read(&self, path: &BStr, mode: Option<FileMode>) -> Result<Option<File>, ApplyError> {
+    let (full, metadata) = match self.metadata(path)? {
+      Some(found) => found,
+      None => return Ok(None),
+    };
+    if metadata.is_dir() {
+      return Ok(Some(File {
+        mode: FileMode::Tree,
+        contents: Vec::new(),
+      }));
+    }
+    let contents = if metadata.file_type().is_symlink() {
+      let target = fs::read_link(&full)?;
+      <[u8]>::from_path(&target)
+        .ok_or_else(|| ApplyError::InvalidPath(path.into()))?
+        .to_vec()
+    } else {
+      let contents = fs::read(&full)?;
+      self
+        .options
+        .checkout
+        .to_git(path, &contents, None)?
+        .into_owned()
+    };
+    Ok(Some(File {
+      mode: worktree_mode(mode, &metadata, &self.options.checkout),
+      contents,
+    }))
+  }
+
+  fn exists(&self, path: &BStr) -> Result<bool, ApplyError> {
+    // Like git, a directory is only in the way if it's still there once
+    // the files in it the patch deletes are gone
+    Ok(
+      self
+        .metadata(path)?
+        .is_some_and(|(_, metadata)| !metadata.is_dir()),
+    )
+  }
+}
+
+/// Files come from the [`Index`] but the working tree has to match it
+struct BothSource<'a> {
+  odb: &'a Odb,
+  index: &'a Index,
+  work_dir: &'a WorkDirSource<'a>,
+}
+
+impl Source for BothSource<'_> {
+  fn read(&self, path: &BStr, _: Option<FileMode>) -> Result<Option<File>, ApplyError> {
+    let entry = match self.index.get(path) {
+      Some(entry) => entry,
+      None => return Ok(None),
+    };
+    // A file missing from the working tree is written again from the result
+    if let Some((full, metadata)) = self.work_dir.metadata(path)? {
+      let options = &self.work_dir.options.checkout;
+      let matches = entry.mode == FileMode::GitLink
+        || (entry.is_stat_clean(&metadata, options) && !self.index.is_racy(entry))
+        || (entry.worktree_mode(&metadata, options) == entry.mode
+          && !metadata.is_dir()
+          && hash_file(&full, &metadata, path, options)? == entry.oid);
+      if !matches {
+        return Err(ApplyError::DoesNotMatchIndex(path.into()));
+      }
+    }
+    File::from_object(self.odb, entry.mode, &entry.oid).map(Some)
+  }
+
+  fn exists(&self, path: &BStr) -> Result<bool, ApplyError> {
+    Ok(self.index.get(path).is_some() || self.work_dir.exists(path)?)
+  }
+}
+
+/// What the [`FilePatch`]es applied so far did to a path, like git's
+/// `fn_table`
+enum PathState {
+  /// A later patch deletes or renames the file
+  ToBeDeleted,
+  /// An earlier patch deleted or renamed the file
+  WasDeleted,
+  /// What an earlier patch left in the file
+  Patched(File),
+}
+
+/// Apply every [`FilePatch`] to the files in `source`, returning each path
+/// they touch in the order they were first touched along with what it ends
+/// up as, with `None` for ones that are removed.
+///
+/// Like git, a patch sees what earlier ones left in its file, except for
+/// renames and copies which always start from the file in `source`. Every
+/// removal happens before any file is written, so a path is only removed
+/// if no patch writes it.
+fn apply_files(
+  odb: &Odb,
+  files: &[&FilePatch],
+  source: &impl Source,
+  options: &ApplyOptions,
+) -> Result<Vec<(BString, Option<File>)>, ApplyError> {
+  let moved = |file: &FilePatch| {
+    file.old_path.is_some() && file.new_path.is_some() && file.old_path != file.new_path
+  };
+  let renamed = |file: &FilePatch| moved(file) && !file.copy;
+  let mut states: HashMap<&BString, PathState> = files
+    .iter()
+    .filter(|file| file.new_path.is_none() || renamed(file))
+    .filter_map(|file| file.old_path.as_ref())
+    .map(|path| (path, PathState::ToBeDeleted))
+    .collect();
+  let mut results: Vec<(BString, Option<File>)> = Vec::new();
+  let mut positions: HashMap<&BString, usize> = HashMap::new();
+  for &file in files {
+    for path in file.old_path.iter().chain(&file.new_path) {
+      check_path(path, options)?;
+    }
+    let old = match &file.old_path {
+      None => None,
+      Some(path) => {
+        let old = match states.get(path) {
+          Some(PathState::Patched(old)) if !moved(file) => Some(old.clone()),
+          Some(PathState::WasDeleted) if !moved(file) => None,
+          _ => source.read(path.as_bstr(), file.old_mode)?,
+        };
+        let old = old.ok_or_else(|| ApplyError::DoesNotExist(path.clone()))?;
+        // Only a different kind of file is an error, git just warns about
+        // the executable bit
+        if old.mode.is_tree()
+          || file
+            .old_mode
+            .is_some_and(|mode| kind_of(mode) != kind_of(old.mode))
+        {
+          return Err(ApplyError::WrongType(path.clone()));
+        }
+        Some(old)
+      }
+    };
+    let (path, new_path) = match (&file.old_path, &file.new_path) {
+      (_, Some(new_path)) => (new_path, Some(new_path)),
+      (Some(old_path), None) => (old_path, None),
+      (None, None) => continue,
+    };
+    if let Some(new_path) = new_path.filter(|&path| file.old_path.as_ref() != Some(path)) {
+      let deleted = matches!(
+        states.get(new_path),
+        Some(PathState::ToBeDeleted | PathState::WasDeleted)
+      );
+      if !deleted && source.exists(new_path.as_bstr())? {
+        return Err(ApplyError::AlreadyExists(new_path.clone()));
+      }
+    }
+
+    let old_contents = old.as_ref().map_or(&[][..], |old| &old.contents);
+    let contents = match &file.binary {
+      Some(binary) => apply_binary(odb, path.as_bstr(), old_contents, file, binary)?,
+      None => apply_hunks(path.as_bstr(), old_contents, &file.hunks, options)?,
+    };
+    let mut record = |path, file: Option<File>| match positions.get(path) {
+      Some(&idx) if file.is_some() => results[idx].1 = file,
+      Some(_) => {}
+      None => {
+        positions.insert(path, results.len());
+        results.push((path.clone(), file));
+      }
+    };
+    match new_path {
+      None if !contents.is_empty() => return Err(ApplyError::LeavesContents(path.clone())),
+      None => {
+        record(path, None);
+        states.insert(path, PathState::WasDeleted);
+      }
+      Some(new_path) => {
+        let mode = file
+          .new_mode
+          .or(file.old_mode)
+          .or(old.map(|old| old.mode))
+          .unwrap_or(FileMode::NonExecutableFile);
+        let new = File { mode, contents };
+        record(new_path, Some(new.clone()));
+        states.insert(new_path, PathState::Patched(new));
+        if renamed(file) {
+          let old_path = file.old_path.as_ref().unwrap();
+          record(old_path, None);
+          states.insert(old_path, PathState::WasDeleted);
+        }
+      }
+    }
+  }
+  Ok(results)
+}
+
+/// Refuse paths git wouldn't check out, like ones with `..` or `.git` in
+/// them
+fn check_path(path: &BString, options: &ApplyOptions) -> Result<(), ApplyError> {
+  for name in path.split_str("/") {
+    entry_path(name.as_bstr(), &options.checkout)
+      .map_err(|_| ApplyError::InvalidPath(path.clone()))?;
+  }
+  Ok(())
+}
+
+/// Apply the hunks of a text patch to `old` like git's
+/// `apply_one_fragment`. A hunk's lines have to match exactly apart from
+/// whitespace after a last line without a newline, and lines added or
+/// changed by an earlier hunk are never matched again. A hunk that
+/// starts at the first line only matches at the start of the file and one
+/// without context after its changes only at the end, until it's tried
+/// again with less context.
+fn apply_hunks(
+  path: &BStr,
+  old: &[u8],
+  hunks: &[Hunk],
+  options: &ApplyOptions,
+) -> Result<Vec<u8>, ApplyError> {
+  // Every line of the file and whether a hunk changed it
+  let mut image: Vec<(&[u8], bool)> = old
+    .lines_with_terminator()
+    .map(|line| (line, false))
+    .collect();
+  let min_context = options.min_context.unwrap_or(usize::MAX);
+  for hunk in hunks {
+    let side = |skip: LineKind| -> Vec<&[u8]> {
+      hunk
+        .lines
+        .iter()
+        .filter(|line| line.kind != skip)
+        .map(|line| line.content.as_bytes())
+        .collect()
+    };
+    let (mut preimage, mut postimage) = (side(LineKind::Added), side(LineKind::Removed));
+    let context = |line: &&DiffLine| line.kind == LineKind::Context;
+    let mut leading = hunk.lines.iter().take_while(context).count();
+    let mut trailing = hunk.lines.iter().rev().take_while(context).count();
+    let mut match_beginning = hunk.old_start == 0 || (hunk.old_start == 1 && !options.unidiff_zero);
+    let mut match_end = !options.unidiff_zero && trailing == 0;
+    let mut line = hunk.new_start.saturating_sub(1) as isize;
+    let at = loop {
+      if let Some(at) = find_position(&image, &preimage, line, match_beginning, match_end) {
+        break at;
+      }
+      if leading <= min_context && trailing <= min_context {
+        return Err(ApplyError::DoesNotApply {
+          path: path.into(),
+          line: hunk.old_start,
+        });
+      }
+      if match_beginning || match_end {
+        match_beginning = false;
+        match_end = false;
+        continue;
+      }
+      // Drop a line of context from the side with more of it, or from both
+      // if they have the same amount
+      if leading >= trailing {
+        preimage.remove(0);
+        postimage.remove(0);
+        line -= 1;
+        leading -= 1;
+      }
+      if trailing > leading {
+        preimage.pop();
+        postimage.pop();
+        trailing -= 1;
+      }
+    };
+    image.splice(
+      at..at + preimage.len(),
+      postimage.iter().map(|line| (*line, true)),
+    );
+  }
+  Ok(
+    image
+      .into_iter()
+      .flat_map(|(line, _)| line.iter().copied())
+      .collect(),
+  )
+}
+
+/// Where `preimage` matches `image` going by git's `find_pos`, which looks
+/// at `line` first and then one line after, one line before, two lines
+/// after, and so on. A `line` before the start of the file, which happens
+/// after dropping leading context from a hunk at the top, starts the search
+/// at the end like it does in git.
+fn find_position(
+  image: &[(&[u8], bool)],
+  preimage: &[&[u8]],
+  line: isize,
+  match_beginning: bool,
+  match_end: bool,
+) -> Option<usize> {
+  if preimage.len() > image.len() {
+    return None;
+  }
+  let line = if match_beginning {
+    0
+  } else if match_end {
+    (image.len() - preimage.len()) as isize
+  } else {
+    line
+  };
+  let line = usize::try_from(line)
+    .ok()
+    .filter(|&line| line <= image.len())
+    .unwrap_or(image.len());
+  let matches = |at: usize| {
+    let end = at + preimage.len();
+    end <= image.len()
+      && (!match_end || end == image.len())
+      && (!match_beginning || at == 0)
+      && image[at..end].iter().zip(preimage).enumerate().all(
+        |(idx, ((line, patched), expected))| {
+          // git compares the lines as one buffer, so a last line without
+          // its newline also matches one with it or other whitespace after
+          // it, but not at the end of the file
+          let last = idx + 1 == preimage.len() && !match_end && !expected.ends_with(b"\n");
+          !patched
+            && (line == expected
+              || (last
+                && line.starts_with(expected)
+                && line[expected.len()..]
+                  .iter()
+                  .all(|c| matches!(c, b' ' | b'\t' | b'\n' | b'\r'))))
+        },
+      )
+  };
+  let (mut backwards, mut forwards) = (line, line);
+  let mut current = line;
+  let mut backwards_turn = false;
+  loop {
+    if matches(current) {
+      return Some(current);
+    }
+    if backwards == 0 && forwards == image.len() {
+      return None;
+    }
+    // Once one direction runs out the other one is taken every time
+    if (backwards_turn && backwards > 0) || forwards == image.len() {
+      backwards -= 1;
+      current = backwards;
+    } else {
+      forwards += 1;
+      current = forwards;
+    }
+    backwards_turn = !backwards_turn;
+  }
+}
+
+/// Apply a binary patch like git does, which needs the full object names
+/// on the `index` line to check the old contents are the ones the patch
+/// was made for and the result is what it should be. If the new contents
+/// are already in the [`Odb`] they are used as they are.
+fn apply_binary(
+  odb: &Odb,
+  path: &BStr,
+  old: &[u8],
+  file: &FilePatch,
+  binary: &BinaryPatch,
+) -> Result<Vec<u8>, ApplyError> {
+  let error = |reason| ApplyError::Binary {
+    path: path.into(),
+    reason,
+  };
+  let full = |id: &Option<String>| {
+    id.as_deref()
+      .filter(|id| id.len() == 40)
+      .and_then(|id| OID::from_hex(id).ok())
+  };
+  let (old_oid, new_oid) = match (full(&file.old_id), full(&file.new_id)) {
+    (Some(old_oid), Some(new_oid)) => (old_oid, new_oid),
+    _ => return Err(error("the patch has no full index line")),
+  };
+  if file.old_path.is_some() && Blob::new(old).id() != old_oid {
+    return Err(error("the file doesn't match what the patch applies to"));
+  }
+  if new_oid == OID::NULL {
+    return Ok(Vec::new());
+  }
+  match odb.read_blob(&new_oid) {
+    Ok(blob) => return Ok(blob.contents().to_vec()),
+    Err(OdbError::NotFound(_)) => {}
+    Err(e) => return Err(e.into()),
+  }
+  let new = match &binary.forward {
+    None => return Err(error("the patch doesn't have the new contents")),
+    Some(BinaryData::Literal(data)) => data.clone(),
+    Some(BinaryData::Delta(delta)) => {
+      apply_delta(old, delta, odb.budget())
+        .map_err(|_| error("the delta doesn't apply"))?
+        .0
+    }
+  };
+  if Blob::new(new.as_slice()).id() != new_oid {
+    return Err(error("the result doesn't match the index line"));
+  }
+  Ok(new)
+}
+
+/// The commit a submodule points at from the contents `git diff` shows for
+/// it
+fn submodule_commit(path: &BString, contents: &[u8]) -> Result<OID, ApplyError> {
+  contents
+    .strip_prefix(b"Subproject commit ")
+    .and_then(|hex| hex.trim_end().to_str().ok())
+    .and_then(|hex| OID::from_hex(hex).ok())
+    .ok_or_else(|| ApplyError::InvalidSubmodule(path.clone()))
+}
+
+/// Add a file to the [`Index`], replacing anything in the way of it like a
+/// file where one of its directories goes
+fn add_to_index(
+  odb: &Odb,
+  index: &mut Index,
+  path: &BString,
+  file: &File,
+  stat: StatData,
+) -> Result<(), ApplyError> {
+  let oid = match file.mode {
+    FileMode::GitLink => submodule_commit(path, &file.contents)?,
+    _ => odb.write_blob(&Blob::new(file.contents.as_slice()))?,
+  };
+  for (slash, _) in path.iter().enumerate().filter(|(_, c)| **c == b'/') {
+    index.remove(&path[..slash]);
+  }
+  let dir = [path.as_bytes(), b"/"].concat();
+  let inside: Vec<BString> = index
+    .entries()
+    .iter()
+    .filter(|entry| entry.path.starts_with(&dir))
+    .map(|entry| entry.path.clone())
+    .collect();
+  for inside in inside {
+    index.remove(inside);
+  }
+  index.remove(path);
+  index.add(IndexEntry::new(path.clone(), file.mode, oid, stat));
+  Ok(())
+}
+
+/// Write the results of a patch to the working tree, and to the [`Index`]
+/// if there is one. Everything is removed first so a file can take the
+/// place of a directory that is going away.
+fn write_work_dir(
+  odb: &Odb,
+  work_dir: &Path,
+  results: &[(BString, Option<File>)],
+  mut index: Option<&mut Index>,
+  options: &ApplyOptions,
+) -> Result<(), ApplyError> {
+  let full_path = |path: &BString| {
+    path
+      .to_path()
+      .map(|path| work_dir.join(path))
+      .map_err(|_| ApplyError::InvalidPath(path.clone()))
+  };
+  for (path, _) in results.iter().filter(|(_, file)| file.is_none()) {
+    let full = full_path(path)?;
+    match fs::symlink_metadata(&full) {
+      // What is left of a submodule is only removed if it's empty
+      Ok(metadata) if metadata.is_dir() => {
+        let _ = fs::remove_dir(&full);
+      }
+      Ok(_) => remove_existing(&full)?,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+      Err(e) => return Err(e.into()),
+    }
+    // Like git, directories left empty are removed too
+    let mut dir = full.parent();
+    while let Some(parent) = dir.filter(|&dir| dir != work_dir) {
+      if fs::remove_dir(parent).is_err() {
+        break;
+      }
+      dir = parent.parent();
+    }
+    if let Some(index) = index.as_deref_mut() {
+      index.remove(path);
+    }
+  }
+  for (path, file) in results {
+    let file = match file {
+      Some(file) => file,
+      None => continue,
+    };
+    let full = full_path(path)?;
+    create_parents(work_dir, path)?;
+    match file.mode {
+      FileMode::GitLink if full.is_dir() => {}
+      FileMode::GitLink => {
+        remove_existing(&full)?;
+        fs::create_dir(&full)?;
+      }
+      FileMode::SymbolicLink => {
+        remove_existing(&full)?;
+        if options.checkout.symlinks {
+          write_symlink(&file.contents, &full)?;
+        } else {
+          write_file(&full, &file.contents, false)?;
+        }
+      }
+      mode => {
+        remove_existing(&full)?;
+        let executable = mode == FileMode::ExecutableFile && options.checkout.file_mode;
+        let contents = options.checkout.to_worktree(path, &file.contents)?;
+        write_file(&full, &contents, executable)?;
+      }
+    }
+    if let Some(index) = index.as_deref_mut() {
+      let stat = match file.mode {
+        FileMode::GitLink => StatData::default(),
+        _ => StatData::from_metadata(&fs::symlink_metadata(&full)?),
+      };
+      add_to_index(odb, index, path, file, stat)?;
+    }
+  }
+  Ok(())
+}
+
+/// Create the directories leading up to `path` in `work_dir`, without
+/// replacing anything that is in the way
+fn create_parents(work_dir: &Path, path: &BString) -> Result<(), ApplyError> {
+  let mut dir = work_dir.to_path_buf();
+  let names: Vec<_> = path.split_str("/").collect();
+  for (idx, name) in names[..names.len() - 1].iter().enumerate() {
+    dir.push(
+      name
+        .to_path()
+        .map_err(|_| ApplyError::InvalidPath(path.clone()))?,
+    );
+    match fs::symlink_metadata(&dir) {
+      Ok(metadata) if metadata.is_dir() && !metadata.file_type().is_symlink() => {}
+      Ok(_) => {
+        return Err(ApplyError::AlreadyExists(
+          names[..=idx].join(&b"/"[..]).into(),
+        ))
+      }
+      Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(&dir)?,
+      Err(e) => return Err(e.into()),
+    }
+  }
+  Ok(())
+}
+
+#[derive(Error, Debug)]
+/// Errors related to parsing and applying a [`Patch`]
+pub enum ApplyError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Index(#[from] IndexError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Checkout(#[from] CheckoutError),
+  #[error("{0}")]
+  Attributes(#[from] AttributesError),
+  #[error("{0}")]
+  Filter(#[from] FilterError),
+  #[error("corrupt patch at line {line}: {reason}")]
+  Malformed { line: usize, reason: &'static str },
+  #[error("no valid patches in input")]
+  NoPatches,
+  #[error("refusing to apply a patch to the invalid path {0:?}")]
+  InvalidPath(BString),
+  #[error("{0:?} does not exist")]
+  DoesNotExist(BString),
+  #[error("{0:?} already exists")]
+  AlreadyExists(BString),
+  #[error("{0:?} is not the kind of file the patch expects")]
+  WrongType(BString),
+  #[error("patch failed: {path}:{line}")]
+  DoesNotApply { path: BString, line: usize },
+  #[error("the patch deleting {0:?} leaves some of its contents")]
+  LeavesContents(BString),
+  #[error("cannot apply the binary patch to {path:?}: {reason}")]
+  Binary { path: BString, reason: &'static str },
+  #[error("the patch for the submodule {0:?} doesn't leave it at a commit")]
+  InvalidSubmodule(BString),
+  #[error("{0:?} in the working tree does not match the index")]
+  DoesNotMatchIndex(BString),
+  #[error("{0:?} is beyond a symbolic link")]
+  BeyondSymlink(BString),
+  #[error("a bare repository has no working tree to apply a patch to")]
+  BareRepository,
+}
+
+#[test]
+fn parse() {
+  use crate::LineKind;
+  let patch = Patch::parse(concat!(
+    "From 1234 Mon Sep 17 00:00:00 2001\n",
+    "Subject: [PATCH] Change things\n",
+    "\n",
+    "---\n",
+    "diff --git a/a b/a\n",
+    "old mode 100644\n",
+    "new mode 100755\n",
+    "index 4cb29ea38f70d7c61b2a3a25b02e3bdf44905402..f04eb265ebd74fba2cddf0a6adf2a6a7f81c87aa\n",
+    "--- a/a\n",
+    "+++ b/a\n",
+    "@@ -1,3 +1,3 @@\n",
+    " one\n",
+    "-two\n",
+    "+2\n",
+    " three\n",
+    "diff --git a/b b/b\n",
+    "index 87ae6b695deceaf160611414f7dcd5c7366b2e79..e26e1337961738144d8f55d5370a65940a04d583 100644\n",
+    "GIT binary patch\n",
+    "literal 7\n",
+    "OcmYew%wup2iUa@&5dtOv\n",
+    "\n",
+    "literal 7\n",
+    "OcmYew%wtF_sssQD(E^45\n",
+    "\n",
+    "diff --git a/created b/created\n",
+    "new file mode 100644\n",
+    "index 0000000000000000000000000000000000000000..3e757656cf36eca53338e520d134963a44f793f8\n",
+    "--- /dev/null\n",
+    "+++ b/created\n",
+    "@@ -0,0 +1 @@\n",
+    "+new\n",
+    "diff --git \"a/tab\\tname\" b/moved\n",
+    "similarity index 100%\n",
+    "rename from \"tab\\tname\"\n",
+    "rename to moved\n",
+    "diff --git a/old b/old\n",
+    "deleted file mode 100644\n",
+    "index 286c5f5776916d7d7d5849988ca9d83e722cf9c2..0000000000000000000000000000000000000000\n",
+    "--- a/old\n",
+    "+++ /dev/null\n",
+    "@@ -1 +0,0 @@\n",
+    "-gone\n",
+    "-- \n",
+    "2.40.0\n",
+  ))
+  .unwrap();
+  let paths: Vec<_> = patch
+    .files
+    .iter()
+    .map(|file| (file.old_path.clone(), file.new_path.clone()))
+    .collect();
+  let path = |path: &str| Some(BString::from(path));
+  assert_eq!(
+    vec![
+      (path("a"), path("a")),
+      (path("b"), path("b")),
+      (None, path("created")),
+      (path("tab\tname"), path("moved")),
+      (path("old"), None),
+    ],
+    paths
+  );
+
+  let a = &patch.files[0];
+  assert_eq!(Some(FileMode::NonExecutableFile), a.old_mode);
+  assert_eq!(Some(FileMode::ExecutableFile), a.new_mode);
+  assert_eq!(
+    Some("4cb29ea38f70d7c61b2a3a25b02e3bdf44905402"),
+    a.old_id.as_deref()
+  );
+  assert_eq!(1, a.hunks.len());
+  let kinds: Vec<_> = a.hunks[0].lines.iter().map(|line| line.kind).collect();
+  assert_eq!(
+    vec![
+      LineKind::Context,
+      LineKind::Removed,
+      LineKind::Added,
+      LineKind::Context
+    ],
+    kinds
+  );
+
+  let binary = patch.files[1].binary.as_ref().unwrap();
+  assert_eq!(
+    Some(&BinaryData::Literal(b"bin\0ARY".to_vec())),
+    binary.forward.as_ref()
+  );
+  assert_eq!(
+    Some(&BinaryData::Literal(b"bin\0ary".to_vec())),
+    binary.reverse.as_ref()
+  );
+  assert_eq!(Some(FileMode::NonExecutableFile), patch.files[1].old_mode);
+  assert_eq!(Some(FileMode::NonExecutableFile), patch.files[2].new_mode);
+  assert!(!patch.files[3].copy && patch.files[3].hunks.is_empty());
+  assert_eq!(Some(FileMode::NonExecutableFile), patch.files[4].old_mode);
+
+  // Reversing swaps the sides
+  let reversed = patch.reverse();
+  assert_eq!(path("moved"), reversed.files[1].old_path);
+  assert_eq!(None, reversed.files[0].old_path);
+  assert_eq!(Some(FileMode::ExecutableFile), reversed.files[4].old_mode);
+  assert_eq!(LineKind::Added, reversed.files[0].hunks[0].lines[0].kind);
+  assert_eq!(patch, reversed.reverse());
+
+  // A traditional diff with a bare path
+  let patch = Patch::parse("--- README.orig\n+++ README\n@@ -1 +1 @@\n-a\n+b\n").unwrap();
+  assert_eq!(path("README"), patch.files[0].new_path);
+
+  assert!(matches!(
+    Patch::parse("nothing to see here\n"),
+    Err(ApplyError::NoPatches)
+  ));
+  assert!(matches!(
+    Patch::parse("diff --git a/a b/a\n--- a/a\n+++ b/a\n@@ -1,2 +1,2 @@\n-a\n+b\n"),
+    Err(ApplyError::Malformed { line: 7, .. })
+  ));
+}
+
+#[test]
+fn apply_trees() {
+  use crate::{diff::write_tree, diff_trees, format_patch, PatchOptions};
+  use FileMode::*;
+  let tmp_dir = tempdir::TempDir::new("apply_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let options = ApplyOptions::default();
+  let old = write_tree(
+    &odb,
+    &[
+      ("README", NonExecutableFile, "one\ntwo\nthree\n"),
+      ("build.sh", NonExecutableFile, "make\n"),
+      ("link", SymbolicLink, "README"),
+      ("src/lib.rs", NonExecutableFile, "lib\n"),
+      ("src/main.rs", NonExecutableFile, "fn main() {}"),
+    ],
+  );
+  let new = write_tree(
+    &odb,
+    &[
+      ("README", NonExecutableFile, "one\n2\nthree\n"),
+      ("build.sh", ExecutableFile, "make\n"),
+      ("link", NonExecutableFile, "README"),
+      ("src", NonExecutableFile, "not a directory anymore\n"),
+    ],
+  );
+  let changes = diff_trees(&odb, Some(&old), Some(&new)).unwrap();
+  let patch = format_patch(
+    &odb,
+    &changes,
+    &PatchOptions {
+      abbrev: Some(40),
+      ..PatchOptions::default()
+    },
+  )
+  .unwrap();
+  let patch = Patch::parse(patch).unwrap();
+  assert_eq!(new, apply_to_tree(&odb, &old, &patch, &options).unwrap());
+  assert_eq!(
+    old,
+    apply_to_tree(&odb, &new, &patch.reverse(), &options).unwrap()
+  );
+  assert!(matches!(
+    apply_to_tree(&odb, &new, &patch, &options),
+    Err(ApplyError::DoesNotApply { .. } | ApplyError::AlreadyExists(_))
+  ));
+
+  // A binary delta made by git
+  let lines = |changed: usize| -> Vec<u8> {
+    (0..40)
+      .flat_map(|i| format!("line {}\0\n", if i == 20 { changed } else { i }).into_bytes())
+      .collect()
+  };
+  let tree = write_tree(
+    &odb,
+    &[("big", NonExecutableFile, lines(20).to_str().unwrap())],
+  );
+  let patch = Patch::parse(concat!(
+    "diff --git a/big b/big\n",
+    "index 2cb381854469e6ff5473ce50e21c837046c3e022..273dea65a66e41bd78b9508f987c8a8d118cff49 100644\n",
+    "GIT binary patch\n",
+    "delta 12\n",
+    "Ucmcb|bdPDmdL~QDi5u4f03u)oV*mgE\n",
+    "\n",
+    "delta 12\n",
+    "Ucmcb|bdPDmdL|=-i5u4f03sO$Qvd(}\n",
+    "\n",
+  ))
+  .unwrap();
+  let patched = apply_to_tree(&odb, &tree, &patch, &options).unwrap();
+  let index = Index::from_tree(&odb, &patched).unwrap();
+  let blob = odb.read_blob(&index.get("big").unwrap().oid).unwrap();
+  assert_eq!(lines(99), blob.contents().as_bytes());
+  assert_eq!(
+    tree,
+    apply_to_tree(&odb, &patched, &patch.reverse(), &options).unwrap()
+  );
+  assert!(matches!(
+    apply_to_tree(&odb, &patched, &patch, &options),
+    Err(ApplyError::Binary { .. })
+  ));
+}
+
+#[test]
+fn apply_offsets() {
+  use crate::diff::write_tree;
+  let tmp_dir = tempdir::TempDir::new("apply_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let apply = |contents: &str, patch: &str, options: &ApplyOptions| {
+    let tree = write_tree(&odb, &[("f", FileMode::NonExecutableFile, contents)]);
+    let tree = apply_to_tree(&odb, &tree, &Patch::parse(patch).unwrap(), options)?;
+    let index = Index::from_tree(&odb, &tree).unwrap();
+    let blob = odb.read_blob(&index.get("f").unwrap().oid).unwrap();
+    Ok::<_, ApplyError>(blob.contents().to_string())
+  };
+  let options = ApplyOptions::default();
+  let patch = "--- a/f\n+++ b/f\n@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n";
+  assert_eq!(
+    "a\nb\nC\nd\ne\n",
+    apply("a\nb\nc\nd\ne\n", patch, &options).unwrap()
+  );
+  // Found further down the file
+  assert_eq!(
+    "x\nx\nx\na\nb\nC\nd\n",
+    apply("x\nx\nx\na\nb\nc\nd\n", patch, &options).unwrap()
+  );
+  // The closest match wins
+  assert_eq!(
+    "b\nC\nd\nb\nc\nd\n",
+    apply("b\nc\nd\nb\nc\nd\n", patch, &options).unwrap()
+  );
+
+  // Context that doesn't match needs a smaller minimum
+  let changed = "a\nB\nc\nd\ne\n";
+  assert!(matches!(
+    apply(changed, patch, &options),
+    Err(ApplyError::DoesNotApply { line: 2, .. })
+  ));
+  let fuzzy = ApplyOptions {
+    min_context: Some(0),
+    ..ApplyOptions::default()
+  };
+  assert_eq!("a\nB\nC\nd\ne\n", apply(changed, patch, &fuzzy).unwrap());
+
+  // Hunks at the start of a file only match there
+  let patch = "--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n-a\n+A\n b\n";
+  assert!(apply("x\na\nb\n", patch, &options).is_err());
+  assert_eq!("A\nb\nx\n", apply("a\nb\nx\n", patch, &options).unwrap());
+
+  // And ones without trailing context only at the end, unless the patch
+  // was made without any
+  let patch = "--- a/f\n+++ b/f\n@@ -2 +2 @@\n-b\n+B\n";
+  assert!(apply("a\nb\nc\n", patch, &options).is_err());
+  let zero = ApplyOptions {
+    unidiff_zero: true,
+    ..ApplyOptions::default()
+  };
+  assert_eq!("a\nB\nc\n", apply("a\nb\nc\n", patch, &zero).unwrap());
+
+  // A missing newline at the end of the file
+  let patch = "--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+b\n";
+  assert_eq!("a\nb\n", apply("a\nb", patch, &options).unwrap());
+}
+
+#[test]
+fn apply_work_dir() {
+  let tmp_dir = tempdir::TempDir::new("apply_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let tree = crate::diff::write_tree(
+    repo.odb(),
+    &[
+      ("a", FileMode::NonExecutableFile, "one\ntwo\n"),
+      ("gone", FileMode::NonExecutableFile, "bye\n"),
+    ],
+  );
+  repo.checkout_tree(&tree).unwrap();
+  let work_dir = tmp_dir.path();
+
+  let patch = Patch::parse(concat!(
+    "diff --git a/a b/dir/a\n",
+    "similarity index 50%\n",
+    "rename from a\n",
+    "rename to dir/a\n",
+    "--- a/a\n",
+    "+++ b/dir/a\n",
+    "@@ -1,2 +1,2 @@\n",
+    " one\n",
+    "-two\n",
+    "+2\n",
+    "diff --git a/gone b/gone\n",
+    "deleted file mode 100644\n",
+    "--- a/gone\n",
+    "+++ /dev/null\n",
+    "@@ -1 +0,0 @@\n",
+    "-bye\n",
+  ))
+  .unwrap();
+  repo.apply(&patch, ApplyLocation::WorkDir).unwrap();
+  assert_eq!(
+    "one\n2\n",
+    fs::read_to_string(work_dir.join("dir/a")).unwrap()
+  );
+  assert!(!work_dir.join("a").exists() && !work_dir.join("gone").exists());
+  // The index wasn't touched
+  assert!(repo.index().unwrap().get("a").is_some());
+
+  // The deleted file is still in the index
+  assert!(matches!(
+    repo.apply(&patch.reverse(), ApplyLocation::Both),
+    Err(ApplyError::AlreadyExists(path)) if path == "gone"
+  ));
+  repo
+    .apply(&patch.reverse(), ApplyLocation::WorkDir)
+    .unwrap();
+  assert!(!work_dir.join("dir").exists());
+  assert_eq!("bye\n", fs::read_to_string(work_dir.join("gone")).unwrap());
+
+  repo.apply(&patch, ApplyLocation::Both).unwrap();
+  let index = repo.index().unwrap();
+  let paths: Vec<_> = index
+    .entries()
+    .iter()
+    .map(|entry| entry.path.clone())
+    .collect();
+  assert_eq!(vec![BString::from("dir/a")], paths);
+  assert!(work_dir.join("dir/a").exists());
+
+  fs::write(work_dir.join("dir/a"), "changed\n").unwrap();
+  assert!(matches!(
+    repo.apply(&patch.reverse(), ApplyLocation::Both),
+    Err(ApplyError::DoesNotMatchIndex(_))
+  ));
+  repo.apply(&patch.reverse(), ApplyLocation::Index).unwrap();
+  assert!(repo.index().unwrap().get("a").is_some());
+  assert_eq!(
+    "changed\n",
+    fs::read_to_string(work_dir.join("dir/a")).unwrap()
+  );
+}
@@ -0,0 +1,864 @@
+//! Exporting a [`Tree`][crate::Tree] as a tar or zip archive the same way
+//! `git archive` does. Files go through the same conversions as when
+//! they're checked out, and the `.gitattributes` files of the tree decide
+//! what's left out with `export-ignore` and which files have their
+//! `$Format:...$` placeholders expanded with `export-subst`.
+//!
+//! Archiving a commit, or a tag pointing at one, gives every entry the
+//! commit time and records the commit in the archive: in a pax global
+//! header for tar and as the archive comment for zip. Tar archives are the
+//! same bytes git writes.
+
+use crate::{
+  peel_tag, zlib, AttributesError, CheckoutOptions, Commit, Config, ConfigError, FileMode,
+  FilterError, ObjectKind, Odb, OdbError, Repository, Time, OID,
+};
+use bstr::{BString, ByteSlice};
+use std::{borrow::Cow, convert::TryFrom, io, io::Write, str::FromStr};
+use thiserror::Error;
+
+/// The size of a tar header, and what entries are padded to
+const TAR_BLOCK: usize = 512;
+/// Tar archives are written in records of 20 blocks like git does
+const TAR_RECORD: usize = TAR_BLOCK * 20;
+/// The largest file size the size field of a tar header holds
+const USTAR_MAX_SIZE: u64 = 0o77_777_777_777;
+
+/// What kind of archive [`Repository::archive`] writes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ArchiveFormat {
+  /// A POSIX tar archive with pax headers for long names
+  #[default]
+  Tar,
+  /// A zip archive with deflated files
+  Zip,
+}
+
+impl FromStr for ArchiveFormat {
+  type Err = ArchiveError;
+
+  fn from_str(format: &str) -> Result<Self, Self::Err> {
+    match format {
+      "tar" => Ok(Self::Tar),
+      "zip" => Ok(Self::Zip),
+      _ => Err(ArchiveError::UnknownFormat(format.into())),
+    }
+  }
+}
+
+/// Options controlling how [`Repository::archive_with_options`] writes an
+/// archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveOptions {
+  /// Prepended to every path, like `--prefix`. Ending it with a `/` puts
+  /// everything in a directory of that name, which gets an entry of its
+  /// own.
+  pub prefix: BString,
+  /// The modification time of every entry in seconds since the epoch.
+  /// `None` uses the commit time, or the current time for a tree.
+  pub mtime: Option<i64>,
+  /// The permission bits taken away from files and directories in tar
+  /// archives, as set by `tar.umask`
+  pub umask: u32,
+}
+
+impl Default for ArchiveOptions {
+  fn default() -> Self {
+    Self {
+      prefix: BString::from(""),
+      mtime: None,
+      umask: 0o002,
+    }
+  }
+}
+
+impl ArchiveOptions {
+  /// Create [`ArchiveOptions`] from `tar.umask` in a [`Config`]
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let mut options = Self::default();
+    if let Some(umask) = config.get_str("tar.umask")? {
+      options.umask = u32::from_str_radix(umask, 8).map_err(|_| ConfigError::InvalidValue {
+        key: "tar.umask".into(),
+        value: umask.into(),
+        expected: "an octal number",
+      })?;
+    }
+    Ok(options)
+  }
+}
+
+/// Where the entries of an archive go, in the order they're in the tree
+trait ArchiveWriter {
+  /// Write one entry. Directories and submodules have a `path` ending with
+  /// `/` and no `data`, and the `data` of a symbolic link is its target.
+  fn entry(
+    &mut self,
+    path: &[u8],
+    mode: FileMode,
+    oid: &OID,
+    data: &[u8],
+  ) -> Result<(), ArchiveError>;
+
+  /// Write whatever comes after the last entry
+  fn finish(&mut self) -> Result<(), ArchiveError>;
+}
+
+/// Writes tar archives byte for byte the same as `git archive`
+struct TarWriter<'a> {
+  out: &'a mut dyn Write,
+  written: usize,
+  mtime: i64,
+  umask: u32,
+}
+
+impl TarWriter<'_> {
+  fn write(&mut self, bytes: &[u8]) -> Result<(), ArchiveError> {
+    self.out.write_all(bytes)?;
+    self.written += bytes.len();
+    Ok(())
+  }
+
+  /// Write `data` padded with zeroes to a whole number of blocks
+  fn write_padded(&mut self, data: &[u8]) -> Result<(), ArchiveError> {
+    self.write(data)?;
+    let padding = (TAR_BLOCK - data.len() % TAR_BLOCK) % TAR_BLOCK;
+    self.write(&[0; TAR_BLOCK][..padding])
+  }
+
+  fn header(
+    &mut self,
+    name: &[u8],
+    prefix: &[u8],
+    typeflag: u8,
+    mode: u32,
+    size: u64,
+    linkname: &[u8],
+  ) -> Result<(), ArchiveError> {
+    fn octal(field: &mut [u8], value: u64) {
+      let digits = format!("{:0width$o}", value, width = field.len() - 1);
+      field[..digits.len()].copy_from_slice(digits.as_bytes());
+      field[digits.len()..].fill(0);
+    }
+    let mut header = [0; TAR_BLOCK];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], u64::from(mode & 0o7777));
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], self.mtime.max(0) as u64);
+    header[156] = typeflag;
+    header[157..157 + linkname.len()].copy_from_slice(linkname);
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[265..269].copy_from_slice(b"root");
+    header[297..301].copy_from_slice(b"root");
+    octal(&mut header[329..337], 0);
+    octal(&mut header[337..345], 0);
+    header[345..345 + prefix.len()].copy_from_slice(prefix);
+    // The checksum is taken with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+    octal(&mut header[148..156], checksum);
+    self.write(&header)
+  }
+
+  /// Write a pax header holding `records`
+  fn pax_header(&mut self, name: &[u8], typeflag: u8, records: &[u8]) -> Result<(), ArchiveError> {
+    self.header(name, b"", typeflag, 0o100666, records.len() as u64, b"")?;
+    self.write_padded(records)
+  }
+
+  /// Write the pax global header recording the commit that was archived
+  fn start(&mut self, commit: &OID) -> Result<(), ArchiveError> {
+    let mut records = Vec::new();
+    pax_record(&mut records, "comment", commit.as_hex().as_bytes());
+    self.pax_header(b"pax_global_header", b'g', &records)
+  }
+}
+
+/// Add a `{len} {key}={value}\n` record to a pax header, where `len`
+/// counts the whole record including itself
+fn pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+  let mut len = key.len() + value.len() + 4;
+  let mut digits = 1;
+  while len / 10 >= digits {
+    len += 1;
+    digits *= 10;
+  }
+  records.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+  records.extend_from_slice(value);
+  records.push(b'\n');
+}
+
+/// Where to split `path` into the prefix and name fields of a tar header:
+/// the last `/` in the first `max` bytes, not counting one at the end
+fn tar_split(path: &[u8], max: usize) -> usize {
+  let mut idx = path.len();
+  if idx > 1 && path[idx - 1] == b'/' {
+    idx -= 1;
+  }
+  idx = idx.min(max);
+  loop {
+    idx -= 1;
+    if idx == 0 || path[idx] == b'/' {
+      return idx;
+    }
+  }
+}
+
+impl ArchiveWriter for TarWriter<'_> {
+  fn entry(
+    &mut self,
+    path: &[u8],
+    mode: FileMode,
+    oid: &OID,
+    data: &[u8],
+  ) -> Result<(), ArchiveError> {
+    let (typeflag, mode) = match mode {
+      FileMode::Tree | FileMode::GitLink => (b'5', 0o40777 & !self.umask),
+      FileMode::SymbolicLink => (b'2', 0o120777),
+      FileMode::ExecutableFile => (b'0', 0o100777 & !self.umask),
+      FileMode::NonExecutableFile => (b'0', 0o100666 & !self.umask),
+    };
+    let mut records = Vec::new();
+    let (mut name, mut prefix) = (path.to_vec(), &b""[..]);
+    if path.len() > 100 {
+      let split = tar_split(path, 155);
+      if split > 0 && path.len() - split - 1 <= 100 {
+        prefix = &path[..split];
+        name = path[split + 1..].to_vec();
+      } else {
+        name = format!("{}.data", oid).into_bytes();
+        pax_record(&mut records, "path", path);
+      }
+    }
+    let see;
+    let linkname = match typeflag {
+      b'2' if data.len() > 100 => {
+        pax_record(&mut records, "linkpath", data);
+        see = format!("see {}.paxheader", oid);
+        see.as_bytes()
+      }
+      b'2' => data,
+      _ => b"",
+    };
+    let mut size = match typeflag {
+      b'0' => data.len() as u64,
+      _ => 0,
+    };
+    if size > USTAR_MAX_SIZE {
+      pax_record(&mut records, "size", size.to_string().as_bytes());
+      size = 0;
+    }
+    if !records.is_empty() {
+      self.pax_header(format!("{}.paxheader", oid).as_bytes(), b'x', &records)?;
+    }
+    self.header(&name, prefix, typeflag, mode, size, linkname)?;
+    match typeflag {
+      b'0' => self.write_padded(data),
+      _ => Ok(()),
+    }
+  }
+
+  fn finish(&mut self) -> Result<(), ArchiveError> {
+    // Two empty blocks end the archive, and it's padded to a whole record
+    let tail = TAR_RECORD - self.written % TAR_RECORD;
+    self.write(&vec![0; tail])?;
+    if tail < 2 * TAR_BLOCK {
+      self.write(&[0; TAR_RECORD])?;
+    }
+    Ok(())
+  }
+}
+
+/// Writes zip archives, with the central directory kept in memory until
+/// the end
+struct ZipWriter<'a> {
+  out: &'a mut dyn Write,
+  written: u64,
+  central: Vec<u8>,
+  entries: usize,
+  mtime: i64,
+  comment: Vec<u8>,
+}
+
+impl ZipWriter<'_> {
+  /// The time and date of the entries in MS-DOS format, in UTC. The exact
+  /// time is in the extended timestamp every entry has.
+  fn dos_time(&self) -> (u16, u16) {
+    let (year, month, day, hour, minute, second, _) = broken_down(self.mtime);
+    let time = (hour << 11) | (minute << 5) | (second / 2);
+    let date = ((year.clamp(1980, 2107) - 1980) as u32) << 9 | month << 5 | day;
+    (time as u16, date as u16)
+  }
+}
+
+impl ArchiveWriter for ZipWriter<'_> {
+  fn entry(
+    &mut self,
+    path: &[u8],
+    mode: FileMode,
+    _: &OID,
+    data: &[u8],
+  ) -> Result<(), ArchiveError> {
+    let too_large = || ArchiveError::TooLarge(path.into());
+    // Only files that are executable or links need the Unix attributes,
+    // the rest are left to what unzip does by default
+    let (creator, attributes): (u16, u32) = match mode {
+      FileMode::Tree | FileMode::GitLink => (0x0014, 0x10),
+      FileMode::SymbolicLink => (0x0317, 0o120777 << 16),
+      FileMode::ExecutableFile => (0x0317, 0o100755 << 16),
+      FileMode::NonExecutableFile => (0x0014, 0),
+    };
+    let deflated = match mode {
+      FileMode::NonExecutableFile | FileMode::ExecutableFile if !data.is_empty() => {
+        Some(zlib::deflate(data)).filter(|deflated| deflated.len() < data.len())
+      }
+      _ => None,
+    };
+    let (method, version, stored): (u16, u16, &[u8]) = match &deflated {
+      Some(deflated) => (8, 20, deflated),
+      None => (0, 10, data),
+    };
+    let flags: u16 = match path.is_ascii() || path.to_str().is_err() {
+      true => 0,
+      false => 0x0800,
+    };
+    let (time, date) = self.dos_time();
+    let crc = zlib::crc32(data);
+    let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+    let stored_size = u32::try_from(stored.len()).map_err(|_| too_large())?;
+    let offset = u32::try_from(self.written).map_err(|_| too_large())?;
+    let name_len = u16::try_from(path.len()).map_err(|_| too_large())?;
+    // The extended timestamp with the modification time
+    let mut extra = vec![0x55, 0x54, 5, 0, 1];
+    extra.extend_from_slice(&(self.mtime.clamp(0, i64::from(u32::MAX)) as u32).to_le_bytes());
+
+    let mut common = Vec::new();
+    common.extend_from_slice(&version.to_le_bytes());
+    common.extend_from_slice(&flags.to_le_bytes());
+    common.extend_from_slice(&method.to_le_bytes());
+    common.extend_from_slice(&time.to_le_bytes());
+    common.extend_from_slice(&date.to_le_bytes());
+    common.extend_from_slice(&crc.to_le_bytes());
+    common.extend_from_slice(&stored_size.to_le_bytes());
+    common.extend_from_slice(&size.to_le_bytes());
+    common.extend_from_slice(&name_len.to_le_bytes());
+    common.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+
+    let local = [
+      &0x0403_4b50u32.to_le_bytes()[..],
+      &common,
+      path,
+      &extra,
+      stored,
+    ]
+    .concat();
+    self.out.write_all(&local)?;
+    self.written += local.len() as u64;
+
+    self
+      .central
+      .extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+    self.central.extend_from_slice(&creator.to_le_bytes());
+    self.central.extend_from_slice(&common);
+    // No comment, on the first disk, and no internal attributes
+    self.central.extend_from_slice(&[0; 6]);
+    self.central.extend_from_slice(&attributes.to_le_bytes());
+    self.central.extend_from_slice(&offset.to_le_bytes());
+    self.central.extend_from_slice(path);
+    self.central.extend_from_slice(&extra);
+    self.entries += 1;
+    Ok(())
+  }
+
+  fn finish(&mut self) -> Result<(), ArchiveError> {
+    let entries = u16::try_from(self.entries).map_err(|_| ArchiveError::TooManyEntries)?;
+    let offset = u32::try_from(self.written).map_err(|_| ArchiveError::TooManyEntries)?;
+    self.out.write_all(&self.central)?;
+    let mut end = Vec::new();
+    end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    end.extend_from_slice(&[0; 4]);
+    end.extend_from_slice(&entries.to_le_bytes());
+    end.extend_from_slice(&entries.to_le_bytes());
+    end.extend_from_slice(&(self.central.len() as u32).to_le_bytes());
+    end.extend_from_slice(&offset.to_le_bytes());
+    end.extend_from_slice(&(self.comment.len() as u16).to_le_bytes());
+    end.extend_from_slice(&self.comment);
+    self.out.write_all(&end)?;
+    Ok(())
+  }
+}
+
+/// What's needed to write the entries of a tree
+struct Context<'a> {
+  odb: &'a Odb,
+  checkout: CheckoutOptions,
+  prefix: &'a [u8],
+  commit: Option<(OID, Commit)>,
+}
+
+impl Context<'_> {
+  fn add_tree(
+    &self,
+    tree: &OID,
+    dir: &[u8],
+    archive: &mut dyn ArchiveWriter,
+  ) -> Result<(), ArchiveError> {
+    let attributes = &self.checkout.line_endings.attributes;
+    for entry in self.odb.read_tree(tree)?.entries() {
+      let path = match dir.is_empty() {
+        true => entry.name().to_vec(),
+        false => [dir, b"/", entry.name().as_bytes()].concat(),
+      };
+      if attributes.get(&path, "export-ignore").is_set() {
+        continue;
+      }
+      let full = [self.prefix, &path].concat();
+      match entry.mode() {
+        FileMode::Tree | FileMode::GitLink => {
+          archive.entry(&[&full, &b"/"[..]].concat(), entry.mode(), entry.oid(), b"")?;
+          if entry.mode().is_tree() {
+            self.add_tree(entry.oid(), &path, archive)?;
+          }
+        }
+        FileMode::SymbolicLink => {
+          let blob = self.odb.read_blob(entry.oid())?;
+          archive.entry(&full, entry.mode(), entry.oid(), blob.contents())?;
+        }
+        mode => {
+          let blob = self.odb.read_blob(entry.oid())?;
+          let mut data = self.checkout.to_worktree(&path, blob.contents())?;
+          if let Some((oid, commit)) = &self.commit {
+            if attributes.get(&path, "export-subst").is_set() {
+              data = Cow::Owned(expand_placeholders(self.odb, oid, commit, &data)?);
+            }
+          }
+          archive.entry(&full, mode, entry.oid(), &data)?;
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Replace every `$Format:...$` in `data` with the commit formatted the way
+/// `git log --format=...` would for the placeholders below, leaving others
+/// as they are:
+///
+/// - `%H` and `%h`: the commit, in full and abbreviated
+/// - `%T` and `%t`: the tree, in full and abbreviated
+/// - `%P` and `%p`: the parents, in full and abbreviated
+/// - `%an`, `%ae`, `%ad`, `%aD`, `%at`, `%ai`, and `%aI`: the author's
+///   name, email, and date in the default, RFC 2822, Unix, ISO 8601 like,
+///   and strict ISO 8601 formats, with `%c...` the same for the committer
+/// - `%s`, `%b`, and `%B`: the subject, the body, and the whole message
+/// - `%n` and `%%`: a newline and a `%`
+fn expand_placeholders(
+  odb: &Odb,
+  oid: &OID,
+  commit: &Commit,
+  data: &[u8],
+) -> Result<Vec<u8>, OdbError> {
+  let mut expanded = Vec::new();
+  let mut rest = data;
+  while let Some(start) = rest.find(b"$Format:") {
+    let end = match rest[start + 8..].find_byte(b'$') {
+      Some(end) => start + 8 + end,
+      None => break,
+    };
+    expanded.extend_from_slice(&rest[..start]);
+    expanded.extend_from_slice(&format_commit(odb, oid, commit, &rest[start + 8..end])?);
+    rest = &rest[end + 1..];
+  }
+  expanded.extend_from_slice(rest);
+  Ok(expanded)
+}
+
+fn format_commit(
+  odb: &Odb,
+  oid: &OID,
+  commit: &Commit,
+  format: &[u8],
+) -> Result<Vec<u8>, OdbError> {
+  let message = commit.message_lossy();
+  let (subject, body) = match message.find("\n\n") {
+    Some(idx) => (&message[..idx], message[idx + 2..].trim_start_matches('\n')),
+    None => (message.trim_end_matches('\n'), ""),
+  };
+  let join = |oids: &mut dyn Iterator<Item = Result<String, OdbError>>| {
+    oids
+      .collect::<Result<Vec<_>, _>>()
+      .map(|oids| oids.join(" "))
+  };
+
+  let mut formatted = Vec::new();
+  let mut idx = 0;
+  while idx < format.len() {
+    if format[idx] != b'%' || idx + 1 == format.len() {
+      formatted.push(format[idx]);
+      idx += 1;
+      continue;
+    }
+    let mut used = 2;
+    let value = match format[idx + 1] {
+      b'H' => oid.as_hex(),
+      b'h' => odb.abbreviate(oid, None)?,
+      b'T' => commit.tree().as_hex(),
+      b't' => odb.abbreviate(commit.tree(), None)?,
+      b'P' => join(&mut commit.parents().iter().map(|oid| Ok(oid.as_hex())))?,
+      b'p' => join(&mut commit.parents().iter().map(|oid| odb.abbreviate(oid, None)))?,
+      b's' => subject.lines().map(str::trim).collect::<Vec<_>>().join(" "),
+      b'b' => body.into(),
+      b'B' => message.to_string(),
+      b'n' => "\n".into(),
+      b'%' => "%".into(),
+      who @ (b'a' | b'c') if idx + 2 < format.len() => {
+        let signature = match who {
+          b'a' => commit.author(),
+          _ => commit.committer(),
+        };
+        used = 3;
+        match format[idx + 2] {
+          b'n' => signature.name.to_string(),
+          b'e' => signature.email.to_string(),
+          style @ (b'd' | b'D' | b't' | b'i' | b'I') => format_date(&signature.time, style),
+          _ => {
+            used = 0;
+            String::new()
+          }
+        }
+      }
+      _ => {
+        used = 0;
+        String::new()
+      }
+    };
+    match used {
+      // Unknown placeholders are left as they are
+      0 => {
+        formatted.push(b'%');
+        idx += 1;
+      }
+      used => {
+        formatted.extend_from_slice(value.as_bytes());
+        idx += used;
+      }
+    }
+  }
+  Ok(formatted)
+}
+
+/// The local time `seconds` since the epoch is as the year, month, day,
+/// hour, minute, second, and day of the week with Sunday as 0
+fn broken_down(seconds: i64) -> (i64, u32, u32, u32, u32, u32, u32) {
+  let (days, secs) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+  // Counting from March 1st of year 0 puts leap days at the end of years
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let day_of_era = z.rem_euclid(146_097);
+  let year_of_era =
+    (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let mp = (5 * day_of_year + 2) / 153;
+  let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  let year = year_of_era + era * 400 + i64::from(month <= 2);
+  let weekday = (days + 4).rem_euclid(7) as u32;
+  let secs = secs as u32;
+  (
+    year,
+    month,
+    day,
+    secs / 3600,
+    secs / 60 % 60,
+    secs % 60,
+    weekday,
+  )
+}
+
+/// Format `time` in its own timezone the way git's `%ad` (`d`), `%aD`
+/// (`D`), `%at` (`t`), `%ai` (`i`), and `%aI` (`I`) do
+fn format_date(time: &Time, style: u8) -> String {
+  const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+  const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+  ];
+  let (y, mo, d, h, mi, s, wday) = broken_down(time.seconds + i64::from(time.offset) * 60);
+  let sign = if time.offset < 0 { '-' } else { '+' };
+  let (oh, om) = (time.offset.abs() / 60, time.offset.abs() % 60);
+  let (wday, month) = (DAYS[wday as usize], MONTHS[mo as usize - 1]);
+  match style {
+    b't' => time.seconds.to_string(),
+    b'i' => format!(
+      "{}-{:02}-{:02} {:02}:{:02}:{:02} {}{:02}{:02}",
+      y, mo, d, h, mi, s, sign, oh, om
+    ),
+    b'I' => format!(
+      "{}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02}",
+      y, mo, d, h, mi, s, sign, oh, om
+    ),
+    b'D' => format!(
+      "{}, {} {} {} {:02}:{:02}:{:02} {}{:02}{:02}",
+      wday, d, month, y, h, mi, s, sign, oh, om
+    ),
+    _ => format!(
+      "{} {} {} {:02}:{:02}:{:02} {} {}{:02}{:02}",
+      wday, month, d, h, mi, s, y, sign, oh, om
+    ),
+  }
+}
+
+impl Repository {
+  /// Write the [`Tree`][crate::Tree] with the given [`OID`] to `writer` as
+  /// an archive in `format`, like `git archive`, using the
+  /// [`ArchiveOptions`] from the [`Config`] of the repository. A commit or
+  /// a tag can be given to archive its tree, which records the commit in
+  /// the archive and uses its time for every entry.
+  pub fn archive(
+    &self,
+    tree: &OID,
+    format: ArchiveFormat,
+    writer: impl Write,
+  ) -> Result<(), ArchiveError> {
+    let options = ArchiveOptions::from_config(self.config())?;
+    self.archive_with_options(tree, format, &options, writer)
+  }
+
+  /// Write an archive the way [`Repository::archive`] does using the given
+  /// [`ArchiveOptions`]
+  pub fn archive_with_options(
+    &self,
+    tree: &OID,
+    format: ArchiveFormat,
+    options: &ArchiveOptions,
+    mut writer: impl Write,
+  ) -> Result<(), ArchiveError> {
+    let (oid, kind) = peel_tag(self.odb(), tree)?;
+    let (root, commit) = match kind {
+      ObjectKind::Commit => {
+        let commit = self.odb().read_commit(&oid)?;
+        (*commit.tree(), Some((oid, commit)))
+      }
+      ObjectKind::Tree => (oid, None),
+      _ => return Err(ArchiveError::NotATree(*tree)),
+    };
+    let mtime = options
+      .mtime
+      .or_else(|| {
+        commit
+          .as_ref()
+          .map(|(_, commit)| commit.committer().time.seconds)
+      })
+      .unwrap_or_else(|| Time::now().seconds);
+    let mut checkout = CheckoutOptions::from_config(self.config())?;
+    checkout.set_attributes(self.tree_attributes(&root)?, self.work_dir());
+    let context = Context {
+      odb: self.odb(),
+      checkout,
+      prefix: &options.prefix,
+      commit,
+    };
+    let commit = context.commit.as_ref().map(|(oid, _)| *oid);
+
+    let mut archive: Box<dyn ArchiveWriter + '_> = match format {
+      ArchiveFormat::Tar => {
+        let mut tar = TarWriter {
+          out: &mut writer,
+          written: 0,
+          mtime,
+          umask: options.umask,
+        };
+        if let Some(commit) = &commit {
+          tar.start(commit)?;
+        }
+        Box::new(tar)
+      }
+      ArchiveFormat::Zip => Box::new(ZipWriter {
+        out: &mut writer,
+        written: 0,
+        central: Vec::new(),
+        entries: 0,
+        mtime,
+        comment: commit.map_or_else(Vec::new, |commit| commit.as_hex().into_bytes()),
+      }),
+    };
+    if options.prefix.ends_with(b"/") {
+      archive.entry(&options.prefix, FileMode::Tree, &root, b"")?;
+    }
+    context.add_tree(&root, b"", &mut *archive)?;
+    archive.finish()?;
+    drop(archive);
+    writer.flush()?;
+    Ok(())
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to writing archives
+pub enum ArchiveError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Attributes(#[from] AttributesError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Filter(#[from] FilterError),
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("unknown archive format {0:?}")]
+  UnknownFormat(String),
+  #[error("{0} is not a tree, commit, or tag of one")]
+  NotATree(OID),
+  #[error("{0:?} is too large for a zip archive")]
+  TooLarge(BString),
+  #[error("too many entries for a zip archive")]
+  TooManyEntries,
+}
+
+#[test]
+fn archive() {
+  use crate::Signature;
+  use std::{fs, process::Command};
+  let tmp_dir = tempdir::TempDir::new("archive_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let odb = repo.odb();
+  let deep = format!("deep/{}/file.txt", "d".repeat(120));
+  let long = "l".repeat(110);
+  let target = format!("{}/{}", "t".repeat(60), "t".repeat(60));
+  let subst = "$Format:%H %h %T %P%n%an <%ae> %ad %aD %at%n%cn %ci %cI%n%s%n%b%n%x$ $Format$\n";
+  let tree = crate::diff::write_tree(
+    odb,
+    &[
+      (
+        ".gitattributes",
+        FileMode::NonExecutableFile,
+        "ignored export-ignore\n*.ignored export-ignore\nsubst.txt export-subst\n",
+      ),
+      ("file.txt", FileMode::NonExecutableFile, "hello\n"),
+      ("ignored/file", FileMode::NonExecutableFile, "ignored\n"),
+      ("also.ignored", FileMode::NonExecutableFile, "ignored\n"),
+      ("link", FileMode::SymbolicLink, "file.txt"),
+      ("long-link", FileMode::SymbolicLink, &target),
+      (&long, FileMode::NonExecutableFile, "long\n"),
+      (&deep, FileMode::NonExecutableFile, "deep\n"),
+      ("run.sh", FileMode::ExecutableFile, "#!/bin/sh\n"),
+      (
+        "sub/file.txt",
+        FileMode::NonExecutableFile,
+        &"repeated ".repeat(100),
+      ),
+      ("subst.txt", FileMode::NonExecutableFile, subst),
+    ],
+  );
+  let blob = odb.write_blob(&crate::Blob::new("blob")).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 90),
+  );
+  let commit = odb
+    .write_commit(&Commit::new(
+      tree,
+      vec![],
+      signature.clone(),
+      signature,
+      "Subject\n\nBody\n",
+    ))
+    .unwrap();
+
+  let options = ArchiveOptions {
+    prefix: "prefix/".into(),
+    ..ArchiveOptions::default()
+  };
+  let mut tar = Vec::new();
+  repo
+    .archive_with_options(&commit, ArchiveFormat::Tar, &options, &mut tar)
+    .unwrap();
+  assert_eq!(0, tar.len() % TAR_RECORD);
+  assert!(tar.starts_with(b"pax_global_header"));
+  assert!(tar.find(b"prefix/run.sh").is_some());
+  assert!(tar.find(b"also.ignored").is_none());
+  let mut tree_tar = Vec::new();
+  repo
+    .archive(&tree, ArchiveFormat::Tar, &mut tree_tar)
+    .unwrap();
+  assert!(tree_tar.starts_with(b".gitattributes"));
+  assert!(matches!(
+    repo.archive(&blob, ArchiveFormat::Tar, Vec::new()),
+    Err(ArchiveError::NotATree(_))
+  ));
+  let mut zip = Vec::new();
+  repo
+    .archive_with_options(&commit, ArchiveFormat::Zip, &options, &mut zip)
+    .unwrap();
+  assert!(zip.ends_with(commit.as_hex().as_bytes()));
+
+  // Git writes the same tar archive, and unzip gets the same files out of
+  // the zip archive that git writes
+  if crate::transport::http::have_git() {
+    let git_archive = |format: &str| {
+      let output = Command::new("git")
+        .args(["archive", "--format", format, "--prefix=prefix/"])
+        .arg(commit.as_hex())
+        .current_dir(tmp_dir.path())
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .output()
+        .unwrap();
+      assert!(output.status.success());
+      output.stdout
+    };
+    assert_eq!(git_archive("tar").as_bstr(), tar.as_bstr());
+
+    let unzip = |zip: &[u8], name: &str| {
+      let file = tmp_dir.path().join(format!("{}.zip", name));
+      fs::write(&file, zip).unwrap();
+      let dir = tmp_dir.path().join(name);
+      let status = Command::new("unzip")
+        .arg("-q")
+        .arg(&file)
+        .arg("-d")
+        .arg(&dir)
+        .status()
+        .ok()?;
+      assert!(status.success());
+      Some(dir)
+    };
+    if let (Some(ours), Some(theirs)) = (unzip(&zip, "ours"), unzip(&git_archive("zip"), "git")) {
+      for path in [
+        "prefix/.gitattributes",
+        "prefix/file.txt",
+        &format!("prefix/{}", long),
+        "prefix/run.sh",
+        "prefix/sub/file.txt",
+        &format!("prefix/{}", deep),
+        "prefix/subst.txt",
+      ] {
+        assert_eq!(
+          fs::read(theirs.join(path)).unwrap(),
+          fs::read(ours.join(path)).unwrap(),
+          "{}",
+          path
+        );
+        #[cfg(unix)]
+        {
+          use std::os::unix::fs::PermissionsExt;
+          let mode = |dir: &std::path::Path| {
+            fs::metadata(dir.join(path)).unwrap().permissions().mode() & 0o111
+          };
+          assert_eq!(mode(&theirs), mode(&ours), "{}", path);
+        }
+      }
+      for link in ["prefix/link", "prefix/long-link"] {
+        assert_eq!(
+          fs::read_link(theirs.join(link)).unwrap(),
+          fs::read_link(ours.join(link)).unwrap()
+        );
+      }
+      assert!(!&ours.join("prefix/ignored").exists());
+      assert!(!&ours.join("prefix/also.ignored").exists());
+    }
+  }
+}
@@ -0,0 +1,553 @@
+//! Attributes, which `.gitattributes` files give paths to change how git
+//! treats them, like `text eol=crlf` to check a file out with CRLF line
+//! endings or `-diff` to show it as binary. Each line of a file is a
+//! pattern followed by the attributes to give the paths it matches:
+//!
+//! ```text
+//! *.txt   text
+//! *.png   -text -diff
+//! /build/ export-ignore
+//! [attr]lfs filter=lfs diff=lfs merge=lfs -text
+//! ```
+//!
+//! `name` sets an attribute, `-name` unsets it, `name=value` gives it a
+//! value, and `!name` makes it unspecified as if no line above said
+//! anything about it. Lines starting with `[attr]` define macros, which
+//! set other attributes when they're set themselves.
+
+use crate::{
+  config::{parse_bool, xdg_config_path},
+  patch::unquote_path,
+  wildmatch::{self, wildmatch},
+  ConfigError, FileMode, IndexError, Odb, OdbError, RefError, Repository, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  collections::{BTreeMap, BTreeSet, HashMap},
+  env, fs, io,
+  path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// What an attribute is for a path
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AttributeState {
+  /// Set with `name`
+  Set,
+  /// Unset with `-name`
+  Unset,
+  /// Given a value with `name=value`
+  Value(BString),
+  /// Not said anything about, or made unspecified with `!name`
+  Unspecified,
+}
+
+impl AttributeState {
+  /// Whether the attribute is set, without a value
+  pub fn is_set(&self) -> bool {
+    *self == Self::Set
+  }
+
+  /// Whether the attribute is unset
+  pub fn is_unset(&self) -> bool {
+    *self == Self::Unset
+  }
+
+  /// Whether anything was said about the attribute
+  pub fn is_specified(&self) -> bool {
+    *self != Self::Unspecified
+  }
+
+  /// The value of the attribute, if it has one
+  pub fn value(&self) -> Option<&BStr> {
+    match self {
+      Self::Value(value) => Some(value.as_bstr()),
+      _ => None,
+    }
+  }
+}
+
+/// One line of an attributes file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+  /// The directory of the file the line is from, relative to the root of
+  /// the working tree and ending in `/`, or empty for the root
+  base: BString,
+  pattern: BString,
+  /// Whether the pattern only matches the last component of a path
+  basename: bool,
+  assignments: Vec<(BString, AttributeState)>,
+}
+
+/// The attributes files of a repository, which say what attributes each
+/// path has. Later files take precedence over earlier ones, and later lines
+/// over earlier lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attributes {
+  rules: Vec<Rule>,
+  macros: HashMap<BString, Vec<(BString, AttributeState)>>,
+  ignore_case: bool,
+}
+
+impl Default for Attributes {
+  /// No rules, with only the `binary` macro that's built into git
+  fn default() -> Self {
+    let binary: Vec<_> = ["diff", "merge", "text"]
+      .iter()
+      .map(|name| (BString::from(*name), AttributeState::Unset))
+      .collect();
+    let mut macros = HashMap::new();
+    macros.insert("binary".into(), binary);
+    Self {
+      rules: Vec::new(),
+      macros,
+      ignore_case: false,
+    }
+  }
+}
+
+impl Attributes {
+  /// Parse an attributes file at the root of the working tree
+  pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Self {
+    let mut attributes = Self::default();
+    attributes.add_bytes(bytes, "");
+    attributes
+  }
+
+  /// Add the lines of an attributes file in the directory `base`, which is
+  /// relative to the root of the working tree, like `src` for
+  /// `src/.gitattributes`, or empty for the root. They take precedence over
+  /// those added before. Macros can only be defined at the root, and lines
+  /// that can't be parsed are skipped like git does.
+  pub fn add_bytes(&mut self, bytes: impl AsRef<[u8]>, base: impl AsRef<[u8]>) {
+    let mut base = BString::from(base.as_ref().trim_with(|c| c == '/'));
+    if !base.is_empty() {
+      base.push(b'/');
+    }
+    for line in bytes.as_ref().lines() {
+      let line = line.trim_start();
+      if line.is_empty() || line.starts_with(b"#") {
+        continue;
+      }
+      let (pattern, rest) = match unquote_path(line) {
+        Some((pattern, used)) => (BString::from(pattern), &line[used..]),
+        None => {
+          let end = line.find_byteset(b" \t\r").unwrap_or(line.len());
+          (BString::from(&line[..end]), &line[end..])
+        }
+      };
+      let assignments: Vec<_> = rest
+        .fields_with(|c| c == ' ' || c == '\t' || c == '\r')
+        .filter_map(parse_assignment)
+        .collect();
+      if let Some(name) = pattern.strip_prefix(b"[attr]") {
+        if base.is_empty() && is_valid_name(name) {
+          self.macros.insert(name.into(), assignments);
+        }
+        continue;
+      }
+      // Negated patterns and patterns for directories never match a path
+      if pattern.starts_with(b"!") || pattern.ends_with(b"/") {
+        continue;
+      }
+      let (pattern, basename) = match pattern.strip_prefix(b"/") {
+        Some(pattern) => (pattern.into(), false),
+        None => {
+          let basename = !pattern.contains(&b'/');
+          (pattern, basename)
+        }
+      };
+      self.rules.push(Rule {
+        base: base.clone(),
+        pattern,
+        basename,
+        assignments,
+      });
+    }
+  }
+
+  /// Add the attributes file at `path` the way [`Attributes::add_bytes`]
+  /// does, doing nothing if there's no file there
+  pub fn add_file(&mut self, path: impl AsRef<Path>, base: impl AsRef<[u8]>) -> io::Result<()> {
+    match fs::read(path) {
+      Ok(bytes) => self.add_bytes(bytes, base),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+      Err(e) => return Err(e),
+    }
+    Ok(())
+  }
+
+  /// Whether patterns match paths regardless of case, like they do when
+  /// `core.ignoreCase` is set
+  pub fn set_ignore_case(&mut self, ignore_case: bool) {
+    self.ignore_case = ignore_case;
+  }
+
+  /// What the attribute `name` is for `path`, which is relative to the
+  /// root of the working tree and separated by `/`, like `git check-attr`
+  pub fn get(&self, path: impl AsRef<[u8]>, name: impl AsRef<[u8]>) -> AttributeState {
+    let name = name.as_ref();
+    self
+      .collect(path.as_ref(), Some(name))
+      .remove(name.as_bstr())
+      .unwrap_or(AttributeState::Unspecified)
+  }
+
+  /// Every attribute specified for `path`, sorted by name, like `git
+  /// check-attr --all`
+  pub fn all(&self, path: impl AsRef<[u8]>) -> Vec<(BString, AttributeState)> {
+    self
+      .collect(path.as_ref(), None)
+      .into_iter()
+      .filter(|(_, state)| state.is_specified())
+      .collect()
+  }
+
+  /// What the rules for `path` say about `only` or every attribute, going
+  /// from the rule that takes precedence the most and keeping the first
+  /// thing said about each attribute, with macros expanded as they're set
+  fn collect(&self, path: &[u8], only: Option<&[u8]>) -> BTreeMap<BString, AttributeState> {
+    let mut found = BTreeMap::new();
+    for rule in self.rules.iter().rev() {
+      if self.matches(rule, path) {
+        // The last thing a line says about an attribute is what counts, and
+        // a macro is expanded right after it's set
+        let mut stack: Vec<&(BString, AttributeState)> = rule.assignments.iter().collect();
+        while let Some((name, state)) = stack.pop() {
+          if found.contains_key(name) {
+            continue;
+          }
+          found.insert(name.clone(), state.clone());
+          if let Some(expansion) = self.macros.get(name).filter(|_| state.is_set()) {
+            stack.extend(expansion);
+          }
+        }
+        if only.is_some_and(|name| found.contains_key(name.as_bstr())) {
+          break;
+        }
+      }
+    }
+    found
+  }
+
+  fn matches(&self, rule: &Rule, path: &[u8]) -> bool {
+    let path = match path.strip_prefix(rule.base.as_bytes()) {
+      Some(path) => path,
+      None => return false,
+    };
+    let flags = if self.ignore_case {
+      wildmatch::CASEFOLD
+    } else {
+      0
+    };
+    match rule.basename {
+      true => {
+        let name = path.rsplit_str("/").next().unwrap_or(path);
+        wildmatch(&rule.pattern, name, flags)
+      }
+      false => wildmatch(&rule.pattern, path, flags | wildmatch::PATHNAME),
+    }
+  }
+}
+
+/// Parse `name`, `-name`, `!name`, or `name=value`
+fn parse_assignment(word: &[u8]) -> Option<(BString, AttributeState)> {
+  let (name, state) = if let Some(name) = word.strip_prefix(b"-") {
+    (name, AttributeState::Unset)
+  } else if let Some(name) = word.strip_prefix(b"!") {
+    (name, AttributeState::Unspecified)
+  } else {
+    match word.find_byte(b'=') {
+      Some(eq) => (&word[..eq], AttributeState::Value(word[eq + 1..].into())),
+      None => (word, AttributeState::Set),
+    }
+  };
+  is_valid_name(name).then(|| (name.into(), state))
+}
+
+/// Whether `name` is made of letters, digits, `-`, `.`, and `_`, without a
+/// `-` first
+fn is_valid_name(name: &[u8]) -> bool {
+  !name.is_empty()
+    && !name.starts_with(b"-")
+    && name
+      .iter()
+      .all(|c| c.is_ascii_alphanumeric() || b"-._".contains(c))
+}
+
+impl Repository {
+  /// The attributes for the paths of the repository, from the same files
+  /// git reads, from least to most precedence:
+  ///
+  /// - `/etc/gitattributes`, unless `GIT_ATTR_NOSYSTEM` is set
+  /// - `core.attributesFile`, which is `$XDG_CONFIG_HOME/git/attributes`
+  ///   by default
+  /// - the `.gitattributes` file of each directory of the working tree
+  ///   with tracked files in it, from the root down, read from the index
+  ///   if it isn't in the working tree
+  /// - `$GIT_DIR/info/attributes`
+  ///
+  /// A bare repository has no working tree to read `.gitattributes` files
+  /// from, so like git they're read from the tree of the commit `HEAD` is
+  /// at, the same as [`Repository::tree_attributes`] does.
+  pub fn attributes(&self) -> Result<Attributes, AttributesError> {
+    if let (true, Some(commit)) = (self.is_bare(), self.refs().resolve("HEAD")?) {
+      let tree = *self.odb().read_commit(&commit)?.tree();
+      return self.tree_attributes(&tree);
+    }
+    self.read_attributes(|attributes| {
+      let work_dir = match self.work_dir() {
+        Some(work_dir) => work_dir,
+        None => return Ok(()),
+      };
+      let index = self.index()?;
+      let mut dirs = BTreeSet::new();
+      dirs.insert(BString::from(""));
+      for entry in index.entries() {
+        let mut path = entry.path.as_bytes();
+        while let Some(slash) = path.rfind_byte(b'/') {
+          path = &path[..slash];
+          dirs.insert(path.into());
+        }
+      }
+      // Directories sort before what's in them, so parents come first
+      for dir in dirs {
+        let name: BString = match dir.is_empty() {
+          true => ".gitattributes".into(),
+          false => [&dir[..], b"/.gitattributes"].concat().into(),
+        };
+        let file: PathBuf = work_dir.join(name.to_path_lossy());
+        match fs::read(&file) {
+          Ok(bytes) => attributes.add_bytes(bytes, &dir),
+          Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if let Some(entry) = index.get(&name) {
+              attributes.add_bytes(self.odb().read_blob(&entry.oid)?.contents(), &dir);
+            }
+          }
+          Err(e) => return Err(e.into()),
+        }
+      }
+      Ok(())
+    })
+  }
+
+  /// The attributes the paths of the repository would have with the
+  /// [`Tree`][crate::Tree] with the given [`OID`] checked out, which are the
+  /// same as [`Repository::attributes`] except that the `.gitattributes`
+  /// files are read from the tree
+  pub fn tree_attributes(&self, tree: &OID) -> Result<Attributes, AttributesError> {
+    fn add_tree(
+      odb: &Odb,
+      tree: &OID,
+      dir: &[u8],
+      attributes: &mut Attributes,
+    ) -> Result<(), AttributesError> {
+      let tree = odb.read_tree(tree)?;
+      let file = tree.entries().iter().find(|entry| {
+        entry.name() == ".gitattributes"
+          && !entry.mode().is_tree()
+          && entry.mode() != FileMode::GitLink
+      });
+      if let Some(file) = file {
+        attributes.add_bytes(odb.read_blob(file.oid())?.contents(), dir);
+      }
+      for entry in tree.entries().iter().filter(|entry| entry.mode().is_tree()) {
+        let dir = match dir.is_empty() {
+          true => entry.name().to_vec(),
+          false => [dir, b"/", entry.name().as_bytes()].concat(),
+        };
+        add_tree(odb, entry.oid(), &dir, attributes)?;
+      }
+      Ok(())
+    }
+    self.read_attributes(|attributes| add_tree(self.odb(), tree, b"", attributes))
+  }
+
+  /// Read the attributes files outside of the working tree around the ones
+  /// `add` adds
+  fn read_attributes(
+    &self,
+    add: impl FnOnce(&mut Attributes) -> Result<(), AttributesError>,
+  ) -> Result<Attributes, AttributesError> {
+    let mut attributes = Attributes::default();
+    attributes.set_ignore_case(self.config().get_bool("core.ignorecase")?.unwrap_or(false));
+    let no_system = env::var("GIT_ATTR_NOSYSTEM").ok();
+    if no_system.and_then(|v| parse_bool(v.as_bytes())) != Some(true) {
+      attributes.add_file("/etc/gitattributes", "")?;
+    }
+    let global = match self.config().get_path("core.attributesFile")? {
+      Some(path) => Some(path),
+      None => xdg_config_path("attributes"),
+    };
+    if let Some(path) = global {
+      attributes.add_file(path, "")?;
+    }
+    add(&mut attributes)?;
+    attributes.add_file(self.common_dir().join("info/attributes"), "")?;
+    Ok(attributes)
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to reading [`Attributes`]
+pub enum AttributesError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Index(#[from] IndexError),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+}
+
+#[test]
+fn attributes() {
+  use crate::{Blob, FileMode, Index, IndexEntry, StatData};
+  use AttributeState::*;
+  let value = |value: &str| Value(value.into());
+  let attributes = Attributes::from_bytes(
+    "# comment\n\
+     *.txt text eol=lf\n\
+     *.png binary\n\
+     /root.txt -text\n\
+     docs/*.md  diff=markdown !eol\n\
+     \"with space.txt\" -diff\n\
+     **/gen/** linguist-generated\n\
+     !negated text\n\
+     dir/ text\n\
+     [attr]mine custom=yes -merge\n\
+     *.mine mine -custom\n\
+     bad -=x 1ok\n",
+  );
+  assert_eq!(Set, attributes.get("a/b.txt", "text"));
+  assert_eq!(value("lf"), attributes.get("a/b.txt", "eol"));
+  assert_eq!(Unset, attributes.get("root.txt", "text"));
+  assert_eq!(Set, attributes.get("sub/root.txt", "text"));
+  assert_eq!(
+    vec![
+      ("diff".into(), Unset),
+      ("merge".into(), Unset),
+      ("text".into(), Unset),
+      ("binary".into(), Set),
+    ]
+    .into_iter()
+    .collect::<BTreeMap<BString, _>>()
+    .into_iter()
+    .collect::<Vec<_>>(),
+    attributes.all("image.png")
+  );
+  assert_eq!(value("markdown"), attributes.get("docs/a.md", "diff"));
+  assert_eq!(Unspecified, attributes.get("other/docs/a.md", "diff"));
+  assert_eq!(Unset, attributes.get("with space.txt", "diff"));
+  assert!(attributes
+    .get("a/gen/b/c.rs", "linguist-generated")
+    .is_set());
+  assert_eq!(Unspecified, attributes.get("negated", "text"));
+  assert_eq!(Unspecified, attributes.get("dir", "text"));
+  // A macro sets what it expands to, unless the line says otherwise
+  assert_eq!(Unset, attributes.get("x.mine", "custom"));
+  assert_eq!(Unset, attributes.get("x.mine", "merge"));
+  assert_eq!(vec![("1ok".into(), Set)], attributes.all("bad"));
+  assert_eq!("lf", attributes.get("b.txt", "eol").value().unwrap());
+
+  // Case only matters without core.ignoreCase
+  let mut attributes = Attributes::from_bytes("*.TXT text\n");
+  assert_eq!(Unspecified, attributes.get("a.txt", "text"));
+  attributes.set_ignore_case(true);
+  assert_eq!(Set, attributes.get("a.txt", "text"));
+
+  // The files of a repository, with deeper ones taking precedence
+  let tmp_dir = tempdir::TempDir::new("attributes_test").unwrap();
+  let repo_dir = tmp_dir.path().join("repo");
+  let global = tmp_dir.path().join("global-attributes");
+  Repository::init(&repo_dir).unwrap();
+  let config = repo_dir.join(".git/config");
+  let mut contents = fs::read_to_string(&config).unwrap();
+  contents.push_str(&format!(
+    "[core]\n\tattributesFile = {}\n",
+    global.display()
+  ));
+  fs::write(&config, contents).unwrap();
+  let repo = Repository::open(&repo_dir).unwrap();
+  fs::create_dir_all(repo_dir.join("src/deep")).unwrap();
+  fs::write(&global, "*.c diff=global eol=crlf\n").unwrap();
+  fs::write(repo_dir.join(".gitattributes"), "*.c diff=cpp text\n").unwrap();
+  fs::write(repo_dir.join("src/.gitattributes"), "*.c -text\n").unwrap();
+  fs::create_dir_all(repo_dir.join(".git/info")).unwrap();
+  fs::write(repo_dir.join(".git/info/attributes"), "main.c diff=info\n").unwrap();
+  // Only in the index
+  let deep = repo.odb().write_blob(&Blob::new("*.c eol=lf\n")).unwrap();
+  let entry =
+    |path: &str, oid| IndexEntry::new(path, FileMode::NonExecutableFile, oid, StatData::default());
+  let file = repo.odb().write_blob(&Blob::new("int x;\n")).unwrap();
+  Index::new(vec![
+    entry("src/deep/.gitattributes", deep),
+    entry("src/deep/x.c", file),
+    entry("src/main.c", file),
+  ])
+  .write(repo.index_path())
+  .unwrap();
+  let attributes = repo.attributes().unwrap();
+  let expected = |path: &str| -> Vec<(BString, AttributeState)> {
+    let mut all = attributes.all(path);
+    all.retain(|(name, _)| ["diff", "eol", "text"].contains(&name.to_str().unwrap()));
+    all
+  };
+  assert_eq!(
+    vec![
+      ("diff".into(), value("info")),
+      ("eol".into(), value("crlf")),
+      ("text".into(), Unset)
+    ],
+    expected("src/main.c")
+  );
+  assert_eq!(
+    vec![
+      ("diff".into(), value("cpp")),
+      ("eol".into(), value("lf")),
+      ("text".into(), Unset)
+    ],
+    expected("src/deep/x.c")
+  );
+  assert_eq!(
+    vec![
+      ("diff".into(), value("cpp")),
+      ("eol".into(), value("crlf")),
+      ("text".into(), Set)
+    ],
+    expected("top.c")
+  );
+
+  // git agrees
+  if !crate::transport::http::have_git() {
+    return;
+  }
+  for path in ["src/main.c", "src/deep/x.c", "top.c"] {
+    let output = std::process::Command::new("git")
+      .args(["check-attr", "diff", "eol", "text", "--", path])
+      .current_dir(&repo_dir)
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .env("GIT_ATTR_NOSYSTEM", "1")
+      .output()
+      .unwrap();
+    let shown: Vec<(BString, AttributeState)> = output
+      .stdout
+      .lines()
+      .map(|line| {
+        let value = line.rsplit_str(": ").next().unwrap();
+        let state = match value {
+          b"set" => Set,
+          b"unset" => Unset,
+          b"unspecified" => Unspecified,
+          value => Value(value.into()),
+        };
+        (line.split_str(": ").nth(1).unwrap().into(), state)
+      })
+      .collect();
+    assert_eq!(expected(path), shown, "{}", path);
+  }
+}
@@ -0,0 +1,392 @@
+//! `lgit`, a small git command line tool that only uses the public API of
+//! the library. It covers enough of a day to day workflow to show the API
+//! is complete enough for real tools and is what the integration tests
+//! drive. Build it with `cargo build --features cli`.
+//!
+//! Commands are run from the top of the working tree and paths are taken
+//! relative to it.
+
+use bstr::{BString, ByteSlice};
+use libgit_rs::{
+  diff_blobs, format_hunks, plumbing, Blob, CheckoutOptions, CloneOptions, DiffOptions, FileMode,
+  Index, IndexEntry, ObjectKind, RawObject, RefTarget, Repository, StatData, WorktreeStatus, OID,
+};
+use std::{
+  env, fs,
+  io::{self, Write},
+  path::{Path, PathBuf},
+  process,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const USAGE: &str = "usage: lgit <command> [<args>]
+
+  init [<directory>]               Create an empty repository
+  add <path>...                    Add files to the index
+  commit -m <message>              Record the index as a new commit
+  log [<revision>]                 Show the commit history
+  diff [--cached]                  Show unstaged or staged changes
+  status                           Show the status in the short format
+  clone <repository> [<directory>] Clone a repository on this machine";
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+  let result = match args.first().map(String::as_str) {
+    Some("init") => init(&args[1..]),
+    Some("add") => add(&args[1..]),
+    Some("commit") => commit(&args[1..]),
+    Some("log") => log(&args[1..]),
+    Some("diff") => diff(&args[1..]),
+    Some("status") => status(),
+    Some("clone") => clone(&args[1..]),
+    _ => Err(USAGE.into()),
+  };
+  if let Err(e) = result {
+    eprintln!("fatal: {}", e);
+    process::exit(128);
+  }
+}
+
+fn init(args: &[String]) -> Result<()> {
+  let dir = PathBuf::from(args.first().map_or(".", String::as_str));
+  let repo = Repository::init(&dir)?;
+  println!(
+    "Initialized empty Git repository in {}",
+    fs::canonicalize(repo.git_dir())?.display()
+  );
+  Ok(())
+}
+
+fn add(args: &[String]) -> Result<()> {
+  let repo = Repository::open(".")?;
+  if args.is_empty() {
+    return Err("nothing specified, nothing added".into());
+  }
+  let mut index = index(&repo)?;
+  let mut options = CheckoutOptions::from_config(repo.config())?;
+  options.line_endings.attributes = repo.attributes()?;
+  let (filters, line_endings) = (&options.filters, &options.line_endings);
+  for arg in args {
+    let path = arg.trim_start_matches("./").trim_end_matches('/');
+    let path = if path == "." { "" } else { path };
+    // Whatever is in the index under the path and isn't there anymore is
+    // removed, like `git add` does for deleted files
+    let gone: Vec<BString> = index
+      .entries()
+      .iter()
+      .filter(|entry| is_under(entry.path.as_bytes(), path.as_bytes()))
+      .filter(|entry| fs::symlink_metadata(entry.path.to_path_lossy()).is_err())
+      .map(|entry| entry.path.clone())
+      .collect();
+    for path in gone {
+      index.remove(path);
+    }
+    let root = if path.is_empty() { "." } else { path };
+    if fs::symlink_metadata(root).is_err() {
+      continue;
+    }
+    let mut files = Vec::new();
+    walk(Path::new(root), path, &mut files)?;
+    for file in files {
+      let metadata = fs::symlink_metadata(file.to_path_lossy())?;
+      // Without core.symlinks a file stands in for a symbolic link that's
+      // already in the index and holds its target, and without
+      // core.fileMode the executable bit comes from the index
+      let existing = index.get(&file).map(|entry| entry.mode);
+      let mode = options.worktree_mode(existing, &metadata);
+      let blob = if metadata.file_type().is_symlink() {
+        let target = fs::read_link(file.to_path_lossy())?;
+        let target = target.to_str().ok_or("symbolic link target is not UTF-8")?;
+        Blob::new(target)
+      } else if mode == FileMode::SymbolicLink {
+        Blob::new(fs::read(file.to_path_lossy())?)
+      } else {
+        let contents = fs::read(file.to_path_lossy())?;
+        let staged = match index.get(&file) {
+          Some(entry) => Some(repo.odb().read_blob(&entry.oid)?),
+          None => None,
+        };
+        let staged = staged.as_ref().map(|blob| blob.contents().as_bytes());
+        // Files go through their filter before line endings are converted
+        let contents = filters.clean(&line_endings.attributes, &file, &contents)?;
+        if let Some(lossy) = line_endings.check(&file, &contents, staged)? {
+          eprintln!(
+            "warning: in the working copy of '{}', {} the next time Git touches it",
+            file, lossy
+          );
+        }
+        let contents = line_endings.to_git(&file, &contents, staged);
+        Blob::new(contents)
+      };
+      let oid = repo.odb().write_blob(&blob)?;
+      let stat = StatData::from_metadata(&metadata);
+      index.add(IndexEntry::new(file, mode, oid, stat));
+    }
+  }
+  index.write(repo.index_path())?;
+  Ok(())
+}
+
+/// The index of the repository, which is empty before anything was added
+fn index(repo: &Repository) -> Result<Index> {
+  if repo.index_path().exists() {
+    Ok(repo.index()?)
+  } else {
+    Ok(Index::default())
+  }
+}
+
+/// Whether `path` is `dir` or inside of it, where an empty `dir` is the
+/// whole working tree
+fn is_under(path: &[u8], dir: &[u8]) -> bool {
+  dir.is_empty() || path == dir || (path.starts_with(dir) && path.get(dir.len()) == Some(&b'/'))
+}
+
+/// Every file and symbolic link at or under `path`, named `name` in the
+/// working tree
+fn walk(path: &Path, name: &str, files: &mut Vec<BString>) -> Result<()> {
+  let metadata = fs::symlink_metadata(path)?;
+  if !metadata.is_dir() {
+    files.push(name.into());
+    return Ok(());
+  }
+  let mut entries: Vec<_> = fs::read_dir(path)?.collect::<io::Result<_>>()?;
+  entries.sort_by_key(|entry| entry.file_name());
+  for entry in entries {
+    let file_name = entry.file_name();
+    let file_name = file_name.to_str().ok_or("file name is not UTF-8")?;
+    if file_name == ".git" {
+      continue;
+    }
+    let name = if name.is_empty() {
+      file_name.to_string()
+    } else {
+      format!("{}/{}", name, file_name)
+    };
+    walk(&entry.path(), &name, files)?;
+  }
+  Ok(())
+}
+
+fn commit(args: &[String]) -> Result<()> {
+  let message = match args {
+    [flag, message] if flag == "-m" => message,
+    _ => return Err("usage: lgit commit -m <message>".into()),
+  };
+  let repo = Repository::open(".")?;
+  let tree = repo.index()?.write_tree(repo.odb())?;
+  let parent = repo.refs().resolve("HEAD")?;
+  let parents: Vec<OID> = parent.into_iter().collect();
+  if let Some(parent) = parent {
+    if *repo.odb().read_commit(&parent)?.tree() == tree {
+      return Err("nothing to commit, working tree clean".into());
+    }
+  }
+  let mut message = message.trim_end().to_string();
+  message.push('\n');
+  let commit = plumbing::commit_tree(&repo, &tree, &parents, &message)?;
+  let zero = OID::from_bytes(&[0; 20])?;
+  plumbing::update_ref(
+    &repo,
+    "HEAD",
+    &commit,
+    Some(parent.as_ref().unwrap_or(&zero)),
+  )?;
+  println!(
+    "[{}{} {}] {}",
+    branch(&repo)?.unwrap_or_else(|| "detached HEAD".into()),
+    if parent.is_none() {
+      " (root-commit)"
+    } else {
+      ""
+    },
+    repo.odb().abbreviate(&commit, None)?,
+    message.lines().next().unwrap_or("")
+  );
+  Ok(())
+}
+
+/// The name of the branch `HEAD` is on
+fn branch(repo: &Repository) -> Result<Option<String>> {
+  Ok(
+    match repo.refs().read("HEAD")?.map(|head| head.target().clone()) {
+      Some(RefTarget::Symbolic(target)) => target
+        .strip_prefix(b"refs/heads/")
+        .map(|name| name.to_str_lossy().into_owned()),
+      _ => None,
+    },
+  )
+}
+
+fn log(args: &[String]) -> Result<()> {
+  let repo = Repository::open(".")?;
+  let start = repo.rev_parse(args.first().map_or("HEAD", String::as_str))?;
+  let stdout = io::stdout();
+  let mut out = stdout.lock();
+  let mut walk = repo.rev_walk();
+  walk.push(&start)?;
+  for (i, oid) in walk.enumerate() {
+    let oid = oid?;
+    let commit = repo.odb().read_commit(&oid)?;
+    if i > 0 {
+      writeln!(out)?;
+    }
+    writeln!(out, "commit {}", oid)?;
+    if commit.parents().len() > 1 {
+      let parents = commit
+        .parents()
+        .iter()
+        .map(|parent| repo.odb().abbreviate(parent, None))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+      writeln!(out, "Merge: {}", parents.join(" "))?;
+    }
+    let author = commit.author();
+    writeln!(out, "Author: {}", author.identity())?;
+    writeln!(
+      out,
+      "Date:   {}",
+      date(author.time.seconds, author.time.offset)
+    )?;
+    writeln!(out)?;
+    for line in commit.message_lossy().lines() {
+      if line.is_empty() {
+        writeln!(out)?;
+      } else {
+        writeln!(out, "    {}", line)?;
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Format a time the way `git log` does by default, like `Thu Sep 3
+/// 12:00:00 2020 +0200`
+fn date(seconds: i64, offset: i32) -> String {
+  const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+  const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+  ];
+  let local = seconds + i64::from(offset) * 60;
+  let (days, time) = (local.div_euclid(86400), local.rem_euclid(86400));
+  // The proleptic Gregorian calendar date of a day counted from 1970-01-01
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let day_of_era = z.rem_euclid(146_097);
+  let year_of_era =
+    (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let month = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * month + 2) / 5 + 1;
+  let month = if month < 10 { month + 3 } else { month - 9 };
+  let year = year_of_era + era * 400 + i64::from(month <= 2);
+  let sign = if offset < 0 { '-' } else { '+' };
+  format!(
+    "{} {} {} {:02}:{:02}:{:02} {} {}{:02}{:02}",
+    DAYS[days.rem_euclid(7) as usize],
+    MONTHS[month as usize - 1],
+    day,
+    time / 3600,
+    time / 60 % 60,
+    time % 60,
+    year,
+    sign,
+    offset.abs() / 60,
+    offset.abs() % 60
+  )
+}
+
+fn diff(args: &[String]) -> Result<()> {
+  let repo = Repository::open(".")?;
+  let index = index(&repo)?;
+  let stdout = io::stdout();
+  let mut out = stdout.lock();
+  if args
+    .iter()
+    .any(|arg| arg == "--cached" || arg == "--staged")
+  {
+    let head = match repo.refs().resolve("HEAD")? {
+      Some(commit) => Some(*repo.odb().read_commit(&commit)?.tree()),
+      None => None,
+    };
+    let tree = index.write_tree(repo.odb())?;
+    let changes = repo.diff_trees(head.as_ref(), Some(&tree))?;
+    out.write_all(&repo.format_patch(&changes)?)?;
+    return Ok(());
+  }
+
+  // The working tree isn't in the object database, so the patch is put
+  // together from the blob diff here instead of with `format_patch`
+  let options = DiffOptions::from_config(repo.config())?;
+  for change in repo.worktree_status()? {
+    let entry = match (change.status, index.get(&change.path)) {
+      (WorktreeStatus::Modified | WorktreeStatus::Deleted, Some(entry)) => entry,
+      _ => continue,
+    };
+    let path = &change.path;
+    let old = repo.odb().read_blob(&entry.oid)?;
+    let mode = entry.mode.as_bytes().as_bstr();
+    let abbrev = |oid: &OID| repo.odb().abbreviate(oid, None);
+    writeln!(out, "diff --git a/{} b/{}", path, path)?;
+    let (new, new_path) = if change.status == WorktreeStatus::Deleted {
+      writeln!(out, "deleted file mode {}", mode)?;
+      writeln!(out, "index {}..0000000", abbrev(&entry.oid)?)?;
+      (Vec::new(), "/dev/null".to_string())
+    } else {
+      let new = if entry.mode == FileMode::SymbolicLink {
+        let target = fs::read_link(path.to_path_lossy())?;
+        target.to_string_lossy().into_owned().into_bytes()
+      } else {
+        fs::read(path.to_path_lossy())?
+      };
+      let new_oid = RawObject::new(ObjectKind::Blob, new.clone()).id();
+      writeln!(
+        out,
+        "index {}..{} {}",
+        abbrev(&entry.oid)?,
+        abbrev(&new_oid)?,
+        mode
+      )?;
+      (new, format!("b/{}", path))
+    };
+    writeln!(out, "--- a/{}\n+++ {}", path, new_path)?;
+    let hunks = diff_blobs(old.contents(), &new, &options);
+    out.write_all(&format_hunks(old.contents(), &hunks))?;
+  }
+  Ok(())
+}
+
+fn status() -> Result<()> {
+  let repo = Repository::open(".")?;
+  for entry in repo.status()? {
+    println!("{}", entry);
+  }
+  Ok(())
+}
+
+fn clone(args: &[String]) -> Result<()> {
+  let source = args
+    .first()
+    .ok_or("usage: lgit clone <repository> [<directory>]")?;
+  // Paths are recorded as the remote's URL the way they'd be found from
+  // anywhere
+  let url = match source.contains("://") {
+    true => source.clone(),
+    false => fs::canonicalize(source)?.to_string_lossy().into_owned(),
+  };
+  let dir = match args.get(1) {
+    Some(dir) => PathBuf::from(dir),
+    None => {
+      let name = Path::new(url.trim_end_matches('/'))
+        .file_name()
+        .ok_or("can't tell the directory name")?;
+      Path::new(name).with_extension("")
+    }
+  };
+  println!("Cloning into '{}'...", dir.display());
+  let repo = Repository::clone(&url, &dir, &CloneOptions::default())?;
+  if repo.refs().resolve("HEAD")?.is_none() {
+    eprintln!("warning: You appear to have cloned an empty repository.");
+  }
+  Ok(())
+}
@@ -0,0 +1,676 @@
+use crate::{
+  cleanup,
+  endian::{read_u16, read_u32, read_u64},
+  transport::{self, TransportError},
+  FileMode, ObjectKind, Odb, OdbError, RefError, Repository, RevWalkError, Trace2, OID,
+};
+use sha1::{Digest, Sha1};
+use std::{
+  borrow::Cow,
+  collections::{HashMap, HashSet},
+  fs, io,
+  path::PathBuf,
+};
+use thiserror::Error;
+
+const SIGNATURE: &[u8; 4] = b"BITM";
+/// Every object reachable from a commit with a bitmap is in the pack. Git
+/// doesn't use bitmaps without it.
+const FULL_DAG: u16 = 1;
+/// How many commits apart the commits that get a bitmap of their own are,
+/// besides the ones the refs point at
+const COMMIT_INTERVAL: usize = 100;
+/// The order of the bitmaps of the objects of each kind in the file
+const KINDS: [ObjectKind; 4] = [
+  ObjectKind::Commit,
+  ObjectKind::Tree,
+  ObjectKind::Blob,
+  ObjectKind::Tag,
+];
+
+/// A set of positions in a pack, one bit per object
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Bitset {
+  words: Vec<u64>,
+}
+
+impl Bitset {
+  fn get(&self, i: u32) -> bool {
+    let i = i as usize;
+    self
+      .words
+      .get(i / 64)
+      .is_some_and(|word| word & (1 << (i % 64)) != 0)
+  }
+
+  pub(crate) fn set(&mut self, i: u32) {
+    let i = i as usize;
+    if self.words.len() <= i / 64 {
+      self.words.resize(i / 64 + 1, 0);
+    }
+    self.words[i / 64] |= 1 << (i % 64);
+  }
+
+  fn or(&mut self, other: &Bitset) {
+    if self.words.len() < other.words.len() {
+      self.words.resize(other.words.len(), 0);
+    }
+    for (word, other) in self.words.iter_mut().zip(&other.words) {
+      *word |= other;
+    }
+  }
+
+  fn and_not(&mut self, other: &Bitset) {
+    for (word, other) in self.words.iter_mut().zip(&other.words) {
+      *word &= !other;
+    }
+  }
+
+  fn xor(&mut self, other: &Bitset) {
+    if self.words.len() < other.words.len() {
+      self.words.resize(other.words.len(), 0);
+    }
+    for (word, other) in self.words.iter_mut().zip(&other.words) {
+      *word ^= other;
+    }
+  }
+
+  pub(crate) fn ones(&self) -> impl Iterator<Item = u32> + '_ {
+    self.words.iter().enumerate().flat_map(|(i, &word)| {
+      (0..64)
+        .filter(move |bit| word & (1 << bit) != 0)
+        .map(move |bit| (i * 64 + bit) as u32)
+    })
+  }
+
+  fn count(&self) -> usize {
+    self
+      .words
+      .iter()
+      .map(|word| word.count_ones() as usize)
+      .sum()
+  }
+
+  /// How many bits there are up to the last one that is set
+  fn len(&self) -> u32 {
+    match self.words.iter().rposition(|&word| word != 0) {
+      Some(i) => (i * 64) as u32 + 64 - self.words[i].leading_zeros(),
+      None => 0,
+    }
+  }
+}
+
+/// Read a bitmap compressed with EWAH, git's run length encoding of 64 bit
+/// words, returning it and how many bytes it took up
+pub(crate) fn read_ewah(data: &[u8]) -> Result<(Bitset, usize), BitmapError> {
+  let truncated = || BitmapError::Malformed("bitmap is truncated");
+  let header = data.get(..8).ok_or_else(truncated)?;
+  let bits = read_u32(header);
+  let count = read_u32(&header[4..]) as usize;
+  let len = count
+    .checked_mul(8)
+    .and_then(|len| len.checked_add(8 + 4))
+    .filter(|len| *len <= data.len())
+    .ok_or_else(truncated)?;
+  let mut words = (0..count).map(|i| read_u64(&data[8 + i * 8..]));
+  let mut bitset = Bitset::default();
+  // Each marker word is a run of words that are all zeros or all ones
+  // followed by how many words come as they are
+  while let Some(marker) = words.next() {
+    let fill = if marker & 1 != 0 { u64::MAX } else { 0 };
+    let run = (marker >> 1) & 0xffff_ffff;
+    let literal = (marker >> 33) as usize;
+    if (bitset.words.len() as u64 + run) * 64 > u64::from(bits) + 63 {
+      return Err(BitmapError::Malformed("bitmap is longer than its size"));
+    }
+    bitset.words.extend(std::iter::repeat_n(fill, run as usize));
+    for _ in 0..literal {
+      bitset.words.push(words.next().ok_or_else(truncated)?);
+    }
+  }
+  if bitset.len() > bits {
+    return Err(BitmapError::Malformed("bitmap is longer than its size"));
+  }
+  Ok((bitset, len))
+}
+
+pub(crate) fn write_ewah(bitset: &Bitset, out: &mut Vec<u8>) {
+  let bits = bitset.len();
+  let words = &bitset.words[..(bits as usize).div_ceil(64)];
+  let mut encoded = Vec::new();
+  let mut i = 0;
+  loop {
+    let marker = encoded.len();
+    encoded.push(0);
+    let fill = if words.get(i) == Some(&u64::MAX) {
+      u64::MAX
+    } else {
+      0
+    };
+    let mut run = 0u64;
+    while words.get(i) == Some(&fill) && run < 0xffff_ffff {
+      run += 1;
+      i += 1;
+    }
+    let mut literal = 0u64;
+    while words.get(i).is_some_and(|&w| w != 0 && w != u64::MAX) && literal < 0x7fff_ffff {
+      encoded.push(words[i]);
+      literal += 1;
+      i += 1;
+    }
+    encoded[marker] = (fill & 1) | (run << 1) | (literal << 33);
+    if i >= words.len() {
+      out.extend_from_slice(&bits.to_be_bytes());
+      out.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+      for word in &encoded {
+        out.extend_from_slice(&word.to_be_bytes());
+      }
+      out.extend_from_slice(&(marker as u32).to_be_bytes());
+      return;
+    }
+  }
+}
+
+/// The `.bitmap` file of a pack, which holds which objects of the pack are
+/// reachable from some of its commits, one bit per object in the order
+/// they're in the pack. Everything reachable from a ref can be worked out
+/// by walking only from the refs to the nearest commits with a bitmap
+/// instead of through all of history, which is most of the work of
+/// serving a clone of a large repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BitmapIndex {
+  /// The objects of the pack in the order they're in it
+  oids: Vec<OID>,
+  positions: HashMap<OID, u32>,
+  /// The objects of each kind, in the order of [`KINDS`]
+  kinds: [Bitset; 4],
+  bitmaps: HashMap<OID, Bitset>,
+}
+
+impl BitmapIndex {
+  fn new(oids: Vec<OID>) -> Self {
+    let positions = oids
+      .iter()
+      .enumerate()
+      .map(|(i, oid)| (*oid, i as u32))
+      .collect();
+    Self {
+      oids,
+      positions,
+      kinds: Default::default(),
+      bitmaps: HashMap::new(),
+    }
+  }
+
+  /// Parse the `.bitmap` of the pack with the `checksum` and the objects
+  /// `oids`, in the order they're in the pack
+  pub(crate) fn parse(data: &[u8], checksum: &OID, oids: Vec<OID>) -> Result<Self, BitmapError> {
+    if data.len() < 32 + 20 {
+      return Err(BitmapError::Malformed("file is too short"));
+    }
+    if &data[..4] != SIGNATURE {
+      return Err(BitmapError::Malformed("missing BITM signature"));
+    }
+    let version = read_u16(&data[4..]);
+    if version != 1 {
+      return Err(BitmapError::UnsupportedVersion(version));
+    }
+    if read_u16(&data[6..]) & FULL_DAG == 0 {
+      return Err(BitmapError::Malformed("bitmap is not of the full history"));
+    }
+    if data[12..32] != checksum.as_bytes()[..] {
+      return Err(BitmapError::Malformed("bitmap is for another pack"));
+    }
+    let (content, trailer) = data.split_at(data.len() - 20);
+    if Sha1::digest(content)[..] != *trailer {
+      return Err(BitmapError::ChecksumMismatch);
+    }
+    let mut index = Self::new(oids);
+    let count = index.oids.len() as u32;
+    let mut pos = 32;
+    for kind in &mut index.kinds {
+      let (bitset, len) = read_ewah(&content[pos..])?;
+      if bitset.len() > count {
+        return Err(BitmapError::Malformed("bitmap has objects past the pack"));
+      }
+      *kind = bitset;
+      pos += len;
+    }
+
+    // Entries name their commit by its position in the pack index, which is
+    // sorted by OID, and can be stored XORed with one a few entries back
+    let mut sorted = index.oids.clone();
+    sorted.sort_unstable();
+    let mut entries: Vec<(OID, Bitset)> = Vec::new();
+    for _ in 0..read_u32(&data[8..]) {
+      let header = content
+        .get(pos..pos + 6)
+        .ok_or(BitmapError::Malformed("bitmap entry is truncated"))?;
+      let oid = *sorted
+        .get(read_u32(header) as usize)
+        .ok_or(BitmapError::Malformed(
+          "bitmap entry for an object past the pack",
+        ))?;
+      let xor = usize::from(header[4]);
+      let (mut bitset, len) = read_ewah(&content[pos + 6..])?;
+      if xor != 0 {
+        let base = entries
+          .len()
+          .checked_sub(xor)
+          .ok_or(BitmapError::Malformed(
+            "bitmap entry XORed with a missing entry",
+          ))?;
+        bitset.xor(&entries[base].1);
+      }
+      if bitset.len() > count {
+        return Err(BitmapError::Malformed("bitmap has objects past the pack"));
+      }
+      entries.push((oid, bitset));
+      pos += 6 + len;
+    }
+    index.bitmaps = entries.into_iter().collect();
+    Ok(index)
+  }
+
+  fn kind(&self, position: u32) -> Option<ObjectKind> {
+    KINDS
+      .iter()
+      .zip(&self.kinds)
+      .find(|(_, bitset)| bitset.get(position))
+      .map(|(kind, _)| *kind)
+  }
+
+  /// Everything reachable from `roots`, or `None` if some of it isn't in
+  /// the pack
+  fn reachable(&self, odb: &Odb, roots: &[OID]) -> Result<Option<Bitset>, OdbError> {
+    let mut reached = Bitset::default();
+    let mut pending = roots.to_vec();
+    while let Some(oid) = pending.pop() {
+      let position = match self.positions.get(&oid) {
+        Some(position) => *position,
+        None => return Ok(None),
+      };
+      if reached.get(position) {
+        continue;
+      }
+      if let Some(bitmap) = self.bitmaps.get(&oid) {
+        reached.or(bitmap);
+        continue;
+      }
+      reached.set(position);
+      match self.kind(position) {
+        Some(ObjectKind::Commit) => {
+          let commit = odb.read_commit(&oid)?;
+          pending.push(*commit.tree());
+          pending.extend_from_slice(commit.parents());
+        }
+        Some(ObjectKind::Tree) => {
+          for entry in odb.read_tree(&oid)?.entries() {
+            // Submodule commits are in another repository
+            if entry.mode() != FileMode::GitLink {
+              pending.push(*entry.oid());
+            }
+          }
+        }
+        Some(ObjectKind::Tag) => pending.push(*odb.read_tag(&oid)?.object()),
+        Some(ObjectKind::Blob) | None => {}
+      }
+    }
+    Ok(Some(reached))
+  }
+
+  /// The objects reachable from `wants` but not from `haves`, or `None`
+  /// if the bitmaps can't tell
+  fn objects_to_send(
+    &self,
+    odb: &Odb,
+    wants: &[OID],
+    haves: &[OID],
+  ) -> Result<Option<Bitset>, OdbError> {
+    // What they have that isn't here at all has nothing to leave out
+    let mut known = Vec::new();
+    for have in haves {
+      if self.positions.contains_key(have) {
+        known.push(*have);
+      } else if odb.contains(have)? {
+        return Ok(None);
+      }
+    }
+    let (mut wanted, had) = match (self.reachable(odb, wants)?, self.reachable(odb, &known)?) {
+      (Some(wanted), Some(had)) => (wanted, had),
+      _ => return Ok(None),
+    };
+    wanted.and_not(&had);
+    Ok(Some(wanted))
+  }
+
+  fn as_bytes(&self, checksum: &OID) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(SIGNATURE);
+    data.extend_from_slice(&1u16.to_be_bytes());
+    data.extend_from_slice(&FULL_DAG.to_be_bytes());
+    data.extend_from_slice(&(self.bitmaps.len() as u32).to_be_bytes());
+    data.extend_from_slice(checksum.as_bytes());
+    for kind in &self.kinds {
+      write_ewah(kind, &mut data);
+    }
+    let mut sorted = self.oids.clone();
+    sorted.sort_unstable();
+    let mut commits: Vec<&OID> = self.bitmaps.keys().collect();
+    commits.sort_unstable();
+    for commit in commits {
+      // The commits are always in the pack
+      let position = sorted.binary_search(commit).unwrap() as u32;
+      data.extend_from_slice(&position.to_be_bytes());
+      // Not XORed with another entry, and no flags
+      data.extend_from_slice(&[0, 0]);
+      write_ewah(&self.bitmaps[commit], &mut data);
+    }
+    let trailer = Sha1::digest(&data);
+    data.extend_from_slice(&trailer);
+    data
+  }
+}
+
+/// The objects someone who has `haves` needs to get `wants`, answered with
+/// the bitmap of a pack if there is one that has everything involved
+pub(crate) fn objects_to_send(
+  odb: &Odb,
+  wants: &[OID],
+  haves: &[OID],
+) -> Result<Option<Vec<OID>>, OdbError> {
+  let index = match odb.bitmap()? {
+    Some(index) => index,
+    None => return Ok(None),
+  };
+  let _region = Trace2::region("pack-objects", "bitmap");
+  Ok(
+    index
+      .objects_to_send(odb, wants, haves)?
+      .map(|bitset| bitset.ones().map(|i| index.oids[i as usize]).collect()),
+  )
+}
+
+impl Repository {
+  /// How many objects someone who has `haves` needs to get `wants`, like
+  /// `git rev-list --count --objects --use-bitmap-index`. It's answered
+  /// from the bitmap of a pack without walking all of history, `None` is
+  /// returned if there is no bitmap that has everything involved.
+  pub fn count_with_bitmap(
+    &self,
+    wants: &[OID],
+    haves: &[OID],
+  ) -> Result<Option<usize>, BitmapError> {
+    let index = match self.odb().bitmap()? {
+      Some(index) => index,
+      None => return Ok(None),
+    };
+    Ok(
+      index
+        .objects_to_send(self.odb(), wants, haves)?
+        .map(|bitset| bitset.count()),
+    )
+  }
+
+  /// Pack everything reachable from `HEAD` and the refs into a new pack
+  /// with a `.bitmap` next to it, like `git repack -a -b`, returning the
+  /// path of the pack. Every ref gets a bitmap, as does every hundredth
+  /// commit so the ones in between don't have to walk far. Other packs and
+  /// loose objects are left as they are.
+  ///
+  /// The objects written and stored are reported to the
+  /// [`Progress`][crate::Progress] of the repository, and if it's cancelled
+  /// nothing is stored unless the pack was already, which is left without
+  /// a `.bitmap`.
+  pub fn repack_with_bitmap(&self) -> Result<PathBuf, BitmapError> {
+    // Bitmaps are of the objects as they're stored
+    if let Cow::Owned(repo) = self.original_objects() {
+      return repo.repack_with_bitmap();
+    }
+    let _region = Trace2::region("repack", "write_bitmaps");
+    let odb = self.odb();
+    let mut tips: Vec<OID> = self
+      .refs()
+      .list("refs/")?
+      .iter()
+      .filter_map(|reference| reference.oid().copied())
+      .collect();
+    tips.extend(self.refs().resolve("HEAD")?);
+    tips.sort();
+    tips.dedup();
+    let (pack, checksum) = transport::write_pack_for(self, &tips, &[], false, Vec::new())?;
+    // What's stored comes back in the order it's in the pack, which is
+    // the order the bits are in
+    let objects = odb.write_pack(&pack)?;
+    let path = odb
+      .path()
+      .join("pack")
+      .join(format!("pack-{}.pack", checksum));
+    let mut index = BitmapIndex::new(objects);
+    for (i, oid) in index.oids.iter().enumerate() {
+      let kind = odb.object_kind(oid)?;
+      let position = KINDS.iter().position(|k| *k == kind).unwrap();
+      index.kinds[position].set(i as u32);
+    }
+
+    // What the tips peel to are the commits that always get a bitmap.
+    // History is walked so the oldest commits get theirs first and the
+    // newer ones can stop at them.
+    let mut walk = self.rev_walk();
+    let mut selected = HashSet::new();
+    for tip in tips {
+      let mut oid = tip;
+      while let Ok(tag) = odb.read_tag(&oid) {
+        oid = *tag.object();
+      }
+      if odb.object_kind(&oid)? == ObjectKind::Commit {
+        walk.push(&oid)?;
+        selected.insert(oid);
+      }
+    }
+    let commits = walk.collect::<Result<Vec<OID>, _>>()?;
+    selected.extend(commits.iter().step_by(COMMIT_INTERVAL).copied());
+    let selected = commits
+      .iter()
+      .rev()
+      .filter(|commit| selected.contains(commit));
+    for &commit in selected {
+      if odb.progress().is_cancelled() {
+        return Err(OdbError::Cancelled.into());
+      }
+      let bitset = index
+        .reachable(odb, &[commit])?
+        .ok_or(BitmapError::Incomplete(commit))?;
+      index.bitmaps.insert(commit, bitset);
+    }
+    Trace2::data("repack", "bitmaps", index.bitmaps.len());
+
+    let bitmap_path = path.with_extension("bitmap");
+    let tmp_path = odb.path().join("pack").join(cleanup::temp_name("bitmap"));
+    let result = fs::write(&tmp_path, index.as_bytes(&checksum))
+      .and_then(|_| fs::rename(&tmp_path, &bitmap_path));
+    if let Err(e) = result {
+      let _ = fs::remove_file(&tmp_path);
+      return Err(e.into());
+    }
+    Ok(path)
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to the bitmaps of packs
+pub enum BitmapError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  RevWalk(#[from] RevWalkError),
+  #[error("{0}")]
+  Transport(#[from] TransportError),
+  #[error("malformed bitmap: {0}")]
+  Malformed(&'static str),
+  #[error("bitmap checksum does not match its contents")]
+  ChecksumMismatch,
+  #[error("bitmap version {0} is not supported")]
+  UnsupportedVersion(u16),
+  #[error("the pack doesn't have everything reachable from {0}")]
+  Incomplete(OID),
+}
+
+#[test]
+fn ewah() {
+  let mut sparse = Bitset::default();
+  for i in [0, 3, 64, 1000, 4095] {
+    sparse.set(i);
+  }
+  let mut runs = Bitset::default();
+  for i in (0..640).chain(700..701).chain(1280..2000) {
+    runs.set(i);
+  }
+  for bitset in [Bitset::default(), sparse, runs] {
+    let mut data = Vec::new();
+    write_ewah(&bitset, &mut data);
+    data.extend_from_slice(b"rest");
+    let (read, len) = read_ewah(&data).unwrap();
+    assert_eq!(data.len() - 4, len);
+    assert_eq!(
+      bitset.ones().collect::<Vec<_>>(),
+      read.ones().collect::<Vec<_>>()
+    );
+    assert_eq!(bitset.len(), read.len());
+  }
+  // A run past the size in the header
+  let data = [0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0];
+  assert!(read_ewah(&data).is_err());
+}
+
+#[test]
+fn write_and_use_bitmaps() {
+  use crate::{Blob, Commit, Signature, Tag, Time, Tree, TreeEntry};
+  use std::process::Command;
+  let tmp_dir = tempdir::TempDir::new("bitmap_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let odb = repo.odb();
+  let signature = |i: i64| {
+    Signature::new(
+      "A U Thor",
+      "author@example.com",
+      Time::new(1_234_567_890 + i, 0),
+    )
+  };
+  let shared = odb.write_blob(&Blob::new("shared\n")).unwrap();
+  let mut commits = Vec::new();
+  for i in 0..120 {
+    let blob = odb.write_blob(&Blob::new(format!("{}\n", i))).unwrap();
+    let tree = odb
+      .write_tree(&Tree::new(vec![
+        TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob),
+        TreeEntry::new(FileMode::NonExecutableFile, "shared.txt", shared),
+      ]))
+      .unwrap();
+    let commit = Commit::new(
+      tree,
+      commits.last().copied().into_iter().collect(),
+      signature(i),
+      signature(i),
+      format!("commit {}\n", i),
+    );
+    commits.push(odb.write_commit(&commit).unwrap());
+  }
+  let tag = Tag::new(commits[50], ObjectKind::Commit, "v1", signature(0), "v1\n");
+  let tag = odb.write_tag(&tag).unwrap();
+  repo
+    .refs()
+    .write("refs/heads/master", &commits[119])
+    .unwrap();
+  repo.refs().write("refs/tags/v1", &tag).unwrap();
+
+  let sorted = |mut objects: Vec<OID>| {
+    objects.sort();
+    objects
+  };
+  let walked = |wants: &[OID], haves: &[OID]| {
+    sorted(
+      transport::objects_to_send(&repo, wants, haves)
+        .unwrap()
+        .into_iter()
+        .map(|(oid, ..)| oid)
+        .collect(),
+    )
+  };
+  let cases = [
+    (vec![commits[119]], vec![]),
+    (vec![commits[119]], vec![commits[50]]),
+    (vec![commits[119], tag], vec![commits[100]]),
+    (vec![tag], vec![]),
+    (vec![commits[70]], vec![commits[30], tag]),
+  ];
+  let expected: Vec<Vec<OID>> = cases
+    .iter()
+    .map(|(wants, haves)| walked(wants, haves))
+    .collect();
+  assert_eq!(None, repo.count_with_bitmap(&cases[0].0, &[]).unwrap());
+
+  let pack = repo.repack_with_bitmap().unwrap();
+  assert!(pack.with_extension("bitmap").is_file());
+  let repo = Repository::open(tmp_dir.path()).unwrap();
+  let odb = repo.odb();
+  for ((wants, haves), expected) in cases.iter().zip(&expected) {
+    let objects = objects_to_send(odb, wants, haves).unwrap().unwrap();
+    assert_eq!(*expected, sorted(objects));
+    assert_eq!(
+      Some(expected.len()),
+      repo.count_with_bitmap(wants, haves).unwrap()
+    );
+  }
+  // The tips, every hundredth commit, and nothing else have a bitmap
+  let index = odb.bitmap().unwrap().unwrap();
+  let mut bitmapped: Vec<OID> = index.bitmaps.keys().copied().collect();
+  bitmapped.sort();
+  assert_eq!(
+    sorted(vec![commits[119], commits[50], commits[19]]),
+    bitmapped
+  );
+
+  // A commit that isn't in the pack can't be answered
+  let tree = odb.read_commit(&commits[119]).unwrap();
+  let loose = Commit::new(
+    *tree.tree(),
+    vec![commits[119]],
+    tree.author().clone(),
+    tree.committer().clone(),
+    "loose\n",
+  );
+  let loose = odb.write_commit(&loose).unwrap();
+  assert_eq!(None, repo.count_with_bitmap(&[loose], &[]).unwrap());
+  assert_eq!(
+    None,
+    repo.count_with_bitmap(&[commits[5]], &[loose]).unwrap()
+  );
+
+  if crate::transport::http::have_git() {
+    let git = |args: &[&str]| {
+      let output = Command::new("git")
+        .args(args)
+        .current_dir(tmp_dir.path())
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .output()
+        .unwrap();
+      assert!(output.status.success(), "{:?}", output);
+      String::from_utf8(output.stdout).unwrap()
+    };
+    git(&["rev-list", "--test-bitmap", "master"]);
+    // And the other way around
+    git(&["repack", "-adbq"]);
+    let count = git(&["rev-list", "--count", "--objects", "master", "^v1"]);
+    let repo = Repository::open(tmp_dir.path()).unwrap();
+    assert_eq!(
+      Some(count.trim().parse().unwrap()),
+      repo.count_with_bitmap(&[commits[119]], &[tag]).unwrap()
+    );
+  }
+}
@@ -0,0 +1,496 @@
+use crate::{
+  blob_diff::{diff_lines, lines},
+  detect_renames, diff_trees, ChangeKind, DiffError, DiffOptions, Odb, OdbError, RefError,
+  RenameOptions, Repository, ShallowError, Trace2, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  collections::{hash_map::Entry, BinaryHeap, HashMap},
+  ops::{Range, RangeInclusive},
+};
+use thiserror::Error;
+
+/// Options for [`Repository::blame`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlameOptions {
+  /// The commit to start from, `HEAD` by default
+  pub commit: Option<OID>,
+  /// Only blame these lines, starting at 1 and including the end, like
+  /// `git blame -L {start},{end}`
+  pub lines: Option<RangeInclusive<usize>>,
+  /// Lines that only differ in whitespace count as unchanged, like `git
+  /// blame -w`
+  pub ignore_whitespace: bool,
+  /// Follow the file to where it was before it was renamed, which git
+  /// always does. Defaults to `true`.
+  pub follow_renames: bool,
+  /// How lines are matched up between versions of the file
+  pub diff: DiffOptions,
+}
+
+impl Default for BlameOptions {
+  fn default() -> Self {
+    Self {
+      commit: None,
+      lines: None,
+      ignore_whitespace: false,
+      follow_renames: true,
+      diff: DiffOptions::default(),
+    }
+  }
+}
+
+/// Consecutive lines of a file that came from the same commit
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BlameHunk {
+  /// The commit that introduced the lines
+  pub commit: OID,
+  /// The path of the file in that commit
+  pub path: BString,
+  /// The first line in the file of that commit, starting at 1
+  pub orig_start: usize,
+  /// The first line in the file that was blamed, starting at 1
+  pub final_start: usize,
+  /// How many lines there are
+  pub len: usize,
+  /// Whether the commit is where history ends, either a root commit or a
+  /// shallow one, so the lines might be older than it
+  pub boundary: bool,
+}
+
+/// What [`Repository::blame`] found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blame {
+  /// The hunks in the order of the lines in the file
+  pub hunks: Vec<BlameHunk>,
+  /// The lines of the file that was blamed, each with its `\n`
+  pub lines: Vec<BString>,
+}
+
+impl Blame {
+  /// The hunk the line `line` of the file is in, starting at 1
+  pub fn hunk_for_line(&self, line: usize) -> Option<&BlameHunk> {
+    self
+      .hunks
+      .iter()
+      .find(|hunk| (hunk.final_start..hunk.final_start + hunk.len).contains(&line))
+  }
+}
+
+/// Lines still to blame on a commit, as where they are in the final file
+/// and in the version of the file in the commit, starting at 0
+#[derive(Debug, Clone, Copy)]
+struct Lines {
+  start: usize,
+  suspect_start: usize,
+  len: usize,
+}
+
+/// A version of the file lines might have come from
+struct Suspect {
+  blob: OID,
+  lines: Vec<Lines>,
+}
+
+/// The next commit to look at is the newest one
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Queued {
+  time: i64,
+  commit: OID,
+  path: BString,
+}
+
+/// The versions of the file left to look at
+#[derive(Default)]
+struct Suspects {
+  suspects: HashMap<(OID, BString), Suspect>,
+  queue: BinaryHeap<Queued>,
+}
+
+impl Suspects {
+  /// Add `lines` to blame on the file at `path` in `commit`, which is
+  /// `blob` there
+  fn add(
+    &mut self,
+    odb: &Odb,
+    commit: OID,
+    path: BString,
+    blob: OID,
+    lines: Vec<Lines>,
+  ) -> Result<(), OdbError> {
+    match self.suspects.entry((commit, path.clone())) {
+      Entry::Occupied(mut suspect) => suspect.get_mut().lines.extend(lines),
+      Entry::Vacant(suspect) => {
+        suspect.insert(Suspect { blob, lines });
+        let time = odb.read_commit(&commit)?.committer().time.seconds;
+        self.queue.push(Queued { time, commit, path });
+      }
+    }
+    Ok(())
+  }
+
+  /// The version of the newest commit left
+  fn pop(&mut self) -> Option<(OID, BString, Suspect)> {
+    let Queued { commit, path, .. } = self.queue.pop()?;
+    let suspect = self.suspects.remove(&(commit, path.clone()))?;
+    Some((commit, path, suspect))
+  }
+}
+
+impl Repository {
+  /// Find the commit each line of the file at `path` came from, like `git
+  /// blame`. History is walked from the newest commit back, handing the
+  /// lines a commit didn't change to its parents, and the lines left are
+  /// blamed on the commit. For a merge every parent gets the lines they
+  /// have in turn, starting with the first.
+  pub fn blame(&self, path: impl AsRef<[u8]>, options: &BlameOptions) -> Result<Blame, BlameError> {
+    let _region = Trace2::region("blame", "blame");
+    let path = path.as_ref().as_bstr();
+    let odb = self.odb();
+    let start = match options.commit {
+      Some(commit) => commit,
+      None => self.refs().resolve("HEAD")?.ok_or(BlameError::NoCommit)?,
+    };
+    let blob = blob_at(odb, odb.read_commit(&start)?.tree(), path)?
+      .ok_or_else(|| BlameError::NotFound(path.into()))?;
+    let contents = odb.read_blob(&blob)?.contents().to_vec();
+    let file_lines: Vec<BString> = lines(&contents).into_iter().map(BString::from).collect();
+    let range = match &options.lines {
+      Some(range) => {
+        let (first, last) = (*range.start(), *range.end());
+        if first == 0 || first > last || last > file_lines.len() {
+          return Err(BlameError::InvalidRange(first, last, file_lines.len()));
+        }
+        first - 1..last
+      }
+      None => 0..file_lines.len(),
+    };
+
+    let shallow = self.shallow_commits()?;
+    let mut suspects = Suspects::default();
+    let mut hunks = Vec::new();
+    if !range.is_empty() {
+      let lines = vec![Lines {
+        start: range.start,
+        suspect_start: range.start,
+        len: range.len(),
+      }];
+      suspects.add(odb, start, path.into(), blob, lines)?;
+    }
+
+    while let Some((commit, path, Suspect { blob, mut lines })) = suspects.pop() {
+      let parents = odb.read_commit(&commit)?.parents().to_vec();
+      let boundary = parents.is_empty() || shallow.contains(&commit);
+      if !boundary {
+        let tree = *odb.read_commit(&commit)?.tree();
+        let suspect_lines = self.blame_lines(&blob, options)?;
+        for parent in parents {
+          if lines.is_empty() {
+            break;
+          }
+          let parent_tree = *odb.read_commit(&parent)?.tree();
+          let (parent_path, parent_blob) =
+            match self.blame_origin(&parent_tree, &tree, path.as_bstr(), options)? {
+              Some(origin) => origin,
+              None => continue,
+            };
+          // A file that didn't change hands everything over
+          if parent_blob == blob {
+            let lines = std::mem::take(&mut lines);
+            suspects.add(odb, parent, parent_path, parent_blob, lines)?;
+            break;
+          }
+          let parent_lines = self.blame_lines(&parent_blob, options)?;
+          let old: Vec<&[u8]> = parent_lines.iter().map(|line| &line[..]).collect();
+          let new: Vec<&[u8]> = suspect_lines.iter().map(|line| &line[..]).collect();
+          let (passed, kept) = split_unchanged(&lines, &diff_lines(&old, &new, &options.diff));
+          lines = kept;
+          if !passed.is_empty() {
+            suspects.add(odb, parent, parent_path, parent_blob, passed)?;
+          }
+        }
+      }
+      hunks.extend(lines.into_iter().map(|lines| BlameHunk {
+        commit,
+        path: path.clone(),
+        orig_start: lines.suspect_start + 1,
+        final_start: lines.start + 1,
+        len: lines.len,
+        boundary,
+      }));
+    }
+
+    // Lines that were split up on the way but came from the same place go
+    // back together
+    hunks.sort_by_key(|hunk| hunk.final_start);
+    let mut merged: Vec<BlameHunk> = Vec::with_capacity(hunks.len());
+    for hunk in hunks {
+      match merged.last_mut() {
+        Some(last)
+          if last.commit == hunk.commit
+            && last.path == hunk.path
+            && last.final_start + last.len == hunk.final_start
+            && last.orig_start + last.len == hunk.orig_start =>
+        {
+          last.len += hunk.len
+        }
+        _ => merged.push(hunk),
+      }
+    }
+    Ok(Blame {
+      hunks: merged,
+      lines: file_lines,
+    })
+  }
+
+  /// The lines of `blob` as they're compared for blame, without any
+  /// whitespace if that's ignored
+  fn blame_lines(&self, blob: &OID, options: &BlameOptions) -> Result<Vec<Vec<u8>>, BlameError> {
+    let contents = self.odb().read_blob(blob)?.contents().to_vec();
+    Ok(
+      lines(&contents)
+        .into_iter()
+        .map(|line| match options.ignore_whitespace {
+          true => line
+            .iter()
+            .filter(|c| !c.is_ascii_whitespace())
+            .copied()
+            .collect(),
+          false => line.to_vec(),
+        })
+        .collect(),
+    )
+  }
+
+  /// Where the file at `path` in `tree` was in the parent's `parent_tree`,
+  /// which is another path if it was renamed
+  fn blame_origin(
+    &self,
+    parent_tree: &OID,
+    tree: &OID,
+    path: &BStr,
+    options: &BlameOptions,
+  ) -> Result<Option<(BString, OID)>, BlameError> {
+    let odb = self.odb();
+    if let Some(blob) = blob_at(odb, parent_tree, path)? {
+      return Ok(Some((path.into(), blob)));
+    }
+    if !options.follow_renames {
+      return Ok(None);
+    }
+    let changes = diff_trees(odb, Some(parent_tree), Some(tree))?;
+    let changes = detect_renames(odb, changes, &RenameOptions::default())?;
+    Ok(
+      changes
+        .into_iter()
+        .find_map(|change| match change.into_sides() {
+          (ChangeKind::Renamed(_), Some(old), Some(new)) if new.path == path => {
+            Some((old.path, old.oid))
+          }
+          _ => None,
+        }),
+    )
+  }
+}
+
+/// The blob at `path` in `tree`, `None` if there isn't a file there
+fn blob_at(odb: &Odb, tree: &OID, path: &BStr) -> Result<Option<OID>, OdbError> {
+  let mut tree = odb.read_tree(tree)?;
+  let mut names = path.split_str("/").peekable();
+  while let Some(name) = names.next() {
+    let entry = match tree.get(name) {
+      Some(entry) => entry.clone(),
+      None => return Ok(None),
+    };
+    match names.peek() {
+      None if entry.mode().is_blob() => return Ok(Some(*entry.oid())),
+      Some(_) if entry.mode().is_tree() => tree = odb.read_tree(entry.oid())?,
+      _ => return Ok(None),
+    }
+  }
+  Ok(None)
+}
+
+/// Split `lines` of a suspect into the ones `changes` from its parent's
+/// version didn't touch, moved to where they are in the parent's version,
+/// and the rest
+fn split_unchanged(
+  lines: &[Lines],
+  changes: &[(Range<usize>, Range<usize>)],
+) -> (Vec<Lines>, Vec<Lines>) {
+  // The stretches of lines that are the same on both sides, as where they
+  // start in the suspect, where they start in the parent, and how long
+  // they are
+  let mut same = Vec::new();
+  let (mut old, mut new) = (0, 0);
+  for (removed, added) in changes {
+    if added.start > new {
+      same.push((new, old, added.start - new));
+    }
+    old = removed.end;
+    new = added.end;
+  }
+  same.push((new, old, usize::MAX - new));
+
+  let (mut passed, mut kept) = (Vec::new(), Vec::new());
+  for lines in lines {
+    let end = lines.suspect_start + lines.len;
+    let mut at = lines.suspect_start;
+    for &(start, parent_start, len) in &same {
+      let (from, to) = (start.max(at), (start + len).min(end));
+      if from >= to {
+        continue;
+      }
+      if from > at {
+        kept.push(Lines {
+          start: lines.start + at - lines.suspect_start,
+          suspect_start: at,
+          len: from - at,
+        });
+      }
+      passed.push(Lines {
+        start: lines.start + from - lines.suspect_start,
+        suspect_start: parent_start + from - start,
+        len: to - from,
+      });
+      at = to;
+    }
+    if at < end {
+      kept.push(Lines {
+        start: lines.start + at - lines.suspect_start,
+        suspect_start: at,
+        len: end - at,
+      });
+    }
+  }
+  (passed, kept)
+}
+
+#[derive(Error, Debug)]
+/// Errors related to blaming the lines of a file
+pub enum BlameError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Diff(#[from] DiffError),
+  #[error("{0}")]
+  Shallow(#[from] ShallowError),
+  #[error("HEAD doesn't point at a commit yet")]
+  NoCommit,
+  #[error("no such path {0} in the commit")]
+  NotFound(BString),
+  #[error("lines {0} to {1} are out of range for a file with {2} lines")]
+  InvalidRange(usize, usize, usize),
+}
+
+#[test]
+fn blame() {
+  use crate::{diff::write_tree, Commit, FileMode, Signature, Time};
+  let tmp_dir = tempdir::TempDir::new("blame_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let odb = repo.odb();
+  let mut history = Vec::new();
+  let mut commit = |files: &[(&str, FileMode, &str)], message: &str| {
+    let signature = Signature::new(
+      "A U Thor",
+      "author@example.com",
+      Time::new(1_234_567_890 + history.len() as i64, 0),
+    );
+    let tree = write_tree(odb, files);
+    let parents = history.last().copied().into_iter().collect();
+    let commit = Commit::new(tree, parents, signature.clone(), signature, message);
+    let oid = odb.write_commit(&commit).unwrap();
+    history.push(oid);
+    oid
+  };
+  let file = FileMode::NonExecutableFile;
+  let first = commit(&[("a.txt", file, "one\ntwo\nthree\nfour\n")], "first\n");
+  let second = commit(
+    &[("a.txt", file, "one\n2\nthree\nfour\nfive\n")],
+    "second\n",
+  );
+  let third = commit(
+    &[("b.txt", file, "one\n2\nthree\nfour\nfive\n")],
+    "rename\n",
+  );
+  let fourth = commit(
+    &[("b.txt", file, "one\n2\n  three\nfour\nfive\n")],
+    "indent\n",
+  );
+  repo.refs().write("refs/heads/master", &fourth).unwrap();
+
+  let blame = repo.blame("b.txt", &BlameOptions::default()).unwrap();
+  let summary: Vec<(OID, &str, usize, usize, usize)> = blame
+    .hunks
+    .iter()
+    .map(|hunk| {
+      (
+        hunk.commit,
+        hunk.path.to_str().unwrap(),
+        hunk.orig_start,
+        hunk.final_start,
+        hunk.len,
+      )
+    })
+    .collect();
+  assert_eq!(
+    vec![
+      (first, "a.txt", 1, 1, 1),
+      (second, "a.txt", 2, 2, 1),
+      (fourth, "b.txt", 3, 3, 1),
+      (first, "a.txt", 4, 4, 1),
+      (second, "a.txt", 5, 5, 1),
+    ],
+    summary
+  );
+  assert!(blame.hunks[0].boundary && !blame.hunks[1].boundary);
+  assert_eq!("  three\n", blame.lines[2]);
+  assert_eq!(Some(&blame.hunks[4]), blame.hunk_for_line(5));
+  let blamed: Vec<String> = (1..=5)
+    .map(|line| blame.hunk_for_line(line).unwrap().commit.as_hex())
+    .collect();
+
+  // Only some of the lines, where changing the indentation doesn't count
+  let options = BlameOptions {
+    lines: Some(2..=3),
+    ignore_whitespace: true,
+    ..BlameOptions::default()
+  };
+  let blame = repo.blame("b.txt", &options).unwrap();
+  assert_eq!(
+    vec![(second, 2, 1), (first, 3, 1)],
+    blame
+      .hunks
+      .iter()
+      .map(|hunk| (hunk.commit, hunk.final_start, hunk.len))
+      .collect::<Vec<_>>()
+  );
+  // Without following renames everything comes from the rename
+  let options = BlameOptions {
+    follow_renames: false,
+    ..BlameOptions::default()
+  };
+  let blame = repo.blame("b.txt", &options).unwrap();
+  assert_eq!(third, blame.hunks[0].commit);
+
+  // Git blames the same lines on the same commits
+  if crate::transport::http::have_git() {
+    let output = std::process::Command::new("git")
+      .args(["blame", "--porcelain", "HEAD", "--", "b.txt"])
+      .current_dir(tmp_dir.path())
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .output()
+      .unwrap();
+    assert!(output.status.success());
+    let expected: Vec<String> = output
+      .stdout
+      .lines()
+      .filter(|line| line.len() > 40 && line[..40].iter().all(u8::is_ascii_hexdigit))
+      .map(|line| line[..40].to_str_lossy().into_owned())
+      .collect();
+    assert_eq!(expected, blamed);
+  }
+}
@@ -1,18 +1,26 @@
-use crate::OID;
-use bstr::{BStr, BString, ByteSlice};
-use std::{fs, io, path::Path};
+use crate::{object, small::SmallBytes, Object, ObjectKind, OID};
+use bstr::BStr;
+use std::{
+  fs,
+  io::{self, Write},
+  path::Path,
+};
 
 /// A [`Blob`] is a git object that represents a file in a git directory. For
 /// instance all of the bytes that makes up the file that these docs for this
 /// struct reside in, would be stored as a [`Blob`] on disk.
-#[derive(Debug, PartialEq, Eq)]
-pub struct Blob(BString);
+///
+/// Small contents are stored inline in the [`Blob`] itself rather than in a
+/// separate allocation. Larger ones are shared between clones of the
+/// [`Blob`], so cloning one is cheap no matter how big it is, and only copied
+/// once a clone is changed with [`Blob::contents_mut`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blob(SmallBytes);
 
 impl Blob {
   /// Create a [`Blob`] given a set of bytes
   pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
-    let bytes: Vec<u8> = bytes.into();
-    Self(bytes.into())
+    Self(bytes.into().into())
   }
 
   /// Turn the [`Blob`] into the on disk representation stored in Object
@@ -30,18 +38,12 @@ impl Blob {
   /// Note that a [`Blob`] is stored on disk with zlib for compression and
   /// that this only represents the uncompressed form
   pub fn as_bytes(&self) -> Vec<u8> {
-    [
-      b"blob ",
-      self.0.len().to_string().as_bytes(),
-      b"\0",
-      &self.0.as_bytes(),
-    ]
-    .concat()
+    object::encode(self)
   }
 
   /// Get the [`OID`] for the [`Blob`]
   pub fn id(&self) -> OID {
-    self.into()
+    OID::for_object(self)
   }
 
   /// Get the size of the contents of the [`Blob`].
@@ -51,7 +53,13 @@ impl Blob {
 
   /// Access the contents of the [`Blob`].
   pub fn contents(&self) -> &BStr {
-    &self.0.as_bstr()
+    self.0.as_bstr()
+  }
+
+  /// Change the contents of the [`Blob`]. If they're shared with a clone
+  /// they're copied first, so the clone keeps the old contents.
+  pub fn contents_mut(&mut self) -> &mut Vec<u8> {
+    self.0.make_mut()
   }
 
   /// Turn a file into a [`Blob`]. This is a convenience function to handle
@@ -62,6 +70,16 @@ impl Blob {
   }
 }
 
+impl Object for Blob {
+  fn kind(&self) -> ObjectKind {
+    ObjectKind::Blob
+  }
+
+  fn serialize_into(&self, writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(&self.0)
+  }
+}
+
 #[test]
 fn as_bytes() {
   let blob = Blob::new("this is a test".as_bytes());
@@ -90,6 +108,16 @@ fn contents() {
   assert_eq!("this is a test", contents);
 }
 #[test]
+fn contents_mut() {
+  let mut blob = Blob::new(vec![b'a'; 100]);
+  let clone = blob.clone();
+  blob.contents_mut().extend_from_slice(b"\n");
+  assert_eq!(101, blob.size());
+  assert_eq!(100, clone.size());
+  assert_ne!(blob.id(), clone.id());
+  assert_eq!(Blob::new(vec![b'a'; 100]).id(), clone.id());
+}
+#[test]
 fn from_file() {
   let tmp_dir = tempdir::TempDir::new("blob_test").unwrap();
   let file_path = tmp_dir.path().join("from_file_test.txt");
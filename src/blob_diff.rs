@@ -0,0 +1,1255 @@
+use crate::{Config, ConfigError};
+use bstr::BString;
+use std::{collections::HashMap, convert::TryFrom, ops::Range};
+
+/// How [`diff_blobs`] works out which lines changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DiffAlgorithm {
+  /// The Myers algorithm, which finds the smallest set of changes or close
+  /// to it for very large diffs, see [`DiffOptions::minimal`]. This is what
+  /// git uses by default.
+  #[default]
+  Myers,
+  /// Lines that occur the least often are matched up first, which keeps
+  /// blocks of code together rather than matching lines like `}` between
+  /// unrelated blocks. Like `git diff --histogram`.
+  Histogram,
+}
+
+/// Options controlling how [`diff_blobs`] compares two blobs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffOptions {
+  /// How many unchanged lines are shown around each change. Changes closer
+  /// than twice this are put in the same [`Hunk`]. Defaults to 3.
+  pub context: usize,
+  /// Which [`DiffAlgorithm`] to use
+  pub algorithm: DiffAlgorithm,
+  /// Whether the Myers algorithm always finds the smallest set of changes,
+  /// like `git diff --minimal`. Otherwise it settles for a slightly larger
+  /// one when the shortest would take a long time to find. Defaults to
+  /// `false`.
+  pub minimal: bool,
+  /// Whether changes that could be shown in more than one place are put
+  /// where the indentation of the lines around them suggests they belong,
+  /// like `diff.indentHeuristic` in git. Otherwise they're moved as far
+  /// down as they can go. Defaults to `true`.
+  pub indent_heuristic: bool,
+}
+
+impl Default for DiffOptions {
+  fn default() -> Self {
+    Self {
+      context: 3,
+      algorithm: DiffAlgorithm::default(),
+      minimal: false,
+      indent_heuristic: true,
+    }
+  }
+}
+
+impl DiffOptions {
+  /// Read the options from `diff.context`, `diff.algorithm`, and
+  /// `diff.indentHeuristic`, using the defaults for any not set
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let mut options = Self::default();
+    if let Some(context) = config.get_int("diff.context")? {
+      options.context = usize::try_from(context).map_err(|_| ConfigError::InvalidValue {
+        key: "diff.context".into(),
+        value: context.to_string().into(),
+        expected: "a positive number of lines",
+      })?;
+    }
+    if let Some(algorithm) = config.get_str("diff.algorithm")? {
+      options.algorithm = match algorithm.to_ascii_lowercase().as_str() {
+        "myers" | "default" => DiffAlgorithm::Myers,
+        "minimal" => {
+          options.minimal = true;
+          DiffAlgorithm::Myers
+        }
+        "histogram" => DiffAlgorithm::Histogram,
+        _ => {
+          return Err(ConfigError::InvalidValue {
+            key: "diff.algorithm".into(),
+            value: algorithm.into(),
+            expected: "myers, minimal, or histogram",
+          })
+        }
+      };
+    }
+    if let Some(indent_heuristic) = config.get_bool("diff.indentheuristic")? {
+      options.indent_heuristic = indent_heuristic;
+    }
+    Ok(options)
+  }
+}
+
+/// Whether a [`DiffLine`] is in both blobs or only one of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LineKind {
+  /// An unchanged line shown for context
+  Context,
+  /// A line only in the old blob
+  Removed,
+  /// A line only in the new blob
+  Added,
+}
+
+/// A single line of a [`Hunk`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DiffLine {
+  /// Which blob the line is from
+  pub kind: LineKind,
+  /// The line including its `\n`, which is missing if it's the last line
+  /// of a blob that doesn't end with a newline
+  pub content: BString,
+}
+
+/// A group of changed lines and the unchanged lines around them
+///
+/// Line numbers start at 1 and follow the same convention as the
+/// `@@ -old_start,old_len +new_start,new_len @@` header of a unified diff,
+/// where a side without any lines starts at the line before the hunk.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Hunk {
+  /// The first line of the hunk in the old blob
+  pub old_start: usize,
+  /// How many lines of the old blob the hunk covers
+  pub old_len: usize,
+  /// The first line of the hunk in the new blob
+  pub new_start: usize,
+  /// How many lines of the new blob the hunk covers
+  pub new_len: usize,
+  /// The lines of the hunk in order, with removed lines before the added
+  /// lines replacing them
+  pub lines: Vec<DiffLine>,
+}
+
+/// Compare the contents of two blobs line by line, returning the changed
+/// lines grouped into [`Hunk`]s with [`DiffOptions::context`] unchanged
+/// lines around them. Nothing is returned if the contents are the same.
+///
+/// Both algorithms work the same way as git's, so the same lines are shown
+/// as changed as `git diff` shows with the same options.
+pub fn diff_blobs(
+  old: impl AsRef<[u8]>,
+  new: impl AsRef<[u8]>,
+  options: &DiffOptions,
+) -> Vec<Hunk> {
+  let old = lines(old.as_ref());
+  let new = lines(new.as_ref());
+  let changes = diff_lines(&old, &new, options);
+  hunks(&old, &new, &changes, options.context)
+}
+
+/// A change between two lists of lines, as the ranges of lines it replaces
+/// in the old one and the lines it replaces them with in the new one
+pub(crate) type LineChange = (Range<usize>, Range<usize>);
+
+/// Compare two lists of lines like [`diff_blobs`] without grouping the
+/// changes into [`Hunk`]s, like git's `xdl_build_script`
+pub(crate) fn diff_lines(old: &[&[u8]], new: &[&[u8]], options: &DiffOptions) -> Vec<LineChange> {
+  // Lines are compared by a number given to every distinct line rather than
+  // by their bytes
+  let mut ids = HashMap::new();
+  let a = intern(&mut ids, old);
+  let b = intern(&mut ids, new);
+  let (mut removed, mut added) = (vec![false; a.len()], vec![false; b.len()]);
+  match options.algorithm {
+    DiffAlgorithm::Myers => myers(&a, &b, options.minimal, &mut removed, &mut added),
+    DiffAlgorithm::Histogram => Histogram {
+      a: &a,
+      b: &b,
+      removed: &mut removed,
+      added: &mut added,
+    }
+    .diff(0, a.len(), 0, b.len()),
+  }
+  let mut old_side = Side {
+    ids: &a,
+    lines: old,
+    changed: &mut removed,
+  };
+  let mut new_side = Side {
+    ids: &b,
+    lines: new,
+    changed: &mut added,
+  };
+  compact(&mut old_side, &mut new_side, options.indent_heuristic);
+  compact(&mut new_side, &mut old_side, options.indent_heuristic);
+
+  let mut changes = Vec::new();
+  let (mut i, mut j) = (0, 0);
+  while i < old.len() || j < new.len() {
+    let (start_i, start_j) = (i, j);
+    while i < old.len() && removed[i] {
+      i += 1;
+    }
+    while j < new.len() && added[j] {
+      j += 1;
+    }
+    if (i, j) != (start_i, start_j) {
+      changes.push((start_i..i, start_j..j));
+    } else {
+      i += 1;
+      j += 1;
+    }
+  }
+  changes
+}
+
+fn intern<'a>(ids: &mut HashMap<&'a [u8], usize>, lines: &[&'a [u8]]) -> Vec<usize> {
+  lines
+    .iter()
+    .map(|line| {
+      let next = ids.len();
+      *ids.entry(line).or_insert(next)
+    })
+    .collect()
+}
+
+/// Split bytes into lines that keep their `\n`
+pub(crate) fn lines(bytes: &[u8]) -> Vec<&[u8]> {
+  let mut lines: Vec<&[u8]> = bytes.split_inclusive(|&byte| byte == b'\n').collect();
+  if lines.last().is_some_and(|line| line.is_empty()) {
+    lines.pop();
+  }
+  lines
+}
+
+/// A group of changed lines in one side, which is empty between two
+/// unchanged lines. The groups of both sides line up one to one.
+#[derive(Clone, Copy)]
+struct Group {
+  start: usize,
+  end: usize,
+}
+
+/// One side of a diff while its changes are being moved around
+struct Side<'a> {
+  ids: &'a [usize],
+  lines: &'a [&'a [u8]],
+  changed: &'a mut [bool],
+}
+
+impl Side<'_> {
+  fn changed(&self, i: usize) -> bool {
+    self.changed.get(i).copied().unwrap_or(false)
+  }
+
+  fn first_group(&self) -> Group {
+    let mut end = 0;
+    while self.changed(end) {
+      end += 1;
+    }
+    Group { start: 0, end }
+  }
+
+  fn next_group(&self, group: &mut Group) -> bool {
+    if group.end == self.ids.len() {
+      return false;
+    }
+    group.start = group.end + 1;
+    group.end = group.start;
+    while self.changed(group.end) {
+      group.end += 1;
+    }
+    true
+  }
+
+  fn previous_group(&self, group: &mut Group) -> bool {
+    if group.start == 0 {
+      return false;
+    }
+    group.end = group.start - 1;
+    group.start = group.end;
+    while group.start > 0 && self.changed(group.start - 1) {
+      group.start -= 1;
+    }
+    true
+  }
+
+  fn slide_down(&mut self, group: &mut Group) -> bool {
+    if group.end >= self.ids.len() || self.ids[group.start] != self.ids[group.end] {
+      return false;
+    }
+    self.changed[group.start] = false;
+    self.changed[group.end] = true;
+    group.start += 1;
+    group.end += 1;
+    while self.changed(group.end) {
+      group.end += 1;
+    }
+    true
+  }
+
+  fn slide_up(&mut self, group: &mut Group) -> bool {
+    if group.start == 0 || self.ids[group.start - 1] != self.ids[group.end - 1] {
+      return false;
+    }
+    group.start -= 1;
+    group.end -= 1;
+    self.changed[group.start] = true;
+    self.changed[group.end] = false;
+    while group.start > 0 && self.changed(group.start - 1) {
+      group.start -= 1;
+    }
+    true
+  }
+}
+
+/// Move groups of changes that could be moved and still describe the same
+/// edit to where git would put them. A group lines up with a group in the
+/// other side if it can, otherwise it goes where the indent heuristic says
+/// reads best, or as far down as it can go.
+fn compact(side: &mut Side<'_>, other: &mut Side<'_>, indent_heuristic: bool) {
+  let mut group = side.first_group();
+  let mut other_group = other.first_group();
+  loop {
+    if group.end != group.start {
+      let mut earliest_end;
+      let mut matches_other;
+      loop {
+        let size = group.end - group.start;
+        matches_other = false;
+        while side.slide_up(&mut group) {
+          other.previous_group(&mut other_group);
+        }
+        earliest_end = group.end;
+        if other_group.end > other_group.start {
+          matches_other = true;
+        }
+        while side.slide_down(&mut group) {
+          other.next_group(&mut other_group);
+          if other_group.end > other_group.start {
+            matches_other = true;
+          }
+        }
+        // Sliding can join groups together, in which case it's done again
+        if size == group.end - group.start {
+          break;
+        }
+      }
+      if group.end == earliest_end {
+        // The group can't move
+      } else if matches_other {
+        while other_group.end == other_group.start {
+          side.slide_up(&mut group);
+          other.previous_group(&mut other_group);
+        }
+      } else if indent_heuristic {
+        let size = group.end - group.start;
+        let shift = earliest_end
+          .max((group.end - size).saturating_sub(1))
+          .max(group.end.saturating_sub(MAX_SLIDING));
+        let mut best: Option<(usize, SplitScore)> = None;
+        for shift in shift..=group.end {
+          let mut score = SplitScore::default();
+          score.add(&side.measure(shift));
+          score.add(&side.measure(shift - size));
+          if best.as_ref().is_none_or(|(_, best)| score.cmp(best) <= 0) {
+            best = Some((shift, score));
+          }
+        }
+        let best = best.map_or(group.end, |(shift, _)| shift);
+        while group.end > best {
+          side.slide_up(&mut group);
+          other.previous_group(&mut other_group);
+        }
+      }
+    }
+    if !side.next_group(&mut group) {
+      break;
+    }
+    other.next_group(&mut other_group);
+  }
+}
+
+/// The indent heuristic from git, which scores where a group of changes
+/// starts and ends by the indentation and blank lines around it. The
+/// weights are the ones git settled on by tuning them against a corpus of
+/// diffs that people rated.
+const MAX_INDENT: isize = 200;
+const MAX_BLANKS: isize = 20;
+const MAX_SLIDING: usize = 100;
+const START_OF_FILE_PENALTY: isize = 1;
+const END_OF_FILE_PENALTY: isize = 21;
+const TOTAL_BLANK_WEIGHT: isize = -30;
+const POST_BLANK_WEIGHT: isize = 6;
+const RELATIVE_INDENT_PENALTY: isize = -4;
+const RELATIVE_INDENT_WITH_BLANK_PENALTY: isize = 10;
+const RELATIVE_OUTDENT_PENALTY: isize = 24;
+const RELATIVE_OUTDENT_WITH_BLANK_PENALTY: isize = 17;
+const RELATIVE_DEDENT_PENALTY: isize = 23;
+const RELATIVE_DEDENT_WITH_BLANK_PENALTY: isize = 17;
+const INDENT_WEIGHT: isize = 60;
+
+/// What the lines around a place a group of changes could start or end at
+/// look like
+struct SplitMeasurement {
+  end_of_file: bool,
+  /// The indent of the line after the split, `None` for blank lines
+  indent: Option<isize>,
+  /// How many blank lines are before the split and the indent of the line
+  /// before those
+  pre_blank: isize,
+  pre_indent: Option<isize>,
+  /// How many blank lines are after the line after the split and the
+  /// indent of the line after those
+  post_blank: isize,
+  post_indent: Option<isize>,
+}
+
+#[derive(Default)]
+struct SplitScore {
+  effective_indent: isize,
+  penalty: isize,
+}
+
+impl SplitScore {
+  fn add(&mut self, m: &SplitMeasurement) {
+    if m.pre_indent.is_none() && m.pre_blank == 0 {
+      self.penalty += START_OF_FILE_PENALTY;
+    }
+    if m.end_of_file {
+      self.penalty += END_OF_FILE_PENALTY;
+    }
+    let post_blank = if m.indent.is_none() {
+      1 + m.post_blank
+    } else {
+      0
+    };
+    let total_blank = m.pre_blank + post_blank;
+    self.penalty += TOTAL_BLANK_WEIGHT * total_blank;
+    self.penalty += POST_BLANK_WEIGHT * post_blank;
+    let indent = m.indent.or(m.post_indent);
+    let any_blanks = total_blank != 0;
+    self.effective_indent += indent.unwrap_or(-1);
+    let (indent, pre_indent) = match (indent, m.pre_indent) {
+      (Some(indent), Some(pre_indent)) => (indent, pre_indent),
+      _ => return,
+    };
+    self.penalty += match indent.cmp(&pre_indent) {
+      std::cmp::Ordering::Greater if any_blanks => RELATIVE_INDENT_WITH_BLANK_PENALTY,
+      std::cmp::Ordering::Greater => RELATIVE_INDENT_PENALTY,
+      std::cmp::Ordering::Equal => 0,
+      std::cmp::Ordering::Less => {
+        let outdent = m.post_indent.is_some_and(|post| post > indent);
+        match (outdent, any_blanks) {
+          (true, true) => RELATIVE_OUTDENT_WITH_BLANK_PENALTY,
+          (true, false) => RELATIVE_OUTDENT_PENALTY,
+          (false, true) => RELATIVE_DEDENT_WITH_BLANK_PENALTY,
+          (false, false) => RELATIVE_DEDENT_PENALTY,
+        }
+      }
+    };
+  }
+
+  /// Less than zero if `self` is the better place to split
+  fn cmp(&self, other: &Self) -> isize {
+    let indents = (self.effective_indent > other.effective_indent) as isize
+      - (self.effective_indent < other.effective_indent) as isize;
+    INDENT_WEIGHT * indents + (self.penalty - other.penalty)
+  }
+}
+
+impl Side<'_> {
+  /// The width of the whitespace a line starts with, with tabs going to the
+  /// next multiple of 8, or `None` if the line is blank
+  fn indent(&self, i: usize) -> Option<isize> {
+    let mut indent = 0;
+    for &byte in self.lines[i] {
+      if !byte.is_ascii_whitespace() && byte != 0x0b {
+        return Some(indent);
+      }
+      match byte {
+        b' ' => indent += 1,
+        b'\t' => indent += 8 - indent % 8,
+        _ => {}
+      }
+      if indent >= MAX_INDENT {
+        return Some(MAX_INDENT);
+      }
+    }
+    None
+  }
+
+  fn measure(&self, split: usize) -> SplitMeasurement {
+    let len = self.lines.len();
+    let mut m = SplitMeasurement {
+      end_of_file: split >= len,
+      indent: if split >= len {
+        None
+      } else {
+        self.indent(split)
+      },
+      pre_blank: 0,
+      pre_indent: None,
+      post_blank: 0,
+      post_indent: None,
+    };
+    for i in (0..split.min(len)).rev() {
+      m.pre_indent = self.indent(i);
+      if m.pre_indent.is_some() {
+        break;
+      }
+      m.pre_blank += 1;
+      if m.pre_blank == MAX_BLANKS {
+        m.pre_indent = Some(0);
+        break;
+      }
+    }
+    for i in split + 1..len {
+      m.post_indent = self.indent(i);
+      if m.post_indent.is_some() {
+        break;
+      }
+      m.post_blank += 1;
+      if m.post_blank == MAX_BLANKS {
+        m.post_indent = Some(0);
+        break;
+      }
+    }
+    m
+  }
+}
+
+/// Limits used by the Myers algorithm, the same as git's xdiff
+const MAX_EQ_LIMIT: usize = 1024;
+const SIMSCAN_WINDOW: usize = 100;
+const KPDIS_RUN: usize = 4;
+const MAX_COST_MIN: isize = 256;
+const SNAKE_CNT: isize = 20;
+const HEUR_MIN_COST: isize = 256;
+const K_HEUR: isize = 4;
+
+/// Myers' algorithm the way git's xdiff does it, marking the lines of `a`
+/// and `b` that changed. Matching lines at the start and end are skipped and
+/// lines that only occur in one side are marked as changed before the
+/// search. Unless `minimal` is set lines that occur many times are also
+/// left out when they're surrounded by changes, and the search gives up on
+/// the shortest path for large diffs that would take too long.
+fn myers(a: &[usize], b: &[usize], minimal: bool, removed: &mut [bool], added: &mut [bool]) {
+  let common = a.len().min(b.len());
+  let start = (0..common).take_while(|&i| a[i] == b[i]).count();
+  let end = (0..common - start)
+    .take_while(|&i| a[a.len() - 1 - i] == b[b.len() - 1 - i])
+    .count();
+  let count = |ids: &[usize]| {
+    let mut counts: HashMap<usize, usize> = HashMap::new();
+    for &id in ids {
+      *counts.entry(id).or_default() += 1;
+    }
+    counts
+  };
+  let (a_counts, b_counts) = (count(a), count(b));
+  let (a_ids, a_index) = discard(a, start..a.len() - end, &b_counts, minimal, removed);
+  let (b_ids, b_index) = discard(b, start..b.len() - end, &a_counts, minimal, added);
+  let diagonals = a_ids.len() + b_ids.len() + 3;
+  let mut search = Myers {
+    a: &a_ids,
+    b: &b_ids,
+    forward: vec![0; diagonals],
+    backward: vec![0; diagonals],
+    offset: b_ids.len() as isize + 1,
+    max_cost: bogo_sqrt(diagonals).max(MAX_COST_MIN as usize) as isize,
+    removed: vec![false; a_ids.len()],
+    added: vec![false; b_ids.len()],
+  };
+  search.compare(0, a_ids.len() as isize, 0, b_ids.len() as isize, minimal);
+  for (i, &changed) in search.removed.iter().enumerate() {
+    removed[a_index[i]] |= changed;
+  }
+  for (i, &changed) in search.added.iter().enumerate() {
+    added[b_index[i]] |= changed;
+  }
+}
+
+/// An approximate square root, the same one xdiff uses
+fn bogo_sqrt(mut n: usize) -> usize {
+  let mut root = 1;
+  while n > 0 {
+    root <<= 1;
+    n >>= 2;
+  }
+  root
+}
+
+/// Leave out the lines in `range` that don't need to be searched, marking
+/// them as changed, and return the ones that are left with their indexes
+fn discard(
+  ids: &[usize],
+  range: std::ops::Range<usize>,
+  other_counts: &HashMap<usize, usize>,
+  minimal: bool,
+  changed: &mut [bool],
+) -> (Vec<usize>, Vec<usize>) {
+  let limit = bogo_sqrt(ids.len()).min(MAX_EQ_LIMIT);
+  // 0 for lines not in the other side, 2 for ones that are in it a lot, and
+  // 1 for the rest
+  let kinds: Vec<u8> = ids[range.clone()]
+    .iter()
+    .map(|id| match other_counts.get(id).copied().unwrap_or(0) {
+      0 => 0,
+      count if count >= limit && !minimal => 2,
+      _ => 1,
+    })
+    .collect();
+  let mut kept = (Vec::new(), Vec::new());
+  for (i, &kind) in kinds.iter().enumerate() {
+    if kind == 1 || (kind == 2 && !surrounded_by_changes(&kinds, i)) {
+      kept.0.push(ids[range.start + i]);
+      kept.1.push(range.start + i);
+    } else {
+      changed[range.start + i] = true;
+    }
+  }
+  kept
+}
+
+/// Whether a line that occurs a lot in the other side is among enough
+/// lines that don't occur in it at all that it's most likely changed too
+fn surrounded_by_changes(kinds: &[u8], i: usize) -> bool {
+  let start = i.saturating_sub(SIMSCAN_WINDOW);
+  let end = (i + SIMSCAN_WINDOW).min(kinds.len() - 1);
+  let run = |lines: &mut dyn Iterator<Item = usize>| {
+    let (mut missing, mut common) = (0, 1);
+    for j in lines {
+      match kinds[j] {
+        0 => missing += 1,
+        2 => common += 1,
+        _ => break,
+      }
+    }
+    (missing, common)
+  };
+  let (missing_before, common_before) = run(&mut (start..i).rev());
+  if missing_before == 0 {
+    return false;
+  }
+  let (missing_after, common_after) = run(&mut (i + 1..=end));
+  if missing_after == 0 {
+    return false;
+  }
+  let (missing, common) = (missing_before + missing_after, common_before + common_after);
+  common * KPDIS_RUN < common + missing
+}
+
+/// Where [`Myers::split`] splits a range and whether each half needs the
+/// shortest path
+struct Split {
+  a: isize,
+  b: isize,
+  minimal_before: bool,
+  minimal_after: bool,
+}
+
+struct Myers<'a> {
+  a: &'a [usize],
+  b: &'a [usize],
+  /// The furthest reaching paths from the start and the end indexed by
+  /// diagonal plus `offset`
+  forward: Vec<isize>,
+  backward: Vec<isize>,
+  offset: isize,
+  /// How much searching is done before settling for a path that isn't the
+  /// shortest
+  max_cost: isize,
+  removed: Vec<bool>,
+  added: Vec<bool>,
+}
+
+impl Myers<'_> {
+  fn compare(
+    &mut self,
+    mut a_lo: isize,
+    mut a_hi: isize,
+    mut b_lo: isize,
+    mut b_hi: isize,
+    minimal: bool,
+  ) {
+    let (a, b) = (self.a, self.b);
+    while a_lo < a_hi && b_lo < b_hi && a[a_lo as usize] == b[b_lo as usize] {
+      a_lo += 1;
+      b_lo += 1;
+    }
+    while a_lo < a_hi && b_lo < b_hi && a[a_hi as usize - 1] == b[b_hi as usize - 1] {
+      a_hi -= 1;
+      b_hi -= 1;
+    }
+    if a_lo == a_hi {
+      self.added[b_lo as usize..b_hi as usize]
+        .iter_mut()
+        .for_each(|c| *c = true);
+    } else if b_lo == b_hi {
+      self.removed[a_lo as usize..a_hi as usize]
+        .iter_mut()
+        .for_each(|c| *c = true);
+    } else {
+      let split = self.split(a_lo, a_hi, b_lo, b_hi, minimal);
+      self.compare(a_lo, split.a, b_lo, split.b, split.minimal_before);
+      self.compare(split.a, a_hi, split.b, b_hi, split.minimal_after);
+    }
+  }
+
+  fn f(&self, diagonal: isize) -> isize {
+    self.forward[(diagonal + self.offset) as usize]
+  }
+
+  fn set_f(&mut self, diagonal: isize, x: isize) {
+    self.forward[(diagonal + self.offset) as usize] = x;
+  }
+
+  fn b(&self, diagonal: isize) -> isize {
+    self.backward[(diagonal + self.offset) as usize]
+  }
+
+  fn set_b(&mut self, diagonal: isize, x: isize) {
+    self.backward[(diagonal + self.offset) as usize] = x;
+  }
+
+  /// Search from both ends of the range at once until the paths meet,
+  /// which is a point on the shortest path the range can be split at
+  fn split(&mut self, a_lo: isize, a_hi: isize, b_lo: isize, b_hi: isize, minimal: bool) -> Split {
+    let (a, b) = (self.a, self.b);
+    let matches = |x: isize, y: isize| a[x as usize] == b[y as usize];
+    let (d_min, d_max) = (a_lo - b_hi, a_hi - b_lo);
+    let (f_mid, b_mid) = (a_lo - b_lo, a_hi - b_hi);
+    let odd = (f_mid - b_mid) & 1 != 0;
+    let (mut f_min, mut f_max) = (f_mid, f_mid);
+    let (mut b_min, mut b_max) = (b_mid, b_mid);
+    self.set_f(f_mid, a_lo);
+    self.set_b(b_mid, a_hi);
+    let found = |a, b| Split {
+      a,
+      b,
+      minimal_before: true,
+      minimal_after: true,
+    };
+    let mut cost = 1;
+    loop {
+      let mut got_snake = false;
+      if f_min > d_min {
+        f_min -= 1;
+        self.set_f(f_min - 1, -1);
+      } else {
+        f_min += 1;
+      }
+      if f_max < d_max {
+        f_max += 1;
+        self.set_f(f_max + 1, -1);
+      } else {
+        f_max -= 1;
+      }
+      for d in (f_min..=f_max).rev().step_by(2) {
+        let mut x = if self.f(d - 1) >= self.f(d + 1) {
+          self.f(d - 1) + 1
+        } else {
+          self.f(d + 1)
+        };
+        let start = x;
+        let mut y = x - d;
+        while x < a_hi && y < b_hi && matches(x, y) {
+          x += 1;
+          y += 1;
+        }
+        if x - start > SNAKE_CNT {
+          got_snake = true;
+        }
+        self.set_f(d, x);
+        if odd && b_min <= d && d <= b_max && self.b(d) <= x {
+          return found(x, y);
+        }
+      }
+
+      if b_min > d_min {
+        b_min -= 1;
+        self.set_b(b_min - 1, isize::MAX);
+      } else {
+        b_min += 1;
+      }
+      if b_max < d_max {
+        b_max += 1;
+        self.set_b(b_max + 1, isize::MAX);
+      } else {
+        b_max -= 1;
+      }
+      for d in (b_min..=b_max).rev().step_by(2) {
+        let mut x = if self.b(d - 1) < self.b(d + 1) {
+          self.b(d - 1)
+        } else {
+          self.b(d + 1) - 1
+        };
+        let start = x;
+        let mut y = x - d;
+        while x > a_lo && y > b_lo && matches(x - 1, y - 1) {
+          x -= 1;
+          y -= 1;
+        }
+        if start - x > SNAKE_CNT {
+          got_snake = true;
+        }
+        self.set_b(d, x);
+        if !odd && f_min <= d && d <= f_max && x <= self.f(d) {
+          return found(x, y);
+        }
+      }
+
+      if minimal {
+        cost += 1;
+        continue;
+      }
+
+      // A long enough run of matching lines on a path that has got far is
+      // taken as good enough once the search has gone on for a while
+      if got_snake && cost > HEUR_MIN_COST {
+        let mut best = None;
+        let mut best_value = 0;
+        for d in (f_min..=f_max).rev().step_by(2) {
+          let x = self.f(d);
+          let y = x - d;
+          let value = (x - a_lo) + (y - b_lo) - (d - f_mid).abs();
+          if value > K_HEUR * cost
+            && value > best_value
+            && a_lo + SNAKE_CNT <= x
+            && x < a_hi
+            && b_lo + SNAKE_CNT <= y
+            && y < b_hi
+            && (1..=SNAKE_CNT).all(|k| matches(x - k, y - k))
+          {
+            best_value = value;
+            best = Some((x, y));
+          }
+        }
+        if let Some((a, b)) = best {
+          return Split {
+            a,
+            b,
+            minimal_before: true,
+            minimal_after: false,
+          };
+        }
+        for d in (b_min..=b_max).rev().step_by(2) {
+          let x = self.b(d);
+          let y = x - d;
+          let value = (a_hi - x) + (b_hi - y) - (d - b_mid).abs();
+          if value > K_HEUR * cost
+            && value > best_value
+            && a_lo < x
+            && x <= a_hi - SNAKE_CNT
+            && b_lo < y
+            && y <= b_hi - SNAKE_CNT
+            && (0..SNAKE_CNT).all(|k| matches(x + k, y + k))
+          {
+            best_value = value;
+            best = Some((x, y));
+          }
+        }
+        if let Some((a, b)) = best {
+          return Split {
+            a,
+            b,
+            minimal_before: false,
+            minimal_after: true,
+          };
+        }
+      }
+
+      // Searching has taken too long so the path that got the furthest is
+      // used
+      if cost >= self.max_cost {
+        let (mut f_best, mut f_best_x) = (-1, -1);
+        for d in (f_min..=f_max).rev().step_by(2) {
+          let mut x = self.f(d).min(a_hi);
+          let mut y = x - d;
+          if b_hi < y {
+            x = b_hi + d;
+            y = b_hi;
+          }
+          if f_best < x + y {
+            f_best = x + y;
+            f_best_x = x;
+          }
+        }
+        let (mut b_best, mut b_best_x) = (isize::MAX, isize::MAX);
+        for d in (b_min..=b_max).rev().step_by(2) {
+          let mut x = self.b(d).max(a_lo);
+          let mut y = x - d;
+          if y < b_lo {
+            x = b_lo + d;
+            y = b_lo;
+          }
+          if x + y < b_best {
+            b_best = x + y;
+            b_best_x = x;
+          }
+        }
+        return if (a_hi + b_hi) - b_best < f_best - (a_lo + b_lo) {
+          Split {
+            a: f_best_x,
+            b: f_best - f_best_x,
+            minimal_before: true,
+            minimal_after: false,
+          }
+        } else {
+          Split {
+            a: b_best_x,
+            b: b_best - b_best_x,
+            minimal_before: false,
+            minimal_after: true,
+          }
+        };
+      }
+      cost += 1;
+    }
+  }
+}
+
+/// How many times a line can occur in the old side and still be used to
+/// line up the two sides in the histogram algorithm
+const MAX_CHAIN: usize = 64;
+
+/// The histogram algorithm the way git does it
+struct Histogram<'a> {
+  a: &'a [usize],
+  b: &'a [usize],
+  removed: &'a mut [bool],
+  added: &'a mut [bool],
+}
+
+impl Histogram<'_> {
+  /// The longest run of matching lines around the lines that occur the
+  /// least often in the old side is used to split both sides, then the
+  /// parts before and after it are diffed the same way
+  fn diff(&mut self, mut a_lo: usize, a_hi: usize, mut b_lo: usize, b_hi: usize) {
+    loop {
+      if a_lo == a_hi || b_lo == b_hi {
+        self.removed[a_lo..a_hi].iter_mut().for_each(|c| *c = true);
+        self.added[b_lo..b_hi].iter_mut().for_each(|c| *c = true);
+        return;
+      }
+      match self.find_run(a_lo, a_hi, b_lo, b_hi) {
+        Run::Found(start_a, start_b, len) => {
+          self.diff(a_lo, start_a, b_lo, start_b);
+          a_lo = start_a + len;
+          b_lo = start_b + len;
+        }
+        // Every line in common is too common to line things up with
+        Run::TooCommon => {
+          return myers(
+            &self.a[a_lo..a_hi],
+            &self.b[b_lo..b_hi],
+            false,
+            &mut self.removed[a_lo..a_hi],
+            &mut self.added[b_lo..b_hi],
+          )
+        }
+        Run::NothingInCommon => {
+          self.removed[a_lo..a_hi].iter_mut().for_each(|c| *c = true);
+          self.added[b_lo..b_hi].iter_mut().for_each(|c| *c = true);
+          return;
+        }
+      }
+    }
+  }
+
+  fn find_run(&self, a_lo: usize, a_hi: usize, b_lo: usize, b_hi: usize) -> Run {
+    let (a, b) = (self.a, self.b);
+    let mut occurrences: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, &id) in (a_lo..).zip(&a[a_lo..a_hi]) {
+      occurrences.entry(id).or_default().push(i);
+    }
+    let count = |i: usize| occurrences[&a[i]].len();
+    // The best run so far as where it starts in each side and its length,
+    // and how often its rarest line occurs
+    let mut best: Option<(usize, usize, usize)> = None;
+    let mut lowest = MAX_CHAIN + 1;
+    let mut has_common = false;
+    let mut j = b_lo;
+    while j < b_hi {
+      let mut next = j + 1;
+      let positions = match occurrences.get(&b[j]) {
+        Some(positions) => positions,
+        None => {
+          j = next;
+          continue;
+        }
+      };
+      has_common = true;
+      if positions.len() > lowest {
+        j = next;
+        continue;
+      }
+      let mut candidates = positions.iter().copied().peekable();
+      while let Some(i) = candidates.next() {
+        let (mut start_a, mut start_b) = (i, j);
+        let mut rarest = positions.len();
+        while start_a > a_lo && start_b > b_lo && a[start_a - 1] == b[start_b - 1] {
+          start_a -= 1;
+          start_b -= 1;
+          rarest = rarest.min(count(start_a));
+        }
+        let (mut end_a, mut end_b) = (i + 1, j + 1);
+        while end_a < a_hi && end_b < b_hi && a[end_a] == b[end_b] {
+          rarest = rarest.min(count(end_a));
+          end_a += 1;
+          end_b += 1;
+        }
+        // Nothing inside this run can start a longer one
+        next = next.max(end_b);
+        let len = end_a - start_a;
+        if best.is_none_or(|(.., best_len)| len > best_len) || rarest < lowest {
+          best = Some((start_a, start_b, len));
+          lowest = rarest;
+        }
+        // Later occurrences inside of the run would only find it again
+        while candidates.next_if(|&i| i < end_a).is_some() {}
+      }
+      j = next;
+    }
+    match best {
+      _ if has_common && lowest > MAX_CHAIN => Run::TooCommon,
+      Some((start_a, start_b, len)) => Run::Found(start_a, start_b, len),
+      None => Run::NothingInCommon,
+    }
+  }
+}
+
+enum Run {
+  /// Where the run starts in each side and how long it is
+  Found(usize, usize, usize),
+  TooCommon,
+  NothingInCommon,
+}
+
+/// Group the changes into [`Hunk`]s, joining changes separated by no more
+/// than twice the context
+fn hunks(old: &[&[u8]], new: &[&[u8]], changes: &[LineChange], context: usize) -> Vec<Hunk> {
+  let mut hunks = Vec::new();
+  let mut changes = changes
+    .iter()
+    .map(|(old, new)| (old.start, old.end, new.start, new.end))
+    .peekable();
+  while let Some(first) = changes.next() {
+    let mut last = first;
+    let mut group = vec![first];
+    while let Some(&next) = changes.peek() {
+      if next.0 - last.1 > 2 * context {
+        break;
+      }
+      last = next;
+      group.push(next);
+      changes.next();
+    }
+    let leading = context.min(first.0);
+    let trailing = context.min(old.len() - last.1);
+    let (old_from, old_to) = (first.0 - leading, last.1 + trailing);
+    let (new_from, new_to) = (first.2 - leading, last.3 + trailing);
+    let mut lines = Vec::new();
+    let line = |kind, content: &[u8]| DiffLine {
+      kind,
+      content: content.into(),
+    };
+    let mut i = old_from;
+    for (start_i, end_i, start_j, end_j) in group {
+      lines.extend(old[i..start_i].iter().map(|l| line(LineKind::Context, l)));
+      lines.extend(
+        old[start_i..end_i]
+          .iter()
+          .map(|l| line(LineKind::Removed, l)),
+      );
+      lines.extend(new[start_j..end_j].iter().map(|l| line(LineKind::Added, l)));
+      i = end_i;
+    }
+    lines.extend(old[i..old_to].iter().map(|l| line(LineKind::Context, l)));
+    let start = |from: usize, to: usize| if from == to { from } else { from + 1 };
+    hunks.push(Hunk {
+      old_start: start(old_from, old_to),
+      old_len: old_to - old_from,
+      new_start: start(new_from, new_to),
+      new_len: new_to - new_from,
+      lines,
+    });
+  }
+  hunks
+}
+
+#[cfg(test)]
+fn render(hunks: &[Hunk]) -> String {
+  let mut out = String::new();
+  for hunk in hunks {
+    out += &format!(
+      "@@ -{},{} +{},{} @@\n",
+      hunk.old_start, hunk.old_len, hunk.new_start, hunk.new_len
+    );
+    for line in &hunk.lines {
+      out.push(match line.kind {
+        LineKind::Context => ' ',
+        LineKind::Removed => '-',
+        LineKind::Added => '+',
+      });
+      out += &String::from_utf8_lossy(&line.content);
+    }
+  }
+  out
+}
+
+#[test]
+fn myers_hunks() {
+  let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+  let new = "a\nb\nx\nc\nd\ne\nf\ng\nh\ni\nk\nl\n";
+  let options = DiffOptions {
+    context: 1,
+    ..DiffOptions::default()
+  };
+  assert_eq!(
+    "@@ -2,2 +2,3 @@\n b\n+x\n c\n@@ -9,3 +10,3 @@\n i\n-j\n k\n+l\n",
+    render(&diff_blobs(old, new, &options))
+  );
+  // Changes no more than twice the context apart are joined up
+  assert_eq!(
+    "@@ -1,11 +1,12 @@\n a\n b\n+x\n c\n d\n e\n f\n g\n h\n i\n-j\n k\n+l\n",
+    render(&diff_blobs(
+      old,
+      new,
+      &DiffOptions {
+        context: 4,
+        ..options
+      }
+    ))
+  );
+  assert!(diff_blobs(old, old, &options).is_empty());
+  assert_eq!(
+    "@@ -0,0 +1,2 @@\n+a\n+b\n",
+    render(&diff_blobs("", "a\nb\n", &options))
+  );
+  assert_eq!(
+    "@@ -1 +0,0 @@\n-a",
+    render(&diff_blobs("a", "", &options)).replace(",1", "")
+  );
+  // A missing newline at the end makes the last line different
+  assert_eq!(
+    "@@ -1,2 +1,2 @@\n a\n-b\n+b",
+    render(&diff_blobs("a\nb\n", "a\nb", &options))
+  );
+}
+
+#[test]
+fn slides_changes_down() {
+  let old = "fn a() {\n}\n";
+  let new = "fn a() {\n}\nfn b() {\n}\n";
+  // A new function is added after the first, not in the middle of it
+  assert_eq!(
+    "@@ -1,2 +1,4 @@\n fn a() {\n }\n+fn b() {\n+}\n",
+    render(&diff_blobs(old, new, &DiffOptions::default()))
+  );
+}
+
+#[test]
+fn indent_heuristic() {
+  let old = "fn b() {\n  b();\n}\nif x {\n  y();\n}\nfn a() {\n  a();\n}\n";
+  let new = format!("{}  c();\nfn a() {{\n  a();\n}}\n", old);
+  let mut options = DiffOptions {
+    context: 1,
+    ..DiffOptions::default()
+  };
+  assert_eq!(
+    "@@ -6,2 +6,6 @@\n }\n+fn a() {\n+  a();\n+}\n+  c();\n fn a() {\n",
+    render(&diff_blobs(old, &new, &options))
+  );
+  options.indent_heuristic = false;
+  assert_eq!(
+    "@@ -9,1 +9,5 @@\n }\n+  c();\n+fn a() {\n+  a();\n+}\n",
+    render(&diff_blobs(old, &new, &options))
+  );
+}
+
+#[test]
+fn histogram() {
+  // Myers matches up the `}` lines, histogram keeps the functions whole
+  let old = "fn a() {\n  a\n}\n";
+  let new = "fn b() {\n  b\n}\nfn a() {\n  a\n}\n";
+  let options = DiffOptions {
+    context: 0,
+    algorithm: DiffAlgorithm::Histogram,
+    ..DiffOptions::default()
+  };
+  assert_eq!(
+    "@@ -0,0 +1,3 @@\n+fn b() {\n+  b\n+}\n",
+    render(&diff_blobs(old, new, &options))
+  );
+  let old = "a\nb\nc\nd\n";
+  let new = "a\nc\nb\nd\n";
+  let hunks = diff_blobs(old, new, &options);
+  let removed = hunks
+    .iter()
+    .flat_map(|h| &h.lines)
+    .filter(|l| l.kind == LineKind::Removed)
+    .count();
+  let added = hunks
+    .iter()
+    .flat_map(|h| &h.lines)
+    .filter(|l| l.kind == LineKind::Added)
+    .count();
+  assert_eq!((1, 1), (removed, added));
+}
+
+#[test]
+fn options_from_config() {
+  let config = Config::from_bytes("[diff]\n\tcontext = 5\n\talgorithm = histogram\n").unwrap();
+  assert_eq!(
+    DiffOptions {
+      context: 5,
+      algorithm: DiffAlgorithm::Histogram,
+      ..DiffOptions::default()
+    },
+    DiffOptions::from_config(&config).unwrap()
+  );
+  let config = Config::from_bytes("[diff]\n\talgorithm = patience\n").unwrap();
+  assert!(DiffOptions::from_config(&config).is_err());
+}
+
+#[test]
+fn random_diffs_apply() {
+  // Applying the hunks to the old side has to give the new side for both
+  // algorithms
+  let mut state = 7u32;
+  let mut random = |n: u32| {
+    state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+    (state >> 16) % n
+  };
+  for _ in 0..200 {
+    let old: String = (0..random(30))
+      .map(|_| format!("{}\n", random(5)))
+      .collect();
+    let new: String = (0..random(30))
+      .map(|_| format!("{}\n", random(5)))
+      .collect();
+    for algorithm in [DiffAlgorithm::Myers, DiffAlgorithm::Histogram].iter() {
+      let options = DiffOptions {
+        context: 2,
+        algorithm: *algorithm,
+        ..DiffOptions::default()
+      };
+      let old_lines = lines(old.as_bytes());
+      let mut result: Vec<&[u8]> = Vec::new();
+      let mut next = 0;
+      let hunks = diff_blobs(&old, &new, &options);
+      for hunk in &hunks {
+        let from = if hunk.old_len == 0 {
+          hunk.old_start
+        } else {
+          hunk.old_start - 1
+        };
+        result.extend(&old_lines[next..from]);
+        for line in &hunk.lines {
+          if line.kind != LineKind::Removed {
+            result.push(&line.content);
+          }
+        }
+        next = from + hunk.old_len;
+      }
+      result.extend(&old_lines[next..]);
+      assert_eq!(new.as_bytes(), result.concat().as_slice());
+    }
+  }
+}
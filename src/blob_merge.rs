@@ -0,0 +1,670 @@
+use crate::{
+  blob_diff::{diff_lines, lines, LineChange},
+  patch::is_binary,
+  Config, ConfigError, DiffOptions,
+};
+use bstr::BString;
+
+/// How hard [`merge_blobs`] tries to make conflicts smaller, like the
+/// levels of git's `xdl_merge`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MergeLevel {
+  /// Every place both sides changed is a conflict, even if they made the
+  /// same change
+  Minimal,
+  /// Both sides making the same change isn't a conflict
+  Eager,
+  /// Conflicts are narrowed down to the lines the sides disagree on, and
+  /// conflicts with at most three lines between them are joined into one.
+  /// This is what git uses when merging.
+  #[default]
+  Zealous,
+  /// Like [`MergeLevel::Zealous`], but conflicts are also joined if the
+  /// lines between them don't have any letters or digits, like
+  /// `git merge-file` does
+  ZealousAlnum,
+}
+
+/// Which side wins where both sides changed the same lines, like
+/// `git merge-file --ours`, `--theirs`, and `--union`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MergeFavor {
+  /// Use our lines
+  Ours,
+  /// Use their lines
+  Theirs,
+  /// Use our lines followed by theirs
+  Union,
+}
+
+/// How conflicts are written, as set by `merge.conflictStyle`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ConflictStyle {
+  /// Our lines and their lines between `<<<<<<<`, `=======`, and `>>>>>>>`
+  #[default]
+  Merge,
+  /// Also show the lines of the ancestor after a `|||||||` marker. Only
+  /// identical changes are merged since the ancestor has to line up with
+  /// both sides, as if [`MergeLevel::Eager`] was used.
+  Diff3,
+  /// Like [`ConflictStyle::Diff3`], but lines both sides agree on at the
+  /// start and end of a conflict are moved out of it
+  ZealousDiff3,
+}
+
+/// Options controlling how [`merge_blobs`] merges contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeOptions {
+  /// How both sides are compared with the ancestor. Only the algorithm and
+  /// how changes are placed matter, the context is never used. Defaults to
+  /// the Myers algorithm without the indent heuristic like git's merges.
+  pub diff: DiffOptions,
+  /// How hard to try to make conflicts smaller
+  pub level: MergeLevel,
+  /// Which side to use for conflicts instead of writing conflict markers.
+  /// `None`, the default, writes conflict markers.
+  pub favor: Option<MergeFavor>,
+  /// How conflicts are written
+  pub style: ConflictStyle,
+  /// How many characters each conflict marker has. Defaults to 7.
+  pub marker_size: usize,
+  /// The label after the `<<<<<<<` marker, usually the name of our branch
+  pub ours_label: Option<BString>,
+  /// The label after the `|||||||` marker of [`ConflictStyle::Diff3`]
+  pub ancestor_label: Option<BString>,
+  /// The label after the `>>>>>>>` marker
+  pub theirs_label: Option<BString>,
+}
+
+impl Default for MergeOptions {
+  fn default() -> Self {
+    Self {
+      diff: DiffOptions {
+        indent_heuristic: false,
+        ..DiffOptions::default()
+      },
+      level: MergeLevel::default(),
+      favor: None,
+      style: ConflictStyle::default(),
+      marker_size: 7,
+      ours_label: None,
+      ancestor_label: None,
+      theirs_label: None,
+    }
+  }
+}
+
+impl MergeOptions {
+  /// Read the [`ConflictStyle`] from `merge.conflictStyle`, using the
+  /// defaults for everything else
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let style = match config.get_str("merge.conflictstyle")? {
+      None => ConflictStyle::Merge,
+      Some("merge") => ConflictStyle::Merge,
+      Some("diff3") => ConflictStyle::Diff3,
+      Some("zdiff3") => ConflictStyle::ZealousDiff3,
+      Some(value) => {
+        return Err(ConfigError::InvalidValue {
+          key: "merge.conflictstyle".into(),
+          value: value.into(),
+          expected: "merge, diff3, or zdiff3",
+        })
+      }
+    };
+    Ok(Self {
+      style,
+      ..Self::default()
+    })
+  }
+}
+
+/// The result of [`merge_blobs`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergedBlob {
+  /// The merged contents, with conflict markers around every conflict
+  pub contents: Vec<u8>,
+  /// How many conflicts there are
+  pub conflicts: usize,
+}
+
+impl MergedBlob {
+  /// Whether the contents merged without any conflicts
+  pub fn is_clean(&self) -> bool {
+    self.conflicts == 0
+  }
+}
+
+/// Merge the changes `ours` and `theirs` made to `ancestor` line by line,
+/// the same way git merges files. Where both sides changed the same lines
+/// differently the result has a conflict with both versions in it, unless
+/// [`MergeOptions::favor`] picks one.
+///
+/// Like git, contents that look binary aren't merged at all. The result is
+/// always our contents, or theirs if [`MergeFavor::Theirs`] is favored, and
+/// one conflict unless [`MergeFavor::Ours`] or [`MergeFavor::Theirs`] is.
+pub fn merge_blobs(
+  ancestor: impl AsRef<[u8]>,
+  ours: impl AsRef<[u8]>,
+  theirs: impl AsRef<[u8]>,
+  options: &MergeOptions,
+) -> MergedBlob {
+  let (ancestor, ours, theirs) = (ancestor.as_ref(), ours.as_ref(), theirs.as_ref());
+  if is_binary(ancestor) || is_binary(ours) || is_binary(theirs) {
+    let (contents, conflicts) = match options.favor {
+      Some(MergeFavor::Ours) => (ours, 0),
+      Some(MergeFavor::Theirs) => (theirs, 0),
+      _ => (ours, 1),
+    };
+    return MergedBlob {
+      contents: contents.to_vec(),
+      conflicts,
+    };
+  }
+
+  let base = lines(ancestor);
+  let merge = Merge {
+    base: &base,
+    ours: &lines(ours),
+    theirs: &lines(theirs),
+    options,
+  };
+  let ours_changes = diff_lines(merge.base, merge.ours, &options.diff);
+  let theirs_changes = diff_lines(merge.base, merge.theirs, &options.diff);
+  if ours_changes.is_empty() || theirs_changes.is_empty() {
+    let contents = if ours_changes.is_empty() {
+      theirs
+    } else {
+      ours
+    };
+    return MergedBlob {
+      contents: contents.to_vec(),
+      conflicts: 0,
+    };
+  }
+  merge.merge(&ours_changes, &theirs_changes)
+}
+
+/// Where the lines in a [`Region`] come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resolution {
+  Conflict,
+  Ours,
+  Theirs,
+  /// Our lines followed by theirs
+  Both,
+  /// Both sides made the same change
+  Same,
+}
+
+/// Lines changed by one or both sides, as where they start and how many
+/// there are in the ancestor and in each side
+#[derive(Debug, Clone, Copy)]
+struct Region {
+  resolution: Resolution,
+  i0: isize,
+  chg0: isize,
+  i1: isize,
+  chg1: isize,
+  i2: isize,
+  chg2: isize,
+}
+
+struct Merge<'a> {
+  base: &'a [&'a [u8]],
+  ours: &'a [&'a [u8]],
+  theirs: &'a [&'a [u8]],
+  options: &'a MergeOptions,
+}
+
+impl Merge<'_> {
+  /// Line up the changes of both sides like git's `xdl_do_merge`
+  fn merge(&self, ours: &[LineChange], theirs: &[LineChange]) -> MergedBlob {
+    let options = self.options;
+    let level = match options.style {
+      ConflictStyle::Diff3 => options.level.min(MergeLevel::Eager),
+      _ => options.level,
+    };
+    // Each change as where it starts and how many lines it covers in the
+    // ancestor and in the side that made it
+    let change = |(old, new): &LineChange| {
+      (
+        old.start as isize,
+        old.len() as isize,
+        new.start as isize,
+        new.len() as isize,
+      )
+    };
+    let mut ours = ours.iter().map(change).peekable();
+    let mut theirs = theirs.iter().map(change).peekable();
+    let mut regions = Vec::new();
+    while let (Some(&a), Some(&b)) = (ours.peek(), theirs.peek()) {
+      let (a_i1, a_chg1, a_i2, a_chg2) = a;
+      let (b_i1, b_chg1, b_i2, b_chg2) = b;
+      if a_i1 + a_chg1 < b_i1 {
+        let i2 = b_i2 - b_i1 + a_i1;
+        let region = (a_i1, a_chg1, a_i2, a_chg2, i2, a_chg1);
+        append(&mut regions, Resolution::Ours, region);
+        ours.next();
+        continue;
+      }
+      if b_i1 + b_chg1 < a_i1 {
+        let i1 = a_i2 - a_i1 + b_i1;
+        let region = (b_i1, b_chg1, i1, b_chg1, b_i2, b_chg2);
+        append(&mut regions, Resolution::Theirs, region);
+        theirs.next();
+        continue;
+      }
+      if level == MergeLevel::Minimal
+        || a_i1 != b_i1
+        || a_chg1 != b_chg1
+        || a_chg2 != b_chg2
+        || self.ours[a_i2 as usize..][..a_chg2 as usize]
+          != self.theirs[b_i2 as usize..][..b_chg2 as usize]
+      {
+        let off = a_i1 - b_i1;
+        let ffo = off + a_chg1 - b_chg1;
+        let (mut i0, mut i1, mut i2) = (a_i1, a_i2, b_i2);
+        if off > 0 {
+          i0 -= off;
+          i1 -= off;
+        } else {
+          i2 += off;
+        }
+        let (mut chg0, mut chg1, mut chg2) =
+          (a_i1 + a_chg1 - i0, a_i2 + a_chg2 - i1, b_i2 + b_chg2 - i2);
+        if ffo < 0 {
+          chg0 -= ffo;
+          chg1 -= ffo;
+        } else {
+          chg2 += ffo;
+        }
+        let region = (i0, chg0, i1, chg1, i2, chg2);
+        append(&mut regions, Resolution::Conflict, region);
+      }
+      let (end1, end2) = (a_i1 + a_chg1, b_i1 + b_chg1);
+      if end1 >= end2 {
+        theirs.next();
+      }
+      if end2 >= end1 {
+        ours.next();
+      }
+    }
+    let offset = |side: &[&[u8]]| side.len() as isize - self.base.len() as isize;
+    for (i1, chg1, i2, chg2) in ours {
+      let region = (i1, chg1, i2, chg2, i1 + offset(self.theirs), chg1);
+      append(&mut regions, Resolution::Ours, region);
+    }
+    for (i1, chg1, i2, chg2) in theirs {
+      let region = (i1, chg1, i1 + offset(self.ours), chg1, i2, chg2);
+      append(&mut regions, Resolution::Theirs, region);
+    }
+
+    if options.style == ConflictStyle::ZealousDiff3 {
+      self.trim_conflicts(&mut regions);
+    } else if level >= MergeLevel::Zealous {
+      regions = self.refine_conflicts(regions);
+      self.join_conflicts(&mut regions, level == MergeLevel::ZealousAlnum);
+    }
+    self.write(&mut regions)
+  }
+
+  /// Narrow conflicts down to the lines that differ between the sides by
+  /// comparing them, like git's `xdl_refine_conflicts`
+  fn refine_conflicts(&self, regions: Vec<Region>) -> Vec<Region> {
+    let mut refined = Vec::with_capacity(regions.len());
+    for mut region in regions {
+      if region.resolution != Resolution::Conflict || region.chg1 == 0 || region.chg2 == 0 {
+        refined.push(region);
+        continue;
+      }
+      let ours = &self.ours[region.i1 as usize..][..region.chg1 as usize];
+      let theirs = &self.theirs[region.i2 as usize..][..region.chg2 as usize];
+      let changes = diff_lines(ours, theirs, &self.options.diff);
+      if changes.is_empty() {
+        region.resolution = Resolution::Same;
+        refined.push(region);
+        continue;
+      }
+      let (i1, i2) = (region.i1, region.i2);
+      for (old, new) in changes {
+        refined.push(Region {
+          i1: i1 + old.start as isize,
+          chg1: old.len() as isize,
+          i2: i2 + new.start as isize,
+          chg2: new.len() as isize,
+          ..region
+        });
+      }
+    }
+    refined
+  }
+
+  /// Join conflicts with at most three lines between them, or ones that
+  /// are only separated by lines without letters or digits if `alnum` is
+  /// set, like git's `xdl_simplify_non_conflicts`
+  fn join_conflicts(&self, regions: &mut Vec<Region>, alnum: bool) {
+    let mut idx = 0;
+    while idx + 1 < regions.len() {
+      let (region, next) = (regions[idx], regions[idx + 1]);
+      let (begin, end) = (region.i1 + region.chg1, next.i1);
+      let has_alnum = || {
+        self.ours[begin as usize..end as usize]
+          .iter()
+          .any(|line| line.iter().any(u8::is_ascii_alphanumeric))
+      };
+      if region.resolution != Resolution::Conflict
+        || next.resolution != Resolution::Conflict
+        || (end - begin > 3 && (!alnum || has_alnum()))
+      {
+        idx += 1;
+        continue;
+      }
+      let region = &mut regions[idx];
+      region.chg0 = next.i0 + next.chg0 - region.i0;
+      region.chg1 = next.i1 + next.chg1 - region.i1;
+      region.chg2 = next.i2 + next.chg2 - region.i2;
+      regions.remove(idx + 1);
+    }
+  }
+
+  /// Move the lines both sides agree on at the start and end of each
+  /// conflict out of it, like git's `xdl_refine_zdiff3_conflicts`
+  fn trim_conflicts(&self, regions: &mut [Region]) {
+    for region in regions
+      .iter_mut()
+      .filter(|region| region.resolution == Resolution::Conflict)
+    {
+      fn line<'a>(side: &[&'a [u8]], i: isize) -> &'a [u8] {
+        side[i as usize]
+      }
+      while region.chg1 > 0
+        && region.chg2 > 0
+        && line(self.ours, region.i1) == line(self.theirs, region.i2)
+      {
+        region.chg1 -= 1;
+        region.chg2 -= 1;
+        region.i1 += 1;
+        region.i2 += 1;
+      }
+      while region.chg1 > 0
+        && region.chg2 > 0
+        && line(self.ours, region.i1 + region.chg1 - 1)
+          == line(self.theirs, region.i2 + region.chg2 - 1)
+      {
+        region.chg1 -= 1;
+        region.chg2 -= 1;
+      }
+    }
+  }
+
+  /// Write the merged lines like git's `xdl_fill_merge_buffer`
+  fn write(&self, regions: &mut [Region]) -> MergedBlob {
+    let favor = self.options.favor.map(|favor| match favor {
+      MergeFavor::Ours => Resolution::Ours,
+      MergeFavor::Theirs => Resolution::Theirs,
+      MergeFavor::Union => Resolution::Both,
+    });
+    let mut out = Vec::new();
+    let mut conflicts = 0;
+    let mut i = 0;
+    for region in regions.iter_mut() {
+      if region.resolution == Resolution::Conflict {
+        if let Some(favor) = favor {
+          region.resolution = favor;
+        }
+      }
+      let ours = &self.ours[region.i1 as usize..][..region.chg1 as usize];
+      let theirs = &self.theirs[region.i2 as usize..][..region.chg2 as usize];
+      match region.resolution {
+        Resolution::Conflict => {
+          conflicts += 1;
+          self.write_conflict(&mut out, i, region);
+        }
+        Resolution::Same => continue,
+        resolution => {
+          out.extend(self.ours[i..region.i1 as usize].concat());
+          if matches!(resolution, Resolution::Ours | Resolution::Both) {
+            let crlf = self.needs_cr(region);
+            copy(&mut out, ours, crlf, resolution == Resolution::Both);
+          }
+          if matches!(resolution, Resolution::Theirs | Resolution::Both) {
+            out.extend(theirs.concat());
+          }
+        }
+      }
+      i = (region.i1 + region.chg1) as usize;
+    }
+    out.extend(self.ours[i..].concat());
+    MergedBlob {
+      contents: out,
+      conflicts,
+    }
+  }
+
+  fn write_conflict(&self, out: &mut Vec<u8>, i: usize, region: &Region) {
+    let options = self.options;
+    let crlf = self.needs_cr(region);
+    let marker = |out: &mut Vec<u8>, c: u8, label: &Option<BString>| {
+      let size = match options.marker_size {
+        0 => 7,
+        size => size,
+      };
+      out.extend(vec![c; size]);
+      if let Some(label) = label {
+        out.push(b' ');
+        out.extend_from_slice(label);
+      }
+      if crlf {
+        out.push(b'\r');
+      }
+      out.push(b'\n');
+    };
+    let range = |side: &'_ [&'_ [u8]], start: isize, len: isize| -> Vec<u8> {
+      let mut lines = Vec::new();
+      copy(
+        &mut lines,
+        &side[start as usize..][..len as usize],
+        crlf,
+        true,
+      );
+      lines
+    };
+    out.extend(self.ours[i..region.i1 as usize].concat());
+    marker(out, b'<', &options.ours_label);
+    out.extend(range(self.ours, region.i1, region.chg1));
+    if options.style != ConflictStyle::Merge {
+      marker(out, b'|', &options.ancestor_label);
+      out.extend(range(self.base, region.i0, region.chg0));
+    }
+    marker(out, b'=', &None);
+    out.extend(range(self.theirs, region.i2, region.chg2));
+    marker(out, b'>', &options.theirs_label);
+  }
+
+  /// Whether lines added to end a side that doesn't end in a newline need a
+  /// `\r`, going by the line endings around the region like git's
+  /// `is_cr_needed`
+  fn needs_cr(&self, region: &Region) -> bool {
+    let before = |i: isize| if i > 0 { i as usize - 1 } else { 0 };
+    let endings = [
+      is_crlf(self.ours, before(region.i1)),
+      is_crlf(self.theirs, before(region.i2)),
+      is_crlf(self.base, 0),
+    ];
+    // A side that can't tell doesn't decide either way, but the ancestor
+    // has the last word
+    !endings.contains(&Some(false)) && endings[2] == Some(true)
+  }
+}
+
+/// Add a change to the regions, joining it with the last one if they
+/// overlap or touch in either side, like git's `xdl_append_merge`
+fn append(
+  regions: &mut Vec<Region>,
+  resolution: Resolution,
+  (i0, chg0, i1, chg1, i2, chg2): (isize, isize, isize, isize, isize, isize),
+) {
+  if let Some(last) = regions
+    .last_mut()
+    .filter(|last| i1 <= last.i1 + last.chg1 || i2 <= last.i2 + last.chg2)
+  {
+    if resolution != last.resolution {
+      last.resolution = Resolution::Conflict;
+    }
+    last.chg0 = i0 + chg0 - last.i0;
+    last.chg1 = i1 + chg1 - last.i1;
+    last.chg2 = i2 + chg2 - last.i2;
+    return;
+  }
+  regions.push(Region {
+    resolution,
+    i0,
+    chg0,
+    i1,
+    chg1,
+    i2,
+    chg2,
+  });
+}
+
+/// Copy lines to `out`, adding a newline after the last one if `add_newline`
+/// is set and it doesn't have one
+fn copy(out: &mut Vec<u8>, lines: &[&[u8]], crlf: bool, add_newline: bool) {
+  for line in lines {
+    out.extend_from_slice(line);
+  }
+  if add_newline && lines.last().is_some_and(|line| !line.ends_with(b"\n")) {
+    if crlf {
+      out.push(b'\r');
+    }
+    out.push(b'\n');
+  }
+}
+
+/// Whether line `i` ends in `\r\n`, or the line before if it's the last
+/// line and has no line ending. `None` if there's no way to tell, like
+/// git's `is_eol_crlf`.
+fn is_crlf(lines: &[&[u8]], i: usize) -> Option<bool> {
+  let ends_crlf = |line: &[u8]| line.ends_with(b"\r\n");
+  if i + 1 < lines.len() {
+    return Some(ends_crlf(lines[i]));
+  }
+  let last = lines.get(i)?;
+  if last.ends_with(b"\n") {
+    return Some(ends_crlf(last));
+  }
+  if i == 0 {
+    return None;
+  }
+  Some(ends_crlf(lines[i - 1]))
+}
+
+#[test]
+fn merge() {
+  let ancestor = "one\ntwo\nthree\nfour\nfive\nsix\n";
+  let ours = "one\nTWO\nthree\nfour\nfive\nsix\nseven\n";
+  let theirs = "one\n2\nthree\nfour\nFIVE\nsix\n";
+  let mut options = MergeOptions {
+    ours_label: Some("HEAD".into()),
+    ancestor_label: Some("base".into()),
+    theirs_label: Some("topic".into()),
+    ..MergeOptions::default()
+  };
+  let merged = merge_blobs(ancestor, ours, theirs, &options);
+  assert_eq!(1, merged.conflicts);
+  assert_eq!(
+    "one\n<<<<<<< HEAD\nTWO\n=======\n2\n>>>>>>> topic\nthree\nfour\nFIVE\nsix\nseven\n",
+    String::from_utf8(merged.contents).unwrap()
+  );
+
+  options.style = ConflictStyle::Diff3;
+  let merged = merge_blobs(ancestor, ours, theirs, &options);
+  assert_eq!(
+    "one\n<<<<<<< HEAD\nTWO\n||||||| base\ntwo\n=======\n2\n>>>>>>> topic\nthree\nfour\nFIVE\nsix\nseven\n",
+    String::from_utf8(merged.contents).unwrap()
+  );
+
+  options.style = ConflictStyle::Merge;
+  options.marker_size = 3;
+  let merged = merge_blobs(ancestor, ours, theirs, &options);
+  assert_eq!(
+    "one\n<<< HEAD\nTWO\n===\n2\n>>> topic\nthree\nfour\nFIVE\nsix\nseven\n",
+    String::from_utf8(merged.contents).unwrap()
+  );
+
+  for (favor, expected) in [
+    (
+      MergeFavor::Ours,
+      "one\nTWO\nthree\nfour\nFIVE\nsix\nseven\n",
+    ),
+    (
+      MergeFavor::Theirs,
+      "one\n2\nthree\nfour\nFIVE\nsix\nseven\n",
+    ),
+    (
+      MergeFavor::Union,
+      "one\nTWO\n2\nthree\nfour\nFIVE\nsix\nseven\n",
+    ),
+  ] {
+    options.favor = Some(favor);
+    let merged = merge_blobs(ancestor, ours, theirs, &options);
+    assert!(merged.is_clean());
+    assert_eq!(expected, String::from_utf8(merged.contents).unwrap());
+  }
+
+  // The same change on both sides is only a conflict at the minimal level
+  let options = MergeOptions::default();
+  let same = "one\nTWO\nthree\nfour\nfive\nsix\n";
+  let merged = merge_blobs(ancestor, same, same, &options);
+  assert!(merged.is_clean());
+  assert_eq!(same.as_bytes(), &merged.contents[..]);
+  let minimal = MergeOptions {
+    level: MergeLevel::Minimal,
+    ..MergeOptions::default()
+  };
+  assert_eq!(1, merge_blobs(ancestor, same, same, &minimal).conflicts);
+
+  // Conflict markers follow the line endings of the contents
+  let merged = merge_blobs("a\r\nb\r\nc", "a\r\nB\r\nc", "a\r\nb2\r\nc", &options);
+  assert_eq!(
+    "a\r\n<<<<<<<\r\nB\r\n=======\r\nb2\r\n>>>>>>>\r\nc",
+    String::from_utf8(merged.contents).unwrap()
+  );
+
+  let binary = merge_blobs("a\0", "b\0", "c\0", &options);
+  assert_eq!((1, &b"b\0"[..]), (binary.conflicts, &binary.contents[..]));
+  let theirs = MergeOptions {
+    favor: Some(MergeFavor::Theirs),
+    ..MergeOptions::default()
+  };
+  let binary = merge_blobs("a\0", "b\0", "c\0", &theirs);
+  assert_eq!((0, &b"c\0"[..]), (binary.conflicts, &binary.contents[..]));
+}
+
+#[test]
+fn zealous_diff3() {
+  let ancestor = "keep\nold\nend\n";
+  let ours = "keep\nsame\nours\nend\n";
+  let theirs = "keep\nsame\ntheirs\nend\n";
+  let mut options =
+    MergeOptions::from_config(&Config::from_bytes("[merge]\n\tconflictStyle = diff3\n").unwrap())
+      .unwrap();
+  assert_eq!(ConflictStyle::Diff3, options.style);
+  let merged = merge_blobs(ancestor, ours, theirs, &options);
+  assert_eq!(
+    "keep\n<<<<<<<\nsame\nours\n|||||||\nold\n=======\nsame\ntheirs\n>>>>>>>\nend\n",
+    String::from_utf8(merged.contents).unwrap()
+  );
+  options.style = ConflictStyle::ZealousDiff3;
+  let merged = merge_blobs(ancestor, ours, theirs, &options);
+  assert_eq!(
+    "keep\nsame\n<<<<<<<\nours\n|||||||\nold\n=======\ntheirs\n>>>>>>>\nend\n",
+    String::from_utf8(merged.contents).unwrap()
+  );
+
+  let invalid = Config::from_bytes("[merge]\n\tconflictStyle = fancy\n").unwrap();
+  assert!(matches!(
+    MergeOptions::from_config(&invalid),
+    Err(ConfigError::InvalidValue { .. })
+  ));
+}
@@ -0,0 +1,430 @@
+//! Bundles, which are files holding refs along with a pack of what they
+//! point at, like the ones `git bundle` makes to carry history to
+//! repositories that can't be reached over a network. A bundle can leave
+//! out history the repository reading it is expected to have already,
+//! the prerequisites of the bundle.
+
+use crate::{
+  peel_tag,
+  transport::{write_pack_for, TransportError},
+  ObjectKind, OdbError, RefError, Repository, RevWalkError, OID,
+};
+use bstr::{BString, ByteSlice};
+use std::{collections::HashSet, fs, io, path::Path};
+use thiserror::Error;
+
+const V2_SIGNATURE: &[u8] = b"# v2 git bundle\n";
+const V3_SIGNATURE: &[u8] = b"# v3 git bundle\n";
+
+/// The versions of the bundle format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BundleVersion {
+  /// The version every git since 1.5 reads
+  V2,
+  /// The version with capabilities before the prerequisites, like
+  /// `@object-format=sha1`
+  V3,
+}
+
+/// A bundle read from a file or made from a repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bundle {
+  version: BundleVersion,
+  capabilities: Vec<BString>,
+  prerequisites: Vec<(OID, BString)>,
+  refs: Vec<(BString, OID)>,
+  pack: Vec<u8>,
+}
+
+impl Bundle {
+  /// Bundle the refs `refs` of `repo`, like `HEAD` or `refs/heads/master`,
+  /// with everything they point at that isn't reachable from `basis`. The
+  /// commits of `basis` that are left out are the prerequisites, so a
+  /// bundle without any basis can be cloned from.
+  pub fn create(
+    repo: &Repository,
+    refs: &[impl AsRef<[u8]>],
+    basis: &[OID],
+  ) -> Result<Self, BundleError> {
+    let mut bundled = Vec::new();
+    for name in refs {
+      let name = name.as_ref();
+      let oid = repo
+        .refs()
+        .resolve(name)?
+        .ok_or_else(|| BundleError::RefNotFound(name.into()))?;
+      bundled.push((BString::from(name), oid));
+    }
+    if bundled.is_empty() {
+      return Err(BundleError::Empty);
+    }
+
+    // The prerequisites are the commits left out whose children are in
+    let mut walk = repo.rev_walk();
+    for (_, oid) in &bundled {
+      if let (commit, ObjectKind::Commit) = peel_tag(repo.odb(), oid)? {
+        walk.push(&commit)?;
+      }
+    }
+    for oid in basis {
+      walk.hide(oid)?;
+    }
+    let commits = walk.collect::<Result<HashSet<OID>, _>>()?;
+    let mut prerequisites = Vec::new();
+    let mut seen = HashSet::new();
+    for oid in &commits {
+      for parent in repo.odb().read_commit(oid)?.parents() {
+        if !commits.contains(parent) && seen.insert(*parent) {
+          let commit = repo.odb().read_commit(parent)?;
+          let subject = commit
+            .message_lossy()
+            .lines()
+            .next()
+            .unwrap_or("")
+            .to_owned();
+          prerequisites.push((*parent, BString::from(subject)));
+        }
+      }
+    }
+    prerequisites.sort();
+
+    let wants: Vec<OID> = bundled.iter().map(|(_, oid)| *oid).collect();
+    let haves: Vec<OID> = prerequisites.iter().map(|(oid, _)| *oid).collect();
+    let (pack, _) = write_pack_for(repo, &wants, &haves, true, Vec::new())?;
+    Ok(Self {
+      version: BundleVersion::V2,
+      capabilities: Vec::new(),
+      prerequisites,
+      refs: bundled,
+      pack,
+    })
+  }
+
+  /// Parse a whole bundle
+  pub fn parse(bytes: impl Into<Vec<u8>>) -> Result<Self, BundleError> {
+    let mut bytes = bytes.into();
+    let malformed = |reason: &str| BundleError::Malformed(reason.into());
+    let (version, mut pos) = if bytes.starts_with(V2_SIGNATURE) {
+      (BundleVersion::V2, V2_SIGNATURE.len())
+    } else if bytes.starts_with(V3_SIGNATURE) {
+      (BundleVersion::V3, V3_SIGNATURE.len())
+    } else {
+      return Err(malformed("missing bundle signature"));
+    };
+    let mut bundle = Self {
+      version,
+      capabilities: Vec::new(),
+      prerequisites: Vec::new(),
+      refs: Vec::new(),
+      pack: Vec::new(),
+    };
+    // The header ends at an empty line, and the pack starts after it
+    loop {
+      let end = bytes[pos..]
+        .find_byte(b'\n')
+        .ok_or_else(|| malformed("header doesn't end"))?;
+      let line = &bytes[pos..pos + end];
+      pos += end + 1;
+      if line.is_empty() {
+        break;
+      }
+      if let Some(capability) = line.strip_prefix(b"@") {
+        if version != BundleVersion::V3 || !bundle.refs.is_empty() {
+          return Err(malformed("capability out of place"));
+        }
+        if capability != b"object-format=sha1" {
+          return Err(BundleError::Unsupported(capability.into()));
+        }
+        bundle.capabilities.push(capability.into());
+        continue;
+      }
+      let (prerequisite, line) = match line.strip_prefix(b"-") {
+        Some(line) => (true, line),
+        None => (false, line),
+      };
+      let (hex, rest) = match line.find_byte(b' ') {
+        Some(space) => (&line[..space], &line[space + 1..]),
+        None => (line, &b""[..]),
+      };
+      let oid = hex
+        .to_str()
+        .ok()
+        .and_then(|hex| OID::from_hex(hex).ok())
+        .ok_or_else(|| malformed(&format!("invalid object id {:?}", hex.as_bstr())))?;
+      match prerequisite {
+        true => bundle.prerequisites.push((oid, rest.into())),
+        false if rest.is_empty() => return Err(malformed("ref without a name")),
+        false => bundle.refs.push((rest.into(), oid)),
+      }
+    }
+    bundle.pack = bytes.split_off(pos);
+    Ok(bundle)
+  }
+
+  /// Read the bundle at `path`
+  pub fn open(path: impl AsRef<Path>) -> Result<Self, BundleError> {
+    Self::parse(fs::read(path)?)
+  }
+
+  /// Write the bundle in the format of its version
+  pub fn write(&self, mut out: impl io::Write) -> io::Result<()> {
+    out.write_all(match self.version {
+      BundleVersion::V2 => V2_SIGNATURE,
+      BundleVersion::V3 => V3_SIGNATURE,
+    })?;
+    let mut header = Vec::new();
+    for capability in &self.capabilities {
+      header.extend_from_slice(&[b"@", &capability[..], b"\n"].concat());
+    }
+    for (oid, comment) in &self.prerequisites {
+      header.extend_from_slice(format!("-{} {}", oid, comment).trim_end().as_bytes());
+      header.push(b'\n');
+    }
+    for (name, oid) in &self.refs {
+      header.extend_from_slice(format!("{} {}\n", oid, name).as_bytes());
+    }
+    header.push(b'\n');
+    out.write_all(&header)?;
+    out.write_all(&self.pack)
+  }
+
+  /// Write the bundle to a file at `path`, which is replaced if it exists
+  pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    self.write(&mut bytes)?;
+    fs::write(path, bytes)
+  }
+
+  /// The version of the format of the bundle
+  pub fn version(&self) -> BundleVersion {
+    self.version
+  }
+
+  /// The refs in the bundle with the objects they point at, in the order
+  /// they're in the bundle
+  pub fn refs(&self) -> &[(BString, OID)] {
+    &self.refs
+  }
+
+  /// The object the ref `name` points at in the bundle
+  pub fn get(&self, name: impl AsRef<[u8]>) -> Option<OID> {
+    let name = name.as_ref();
+    self
+      .refs
+      .iter()
+      .find_map(|(ref_name, oid)| (ref_name == name).then_some(*oid))
+  }
+
+  /// The commits a repository needs to have to read the bundle, with the
+  /// subject of each
+  pub fn prerequisites(&self) -> &[(OID, BString)] {
+    &self.prerequisites
+  }
+
+  /// The pack of the objects in the bundle, which is thin when there are
+  /// prerequisites
+  pub fn pack(&self) -> &[u8] {
+    &self.pack
+  }
+
+  /// Check that `repo` has every prerequisite of the bundle, like `git
+  /// bundle verify`
+  pub fn verify(&self, repo: &Repository) -> Result<(), BundleError> {
+    for (oid, _) in &self.prerequisites {
+      match repo.odb().read_commit(oid) {
+        Ok(_) => {}
+        Err(OdbError::NotFound(_)) | Err(OdbError::WrongKind { .. }) => {
+          return Err(BundleError::MissingPrerequisite(*oid))
+        }
+        Err(e) => return Err(e.into()),
+      }
+    }
+    Ok(())
+  }
+
+  /// Store the objects of the bundle in `repo` once it's verified, like
+  /// `git bundle unbundle`, returning the objects stored. Updating refs
+  /// to the refs of the bundle is left to the caller.
+  pub fn unbundle(&self, repo: &Repository) -> Result<Vec<OID>, BundleError> {
+    self.verify(repo)?;
+    Ok(repo.odb().write_thin_pack(&self.pack, repo.odb())?)
+  }
+}
+
+/// Whether the file at `path` starts like a bundle
+pub(crate) fn is_bundle(path: impl AsRef<Path>) -> bool {
+  use io::Read;
+  let mut signature = [0; 16];
+  fs::File::open(path)
+    .and_then(|mut file| file.read_exact(&mut signature))
+    .is_ok_and(|_| signature == V2_SIGNATURE || signature == V3_SIGNATURE)
+}
+
+#[derive(Error, Debug)]
+/// Errors related to making and reading a [`Bundle`]
+pub enum BundleError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  RevWalk(#[from] RevWalkError),
+  #[error("{0}")]
+  Transport(#[from] TransportError),
+  #[error("ref {0} doesn't exist")]
+  RefNotFound(BString),
+  #[error("a bundle needs at least one ref")]
+  Empty,
+  #[error("malformed bundle: {0}")]
+  Malformed(String),
+  #[error("bundle capability {0} is not supported")]
+  Unsupported(BString),
+  #[error("the repository lacks the prerequisite commit {0}")]
+  MissingPrerequisite(OID),
+}
+
+#[test]
+fn create_and_unbundle() {
+  use crate::{Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  let tmp_dir = tempdir::TempDir::new("bundle_test").unwrap();
+  let source = Repository::init_bare(tmp_dir.path().join("source.git")).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |contents: &str, parents: Vec<OID>| {
+    let odb = source.odb();
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let message = format!("Write {}\nbody\n", contents.trim_end());
+    let commit = Commit::new(tree, parents, signature.clone(), signature.clone(), message);
+    let oid = odb.write_commit(&commit).unwrap();
+    source.refs().write("refs/heads/master", &oid).unwrap();
+    oid
+  };
+  let first = commit("first\n", vec![]);
+  let second = commit("second\n", vec![first]);
+  assert!(matches!(
+    Bundle::create(&source, &[] as &[&str], &[]),
+    Err(BundleError::Empty)
+  ));
+  assert!(matches!(
+    Bundle::create(&source, &["refs/heads/missing"], &[]),
+    Err(BundleError::RefNotFound(_))
+  ));
+
+  // A whole bundle has every object and round trips through a file
+  let bundle = Bundle::create(&source, &["HEAD", "refs/heads/master"], &[]).unwrap();
+  assert_eq!(BundleVersion::V2, bundle.version());
+  assert!(bundle.prerequisites().is_empty());
+  assert_eq!(Some(second), bundle.get("HEAD"));
+  let path = tmp_dir.path().join("all.bundle");
+  bundle.save(&path).unwrap();
+  assert!(is_bundle(&path));
+  assert!(!is_bundle(tmp_dir.path().join("source.git")));
+  assert_eq!(bundle, Bundle::open(&path).unwrap());
+  let clone = Repository::init_bare(tmp_dir.path().join("clone.git")).unwrap();
+  assert_eq!(6, bundle.unbundle(&clone).unwrap().len());
+  clone.odb().read_commit(&second).unwrap();
+
+  // An incremental one needs what it leaves out
+  let third = commit("third\n", vec![second]);
+  let bundle = Bundle::create(&source, &["refs/heads/master"], &[second]).unwrap();
+  assert_eq!(&[(second, "Write second".into())], bundle.prerequisites());
+  let empty = Repository::init_bare(tmp_dir.path().join("empty.git")).unwrap();
+  assert!(matches!(
+    bundle.verify(&empty),
+    Err(BundleError::MissingPrerequisite(oid)) if oid == second
+  ));
+  assert!(empty.odb().oids().unwrap().is_empty());
+  bundle.verify(&clone).unwrap();
+  bundle.unbundle(&clone).unwrap();
+  clone.odb().read_commit(&third).unwrap();
+  let mut bytes = Vec::new();
+  bundle.write(&mut bytes).unwrap();
+  let header = format!(
+    "# v2 git bundle\n-{} Write second\n{} refs/heads/master\n\n",
+    second, third
+  );
+  assert!(bytes.starts_with(header.as_bytes()));
+
+  // v3 bundles only come with SHA-1 objects
+  let v3 = format!(
+    "# v3 git bundle\n@object-format=sha1\n{} HEAD\n\nPACK",
+    third
+  );
+  let parsed = Bundle::parse(v3).unwrap();
+  assert_eq!(
+    (BundleVersion::V3, b"PACK" as &[u8]),
+    (parsed.version(), parsed.pack())
+  );
+  let v3 = format!("# v3 git bundle\n@object-format=sha256\n{} HEAD\n\n", third);
+  assert!(matches!(
+    Bundle::parse(v3),
+    Err(BundleError::Unsupported(_))
+  ));
+  let v2 = format!("# v2 git bundle\n@object-format=sha1\n{} HEAD\n\n", third);
+  assert!(matches!(Bundle::parse(v2), Err(BundleError::Malformed(_))));
+  for malformed in [
+    "# v4 git bundle\n\n",
+    "# v2 git bundle\nabc HEAD\n\n",
+    "# v2 git bundle\n",
+  ] {
+    assert!(matches!(
+      Bundle::parse(malformed),
+      Err(BundleError::Malformed(_))
+    ));
+  }
+
+  // git reads our bundles and we read its
+  if !crate::transport::http::have_git() {
+    return;
+  }
+  let git = |args: &[&str]| {
+    let output = std::process::Command::new("git")
+      .args(args)
+      .current_dir(tmp_dir.path())
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{}", output.stderr.as_bstr());
+  };
+  bundle
+    .save(tmp_dir.path().join("incremental.bundle"))
+    .unwrap();
+  git(&["clone", "--quiet", "--bare", "all.bundle", "git.git"]);
+  git(&[
+    "--git-dir",
+    "git.git",
+    "bundle",
+    "verify",
+    "-q",
+    "incremental.bundle",
+  ]);
+  git(&[
+    "--git-dir",
+    "git.git",
+    "fetch",
+    "--quiet",
+    "incremental.bundle",
+    "master",
+  ]);
+  git(&[
+    "--git-dir",
+    "source.git",
+    "bundle",
+    "create",
+    "git.bundle",
+    "--all",
+  ]);
+  let bundle = Bundle::open(tmp_dir.path().join("git.bundle")).unwrap();
+  assert_eq!(Some(third), bundle.get("refs/heads/master"));
+  let other = Repository::init_bare(tmp_dir.path().join("other.git")).unwrap();
+  bundle.unbundle(&other).unwrap();
+  other.odb().read_commit(&first).unwrap();
+}
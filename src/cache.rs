@@ -0,0 +1,316 @@
+//! A cache for data derived from objects, like diffs between two trees,
+//! blame of a file at a commit, or highlighted blobs. Objects never change
+//! once they have an [`OID`], so anything computed only from objects, the
+//! name and version of the computation, and its parameters never goes
+//! stale. [`CacheKey`] hashes all of those together, which leaves nothing
+//! to invalidate: changing any input, or bumping the version when the
+//! computation changes, gives a different key.
+//!
+//! The values are kept in a [`CacheStore`], either one of the two here or
+//! one backed by something like a database shared between servers.
+
+use crate::{MemoryBudget, Reservation, OID};
+use sha1::{Digest, Sha1};
+use std::{
+  collections::{HashMap, VecDeque},
+  fs, io,
+  path::PathBuf,
+  sync::Mutex,
+};
+use thiserror::Error;
+
+/// What a cached value is derived from. Only objects, which are named by
+/// their content, and plain parameters can go into a key, so a key always
+/// names the same value.
+#[derive(Debug, Clone)]
+pub struct CacheKey {
+  hasher: Sha1,
+}
+
+impl CacheKey {
+  /// Start a key for the computation `name`, like `blame`, at `version`,
+  /// which should change whenever the computation gives different results
+  pub fn new(name: &str, version: u32) -> Self {
+    let mut key = Self {
+      hasher: Sha1::new(),
+    };
+    key.field(b'n', name.as_bytes());
+    key.field(b'v', &version.to_be_bytes());
+    key
+  }
+
+  /// Each part goes in with a tag and its length so no two different
+  /// sequences of parts hash the same bytes
+  fn field(&mut self, tag: u8, bytes: &[u8]) {
+    self.hasher.update([tag]);
+    self.hasher.update((bytes.len() as u64).to_be_bytes());
+    self.hasher.update(bytes);
+  }
+
+  /// Add an object the value is derived from
+  pub fn object(mut self, oid: &OID) -> Self {
+    self.field(b'o', oid.as_bytes());
+    self
+  }
+
+  /// Add a parameter of the computation, like the options of a diff
+  pub fn param(mut self, bytes: impl AsRef<[u8]>) -> Self {
+    self.field(b'p', bytes.as_ref());
+    self
+  }
+
+  /// The [`OID`] the value is stored under
+  pub fn id(&self) -> OID {
+    OID::from_bytes(&self.hasher.clone().finalize()).unwrap()
+  }
+}
+
+/// Where a [`DerivedCache`] keeps its values. A store is free to forget
+/// values whenever it likes, they are computed again when next asked for.
+pub trait CacheStore {
+  /// The value stored for `key`, if there is one
+  fn get(&self, key: &OID) -> Result<Option<Vec<u8>>, CacheError>;
+
+  /// Store `value` for `key`. Since a key always names the same value,
+  /// storing one that's already there can be skipped.
+  fn put(&self, key: &OID, value: &[u8]) -> Result<(), CacheError>;
+}
+
+/// A [`CacheStore`] in memory that counts its values against a
+/// [`MemoryBudget`], forgetting the oldest ones first when the budget runs
+/// out
+#[derive(Debug)]
+pub struct MemoryCacheStore {
+  budget: MemoryBudget,
+  values: Mutex<MemoryValues>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryValues {
+  by_key: HashMap<OID, (Vec<u8>, Reservation)>,
+  /// Keys oldest first
+  order: VecDeque<OID>,
+}
+
+impl MemoryCacheStore {
+  /// Create an empty store that holds as much as `budget` allows
+  pub fn new(budget: MemoryBudget) -> Self {
+    Self {
+      budget,
+      values: Mutex::new(MemoryValues::default()),
+    }
+  }
+
+  /// How many values are stored
+  pub fn len(&self) -> usize {
+    self
+      .values
+      .lock()
+      .unwrap_or_else(|e| e.into_inner())
+      .by_key
+      .len()
+  }
+
+  /// Whether nothing is stored
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl CacheStore for MemoryCacheStore {
+  fn get(&self, key: &OID) -> Result<Option<Vec<u8>>, CacheError> {
+    let values = self.values.lock().unwrap_or_else(|e| e.into_inner());
+    Ok(values.by_key.get(key).map(|(value, _)| value.clone()))
+  }
+
+  fn put(&self, key: &OID, value: &[u8]) -> Result<(), CacheError> {
+    let mut values = self.values.lock().unwrap_or_else(|e| e.into_inner());
+    if values.by_key.contains_key(key) {
+      return Ok(());
+    }
+    let reservation = loop {
+      match self.budget.try_reserve(value.len()) {
+        Ok(reservation) => break reservation,
+        Err(_) => match values.order.pop_front() {
+          Some(oldest) => drop(values.by_key.remove(&oldest)),
+          // Too big to keep even with everything else gone
+          None => return Ok(()),
+        },
+      }
+    };
+    values.by_key.insert(*key, (value.to_vec(), reservation));
+    values.order.push_back(*key);
+    Ok(())
+  }
+}
+
+/// A [`CacheStore`] keeping each value in a file of a directory, laid out
+/// like loose objects are with the first two hex digits of the key as a
+/// subdirectory. Nothing is ever removed, the whole directory can be
+/// deleted at any time to clear it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirCacheStore {
+  dir: PathBuf,
+}
+
+impl DirCacheStore {
+  /// Create a store in `dir`, which is made when the first value is stored
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    Self { dir: dir.into() }
+  }
+
+  fn path(&self, key: &OID) -> PathBuf {
+    let hex = key.as_hex();
+    self.dir.join(&hex[..2]).join(&hex[2..])
+  }
+}
+
+impl CacheStore for DirCacheStore {
+  fn get(&self, key: &OID) -> Result<Option<Vec<u8>>, CacheError> {
+    match fs::read(self.path(key)) {
+      Ok(value) => Ok(Some(value)),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  fn put(&self, key: &OID, value: &[u8]) -> Result<(), CacheError> {
+    let path = self.path(key);
+    if path.exists() {
+      return Ok(());
+    }
+    // The path always has a parent since it's inside of the directory
+    fs::create_dir_all(path.parent().unwrap())?;
+    // Written to a temporary file and renamed so readers never see part of
+    // a value, and writers racing on the same key write the same bytes
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".tmp{}", std::process::id()));
+    fs::write(&tmp_path, value)?;
+    if let Err(e) = fs::rename(&tmp_path, &path) {
+      let _ = fs::remove_file(&tmp_path);
+      return Err(e.into());
+    }
+    Ok(())
+  }
+}
+
+/// Memoizes computations on objects in a [`CacheStore`]
+#[derive(Debug)]
+pub struct DerivedCache<S> {
+  store: S,
+}
+
+impl<S: CacheStore> DerivedCache<S> {
+  /// Create a cache keeping its values in `store`
+  pub fn new(store: S) -> Self {
+    Self { store }
+  }
+
+  /// The store the values are kept in
+  pub fn store(&self) -> &S {
+    &self.store
+  }
+
+  /// The value cached for `key`, if there is one
+  pub fn get(&self, key: &CacheKey) -> Result<Option<Vec<u8>>, CacheError> {
+    self.store.get(&key.id())
+  }
+
+  /// The value cached for `key`, or the result of `compute` which is
+  /// cached for next time. Errors from `compute` are returned and not
+  /// cached.
+  pub fn get_or_compute<E>(
+    &self,
+    key: &CacheKey,
+    compute: impl FnOnce() -> Result<Vec<u8>, E>,
+  ) -> Result<Vec<u8>, E>
+  where
+    E: From<CacheError>,
+  {
+    let id = key.id();
+    if let Some(value) = self.store.get(&id)? {
+      return Ok(value);
+    }
+    let value = compute()?;
+    self.store.put(&id, &value)?;
+    Ok(value)
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to storing derived data in a [`CacheStore`]
+pub enum CacheError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  /// For stores outside of this crate to report their own errors
+  #[error("{0}")]
+  Store(String),
+}
+
+#[test]
+fn keys() {
+  let (a, b) = (OID::hash("a"), OID::hash("b"));
+  let key = |name, version, objects: &[OID], params: &[&str]| {
+    let key = objects
+      .iter()
+      .fold(CacheKey::new(name, version), |key, oid| key.object(oid));
+    params.iter().fold(key, |key, param| key.param(param)).id()
+  };
+  let diff = key("diff", 1, &[a, b], &["-M"]);
+  assert_eq!(diff, key("diff", 1, &[a, b], &["-M"]));
+  for other in [
+    key("diff", 2, &[a, b], &["-M"]),
+    key("blame", 1, &[a, b], &["-M"]),
+    key("diff", 1, &[b, a], &["-M"]),
+    key("diff", 1, &[a, b], &[]),
+    key("diff", 1, &[a, b], &["-", "M"]),
+  ] {
+    assert_ne!(diff, other);
+  }
+}
+
+#[test]
+fn memoize() {
+  use std::cell::Cell;
+  let tmp_dir = tempdir::TempDir::new("cache_test").unwrap();
+  let computed = Cell::new(0);
+  let compute = |value: &str| {
+    computed.set(computed.get() + 1);
+    Ok::<_, CacheError>(value.as_bytes().to_vec())
+  };
+  let key = CacheKey::new("upper", 1).object(&OID::hash("a"));
+  let other = CacheKey::new("upper", 1).object(&OID::hash("b"));
+
+  let cache = DerivedCache::new(DirCacheStore::new(tmp_dir.path().join("cache")));
+  assert_eq!(None, cache.get(&key).unwrap());
+  assert_eq!(
+    b"A"[..],
+    cache.get_or_compute(&key, || compute("A")).unwrap()[..]
+  );
+  assert_eq!(
+    b"A"[..],
+    cache.get_or_compute(&key, || compute("X")).unwrap()[..]
+  );
+  assert_eq!(1, computed.get());
+  // A new cache over the same directory sees what was stored before
+  let cache = DerivedCache::new(DirCacheStore::new(tmp_dir.path().join("cache")));
+  assert_eq!(Some(b"A".to_vec()), cache.get(&key).unwrap());
+  assert!(matches!(
+    cache.get_or_compute(&other, || Err(CacheError::Store("failed".into()))),
+    Err(CacheError::Store(_))
+  ));
+  assert_eq!(None, cache.get(&other).unwrap());
+
+  // The oldest values go when the budget runs out
+  let budget = MemoryBudget::new(4);
+  let cache = DerivedCache::new(MemoryCacheStore::new(budget.clone()));
+  cache.get_or_compute(&key, || compute("AAA")).unwrap();
+  assert_eq!(3, budget.in_use());
+  cache.get_or_compute(&other, || compute("BB")).unwrap();
+  assert_eq!(None, cache.get(&key).unwrap());
+  assert_eq!(Some(b"BB".to_vec()), cache.get(&other).unwrap());
+  assert_eq!(2, budget.in_use());
+  cache.get_or_compute(&key, || compute("too big")).unwrap();
+  assert!(cache.store().is_empty());
+  assert_eq!(0, budget.in_use());
+}
@@ -0,0 +1,811 @@
+use crate::{
+  collision::{self, PathCollision},
+  index, Attributes, AttributesError, Config, ConfigError, FileMode, FilterError, Filters, Index,
+  IndexEntry, IndexError, LineEndings, MergedTree, Odb, OdbError, ProgressStage, Repository,
+  SparseCheckout, StatData, Trace2, Tree, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  borrow::Cow,
+  fs, io,
+  path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Options controlling how a [`Tree`] is written out to a directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckoutOptions {
+  /// Whether to create symbolic links. When this is `false`, as it is with
+  /// `core.symlinks=false`, symbolic links are written as plain files that
+  /// contain the link target instead, and those files stay symbolic links
+  /// in the [`Index`]. Defaults to `false` on Windows, where making them
+  /// takes privileges most users don't have, like in Git for Windows.
+  pub symlinks: bool,
+  /// Whether the executable bit of files can be trusted, as set by
+  /// `core.fileMode`. When this is `false` files are never made executable
+  /// on checkout and the executable bit on disk is ignored when comparing
+  /// files against the [`Index`], which keeps its recorded mode instead.
+  /// Defaults to `false` where files have no executable bit, like on
+  /// Windows.
+  pub file_mode: bool,
+  /// Whether the filesystem treats names that only differ in case as the
+  /// same file, as set by `core.ignoreCase`
+  pub ignore_case: bool,
+  /// Whether the filesystem treats names that only differ in Unicode
+  /// normalization as the same file like macOS does, as set by
+  /// `core.precomposeUnicode`
+  pub precompose_unicode: bool,
+  /// Whether to protect against NTFS specific name handling, as set by
+  /// `core.protectNTFS`. This refuses names like `GIT~1` that NTFS treats as
+  /// `.git` and checks for names that alias each other through 8.3 short
+  /// names. Unlike git this is only on by default on Windows.
+  pub protect_ntfs: bool,
+  /// Whether a changed ctime means a file changed, as set by
+  /// `core.trustctime`. Backup tools and file indexers change the ctime of
+  /// files without touching their contents.
+  pub trust_ctime: bool,
+  /// Which parts of the recorded [`StatData`] are compared with a file to
+  /// tell whether it changed, as set by `core.checkStat`
+  pub check_stat: CheckStat,
+  /// How line endings are converted between blobs and files, as set by
+  /// `core.autocrlf`, `core.eol`, and `core.safecrlf`. Its [`Attributes`]
+  /// are empty unless they're filled in, which
+  /// [`Repository::checkout_tree`] does.
+  pub line_endings: LineEndings,
+  /// The filter drivers files go through, from `filter.<name>.*`. Which
+  /// one a path uses is its `filter` attribute in the [`Attributes`] of
+  /// [`CheckoutOptions::line_endings`].
+  pub filters: Filters,
+  /// The paths a sparse checkout keeps in the working tree. The rest get
+  /// the skip-worktree bit in the [`Index`] instead of being written.
+  /// `None` writes every file, and [`Repository::checkout_tree`] fills it
+  /// in from `.git/info/sparse-checkout` when `core.sparseCheckout` is set.
+  pub sparse: Option<SparseCheckout>,
+}
+
+/// How much of the [`StatData`] of a file is compared with what the
+/// [`Index`] recorded, as set by `core.checkStat`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum CheckStat {
+  /// Compare the times, inode, owner, and size. Like git the device is
+  /// never compared, since it isn't stable on every filesystem.
+  #[default]
+  Default,
+  /// Only compare the whole seconds of the modification time and the size,
+  /// for filesystems that change the other fields on their own like some
+  /// network filesystems do
+  Minimal,
+}
+
+impl Default for CheckoutOptions {
+  fn default() -> Self {
+    Self {
+      symlinks: cfg!(not(windows)),
+      file_mode: cfg!(unix),
+      ignore_case: cfg!(any(windows, target_os = "macos")),
+      precompose_unicode: cfg!(target_os = "macos"),
+      protect_ntfs: cfg!(windows),
+      trust_ctime: true,
+      check_stat: CheckStat::Default,
+      line_endings: LineEndings::default(),
+      filters: Filters::default(),
+      sparse: None,
+    }
+  }
+}
+
+impl CheckoutOptions {
+  /// Create [`CheckoutOptions`] from the `core.*` settings in a [`Config`]
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let default = Self::default();
+    let check_stat = match config.get_str("core.checkstat")? {
+      None => CheckStat::Default,
+      Some(value) => match value.to_ascii_lowercase().as_str() {
+        "default" => CheckStat::Default,
+        "minimal" => CheckStat::Minimal,
+        _ => {
+          return Err(ConfigError::InvalidValue {
+            key: "core.checkstat".into(),
+            value: value.into(),
+            expected: "default or minimal",
+          })
+        }
+      },
+    };
+    Ok(Self {
+      symlinks: config
+        .get_bool("core.symlinks")?
+        .unwrap_or(default.symlinks),
+      file_mode: config
+        .get_bool("core.filemode")?
+        .unwrap_or(default.file_mode),
+      ignore_case: config
+        .get_bool("core.ignorecase")?
+        .unwrap_or(default.ignore_case),
+      precompose_unicode: config
+        .get_bool("core.precomposeunicode")?
+        .unwrap_or(default.precompose_unicode),
+      protect_ntfs: config
+        .get_bool("core.protectntfs")?
+        .unwrap_or(default.protect_ntfs),
+      trust_ctime: config.get_bool("core.trustctime")?.unwrap_or(true),
+      check_stat,
+      line_endings: LineEndings::from_config(config)?,
+      filters: Filters::from_config(config)?,
+      sparse: None,
+    })
+  }
+
+  /// The mode to record for a file in the working tree with `metadata`,
+  /// given the mode it has in the [`Index`] if it's there, like
+  /// [`IndexEntry::worktree_mode`]. A new file is only ever executable if
+  /// [`CheckoutOptions::file_mode`] is set, and only a symbolic link if
+  /// it's one on disk.
+  pub fn worktree_mode(&self, existing: Option<FileMode>, metadata: &fs::Metadata) -> FileMode {
+    index::worktree_mode(existing, metadata, self)
+  }
+
+  /// Convert the contents of the file at `path`, relative to the root of
+  /// the working tree, to what its blob should have by running it through
+  /// its clean filter and then converting its line endings. See
+  /// [`LineEndings::to_git`] for what `staged` is.
+  pub fn to_git<'a>(
+    &self,
+    path: impl AsRef<[u8]>,
+    data: &'a [u8],
+    staged: Option<&[u8]>,
+  ) -> Result<Cow<'a, [u8]>, FilterError> {
+    let path = path.as_ref();
+    let cleaned = self
+      .filters
+      .clean(&self.line_endings.attributes, path, data)?;
+    Ok(match self.line_endings.to_git(path, &cleaned, staged) {
+      Cow::Borrowed(_) => cleaned,
+      Cow::Owned(converted) => Cow::Owned(converted),
+    })
+  }
+
+  /// Convert the contents of the blob for `path` to what the file in the
+  /// working tree should have by converting its line endings and then
+  /// running it through its smudge filter
+  pub fn to_worktree<'a>(
+    &self,
+    path: impl AsRef<[u8]>,
+    data: &'a [u8],
+  ) -> Result<Cow<'a, [u8]>, FilterError> {
+    let path = path.as_ref();
+    let converted = self.line_endings.to_worktree(path, data);
+    let attributes = &self.line_endings.attributes;
+    Ok(match self.filters.smudge(attributes, path, &converted)? {
+      Cow::Borrowed(_) => converted,
+      Cow::Owned(smudged) => Cow::Owned(smudged),
+    })
+  }
+
+  /// Use the [`Attributes`] of a repository, running filters in its working
+  /// tree if it has one
+  pub(crate) fn set_attributes(&mut self, attributes: Attributes, work_dir: Option<&Path>) {
+    self.line_endings.attributes = attributes;
+    if let Some(work_dir) = work_dir {
+      self.filters.set_work_dir(work_dir);
+    }
+  }
+}
+
+/// Write every file in the [`Tree`] with the given [`OID`] into `target`.
+///
+/// Paths inside of `target` are never followed if they are symbolic links.
+/// If a directory from the [`Tree`] would be written where a symbolic link
+/// already exists the link itself is replaced with the directory, so a
+/// malicious [`Tree`] or an existing link can't be used to write files
+/// outside of `target`. Entries with names git refuses to check out, like
+/// `..` or `.git`, are an error.
+///
+/// Before anything is written every directory of the [`Tree`] is checked for
+/// names that the filesystem would treat as the same file, going by the
+/// [`CheckoutOptions`], and if there are any they are all returned in
+/// [`CheckoutError::Collisions`] instead of one file overwriting another.
+///
+/// The returned [`Index`] has an entry for every file that was written along
+/// with its [`StatData`], so later comparisons with the working tree don't
+/// need to hash the files again.
+///
+/// The files written so far are reported to the
+/// [`Progress`][crate::Progress] of the [`Odb`]. If it's cancelled the files
+/// written stay and the checkout fails with [`OdbError::Cancelled`].
+pub fn checkout_tree(
+  odb: &Odb,
+  tree: &OID,
+  target: impl AsRef<Path>,
+  options: &CheckoutOptions,
+) -> Result<Index, CheckoutError> {
+  let _region = Trace2::region("checkout", "checkout_tree");
+  let target = target.as_ref();
+  let tree = odb.read_tree(tree)?;
+  if collision::needs_check(options) {
+    let mut collisions = Vec::new();
+    check_collisions(odb, &tree, b"", options, &mut collisions)?;
+    if !collisions.is_empty() {
+      return Err(CheckoutError::Collisions(collisions));
+    }
+  }
+  fs::create_dir_all(target)?;
+  let mut entries = Vec::new();
+  checkout_dir(odb, &tree, target, b"", options, &mut entries)?;
+  Trace2::data("checkout", "files", entries.len());
+  Ok(Index::new(entries))
+}
+
+impl Repository {
+  /// Write the [`Tree`] with the given [`OID`] into the working tree of the
+  /// repository using the [`CheckoutOptions`] from its [`Config`], and
+  /// replace the [`Index`] with one matching the [`Tree`]. Line endings are
+  /// converted going by the [`Attributes`][crate::Attributes] the
+  /// repository has once the [`Tree`] is checked out, and only the paths in
+  /// its sparse checkout are written if it has one.
+  pub fn checkout_tree(&self, tree: &OID) -> Result<(), CheckoutError> {
+    let work_dir = self.work_dir().ok_or(CheckoutError::BareRepository)?;
+    let mut options = CheckoutOptions::from_config(self.config())?;
+    options.set_attributes(self.tree_attributes(tree)?, Some(work_dir));
+    options.sparse = self.sparse_checkout()?;
+    let index = checkout_tree(self.odb(), tree, work_dir, &options)?;
+    index.write(self.index_path())?;
+    Ok(())
+  }
+
+  /// Check out the [`Tree`] with the given [`OID`] over a working tree
+  /// whose tracked files are the ones in `old`, deleting those the [`Tree`]
+  /// doesn't have along with the directories that leaves empty. Changes to
+  /// the tracked files are overwritten.
+  pub(crate) fn switch_tree(&self, old: &Index, tree: &OID) -> Result<(), CheckoutError> {
+    let work_dir = self.work_dir().ok_or(CheckoutError::BareRepository)?;
+    let new = Index::from_tree(self.odb(), tree)?;
+    for entry in old.entries() {
+      if new.get(&entry.path).is_some() {
+        continue;
+      }
+      let mut path = match entry.path.to_path() {
+        Ok(path) => work_dir.join(path),
+        Err(_) => continue,
+      };
+      match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+      }
+      while path.pop() && path != work_dir && fs::remove_dir(&path).is_ok() {}
+    }
+    self.checkout_tree(tree)
+  }
+
+  /// Move the working tree and the [`Index`], which match `HEAD`, to the
+  /// result of a merge. The conflicting paths get their stages in the
+  /// [`Index`], and nothing is written if an untracked file is in the way
+  /// of a path the merge added.
+  pub(crate) fn checkout_merged(&self, merged: &MergedTree) -> Result<(), CheckoutError> {
+    let work_dir = self.work_dir().ok_or(CheckoutError::BareRepository)?;
+    let old = self.index()?;
+    for entry in Index::from_tree(self.odb(), &merged.tree)?.entries() {
+      let path = entry
+        .path
+        .to_path()
+        .map_err(|_| CheckoutError::InvalidPath(entry.path.clone()))?;
+      if old.get(&entry.path).is_none() && fs::symlink_metadata(work_dir.join(path)).is_ok() {
+        return Err(CheckoutError::UntrackedInTheWay(entry.path.clone()));
+      }
+    }
+    self.switch_tree(&old, &merged.tree)?;
+    if merged.is_clean() {
+      return Ok(());
+    }
+    let mut index = self.index()?;
+    let staged = merged
+      .index
+      .entries()
+      .iter()
+      .filter(|entry| entry.stage > 0);
+    for entry in staged.clone() {
+      index.remove(&entry.path);
+    }
+    for entry in staged {
+      index.add(entry.clone());
+    }
+    Ok(index.write(self.index_path())?)
+  }
+}
+
+fn check_collisions(
+  odb: &Odb,
+  tree: &Tree,
+  prefix: &[u8],
+  options: &CheckoutOptions,
+  collisions: &mut Vec<PathCollision>,
+) -> Result<(), CheckoutError> {
+  let names: Vec<_> = tree.entries().iter().map(|entry| entry.name()).collect();
+  collisions.extend(collision::find_collisions(&names, prefix, options));
+  for entry in tree.entries().iter().filter(|entry| entry.mode().is_tree()) {
+    let prefix = [prefix, entry.name().as_bytes(), b"/"].concat();
+    let subtree = odb.read_tree(entry.oid())?;
+    check_collisions(odb, &subtree, &prefix, options, collisions)?;
+  }
+  Ok(())
+}
+
+fn checkout_dir(
+  odb: &Odb,
+  tree: &Tree,
+  dir: &Path,
+  prefix: &[u8],
+  options: &CheckoutOptions,
+  entries: &mut Vec<IndexEntry>,
+) -> Result<(), CheckoutError> {
+  for entry in tree.entries() {
+    let path = dir.join(entry_path(entry.name(), options)?);
+    let index_path = [prefix, entry.name().as_bytes()].concat();
+    if entry.mode().is_tree() {
+      prepare_dir(&path)?;
+      let subtree = odb.read_tree(entry.oid())?;
+      let prefix = [&index_path[..], b"/"].concat();
+      let start = entries.len();
+      checkout_dir(odb, &subtree, &path, &prefix, options, entries)?;
+      // Nothing in the directory may be part of a sparse checkout
+      if options.sparse.is_some() && entries[start..].iter().all(|entry| entry.skip_worktree) {
+        let _ = fs::remove_dir(&path);
+      }
+      continue;
+    }
+    let sparse = options.sparse.as_ref();
+    if sparse.is_some_and(|sparse| !sparse.contains(&index_path)) {
+      if entry.mode() != FileMode::GitLink {
+        remove_existing(&path)?;
+      }
+      let mut skipped =
+        IndexEntry::new(index_path, entry.mode(), *entry.oid(), StatData::default());
+      skipped.skip_worktree = true;
+      entries.push(skipped);
+      continue;
+    }
+    if odb.progress().is_cancelled() {
+      return Err(OdbError::Cancelled.into());
+    }
+    checkout_file(odb, &path, &index_path, entry.mode(), entry.oid(), options)?;
+    entries.push(IndexEntry::new(
+      index_path,
+      entry.mode(),
+      *entry.oid(),
+      StatData::from_metadata(&fs::symlink_metadata(&path)?),
+    ));
+    let written = entries.len() as u64;
+    odb
+      .progress()
+      .update(ProgressStage::CheckingOut, written, None);
+  }
+  Ok(())
+}
+
+/// Write the blob with the given [`OID`] to `path` as a file or symbolic
+/// link going by `mode`, replacing whatever is there. For a submodule only
+/// the directory is made so there is a place to put it, since they're
+/// checked out separately.
+pub(crate) fn checkout_file(
+  odb: &Odb,
+  path: &Path,
+  index_path: &[u8],
+  mode: FileMode,
+  oid: &OID,
+  options: &CheckoutOptions,
+) -> Result<(), CheckoutError> {
+  match mode {
+    FileMode::Tree | FileMode::GitLink => prepare_dir(path)?,
+    FileMode::SymbolicLink => {
+      remove_existing(path)?;
+      let blob = odb.read_blob(oid)?;
+      if options.symlinks {
+        write_symlink(blob.contents(), path)?;
+      } else {
+        write_file(path, blob.contents(), false)?;
+      }
+    }
+    mode => {
+      remove_existing(path)?;
+      let blob = odb.read_blob(oid)?;
+      let executable = mode == FileMode::ExecutableFile && options.file_mode;
+      let contents = options.to_worktree(index_path, blob.contents())?;
+      write_file(path, &contents, executable)?;
+    }
+  }
+  Ok(())
+}
+
+/// Check that a [`Tree`] entry name is safe to write to disk and turn it
+/// into a path
+pub(crate) fn entry_path<'a>(
+  name: &'a BStr,
+  options: &CheckoutOptions,
+) -> Result<&'a Path, CheckoutError> {
+  let invalid = || CheckoutError::InvalidPath(name.into());
+  let forbidden: &[u8] = if cfg!(windows) { b"/\0\\:" } else { b"/\0" };
+  // NTFS ignores trailing dots and spaces and has `GIT~1` as the short name
+  // of `.git`, so these all end up being `.git` there
+  let ntfs_dot_git = || {
+    let trimmed = name.trim_end_with(|c| c == '.' || c == ' ');
+    trimmed.eq_ignore_ascii_case(b".git") || trimmed.eq_ignore_ascii_case(b"git~1")
+  };
+  if name.is_empty()
+    || name == "."
+    || name == ".."
+    || name.eq_ignore_ascii_case(b".git")
+    || (options.protect_ntfs && ntfs_dot_git())
+    || name.iter().any(|b| forbidden.contains(b))
+  {
+    return Err(invalid());
+  }
+  name.to_path().map_err(|_| invalid())
+}
+
+fn is_symlink(metadata: &fs::Metadata) -> bool {
+  metadata.file_type().is_symlink()
+}
+
+/// Make sure `path` is a real directory, replacing a file or symbolic link
+/// that is in the way
+pub(crate) fn prepare_dir(path: &Path) -> Result<(), CheckoutError> {
+  match fs::symlink_metadata(path) {
+    Ok(metadata) if metadata.is_dir() && !is_symlink(&metadata) => return Ok(()),
+    Ok(_) => remove_existing(path)?,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+    Err(e) => return Err(e.into()),
+  }
+  fs::create_dir(path)?;
+  Ok(())
+}
+
+/// Remove a file or symbolic link at `path` so a new one can be written
+/// without following an existing link
+pub(crate) fn remove_existing(path: &Path) -> Result<(), CheckoutError> {
+  match fs::symlink_metadata(path) {
+    Ok(metadata) if is_symlink(&metadata) => {
+      // Windows has directory symlinks that have to be removed as a
+      // directory, this never touches what the link points at
+      fs::remove_file(path).or_else(|_| fs::remove_dir(path))?
+    }
+    Ok(metadata) if metadata.is_dir() => return Err(CheckoutError::DirectoryInTheWay(path.into())),
+    Ok(_) => fs::remove_file(path)?,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+    Err(e) => return Err(e.into()),
+  }
+  Ok(())
+}
+
+pub(crate) fn write_file(
+  path: &Path,
+  contents: &[u8],
+  executable: bool,
+) -> Result<(), CheckoutError> {
+  let mut options = fs::OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(if executable { 0o777 } else { 0o666 });
+  }
+  #[cfg(not(unix))]
+  let _ = executable;
+  io::Write::write_all(&mut options.open(path)?, contents)?;
+  Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn write_symlink(target: &[u8], path: &Path) -> Result<(), CheckoutError> {
+  let target = target
+    .to_path()
+    .map_err(|_| CheckoutError::InvalidPath(target.into()))?;
+  std::os::unix::fs::symlink(target, path)?;
+  Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn write_symlink(target: &[u8], path: &Path) -> Result<(), CheckoutError> {
+  let link_target = target
+    .to_path()
+    .map_err(|_| CheckoutError::InvalidPath(target.into()))?;
+  #[cfg(windows)]
+  if std::os::windows::fs::symlink_file(link_target, path).is_ok() {
+    return Ok(());
+  }
+  // Creating symbolic links usually needs extra privileges on Windows, so
+  // fall back to what git does with core.symlinks=false
+  let _ = link_target;
+  write_file(path, target, false)
+}
+
+#[derive(Error, Debug)]
+/// Errors related to checking out a [`Tree`]
+pub enum CheckoutError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Index(#[from] IndexError),
+  #[error("{0}")]
+  Attributes(#[from] AttributesError),
+  #[error("{0}")]
+  Filter(#[from] FilterError),
+  #[error("refusing to check out the invalid path {0:?}")]
+  InvalidPath(BString),
+  #[error("paths in the tree collide on this filesystem: {}", join_collisions(.0))]
+  Collisions(Vec<PathCollision>),
+  #[error("a directory is in the way of checking out {0:?}")]
+  DirectoryInTheWay(PathBuf),
+  #[error("the untracked file {0:?} is in the way of checking out")]
+  UntrackedInTheWay(BString),
+  #[error("a bare repository has no working tree to check out to")]
+  BareRepository,
+}
+
+fn join_collisions(collisions: &[PathCollision]) -> String {
+  let collisions: Vec<_> = collisions.iter().map(ToString::to_string).collect();
+  collisions.join("; ")
+}
+
+#[cfg(test)]
+fn test_tree(odb: &Odb) -> OID {
+  use crate::{Blob, TreeEntry};
+  let file = odb.write_blob(&Blob::new("this is a test")).unwrap();
+  let link = odb.write_blob(&Blob::new("a.txt")).unwrap();
+  let sub = odb
+    .write_tree(&Tree::new(vec![TreeEntry::new(
+      FileMode::ExecutableFile,
+      "run.sh",
+      file,
+    )]))
+    .unwrap();
+  odb
+    .write_tree(&Tree::new(vec![
+      TreeEntry::new(FileMode::NonExecutableFile, "a.txt", file),
+      TreeEntry::new(FileMode::SymbolicLink, "link", link),
+      TreeEntry::new(FileMode::Tree, "sub", sub),
+    ]))
+    .unwrap()
+}
+
+#[test]
+fn checkout() {
+  let tmp_dir = tempdir::TempDir::new("checkout_test").unwrap();
+  let odb = Odb::new(tmp_dir.path().join("objects"));
+  let tree = test_tree(&odb);
+  let target = tmp_dir.path().join("work");
+  checkout_tree(&odb, &tree, &target, &CheckoutOptions::default()).unwrap();
+  assert_eq!(
+    "this is a test",
+    fs::read_to_string(target.join("a.txt")).unwrap()
+  );
+  assert_eq!(
+    "this is a test",
+    fs::read_to_string(target.join("sub/run.sh")).unwrap()
+  );
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(target.join("sub/run.sh"))
+      .unwrap()
+      .permissions()
+      .mode();
+    assert_ne!(0, mode & 0o100);
+    assert_eq!(
+      Path::new("a.txt"),
+      fs::read_link(target.join("link")).unwrap()
+    );
+  }
+  // Checking out again over the old files works
+  checkout_tree(&odb, &tree, &target, &CheckoutOptions::default()).unwrap();
+  assert_eq!(tree, Tree::from_dir(&target).unwrap().id());
+}
+
+#[test]
+fn checkout_without_symlinks() {
+  let tmp_dir = tempdir::TempDir::new("checkout_test").unwrap();
+  let odb = Odb::new(tmp_dir.path().join("objects"));
+  let tree = test_tree(&odb);
+  let target = tmp_dir.path().join("work");
+  let options = CheckoutOptions {
+    symlinks: false,
+    ..CheckoutOptions::default()
+  };
+  checkout_tree(&odb, &tree, &target, &options).unwrap();
+  let metadata = fs::symlink_metadata(target.join("link")).unwrap();
+  assert!(metadata.is_file());
+  assert_eq!("a.txt", fs::read_to_string(target.join("link")).unwrap());
+  // The file stays a symbolic link in the index, but a new one is a file
+  let existing = Some(FileMode::SymbolicLink);
+  assert_eq!(
+    FileMode::SymbolicLink,
+    options.worktree_mode(existing, &metadata)
+  );
+  assert_eq!(
+    FileMode::NonExecutableFile,
+    options.worktree_mode(None, &metadata)
+  );
+}
+
+#[cfg(unix)]
+#[test]
+fn checkout_never_follows_symlinks() {
+  let tmp_dir = tempdir::TempDir::new("checkout_test").unwrap();
+  let odb = Odb::new(tmp_dir.path().join("objects"));
+  let tree = test_tree(&odb);
+  let outside = tmp_dir.path().join("outside");
+  let target = tmp_dir.path().join("work");
+  fs::create_dir_all(&outside).unwrap();
+  fs::create_dir_all(&target).unwrap();
+  std::os::unix::fs::symlink(&outside, target.join("sub")).unwrap();
+  std::os::unix::fs::symlink(outside.join("victim"), target.join("a.txt")).unwrap();
+  checkout_tree(&odb, &tree, &target, &CheckoutOptions::default()).unwrap();
+  assert!(!outside.join("run.sh").exists());
+  assert!(!outside.join("victim").exists());
+  assert!(!fs::symlink_metadata(target.join("sub"))
+    .unwrap()
+    .file_type()
+    .is_symlink());
+  assert!(target.join("sub/run.sh").is_file());
+}
+
+#[test]
+fn checkout_invalid_names() {
+  use crate::{Blob, TreeEntry};
+  let tmp_dir = tempdir::TempDir::new("checkout_test").unwrap();
+  let odb = Odb::new(tmp_dir.path().join("objects"));
+  let blob = odb.write_blob(&Blob::new("[core]")).unwrap();
+  for name in [".git", ".GIT", "..", "a/b"] {
+    let tree = odb
+      .write_tree(&Tree::new(vec![TreeEntry::new(
+        FileMode::NonExecutableFile,
+        name,
+        blob,
+      )]))
+      .unwrap();
+    let result = checkout_tree(
+      &odb,
+      &tree,
+      tmp_dir.path().join("work"),
+      &CheckoutOptions::default(),
+    );
+    assert!(
+      matches!(result, Err(CheckoutError::InvalidPath(_))),
+      "{}",
+      name
+    );
+  }
+}
+
+#[test]
+fn checkout_index() {
+  let tmp_dir = tempdir::TempDir::new("checkout_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let tree = test_tree(repo.odb());
+  repo.checkout_tree(&tree).unwrap();
+  let index = repo.index().unwrap();
+  let paths: Vec<_> = index.entries().iter().map(|e| e.path.clone()).collect();
+  assert_eq!(vec!["a.txt", "link", "sub/run.sh"], paths);
+  let options = CheckoutOptions::from_config(repo.config()).unwrap();
+  for entry in index.entries() {
+    let path = tmp_dir.path().join(entry.path.to_path().unwrap());
+    let metadata = fs::symlink_metadata(path).unwrap();
+    assert!(entry.is_stat_clean(&metadata, &options), "{}", entry.path);
+  }
+}
+
+#[cfg(unix)]
+#[test]
+fn checkout_without_file_mode() {
+  use std::os::unix::fs::PermissionsExt;
+  let tmp_dir = tempdir::TempDir::new("checkout_test").unwrap();
+  let odb = Odb::new(tmp_dir.path().join("objects"));
+  let tree = test_tree(&odb);
+  let target = tmp_dir.path().join("work");
+  let options = CheckoutOptions {
+    file_mode: false,
+    ..CheckoutOptions::default()
+  };
+  let index = checkout_tree(&odb, &tree, &target, &options).unwrap();
+  let metadata = fs::metadata(target.join("sub/run.sh")).unwrap();
+  assert_eq!(0, metadata.permissions().mode() & 0o111);
+  // The index still records the file as executable and a file without the
+  // executable bit is not a change
+  let entry = index.get("sub/run.sh").unwrap();
+  assert_eq!(FileMode::ExecutableFile, entry.mode);
+  assert!(entry.is_stat_clean(&metadata, &options));
+  assert!(!entry.is_stat_clean(&metadata, &CheckoutOptions::default()));
+}
+
+#[test]
+fn checkout_collisions() {
+  use crate::{Blob, CollisionKind, TreeEntry};
+  let tmp_dir = tempdir::TempDir::new("checkout_test").unwrap();
+  let odb = Odb::new(tmp_dir.path().join("objects"));
+  let blob = odb.write_blob(&Blob::new("this is a test")).unwrap();
+  let file = |name: &str| TreeEntry::new(FileMode::NonExecutableFile, name, blob);
+  let sub = odb
+    .write_tree(&Tree::new(vec![
+      file("caf\u{e9}.txt"),
+      file("cafe\u{301}.txt"),
+      file("Long File Name.txt"),
+      file("LONGFI~1.TXT"),
+    ]))
+    .unwrap();
+  let tree = odb
+    .write_tree(&Tree::new(vec![
+      file("README"),
+      file("readme"),
+      TreeEntry::new(FileMode::Tree, "sub", sub),
+    ]))
+    .unwrap();
+  let target = tmp_dir.path().join("work");
+
+  let options = CheckoutOptions {
+    ignore_case: false,
+    precompose_unicode: false,
+    protect_ntfs: false,
+    ..CheckoutOptions::default()
+  };
+  checkout_tree(&odb, &tree, &target, &options).unwrap();
+
+  let options = CheckoutOptions {
+    ignore_case: true,
+    precompose_unicode: true,
+    protect_ntfs: true,
+    ..CheckoutOptions::default()
+  };
+  let collisions = match checkout_tree(&odb, &tree, tmp_dir.path().join("other"), &options) {
+    Err(CheckoutError::Collisions(collisions)) => collisions,
+    result => panic!("expected collisions, got {:?}", result),
+  };
+  assert!(!tmp_dir.path().join("other").exists());
+  let collision = |kind: CollisionKind, paths: &[&str]| PathCollision {
+    kind,
+    paths: paths.iter().map(|&p| p.into()).collect(),
+  };
+  assert_eq!(
+    vec![
+      collision(CollisionKind::Case, &["README", "readme"]),
+      collision(
+        CollisionKind::UnicodeNormalization,
+        &["sub/cafe\u{301}.txt", "sub/caf\u{e9}.txt"]
+      ),
+      collision(
+        CollisionKind::ShortName,
+        &["sub/Long File Name.txt", "sub/LONGFI~1.TXT"]
+      ),
+    ],
+    collisions
+  );
+}
+
+#[test]
+fn checkout_ntfs_dot_git() {
+  use crate::{Blob, TreeEntry};
+  let tmp_dir = tempdir::TempDir::new("checkout_test").unwrap();
+  let odb = Odb::new(tmp_dir.path().join("objects"));
+  let blob = odb.write_blob(&Blob::new("[core]")).unwrap();
+  let options = CheckoutOptions {
+    protect_ntfs: true,
+    ..CheckoutOptions::default()
+  };
+  for name in ["GIT~1", ".git. ", "git~1."] {
+    let tree = odb
+      .write_tree(&Tree::new(vec![TreeEntry::new(
+        FileMode::NonExecutableFile,
+        name,
+        blob,
+      )]))
+      .unwrap();
+    let result = checkout_tree(&odb, &tree, tmp_dir.path().join("work"), &options);
+    assert!(
+      matches!(result, Err(CheckoutError::InvalidPath(_))),
+      "{}",
+      name
+    );
+  }
+}
@@ -0,0 +1,402 @@
+//! Applying the change a commit made on top of `HEAD`, like
+//! `git cherry-pick`, or undoing it, like `git revert`. Both are three-way
+//! merges with [`merge_trees`][crate::merge_trees] where the commit and its
+//! parent are the two ends of the change: a cherry-pick merges the commit
+//! against its parent, and a revert merges the parent against the commit.
+//!
+//! When the merge is clean the result is committed, otherwise the index and
+//! working tree are left with the conflicts and `CHERRY_PICK_HEAD` or
+//! `REVERT_HEAD` says which commit was being applied, for whatever commits
+//! the resolution to pick up.
+
+use crate::{
+  CheckoutError, Commit, ConfigError, HeadError, MergeError, MergeOptions, OdbError, RefError,
+  Repository, Signature, StatusError, Tree, TreeConflict, OID,
+};
+use bstr::BString;
+use std::{fs, io};
+use thiserror::Error;
+
+/// Options controlling how [`Repository::cherry_pick`] and
+/// [`Repository::revert`] apply a commit
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CherryPickOptions {
+  /// Which parent of a merge commit its change is taken against, counting
+  /// from 1 like `--mainline`. It has to be set for merge commits and only
+  /// for them.
+  pub mainline: Option<usize>,
+  /// Leave the change in the index and the working tree without
+  /// committing it, like `--no-commit`
+  pub no_commit: bool,
+  /// Add a line saying which commit was cherry-picked to the message, like
+  /// `-x`. Reverts always say which commit they revert.
+  pub record_origin: bool,
+}
+
+/// What [`Repository::cherry_pick`] and [`Repository::revert`] did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedCommit {
+  /// The new commit `HEAD` is at, or `None` if there were conflicts or
+  /// [`CherryPickOptions::no_commit`] was set
+  pub commit: Option<OID>,
+  /// The paths that couldn't be merged, which are left in the index and the
+  /// working tree to be resolved
+  pub conflicts: Vec<TreeConflict>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+  CherryPick,
+  Revert,
+}
+
+impl Action {
+  /// The ref naming the commit being applied while there are conflicts
+  fn head(self) -> &'static str {
+    match self {
+      Self::CherryPick => "CHERRY_PICK_HEAD",
+      Self::Revert => "REVERT_HEAD",
+    }
+  }
+}
+
+impl Repository {
+  /// Apply the change `commit` made on top of `HEAD`, like
+  /// `git cherry-pick {commit}`, and commit it with the author and message
+  /// of `commit` and `committer` as the committer.
+  ///
+  /// The tracked files in the index and the working tree have to match
+  /// `HEAD` first, and untracked files are never overwritten. When there
+  /// are conflicts nothing is committed, `CHERRY_PICK_HEAD` points at
+  /// `commit`, and `MERGE_MSG` has the message to commit the resolution
+  /// with.
+  pub fn cherry_pick(
+    &self,
+    commit: &OID,
+    committer: &Signature,
+    options: &CherryPickOptions,
+  ) -> Result<AppliedCommit, CherryPickError> {
+    self.replay(Action::CherryPick, commit, committer, options)
+  }
+
+  /// Undo the change `commit` made on top of `HEAD`, like
+  /// `git revert {commit}`, and commit that with `committer` as both the
+  /// author and the committer. When there are conflicts `REVERT_HEAD`
+  /// points at `commit`, the same as [`Repository::cherry_pick`] does with
+  /// `CHERRY_PICK_HEAD`.
+  pub fn revert(
+    &self,
+    commit: &OID,
+    committer: &Signature,
+    options: &CherryPickOptions,
+  ) -> Result<AppliedCommit, CherryPickError> {
+    self.replay(Action::Revert, commit, committer, options)
+  }
+
+  fn replay(
+    &self,
+    action: Action,
+    oid: &OID,
+    committer: &Signature,
+    options: &CherryPickOptions,
+  ) -> Result<AppliedCommit, CherryPickError> {
+    if self.is_bare() {
+      return Err(CherryPickError::BareRepository);
+    }
+    for action in [Action::CherryPick, Action::Revert] {
+      if self.refs().read(action.head())?.is_some() {
+        return Err(CherryPickError::InProgress(action.head()));
+      }
+    }
+    let head = *self.head()?.oid().ok_or(CherryPickError::Unborn)?;
+    let changed = self.tracked_changes()?;
+    if !changed.is_empty() {
+      return Err(CherryPickError::LocalChanges(changed));
+    }
+
+    let odb = self.odb();
+    let commit = odb.read_commit(oid)?;
+    let parents = commit.parents();
+    let parent = match (parents.len(), options.mainline) {
+      (0, None) => None,
+      (1, None) => Some(parents[0]),
+      (_, None) => return Err(CherryPickError::MainlineRequired(*oid)),
+      (0 | 1, Some(_)) => return Err(CherryPickError::NotAMerge(*oid)),
+      (count, Some(mainline)) if mainline == 0 || mainline > count => {
+        return Err(CherryPickError::InvalidMainline {
+          commit: *oid,
+          mainline,
+        })
+      }
+      (_, Some(mainline)) => Some(parents[mainline - 1]),
+    };
+    let parent_tree = match parent {
+      Some(parent) => *odb.read_commit(&parent)?.tree(),
+      None => odb.write_tree(&Tree::default())?,
+    };
+
+    let message = commit.message_lossy();
+    let subject = message.lines().next().unwrap_or("");
+    let name = format!("{} ({})", odb.abbreviate(oid, None)?, subject);
+    let mut merge_options = MergeOptions::from_config(self.config())?;
+    merge_options.ours_label = Some("HEAD".into());
+    let (ancestor, theirs) = match action {
+      Action::CherryPick => (&parent_tree, commit.tree()),
+      Action::Revert => (commit.tree(), &parent_tree),
+    };
+    let (ancestor_label, theirs_label) = match action {
+      Action::CherryPick => (format!("parent of {}", name), name),
+      Action::Revert => (name.clone(), format!("parent of {}", name)),
+    };
+    merge_options.ancestor_label = Some(ancestor_label.into());
+    merge_options.theirs_label = Some(theirs_label.into());
+    let head_tree = *odb.read_commit(&head)?.tree();
+    let merged = crate::merge_trees(odb, Some(ancestor), &head_tree, theirs, &merge_options)?;
+    self.checkout_merged(&merged)?;
+
+    let message = match action {
+      Action::CherryPick if options.record_origin => format!(
+        "{}\n\n(cherry picked from commit {})\n",
+        message.trim_end(),
+        oid
+      ),
+      Action::CherryPick => message.into_owned(),
+      Action::Revert => match (parent, options.mainline) {
+        (Some(parent), Some(_)) => format!(
+          "Revert \"{}\"\n\nThis reverts commit {}, reversing\nchanges made to {}.\n",
+          subject, oid, parent
+        ),
+        _ => format!("Revert \"{}\"\n\nThis reverts commit {}.\n", subject, oid),
+      },
+    };
+    if !merged.is_clean() || options.no_commit {
+      let mut merge_msg = message;
+      if !merged.is_clean() {
+        self.refs().write(action.head(), oid)?;
+        merge_msg.push_str("\n# Conflicts:\n");
+        for conflict in &merged.conflicts {
+          merge_msg.push_str(&format!("#\t{}\n", conflict.path));
+        }
+      }
+      fs::write(self.git_dir().join("MERGE_MSG"), merge_msg)?;
+      return Ok(AppliedCommit {
+        commit: None,
+        conflicts: merged.conflicts,
+      });
+    }
+
+    let author = match action {
+      Action::CherryPick => commit.author().clone(),
+      Action::Revert => committer.clone(),
+    };
+    let reflog = match action {
+      Action::CherryPick => format!("cherry-pick: {}", message.lines().next().unwrap_or("")),
+      Action::Revert => format!("revert: {}", message.lines().next().unwrap_or("")),
+    };
+    let new = Commit::new(merged.tree, vec![head], author, committer.clone(), message);
+    let new = odb.write_commit(&new)?;
+    self.advance_head(Some(head), &new, committer, &reflog)?;
+    Ok(AppliedCommit {
+      commit: Some(new),
+      conflicts: Vec::new(),
+    })
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to cherry-picking and reverting commits
+pub enum CherryPickError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Head(#[from] HeadError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Merge(#[from] MergeError),
+  #[error("{0}")]
+  Status(#[from] StatusError),
+  #[error("{0}")]
+  Checkout(#[from] CheckoutError),
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("a bare repository has no working tree to apply commits to")]
+  BareRepository,
+  #[error("HEAD is on a branch with no commits yet")]
+  Unborn,
+  #[error("{0} exists, finish or abort the commit being applied first")]
+  InProgress(&'static str),
+  #[error("local changes would be overwritten: {0:?}")]
+  LocalChanges(Vec<BString>),
+  #[error("commit {0} is a merge but no mainline was given")]
+  MainlineRequired(OID),
+  #[error("a mainline was given but commit {0} is not a merge")]
+  NotAMerge(OID),
+  #[error("commit {commit} has no parent {mainline}")]
+  InvalidMainline { commit: OID, mainline: usize },
+}
+
+#[test]
+fn cherry_pick_and_revert() {
+  use crate::{diff::write_tree, FileMode::NonExecutableFile, Time};
+  use bstr::ByteSlice;
+  let tmp_dir = tempdir::TempDir::new("cherry_pick_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let work_dir = repo.work_dir().unwrap();
+  let odb = repo.odb().clone();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |parents: Vec<OID>, files: &[(&str, &str)], message: &str| {
+    let files: Vec<_> = files
+      .iter()
+      .map(|&(path, contents)| (path, NonExecutableFile, contents))
+      .collect();
+    let tree = write_tree(&odb, &files);
+    let commit = Commit::new(tree, parents, signature.clone(), signature.clone(), message);
+    odb.write_commit(&commit).unwrap()
+  };
+  let base = [("a.txt", "one\ntwo\nthree\n"), ("b.txt", "b\n")];
+  let root = commit(vec![], &base, "root\n");
+  let side = commit(
+    vec![root],
+    &[("a.txt", "one\ntwo\nTHREE\n"), ("c/d.txt", "d\n")],
+    "side\n",
+  );
+  let master = commit(
+    vec![root],
+    &[("a.txt", "ONE\ntwo\nthree\n"), ("b.txt", "b\n")],
+    "master\n",
+  );
+  repo.refs().write("refs/heads/master", &master).unwrap();
+  repo
+    .checkout_tree(odb.read_commit(&master).unwrap().tree())
+    .unwrap();
+  let committer = Signature::new(
+    "C O Mitter",
+    "committer@example.com",
+    Time::new(1_234_567_900, 0),
+  );
+  let options = CherryPickOptions::default();
+
+  // The change the side branch made lands on top of master, deleting b.txt
+  let picked = repo
+    .cherry_pick(&side, &committer, &options)
+    .unwrap()
+    .commit
+    .unwrap();
+  let picked_commit = odb.read_commit(&picked).unwrap();
+  assert_eq!(&[master], picked_commit.parents());
+  assert_eq!("A U Thor", picked_commit.author().name);
+  assert_eq!("side\n", picked_commit.raw_message());
+  assert_eq!(Some(picked), repo.refs().resolve("HEAD").unwrap());
+  assert_eq!(
+    "ONE\ntwo\nTHREE\n",
+    fs::read_to_string(work_dir.join("a.txt")).unwrap()
+  );
+  assert!(!work_dir.join("b.txt").exists());
+  assert!(work_dir.join("c/d.txt").exists());
+  assert!(repo.status().unwrap().is_empty());
+
+  // Reverting it brings master back
+  let reverted = repo
+    .revert(&picked, &committer, &options)
+    .unwrap()
+    .commit
+    .unwrap();
+  let reverted_commit = odb.read_commit(&reverted).unwrap();
+  assert_eq!(
+    odb.read_commit(&master).unwrap().tree(),
+    reverted_commit.tree()
+  );
+  assert_eq!("C O Mitter", reverted_commit.author().name);
+  assert_eq!(
+    format!("Revert \"side\"\n\nThis reverts commit {}.\n", picked),
+    reverted_commit.raw_message()
+  );
+  assert!(!work_dir.join("c").exists());
+
+  // Merges need a mainline
+  let merge = commit(
+    vec![master, side],
+    &[("a.txt", "ONE\ntwo\nTHREE\n"), ("c/d.txt", "d\n")],
+    "merge\n",
+  );
+  assert!(matches!(
+    repo.cherry_pick(&merge, &committer, &options),
+    Err(CherryPickError::MainlineRequired(_))
+  ));
+  assert!(matches!(
+    repo.cherry_pick(
+      &side,
+      &committer,
+      &CherryPickOptions {
+        mainline: Some(1),
+        ..CherryPickOptions::default()
+      }
+    ),
+    Err(CherryPickError::NotAMerge(_))
+  ));
+  let mainline = CherryPickOptions {
+    mainline: Some(1),
+    no_commit: true,
+    ..CherryPickOptions::default()
+  };
+  let applied = repo.cherry_pick(&merge, &committer, &mainline).unwrap();
+  assert_eq!(None, applied.commit);
+  assert!(applied.conflicts.is_empty());
+  assert_eq!(
+    "ONE\ntwo\nTHREE\n",
+    fs::read_to_string(work_dir.join("a.txt")).unwrap()
+  );
+  assert!(matches!(
+    repo.cherry_pick(&side, &committer, &options),
+    Err(CherryPickError::LocalChanges(_))
+  ));
+  repo.checkout_tree(reverted_commit.tree()).unwrap();
+  fs::remove_dir_all(work_dir.join("c")).unwrap();
+
+  // Conflicts are left to be resolved
+  let conflicting = commit(
+    vec![root],
+    &[("a.txt", "uno\ntwo\nthree\n"), ("b.txt", "b\n")],
+    "conflicting\n",
+  );
+  let applied = repo
+    .cherry_pick(&conflicting, &committer, &options)
+    .unwrap();
+  assert_eq!(None, applied.commit);
+  assert_eq!(1, applied.conflicts.len());
+  assert_eq!("a.txt", applied.conflicts[0].path);
+  assert_eq!(
+    Some(conflicting),
+    repo.refs().resolve("CHERRY_PICK_HEAD").unwrap()
+  );
+  assert_eq!(Some(reverted), repo.refs().resolve("HEAD").unwrap());
+  let merge_msg = fs::read_to_string(repo.git_dir().join("MERGE_MSG")).unwrap();
+  assert_eq!("conflicting\n\n# Conflicts:\n#\ta.txt\n", merge_msg);
+  assert!(fs::read_to_string(work_dir.join("a.txt"))
+    .unwrap()
+    .starts_with("<<<<<<< HEAD\n"));
+  assert!(matches!(
+    repo.revert(&picked, &committer, &options),
+    Err(CherryPickError::InProgress("CHERRY_PICK_HEAD"))
+  ));
+
+  // Git sees the same conflict
+  if crate::transport::http::have_git() {
+    let output = std::process::Command::new("git")
+      .args(["status", "--porcelain"])
+      .current_dir(work_dir)
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .output()
+      .unwrap();
+    assert!(output.status.success());
+    assert_eq!("UU a.txt\n", output.stdout.to_str_lossy());
+  }
+}
@@ -0,0 +1,270 @@
+use crate::{zlib, Repository, RepositoryError};
+use std::{
+  fs, io,
+  path::{Path, PathBuf},
+  process,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    OnceLock,
+  },
+  time::{Duration, SystemTime},
+};
+
+/// How old files have to be before [`Repository::cleanup_stale`] removes
+/// them. Temporary files left behind by a process that is known to have
+/// exited are removed no matter how old they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CleanupOptions {
+  /// Temporary objects and packs older than this are removed even if the
+  /// process that made them can't be checked. Defaults to two weeks, the
+  /// same as `git prune`.
+  pub temp_max_age: Duration,
+  /// `*.lock` files older than this are removed. Lock files don't record
+  /// who made them so only their age is used. Defaults to one hour.
+  pub lock_max_age: Duration,
+}
+
+impl Default for CleanupOptions {
+  fn default() -> Self {
+    Self {
+      temp_max_age: Duration::from_secs(14 * 24 * 60 * 60),
+      lock_max_age: Duration::from_secs(60 * 60),
+    }
+  }
+}
+
+impl Repository {
+  /// Remove temporary objects, temporary packs, and lock files that were
+  /// left behind by processes that crashed or were killed, returning the
+  /// paths that were removed. This is meant to be run when a long lived
+  /// process, like a server, opens a repository or every so often after.
+  pub fn cleanup_stale(&self, options: &CleanupOptions) -> Result<Vec<PathBuf>, RepositoryError> {
+    let now = SystemTime::now();
+    let mut removed = Vec::new();
+
+    let objects = self.odb().path();
+    for dir in read_dir(objects)? {
+      let name = dir.file_name().and_then(|name| name.to_str()).unwrap_or("");
+      if (name.len() == 2 && name.bytes().all(|c| c.is_ascii_hexdigit())) || name == "pack" {
+        for path in read_dir(&dir)? {
+          if is_temp(&path) && temp_is_stale(&path, now, options)? {
+            remove(path, &mut removed)?;
+          }
+        }
+      }
+    }
+
+    let git_dir = self.common_dir();
+    for path in read_dir(git_dir)?
+      .into_iter()
+      .chain(read_dir_recursive(&git_dir.join("refs"))?)
+    {
+      if path.extension() == Some("lock".as_ref()) && older_than(&path, now, options.lock_max_age)?
+      {
+        remove(path, &mut removed)?;
+      }
+    }
+    Ok(removed)
+  }
+}
+
+/// A name for a temporary file like `tmp_obj_{host}_{pid}_{n}` that is
+/// unique within this process and across processes. The host and pid let
+/// [`Repository::cleanup_stale`] tell when the process that made the file is
+/// gone.
+pub(crate) fn temp_name(kind: &str) -> String {
+  static COUNTER: AtomicUsize = AtomicUsize::new(0);
+  format!(
+    "tmp_{}_{}_{}_{}",
+    kind,
+    host_id().unwrap_or("unknown"),
+    process::id(),
+    COUNTER.fetch_add(1, Ordering::Relaxed)
+  )
+}
+
+/// Which pids this process can see, as the boot id of the machine together
+/// with the pid namespace, so that a file made by a container or another
+/// machine sharing the repository over NFS isn't mistaken for one whose
+/// process is gone. `None` where that can't be known.
+fn host_id() -> Option<&'static str> {
+  static HOST_ID: OnceLock<Option<String>> = OnceLock::new();
+  HOST_ID
+    .get_or_init(|| {
+      if !cfg!(target_os = "linux") {
+        return None;
+      }
+      let boot_id = fs::read("/proc/sys/kernel/random/boot_id").ok()?;
+      let namespace = fs::read_link("/proc/self/ns/pid").ok()?;
+      let id = [boot_id.trim_ascii(), namespace.to_str()?.as_bytes()].concat();
+      Some(format!("{:08x}", zlib::crc32(&id)))
+    })
+    .as_deref()
+}
+
+/// Whether the file is one of our temporary files or one of git's, which
+/// use the same prefixes with a random suffix instead of a pid
+fn is_temp(path: &Path) -> bool {
+  let name = match path.file_name().and_then(|name| name.to_str()) {
+    Some(name) => name,
+    None => return false,
+  };
+  ["tmp_obj_", "tmp_pack_", "tmp_idx_"]
+    .iter()
+    .any(|prefix| name.starts_with(prefix))
+}
+
+fn temp_is_stale(path: &Path, now: SystemTime, options: &CleanupOptions) -> io::Result<bool> {
+  let owner = path
+    .file_name()
+    .and_then(|name| name.to_str())
+    .and_then(temp_owner);
+  match owner {
+    // Pids from another machine or pid namespace say nothing about the
+    // processes here
+    Some((host, pid)) if Some(host) == host_id() => {
+      // This process may still be writing it
+      if pid == process::id() {
+        return Ok(false);
+      }
+      if process_alive(pid) == Some(false) {
+        return Ok(true);
+      }
+    }
+    _ => {}
+  }
+  older_than(path, now, options.temp_max_age)
+}
+
+/// The host and pid in a temporary file name made by [`temp_name`]
+fn temp_owner(name: &str) -> Option<(&str, u32)> {
+  let mut parts = name.strip_prefix("tmp_")?.split('_').skip(1);
+  let host = parts.next()?;
+  let pid = parts.next()?.parse().ok()?;
+  let counter = parts.next()?;
+  if parts.next().is_some() || counter.is_empty() || !counter.bytes().all(|c| c.is_ascii_digit()) {
+    return None;
+  }
+  Some((host, pid))
+}
+
+/// Whether a process is still running, or `None` if that can't be known on
+/// this platform. A pid that was reused by another process counts as
+/// running, which only means the file waits until it's old enough instead.
+fn process_alive(pid: u32) -> Option<bool> {
+  if cfg!(target_os = "linux") {
+    Some(Path::new("/proc").join(pid.to_string()).exists())
+  } else {
+    None
+  }
+}
+
+fn older_than(path: &Path, now: SystemTime, max_age: Duration) -> io::Result<bool> {
+  let modified = match fs::symlink_metadata(path) {
+    Ok(metadata) => metadata.modified()?,
+    // Another process may have finished with the file in the meantime
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+    Err(e) => return Err(e),
+  };
+  // A time in the future gives an error here and is treated as new
+  Ok(now.duration_since(modified).is_ok_and(|age| age > max_age))
+}
+
+fn remove(path: PathBuf, removed: &mut Vec<PathBuf>) -> io::Result<()> {
+  match fs::remove_file(&path) {
+    Ok(()) => removed.push(path),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+    Err(e) => return Err(e),
+  }
+  Ok(())
+}
+
+fn read_dir(dir: &Path) -> io::Result<Vec<PathBuf>> {
+  match fs::read_dir(dir) {
+    Ok(entries) => entries.map(|entry| Ok(entry?.path())).collect(),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+    Err(e) => Err(e),
+  }
+}
+
+fn read_dir_recursive(dir: &Path) -> io::Result<Vec<PathBuf>> {
+  let mut paths = Vec::new();
+  for path in read_dir(dir)? {
+    if fs::symlink_metadata(&path)?.is_dir() {
+      paths.extend(read_dir_recursive(&path)?);
+    } else {
+      paths.push(path);
+    }
+  }
+  Ok(paths)
+}
+
+#[test]
+fn cleanup_stale() {
+  let tmp_dir = tempdir::TempDir::new("cleanup_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let git_dir = repo.git_dir();
+  fs::create_dir_all(git_dir.join("objects/ab")).unwrap();
+  fs::create_dir_all(git_dir.join("refs/heads/feature")).unwrap();
+
+  let ours = git_dir.join("objects/ab").join(temp_name("obj"));
+  // pids are at most 2^22 on Linux so this one can't be running
+  let host = host_id().unwrap_or("unknown");
+  let dead = git_dir.join(format!("objects/ab/tmp_obj_{}_4294967295_0", host));
+  // The same pid from another machine or container has to wait until it's
+  // old enough
+  let elsewhere = git_dir.join("objects/ab/tmp_obj_00000000_4294967295_0");
+  let git_temp = git_dir.join("objects/pack/tmp_pack_Xy12Ab");
+  let config_lock = git_dir.join("config.lock");
+  let ref_lock = git_dir.join("refs/heads/feature/x.lock");
+  for path in [&ours, &dead, &elsewhere, &git_temp, &config_lock, &ref_lock] {
+    fs::write(path, "").unwrap();
+  }
+
+  let removed = repo.cleanup_stale(&CleanupOptions::default()).unwrap();
+  if cfg!(target_os = "linux") {
+    assert_eq!(vec![dead.clone()], removed);
+  }
+  assert!(ours.exists());
+  assert!(elsewhere.exists());
+  assert!(git_temp.exists());
+  assert!(config_lock.exists());
+
+  let hours_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+  for path in [&ours, &elsewhere, &git_temp, &config_lock, &ref_lock] {
+    let file = fs::File::options().write(true).open(path).unwrap();
+    file.set_modified(hours_ago).unwrap();
+  }
+  let removed = repo
+    .cleanup_stale(&CleanupOptions {
+      temp_max_age: Duration::from_secs(60),
+      lock_max_age: Duration::from_secs(60),
+    })
+    .unwrap();
+  // Files from this process are never removed since they may still be in
+  // use
+  assert!(ours.exists());
+  assert!(!dead.exists());
+  assert!(removed.contains(&elsewhere));
+  assert!(removed.contains(&git_temp));
+  assert!(removed.contains(&config_lock));
+  assert!(removed.contains(&ref_lock));
+  assert!(git_dir.join("HEAD").exists());
+}
+
+#[test]
+fn temp_names() {
+  let name = temp_name("obj");
+  assert_eq!(
+    Some((host_id().unwrap_or("unknown"), process::id())),
+    temp_owner(&name)
+  );
+  if cfg!(target_os = "linux") {
+    assert!(host_id().is_some());
+  }
+  assert_eq!(Some(("0a1b2c3d", 12)), temp_owner("tmp_pack_0a1b2c3d_12_3"));
+  assert_eq!(None, temp_owner("tmp_obj_Xy12Ab"));
+  assert_eq!(None, temp_owner("tmp_obj_12_3"));
+  assert_eq!(None, temp_owner("tmp_obj_0a1b2c3d_12_"));
+  assert_eq!(None, temp_owner("tmp_obj_0a1b2c3d_12_3_4"));
+}
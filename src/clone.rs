@@ -0,0 +1,408 @@
+use crate::{
+  transport::{self, FetchOptions, TransportError},
+  CancellationToken, CheckoutError, ConfigError, ConfigFile, ConfigLevel, FileMode, Odb, OdbError,
+  Progress, RefError, Refspec, RemotePromisor, Repository, RepositoryError, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+use thiserror::Error;
+
+/// Options for [`Repository::clone`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloneOptions {
+  /// Make a bare repository, with the branches of the remote as its own
+  /// branches and nothing checked out, like `git clone --bare`
+  pub bare: bool,
+  /// The branch to check out instead of the one the remote's `HEAD` is on,
+  /// like `git clone --branch`
+  pub branch: Option<String>,
+  /// Only fetch the branch that's checked out and leave out the tags, like
+  /// `git clone --single-branch --no-tags`
+  pub single_branch: bool,
+  /// Only fetch this many commits of history, like `git clone --depth`,
+  /// which makes a shallow clone. Local paths can't do that.
+  pub depth: Option<u32>,
+  /// Leave out objects with a filter like `blob:none` or `blob:limit=1m`,
+  /// which makes a partial clone, like `git clone --filter`. What's left
+  /// out is fetched from the remote when it's needed.
+  pub filter: Option<String>,
+  /// The name of the remote, `origin` by default
+  pub remote: String,
+  /// Borrow the objects of the repository at this path through
+  /// `objects/info/alternates` instead of fetching them, like `git clone
+  /// --reference`. Only what it doesn't have is fetched, so it must not
+  /// lose its objects while the clone is around.
+  pub reference: Option<PathBuf>,
+  /// Stop the clone once this is cancelled, with the repository keeping it
+  /// afterwards. What was cloned so far is left behind.
+  pub cancellation: Option<CancellationToken>,
+}
+
+impl Default for CloneOptions {
+  fn default() -> Self {
+    Self {
+      bare: false,
+      branch: None,
+      single_branch: false,
+      depth: None,
+      filter: None,
+      remote: "origin".into(),
+      reference: None,
+      cancellation: None,
+    }
+  }
+}
+
+impl Repository {
+  /// Clone the repository at `url` into `path`, which can't exist yet or
+  /// has to be an empty directory, like `git clone`. The remote is set up
+  /// in the config with its branches under `refs/remotes/{remote}/`, the
+  /// branch the remote's `HEAD` is on is made a local branch following it,
+  /// and that branch is checked out. The transport is picked by
+  /// [`transport::connect`].
+  ///
+  /// Cloning an empty repository leaves an unborn branch checked out.
+  pub fn clone(
+    url: &str,
+    path: impl AsRef<Path>,
+    options: &CloneOptions,
+  ) -> Result<Self, CloneError> {
+    Self::clone_into(url, path.as_ref(), options, None)
+  }
+
+  /// [`Repository::clone`] reporting how far along receiving the objects
+  /// and checking out the branch are to `progress`, which the repository
+  /// keeps. If it's cancelled the clone fails with [`OdbError::Cancelled`],
+  /// leaving what was cloned so far behind.
+  pub fn clone_with_progress(
+    url: &str,
+    path: impl AsRef<Path>,
+    options: &CloneOptions,
+    progress: impl Progress + 'static,
+  ) -> Result<Self, CloneError> {
+    Self::clone_into(url, path.as_ref(), options, Some(Arc::new(progress)))
+  }
+
+  fn clone_into(
+    url: &str,
+    path: &Path,
+    options: &CloneOptions,
+    progress: Option<Arc<dyn Progress>>,
+  ) -> Result<Self, CloneError> {
+    if path.exists() && fs::read_dir(path)?.next().is_some() {
+      return Err(CloneError::NotEmpty(path.into()));
+    }
+    let mut transport = transport::connect(url)?;
+    let advertisement = transport.list_refs()?.clone();
+    let branch = match &options.branch {
+      Some(branch) => {
+        let name = format!("refs/heads/{}", branch);
+        match advertisement.get(&name) {
+          Some(reference) => Some((name, reference.oid)),
+          None => return Err(CloneError::BranchNotFound(branch.clone())),
+        }
+      }
+      None => {
+        default_branch(&advertisement).map(|(name, oid)| (name.to_str_lossy().into_owned(), oid))
+      }
+    };
+
+    let mut repo = match options.bare {
+      true => Repository::init_bare(path)?,
+      false => Repository::init(path)?,
+    };
+    if let Some(progress) = progress {
+      repo.set_shared_progress(progress);
+    }
+    if let Some(token) = &options.cancellation {
+      repo.set_cancellation(token.clone());
+    }
+    if let Some(reference) = &options.reference {
+      let reference = Repository::open(reference)?;
+      repo.add_alternate(reference.common_dir().join("objects"))?;
+    }
+    // Bare repositories take the branches as they are
+    let remote = &options.remote;
+    let refspec = match (options.bare, &branch) {
+      (true, Some((name, _))) if options.single_branch => {
+        Refspec::new(name.as_str(), name.as_str(), true)
+      }
+      (true, _) => Refspec::new("refs/heads/*", "refs/heads/*", true),
+      (false, Some((name, _))) if options.single_branch => {
+        let short = &name["refs/heads/".len()..];
+        Refspec::new(
+          name.as_str(),
+          format!("refs/remotes/{}/{}", remote, short),
+          true,
+        )
+      }
+      (false, _) => Refspec::new("refs/heads/*", format!("refs/remotes/{}/*", remote), true),
+    };
+    let mut config = ConfigFile::from_file(repo.git_dir().join("config"), ConfigLevel::Local)?;
+    config.set(&format!("remote.{}.url", remote), url)?;
+    if !options.bare {
+      config.set(&format!("remote.{}.fetch", remote), refspec.to_string())?;
+    }
+    if let Some(filter) = &options.filter {
+      // Versions of git that don't know about partial clones can't
+      // open the repository then
+      config.set("core.repositoryformatversion", "1")?;
+      config.set("extensions.partialclone", remote)?;
+      config.set(&format!("remote.{}.promisor", remote), "true")?;
+      config.set(&format!("remote.{}.partialclonefilter", remote), filter)?;
+    }
+
+    // The refs that are cloned and where they go
+    let mut updates = Vec::new();
+    for reference in &advertisement.refs {
+      let name = reference.name.as_bstr();
+      if let Some(dst) = refspec.transform(name) {
+        updates.push((dst.to_string(), reference.oid));
+      } else if name.starts_with(b"refs/tags/") && !options.single_branch {
+        updates.push((name.to_string(), reference.oid));
+      }
+    }
+    let wants: Vec<OID> = updates.iter().map(|(_, oid)| *oid).collect();
+    let fetch_options = FetchOptions {
+      depth: options.depth,
+      filter: options.filter.clone(),
+      ..FetchOptions::default()
+    };
+    transport.fetch_with_options(&repo, &wants, &fetch_options)?;
+    for (name, oid) in &updates {
+      repo.refs().write(name, oid)?;
+    }
+
+    let head = match &branch {
+      Some((name, oid)) => Some((name.clone(), *oid)),
+      // A remote without any commits still says which branch is unborn
+      None => {
+        let target = advertisement
+          .get("HEAD")
+          .and_then(|head| head.symref_target.as_ref());
+        if let Some(target) = target {
+          repo.refs().write_symbolic("HEAD", target)?;
+        }
+        None
+      }
+    };
+    if let Some((name, oid)) = &head {
+      repo.refs().write_symbolic("HEAD", name)?;
+      if !options.bare {
+        let short = &name["refs/heads/".len()..];
+        repo.refs().write(name, oid)?;
+        repo.refs().write_symbolic(
+          format!("refs/remotes/{}/HEAD", remote),
+          format!("refs/remotes/{}/{}", remote, short),
+        )?;
+        config.set(&format!("branch.{}.remote", short), remote)?;
+        config.set(&format!("branch.{}.merge", short), name)?;
+      }
+    }
+    config.save()?;
+
+    repo.reload_config()?;
+    if let Some(promisor) = RemotePromisor::from_config(&repo) {
+      repo.set_promisor(promisor);
+    }
+    if let (Some((_, oid)), false) = (&head, options.bare) {
+      let tree = *repo.odb().read_commit(oid)?.tree();
+      // Everything a partial clone left out of the tree comes in one fetch
+      if options.filter.is_some() {
+        let mut blobs = Vec::new();
+        tree_blobs(repo.odb(), &tree, &mut blobs)?;
+        repo.odb().prefetch(&blobs)?;
+      }
+      repo.checkout_tree(&tree)?;
+    }
+    Ok(repo)
+  }
+}
+
+/// Add the blobs of `tree` and its subtrees to `blobs`
+fn tree_blobs(odb: &Odb, tree: &OID, blobs: &mut Vec<OID>) -> Result<(), OdbError> {
+  for entry in odb.read_tree(tree)?.entries() {
+    match entry.mode() {
+      FileMode::Tree => tree_blobs(odb, entry.oid(), blobs)?,
+      FileMode::GitLink => {}
+      _ => blobs.push(*entry.oid()),
+    }
+  }
+  Ok(())
+}
+
+/// The branch the remote's `HEAD` is on. Remotes that don't say which one
+/// it is get the one `HEAD` is at guessed, preferring `master` like git
+/// does.
+fn default_branch(advertisement: &transport::Advertisement) -> Option<(BString, OID)> {
+  let head = advertisement.get("HEAD")?;
+  let is_branch = |name: &BStr| name.starts_with(b"refs/heads/");
+  if let Some(target) = head
+    .symref_target
+    .as_ref()
+    .filter(|t| is_branch(t.as_bstr()))
+  {
+    return Some((target.clone(), advertisement.get(target)?.oid));
+  }
+  let mut branches = advertisement
+    .refs
+    .iter()
+    .filter(|reference| is_branch(reference.name.as_bstr()) && reference.oid == head.oid);
+  let first = branches.clone().next()?;
+  let branch = branches
+    .find(|reference| reference.name == "refs/heads/master")
+    .unwrap_or(first);
+  Some((branch.name.clone(), branch.oid))
+}
+
+#[derive(Error, Debug)]
+/// Errors related to cloning repositories
+pub enum CloneError {
+  #[error("{0}")]
+  Io(#[from] std::io::Error),
+  #[error("{0}")]
+  Repository(#[from] RepositoryError),
+  #[error("{0}")]
+  Transport(#[from] TransportError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Checkout(#[from] CheckoutError),
+  #[error("destination path {0:?} already exists and is not an empty directory")]
+  NotEmpty(PathBuf),
+  #[error("remote branch {0} not found")]
+  BranchNotFound(String),
+}
+
+#[test]
+fn clone() {
+  use crate::{Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  let tmp_dir = tempdir::TempDir::new("clone_test").unwrap();
+  let source = Repository::init_bare(tmp_dir.path().join("source.git")).unwrap();
+  let url = tmp_dir.path().join("source.git").display().to_string();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |branch: &str, contents: &str| {
+    let odb = source.odb();
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(
+      tree,
+      vec![],
+      signature.clone(),
+      signature.clone(),
+      "commit\n",
+    );
+    let oid = odb.write_commit(&commit).unwrap();
+    source.refs().write(branch, &oid).unwrap();
+    oid
+  };
+
+  // An empty repository still gets its remote
+  let empty =
+    Repository::clone(&url, tmp_dir.path().join("empty"), &CloneOptions::default()).unwrap();
+  assert_eq!(None, empty.refs().resolve("HEAD").unwrap());
+  assert_eq!(
+    Some(url.as_bytes().as_bstr()),
+    empty.config().get("remote.origin.url")
+  );
+
+  let main = commit("refs/heads/master", "main\n");
+  let topic = commit("refs/heads/topic", "topic\n");
+  source.refs().write("refs/tags/v1", &main).unwrap();
+  let path = tmp_dir.path().join("clone");
+  let repo = Repository::clone(&url, &path, &CloneOptions::default()).unwrap();
+  assert_eq!("main\n", fs::read_to_string(path.join("file.txt")).unwrap());
+  for (name, oid) in [
+    ("HEAD", main),
+    ("refs/remotes/origin/HEAD", main),
+    ("refs/remotes/origin/topic", topic),
+    ("refs/tags/v1", main),
+  ]
+  .iter()
+  {
+    assert_eq!(Some(*oid), repo.refs().resolve(name).unwrap(), "{}", name);
+  }
+  let config = repo.config();
+  assert_eq!(
+    Some(b"+refs/heads/*:refs/remotes/origin/*".as_bstr()),
+    config.get("remote.origin.fetch")
+  );
+  assert_eq!(
+    Some(b"refs/heads/master".as_bstr()),
+    config.get("branch.master.merge")
+  );
+  assert!(matches!(
+    Repository::clone(&url, &path, &CloneOptions::default()),
+    Err(CloneError::NotEmpty(_))
+  ));
+
+  // Only the branch asked for, and no tags
+  let options = CloneOptions {
+    branch: Some("topic".into()),
+    single_branch: true,
+    remote: "upstream".into(),
+    ..CloneOptions::default()
+  };
+  let path = tmp_dir.path().join("topic");
+  let repo = Repository::clone(&url, &path, &options).unwrap();
+  assert_eq!(
+    "topic\n",
+    fs::read_to_string(path.join("file.txt")).unwrap()
+  );
+  assert_eq!(
+    Some(topic),
+    repo.refs().resolve("refs/heads/topic").unwrap()
+  );
+  assert_eq!(
+    None,
+    repo.refs().resolve("refs/remotes/upstream/master").unwrap()
+  );
+  assert_eq!(None, repo.refs().resolve("refs/tags/v1").unwrap());
+  let options = CloneOptions {
+    branch: Some("missing".into()),
+    ..CloneOptions::default()
+  };
+  assert!(matches!(
+    Repository::clone(&url, tmp_dir.path().join("missing"), &options),
+    Err(CloneError::BranchNotFound(_))
+  ));
+
+  // A bare clone has the branches as its own
+  let options = CloneOptions {
+    bare: true,
+    ..CloneOptions::default()
+  };
+  let repo = Repository::clone(&url, tmp_dir.path().join("bare.git"), &options).unwrap();
+  assert!(repo.work_dir().is_none());
+  assert_eq!(
+    Some(topic),
+    repo.refs().resolve("refs/heads/topic").unwrap()
+  );
+  assert_eq!(Some(main), repo.refs().resolve("HEAD").unwrap());
+  assert_eq!(None, repo.config().get("remote.origin.fetch"));
+
+  // A clone borrowing the objects of one that has them all fetches nothing
+  let options = CloneOptions {
+    reference: Some(tmp_dir.path().join("clone")),
+    ..CloneOptions::default()
+  };
+  let path = tmp_dir.path().join("reference");
+  let repo = Repository::clone(&url, &path, &options).unwrap();
+  assert_eq!("main\n", fs::read_to_string(path.join("file.txt")).unwrap());
+  assert!(repo.odb().oids().unwrap().is_empty());
+  assert_eq!(1, repo.odb().alternates().len());
+  assert!(repo.fsck(&crate::FsckOptions::default()).unwrap().is_ok());
+}
@@ -0,0 +1,391 @@
+use crate::CheckoutOptions;
+use bstr::{BStr, BString, ByteSlice};
+use std::{collections::BTreeMap, fmt};
+
+/// Why two paths from a [`Tree`][crate::Tree] would end up as the same file
+/// when checked out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CollisionKind {
+  /// The names only differ in case, like `README` and `readme`
+  Case,
+  /// The names are the same text in different Unicode normalization forms,
+  /// like `é` written precomposed and as `e` followed by a combining accent
+  UnicodeNormalization,
+  /// One name is the NTFS 8.3 short name of the other, like `PROGRA~1` for
+  /// `Program Files`
+  ShortName,
+}
+
+/// A set of paths in the same directory that can't all be checked out
+/// because the filesystem would treat them as the same file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathCollision {
+  /// Why the paths collide
+  pub kind: CollisionKind,
+  /// The full paths that collide, in the order they appear in the
+  /// [`Tree`][crate::Tree]
+  pub paths: Vec<BString>,
+}
+
+impl fmt::Display for PathCollision {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let kind = match self.kind {
+      CollisionKind::Case => "differ only in case",
+      CollisionKind::UnicodeNormalization => "differ only in Unicode normalization",
+      CollisionKind::ShortName => "alias through an NTFS short name",
+    };
+    let paths: Vec<_> = self.paths.iter().map(|p| p.to_str_lossy()).collect();
+    write!(f, "{} {}", paths.join(", "), kind)
+  }
+}
+
+/// Whether the [`CheckoutOptions`] ask for any collision checks at all
+pub(crate) fn needs_check(options: &CheckoutOptions) -> bool {
+  options.ignore_case || options.precompose_unicode || options.protect_ntfs
+}
+
+/// Find the names in a single directory of a [`Tree`][crate::Tree] that
+/// would collide when written to a filesystem with the behavior described
+/// by the [`CheckoutOptions`]. `prefix` is the path of the directory, used
+/// to report full paths.
+pub(crate) fn find_collisions(
+  names: &[&BStr],
+  prefix: &[u8],
+  options: &CheckoutOptions,
+) -> Vec<PathCollision> {
+  let full_path = |name: &BStr| BString::from([prefix, name.as_bytes()].concat());
+  let mut collisions = Vec::new();
+
+  if options.ignore_case || options.precompose_unicode {
+    let mut groups: BTreeMap<Vec<u8>, Vec<&BStr>> = BTreeMap::new();
+    for &name in names {
+      groups.entry(fold(name, options)).or_default().push(name);
+    }
+    for group in groups.values().filter(|group| group.len() > 1) {
+      let normalized = |name: &BStr| decompose(name);
+      let kind = if group
+        .iter()
+        .all(|name| normalized(name) == normalized(group[0]))
+      {
+        CollisionKind::UnicodeNormalization
+      } else {
+        CollisionKind::Case
+      };
+      collisions.push(PathCollision {
+        kind,
+        paths: group.iter().map(|name| full_path(name)).collect(),
+      });
+    }
+  }
+
+  if options.protect_ntfs {
+    for &alias in names {
+      let short = match parse_short_name(alias) {
+        Some(short) => short,
+        None => continue,
+      };
+      for &long in names.iter().filter(|&&long| long != alias) {
+        if short.matches(long) {
+          collisions.push(PathCollision {
+            kind: CollisionKind::ShortName,
+            paths: vec![full_path(long), full_path(alias)],
+          });
+        }
+      }
+    }
+  }
+  collisions
+}
+
+/// The key two names collide on. Names that aren't UTF-8 can only collide
+/// through ASCII case differences.
+fn fold(name: &BStr, options: &CheckoutOptions) -> Vec<u8> {
+  let name = if options.precompose_unicode {
+    decompose(name)
+  } else {
+    name.to_vec()
+  };
+  if !options.ignore_case {
+    return name;
+  }
+  match name.to_str() {
+    Ok(name) => name.to_lowercase().into_bytes(),
+    Err(_) => name.to_ascii_lowercase(),
+  }
+}
+
+/// Put a name in Unicode normalization form D, where accented letters are
+/// written as the base letter followed by a combining mark as macOS stores
+/// them. Only the precomposed letters of Latin-1 and Latin Extended-A are
+/// decomposed, which covers the names that clash in practice without
+/// needing the full Unicode tables.
+fn decompose(name: &BStr) -> Vec<u8> {
+  let name = match name.to_str() {
+    Ok(name) => name,
+    Err(_) => return name.to_vec(),
+  };
+  let mut decomposed = String::with_capacity(name.len());
+  for c in name.chars() {
+    match DECOMPOSITIONS.binary_search_by_key(&c, |&(composed, _, _)| composed) {
+      Ok(idx) => {
+        let (_, base, mark) = DECOMPOSITIONS[idx];
+        decomposed.push(base);
+        decomposed.push(mark);
+      }
+      Err(_) => decomposed.push(c),
+    }
+  }
+  decomposed.into_bytes()
+}
+
+/// A name of the form `BASE~N.EXT` that NTFS could have generated as the
+/// short name of a longer one
+struct ShortName {
+  base: Vec<u8>,
+  digits: usize,
+  ext: Vec<u8>,
+}
+
+fn parse_short_name(name: &BStr) -> Option<ShortName> {
+  let (stem, ext) = split_extension(name);
+  let tilde = stem.rfind_byte(b'~')?;
+  let (base, number) = (&stem[..tilde], &stem[tilde + 1..]);
+  if base.is_empty()
+    || stem.len() > 8
+    || ext.len() > 3
+    || number.first().is_none_or(|&d| d == b'0')
+    || !number.iter().all(u8::is_ascii_digit)
+  {
+    return None;
+  }
+  Some(ShortName {
+    base: base.to_ascii_uppercase(),
+    digits: number.len(),
+    ext: ext.to_ascii_uppercase(),
+  })
+}
+
+impl ShortName {
+  /// Whether NTFS could generate this short name for `long`. Windows builds
+  /// short names from the first characters of the name with dots and
+  /// spaces removed, followed by `~` and a number that depends on which
+  /// other names existed when the file was made, so any number matches.
+  fn matches(&self, long: &BStr) -> bool {
+    if !needs_short_name(long) {
+      return false;
+    }
+    let trimmed = long.trim_start_with(|c| c == '.');
+    let (stem, ext) = split_extension(trimmed);
+    let basis = short_name_chars(stem);
+    let ext = short_name_chars(ext);
+    // The base and `~N` together are at most 8 characters
+    let base_len = basis.len().min(7usize.saturating_sub(self.digits));
+    basis[..base_len] == self.base[..] && ext[..ext.len().min(3)] == self.ext[..]
+  }
+}
+
+/// Split a name into the part before the last `.` and the extension after
+/// it
+fn split_extension(name: &[u8]) -> (&[u8], &[u8]) {
+  match name.rfind_byte(b'.') {
+    Some(dot) => (&name[..dot], &name[dot + 1..]),
+    None => (name, b""),
+  }
+}
+
+/// Whether NTFS gives a name a separate 8.3 short name, which it does for
+/// every name that isn't already a valid 8.3 name
+fn needs_short_name(name: &BStr) -> bool {
+  let (stem, ext) = split_extension(name);
+  stem.is_empty()
+    || stem.len() > 8
+    || ext.len() > 3
+    || stem.contains(&b'.')
+    || name
+      .iter()
+      .any(|&c| !(c.is_ascii_alphanumeric() || b".!#$%&'()-@^_`{}~".contains(&c)))
+}
+
+/// The characters of a name as they are used in a short name: upper case,
+/// with dots and spaces dropped and characters that aren't allowed in short
+/// names replaced with `_`
+fn short_name_chars(name: &[u8]) -> Vec<u8> {
+  name
+    .iter()
+    // Continuation bytes are skipped so a character outside of ASCII is a
+    // single `_`
+    .filter(|&&c| c != b'.' && c != b' ' && !(0x80..0xc0).contains(&c))
+    .map(|&c| match c {
+      b'+' | b',' | b';' | b'=' | b'[' | b']' => b'_',
+      c if !c.is_ascii() => b'_',
+      c => c.to_ascii_uppercase(),
+    })
+    .collect()
+}
+
+/// Precomposed characters and the base character and combining mark they
+/// decompose to, sorted by the precomposed character
+#[rustfmt::skip]
+const DECOMPOSITIONS: &[(char, char, char)] = &[
+  ('\u{c0}', 'A', '\u{300}'),
+  ('\u{c1}', 'A', '\u{301}'),
+  ('\u{c2}', 'A', '\u{302}'),
+  ('\u{c3}', 'A', '\u{303}'),
+  ('\u{c4}', 'A', '\u{308}'),
+  ('\u{c5}', 'A', '\u{30a}'),
+  ('\u{c7}', 'C', '\u{327}'),
+  ('\u{c8}', 'E', '\u{300}'),
+  ('\u{c9}', 'E', '\u{301}'),
+  ('\u{ca}', 'E', '\u{302}'),
+  ('\u{cb}', 'E', '\u{308}'),
+  ('\u{cc}', 'I', '\u{300}'),
+  ('\u{cd}', 'I', '\u{301}'),
+  ('\u{ce}', 'I', '\u{302}'),
+  ('\u{cf}', 'I', '\u{308}'),
+  ('\u{d1}', 'N', '\u{303}'),
+  ('\u{d2}', 'O', '\u{300}'),
+  ('\u{d3}', 'O', '\u{301}'),
+  ('\u{d4}', 'O', '\u{302}'),
+  ('\u{d5}', 'O', '\u{303}'),
+  ('\u{d6}', 'O', '\u{308}'),
+  ('\u{d9}', 'U', '\u{300}'),
+  ('\u{da}', 'U', '\u{301}'),
+  ('\u{db}', 'U', '\u{302}'),
+  ('\u{dc}', 'U', '\u{308}'),
+  ('\u{dd}', 'Y', '\u{301}'),
+  ('\u{e0}', 'a', '\u{300}'),
+  ('\u{e1}', 'a', '\u{301}'),
+  ('\u{e2}', 'a', '\u{302}'),
+  ('\u{e3}', 'a', '\u{303}'),
+  ('\u{e4}', 'a', '\u{308}'),
+  ('\u{e5}', 'a', '\u{30a}'),
+  ('\u{e7}', 'c', '\u{327}'),
+  ('\u{e8}', 'e', '\u{300}'),
+  ('\u{e9}', 'e', '\u{301}'),
+  ('\u{ea}', 'e', '\u{302}'),
+  ('\u{eb}', 'e', '\u{308}'),
+  ('\u{ec}', 'i', '\u{300}'),
+  ('\u{ed}', 'i', '\u{301}'),
+  ('\u{ee}', 'i', '\u{302}'),
+  ('\u{ef}', 'i', '\u{308}'),
+  ('\u{f1}', 'n', '\u{303}'),
+  ('\u{f2}', 'o', '\u{300}'),
+  ('\u{f3}', 'o', '\u{301}'),
+  ('\u{f4}', 'o', '\u{302}'),
+  ('\u{f5}', 'o', '\u{303}'),
+  ('\u{f6}', 'o', '\u{308}'),
+  ('\u{f9}', 'u', '\u{300}'),
+  ('\u{fa}', 'u', '\u{301}'),
+  ('\u{fb}', 'u', '\u{302}'),
+  ('\u{fc}', 'u', '\u{308}'),
+  ('\u{fd}', 'y', '\u{301}'),
+  ('\u{ff}', 'y', '\u{308}'),
+  ('\u{100}', 'A', '\u{304}'),
+  ('\u{101}', 'a', '\u{304}'),
+  ('\u{102}', 'A', '\u{306}'),
+  ('\u{103}', 'a', '\u{306}'),
+  ('\u{104}', 'A', '\u{328}'),
+  ('\u{105}', 'a', '\u{328}'),
+  ('\u{106}', 'C', '\u{301}'),
+  ('\u{107}', 'c', '\u{301}'),
+  ('\u{108}', 'C', '\u{302}'),
+  ('\u{109}', 'c', '\u{302}'),
+  ('\u{10a}', 'C', '\u{307}'),
+  ('\u{10b}', 'c', '\u{307}'),
+  ('\u{10c}', 'C', '\u{30c}'),
+  ('\u{10d}', 'c', '\u{30c}'),
+  ('\u{10e}', 'D', '\u{30c}'),
+  ('\u{10f}', 'd', '\u{30c}'),
+  ('\u{112}', 'E', '\u{304}'),
+  ('\u{113}', 'e', '\u{304}'),
+  ('\u{114}', 'E', '\u{306}'),
+  ('\u{115}', 'e', '\u{306}'),
+  ('\u{116}', 'E', '\u{307}'),
+  ('\u{117}', 'e', '\u{307}'),
+  ('\u{118}', 'E', '\u{328}'),
+  ('\u{119}', 'e', '\u{328}'),
+  ('\u{11a}', 'E', '\u{30c}'),
+  ('\u{11b}', 'e', '\u{30c}'),
+  ('\u{11c}', 'G', '\u{302}'),
+  ('\u{11d}', 'g', '\u{302}'),
+  ('\u{11e}', 'G', '\u{306}'),
+  ('\u{11f}', 'g', '\u{306}'),
+  ('\u{120}', 'G', '\u{307}'),
+  ('\u{121}', 'g', '\u{307}'),
+  ('\u{122}', 'G', '\u{327}'),
+  ('\u{123}', 'g', '\u{327}'),
+  ('\u{124}', 'H', '\u{302}'),
+  ('\u{125}', 'h', '\u{302}'),
+  ('\u{128}', 'I', '\u{303}'),
+  ('\u{129}', 'i', '\u{303}'),
+  ('\u{12a}', 'I', '\u{304}'),
+  ('\u{12b}', 'i', '\u{304}'),
+  ('\u{12c}', 'I', '\u{306}'),
+  ('\u{12d}', 'i', '\u{306}'),
+  ('\u{12e}', 'I', '\u{328}'),
+  ('\u{12f}', 'i', '\u{328}'),
+  ('\u{130}', 'I', '\u{307}'),
+  ('\u{134}', 'J', '\u{302}'),
+  ('\u{135}', 'j', '\u{302}'),
+  ('\u{136}', 'K', '\u{327}'),
+  ('\u{137}', 'k', '\u{327}'),
+  ('\u{139}', 'L', '\u{301}'),
+  ('\u{13a}', 'l', '\u{301}'),
+  ('\u{13b}', 'L', '\u{327}'),
+  ('\u{13c}', 'l', '\u{327}'),
+  ('\u{13d}', 'L', '\u{30c}'),
+  ('\u{13e}', 'l', '\u{30c}'),
+  ('\u{143}', 'N', '\u{301}'),
+  ('\u{144}', 'n', '\u{301}'),
+  ('\u{145}', 'N', '\u{327}'),
+  ('\u{146}', 'n', '\u{327}'),
+  ('\u{147}', 'N', '\u{30c}'),
+  ('\u{148}', 'n', '\u{30c}'),
+  ('\u{14c}', 'O', '\u{304}'),
+  ('\u{14d}', 'o', '\u{304}'),
+  ('\u{14e}', 'O', '\u{306}'),
+  ('\u{14f}', 'o', '\u{306}'),
+  ('\u{150}', 'O', '\u{30b}'),
+  ('\u{151}', 'o', '\u{30b}'),
+  ('\u{154}', 'R', '\u{301}'),
+  ('\u{155}', 'r', '\u{301}'),
+  ('\u{156}', 'R', '\u{327}'),
+  ('\u{157}', 'r', '\u{327}'),
+  ('\u{158}', 'R', '\u{30c}'),
+  ('\u{159}', 'r', '\u{30c}'),
+  ('\u{15a}', 'S', '\u{301}'),
+  ('\u{15b}', 's', '\u{301}'),
+  ('\u{15c}', 'S', '\u{302}'),
+  ('\u{15d}', 's', '\u{302}'),
+  ('\u{15e}', 'S', '\u{327}'),
+  ('\u{15f}', 's', '\u{327}'),
+  ('\u{160}', 'S', '\u{30c}'),
+  ('\u{161}', 's', '\u{30c}'),
+  ('\u{162}', 'T', '\u{327}'),
+  ('\u{163}', 't', '\u{327}'),
+  ('\u{164}', 'T', '\u{30c}'),
+  ('\u{165}', 't', '\u{30c}'),
+  ('\u{168}', 'U', '\u{303}'),
+  ('\u{169}', 'u', '\u{303}'),
+  ('\u{16a}', 'U', '\u{304}'),
+  ('\u{16b}', 'u', '\u{304}'),
+  ('\u{16c}', 'U', '\u{306}'),
+  ('\u{16d}', 'u', '\u{306}'),
+  ('\u{16e}', 'U', '\u{30a}'),
+  ('\u{16f}', 'u', '\u{30a}'),
+  ('\u{170}', 'U', '\u{30b}'),
+  ('\u{171}', 'u', '\u{30b}'),
+  ('\u{172}', 'U', '\u{328}'),
+  ('\u{173}', 'u', '\u{328}'),
+  ('\u{174}', 'W', '\u{302}'),
+  ('\u{175}', 'w', '\u{302}'),
+  ('\u{176}', 'Y', '\u{302}'),
+  ('\u{177}', 'y', '\u{302}'),
+  ('\u{178}', 'Y', '\u{308}'),
+  ('\u{179}', 'Z', '\u{301}'),
+  ('\u{17a}', 'z', '\u{301}'),
+  ('\u{17b}', 'Z', '\u{307}'),
+  ('\u{17c}', 'z', '\u{307}'),
+  ('\u{17d}', 'Z', '\u{30c}'),
+  ('\u{17e}', 'z', '\u{30c}'),
+];
@@ -0,0 +1,410 @@
+use crate::{
+  object, small::SmallBytes, Encoding, EncodingError, OIDError, Object, ObjectKind, Signature,
+  SignatureError, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  borrow::Cow,
+  io::{self, Write},
+};
+use thiserror::Error;
+
+/// A [`Commit`] is a git object that records a snapshot of the working tree
+/// as a Tree, the commits that came before it, who made it and when, as well
+/// as a message describing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+  tree: OID,
+  parents: Vec<OID>,
+  author: Signature,
+  committer: Signature,
+  encoding: Option<BString>,
+  extra_headers: Vec<(BString, BString)>,
+  /// The headers as they were parsed, up to and including the blank line
+  /// before the message, when writing the fields back out in git's usual
+  /// order would change them
+  raw_headers: Option<BString>,
+  message: SmallBytes,
+}
+
+impl Commit {
+  /// Create a new [`Commit`]. The message is stored as UTF-8, use
+  /// [`Commit::with_encoding`] to store it in a different encoding.
+  pub fn new(
+    tree: OID,
+    parents: Vec<OID>,
+    author: Signature,
+    committer: Signature,
+    message: impl Into<String>,
+  ) -> Self {
+    Self {
+      tree,
+      parents,
+      author,
+      committer,
+      encoding: None,
+      extra_headers: Vec::new(),
+      raw_headers: None,
+      message: message.into().into(),
+    }
+  }
+
+  /// Parse the contents of a [`Commit`] as stored in the Object Database,
+  /// without the `commit {content_len}\0` prefix.
+  ///
+  /// Headers other than `tree`, `parent`, `author`, `committer`, and
+  /// `encoding` (for example `gpgsig` or `mergetag`) are kept as is so that
+  /// the [`Commit`] still has the same [`OID`] when written back out. The
+  /// headers are written back exactly as they were, even when they're not
+  /// in the order git writes them, until they're changed.
+  pub fn parse(bytes: impl AsRef<[u8]>) -> Result<Self, CommitError> {
+    let bytes = bytes.as_ref();
+    let (headers, message) = match bytes.find(b"\n\n") {
+      Some(idx) => (&bytes[..idx], &bytes[idx + 2..]),
+      None => (bytes, &b""[..]),
+    };
+
+    let mut tree = None;
+    let mut parents = Vec::new();
+    let mut author = None;
+    let mut committer = None;
+    let mut encoding = None;
+    let mut extra_headers: Vec<(BString, BString)> = Vec::new();
+    for line in headers.split_str("\n") {
+      if let Some(continuation) = line.strip_prefix(b" ") {
+        // Multi-line headers such as gpgsig continue on lines starting with
+        // a space
+        let (_, value) = extra_headers
+          .last_mut()
+          .ok_or_else(|| CommitError::InvalidHeader(line.into()))?;
+        value.push(b'\n');
+        value.extend_from_slice(continuation);
+        continue;
+      }
+      let (key, value) = match line.find_byte(b' ') {
+        Some(idx) => (&line[..idx], &line[idx + 1..]),
+        None => return Err(CommitError::InvalidHeader(line.into())),
+      };
+      match key {
+        b"tree" if tree.is_none() => tree = Some(parse_oid(value)?),
+        b"parent" => parents.push(parse_oid(value)?),
+        b"author" if author.is_none() => author = Some(Signature::parse(value)?),
+        b"committer" if committer.is_none() => committer = Some(Signature::parse(value)?),
+        b"encoding" if encoding.is_none() => encoding = Some(value.into()),
+        _ => extra_headers.push((key.into(), value.into())),
+      }
+    }
+
+    let mut commit = Self {
+      tree: tree.ok_or(CommitError::MissingHeader("tree"))?,
+      parents,
+      author: author.ok_or(CommitError::MissingHeader("author"))?,
+      committer: committer.ok_or(CommitError::MissingHeader("committer"))?,
+      encoding,
+      extra_headers,
+      raw_headers: None,
+      message: message.into(),
+    };
+    if commit.content() != bytes {
+      commit.raw_headers = Some(bytes[..bytes.len() - message.len()].into());
+    }
+    Ok(commit)
+  }
+
+  /// Turn the [`Commit`] into the on disk representation stored in Object
+  /// Database, which is in the form below:
+  ///
+  /// ```text
+  /// commit {content_len}\0tree {tree}
+  /// parent {parent}
+  /// author {author}
+  /// committer {committer}
+  /// encoding {encoding}
+  ///
+  /// {message}
+  /// ```
+  ///
+  /// There is one `parent` line per parent and the `encoding` line is only
+  /// there if the message is not UTF-8.
+  pub fn as_bytes(&self) -> Vec<u8> {
+    object::encode(self)
+  }
+
+  pub(crate) fn content(&self) -> Vec<u8> {
+    if let Some(headers) = &self.raw_headers {
+      return [headers.as_slice(), &self.message].concat();
+    }
+    let mut content = Vec::new();
+    content.extend_from_slice(b"tree ");
+    content.extend_from_slice(self.tree.as_hex().as_bytes());
+    content.push(b'\n');
+    for parent in &self.parents {
+      content.extend_from_slice(b"parent ");
+      content.extend_from_slice(parent.as_hex().as_bytes());
+      content.push(b'\n');
+    }
+    content.extend_from_slice(b"author ");
+    content.extend_from_slice(&self.author.as_bytes());
+    content.push(b'\n');
+    content.extend_from_slice(b"committer ");
+    content.extend_from_slice(&self.committer.as_bytes());
+    content.push(b'\n');
+    if let Some(encoding) = &self.encoding {
+      content.extend_from_slice(b"encoding ");
+      content.extend_from_slice(encoding);
+      content.push(b'\n');
+    }
+    for (key, value) in &self.extra_headers {
+      content.extend_from_slice(key);
+      content.push(b' ');
+      content.extend_from_slice(&value.replace("\n", "\n "));
+      content.push(b'\n');
+    }
+    content.push(b'\n');
+    content.extend_from_slice(&self.message);
+    content
+  }
+
+  /// Get the [`OID`] for the [`Commit`]
+  pub fn id(&self) -> OID {
+    OID::for_object(self)
+  }
+
+  /// The [`OID`] of the Tree this [`Commit`] is a snapshot of
+  pub fn tree(&self) -> &OID {
+    &self.tree
+  }
+
+  /// The [`OID`]s of the parents of this [`Commit`]
+  pub fn parents(&self) -> &[OID] {
+    &self.parents
+  }
+
+  /// Who wrote the changes in this [`Commit`]
+  pub fn author(&self) -> &Signature {
+    &self.author
+  }
+
+  /// Who created this [`Commit`]
+  pub fn committer(&self) -> &Signature {
+    &self.committer
+  }
+
+  /// Any headers git does not give a special meaning to, such as `gpgsig`,
+  /// in the order they appear
+  pub fn extra_headers(&self) -> &[(BString, BString)] {
+    &self.extra_headers
+  }
+
+  /// The value of the `encoding` header if there is one. Git treats a
+  /// missing header as UTF-8.
+  pub fn encoding(&self) -> Option<&BStr> {
+    self.encoding.as_ref().map(|e| e.as_bstr())
+  }
+
+  /// The message exactly as it is stored, in whatever encoding the
+  /// `encoding` header says it is in
+  pub fn raw_message(&self) -> &BStr {
+    self.message.as_bstr()
+  }
+
+  /// The message decoded to UTF-8 from the encoding it was stored in. This
+  /// fails if the encoding is not supported or if the message is not valid
+  /// in the encoding it claims to be in.
+  pub fn message(&self) -> Result<Cow<'_, str>, CommitError> {
+    Ok(self.message_encoding()?.decode(&self.message)?)
+  }
+
+  /// The message decoded to UTF-8, replacing anything that can't be decoded
+  /// with U+FFFD. This is what should be used for display purposes to show
+  /// legacy histories as well as possible.
+  pub fn message_lossy(&self) -> Cow<'_, str> {
+    match self.message() {
+      Ok(message) => message,
+      Err(_) => String::from_utf8_lossy(&self.message),
+    }
+  }
+
+  /// The [`Encoding`] of the message as declared by the `encoding` header
+  pub fn message_encoding(&self) -> Result<Encoding, CommitError> {
+    match &self.encoding {
+      None => Ok(Encoding::Utf8),
+      Some(label) => Encoding::for_label(label)
+        .ok_or_else(|| EncodingError::Unsupported(label.to_string()).into()),
+    }
+  }
+
+  /// Re-encode the message into the given [`Encoding`] and set the
+  /// `encoding` header to match. This is what should be used to honor
+  /// `i18n.commitEncoding` when writing a commit. Using [`Encoding::Utf8`]
+  /// removes the header as git does not write it out for UTF-8.
+  pub fn with_encoding(mut self, encoding: Encoding) -> Result<Self, CommitError> {
+    let message = encoding.encode(&self.message()?)?.into_owned();
+    self.message = message.into();
+    self.raw_headers = None;
+    self.encoding = match encoding {
+      Encoding::Utf8 => None,
+      encoding => Some(encoding.label().into()),
+    };
+    Ok(self)
+  }
+
+  /// The headers other than the ones every commit has, to add or remove
+  /// some of them
+  pub(crate) fn extra_headers_mut(&mut self) -> &mut Vec<(BString, BString)> {
+    self.raw_headers = None;
+    &mut self.extra_headers
+  }
+
+  /// Replace the message, encoding it in the [`Encoding`] the `encoding`
+  /// header says the message is in
+  pub(crate) fn with_message(mut self, message: &str) -> Result<Self, CommitError> {
+    let message = self.message_encoding()?.encode(message)?.into_owned();
+    self.message = message.into();
+    Ok(self)
+  }
+}
+
+fn parse_oid(hex: &[u8]) -> Result<OID, CommitError> {
+  let hex = hex
+    .to_str()
+    .map_err(|_| CommitError::InvalidHeader(hex.into()))?;
+  Ok(OID::from_hex(hex)?)
+}
+
+impl Object for Commit {
+  fn kind(&self) -> ObjectKind {
+    ObjectKind::Commit
+  }
+
+  fn serialize_into(&self, writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(&self.content())
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`Commit`] type
+pub enum CommitError {
+  #[error("commit is missing the {0} header")]
+  MissingHeader(&'static str),
+  #[error("invalid commit header {0:?}")]
+  InvalidHeader(BString),
+  #[error("invalid OID in commit header: {0}")]
+  InvalidOID(#[from] OIDError),
+  #[error("{0}")]
+  InvalidSignature(#[from] SignatureError),
+  #[error("{0}")]
+  Encoding(#[from] EncodingError),
+}
+
+#[cfg(test)]
+const TEST_COMMIT: &[u8] = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+author Jane Doe <jane@example.com> 1600000000 +0000
+committer Jane Doe <jane@example.com> 1600000000 +0000
+encoding ISO-8859-1
+
+Caf\xe9 ouvert
+";
+
+#[test]
+fn parse() {
+  let commit = Commit::parse(TEST_COMMIT).unwrap();
+  assert_eq!(
+    "4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+    commit.tree().as_hex()
+  );
+  assert!(commit.parents().is_empty());
+  assert_eq!("Jane Doe", commit.author().name());
+  assert_eq!(Some("ISO-8859-1".into()), commit.encoding());
+  assert_eq!(b"Caf\xe9 ouvert\n".as_bstr(), commit.raw_message());
+  assert_eq!("Café ouvert\n", commit.message().unwrap());
+}
+
+#[test]
+fn round_trip() {
+  let commit = Commit::parse(TEST_COMMIT).unwrap();
+  let bytes = commit.as_bytes();
+  assert_eq!(
+    [b"commit 186\0", TEST_COMMIT].concat(),
+    bytes,
+    "{}",
+    bytes.as_bstr()
+  );
+
+  let signed = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+author Jane Doe <jane@example.com> 1600000000 +0000
+committer Jane Doe <jane@example.com> 1600000000 +0000
+gpgsig -----BEGIN PGP SIGNATURE-----
+
+ abc
+ -----END PGP SIGNATURE-----
+
+message
+";
+  let commit = Commit::parse(&signed[..]).unwrap();
+  assert_eq!(1, commit.extra_headers().len());
+  assert_eq!(&signed[..], &commit.content()[..]);
+}
+
+#[test]
+fn round_trip_header_order() {
+  let unusual = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+author Jane Doe <jane@example.com> 1600000000 +0000
+parent 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+x-custom value
+committer Jane Doe <jane@example.com> 1600000000 +0000
+encoding ISO-8859-1
+
+message
+";
+  let commit = Commit::parse(&unusual[..]).unwrap();
+  assert_eq!(1, commit.parents().len());
+  assert_eq!(1, commit.extra_headers().len());
+  assert_eq!(&unusual[..], &commit.content()[..]);
+  let commit = commit.with_message("changed\n").unwrap();
+  assert!(commit.content().starts_with(&unusual[..unusual.len() - 8]));
+
+  let mut changed = Commit::parse(&unusual[..]).unwrap();
+  changed.extra_headers_mut().clear();
+  let canonical = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+parent 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+author Jane Doe <jane@example.com> 1600000000 +0000
+committer Jane Doe <jane@example.com> 1600000000 +0000
+encoding ISO-8859-1
+
+message
+";
+  assert_eq!(&canonical[..], &changed.content()[..]);
+}
+
+#[test]
+fn with_encoding() {
+  let tree = OID::from_hex("4b825dc642cb6eb9a060e54bf8d69288fbee4904").unwrap();
+  let sig = Signature::parse("Jane Doe <jane@example.com> 1600000000 +0000").unwrap();
+  let commit = Commit::new(tree, Vec::new(), sig.clone(), sig, "Café ouvert\n");
+  assert_eq!(None, commit.encoding());
+  let commit = commit.with_encoding(Encoding::Latin1).unwrap();
+  assert_eq!(Commit::parse(TEST_COMMIT).unwrap(), commit);
+  let commit = commit.with_encoding(Encoding::Utf8).unwrap();
+  assert_eq!(None, commit.encoding());
+  assert_eq!("Café ouvert\n".as_bytes().as_bstr(), commit.raw_message());
+}
+
+#[test]
+fn unsupported_encoding() {
+  let commit = Commit::parse(
+    &b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+author Jane Doe <jane@example.com> 1600000000 +0000
+committer Jane Doe <jane@example.com> 1600000000 +0000
+encoding EUC-JP
+
+\xa4\xa2
+"[..],
+  )
+  .unwrap();
+  assert!(matches!(
+    commit.message(),
+    Err(CommitError::Encoding(EncodingError::Unsupported(_)))
+  ));
+  assert_eq!("\u{FFFD}\u{FFFD}\n", commit.message_lossy());
+}
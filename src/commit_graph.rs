@@ -0,0 +1,525 @@
+use crate::{
+  endian::{read_u32, read_u64, table_len},
+  ObjectKind, Odb, OdbError, Trace2, OID,
+};
+use sha1::{Digest, Sha1};
+use std::{
+  collections::HashMap,
+  convert::TryInto,
+  fs,
+  io::{self, Write},
+  path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+const SIGNATURE: &[u8; 4] = b"CGPH";
+const OID_FANOUT: &[u8; 4] = b"OIDF";
+const OID_LOOKUP: &[u8; 4] = b"OIDL";
+const COMMIT_DATA: &[u8; 4] = b"CDAT";
+const EXTRA_EDGES: &[u8; 4] = b"EDGE";
+/// The value of the first or second parent when there isn't one
+const PARENT_NONE: u32 = 0x7000_0000;
+/// Set on the second parent when it's an index into the extra edges of an
+/// octopus merge, and on the last of those edges
+const EDGE_BIT: u32 = 0x8000_0000;
+/// The largest topological level that fits in a commit graph
+const MAX_GENERATION: u32 = 0x3fff_ffff;
+
+/// A commit as stored in a [`CommitGraph`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GraphCommit {
+  /// The [`OID`] of the Tree of the commit
+  pub tree: OID,
+  /// The parents of the commit in order
+  pub parents: Vec<OID>,
+  /// The topological level of the commit, 1 for commits without parents
+  /// and otherwise one more than the highest level of its parents
+  pub generation: u32,
+  /// The committer time of the commit in seconds since the epoch
+  pub commit_time: i64,
+}
+
+/// The commit-graph file in `objects/info/commit-graph`, or a chain of them
+/// in `objects/info/commit-graphs`, which stores the parents, generation,
+/// and time of commits so history can be walked without reading and parsing
+/// every commit. The [`GraphCommit`] for a commit is only as much as git
+/// stores, bloom filters and corrected commit dates are not read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitGraph {
+  /// The layers of a chain with the base first, or only one layer if there
+  /// is no chain
+  layers: Vec<Layer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Layer {
+  data: Vec<u8>,
+  commits: u32,
+  /// How many commits are in the layers below this one
+  base: u32,
+  lookup: usize,
+  commit_data: usize,
+  edges: Option<usize>,
+}
+
+impl CommitGraph {
+  /// Open the commit graph of the objects directory `objects`. A single
+  /// `commit-graph` file is used if there is one, otherwise the chain in
+  /// `commit-graphs`. `None` is returned if there are neither.
+  pub fn open(objects: impl AsRef<Path>) -> Result<Option<Self>, CommitGraphError> {
+    let info = objects.as_ref().join("info");
+    match fs::read(info.join("commit-graph")) {
+      Ok(data) => return Self::parse(data).map(Some),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+      Err(e) => return Err(e.into()),
+    }
+    let dir = info.join("commit-graphs");
+    let chain = match fs::read_to_string(dir.join("commit-graph-chain")) {
+      Ok(chain) => chain,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    let mut graph = Self { layers: Vec::new() };
+    for hash in chain.lines().filter(|line| !line.is_empty()) {
+      if hash.len() != 40 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(CommitGraphError::Malformed(
+          "invalid hash in commit-graph-chain",
+        ));
+      }
+      let data = fs::read(dir.join(format!("graph-{}.graph", hash)))?;
+      let base = graph.len();
+      let layer = Layer::parse(data, base)?;
+      if usize::from(layer.data[7]) != graph.layers.len() {
+        return Err(CommitGraphError::Malformed(
+          "wrong number of base graphs in a layer",
+        ));
+      }
+      graph.layers.push(layer);
+    }
+    Ok(Some(graph))
+  }
+
+  /// Parse a single commit-graph file
+  pub fn parse(data: impl Into<Vec<u8>>) -> Result<Self, CommitGraphError> {
+    let layer = Layer::parse(data.into(), 0)?;
+    if layer.data[7] != 0 {
+      return Err(CommitGraphError::Malformed(
+        "a commit-graph file outside of a chain has base graphs",
+      ));
+    }
+    Ok(Self {
+      layers: vec![layer],
+    })
+  }
+
+  /// How many commits are in the graph
+  pub fn len(&self) -> u32 {
+    self
+      .layers
+      .last()
+      .map_or(0, |layer| layer.base + layer.commits)
+  }
+
+  /// Whether the graph has no commits
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Whether the commit is in the graph
+  pub fn contains(&self, oid: &OID) -> bool {
+    self.position(oid).is_some()
+  }
+
+  /// The [`GraphCommit`] for a commit, or `None` if it isn't in the graph
+  pub fn get(&self, oid: &OID) -> Result<Option<GraphCommit>, CommitGraphError> {
+    let position = match self.position(oid) {
+      Some(position) => position,
+      None => return Ok(None),
+    };
+    let (layer, index) = self.layer(position);
+    let entry = &layer.data[layer.commit_data + index * 36..][..36];
+    let tree = OID::from_bytes(&entry[..20]).unwrap();
+    let mut parents = Vec::new();
+    let first = read_u32(&entry[20..]);
+    let second = read_u32(&entry[24..]);
+    if first != PARENT_NONE {
+      parents.push(self.oid_at(first)?);
+    }
+    if second & EDGE_BIT != 0 {
+      let edges = layer
+        .edges
+        .ok_or(CommitGraphError::Malformed("missing EDGE chunk"))?;
+      let mut edge =
+        table_len(second & !EDGE_BIT, 4).and_then(|start: usize| start.checked_add(edges));
+      loop {
+        let parent = read_u32(
+          edge
+            .and_then(|edge| layer.data.get(edge..edge.checked_add(4)?))
+            .ok_or(CommitGraphError::Malformed("extra edge out of bounds"))?,
+        );
+        parents.push(self.oid_at(parent & !EDGE_BIT)?);
+        if parent & EDGE_BIT != 0 {
+          break;
+        }
+        edge = edge.and_then(|edge| edge.checked_add(4));
+      }
+    } else if second != PARENT_NONE {
+      parents.push(self.oid_at(second)?);
+    }
+    let generation_and_time = read_u32(&entry[28..]);
+    Ok(Some(GraphCommit {
+      tree,
+      parents,
+      generation: generation_and_time >> 2,
+      commit_time: (i64::from(generation_and_time & 3) << 32) | i64::from(read_u32(&entry[32..])),
+    }))
+  }
+
+  /// The [`OID`] of every commit in the graph, sorted within each layer
+  pub fn oids(&self) -> impl Iterator<Item = OID> + '_ {
+    self
+      .layers
+      .iter()
+      .flat_map(|layer| (0..layer.commits as usize).map(move |i| layer.oid(i)))
+  }
+
+  fn position(&self, oid: &OID) -> Option<u32> {
+    self
+      .layers
+      .iter()
+      .find_map(|layer| layer.find(oid).map(|index| layer.base + index))
+  }
+
+  fn layer(&self, position: u32) -> (&Layer, usize) {
+    let layer = self
+      .layers
+      .iter()
+      .rfind(|layer| layer.base <= position)
+      .unwrap();
+    (layer, (position - layer.base) as usize)
+  }
+
+  fn oid_at(&self, position: u32) -> Result<OID, CommitGraphError> {
+    if position >= self.len() {
+      return Err(CommitGraphError::Malformed("parent position out of bounds"));
+    }
+    let (layer, index) = self.layer(position);
+    Ok(layer.oid(index))
+  }
+
+  /// Write a commit-graph file with every commit in `odb` to
+  /// `objects/info/commit-graph`, which is used instead of a chain of
+  /// commit graphs if there is one. The file is written to
+  /// `commit-graph.lock` first and then moved into place. Commits are
+  /// written as they're stored, not as replace refs replace them.
+  pub fn write(odb: &Odb) -> Result<(), CommitGraphError> {
+    let _region = Trace2::region("commit-graph", "write");
+    let odb = &odb.clone().without_replacements();
+    let mut commits = HashMap::new();
+    for oid in odb.oids()? {
+      let object = odb.read(&oid)?;
+      if object.kind == ObjectKind::Commit {
+        commits.insert(
+          oid,
+          crate::Commit::parse(object.data).map_err(OdbError::from)?,
+        );
+      }
+    }
+    let mut oids: Vec<OID> = commits.keys().copied().collect();
+    oids.sort();
+    let positions: HashMap<OID, u32> = oids
+      .iter()
+      .enumerate()
+      .map(|(i, oid)| (*oid, i as u32))
+      .collect();
+    for commit in commits.values() {
+      if let Some(parent) = commit.parents().iter().find(|p| !positions.contains_key(p)) {
+        return Err(CommitGraphError::MissingParent(*parent));
+      }
+    }
+
+    // Work out the generations from the roots up without recursing since
+    // histories can be very deep
+    let mut generations: HashMap<OID, u32> = HashMap::new();
+    for oid in &oids {
+      let mut stack = vec![*oid];
+      while let Some(&oid) = stack.last() {
+        if generations.contains_key(&oid) {
+          stack.pop();
+          continue;
+        }
+        let parents = commits[&oid].parents();
+        let missing: Vec<OID> = parents
+          .iter()
+          .filter(|p| !generations.contains_key(p))
+          .copied()
+          .collect();
+        if missing.is_empty() {
+          let generation = parents
+            .iter()
+            .map(|p| generations[p])
+            .max()
+            .map_or(1, |max| (max + 1).min(MAX_GENERATION));
+          generations.insert(oid, generation);
+          stack.pop();
+        } else {
+          stack.extend(missing);
+        }
+      }
+    }
+
+    let mut fanout = Vec::with_capacity(256 * 4);
+    for byte in 0..=255u8 {
+      let count = oids.partition_point(|oid| oid.as_bytes()[0] <= byte) as u32;
+      fanout.extend_from_slice(&count.to_be_bytes());
+    }
+    let lookup: Vec<u8> = oids.iter().flat_map(|oid| *oid.as_bytes()).collect();
+    let mut commit_data = Vec::with_capacity(oids.len() * 36);
+    let mut edges = Vec::new();
+    for oid in &oids {
+      let commit = &commits[oid];
+      let parents: Vec<u32> = commit.parents().iter().map(|p| positions[p]).collect();
+      commit_data.extend_from_slice(commit.tree().as_bytes());
+      commit_data.extend_from_slice(&parents.first().unwrap_or(&PARENT_NONE).to_be_bytes());
+      let second = match parents.len() {
+        0 | 1 => PARENT_NONE,
+        2 => parents[1],
+        _ => {
+          let index = (edges.len() / 4) as u32 | EDGE_BIT;
+          for (i, parent) in parents[1..].iter().enumerate() {
+            let last = if i == parents.len() - 2 { EDGE_BIT } else { 0 };
+            edges.extend_from_slice(&(parent | last).to_be_bytes());
+          }
+          index
+        }
+      };
+      commit_data.extend_from_slice(&second.to_be_bytes());
+      // Times before 1970 or after 2514 don't fit so they're clamped to 0
+      let time = commit.committer().time.seconds;
+      let time = if (0..1 << 34).contains(&time) {
+        time as u64
+      } else {
+        0
+      };
+      let generation = generations[oid] << 2 | (time >> 32) as u32;
+      commit_data.extend_from_slice(&generation.to_be_bytes());
+      commit_data.extend_from_slice(&(time as u32).to_be_bytes());
+    }
+
+    let mut chunks = vec![
+      (OID_FANOUT, fanout),
+      (OID_LOOKUP, lookup),
+      (COMMIT_DATA, commit_data),
+    ];
+    if !edges.is_empty() {
+      chunks.push((EXTRA_EDGES, edges));
+    }
+    let mut data = Vec::new();
+    data.extend_from_slice(SIGNATURE);
+    // Version 1, SHA-1, the number of chunks, and no base graphs
+    data.extend_from_slice(&[1, 1, chunks.len() as u8, 0]);
+    let mut offset = (data.len() + (chunks.len() + 1) * 12) as u64;
+    for (id, chunk) in &chunks {
+      data.extend_from_slice(*id);
+      data.extend_from_slice(&offset.to_be_bytes());
+      offset += chunk.len() as u64;
+    }
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&offset.to_be_bytes());
+    for (_, chunk) in &chunks {
+      data.extend_from_slice(chunk);
+    }
+    let checksum = Sha1::digest(&data);
+    data.extend_from_slice(&checksum);
+    Trace2::data("commit-graph", "num_commits", oids.len());
+
+    let info = odb.path().join("info");
+    fs::create_dir_all(&info)?;
+    let path = info.join("commit-graph");
+    let lock_path = info.join("commit-graph.lock");
+    let mut lock = match fs::OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .open(&lock_path)
+    {
+      Ok(lock) => lock,
+      Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+        return Err(CommitGraphError::Locked(lock_path))
+      }
+      Err(e) => return Err(e.into()),
+    };
+    let result = lock
+      .write_all(&data)
+      .and_then(|_| lock.sync_all())
+      .and_then(|_| fs::rename(&lock_path, &path));
+    if let Err(e) = result {
+      let _ = fs::remove_file(&lock_path);
+      return Err(e.into());
+    }
+    Ok(())
+  }
+}
+
+impl Layer {
+  fn parse(data: Vec<u8>, base: u32) -> Result<Self, CommitGraphError> {
+    if data.len() < 8 + 12 + 20 {
+      return Err(CommitGraphError::Malformed("file is too short"));
+    }
+    if &data[..4] != SIGNATURE {
+      return Err(CommitGraphError::Malformed("missing CGPH signature"));
+    }
+    if data[4] != 1 {
+      return Err(CommitGraphError::UnsupportedVersion(data[4]));
+    }
+    if data[5] != 1 {
+      return Err(CommitGraphError::Malformed("hash is not SHA-1"));
+    }
+    let (content, checksum) = data.split_at(data.len() - 20);
+    if Sha1::digest(content)[..] != *checksum {
+      return Err(CommitGraphError::ChecksumMismatch);
+    }
+
+    let chunk_count = usize::from(data[6]);
+    let table_end = 8 + (chunk_count + 1) * 12;
+    if table_end > content.len() {
+      return Err(CommitGraphError::Malformed("chunk table out of bounds"));
+    }
+    let mut chunks: HashMap<[u8; 4], (usize, usize)> = HashMap::new();
+    for i in 0..chunk_count {
+      let entry = &data[8 + i * 12..];
+      let next = &data[8 + (i + 1) * 12..];
+      let start = read_u64(&entry[4..]);
+      let end = read_u64(&next[4..]);
+      if start < table_end as u64 || start > end || end > content.len() as u64 {
+        return Err(CommitGraphError::Malformed("chunk out of bounds"));
+      }
+      chunks.insert(
+        entry[..4].try_into().unwrap(),
+        (start as usize, (end - start) as usize),
+      );
+    }
+    let chunk = |id: &[u8; 4], name| {
+      chunks
+        .get(id)
+        .copied()
+        .ok_or(CommitGraphError::Missing(name))
+    };
+
+    let (fanout, fanout_len) = chunk(OID_FANOUT, "OIDF")?;
+    if fanout_len != 256 * 4 {
+      return Err(CommitGraphError::Malformed("OIDF chunk has the wrong size"));
+    }
+    let commits = read_u32(&data[fanout + 255 * 4..]);
+    let (lookup, lookup_len) = chunk(OID_LOOKUP, "OIDL")?;
+    let (commit_data, commit_data_len) = chunk(COMMIT_DATA, "CDAT")?;
+    if table_len(commits, 20) != Some(lookup_len) || table_len(commits, 36) != Some(commit_data_len)
+    {
+      return Err(CommitGraphError::Malformed(
+        "OIDL or CDAT chunk has the wrong size",
+      ));
+    }
+    if base.checked_add(commits).is_none() {
+      return Err(CommitGraphError::Malformed(
+        "too many commits in the commit-graph chain",
+      ));
+    }
+    Ok(Self {
+      commits,
+      base,
+      lookup,
+      commit_data,
+      edges: chunks.get(EXTRA_EDGES).map(|(start, _)| *start),
+      data,
+    })
+  }
+
+  fn oid(&self, index: usize) -> OID {
+    OID::from_bytes(&self.data[self.lookup + index * 20..][..20]).unwrap()
+  }
+
+  fn find(&self, oid: &OID) -> Option<u32> {
+    let oids = &self.data[self.lookup..self.lookup + self.commits as usize * 20];
+    let (mut low, mut high) = (0, self.commits as usize);
+    while low < high {
+      let mid = (low + high) / 2;
+      match oids[mid * 20..][..20].cmp(&oid.as_bytes()[..]) {
+        std::cmp::Ordering::Less => low = mid + 1,
+        std::cmp::Ordering::Greater => high = mid,
+        std::cmp::Ordering::Equal => return Some(mid as u32),
+      }
+    }
+    None
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`CommitGraph`] type
+pub enum CommitGraphError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("malformed commit-graph: {0}")]
+  Malformed(&'static str),
+  #[error("commit-graph is missing the {0} chunk")]
+  Missing(&'static str),
+  #[error("commit-graph checksum does not match its contents")]
+  ChecksumMismatch,
+  #[error("commit-graph version {0} is not supported")]
+  UnsupportedVersion(u8),
+  #[error("parent commit {0} is not in the object database")]
+  MissingParent(OID),
+  #[error("{0:?} already exists, another process may be writing the commit-graph")]
+  Locked(PathBuf),
+}
+
+#[test]
+fn write_and_read() {
+  use crate::{Commit, Signature, Time, Tree};
+  let tmp_dir = tempdir::TempDir::new("commit_graph_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  assert!(CommitGraph::open(odb.path()).unwrap().is_none());
+
+  let tree = odb.write_tree(&Tree::new(Vec::new())).unwrap();
+  let mut commits = Vec::new();
+  // An octopus merge of three commits on top of a root commit
+  for parents in [&[][..], &[0], &[0], &[0], &[1, 2, 3]] {
+    let time = Time::new(1_600_000_000 + commits.len() as i64, 0);
+    let signature = Signature::new("Jane Doe", "jane@example.com", time);
+    let parents = parents.iter().map(|&p| commits[p]).collect();
+    let commit = Commit::new(
+      tree,
+      parents,
+      signature.clone(),
+      signature,
+      commits.len().to_string(),
+    );
+    commits.push(odb.write_commit(&commit).unwrap());
+  }
+  CommitGraph::write(&odb).unwrap();
+  let graph = CommitGraph::open(odb.path()).unwrap().unwrap();
+  assert_eq!(5, graph.len());
+  assert!(!graph.contains(&tree));
+  let mut oids = commits.clone();
+  oids.sort();
+  assert_eq!(oids, graph.oids().collect::<Vec<_>>());
+
+  let root = graph.get(&commits[0]).unwrap().unwrap();
+  assert_eq!(tree, root.tree);
+  assert!(root.parents.is_empty());
+  assert_eq!(1, root.generation);
+  assert_eq!(1_600_000_000, root.commit_time);
+  let merge = graph.get(&commits[4]).unwrap().unwrap();
+  assert_eq!(&commits[1..4], &merge.parents[..]);
+  assert_eq!(3, merge.generation);
+  assert_eq!(1_600_000_004, merge.commit_time);
+  assert_eq!(None, graph.get(&tree).unwrap());
+
+  let mut data = fs::read(odb.path().join("info/commit-graph")).unwrap();
+  let last = data.len() - 1;
+  data[last] ^= 1;
+  assert!(matches!(
+    CommitGraph::parse(data),
+    Err(CommitGraphError::ChecksumMismatch)
+  ));
+}
@@ -0,0 +1,1549 @@
+use crate::wildmatch::{self, wildmatch};
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  borrow::Cow,
+  env, fs,
+  io::{self, Write},
+  ops::Range,
+  path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// A [`Config`] holds the settings read from git's config files. Files are
+/// layered on top of each other, so a value set in the repository config
+/// overrides one set in the global config, which in turn overrides the
+/// system config.
+///
+/// Keys are written as `section.name` or `section.subsection.name`, for
+/// example `core.fileMode` or `remote.origin.url`. The section and name are
+/// case insensitive while the subsection is case sensitive, the same as git.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+  files: Vec<ConfigFile>,
+}
+
+/// Where a [`ConfigFile`] came from, which decides its priority in a
+/// [`Config`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ConfigLevel {
+  /// The config for every user on the system, usually `/etc/gitconfig`
+  System,
+  /// The config for the current user, `~/.gitconfig` or
+  /// `$XDG_CONFIG_HOME/git/config`
+  Global,
+  /// The config of a repository in `.git/config`
+  Local,
+}
+
+/// The parsed contents of a single config file. The original text of the
+/// file is kept so that changing values with [`ConfigFile::set`],
+/// [`ConfigFile::add`], and [`ConfigFile::unset`] leaves comments, ordering,
+/// and whitespace of every other line untouched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigFile {
+  path: Option<PathBuf>,
+  level: ConfigLevel,
+  source: BString,
+  sections: Vec<SectionHeader>,
+  entries: Vec<ConfigEntry>,
+  includes: Vec<Include>,
+}
+
+/// A file pulled in by an `include.path` or `includeIf.*.path` entry of a
+/// [`ConfigFile`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Include {
+  /// The key and value of the entry that included the file, and how many
+  /// entries with the same key and value came before it. This finds the
+  /// entry again after the including file was changed.
+  key: String,
+  value: BString,
+  nth: usize,
+  file: ConfigFile,
+}
+
+/// What `includeIf` conditions are checked against when resolving includes
+/// with [`ConfigFile::resolve_includes`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IncludeContext {
+  /// The git directory for `gitdir:` conditions. Without one they never
+  /// match.
+  pub git_dir: Option<PathBuf>,
+  /// The short name of the current branch for `onbranch:` conditions
+  pub branch: Option<BString>,
+}
+
+impl IncludeContext {
+  /// Create an [`IncludeContext`] for the repository at `git_dir`, reading
+  /// the current branch from `HEAD`
+  pub fn new(git_dir: impl Into<PathBuf>) -> Self {
+    let git_dir = git_dir.into();
+    let branch = fs::read(git_dir.join("HEAD")).ok().and_then(|head| {
+      head
+        .trim_end()
+        .strip_prefix(b"ref: refs/heads/")
+        .map(BString::from)
+    });
+    Self {
+      git_dir: Some(git_dir),
+      branch,
+    }
+  }
+}
+
+/// How many files deep includes can go, the same limit git has
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// Where a section header is in the source of a [`ConfigFile`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SectionHeader {
+  section: BString,
+  subsection: Option<BString>,
+  /// Where the `[` of the header is
+  start: usize,
+  /// Where the `]` of the header is
+  end: usize,
+  /// The end of the line the header is on
+  line_end: usize,
+}
+
+/// A single `name = value` line in a config file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigEntry {
+  section: BString,
+  subsection: Option<BString>,
+  name: BString,
+  value: Option<BString>,
+  line: usize,
+  /// The index of the [`SectionHeader`] the entry is under
+  header: usize,
+  /// The bytes of the source the entry takes up
+  span: Range<usize>,
+}
+
+impl ConfigEntry {
+  /// The section of the entry in lowercase
+  pub fn section(&self) -> &BStr {
+    self.section.as_bstr()
+  }
+
+  /// The subsection of the entry if it has one
+  pub fn subsection(&self) -> Option<&BStr> {
+    self.subsection.as_ref().map(|s| s.as_bstr())
+  }
+
+  /// The name of the entry in lowercase
+  pub fn name(&self) -> &BStr {
+    self.name.as_bstr()
+  }
+
+  /// The value of the entry. This is `None` for a name without an `=` which
+  /// git treats as a boolean `true`.
+  pub fn value(&self) -> Option<&BStr> {
+    self.value.as_ref().map(|v| v.as_bstr())
+  }
+
+  /// The line number (starting at 1) the entry was on
+  pub fn line(&self) -> usize {
+    self.line
+  }
+
+  /// The full key of the entry such as `remote.origin.url`
+  pub fn key(&self) -> String {
+    match &self.subsection {
+      Some(subsection) => format!("{}.{}.{}", self.section, subsection, self.name),
+      None => format!("{}.{}", self.section, self.name),
+    }
+  }
+
+  /// Whether this is an `include.path` or `includeIf.<condition>.path`
+  /// entry
+  fn is_include(&self) -> bool {
+    let section = match self.subsection {
+      Some(_) => "includeif",
+      None => "include",
+    };
+    self.section == section && self.name == "path"
+  }
+
+  fn matches(&self, key: &Key<'_>) -> bool {
+    self.section.eq_ignore_ascii_case(key.section)
+      && self.subsection.as_deref().map(|s| s.as_bytes()) == key.subsection
+      && self.name.eq_ignore_ascii_case(key.name)
+  }
+}
+
+impl ConfigFile {
+  /// Parse the contents of a config file
+  pub fn from_bytes(bytes: impl AsRef<[u8]>, level: ConfigLevel) -> Result<Self, ConfigError> {
+    let source = bytes.as_ref();
+    let (sections, entries) = parse(source).map_err(|(line, reason)| ConfigError::Syntax {
+      path: None,
+      line,
+      reason,
+    })?;
+    Ok(Self {
+      path: None,
+      level,
+      source: source.into(),
+      sections,
+      entries,
+      includes: Vec::new(),
+    })
+  }
+
+  /// Read and parse a config file from disk
+  pub fn from_file(path: impl AsRef<Path>, level: ConfigLevel) -> Result<Self, ConfigError> {
+    let path = path.as_ref();
+    let bytes = fs::read(path)?;
+    let mut file = Self::from_bytes(bytes, level).map_err(|e| match e {
+      ConfigError::Syntax { line, reason, .. } => ConfigError::Syntax {
+        path: Some(path.into()),
+        line,
+        reason,
+      },
+      e => e,
+    })?;
+    file.path = Some(path.into());
+    Ok(file)
+  }
+
+  /// The path the file was read from, if it was read from disk
+  pub fn path(&self) -> Option<&Path> {
+    self.path.as_deref()
+  }
+
+  /// The [`ConfigLevel`] of the file
+  pub fn level(&self) -> ConfigLevel {
+    self.level
+  }
+
+  /// Every entry in the file in the order they appear
+  pub fn entries(&self) -> &[ConfigEntry] {
+    &self.entries
+  }
+
+  /// The files pulled in with `include.path` and `includeIf.*.path` by
+  /// [`ConfigFile::resolve_includes`], in the order they are included
+  pub fn included_files(&self) -> impl Iterator<Item = &ConfigFile> {
+    self.includes.iter().map(|include| &include.file)
+  }
+
+  /// Every entry of the file with the entries of included files in place of
+  /// the entries that included them
+  fn resolved_entries(&self) -> Vec<&ConfigEntry> {
+    let mut entries = Vec::with_capacity(self.entries.len());
+    let mut seen: Vec<(String, Option<&BStr>)> = Vec::new();
+    for entry in &self.entries {
+      entries.push(entry);
+      if !entry.is_include() {
+        continue;
+      }
+      let key = (entry.key(), entry.value());
+      let nth = seen.iter().filter(|seen| **seen == key).count();
+      if let Some(include) = self.includes.iter().find(|include| {
+        include.key == key.0 && Some(include.value.as_bstr()) == key.1 && include.nth == nth
+      }) {
+        entries.extend(include.file.resolved_entries());
+      }
+      seen.push(key);
+    }
+    entries
+  }
+
+  /// Read the files this file includes with `include.path`, and with
+  /// `includeIf.<condition>.path` when the condition holds, the same way git
+  /// does. Included files can include other files. Relative paths are
+  /// relative to the directory of the including file and files that don't
+  /// exist are skipped.
+  ///
+  /// The conditions `gitdir:`, `gitdir/i:`, and `onbranch:` are supported,
+  /// others never match. A file including itself, directly or through
+  /// other files, is an error.
+  pub fn resolve_includes(&mut self, context: &IncludeContext) -> Result<(), ConfigError> {
+    let mut stack = Vec::new();
+    if let Some(path) = &self.path {
+      stack.push(fs::canonicalize(path).unwrap_or_else(|_| path.clone()));
+    }
+    self.resolve_includes_inner(context, &mut stack)
+  }
+
+  fn resolve_includes_inner(
+    &mut self,
+    context: &IncludeContext,
+    stack: &mut Vec<PathBuf>,
+  ) -> Result<(), ConfigError> {
+    let mut includes = Vec::new();
+    let mut seen: Vec<(String, &BStr)> = Vec::new();
+    for entry in self.entries.iter().filter(|entry| entry.is_include()) {
+      let value = match entry.value() {
+        Some(value) => value,
+        None => continue,
+      };
+      let key = entry.key();
+      let nth = seen
+        .iter()
+        .filter(|(k, v)| *k == key && *v == value)
+        .count();
+      seen.push((key.clone(), value));
+      let condition = entry.subsection();
+      if !condition.map_or(Ok(true), |condition| {
+        self.include_condition(condition, context)
+      })? {
+        continue;
+      }
+      let path = self.include_path(&key, value)?;
+      let real_path = match fs::canonicalize(&path) {
+        Ok(path) => path,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(e.into()),
+      };
+      if stack.contains(&real_path) {
+        return Err(ConfigError::IncludeCycle(path));
+      }
+      if stack.len() >= MAX_INCLUDE_DEPTH {
+        return Err(ConfigError::IncludeDepth(path));
+      }
+      let mut file = ConfigFile::from_file(&path, self.level)?;
+      stack.push(real_path);
+      file.resolve_includes_inner(context, stack)?;
+      stack.pop();
+      includes.push(Include {
+        key,
+        value: value.into(),
+        nth,
+        file,
+      });
+    }
+    self.includes = includes;
+    Ok(())
+  }
+
+  /// Where the file included by the value of an include entry is
+  fn include_path(&self, key: &str, value: &BStr) -> Result<PathBuf, ConfigError> {
+    let value = value.to_str().map_err(|_| ConfigError::InvalidValue {
+      key: key.into(),
+      value: value.into(),
+      expected: "a UTF-8 path",
+    })?;
+    let path = expand_path(value).ok_or(ConfigError::NoHome)?;
+    if path.is_absolute() {
+      return Ok(path);
+    }
+    match self.path.as_ref().and_then(|path| path.parent()) {
+      Some(dir) => Ok(dir.join(path)),
+      None => Err(ConfigError::RelativeInclude(value.into())),
+    }
+  }
+
+  /// Whether the condition of an `includeIf` section holds
+  fn include_condition(
+    &self,
+    condition: &BStr,
+    context: &IncludeContext,
+  ) -> Result<bool, ConfigError> {
+    if let Some(pattern) = condition.strip_prefix(b"gitdir:") {
+      self.gitdir_matches(pattern, context, 0)
+    } else if let Some(pattern) = condition.strip_prefix(b"gitdir/i:") {
+      self.gitdir_matches(pattern, context, wildmatch::CASEFOLD)
+    } else if let Some(pattern) = condition.strip_prefix(b"onbranch:") {
+      let branch = match &context.branch {
+        Some(branch) => branch,
+        None => return Ok(false),
+      };
+      let mut pattern = pattern.to_vec();
+      if pattern.ends_with(b"/") {
+        pattern.extend_from_slice(b"**");
+      }
+      Ok(wildmatch(&pattern, branch, wildmatch::PATHNAME))
+    } else {
+      Ok(false)
+    }
+  }
+
+  fn gitdir_matches(
+    &self,
+    pattern: &[u8],
+    context: &IncludeContext,
+    flags: u8,
+  ) -> Result<bool, ConfigError> {
+    let git_dir = match &context.git_dir {
+      Some(git_dir) => git_dir,
+      None => return Ok(false),
+    };
+    let mut full = Vec::new();
+    if let Some(rest) = pattern.strip_prefix(b"~/") {
+      let home = home_dir().ok_or(ConfigError::NoHome)?;
+      full.extend_from_slice(&path_bytes(&home));
+      full.push(b'/');
+      full.extend_from_slice(rest);
+    } else if let Some(rest) = pattern.strip_prefix(b"./") {
+      let dir = match self.path.as_ref().and_then(|path| path.parent()) {
+        Some(dir) => dir,
+        None => return Err(ConfigError::RelativeInclude(pattern.as_bstr().to_string())),
+      };
+      full.extend_from_slice(&path_bytes(&fs::canonicalize(dir)?));
+      full.push(b'/');
+      full.extend_from_slice(rest);
+    } else if pattern.starts_with(b"/") || pattern.starts_with(b"**/") {
+      full.extend_from_slice(pattern);
+    } else {
+      full.extend_from_slice(b"**/");
+      full.extend_from_slice(pattern);
+    }
+    if full.ends_with(b"/") {
+      full.extend_from_slice(b"**");
+    }
+    let flags = flags | wildmatch::PATHNAME;
+    let real = fs::canonicalize(git_dir).ok();
+    Ok(
+      [Some(git_dir.as_path()), real.as_deref()]
+        .iter()
+        .flatten()
+        .any(|dir| wildmatch(&full, &path_bytes(dir), flags)),
+    )
+  }
+
+  /// The text of the file including any changes that were made to it
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.source
+  }
+
+  /// Set a key to a single value, like `git config key value`. If the key is
+  /// already set its line is replaced in place, otherwise it is added to the
+  /// end of the last section it belongs in, creating the section if needed.
+  /// Setting a key that has more than one value is an error, use
+  /// [`ConfigFile::unset_all`] first to replace all of them.
+  pub fn set(&mut self, key: &str, value: impl AsRef<[u8]>) -> Result<(), ConfigError> {
+    let parsed = Key::parse(key)?;
+    let existing: Vec<_> = self.matching(&parsed).collect();
+    match existing.as_slice() {
+      [] => self.add(key, value),
+      [idx] => {
+        let span = self.entries[*idx].span.clone();
+        let mut line = format_entry(parsed.name, value.as_ref());
+        // An entry on the same line as its section header keeps the newline
+        // outside of its span
+        if self.source[span.clone()].last() != Some(&b'\n') {
+          line.pop();
+        }
+        self.splice(span, &line)
+      }
+      _ => Err(ConfigError::MultipleValues(key.into())),
+    }
+  }
+
+  /// Add another value for a key without touching existing values, like
+  /// `git config --add key value`. This is how multi-valued keys such as
+  /// `remote.origin.fetch` are built up.
+  pub fn add(&mut self, key: &str, value: impl AsRef<[u8]>) -> Result<(), ConfigError> {
+    let key = Key::parse(key)?;
+    let line = format_entry(key.name, value.as_ref());
+    let header = self.sections.iter().rposition(|header| {
+      header.section.eq_ignore_ascii_case(key.section)
+        && header.subsection.as_deref().map(|s| s.as_bytes()) == key.subsection
+    });
+    match header {
+      Some(header) => {
+        let after = self
+          .entries
+          .iter()
+          .rfind(|entry| entry.header == header)
+          .map_or(self.sections[header].line_end, |entry| entry.span.end);
+        let at = self.source[after..]
+          .find_byte(b'\n')
+          .filter(|_| after > 0 && self.source[after - 1] != b'\n')
+          .map_or(after, |idx| after + idx + 1);
+        let line = if at > 0 && self.source[at - 1] != b'\n' {
+          [&b"\n"[..], &line].concat()
+        } else {
+          line
+        };
+        self.splice(at..at, &line)
+      }
+      None => {
+        let mut text = Vec::new();
+        if !self.source.is_empty() && !self.source.ends_with(b"\n") {
+          text.push(b'\n');
+        }
+        text.extend_from_slice(&format_header(key.section, key.subsection));
+        text.extend_from_slice(&line);
+        let end = self.source.len();
+        self.splice(end..end, &text)
+      }
+    }
+  }
+
+  /// Remove a key, like `git config --unset key`. Removing a key that has
+  /// more than one value is an error, use [`ConfigFile::unset_all`] for
+  /// that. Returns whether the key was set.
+  pub fn unset(&mut self, key: &str) -> Result<bool, ConfigError> {
+    let parsed = Key::parse(key)?;
+    if self.matching(&parsed).count() > 1 {
+      return Err(ConfigError::MultipleValues(key.into()));
+    }
+    Ok(self.unset_all(key)? == 1)
+  }
+
+  /// Remove every value of a key, like `git config --unset-all key`,
+  /// returning how many values were removed. Section headers are left in
+  /// place even if they become empty, as git does.
+  pub fn unset_all(&mut self, key: &str) -> Result<usize, ConfigError> {
+    let parsed = Key::parse(key)?;
+    let spans: Vec<_> = self
+      .matching(&parsed)
+      .map(|idx| self.entries[idx].span.clone())
+      .collect();
+    let mut source = self.source.clone();
+    // Go back to front so the earlier spans stay valid
+    for span in spans.iter().rev() {
+      source.drain(span.clone());
+    }
+    self.reparse(source)?;
+    Ok(spans.len())
+  }
+
+  /// The headers of the section `name`, which is `section` or
+  /// `section.subsection` like `remote.origin`
+  fn section_headers(&self, name: &str) -> Result<Vec<usize>, ConfigError> {
+    // Checked the same way as the section of a key, `name` can't have one
+    let key = format!("{}.name", name);
+    let key = Key::parse(&key).map_err(|_| ConfigError::InvalidKey(name.into()))?;
+    Ok(
+      self
+        .sections
+        .iter()
+        .enumerate()
+        .filter(|(_, header)| {
+          header.section.eq_ignore_ascii_case(key.section)
+            && header.subsection.as_deref().map(|s| s.as_bytes()) == key.subsection
+        })
+        .map(|(idx, _)| idx)
+        .collect(),
+    )
+  }
+
+  /// Rename the section `name` to `new_name`, like `git config
+  /// --rename-section remote.old remote.new`. Names are `section` or
+  /// `section.subsection`. Every header of the section is rewritten and
+  /// its entries stay where they are. Returns whether there was such a
+  /// section.
+  pub fn rename_section(&mut self, name: &str, new_name: &str) -> Result<bool, ConfigError> {
+    let headers = self.section_headers(name)?;
+    let key = format!("{}.name", new_name);
+    let key = Key::parse(&key).map_err(|_| ConfigError::InvalidKey(new_name.into()))?;
+    let mut header = format_header(key.section, key.subsection);
+    // Only the brackets are replaced, the newline after them stays
+    header.pop();
+    let mut source = self.source.clone();
+    for idx in headers.iter().rev() {
+      let header_span = self.sections[*idx].start..self.sections[*idx].end;
+      source.splice(header_span, header.iter().copied());
+    }
+    self.reparse(source)?;
+    Ok(!headers.is_empty())
+  }
+
+  /// Remove the section `name` with every entry and comment in it, like
+  /// `git config --remove-section remote.origin`. Names are `section` or
+  /// `section.subsection`. Returns whether there was such a section.
+  pub fn remove_section(&mut self, name: &str) -> Result<bool, ConfigError> {
+    let headers = self.section_headers(name)?;
+    let mut source = self.source.clone();
+    for idx in headers.iter().rev() {
+      let end = self
+        .sections
+        .get(idx + 1)
+        .map_or(self.source.len(), |next| next.start);
+      source.drain(self.sections[*idx].start..end);
+    }
+    self.reparse(source)?;
+    Ok(!headers.is_empty())
+  }
+
+  /// Write the file back to where it was read from. The new contents are
+  /// written to `{path}.lock` first and then moved into place, and it's an
+  /// error if the lock file already exists since that means someone else is
+  /// changing the file.
+  pub fn save(&self) -> Result<(), ConfigError> {
+    let path = self.path.as_ref().ok_or(ConfigError::NoPath)?;
+    let mut lock_path = path.clone().into_os_string();
+    lock_path.push(".lock");
+    let lock_path = PathBuf::from(lock_path);
+    let mut lock = match fs::OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .open(&lock_path)
+    {
+      Ok(lock) => lock,
+      Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+        return Err(ConfigError::Locked(lock_path))
+      }
+      Err(e) => return Err(e.into()),
+    };
+    let result = lock
+      .write_all(&self.source)
+      .and_then(|_| lock.sync_all())
+      .and_then(|_| fs::rename(&lock_path, path));
+    if let Err(e) = result {
+      let _ = fs::remove_file(&lock_path);
+      return Err(e.into());
+    }
+    Ok(())
+  }
+
+  fn matching<'a>(&'a self, key: &'a Key<'_>) -> impl Iterator<Item = usize> + 'a {
+    self
+      .entries
+      .iter()
+      .enumerate()
+      .filter(move |(_, entry)| entry.matches(key))
+      .map(|(idx, _)| idx)
+  }
+
+  fn splice(&mut self, range: Range<usize>, text: &[u8]) -> Result<(), ConfigError> {
+    let mut source = self.source.clone();
+    source.splice(range, text.iter().copied());
+    self.reparse(source)
+  }
+
+  fn reparse(&mut self, source: BString) -> Result<(), ConfigError> {
+    let (sections, entries) = parse(&source).map_err(|(line, reason)| ConfigError::Syntax {
+      path: self.path.clone(),
+      line,
+      reason,
+    })?;
+    // Included files stay attached to the entries that included them, which
+    // are found again by key and value
+    self.source = source;
+    self.sections = sections;
+    self.entries = entries;
+    Ok(())
+  }
+}
+
+impl Config {
+  /// Create an empty [`Config`]
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Load the system, global, and repository config files for the
+  /// repository at `git_dir` the same way git does. Files that don't exist
+  /// are skipped. `GIT_CONFIG_NOSYSTEM`, `GIT_CONFIG_SYSTEM`, and
+  /// `GIT_CONFIG_GLOBAL` are honored. A linked worktree reads the config of
+  /// the repository it belongs to, then its own `config.worktree` if
+  /// `extensions.worktreeConfig` is set.
+  pub fn open(git_dir: impl AsRef<Path>) -> Result<Self, ConfigError> {
+    let git_dir = git_dir.as_ref();
+    let common_dir = crate::repository::common_dir(git_dir);
+    let mut config = Self::new();
+    config.add_default_files()?;
+    config.add_file_if_exists(common_dir.join("config"), ConfigLevel::Local)?;
+    if config.get_bool("extensions.worktreeConfig")? == Some(true) {
+      config.add_file_if_exists(git_dir.join("config.worktree"), ConfigLevel::Local)?;
+    }
+    config.resolve_includes(&IncludeContext::new(git_dir))?;
+    Ok(config)
+  }
+
+  /// Load only the system and global config files, for when there is no
+  /// repository
+  pub fn open_global() -> Result<Self, ConfigError> {
+    let mut config = Self::new();
+    config.add_default_files()?;
+    config.resolve_includes(&IncludeContext::default())?;
+    Ok(config)
+  }
+
+  fn add_default_files(&mut self) -> Result<(), ConfigError> {
+    for path in system_paths() {
+      self.add_file_if_exists(path, ConfigLevel::System)?;
+    }
+    for path in global_paths() {
+      self.add_file_if_exists(path, ConfigLevel::Global)?;
+    }
+    Ok(())
+  }
+
+  /// Resolve the includes of every file with
+  /// [`ConfigFile::resolve_includes`]. [`Config::open`] and
+  /// [`Config::open_global`] already do this.
+  pub fn resolve_includes(&mut self, context: &IncludeContext) -> Result<(), ConfigError> {
+    for file in &mut self.files {
+      file.resolve_includes(context)?;
+    }
+    Ok(())
+  }
+
+  /// Parse a single config file from bytes into a [`Config`]
+  pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<Self, ConfigError> {
+    let mut config = Self::new();
+    config.add(ConfigFile::from_bytes(bytes, ConfigLevel::Local)?);
+    Ok(config)
+  }
+
+  /// Add a [`ConfigFile`]. Files are kept ordered by their [`ConfigLevel`]
+  /// and files of the same level added later take priority.
+  pub fn add(&mut self, file: ConfigFile) {
+    let idx = self
+      .files
+      .iter()
+      .position(|f| f.level > file.level)
+      .unwrap_or(self.files.len());
+    self.files.insert(idx, file);
+  }
+
+  /// Read the file at `path` and add it if it exists
+  pub fn add_file_if_exists(
+    &mut self,
+    path: impl AsRef<Path>,
+    level: ConfigLevel,
+  ) -> Result<(), ConfigError> {
+    match ConfigFile::from_file(path, level) {
+      Ok(file) => self.add(file),
+      Err(ConfigError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+      Err(e) => return Err(e),
+    }
+    Ok(())
+  }
+
+  /// All of the files that make up this [`Config`], lowest priority first
+  pub fn files(&self) -> &[ConfigFile] {
+    &self.files
+  }
+
+  /// The highest priority file of the given [`ConfigLevel`], which is the
+  /// one changes at that level should be made to
+  pub fn file_mut(&mut self, level: ConfigLevel) -> Option<&mut ConfigFile> {
+    self.files.iter_mut().rev().find(|file| file.level == level)
+  }
+
+  /// Every entry across all files including included files, lowest
+  /// priority first
+  pub fn entries(&self) -> impl Iterator<Item = &ConfigEntry> {
+    self.files.iter().flat_map(|file| file.resolved_entries())
+  }
+
+  fn matching(&self, key: &str) -> Result<Vec<&ConfigEntry>, ConfigError> {
+    let key = Key::parse(key)?;
+    Ok(self.entries().filter(|e| e.matches(&key)).collect())
+  }
+
+  fn last(&self, key: &str) -> Result<Option<&ConfigEntry>, ConfigError> {
+    Ok(self.matching(key)?.pop())
+  }
+
+  /// Get the value for a key. If the key is set more than once the value
+  /// with the highest priority is returned. A name without an `=` is
+  /// returned as an empty value.
+  pub fn get(&self, key: &str) -> Option<&BStr> {
+    let entry = self.last(key).ok()??;
+    Some(entry.value().unwrap_or_else(|| b"".as_bstr()))
+  }
+
+  /// Get the value for a key as a UTF-8 string
+  pub fn get_str(&self, key: &str) -> Result<Option<&str>, ConfigError> {
+    self
+      .get(key)
+      .map(|value| {
+        value.to_str().map_err(|_| ConfigError::InvalidValue {
+          key: key.into(),
+          value: value.into(),
+          expected: "a UTF-8 string",
+        })
+      })
+      .transpose()
+  }
+
+  /// Get every value for a multi-valued key such as `remote.origin.fetch`,
+  /// lowest priority first
+  pub fn get_all(&self, key: &str) -> Vec<&BStr> {
+    match self.matching(key) {
+      Ok(entries) => entries
+        .into_iter()
+        .map(|e| e.value().unwrap_or_else(|| b"".as_bstr()))
+        .collect(),
+      Err(_) => Vec::new(),
+    }
+  }
+
+  /// Get a key as a boolean. `true`, `yes`, `on`, a name without a value, and
+  /// any non zero number are true. `false`, `no`, `off`, `0`, and an empty
+  /// value are false.
+  pub fn get_bool(&self, key: &str) -> Result<Option<bool>, ConfigError> {
+    let entry = match self.last(key)? {
+      Some(entry) => entry,
+      None => return Ok(None),
+    };
+    let value = match entry.value() {
+      Some(value) => value,
+      None => return Ok(Some(true)),
+    };
+    parse_bool(value)
+      .map(Some)
+      .ok_or_else(|| ConfigError::InvalidValue {
+        key: key.into(),
+        value: value.into(),
+        expected: "a boolean",
+      })
+  }
+
+  /// Get a key as an integer. The suffixes `k`, `m`, and `g` multiply the
+  /// value by 1024, 1024², and 1024³ the same way they do in git.
+  pub fn get_int(&self, key: &str) -> Result<Option<i64>, ConfigError> {
+    self
+      .get(key)
+      .map(|value| {
+        parse_int(value).ok_or_else(|| ConfigError::InvalidValue {
+          key: key.into(),
+          value: value.into(),
+          expected: "an integer",
+        })
+      })
+      .transpose()
+  }
+
+  /// Get a key as a path. A leading `~/` is expanded to the home directory
+  /// of the current user.
+  pub fn get_path(&self, key: &str) -> Result<Option<PathBuf>, ConfigError> {
+    self
+      .get_str(key)?
+      .map(|value| expand_path(value).ok_or(ConfigError::NoHome))
+      .transpose()
+  }
+}
+
+/// A key split into its parts. The subsection is `None` for two part keys.
+struct Key<'a> {
+  section: &'a [u8],
+  subsection: Option<&'a [u8]>,
+  name: &'a [u8],
+}
+
+impl<'a> Key<'a> {
+  fn parse(key: &'a str) -> Result<Self, ConfigError> {
+    let invalid = || ConfigError::InvalidKey(key.into());
+    let first = key.find('.').ok_or_else(invalid)?;
+    let last = key.rfind('.').ok_or_else(invalid)?;
+    let section = &key[..first];
+    let name = &key[last + 1..];
+    if section.is_empty() || name.is_empty() {
+      return Err(invalid());
+    }
+    let subsection = (first != last).then(|| &key.as_bytes()[first + 1..last]);
+    Ok(Self {
+      section: section.as_bytes(),
+      subsection,
+      name: name.as_bytes(),
+    })
+  }
+}
+
+pub(crate) fn parse_bool(value: &[u8]) -> Option<bool> {
+  match value.to_ascii_lowercase().as_slice() {
+    b"true" | b"yes" | b"on" => Some(true),
+    b"false" | b"no" | b"off" | b"" => Some(false),
+    _ => parse_int(value).map(|i| i != 0),
+  }
+}
+
+pub(crate) fn parse_int(value: &[u8]) -> Option<i64> {
+  let value = value.to_str().ok()?.trim();
+  let (digits, factor) = match value.as_bytes().last()?.to_ascii_lowercase() {
+    b'k' => (&value[..value.len() - 1], 1024),
+    b'm' => (&value[..value.len() - 1], 1024 * 1024),
+    b'g' => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+    _ => (value, 1),
+  };
+  digits.parse::<i64>().ok()?.checked_mul(factor)
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+  use std::os::unix::ffi::OsStrExt;
+  Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+/// Paths are matched against patterns with `/` separators like git does on
+/// every platform
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+  Cow::Owned(path.to_string_lossy().replace('\\', "/").into_bytes())
+}
+
+fn home_dir() -> Option<PathBuf> {
+  env::var_os("HOME")
+    .or_else(|| env::var_os("USERPROFILE"))
+    .map(PathBuf::from)
+}
+
+fn expand_path(value: &str) -> Option<PathBuf> {
+  match value.strip_prefix("~/") {
+    Some(rest) => Some(home_dir()?.join(rest)),
+    None => Some(PathBuf::from(value)),
+  }
+}
+
+fn system_paths() -> Vec<PathBuf> {
+  let no_system = env::var("GIT_CONFIG_NOSYSTEM").ok();
+  if no_system.and_then(|v| parse_bool(v.as_bytes())) == Some(true) {
+    return Vec::new();
+  }
+  match env::var_os("GIT_CONFIG_SYSTEM") {
+    Some(path) => vec![path.into()],
+    None => vec![PathBuf::from("/etc/gitconfig")],
+  }
+}
+
+fn global_paths() -> Vec<PathBuf> {
+  if let Some(path) = env::var_os("GIT_CONFIG_GLOBAL") {
+    return vec![path.into()];
+  }
+  let mut paths = Vec::new();
+  paths.extend(xdg_config_path("config"));
+  paths.extend(home_dir().map(|home| home.join(".gitconfig")));
+  paths
+}
+
+/// The path of the file `name` in `$XDG_CONFIG_HOME/git`, which is
+/// `~/.config/git` when the variable isn't set
+pub(crate) fn xdg_config_path(name: &str) -> Option<PathBuf> {
+  match env::var_os("XDG_CONFIG_HOME") {
+    Some(xdg) if !xdg.is_empty() => Some(PathBuf::from(xdg).join("git").join(name)),
+    _ => Some(home_dir()?.join(".config/git").join(name)),
+  }
+}
+
+fn format_header(section: &[u8], subsection: Option<&[u8]>) -> Vec<u8> {
+  let mut header = vec![b'['];
+  header.extend_from_slice(section);
+  if let Some(subsection) = subsection {
+    header.extend_from_slice(b" \"");
+    for &c in subsection {
+      if c == b'"' || c == b'\\' {
+        header.push(b'\\');
+      }
+      header.push(c);
+    }
+    header.push(b'"');
+  }
+  header.extend_from_slice(b"]\n");
+  header
+}
+
+/// Format an entry as `\tname = value\n`, quoting and escaping the value so
+/// that it parses back to exactly the same bytes
+fn format_entry(name: &[u8], value: &[u8]) -> Vec<u8> {
+  let needs_quotes = value.first().is_some_and(u8::is_ascii_whitespace)
+    || value.last().is_some_and(u8::is_ascii_whitespace)
+    || value.iter().any(|c| matches!(c, b'#' | b';'))
+    || value.find(b"  ").is_some();
+  let mut line = vec![b'\t'];
+  line.extend_from_slice(name);
+  line.extend_from_slice(b" = ");
+  if needs_quotes {
+    line.push(b'"');
+  }
+  for &c in value {
+    match c {
+      b'\n' => line.extend_from_slice(b"\\n"),
+      b'\t' => line.extend_from_slice(b"\\t"),
+      0x08 => line.extend_from_slice(b"\\b"),
+      b'"' | b'\\' => line.extend_from_slice(&[b'\\', c]),
+      c => line.push(c),
+    }
+  }
+  if needs_quotes {
+    line.push(b'"');
+  }
+  line.push(b'\n');
+  line
+}
+
+struct Parser<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+  line: usize,
+}
+
+type ParseError = (usize, &'static str);
+
+impl Parser<'_> {
+  fn peek(&self) -> Option<u8> {
+    self.bytes.get(self.pos).copied()
+  }
+
+  fn next(&mut self) -> Option<u8> {
+    let c = self.peek()?;
+    self.pos += 1;
+    if c == b'\n' {
+      self.line += 1;
+    }
+    Some(c)
+  }
+
+  fn error(&self, reason: &'static str) -> ParseError {
+    (self.line, reason)
+  }
+
+  fn skip_comment(&mut self) {
+    while !matches!(self.peek(), None | Some(b'\n')) {
+      self.next();
+    }
+  }
+
+  fn skip_blanks(&mut self) {
+    while matches!(self.peek(), Some(b' ' | b'\t' | b'\r')) {
+      self.next();
+    }
+  }
+
+  /// Parse `[section]`, `[section "subsection"]`, or the deprecated
+  /// `[section.subsection]` headers
+  fn section(&mut self) -> Result<(BString, Option<BString>), ParseError> {
+    let start = self.line;
+    let error = |reason| (start, reason);
+    // Skip the [
+    self.next();
+    let mut name = BString::from("");
+    loop {
+      match self.next() {
+        Some(b']') => break,
+        Some(c) if c.is_ascii_alphanumeric() || c == b'-' || c == b'.' => {
+          name.push(c.to_ascii_lowercase())
+        }
+        Some(b' ' | b'\t') => {
+          self.skip_blanks();
+          if self.next() != Some(b'"') {
+            return Err(error("expected a quoted subsection"));
+          }
+          let subsection = self.subsection(start)?;
+          if self.next() != Some(b']') {
+            return Err(error("expected ] after the subsection"));
+          }
+          if name.is_empty() || name.contains(&b'.') {
+            return Err(error("invalid section name"));
+          }
+          return Ok((name, Some(subsection)));
+        }
+        _ => return Err(error("invalid section header")),
+      }
+    }
+    match name.find_byte(b'.') {
+      _ if name.is_empty() => Err(error("empty section name")),
+      Some(idx) => Ok((name[..idx].into(), Some(name[idx + 1..].into()))),
+      None => Ok((name, None)),
+    }
+  }
+
+  fn subsection(&mut self, start: usize) -> Result<BString, ParseError> {
+    let mut subsection = BString::from("");
+    loop {
+      match self.next() {
+        Some(b'"') => return Ok(subsection),
+        Some(b'\\') => match self.next() {
+          Some(b'\n') | None => return Err((start, "unterminated subsection")),
+          Some(c) => subsection.push(c),
+        },
+        Some(b'\n') | None => return Err((start, "unterminated subsection")),
+        Some(c) => subsection.push(c),
+      }
+    }
+  }
+
+  fn name(&mut self) -> BString {
+    let mut name = BString::from("");
+    while let Some(c) = self.peek() {
+      if c.is_ascii_alphanumeric() || c == b'-' {
+        name.push(c.to_ascii_lowercase());
+        self.next();
+      } else {
+        break;
+      }
+    }
+    name
+  }
+
+  /// Parse a value after the `=` following git's rules: surrounding
+  /// whitespace is dropped, inner whitespace becomes single spaces for each
+  /// whitespace character, double quotes preserve whitespace and comment
+  /// characters, and backslash escapes `\n`, `\t`, `\b`, `\"`, `\\`, and line
+  /// continuations.
+  fn value(&mut self) -> Result<BString, ParseError> {
+    let mut value = BString::from("");
+    let mut quoted = false;
+    let mut comment = false;
+    let mut spaces = 0;
+    loop {
+      let c = match self.next() {
+        None if quoted => return Err(self.error("unterminated quoted value")),
+        // The newline was already counted but the error is on the line before
+        Some(b'\n') if quoted => return Err((self.line - 1, "unterminated quoted value")),
+        None | Some(b'\n') => return Ok(value),
+        Some(c) => c,
+      };
+      if comment {
+        continue;
+      }
+      if c.is_ascii_whitespace() && !quoted {
+        if !value.is_empty() {
+          spaces += 1;
+        }
+        continue;
+      }
+      if !quoted && (c == b'#' || c == b';') {
+        comment = true;
+        continue;
+      }
+      for _ in 0..spaces {
+        value.push(b' ');
+      }
+      spaces = 0;
+      match c {
+        b'\\' => match self.next() {
+          Some(b'\n') => {}
+          Some(b'\r') if self.peek() == Some(b'\n') => {
+            self.next();
+          }
+          Some(b'n') => value.push(b'\n'),
+          Some(b't') => value.push(b'\t'),
+          Some(b'b') => value.push(0x08),
+          Some(c @ (b'"' | b'\\')) => value.push(c),
+          _ => return Err(self.error("invalid escape sequence in value")),
+        },
+        b'"' => quoted = !quoted,
+        c => value.push(c),
+      }
+    }
+  }
+}
+
+fn parse(bytes: &[u8]) -> Result<(Vec<SectionHeader>, Vec<ConfigEntry>), ParseError> {
+  let mut parser = Parser {
+    bytes,
+    pos: if bytes.starts_with(b"\xef\xbb\xbf") {
+      3
+    } else {
+      0
+    },
+    line: 1,
+  };
+  let mut sections: Vec<SectionHeader> = Vec::new();
+  let mut entries = Vec::new();
+  // Where the last section header ended, so an entry on the same line as
+  // a header does not take the header with it when it is removed
+  let mut header_end = 0;
+  loop {
+    match parser.peek() {
+      None => return Ok((sections, entries)),
+      Some(c) if c.is_ascii_whitespace() => {
+        parser.next();
+      }
+      Some(b'#' | b';') => parser.skip_comment(),
+      Some(b'[') => {
+        let start = parser.pos;
+        let (section, subsection) = parser.section()?;
+        header_end = parser.pos;
+        let line_end = bytes[header_end..]
+          .find_byte(b'\n')
+          .map_or(bytes.len(), |idx| header_end + idx + 1);
+        sections.push(SectionHeader {
+          section,
+          subsection,
+          start,
+          end: header_end,
+          line_end,
+        });
+      }
+      Some(c) if c.is_ascii_alphabetic() => {
+        let header = sections
+          .len()
+          .checked_sub(1)
+          .ok_or_else(|| parser.error("entry outside of a section"))?;
+        let line_start = bytes[..parser.pos]
+          .rfind_byte(b'\n')
+          .map_or(0, |idx| idx + 1);
+        let start = line_start.max(header_end);
+        let line = parser.line;
+        let name = parser.name();
+        parser.skip_blanks();
+        let value = match parser.peek() {
+          Some(b'=') => {
+            parser.next();
+            Some(parser.value()?)
+          }
+          None | Some(b'\n') => None,
+          Some(b'#' | b';') => {
+            parser.skip_comment();
+            None
+          }
+          Some(_) => return Err(parser.error("invalid character in name")),
+        };
+        if value.is_none() && parser.peek() == Some(b'\n') {
+          parser.next();
+        }
+        let mut end = parser.pos;
+        if start == header_end && bytes[end - 1] == b'\n' {
+          end -= 1;
+        }
+        entries.push(ConfigEntry {
+          section: sections[header].section.clone(),
+          subsection: sections[header].subsection.clone(),
+          name,
+          value,
+          line,
+          header,
+          span: start..end,
+        });
+      }
+      Some(_) => return Err(parser.error("unexpected character")),
+    }
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`Config`] type
+pub enum ConfigError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("bad config line {line}{}: {reason}", path.as_ref().map(|p| format!(" in {}", p.display())).unwrap_or_default())]
+  Syntax {
+    path: Option<PathBuf>,
+    line: usize,
+    reason: &'static str,
+  },
+  #[error("invalid config key {0:?}")]
+  InvalidKey(String),
+  #[error("invalid value {value:?} for {key}, expected {expected}")]
+  InvalidValue {
+    key: String,
+    value: BString,
+    expected: &'static str,
+  },
+  #[error("could not find the home directory to expand a path")]
+  NoHome,
+  #[error("{0} has multiple values")]
+  MultipleValues(String),
+  #[error("the config file was not read from disk so it can't be saved")]
+  NoPath,
+  #[error("the config file is locked by {0:?}")]
+  Locked(PathBuf),
+  #[error("relative config include {0:?} is not in a file")]
+  RelativeInclude(String),
+  #[error("config file {0:?} includes itself")]
+  IncludeCycle(PathBuf),
+  #[error(
+    "including {0:?} exceeds the maximum include depth of {}",
+    MAX_INCLUDE_DEPTH
+  )]
+  IncludeDepth(PathBuf),
+}
+
+#[cfg(test)]
+const TEST_CONFIG: &str = r#"
+# Comment
+[core]
+	fileMode = false
+	bare
+	compression = 2k ; trailing comment
+[remote "origin"]
+	url = https://example.com/repo.git
+	fetch = +refs/heads/*:refs/remotes/origin/*
+	fetch = +refs/tags/*:refs/tags/*
+[Remote "Origin"]
+	url = "  quoted # not a comment  "
+[branch.Main]
+	remote = origin
+[alias]
+	lg = log --oneline \
+	  --graph
+	say = "!echo \"hi\"\tthere"
+"#;
+
+#[test]
+fn parse_entries() {
+  let file = ConfigFile::from_bytes(TEST_CONFIG, ConfigLevel::Local).unwrap();
+  let keys: Vec<_> = file.entries().iter().map(|e| e.key()).collect();
+  assert_eq!(
+    vec![
+      "core.filemode",
+      "core.bare",
+      "core.compression",
+      "remote.origin.url",
+      "remote.origin.fetch",
+      "remote.origin.fetch",
+      "remote.Origin.url",
+      "branch.main.remote",
+      "alias.lg",
+      "alias.say",
+    ],
+    keys
+  );
+  assert_eq!(4, file.entries()[0].line());
+}
+
+#[test]
+fn getters() {
+  let config = Config::from_bytes(TEST_CONFIG).unwrap();
+  assert_eq!(Some(false), config.get_bool("core.fileMode").unwrap());
+  assert_eq!(Some(true), config.get_bool("core.bare").unwrap());
+  assert_eq!(None, config.get_bool("core.missing").unwrap());
+  assert!(config.get_bool("remote.origin.url").is_err());
+  assert_eq!(Some(2048), config.get_int("core.compression").unwrap());
+  assert_eq!(
+    Some("https://example.com/repo.git"),
+    config.get_str("remote.origin.url").unwrap()
+  );
+  assert_eq!(
+    Some("  quoted # not a comment  "),
+    config.get_str("Remote.Origin.URL").unwrap()
+  );
+  assert_eq!(2, config.get_all("remote.origin.fetch").len());
+  assert_eq!(
+    Some("origin"),
+    config.get_str("branch.main.remote").unwrap()
+  );
+  assert_eq!(
+    Some("log --oneline    --graph"),
+    config.get_str("alias.lg").unwrap()
+  );
+  assert_eq!(
+    Some("!echo \"hi\"\tthere"),
+    config.get_str("alias.say").unwrap()
+  );
+  assert!(Config::from_bytes("").unwrap().get("invalid").is_none());
+}
+
+#[test]
+fn layering() {
+  let mut config = Config::new();
+  config.add(ConfigFile::from_bytes("[core]\nfileMode = true\n", ConfigLevel::Local).unwrap());
+  config.add(
+    ConfigFile::from_bytes(
+      "[core]\nfileMode = false\n[user]\nname = Jane\n",
+      ConfigLevel::Global,
+    )
+    .unwrap(),
+  );
+  assert_eq!(Some(true), config.get_bool("core.filemode").unwrap());
+  assert_eq!(Some("Jane".into()), config.get("user.name"));
+  assert_eq!(ConfigLevel::Global, config.files()[0].level());
+}
+
+#[test]
+fn syntax_errors() {
+  for (input, line) in [
+    ("[core\nbare", 1),
+    ("bare = true", 1),
+    ("[core]\n\nname = \"unterminated\n", 3),
+    ("[core]\nname = \\q\n", 2),
+    ("[remote \"origin]\n", 1),
+  ] {
+    match ConfigFile::from_bytes(input, ConfigLevel::Local) {
+      Err(ConfigError::Syntax { line: l, .. }) => assert_eq!(line, l, "{}", input),
+      other => panic!("{:?} parsed as {:?}", input, other),
+    }
+  }
+}
+
+#[test]
+fn from_file() {
+  let tmp_dir = tempdir::TempDir::new("config_test").unwrap();
+  let path = tmp_dir.path().join("config");
+  fs::write(&path, "[user]\n\temail = jane@example.com\n").unwrap();
+  let mut config = Config::new();
+  config
+    .add_file_if_exists(&path, ConfigLevel::Local)
+    .unwrap();
+  config
+    .add_file_if_exists(tmp_dir.path().join("missing"), ConfigLevel::Local)
+    .unwrap();
+  assert_eq!(1, config.files().len());
+  assert_eq!(Some(path.as_path()), config.files()[0].path());
+  assert_eq!(
+    Some("jane@example.com"),
+    config.get_str("user.email").unwrap()
+  );
+}
+
+#[test]
+fn set_preserves_formatting() {
+  let mut file = ConfigFile::from_bytes(TEST_CONFIG, ConfigLevel::Local).unwrap();
+  file.set("core.fileMode", "true").unwrap();
+  file.set("core.autocrlf", "input").unwrap();
+  file.set("branch.main.merge", "refs/heads/main").unwrap();
+  file.set("user.name", " Jane # Doe ").unwrap();
+  assert!(matches!(
+    file.set("remote.origin.fetch", "x"),
+    Err(ConfigError::MultipleValues(_))
+  ));
+  let expected = TEST_CONFIG
+    .replace("\tfileMode = false\n", "\tfileMode = true\n")
+    .replace(
+      "trailing comment\n",
+      "trailing comment\n\tautocrlf = input\n",
+    )
+    .replace(
+      "\tremote = origin\n",
+      "\tremote = origin\n\tmerge = refs/heads/main\n",
+    )
+    + "[user]\n\tname = \" Jane # Doe \"\n";
+  assert_eq!(expected, file.as_bytes().to_str().unwrap());
+
+  let config = Config::from_bytes(file.as_bytes()).unwrap();
+  assert_eq!(Some(" Jane # Doe "), config.get_str("user.name").unwrap());
+  assert_eq!(Some(true), config.get_bool("core.filemode").unwrap());
+}
+
+#[test]
+fn add_and_unset() {
+  let mut file = ConfigFile::from_bytes(
+    "[core] bare\n[remote \"origin\"]\n\turl = x",
+    ConfigLevel::Local,
+  )
+  .unwrap();
+  file
+    .add("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")
+    .unwrap();
+  file
+    .add("remote.origin.fetch", "+refs/tags/*:refs/tags/*")
+    .unwrap();
+  assert_eq!(
+    "[core] bare\n[remote \"origin\"]\n\turl = x\n\tfetch = +refs/heads/*:refs/remotes/origin/*\n\tfetch = +refs/tags/*:refs/tags/*\n",
+    file.as_bytes().to_str().unwrap()
+  );
+  assert!(matches!(
+    file.unset("remote.origin.fetch"),
+    Err(ConfigError::MultipleValues(_))
+  ));
+  assert_eq!(2, file.unset_all("remote.origin.fetch").unwrap());
+  assert!(file.unset("core.bare").unwrap());
+  assert!(!file.unset("core.bare").unwrap());
+  assert_eq!(
+    "[core]\n[remote \"origin\"]\n\turl = x\n",
+    file.as_bytes().to_str().unwrap()
+  );
+}
+
+#[test]
+fn rename_and_remove_sections() {
+  let mut file = ConfigFile::from_bytes(
+    "[remote \"origin\"] url = x\n# the mirror\n[core]\n\tbare = false\n[Remote \"origin\"]\n\tfetch = y\n",
+    ConfigLevel::Local,
+  )
+  .unwrap();
+  assert!(file
+    .rename_section("remote.origin", "remote.up\"stream")
+    .unwrap());
+  assert!(!file
+    .rename_section("remote.origin", "remote.other")
+    .unwrap());
+  assert_eq!(
+    "[remote \"up\\\"stream\"] url = x\n# the mirror\n[core]\n\tbare = false\n[remote \"up\\\"stream\"]\n\tfetch = y\n",
+    file.as_bytes().to_str().unwrap()
+  );
+  assert_eq!(
+    Some("x".into()),
+    file.entries()[0].value().map(BString::from)
+  );
+  assert!(matches!(
+    file.rename_section("remote.up\"stream", ""),
+    Err(ConfigError::InvalidKey(_))
+  ));
+  assert!(file.remove_section("remote.up\"stream").unwrap());
+  assert!(!file.remove_section("remote.up\"stream").unwrap());
+  assert_eq!(
+    "[core]\n\tbare = false\n",
+    file.as_bytes().to_str().unwrap()
+  );
+}
+
+#[test]
+fn save() {
+  let tmp_dir = tempdir::TempDir::new("config_test").unwrap();
+  let path = tmp_dir.path().join("config");
+  fs::write(&path, "# keep me\n[core]\n\tbare = false\n").unwrap();
+  let mut file = ConfigFile::from_file(&path, ConfigLevel::Local).unwrap();
+  file.set("core.bare", "true").unwrap();
+  file.save().unwrap();
+  assert_eq!(
+    "# keep me\n[core]\n\tbare = true\n",
+    fs::read_to_string(&path).unwrap()
+  );
+  fs::write(tmp_dir.path().join("config.lock"), "").unwrap();
+  assert!(matches!(file.save(), Err(ConfigError::Locked(_))));
+}
+
+#[test]
+fn includes() {
+  let tmp_dir = tempdir::TempDir::new("config_test").unwrap();
+  let git_dir = tmp_dir.path().join("work/repo/.git");
+  fs::create_dir_all(&git_dir).unwrap();
+  fs::write(git_dir.join("HEAD"), "ref: refs/heads/feature/x\n").unwrap();
+  fs::write(
+    git_dir.join("config"),
+    "[user]\n\tname = Local\n\
+     [include]\n\tpath = ../../../shared.inc\n\
+     [includeIf \"gitdir:work/\"]\n\tpath = ../../../work.inc\n\
+     [includeIf \"gitdir:elsewhere/\"]\n\tpath = ../../../never.inc\n\
+     [includeIf \"onbranch:feature/\"]\n\tpath = ../../../branch.inc\n\
+     [includeIf \"unknown:x\"]\n\tpath = ../../../never.inc\n\
+     [include]\n\tpath = missing.inc\n",
+  )
+  .unwrap();
+  let write = |name: &str, contents: &str| fs::write(tmp_dir.path().join(name), contents).unwrap();
+  write(
+    "shared.inc",
+    "[user]\n\tname = Shared\n\temail = shared@example.com\n",
+  );
+  write(
+    "work.inc",
+    "[user]\n\temail = work@example.com\n[include]\n\tpath = nested.inc\n",
+  );
+  write("nested.inc", "[core]\n\tabbrev = 12\n");
+  write("never.inc", "[core]\n\tabbrev = 4\n");
+  write("branch.inc", "[core]\n\tbare = true\n");
+
+  let mut config = Config::new();
+  config
+    .add_file_if_exists(git_dir.join("config"), ConfigLevel::Local)
+    .unwrap();
+  config
+    .resolve_includes(&IncludeContext::new(&git_dir))
+    .unwrap();
+  // Values from an include are used in place of the include entry, so a
+  // value set before it is overridden
+  assert_eq!(Some("Shared"), config.get_str("user.name").unwrap());
+  assert_eq!(
+    Some("work@example.com"),
+    config.get_str("user.email").unwrap()
+  );
+  assert_eq!(Some(12), config.get_int("core.abbrev").unwrap());
+  assert_eq!(Some(true), config.get_bool("core.bare").unwrap());
+
+  // Editing the including file keeps the includes in place
+  let file = config.file_mut(ConfigLevel::Local).unwrap();
+  file.set("user.name", "Someone").unwrap();
+  assert_eq!(3, file.included_files().count());
+  assert_eq!(Some("Shared"), config.get_str("user.name").unwrap());
+}
+
+#[test]
+fn include_cycle() {
+  let tmp_dir = tempdir::TempDir::new("config_test").unwrap();
+  let a = tmp_dir.path().join("a");
+  fs::write(&a, "[include]\n\tpath = b\n").unwrap();
+  fs::write(tmp_dir.path().join("b"), "[include]\n\tpath = a\n").unwrap();
+  let mut file = ConfigFile::from_file(&a, ConfigLevel::Local).unwrap();
+  assert!(matches!(
+    file.resolve_includes(&IncludeContext::default()),
+    Err(ConfigError::IncludeCycle(_))
+  ));
+
+  let mut file = ConfigFile::from_bytes("[include]\npath = b\n", ConfigLevel::Local).unwrap();
+  assert!(matches!(
+    file.resolve_includes(&IncludeContext::default()),
+    Err(ConfigError::RelativeInclude(_))
+  ));
+}
@@ -0,0 +1,562 @@
+//! A server for the native `git://` protocol, what `git daemon` does. A
+//! client connects, sends one pkt-line naming the service and repository it
+//! wants, and from there on talks to [`UploadPack`] or [`ReceivePack`] as
+//! it would over SSH.
+//!
+//! [`Daemon::run`] takes connections on a [`TcpListener`] with a thread for
+//! each. With the `async` feature `Daemon::run_async` takes them on a tokio
+//! listener instead, and `Daemon::serve_connection_async` serves any tokio
+//! stream, for embedding the daemon in a service running on tokio. The
+//! request is read without blocking and the fetch or push is served on
+//! tokio's blocking threads, since packs are made and stored there.
+//! [`DaemonRequest::parse`] reads the request from bytes alone, for
+//! deciding what to do with a connection before that.
+
+use crate::{
+  ConfigError, Packet, PktLineError, PktLineReader, ReceivePack, ReceivePackError, Repository,
+  RepositoryError, UploadPack, UploadPackError,
+};
+use bstr::{BString, ByteSlice};
+use std::{
+  fmt, fs, io,
+  net::TcpListener,
+  path::{Component, PathBuf},
+  sync::Arc,
+  thread,
+};
+use thiserror::Error;
+
+/// The services a [`Daemon`] can dispatch a connection to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DaemonService {
+  /// Fetches, served by [`UploadPack`]
+  UploadPack,
+  /// Pushes, served by [`ReceivePack`]
+  ReceivePack,
+}
+
+impl DaemonService {
+  /// The name the client asks for the service by, like `git-upload-pack`
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::UploadPack => "git-upload-pack",
+      Self::ReceivePack => "git-receive-pack",
+    }
+  }
+
+  /// The key of the repository config that turns the service on or off,
+  /// like `daemon.uploadpack`
+  fn config_key(&self) -> &'static str {
+    match self {
+      Self::UploadPack => "daemon.uploadpack",
+      Self::ReceivePack => "daemon.receivepack",
+    }
+  }
+}
+
+impl fmt::Display for DaemonService {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+/// The first pkt-line of a `git://` connection, like
+/// `git-upload-pack /project.git\0host=example.com\0\0version=2\0`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DaemonRequest {
+  /// The service asked for
+  pub service: DaemonService,
+  /// The path of the repository as the client sent it
+  pub path: BString,
+  /// The host the client connected to, with the port if it gave one
+  pub host: Option<BString>,
+  /// The extra parameters after the host, like `version=2`
+  pub extra: Vec<BString>,
+}
+
+impl DaemonRequest {
+  /// Parse the data of the request's pkt-line
+  pub fn parse(line: impl AsRef<[u8]>) -> Result<Self, DaemonError> {
+    let line = line.as_ref();
+    let invalid = || DaemonError::Protocol(format!("invalid request {:?}", line.as_bstr()));
+    let mut fields = line.strip_suffix(b"\n").unwrap_or(line).split_str("\0");
+    // There's always a first field even when the line is empty
+    let command = fields.next().unwrap();
+    let space = command.find_byte(b' ').ok_or_else(invalid)?;
+    let service = match &command[..space] {
+      b"git-upload-pack" => DaemonService::UploadPack,
+      b"git-receive-pack" => DaemonService::ReceivePack,
+      _ => return Err(invalid()),
+    };
+    let path = &command[space + 1..];
+    if path.is_empty() {
+      return Err(invalid());
+    }
+    let mut request = Self {
+      service,
+      path: path.into(),
+      host: None,
+      extra: Vec::new(),
+    };
+    // The host comes first and the extra parameters after an empty field
+    let mut extra = false;
+    for field in fields {
+      match field.strip_prefix(b"host=") {
+        _ if field.is_empty() => extra = true,
+        Some(host) if !extra => request.host = Some(host.into()),
+        _ if extra => request.extra.push(field.into()),
+        _ => return Err(invalid()),
+      }
+    }
+    Ok(request)
+  }
+
+  /// The request as the data of its pkt-line
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = format!("{} {}\0", self.service, self.path).into_bytes();
+    if let Some(host) = &self.host {
+      bytes.extend_from_slice(&[b"host=", &host[..], b"\0"].concat());
+    }
+    if !self.extra.is_empty() {
+      bytes.push(0);
+      for extra in &self.extra {
+        bytes.extend_from_slice(&[&extra[..], b"\0"].concat());
+      }
+    }
+    bytes
+  }
+}
+
+/// Which repositories are served over `git://` and how. Like `git
+/// daemon`, a repository is only served if it has a `git-daemon-export-ok`
+/// file in its git directory unless every repository is exported, pushes
+/// are off unless they're turned on, and a repository can turn either
+/// service on or off for itself with `daemon.uploadpack` and
+/// `daemon.receivepack` in its config.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Daemon {
+  base_path: Option<PathBuf>,
+  allowed: Vec<PathBuf>,
+  export_all: bool,
+  receive_pack: bool,
+}
+
+impl Daemon {
+  /// Serve repositories at the paths clients ask for as they are
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Look for repositories under `base_path`, so `/project.git` is
+  /// `{base_path}/project.git`, like `git daemon --base-path`
+  pub fn base_path(mut self, base_path: impl Into<PathBuf>) -> Self {
+    self.base_path = Some(base_path.into());
+    self
+  }
+
+  /// Only serve repositories inside of `dir`, or of any of the
+  /// directories given this way, like the directories on the command line
+  /// of `git daemon`
+  pub fn allow(mut self, dir: impl Into<PathBuf>) -> Self {
+    self.allowed.push(dir.into());
+    self
+  }
+
+  /// Serve repositories without a `git-daemon-export-ok` file too, like
+  /// `git daemon --export-all`
+  pub fn export_all(mut self, export_all: bool) -> Self {
+    self.export_all = export_all;
+    self
+  }
+
+  /// Take pushes to repositories whose config doesn't say otherwise, like
+  /// `git daemon --enable=receive-pack`. Anyone who can connect can push,
+  /// so it's only for networks where everyone is trusted.
+  pub fn receive_pack(mut self, receive_pack: bool) -> Self {
+    self.receive_pack = receive_pack;
+    self
+  }
+
+  /// Open the repository `request` is for if it's exported and the
+  /// service it asks for is on. What's wrong is never told apart, so
+  /// clients can't find out which repositories exist.
+  pub fn open(&self, request: &DaemonRequest) -> Result<Repository, DaemonError> {
+    let denied = || DaemonError::Denied(request.path.clone());
+    let path = request.path.to_path().map_err(|_| denied())?;
+    // Nothing outside of the directories served can be asked for
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+      return Err(denied());
+    }
+    let path = match &self.base_path {
+      Some(base_path) => base_path.join(path.strip_prefix("/").unwrap_or(path)),
+      None => path.to_path_buf(),
+    };
+    let mut with_git = path.clone().into_os_string();
+    with_git.push(".git");
+    let repo = match Repository::open(&with_git) {
+      Ok(repo) => repo,
+      Err(RepositoryError::NotFound(_)) => Repository::open(&path).map_err(|_| denied())?,
+      Err(_) => return Err(denied()),
+    };
+    // Symlinks can't lead out of the allowed directories either
+    let git_dir = fs::canonicalize(repo.git_dir())?;
+    let allowed = self.allowed.is_empty()
+      || self
+        .allowed
+        .iter()
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .any(|dir| git_dir.starts_with(dir));
+    let exported = self.export_all || repo.git_dir().join("git-daemon-export-ok").is_file();
+    if !allowed || !exported {
+      return Err(denied());
+    }
+    let enabled = match request.service {
+      DaemonService::UploadPack => true,
+      DaemonService::ReceivePack => self.receive_pack,
+    };
+    let config_key = request.service.config_key();
+    if !repo.config().get_bool(config_key)?.unwrap_or(enabled) {
+      return Err(DaemonError::Disabled(request.service));
+    }
+    Ok(repo)
+  }
+
+  /// Serve one connection from its request to the end of the fetch or
+  /// push. An error before anything else was sent is sent to the client as
+  /// an `ERR` line, and the error is returned either way.
+  pub fn serve_connection(
+    &self,
+    input: impl io::Read,
+    output: impl io::Write,
+  ) -> Result<(), DaemonError> {
+    let mut reader = PktLineReader::new(input);
+    let request = match request(reader.read_packet()?) {
+      Some(request) => request,
+      None => return Ok(()),
+    };
+    self.serve_request(request, reader.into_inner(), output)
+  }
+
+  /// Open the repository of `request` and serve it the rest of the
+  /// connection, or tell the client what's wrong
+  fn serve_request(
+    &self,
+    request: Result<DaemonRequest, DaemonError>,
+    input: impl io::Read,
+    mut output: impl io::Write,
+  ) -> Result<(), DaemonError> {
+    let opened = request.and_then(|request| Ok((self.open(&request)?, request.service)));
+    let (repo, service) = match opened {
+      Ok(opened) => opened,
+      Err(e) => {
+        let mut bytes = Vec::new();
+        Packet::Data(format!("ERR {}", e).as_bytes().as_bstr()).encode(&mut bytes)?;
+        output.write_all(&bytes)?;
+        return Err(e);
+      }
+    };
+    match service {
+      DaemonService::UploadPack => UploadPack::new(&repo).serve(input, output)?,
+      DaemonService::ReceivePack => {
+        ReceivePack::new(&repo).serve(input, output)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Take connections on `listener` until it fails, serving each on a
+  /// thread of its own. Errors of a connection only end that connection.
+  pub fn run(&self, listener: &TcpListener) -> io::Result<()> {
+    let daemon = Arc::new(self.clone());
+    loop {
+      let (stream, _) = listener.accept()?;
+      let daemon = daemon.clone();
+      thread::spawn(move || -> Result<(), DaemonError> {
+        let input = stream.try_clone()?;
+        daemon.serve_connection(input, stream)
+      });
+    }
+  }
+}
+
+/// The request of the first packet of a connection, or `None` if the
+/// client hung up before sending one
+fn request(packet: Option<Packet<'_>>) -> Option<Result<DaemonRequest, DaemonError>> {
+  match packet? {
+    Packet::Data(line) => Some(DaemonRequest::parse(line)),
+    packet => Some(Err(DaemonError::Protocol(format!(
+      "expected a request, found {:?}",
+      packet
+    )))),
+  }
+}
+
+/// The async versions of serving connections, for code running on tokio
+#[cfg(feature = "async")]
+impl Daemon {
+  /// [`Daemon::serve_connection`] for a tokio stream. The request is read
+  /// without blocking, then the repository is opened and served on one of
+  /// tokio's blocking threads, which wait on `input` and `output` through
+  /// the runtime.
+  pub async fn serve_connection_async<R, W>(&self, input: R, output: W) -> Result<(), DaemonError>
+  where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+    W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+  {
+    let mut reader = crate::AsyncPktLineReader::new(input);
+    let request = match request(reader.read_packet().await?) {
+      Some(request) => request,
+      None => return Ok(()),
+    };
+    let input = reader.into_inner();
+    let daemon = self.clone();
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+      let input = Blocking::new(input, handle.clone());
+      let output = Blocking::new(output, handle);
+      daemon.serve_request(request, input, output)
+    })
+    .await
+    .map_err(io::Error::other)?
+  }
+
+  /// [`Daemon::run`] for a tokio listener, serving each connection on a
+  /// task of its own. Errors of a connection only end that connection.
+  pub async fn run_async(&self, listener: &tokio::net::TcpListener) -> io::Result<()> {
+    loop {
+      let (stream, _) = listener.accept().await?;
+      let daemon = self.clone();
+      tokio::spawn(async move {
+        let (input, output) = stream.into_split();
+        daemon.serve_connection_async(input, output).await
+      });
+    }
+  }
+}
+
+/// A tokio stream waited on from a blocking thread, for serving fetches and
+/// pushes with the blocking [`UploadPack`] and [`ReceivePack`]
+#[cfg(feature = "async")]
+struct Blocking<T> {
+  inner: T,
+  handle: tokio::runtime::Handle,
+}
+
+#[cfg(feature = "async")]
+impl<T> Blocking<T> {
+  fn new(inner: T, handle: tokio::runtime::Handle) -> Self {
+    Self { inner, handle }
+  }
+}
+
+#[cfg(feature = "async")]
+impl<T: tokio::io::AsyncRead + Unpin> io::Read for Blocking<T> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    use tokio::io::AsyncReadExt;
+    self.handle.block_on(self.inner.read(buf))
+  }
+}
+
+#[cfg(feature = "async")]
+impl<T: tokio::io::AsyncWrite + Unpin> io::Write for Blocking<T> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    use tokio::io::AsyncWriteExt;
+    self.handle.block_on(self.inner.write(buf))
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+    self.handle.block_on(self.inner.flush())
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to serving `git://` connections with a [`Daemon`]
+pub enum DaemonError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  PktLine(#[from] PktLineError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  UploadPack(#[from] UploadPackError),
+  #[error("{0}")]
+  ReceivePack(#[from] ReceivePackError),
+  #[error("access denied or repository not exported: {0}")]
+  Denied(BString),
+  #[error("service not enabled: {0}")]
+  Disabled(DaemonService),
+  #[error("protocol error: {0}")]
+  Protocol(String),
+}
+
+#[test]
+fn serve_connections() {
+  use std::{net::TcpListener, process::Command};
+  let request =
+    DaemonRequest::parse(b"git-upload-pack /project.git\0host=example.com:9418\0\0version=2\0")
+      .unwrap();
+  assert_eq!(DaemonService::UploadPack, request.service);
+  assert_eq!("/project.git", request.path);
+  assert_eq!(Some("example.com:9418".into()), request.host);
+  assert_eq!(vec![BString::from("version=2")], request.extra);
+  assert_eq!(request, DaemonRequest::parse(request.to_bytes()).unwrap());
+  for invalid in [
+    &b"git-upload-pack"[..],
+    b"git-frobnicate /a",
+    b"git-upload-pack \0",
+  ] {
+    assert!(matches!(
+      DaemonRequest::parse(invalid),
+      Err(DaemonError::Protocol(_))
+    ));
+  }
+
+  let tmp_dir = tempdir::TempDir::new("daemon_test").unwrap();
+  let base = tmp_dir.path().join("base");
+  let repo = Repository::init_bare(base.join("project.git")).unwrap();
+  Repository::init_bare(base.join("private.git")).unwrap();
+  let commit = crate::Commit::new(
+    repo.odb().write_tree(&crate::Tree::new(vec![])).unwrap(),
+    vec![],
+    crate::Signature::new("A U Thor", "author@example.com", crate::Time::new(0, 0)),
+    crate::Signature::new("A U Thor", "author@example.com", crate::Time::new(0, 0)),
+    "commit\n",
+  );
+  let oid = repo.odb().write_commit(&commit).unwrap();
+  repo.refs().write("refs/heads/master", &oid).unwrap();
+  fs::write(repo.git_dir().join("git-daemon-export-ok"), "").unwrap();
+  let daemon = Daemon::new().base_path(&base);
+  let connect = |request: &str| {
+    let mut input = Vec::new();
+    Packet::Data(request.as_bytes().as_bstr())
+      .encode(&mut input)
+      .unwrap();
+    let mut output = Vec::new();
+    let result = daemon.serve_connection(&input[..], &mut output);
+    (result, output)
+  };
+
+  // Both `/project.git` and `/project` find the repository
+  for path in ["/project.git", "/project"] {
+    let (result, output) = connect(&format!("git-upload-pack {}\0host=localhost\0", path));
+    result.unwrap();
+    assert!(output.ends_with(b"0000"));
+    assert!(output.find(format!("{} refs/heads/master", oid)).is_some());
+  }
+  for path in [
+    "/private.git",
+    "/missing.git",
+    "/../base/project.git",
+    "project.git",
+  ] {
+    let (result, output) = connect(&format!("git-upload-pack {}\0", path));
+    assert!(matches!(result, Err(DaemonError::Denied(_))), "{}", path);
+    assert_eq!(
+      format!("ERR access denied or repository not exported: {}", path),
+      output[4..].as_bstr()
+    );
+  }
+  let (result, _) = connect("git-receive-pack /project.git\0");
+  assert!(matches!(
+    result,
+    Err(DaemonError::Disabled(DaemonService::ReceivePack))
+  ));
+  assert!(Daemon::new()
+    .base_path(&base)
+    .allow(tmp_dir.path().join("elsewhere"))
+    .open(&DaemonRequest::parse("git-upload-pack /project.git").unwrap())
+    .is_err());
+
+  if !crate::transport::http::have_git() {
+    return;
+  }
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("git://{}/project.git", listener.local_addr().unwrap());
+  let daemon = daemon.receive_pack(true);
+  std::thread::spawn(move || daemon.run(&listener));
+  let git = |args: &[&str]| {
+    let output = Command::new("git")
+      .args(args)
+      .current_dir(tmp_dir.path())
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .env("GIT_AUTHOR_NAME", "A U Thor")
+      .env("GIT_AUTHOR_EMAIL", "author@example.com")
+      .env("GIT_COMMITTER_NAME", "A U Thor")
+      .env("GIT_COMMITTER_EMAIL", "author@example.com")
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{}", output.stderr.as_bstr());
+  };
+  git(&["clone", "--quiet", &url, "clone"]);
+  git(&[
+    "-C",
+    "clone",
+    "commit",
+    "--quiet",
+    "--allow-empty",
+    "-m",
+    "second",
+  ]);
+  git(&["-C", "clone", "push", "--quiet", "origin", "master"]);
+  let pushed = repo.refs().resolve("refs/heads/master").unwrap().unwrap();
+  assert_eq!(
+    &[oid][..],
+    repo.odb().read_commit(&pushed).unwrap().parents()
+  );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn serve_connections_async() {
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  let tmp_dir = tempdir::TempDir::new("daemon_test").unwrap();
+  let base = tmp_dir.path().join("base");
+  let repo = Repository::init_bare(base.join("project.git")).unwrap();
+  Repository::init_bare(base.join("private.git")).unwrap();
+  let commit = crate::Commit::new(
+    repo.odb().write_tree(&crate::Tree::new(vec![])).unwrap(),
+    vec![],
+    crate::Signature::new("A U Thor", "author@example.com", crate::Time::new(0, 0)),
+    crate::Signature::new("A U Thor", "author@example.com", crate::Time::new(0, 0)),
+    "commit\n",
+  );
+  let oid = repo.odb().write_commit(&commit).unwrap();
+  repo.refs().write("refs/heads/master", &oid).unwrap();
+  fs::write(repo.git_dir().join("git-daemon-export-ok"), "").unwrap();
+  let daemon = Daemon::new().base_path(&base);
+
+  let runtime = tokio::runtime::Builder::new_current_thread()
+    .enable_io()
+    .build()
+    .unwrap();
+  runtime.block_on(async {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { daemon.run_async(&listener).await });
+    let connect = |request: &'static str| async move {
+      let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+      let mut bytes = Vec::new();
+      Packet::Data(request.as_bytes().as_bstr())
+        .encode(&mut bytes)
+        .unwrap();
+      stream.write_all(&bytes).await.unwrap();
+      // Only the advertisement is wanted
+      stream.shutdown().await.unwrap();
+      let mut output = Vec::new();
+      stream.read_to_end(&mut output).await.unwrap();
+      output
+    };
+
+    let output = connect("git-upload-pack /project.git\0host=localhost\0").await;
+    assert!(output.ends_with(b"0000"));
+    assert!(output.find(format!("{} refs/heads/master", oid)).is_some());
+    let output = connect("git-upload-pack /private.git\0").await;
+    assert_eq!(
+      "ERR access denied or repository not exported: /private.git",
+      output[4..].as_bstr()
+    );
+  });
+}
@@ -0,0 +1,713 @@
+//! Delta compression for packs. A delta turns a base object into another
+//! one by copying ranges of the base and inserting new bytes, so an object
+//! that's mostly the same as another one takes a fraction of the space
+//! stored as a delta of it.
+//!
+//! Deltas read from packs are checked as they're applied, since a pack
+//! from someone else can be made to copy from past the end of its base,
+//! make more or less than it says it does, or have chains of deltas that
+//! loop or go on and on.
+//!
+//! Which objects to try as bases is worked out the way git does in
+//! `pack-objects`: the objects are sorted by kind, then by a hash of the
+//! end of their paths so the versions of a file and files with the same
+//! name and extension are next to each other, and then biggest first so
+//! deltas mostly remove data, which makes them smaller. Each object is
+//! tried against the ones in a window of those just before it, and the
+//! smallest delta wins as long as it doesn't make a chain of deltas
+//! deeper than the limit, since every link has to be applied to read it.
+
+use crate::{
+  Config, ConfigError, MemoryBudget, ObjectKind, Odb, OdbError, PackError, Reservation, Trace2, OID,
+};
+use std::{
+  collections::{HashSet, VecDeque},
+  convert::TryFrom,
+};
+
+/// How many bytes a match has to be to be found, and how far apart the
+/// offsets of the base are that are looked up
+const BLOCK: usize = 16;
+/// How many places of the base with the same hash are compared before
+/// giving up on finding a longer match, which is what git limits each
+/// bucket of its index to
+const MAX_CANDIDATES: usize = 64;
+/// Objects smaller than this are never worth a delta
+const MIN_SIZE: usize = 50;
+/// Objects bigger than this are stored whole, git's default for
+/// `core.bigFileThreshold`
+const BIG_FILE_THRESHOLD: usize = 512 << 20;
+/// The multiplier of the rolling hash
+const PRIME: u32 = 0x0100_0193;
+
+/// How hard writing a pack tries to find deltas
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaOptions {
+  /// How many of the objects before each one are tried as its base, like
+  /// `pack.window`. More finds smaller deltas but takes longer.
+  pub window: usize,
+  /// How long a chain of deltas can get, like `pack.depth`. Longer chains
+  /// make packs smaller but objects slower to read.
+  pub depth: usize,
+}
+
+impl Default for DeltaOptions {
+  fn default() -> Self {
+    Self {
+      window: 10,
+      depth: 50,
+    }
+  }
+}
+
+impl DeltaOptions {
+  /// Read `pack.window` and `pack.depth`. Like git the depth is capped at
+  /// 4095, the most a pack index can tell apart.
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let mut options = Self::default();
+    let get = |key: &str| -> Result<Option<usize>, ConfigError> {
+      match config.get_int(key)? {
+        Some(value) => usize::try_from(value)
+          .map(Some)
+          .map_err(|_| ConfigError::InvalidValue {
+            key: key.into(),
+            value: value.to_string().into(),
+            expected: "a number that isn't negative",
+          }),
+        None => Ok(None),
+      }
+    };
+    if let Some(window) = get("pack.window")? {
+      options.window = window;
+    }
+    if let Some(depth) = get("pack.depth")? {
+      options.depth = depth.min(4095);
+    }
+    Ok(options)
+  }
+}
+
+/// The hash git sorts objects by to find bases for deltas, which is mostly
+/// decided by the last characters of the path so files with the same name
+/// or extension end up together
+pub(crate) fn name_hash(path: &[u8]) -> u32 {
+  path
+    .iter()
+    .filter(|c| !c.is_ascii_whitespace())
+    .fold(0u32, |hash, &c| {
+      (hash >> 2).wrapping_add(u32::from(c) << 24)
+    })
+}
+
+/// The hash of the [`BLOCK`] bytes at the start of `bytes`
+fn block_hash(bytes: &[u8]) -> u32 {
+  bytes[..BLOCK].iter().fold(0u32, |hash, &c| {
+    hash.wrapping_mul(PRIME).wrapping_add(u32::from(c))
+  })
+}
+
+/// A base along with an index of where its blocks are, made once to find
+/// deltas of many objects against it
+pub(crate) struct DeltaIndex {
+  base: Vec<u8>,
+  /// The last offset with each hash, plus one so that 0 is none
+  buckets: Vec<u32>,
+  /// The offset before each one with the same hash, also plus one
+  chain: Vec<u32>,
+  shift: u32,
+  /// What the first byte of a block is multiplied by in its hash, to take
+  /// it out when rolling the hash along
+  first_factor: u32,
+}
+
+impl DeltaIndex {
+  pub(crate) fn new(base: Vec<u8>) -> Self {
+    let blocks = base.len() / BLOCK;
+    let bits = (blocks.max(1).next_power_of_two().trailing_zeros()).clamp(4, 24);
+    let mut index = Self {
+      base,
+      buckets: vec![0; 1 << bits],
+      chain: vec![0; blocks],
+      shift: 32 - bits,
+      first_factor: (1..BLOCK).fold(1u32, |factor, _| factor.wrapping_mul(PRIME)),
+    };
+    // Later blocks are put in first so the earliest ones are found first,
+    // which keeps the offsets of copies small
+    for block in (0..blocks).rev() {
+      let bucket = index.bucket(block_hash(&index.base[block * BLOCK..]));
+      index.chain[block] = index.buckets[bucket];
+      index.buckets[bucket] = block as u32 + 1;
+    }
+    index
+  }
+
+  fn len(&self) -> usize {
+    self.base.len()
+  }
+
+  fn bucket(&self, hash: u32) -> usize {
+    (hash.wrapping_mul(0x9e37_79b1) >> self.shift) as usize
+  }
+
+  /// The longest match in the base for the start of `target`, as its
+  /// offset and length
+  fn find(&self, hash: u32, target: &[u8]) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize)> = None;
+    let mut next = self.buckets[self.bucket(hash)];
+    for _ in 0..MAX_CANDIDATES {
+      if next == 0 {
+        break;
+      }
+      let offset = (next - 1) as usize * BLOCK;
+      next = self.chain[next as usize - 1];
+      let len = self.base[offset..]
+        .iter()
+        .zip(target)
+        .take_while(|(a, b)| a == b)
+        .count();
+      if len >= BLOCK && best.is_none_or(|(_, best)| len > best) {
+        best = Some((offset, len));
+      }
+    }
+    best
+  }
+
+  /// A delta that turns the base into `target`, or `None` if it would be
+  /// more than `max_size` bytes. Copies are found wherever a block of the
+  /// base shows up in `target` and grown as far as both match in either
+  /// direction, everything else is inserted.
+  pub(crate) fn delta(&self, target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    let mut delta = Vec::new();
+    write_size(&mut delta, self.base.len());
+    write_size(&mut delta, target.len());
+    let mut inserted = 0;
+    let mut pos = 0;
+    let mut hash = match target.len() >= BLOCK && !self.chain.is_empty() {
+      true => block_hash(target),
+      false => 0,
+    };
+    while pos + BLOCK <= target.len() && !self.chain.is_empty() {
+      match self.find(hash, &target[pos..]) {
+        Some((mut offset, mut len)) => {
+          // The bytes just before often match too, and are cheaper copied
+          // than inserted
+          while offset > 0 && pos > inserted && self.base[offset - 1] == target[pos - 1] {
+            offset -= 1;
+            pos -= 1;
+            len += 1;
+          }
+          write_insert(&mut delta, &target[inserted..pos]);
+          write_copy(&mut delta, offset, len);
+          pos += len;
+          inserted = pos;
+          if delta.len() > max_size {
+            return None;
+          }
+          if pos + BLOCK <= target.len() {
+            hash = block_hash(&target[pos..]);
+          }
+        }
+        None => {
+          if pos + BLOCK < target.len() {
+            hash = hash
+              .wrapping_sub(u32::from(target[pos]).wrapping_mul(self.first_factor))
+              .wrapping_mul(PRIME)
+              .wrapping_add(u32::from(target[pos + BLOCK]));
+          }
+          pos += 1;
+        }
+      }
+    }
+    write_insert(&mut delta, &target[inserted..]);
+    Some(delta).filter(|delta| delta.len() <= max_size)
+  }
+}
+
+/// The sizes at the start of a delta, 7 bits a byte with the low bits first
+fn write_size(delta: &mut Vec<u8>, mut size: usize) {
+  while size >= 0x80 {
+    delta.push(size as u8 | 0x80);
+    size >>= 7;
+  }
+  delta.push(size as u8);
+}
+
+fn write_copy(delta: &mut Vec<u8>, mut offset: usize, len: usize) {
+  // A copy can't be longer than 0xffffff, which is split into ones that
+  // are well within it
+  for len in (0..len)
+    .step_by(0x10000)
+    .map(|start| (len - start).min(0x10000))
+  {
+    let mut op = 0x80;
+    let mut args = Vec::new();
+    for (i, byte) in (offset as u32).to_le_bytes().iter().enumerate() {
+      if *byte != 0 {
+        op |= 1 << i;
+        args.push(*byte);
+      }
+    }
+    // A size of 0x10000 is written as no size at all
+    for (i, byte) in (len as u32 & 0xffff).to_le_bytes()[..2].iter().enumerate() {
+      if *byte != 0 {
+        op |= 0x10 << i;
+        args.push(*byte);
+      }
+    }
+    delta.push(op);
+    delta.extend(args);
+    offset += len;
+  }
+}
+
+fn write_insert(delta: &mut Vec<u8>, data: &[u8]) {
+  for insert in data.chunks(0x7f) {
+    delta.push(insert.len() as u8);
+    delta.extend_from_slice(insert);
+  }
+}
+
+/// Make a delta that turns `base` into `target`
+pub(crate) fn make_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+  DeltaIndex::new(base.to_vec())
+    .delta(target, usize::MAX)
+    .unwrap()
+}
+
+/// Read one of the sizes at the start of a delta
+fn delta_varint(delta: &[u8], pos: &mut usize) -> Result<u64, PackError> {
+  let mut value = 0u64;
+  let mut shift = 0;
+  loop {
+    let byte = *delta
+      .get(*pos)
+      .ok_or(PackError::Delta("delta is truncated"))?;
+    *pos += 1;
+    if shift > 63 {
+      return Err(PackError::Delta("delta size is too large"));
+    }
+    value |= u64::from(byte & 0x7f) << shift;
+    shift += 7;
+    if byte & 0x80 == 0 {
+      return Ok(value);
+    }
+  }
+}
+
+/// The size of the object a delta makes, from the start of the delta
+pub(crate) fn delta_target_size(delta: &[u8]) -> Result<usize, PackError> {
+  let mut pos = 0;
+  delta_varint(delta, &mut pos)?;
+  usize::try_from(delta_varint(delta, &mut pos)?)
+    .map_err(|_| PackError::Delta("delta target is too large"))
+}
+
+/// Rebuild an object from its base and a delta against it
+pub(crate) fn apply_delta(
+  base: &[u8],
+  delta: &[u8],
+  budget: &MemoryBudget,
+) -> Result<(Vec<u8>, Reservation), PackError> {
+  let invalid = PackError::Delta;
+  let mut pos = 0;
+  let base_size = delta_varint(delta, &mut pos)?;
+  let target_size = delta_varint(delta, &mut pos)?;
+  if base_size != base.len() as u64 {
+    return Err(invalid("base size does not match the delta"));
+  }
+  let target_size =
+    usize::try_from(target_size).map_err(|_| invalid("delta target is too large"))?;
+  let reservation = budget.try_reserve(target_size)?;
+  // The size comes from the delta, it's only trusted as far as the budget
+  // allows and the buffer grows past what the delta could make on its own
+  let mut target = Vec::with_capacity(target_size.min(base.len() + delta.len()));
+  while pos < delta.len() {
+    let op = delta[pos];
+    pos += 1;
+    if op & 0x80 != 0 {
+      // Copy from the base. The bits of `op` say which bytes of the offset
+      // and size follow.
+      let mut read = |bits: u8, count: usize| -> Result<u64, PackError> {
+        let mut value = 0;
+        for i in 0..count {
+          if bits & (1 << i) != 0 {
+            let byte = *delta.get(pos).ok_or(invalid("delta is truncated"))?;
+            pos += 1;
+            value |= u64::from(byte) << (8 * i);
+          }
+        }
+        Ok(value)
+      };
+      let offset = read(op, 4)? as usize;
+      let size = match read(op >> 4, 3)? as usize {
+        0 => 0x10000,
+        size => size,
+      };
+      let copy = offset
+        .checked_add(size)
+        .and_then(|end| base.get(offset..end))
+        .ok_or(invalid("delta copies past the end of the base"))?;
+      target.extend_from_slice(copy);
+    } else if op != 0 {
+      let insert = delta
+        .get(pos..pos + op as usize)
+        .ok_or(invalid("delta is truncated"))?;
+      target.extend_from_slice(insert);
+      pos += op as usize;
+    } else {
+      return Err(invalid("delta has a reserved instruction"));
+    }
+    if target.len() > target_size {
+      return Err(invalid("delta makes more data than it says"));
+    }
+  }
+  if target.len() != target_size {
+    return Err(invalid("delta makes less data than it says"));
+  }
+  Ok((target, reservation))
+}
+
+/// How long a chain of deltas can be by default. Chains are rarely more
+/// than 50 long and git never makes them longer than 4095, so anything this
+/// long was made to take up time reading it.
+pub(crate) const MAX_DEPTH: usize = 10_000;
+
+/// Follows a chain of deltas in a pack from an entry back to the whole
+/// object it starts from. Packs come from anywhere, so a chain that comes
+/// back around to an entry it went through, which ref deltas can do, or
+/// goes on for longer than allowed is an error instead of being followed.
+pub(crate) struct DeltaChain {
+  max_depth: usize,
+  seen: HashSet<u64>,
+}
+
+impl DeltaChain {
+  /// Start a chain at the entry at `offset`
+  pub(crate) fn new(offset: u64, max_depth: usize) -> Self {
+    Self {
+      max_depth,
+      seen: HashSet::from([offset]),
+    }
+  }
+
+  /// Go on to the base at `offset`
+  pub(crate) fn follow(&mut self, offset: u64) -> Result<(), PackError> {
+    if !self.seen.insert(offset) {
+      return Err(PackError::Malformed("delta chain loops back on itself"));
+    }
+    if self.seen.len() - 1 > self.max_depth {
+      return Err(PackError::DeltaTooDeep(self.max_depth));
+    }
+    Ok(())
+  }
+}
+
+/// An object going in a pack, with the path it was found at if it's a tree
+/// or blob
+pub(crate) struct DeltaCandidate<'a> {
+  pub(crate) oid: OID,
+  pub(crate) path: Option<&'a [u8]>,
+}
+
+/// The index of the base of an object and the delta against it
+type Delta = (usize, Vec<u8>);
+
+/// An object in the window, with what's needed to try it as a base
+struct WindowEntry {
+  idx: usize,
+  kind: ObjectKind,
+  index: DeltaIndex,
+  depth: usize,
+}
+
+/// Find the best base in the pack for each of `objects`, returning for each
+/// one the index of its base and the delta against it, if it's worth
+/// storing as one. Bases always come before their deltas in the order
+/// objects are tried, so there are never any cycles.
+pub(crate) fn find_deltas(
+  odb: &Odb,
+  objects: &[DeltaCandidate<'_>],
+  options: &DeltaOptions,
+) -> Result<Vec<Option<Delta>>, OdbError> {
+  let mut deltas: Vec<Option<Delta>> = Vec::new();
+  deltas.resize_with(objects.len(), || None);
+  if options.window == 0 || options.depth == 0 {
+    return Ok(deltas);
+  }
+  let _region = Trace2::region("pack-objects", "find_deltas");
+  let mut sorted = Vec::with_capacity(objects.len());
+  for (idx, object) in objects.iter().enumerate() {
+    let kind = odb.object_kind(&object.oid)?;
+    let size = odb.object_size(&object.oid)?;
+    let hash = object.path.map_or(0, name_hash);
+    sorted.push((kind.as_str(), hash, std::cmp::Reverse(size), idx));
+  }
+  sorted.sort();
+
+  let mut window: VecDeque<WindowEntry> = VecDeque::with_capacity(options.window + 1);
+  let mut found = 0;
+  for (_, _, std::cmp::Reverse(size), idx) in sorted {
+    if !(MIN_SIZE..=BIG_FILE_THRESHOLD).contains(&size) {
+      continue;
+    }
+    let object = odb.read(&objects[idx].oid)?;
+    let mut best: Option<(usize, Vec<u8>, usize)> = None;
+    for base in window.iter().rev().filter(|base| base.kind == object.kind) {
+      if base.depth >= options.depth || size < base.index.len() / 32 {
+        continue;
+      }
+      // Deltas have to be a lot smaller than the object to be worth it,
+      // and more so the deeper they'd be than the best one so far
+      let (limit, best_depth) = match &best {
+        Some((_, delta, depth)) => (delta.len() - 1, *depth),
+        None => ((size / 2).saturating_sub(20), 1),
+      };
+      let limit = limit * (options.depth - base.depth) / (options.depth - best_depth + 1);
+      if limit == 0 || size.saturating_sub(base.index.len()) >= limit {
+        continue;
+      }
+      if let Some(delta) = base.index.delta(&object.data, limit) {
+        best = Some((base.idx, delta, base.depth + 1));
+      }
+    }
+    let depth = match best {
+      Some((base, delta, depth)) => {
+        deltas[idx] = Some((base, delta));
+        found += 1;
+        depth
+      }
+      None => 0,
+    };
+    window.push_back(WindowEntry {
+      idx,
+      kind: object.kind,
+      index: DeltaIndex::new(object.data),
+      depth,
+    });
+    if window.len() > options.window {
+      window.pop_front();
+    }
+  }
+  Trace2::data("pack-objects", "deltas", found);
+  Ok(deltas)
+}
+
+#[test]
+fn deltas() {
+  let budget = crate::MemoryBudget::unlimited();
+  let text: Vec<u8> = (0..2000)
+    .flat_map(|i| format!("line {} of a file\n", i * 7919 % 2000).into_bytes())
+    .collect();
+  // Lines moved around, changed, and added all over
+  let mut edited = text.clone();
+  edited.splice(1000..1000, b"inserted line\n".iter().copied());
+  edited.drain(20_000..20_100);
+  let moved: Vec<u8> = edited.drain(5_000..6_000).collect();
+  edited.extend(moved);
+  for (base, target) in [
+    (&b""[..], &b""[..]),
+    (b"short", b"short but longer"),
+    (&text[..], &edited[..]),
+    (&edited[..], &text[..]),
+    (&text[..], &text[..1000]),
+    (&text[..1000], &text[..]),
+  ] {
+    let delta = make_delta(base, target);
+    assert_eq!(target, &apply_delta(base, &delta, &budget).unwrap().0[..]);
+  }
+  assert!(make_delta(&text, &edited).len() < 300);
+  assert!(DeltaIndex::new(text.clone()).delta(&edited, 100).is_none());
+
+  assert_eq!(name_hash(b"src/main.rs"), name_hash(b"src/ main.rs"));
+  // The end of the path counts the most
+  let (a, b) = (name_hash(b"a/lib.rs"), name_hash(b"b/lib.rs"));
+  assert_eq!(a >> 24, b >> 24);
+
+  let config = Config::from_bytes("[pack]\n\twindow = 3\n\tdepth = 10000\n").unwrap();
+  assert_eq!(
+    DeltaOptions {
+      window: 3,
+      depth: 4095
+    },
+    DeltaOptions::from_config(&config).unwrap()
+  );
+}
+
+#[test]
+fn packs_like_git() {
+  use crate::{
+    diff::write_tree, pack::parse_pack, transport::write_pack_for, Commit,
+    FileMode::NonExecutableFile, MemoryBudget, Repository, Signature, Time,
+  };
+  use std::{fs, io::Write};
+  let tmp_dir = tempdir::TempDir::new("delta_test").unwrap();
+  let mut repo = Repository::init(tmp_dir.path()).unwrap();
+  let odb = repo.odb();
+  // Files that are edited a little in each commit, with lines that don't
+  // compress away on their own
+  let line = |i: usize, version: usize| {
+    format!(
+      "fn f{}() -> u64 {{ {} }}\n",
+      i,
+      (i * 7919 + version * i.is_multiple_of(7) as usize) % 104_729
+    )
+  };
+  let mut parents = Vec::new();
+  for version in 0..30 {
+    let lib: String = (0..300 + version * 5).map(|i| line(i, version)).collect();
+    let main: String = (0..100).map(|i| line(i * 3, version / 3)).collect();
+    let readme = format!("# Readme\n\n{}", "Some words. ".repeat(10 + version));
+    let tree = write_tree(
+      odb,
+      &[
+        ("README.md", NonExecutableFile, &readme),
+        ("src/lib.rs", NonExecutableFile, &lib),
+        ("src/main.rs", NonExecutableFile, &main),
+      ],
+    );
+    let signature = Signature::new(
+      "A U Thor",
+      "author@example.com",
+      Time::new(1_600_000_000 + version as i64, 0),
+    );
+    let commit = Commit::new(tree, parents, signature.clone(), signature, "commit\n");
+    parents = vec![odb.write_commit(&commit).unwrap()];
+  }
+  let head = parents[0];
+
+  let (pack, _) = write_pack_for(&repo, &[head], &[], false, Vec::new()).unwrap();
+  let objects = parse_pack(&pack, &MemoryBudget::unlimited()).unwrap();
+  for object in &objects {
+    assert_eq!(repo.odb().read(&object.id()).unwrap(), *object);
+  }
+  // Without a window every object is whole
+  let config = repo.git_dir().join("config");
+  let mut contents = fs::read_to_string(&config).unwrap();
+  contents.push_str("[pack]\n\twindow = 0\n");
+  fs::write(&config, contents).unwrap();
+  repo.reload_config().unwrap();
+  let (whole, _) = write_pack_for(&repo, &[head], &[], false, Vec::new()).unwrap();
+  assert!(pack.len() * 3 < whole.len());
+
+  if crate::transport::http::have_git() {
+    let mut child = std::process::Command::new("git")
+      .args(["pack-objects", "--revs", "--stdout", "-q"])
+      .current_dir(tmp_dir.path())
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .stdin(std::process::Stdio::piped())
+      .stdout(std::process::Stdio::piped())
+      .spawn()
+      .unwrap();
+    writeln!(child.stdin.take().unwrap(), "{}", head).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    // The same objects in a pack about as small as git's
+    assert_eq!(output.stdout[8..12], pack[8..12]);
+    assert!(pack.len() < output.stdout.len() * 11 / 10);
+    // And git can read it
+    let path = tmp_dir.path().join("ours.pack");
+    fs::write(&path, &pack).unwrap();
+    let status = std::process::Command::new("git")
+      .arg("index-pack")
+      .arg(&path)
+      .current_dir(tmp_dir.path())
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .stdout(std::process::Stdio::null())
+      .status()
+      .unwrap();
+    assert!(status.success());
+  }
+}
+
+#[test]
+fn delta_chains() {
+  use crate::{
+    pack::{parse_thin_pack, write_index, PackObject, PackWriter},
+    OdbError, PackLimits, RawObject,
+  };
+  use std::fs;
+  let budget = MemoryBudget::unlimited();
+  let tmp_dir = tempdir::TempDir::new("delta_test").unwrap();
+  // Each version is a delta against the one before it
+  let versions: Vec<RawObject> = (0..6)
+    .map(|i| RawObject::new(ObjectKind::Blob, "a line of text\n".repeat(10 + i)))
+    .collect();
+  let mut writer = PackWriter::new(Vec::new(), 6).unwrap();
+  let mut base = 0;
+  for (i, version) in versions.iter().enumerate() {
+    let offset = writer.offset();
+    let object = match i {
+      0 => PackObject::Whole(version.clone()),
+      _ => {
+        let delta = make_delta(&versions[i - 1].data, &version.data);
+        PackObject::OfsDelta { base, delta }
+      }
+    };
+    writer.add(&object).unwrap();
+    base = offset;
+  }
+  let (pack, _) = writer.finish().unwrap();
+  assert!(matches!(
+    parse_thin_pack(&pack, &budget, 4, |_| None),
+    Err(PackError::DeltaTooDeep(4))
+  ));
+  assert_eq!(
+    versions,
+    parse_thin_pack(&pack, &budget, 5, |_| None).unwrap()
+  );
+
+  let odb = Odb::new(tmp_dir.path());
+  odb.write_pack(&pack).unwrap();
+  let shallow = Odb::new(tmp_dir.path()).with_pack_limits(PackLimits {
+    max_delta_depth: 4,
+    ..PackLimits::default()
+  });
+  assert_eq!(versions[4], shallow.read(&versions[4].id()).unwrap());
+  for result in [
+    shallow.read(&versions[5].id()).map(drop),
+    shallow.object_size(&versions[5].id()).map(drop),
+  ] {
+    assert!(matches!(
+      result,
+      Err(OdbError::Pack(PackError::DeltaTooDeep(4)))
+    ));
+  }
+
+  // Two ref deltas that are each other's base never get to a whole object
+  let (first, second) = (
+    OID::from_bytes(&[1; 20]).unwrap(),
+    OID::from_bytes(&[2; 20]).unwrap(),
+  );
+  let mut writer = PackWriter::new(Vec::new(), 2).unwrap();
+  let mut offsets = Vec::new();
+  for base in [second, first] {
+    offsets.push(writer.offset());
+    let delta = make_delta(b"base", b"target");
+    writer.add(&PackObject::RefDelta { base, delta }).unwrap();
+  }
+  let (pack, checksum) = writer.finish().unwrap();
+  let dir = tmp_dir.path().join("looped/pack");
+  fs::create_dir_all(&dir).unwrap();
+  fs::write(dir.join(format!("pack-{}.pack", checksum)), &pack).unwrap();
+  fs::write(
+    dir.join(format!("pack-{}.idx", checksum)),
+    write_index(&[first, second], &offsets, &[0, 0], &checksum),
+  )
+  .unwrap();
+  let odb = Odb::new(tmp_dir.path().join("looped"));
+  for result in [
+    odb.read(&first).map(drop),
+    odb.object_kind(&second).map(drop),
+  ] {
+    assert!(matches!(
+      result,
+      Err(OdbError::Pack(PackError::Malformed(reason))) if reason.contains("loops")
+    ));
+  }
+  assert!(matches!(
+    parse_thin_pack(&pack, &budget, MAX_DEPTH, |_| None),
+    Err(PackError::MissingBase(_))
+  ));
+}
@@ -0,0 +1,402 @@
+//! Naming a commit after the closest tag it's reachable from, like
+//! `git describe`, which gives names such as `v1.2.3-14-gabc1234` for the
+//! 14th commit after `v1.2.3` that are handy as version strings of builds.
+//!
+//! The history of the commit is walked newest first and the first tagged
+//! commits reached are the candidates. The one with the fewest commits
+//! between it and the commit being described wins, and ties go to the one
+//! reached first.
+
+use crate::{
+  wildmatch::wildmatch, ObjectKind, OdbError, RefError, Repository, RevWalkError, StatusError, OID,
+};
+use bstr::{BString, ByteSlice};
+use std::{collections::HashMap, fmt};
+use thiserror::Error;
+
+/// Options controlling how [`Repository::describe`] names a commit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescribeOptions {
+  /// Use lightweight tags as well as annotated ones, like `--tags`. An
+  /// annotated tag is still preferred when both point at the same commit.
+  pub tags: bool,
+  /// Only use tags whose name matches one of these globs, like `--match`.
+  /// Every tag is used when there are none.
+  pub patterns: Vec<String>,
+  /// How many tagged commits are considered before picking the closest,
+  /// like `--candidates`. With 0 only a tag of the commit itself is used,
+  /// like `--exact-match`.
+  pub candidates: usize,
+  /// How many hex digits of the commit name are shown at least, like
+  /// `--abbrev`. `None` picks a length from the number of objects the same
+  /// as [`Odb::abbreviate`][crate::Odb::abbreviate], and 0 leaves out
+  /// everything after the tag name.
+  pub abbrev: Option<usize>,
+  /// Show the number of commits and the commit name even when the commit
+  /// is tagged itself, like `--long`
+  pub long: bool,
+  /// Fall back to the abbreviated commit name when no tag can describe the
+  /// commit, like `--always`
+  pub always: bool,
+  /// Add this suffix when tracked files in the working tree or the
+  /// [`Index`][crate::Index] differ from `HEAD`, like `--dirty=-dirty`.
+  /// This can only be used when describing `HEAD`.
+  pub dirty: Option<String>,
+}
+
+impl Default for DescribeOptions {
+  fn default() -> Self {
+    Self {
+      tags: false,
+      patterns: Vec::new(),
+      candidates: 10,
+      abbrev: None,
+      long: false,
+      always: false,
+      dirty: None,
+    }
+  }
+}
+
+/// The name [`Repository::describe`] gave a commit. It's shown with
+/// [`fmt::Display`] the way `git describe` prints it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Description {
+  /// The name of the tag the commit was described by, without
+  /// `refs/tags/`, or `None` when it fell back to the commit name
+  pub tag: Option<BString>,
+  /// How many commits are reachable from the commit but not from the tag
+  pub depth: usize,
+  /// The commit that was described
+  pub commit: OID,
+  abbrev: String,
+  long: bool,
+  dirty: Option<String>,
+}
+
+impl Description {
+  /// Whether the working tree differed from `HEAD`
+  pub fn is_dirty(&self) -> bool {
+    self.dirty.is_some()
+  }
+}
+
+impl fmt::Display for Description {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.tag {
+      None => write!(f, "{}", self.abbrev)?,
+      Some(tag) if self.abbrev.is_empty() || (self.depth == 0 && !self.long) => {
+        write!(f, "{}", tag)?
+      }
+      Some(tag) => write!(f, "{}-{}-g{}", tag, self.depth, self.abbrev)?,
+    }
+    write!(f, "{}", self.dirty.as_deref().unwrap_or(""))
+  }
+}
+
+/// A tag that could name a commit
+#[derive(Debug)]
+struct Name {
+  tag: BString,
+  annotated: bool,
+  /// When an annotated tag was made, to pick the newest of several
+  time: i64,
+}
+
+impl Repository {
+  /// Name `commit` after the closest tag it's reachable from, like
+  /// `git describe {commit}`. Tags are followed to the commit they point
+  /// at, and tags of anything other than a commit are ignored.
+  pub fn describe(
+    &self,
+    commit: &OID,
+    options: &DescribeOptions,
+  ) -> Result<Description, DescribeError> {
+    let dirty = match &options.dirty {
+      None => None,
+      Some(suffix) => {
+        if self.refs().resolve("HEAD")? != Some(*commit) {
+          return Err(DescribeError::DirtyNotHead(*commit));
+        }
+        let dirty = !self.tracked_changes()?.is_empty();
+        dirty.then(|| suffix.clone())
+      }
+    };
+    let names = self.describe_names(options)?;
+    let abbrev = |oid: &OID| match options.abbrev {
+      Some(0) => Ok(String::new()),
+      len => self.odb().abbreviate(oid, len),
+    };
+    let described = |tag: &Name, depth| -> Result<_, DescribeError> {
+      Ok(Description {
+        tag: Some(tag.tag.clone()),
+        depth,
+        commit: *commit,
+        abbrev: abbrev(commit)?,
+        long: options.long,
+        dirty: dirty.clone(),
+      })
+    };
+    let fallback = || -> Result<_, DescribeError> {
+      match options.always {
+        true => Ok(Description {
+          tag: None,
+          depth: 0,
+          commit: *commit,
+          abbrev: self
+            .odb()
+            .abbreviate(commit, options.abbrev.filter(|&len| len > 0))?,
+          long: options.long,
+          dirty: dirty.clone(),
+        }),
+        false if names.is_empty() => Err(DescribeError::NoNames),
+        false => Err(DescribeError::NoTags(*commit)),
+      }
+    };
+
+    if let Some(name) = names.get(commit) {
+      return described(name, 0);
+    }
+    if options.candidates == 0 {
+      return fallback();
+    }
+    let mut candidates = Vec::new();
+    let mut walk = self.rev_walk();
+    walk.push(commit)?;
+    for oid in walk {
+      let oid = oid?;
+      if names.contains_key(&oid) {
+        candidates.push(oid);
+        if candidates.len() == options.candidates {
+          break;
+        }
+      }
+    }
+    let mut best: Option<(usize, OID)> = None;
+    for candidate in candidates {
+      let mut walk = self.rev_walk();
+      walk.push(commit)?.hide(&candidate)?;
+      let mut depth = 0;
+      for oid in walk {
+        oid?;
+        depth += 1;
+      }
+      if best.is_none_or(|(best, _)| depth < best) {
+        best = Some((depth, candidate));
+      }
+    }
+    match best {
+      Some((depth, candidate)) => described(&names[&candidate], depth),
+      None => fallback(),
+    }
+  }
+
+  /// The tags [`Repository::describe`] can use to name commits, by the
+  /// commit they point at
+  fn describe_names(&self, options: &DescribeOptions) -> Result<HashMap<OID, Name>, DescribeError> {
+    let mut names: HashMap<OID, Name> = HashMap::new();
+    for reference in self.refs().list("refs/tags/")? {
+      let tag = reference.name()[b"refs/tags/".len()..].as_bstr();
+      if !options.patterns.is_empty()
+        && !options
+          .patterns
+          .iter()
+          .any(|pattern| wildmatch(pattern.as_bytes(), tag, 0))
+      {
+        continue;
+      }
+      let oid = match self.refs().resolve(reference.name())? {
+        Some(oid) => oid,
+        None => continue,
+      };
+      let (annotated, time) = match self.odb().object_kind(&oid)? {
+        ObjectKind::Tag => {
+          let object = self.odb().read_tag(&oid)?;
+          (
+            true,
+            object.tagger().map_or(0, |tagger| tagger.time.seconds),
+          )
+        }
+        _ if options.tags => (false, 0),
+        _ => continue,
+      };
+      let commit = match crate::peel_tag(self.odb(), &oid)? {
+        (commit, ObjectKind::Commit) => commit,
+        _ => continue,
+      };
+      let name = Name {
+        tag: tag.into(),
+        annotated,
+        time,
+      };
+      // Like git, an annotated tag beats a lightweight one and a newer
+      // annotated tag beats an older one, otherwise the first tag is kept
+      match names.get(&commit) {
+        Some(old) if old.annotated >= annotated && !(annotated && time > old.time) => {}
+        _ => {
+          names.insert(commit, name);
+        }
+      }
+    }
+    Ok(names)
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to describing commits
+pub enum DescribeError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  RevWalk(#[from] RevWalkError),
+  #[error("{0}")]
+  Status(#[from] StatusError),
+  #[error("no names found, cannot describe anything")]
+  NoNames,
+  #[error("no tags can describe {0}")]
+  NoTags(OID),
+  #[error("a dirty suffix can only be used when describing HEAD, not {0}")]
+  DirtyNotHead(OID),
+}
+
+#[test]
+fn describe() {
+  use crate::{Commit, Signature, Time};
+  use std::fs;
+  let tmp_dir = tempdir::TempDir::new("describe_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let odb = repo.odb().clone();
+  let mut time = 1_234_567_890;
+  let mut commit = |parents: Vec<OID>, contents: &str| {
+    time += 1;
+    let signature = Signature::new("A U Thor", "author@example.com", Time::new(time, 0));
+    let tree = crate::diff::write_tree(
+      &odb,
+      &[("a.txt", crate::FileMode::NonExecutableFile, contents)],
+    );
+    let commit = Commit::new(tree, parents, signature.clone(), signature, contents);
+    odb.write_commit(&commit).unwrap()
+  };
+  let root = commit(vec![], "root\n");
+  let first = commit(vec![root], "first\n");
+  let second = commit(vec![first], "second\n");
+  let side = commit(vec![root], "side\n");
+  let merge = commit(vec![second, side], "merge\n");
+  let tip = commit(vec![merge], "tip\n");
+  repo.refs().write("refs/heads/master", &tip).unwrap();
+
+  let options = DescribeOptions::default();
+  assert!(matches!(
+    repo.describe(&tip, &options),
+    Err(DescribeError::NoNames)
+  ));
+  let always = DescribeOptions {
+    always: true,
+    abbrev: Some(7),
+    ..DescribeOptions::default()
+  };
+  assert_eq!(
+    tip.as_hex()[..7],
+    repo.describe(&tip, &always).unwrap().to_string()
+  );
+
+  let tagger = Signature::new("A U Thor", "author@example.com", Time::new(time, 0));
+  repo
+    .tags()
+    .create_annotated("v1.0", &root, tagger.clone(), "v1.0\n", false)
+    .unwrap();
+  repo
+    .tags()
+    .create_annotated("v1.1", &first, tagger, "v1.1\n", false)
+    .unwrap();
+  repo.tags().create("light", &side, false).unwrap();
+
+  let abbrev = repo.odb().abbreviate(&tip, None).unwrap();
+  let description = repo.describe(&tip, &options).unwrap();
+  assert_eq!(Some("v1.1".into()), description.tag);
+  assert_eq!(4, description.depth);
+  assert_eq!(format!("v1.1-4-g{}", abbrev), description.to_string());
+  assert_eq!("v1.1", repo.describe(&first, &options).unwrap().to_string());
+  let long = DescribeOptions {
+    long: true,
+    ..DescribeOptions::default()
+  };
+  assert_eq!(
+    format!("v1.1-0-g{}", repo.odb().abbreviate(&first, None).unwrap()),
+    repo.describe(&first, &long).unwrap().to_string()
+  );
+  let short = DescribeOptions {
+    abbrev: Some(0),
+    ..DescribeOptions::default()
+  };
+  assert_eq!("v1.1", repo.describe(&tip, &short).unwrap().to_string());
+  let matching = DescribeOptions {
+    patterns: vec!["v1.0".into()],
+    ..DescribeOptions::default()
+  };
+  assert_eq!(
+    format!("v1.0-5-g{}", abbrev),
+    repo.describe(&tip, &matching).unwrap().to_string()
+  );
+  let tags = DescribeOptions {
+    tags: true,
+    ..DescribeOptions::default()
+  };
+  assert_eq!("light", repo.describe(&side, &tags).unwrap().to_string());
+  assert_eq!("v1.0", repo.describe(&root, &tags).unwrap().to_string());
+  let exact = DescribeOptions {
+    candidates: 0,
+    ..DescribeOptions::default()
+  };
+  assert!(matches!(
+    repo.describe(&tip, &exact),
+    Err(DescribeError::NoTags(_))
+  ));
+
+  // Dirty is only about tracked files
+  let dirty = DescribeOptions {
+    dirty: Some("-dirty".into()),
+    ..DescribeOptions::default()
+  };
+  assert!(matches!(
+    repo.describe(&first, &dirty),
+    Err(DescribeError::DirtyNotHead(_))
+  ));
+  let work_dir = repo.work_dir().unwrap();
+  repo
+    .checkout_tree(repo.odb().read_commit(&tip).unwrap().tree())
+    .unwrap();
+  fs::write(work_dir.join("untracked.txt"), "untracked\n").unwrap();
+  let clean = repo.describe(&tip, &dirty).unwrap();
+  assert!(!clean.is_dirty());
+  fs::write(work_dir.join("a.txt"), "changed\n").unwrap();
+  let changed = repo.describe(&tip, &dirty).unwrap();
+  assert!(changed.is_dirty());
+  assert_eq!(format!("v1.1-4-g{}-dirty", abbrev), changed.to_string());
+
+  // Git describes the commits the same way
+  if crate::transport::http::have_git() {
+    let git = |args: &[&str]| {
+      let output = std::process::Command::new("git")
+        .args(args)
+        .current_dir(work_dir)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .output()
+        .unwrap();
+      assert!(output.status.success());
+      output.stdout.trim_end().to_str_lossy().into_owned()
+    };
+    assert_eq!(changed.to_string(), git(&["describe", "--dirty"]));
+    assert_eq!(
+      repo.describe(&side, &tags).unwrap().to_string(),
+      git(&["describe", "--tags", &side.as_hex()])
+    );
+    assert_eq!(
+      repo.describe(&tip, &matching).unwrap().to_string(),
+      git(&["describe", "--match", "v1.0", "master"])
+    );
+  }
+}
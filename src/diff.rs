@@ -0,0 +1,409 @@
+use crate::{
+  detect_renames, patch::quote_path, tree::entry_order, ConfigError, FileMode, Odb, OdbError,
+  RenameOptions, Repository, Trace2, Tree, TreeEntry, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::{cmp::Ordering, fmt};
+use thiserror::Error;
+
+/// What happened to a path between two [`Tree`]s
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeKind {
+  /// The path only exists in the new [`Tree`]
+  Added,
+  /// The path only exists in the old [`Tree`]
+  Deleted,
+  /// The contents or the executable bit of the path changed
+  Modified,
+  /// The path changed between a file, a symbolic link, and a submodule
+  TypeChanged,
+  /// The file was moved from the path of [`Change::old_file`] to the one of
+  /// [`Change::new_file`], keeping the given percentage of its contents the same
+  Renamed(u8),
+  /// The file at the path of [`Change::old_file`] was copied to the one of
+  /// [`Change::new_file`], keeping the given percentage of its contents the same
+  Copied(u8),
+  /// The contents changed so much that the file is shown as rewritten
+  /// from scratch, with the given percentage of the old contents gone
+  Rewritten(u8),
+}
+
+/// One side of a [`Change`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DiffFile {
+  /// The full path from the root of the [`Tree`]
+  pub path: BString,
+  /// The [`FileMode`] of the entry
+  pub mode: FileMode,
+  /// The [`OID`] of the [`Blob`][crate::Blob] or submodule commit
+  pub oid: OID,
+}
+
+/// A difference between two [`Tree`]s for a single path. Only files,
+/// symbolic links, and submodules are reported, never directories.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Change {
+  kind: ChangeKind,
+  sides: Sides,
+}
+
+/// Which sides of a [`Change`] exist, so there's always at least one
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Sides {
+  Old(DiffFile),
+  New(DiffFile),
+  Both(DiffFile, DiffFile),
+}
+
+impl Change {
+  /// A file that only exists in the new [`Tree`]
+  pub fn added(new: DiffFile) -> Self {
+    Self {
+      kind: ChangeKind::Added,
+      sides: Sides::New(new),
+    }
+  }
+
+  /// A file that only exists in the old [`Tree`]
+  pub fn deleted(old: DiffFile) -> Self {
+    Self {
+      kind: ChangeKind::Deleted,
+      sides: Sides::Old(old),
+    }
+  }
+
+  /// A file that exists in both [`Tree`]s, like one that was modified or
+  /// renamed
+  pub fn between(kind: ChangeKind, old: DiffFile, new: DiffFile) -> Self {
+    Self {
+      kind,
+      sides: Sides::Both(old, new),
+    }
+  }
+
+  /// What kind of change this is
+  pub fn kind(&self) -> ChangeKind {
+    self.kind
+  }
+
+  /// The file in the old [`Tree`], `None` if it was added
+  pub fn old_file(&self) -> Option<&DiffFile> {
+    match &self.sides {
+      Sides::Old(old) | Sides::Both(old, _) => Some(old),
+      Sides::New(_) => None,
+    }
+  }
+
+  /// The file in the new [`Tree`], `None` if it was deleted
+  pub fn new_file(&self) -> Option<&DiffFile> {
+    match &self.sides {
+      Sides::New(new) | Sides::Both(_, new) => Some(new),
+      Sides::Old(_) => None,
+    }
+  }
+
+  /// The old and the new file, where the one that's missing is the same as
+  /// the other
+  fn files(&self) -> (&DiffFile, &DiffFile) {
+    match &self.sides {
+      Sides::Old(file) | Sides::New(file) => (file, file),
+      Sides::Both(old, new) => (old, new),
+    }
+  }
+
+  /// A [`Change`] from whichever sides exist, `None` if neither does
+  pub(crate) fn from_sides(
+    kind: ChangeKind,
+    old: Option<DiffFile>,
+    new: Option<DiffFile>,
+  ) -> Option<Self> {
+    let sides = match (old, new) {
+      (Some(old), Some(new)) => Sides::Both(old, new),
+      (Some(old), None) => Sides::Old(old),
+      (None, Some(new)) => Sides::New(new),
+      (None, None) => return None,
+    };
+    Some(Self { kind, sides })
+  }
+
+  /// The kind and the old and new files of the [`Change`]
+  pub(crate) fn into_sides(self) -> (ChangeKind, Option<DiffFile>, Option<DiffFile>) {
+    match self.sides {
+      Sides::Old(old) => (self.kind, Some(old), None),
+      Sides::New(new) => (self.kind, None, Some(new)),
+      Sides::Both(old, new) => (self.kind, Some(old), Some(new)),
+    }
+  }
+
+  /// The path that changed, the new one if it was renamed or copied
+  pub fn path(&self) -> &BStr {
+    self.files().1.path.as_bstr()
+  }
+}
+
+/// Shown the way `git diff --name-status` shows it, like `M\tpath` or
+/// `R100\told\tnew` with paths quoted if they need to be
+impl fmt::Display for Change {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let quote = |file: &DiffFile| BString::from(quote_path(b"", &file.path, true));
+    let (old, new) = self.files();
+    match self.kind {
+      ChangeKind::Added => write!(f, "A\t{}", quote(new)),
+      ChangeKind::Deleted => write!(f, "D\t{}", quote(old)),
+      ChangeKind::Modified => write!(f, "M\t{}", quote(new)),
+      ChangeKind::TypeChanged => write!(f, "T\t{}", quote(new)),
+      ChangeKind::Rewritten(score) => write!(f, "M{:03}\t{}", score, quote(new)),
+      ChangeKind::Renamed(score) => {
+        write!(f, "R{:03}\t{}\t{}", score, quote(old), quote(new))
+      }
+      ChangeKind::Copied(score) => {
+        write!(f, "C{:03}\t{}\t{}", score, quote(old), quote(new))
+      }
+    }
+  }
+}
+
+/// Compare the [`Tree`]s with the [`OID`]s `old` and `new` like
+/// `git diff-tree -r`, where `None` is an empty [`Tree`]. Subdirectories
+/// are compared recursively and skipped entirely if their [`OID`] is the
+/// same on both sides. The changes are in the order git sorts paths in.
+///
+/// A directory replaced by a file of the same name, or the other way
+/// around, shows up as every file in the directory being deleted or added
+/// and the file being added or deleted.
+pub fn diff_trees(
+  odb: &Odb,
+  old: Option<&OID>,
+  new: Option<&OID>,
+) -> Result<Vec<Change>, DiffError> {
+  let _region = Trace2::region("diff", "diff_trees");
+  let read = |oid: Option<&OID>| oid.map_or(Ok(Tree::default()), |oid| odb.read_tree(oid));
+  let mut changes = Vec::new();
+  if old != new {
+    diff_dir(odb, &read(old)?, &read(new)?, b"", &mut changes)?;
+  }
+  Trace2::data("diff", "changes", changes.len());
+  Ok(changes)
+}
+
+impl Repository {
+  /// Compare two [`Tree`]s of the repository like `git diff` does, with
+  /// renames found using the [`RenameOptions`] from the [`Config`] of the
+  /// repository. See [`diff_trees`] and [`detect_renames`].
+  ///
+  /// [`Config`]: crate::Config
+  pub fn diff_trees(&self, old: Option<&OID>, new: Option<&OID>) -> Result<Vec<Change>, DiffError> {
+    let changes = diff_trees(self.odb(), old, new)?;
+    detect_renames(
+      self.odb(),
+      changes,
+      &RenameOptions::from_config(self.config())?,
+    )
+  }
+}
+
+fn diff_dir(
+  odb: &Odb,
+  old: &Tree,
+  new: &Tree,
+  prefix: &[u8],
+  changes: &mut Vec<Change>,
+) -> Result<(), DiffError> {
+  let (mut old, mut new) = (
+    old.entries().iter().peekable(),
+    new.entries().iter().peekable(),
+  );
+  loop {
+    let order = match (old.peek(), new.peek()) {
+      (None, None) => return Ok(()),
+      (Some(_), None) => Ordering::Less,
+      (None, Some(_)) => Ordering::Greater,
+      (Some(a), Some(b)) => entry_order(a, b),
+    };
+    match order {
+      Ordering::Less => add_all(
+        odb,
+        old.next().unwrap(),
+        prefix,
+        ChangeKind::Deleted,
+        changes,
+      )?,
+      Ordering::Greater => add_all(odb, new.next().unwrap(), prefix, ChangeKind::Added, changes)?,
+      Ordering::Equal => {
+        let (a, b) = (old.next().unwrap(), new.next().unwrap());
+        if a.oid() == b.oid() && a.mode() == b.mode() {
+          continue;
+        }
+        if a.mode().is_tree() {
+          // Both are trees since ones with the same name as a file sort
+          // differently
+          let path = join(prefix, a.name());
+          diff_dir(
+            odb,
+            &odb.read_tree(a.oid())?,
+            &odb.read_tree(b.oid())?,
+            &path,
+            changes,
+          )?;
+          continue;
+        }
+        let kind = if kind_of(a.mode()) == kind_of(b.mode()) {
+          ChangeKind::Modified
+        } else {
+          ChangeKind::TypeChanged
+        };
+        changes.push(Change::between(kind, file(prefix, a), file(prefix, b)));
+      }
+    }
+  }
+}
+
+/// Report `entry` and everything under it if it's a directory as added or
+/// deleted
+fn add_all(
+  odb: &Odb,
+  entry: &TreeEntry,
+  prefix: &[u8],
+  kind: ChangeKind,
+  changes: &mut Vec<Change>,
+) -> Result<(), DiffError> {
+  if entry.mode().is_tree() {
+    let path = join(prefix, entry.name());
+    for child in odb.read_tree(entry.oid())?.entries() {
+      add_all(odb, child, &path, kind, changes)?;
+    }
+    return Ok(());
+  }
+  let file = file(prefix, entry);
+  changes.push(match kind {
+    ChangeKind::Added => Change::added(file),
+    _ => Change::deleted(file),
+  });
+  Ok(())
+}
+
+/// Files and executables are the same kind, a change between them is a
+/// modification
+pub(crate) fn kind_of(mode: FileMode) -> FileMode {
+  match mode {
+    FileMode::ExecutableFile => FileMode::NonExecutableFile,
+    mode => mode,
+  }
+}
+
+fn file(prefix: &[u8], entry: &TreeEntry) -> DiffFile {
+  DiffFile {
+    path: join(prefix, entry.name()).into(),
+    mode: entry.mode(),
+    oid: *entry.oid(),
+  }
+}
+
+fn join(prefix: &[u8], name: &BStr) -> Vec<u8> {
+  if prefix.is_empty() {
+    return name.to_vec();
+  }
+  [prefix, b"/", name.as_bytes()].concat()
+}
+
+#[derive(Error, Debug)]
+/// Errors related to comparing [`Tree`]s
+pub enum DiffError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+}
+
+/// Write the nested [`Tree`]s holding files at the given paths
+#[cfg(test)]
+pub(crate) fn write_tree(odb: &Odb, files: &[(&str, FileMode, &str)]) -> OID {
+  use crate::Blob;
+  let mut entries = Vec::new();
+  let mut dirs: Vec<&str> = Vec::new();
+  for &(path, mode, contents) in files {
+    match path.split_once('/') {
+      Some((dir, _)) if !dirs.contains(&dir) => dirs.push(dir),
+      Some(_) => {}
+      None => {
+        let oid = odb.write_blob(&Blob::new(contents)).unwrap();
+        entries.push(TreeEntry::new(mode, path, oid));
+      }
+    }
+  }
+  for dir in dirs {
+    let children: Vec<_> = files
+      .iter()
+      .filter_map(|&(path, mode, contents)| {
+        let rest = path.strip_prefix(dir)?.strip_prefix('/')?;
+        Some((rest, mode, contents))
+      })
+      .collect();
+    entries.push(TreeEntry::new(
+      FileMode::Tree,
+      dir,
+      write_tree(odb, &children),
+    ));
+  }
+  odb.write_tree(&Tree::new(entries)).unwrap()
+}
+
+#[test]
+fn diff() {
+  use FileMode::*;
+  let tmp_dir = tempdir::TempDir::new("diff_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let old = write_tree(
+    &odb,
+    &[
+      ("README", NonExecutableFile, "readme"),
+      ("build.sh", NonExecutableFile, "make"),
+      ("link", SymbolicLink, "README"),
+      ("src/lib.rs", NonExecutableFile, "lib"),
+      ("src/main.rs", NonExecutableFile, "main"),
+      ("same/a", NonExecutableFile, "unchanged"),
+      ("docs", NonExecutableFile, "a file named docs"),
+    ],
+  );
+  let new = write_tree(
+    &odb,
+    &[
+      ("README", NonExecutableFile, "readme!"),
+      ("build.sh", ExecutableFile, "make"),
+      ("link", NonExecutableFile, "README"),
+      ("src/lib.rs", NonExecutableFile, "lib"),
+      ("src/new.rs", NonExecutableFile, "new"),
+      ("same/a", NonExecutableFile, "unchanged"),
+      ("docs/index.md", NonExecutableFile, "docs"),
+    ],
+  );
+  let changes = diff_trees(&odb, Some(&old), Some(&new)).unwrap();
+  let summary: Vec<(ChangeKind, String)> = changes
+    .iter()
+    .map(|change| (change.kind(), change.path().to_string()))
+    .collect();
+  assert_eq!(
+    vec![
+      (ChangeKind::Modified, "README".to_string()),
+      (ChangeKind::Modified, "build.sh".into()),
+      (ChangeKind::Deleted, "docs".into()),
+      (ChangeKind::Added, "docs/index.md".into()),
+      (ChangeKind::TypeChanged, "link".into()),
+      (ChangeKind::Deleted, "src/main.rs".into()),
+      (ChangeKind::Added, "src/new.rs".into()),
+    ],
+    summary
+  );
+  let build = &changes[1];
+  assert_eq!(NonExecutableFile, build.old_file().unwrap().mode);
+  assert_eq!(ExecutableFile, build.new_file().unwrap().mode);
+  assert_eq!(build.old_file().unwrap().oid, build.new_file().unwrap().oid);
+
+  // Against an empty tree everything is added
+  let added = diff_trees(&odb, None, Some(&old)).unwrap();
+  assert_eq!(7, added.len());
+  assert!(added
+    .iter()
+    .all(|change| change.kind() == ChangeKind::Added && change.old_file().is_none()));
+  assert!(diff_trees(&odb, Some(&old), Some(&old)).unwrap().is_empty());
+}
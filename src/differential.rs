@@ -0,0 +1,598 @@
+//! Differential testing against the system `git`. [`generate_repo`] builds
+//! a repository with a random but reproducible history using `git` itself,
+//! and [`compare`] reads it back with this crate and checks the objects,
+//! refs, history, diffs, and index agree byte for byte with what `git`
+//! says about them. Running both over many seeds, before and after
+//! `git repack`, catches the places where the crate is merely close to
+//! what git does.
+//!
+//! ```ignore
+//! let git = generate_repo(dir, seed, &GenerateOptions::default())?;
+//! assert_eq!(Vec::<Mismatch>::new(), compare(&git)?);
+//! git.run(&["repack", "-adq"])?;
+//! assert_eq!(Vec::<Mismatch>::new(), compare(&git)?);
+//! ```
+
+use crate::{
+  diff_trees, format_patch, Commit, DiffError, IndexError, ObjectKind, OdbError, PatchOptions,
+  RefError, RefTarget, Repository, RepositoryError, RevWalk, RevWalkError, Tag, Tree, OID,
+};
+use bstr::{BString, ByteSlice};
+use std::{
+  cell::Cell,
+  fmt, fs, io,
+  io::Write,
+  path::{Path, PathBuf},
+  process::{Command, Stdio},
+};
+use thiserror::Error;
+
+/// The time of the first commit [`generate_repo`] makes. Every command run
+/// after [`SystemGit::tick`] is a minute later.
+const START_TIME: i64 = 1_600_000_000;
+
+/// Runs the system `git` in one repository with none of the user's config
+/// and a fixed identity, so the objects it makes only depend on what it's
+/// told to do
+#[derive(Debug)]
+pub struct SystemGit {
+  dir: PathBuf,
+  time: Cell<i64>,
+}
+
+impl SystemGit {
+  /// Run `git` in the working tree at `dir`
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    Self {
+      dir: dir.into(),
+      time: Cell::new(START_TIME),
+    }
+  }
+
+  /// Whether there is a `git` to run at all, so tests can be skipped where
+  /// there isn't
+  pub fn available() -> bool {
+    Command::new("git")
+      .arg("--version")
+      .stdout(Stdio::null())
+      .stderr(Stdio::null())
+      .status()
+      .is_ok_and(|status| status.success())
+  }
+
+  /// The working tree `git` runs in
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  /// Move the author and committer dates forward a minute
+  pub fn tick(&self) {
+    self.time.set(self.time.get() + 60);
+  }
+
+  /// Run `git` with `args` and return what it printed
+  pub fn run(&self, args: &[&str]) -> Result<BString, DifferentialError> {
+    self.run_with_input(args, b"")
+  }
+
+  /// Run `git` with `args` and `input` on its standard input and return
+  /// what it printed
+  pub fn run_with_input(&self, args: &[&str], input: &[u8]) -> Result<BString, DifferentialError> {
+    let date = format!("{} +0000", self.time.get());
+    let mut child = Command::new("git")
+      .args([
+        "-c",
+        "init.defaultBranch=master",
+        "-c",
+        "core.autocrlf=false",
+        "-c",
+        "gc.auto=0",
+        "-c",
+        "commit.gpgSign=false",
+        "-c",
+        "tag.gpgSign=false",
+      ])
+      .args(args)
+      .current_dir(&self.dir)
+      .env_remove("GIT_DIR")
+      .env_remove("GIT_WORK_TREE")
+      .env_remove("GIT_INDEX_FILE")
+      .env_remove("GIT_OBJECT_DIRECTORY")
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .env("HOME", &self.dir)
+      .env("GIT_AUTHOR_NAME", "A U Thor")
+      .env("GIT_AUTHOR_EMAIL", "author@example.com")
+      .env("GIT_AUTHOR_DATE", &date)
+      .env("GIT_COMMITTER_NAME", "C O Mitter")
+      .env("GIT_COMMITTER_EMAIL", "committer@example.com")
+      .env("GIT_COMMITTER_DATE", &date)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()?;
+    child.stdin.take().unwrap().write_all(input)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+      return Err(DifferentialError::Git {
+        args: args.join(" "),
+        stderr: output.stderr.into(),
+      });
+    }
+    Ok(output.stdout.into())
+  }
+}
+
+/// How big a repository [`generate_repo`] makes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerateOptions {
+  /// How many commits to make
+  pub commits: usize,
+  /// How many paths the files are spread over, some of them in
+  /// subdirectories
+  pub paths: usize,
+}
+
+impl Default for GenerateOptions {
+  fn default() -> Self {
+    Self {
+      commits: 20,
+      paths: 12,
+    }
+  }
+}
+
+/// Make a new repository at `dir` with `git` and fill it with history. The
+/// same `seed` always makes the same repository, with files being written,
+/// deleted, made executable, and replaced with symbolic links or
+/// directories, and with branches, merges, lightweight and annotated tags,
+/// and some changes left staged in the index.
+pub fn generate_repo(
+  dir: impl Into<PathBuf>,
+  seed: u64,
+  options: &GenerateOptions,
+) -> Result<SystemGit, DifferentialError> {
+  let git = SystemGit::new(dir);
+  fs::create_dir_all(git.dir())?;
+  git.run(&["init", "-q"])?;
+  let mut random = xorshift(seed);
+  let paths: Vec<String> = (0..options.paths.max(1))
+    .map(|i| match i % 4 {
+      0 | 1 => format!("file{}", i),
+      2 => format!("dir/file{}", i),
+      _ => format!("dir/sub{}/file{}", i % 3, i),
+    })
+    .collect();
+  for commit in 0..options.commits {
+    for _ in 0..1 + random(3) {
+      let path = git.dir().join(&paths[random(paths.len())]);
+      change_path(&path, &mut random)?;
+    }
+    git.run(&["add", "-A"])?;
+    git.tick();
+    git.run(&["commit", "-q", "--allow-empty", "-m", &message(commit)])?;
+    match random(8) {
+      0 => drop(git.run(&["tag", &format!("light{}", commit)])?),
+      1 => drop(git.run(&[
+        "tag",
+        "-a",
+        &format!("v{}", commit),
+        "-m",
+        &format!("Release {}\n", commit),
+      ])?),
+      2 if commit > 0 => {
+        // A branch off the commit before with a commit of its own
+        git.run(&[
+          "checkout",
+          "-q",
+          "-b",
+          &format!("topic{}", commit),
+          "HEAD~1",
+        ])?;
+        let path = git.dir().join(&paths[random(paths.len())]);
+        change_path(&path, &mut random)?;
+        git.run(&["add", "-A"])?;
+        git.tick();
+        git.run(&["commit", "-q", "--allow-empty", "-m", "On a branch"])?;
+        git.run(&["checkout", "-q", "master"])?;
+      }
+      3 => {
+        // Merge a branch made earlier, keeping ours on any conflict
+        let branches = git.run(&["branch", "--list", "topic*", "--format=%(refname:short)"])?;
+        if let Some(branch) = branches.lines().next() {
+          let branch = branch.to_str_lossy().into_owned();
+          git.tick();
+          git.run(&[
+            "merge",
+            "-q",
+            "--no-ff",
+            "-s",
+            "ours",
+            "-m",
+            &format!("Merge {}", branch),
+            &branch,
+          ])?;
+          git.run(&["branch", "-D", &branch])?;
+        }
+      }
+      _ => {}
+    }
+  }
+  // Leave something staged for the index to be compared against
+  let path = git.dir().join(&paths[random(paths.len())]);
+  change_path(&path, &mut random)?;
+  git.run(&["add", "-A"])?;
+  Ok(git)
+}
+
+fn message(commit: usize) -> String {
+  match commit % 3 {
+    0 => format!("Commit {}", commit),
+    1 => format!("Commit {}\n\nWith a body\nover two lines", commit),
+    _ => format!("Commit {} with ünïcödé", commit),
+  }
+}
+
+/// Do one random thing to the file at `path`
+fn change_path(path: &Path, random: &mut impl FnMut(usize) -> usize) -> io::Result<()> {
+  // Whatever is there might be a directory or a symbolic link made by an
+  // earlier change, which files shouldn't be written through
+  match fs::symlink_metadata(path) {
+    Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+    Ok(metadata) if metadata.file_type().is_symlink() => fs::remove_file(path)?,
+    _ => {}
+  }
+  fs::create_dir_all(path.parent().unwrap())?;
+  match random(8) {
+    0 if path.exists() => fs::remove_file(path),
+    #[cfg(unix)]
+    1 if path.is_file() => {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = fs::metadata(path)?.permissions().mode() ^ 0o111;
+      fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+    #[cfg(unix)]
+    2 => {
+      let _ = fs::remove_file(path);
+      std::os::unix::fs::symlink(format!("target{}", random(100)), path)
+    }
+    3 => {
+      let _ = fs::remove_file(path);
+      fs::create_dir(path)?;
+      fs::write(path.join("inner"), format!("inner {}\n", random(100)))
+    }
+    4 => fs::write(
+      path,
+      [b"binary\0".to_vec(), vec![random(256) as u8; 64]].concat(),
+    ),
+    _ => {
+      // Mostly the same lines so diffs have context around the hunks
+      let mut contents = match fs::read(path) {
+        Ok(contents) if !contents.contains(&0) => contents,
+        _ => (0..20)
+          .flat_map(|i| format!("line {}\n", i).into_bytes())
+          .collect(),
+      };
+      let lines: Vec<&[u8]> = contents.lines_with_terminator().collect();
+      let at = random(lines.len() + 1);
+      let mut changed: Vec<u8> = lines[..at].concat();
+      changed.extend(format!("changed {}\n", random(1000)).as_bytes());
+      changed.extend(lines.get(at + 1..).unwrap_or_default().concat());
+      contents = changed;
+      fs::write(path, contents)
+    }
+  }
+}
+
+fn xorshift(seed: u64) -> impl FnMut(usize) -> usize {
+  let mut state = seed | 1;
+  move |bound: usize| {
+    state ^= state << 13;
+    state ^= state >> 7;
+    state ^= state << 17;
+    (state % bound.max(1) as u64) as usize
+  }
+}
+
+/// Something this crate and `git` disagree on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+  /// What was compared, like `diff-tree {old} {new}`
+  pub what: String,
+  /// What this crate came up with
+  pub ours: BString,
+  /// What `git` came up with
+  pub git: BString,
+}
+
+impl fmt::Display for Mismatch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} differs\n--- ours\n{}\n--- git\n{}",
+      self.what, self.ours, self.git
+    )
+  }
+}
+
+/// Compare everything this crate reads from the repository `git` runs in
+/// with what `git` says, returning every difference found. See the
+/// `compare_*` functions for what is compared.
+pub fn compare(git: &SystemGit) -> Result<Vec<Mismatch>, DifferentialError> {
+  let repo = Repository::open(git.dir())?;
+  let mut mismatches = Vec::new();
+  compare_objects(git, &repo, &mut mismatches)?;
+  compare_refs(git, &repo, &mut mismatches)?;
+  compare_history(git, &repo, &mut mismatches)?;
+  compare_diffs(git, &repo, &mut mismatches)?;
+  compare_index(git, &repo, &mut mismatches)?;
+  Ok(mismatches)
+}
+
+fn check(mismatches: &mut Vec<Mismatch>, what: String, ours: BString, git: BString) {
+  if ours != git {
+    mismatches.push(Mismatch { what, ours, git });
+  }
+}
+
+/// Every object `git` has, loose or packed, has the same kind, size, and
+/// contents when read by this crate, and parsed trees, commits, and tags
+/// write back out to the same bytes
+pub fn compare_objects(
+  git: &SystemGit,
+  repo: &Repository,
+  mismatches: &mut Vec<Mismatch>,
+) -> Result<(), DifferentialError> {
+  let objects = git.run(&["cat-file", "--batch-all-objects", "--batch-check"])?;
+  let mut ours = Vec::new();
+  for line in objects.lines() {
+    let oid = repo.odb().find_prefix(line[..40].to_str_lossy().as_ref())?;
+    let object = repo.odb().read(&oid)?;
+    ours.push(format!("{} {} {}", oid, object.kind, object.data.len()));
+    let rewritten = match object.kind {
+      ObjectKind::Blob => continue,
+      ObjectKind::Tree => Tree::parse(&object.data)
+        .map_err(OdbError::from)?
+        .as_bytes(),
+      ObjectKind::Commit => Commit::parse(&object.data)
+        .map_err(OdbError::from)?
+        .as_bytes(),
+      ObjectKind::Tag => Tag::parse(&object.data).map_err(OdbError::from)?.as_bytes(),
+    };
+    check(
+      mismatches,
+      format!("parsing and writing {}", oid),
+      rewritten.into(),
+      object.as_bytes().into(),
+    );
+  }
+  check(
+    mismatches,
+    "cat-file --batch-all-objects --batch-check".into(),
+    ours.join("\n").into(),
+    objects.trim_end().into(),
+  );
+  Ok(())
+}
+
+/// Every ref resolves to the same object, and `HEAD` points at the same
+/// branch
+pub fn compare_refs(
+  git: &SystemGit,
+  repo: &Repository,
+  mismatches: &mut Vec<Mismatch>,
+) -> Result<(), DifferentialError> {
+  let mut ours = Vec::new();
+  for reference in repo.refs().list("refs/")? {
+    let oid = match reference.target() {
+      RefTarget::Direct(oid) => *oid,
+      RefTarget::Symbolic(_) => repo.refs().resolve(reference.name())?.unwrap(),
+    };
+    ours.push(format!("{} {}", oid, reference.name()));
+  }
+  check(
+    mismatches,
+    "for-each-ref".into(),
+    ours.join("\n").into(),
+    git
+      .run(&["for-each-ref", "--format=%(objectname) %(refname)"])?
+      .trim_end()
+      .into(),
+  );
+  let head = match repo.refs().read("HEAD")?.map(|head| head.target().clone()) {
+    Some(RefTarget::Symbolic(name)) => name,
+    Some(RefTarget::Direct(oid)) => oid.as_hex().into(),
+    None => BString::from(""),
+  };
+  let git_head = git
+    .run(&["symbolic-ref", "-q", "HEAD"])
+    .or_else(|_| git.run(&["rev-parse", "HEAD"]))?;
+  check(
+    mismatches,
+    "symbolic-ref HEAD".into(),
+    head,
+    git_head.trim_end().into(),
+  );
+  Ok(())
+}
+
+/// Walking back from every ref gives the commits in the same order as
+/// `git rev-list --all`
+pub fn compare_history(
+  git: &SystemGit,
+  repo: &Repository,
+  mismatches: &mut Vec<Mismatch>,
+) -> Result<(), DifferentialError> {
+  let mut walk = RevWalk::new(repo.odb());
+  for oid in commit_tips(repo)? {
+    walk.push(&oid)?;
+  }
+  let ours = walk
+    .map(|oid| oid.map(|oid| oid.as_hex()))
+    .collect::<Result<Vec<_>, _>>()?;
+  check(
+    mismatches,
+    "rev-list --all".into(),
+    ours.join("\n").into(),
+    git.run(&["rev-list", "--all"])?.trim_end().into(),
+  );
+  Ok(())
+}
+
+/// The refs that end up at commits, with tags peeled
+fn commit_tips(repo: &Repository) -> Result<Vec<OID>, DifferentialError> {
+  let mut tips = Vec::new();
+  for reference in repo.refs().list("refs/")? {
+    let mut oid = match repo.refs().resolve(reference.name())? {
+      Some(oid) => oid,
+      None => continue,
+    };
+    while repo.odb().read(&oid)?.kind == ObjectKind::Tag {
+      oid = *repo.odb().read_tag(&oid)?.object();
+    }
+    if repo.odb().read(&oid)?.kind == ObjectKind::Commit {
+      tips.push(oid);
+    }
+  }
+  Ok(tips)
+}
+
+/// Every commit has the same changes from its first parent in both
+/// `git diff-tree -r --name-status` form and as a patch
+pub fn compare_diffs(
+  git: &SystemGit,
+  repo: &Repository,
+  mismatches: &mut Vec<Mismatch>,
+) -> Result<(), DifferentialError> {
+  let mut walk = RevWalk::new(repo.odb());
+  for oid in commit_tips(repo)? {
+    walk.push(&oid)?;
+  }
+  let options = PatchOptions {
+    abbrev: Some(7),
+    ..PatchOptions::default()
+  };
+  for oid in walk {
+    let commit = repo.odb().read_commit(&oid?)?;
+    let parent = match commit.parents().first() {
+      Some(parent) => *repo.odb().read_commit(parent)?.tree(),
+      None => continue,
+    };
+    let (old, new) = (parent.as_hex(), commit.tree().as_hex());
+    let changes = diff_trees(repo.odb(), Some(&parent), Some(commit.tree()))?;
+    let names: Vec<String> = changes.iter().map(ToString::to_string).collect();
+    check(
+      mismatches,
+      format!("diff-tree -r --name-status {} {}", old, new),
+      names.join("\n").into(),
+      git
+        .run(&[
+          "diff-tree",
+          "-r",
+          "--no-renames",
+          "--name-status",
+          &old,
+          &new,
+        ])?
+        .trim_end()
+        .into(),
+    );
+    check(
+      mismatches,
+      format!("diff-tree -p {} {}", old, new),
+      format_patch(repo.odb(), &changes, &options)?,
+      git.run(&[
+        "diff-tree",
+        "-p",
+        "--no-renames",
+        "--abbrev=7",
+        "--no-color",
+        &old,
+        &new,
+      ])?,
+    );
+  }
+  Ok(())
+}
+
+/// The index holds the same entries and writes the same tree as `git
+/// write-tree`
+pub fn compare_index(
+  git: &SystemGit,
+  repo: &Repository,
+  mismatches: &mut Vec<Mismatch>,
+) -> Result<(), DifferentialError> {
+  let index = repo.index()?;
+  let entries: Vec<String> = index
+    .entries()
+    .iter()
+    .map(|entry| {
+      format!(
+        "{} {} {}\t{}",
+        entry.mode.as_bytes().as_bstr(),
+        entry.oid,
+        entry.stage,
+        entry.path
+      )
+    })
+    .collect();
+  check(
+    mismatches,
+    "ls-files --stage".into(),
+    entries.join("\n").into(),
+    git.run(&["ls-files", "--stage"])?.trim_end().into(),
+  );
+  let tree = index.write_tree(repo.odb())?;
+  check(
+    mismatches,
+    "write-tree".into(),
+    tree.as_hex().into(),
+    git.run(&["write-tree"])?.trim_end().into(),
+  );
+  Ok(())
+}
+
+#[derive(Error, Debug)]
+/// Errors related to differential testing
+pub enum DifferentialError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("git {args} failed: {stderr}")]
+  Git { args: String, stderr: BString },
+  #[error("{0}")]
+  Repository(#[from] RepositoryError),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  RevWalk(#[from] RevWalkError),
+  #[error("{0}")]
+  Diff(#[from] DiffError),
+  #[error("{0}")]
+  Index(#[from] IndexError),
+}
+
+#[test]
+fn against_system_git() {
+  if !SystemGit::available() {
+    return;
+  }
+  let tmp_dir = tempdir::TempDir::new("differential_test").unwrap();
+  for seed in 1..=4 {
+    let git = generate_repo(
+      tmp_dir.path().join(seed.to_string()),
+      seed,
+      &GenerateOptions::default(),
+    )
+    .unwrap();
+    let report = |mismatches: Vec<Mismatch>| {
+      let report: Vec<String> = mismatches.iter().map(ToString::to_string).collect();
+      report.join("\n\n")
+    };
+    assert_eq!("", report(compare(&git).unwrap()));
+    git.run(&["repack", "-adq"]).unwrap();
+    git.run(&["pack-refs", "--all"]).unwrap();
+    assert_eq!("", report(compare(&git).unwrap()));
+  }
+}
@@ -0,0 +1,195 @@
+use std::{borrow::Cow, convert::TryFrom, fmt};
+use thiserror::Error;
+
+/// A character encoding that messages can be stored in. Git assumes UTF-8
+/// unless a commit or tag has an `encoding` header, which older repositories
+/// often set to one of the legacy single byte encodings supported here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+  /// UTF-8, the default encoding for git
+  Utf8,
+  /// 7 bit US-ASCII
+  Ascii,
+  /// ISO-8859-1 also known as Latin-1
+  Latin1,
+  /// ISO-8859-15 also known as Latin-9
+  Latin9,
+  /// Windows-1252, the Windows superset of Latin-1
+  Windows1252,
+}
+
+impl Encoding {
+  /// Find the [`Encoding`] matching a label as used in the `encoding` header
+  /// or the `i18n.commitEncoding` config value. Labels are case insensitive
+  /// and common aliases like `latin1` or `cp1252` are accepted.
+  pub fn for_label(label: impl AsRef<[u8]>) -> Option<Self> {
+    let label = String::from_utf8_lossy(label.as_ref())
+      .trim()
+      .to_lowercase();
+    let encoding = match label.as_str() {
+      "utf-8" | "utf8" => Self::Utf8,
+      "us-ascii" | "ascii" | "ansi_x3.4-1968" => Self::Ascii,
+      "iso-8859-1" | "iso8859-1" | "iso_8859-1" | "latin1" | "latin-1" | "l1" => Self::Latin1,
+      "iso-8859-15" | "iso8859-15" | "iso_8859-15" | "latin9" | "latin-9" | "l9" => Self::Latin9,
+      "windows-1252" | "cp1252" | "x-cp1252" => Self::Windows1252,
+      _ => return None,
+    };
+    Some(encoding)
+  }
+
+  /// The canonical label to write out in headers for this [`Encoding`]
+  pub fn label(&self) -> &'static str {
+    match self {
+      Self::Utf8 => "UTF-8",
+      Self::Ascii => "US-ASCII",
+      Self::Latin1 => "ISO-8859-1",
+      Self::Latin9 => "ISO-8859-15",
+      Self::Windows1252 => "windows-1252",
+    }
+  }
+
+  /// Decode bytes in this [`Encoding`] into a UTF-8 string. This only
+  /// allocates if the bytes needed to be transcoded.
+  pub fn decode<'a>(&self, bytes: &'a [u8]) -> Result<Cow<'a, str>, EncodingError> {
+    match self {
+      Self::Utf8 => std::str::from_utf8(bytes)
+        .map(Cow::Borrowed)
+        .map_err(|e| EncodingError::Decode(*self, e.valid_up_to())),
+      Self::Ascii => match bytes.iter().position(|b| !b.is_ascii()) {
+        Some(idx) => Err(EncodingError::Decode(*self, idx)),
+        // All ASCII is valid UTF-8
+        None => Ok(Cow::Borrowed(std::str::from_utf8(bytes).unwrap())),
+      },
+      Self::Latin1 | Self::Latin9 | Self::Windows1252 => {
+        if bytes.is_ascii() {
+          return Ok(Cow::Borrowed(std::str::from_utf8(bytes).unwrap()));
+        }
+        Ok(Cow::Owned(
+          bytes.iter().map(|&b| self.decode_byte(b)).collect(),
+        ))
+      }
+    }
+  }
+
+  /// Encode a UTF-8 string into this [`Encoding`]. This fails if the string
+  /// contains a character that can't be represented in the [`Encoding`].
+  pub fn encode<'a>(&self, s: &'a str) -> Result<Cow<'a, [u8]>, EncodingError> {
+    if *self == Self::Utf8 || s.is_ascii() {
+      return Ok(Cow::Borrowed(s.as_bytes()));
+    }
+    s.chars()
+      .map(|c| self.encode_char(c).ok_or(EncodingError::Encode(*self, c)))
+      .collect::<Result<Vec<u8>, _>>()
+      .map(Cow::Owned)
+  }
+
+  fn decode_byte(&self, byte: u8) -> char {
+    let code_point = match (self, byte) {
+      (Self::Latin9, _) => LATIN9
+        .iter()
+        .find(|(b, _)| *b == byte)
+        .map_or(byte as u32, |(_, c)| *c),
+      (Self::Windows1252, 0x80..=0x9F) => WINDOWS1252[byte as usize - 0x80],
+      _ => byte as u32,
+    };
+    // Every entry in the tables is a valid scalar value
+    char::from_u32(code_point).unwrap()
+  }
+
+  fn encode_char(&self, c: char) -> Option<u8> {
+    let code_point = c as u32;
+    match self {
+      Self::Utf8 => None,
+      Self::Ascii => u8::try_from(code_point).ok().filter(u8::is_ascii),
+      Self::Latin1 => u8::try_from(code_point).ok(),
+      Self::Latin9 => match LATIN9.iter().find(|(_, c)| *c == code_point) {
+        Some((b, _)) => Some(*b),
+        None => u8::try_from(code_point)
+          .ok()
+          .filter(|b| !LATIN9.iter().any(|(replaced, _)| replaced == b)),
+      },
+      Self::Windows1252 => match WINDOWS1252.iter().position(|c| *c == code_point) {
+        Some(idx) => Some(0x80 + idx as u8),
+        None => u8::try_from(code_point)
+          .ok()
+          .filter(|b| !(0x80..=0x9F).contains(b)),
+      },
+    }
+  }
+}
+
+impl fmt::Display for Encoding {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.label())
+  }
+}
+
+/// The bytes where ISO-8859-15 differs from ISO-8859-1
+const LATIN9: [(u8, u32); 8] = [
+  (0xA4, 0x20AC),
+  (0xA6, 0x0160),
+  (0xA8, 0x0161),
+  (0xB4, 0x017D),
+  (0xB8, 0x017E),
+  (0xBC, 0x0152),
+  (0xBD, 0x0153),
+  (0xBE, 0x0178),
+];
+
+/// The code points for bytes 0x80 to 0x9F in Windows-1252. Bytes with no
+/// assigned character map to the matching C1 control the same way browsers
+/// decode them.
+const WINDOWS1252: [u32; 32] = [
+  0x20AC, 0x0081, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021, 0x02C6, 0x2030, 0x0160, 0x2039,
+  0x0152, 0x008D, 0x017D, 0x008F, 0x0090, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014,
+  0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0x009D, 0x017E, 0x0178,
+];
+
+#[derive(Error, Debug, PartialEq, Eq)]
+/// Errors related to transcoding text with an [`Encoding`]
+pub enum EncodingError {
+  #[error("the encoding {0:?} is not supported")]
+  Unsupported(String),
+  #[error("invalid {0} data at byte {1}")]
+  Decode(Encoding, usize),
+  #[error("the character {1:?} can't be represented in {0}")]
+  Encode(Encoding, char),
+}
+
+#[test]
+fn for_label() {
+  assert_eq!(Some(Encoding::Latin1), Encoding::for_label("ISO-8859-1"));
+  assert_eq!(Some(Encoding::Windows1252), Encoding::for_label("CP1252"));
+  assert_eq!(Some(Encoding::Utf8), Encoding::for_label(" utf8 "));
+  assert_eq!(None, Encoding::for_label("EUC-JP"));
+}
+
+#[test]
+fn decode() {
+  assert_eq!("café", Encoding::Latin1.decode(b"caf\xe9").unwrap());
+  assert_eq!("€5", Encoding::Latin9.decode(b"\xa45").unwrap());
+  assert_eq!("“hi”", Encoding::Windows1252.decode(b"\x93hi\x94").unwrap());
+  assert_eq!(
+    Err(EncodingError::Decode(Encoding::Utf8, 3)),
+    Encoding::Utf8.decode(b"caf\xe9")
+  );
+  assert_eq!(
+    Err(EncodingError::Decode(Encoding::Ascii, 0)),
+    Encoding::Ascii.decode(b"\xe9")
+  );
+}
+
+#[test]
+fn encode() {
+  assert_eq!(&b"caf\xe9"[..], &*Encoding::Latin1.encode("café").unwrap());
+  assert_eq!(&b"\xa4"[..], &*Encoding::Latin9.encode("€").unwrap());
+  assert_eq!(
+    Err(EncodingError::Encode(Encoding::Latin9, '¤')),
+    Encoding::Latin9.encode("¤")
+  );
+  assert_eq!(&b"\x80"[..], &*Encoding::Windows1252.encode("€").unwrap());
+  assert_eq!(
+    Err(EncodingError::Encode(Encoding::Latin1, '€')),
+    Encoding::Latin1.encode("€")
+  );
+}
@@ -0,0 +1,53 @@
+//! Reading the integers of git's binary formats. The index, pack files,
+//! pack indexes, and commit graphs all store them big endian no matter what
+//! the host is, and their sizes and offsets can be more than a 32 bit
+//! `usize` holds, so every conversion is explicit and checked here.
+
+use std::convert::{TryFrom, TryInto};
+
+/// The big endian `u16` at the start of `bytes`
+pub(crate) fn read_u16(bytes: &[u8]) -> u16 {
+  u16::from_be_bytes(bytes[..2].try_into().unwrap())
+}
+
+/// The big endian `u32` at the start of `bytes`
+pub(crate) fn read_u32(bytes: &[u8]) -> u32 {
+  u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+/// The big endian `u64` at the start of `bytes`
+pub(crate) fn read_u64(bytes: &[u8]) -> u64 {
+  u64::from_be_bytes(bytes[..8].try_into().unwrap())
+}
+
+/// How many bytes `count` entries of `size` bytes take up, or `None` if
+/// that doesn't fit in a `T`. The parsers use `usize`, the tests use `u32`
+/// to check what happens on 32 bit targets without needing one.
+pub(crate) fn table_len<T: TryFrom<u64>>(count: u32, size: u32) -> Option<T> {
+  T::try_from(u64::from(count) * u64::from(size)).ok()
+}
+
+#[test]
+fn big_endian() {
+  let bytes = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0xff];
+  assert_eq!(0x0102, read_u16(&bytes));
+  assert_eq!(0x0102_0304, read_u32(&bytes));
+  assert_eq!(0x0102_0304_0506_0708, read_u64(&bytes));
+  for value in [0, 1, 0x8000_0000, u32::MAX] {
+    assert_eq!(value, read_u32(&value.to_be_bytes()));
+  }
+  let large = 0x1_2345_6789_u64;
+  assert_eq!(large, read_u64(&large.to_be_bytes()));
+}
+
+#[test]
+fn sizes() {
+  assert_eq!(Some(3600usize), table_len(100, 36));
+  assert_eq!(
+    Some(u64::from(u32::MAX) * 36),
+    table_len::<u64>(u32::MAX, 36)
+  );
+  // What a 32 bit target sees for the commit data of a huge commit graph
+  assert_eq!(None, table_len::<u32>(0x0800_0000, 36));
+  assert_eq!(Some(0x7fff_fffc_u32), table_len(0x1fff_ffff, 4));
+}
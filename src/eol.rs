@@ -0,0 +1,526 @@
+//! Line ending conversion, which lets files be checked out with CRLF line
+//! endings while the blobs in the repository only have LF. Which files are
+//! converted comes from the `text` and `eol` [`Attributes`], or the older
+//! `crlf` one, and `core.autocrlf` for files they say nothing about:
+//!
+//! ```text
+//! *       text=auto
+//! *.txt   text
+//! *.bat   text eol=crlf
+//! *.png   -text
+//! ```
+//!
+//! Files that are `text=auto`, or only converted because of
+//! `core.autocrlf`, are left alone when they look binary, and aren't
+//! converted when they're checked in if the version in the [`Index`] has
+//! CRLF line endings already, so repositories that committed CRLF don't
+//! end up with every file changed.
+//!
+//! [`Index`]: crate::Index
+
+use crate::{AttributeState, Attributes, Config, ConfigError};
+use bstr::{BString, ByteSlice};
+use std::{borrow::Cow, fmt};
+use thiserror::Error;
+
+/// Whether files without line ending [`Attributes`] are converted, as set
+/// by `core.autocrlf`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum AutoCrlf {
+  /// Leave files alone
+  #[default]
+  False,
+  /// Check text files in with LF and out with CRLF
+  True,
+  /// Check text files in with LF and out as they are
+  Input,
+}
+
+/// The line endings text files are checked out with, as set by `core.eol`.
+/// It only matters when `core.autocrlf` is `false`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Eol {
+  /// LF line endings
+  Lf,
+  /// CRLF line endings
+  Crlf,
+  /// The line endings of the platform, CRLF on Windows and LF everywhere
+  /// else
+  #[default]
+  Native,
+}
+
+/// What to do when checking a file in and out again wouldn't give back the
+/// same file, as set by `core.safecrlf`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SafeCrlf {
+  /// Convert the file anyway
+  False,
+  /// Convert the file, warning about it
+  #[default]
+  Warn,
+  /// Refuse to convert the file
+  True,
+}
+
+/// How the line endings of a file would change if it were checked in and
+/// then checked out again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LossyConversion {
+  /// Its CRLF line endings would become LF
+  CrlfToLf,
+  /// Its LF line endings would become CRLF
+  LfToCrlf,
+}
+
+impl fmt::Display for LossyConversion {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Self::CrlfToLf => "CRLF will be replaced by LF",
+      Self::LfToCrlf => "LF will be replaced by CRLF",
+    })
+  }
+}
+
+/// The line ending settings of a repository, which convert files between
+/// the blobs in the repository and the files in the working tree
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineEndings {
+  /// The `core.autocrlf` setting
+  pub auto_crlf: AutoCrlf,
+  /// The `core.eol` setting
+  pub eol: Eol,
+  /// The `core.safecrlf` setting
+  pub safe_crlf: SafeCrlf,
+  /// The [`Attributes`] that say which paths are text, and which filter
+  /// drivers they go through. These are empty unless they were filled in.
+  pub attributes: Attributes,
+}
+
+/// What is done to the line endings of a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+  Binary,
+  /// Always text, checked out with LF
+  TextInput,
+  /// Always text, checked out with CRLF
+  TextCrlf,
+  /// Text if it doesn't look binary, checked out with LF
+  AutoInput,
+  /// Text if it doesn't look binary, checked out with CRLF
+  AutoCrlf,
+}
+
+impl Action {
+  fn is_auto(self) -> bool {
+    matches!(self, Self::AutoInput | Self::AutoCrlf)
+  }
+
+  fn is_crlf(self) -> bool {
+    matches!(self, Self::TextCrlf | Self::AutoCrlf)
+  }
+}
+
+/// What a file has in it, the same things git counts to guess whether a
+/// file is text
+#[derive(Debug, Default, Clone, Copy)]
+struct Stats {
+  nul: usize,
+  lone_cr: usize,
+  lone_lf: usize,
+  crlf: usize,
+  printable: usize,
+  nonprintable: usize,
+}
+
+impl Stats {
+  fn new(data: &[u8]) -> Self {
+    let mut stats = Self::default();
+    let mut bytes = data.iter().peekable();
+    while let Some(&c) = bytes.next() {
+      match c {
+        b'\r' if bytes.peek() == Some(&&b'\n') => {
+          bytes.next();
+          stats.crlf += 1;
+        }
+        b'\r' => stats.lone_cr += 1,
+        b'\n' => stats.lone_lf += 1,
+        0 => {
+          stats.nul += 1;
+          stats.nonprintable += 1;
+        }
+        // Backspace, tab, escape, and form feed show up in text
+        8 | 9 | 27 | 12 => stats.printable += 1,
+        127 => stats.nonprintable += 1,
+        c if c < 32 => stats.nonprintable += 1,
+        _ => stats.printable += 1,
+      }
+    }
+    // A DOS end of file marker at the end doesn't make a file binary
+    if data.last() == Some(&0x1a) {
+      stats.nonprintable -= 1;
+    }
+    stats
+  }
+
+  fn is_binary(&self) -> bool {
+    self.lone_cr > 0 || self.nul > 0 || (self.printable >> 7) < self.nonprintable
+  }
+}
+
+impl LineEndings {
+  /// Create [`LineEndings`] from the `core.*` settings in a [`Config`], with
+  /// no [`Attributes`]
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let invalid = |key: &str, value: &str, expected| ConfigError::InvalidValue {
+      key: key.into(),
+      value: value.into(),
+      expected,
+    };
+    let auto_crlf = match config.get("core.autocrlf") {
+      None => AutoCrlf::False,
+      Some(value) if value.eq_ignore_ascii_case(b"input") => AutoCrlf::Input,
+      Some(_) => match config.get_bool("core.autocrlf")? {
+        Some(true) => AutoCrlf::True,
+        _ => AutoCrlf::False,
+      },
+    };
+    let eol = match config.get_str("core.eol")? {
+      None => Eol::Native,
+      Some(value) => match value.to_ascii_lowercase().as_str() {
+        "lf" => Eol::Lf,
+        "crlf" => Eol::Crlf,
+        "native" => Eol::Native,
+        _ => return Err(invalid("core.eol", value, "lf, crlf, or native")),
+      },
+    };
+    let safe_crlf = match config.get("core.safecrlf") {
+      None => SafeCrlf::Warn,
+      Some(value) if value.eq_ignore_ascii_case(b"warn") => SafeCrlf::Warn,
+      Some(_) => match config.get_bool("core.safecrlf")? {
+        Some(true) => SafeCrlf::True,
+        _ => SafeCrlf::False,
+      },
+    };
+    Ok(Self {
+      auto_crlf,
+      eol,
+      safe_crlf,
+      attributes: Attributes::default(),
+    })
+  }
+
+  /// Convert the contents of the file at `path`, relative to the root of
+  /// the working tree, to what its blob should have, turning CRLF line
+  /// endings into LF if it's text. `staged` is what the [`Index`] has for
+  /// the path, if anything, which stops files that are only guessed to be
+  /// text from being converted when it has CRLF line endings.
+  ///
+  /// This never fails, even if `core.safecrlf` is `true`, so files can be
+  /// compared with their blobs. Check [`LineEndings::check`] before
+  /// writing a blob that was converted.
+  ///
+  /// [`Index`]: crate::Index
+  pub fn to_git<'a>(
+    &self,
+    path: impl AsRef<[u8]>,
+    data: &'a [u8],
+    staged: Option<&[u8]>,
+  ) -> Cow<'a, [u8]> {
+    let action = self.action(path.as_ref());
+    if action == Action::Binary || data.is_empty() {
+      return Cow::Borrowed(data);
+    }
+    let stats = Stats::new(data);
+    if stats.crlf == 0 || !converts_to_git(action, &stats, staged) {
+      return Cow::Borrowed(data);
+    }
+    let mut converted = Vec::with_capacity(data.len() - stats.crlf);
+    let mut bytes = data.iter().peekable();
+    while let Some(&c) = bytes.next() {
+      if c != b'\r' || bytes.peek() != Some(&&b'\n') {
+        converted.push(c);
+      }
+    }
+    Cow::Owned(converted)
+  }
+
+  /// Convert the contents of the blob for `path` to what the file in the
+  /// working tree should have, turning LF line endings into CRLF if it's
+  /// text that's checked out with CRLF
+  pub fn to_worktree<'a>(&self, path: impl AsRef<[u8]>, data: &'a [u8]) -> Cow<'a, [u8]> {
+    let action = self.action(path.as_ref());
+    if !converts_to_worktree(action, &Stats::new(data)) {
+      return Cow::Borrowed(data);
+    }
+    let mut converted = Vec::with_capacity(data.len() * 2);
+    let mut last = None;
+    for &c in data {
+      if c == b'\n' && last != Some(b'\r') {
+        converted.push(b'\r');
+      }
+      converted.push(c);
+      last = Some(c);
+    }
+    Cow::Owned(converted)
+  }
+
+  /// Check whether checking in the file at `path` with [`LineEndings::to_git`]
+  /// and checking it out again with [`LineEndings::to_worktree`] would
+  /// change its line endings, going by `core.safecrlf`. This is an error
+  /// when it's `true`, a [`LossyConversion`] to warn about when it's `warn`,
+  /// and nothing when it's `false`.
+  pub fn check(
+    &self,
+    path: impl AsRef<[u8]>,
+    data: &[u8],
+    staged: Option<&[u8]>,
+  ) -> Result<Option<LossyConversion>, EolError> {
+    let path = path.as_ref();
+    let action = self.action(path);
+    if self.safe_crlf == SafeCrlf::False || action == Action::Binary || data.is_empty() {
+      return Ok(None);
+    }
+    let old = Stats::new(data);
+    let mut new = old;
+    if converts_to_git(action, &old, staged) {
+      new.lone_lf += new.crlf;
+      new.crlf = 0;
+    }
+    if converts_to_worktree(action, &new) {
+      new.crlf += new.lone_lf;
+      new.lone_lf = 0;
+    }
+    let lossy = if old.crlf > 0 && new.crlf == 0 {
+      LossyConversion::CrlfToLf
+    } else if old.lone_lf > 0 && new.lone_lf == 0 {
+      LossyConversion::LfToCrlf
+    } else {
+      return Ok(None);
+    };
+    match self.safe_crlf {
+      SafeCrlf::True => Err(EolError::Lossy {
+        path: path.into(),
+        conversion: lossy,
+      }),
+      _ => Ok(Some(lossy)),
+    }
+  }
+
+  fn action(&self, path: &[u8]) -> Action {
+    let text_is_crlf = match (self.auto_crlf, self.eol) {
+      (AutoCrlf::True, _) => true,
+      (AutoCrlf::Input, _) => false,
+      (AutoCrlf::False, Eol::Crlf) => true,
+      (AutoCrlf::False, Eol::Lf) => false,
+      (AutoCrlf::False, Eol::Native) => cfg!(windows),
+    };
+    #[derive(PartialEq)]
+    enum Text {
+      Set,
+      Input,
+      Auto,
+    }
+    let text = |state: AttributeState| match state {
+      AttributeState::Set => Some(Some(Text::Set)),
+      AttributeState::Unset => Some(None),
+      AttributeState::Value(value) if value == "input" => Some(Some(Text::Input)),
+      AttributeState::Value(value) if value == "auto" => Some(Some(Text::Auto)),
+      _ => None,
+    };
+    let text = match text(self.attributes.get(path, "text")) {
+      Some(text) => Some(text),
+      None => text(self.attributes.get(path, "crlf")),
+    };
+    let text = match text {
+      Some(None) => return Action::Binary,
+      Some(Some(text)) => Some(text),
+      None => None,
+    };
+    let eol = self.attributes.get(path, "eol");
+    let eol = eol.value().map(|value| value.as_bytes());
+    match (text, eol) {
+      (Some(Text::Auto), Some(b"lf")) => Action::AutoInput,
+      (Some(Text::Auto), Some(b"crlf")) => Action::AutoCrlf,
+      (_, Some(b"lf")) | (Some(Text::Input), _) => Action::TextInput,
+      (_, Some(b"crlf")) => Action::TextCrlf,
+      (Some(Text::Set), _) if text_is_crlf => Action::TextCrlf,
+      (Some(Text::Set), _) => Action::TextInput,
+      (Some(Text::Auto), _) if text_is_crlf => Action::AutoCrlf,
+      (Some(Text::Auto), _) => Action::AutoInput,
+      (None, _) => match self.auto_crlf {
+        AutoCrlf::False => Action::Binary,
+        AutoCrlf::True => Action::AutoCrlf,
+        AutoCrlf::Input => Action::AutoInput,
+      },
+    }
+  }
+}
+
+/// Whether CRLF line endings are turned into LF on checkin
+fn converts_to_git(action: Action, stats: &Stats, staged: Option<&[u8]>) -> bool {
+  if !action.is_auto() {
+    return true;
+  }
+  // Files that had CRLF checked in already are kept that way, otherwise
+  // the next commit would change every line of them
+  let staged_crlf = staged.is_some_and(|staged| {
+    let stats = Stats::new(staged);
+    stats.crlf > 0 && !stats.is_binary()
+  });
+  !stats.is_binary() && !staged_crlf
+}
+
+/// Whether lone LF line endings are turned into CRLF on checkout
+fn converts_to_worktree(action: Action, stats: &Stats) -> bool {
+  if !action.is_crlf() || stats.lone_lf == 0 {
+    return false;
+  }
+  // Files that are only guessed to be text are left alone when they have
+  // CRLF or CR in them already, since they weren't converted on checkin
+  !action.is_auto() || (stats.lone_cr == 0 && stats.crlf == 0 && !stats.is_binary())
+}
+
+#[derive(Error, Debug)]
+/// Errors related to converting line endings
+pub enum EolError {
+  #[error("{conversion} in {path}")]
+  Lossy {
+    path: BString,
+    conversion: LossyConversion,
+  },
+}
+
+#[test]
+fn line_endings() {
+  use crate::{diff::write_tree, Blob, FileMode::NonExecutableFile, Repository};
+  use std::{fs, process::Command};
+  let config = |text: &str| {
+    let config = Config::from_bytes(format!("[core]\n{}", text)).unwrap();
+    LineEndings::from_config(&config).unwrap()
+  };
+  let crlf = b"one\r\ntwo\r\n".as_ref();
+  let lf = b"one\ntwo\n".as_ref();
+  let mixed = b"one\r\ntwo\n".as_ref();
+  let binary = b"one\r\n\0two\r\n".as_ref();
+
+  // Nothing is converted by default
+  let default = LineEndings::default();
+  assert_eq!(crlf, &default.to_git("a.txt", crlf, None)[..]);
+  assert_eq!(lf, &default.to_worktree("a.txt", lf)[..]);
+  assert_eq!(None, default.check("a.txt", mixed, None).unwrap());
+
+  let auto = config("autocrlf = true");
+  assert_eq!(AutoCrlf::True, auto.auto_crlf);
+  assert_eq!(lf, &auto.to_git("a.txt", crlf, None)[..]);
+  assert_eq!(lf, &auto.to_git("a.txt", mixed, None)[..]);
+  assert_eq!(crlf, &auto.to_worktree("a.txt", lf)[..]);
+  assert_eq!(crlf, &auto.to_worktree("a.txt", crlf)[..]);
+  // Binary files and files with CRLF in the index are left alone
+  assert_eq!(binary, &auto.to_git("a.bin", binary, None)[..]);
+  assert_eq!(crlf, &auto.to_git("a.txt", crlf, Some(crlf))[..]);
+  assert_eq!(lf, &auto.to_git("a.txt", crlf, Some(lf))[..]);
+  assert_eq!(mixed, &auto.to_worktree("a.txt", mixed)[..]);
+  assert_eq!(None, auto.check("a.txt", crlf, None).unwrap());
+  assert_eq!(
+    Some(LossyConversion::LfToCrlf),
+    auto.check("a.txt", mixed, None).unwrap()
+  );
+
+  let input = config("autocrlf = input\nsafecrlf = true");
+  assert_eq!(
+    (AutoCrlf::Input, SafeCrlf::True),
+    (input.auto_crlf, input.safe_crlf)
+  );
+  assert_eq!(lf, &input.to_git("a.txt", crlf, None)[..]);
+  assert_eq!(lf, &input.to_worktree("a.txt", lf)[..]);
+  assert!(matches!(
+    input.check("a.txt", crlf, None),
+    Err(EolError::Lossy {
+      conversion: LossyConversion::CrlfToLf,
+      ..
+    })
+  ));
+  assert_eq!(None, input.check("a.txt", lf, None).unwrap());
+  let invalid = Config::from_bytes("[core]\neol = cr").unwrap();
+  assert!(LineEndings::from_config(&invalid).is_err());
+
+  // Attributes take precedence over core.autocrlf, and text files are
+  // converted even if they look binary
+  let mut attributes = config("autocrlf = true\neol = lf");
+  attributes.attributes = Attributes::from_bytes(
+    "*.lf text eol=lf\n\
+     *.crlf eol=crlf\n\
+     *.bin -text\n\
+     *.legacy -crlf\n\
+     *.text text\n\
+     *.auto text=auto\n",
+  );
+  assert_eq!(lf, &attributes.to_worktree("a.lf", lf)[..]);
+  assert_eq!(lf, &attributes.to_git("a.lf", crlf, Some(crlf))[..]);
+  assert_eq!(crlf, &attributes.to_worktree("a.crlf", lf)[..]);
+  assert_eq!(
+    b"one\0two\r\n",
+    &attributes.to_worktree("a.crlf", b"one\0two\n")[..]
+  );
+  assert_eq!(crlf, &attributes.to_git("a.bin", crlf, None)[..]);
+  assert_eq!(lf, &attributes.to_worktree("a.bin", lf)[..]);
+  assert_eq!(crlf, &attributes.to_git("a.legacy", crlf, None)[..]);
+  // core.autocrlf decides the line endings of text files over core.eol
+  assert_eq!(crlf, &attributes.to_worktree("a.text", lf)[..]);
+  assert_eq!(binary, &attributes.to_git("a.auto", binary, None)[..]);
+  assert_eq!(crlf, &attributes.to_worktree("a.auto", lf)[..]);
+  assert_eq!(crlf, &attributes.to_worktree("a.other", lf)[..]);
+
+  // Checking out a tree converts going by its .gitattributes, and the files
+  // it wrote match the index again once converted back
+  let tmp_dir = tempdir::TempDir::new("eol_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let tree = write_tree(
+    repo.odb(),
+    &[
+      (".gitattributes", NonExecutableFile, "*.bat text eol=crlf\n"),
+      ("run.bat", NonExecutableFile, "one\ntwo\n"),
+      ("run.sh", NonExecutableFile, "one\ntwo\n"),
+    ],
+  );
+  repo.checkout_tree(&tree).unwrap();
+  let read = |name: &str| fs::read(tmp_dir.path().join(name)).unwrap();
+  assert_eq!(crlf, &read("run.bat")[..]);
+  assert_eq!(lf, &read("run.sh")[..]);
+  fs::write(tmp_dir.path().join("run.bat"), crlf).unwrap();
+  assert!(repo.worktree_status().unwrap().is_empty());
+  fs::write(tmp_dir.path().join("run.sh"), crlf).unwrap();
+  assert_eq!(1, repo.worktree_status().unwrap().len());
+
+  if !crate::transport::http::have_git() {
+    return;
+  }
+  // git hashes files the same way once they're converted
+  let files: &[(&str, &[u8])] = &[
+    ("crlf.txt", crlf),
+    ("mixed.txt", mixed),
+    ("binary.bin", binary),
+    ("lonecr.txt", b"one\rtwo\r\n"),
+  ];
+  for (name, contents) in files {
+    fs::write(tmp_dir.path().join(name), contents).unwrap();
+    for setting in ["true", "input", "false"].iter() {
+      let output = Command::new("git")
+        .args(["-c", &format!("core.autocrlf={}", setting), "hash-object"])
+        .arg(name)
+        .current_dir(tmp_dir.path())
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .output()
+        .unwrap();
+      assert!(output.status.success());
+      let config = config(&format!("autocrlf = {}", setting));
+      let blob = Blob::new(config.to_git(name, contents, None));
+      assert_eq!(
+        blob.id().to_string(),
+        output.stdout.trim().to_str().unwrap()
+      );
+    }
+  }
+}
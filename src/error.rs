@@ -0,0 +1,180 @@
+//! One error type for everything in the crate, for code that calls into
+//! several parts of it and doesn't need to tell their errors apart by type.
+//! Every error of a part converts into [`Error`] with `?`.
+
+use crate::{
+  plumbing::PlumbingError, transport::TransportError, ApplyError, ArchiveError, AttributesError,
+  BitmapError, BlameError, BundleError, CacheError, CheckoutError, CherryPickError, CloneError,
+  CommitError, CommitGraphError, ConfigError, CredentialError, DaemonError, DescribeError,
+  DiffError, EncodingError, EolError, FastExportError, FastImportError, FilterError, FsckError,
+  HeadError, IndexError, LfsError, MailmapError, MemoryError, MergeError, MultiPackIndexError,
+  NotesError, OIDError, OdbError, PackError, PktLineError, QuotaError, ReceivePackError, RefError,
+  RefspecError, RemoteError, ReplaceError, RepositoryError, RevParseError, RevWalkError,
+  ShallowError, SignatureError, SigningError, SparseError, StashError, StatusError, SubmoduleError,
+  TagError, TagsError, TreeError, UploadPackError, WorktreeError, ZlibError,
+};
+use std::{error::Error as StdError, io};
+use thiserror::Error;
+
+macro_rules! errors {
+  ($($(#[$meta:meta])* $variant:ident($error:ty),)*) => {
+    #[derive(Error, Debug)]
+    /// Any error of the crate. An error that came from cancelling an
+    /// operation with a [`CancellationToken`][crate::CancellationToken] or
+    /// a [`Progress`][crate::Progress] is always [`Error::Cancelled`],
+    /// however deep in the error of a part it was.
+    #[non_exhaustive]
+    pub enum Error {
+      #[error("the operation was cancelled")]
+      Cancelled,
+      $($(#[$meta])* #[error("{0}")] $variant(#[source] $error),)*
+    }
+
+    $($(#[$meta])*
+    impl From<$error> for Error {
+      fn from(e: $error) -> Self {
+        match was_cancelled(&e) {
+          true => Self::Cancelled,
+          false => Self::$variant(e),
+        }
+      }
+    })*
+  };
+}
+
+errors! {
+  Io(io::Error),
+  Oid(OIDError),
+  Odb(OdbError),
+  Pack(PackError),
+  MultiPackIndex(MultiPackIndexError),
+  Bitmap(BitmapError),
+  CommitGraph(CommitGraphError),
+  Zlib(ZlibError),
+  Memory(MemoryError),
+  Quota(QuotaError),
+  Tree(TreeError),
+  Commit(CommitError),
+  Tag(TagError),
+  Signature(SignatureError),
+  Encoding(EncodingError),
+  Repository(RepositoryError),
+  Config(ConfigError),
+  Ref(RefError),
+  Refspec(RefspecError),
+  RevParse(RevParseError),
+  RevWalk(RevWalkError),
+  Head(HeadError),
+  Index(IndexError),
+  Cache(CacheError),
+  Status(StatusError),
+  Checkout(CheckoutError),
+  Sparse(SparseError),
+  Attributes(AttributesError),
+  Eol(EolError),
+  Filter(FilterError),
+  Lfs(LfsError),
+  Diff(DiffError),
+  Apply(ApplyError),
+  Merge(MergeError),
+  CherryPick(CherryPickError),
+  Stash(StashError),
+  Blame(BlameError),
+  Describe(DescribeError),
+  Notes(NotesError),
+  Mailmap(MailmapError),
+  Tags(TagsError),
+  Replace(ReplaceError),
+  Shallow(ShallowError),
+  Submodule(SubmoduleError),
+  Worktree(WorktreeError),
+  Fsck(FsckError),
+  Archive(ArchiveError),
+  Bundle(BundleError),
+  FastExport(FastExportError),
+  FastImport(FastImportError),
+  Signing(SigningError),
+  Credential(CredentialError),
+  PktLine(PktLineError),
+  Transport(TransportError),
+  Remote(RemoteError),
+  Clone(CloneError),
+  UploadPack(UploadPackError),
+  ReceivePack(ReceivePackError),
+  Daemon(DaemonError),
+  Plumbing(PlumbingError),
+  #[cfg(feature = "differential")]
+  Differential(crate::DifferentialError),
+}
+
+impl Error {
+  /// Whether the error is a ref that couldn't be changed since someone
+  /// else is changing it, holding its `.lock` file. Trying again later can
+  /// work.
+  pub fn is_locked(&self) -> bool {
+    chain(self).any(|e| matches!(e.downcast_ref(), Some(RefError::Locked(_))))
+  }
+
+  /// Whether the error is an object that isn't in the repository
+  pub fn is_not_found(&self) -> bool {
+    chain(self).any(|e| matches!(e.downcast_ref(), Some(OdbError::NotFound(_))))
+  }
+
+  /// Whether the error is from data that isn't what git writes, like a
+  /// corrupt object, ref, or pack
+  pub fn is_corrupt(&self) -> bool {
+    chain(self).any(|e| {
+      matches!(e.downcast_ref(), Some(OdbError::Corrupt(..)))
+        || matches!(e.downcast_ref(), Some(RefError::Corrupt(_)))
+        || matches!(
+          e.downcast_ref(),
+          Some(PackError::Malformed(_) | PackError::Delta(_))
+        )
+    })
+  }
+}
+
+/// `e` followed by what caused it, and so on
+fn chain<'a>(
+  e: &'a (dyn StdError + 'static),
+) -> impl Iterator<Item = &'a (dyn StdError + 'static)> {
+  std::iter::successors(Some(e), |&e| e.source())
+}
+
+/// Whether `e` comes from an operation that was cancelled
+fn was_cancelled(e: &(dyn StdError + 'static)) -> bool {
+  chain(e).any(|e| matches!(e.downcast_ref(), Some(OdbError::Cancelled)))
+}
+
+#[test]
+fn error() {
+  use crate::{CancellationToken, Odb, Repository, RevWalk, OID};
+  let tmp_dir = tempdir::TempDir::new("error_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let missing = OID::hash("missing");
+  let read = || -> Result<(), Error> {
+    repo.odb().read_commit(&missing)?;
+    Ok(())
+  };
+  let e = read().unwrap_err();
+  assert!(e.is_not_found() && !e.is_corrupt() && !e.is_locked());
+  assert!(matches!(e, Error::Odb(OdbError::NotFound(oid)) if oid == missing));
+
+  let lock = repo.git_dir().join("refs/heads/master.lock");
+  std::fs::write(&lock, "").unwrap();
+  let e = Error::from(
+    repo
+      .refs()
+      .write("refs/heads/master", &missing)
+      .unwrap_err(),
+  );
+  assert!(e.is_locked());
+
+  // However deep it is, cancelling is always the same error
+  let token = CancellationToken::new();
+  token.cancel();
+  let odb = Odb::new(tmp_dir.path().join("cancelled")).with_cancellation(token);
+  let mut walk = RevWalk::new(&odb);
+  let walked = walk.next().unwrap().unwrap_err();
+  assert!(matches!(Error::from(walked), Error::Cancelled));
+}
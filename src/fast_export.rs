@@ -0,0 +1,474 @@
+//! Writing history as a `git fast-import` stream like `git fast-export`
+//! does, which most version control tools can read to take over a
+//! repository. Every blob, commit, and tag gets a mark, a number standing
+//! in for its [`OID`] in the rest of the stream, and the marks can be
+//! saved so a later export only writes what's new.
+
+use crate::{
+  diff_trees, patch::quote_path, ChangeKind, DiffError, FileMode, ObjectKind, OdbError, RefError,
+  Repository, RevWalkError, Signature, Sort, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  collections::HashMap,
+  io::{self, BufRead},
+};
+use thiserror::Error;
+
+/// Writes the history of refs of a [`Repository`] as a `git fast-import`
+/// stream
+#[derive(Debug)]
+pub struct FastExport<'a> {
+  repo: &'a Repository,
+  marks: HashMap<OID, u64>,
+  last_mark: u64,
+}
+
+impl<'a> FastExport<'a> {
+  /// Export from `repo` with no marks yet
+  pub fn new(repo: &'a Repository) -> Self {
+    Self {
+      repo,
+      marks: HashMap::new(),
+      last_mark: 0,
+    }
+  }
+
+  /// Read marks saved by [`FastExport::export_marks`], or by `git
+  /// fast-export --export-marks`, as lines of `:{mark} {oid}`. Objects with
+  /// a mark aren't written again, and commits with one are left out of the
+  /// history walked.
+  pub fn import_marks(&mut self, marks: impl BufRead) -> Result<(), FastExportError> {
+    for line in marks.split(b'\n') {
+      let line = line?;
+      if line.is_empty() {
+        continue;
+      }
+      let (mark, oid) = parse_mark(&line)
+        .ok_or_else(|| FastExportError::Malformed(format!("mark {:?}", line.as_bstr())))?;
+      self.marks.insert(oid, mark);
+      self.last_mark = self.last_mark.max(mark);
+    }
+    Ok(())
+  }
+
+  /// Write every mark given out so far as lines of `:{mark} {oid}`, sorted
+  /// by mark, for [`FastExport::import_marks`] to read
+  pub fn export_marks(&self, mut out: impl io::Write) -> io::Result<()> {
+    let mut marks: Vec<(&u64, &OID)> = self.marks.iter().map(|(oid, mark)| (mark, oid)).collect();
+    marks.sort();
+    for (mark, oid) in marks {
+      writeln!(out, ":{} {}", mark, oid)?;
+    }
+    Ok(())
+  }
+
+  /// The mark given to the object `oid`, if it has one
+  pub fn mark(&self, oid: &OID) -> Option<u64> {
+    self.marks.get(oid).copied()
+  }
+
+  /// Write the history of the refs `refs`, which are full names like
+  /// `refs/heads/main`, to `out`. Commits come before their children, each
+  /// after the blobs it adds, and tags come last. A ref whose history was
+  /// exported under another name gets a `reset` to put it in place.
+  ///
+  /// Commits are written with the changes from their first parent, so
+  /// importing the stream gives objects with the same [`OID`]s as long as
+  /// no commit is signed.
+  pub fn export(
+    &mut self,
+    refs: &[impl AsRef<[u8]>],
+    mut out: impl io::Write,
+  ) -> Result<(), FastExportError> {
+    let odb = self.repo.odb();
+    // What each ref points at once tags are peeled, with the tags on the
+    // way from the ref to it
+    let mut tips = Vec::new();
+    for name in refs {
+      let name = BString::from(name.as_ref());
+      let mut oid = self
+        .repo
+        .refs()
+        .resolve(&name)?
+        .ok_or_else(|| FastExportError::RefNotFound(name.clone()))?;
+      let mut tags = Vec::new();
+      let kind = loop {
+        let object = odb.read(&oid)?;
+        if object.kind != ObjectKind::Tag {
+          break object.kind;
+        }
+        tags.push(oid);
+        oid = *odb.read_tag(&oid)?.object();
+      };
+      // Only tags can point at blobs in the stream
+      if kind == ObjectKind::Tree || (kind == ObjectKind::Blob && tags.is_empty()) {
+        return Err(FastExportError::Unsupported(format!(
+          "{} points at a {}",
+          name, kind
+        )));
+      }
+      tips.push((name, oid, kind, tags));
+    }
+
+    // Commits are named after the first ref that reaches them
+    let mut walk = self.repo.rev_walk();
+    walk.sort(Sort::Topological);
+    for (_, oid, kind, _) in &tips {
+      if *kind == ObjectKind::Commit {
+        walk.push(oid)?;
+      }
+    }
+    for oid in self.marks.keys() {
+      if odb.read_commit(oid).is_ok() {
+        walk.hide(oid)?;
+      }
+    }
+    let commits = walk.collect::<Result<Vec<OID>, _>>()?;
+    let mut names: HashMap<OID, BString> = HashMap::new();
+    for oid in &commits {
+      // Children come first, so a commit without a name yet is a tip
+      let name = names
+        .entry(*oid)
+        .or_insert_with(|| tips.iter().find(|tip| tip.1 == *oid).unwrap().0.clone())
+        .clone();
+      for parent in odb.read_commit(oid)?.parents() {
+        names.entry(*parent).or_insert_with(|| name.clone());
+      }
+    }
+
+    for oid in commits.iter().rev() {
+      self.write_commit(oid, names[oid].as_bstr(), &mut out)?;
+    }
+    for (name, oid, kind, tags) in &tips {
+      if *kind == ObjectKind::Blob {
+        self.write_blob(oid, &mut out)?;
+      }
+      // A tag command puts the tag on the ref itself
+      if let Some(short) = name
+        .strip_prefix(b"refs/tags/")
+        .filter(|_| !tags.is_empty())
+      {
+        for tag in tags.iter().rev() {
+          self.write_tag(tag, short.as_bstr(), &mut out)?;
+        }
+        continue;
+      }
+      // Commits are already on the ref they're named after
+      let target = tags.first().unwrap_or(oid);
+      if names.get(target) != Some(name) || !commits.contains(target) {
+        writeln!(out, "reset {}", name)?;
+        writeln!(out, "from {}\n", self.committish(target))?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Give `oid` the next mark
+  fn assign(&mut self, oid: &OID) -> u64 {
+    self.last_mark += 1;
+    self.marks.insert(*oid, self.last_mark);
+    self.last_mark
+  }
+
+  /// How the stream refers to a commit or tag, by its mark if it has one
+  fn committish(&self, oid: &OID) -> String {
+    match self.mark(oid) {
+      Some(mark) => format!(":{}", mark),
+      None => oid.to_string(),
+    }
+  }
+
+  fn write_blob(&mut self, oid: &OID, out: &mut impl io::Write) -> Result<(), FastExportError> {
+    if self.marks.contains_key(oid) {
+      return Ok(());
+    }
+    let data = self.repo.odb().read(oid)?.data;
+    writeln!(out, "blob\nmark :{}", self.assign(oid))?;
+    write_data(&data, out)?;
+    Ok(())
+  }
+
+  fn write_commit(
+    &mut self,
+    oid: &OID,
+    name: &BStr,
+    out: &mut impl io::Write,
+  ) -> Result<(), FastExportError> {
+    let odb = self.repo.odb();
+    let commit = odb.read_commit(oid)?;
+    let parent_tree = match commit.parents().first() {
+      Some(parent) => Some(*odb.read_commit(parent)?.tree()),
+      None => None,
+    };
+    let changes = diff_trees(odb, parent_tree.as_ref(), Some(commit.tree()))?;
+    for change in &changes {
+      if let Some(new) = change
+        .new_file()
+        .filter(|new| new.mode != FileMode::GitLink)
+      {
+        self.write_blob(&new.oid, out)?;
+      }
+    }
+
+    // A root commit would otherwise go on top of what's already on the ref
+    if commit.parents().is_empty() {
+      writeln!(out, "reset {}", name)?;
+    }
+    writeln!(out, "commit {}\nmark :{}", name, self.assign(oid))?;
+    write_signature("author", commit.author(), out)?;
+    write_signature("committer", commit.committer(), out)?;
+    if let Some(encoding) = commit.encoding() {
+      writeln!(out, "encoding {}", encoding)?;
+    }
+    write_data(commit.raw_message(), out)?;
+    for (i, parent) in commit.parents().iter().enumerate() {
+      let kind = if i == 0 { "from" } else { "merge" };
+      writeln!(out, "{} {}", kind, self.committish(parent))?;
+    }
+    for change in changes {
+      match (change.kind(), change.new_file()) {
+        (ChangeKind::Deleted, _) | (_, None) => {
+          let path = change
+            .old_file()
+            .map_or(change.path(), |old| old.path.as_bstr());
+          out.write_all(&[b"D ", &quote_path(b"", path, false)[..], b"\n"].concat())?;
+        }
+        (_, Some(new)) => {
+          let data = match new.mode {
+            FileMode::GitLink => new.oid.to_string(),
+            _ => self.committish(&new.oid),
+          };
+          let line = format!("M {} {} ", new.mode.as_bytes().as_bstr(), data);
+          out.write_all(&[line.as_bytes(), &quote_path(b"", &new.path, false), b"\n"].concat())?;
+        }
+      }
+    }
+    writeln!(out)?;
+    Ok(())
+  }
+
+  fn write_tag(
+    &mut self,
+    oid: &OID,
+    name: &BStr,
+    out: &mut impl io::Write,
+  ) -> Result<(), FastExportError> {
+    if self.marks.contains_key(oid) {
+      return Ok(());
+    }
+    let tag = self.repo.odb().read_tag(oid)?;
+    let from = self.committish(tag.object());
+    let mark = self.assign(oid);
+    writeln!(out, "tag {}\nmark :{}\nfrom {}", name, mark, from)?;
+    if let Some(tagger) = tag.tagger() {
+      write_signature("tagger", tagger, out)?;
+    }
+    write_data(tag.message(), out)?;
+    Ok(())
+  }
+}
+
+/// Parse a line of a marks file, `:{mark} {oid}`
+pub(crate) fn parse_mark(line: &[u8]) -> Option<(u64, OID)> {
+  let line = line.strip_prefix(b":")?.to_str().ok()?;
+  let space = line.find(' ')?;
+  Some((
+    line[..space].parse().ok()?,
+    OID::from_hex(&line[space + 1..]).ok()?,
+  ))
+}
+
+fn write_signature(
+  header: &str,
+  signature: &Signature,
+  out: &mut impl io::Write,
+) -> io::Result<()> {
+  out.write_all(&[header.as_bytes(), b" ", &signature.as_bytes(), b"\n"].concat())
+}
+
+/// Write `data` with its length first, and a newline after it that the
+/// length doesn't count
+fn write_data(data: &[u8], out: &mut impl io::Write) -> io::Result<()> {
+  writeln!(out, "data {}", data.len())?;
+  out.write_all(data)?;
+  writeln!(out)
+}
+
+#[derive(Error, Debug)]
+/// Errors related to exporting history with [`FastExport`]
+pub enum FastExportError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  RevWalk(#[from] RevWalkError),
+  #[error("{0}")]
+  Diff(#[from] DiffError),
+  #[error("ref {0} doesn't exist")]
+  RefNotFound(BString),
+  #[error("malformed {0}")]
+  Malformed(String),
+  #[error("{0}")]
+  Unsupported(String),
+}
+
+#[test]
+fn export_history() {
+  use crate::{Blob, Commit, Tag, Time, Tree, TreeEntry};
+  let tmp_dir = tempdir::TempDir::new("fast_export_test").unwrap();
+  let repo = Repository::init_bare(tmp_dir.path().join("repo.git")).unwrap();
+  let odb = repo.odb();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let blob = |contents: &str| odb.write_blob(&Blob::new(contents)).unwrap();
+  let commit = |files: &[(&str, FileMode, &str)], parents: Vec<OID>, message: &str| {
+    let mut dir = Vec::new();
+    let mut root = Vec::new();
+    for (path, mode, contents) in files {
+      match path.strip_prefix("dir/") {
+        Some(name) => dir.push(TreeEntry::new(*mode, name, blob(contents))),
+        None => root.push(TreeEntry::new(*mode, *path, blob(contents))),
+      }
+    }
+    if !dir.is_empty() {
+      let dir = odb.write_tree(&Tree::new(dir)).unwrap();
+      root.push(TreeEntry::new(FileMode::Tree, "dir", dir));
+    }
+    let tree = odb.write_tree(&Tree::new(root)).unwrap();
+    let commit = Commit::new(tree, parents, signature.clone(), signature.clone(), message);
+    odb.write_commit(&commit).unwrap()
+  };
+  let file = FileMode::NonExecutableFile;
+  let first = commit(
+    &[
+      ("file.txt", file, "first\n"),
+      ("dir/run.sh", FileMode::ExecutableFile, "#!/bin/sh\n"),
+    ],
+    vec![],
+    "first\n",
+  );
+  let second = commit(
+    &[
+      ("file.txt", file, "second\n"),
+      ("with space.txt", file, "x\n"),
+    ],
+    vec![first],
+    "second\n",
+  );
+  let side = commit(
+    &[
+      ("file.txt", file, "first\n"),
+      ("dir/run.sh", FileMode::ExecutableFile, "#!/bin/sh\n"),
+      ("side.txt", file, "side\n"),
+    ],
+    vec![first],
+    "side\n",
+  );
+  let merge = commit(
+    &[
+      ("file.txt", file, "second\n"),
+      ("with space.txt", file, "x\n"),
+      ("side.txt", file, "side\n"),
+    ],
+    vec![second, side],
+    "merge\n",
+  );
+  let tag = Tag::new(second, ObjectKind::Commit, "v1", signature.clone(), "v1\n");
+  let tag = odb.write_tag(&tag).unwrap();
+  repo.refs().write("refs/heads/master", &merge).unwrap();
+  repo.refs().write("refs/heads/side", &side).unwrap();
+  repo.refs().write("refs/tags/v1", &tag).unwrap();
+  repo.refs().write("refs/tags/light", &first).unwrap();
+  let refs = [
+    "refs/heads/master",
+    "refs/heads/side",
+    "refs/tags/v1",
+    "refs/tags/light",
+  ];
+
+  let mut export = FastExport::new(&repo);
+  assert!(matches!(
+    export.export(&["refs/heads/missing"], Vec::new()),
+    Err(FastExportError::RefNotFound(_))
+  ));
+  let mut stream = Vec::new();
+  export.export(&refs, &mut stream).unwrap();
+  let start = "blob\nmark :1\ndata 10\n#!/bin/sh\n\nblob\nmark :2\ndata 6\nfirst\n\n\
+               reset refs/heads/master\ncommit refs/heads/master\nmark :3\n\
+               author A U Thor <author@example.com> 1234567890 +0000\n\
+               committer A U Thor <author@example.com> 1234567890 +0000\n\
+               data 6\nfirst\n\nM 100755 :1 dir/run.sh\nM 100644 :2 file.txt\n\n";
+  assert_eq!(start, &stream.to_str().unwrap()[..start.len()]);
+  let stream = stream.to_str().unwrap().to_owned();
+  assert!(stream.contains("D dir/run.sh\n"));
+  assert!(stream.contains(" with space.txt\n"));
+  assert!(stream.contains(&format!(
+    "from :{}\nmerge :{}\n",
+    export.mark(&second).unwrap(),
+    export.mark(&side).unwrap()
+  )));
+  assert!(stream.contains(&format!(
+    "tag v1\nmark :{}\nfrom :{}\ntagger ",
+    export.mark(&tag).unwrap(),
+    export.mark(&second).unwrap()
+  )));
+  assert!(stream.ends_with("reset refs/tags/light\nfrom :3\n\n"));
+  assert_eq!(None, export.mark(&blob("missing\n")));
+
+  // Marks carry over, so only what's new is written the next time
+  let mut marks = Vec::new();
+  export.export_marks(&mut marks).unwrap();
+  let third = commit(&[("file.txt", file, "third\n")], vec![merge], "third\n");
+  repo.refs().write("refs/heads/master", &third).unwrap();
+  let mut export = FastExport::new(&repo);
+  export.import_marks(&marks[..]).unwrap();
+  assert!(export.import_marks(&b":x y\n"[..]).is_err());
+  let mut incremental = Vec::new();
+  export.export(&refs[..1], &mut incremental).unwrap();
+  let incremental = incremental.to_str().unwrap().to_owned();
+  assert!(incremental.starts_with("blob\nmark :11\ndata 6\nthird\n\ncommit refs/heads/master\n"));
+  assert!(incremental.contains(&format!(
+    "from :{}\nM 100644 :11 file.txt\nD side.txt\nD with space.txt\n\n",
+    export.mark(&merge).unwrap()
+  )));
+
+  // git imports the stream into the same objects
+  if !crate::transport::http::have_git() {
+    return;
+  }
+  let target = tmp_dir.path().join("target.git");
+  Repository::init_bare(&target).unwrap();
+  let mut child = std::process::Command::new("git")
+    .args(["fast-import", "--quiet"])
+    .current_dir(&target)
+    .env("GIT_CONFIG_NOSYSTEM", "1")
+    .env("GIT_CONFIG_GLOBAL", "/dev/null")
+    .stdin(std::process::Stdio::piped())
+    .spawn()
+    .unwrap();
+  let mut stdin = child.stdin.take().unwrap();
+  io::Write::write_all(&mut stdin, [stream, incremental].concat().as_bytes()).unwrap();
+  drop(stdin);
+  assert!(child.wait().unwrap().success());
+  let target = Repository::open(&target).unwrap();
+  assert_eq!(
+    Some(third),
+    target.refs().resolve("refs/heads/master").unwrap()
+  );
+  assert_eq!(
+    Some(side),
+    target.refs().resolve("refs/heads/side").unwrap()
+  );
+  assert_eq!(Some(tag), target.refs().resolve("refs/tags/v1").unwrap());
+  assert_eq!(
+    Some(first),
+    target.refs().resolve("refs/tags/light").unwrap()
+  );
+}
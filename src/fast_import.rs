@@ -0,0 +1,809 @@
+//! Reading a `git fast-import` stream like the ones [`FastExport`] and
+//! `git fast-export` write, storing the objects it describes and updating
+//! the refs it names once it's done. Branches are kept as a list of files
+//! while the stream is read, so commits only say what changed, and trees
+//! are written for each commit from that list.
+//!
+//! [`FastExport`]: crate::FastExport
+
+use crate::{
+  fast_export::parse_mark, patch::unquote_path, FileMode, ObjectKind, OdbError, RawObject,
+  RefError, Repository, RevWalkError, Signature, SignatureError, Tree, TreeEntry, OID,
+};
+use bstr::{BString, ByteSlice};
+use std::{
+  collections::{BTreeMap, HashMap},
+  io::{self, BufRead},
+};
+use thiserror::Error;
+
+/// The files of a tree by their full paths
+type Files = BTreeMap<Vec<u8>, (FileMode, OID)>;
+
+#[derive(Debug, Default)]
+struct Branch {
+  tip: Option<OID>,
+  files: Files,
+}
+
+/// Reads `git fast-import` streams into a [`Repository`]
+#[derive(Debug)]
+pub struct FastImport<'a> {
+  repo: &'a Repository,
+  force: bool,
+  marks: HashMap<u64, OID>,
+  branches: BTreeMap<BString, Branch>,
+  tags: BTreeMap<BString, OID>,
+}
+
+impl<'a> FastImport<'a> {
+  /// Import into `repo` with no marks yet
+  pub fn new(repo: &'a Repository) -> Self {
+    Self {
+      repo,
+      force: false,
+      marks: HashMap::new(),
+      branches: BTreeMap::new(),
+      tags: BTreeMap::new(),
+    }
+  }
+
+  /// Whether branches can be moved to commits that don't have the commit
+  /// they were at in their history, like `git fast-import --force`. Off by
+  /// default.
+  pub fn force(mut self, force: bool) -> Self {
+    self.force = force;
+    self
+  }
+
+  /// Read marks as lines of `:{mark} {oid}`, like those saved by
+  /// [`FastImport::export_marks`], so the stream can refer to objects
+  /// imported before
+  pub fn import_marks(&mut self, marks: impl BufRead) -> Result<(), FastImportError> {
+    for line in marks.split(b'\n') {
+      let line = line?;
+      if line.is_empty() {
+        continue;
+      }
+      let (mark, oid) = parse_mark(&line).ok_or_else(|| FastImportError::Malformed {
+        line: 0,
+        reason: format!("mark {:?}", line.as_bstr()),
+      })?;
+      self.marks.insert(mark, oid);
+    }
+    Ok(())
+  }
+
+  /// Write every mark set so far as lines of `:{mark} {oid}`, sorted by
+  /// mark
+  pub fn export_marks(&self, mut out: impl io::Write) -> io::Result<()> {
+    let mut marks: Vec<(&u64, &OID)> = self.marks.iter().collect();
+    marks.sort();
+    for (mark, oid) in marks {
+      writeln!(out, ":{} {}", mark, oid)?;
+    }
+    Ok(())
+  }
+
+  /// The object given the mark `mark`
+  pub fn mark(&self, mark: u64) -> Option<OID> {
+    self.marks.get(&mark).copied()
+  }
+
+  /// Store the objects described by the stream `input`, then update the
+  /// refs it names, returning those that moved with where they are now.
+  /// Refs are also updated at each `checkpoint`.
+  ///
+  /// Dates have to be in the `raw` format, and the commands that ask for
+  /// answers, like `cat-blob` and `ls`, or add notes aren't supported.
+  pub fn import(&mut self, input: impl BufRead) -> Result<Vec<(BString, OID)>, FastImportError> {
+    let mut stream = Stream {
+      input,
+      peeked: None,
+      line: 0,
+    };
+    let mut updated = Vec::new();
+    let mut needs_done = false;
+    let mut done = false;
+    while let Some(line) = stream.next_line()? {
+      let (command, argument) = match line.find_byte(b' ') {
+        Some(space) => (&line[..space], &line[space + 1..]),
+        None => (&line[..], &b""[..]),
+      };
+      match command {
+        b"" => {}
+        b"blob" => self.blob(&mut stream)?,
+        b"commit" => self.commit(argument.into(), &mut stream)?,
+        b"tag" => self.tag(argument.into(), &mut stream)?,
+        b"reset" => self.reset(argument.into(), &mut stream)?,
+        b"checkpoint" => updated.extend(self.update_refs()?),
+        b"progress" => {}
+        b"feature" => match argument {
+          b"done" => needs_done = true,
+          b"force" => self.force = true,
+          b"date-format=raw" => {}
+          _ => {
+            return Err(FastImportError::Unsupported(format!(
+              "feature {}",
+              argument.as_bstr()
+            )))
+          }
+        },
+        // Options for other tools can be left out, but not ones for git
+        b"option" if !argument.starts_with(b"git ") => {}
+        b"done" => {
+          done = true;
+          break;
+        }
+        b"option" | b"cat-blob" | b"ls" | b"get-mark" | b"alias" => {
+          return Err(FastImportError::Unsupported(format!("{}", line.as_bstr())))
+        }
+        _ => return Err(stream.malformed(format!("unknown command {:?}", line.as_bstr()))),
+      }
+    }
+    if needs_done && !done {
+      return Err(stream.malformed("stream ended without done".into()));
+    }
+    updated.extend(self.update_refs()?);
+    Ok(updated)
+  }
+
+  fn blob<R: BufRead>(&mut self, stream: &mut Stream<R>) -> Result<(), FastImportError> {
+    let mark = stream.mark()?;
+    stream.optional(b"original-oid ")?;
+    let data = stream.expect_data()?;
+    let oid = self.write(ObjectKind::Blob, data)?;
+    if let Some(mark) = mark {
+      self.marks.insert(mark, oid);
+    }
+    Ok(())
+  }
+
+  fn commit<R: BufRead>(
+    &mut self,
+    name: BString,
+    stream: &mut Stream<R>,
+  ) -> Result<(), FastImportError> {
+    let mark = stream.mark()?;
+    stream.optional(b"original-oid ")?;
+    let author = stream.optional(b"author ")?;
+    let committer = stream
+      .optional(b"committer ")?
+      .ok_or_else(|| stream.malformed("commit without a committer".into()))?;
+    if let Some(author) = &author {
+      Signature::parse(author)?;
+    }
+    Signature::parse(&committer)?;
+    let encoding = stream.optional(b"encoding ")?;
+    let message = stream.expect_data()?;
+
+    // The branch goes on from where it was unless it says where to go from
+    let mut parents = Vec::new();
+    let mut branch = match self.branches.remove(&name) {
+      Some(branch) => branch,
+      None => match self.repo.refs().resolve(&name)? {
+        Some(tip) => self.branch_at(stream, Some(tip))?,
+        None => Branch::default(),
+      },
+    };
+    if let Some(from) = stream.optional(b"from ")? {
+      let from = self.committish(stream, &from)?;
+      if branch.tip != from {
+        branch = self.branch_at(stream, from)?;
+      }
+    }
+    parents.extend(branch.tip);
+    while let Some(merge) = stream.optional(b"merge ")? {
+      parents.extend(self.committish(stream, &merge)?);
+    }
+
+    while let Some(line) = stream.peek()?.map(<[u8]>::to_vec) {
+      let files = &mut branch.files;
+      if line == b"deleteall" {
+        files.clear();
+      } else if let Some(line) = line.strip_prefix(b"M ") {
+        let mut fields = line.splitn(3, |&c| c == b' ');
+        let (mode, data, path) = match (fields.next(), fields.next(), fields.next()) {
+          (Some(mode), Some(data), Some(path)) => (mode, data, path),
+          _ => return Err(stream.malformed(format!("M {:?}", line.as_bstr()))),
+        };
+        let mode = match mode {
+          b"644" | b"100644" => FileMode::NonExecutableFile,
+          b"755" | b"100755" => FileMode::ExecutableFile,
+          b"120000" => FileMode::SymbolicLink,
+          b"160000" => FileMode::GitLink,
+          b"040000" | b"40000" => FileMode::Tree,
+          _ => return Err(stream.malformed(format!("mode {:?}", mode.as_bstr()))),
+        };
+        let path = stream.path(path)?.0;
+        stream.next_line()?;
+        let oid = match data {
+          b"inline" => {
+            let data = stream.expect_data()?;
+            self.write(ObjectKind::Blob, data)?
+          }
+          data => self.dataref(stream, data)?,
+        };
+        delete(files, &path);
+        // A file in the way of the path goes
+        for i in (0..path.len()).filter(|&i| path[i] == b'/') {
+          files.remove(&path[..i]);
+        }
+        match mode {
+          FileMode::Tree => self.flatten(&oid, &path, files)?,
+          mode => {
+            files.insert(path, (mode, oid));
+          }
+        }
+        continue;
+      } else if let Some(path) = line.strip_prefix(b"D ") {
+        let path = stream.path(path)?.0;
+        delete(files, &path);
+      } else if line.starts_with(b"C ") || line.starts_with(b"R ") {
+        let rename = line.starts_with(b"R ");
+        let line = &line[2..];
+        let (from, used) = stream.path_until_space(line)?;
+        let to = match line.get(used) {
+          Some(b' ') => stream.path(&line[used + 1..])?.0,
+          _ => return Err(stream.malformed(format!("copy {:?}", line.as_bstr()))),
+        };
+        let copied: Vec<(Vec<u8>, (FileMode, OID))> = files
+          .iter()
+          .filter_map(|(path, entry)| {
+            let rest = path.strip_prefix(&from[..])?;
+            (rest.is_empty() || rest.starts_with(b"/")).then(|| ([&to[..], rest].concat(), *entry))
+          })
+          .collect();
+        if copied.is_empty() {
+          return Err(stream.malformed(format!("{:?} doesn't exist", from.as_bstr())));
+        }
+        if rename {
+          delete(files, &from);
+        }
+        delete(files, &to);
+        files.extend(copied);
+      } else if line.starts_with(b"N ") {
+        return Err(FastImportError::Unsupported("notes".into()));
+      } else {
+        break;
+      }
+      stream.next_line()?;
+    }
+
+    let tree = self.write_tree(&branch.files, b"")?;
+    let mut bytes = format!("tree {}\n", tree).into_bytes();
+    for parent in &parents {
+      bytes.extend_from_slice(format!("parent {}\n", parent).as_bytes());
+    }
+    let author = author.as_ref().unwrap_or(&committer);
+    bytes.extend_from_slice(&[&b"author "[..], author, b"\n"].concat());
+    bytes.extend_from_slice(&[&b"committer "[..], &committer, b"\n"].concat());
+    if let Some(encoding) = &encoding {
+      bytes.extend_from_slice(&[&b"encoding "[..], encoding, b"\n"].concat());
+    }
+    bytes.push(b'\n');
+    bytes.extend_from_slice(&message);
+    let oid = self.write(ObjectKind::Commit, bytes)?;
+    if let Some(mark) = mark {
+      self.marks.insert(mark, oid);
+    }
+    branch.tip = Some(oid);
+    self.branches.insert(name, branch);
+    Ok(())
+  }
+
+  fn tag<R: BufRead>(
+    &mut self,
+    name: BString,
+    stream: &mut Stream<R>,
+  ) -> Result<(), FastImportError> {
+    let mark = stream.mark()?;
+    let from = stream
+      .optional(b"from ")?
+      .ok_or_else(|| stream.malformed("tag without from".into()))?;
+    let object = self
+      .committish(stream, &from)?
+      .ok_or_else(|| stream.malformed("tag of nothing".into()))?;
+    let kind = self.repo.odb().read(&object)?.kind;
+    stream.optional(b"original-oid ")?;
+    let tagger = stream.optional(b"tagger ")?;
+    if let Some(tagger) = &tagger {
+      Signature::parse(tagger)?;
+    }
+    let message = stream.expect_data()?;
+    let mut bytes = format!("object {}\ntype {}\ntag {}\n", object, kind, name).into_bytes();
+    if let Some(tagger) = tagger {
+      bytes.extend_from_slice(&[&b"tagger "[..], &tagger, b"\n"].concat());
+    }
+    bytes.push(b'\n');
+    bytes.extend_from_slice(&message);
+    let oid = self.write(ObjectKind::Tag, bytes)?;
+    if let Some(mark) = mark {
+      self.marks.insert(mark, oid);
+    }
+    self.tags.insert(name, oid);
+    Ok(())
+  }
+
+  fn reset<R: BufRead>(
+    &mut self,
+    name: BString,
+    stream: &mut Stream<R>,
+  ) -> Result<(), FastImportError> {
+    let tip = match stream.optional(b"from ")? {
+      Some(from) => self.committish(stream, &from)?,
+      None => None,
+    };
+    let branch = self.branch_at(stream, tip)?;
+    self.branches.insert(name, branch);
+    Ok(())
+  }
+
+  /// A branch at the commit `tip`, with the files of its tree
+  fn branch_at<R: BufRead>(
+    &self,
+    stream: &Stream<R>,
+    tip: Option<OID>,
+  ) -> Result<Branch, FastImportError> {
+    let mut files = Files::new();
+    if let Some(tip) = tip {
+      let commit = match self.repo.odb().read_commit(&tip) {
+        Ok(commit) => commit,
+        Err(OdbError::WrongKind { .. }) => {
+          return Err(stream.malformed(format!("{} is not a commit", tip)))
+        }
+        Err(e) => return Err(e.into()),
+      };
+      self.flatten(commit.tree(), b"", &mut files)?;
+    }
+    Ok(Branch { tip, files })
+  }
+
+  /// What a `from` or `merge` points at, which is a mark, an object id, or
+  /// the name of a branch. The zero object id means no commit at all.
+  fn committish<R: BufRead>(
+    &self,
+    stream: &Stream<R>,
+    committish: &[u8],
+  ) -> Result<Option<OID>, FastImportError> {
+    let is_oid = committish
+      .to_str()
+      .is_ok_and(|hex| OID::from_hex(hex).is_ok());
+    if committish.starts_with(b":") || is_oid {
+      let oid = self.dataref(stream, committish)?;
+      return Ok(Some(oid).filter(|oid| *oid.as_bytes() != [0; 20]));
+    }
+    if let Some(branch) = self.branches.get(committish.as_bstr()) {
+      return Ok(branch.tip);
+    }
+    match self.repo.refs().resolve(committish)? {
+      Some(oid) => Ok(Some(oid)),
+      None => Err(stream.malformed(format!("no branch {:?}", committish.as_bstr()))),
+    }
+  }
+
+  /// The object a mark or an object id stands for
+  fn dataref<R: BufRead>(&self, stream: &Stream<R>, data: &[u8]) -> Result<OID, FastImportError> {
+    if let Some(mark) = data.strip_prefix(b":") {
+      let mark = mark
+        .to_str()
+        .ok()
+        .and_then(|mark| mark.parse().ok())
+        .ok_or_else(|| stream.malformed(format!("mark {:?}", data.as_bstr())))?;
+      return self.mark(mark).ok_or(FastImportError::UnknownMark(mark));
+    }
+    data
+      .to_str()
+      .ok()
+      .and_then(|hex| OID::from_hex(hex).ok())
+      .ok_or_else(|| stream.malformed(format!("object id {:?}", data.as_bstr())))
+  }
+
+  /// Add the files of the tree `oid` to `files`, under `prefix`
+  fn flatten(&self, oid: &OID, prefix: &[u8], files: &mut Files) -> Result<(), FastImportError> {
+    for entry in self.repo.odb().read_tree(oid)?.entries() {
+      let path = match prefix {
+        b"" => entry.name().to_vec(),
+        prefix => [prefix, b"/", entry.name()].concat(),
+      };
+      match entry.mode() {
+        FileMode::Tree => self.flatten(entry.oid(), &path, files)?,
+        mode => {
+          files.insert(path, (mode, *entry.oid()));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Write the tree of every file in `files`, whose paths are all under
+  /// `prefix`
+  fn write_tree(&self, files: &Files, prefix: &[u8]) -> Result<OID, FastImportError> {
+    let mut entries = Vec::new();
+    let mut dirs: BTreeMap<&[u8], Files> = BTreeMap::new();
+    for (path, (mode, oid)) in files {
+      let path = &path[prefix.len()..];
+      match path.find_byte(b'/') {
+        Some(slash) => {
+          dirs
+            .entry(&path[..slash])
+            .or_default()
+            .insert([prefix, path].concat(), (*mode, *oid));
+        }
+        None => entries.push(TreeEntry::new(*mode, path, *oid)),
+      }
+    }
+    for (name, files) in dirs {
+      let prefix = [prefix, name, b"/"].concat();
+      let oid = self.write_tree(&files, &prefix)?;
+      entries.push(TreeEntry::new(FileMode::Tree, name, oid));
+    }
+    Ok(self.repo.odb().write_tree(&Tree::new(entries))?)
+  }
+
+  fn write(&self, kind: ObjectKind, data: Vec<u8>) -> Result<OID, FastImportError> {
+    Ok(self.repo.odb().write(&RawObject::new(kind, data))?)
+  }
+
+  /// Point the refs of the branches and tags at what the stream put on
+  /// them, returning those that moved
+  fn update_refs(&mut self) -> Result<Vec<(BString, OID)>, FastImportError> {
+    let refs = self.repo.refs();
+    let mut transaction = refs.transaction();
+    let mut updated = Vec::new();
+    let branches = self
+      .branches
+      .iter()
+      .filter_map(|(name, branch)| Some((name.clone(), branch.tip?)));
+    let tags = self
+      .tags
+      .iter()
+      .map(|(name, oid)| ([&b"refs/tags/"[..], name].concat().into(), *oid));
+    for (name, new) in branches.chain(tags) {
+      let old = refs.resolve(&name)?;
+      if old == Some(new) {
+        continue;
+      }
+      if let Some(old) = old.filter(|_| !self.force && name.starts_with(b"refs/heads/")) {
+        let mut walk = self.repo.rev_walk();
+        walk.push(&old)?.hide(&new)?;
+        if walk.next().is_some() {
+          return Err(FastImportError::NotFastForward(name));
+        }
+      }
+      transaction.update(name.clone(), old, Some(new));
+      updated.push((name, new));
+    }
+    transaction.commit()?;
+    Ok(updated)
+  }
+}
+
+/// Remove `path` from `files`, with every file under it if it's a
+/// directory
+fn delete(files: &mut Files, path: &[u8]) {
+  files.remove(path);
+  let dir = [path, b"/"].concat();
+  files.retain(|file, _| !file.starts_with(&dir));
+}
+
+/// The lines of a stream, with the one after the current command peeked
+/// at to tell whether it belongs to the command
+struct Stream<R> {
+  input: R,
+  peeked: Option<Vec<u8>>,
+  line: usize,
+}
+
+impl<R: BufRead> Stream<R> {
+  fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+    loop {
+      let mut line = Vec::new();
+      if self.input.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+      }
+      self.line += 1;
+      if line.last() == Some(&b'\n') {
+        line.pop();
+      }
+      if !line.starts_with(b"#") {
+        return Ok(Some(line));
+      }
+    }
+  }
+
+  fn next_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+    match self.peeked.take() {
+      Some(line) => Ok(Some(line)),
+      None => self.read_line(),
+    }
+  }
+
+  fn peek(&mut self) -> io::Result<Option<&[u8]>> {
+    if self.peeked.is_none() {
+      self.peeked = self.read_line()?;
+    }
+    Ok(self.peeked.as_deref())
+  }
+
+  /// The rest of the next line if it starts with `prefix`, which is then
+  /// taken
+  fn optional(&mut self, prefix: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    match self.peek()? {
+      Some(line) if line.starts_with(prefix) => {
+        let line = self.next_line()?.unwrap();
+        Ok(Some(line[prefix.len()..].to_vec()))
+      }
+      _ => Ok(None),
+    }
+  }
+
+  fn mark(&mut self) -> Result<Option<u64>, FastImportError> {
+    match self.optional(b"mark :")? {
+      Some(mark) => match mark.to_str().ok().and_then(|mark| mark.parse().ok()) {
+        Some(mark) => Ok(Some(mark)),
+        None => Err(self.malformed(format!("mark :{:?}", mark.as_bstr()))),
+      },
+      None => Ok(None),
+    }
+  }
+
+  /// The contents of a `data` command, either `data {len}` followed by
+  /// that many bytes or `data <<{delimiter}` followed by lines up to the
+  /// delimiter
+  fn expect_data(&mut self) -> Result<Vec<u8>, FastImportError> {
+    let spec = self
+      .optional(b"data ")?
+      .ok_or_else(|| self.malformed("expected data".into()))?;
+    if let Some(delimiter) = spec.strip_prefix(b"<<") {
+      let mut data = Vec::new();
+      loop {
+        match self.read_line()? {
+          Some(line) if line == delimiter => return Ok(data),
+          Some(line) => {
+            data.extend_from_slice(&line);
+            data.push(b'\n');
+          }
+          None => return Err(self.malformed("data doesn't end".into())),
+        }
+      }
+    }
+    let len = spec
+      .to_str()
+      .ok()
+      .and_then(|len| len.parse::<usize>().ok())
+      .ok_or_else(|| self.malformed(format!("data {:?}", spec.as_bstr())))?;
+    let mut data = vec![0; len];
+    self.input.read_exact(&mut data)?;
+    self.line += data.iter().filter(|&&c| c == b'\n').count();
+    // The newline after the data can be left out
+    if self.input.fill_buf()?.first() == Some(&b'\n') {
+      self.input.consume(1);
+      self.line += 1;
+    }
+    Ok(data)
+  }
+
+  /// A path that's either quoted or goes to the end of `line`
+  fn path(&self, line: &[u8]) -> Result<(Vec<u8>, usize), FastImportError> {
+    match line.first() {
+      Some(b'"') => {
+        unquote_path(line).ok_or_else(|| self.malformed(format!("path {:?}", line.as_bstr())))
+      }
+      Some(_) => Ok((line.to_vec(), line.len())),
+      None => Err(self.malformed("empty path".into())),
+    }
+  }
+
+  /// A path that's either quoted or goes up to the first space of `line`
+  fn path_until_space(&self, line: &[u8]) -> Result<(Vec<u8>, usize), FastImportError> {
+    match line.find_byte(b' ') {
+      Some(space) if !line.starts_with(b"\"") => Ok((line[..space].to_vec(), space)),
+      _ => self.path(line),
+    }
+  }
+
+  fn malformed(&self, reason: String) -> FastImportError {
+    FastImportError::Malformed {
+      line: self.line,
+      reason,
+    }
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to importing a stream with [`FastImport`]
+pub enum FastImportError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  RevWalk(#[from] RevWalkError),
+  #[error("{0}")]
+  Signature(#[from] SignatureError),
+  #[error("malformed stream at line {line}: {reason}")]
+  Malformed { line: usize, reason: String },
+  #[error("mark :{0} was never set")]
+  UnknownMark(u64),
+  #[error("{0} isn't supported")]
+  Unsupported(String),
+  #[error("not updating {0}, its new commit doesn't have the old one in its history")]
+  NotFastForward(BString),
+}
+
+#[test]
+fn import_streams() {
+  use crate::{Blob, Commit, FastExport, Tag, Time};
+  let tmp_dir = tempdir::TempDir::new("fast_import_test").unwrap();
+  let source = Repository::init_bare(tmp_dir.path().join("source.git")).unwrap();
+  let odb = source.odb();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 3600),
+  );
+  let commit = |files: &[(&str, &str)], parents: Vec<OID>, message: &str| {
+    let mut entries = Vec::new();
+    for (name, contents) in files {
+      let blob = odb.write_blob(&Blob::new(*contents)).unwrap();
+      entries.push(TreeEntry::new(FileMode::NonExecutableFile, *name, blob));
+    }
+    let dir = odb.write_tree(&Tree::new(entries.clone())).unwrap();
+    entries.push(TreeEntry::new(FileMode::Tree, "dir", dir));
+    let tree = odb.write_tree(&Tree::new(entries)).unwrap();
+    let commit = Commit::new(tree, parents, signature.clone(), signature.clone(), message);
+    odb.write_commit(&commit).unwrap()
+  };
+  let first = commit(&[("a.txt", "a\n")], vec![], "first\n");
+  let second = commit(
+    &[("a.txt", "b\n"), ("b c.txt", "c\n")],
+    vec![first],
+    "second\n",
+  );
+  let side = commit(&[("side.txt", "side\n")], vec![first], "side\n");
+  let merge = commit(
+    &[("a.txt", "b\n"), ("side.txt", "side\n")],
+    vec![second, side],
+    "merge\n",
+  );
+  let tag = Tag::new(merge, ObjectKind::Commit, "v1", signature.clone(), "v1\n");
+  let tag = odb.write_tag(&tag).unwrap();
+  source.refs().write("refs/heads/master", &merge).unwrap();
+  source.refs().write("refs/heads/side", &side).unwrap();
+  source.refs().write("refs/tags/v1", &tag).unwrap();
+  let refs = ["refs/heads/master", "refs/heads/side", "refs/tags/v1"];
+
+  // What's exported imports into the same objects
+  let mut stream = Vec::new();
+  let mut export = FastExport::new(&source);
+  export.export(&refs, &mut stream).unwrap();
+  let target = Repository::init_bare(tmp_dir.path().join("target.git")).unwrap();
+  let mut import = FastImport::new(&target);
+  let updated = import.import(&stream[..]).unwrap();
+  assert_eq!(3, updated.len());
+  for name in refs {
+    let expected = source.refs().resolve(name).unwrap();
+    assert_eq!(expected, target.refs().resolve(name).unwrap(), "{}", name);
+  }
+  let first_mark = export.mark(&first).unwrap();
+  assert_eq!(Some(first), import.mark(first_mark));
+  let mut marks = Vec::new();
+  import.export_marks(&mut marks).unwrap();
+  assert!(marks.starts_with(b":1 "));
+  assert!(import.import(&stream[..]).unwrap().is_empty());
+
+  // A stream written by hand, going on from what the last one imported
+  let mut import = FastImport::new(&target);
+  import.import_marks(&marks[..]).unwrap();
+  let stream = format!(
+    "# a comment\nfeature done\nblob\nmark :100\ndata <<EOF\nheredoc\nEOF\n\
+     commit refs/heads/master\nmark :101\ncommitter C O Mitter <c@example.com> 1234567890 +0000\n\
+     data 6\nmoved\nfrom :{merge}\nR a.txt \"new\\ta.txt\"\nC dir copied\nD side.txt\n\
+     M 100755 :100 dir/run.sh\nM 644 inline dir\ndata 5\nfile\n\n\
+     reset refs/heads/root\ncommit refs/heads/root\ncommitter C <c@example.com> 1 +0000\n\
+     data 5\nroot\nmerge refs/heads/side\nM 644 :100 only.txt\n\n\
+     commit refs/heads/root\ncommitter C <c@example.com> 2 +0000\ndata 6\nempty\ndeleteall\n\n\
+     tag v2\nfrom :101\ndata 3\nv2\ncheckpoint\nprogress now\ndone\n",
+    merge = export.mark(&merge).unwrap()
+  );
+  let updated = import.import(stream.as_bytes()).unwrap();
+  let names: Vec<&[u8]> = updated.iter().map(|(name, _)| name.as_bytes()).collect();
+  assert_eq!(
+    vec![
+      &b"refs/heads/master"[..],
+      b"refs/heads/root",
+      b"refs/tags/v2"
+    ],
+    names
+  );
+  let moved = target
+    .odb()
+    .read_commit(&import.mark(101).unwrap())
+    .unwrap();
+  assert_eq!(&[merge], moved.parents());
+  let tree = target.odb().read_tree(moved.tree()).unwrap();
+  let names: Vec<&[u8]> = tree.entries().iter().map(|e| e.name().as_bytes()).collect();
+  assert_eq!(vec![&b"copied"[..], b"dir", b"new\ta.txt"], names);
+  assert_eq!(FileMode::NonExecutableFile, tree.get("dir").unwrap().mode());
+  let copied = target
+    .odb()
+    .read_tree(tree.get("copied").unwrap().oid())
+    .unwrap();
+  assert!(copied.get("side.txt").is_some() && copied.get("a.txt").is_some());
+  let root = target.refs().resolve("refs/heads/root").unwrap().unwrap();
+  let root = target.odb().read_commit(&root).unwrap();
+  assert!(target.odb().read_tree(root.tree()).unwrap().is_empty());
+  let first_root = target.odb().read_commit(&root.parents()[0]).unwrap();
+  assert_eq!(&[side], first_root.parents());
+  let v2 = target.refs().resolve("refs/tags/v2").unwrap().unwrap();
+  assert_eq!(
+    import.mark(101).as_ref(),
+    Some(target.odb().read_tag(&v2).unwrap().object())
+  );
+
+  // Bad streams
+  let committer = "committer C <c@example.com> 1 +0000";
+  let cases = [
+    format!(
+      "commit refs/heads/master\n{}\ndata 1\nx\nfrom :999\n",
+      committer
+    ),
+    "commit refs/heads/x\ndata 1\nx\n".to_string(),
+    "feature done\nblob\ndata 0\n".to_string(),
+    "cat-blob :1\n".to_string(),
+    "bogus\n".to_string(),
+    format!("commit refs/heads/x\n{}\ndata 5\nx\n", committer),
+  ];
+  for case in &cases {
+    let error = FastImport::new(&target)
+      .import(case.as_bytes())
+      .unwrap_err();
+    assert!(
+      matches!(
+        error,
+        FastImportError::UnknownMark(999)
+          | FastImportError::Malformed { .. }
+          | FastImportError::Unsupported(_)
+          | FastImportError::Io(_)
+      ),
+      "{:?}",
+      error
+    );
+  }
+  // Moving a branch off its history needs force
+  let rewind = format!("reset refs/heads/master\nfrom {}\n", first);
+  assert!(matches!(
+    FastImport::new(&target).import(rewind.as_bytes()),
+    Err(FastImportError::NotFastForward(_))
+  ));
+  FastImport::new(&target)
+    .force(true)
+    .import(rewind.as_bytes())
+    .unwrap();
+  assert_eq!(
+    Some(first),
+    target.refs().resolve("refs/heads/master").unwrap()
+  );
+
+  // Streams from git import into the same objects too
+  if !crate::transport::http::have_git() {
+    return;
+  }
+  let output = std::process::Command::new("git")
+    .args(["fast-export", "--all", "--reencode=no"])
+    .current_dir(tmp_dir.path().join("source.git"))
+    .env("GIT_CONFIG_NOSYSTEM", "1")
+    .env("GIT_CONFIG_GLOBAL", "/dev/null")
+    .output()
+    .unwrap();
+  assert!(output.status.success(), "{}", output.stderr.as_bstr());
+  let other = Repository::init_bare(tmp_dir.path().join("other.git")).unwrap();
+  FastImport::new(&other).import(&output.stdout[..]).unwrap();
+  for name in refs {
+    let expected = source.refs().resolve(name).unwrap();
+    assert_eq!(expected, other.refs().resolve(name).unwrap(), "{}", name);
+  }
+}
@@ -0,0 +1,530 @@
+//! Filter drivers, which are commands that change the contents of files as
+//! they're checked in and out. The `filter` attribute names the driver a
+//! path uses, and the config says what it runs:
+//!
+//! ```text
+//! [filter "lfs"]
+//!     clean = git-lfs clean -- %f
+//!     smudge = git-lfs smudge -- %f
+//!     process = git-lfs filter-process
+//!     required = true
+//! ```
+//!
+//! `clean` is run on a file with its contents on stdin to get what its blob
+//! should have, and `smudge` is run on a blob to get the file, with `%f`
+//! replaced by the path. A `process` is started once and then asked to
+//! filter every file over pkt-lines, which is how Git LFS does it as it
+//! would be too slow to start a command for every file. When a driver
+//! fails the file is used as it is, unless the driver is `required`.
+
+use crate::{Attributes, Config, ConfigError, Packet, PktLineError, PktLineReader, PktLineWriter};
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  borrow::Cow,
+  collections::{BTreeMap, HashMap},
+  io::{self, Write},
+  path::PathBuf,
+  process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+  sync::{Arc, Mutex},
+  thread,
+};
+use thiserror::Error;
+
+/// The commands of a filter driver, from `filter.<name>.*`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FilterDriver {
+  /// The command files are run through when they're checked in
+  pub clean: Option<String>,
+  /// The command blobs are run through when they're checked out
+  pub smudge: Option<String>,
+  /// The long running command that does both for every file, which is used
+  /// over `clean` and `smudge` when it's set
+  pub process: Option<String>,
+  /// Whether a file has to go through the driver, so failing is an error
+  /// instead of leaving the file as it is
+  pub required: bool,
+}
+
+/// Which way a file is going through a filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FilterDirection {
+  /// From the working tree to a blob
+  Clean,
+  /// From a blob to the working tree
+  Smudge,
+}
+
+impl FilterDirection {
+  fn as_str(self) -> &'static str {
+    match self {
+      Self::Clean => "clean",
+      Self::Smudge => "smudge",
+    }
+  }
+}
+
+/// The filter drivers of a repository. The `process` commands that were
+/// started are kept running for as long as the [`Filters`] or any of its
+/// clones are around.
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+  drivers: BTreeMap<BString, FilterDriver>,
+  work_dir: Option<PathBuf>,
+  processes: Arc<Mutex<HashMap<BString, FilterProcess>>>,
+}
+
+impl PartialEq for Filters {
+  fn eq(&self, other: &Self) -> bool {
+    self.drivers == other.drivers && self.work_dir == other.work_dir
+  }
+}
+
+impl Eq for Filters {}
+
+impl Filters {
+  /// Create [`Filters`] from the `filter.<name>.*` settings in a
+  /// [`Config`]. Commands are run in the current directory until
+  /// [`Filters::set_work_dir`] says otherwise.
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let mut drivers: BTreeMap<BString, FilterDriver> = BTreeMap::new();
+    for entry in config.entries() {
+      let name = match (entry.section() == "filter", entry.subsection()) {
+        (true, Some(name)) => name,
+        _ => continue,
+      };
+      let driver = drivers.entry(name.into()).or_default();
+      let command = || entry.value().map(|value| value.to_str_lossy().into_owned());
+      match entry.name().as_bytes() {
+        b"clean" => driver.clean = command(),
+        b"smudge" => driver.smudge = command(),
+        b"process" => driver.process = command(),
+        b"required" => {
+          driver.required = config.get_bool(&entry.key())?.unwrap_or(false);
+        }
+        _ => {}
+      }
+    }
+    Ok(Self {
+      drivers,
+      ..Self::default()
+    })
+  }
+
+  /// The driver with the given name
+  pub fn get(&self, name: impl AsRef<[u8]>) -> Option<&FilterDriver> {
+    self.drivers.get(name.as_ref().as_bstr())
+  }
+
+  /// Add a driver, replacing any with the same name
+  pub fn insert(&mut self, name: impl Into<BString>, driver: FilterDriver) {
+    self.drivers.insert(name.into(), driver);
+  }
+
+  /// Run the commands in `work_dir`, which should be the root of the
+  /// working tree since the paths given to them are relative to it
+  pub fn set_work_dir(&mut self, work_dir: impl Into<PathBuf>) {
+    self.work_dir = Some(work_dir.into());
+  }
+
+  /// Run the contents of the file at `path`, relative to the root of the
+  /// working tree, through the clean filter of the driver its `filter`
+  /// attribute names
+  pub fn clean<'a>(
+    &self,
+    attributes: &Attributes,
+    path: impl AsRef<[u8]>,
+    data: &'a [u8],
+  ) -> Result<Cow<'a, [u8]>, FilterError> {
+    self.apply(attributes, path.as_ref(), data, FilterDirection::Clean)
+  }
+
+  /// Run the contents of the blob for `path` through the smudge filter of
+  /// the driver its `filter` attribute names
+  pub fn smudge<'a>(
+    &self,
+    attributes: &Attributes,
+    path: impl AsRef<[u8]>,
+    data: &'a [u8],
+  ) -> Result<Cow<'a, [u8]>, FilterError> {
+    self.apply(attributes, path.as_ref(), data, FilterDirection::Smudge)
+  }
+
+  fn apply<'a>(
+    &self,
+    attributes: &Attributes,
+    path: &[u8],
+    data: &'a [u8],
+    direction: FilterDirection,
+  ) -> Result<Cow<'a, [u8]>, FilterError> {
+    let name = match attributes.get(path, "filter").value() {
+      Some(name) => BString::from(name),
+      None => return Ok(Cow::Borrowed(data)),
+    };
+    let driver = match self.drivers.get(&name) {
+      Some(driver) => driver,
+      // Like git, naming a driver that isn't configured does nothing
+      None => return Ok(Cow::Borrowed(data)),
+    };
+    let command = match direction {
+      FilterDirection::Clean => &driver.clean,
+      FilterDirection::Smudge => &driver.smudge,
+    };
+    let filtered = match (&driver.process, command) {
+      (Some(process), _) if !process.is_empty() => {
+        self.run_process(name.as_bstr(), process, path, data, direction)
+      }
+      (_, Some(command)) if !command.is_empty() => self.run(command, path, data),
+      _ => None,
+    };
+    match filtered {
+      Some(filtered) => Ok(Cow::Owned(filtered)),
+      None if driver.required => Err(FilterError::Failed {
+        driver: name,
+        direction,
+        path: path.into(),
+      }),
+      None => Ok(Cow::Borrowed(data)),
+    }
+  }
+
+  fn command(&self, command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    if let Some(work_dir) = &self.work_dir {
+      shell.current_dir(work_dir);
+    }
+    shell
+  }
+
+  /// Run a `clean` or `smudge` command, returning `None` if it failed
+  fn run(&self, command: &str, path: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    let command = expand_path(command, path);
+    let mut child = self
+      .command(&command)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::inherit())
+      .spawn()
+      .ok()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // The command can write its output before it's read all of its input,
+    // so the input is written from another thread
+    let output = thread::scope(|scope| {
+      scope.spawn(move || {
+        // Commands can quit without reading what they're given
+        let _ = stdin.write_all(data);
+      });
+      child.wait_with_output()
+    });
+    match output {
+      Ok(output) if output.status.success() => Some(output.stdout),
+      _ => None,
+    }
+  }
+
+  /// Filter a file with a `process` command, starting it if it isn't
+  /// running yet, returning `None` if it failed
+  fn run_process(
+    &self,
+    name: &BStr,
+    command: &str,
+    path: &[u8],
+    data: &[u8],
+    direction: FilterDirection,
+  ) -> Option<Vec<u8>> {
+    let mut processes = self.processes.lock().unwrap_or_else(|e| e.into_inner());
+    if !processes.contains_key(name) {
+      let child = self
+        .command(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .ok()?;
+      processes.insert(name.into(), FilterProcess::start(child).ok()?);
+    }
+    let process = processes.get_mut(name).unwrap();
+    match process.filter(path, data, direction) {
+      Ok(filtered) => filtered,
+      // A process that stopped talking the protocol is started again for
+      // the next file
+      Err(_) => {
+        processes.remove(name);
+        None
+      }
+    }
+  }
+}
+
+/// Replace `%f` in a filter command with the quoted path, and `%%` with `%`
+fn expand_path(command: &str, path: &[u8]) -> String {
+  let mut expanded = String::with_capacity(command.len());
+  let mut chars = command.chars();
+  while let Some(c) = chars.next() {
+    match (c, chars.clone().next()) {
+      ('%', Some('f')) => {
+        chars.next();
+        expanded.push('\'');
+        expanded.push_str(&path.to_str_lossy().replace('\'', "'\\''"));
+        expanded.push('\'');
+      }
+      ('%', Some('%')) => {
+        chars.next();
+        expanded.push('%');
+      }
+      _ => expanded.push(c),
+    }
+  }
+  expanded
+}
+
+/// A running `process` command
+#[derive(Debug)]
+struct FilterProcess {
+  child: Child,
+  stdin: Option<PktLineWriter<ChildStdin>>,
+  stdout: PktLineReader<ChildStdout>,
+  capabilities: Vec<BString>,
+}
+
+impl FilterProcess {
+  /// Take over a started `process` command and do the handshake, which
+  /// says which version of the protocol is spoken and what the process can
+  /// do
+  fn start(mut child: Child) -> Result<Self, FilterError> {
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let mut process = Self {
+      child,
+      stdin: Some(PktLineWriter::new(stdin)),
+      stdout: PktLineReader::new(stdout),
+      capabilities: Vec::new(),
+    };
+    process.write_lines(&["git-filter-client", "version=2"])?;
+    let welcome = process.read_lines()?;
+    if welcome.first().map(|line| line.as_bytes()) != Some(b"git-filter-server")
+      || !welcome.iter().any(|line| line == "version=2")
+    {
+      return Err(FilterError::Protocol("no version 2 welcome".into()));
+    }
+    process.write_lines(&["capability=clean", "capability=smudge"])?;
+    process.capabilities = process
+      .read_lines()?
+      .iter()
+      .filter_map(|line| line.strip_prefix(b"capability="))
+      .map(BString::from)
+      .collect();
+    Ok(process)
+  }
+
+  /// Filter one file. `Ok(None)` is a file the process couldn't filter,
+  /// and an error is the process not following the protocol.
+  fn filter(
+    &mut self,
+    path: &[u8],
+    data: &[u8],
+    direction: FilterDirection,
+  ) -> Result<Option<Vec<u8>>, FilterError> {
+    if !self.capabilities.iter().any(|c| c == direction.as_str()) {
+      return Ok(None);
+    }
+    let command = format!("command={}", direction.as_str());
+    let pathname = [b"pathname=", path].concat();
+    let writer = self.stdin.as_mut().unwrap();
+    writer.write_packet(&Packet::Data(command.as_bytes().as_bstr()))?;
+    writer.write_packet(&Packet::Data(pathname.as_bstr()))?;
+    writer.write_packet(&Packet::Flush)?;
+    // Writing to the writer sends the data in as many packets as it takes
+    writer.write_all(data)?;
+    writer.write_packet(&Packet::Flush)?;
+    writer.flush()?;
+
+    if status(&self.read_lines()?) != Some(b"success".as_ref()) {
+      return Ok(None);
+    }
+    let mut filtered = Vec::new();
+    loop {
+      match self.stdout.read_packet()? {
+        Some(Packet::Data(data)) => filtered.extend_from_slice(data),
+        Some(Packet::Flush) => break,
+        _ => return Err(FilterError::Protocol("the content didn't end".into())),
+      }
+    }
+    // The status can change after the content, and staying the same is an
+    // empty list
+    let after = self.read_lines()?;
+    match status(&after) {
+      None | Some(b"success") => Ok(Some(filtered)),
+      Some(_) => Ok(None),
+    }
+  }
+
+  fn write_lines(&mut self, lines: &[&str]) -> Result<(), FilterError> {
+    let writer = self.stdin.as_mut().unwrap();
+    for line in lines {
+      let line = format!("{}\n", line);
+      writer.write_packet(&Packet::Data(line.as_bytes().as_bstr()))?;
+    }
+    writer.write_packet(&Packet::Flush)?;
+    writer.flush()?;
+    Ok(())
+  }
+
+  /// Read lines up to a flush, without their newlines
+  fn read_lines(&mut self) -> Result<Vec<BString>, FilterError> {
+    let mut lines = Vec::new();
+    loop {
+      match self.stdout.read_packet()? {
+        Some(Packet::Data(line)) => {
+          lines.push(line.strip_suffix(b"\n").unwrap_or(line).into());
+        }
+        Some(Packet::Flush) => return Ok(lines),
+        _ => return Err(FilterError::Protocol("the process stopped".into())),
+      }
+    }
+  }
+}
+
+impl Drop for FilterProcess {
+  fn drop(&mut self) {
+    // Closing stdin is how the process is told there's nothing left to do
+    drop(self.stdin.take());
+    let _ = self.child.wait();
+  }
+}
+
+/// The last `status=` of a list of lines
+fn status(lines: &[BString]) -> Option<&[u8]> {
+  lines
+    .iter()
+    .rev()
+    .find_map(|line| line.strip_prefix(b"status="))
+}
+
+#[derive(Error, Debug)]
+/// Errors related to running files through filter drivers
+pub enum FilterError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  PktLine(#[from] PktLineError),
+  #[error("the filter process didn't follow the protocol: {0}")]
+  Protocol(String),
+  #[error("the required {} filter {driver:?} failed for {path:?}", .direction.as_str())]
+  Failed {
+    driver: BString,
+    direction: FilterDirection,
+    path: BString,
+  },
+}
+
+#[test]
+fn filters() {
+  use crate::{diff::write_tree, FileMode::NonExecutableFile, Repository};
+  use std::fs;
+  let tmp_dir = tempdir::TempDir::new("filter_test").unwrap();
+  let attributes = Attributes::from_bytes(
+    "*.up filter=upper\n\
+     *.broken filter=broken\n\
+     *.needed filter=needed\n\
+     *.unknown filter=unknown\n\
+     *.process filter=process\n",
+  );
+  let config = Config::from_bytes(
+    "[filter \"upper\"]\n\
+     \tclean = tr a-z A-Z\n\
+     \tsmudge = tr A-Z a-z\n\
+     [filter \"broken\"]\n\
+     \tclean = false\n\
+     [filter \"needed\"]\n\
+     \tclean = false\n\
+     \trequired\n",
+  )
+  .unwrap();
+  let filters = Filters::from_config(&config).unwrap();
+  assert!(filters.get("needed").unwrap().required);
+  assert_eq!(None, filters.get("needed").unwrap().smudge);
+  let clean = |path: &str, data: &'static str| filters.clean(&attributes, path, data.as_bytes());
+  assert_eq!(b"HELLO\n", &clean("a.up", "hello\n").unwrap()[..]);
+  assert_eq!(b"hello\n", &clean("a.txt", "hello\n").unwrap()[..]);
+  assert_eq!(b"hello\n", &clean("a.unknown", "hello\n").unwrap()[..]);
+  // Failing drivers leave files alone unless they're required, and a
+  // required driver without a smudge command fails on checkout
+  assert_eq!(b"hello\n", &clean("a.broken", "hello\n").unwrap()[..]);
+  assert!(matches!(
+    clean("a.needed", "hello\n"),
+    Err(FilterError::Failed {
+      direction: FilterDirection::Clean,
+      ..
+    })
+  ));
+  assert!(filters.smudge(&attributes, "a.needed", b"x").is_err());
+  assert_eq!(
+    b"hello\n",
+    &filters.smudge(&attributes, "a.up", b"HELLO\n").unwrap()[..]
+  );
+  assert_eq!("cat 'it'\\''s' 100%", expand_path("cat %f 100%%", b"it's"));
+
+  // A process that answers with what it was going to say anyway, which is
+  // as much of the protocol as a shell can speak
+  let mut responses = Vec::new();
+  let lines = |lines: &[&str], out: &mut Vec<u8>| {
+    for line in lines {
+      Packet::Data(line.as_bytes().as_bstr()).encode(out).unwrap();
+    }
+    Packet::Flush.encode(out).unwrap();
+  };
+  lines(&["git-filter-server\n", "version=2\n"], &mut responses);
+  lines(&["capability=smudge\n"], &mut responses);
+  lines(&["status=success\n"], &mut responses);
+  lines(&["first\n"], &mut responses);
+  lines(&[], &mut responses);
+  lines(&["status=error\n"], &mut responses);
+  lines(&["status=success\n"], &mut responses);
+  lines(&["third\n"], &mut responses);
+  lines(&["status=error\n"], &mut responses);
+  fs::write(tmp_dir.path().join("responses"), responses).unwrap();
+  let mut filters = Filters::default();
+  filters.set_work_dir(tmp_dir.path());
+  let process = FilterDriver {
+    process: Some("cat responses; cat >/dev/null".into()),
+    ..FilterDriver::default()
+  };
+  filters.insert("process", process);
+  let smudge = |data: &'static [u8]| filters.smudge(&attributes, "a.process", data).unwrap();
+  assert_eq!(b"first\n", &smudge(b"1")[..]);
+  assert_eq!(b"2", &smudge(b"2")[..]);
+  assert_eq!(b"3", &smudge(b"3")[..]);
+  // The process can't clean, so that's left alone without asking it
+  assert_eq!(
+    b"4",
+    &filters.clean(&attributes, "a.process", b"4").unwrap()[..]
+  );
+  drop(filters);
+
+  // Checking out smudges files, and comparing them with the index cleans
+  // them again
+  let mut repo = Repository::init(tmp_dir.path().join("repo")).unwrap();
+  let config = repo.git_dir().join("config");
+  let mut contents = fs::read_to_string(&config).unwrap();
+  contents.push_str("[filter \"upper\"]\n\tclean = tr a-z A-Z\n\tsmudge = tr A-Z a-z\n");
+  fs::write(&config, contents).unwrap();
+  repo.reload_config().unwrap();
+  let tree = write_tree(
+    repo.odb(),
+    &[
+      (".gitattributes", NonExecutableFile, "*.up filter=upper\n"),
+      ("a.up", NonExecutableFile, "HELLO\n"),
+    ],
+  );
+  repo.checkout_tree(&tree).unwrap();
+  let work_dir = repo.work_dir().unwrap().to_owned();
+  assert_eq!(
+    "hello\n",
+    fs::read_to_string(work_dir.join("a.up")).unwrap()
+  );
+  fs::write(work_dir.join("a.up"), "hello\n").unwrap();
+  assert!(repo.worktree_status().unwrap().is_empty());
+  fs::write(work_dir.join("a.up"), "world\n").unwrap();
+  assert_eq!(1, repo.worktree_status().unwrap().len());
+}
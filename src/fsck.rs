@@ -0,0 +1,595 @@
+use crate::{
+  tree::entry_order, FileMode, Index, IndexError, ObjectKind, OdbError, RefError, RefStore,
+  Repository, ShallowError, Signature, TreeEntry, WorktreeError, OID,
+};
+use bstr::{BString, ByteSlice};
+use std::{
+  cmp::Ordering,
+  collections::{HashMap, HashSet},
+  fmt,
+};
+use thiserror::Error;
+
+/// Options for [`Repository::fsck`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckOptions {
+  /// Report every object that can't be reached from a ref, like
+  /// `git fsck --unreachable`, instead of only the dangling ones that no
+  /// other object points at either
+  pub unreachable: bool,
+}
+
+/// What [`Repository::fsck`] found, one [`FsckIssue`] per problem
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+  /// How many objects were checked
+  pub checked: usize,
+  /// The problems that were found, sorted by [`OID`]
+  pub issues: Vec<FsckIssue>,
+}
+
+impl FsckReport {
+  /// Whether nothing is wrong with the repository. Dangling and
+  /// unreachable objects are only reported, git prunes them eventually.
+  pub fn is_ok(&self) -> bool {
+    self.errors().next().is_none()
+  }
+
+  /// The issues that are errors rather than dangling or unreachable objects
+  pub fn errors(&self) -> impl Iterator<Item = &FsckIssue> {
+    self.issues.iter().filter(|issue| issue.problem.is_error())
+  }
+}
+
+/// A problem with a single object found by [`Repository::fsck`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsckIssue {
+  /// The object with the problem
+  pub oid: OID,
+  /// The kind of the object, `None` if it couldn't be read
+  pub kind: Option<ObjectKind>,
+  /// What is wrong with it
+  pub problem: FsckProblem,
+}
+
+/// What is wrong with an object, see [`FsckIssue`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+  /// The object couldn't be read, with the reason
+  Unreadable(String),
+  /// The contents hash to this [`OID`] rather than the one the object is
+  /// stored under
+  HashMismatch(OID),
+  /// An object this one points at isn't in the repository
+  Missing(OID),
+  /// An object this one points at is of another kind than it should be
+  WrongKind {
+    oid: OID,
+    expected: ObjectKind,
+    found: ObjectKind,
+  },
+  /// A ref, a reflog, or the index points at the object but it isn't in
+  /// the repository
+  BrokenRef(BString),
+  /// The entries of a tree can't be parsed
+  MalformedTree,
+  /// A tree entry has a mode git doesn't write
+  BadMode(BString),
+  /// A tree entry has a mode with leading zeros, like `040000`
+  ZeroPaddedMode(BString),
+  /// A tree entry has a name that can't be checked out, like `..`, `.git`,
+  /// or one with a `/`
+  BadEntryName(BString),
+  /// A tree has two entries with the same name
+  DuplicateEntry(BString),
+  /// A tree entry comes before the one in front of it in git's order
+  NotSorted(BString),
+  /// A commit or tag is missing a required header
+  MissingHeader(&'static str),
+  /// A commit or tag header is malformed or out of order
+  BadHeader(BString),
+  /// Nothing points at the object
+  Dangling,
+  /// The object can't be reached from any ref, see
+  /// [`FsckOptions::unreachable`]
+  Unreachable,
+}
+
+impl FsckProblem {
+  /// Whether the problem is an error rather than an object that's merely
+  /// not used anymore
+  pub fn is_error(&self) -> bool {
+    !matches!(self, Self::Dangling | Self::Unreachable)
+  }
+}
+
+impl fmt::Display for FsckProblem {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Unreadable(reason) => write!(f, "unreadable: {}", reason),
+      Self::HashMismatch(oid) => write!(f, "hash mismatch, the contents are {}", oid),
+      Self::Missing(oid) => write!(f, "broken link to {}", oid),
+      Self::WrongKind {
+        oid,
+        expected,
+        found,
+      } => write!(f, "{} is a {} not a {}", oid, found, expected),
+      Self::BrokenRef(name) => write!(f, "{} points at a missing object", name),
+      Self::MalformedTree => f.write_str("malformed tree"),
+      Self::BadMode(name) => write!(f, "{} has a bad mode", name),
+      Self::ZeroPaddedMode(name) => write!(f, "{} has a zero padded mode", name),
+      Self::BadEntryName(name) => write!(f, "{:?} is not a valid entry name", name),
+      Self::DuplicateEntry(name) => write!(f, "{} is in the tree more than once", name),
+      Self::NotSorted(name) => write!(f, "{} is not sorted", name),
+      Self::MissingHeader(header) => write!(f, "missing {} header", header),
+      Self::BadHeader(line) => write!(f, "bad header {:?}", line),
+      Self::Dangling => f.write_str("dangling"),
+      Self::Unreachable => f.write_str("unreachable"),
+    }
+  }
+}
+
+impl fmt::Display for FsckIssue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let kind = self.kind.map_or("object", |kind| kind.as_str());
+    match &self.problem {
+      FsckProblem::Dangling | FsckProblem::Unreachable => {
+        write!(f, "{} {} {}", self.problem, kind, self.oid)
+      }
+      problem => write!(f, "error in {} {}: {}", kind, self.oid, problem),
+    }
+  }
+}
+
+/// What an object was found to point at while checking it
+type Links = Vec<(OID, ObjectKind)>;
+
+impl Repository {
+  /// Verify every object in the repository like `git fsck`: each one has
+  /// to hash to its [`OID`] and parse, trees have to be sorted with valid
+  /// modes and names, and commits and tags need well formed headers.
+  /// Everything reachable from the refs, their reflogs, the `HEAD` and
+  /// index of every worktree has to be there too, apart from what's behind
+  /// shallow commits or promised by a promisor remote. Nothing is fetched.
+  /// Objects borrowed from alternates only have to be there, they're
+  /// checked along with the repository they belong to.
+  ///
+  /// Problems with objects end up in the [`FsckReport`], an error is only
+  /// returned if the repository can't be looked at at all.
+  pub fn fsck(&self, options: &FsckOptions) -> Result<FsckReport, FsckError> {
+    let odb = self.odb();
+    let stored = odb.oids()?;
+    let mut issues = Vec::new();
+    let mut objects: HashMap<OID, (ObjectKind, Links)> = HashMap::new();
+    for oid in &stored {
+      let mut problems = Vec::new();
+      let object = match odb.read_stored(oid) {
+        Ok(Some(object)) => object,
+        Ok(None) => continue,
+        Err(e) => {
+          issues.push(FsckIssue {
+            oid: *oid,
+            kind: None,
+            problem: FsckProblem::Unreadable(e.to_string()),
+          });
+          continue;
+        }
+      };
+      let id = object.id();
+      if id != *oid {
+        problems.push(FsckProblem::HashMismatch(id));
+      }
+      let mut links = Vec::new();
+      match object.kind {
+        ObjectKind::Blob => {}
+        ObjectKind::Tree => check_tree(&object.data, &mut links, &mut problems),
+        ObjectKind::Commit => check_commit(&object.data, &mut links, &mut problems),
+        ObjectKind::Tag => check_tag(&object.data, &mut links, &mut problems),
+      }
+      issues.extend(problems.into_iter().map(|problem| FsckIssue {
+        oid: *oid,
+        kind: Some(object.kind),
+        problem,
+      }));
+      objects.insert(*oid, (object.kind, links));
+    }
+
+    // A partial clone is missing whatever its promisor packs point at and
+    // a shallow one the parents of its shallow commits
+    let mut promised = HashSet::new();
+    for oid in odb.promisor_oids()? {
+      if let Some((_, links)) = objects.get(&oid) {
+        promised.extend(links.iter().map(|(link, _)| *link));
+      }
+    }
+    let shallow = self.shallow_commits()?;
+    let borrowed = |oid: &OID| -> Result<Option<ObjectKind>, OdbError> {
+      for alternate in odb.alternates() {
+        if alternate.contains(oid)? {
+          return alternate.object_kind(oid).map(Some);
+        }
+      }
+      Ok(None)
+    };
+    let mut referenced = HashSet::new();
+    for oid in &stored {
+      let (kind, links) = match objects.get(oid) {
+        Some(object) => object,
+        None => continue,
+      };
+      let shallow = *kind == ObjectKind::Commit && shallow.contains(oid);
+      for (link, expected) in links {
+        referenced.insert(*link);
+        let found = match objects.get(link) {
+          Some((found, _)) => Some(*found),
+          None => borrowed(link)?,
+        };
+        let problem = match found {
+          Some(found) if found != *expected => FsckProblem::WrongKind {
+            oid: *link,
+            expected: *expected,
+            found,
+          },
+          Some(_) => continue,
+          None if promised.contains(link) => continue,
+          None if shallow && *expected == ObjectKind::Commit => continue,
+          None => FsckProblem::Missing(*link),
+        };
+        issues.push(FsckIssue {
+          oid: *oid,
+          kind: Some(*kind),
+          problem,
+        });
+      }
+    }
+
+    let mut reachable = HashSet::new();
+    let mut pending = Vec::new();
+    for (name, oid, required) in self.fsck_roots()? {
+      if objects.contains_key(&oid) || promised.contains(&oid) || borrowed(&oid)?.is_some() {
+        pending.push(oid);
+      } else if required {
+        issues.push(FsckIssue {
+          oid,
+          kind: None,
+          problem: FsckProblem::BrokenRef(name),
+        });
+      }
+    }
+    while let Some(oid) = pending.pop() {
+      if !reachable.insert(oid) {
+        continue;
+      }
+      if let Some((_, links)) = objects.get(&oid) {
+        pending.extend(links.iter().map(|(link, _)| *link));
+      }
+    }
+    for oid in &stored {
+      if reachable.contains(oid) {
+        continue;
+      }
+      let problem = if options.unreachable {
+        FsckProblem::Unreachable
+      } else if !referenced.contains(oid) {
+        FsckProblem::Dangling
+      } else {
+        continue;
+      };
+      issues.push(FsckIssue {
+        oid: *oid,
+        kind: objects.get(oid).map(|(kind, _)| *kind),
+        problem,
+      });
+    }
+
+    issues.sort_by_key(|issue| issue.oid);
+    Ok(FsckReport {
+      checked: stored.len(),
+      issues,
+    })
+  }
+
+  /// Where reachability starts, by name and whether the object has to be
+  /// there. Old reflog entries may point at objects that were pruned.
+  fn fsck_roots(&self) -> Result<Vec<(BString, OID, bool)>, FsckError> {
+    let mut git_dirs = vec![self.common_dir().to_path_buf()];
+    git_dirs.extend(
+      self
+        .worktrees()?
+        .into_iter()
+        .map(|worktree| worktree.git_dir().to_path_buf()),
+    );
+    let mut roots = Vec::new();
+    for git_dir in git_dirs {
+      let refs = RefStore::new(&git_dir);
+      let mut names = vec![BString::from("HEAD")];
+      names.extend(refs.list("refs/")?.into_iter().map(|r| r.name().to_owned()));
+      for name in names {
+        if let Some(oid) = refs.resolve(&name)? {
+          roots.push((name.clone(), oid, true));
+        }
+        for entry in refs.reflog(&name)? {
+          for oid in [entry.old, entry.new] {
+            if oid != OID::NULL {
+              roots.push((name.clone(), oid, false));
+            }
+          }
+        }
+      }
+      for entry in Index::open(git_dir.join("index"))?.entries() {
+        if entry.mode != FileMode::GitLink {
+          roots.push((BString::from("index"), entry.oid, true));
+        }
+      }
+    }
+    Ok(roots)
+  }
+}
+
+fn check_tree(mut data: &[u8], links: &mut Links, problems: &mut Vec<FsckProblem>) {
+  let mut previous: Option<TreeEntry> = None;
+  while !data.is_empty() {
+    let entry = data.find_byte(b' ').and_then(|space| {
+      let nul = space + data[space..].find_byte(0)?;
+      let oid = OID::from_bytes(data.get(nul + 1..nul + 21)?).ok()?;
+      Some((&data[..space], &data[space + 1..nul], oid, nul + 21))
+    });
+    let (mode, name, oid, len) = match entry {
+      Some(entry) => entry,
+      None => {
+        problems.push(FsckProblem::MalformedTree);
+        return;
+      }
+    };
+    data = &data[len..];
+    let stripped = mode.trim_start_with(|c| c == '0');
+    if stripped.len() != mode.len() {
+      problems.push(FsckProblem::ZeroPaddedMode(name.into()));
+    }
+    let mode = FileMode::from_bytes(stripped).unwrap_or_else(|_| {
+      problems.push(FsckProblem::BadMode(name.into()));
+      FileMode::NonExecutableFile
+    });
+    if name.is_empty()
+      || name == b"."
+      || name == b".."
+      || name.eq_ignore_ascii_case(b".git")
+      || name.contains(&b'/')
+    {
+      problems.push(FsckProblem::BadEntryName(name.into()));
+    }
+    let entry = TreeEntry::new(mode, name, oid);
+    if let Some(previous) = &previous {
+      if previous.name() == entry.name() {
+        problems.push(FsckProblem::DuplicateEntry(name.into()));
+      } else if entry_order(previous, &entry) != Ordering::Less {
+        problems.push(FsckProblem::NotSorted(name.into()));
+      }
+    }
+    if mode.is_tree() {
+      links.push((oid, ObjectKind::Tree));
+    } else if mode.is_blob() {
+      links.push((oid, ObjectKind::Blob));
+    }
+    previous = Some(entry);
+  }
+}
+
+/// The header lines of a commit or tag, which have to end with a line
+/// ending before the message
+fn header_lines(data: &[u8]) -> Result<Vec<&[u8]>, FsckProblem> {
+  let headers = match data.find(b"\n\n") {
+    Some(idx) => &data[..idx],
+    None => match data.strip_suffix(b"\n") {
+      Some(headers) => headers,
+      None => return Err(FsckProblem::BadHeader("unterminated header".into())),
+    },
+  };
+  if headers.contains(&0) {
+    return Err(FsckProblem::BadHeader("NUL byte in header".into()));
+  }
+  Ok(headers.split_str("\n").collect())
+}
+
+fn header_oid(line: &[u8], key: &'static str) -> Result<OID, FsckProblem> {
+  let hex = line
+    .strip_prefix(key.as_bytes())
+    .and_then(|value| value.strip_prefix(b" "))
+    .ok_or(FsckProblem::MissingHeader(key))?;
+  hex
+    .to_str()
+    .ok()
+    .filter(|hex| hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')))
+    .and_then(|hex| OID::from_hex(hex).ok())
+    .ok_or_else(|| FsckProblem::BadHeader(line.into()))
+}
+
+fn header_signature(line: &[u8], key: &'static str) -> Result<(), FsckProblem> {
+  let value = line
+    .strip_prefix(key.as_bytes())
+    .and_then(|value| value.strip_prefix(b" "))
+    .ok_or(FsckProblem::MissingHeader(key))?;
+  Signature::parse(value).map_err(|_| FsckProblem::BadHeader(line.into()))?;
+  Ok(())
+}
+
+fn check_commit(data: &[u8], links: &mut Links, problems: &mut Vec<FsckProblem>) {
+  let result = header_lines(data).and_then(|lines| {
+    let mut lines = lines.into_iter().peekable();
+    let tree = header_oid(lines.next().unwrap_or_default(), "tree")?;
+    links.push((tree, ObjectKind::Tree));
+    while let Some(line) = lines.next_if(|line| line.starts_with(b"parent ")) {
+      links.push((header_oid(line, "parent")?, ObjectKind::Commit));
+    }
+    header_signature(lines.next().unwrap_or_default(), "author")?;
+    header_signature(lines.next().unwrap_or_default(), "committer")
+  });
+  if let Err(problem) = result {
+    problems.push(problem);
+  }
+}
+
+fn check_tag(data: &[u8], links: &mut Links, problems: &mut Vec<FsckProblem>) {
+  let result = header_lines(data).and_then(|lines| {
+    let mut lines = lines.into_iter().peekable();
+    let object = header_oid(lines.next().unwrap_or_default(), "object")?;
+    let line = lines.next().unwrap_or_default();
+    let kind = line
+      .strip_prefix(b"type ")
+      .ok_or(FsckProblem::MissingHeader("type"))?;
+    let kind = ObjectKind::from_bytes(kind).ok_or_else(|| FsckProblem::BadHeader(line.into()))?;
+    links.push((object, kind));
+    let line = lines.next().unwrap_or_default();
+    match line.strip_prefix(b"tag ") {
+      Some(name) if !name.is_empty() => {}
+      Some(_) => return Err(FsckProblem::BadHeader(line.into())),
+      None => return Err(FsckProblem::MissingHeader("tag")),
+    }
+    // Old tags don't have a tagger
+    match lines.next_if(|line| line.starts_with(b"tagger ")) {
+      Some(line) => header_signature(line, "tagger"),
+      None => Ok(()),
+    }
+  });
+  if let Err(problem) = result {
+    problems.push(problem);
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to checking a repository with [`Repository::fsck`]
+pub enum FsckError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Index(#[from] IndexError),
+  #[error("{0}")]
+  Shallow(#[from] ShallowError),
+  #[error("{0}")]
+  Worktree(#[from] WorktreeError),
+}
+
+#[test]
+fn fsck() {
+  use crate::{zlib, Blob, Commit, RawObject, Time, Tree};
+  use std::{fs, process::Command};
+  let tmp_dir = tempdir::TempDir::new("fsck_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let odb = repo.odb();
+  let blob = odb.write_blob(&Blob::new("hello\n")).unwrap();
+  let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+  let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+  let commit = Commit::new(tree, vec![], signature.clone(), signature, "commit\n");
+  let commit = odb.write_commit(&commit).unwrap();
+  repo.refs().write("refs/heads/master", &commit).unwrap();
+  repo.checkout_tree(&tree).unwrap();
+
+  let report = repo.fsck(&FsckOptions::default()).unwrap();
+  assert!(report.is_ok());
+  assert_eq!(3, report.checked);
+  assert!(report.issues.is_empty());
+  if crate::transport::http::have_git() {
+    let status = Command::new("git")
+      .args(["fsck", "--strict", "--no-progress"])
+      .current_dir(tmp_dir.path())
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .status()
+      .unwrap();
+    assert!(status.success());
+  }
+
+  // Everything is reachable from the branch
+  let report = repo.fsck(&FsckOptions { unreachable: true }).unwrap();
+  assert!(report.is_ok());
+  assert!(report.issues.is_empty());
+
+  // An unsorted tree with a zero padded mode pointing at a missing blob.
+  // Nothing points at it so it's dangling too.
+  let missing = OID::hash(b"missing");
+  let bad_tree = [
+    &b"100644 b\0"[..],
+    missing.as_bytes(),
+    b"040000 a\0",
+    tree.as_bytes(),
+  ]
+  .concat();
+  let bad_tree = odb
+    .write(&RawObject::new(ObjectKind::Tree, bad_tree))
+    .unwrap();
+  let bad_commit = format!("tree {}\n\ncommit\n", tree);
+  let bad_commit = odb
+    .write(&RawObject::new(ObjectKind::Commit, bad_commit))
+    .unwrap();
+  // A blob stored under the OID of other contents
+  let wrong = RawObject::new(ObjectKind::Blob, "other\n").id();
+  let hex = wrong.as_hex();
+  fs::create_dir_all(odb.path().join(&hex[..2])).unwrap();
+  fs::write(
+    odb.path().join(&hex[..2]).join(&hex[2..]),
+    zlib::compress(b"blob 6\0hello!"),
+  )
+  .unwrap();
+  repo.refs().write("refs/heads/broken", &missing).unwrap();
+
+  let report = repo.fsck(&FsckOptions::default()).unwrap();
+  assert!(!report.is_ok());
+  assert_eq!(6, report.checked);
+  let problems = |oid: OID| {
+    report
+      .issues
+      .iter()
+      .filter(|issue| issue.oid == oid)
+      .map(|issue| issue.problem.clone())
+      .collect::<Vec<_>>()
+  };
+  assert_eq!(
+    vec![
+      FsckProblem::ZeroPaddedMode("a".into()),
+      FsckProblem::NotSorted("a".into()),
+      FsckProblem::Missing(missing),
+      FsckProblem::Dangling,
+    ],
+    problems(bad_tree)
+  );
+  assert_eq!(
+    vec![FsckProblem::MissingHeader("author"), FsckProblem::Dangling],
+    problems(bad_commit)
+  );
+  assert_eq!(
+    vec![
+      FsckProblem::HashMismatch(RawObject::new(ObjectKind::Blob, "hello!").id()),
+      FsckProblem::Dangling,
+    ],
+    problems(wrong)
+  );
+  assert_eq!(
+    vec![FsckProblem::BrokenRef("refs/heads/broken".into())],
+    problems(missing)
+  );
+  assert_eq!(
+    format!("error in commit {}: missing author header", bad_commit),
+    report
+      .issues
+      .iter()
+      .find(|i| i.oid == bad_commit)
+      .unwrap()
+      .to_string()
+  );
+  assert_eq!(
+    format!("dangling tree {}", bad_tree),
+    report
+      .issues
+      .iter()
+      .find(|i| i.oid == bad_tree && !i.problem.is_error())
+      .unwrap()
+      .to_string()
+  );
+}
@@ -0,0 +1,234 @@
+//! Entry points for fuzzing the parsers of data that comes from outside of
+//! a repository: objects, pack files, index files, and pkt-lines. Each one
+//! takes the bytes as they would be read from disk or the network and never
+//! touches the filesystem, so they can be handed straight to a fuzzer like
+//! `cargo fuzz`:
+//!
+//! ```ignore
+//! fuzz_target!(|data: &[u8]| {
+//!   let _ = libgit_rs::parse_pack_unchecked_input(data, &MemoryBudget::new(64 << 20));
+//! });
+//! ```
+//!
+//! They are also the contract the rest of the crate holds itself to for
+//! untrusted input. For any bytes at all they return an error rather than
+//! panic, loop forever, or recurse without bound, and they allocate no more
+//! than a small multiple of the input. Anything that can grow past that,
+//! like inflating zlib streams or applying deltas, is counted against the
+//! [`MemoryBudget`] passed in.
+
+use crate::{
+  odb, pack, Commit, Index, IndexError, MemoryBudget, ObjectKind, OdbError, PackError, Packet,
+  PktLineError, RawObject, Tag, Tree, OID,
+};
+
+/// Inflate and parse a loose object file, then parse its contents as the
+/// kind of object it says it is. Corrupt objects are reported with the all
+/// zero [`OID`] since the real one isn't known.
+pub fn parse_object_unchecked_input(
+  bytes: &[u8],
+  budget: &MemoryBudget,
+) -> Result<RawObject, OdbError> {
+  let oid = OID::from_bytes(&[0; 20]).unwrap();
+  let object = odb::parse_loose(&oid, bytes, budget)?;
+  match object.kind {
+    ObjectKind::Blob => {}
+    ObjectKind::Tree => drop(Tree::parse(&object.data)?),
+    ObjectKind::Commit => drop(Commit::parse(&object.data)?),
+    ObjectKind::Tag => drop(Tag::parse(&object.data)?),
+  }
+  Ok(object)
+}
+
+/// Parse a whole pack file as it's received during a fetch, resolving every
+/// delta against the other objects in it. The objects are returned in the
+/// order they are in the pack.
+pub fn parse_pack_unchecked_input(
+  bytes: &[u8],
+  budget: &MemoryBudget,
+) -> Result<Vec<RawObject>, PackError> {
+  pack::parse_pack(bytes, budget)
+}
+
+/// Parse an index file, the same as [`Index::parse`]
+pub fn parse_index_unchecked_input(bytes: &[u8]) -> Result<Index, IndexError> {
+  Index::parse(bytes)
+}
+
+/// Split a pkt-line stream into its packets. A packet cut off at the end of
+/// `bytes` is an error here since nothing more is coming.
+pub fn parse_pkt_lines_unchecked_input(bytes: &[u8]) -> Result<Vec<Packet<'_>>, PktLineError> {
+  let mut packets = Vec::new();
+  let mut rest = bytes;
+  while !rest.is_empty() {
+    let (packet, len) = Packet::decode(rest)?.ok_or(PktLineError::Truncated)?;
+    packets.push(packet);
+    rest = &rest[len..];
+  }
+  Ok(packets)
+}
+
+/// A small deterministic fuzzer, feeding each entry point mutations of a
+/// valid input. The checksums of packs and indexes are fixed up after
+/// mutating so most inputs get past them to the rest of the parser.
+#[cfg(test)]
+fn mutations(input: &[u8], checksum: bool, mut check: impl FnMut(&[u8])) {
+  use sha1::{Digest, Sha1};
+  let mut state = 0x2545_f491_4f6c_dd1d_u64;
+  let mut random = move |bound: usize| {
+    state ^= state << 13;
+    state ^= state >> 7;
+    state ^= state << 17;
+    (state % bound.max(1) as u64) as usize
+  };
+  check(&[]);
+  check(input);
+  for len in 0..input.len() {
+    check(&input[..len]);
+  }
+  for _ in 0..2000 {
+    let mut bytes = input.to_vec();
+    for _ in 0..1 + random(4) {
+      let at = random(bytes.len());
+      match random(4) {
+        0 => bytes[at] ^= 1 << random(8),
+        1 => bytes[at] = [0, 0x7f, 0x80, 0xff][random(4)],
+        2 => drop(bytes.drain(at..(at + random(8)).min(bytes.len()))),
+        _ => bytes.insert(at, random(256) as u8),
+      }
+      if bytes.is_empty() {
+        bytes.push(0);
+      }
+    }
+    if checksum && bytes.len() >= 20 {
+      let len = bytes.len() - 20;
+      let digest = Sha1::digest(&bytes[..len]);
+      bytes[len..].copy_from_slice(&digest);
+    }
+    check(&bytes);
+  }
+}
+
+#[test]
+fn fuzz_objects() {
+  use crate::{zlib, FileMode, Signature, Time, TreeEntry};
+  let budget = MemoryBudget::new(1 << 20);
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, -90),
+  );
+  let tree = Tree::new(vec![
+    TreeEntry::new(FileMode::NonExecutableFile, "file", OID::hash("file")),
+    TreeEntry::new(FileMode::Tree, "dir", OID::hash("dir")),
+  ]);
+  let commit = Commit::new(
+    tree.id(),
+    vec![OID::hash("parent")],
+    signature.clone(),
+    signature.clone(),
+    "message\n",
+  );
+  let tag = Tag::new(
+    commit.id(),
+    ObjectKind::Commit,
+    "v1.0",
+    signature,
+    "release\n",
+  );
+  for raw_bytes in [
+    RawObject::new(ObjectKind::Blob, "contents\n").as_bytes(),
+    tree.as_bytes(),
+    commit.as_bytes(),
+    tag.as_bytes(),
+  ] {
+    let compressed = zlib::compress(&raw_bytes);
+    let object = parse_object_unchecked_input(&compressed, &budget).unwrap();
+    assert_eq!(raw_bytes, object.as_bytes());
+    mutations(&compressed, false, |bytes| {
+      let _ = parse_object_unchecked_input(bytes, &budget);
+    });
+    // Mutating the inflated object reaches the object parsers more often
+    mutations(&raw_bytes, false, |bytes| {
+      let _ = parse_object_unchecked_input(&zlib::compress(bytes), &budget);
+    });
+  }
+  assert_eq!(0, budget.in_use());
+
+  // A zlib bomb runs into the budget instead of filling memory
+  let bomb = zlib::compress(&RawObject::new(ObjectKind::Blob, vec![0; 4 << 20]).as_bytes());
+  assert!(matches!(
+    parse_object_unchecked_input(&bomb, &budget),
+    Err(OdbError::Memory(_))
+  ));
+}
+
+#[test]
+fn fuzz_pack() {
+  use crate::pack::{test_delta, write_test_pack};
+  let tmp_dir = tempdir::TempDir::new("fuzz_test").unwrap();
+  let base = RawObject::new(ObjectKind::Blob, "the quick brown fox\n");
+  let delta = |data: &[u8]| {
+    RawObject::new(
+      ObjectKind::Blob,
+      test_delta(base.data.len(), 10 + data.len(), (0, 10), data),
+    )
+  };
+  let entries = [
+    (None, base.clone()),
+    (Some(0), delta(b"jumps\n")),
+    (None, RawObject::new(ObjectKind::Blob, "other\n")),
+    (Some(0), delta(b"sleeps\n")),
+  ];
+  let oids = write_test_pack(tmp_dir.path(), "fuzz", &entries);
+  let bytes = std::fs::read(tmp_dir.path().join("pack-fuzz.pack")).unwrap();
+  let budget = MemoryBudget::new(1 << 20);
+  let objects = parse_pack_unchecked_input(&bytes, &budget).unwrap();
+  assert_eq!(oids, objects.iter().map(RawObject::id).collect::<Vec<_>>());
+  assert_eq!(b"the quick jumps\n"[..], objects[1].data[..]);
+  assert_eq!(b"the quick sleeps\n"[..], objects[3].data[..]);
+  mutations(&bytes, true, |bytes| {
+    let _ = parse_pack_unchecked_input(bytes, &budget);
+  });
+  assert_eq!(0, budget.in_use());
+}
+
+#[test]
+fn fuzz_index() {
+  use crate::{FileMode, IndexEntry, StatData};
+  let index = Index::new(vec![
+    IndexEntry::new(
+      "README",
+      FileMode::NonExecutableFile,
+      OID::hash("a"),
+      StatData::default(),
+    ),
+    IndexEntry::new(
+      "src/lib.rs",
+      FileMode::ExecutableFile,
+      OID::hash("b"),
+      StatData::default(),
+    ),
+  ]);
+  let bytes = index.as_bytes();
+  assert_eq!(index, parse_index_unchecked_input(&bytes).unwrap());
+  mutations(&bytes, true, |bytes| {
+    let _ = parse_index_unchecked_input(bytes);
+  });
+}
+
+#[test]
+fn fuzz_pkt_lines() {
+  let bytes = b"0032want 0123456789012345678901234567890123456789\n0001000dthin-pack0000";
+  let packets = parse_pkt_lines_unchecked_input(bytes).unwrap();
+  assert_eq!(4, packets.len());
+  assert_eq!(Packet::Delim, packets[1]);
+  assert_eq!(Packet::Data("thin-pack".into()), packets[2]);
+  assert!(matches!(
+    parse_pkt_lines_unchecked_input(b"0009want"),
+    Err(PktLineError::Truncated)
+  ));
+  mutations(bytes, false, |bytes| {
+    let _ = parse_pkt_lines_unchecked_input(bytes);
+  });
+}
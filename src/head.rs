@@ -0,0 +1,288 @@
+use crate::{
+  peel_tag, ConfigError, ObjectKind, OdbError, RefError, RefTarget, ReflogEntry, Repository,
+  Signature, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use thiserror::Error;
+
+/// Where `HEAD` of a [`Repository`] is
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Head {
+  /// On a branch, with the full name of its ref and the commit it's at
+  Branch { name: BString, oid: OID },
+  /// On a branch that has no commits yet, like `master` in a new
+  /// repository
+  Unborn(BString),
+  /// Detached at a commit instead of being on a branch
+  Detached(OID),
+}
+
+impl Head {
+  /// The commit `HEAD` is at, if there is one
+  pub fn oid(&self) -> Option<&OID> {
+    match self {
+      Self::Branch { oid, .. } | Self::Detached(oid) => Some(oid),
+      Self::Unborn(_) => None,
+    }
+  }
+
+  /// The full name of the branch `HEAD` is on, including unborn ones
+  pub fn branch(&self) -> Option<&BStr> {
+    match self {
+      Self::Branch { name, .. } | Self::Unborn(name) => Some(name.as_bstr()),
+      Self::Detached(_) => None,
+    }
+  }
+
+  /// How `git checkout` names where `HEAD` is in its reflog messages, the
+  /// short branch name or the full object name when detached
+  fn describe(&self) -> String {
+    match self {
+      Self::Branch { name, .. } | Self::Unborn(name) => name
+        .strip_prefix(b"refs/heads/")
+        .unwrap_or(name)
+        .to_str_lossy()
+        .into_owned(),
+      Self::Detached(oid) => oid.as_hex(),
+    }
+  }
+}
+
+impl Repository {
+  /// Read where `HEAD` is. A `HEAD` pointing at something other than a
+  /// branch, like a tag or a symbolic ref to another symbolic ref, is still
+  /// reported as being on that ref.
+  pub fn head(&self) -> Result<Head, HeadError> {
+    let refs = self.refs();
+    match refs.read("HEAD")?.map(|head| head.target().clone()) {
+      Some(RefTarget::Direct(oid)) => Ok(Head::Detached(oid)),
+      Some(RefTarget::Symbolic(name)) => match refs.resolve(&name)? {
+        Some(oid) => Ok(Head::Branch { name, oid }),
+        None => Ok(Head::Unborn(name)),
+      },
+      None => Err(HeadError::Missing),
+    }
+  }
+
+  /// The short name of the branch `HEAD` is on, like `master`, or `None`
+  /// if it's detached. Unborn branches are included.
+  pub fn current_branch(&self) -> Result<Option<BString>, HeadError> {
+    Ok(self.head()?.branch().map(|name| {
+      name
+        .strip_prefix(b"refs/heads/")
+        .unwrap_or(name)
+        .as_bstr()
+        .to_owned()
+    }))
+  }
+
+  /// Put `HEAD` on the ref `name`, like `refs/heads/topic`, without
+  /// touching the index or the working tree. The ref doesn't have to exist
+  /// yet, in which case the branch is unborn like `git checkout --orphan`
+  /// leaves it. If it does it has to point at a commit.
+  ///
+  /// When `HEAD` ends up at a commit an entry is added to its reflog the
+  /// way `git checkout` does.
+  pub fn set_head(&self, name: &str, committer: &Signature) -> Result<(), HeadError> {
+    if !name.starts_with("refs/") {
+      return Err(HeadError::InvalidName(name.into()));
+    }
+    let old = self.head()?;
+    let new = match self.refs().resolve(name)? {
+      Some(oid) => Head::Branch {
+        name: name.into(),
+        oid: self.commit(&oid)?,
+      },
+      None => Head::Unborn(name.into()),
+    };
+    self.refs().write_symbolic("HEAD", name)?;
+    self.log_head(&old, &new, committer)
+  }
+
+  /// Detach `HEAD` at a commit, or at the commit a tag points at, like
+  /// `git checkout --detach {commit}`. The index and the working tree are
+  /// not touched. An entry is added to the reflog of `HEAD`.
+  pub fn detach_head(&self, commit: &OID, committer: &Signature) -> Result<(), HeadError> {
+    let old = self.head()?;
+    let oid = self.commit(commit)?;
+    self.refs().write("HEAD", &oid)?;
+    self.log_head(&old, &Head::Detached(oid), committer)
+  }
+
+  /// Peel `oid` to a commit, failing for anything else
+  fn commit(&self, oid: &OID) -> Result<OID, HeadError> {
+    match peel_tag(self.odb(), oid)? {
+      (oid, ObjectKind::Commit) => Ok(oid),
+      (_, found) => Err(HeadError::NotACommit { oid: *oid, found }),
+    }
+  }
+
+  fn log_head(&self, old: &Head, new: &Head, committer: &Signature) -> Result<(), HeadError> {
+    let new_oid = match new.oid() {
+      Some(oid) => *oid,
+      None => return Ok(()),
+    };
+    if !self.logs_ref_updates()? && !self.refs().has_reflog("HEAD")? {
+      return Ok(());
+    }
+    let old_oid = old
+      .oid()
+      .copied()
+      .unwrap_or_else(|| OID::from_bytes(&[0; 20]).unwrap());
+    let message = format!(
+      "checkout: moving from {} to {}",
+      old.describe(),
+      new.describe()
+    );
+    let entry = ReflogEntry::new(old_oid, new_oid, committer.clone(), message);
+    Ok(self.refs().append_reflog("HEAD", &entry)?)
+  }
+
+  /// Whether updates to refs should be logged when they don't have a reflog
+  /// yet, going by `core.logAllRefUpdates`
+  fn logs_ref_updates(&self) -> Result<bool, ConfigError> {
+    Ok(match self.config().get_str("core.logallrefupdates")? {
+      Some(value) if value.eq_ignore_ascii_case("always") => true,
+      _ => self
+        .config()
+        .get_bool("core.logallrefupdates")?
+        .unwrap_or(!self.is_bare()),
+    })
+  }
+
+  /// Move the branch `HEAD` is on, or `HEAD` itself when it's detached,
+  /// from `old` to the commit `new` the way making a commit does, adding
+  /// `message` to the reflogs of both. `old` is `None` on an unborn branch.
+  pub(crate) fn advance_head(
+    &self,
+    old: Option<OID>,
+    new: &OID,
+    committer: &Signature,
+    message: &str,
+  ) -> Result<(), HeadError> {
+    let name = match self.head()? {
+      Head::Branch { name, .. } | Head::Unborn(name) => name,
+      Head::Detached(_) => "HEAD".into(),
+    };
+    let mut transaction = self.refs().transaction();
+    transaction.update(name.clone(), old, Some(*new));
+    transaction.commit()?;
+    let zero = OID::from_bytes(&[0; 20]).unwrap();
+    let entry = ReflogEntry::new(old.unwrap_or(zero), *new, committer.clone(), message);
+    let log_updates = self.logs_ref_updates()?;
+    let mut logs = vec![name.as_bstr()];
+    if name != "HEAD" {
+      logs.push("HEAD".into());
+    }
+    for log in logs {
+      if log_updates || self.refs().has_reflog(log)? {
+        self.refs().append_reflog(log, &entry)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to reading and moving `HEAD`
+pub enum HeadError {
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("the repository has no HEAD")]
+  Missing,
+  #[error("HEAD can't point at {0:?}, only at refs")]
+  InvalidName(BString),
+  #[error("object {oid} is a {found} not a commit")]
+  NotACommit { oid: OID, found: ObjectKind },
+}
+
+#[test]
+fn move_head() {
+  use crate::{Commit, Time, Tree};
+  let tmp_dir = tempdir::TempDir::new("head_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let refs = repo.refs();
+  let committer = Signature::new(
+    "C O Mitter",
+    "committer@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  assert_eq!(
+    Head::Unborn("refs/heads/master".into()),
+    repo.head().unwrap()
+  );
+  assert_eq!(Some("master".into()), repo.current_branch().unwrap());
+
+  let tree = repo.odb().write_tree(&Tree::default()).unwrap();
+  let commit = |message: &str| {
+    let commit = Commit::new(tree, vec![], committer.clone(), committer.clone(), message);
+    repo.odb().write_commit(&commit).unwrap()
+  };
+  let (first, second) = (commit("first\n"), commit("second\n"));
+  refs.write("refs/heads/master", &first).unwrap();
+  refs.write("refs/heads/topic", &second).unwrap();
+  assert_eq!(Some(&first), repo.head().unwrap().oid());
+
+  repo.set_head("refs/heads/topic", &committer).unwrap();
+  assert_eq!(
+    Head::Branch {
+      name: "refs/heads/topic".into(),
+      oid: second
+    },
+    repo.head().unwrap()
+  );
+  assert_eq!(Some("topic".into()), repo.current_branch().unwrap());
+  repo.detach_head(&first, &committer).unwrap();
+  assert_eq!(Head::Detached(first), repo.head().unwrap());
+  assert_eq!(None, repo.current_branch().unwrap());
+  // Moving to a branch with no commits leaves HEAD at nothing to log
+  repo.set_head("refs/heads/orphan", &committer).unwrap();
+  assert_eq!(
+    Head::Unborn("refs/heads/orphan".into()),
+    repo.head().unwrap()
+  );
+  repo.set_head("refs/heads/master", &committer).unwrap();
+
+  let zero = OID::from_bytes(&[0; 20]).unwrap();
+  let log: Vec<_> = refs
+    .reflog("HEAD")
+    .unwrap()
+    .into_iter()
+    .map(|entry| (entry.old, entry.new, entry.message.to_string()))
+    .collect();
+  assert_eq!(
+    vec![
+      (
+        first,
+        second,
+        "checkout: moving from master to topic".into()
+      ),
+      (
+        second,
+        first,
+        format!("checkout: moving from topic to {}", first)
+      ),
+      (zero, first, "checkout: moving from orphan to master".into()),
+    ],
+    log
+  );
+
+  assert!(matches!(
+    repo.set_head("topic", &committer),
+    Err(HeadError::InvalidName(_))
+  ));
+  refs.write("refs/heads/tree", &tree).unwrap();
+  assert!(matches!(
+    repo.set_head("refs/heads/tree", &committer),
+    Err(HeadError::NotACommit { .. })
+  ));
+  assert!(matches!(
+    repo.detach_head(&tree, &committer),
+    Err(HeadError::NotACommit { .. })
+  ));
+  assert_eq!(Some("master".into()), repo.current_branch().unwrap());
+}
@@ -0,0 +1,1476 @@
+use crate::{
+  bitmap::{read_ewah, write_ewah, Bitset},
+  endian::{read_u16, read_u32},
+  AttributesError, Blob, CheckStat, CheckoutOptions, Config, ConfigError, FileMode, FilterError,
+  OIDError, Odb, OdbError, Repository, Trace2, Tree, TreeEntry, OID,
+};
+use bstr::{BString, ByteSlice};
+use sha1::{Digest, Sha1};
+use std::{
+  cmp::Ordering,
+  convert::TryFrom,
+  fs,
+  io::{self, Read, Write},
+  path::{Path, PathBuf},
+  time::SystemTime,
+};
+use thiserror::Error;
+
+/// The index, also called the staging area, stored in `.git/index`. It
+/// records every path that will be part of the next commit along with the
+/// [`StatData`] of the file in the working tree when it was last looked at,
+/// so unchanged files don't have to be hashed again.
+///
+/// With `core.splitIndex` the file can be split in two, a shared index in
+/// `.git/sharedindex.{oid}` that rarely changes and the index file itself
+/// holding only the entries that were changed since it was written. This
+/// saves rewriting every entry of a huge index whenever one of them changes.
+///
+/// Two [`Index`]es are equal if they have the same entries, no matter when
+/// they were read or how they are stored.
+#[derive(Debug, Clone)]
+pub struct Index {
+  entries: Vec<IndexEntry>,
+  /// The modification time of the index file when it was read, as seconds
+  /// and nanoseconds
+  timestamp: Option<(u32, u32)>,
+  /// The version to write, see [`Index::set_version`]
+  version: u32,
+  /// Whether to write a split index, see [`Index::set_split`]
+  split: bool,
+  /// The shared index the file was split from when it was read
+  shared: Option<SharedIndex>,
+}
+
+/// The entries of a shared index file and the [`OID`] naming it, which is
+/// its checksum
+#[derive(Debug, Clone)]
+struct SharedIndex {
+  oid: OID,
+  entries: Vec<IndexEntry>,
+}
+
+/// The `link` extension of a split index, which says which entries of the
+/// shared index it deletes and which ones the first of its own entries
+/// replace
+struct Link {
+  shared: OID,
+  delete: Bitset,
+  replace: Bitset,
+}
+
+/// A single path in the [`Index`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+  /// The state of the file in the working tree when it was added
+  pub stat: StatData,
+  /// The mode of the file as it will be committed
+  pub mode: FileMode,
+  /// The [`OID`] of the [`Blob`][crate::Blob] with the file contents
+  pub oid: OID,
+  /// The merge stage of the entry, `0` unless there is a conflict
+  pub stage: u8,
+  /// Whether the file is left out of the working tree by a sparse checkout,
+  /// the skip-worktree bit. These entries are never compared with the
+  /// working tree, since their files aren't supposed to be there.
+  pub skip_worktree: bool,
+  /// The path of the file from the root of the working tree, separated by
+  /// `/`
+  pub path: BString,
+}
+
+/// The parts of a file's metadata that git uses to tell whether it changed.
+/// Every field is truncated to 32 bits like git does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatData {
+  pub ctime: u32,
+  pub ctime_nsec: u32,
+  pub mtime: u32,
+  pub mtime_nsec: u32,
+  pub dev: u32,
+  pub ino: u32,
+  pub uid: u32,
+  pub gid: u32,
+  pub size: u32,
+}
+
+impl StatData {
+  /// Get the [`StatData`] of a file
+  #[cfg(unix)]
+  pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+    use std::os::unix::fs::MetadataExt;
+    Self {
+      ctime: metadata.ctime() as u32,
+      ctime_nsec: metadata.ctime_nsec() as u32,
+      mtime: metadata.mtime() as u32,
+      mtime_nsec: metadata.mtime_nsec() as u32,
+      dev: metadata.dev() as u32,
+      ino: metadata.ino() as u32,
+      uid: metadata.uid(),
+      gid: metadata.gid(),
+      size: metadata.len() as u32,
+    }
+  }
+
+  /// Get the [`StatData`] of a file. Only the times and size are available
+  /// on this platform.
+  #[cfg(not(unix))]
+  pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let split = |time: io::Result<SystemTime>| {
+      time
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or((0, 0), |d| (d.as_secs() as u32, d.subsec_nanos()))
+    };
+    let (ctime, ctime_nsec) = split(metadata.created());
+    let (mtime, mtime_nsec) = split(metadata.modified());
+    Self {
+      ctime,
+      ctime_nsec,
+      mtime,
+      mtime_nsec,
+      size: metadata.len() as u32,
+      ..Self::default()
+    }
+  }
+}
+
+impl IndexEntry {
+  /// Create a new [`IndexEntry`] at stage 0
+  pub fn new(path: impl Into<BString>, mode: FileMode, oid: OID, stat: StatData) -> Self {
+    Self {
+      stat,
+      mode,
+      oid,
+      stage: 0,
+      skip_worktree: false,
+      path: path.into(),
+    }
+  }
+
+  /// The mode a file in the working tree should be recorded with, like
+  /// git's `ce_mode_from_stat`. With `core.fileMode=false` the executable
+  /// bit on disk is ignored and the mode already in the index is kept, and
+  /// with `core.symlinks=false` a plain file standing in for a symbolic
+  /// link stays a symbolic link.
+  pub fn worktree_mode(&self, metadata: &fs::Metadata, options: &CheckoutOptions) -> FileMode {
+    worktree_mode(Some(self.mode), metadata, options)
+  }
+
+  /// Whether the file in the working tree looks unchanged from this entry
+  /// going by its metadata alone, like git's `match_stat_data`. Executable
+  /// bits are only compared when `core.fileMode` is true, the ctime only
+  /// when `core.trustctime` is, and with `core.checkStat=minimal` nothing
+  /// but the modification time in seconds and the size.
+  pub fn is_stat_clean(&self, metadata: &fs::Metadata, options: &CheckoutOptions) -> bool {
+    let stat = StatData::from_metadata(metadata);
+    let (old, full) = (&self.stat, options.check_stat == CheckStat::Default);
+    self.worktree_mode(metadata, options) == self.mode
+      && stat.mtime == old.mtime
+      && stat.size == old.size
+      && (!full
+        || (stat.mtime_nsec == old.mtime_nsec
+          && stat.ino == old.ino
+          && stat.uid == old.uid
+          && stat.gid == old.gid))
+      && (!full
+        || !options.trust_ctime
+        || (stat.ctime == old.ctime && stat.ctime_nsec == old.ctime_nsec))
+  }
+}
+
+/// The mode for a file in the working tree given the mode it had in the
+/// [`Index`], if it was there at all
+pub(crate) fn worktree_mode(
+  existing: Option<FileMode>,
+  metadata: &fs::Metadata,
+  options: &CheckoutOptions,
+) -> FileMode {
+  let mode = FileMode::from_metadata(metadata);
+  match (existing, mode) {
+    (Some(FileMode::SymbolicLink), FileMode::NonExecutableFile | FileMode::ExecutableFile)
+      if !options.symlinks =>
+    {
+      FileMode::SymbolicLink
+    }
+    (existing, FileMode::NonExecutableFile | FileMode::ExecutableFile) if !options.file_mode => {
+      match existing {
+        Some(FileMode::ExecutableFile) => FileMode::ExecutableFile,
+        _ => FileMode::NonExecutableFile,
+      }
+    }
+    (_, mode) => mode,
+  }
+}
+
+const SIGNATURE: &[u8] = b"DIRC";
+/// The flag saying an entry has a second set of flags after the first
+const EXTENDED: u16 = 0x4000;
+/// The extended flag for the skip-worktree bit
+const SKIP_WORKTREE: u16 = 0x4000;
+/// The percentage of entries that can be missing from the shared index
+/// before a split index gets a new one, git's default for
+/// `splitIndex.maxPercentChange`
+const MAX_PERCENT_SPLIT_CHANGE: usize = 20;
+
+impl Default for Index {
+  fn default() -> Self {
+    Self::new(Vec::new())
+  }
+}
+
+impl Index {
+  /// Create an [`Index`] from a list of entries, which are sorted by path
+  /// and stage as git expects
+  pub fn new(mut entries: Vec<IndexEntry>) -> Self {
+    entries.sort_by(|a, b| (&a.path, a.stage).cmp(&(&b.path, b.stage)));
+    Self {
+      entries,
+      timestamp: None,
+      version: 2,
+      split: false,
+      shared: None,
+    }
+  }
+
+  /// Read the index file at `path`. A missing file is an empty [`Index`],
+  /// as is the case in a repository nothing has been added to yet. A split
+  /// index is read along with the shared index next to it.
+  pub fn open(path: impl AsRef<Path>) -> Result<Self, IndexError> {
+    let _region = Trace2::region("index", "do_read_index");
+    let path = path.as_ref();
+    let mut file = match fs::File::open(path) {
+      Ok(file) => file,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+      Err(e) => return Err(e.into()),
+    };
+    let stat = StatData::from_metadata(&file.metadata()?);
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let mut index = Self::parse_split(&bytes, |oid| {
+      let shared_path = shared_index_path(path, oid);
+      let bytes = match fs::read(&shared_path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+          return Err(IndexError::MissingSharedIndex(shared_path))
+        }
+        bytes => bytes?,
+      };
+      if bytes.len() < 20 || bytes[bytes.len() - 20..] != *oid.as_bytes() {
+        return Err(IndexError::Malformed("shared index has the wrong checksum"));
+      }
+      Self::parse(bytes)
+    })?;
+    index.timestamp = Some((stat.mtime, stat.mtime_nsec));
+    Trace2::data("index", "read/cache_nr", index.len());
+    Ok(index)
+  }
+
+  /// Read the index of a repository, which is written the way its
+  /// [`Config`][crate::Config] asks: split if `core.splitIndex` is set, and
+  /// as the version in `index.version` if the index file is new.
+  pub(crate) fn open_with_config(path: &Path, config: &Config) -> Result<Self, IndexError> {
+    let mut index = Self::open(path)?;
+    if index.timestamp.is_none() {
+      if let Some(version) = config.get_int("index.version")? {
+        let version = u32::try_from(version)
+          .ok()
+          .filter(|version| (2..=4).contains(version))
+          .ok_or_else(|| ConfigError::InvalidValue {
+            key: "index.version".into(),
+            value: version.to_string().into(),
+            expected: "2, 3, or 4",
+          })?;
+        index.set_version(version)?;
+      }
+    }
+    if let Some(split) = config.get_bool("core.splitindex")? {
+      index.set_split(split);
+    }
+    Ok(index)
+  }
+
+  /// Parse the contents of an index file. Versions 2, 3, and 4 are
+  /// supported. A split index can't be parsed on its own, see
+  /// [`Index::open`].
+  pub fn parse(bytes: impl AsRef<[u8]>) -> Result<Self, IndexError> {
+    Self::parse_split(bytes.as_ref(), |oid| {
+      Err(IndexError::MissingSharedIndex(
+        format!("sharedindex.{}", oid).into(),
+      ))
+    })
+  }
+
+  /// Parse the contents of an index file, reading the shared index with
+  /// `shared` if it's split
+  fn parse_split(
+    bytes: &[u8],
+    shared: impl FnOnce(&OID) -> Result<Self, IndexError>,
+  ) -> Result<Self, IndexError> {
+    if bytes.len() < 12 + 20 {
+      return Err(IndexError::Malformed("file is too short"));
+    }
+    let (content, checksum) = bytes.split_at(bytes.len() - 20);
+    if Sha1::digest(content)[..] != *checksum {
+      return Err(IndexError::ChecksumMismatch);
+    }
+    if &content[..4] != SIGNATURE {
+      return Err(IndexError::Malformed("missing DIRC signature"));
+    }
+    let version = read_u32(&content[4..]);
+    if !(2..=4).contains(&version) {
+      return Err(IndexError::UnsupportedVersion(version));
+    }
+    let count = read_u32(&content[8..]) as usize;
+    let mut pos = 12;
+    let mut entries: Vec<IndexEntry> = Vec::with_capacity(count.min(content.len() / 62));
+    for _ in 0..count {
+      let previous = entries.last().map_or(&b""[..], |entry| &entry.path);
+      let (entry, len) = parse_entry(&content[pos..], version, previous)?;
+      entries.push(entry);
+      pos += len;
+    }
+    let mut link = None;
+    // Extensions hold cached data like the cache tree. The ones whose
+    // signature starts with an uppercase letter are optional and can be
+    // skipped, the rest change the meaning of the index.
+    while pos < content.len() {
+      let header = content
+        .get(pos..pos + 8)
+        .ok_or(IndexError::Malformed("truncated extension"))?;
+      let len = u64::from(read_u32(&header[4..])) + 8;
+      let end = usize::try_from(len)
+        .ok()
+        .and_then(|len| pos.checked_add(len))
+        .filter(|end| *end <= content.len())
+        .ok_or(IndexError::Malformed("truncated extension"))?;
+      match &header[..4] {
+        b"link" => link = Some(parse_link(&content[pos + 8..end])?),
+        signature if !signature[0].is_ascii_uppercase() => {
+          return Err(IndexError::UnsupportedExtension(signature.into()))
+        }
+        _ => {}
+      }
+      pos = end;
+    }
+    let mut index = Self {
+      entries,
+      timestamp: None,
+      version,
+      split: false,
+      shared: None,
+    };
+    if let Some(link) = link {
+      let shared = shared(&link.shared)?;
+      index.merge_shared(link, shared)?;
+    }
+    Ok(index)
+  }
+
+  /// Turn the entries of a split index into every entry by applying them to
+  /// the ones of its shared index
+  fn merge_shared(&mut self, link: Link, shared: Self) -> Result<(), IndexError> {
+    let corrupt = IndexError::Malformed("link extension doesn't match the shared index");
+    let mut own = std::mem::take(&mut self.entries).into_iter();
+    let mut entries: Vec<Option<IndexEntry>> = shared.entries.iter().cloned().map(Some).collect();
+    // The entries replacing ones of the shared index come first and have no
+    // path since they take the one they replace
+    for position in link.replace.ones() {
+      let entry = own.next().filter(|entry| entry.path.is_empty());
+      match (entry, entries.get_mut(position as usize)) {
+        (Some(entry), Some(Some(replaced))) => {
+          *replaced = IndexEntry {
+            path: std::mem::take(&mut replaced.path),
+            ..entry
+          };
+        }
+        _ => return Err(corrupt),
+      }
+    }
+    for position in link.delete.ones() {
+      match entries.get_mut(position as usize) {
+        Some(entry) => *entry = None,
+        None => return Err(corrupt),
+      }
+    }
+    self.entries = entries.into_iter().flatten().collect();
+    for entry in own {
+      if entry.path.is_empty() {
+        return Err(corrupt);
+      }
+      self.add(entry);
+    }
+    self.split = true;
+    self.shared = Some(SharedIndex {
+      oid: link.shared,
+      entries: shared.entries,
+    });
+    Ok(())
+  }
+
+  /// The version of the index file format this [`Index`] is written as.
+  /// Version 3 adds flags that only some entries need, so it's only written
+  /// when they do and version 2 otherwise.
+  pub fn version(&self) -> u32 {
+    self.version
+  }
+
+  /// Set the version of the index file format, like `index.version`.
+  /// Version 4 stores each path as how it differs from the one before it,
+  /// which makes indexes with deep directories a lot smaller. Versions
+  /// other than 2, 3, and 4 are [`IndexError::UnsupportedVersion`].
+  pub fn set_version(&mut self, version: u32) -> Result<(), IndexError> {
+    if !(2..=4).contains(&version) {
+      return Err(IndexError::UnsupportedVersion(version));
+    }
+    self.version = version;
+    Ok(())
+  }
+
+  /// Whether [`Index::write`] splits the index, which is the case when it
+  /// was read from a split index or [`Index::set_split`] is used
+  pub fn is_split(&self) -> bool {
+    self.split
+  }
+
+  /// Set whether [`Index::write`] splits the index into a shared index and
+  /// the entries changed since, like `core.splitIndex`
+  pub fn set_split(&mut self, split: bool) {
+    self.split = split;
+  }
+
+  /// The on disk representation of the [`Index`] with every entry in one
+  /// file and no extensions. This is the version from [`Index::version`],
+  /// or version 3 when it's 2 and an entry has the skip-worktree bit set
+  /// since that needs the extended flags.
+  pub fn as_bytes(&self) -> Vec<u8> {
+    let entries: Vec<_> = self
+      .entries
+      .iter()
+      .map(|entry| (entry, entry.path.as_bytes()))
+      .collect();
+    self.encode(&entries, None)
+  }
+
+  /// Write the index file with `entries` and the path each of them is
+  /// written with, which is empty for the entries of a split index that
+  /// replace ones of the shared index
+  fn encode(&self, entries: &[(&IndexEntry, &[u8])], link: Option<&Link>) -> Vec<u8> {
+    let extended = entries.iter().any(|(entry, _)| entry.skip_worktree);
+    let version: u32 = match self.version {
+      4 => 4,
+      _ if extended => 3,
+      _ => 2,
+    };
+    let mut bytes = SIGNATURE.to_vec();
+    bytes.extend_from_slice(&version.to_be_bytes());
+    bytes.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    let mut previous: &[u8] = b"";
+    for &(entry, path) in entries {
+      let start = bytes.len();
+      let stat = &entry.stat;
+      for field in [
+        stat.ctime,
+        stat.ctime_nsec,
+        stat.mtime,
+        stat.mtime_nsec,
+        stat.dev,
+        stat.ino,
+        mode_to_u32(entry.mode),
+        stat.uid,
+        stat.gid,
+        stat.size,
+      ] {
+        bytes.extend_from_slice(&field.to_be_bytes());
+      }
+      bytes.extend_from_slice(entry.oid.as_bytes());
+      let mut flags = (u16::from(entry.stage & 0b11) << 12) | path.len().min(0xfff) as u16;
+      if entry.skip_worktree {
+        flags |= EXTENDED;
+      }
+      bytes.extend_from_slice(&flags.to_be_bytes());
+      if entry.skip_worktree {
+        bytes.extend_from_slice(&SKIP_WORKTREE.to_be_bytes());
+      }
+      if version == 4 {
+        // How many bytes to drop from the end of the path before, and then
+        // what to add in their place
+        let common = previous
+          .iter()
+          .zip(path)
+          .take_while(|(a, b)| a == b)
+          .count();
+        write_varint(&mut bytes, (previous.len() - common) as u64);
+        bytes.extend_from_slice(&path[common..]);
+        bytes.push(0);
+        previous = path;
+      } else {
+        bytes.extend_from_slice(path);
+        // Entries are padded with 1 to 8 NUL bytes to a multiple of 8
+        let len = bytes.len() - start;
+        bytes.resize(start + (len + 8) / 8 * 8, 0);
+      }
+    }
+    if let Some(link) = link {
+      let mut data = link.shared.as_bytes().to_vec();
+      write_ewah(&link.delete, &mut data);
+      write_ewah(&link.replace, &mut data);
+      bytes.extend_from_slice(b"link");
+      bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+      bytes.extend_from_slice(&data);
+    }
+    let checksum = Sha1::digest(&bytes);
+    bytes.extend_from_slice(&checksum);
+    bytes
+  }
+
+  /// The contents of a split index file at `path`. The shared index it was
+  /// read with is kept unless too many of the entries aren't in it any
+  /// more, in which case every entry is written to a new one.
+  fn split_bytes(&self, path: &Path) -> Result<Vec<u8>, IndexError> {
+    let shared = self
+      .shared
+      .as_ref()
+      .filter(|shared| shared_index_path(path, &shared.oid).is_file());
+    if let Some(shared) = shared {
+      let (mut delete, mut replace) = (Bitset::default(), Bitset::default());
+      let (mut replaced, mut added) = (Vec::new(), Vec::new());
+      let (mut old, mut new) = (
+        shared.entries.iter().peekable(),
+        self.entries.iter().peekable(),
+      );
+      let mut position = 0u32;
+      loop {
+        let order = match (old.peek(), new.peek()) {
+          (None, None) => break,
+          (Some(a), Some(b)) => (&a.path, a.stage).cmp(&(&b.path, b.stage)),
+          (Some(_), None) => Ordering::Less,
+          (None, Some(_)) => Ordering::Greater,
+        };
+        match order {
+          Ordering::Less => {
+            delete.set(position);
+            old.next();
+            position += 1;
+          }
+          Ordering::Greater => added.push(new.next().unwrap()),
+          Ordering::Equal => {
+            let entry = new.next().unwrap();
+            if old.next() != Some(entry) {
+              replace.set(position);
+              replaced.push(entry);
+            }
+            position += 1;
+          }
+        }
+      }
+      if added.len() * 100 <= self.entries.len() * MAX_PERCENT_SPLIT_CHANGE {
+        let entries: Vec<_> = replaced
+          .into_iter()
+          .map(|entry| (entry, &b""[..]))
+          .chain(
+            added
+              .into_iter()
+              .map(|entry| (entry, entry.path.as_bytes())),
+          )
+          .collect();
+        // Someone else cleaning up shared indexes goes by when they were
+        // last used
+        let shared_path = shared_index_path(path, &shared.oid);
+        fs::File::options()
+          .write(true)
+          .open(shared_path)?
+          .set_modified(SystemTime::now())?;
+        let link = Link {
+          shared: shared.oid,
+          delete,
+          replace,
+        };
+        return Ok(self.encode(&entries, Some(&link)));
+      }
+    }
+    let bytes = self.as_bytes();
+    let oid = OID::from_bytes(&bytes[bytes.len() - 20..])?;
+    let shared_path = shared_index_path(path, &oid);
+    if !shared_path.is_file() {
+      write_locked(&shared_path, &bytes)?;
+    }
+    let link = Link {
+      shared: oid,
+      delete: Bitset::default(),
+      replace: Bitset::default(),
+    };
+    Ok(self.encode(&[], Some(&link)))
+  }
+
+  /// Write the [`Index`] to `path`. The new contents are written to
+  /// `{path}.lock` first and then moved into place, and it's an error if the
+  /// lock file already exists since that means someone else is changing the
+  /// index. A split index, see [`Index::set_split`], gets its shared index
+  /// written next to it if it needs a new one.
+  pub fn write(&self, path: impl AsRef<Path>) -> Result<(), IndexError> {
+    let _region = Trace2::region("index", "do_write_index");
+    let path = path.as_ref();
+    let bytes = match self.split {
+      true => self.split_bytes(path)?,
+      false => self.as_bytes(),
+    };
+    write_locked(path, &bytes)
+  }
+
+  /// Make an [`Index`] with every file in the [`Tree`][crate::Tree] with the
+  /// given [`OID`], like `git read-tree`. The entries have no [`StatData`]
+  /// so every file will be looked at the next time it's compared with the
+  /// working tree.
+  pub fn from_tree(odb: &Odb, tree: &OID) -> Result<Self, IndexError> {
+    let mut entries = Vec::new();
+    read_tree(odb, tree, b"", &mut entries)?;
+    Ok(Self::new(entries))
+  }
+
+  /// Write the [`Tree`]s holding every entry to the [`Odb`] and return the
+  /// [`OID`] of the top one, like `git write-tree`. It's an error if any
+  /// path still has merge conflicts.
+  pub fn write_tree(&self, odb: &Odb) -> Result<OID, IndexError> {
+    if let Some(entry) = self.entries.iter().find(|entry| entry.stage != 0) {
+      return Err(IndexError::Unmerged(entry.path.clone()));
+    }
+    write_tree(odb, &self.entries, 0)
+  }
+
+  /// Every entry sorted by path and stage
+  pub fn entries(&self) -> &[IndexEntry] {
+    &self.entries
+  }
+
+  /// Get the stage 0 entry for a path
+  pub fn get(&self, path: impl AsRef<[u8]>) -> Option<&IndexEntry> {
+    let path = path.as_ref();
+    self.find(path, 0).ok().map(|idx| &self.entries[idx])
+  }
+
+  /// Add an entry, replacing an existing one with the same path and stage
+  pub fn add(&mut self, entry: IndexEntry) {
+    match self.find(&entry.path, entry.stage) {
+      Ok(idx) => self.entries[idx] = entry,
+      Err(idx) => self.entries.insert(idx, entry),
+    }
+  }
+
+  /// Remove every stage of a path, returning whether it was in the index
+  pub fn remove(&mut self, path: impl AsRef<[u8]>) -> bool {
+    let path = path.as_ref();
+    let len = self.entries.len();
+    self.entries.retain(|entry| entry.path != path);
+    len != self.entries.len()
+  }
+
+  /// The number of entries
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  /// Whether there are no entries
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// Whether an entry was changed so close to when the index file was
+  /// written that its [`StatData`] can't be trusted. A file changed again in
+  /// the same instant as it was added, without changing size, looks clean
+  /// going by its metadata, so the contents of racy entries always have to
+  /// be compared. This is never the case for an [`Index`] that wasn't read
+  /// with [`Index::open`].
+  pub fn is_racy(&self, entry: &IndexEntry) -> bool {
+    match self.timestamp {
+      Some(timestamp) if entry.mode != FileMode::GitLink => {
+        timestamp <= (entry.stat.mtime, entry.stat.mtime_nsec)
+      }
+      _ => false,
+    }
+  }
+
+  /// Compare every entry with the file at its path in `work_dir` and record
+  /// the current [`StatData`] of the ones whose contents haven't changed but
+  /// whose metadata has, so the next comparison doesn't have to hash them
+  /// again, like `git update-index --refresh`. Returns how many entries were
+  /// changed, if any were the [`Index`] should be written back.
+  ///
+  /// Racy entries, see [`Index::is_racy`], whose metadata matches but whose
+  /// contents changed are smudged by setting their size to 0 like git does,
+  /// which makes sure they're seen as changed once the index is written
+  /// again and the time of the change is no longer close to when the index
+  /// was written.
+  pub fn refresh(
+    &mut self,
+    work_dir: impl AsRef<Path>,
+    options: &CheckoutOptions,
+  ) -> Result<usize, IndexError> {
+    let _region = Trace2::region("index", "refresh");
+    let work_dir = work_dir.as_ref();
+    let mut changed = 0;
+    for idx in 0..self.entries.len() {
+      let entry = &self.entries[idx];
+      if entry.stage != 0 || entry.mode == FileMode::GitLink || entry.skip_worktree {
+        continue;
+      }
+      let path = match entry.path.to_path() {
+        Ok(path) => work_dir.join(path),
+        Err(_) => continue,
+      };
+      let metadata = match fs::symlink_metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(e.into()),
+      };
+      let clean = entry.is_stat_clean(&metadata, options);
+      if (clean && !self.is_racy(entry))
+        || metadata.is_dir()
+        || entry.worktree_mode(&metadata, options) != entry.mode
+        || (entry.stat.size != 0 && entry.stat.size != metadata.len() as u32)
+      {
+        continue;
+      }
+      let same = hash_file(&path, &metadata, &entry.path, options)? == entry.oid;
+      let entry = &mut self.entries[idx];
+      if same && !clean {
+        entry.stat = StatData::from_metadata(&metadata);
+        changed += 1;
+      } else if !same && clean {
+        entry.stat.size = 0;
+        changed += 1;
+      }
+    }
+    Trace2::data("index", "refresh/changed", changed);
+    Ok(changed)
+  }
+
+  fn find(&self, path: &[u8], stage: u8) -> Result<usize, usize> {
+    self
+      .entries
+      .binary_search_by(|entry| (entry.path.as_bytes(), entry.stage).cmp(&(path, stage)))
+  }
+}
+
+impl Repository {
+  /// Refresh the [`Index`] of the repository against its working tree using
+  /// the [`CheckoutOptions`] from its [`Config`][crate::Config], writing it
+  /// back if any entries changed. See [`Index::refresh`].
+  pub fn refresh_index(&self) -> Result<usize, IndexError> {
+    let work_dir = self.work_dir().ok_or(IndexError::BareRepository)?;
+    let mut options = CheckoutOptions::from_config(self.config())?;
+    options.set_attributes(self.attributes().map_err(Box::new)?, Some(work_dir));
+    let mut index = self.index()?;
+    let changed = index.refresh(work_dir, &options)?;
+    if changed > 0 {
+      index.write(self.index_path())?;
+    }
+    Ok(changed)
+  }
+}
+
+impl PartialEq for Index {
+  fn eq(&self, other: &Self) -> bool {
+    self.entries == other.entries
+  }
+}
+
+impl Eq for Index {}
+
+/// The [`OID`] of the file or symbolic link at `path` as it would be added
+/// to the [`Index`] at `index_path`, with its line endings converted
+pub(crate) fn hash_file(
+  path: &Path,
+  metadata: &fs::Metadata,
+  index_path: &[u8],
+  options: &CheckoutOptions,
+) -> Result<OID, FilterError> {
+  Ok(file_blob(path, metadata, index_path, options)?.id())
+}
+
+/// The [`Blob`] [`hash_file`] hashes
+pub(crate) fn file_blob(
+  path: &Path,
+  metadata: &fs::Metadata,
+  index_path: &[u8],
+  options: &CheckoutOptions,
+) -> Result<Blob, FilterError> {
+  let blob = if metadata.file_type().is_symlink() {
+    let target = fs::read_link(path)?;
+    let target = <[u8]>::from_path(&target).ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("the target of {:?} can't be stored in git", path),
+      )
+    })?;
+    Blob::new(target)
+  } else {
+    let contents = fs::read(path)?;
+    Blob::new(options.to_git(index_path, &contents, None)?)
+  };
+  Ok(blob)
+}
+
+/// Write `bytes` to `{path}.lock` and move it into place
+fn write_locked(path: &Path, bytes: &[u8]) -> Result<(), IndexError> {
+  let mut lock_path = path.as_os_str().to_owned();
+  lock_path.push(".lock");
+  let lock_path = PathBuf::from(lock_path);
+  let mut lock = match fs::OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .open(&lock_path)
+  {
+    Ok(lock) => lock,
+    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+      return Err(IndexError::Locked(lock_path))
+    }
+    Err(e) => return Err(e.into()),
+  };
+  let result = lock
+    .write_all(bytes)
+    .and_then(|_| lock.sync_all())
+    .and_then(|_| fs::rename(&lock_path, path));
+  if let Err(e) = result {
+    let _ = fs::remove_file(&lock_path);
+    return Err(e.into());
+  }
+  Ok(())
+}
+
+/// The shared index with the given [`OID`] of the split index at `path`,
+/// which is in the same directory
+fn shared_index_path(path: &Path, oid: &OID) -> PathBuf {
+  let name = format!("sharedindex.{}", oid);
+  match path.parent() {
+    Some(dir) => dir.join(name),
+    None => name.into(),
+  }
+}
+
+fn parse_link(data: &[u8]) -> Result<Link, IndexError> {
+  let malformed = |_| IndexError::Malformed("malformed link extension");
+  let shared = OID::from_bytes(
+    data
+      .get(..20)
+      .ok_or(IndexError::Malformed("truncated link extension"))?,
+  )?;
+  // The bitmaps can be left out when they'd both be empty
+  if data.len() == 20 {
+    return Ok(Link {
+      shared,
+      delete: Bitset::default(),
+      replace: Bitset::default(),
+    });
+  }
+  let (delete, len) = read_ewah(&data[20..]).map_err(malformed)?;
+  let (replace, rest) = read_ewah(&data[20 + len..]).map_err(malformed)?;
+  if 20 + len + rest != data.len() {
+    return Err(IndexError::Malformed("malformed link extension"));
+  }
+  Ok(Link {
+    shared,
+    delete,
+    replace,
+  })
+}
+
+/// Read the variable length integer at the start of `bytes` used by
+/// version 4 of the index, the same encoding pack files use for the offsets
+/// of deltas: 7 bits a byte with the high bit set while there are more, and
+/// one added to the value before each shift so no value has two encodings
+fn read_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+  let mut len = 0;
+  let mut value = 0u64;
+  loop {
+    let byte = *bytes.get(len)?;
+    len += 1;
+    value = (value << 7) | u64::from(byte & 0x7f);
+    if byte & 0x80 == 0 {
+      return Some((value, len));
+    }
+    value = value.checked_add(1).filter(|value| value >> 57 == 0)?;
+  }
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+  let mut encoded = vec![value as u8 & 0x7f];
+  while value >> 7 != 0 {
+    value = (value >> 7) - 1;
+    encoded.push(0x80 | (value as u8 & 0x7f));
+  }
+  bytes.extend(encoded.iter().rev());
+}
+
+/// Parse the entry at the start of `bytes`, whose path in version 4 is
+/// stored as how it differs from `previous`
+fn parse_entry(
+  bytes: &[u8],
+  version: u32,
+  previous: &[u8],
+) -> Result<(IndexEntry, usize), IndexError> {
+  let truncated = IndexError::Malformed("truncated entry");
+  if bytes.len() < 62 {
+    return Err(truncated);
+  }
+  let field = |idx: usize| read_u32(&bytes[idx * 4..]);
+  let stat = StatData {
+    ctime: field(0),
+    ctime_nsec: field(1),
+    mtime: field(2),
+    mtime_nsec: field(3),
+    dev: field(4),
+    ino: field(5),
+    uid: field(7),
+    gid: field(8),
+    size: field(9),
+  };
+  let mode = mode_from_u32(field(6))?;
+  let oid = OID::from_bytes(&bytes[40..60])?;
+  let flags = read_u16(&bytes[60..]);
+  let mut path_start = 62;
+  let mut skip_worktree = false;
+  if flags & EXTENDED != 0 {
+    if version < 3 {
+      return Err(IndexError::Malformed("extended flags before version 3"));
+    }
+    let extended = read_u16(
+      bytes
+        .get(62..64)
+        .ok_or(IndexError::Malformed("truncated entry"))?,
+    );
+    skip_worktree = extended & SKIP_WORKTREE != 0;
+    path_start += 2;
+  }
+  let (path, len) = if version == 4 {
+    let (strip, varint_len) = read_varint(&bytes[path_start..])
+      .ok_or(IndexError::Malformed("malformed path compression"))?;
+    let kept = usize::try_from(strip)
+      .ok()
+      .and_then(|strip| previous.len().checked_sub(strip))
+      .ok_or(IndexError::Malformed("malformed path compression"))?;
+    let suffix_start = path_start + varint_len;
+    let suffix_len = bytes[suffix_start..].find_byte(0).ok_or(truncated)?;
+    let path = [
+      &previous[..kept],
+      &bytes[suffix_start..suffix_start + suffix_len],
+    ]
+    .concat();
+    (path, suffix_start + suffix_len + 1)
+  } else {
+    let path_len = bytes
+      .get(path_start..)
+      .and_then(|rest| rest.find_byte(0))
+      .ok_or(truncated)?;
+    let len = (path_start + path_len + 8) / 8 * 8;
+    if bytes.len() < len {
+      return Err(IndexError::Malformed("truncated entry"));
+    }
+    (bytes[path_start..path_start + path_len].to_vec(), len)
+  };
+  let entry = IndexEntry {
+    stat,
+    mode,
+    oid,
+    stage: ((flags >> 12) & 0b11) as u8,
+    skip_worktree,
+    path: path.into(),
+  };
+  Ok((entry, len))
+}
+
+fn mode_to_u32(mode: FileMode) -> u32 {
+  match mode {
+    FileMode::Tree => 0o040000,
+    FileMode::NonExecutableFile => 0o100644,
+    FileMode::ExecutableFile => 0o100755,
+    FileMode::SymbolicLink => 0o120000,
+    FileMode::GitLink => 0o160000,
+  }
+}
+
+fn mode_from_u32(mode: u32) -> Result<FileMode, IndexError> {
+  match mode {
+    0o100644 => Ok(FileMode::NonExecutableFile),
+    0o100755 => Ok(FileMode::ExecutableFile),
+    0o120000 => Ok(FileMode::SymbolicLink),
+    0o160000 => Ok(FileMode::GitLink),
+    mode => Err(IndexError::InvalidMode(mode)),
+  }
+}
+
+fn read_tree(
+  odb: &Odb,
+  tree: &OID,
+  prefix: &[u8],
+  entries: &mut Vec<IndexEntry>,
+) -> Result<(), IndexError> {
+  for entry in odb.read_tree(tree)?.entries() {
+    let path = [prefix, entry.name().as_bytes()].concat();
+    if entry.mode().is_tree() {
+      read_tree(odb, entry.oid(), &[&path[..], b"/"].concat(), entries)?;
+    } else {
+      entries.push(IndexEntry::new(
+        path,
+        entry.mode(),
+        *entry.oid(),
+        StatData::default(),
+      ));
+    }
+  }
+  Ok(())
+}
+
+/// Write the [`Tree`] for the entries that are all in the same directory,
+/// whose path is the first `prefix_len` bytes of theirs
+fn write_tree(odb: &Odb, entries: &[IndexEntry], prefix_len: usize) -> Result<OID, IndexError> {
+  let mut tree = Vec::new();
+  let mut idx = 0;
+  while idx < entries.len() {
+    let name = &entries[idx].path[prefix_len..];
+    match name.find_byte(b'/') {
+      None => {
+        tree.push(TreeEntry::new(entries[idx].mode, name, entries[idx].oid));
+        idx += 1;
+      }
+      Some(slash) => {
+        // Entries are sorted by path so everything in a directory is
+        // together
+        let dir = &name[..=slash];
+        let len = entries[idx..]
+          .iter()
+          .take_while(|entry| entry.path[prefix_len..].starts_with(dir))
+          .count();
+        let oid = write_tree(odb, &entries[idx..idx + len], prefix_len + dir.len())?;
+        tree.push(TreeEntry::new(FileMode::Tree, &name[..slash], oid));
+        idx += len;
+      }
+    }
+  }
+  Ok(odb.write_tree(&Tree::new(tree))?)
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`Index`] type
+pub enum IndexError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  InvalidOID(#[from] OIDError),
+  #[error("the index is malformed: {0}")]
+  Malformed(&'static str),
+  #[error("the index checksum does not match its contents")]
+  ChecksumMismatch,
+  #[error("index version {0} is not supported")]
+  UnsupportedVersion(u32),
+  #[error("the index uses the unsupported required extension {0}")]
+  UnsupportedExtension(BString),
+  #[error("{0:o} is not a valid mode for an index entry")]
+  InvalidMode(u32),
+  #[error("the index is locked by {0:?}")]
+  Locked(PathBuf),
+  #[error("the shared index {0:?} of the split index is missing")]
+  MissingSharedIndex(PathBuf),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Attributes(#[from] Box<AttributesError>),
+  #[error("{0}")]
+  Filter(#[from] FilterError),
+  #[error("a bare repository has no working tree to refresh the index from")]
+  BareRepository,
+  #[error("{0:?} has unresolved merge conflicts")]
+  Unmerged(BString),
+}
+
+#[test]
+fn round_trip() {
+  let tmp_dir = tempdir::TempDir::new("index_test").unwrap();
+  let path = tmp_dir.path().join("index");
+  let oid = crate::Blob::new("this is a test").id();
+  let mut index = Index::new(vec![
+    IndexEntry::new(
+      "sub/run.sh",
+      FileMode::ExecutableFile,
+      oid,
+      StatData::default(),
+    ),
+    IndexEntry::new(
+      "a.txt",
+      FileMode::NonExecutableFile,
+      oid,
+      StatData::default(),
+    ),
+  ]);
+  index.add(IndexEntry::new(
+    "b",
+    FileMode::SymbolicLink,
+    oid,
+    StatData {
+      mtime: 5,
+      size: 14,
+      ..StatData::default()
+    },
+  ));
+  let paths: Vec<_> = index.entries().iter().map(|e| e.path.clone()).collect();
+  assert_eq!(vec!["a.txt", "b", "sub/run.sh"], paths);
+  index.write(&path).unwrap();
+  let read = Index::open(&path).unwrap();
+  assert_eq!(index, read);
+  assert_eq!(5, read.get("b").unwrap().stat.mtime);
+  assert!(index.remove("b"));
+  assert_eq!(2, index.len());
+
+  let mut bytes = fs::read(&path).unwrap();
+  bytes[20] ^= 1;
+  assert!(matches!(
+    Index::parse(&bytes),
+    Err(IndexError::ChecksumMismatch)
+  ));
+  assert!(Index::open(tmp_dir.path().join("missing"))
+    .unwrap()
+    .is_empty());
+}
+
+#[test]
+fn write_trees() {
+  use FileMode::*;
+  let tmp_dir = tempdir::TempDir::new("index_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  // `a-b` and `a0` sort around the entries in `a/` in the index
+  let tree = crate::diff::write_tree(
+    &odb,
+    &[
+      ("a-b", NonExecutableFile, "dash"),
+      ("a/c", ExecutableFile, "c"),
+      ("a/d/e", SymbolicLink, "../c"),
+      ("a0", NonExecutableFile, "zero"),
+      ("z", NonExecutableFile, "z"),
+    ],
+  );
+  let mut index = Index::from_tree(&odb, &tree).unwrap();
+  assert_eq!(tree, index.write_tree(&odb).unwrap());
+  assert_eq!(
+    odb.write_tree(&crate::Tree::default()).unwrap(),
+    Index::default().write_tree(&odb).unwrap()
+  );
+
+  let mut conflict = index.get("z").unwrap().clone();
+  conflict.stage = 2;
+  index.add(conflict);
+  assert!(matches!(
+    index.write_tree(&odb),
+    Err(IndexError::Unmerged(path)) if path == "z"
+  ));
+}
+
+#[cfg(unix)]
+#[test]
+fn worktree_mode_without_file_mode() {
+  use std::os::unix::fs::PermissionsExt;
+  let tmp_dir = tempdir::TempDir::new("index_test").unwrap();
+  let path = tmp_dir.path().join("run.sh");
+  fs::write(&path, "this is a test").unwrap();
+  fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+  let metadata = fs::symlink_metadata(&path).unwrap();
+  let oid = crate::Blob::new("this is a test").id();
+  let mut entry = IndexEntry::new(
+    "run.sh",
+    FileMode::NonExecutableFile,
+    oid,
+    StatData::from_metadata(&metadata),
+  );
+
+  let options = CheckoutOptions::default();
+  assert_eq!(
+    FileMode::ExecutableFile,
+    entry.worktree_mode(&metadata, &options)
+  );
+  assert!(!entry.is_stat_clean(&metadata, &options));
+
+  let options = CheckoutOptions {
+    file_mode: false,
+    ..CheckoutOptions::default()
+  };
+  assert_eq!(
+    FileMode::NonExecutableFile,
+    entry.worktree_mode(&metadata, &options)
+  );
+  assert!(entry.is_stat_clean(&metadata, &options));
+  entry.mode = FileMode::ExecutableFile;
+  assert!(entry.is_stat_clean(&metadata, &options));
+}
+
+#[test]
+fn stat_config() {
+  use crate::Config;
+  let tmp_dir = tempdir::TempDir::new("index_test").unwrap();
+  let path = tmp_dir.path().join("file");
+  fs::write(&path, "contents").unwrap();
+  let metadata = fs::symlink_metadata(&path).unwrap();
+  let stat = StatData::from_metadata(&metadata);
+  let entry = |stat: StatData| {
+    IndexEntry::new(
+      "file",
+      FileMode::NonExecutableFile,
+      OID::from_bytes(&[0; 20]).unwrap(),
+      stat,
+    )
+  };
+  let default = CheckoutOptions::default();
+  let config = |text: &str| CheckoutOptions::from_config(&Config::from_bytes(text).unwrap());
+  let no_ctime = config("[core]\n\ttrustctime = false\n").unwrap();
+  let minimal = config("[core]\n\tcheckStat = Minimal\n").unwrap();
+  assert_eq!(CheckStat::Minimal, minimal.check_stat);
+  assert!(config("[core]\n\tcheckstat = some\n").is_err());
+
+  // The device is never compared
+  let moved = StatData {
+    dev: stat.dev + 1,
+    ..stat
+  };
+  assert!(entry(moved).is_stat_clean(&metadata, &default));
+
+  let touched = StatData {
+    ctime: stat.ctime + 1,
+    ..stat
+  };
+  assert!(!entry(touched).is_stat_clean(&metadata, &default));
+  assert!(entry(touched).is_stat_clean(&metadata, &no_ctime));
+
+  let remounted = StatData {
+    ino: stat.ino + 1,
+    uid: stat.uid + 1,
+    gid: stat.gid + 1,
+    mtime_nsec: stat.mtime_nsec + 1,
+    ..touched
+  };
+  assert!(!entry(remounted).is_stat_clean(&metadata, &no_ctime));
+  assert!(entry(remounted).is_stat_clean(&metadata, &minimal));
+  let resized = StatData {
+    size: stat.size + 1,
+    ..remounted
+  };
+  assert!(!entry(resized).is_stat_clean(&metadata, &minimal));
+}
+
+#[test]
+fn racy_entries() {
+  use crate::{worktree_status, StatusOptions};
+  let tmp_dir = tempdir::TempDir::new("index_test").unwrap();
+  let index_path = tmp_dir.path().join("index");
+  let work_dir = tmp_dir.path().join("work");
+  fs::create_dir(&work_dir).unwrap();
+  // The file changed in the same instant the entry for it was made, so its
+  // metadata matches but the contents don't
+  let path = work_dir.join("a.txt");
+  fs::write(&path, "xyz").unwrap();
+  let metadata = fs::symlink_metadata(&path).unwrap();
+  let entry = IndexEntry::new(
+    "a.txt",
+    FileMode::NonExecutableFile,
+    Blob::new("abc").id(),
+    StatData::from_metadata(&metadata),
+  );
+  Index::new(vec![entry]).write(&index_path).unwrap();
+  let set_index_mtime = |time| {
+    fs::File::options()
+      .write(true)
+      .open(&index_path)
+      .unwrap()
+      .set_modified(time)
+      .unwrap()
+  };
+  let status = |index: &Index| worktree_status(index, &work_dir, &StatusOptions::default());
+
+  // Written at the same time as the file was changed
+  set_index_mtime(metadata.modified().unwrap());
+  let mut index = Index::open(&index_path).unwrap();
+  assert!(index.is_racy(&index.entries()[0]));
+  assert_eq!(1, status(&index).unwrap().len());
+
+  // Once the index is written later the metadata is trusted, which is why
+  // racy entries have to be smudged before that happens
+  let later = metadata.modified().unwrap() + std::time::Duration::from_secs(10);
+  set_index_mtime(later);
+  let stale = Index::open(&index_path).unwrap();
+  assert!(!stale.is_racy(&stale.entries()[0]));
+  assert!(status(&stale).unwrap().is_empty());
+
+  assert_eq!(
+    1,
+    index
+      .refresh(&work_dir, &CheckoutOptions::default())
+      .unwrap()
+  );
+  assert_eq!(0, index.entries()[0].stat.size);
+  index.write(&index_path).unwrap();
+  set_index_mtime(later);
+  let index = Index::open(&index_path).unwrap();
+  assert!(!index.is_racy(&index.entries()[0]));
+  assert_eq!(1, status(&index).unwrap().len());
+  assert!(!Index::new(index.entries().to_vec()).is_racy(&index.entries()[0]));
+}
+
+#[test]
+fn refresh() {
+  let tmp_dir = tempdir::TempDir::new("index_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let tree = crate::diff::write_tree(
+    repo.odb(),
+    &[
+      ("a.txt", FileMode::NonExecutableFile, "a"),
+      ("changed", FileMode::NonExecutableFile, "changed"),
+      ("sub/b.txt", FileMode::NonExecutableFile, "b"),
+    ],
+  );
+  repo.checkout_tree(&tree).unwrap();
+  fs::write(tmp_dir.path().join("changed"), "CHANGED").unwrap();
+  // An index made from the tree has no stat data, refreshing records it for
+  // every file that still matches
+  Index::from_tree(repo.odb(), &tree)
+    .unwrap()
+    .write(repo.index_path())
+    .unwrap();
+  assert_eq!(2, repo.refresh_index().unwrap());
+  let index = repo.index().unwrap();
+  let options = CheckoutOptions::default();
+  let clean = |path: &str| {
+    let metadata = fs::symlink_metadata(tmp_dir.path().join(path)).unwrap();
+    index.get(path).unwrap().is_stat_clean(&metadata, &options)
+  };
+  assert!(clean("a.txt"));
+  assert!(clean("sub/b.txt"));
+  assert!(!clean("changed"));
+  assert_eq!(StatData::default(), index.get("changed").unwrap().stat);
+}
+
+#[test]
+fn split_index_and_path_compression() {
+  use std::process::Command;
+  for value in [0, 1, 127, 128, 16511, 16512, u64::MAX >> 8] {
+    let mut bytes = Vec::new();
+    write_varint(&mut bytes, value);
+    assert_eq!(Some((value, bytes.len())), read_varint(&bytes));
+  }
+  assert_eq!(vec![0x80, 0], {
+    let mut bytes = Vec::new();
+    write_varint(&mut bytes, 128);
+    bytes
+  });
+
+  let tmp_dir = tempdir::TempDir::new("index_test").unwrap();
+  let path = tmp_dir.path().join("index");
+  let entry = |path: &str, contents: &str| {
+    IndexEntry::new(
+      path,
+      FileMode::NonExecutableFile,
+      crate::Blob::new(contents).id(),
+      StatData::default(),
+    )
+  };
+  let mut index = Index::new(
+    (0..10)
+      .map(|i| entry(&format!("deep/dir/file{}.txt", i), "contents"))
+      .collect(),
+  );
+  for unsupported in [1, 5] {
+    assert!(matches!(
+      index.set_version(unsupported),
+      Err(IndexError::UnsupportedVersion(v)) if v == unsupported
+    ));
+  }
+  assert_eq!(2, index.version());
+  index.set_version(4).unwrap();
+  let bytes = index.as_bytes();
+  assert!(bytes.len() < Index::new(index.entries().to_vec()).as_bytes().len());
+  assert_eq!(index, Index::parse(&bytes).unwrap());
+  assert_eq!(4, Index::parse(&bytes).unwrap().version());
+
+  // Splitting writes every entry to a shared index the first time, then
+  // only the changes while there are few enough of them
+  index.set_split(true);
+  index.write(&path).unwrap();
+  let shared_indexes = || {
+    let mut names: Vec<_> = fs::read_dir(tmp_dir.path())
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .filter(|name| name.starts_with("sharedindex."))
+      .collect();
+    names.sort();
+    names
+  };
+  assert_eq!(1, shared_indexes().len());
+  let mut read = Index::open(&path).unwrap();
+  assert_eq!(index, read);
+  assert!(read.is_split());
+  assert!(matches!(
+    Index::parse(fs::read(&path).unwrap()),
+    Err(IndexError::MissingSharedIndex(_))
+  ));
+  read.add(entry("deep/dir/file3.txt", "changed"));
+  read.remove("deep/dir/file5.txt");
+  read.add(entry("new.txt", "new"));
+  read.write(&path).unwrap();
+  assert_eq!(1, shared_indexes().len());
+  assert_eq!(read, Index::open(&path).unwrap());
+  assert!(fs::metadata(&path).unwrap().len() < bytes.len() as u64);
+  let mut read = Index::open(&path).unwrap();
+  read.add(entry("new2.txt", "new"));
+  read.add(entry("new3.txt", "new"));
+  read.write(&path).unwrap();
+  assert_eq!(2, shared_indexes().len());
+  assert_eq!(read, Index::open(&path).unwrap());
+  read.set_split(false);
+  read.write(&path).unwrap();
+  assert!(!Index::open(&path).unwrap().is_split());
+
+  // Git reads the indexes written here and the other way around
+  if !crate::transport::http::have_git() {
+    return;
+  }
+  let tmp_dir = tempdir::TempDir::new("index_test").unwrap();
+  let work_dir = tmp_dir.path();
+  let repo = Repository::init(work_dir).unwrap();
+  let git = |args: &[&str]| {
+    let output = Command::new("git")
+      .args(args)
+      .current_dir(work_dir)
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{}", output.stderr.as_bstr());
+    output.stdout
+  };
+  fs::create_dir_all(work_dir.join("a/b")).unwrap();
+  for i in 0..10 {
+    fs::write(work_dir.join(format!("a/b/{}.txt", i)), i.to_string()).unwrap();
+  }
+  git(&["config", "core.splitIndex", "true"]);
+  git(&["update-index", "--index-version", "4"]);
+  git(&["add", "."]);
+  fs::write(work_dir.join("a/b/3.txt"), "changed").unwrap();
+  git(&["add", "a/b/3.txt"]);
+  git(&["rm", "-q", "--cached", "a/b/5.txt"]);
+  let stage = |index: &Index| -> String {
+    index
+      .entries()
+      .iter()
+      .map(|entry| format!("100644 {} 0\t{}\n", entry.oid, entry.path))
+      .collect()
+  };
+  let mut index = repo.index().unwrap();
+  assert!(index.is_split());
+  assert_eq!(4, index.version());
+  assert_eq!(git(&["ls-files", "--stage"]).as_bstr(), stage(&index));
+  index.add(entry("a/b/new.txt", "new"));
+  index.remove("a/b/0.txt");
+  index.write(repo.index_path()).unwrap();
+  assert_eq!(git(&["ls-files", "--stage"]).as_bstr(), stage(&index));
+  git(&["rm", "-q", "--cached", "a/b/1.txt"]);
+  index.remove("a/b/1.txt");
+  assert_eq!(index, repo.index().unwrap());
+}
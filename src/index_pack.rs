@@ -0,0 +1,537 @@
+//! Storing packs as they're received, like `git index-pack`. The pack is
+//! read as a stream and written to a temporary file as it goes, so it's
+//! never in memory as a whole. Whole objects are hashed as they go by, and
+//! deltas are resolved once the pack is there by reading them back from
+//! the file, starting from the objects they're based on. A thin pack, with
+//! deltas against objects that aren't in it, is made whole by adding those
+//! objects to the end of it first.
+
+use crate::{
+  cleanup,
+  delta::apply_delta,
+  endian::read_u32,
+  pack::{write_index, EntryHeader, EntryKind, PackObject, PackWriter, PACK_SIGNATURE},
+  progress::Reporter,
+  zlib::{self, ZlibError},
+  MemoryBudget, ObjectKind, Odb, OdbError, PackError, ProgressStage, RawObject, Reservation, OID,
+};
+use std::{
+  collections::HashMap,
+  fs,
+  io::{self, Read, Seek, SeekFrom, Write},
+  path::{Path, PathBuf},
+};
+
+/// What [`Odb::index_pack`] stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexPackOutcome {
+  /// The checksum at the end of the pack, which it's named after
+  pub checksum: OID,
+  /// Every object in the pack in the order they're in it
+  pub objects: Vec<OID>,
+  /// How many of the objects at the end are bases from outside of a thin
+  /// pack that were added to make it whole
+  pub added_bases: usize,
+}
+
+/// How [`index_pack`] checks and stores a pack
+pub(crate) struct IndexOptions<'a> {
+  pub(crate) budget: &'a MemoryBudget,
+  pub(crate) max_depth: usize,
+  /// Where the bases of deltas that aren't in the pack are read from. A
+  /// pack that needs any fails with [`PackError::MissingBase`] without it.
+  pub(crate) bases: Option<&'a Odb>,
+  /// Whether to mark the pack as fetched from a promisor remote
+  pub(crate) promisor: bool,
+  /// Told about the objects received and the deltas resolved
+  pub(crate) progress: &'a Reporter,
+}
+
+/// A file in the pack directory that's removed again unless it's moved
+/// into place
+struct TempFile {
+  path: PathBuf,
+}
+
+impl TempFile {
+  fn new(dir: &Path, kind: &str) -> Self {
+    Self {
+      path: dir.join(cleanup::temp_name(kind)),
+    }
+  }
+
+  /// Move the file to `path`, unless the same file is there already
+  fn persist(self, path: &Path) -> io::Result<()> {
+    match path.exists() {
+      true => Ok(()),
+      false => fs::rename(&self.path, path),
+    }
+  }
+}
+
+impl Drop for TempFile {
+  fn drop(&mut self) {
+    let _ = fs::remove_file(&self.path);
+  }
+}
+
+/// The pack being read, which is hashed and written to `file` as entries
+/// are taken out of it
+struct Stream<R> {
+  input: R,
+  buf: Vec<u8>,
+  /// How much of `buf` was taken already
+  taken: usize,
+  /// Where in the pack what's next is
+  offset: u64,
+  eof: bool,
+  hasher: sha1::Sha1,
+  file: io::BufWriter<fs::File>,
+}
+
+impl<R: Read> Stream<R> {
+  fn new(input: R, file: fs::File) -> Self {
+    use sha1::Digest;
+    Self {
+      input,
+      buf: Vec::new(),
+      taken: 0,
+      offset: 0,
+      eof: false,
+      hasher: sha1::Sha1::new(),
+      file: io::BufWriter::new(file),
+    }
+  }
+
+  /// What's next in the pack, at least `len` bytes of it unless the pack
+  /// ends first
+  fn peek(&mut self, len: usize) -> io::Result<&[u8]> {
+    if self.buf.len() - self.taken < len {
+      self.buf.drain(..self.taken);
+      self.taken = 0;
+    }
+    while self.buf.len() < len && !self.eof {
+      // What's read is only as much as the pack has, however much is
+      // asked for
+      let start = self.buf.len();
+      self
+        .buf
+        .resize(start + (len - start).clamp(64 * 1024, 1 << 20), 0);
+      let read = loop {
+        match self.input.read(&mut self.buf[start..]) {
+          Ok(read) => break read,
+          Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+          Err(e) => {
+            self.buf.truncate(start);
+            return Err(e);
+          }
+        }
+      };
+      self.buf.truncate(start + read);
+      self.eof = read == 0;
+    }
+    Ok(&self.buf[self.taken..])
+  }
+
+  /// Take the next `len` bytes, which were peeked at already
+  fn take(&mut self, len: usize) -> io::Result<()> {
+    use sha1::Digest;
+    let bytes = &self.buf[self.taken..self.taken + len];
+    self.hasher.update(bytes);
+    self.file.write_all(bytes)?;
+    self.taken += len;
+    self.offset += len as u64;
+    Ok(())
+  }
+}
+
+/// An entry of the pack, as found while reading it
+struct Entry {
+  offset: u64,
+  /// Where the zlib stream of the entry starts
+  data_offset: u64,
+  kind: EntryKind,
+  size: usize,
+  crc: u32,
+  /// The object it is, once it's known
+  oid: Option<OID>,
+}
+
+/// Works out the objects the deltas of a pack make, reading them back from
+/// the pack file
+struct Resolver<'a> {
+  file: fs::File,
+  entries: Vec<Entry>,
+  /// Where the last entry ends
+  end: u64,
+  by_offset: HashMap<u64, Vec<usize>>,
+  by_oid: HashMap<OID, Vec<usize>>,
+  options: &'a IndexOptions<'a>,
+  /// How many deltas there are and how many were resolved so far
+  deltas: u64,
+  resolved: u64,
+}
+
+impl Resolver<'_> {
+  /// The deltas against the entry at `offset`, or the object `oid`, that
+  /// haven't been resolved yet
+  fn deltas_of(&mut self, offset: Option<u64>, oid: &OID) -> Vec<usize> {
+    let mut deltas = offset
+      .and_then(|offset| self.by_offset.remove(&offset))
+      .unwrap_or_default();
+    deltas.extend(self.by_oid.remove(oid).unwrap_or_default());
+    deltas
+  }
+
+  /// Inflate the data of entry `i`
+  fn read(&mut self, i: usize) -> Result<(Vec<u8>, Reservation), OdbError> {
+    let entry = &self.entries[i];
+    let end = self.entries.get(i + 1).map_or(self.end, |next| next.offset);
+    let mut compressed = vec![0; (end - entry.data_offset) as usize];
+    self.file.seek(SeekFrom::Start(entry.data_offset))?;
+    self.file.read_exact(&mut compressed)?;
+    let reservation = self.options.budget.try_reserve(entry.size)?;
+    let (data, _) =
+      zlib::decompress_with_limit(&compressed, entry.size).map_err(PackError::from)?;
+    Ok((data, reservation))
+  }
+
+  /// Resolve `deltas`, which are against the object `kind` and `data`, and
+  /// then the deltas against what they make and so on. Only the objects
+  /// along one chain are in memory at once.
+  fn resolve(
+    &mut self,
+    kind: ObjectKind,
+    data: Vec<u8>,
+    reservation: Reservation,
+    deltas: Vec<usize>,
+  ) -> Result<(), OdbError> {
+    let mut chain = vec![(data, reservation, deltas)];
+    loop {
+      let depth = chain.len();
+      let (base, _, deltas) = match chain.last_mut() {
+        Some(top) => top,
+        None => return Ok(()),
+      };
+      let i = match deltas.pop() {
+        Some(i) => i,
+        None => {
+          chain.pop();
+          continue;
+        }
+      };
+      if depth > self.options.max_depth {
+        return Err(PackError::DeltaTooDeep(self.options.max_depth).into());
+      }
+      if self.options.progress.is_cancelled() {
+        return Err(OdbError::Cancelled);
+      }
+      let (delta, _delta_reservation) = self.read(i)?;
+      let (data, reservation) = apply_delta(base, &delta, self.options.budget)?;
+      let object = RawObject::new(kind, data);
+      let oid = object.id();
+      self.entries[i].oid = Some(oid);
+      self.resolved += 1;
+      let progress = self.options.progress;
+      progress.update(
+        ProgressStage::ResolvingDeltas,
+        self.resolved,
+        Some(self.deltas),
+      );
+      let deltas = self.deltas_of(Some(self.entries[i].offset), &oid);
+      if !deltas.is_empty() {
+        chain.push((object.data, reservation, deltas));
+      }
+    }
+  }
+}
+
+/// Read a pack from `input` and store it in `dir` with an index, finding
+/// out the [`OID`] of every object in it along the way. Nothing is left in
+/// `dir` if the pack turns out to be corrupt, or thin without the bases it
+/// needs.
+pub(crate) fn index_pack(
+  dir: &Path,
+  input: impl Read,
+  options: &IndexOptions<'_>,
+) -> Result<IndexPackOutcome, OdbError> {
+  let created = !dir.exists();
+  fs::create_dir_all(dir)?;
+  let result = index_pack_in(dir, input, options);
+  if result.is_err() && created {
+    let _ = fs::remove_dir(dir);
+  }
+  result
+}
+
+fn index_pack_in(
+  dir: &Path,
+  input: impl Read,
+  options: &IndexOptions<'_>,
+) -> Result<IndexPackOutcome, OdbError> {
+  let received = TempFile::new(dir, "pack");
+  let mut stream = Stream::new(input, fs::File::create(&received.path)?);
+  let header = stream.peek(12)?;
+  if header.len() < 12 {
+    return Err(PackError::Malformed("pack is too short").into());
+  }
+  if &header[..4] != PACK_SIGNATURE {
+    return Err(PackError::Malformed("missing PACK signature").into());
+  }
+  let version = read_u32(&header[4..]);
+  if version != 2 && version != 3 {
+    return Err(PackError::UnsupportedVersion(version).into());
+  }
+  let count = read_u32(&header[8..]);
+  stream.take(12)?;
+
+  // Only whole objects can be hashed right away, deltas are only looked at
+  // to see where the next entry starts
+  let mut entries = Vec::new();
+  let mut by_offset: HashMap<u64, Vec<usize>> = HashMap::new();
+  let mut by_oid: HashMap<OID, Vec<usize>> = HashMap::new();
+  let mut starts = HashMap::new();
+  for i in 0..count as usize {
+    if options.progress.is_cancelled() {
+      return Err(OdbError::Cancelled);
+    }
+    let offset = stream.offset;
+    let bytes = stream.peek(32)?;
+    if bytes.is_empty() {
+      return Err(PackError::Malformed("pack has fewer entries than it says").into());
+    }
+    let (header, header_len) = EntryHeader::parse(bytes, offset)?;
+    let reservation = options.budget.try_reserve(header.size)?;
+    // Compressed data is rarely much bigger than it is inflated, but more
+    // of the pack is read until it's all there
+    let mut want = header_len + header.size.min(1 << 20) + 64;
+    let (data, len) = loop {
+      let bytes = stream.peek(want)?;
+      match zlib::decompress_with_limit(&bytes[header_len..], header.size) {
+        Ok(inflated) => break inflated,
+        Err(ZlibError::UnexpectedEnd) if bytes.len() >= want => want *= 2,
+        Err(e) => return Err(PackError::from(e).into()),
+      }
+    };
+    if data.len() != header.size {
+      return Err(PackError::Malformed("entry size does not match its data").into());
+    }
+    let crc = zlib::crc32(&stream.peek(header_len + len)?[..header_len + len]);
+    stream.take(header_len + len)?;
+    let oid = match header.kind {
+      EntryKind::Object(kind) => Some(RawObject::new(kind, data).id()),
+      EntryKind::OfsDelta(base) => {
+        if !starts.contains_key(&base) {
+          return Err(PackError::Malformed("delta base is not the start of an entry").into());
+        }
+        by_offset.entry(base).or_default().push(i);
+        None
+      }
+      EntryKind::RefDelta(base) => {
+        by_oid.entry(base).or_default().push(i);
+        None
+      }
+    };
+    drop(reservation);
+    let progress = options.progress;
+    progress.update(
+      ProgressStage::ReceivingObjects,
+      i as u64 + 1,
+      Some(count.into()),
+    );
+    progress.update(ProgressStage::ReceivingBytes, stream.offset, None);
+    starts.insert(offset, i);
+    entries.push(Entry {
+      offset,
+      data_offset: offset + header_len as u64,
+      kind: header.kind,
+      size: header.size,
+      crc,
+      oid,
+    });
+  }
+  let trailer = stream.peek(21)?;
+  if trailer.len() < 20 {
+    return Err(PackError::Malformed("pack is truncated").into());
+  }
+  if trailer.len() > 20 {
+    return Err(PackError::Malformed("pack has data after the last entry").into());
+  }
+  let mut checksum = OID::from_bytes(trailer).unwrap();
+  let end = stream.offset;
+  {
+    use sha1::Digest;
+    if stream.hasher.finalize()[..] != *checksum.as_bytes() {
+      return Err(PackError::Malformed("pack checksum does not match").into());
+    }
+  }
+  let mut file = stream.file;
+  file.write_all(checksum.as_bytes())?;
+  file.flush()?;
+  drop(file);
+
+  let deltas = entries.iter().filter(|entry| entry.oid.is_none()).count();
+  let mut resolver = Resolver {
+    file: fs::File::open(&received.path)?,
+    entries,
+    end,
+    by_offset,
+    by_oid,
+    options,
+    deltas: deltas as u64,
+    resolved: 0,
+  };
+  for i in 0..resolver.entries.len() {
+    if let (EntryKind::Object(kind), Some(oid)) =
+      (resolver.entries[i].kind, resolver.entries[i].oid)
+    {
+      let deltas = resolver.deltas_of(Some(resolver.entries[i].offset), &oid);
+      if !deltas.is_empty() {
+        let (data, reservation) = resolver.read(i)?;
+        resolver.resolve(kind, data, reservation, deltas)?;
+      }
+    }
+  }
+  // What's left are deltas against objects from outside of the pack, and
+  // the deltas against those
+  let mut added = Vec::new();
+  while let Some(base) = resolver.by_oid.keys().next().copied() {
+    let object = match options.bases.map(|bases| bases.read(&base)) {
+      Some(Ok(object)) => object,
+      Some(Err(OdbError::NotFound(_))) | None => return Err(PackError::MissingBase(base).into()),
+      Some(Err(e)) => return Err(e),
+    };
+    let deltas = resolver.deltas_of(None, &base);
+    let reservation = options.budget.try_reserve(object.data.len())?;
+    resolver.resolve(object.kind, object.data, reservation, deltas)?;
+    added.push(base);
+  }
+
+  let entries = resolver.entries;
+  let mut oids = Vec::with_capacity(entries.len() + added.len());
+  for entry in &entries {
+    oids.push(
+      entry
+        .oid
+        .ok_or(PackError::Malformed("delta base can't be resolved"))?,
+    );
+  }
+  let mut offsets: Vec<u64> = entries.iter().map(|entry| entry.offset).collect();
+  let mut crcs: Vec<u32> = entries.iter().map(|entry| entry.crc).collect();
+  let mut file = resolver.file;
+  let pack = match added.is_empty() {
+    true => {
+      drop(file);
+      received
+    }
+    false => {
+      // The added bases go after the entries that are there, which changes
+      // how many there are and so the checksum
+      let fixed = TempFile::new(dir, "pack");
+      let out = io::BufWriter::new(fs::File::create(&fixed.path)?);
+      let mut writer = PackWriter::new(out, count + added.len() as u32)?;
+      file.seek(SeekFrom::Start(12))?;
+      writer.copy(file.take(end - 12), count)?;
+      let bases = options
+        .bases
+        .expect("bases are only added when there's an odb to read them from");
+      for oid in &added {
+        // The bases are only needed once the deltas are resolved, so they
+        // aren't kept until then
+        let object = bases.read(oid)?;
+        offsets.push(writer.offset());
+        crcs.push(writer.add(&PackObject::Whole(object))?);
+        oids.push(*oid);
+      }
+      let (mut out, fixed_checksum) = writer.finish()?;
+      out.flush()?;
+      checksum = fixed_checksum;
+      fixed
+    }
+  };
+
+  let index = TempFile::new(dir, "idx");
+  fs::write(&index.path, write_index(&oids, &offsets, &crcs, &checksum))?;
+  let name = |extension: &str| dir.join(format!("pack-{}.{}", checksum, extension));
+  // The marker goes first so the pack is never seen without it, and the
+  // index last so the pack is only seen once it's complete
+  if options.promisor {
+    let marker = TempFile::new(dir, "promisor");
+    fs::write(&marker.path, b"")?;
+    marker.persist(&name("promisor"))?;
+  }
+  pack.persist(&name("pack"))?;
+  index.persist(&name("idx"))?;
+  Ok(IndexPackOutcome {
+    checksum,
+    objects: oids,
+    added_bases: added.len(),
+  })
+}
+
+#[test]
+fn index_thin_pack() {
+  use crate::{delta::make_delta, pack::build_pack, Blob};
+  /// Hands out the pack a few bytes at a time like a slow connection
+  struct Trickle<'a>(&'a [u8]);
+  impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      let len = buf.len().min(self.0.len()).min(7);
+      buf[..len].copy_from_slice(&self.0[..len]);
+      self.0 = &self.0[len..];
+      Ok(len)
+    }
+  }
+
+  let tmp_dir = tempdir::TempDir::new("index_pack_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let base = b"the base that the receiving side already has\n".repeat(20);
+  let base_oid = odb.write_blob(&Blob::new(base.clone())).unwrap();
+  let first = [&base[..], b"and a line more\n"].concat();
+  let second = [&first[..], b"and another\n"].concat();
+  let whole = RawObject::new(ObjectKind::Blob, "a whole object\n");
+  let pack = build_pack(&[
+    PackObject::RefDelta {
+      base: base_oid,
+      delta: make_delta(&base, &first),
+    },
+    PackObject::OfsDelta {
+      base: 12,
+      delta: make_delta(&first, &second),
+    },
+    PackObject::Whole(whole.clone()),
+  ]);
+
+  let outcome = odb.index_pack(Trickle(&pack)).unwrap();
+  let expected = vec![
+    RawObject::new(ObjectKind::Blob, first).id(),
+    RawObject::new(ObjectKind::Blob, second.clone()).id(),
+    whole.id(),
+    base_oid,
+  ];
+  assert_eq!(expected, outcome.objects);
+  assert_eq!(1, outcome.added_bases);
+  let pack_dir = tmp_dir.path().join("pack");
+  let name = format!("pack-{}", outcome.checksum);
+  assert!(pack_dir.join(format!("{}.idx", name)).is_file());
+  // The pack that's stored is whole, so it reads without the loose base
+  let stored = fs::read(pack_dir.join(format!("{}.pack", name))).unwrap();
+  assert_eq!(4, read_u32(&stored[8..]));
+  let packed = Odb::new(tmp_dir.path().join("packed"));
+  assert_eq!(expected, packed.write_pack(&stored).unwrap());
+  assert_eq!(second, packed.read(&expected[1]).unwrap().data);
+
+  // Without the base, or with a checksum that's off, nothing is stored
+  let empty = Odb::new(tmp_dir.path().join("empty"));
+  assert!(matches!(
+    empty.write_pack(&pack),
+    Err(OdbError::Pack(PackError::MissingBase(oid))) if oid == base_oid
+  ));
+  let mut corrupt = pack.clone();
+  *corrupt.last_mut().unwrap() ^= 1;
+  assert!(matches!(
+    empty.index_pack(&corrupt[..]),
+    Err(OdbError::Pack(PackError::Malformed(_)))
+  ));
+  assert!(!empty.path().join("pack").exists());
+}
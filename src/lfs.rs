@@ -0,0 +1,455 @@
+//! Git LFS pointers, the small text files Git LFS stores in blobs in place
+//! of large files. The file itself is kept in the LFS object store, named
+//! by its SHA-256:
+//!
+//! ```text
+//! version https://git-lfs.github.com/spec/v1
+//! oid sha256:4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393
+//! size 12345
+//! ```
+//!
+//! Reading and writing the store is all that's done here. Getting objects
+//! that aren't in it from an LFS server is left to Git LFS, which
+//! [`Repository::lfs_unhydrated`] says which files need.
+
+use crate::{cleanup, ConfigError, Index, IndexError, Odb, OdbError, Repository};
+use bstr::{BString, ByteSlice};
+use std::{
+  borrow::Cow,
+  fmt, fs,
+  io::{self, Write},
+  path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// The version line every pointer starts with
+const VERSION: &str = "https://git-lfs.github.com/spec/v1";
+
+/// The version line of pointers from before Git LFS had its name
+const OLD_VERSION: &str = "https://hawser.github.com/spec/v1";
+
+/// A pointer can't be any bigger than this, which is what lets blobs be
+/// ruled out without parsing them
+const MAX_POINTER_LEN: usize = 1024;
+
+/// A Git LFS pointer to a file in the LFS object store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LfsPointer {
+  oid: [u8; 32],
+  size: u64,
+}
+
+impl LfsPointer {
+  /// A pointer to the file with the SHA-256 `oid` that's `size` bytes long
+  pub fn new(oid: [u8; 32], size: u64) -> Self {
+    Self { oid, size }
+  }
+
+  /// The pointer for a file with the given contents
+  pub fn from_contents(contents: &[u8]) -> Self {
+    Self::new(sha256(contents), contents.len() as u64)
+  }
+
+  /// Parse a pointer. Like Git LFS this is strict, so a file that only
+  /// looks a bit like a pointer is taken to be a file of its own.
+  pub fn parse(bytes: &[u8]) -> Result<Self, LfsError> {
+    let malformed = |reason| Err(LfsError::Malformed(reason));
+    if bytes.len() > MAX_POINTER_LEN {
+      return malformed("too long to be a pointer");
+    }
+    if !bytes.ends_with(b"\n") {
+      return malformed("missing the final newline");
+    }
+    let mut lines = bytes[..bytes.len() - 1].split_str("\n");
+    match lines.next().and_then(|line| line.strip_prefix(b"version ")) {
+      Some(version) if version == VERSION.as_bytes() || version == OLD_VERSION.as_bytes() => {}
+      Some(version) => return Err(LfsError::UnsupportedVersion(version.into())),
+      None => return malformed("missing the version"),
+    }
+    let oid = match lines
+      .next()
+      .and_then(|line| line.strip_prefix(b"oid sha256:"))
+    {
+      Some(hex) if hex.len() == 64 && hex.iter().all(|c| b"0123456789abcdef".contains(c)) => {
+        let mut oid = [0; 32];
+        hex::decode_to_slice(hex, &mut oid).unwrap();
+        oid
+      }
+      _ => return malformed("missing a sha256 oid"),
+    };
+    let size = match lines.next().and_then(|line| line.strip_prefix(b"size ")) {
+      Some(size) if !size.is_empty() && size.iter().all(u8::is_ascii_digit) => {
+        match size.to_str().ok().and_then(|size| size.parse().ok()) {
+          Some(size) => size,
+          None => return malformed("the size is too big"),
+        }
+      }
+      _ => return malformed("missing the size"),
+    };
+    if lines.next().is_some() {
+      return malformed("extra lines after the size");
+    }
+    Ok(Self { oid, size })
+  }
+
+  /// Whether `bytes` are a pointer
+  pub fn is_pointer(bytes: &[u8]) -> bool {
+    Self::parse(bytes).is_ok()
+  }
+
+  /// The SHA-256 of the file
+  pub fn oid(&self) -> &[u8; 32] {
+    &self.oid
+  }
+
+  /// The SHA-256 of the file in hex, which names it in the store
+  pub fn oid_hex(&self) -> String {
+    hex::encode(self.oid)
+  }
+
+  /// The size of the file
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// The pointer as it's stored in a blob
+  pub fn to_bytes(&self) -> Vec<u8> {
+    self.to_string().into_bytes()
+  }
+}
+
+impl fmt::Display for LfsPointer {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "version {}\noid sha256:{}\nsize {}\n",
+      VERSION,
+      self.oid_hex(),
+      self.size
+    )
+  }
+}
+
+/// The files Git LFS has in a repository, in `lfs/objects` in the git
+/// directory unless `lfs.storage` says otherwise
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LfsStore {
+  dir: PathBuf,
+}
+
+impl LfsStore {
+  /// The store of files in `dir`, which is the `objects` directory, not
+  /// the `lfs` directory that has it
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    Self { dir: dir.into() }
+  }
+
+  /// The directory the files are in
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  /// Where the file `pointer` points to is stored, which is split up by
+  /// its first two bytes like `4d/7a/4d7a2146…`
+  pub fn path(&self, pointer: &LfsPointer) -> PathBuf {
+    let hex = pointer.oid_hex();
+    self.dir.join(&hex[..2]).join(&hex[2..4]).join(hex)
+  }
+
+  /// Whether the store has the file `pointer` points to
+  pub fn contains(&self, pointer: &LfsPointer) -> bool {
+    fs::metadata(self.path(pointer)).is_ok_and(|metadata| metadata.len() == pointer.size)
+  }
+
+  /// Read the file `pointer` points to, checking it's the right one
+  pub fn read(&self, pointer: &LfsPointer) -> Result<Vec<u8>, LfsError> {
+    let contents = match fs::read(self.path(pointer)) {
+      Ok(contents) => contents,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        return Err(LfsError::NotFound(pointer.oid_hex()))
+      }
+      Err(e) => return Err(e.into()),
+    };
+    if LfsPointer::from_contents(&contents) != *pointer {
+      return Err(LfsError::Corrupt(pointer.oid_hex()));
+    }
+    Ok(contents)
+  }
+
+  /// Store a file, returning the pointer to it. The file is written to a
+  /// temporary file first and then moved into place so other readers never
+  /// see part of it.
+  pub fn write(&self, contents: &[u8]) -> Result<LfsPointer, LfsError> {
+    let pointer = LfsPointer::from_contents(contents);
+    if self.contains(&pointer) {
+      return Ok(pointer);
+    }
+    let path = self.path(&pointer);
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    let tmp_path = dir.join(cleanup::temp_name("lfs"));
+    let result = fs::File::create(&tmp_path)
+      .and_then(|mut file| file.write_all(contents))
+      .and_then(|_| fs::rename(&tmp_path, &path));
+    if let Err(e) = result {
+      let _ = fs::remove_file(&tmp_path);
+      return Err(e.into());
+    }
+    Ok(pointer)
+  }
+
+  /// What the blob for a file should have: a pointer to it, once it's in
+  /// the store, when it's at least `threshold` bytes, or the file itself
+  /// when it's smaller or a pointer already
+  pub fn clean<'a>(&self, contents: &'a [u8], threshold: u64) -> Result<Cow<'a, [u8]>, LfsError> {
+    if (contents.len() as u64) < threshold || LfsPointer::is_pointer(contents) {
+      return Ok(Cow::Borrowed(contents));
+    }
+    Ok(Cow::Owned(self.write(contents)?.to_bytes()))
+  }
+
+  /// What the file for a blob should have: the file a pointer points to if
+  /// the store has it, or the blob itself when it isn't a pointer or the
+  /// file still has to be fetched
+  pub fn smudge<'a>(&self, contents: &'a [u8]) -> Result<Cow<'a, [u8]>, LfsError> {
+    match LfsPointer::parse(contents) {
+      Ok(pointer) if self.contains(&pointer) => Ok(Cow::Owned(self.read(&pointer)?)),
+      _ => Ok(Cow::Borrowed(contents)),
+    }
+  }
+}
+
+/// Every path of the [`Index`] whose blob is an LFS pointer, sorted by path
+pub fn index_pointers(odb: &Odb, index: &Index) -> Result<Vec<(BString, LfsPointer)>, LfsError> {
+  let mut pointers = Vec::new();
+  for entry in index.entries().iter().filter(|entry| entry.stage == 0) {
+    // Entries with a recorded size that couldn't be a pointer aren't read
+    if !entry.mode.is_blob() || entry.stat.size as usize > MAX_POINTER_LEN {
+      continue;
+    }
+    let blob = odb.read_blob(&entry.oid)?;
+    if let Ok(pointer) = LfsPointer::parse(blob.contents()) {
+      pointers.push((entry.path.clone(), pointer));
+    }
+  }
+  Ok(pointers)
+}
+
+impl Repository {
+  /// The LFS object store of the repository
+  pub fn lfs_store(&self) -> Result<LfsStore, LfsError> {
+    let dir = match self.config().get_path("lfs.storage")? {
+      Some(dir) if dir.is_absolute() => dir,
+      Some(dir) => self.common_dir().join(dir),
+      None => self.common_dir().join("lfs"),
+    };
+    Ok(LfsStore::new(dir.join("objects")))
+  }
+
+  /// The files of the working tree that are still the LFS pointer their
+  /// blob has, rather than the file it points to, because they were checked
+  /// out without Git LFS or before the file was fetched. These are what a
+  /// `git lfs pull` would replace.
+  pub fn lfs_unhydrated(&self) -> Result<Vec<(BString, LfsPointer)>, LfsError> {
+    let work_dir = self.work_dir().ok_or(LfsError::BareRepository)?;
+    let mut unhydrated = Vec::new();
+    for (path, pointer) in index_pointers(self.odb(), &self.index()?)? {
+      let file = work_dir.join(path.to_path_lossy());
+      let small = fs::symlink_metadata(&file)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.len() <= MAX_POINTER_LEN as u64);
+      if small && LfsPointer::parse(&fs::read(&file)?).ok() == Some(pointer) {
+        unhydrated.push((path, pointer));
+      }
+    }
+    Ok(unhydrated)
+  }
+}
+
+/// The SHA-256 of `data`, which is only used to name LFS files
+fn sha256(data: &[u8]) -> [u8; 32] {
+  const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+  ];
+  let mut state: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+  ];
+  let mut padded = data.to_vec();
+  padded.push(0x80);
+  while padded.len() % 64 != 56 {
+    padded.push(0);
+  }
+  padded.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+  for block in padded.chunks_exact(64) {
+    let mut w = [0u32; 64];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+      w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..64 {
+      let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+      let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+      w[i] = w[i - 16]
+        .wrapping_add(s0)
+        .wrapping_add(w[i - 7])
+        .wrapping_add(s1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+    for i in 0..64 {
+      let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+      let ch = (e & f) ^ (!e & g);
+      let t1 = h
+        .wrapping_add(s1)
+        .wrapping_add(ch)
+        .wrapping_add(K[i])
+        .wrapping_add(w[i]);
+      let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+      let maj = (a & b) ^ (a & c) ^ (b & c);
+      let t2 = s0.wrapping_add(maj);
+      h = g;
+      g = f;
+      f = e;
+      e = d.wrapping_add(t1);
+      d = c;
+      c = b;
+      b = a;
+      a = t1.wrapping_add(t2);
+    }
+    for (state, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+      *state = state.wrapping_add(*value);
+    }
+  }
+  let mut hash = [0; 32];
+  for (bytes, word) in hash.chunks_exact_mut(4).zip(state.iter()) {
+    bytes.copy_from_slice(&word.to_be_bytes());
+  }
+  hash
+}
+
+#[derive(Error, Debug)]
+/// Errors related to Git LFS pointers and the LFS object store
+pub enum LfsError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Index(#[from] IndexError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("not an LFS pointer: {0}")]
+  Malformed(&'static str),
+  #[error("LFS pointer version {0:?} is not supported")]
+  UnsupportedVersion(BString),
+  #[error("the LFS object {0} is not in the store")]
+  NotFound(String),
+  #[error("the LFS object {0} in the store doesn't match its oid")]
+  Corrupt(String),
+  #[error("a bare repository has no working tree to hydrate")]
+  BareRepository,
+}
+
+#[test]
+fn lfs_pointers() {
+  use crate::{diff::write_tree, FileMode::NonExecutableFile};
+  assert_eq!(
+    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+    hex::encode(sha256(b""))
+  );
+  assert_eq!(
+    "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+    hex::encode(sha256(
+      b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+    ))
+  );
+  let long = vec![b'a'; 1_000];
+  assert_eq!(
+    "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3",
+    hex::encode(sha256(&long))
+  );
+
+  let text = "version https://git-lfs.github.com/spec/v1\n\
+              oid sha256:4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393\n\
+              size 12345\n";
+  let pointer = LfsPointer::parse(text.as_bytes()).unwrap();
+  assert_eq!(12345, pointer.size());
+  assert_eq!(
+    "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393",
+    pointer.oid_hex()
+  );
+  assert_eq!(text.as_bytes(), &pointer.to_bytes()[..]);
+  let old = text.replace("git-lfs.github.com", "hawser.github.com");
+  assert_eq!(pointer, LfsPointer::parse(old.as_bytes()).unwrap());
+  assert!(matches!(
+    LfsPointer::parse(text.replace("v1", "v2").as_bytes()),
+    Err(LfsError::UnsupportedVersion(_))
+  ));
+  for bad in [
+    text.trim_end().to_string(),
+    text.replace("4d7a", "4D7A"),
+    text.replace("sha256", "sha1"),
+    text.replace("12345", "-1"),
+    format!("{}extra\n", text),
+    "hello\n".to_string(),
+  ]
+  .iter()
+  {
+    assert!(!LfsPointer::is_pointer(bad.as_bytes()), "{:?}", bad);
+  }
+
+  let tmp_dir = tempdir::TempDir::new("lfs_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let store = repo.lfs_store().unwrap();
+  assert_eq!(repo.git_dir().join("lfs/objects"), store.dir());
+  let contents = b"a large file\n".repeat(100);
+  assert_eq!(b"small", &store.clean(b"small", 1024).unwrap()[..]);
+  let cleaned = store.clean(&contents, 1024).unwrap().into_owned();
+  let pointer = LfsPointer::parse(&cleaned).unwrap();
+  assert_eq!(LfsPointer::from_contents(&contents), pointer);
+  assert!(store.contains(&pointer));
+  assert!(store.path(&pointer).ends_with(format!(
+    "{}/{}/{}",
+    &pointer.oid_hex()[..2],
+    &pointer.oid_hex()[2..4],
+    pointer.oid_hex()
+  )));
+  // Pointers aren't turned into pointers to themselves
+  assert_eq!(cleaned, &store.clean(&cleaned, 0).unwrap()[..]);
+  assert_eq!(contents, &store.smudge(&cleaned).unwrap()[..]);
+  let missing = LfsPointer::from_contents(b"missing").to_bytes();
+  assert_eq!(missing, &store.smudge(&missing).unwrap()[..]);
+  assert!(matches!(
+    store.read(&LfsPointer::from_contents(b"missing")),
+    Err(LfsError::NotFound(_))
+  ));
+
+  // A checkout without Git LFS leaves the pointers for it to replace
+  let missing = String::from_utf8(missing).unwrap();
+  let cleaned = String::from_utf8(cleaned).unwrap();
+  let tree = write_tree(
+    repo.odb(),
+    &[
+      ("big.bin", NonExecutableFile, &cleaned),
+      ("gone.bin", NonExecutableFile, &missing),
+      ("hydrated.bin", NonExecutableFile, &missing),
+      ("small.txt", NonExecutableFile, "small\n"),
+    ],
+  );
+  repo.checkout_tree(&tree).unwrap();
+  std::fs::write(tmp_dir.path().join("hydrated.bin"), "missing").unwrap();
+  std::fs::remove_file(tmp_dir.path().join("gone.bin")).unwrap();
+  let index = repo.index().unwrap();
+  let paths = |pointers: Vec<(BString, LfsPointer)>| -> Vec<BString> {
+    pointers.into_iter().map(|(path, _)| path).collect()
+  };
+  assert_eq!(
+    vec!["big.bin", "gone.bin", "hydrated.bin"],
+    paths(index_pointers(repo.odb(), &index).unwrap())
+  );
+  assert_eq!(vec!["big.bin"], paths(repo.lfs_unhydrated().unwrap()));
+}
@@ -1,5 +1,168 @@
+mod alternates;
+mod apply;
+mod archive;
+mod attributes;
+mod bitmap;
+mod blame;
 mod blob;
+mod blob_diff;
+mod blob_merge;
+mod bundle;
+mod cache;
+mod checkout;
+mod cherry_pick;
+mod cleanup;
+mod clone;
+mod collision;
+mod commit;
+mod commit_graph;
+mod config;
+mod credential;
+mod daemon;
+mod delta;
+mod describe;
+mod diff;
+#[cfg(feature = "differential")]
+mod differential;
+mod encoding;
+mod endian;
+mod eol;
+mod error;
+mod fast_export;
+mod fast_import;
+mod filter;
+mod fsck;
+mod fuzz;
+mod head;
+mod index;
+mod index_pack;
+mod lfs;
+mod mailmap;
+mod memory;
+mod merge;
+mod midx;
+mod mmap;
+mod notes;
+mod object;
+mod object_cache;
+mod object_walk;
+mod odb;
 mod oid;
+mod pack;
+mod pack_spool;
+mod patch;
+mod pkt_line;
+pub mod plumbing;
+mod probe;
+mod progress;
+mod promisor;
+mod quota;
+mod receive_pack;
+mod reflog;
+mod refs;
+mod refspec;
+mod remote;
+mod rename;
+mod replace;
+mod repository;
+mod revparse;
+mod revwalk;
+mod shallow;
+mod signature;
+mod signing;
+mod small;
+mod sparse;
+mod stash;
+mod status;
+mod submodule;
+mod tag;
+mod tags;
+mod trace2;
+mod trailers;
+pub mod transport;
+mod tree;
+mod upload_pack;
+mod wildmatch;
+mod worktree;
+mod zlib;
 
+pub use apply::*;
+pub use archive::*;
+pub use attributes::*;
+pub use bitmap::BitmapError;
+pub use blame::*;
 pub use blob::*;
+pub use blob_diff::*;
+pub use blob_merge::*;
+pub use bundle::*;
+pub use cache::*;
+pub use checkout::*;
+pub use cherry_pick::*;
+pub use cleanup::CleanupOptions;
+pub use clone::*;
+pub use collision::{CollisionKind, PathCollision};
+pub use commit::*;
+pub use commit_graph::*;
+pub use config::*;
+pub use credential::*;
+pub use daemon::*;
+pub use delta::*;
+pub use describe::*;
+pub use diff::*;
+#[cfg(feature = "differential")]
+pub use differential::*;
+pub use encoding::*;
+pub use eol::*;
+pub use error::Error;
+pub use fast_export::*;
+pub use fast_import::*;
+pub use filter::*;
+pub use fsck::*;
+pub use fuzz::*;
+pub use head::*;
+pub use index::*;
+pub use index_pack::*;
+pub use lfs::*;
+pub use mailmap::*;
+pub use memory::*;
+pub use merge::*;
+pub use midx::{MultiPackIndex, MultiPackIndexError};
+pub use notes::*;
+pub use object::Object;
+pub use object_cache::{ObjectCacheLimits, ObjectCacheStats};
+pub use object_walk::*;
+pub use odb::*;
 pub use oid::*;
+pub use pack::{PackError, PackLimits};
+pub use pack_spool::*;
+pub use patch::*;
+pub use pkt_line::*;
+pub use probe::*;
+pub use progress::{CancellationToken, Progress, ProgressStage};
+pub use promisor::*;
+pub use quota::*;
+pub use receive_pack::*;
+pub use reflog::*;
+pub use refs::*;
+pub use refspec::*;
+pub use remote::*;
+pub use rename::*;
+pub use replace::*;
+pub use repository::*;
+pub use revparse::*;
+pub use revwalk::*;
+pub use shallow::*;
+pub use signature::*;
+pub use signing::*;
+pub use sparse::*;
+pub use stash::*;
+pub use status::*;
+pub use submodule::*;
+pub use tag::*;
+pub use tags::*;
+pub use trace2::*;
+pub use trailers::*;
+pub use tree::*;
+pub use upload_pack::*;
+pub use worktree::*;
+pub use zlib::ZlibError;
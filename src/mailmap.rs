@@ -0,0 +1,336 @@
+use bstr::{BString, ByteSlice};
+use std::{fmt, fs, io, path::Path};
+use thiserror::Error;
+
+/// A [`Mailmap`] holds the rules found in a `.mailmap` file that map the
+/// names and emails recorded in commits to the canonical identity of a person.
+/// Each line of the file is in one of the forms below:
+///
+/// ```text
+/// Proper Name <commit@email.xx>
+/// <proper@email.xx> <commit@email.xx>
+/// Proper Name <proper@email.xx> <commit@email.xx>
+/// Proper Name <proper@email.xx> Commit Name <commit@email.xx>
+/// ```
+///
+/// Like git, lines that can't be parsed are skipped rather than treated as an
+/// error and anything after a `#` is a comment.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Mailmap {
+  entries: Vec<MailmapEntry>,
+}
+
+/// A single rule from a [`Mailmap`]. Only `commit_email` is required as it is
+/// what git uses to look up rules. The other fields are `None` when they were
+/// not part of the line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailmapEntry {
+  /// The name to replace the commit name with
+  pub proper_name: Option<BString>,
+  /// The email to replace the commit email with
+  pub proper_email: Option<BString>,
+  /// The name that must match for this rule to apply
+  pub commit_name: Option<BString>,
+  /// The email that must match for this rule to apply
+  pub commit_email: BString,
+  /// The line number (starting at 1) of the rule in the file it came from
+  pub line: usize,
+}
+
+/// A name and email pair as found in a commit or after being resolved
+/// through a [`Mailmap`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity {
+  /// The name of the person
+  pub name: BString,
+  /// The email of the person
+  pub email: BString,
+}
+
+/// The outcome of looking up an [`Identity`] in a [`Mailmap`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Resolution<'a> {
+  /// The canonical identity. If no rule matched this is the same as the input
+  pub identity: Identity,
+  /// The rules that were used to produce `identity` in the order they appear
+  /// in the file. This is empty if no rule matched. More than one rule can be
+  /// used as git combines email only rules, for example one line can provide
+  /// the proper name while another provides the proper email.
+  pub rules: Vec<&'a MailmapEntry>,
+}
+
+impl Resolution<'_> {
+  /// Whether any rule in the [`Mailmap`] applied to the input
+  pub fn is_mapped(&self) -> bool {
+    !self.rules.is_empty()
+  }
+}
+
+impl Identity {
+  /// Create a new [`Identity`] from a name and an email
+  pub fn new(name: impl Into<BString>, email: impl Into<BString>) -> Self {
+    Self {
+      name: name.into(),
+      email: email.into(),
+    }
+  }
+
+  /// Parse an [`Identity`] in the `Name <email>` form used by
+  /// `git check-mailmap`. The name is allowed to be empty as in `<email>`.
+  pub fn parse(contact: impl AsRef<[u8]>) -> Result<Self, MailmapError> {
+    let contact = contact.as_ref();
+    let (name, email, rest) =
+      parse_name_and_email(contact).ok_or_else(|| MailmapError::InvalidContact(contact.into()))?;
+    if !rest.trim().is_empty() {
+      return Err(MailmapError::InvalidContact(contact.into()));
+    }
+    Ok(Self::new(name.unwrap_or_default(), email))
+  }
+}
+
+impl fmt::Display for Identity {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.name.is_empty() {
+      write!(f, "<{}>", self.email)
+    } else {
+      write!(f, "{} <{}>", self.name, self.email)
+    }
+  }
+}
+
+impl Mailmap {
+  /// Parse a [`Mailmap`] from the contents of a `.mailmap` file
+  pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Self {
+    let mut mailmap = Self::default();
+    mailmap.add_bytes(bytes);
+    mailmap
+  }
+
+  /// Read and parse a `.mailmap` file from disk
+  pub fn from_file(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+    Ok(Self::from_bytes(fs::read(path.as_ref())?))
+  }
+
+  /// Add the rules from another mailmap file to this one. Rules added later
+  /// take precedence over earlier ones the same way git treats `mailmap.file`
+  /// and `mailmap.blob` on top of the `.mailmap` in the working tree.
+  pub fn add_bytes(&mut self, bytes: impl AsRef<[u8]>) {
+    for (idx, line) in bytes.as_ref().lines().enumerate() {
+      if let Some(entry) = parse_line(line, idx + 1) {
+        self.entries.push(entry);
+      }
+    }
+  }
+
+  /// All of the rules in the order they were read
+  pub fn entries(&self) -> &[MailmapEntry] {
+    &self.entries
+  }
+
+  /// Whether there are no rules at all
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// Resolve a name and email pair to its canonical [`Identity`] and report
+  /// which rules matched.
+  ///
+  /// This follows the same rules as git. Emails and names are compared
+  /// case insensitively. A rule that also names the commit name is preferred
+  /// over a rule that only has the commit email and when several rules of the
+  /// same kind match the one that comes last wins.
+  pub fn resolve(&self, name: impl AsRef<[u8]>, email: impl AsRef<[u8]>) -> Resolution<'_> {
+    let name = name.as_ref();
+    let email = email.as_ref();
+    let matches_email = |entry: &&MailmapEntry| eq_ignore_case(&entry.commit_email, email);
+
+    let by_name = self
+      .entries
+      .iter()
+      .filter(matches_email)
+      .rev()
+      .find(|entry| {
+        entry
+          .commit_name
+          .as_ref()
+          .is_some_and(|commit_name| eq_ignore_case(commit_name, name))
+      });
+
+    let mut identity = Identity::new(name, email);
+    let mut rules = Vec::new();
+    if let Some(entry) = by_name {
+      apply(&mut identity, entry);
+      rules.push(entry);
+    } else {
+      let email_only = self
+        .entries
+        .iter()
+        .filter(matches_email)
+        .filter(|entry| entry.commit_name.is_none());
+      let proper_name = email_only
+        .clone()
+        .rev()
+        .find(|entry| entry.proper_name.is_some());
+      let proper_email = email_only.rev().find(|entry| entry.proper_email.is_some());
+      for entry in proper_name.into_iter().chain(proper_email) {
+        apply(&mut identity, entry);
+        if !rules.contains(&entry) {
+          rules.push(entry);
+        }
+      }
+      rules.sort_by_key(|entry| entry.line);
+    }
+
+    Resolution { identity, rules }
+  }
+
+  /// Resolve a contact in the `Name <email>` form the same way
+  /// `git check-mailmap` does. See [`Mailmap::resolve`] for details on how
+  /// rules are matched.
+  pub fn check(&self, contact: impl AsRef<[u8]>) -> Result<Resolution<'_>, MailmapError> {
+    let contact = Identity::parse(contact)?;
+    Ok(self.resolve(&contact.name, &contact.email))
+  }
+}
+
+fn apply(identity: &mut Identity, entry: &MailmapEntry) {
+  if let Some(name) = &entry.proper_name {
+    identity.name = name.clone();
+  }
+  if let Some(email) = &entry.proper_email {
+    identity.email = email.clone();
+  }
+}
+
+fn eq_ignore_case(a: &[u8], b: &[u8]) -> bool {
+  match (a.to_str(), b.to_str()) {
+    (Ok(a), Ok(b)) => a.to_lowercase() == b.to_lowercase(),
+    _ => a.eq_ignore_ascii_case(b),
+  }
+}
+
+/// Parse a `Name <email>` pair from the start of `input` returning the name
+/// (if it was not empty), the email, and whatever remains after the `>`
+fn parse_name_and_email(input: &[u8]) -> Option<(Option<BString>, BString, &[u8])> {
+  let start = input.find_byte(b'<')?;
+  let end = start + input[start..].find_byte(b'>')?;
+  let name = input[..start].trim();
+  let name = if name.is_empty() {
+    None
+  } else {
+    Some(name.into())
+  };
+  Some((name, input[start + 1..end].into(), &input[end + 1..]))
+}
+
+fn parse_line(line: &[u8], line_number: usize) -> Option<MailmapEntry> {
+  let line = match line.find_byte(b'#') {
+    Some(idx) => &line[..idx],
+    None => line,
+  };
+  let (proper_name, first_email, rest) = parse_name_and_email(line)?;
+  let entry = match parse_name_and_email(rest) {
+    Some((commit_name, commit_email, _)) => MailmapEntry {
+      proper_name,
+      proper_email: Some(first_email),
+      commit_name,
+      commit_email,
+      line: line_number,
+    },
+    None => MailmapEntry {
+      proper_name,
+      proper_email: None,
+      commit_name: None,
+      commit_email: first_email,
+      line: line_number,
+    },
+  };
+  Some(entry)
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`Mailmap`] type
+pub enum MailmapError {
+  #[error("contact {0:?} is not in the form 'Name <email>'")]
+  InvalidContact(BString),
+}
+
+#[cfg(test)]
+const TEST_MAILMAP: &str = "\
+# A comment line
+Jane Doe <jane@example.com>
+<jane@example.com> <jane@old.example.com> # trailing comment
+Joe Dev <joe@example.com> Joe <JOE@laptop.local>
+Joe Developer <joe@example.com> <joe@laptop.local>
+not a rule
+";
+
+#[test]
+fn parse() {
+  let mailmap = Mailmap::from_bytes(TEST_MAILMAP);
+  assert_eq!(4, mailmap.entries().len());
+  assert_eq!(
+    &MailmapEntry {
+      proper_name: Some("Joe Dev".into()),
+      proper_email: Some("joe@example.com".into()),
+      commit_name: Some("Joe".into()),
+      commit_email: "JOE@laptop.local".into(),
+      line: 4,
+    },
+    &mailmap.entries()[2]
+  );
+}
+
+#[test]
+fn resolve_name_only() {
+  let mailmap = Mailmap::from_bytes(TEST_MAILMAP);
+  let resolved = mailmap.resolve("jd", "Jane@Example.com");
+  assert_eq!(
+    Identity::new("Jane Doe", "Jane@Example.com"),
+    resolved.identity
+  );
+  assert_eq!(vec![2], lines(&resolved));
+}
+
+#[test]
+fn resolve_prefers_commit_name() {
+  let mailmap = Mailmap::from_bytes(TEST_MAILMAP);
+  let resolved = mailmap.resolve("joe", "joe@laptop.local");
+  assert_eq!(
+    Identity::new("Joe Dev", "joe@example.com"),
+    resolved.identity
+  );
+  assert_eq!(vec![4], lines(&resolved));
+
+  let resolved = mailmap.resolve("Someone", "joe@laptop.local");
+  assert_eq!(
+    Identity::new("Joe Developer", "joe@example.com"),
+    resolved.identity
+  );
+  assert_eq!(vec![5], lines(&resolved));
+}
+
+#[test]
+fn resolve_unmapped() {
+  let mailmap = Mailmap::from_bytes(TEST_MAILMAP);
+  let resolved = mailmap.resolve("Nobody", "nobody@example.com");
+  assert!(!resolved.is_mapped());
+  assert_eq!(
+    Identity::new("Nobody", "nobody@example.com"),
+    resolved.identity
+  );
+}
+
+#[test]
+fn check() {
+  let mailmap = Mailmap::from_bytes(TEST_MAILMAP);
+  let resolved = mailmap.check("<jane@old.example.com>").unwrap();
+  assert_eq!("<jane@example.com>", resolved.identity.to_string());
+  assert_eq!(vec![3], lines(&resolved));
+  assert!(mailmap.check("no email here").is_err());
+}
+
+#[cfg(test)]
+fn lines(resolution: &Resolution<'_>) -> Vec<usize> {
+  resolution.rules.iter().map(|entry| entry.line).collect()
+}
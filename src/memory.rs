@@ -0,0 +1,340 @@
+use std::{
+  fmt,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Condvar, Mutex, OnceLock,
+  },
+  time::{Duration, Instant},
+};
+use thiserror::Error;
+
+/// The limit of a budget that doesn't have one
+const UNLIMITED: usize = usize::MAX;
+
+/// Lets a thread waiting for memory sleep until another thread gives some
+/// back to any budget
+static FREED: (Mutex<()>, Condvar) = (Mutex::new(()), Condvar::new());
+
+/// Keeps count of how much memory large buffers like inflated objects,
+/// delta windows, and caches take up, and refuses to hand out more than its
+/// limit. This doesn't replace the allocator, buffers reserve their size
+/// up front with [`MemoryBudget::try_reserve`] or
+/// [`MemoryBudget::reserve_timeout`] and give it back when the
+/// [`Reservation`] is dropped.
+///
+/// A budget made with [`MemoryBudget::child`] also counts against its
+/// parent, so a server can give every request its own budget that all
+/// share the [`MemoryBudget::global`] one.
+#[derive(Clone)]
+pub struct MemoryBudget {
+  inner: Arc<Inner>,
+}
+
+struct Inner {
+  limit: AtomicUsize,
+  in_use: AtomicUsize,
+  parent: Option<MemoryBudget>,
+}
+
+impl MemoryBudget {
+  /// Create a budget that never hands out more than `limit` bytes at once
+  pub fn new(limit: usize) -> Self {
+    Self::with_parent(limit, None)
+  }
+
+  /// Create a budget that only keeps count
+  pub fn unlimited() -> Self {
+    Self::new(UNLIMITED)
+  }
+
+  /// The budget used by every [`Odb`][crate::Odb] unless it's given a
+  /// different one. It has no limit until [`MemoryBudget::set_limit`] is
+  /// called on it.
+  pub fn global() -> &'static MemoryBudget {
+    static GLOBAL: OnceLock<MemoryBudget> = OnceLock::new();
+    GLOBAL.get_or_init(MemoryBudget::unlimited)
+  }
+
+  /// Create a budget limited to `limit` bytes, or no limit, whose
+  /// reservations also count against this one
+  pub fn child(&self, limit: Option<usize>) -> Self {
+    Self::with_parent(limit.unwrap_or(UNLIMITED), Some(self.clone()))
+  }
+
+  fn with_parent(limit: usize, parent: Option<MemoryBudget>) -> Self {
+    Self {
+      inner: Arc::new(Inner {
+        limit: AtomicUsize::new(limit),
+        in_use: AtomicUsize::new(0),
+        parent,
+      }),
+    }
+  }
+
+  /// Change the limit. Memory that is already reserved stays reserved even
+  /// if it's over the new limit.
+  pub fn set_limit(&self, limit: Option<usize>) {
+    self
+      .inner
+      .limit
+      .store(limit.unwrap_or(UNLIMITED), Ordering::SeqCst);
+  }
+
+  /// The most this budget hands out at once, not counting the limits of
+  /// its parents
+  pub fn limit(&self) -> Option<usize> {
+    match self.inner.limit.load(Ordering::SeqCst) {
+      UNLIMITED => None,
+      limit => Some(limit),
+    }
+  }
+
+  /// How many bytes are reserved right now
+  pub fn in_use(&self) -> usize {
+    self.inner.in_use.load(Ordering::SeqCst)
+  }
+
+  /// How many more bytes could be reserved right now taking the limits of
+  /// the parents into account
+  pub fn available(&self) -> usize {
+    let own = self
+      .inner
+      .limit
+      .load(Ordering::SeqCst)
+      .saturating_sub(self.in_use());
+    match &self.inner.parent {
+      Some(parent) => own.min(parent.available()),
+      None => own,
+    }
+  }
+
+  /// Reserve `bytes`, failing right away if there isn't enough left
+  pub fn try_reserve(&self, bytes: usize) -> Result<Reservation, MemoryError> {
+    self.check_size(bytes)?;
+    if self.acquire(bytes) {
+      Ok(self.reservation(bytes))
+    } else {
+      Err(self.exceeded(bytes))
+    }
+  }
+
+  /// Reserve `bytes`, waiting up to `timeout` for other reservations to be
+  /// dropped if there isn't enough left. Requests that could never fit fail
+  /// right away.
+  pub fn reserve_timeout(
+    &self,
+    bytes: usize,
+    timeout: Duration,
+  ) -> Result<Reservation, MemoryError> {
+    self.check_size(bytes)?;
+    let deadline = Instant::now() + timeout;
+    let (lock, freed) = &FREED;
+    let mut guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+    loop {
+      if self.acquire(bytes) {
+        return Ok(self.reservation(bytes));
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(self.exceeded(bytes));
+      }
+      guard = freed
+        .wait_timeout(guard, deadline - now)
+        .unwrap_or_else(|e| e.into_inner())
+        .0;
+    }
+  }
+
+  fn reservation(&self, bytes: usize) -> Reservation {
+    Reservation {
+      budget: self.clone(),
+      bytes,
+    }
+  }
+
+  /// Fail if `bytes` is more than this budget or one of its parents would
+  /// hand out even with nothing else reserved
+  fn check_size(&self, bytes: usize) -> Result<(), MemoryError> {
+    let mut budget = Some(self);
+    while let Some(current) = budget {
+      if let Some(limit) = current.limit().filter(|&limit| bytes > limit) {
+        return Err(MemoryError::TooLarge {
+          requested: bytes,
+          limit,
+        });
+      }
+      budget = current.inner.parent.as_ref();
+    }
+    Ok(())
+  }
+
+  fn exceeded(&self, bytes: usize) -> MemoryError {
+    MemoryError::LimitExceeded {
+      requested: bytes,
+      available: self.available(),
+    }
+  }
+
+  /// Count `bytes` against this budget and its parents if they all have
+  /// room for it
+  fn acquire(&self, bytes: usize) -> bool {
+    let limit = self.inner.limit.load(Ordering::SeqCst);
+    let reserved = self
+      .inner
+      .in_use
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_use| {
+        in_use.checked_add(bytes).filter(|&total| total <= limit)
+      })
+      .is_ok();
+    if !reserved {
+      return false;
+    }
+    match &self.inner.parent {
+      Some(parent) if !parent.acquire(bytes) => {
+        self.inner.in_use.fetch_sub(bytes, Ordering::SeqCst);
+        false
+      }
+      _ => true,
+    }
+  }
+
+  fn release(&self, bytes: usize) {
+    self.inner.in_use.fetch_sub(bytes, Ordering::SeqCst);
+    if let Some(parent) = &self.inner.parent {
+      parent.release(bytes);
+    } else {
+      // Taking the lock first makes sure a waiter is either still trying
+      // to reserve or already asleep and gets woken up
+      let (lock, freed) = &FREED;
+      drop(lock.lock().unwrap_or_else(|e| e.into_inner()));
+      freed.notify_all();
+    }
+  }
+}
+
+impl fmt::Debug for MemoryBudget {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("MemoryBudget")
+      .field("limit", &self.limit())
+      .field("in_use", &self.in_use())
+      .field("parent", &self.inner.parent)
+      .finish()
+  }
+}
+
+/// Budgets are only equal to themselves and their clones
+impl PartialEq for MemoryBudget {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.inner, &other.inner)
+  }
+}
+
+impl Eq for MemoryBudget {}
+
+/// Memory reserved from a [`MemoryBudget`] that is given back when dropped
+#[must_use = "the memory is given back as soon as this is dropped"]
+#[derive(Debug)]
+pub struct Reservation {
+  budget: MemoryBudget,
+  bytes: usize,
+}
+
+impl Reservation {
+  /// How many bytes are reserved
+  pub fn bytes(&self) -> usize {
+    self.bytes
+  }
+
+  /// Reserve `additional` more bytes, for a buffer that grew
+  pub fn try_grow(&mut self, additional: usize) -> Result<(), MemoryError> {
+    let total = self.bytes.saturating_add(additional);
+    self.budget.check_size(total)?;
+    if !self.budget.acquire(additional) {
+      return Err(self.budget.exceeded(additional));
+    }
+    self.bytes = total;
+    Ok(())
+  }
+}
+
+impl Drop for Reservation {
+  fn drop(&mut self) {
+    if self.bytes > 0 {
+      self.budget.release(self.bytes);
+    }
+  }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+/// Errors related to operations done with the [`MemoryBudget`] type
+pub enum MemoryError {
+  #[error("{requested} bytes are needed but only {available} bytes of the memory budget are left")]
+  LimitExceeded { requested: usize, available: usize },
+  #[error("{requested} bytes are needed which is more than the memory budget of {limit} bytes")]
+  TooLarge { requested: usize, limit: usize },
+}
+
+#[test]
+fn reserve_and_release() {
+  let budget = MemoryBudget::new(100);
+  let first = budget.try_reserve(60).unwrap();
+  assert_eq!(60, budget.in_use());
+  assert_eq!(
+    MemoryError::LimitExceeded {
+      requested: 50,
+      available: 40
+    },
+    budget.try_reserve(50).unwrap_err()
+  );
+  assert!(matches!(
+    budget.try_reserve(101),
+    Err(MemoryError::TooLarge { .. })
+  ));
+  drop(first);
+  assert_eq!(0, budget.in_use());
+  let mut second = budget.try_reserve(50).unwrap();
+  second.try_grow(50).unwrap();
+  assert_eq!(100, second.bytes());
+  assert!(second.try_grow(1).is_err());
+  assert_eq!(100, budget.in_use());
+}
+
+#[test]
+fn child_budgets() {
+  let parent = MemoryBudget::new(100);
+  let a = parent.child(Some(80));
+  let b = parent.child(None);
+  let held = a.try_reserve(70).unwrap();
+  assert_eq!(70, parent.in_use());
+  assert_eq!(30, b.available());
+  assert!(b.try_reserve(40).is_err());
+  // A failed reservation in the parent doesn't leak into the child
+  assert_eq!(0, b.in_use());
+  assert!(matches!(
+    b.try_reserve(101),
+    Err(MemoryError::TooLarge { limit: 100, .. })
+  ));
+  drop(held);
+  assert_eq!(0, parent.in_use());
+  assert!(b.try_reserve(100).is_ok());
+}
+
+#[test]
+fn reserve_waits() {
+  let budget = MemoryBudget::new(10);
+  let held = budget.try_reserve(10).unwrap();
+  assert!(budget
+    .reserve_timeout(5, Duration::from_millis(10))
+    .is_err());
+  let thread = {
+    let budget = budget.clone();
+    std::thread::spawn(move || {
+      budget
+        .reserve_timeout(5, Duration::from_secs(10))
+        .map(|r| r.bytes())
+    })
+  };
+  std::thread::sleep(Duration::from_millis(20));
+  drop(held);
+  assert_eq!(Ok(5), thread.join().unwrap());
+}
@@ -0,0 +1,602 @@
+use crate::{
+  diff::kind_of, merge_blobs, Blob, ConfigError, FileMode, Index, IndexEntry, MergeFavor,
+  MergeOptions, Odb, OdbError, Repository, StatData, Trace2, Tree, TreeEntry, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// Why a path couldn't be merged by [`merge_trees`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ConflictKind {
+  /// Both sides changed the contents differently, or both added the path
+  /// with different contents. Files that can be merged have conflict
+  /// markers where the changes overlap.
+  Content,
+  /// One side changed the path and the other deleted it
+  ModifyDelete,
+  /// Both sides added a file but only one of them made it executable
+  Mode,
+  /// The sides made it different kinds of entries, like a file and a
+  /// symbolic link. Both can't be at the path, so the regular file is
+  /// moved out of the way like for [`ConflictKind::FileDirectory`], or
+  /// both are if neither is a regular file. These are the paths the sides
+  /// ended up at, with their stages in the [`Index`].
+  DistinctTypes {
+    /// Where our entry is
+    ours: BString,
+    /// Where their entry is
+    theirs: BString,
+  },
+  /// One side has a file where the other has a directory. The file was
+  /// moved out of the way to this path, where its stages are in the
+  /// [`Index`] too.
+  FileDirectory(BString),
+}
+
+/// A path that [`merge_trees`] couldn't merge
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TreeConflict {
+  /// The full path from the root of the [`Tree`]
+  pub path: BString,
+  /// Why it couldn't be merged
+  pub kind: ConflictKind,
+}
+
+/// The result of [`merge_trees`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergedTree {
+  /// The [`OID`] of the merged [`Tree`]. Conflicting paths are in it too,
+  /// with conflict markers in their contents or with our version if they
+  /// can't be merged, the same as what `git merge` leaves in the working
+  /// tree.
+  pub tree: OID,
+  /// The merged paths at stage 0, and the conflicting ones at stages 1, 2,
+  /// and 3 for the ancestor, our, and their version like `git merge` leaves
+  /// the index. A side without the path has no entry at its stage.
+  pub index: Index,
+  /// Every path that couldn't be merged, sorted by path
+  pub conflicts: Vec<TreeConflict>,
+}
+
+impl MergedTree {
+  /// Whether the trees merged without any conflicts, in which case
+  /// [`MergedTree::tree`] can be committed as it is
+  pub fn is_clean(&self) -> bool {
+    self.conflicts.is_empty()
+  }
+}
+
+/// Merge the changes the [`Tree`]s `ours` and `theirs` made to `ancestor`,
+/// their merge base, like `git merge` does. `None` for `ancestor` is an
+/// empty [`Tree`], for merging histories without a common commit. For
+/// cherry-picking a commit, `ancestor` is its parent and `theirs` the
+/// commit.
+///
+/// Paths only one side changed, or both changed the same way, are taken
+/// as they are, and files both sides changed are merged with
+/// [`merge_blobs`] using `options`. Renames aren't detected, a renamed file
+/// is deleted in one place and added in another.
+pub fn merge_trees(
+  odb: &Odb,
+  ancestor: Option<&OID>,
+  ours: &OID,
+  theirs: &OID,
+  options: &MergeOptions,
+) -> Result<MergedTree, MergeError> {
+  let _region = Trace2::region("merge", "merge_trees");
+  let ancestor = match ancestor {
+    Some(oid) => odb.read_tree(oid)?,
+    None => Tree::default(),
+  };
+  let mut merger = TreeMerger {
+    odb,
+    options,
+    entries: Vec::new(),
+    conflicts: Vec::new(),
+  };
+  let entries = merger.merge_dir(
+    [&ancestor, &odb.read_tree(ours)?, &odb.read_tree(theirs)?],
+    b"",
+  )?;
+  let tree = odb.write_tree(&Tree::new(entries))?;
+  let mut conflicts = merger.conflicts;
+  conflicts.sort_by(|a, b| a.path.cmp(&b.path));
+  Trace2::data("merge", "conflicts", conflicts.len());
+  Ok(MergedTree {
+    tree,
+    index: Index::new(merger.entries),
+    conflicts,
+  })
+}
+
+impl Repository {
+  /// Merge two [`Tree`]s of the repository against their merge base with
+  /// the [`MergeOptions`] from the [`Config`] of the repository. See
+  /// [`merge_trees`].
+  ///
+  /// [`Config`]: crate::Config
+  pub fn merge_trees(
+    &self,
+    ancestor: Option<&OID>,
+    ours: &OID,
+    theirs: &OID,
+  ) -> Result<MergedTree, MergeError> {
+    merge_trees(
+      self.odb(),
+      ancestor,
+      ours,
+      theirs,
+      &MergeOptions::from_config(self.config())?,
+    )
+  }
+}
+
+/// The entries of the ancestor, ours, and theirs for a path
+type Sides<'a> = [Option<&'a TreeEntry>; 3];
+
+/// The [`Sides`] of every name in a directory
+type Names<'a> = BTreeMap<&'a BStr, Sides<'a>>;
+
+/// How a path is merged when it doesn't need its contents merged
+enum Trivial<'a> {
+  /// Use this side, or nothing if it was deleted
+  Take(Option<&'a TreeEntry>),
+  /// Both sides changed it differently
+  Changed,
+}
+
+fn trivial<'a>([ancestor, ours, theirs]: Sides<'a>) -> Trivial<'a> {
+  let same = |a: Option<&TreeEntry>, b: Option<&TreeEntry>| match (a, b) {
+    (Some(a), Some(b)) => a.mode() == b.mode() && a.oid() == b.oid(),
+    (a, b) => a.is_none() && b.is_none(),
+  };
+  if same(ours, theirs) || same(ancestor, theirs) {
+    Trivial::Take(ours)
+  } else if same(ancestor, ours) {
+    Trivial::Take(theirs)
+  } else {
+    Trivial::Changed
+  }
+}
+
+struct TreeMerger<'a> {
+  odb: &'a Odb,
+  options: &'a MergeOptions,
+  entries: Vec<IndexEntry>,
+  conflicts: Vec<TreeConflict>,
+}
+
+impl TreeMerger<'_> {
+  /// Merge the directory at `prefix` and return the entries of the merged
+  /// [`Tree`] for it
+  fn merge_dir(&mut self, trees: [&Tree; 3], prefix: &[u8]) -> Result<Vec<TreeEntry>, MergeError> {
+    // A file and a directory with the same name sort apart, so the sides
+    // are lined up by name
+    let mut names = Names::new();
+    for (side, tree) in trees.iter().enumerate() {
+      for entry in tree.entries() {
+        names.entry(entry.name()).or_default()[side] = Some(entry);
+      }
+    }
+    let mut merged = Vec::new();
+    for (name, sides) in &names {
+      let path = join(prefix, name);
+      if let Trivial::Take(entry) = trivial(*sides) {
+        if let Some(entry) = entry {
+          self.add(entry, &path, 0)?;
+          merged.push(entry.clone());
+        }
+        continue;
+      }
+
+      // What's a directory and what isn't on each side are merged
+      // separately, and end up in the same place unless both are left
+      let dirs = sides.map(|entry| entry.filter(|entry| entry.mode().is_tree()));
+      let files = sides.map(|entry| entry.filter(|entry| !entry.mode().is_tree()));
+      let mut dir = None;
+      match trivial(dirs) {
+        Trivial::Take(Some(entry)) => {
+          self.add(entry, &path, 0)?;
+          dir = Some(entry.clone());
+        }
+        Trivial::Take(None) => {}
+        Trivial::Changed => {
+          let read = |entry: Option<&TreeEntry>| {
+            entry.map_or(Ok(Tree::default()), |entry| self.odb.read_tree(entry.oid()))
+          };
+          let trees = [read(dirs[0])?, read(dirs[1])?, read(dirs[2])?];
+          let path = [&path[..], b"/"].concat();
+          let entries = self.merge_dir([&trees[0], &trees[1], &trees[2]], &path)?;
+          if !entries.is_empty() {
+            let oid = self.odb.write_tree(&Tree::new(entries))?;
+            dir = Some(TreeEntry::new(FileMode::Tree, *name, oid));
+          }
+        }
+      }
+      let in_the_way = dir.is_some() && !matches!(trivial(files), Trivial::Take(None));
+      if let Some(dir) = dir {
+        merged.push(dir);
+      }
+      if files.iter().all(Option::is_none) {
+        continue;
+      }
+      if !in_the_way {
+        self.merge_file(files, name, prefix, &names, &mut merged)?;
+        continue;
+      }
+
+      // The file is moved next to the directory, named after the side it's
+      // from like git does
+      let side = if files[1].is_some() { 1 } else { 2 };
+      let moved = self.moved_name(&names, &merged, name, side);
+      let moved_path = join(prefix, moved.as_bstr());
+      self.conflicts.push(TreeConflict {
+        path: path.into(),
+        kind: ConflictKind::FileDirectory(moved_path.clone().into()),
+      });
+      match trivial(files) {
+        Trivial::Take(taken) => {
+          for (stage, entry) in files.iter().enumerate() {
+            if let Some(entry) = entry {
+              self.add(entry, &moved_path, stage as u8 + 1)?;
+            }
+          }
+          merged.extend(taken.map(|entry| TreeEntry::new(entry.mode(), moved, *entry.oid())));
+        }
+        Trivial::Changed => {
+          self.merge_file(files, moved.as_bstr(), prefix, &names, &mut merged)?;
+        }
+      }
+    }
+    Ok(merged)
+  }
+
+  /// Merge the non-directory entries of a path both sides changed, adding
+  /// what goes in the merged [`Tree`] to `merged`. `names` are the names in
+  /// the directory on every side.
+  fn merge_file(
+    &mut self,
+    sides: Sides,
+    name: &BStr,
+    prefix: &[u8],
+    names: &Names,
+    merged: &mut Vec<TreeEntry>,
+  ) -> Result<(), MergeError> {
+    let path = join(prefix, name);
+    let [ancestor, ours, theirs] = sides;
+    let (ours, theirs) = match (ours, theirs) {
+      (Some(ours), Some(theirs)) => (ours, theirs),
+      (ours, theirs) => {
+        if let Trivial::Take(entry) = trivial(sides) {
+          if let Some(entry) = entry {
+            self.add(entry, &path, 0)?;
+            merged.push(TreeEntry::new(entry.mode(), name, *entry.oid()));
+          }
+          return Ok(());
+        }
+        // The side that changed it wins in the merged tree
+        self.conflict(sides, &path, ConflictKind::ModifyDelete)?;
+        let entry = ours.or(theirs).unwrap();
+        merged.push(TreeEntry::new(entry.mode(), name, *entry.oid()));
+        return Ok(());
+      }
+    };
+
+    let regular = |entry: &TreeEntry| kind_of(entry.mode()) == FileMode::NonExecutableFile;
+    if kind_of(ours.mode()) != kind_of(theirs.mode()) {
+      // Both can't be at the path, so the regular file is moved out of the
+      // way like git does, or both sides if neither is one. Each keeps the
+      // ancestor at stage 1 if it's the same kind.
+      let moved = if regular(ours) {
+        [true, false]
+      } else {
+        [!regular(theirs), true]
+      };
+      let mut paths = Vec::new();
+      for (side, entry) in [(1, ours), (2, theirs)] {
+        let name = match moved[side - 1] {
+          true => self.moved_name(names, merged, name, side),
+          false => name.into(),
+        };
+        let path = join(prefix, name.as_bstr());
+        if let Some(ancestor) = ancestor.filter(|a| kind_of(a.mode()) == kind_of(entry.mode())) {
+          self.add(ancestor, &path, 1)?;
+        }
+        self.add(entry, &path, side as u8 + 1)?;
+        merged.push(TreeEntry::new(entry.mode(), name, *entry.oid()));
+        paths.push(BString::from(path));
+      }
+      let theirs = paths.pop().unwrap();
+      self.conflicts.push(TreeConflict {
+        path: path.into(),
+        kind: ConflictKind::DistinctTypes {
+          ours: paths.pop().unwrap(),
+          theirs,
+        },
+      });
+      return Ok(());
+    }
+    if !regular(ours) {
+      // Symbolic links and submodules can't be merged, so ours is kept
+      // unless theirs is favored
+      self.conflict(sides, &path, ConflictKind::Content)?;
+      let entry = match self.options.favor {
+        Some(MergeFavor::Theirs) => theirs,
+        _ => ours,
+      };
+      merged.push(TreeEntry::new(entry.mode(), name, *entry.oid()));
+      return Ok(());
+    }
+
+    let ancestor_mode = ancestor.map(TreeEntry::mode);
+    let (mode, mode_conflict) = if ancestor_mode == Some(ours.mode()) {
+      (theirs.mode(), false)
+    } else if ancestor_mode == Some(theirs.mode()) {
+      (ours.mode(), false)
+    } else {
+      (ours.mode(), ours.mode() != theirs.mode())
+    };
+    let (oid, conflicts) = if ours.oid() == theirs.oid() {
+      (*ours.oid(), 0)
+    } else {
+      let read = |oid: &OID| self.odb.read_blob(oid).map(|blob| blob.contents().to_vec());
+      let base = match ancestor.filter(|entry| regular(entry)) {
+        Some(entry) => read(entry.oid())?,
+        None => Vec::new(),
+      };
+      let merged = merge_blobs(base, read(ours.oid())?, read(theirs.oid())?, self.options);
+      let oid = self.odb.write_blob(&Blob::new(merged.contents))?;
+      (oid, merged.conflicts)
+    };
+    if conflicts != 0 {
+      self.conflict(sides, &path, ConflictKind::Content)?;
+    } else if mode_conflict {
+      self.conflict(sides, &path, ConflictKind::Mode)?;
+    } else {
+      self
+        .entries
+        .push(IndexEntry::new(path, mode, oid, StatData::default()));
+    }
+    merged.push(TreeEntry::new(mode, name, oid));
+    Ok(())
+  }
+
+  /// A name for the entry of `side` at `name` to move it out of the way of
+  /// the other side, which isn't used by any side or already merged
+  fn moved_name(&self, names: &Names, merged: &[TreeEntry], name: &BStr, side: usize) -> BString {
+    let label = match side {
+      1 => &self.options.ours_label,
+      _ => &self.options.theirs_label,
+    };
+    let label = label.as_ref().map_or(
+      if side == 1 {
+        &b"ours"[..]
+      } else {
+        &b"theirs"[..]
+      },
+      |label| label.as_bytes(),
+    );
+    // Slashes in labels like branch names are flattened like git does
+    let label = label.replace("/", "_");
+    let moved = [name.as_bytes(), b"~", &label].concat();
+    let mut candidate = moved.clone();
+    for n in 0.. {
+      let taken = names.contains_key(candidate.as_bstr())
+        || merged
+          .iter()
+          .any(|entry| entry.name() == candidate.as_bstr());
+      if !taken {
+        break;
+      }
+      candidate = [&moved[..], b"_", n.to_string().as_bytes()].concat();
+    }
+    candidate.into()
+  }
+
+  /// Record a conflict and the stages for it
+  fn conflict(&mut self, sides: Sides, path: &[u8], kind: ConflictKind) -> Result<(), MergeError> {
+    for (stage, entry) in sides.iter().enumerate() {
+      if let Some(entry) = entry {
+        self.add(entry, path, stage as u8 + 1)?;
+      }
+    }
+    self.conflicts.push(TreeConflict {
+      path: path.into(),
+      kind,
+    });
+    Ok(())
+  }
+
+  /// Add `entry` to the index at `path` and `stage`, or every file under it
+  /// if it's a directory
+  fn add(&mut self, entry: &TreeEntry, path: &[u8], stage: u8) -> Result<(), MergeError> {
+    if !entry.mode().is_tree() {
+      let mut entry = IndexEntry::new(path, entry.mode(), *entry.oid(), StatData::default());
+      entry.stage = stage;
+      self.entries.push(entry);
+      return Ok(());
+    }
+    for child in self.odb.read_tree(entry.oid())?.entries() {
+      let path = [path, b"/", child.name().as_bytes()].concat();
+      self.add(child, &path, stage)?;
+    }
+    Ok(())
+  }
+}
+
+fn join(prefix: &[u8], name: &BStr) -> Vec<u8> {
+  [prefix, name.as_bytes()].concat()
+}
+
+#[derive(Error, Debug)]
+/// Errors related to merging [`Tree`]s
+pub enum MergeError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+}
+
+#[test]
+fn clean_merge() {
+  use crate::diff::write_tree;
+  use FileMode::*;
+  let tmp_dir = tempdir::TempDir::new("merge_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let ancestor = write_tree(
+    &odb,
+    &[
+      ("README", NonExecutableFile, "one\ntwo\nthree\nfour\nfive\n"),
+      ("docs", NonExecutableFile, "docs\n"),
+      ("src/lib.rs", NonExecutableFile, "a\n"),
+      ("src/old.rs", NonExecutableFile, "old\n"),
+    ],
+  );
+  let ours = write_tree(
+    &odb,
+    &[
+      ("README", NonExecutableFile, "ONE\ntwo\nthree\nfour\nfive\n"),
+      ("docs", NonExecutableFile, "docs\n"),
+      ("new.txt", NonExecutableFile, "new\n"),
+      ("src/lib.rs", NonExecutableFile, "a\n"),
+    ],
+  );
+  let theirs = write_tree(
+    &odb,
+    &[
+      ("README", NonExecutableFile, "one\ntwo\nthree\nfour\nFIVE\n"),
+      ("docs/index.md", NonExecutableFile, "index\n"),
+      ("run.sh", ExecutableFile, "#!/bin/sh\n"),
+      ("src/lib.rs", NonExecutableFile, "b\n"),
+      ("src/old.rs", NonExecutableFile, "old\n"),
+    ],
+  );
+  let expected = write_tree(
+    &odb,
+    &[
+      ("README", NonExecutableFile, "ONE\ntwo\nthree\nfour\nFIVE\n"),
+      ("docs/index.md", NonExecutableFile, "index\n"),
+      ("new.txt", NonExecutableFile, "new\n"),
+      ("run.sh", ExecutableFile, "#!/bin/sh\n"),
+      ("src/lib.rs", NonExecutableFile, "b\n"),
+    ],
+  );
+  let merged = merge_trees(
+    &odb,
+    Some(&ancestor),
+    &ours,
+    &theirs,
+    &MergeOptions::default(),
+  )
+  .unwrap();
+  assert!(merged.is_clean());
+  assert_eq!(expected, merged.tree);
+  assert_eq!(5, merged.index.len());
+  assert_eq!(expected, merged.index.write_tree(&odb).unwrap());
+
+  // Without an ancestor everything is added on both sides, so only paths
+  // with the same contents merge
+  let merged = merge_trees(&odb, None, &ours, &ours, &MergeOptions::default()).unwrap();
+  assert!(merged.is_clean());
+  assert_eq!(ours, merged.tree);
+}
+
+#[test]
+fn tree_conflicts() {
+  use crate::diff::write_tree;
+  use FileMode::*;
+  let tmp_dir = tempdir::TempDir::new("merge_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let ancestor = write_tree(
+    &odb,
+    &[
+      ("both", NonExecutableFile, "base\n"),
+      ("gone", NonExecutableFile, "keep\n"),
+      ("link", NonExecutableFile, "target\n"),
+    ],
+  );
+  let ours = write_tree(
+    &odb,
+    &[
+      ("both", NonExecutableFile, "ours\n"),
+      ("link", SymbolicLink, "elsewhere"),
+      ("path", NonExecutableFile, "file\n"),
+    ],
+  );
+  let theirs = write_tree(
+    &odb,
+    &[
+      ("both", NonExecutableFile, "theirs\n"),
+      ("gone", NonExecutableFile, "changed\n"),
+      ("link", NonExecutableFile, "changed target\n"),
+      ("path/inner", NonExecutableFile, "inner\n"),
+    ],
+  );
+  let options = MergeOptions {
+    ours_label: Some("HEAD".into()),
+    theirs_label: Some("topic".into()),
+    ..MergeOptions::default()
+  };
+  let merged = merge_trees(&odb, Some(&ancestor), &ours, &theirs, &options).unwrap();
+  let conflict = |path: &str, kind| TreeConflict {
+    path: path.into(),
+    kind,
+  };
+  assert_eq!(
+    vec![
+      conflict("both", ConflictKind::Content),
+      conflict("gone", ConflictKind::ModifyDelete),
+      conflict(
+        "link",
+        ConflictKind::DistinctTypes {
+          ours: "link".into(),
+          theirs: "link~topic".into(),
+        }
+      ),
+      conflict("path", ConflictKind::FileDirectory("path~HEAD".into())),
+    ],
+    merged.conflicts
+  );
+
+  let stages: Vec<(&str, u8)> = merged
+    .index
+    .entries()
+    .iter()
+    .map(|entry| (entry.path.to_str().unwrap(), entry.stage))
+    .collect();
+  assert_eq!(
+    vec![
+      ("both", 1),
+      ("both", 2),
+      ("both", 3),
+      ("gone", 1),
+      ("gone", 3),
+      ("link", 2),
+      ("link~topic", 1),
+      ("link~topic", 3),
+      ("path/inner", 0),
+      ("path~HEAD", 2),
+    ],
+    stages
+  );
+  assert!(merged.index.write_tree(&odb).is_err());
+
+  // The merged tree has what's left in the working tree after `git merge`
+  let expected = write_tree(
+    &odb,
+    &[
+      (
+        "both",
+        NonExecutableFile,
+        "<<<<<<< HEAD\nours\n=======\ntheirs\n>>>>>>> topic\n",
+      ),
+      ("gone", NonExecutableFile, "changed\n"),
+      ("link", SymbolicLink, "elsewhere"),
+      ("link~topic", NonExecutableFile, "changed target\n"),
+      ("path/inner", NonExecutableFile, "inner\n"),
+      ("path~HEAD", NonExecutableFile, "file\n"),
+    ],
+  );
+  assert_eq!(expected, merged.tree);
+}
@@ -0,0 +1,456 @@
+use crate::{
+  endian::{read_u32, read_u64, table_len},
+  mmap::Mmap,
+  PackError, Trace2, OID,
+};
+use sha1::{Digest, Sha1};
+use std::{
+  cmp::Reverse,
+  collections::HashMap,
+  convert::{TryFrom, TryInto},
+  fmt, fs,
+  io::{self, Write},
+  path::{Path, PathBuf},
+  time::SystemTime,
+};
+use thiserror::Error;
+
+const SIGNATURE: &[u8; 4] = b"MIDX";
+const PACK_NAMES: &[u8; 4] = b"PNAM";
+const OID_FANOUT: &[u8; 4] = b"OIDF";
+const OID_LOOKUP: &[u8; 4] = b"OIDL";
+const OBJECT_OFFSETS: &[u8; 4] = b"OOFF";
+const LARGE_OFFSETS: &[u8; 4] = b"LOFF";
+/// Set on an offset in the OOFF chunk that is an index into the LOFF chunk
+/// instead, if there is one
+const LARGE_OFFSET: u32 = 0x8000_0000;
+
+/// The multi-pack-index file in `objects/pack/multi-pack-index`, which
+/// lists every object of many packs in one sorted table along with the
+/// pack it's in and where, so finding an object is a single binary search
+/// instead of one for every pack. Objects in more than one pack are only
+/// listed once. Packs added after it was written aren't in it and are
+/// looked through one by one as before.
+pub struct MultiPackIndex {
+  data: Mmap,
+  packs: Vec<String>,
+  objects: u32,
+  fanout: usize,
+  lookup: usize,
+  offsets: usize,
+  large_offsets: Option<(usize, usize)>,
+}
+
+/// A pack to put in a multi-pack-index by [`write`]
+pub(crate) struct IndexedPack {
+  /// The name of the pack's `.idx` file
+  pub(crate) name: String,
+  /// When the pack was written, the newest pack wins for objects that are
+  /// in more than one
+  pub(crate) modified: SystemTime,
+  /// The [`OID`] and offset of every object in the pack
+  pub(crate) objects: Vec<(OID, u64)>,
+}
+
+impl MultiPackIndex {
+  /// Open the multi-pack-index of the pack directory `objects/pack`, or
+  /// `None` if there isn't one
+  pub fn open(pack_dir: impl AsRef<Path>) -> Result<Option<Self>, MultiPackIndexError> {
+    let file = match fs::File::open(pack_dir.as_ref().join("multi-pack-index")) {
+      Ok(file) => file,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    let len = usize::try_from(file.metadata()?.len())
+      .map_err(|_| MultiPackIndexError::Malformed("file is too large to map"))?;
+    if len < 12 + 12 + 20 {
+      return Err(MultiPackIndexError::Malformed("file is too short"));
+    }
+    Self::parse(Mmap::map(&file, 0, len)?).map(Some)
+  }
+
+  fn parse(data: Mmap) -> Result<Self, MultiPackIndexError> {
+    if &data[..4] != SIGNATURE {
+      return Err(MultiPackIndexError::Malformed("missing MIDX signature"));
+    }
+    if data[4] != 1 {
+      return Err(MultiPackIndexError::UnsupportedVersion(data[4]));
+    }
+    if data[5] != 1 {
+      return Err(MultiPackIndexError::Malformed("hash is not SHA-1"));
+    }
+    if data[7] != 0 {
+      return Err(MultiPackIndexError::Malformed(
+        "incremental multi-pack-indexes are not supported",
+      ));
+    }
+    let (content, checksum) = data.split_at(data.len() - 20);
+    if Sha1::digest(content)[..] != *checksum {
+      return Err(MultiPackIndexError::ChecksumMismatch);
+    }
+
+    let chunk_count = usize::from(data[6]);
+    let table_end = 12 + (chunk_count + 1) * 12;
+    if table_end > content.len() {
+      return Err(MultiPackIndexError::Malformed("chunk table out of bounds"));
+    }
+    let mut chunks: HashMap<[u8; 4], (usize, usize)> = HashMap::new();
+    for i in 0..chunk_count {
+      let entry = &data[12 + i * 12..];
+      let next = &data[12 + (i + 1) * 12..];
+      let start = read_u64(&entry[4..]);
+      let end = read_u64(&next[4..]);
+      if start < table_end as u64 || start > end || end > content.len() as u64 {
+        return Err(MultiPackIndexError::Malformed("chunk out of bounds"));
+      }
+      chunks.insert(
+        entry[..4].try_into().unwrap(),
+        (start as usize, (end - start) as usize),
+      );
+    }
+    let chunk = |id: &[u8; 4], name| {
+      chunks
+        .get(id)
+        .copied()
+        .ok_or(MultiPackIndexError::Missing(name))
+    };
+
+    let pack_count = read_u32(&data[8..]);
+    let (names, names_len) = chunk(PACK_NAMES, "PNAM")?;
+    let packs = data[names..names + names_len]
+      .split(|&b| b == 0)
+      .filter(|name| !name.is_empty())
+      .map(|name| String::from_utf8(name.to_vec()))
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(|_| MultiPackIndexError::Malformed("pack name is not UTF-8"))?;
+    if packs.len() != pack_count as usize {
+      return Err(MultiPackIndexError::Malformed(
+        "PNAM chunk has the wrong number of packs",
+      ));
+    }
+    if packs.windows(2).any(|pair| pair[0] >= pair[1])
+      || packs
+        .iter()
+        .any(|name| name.contains(['/', '\\']) || !name.ends_with(".idx"))
+    {
+      return Err(MultiPackIndexError::Malformed("invalid pack names"));
+    }
+
+    let (fanout, fanout_len) = chunk(OID_FANOUT, "OIDF")?;
+    if fanout_len != 256 * 4 {
+      return Err(MultiPackIndexError::Malformed(
+        "OIDF chunk has the wrong size",
+      ));
+    }
+    let objects = read_u32(&data[fanout + 255 * 4..]);
+    let (lookup, lookup_len) = chunk(OID_LOOKUP, "OIDL")?;
+    let (offsets, offsets_len) = chunk(OBJECT_OFFSETS, "OOFF")?;
+    if table_len(objects, 20) != Some(lookup_len) || table_len(objects, 8) != Some(offsets_len) {
+      return Err(MultiPackIndexError::Malformed(
+        "OIDL or OOFF chunk has the wrong size",
+      ));
+    }
+    Ok(Self {
+      packs,
+      objects,
+      fanout,
+      lookup,
+      offsets,
+      large_offsets: chunks.get(LARGE_OFFSETS).copied(),
+      data,
+    })
+  }
+
+  /// How many objects are in the multi-pack-index
+  pub fn len(&self) -> u32 {
+    self.objects
+  }
+
+  /// Whether there are no objects in the multi-pack-index
+  pub fn is_empty(&self) -> bool {
+    self.objects == 0
+  }
+
+  /// The names of the `.idx` files of the packs, sorted
+  pub fn pack_names(&self) -> &[String] {
+    &self.packs
+  }
+
+  /// The [`OID`] of every object, sorted
+  pub fn oids(&self) -> impl Iterator<Item = OID> + '_ {
+    (0..self.objects).map(move |i| self.oid(i))
+  }
+
+  /// Whether the object is in one of the packs
+  pub fn contains(&self, oid: &OID) -> bool {
+    self.position(oid).is_some()
+  }
+
+  /// Where the object is, as the position of its pack in
+  /// [`MultiPackIndex::pack_names`] and its offset in that pack
+  pub fn find(&self, oid: &OID) -> Option<(u32, u64)> {
+    let i = self.position(oid)? as usize;
+    let entry = &self.data[self.offsets + i * 8..][..8];
+    let pack = read_u32(entry);
+    let offset = read_u32(&entry[4..]);
+    let offset = match self.large_offsets {
+      Some((start, len)) if offset & LARGE_OFFSET != 0 => {
+        // A large offset past the end of the chunk gives an offset past the
+        // end of the pack which is caught when it's read
+        table_len(offset & !LARGE_OFFSET, 8)
+          .filter(|at: &usize| at.checked_add(8).is_some_and(|end| end <= len))
+          .map_or(u64::MAX, |at| read_u64(&self.data[start + at..]))
+      }
+      _ => u64::from(offset),
+    };
+    Some((pack, offset))
+  }
+
+  fn oid(&self, i: u32) -> OID {
+    OID::from_bytes(&self.data[self.lookup + i as usize * 20..][..20]).unwrap()
+  }
+
+  fn position(&self, oid: &OID) -> Option<u32> {
+    let fanout = |i: usize| read_u32(&self.data[self.fanout + i * 4..]).min(self.objects);
+    let first = usize::from(oid.as_bytes()[0]);
+    let mut low = if first == 0 { 0 } else { fanout(first - 1) };
+    let mut high = fanout(first);
+    while low < high {
+      let mid = low + (high - low) / 2;
+      match self.oid(mid).cmp(oid) {
+        std::cmp::Ordering::Less => low = mid + 1,
+        std::cmp::Ordering::Greater => high = mid,
+        std::cmp::Ordering::Equal => return Some(mid),
+      }
+    }
+    None
+  }
+}
+
+impl fmt::Debug for MultiPackIndex {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("MultiPackIndex")
+      .field("packs", &self.packs)
+      .field("objects", &self.objects)
+      .finish()
+  }
+}
+
+/// Write a multi-pack-index of `packs` to `pack_dir`. The file is written
+/// to `multi-pack-index.lock` first and then moved into place.
+pub(crate) fn write(pack_dir: &Path, packs: &mut [IndexedPack]) -> Result<(), MultiPackIndexError> {
+  let _region = Trace2::region("midx", "write");
+  packs.sort_by(|a, b| a.name.cmp(&b.name));
+  // The entries of every pack with the newest pack first for each object
+  let mut objects: Vec<(OID, Reverse<SystemTime>, u32, u64)> = Vec::new();
+  for (id, pack) in packs.iter().enumerate() {
+    objects.extend(
+      pack
+        .objects
+        .iter()
+        .map(|(oid, offset)| (*oid, Reverse(pack.modified), id as u32, *offset)),
+    );
+  }
+  objects.sort_unstable();
+  objects.dedup_by_key(|(oid, ..)| *oid);
+  if objects.len() > u32::MAX as usize {
+    return Err(MultiPackIndexError::Malformed("too many objects"));
+  }
+
+  let mut names = Vec::new();
+  for pack in packs.iter() {
+    names.extend_from_slice(pack.name.as_bytes());
+    names.push(0);
+  }
+  names.resize(names.len().div_ceil(4) * 4, 0);
+  let mut fanout = Vec::with_capacity(256 * 4);
+  for byte in 0..=255u8 {
+    let count = objects.partition_point(|(oid, ..)| oid.as_bytes()[0] <= byte) as u32;
+    fanout.extend_from_slice(&count.to_be_bytes());
+  }
+  let lookup: Vec<u8> = objects
+    .iter()
+    .flat_map(|(oid, ..)| *oid.as_bytes())
+    .collect();
+  // Offsets only go in the LOFF chunk if some don't fit in 32 bits, and
+  // then only the ones with the top bit set
+  let large_needed = objects
+    .iter()
+    .any(|(.., offset)| *offset > u64::from(u32::MAX));
+  let mut offsets = Vec::with_capacity(objects.len() * 8);
+  let mut large = Vec::new();
+  for (_, _, pack, offset) in &objects {
+    offsets.extend_from_slice(&pack.to_be_bytes());
+    let offset = if large_needed && offset >> 31 != 0 {
+      let index = (large.len() / 8) as u32 | LARGE_OFFSET;
+      large.extend_from_slice(&offset.to_be_bytes());
+      index
+    } else {
+      *offset as u32
+    };
+    offsets.extend_from_slice(&offset.to_be_bytes());
+  }
+
+  let mut chunks = vec![
+    (PACK_NAMES, names),
+    (OID_FANOUT, fanout),
+    (OID_LOOKUP, lookup),
+    (OBJECT_OFFSETS, offsets),
+  ];
+  if !large.is_empty() {
+    chunks.push((LARGE_OFFSETS, large));
+  }
+  let mut data = Vec::new();
+  data.extend_from_slice(SIGNATURE);
+  // Version 1, SHA-1, the number of chunks, no base multi-pack-indexes,
+  // and the number of packs
+  data.extend_from_slice(&[1, 1, chunks.len() as u8, 0]);
+  data.extend_from_slice(&(packs.len() as u32).to_be_bytes());
+  let mut offset = (data.len() + (chunks.len() + 1) * 12) as u64;
+  for (id, chunk) in &chunks {
+    data.extend_from_slice(*id);
+    data.extend_from_slice(&offset.to_be_bytes());
+    offset += chunk.len() as u64;
+  }
+  data.extend_from_slice(&[0; 4]);
+  data.extend_from_slice(&offset.to_be_bytes());
+  for (_, chunk) in &chunks {
+    data.extend_from_slice(chunk);
+  }
+  let checksum = Sha1::digest(&data);
+  data.extend_from_slice(&checksum);
+  Trace2::data("midx", "num_objects", objects.len());
+
+  let path = pack_dir.join("multi-pack-index");
+  let lock_path = pack_dir.join("multi-pack-index.lock");
+  let mut lock = match fs::OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .open(&lock_path)
+  {
+    Ok(lock) => lock,
+    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+      return Err(MultiPackIndexError::Locked(lock_path))
+    }
+    Err(e) => return Err(e.into()),
+  };
+  let result = lock
+    .write_all(&data)
+    .and_then(|_| lock.sync_all())
+    .and_then(|_| fs::rename(&lock_path, &path));
+  if let Err(e) = result {
+    let _ = fs::remove_file(&lock_path);
+    return Err(e.into());
+  }
+  Ok(())
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`MultiPackIndex`] type
+pub enum MultiPackIndexError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Pack(#[from] PackError),
+  #[error("malformed multi-pack-index: {0}")]
+  Malformed(&'static str),
+  #[error("multi-pack-index is missing the {0} chunk")]
+  Missing(&'static str),
+  #[error("multi-pack-index checksum does not match its contents")]
+  ChecksumMismatch,
+  #[error("multi-pack-index version {0} is not supported")]
+  UnsupportedVersion(u8),
+  #[error("{0:?} already exists, another process may be writing the multi-pack-index")]
+  Locked(PathBuf),
+}
+
+#[test]
+fn write_and_read() {
+  use crate::{pack::write_test_pack, Blob, ObjectKind, RawObject, Repository};
+  use std::process::Command;
+  let tmp_dir = tempdir::TempDir::new("midx_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let odb = repo.odb();
+  let pack_dir = odb.path().join("pack");
+  let blob = |data: &str| RawObject::new(ObjectKind::Blob, data);
+  let a = write_test_pack(
+    &pack_dir,
+    "a",
+    &[(None, blob("one\n")), (None, blob("two\n"))],
+  );
+  let b = write_test_pack(
+    &pack_dir,
+    "b",
+    &[
+      (None, blob("two\n")),
+      (None, RawObject::new(ObjectKind::Tree, "")),
+    ],
+  );
+  let loose = odb.write_blob(&Blob::new("loose\n")).unwrap();
+  assert!(MultiPackIndex::open(&pack_dir).unwrap().is_none());
+
+  odb.write_midx().unwrap();
+  let midx = MultiPackIndex::open(&pack_dir).unwrap().unwrap();
+  assert_eq!(3, midx.len());
+  assert_eq!(["pack-a.idx", "pack-b.idx"], midx.pack_names());
+  let mut oids = vec![a[0], a[1], b[1]];
+  oids.sort();
+  assert_eq!(oids, midx.oids().collect::<Vec<_>>());
+  assert_eq!(Some((0, 12)), midx.find(&a[0]));
+  assert_eq!(Some(1), midx.find(&b[1]).map(|(pack, _)| pack));
+  assert!(midx.contains(&a[1]));
+  assert!(!midx.contains(&loose));
+  for oid in [a[0], a[1], b[1], loose] {
+    assert!(odb.contains(&oid).unwrap());
+    assert_eq!(oid, odb.read(&oid).unwrap().id());
+  }
+
+  if crate::transport::http::have_git() {
+    let git = |args: &[&str]| {
+      Command::new("git")
+        .args(args)
+        .current_dir(tmp_dir.path())
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .output()
+        .unwrap()
+    };
+    let verify = git(&["multi-pack-index", "verify", "--no-progress"]);
+    assert!(verify.status.success(), "{:?}", verify);
+    fs::remove_file(pack_dir.join("multi-pack-index")).unwrap();
+    assert!(git(&["multi-pack-index", "write", "--no-progress"])
+      .status
+      .success());
+    let written = MultiPackIndex::open(&pack_dir).unwrap().unwrap();
+    assert_eq!(oids, written.oids().collect::<Vec<_>>());
+    assert_eq!(midx.find(&a[0]), written.find(&a[0]));
+    assert_eq!(midx.find(&b[1]), written.find(&b[1]));
+  }
+
+  // A pack added later is looked through on its own
+  let c = write_test_pack(&pack_dir, "c", &[(None, blob("three\n"))]);
+  let repo = Repository::open(tmp_dir.path()).unwrap();
+  assert_eq!(c[0], repo.odb().read(&c[0]).unwrap().id());
+  assert!(!MultiPackIndex::open(&pack_dir)
+    .unwrap()
+    .unwrap()
+    .contains(&c[0]));
+
+  // One that lists a pack that was removed isn't used
+  for extension in ["pack", "idx"] {
+    fs::remove_file(pack_dir.join(format!("pack-a.{}", extension))).unwrap();
+  }
+  let repo = Repository::open(tmp_dir.path()).unwrap();
+  assert_eq!(a[1], repo.odb().read(&a[1]).unwrap().id());
+  assert!(!repo.odb().contains(&a[0]).unwrap());
+
+  let path = pack_dir.join("multi-pack-index");
+  let mut data = fs::read(&path).unwrap();
+  let last = data.len() - 1;
+  data[last] ^= 1;
+  fs::write(&path, data).unwrap();
+  assert!(matches!(
+    MultiPackIndex::open(&pack_dir),
+    Err(MultiPackIndexError::ChecksumMismatch)
+  ));
+}
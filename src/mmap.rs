@@ -0,0 +1,161 @@
+//! Read only views of part of a file. With the `mmap` feature these are
+//! memory maps made by memmap2, so only the pages that are used are read
+//! in. Without it, or for a file that can't be mapped, like one on a
+//! filesystem that doesn't support it, the bytes are read into a buffer
+//! instead.
+
+use std::{fs::File, io, ops::Deref};
+
+/// A read only view of `len` bytes of a file starting at an offset
+pub(crate) struct Mmap {
+  view: View,
+}
+
+enum View {
+  #[cfg(feature = "mmap")]
+  Mapped(memmap2::Mmap),
+  Buffer(Vec<u8>),
+}
+
+impl Mmap {
+  /// Map `len` bytes of `file` starting at `offset`, which has to be a
+  /// multiple of [`page_size`]. Offsets past 4 GiB work on 32 bit targets
+  /// too, as long as `len` fits in memory.
+  pub(crate) fn map(file: &File, offset: u64, len: usize) -> io::Result<Self> {
+    // Zero length maps aren't allowed so an empty view is an empty buffer
+    if len == 0 {
+      return Ok(Self::buffer(Vec::new()));
+    }
+    #[cfg(feature = "mmap")]
+    match map(file, offset, len) {
+      Ok(map) => Ok(Self {
+        view: View::Mapped(map),
+      }),
+      Err(e) => {
+        let mut buffer = vec![0; len];
+        // The bytes are read instead, and if that fails too it's the map
+        // failing that says best what went wrong
+        read_exact_at(file, &mut buffer, offset).map_err(|_| e)?;
+        Ok(Self::buffer(buffer))
+      }
+    }
+    #[cfg(not(feature = "mmap"))]
+    {
+      let mut buffer = vec![0; len];
+      read_exact_at(file, &mut buffer, offset)?;
+      Ok(Self::buffer(buffer))
+    }
+  }
+
+  fn buffer(buffer: Vec<u8>) -> Self {
+    Self {
+      view: View::Buffer(buffer),
+    }
+  }
+}
+
+#[cfg(feature = "mmap")]
+fn map(file: &File, offset: u64, len: usize) -> io::Result<memmap2::Mmap> {
+  // SAFETY: the files that are mapped, packs and their indexes and loose
+  // objects, are only ever replaced by renaming another file over them and
+  // never written to once they're in place
+  unsafe {
+    memmap2::MmapOptions::new()
+      .offset(offset)
+      .len(len)
+      .map(file)
+  }
+}
+
+impl Deref for Mmap {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match &self.view {
+      #[cfg(feature = "mmap")]
+      View::Mapped(map) => map,
+      View::Buffer(buffer) => buffer,
+    }
+  }
+}
+
+/// The size of a page of memory, which offsets of maps have to be a multiple
+/// of
+pub(crate) fn page_size() -> usize {
+  #[cfg(all(unix, feature = "mmap"))]
+  {
+    // SAFETY: sysconf has no preconditions
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 {
+      return size as usize;
+    }
+  }
+  4096
+}
+
+/// Fill `buffer` with the bytes of `file` starting at `offset` without
+/// using the position of the file, so several threads can read at once
+pub(crate) fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buffer, offset)
+  }
+  #[cfg(windows)]
+  {
+    use std::os::windows::fs::FileExt;
+    let mut read = 0;
+    while read < buffer.len() {
+      match file.seek_read(&mut buffer[read..], offset + read as u64) {
+        Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+        Ok(n) => read += n,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(())
+  }
+  #[cfg(not(any(unix, windows)))]
+  {
+    let _ = (file, buffer, offset);
+    Err(io::Error::new(
+      io::ErrorKind::Unsupported,
+      "reading at an offset is not supported on this platform",
+    ))
+  }
+}
+
+#[test]
+fn views() {
+  use crate::{Blob, Odb};
+  let tmp_dir = tempdir::TempDir::new("mmap_test").unwrap();
+  let page = page_size();
+  // Bytes that don't compress, so the loose object is big enough to map
+  let mut state = 1u32;
+  let bytes: Vec<u8> = (0..page * 20)
+    .map(|_| {
+      state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+      (state >> 16) as u8
+    })
+    .collect();
+  let path = tmp_dir.path().join("file");
+  std::fs::write(&path, &bytes).unwrap();
+  let file = File::open(&path).unwrap();
+  assert_eq!(
+    bytes[page..page + 10],
+    Mmap::map(&file, page as u64, 10).unwrap()[..]
+  );
+  assert!(Mmap::map(&file, 0, 0).unwrap().is_empty());
+  // Only the mmap feature maps the file, otherwise it's read
+  let view = Mmap::map(&file, 0, page).unwrap();
+  assert_eq!(bytes[..page], view[..]);
+  #[cfg(feature = "mmap")]
+  assert!(matches!(view.view, View::Mapped(_)));
+  #[cfg(not(feature = "mmap"))]
+  assert!(matches!(view.view, View::Buffer(_)));
+
+  let odb = Odb::new(tmp_dir.path().join("objects"));
+  let oid = odb.write_blob(&Blob::new(bytes.clone())).unwrap();
+  let uncached = odb.with_object_cache(crate::ObjectCacheLimits::none());
+  assert_eq!(bytes, uncached.read(&oid).unwrap().data);
+}
@@ -0,0 +1,570 @@
+use crate::{
+  refs::check_ref_name, Blob, Commit, FileMode, Odb, OdbError, RefError, Repository, RevWalkError,
+  Signature, Tree, TreeEntry, OID,
+};
+use bstr::{BString, ByteSlice};
+use std::{
+  collections::{BTreeMap, HashSet},
+  env,
+  str::FromStr,
+};
+use thiserror::Error;
+
+/// The notes ref used when neither `GIT_NOTES_REF` nor `core.notesRef`
+/// name another one
+const DEFAULT_NOTES_REF: &str = "refs/notes/commits";
+
+/// The notes of a [`Repository`] kept in one notes ref, like
+/// `refs/notes/commits`. The ref points at a history of commits whose
+/// trees have a blob for each object with a note, named after the
+/// object's [`OID`]. Big trees are fanned out into directories named after
+/// the first two hex digits, and any fan-out is read.
+#[derive(Debug, Clone)]
+pub struct Notes<'a> {
+  repo: &'a Repository,
+  name: String,
+}
+
+/// How [`Notes::merge`] resolves an object both sides gave different notes,
+/// like `git notes merge --strategy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NotesMergeStrategy {
+  /// Fail with [`NotesError::Conflict`]
+  #[default]
+  Manual,
+  /// Keep our note
+  Ours,
+  /// Keep their note
+  Theirs,
+  /// Our note followed by a blank line and theirs
+  Union,
+  /// The lines of both notes, sorted with the duplicates left out
+  CatSortUniq,
+}
+
+impl FromStr for NotesMergeStrategy {
+  type Err = NotesError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "manual" => Ok(Self::Manual),
+      "ours" => Ok(Self::Ours),
+      "theirs" => Ok(Self::Theirs),
+      "union" => Ok(Self::Union),
+      "cat_sort_uniq" => Ok(Self::CatSortUniq),
+      _ => Err(NotesError::UnknownStrategy(s.into())),
+    }
+  }
+}
+
+/// The full name of the notes ref `name`, which can leave out `refs/notes/`
+/// like git's `--ref` does
+fn expand_notes_ref(name: &str) -> String {
+  if name.starts_with("refs/notes/") {
+    name.into()
+  } else if let Some(rest) = name.strip_prefix("notes/") {
+    format!("refs/notes/{}", rest)
+  } else {
+    format!("refs/notes/{}", name)
+  }
+}
+
+impl Repository {
+  /// The notes in `GIT_NOTES_REF` or `core.notesRef`, or in
+  /// `refs/notes/commits` if neither is set, like `git notes` uses
+  pub fn notes(&self) -> Notes<'_> {
+    let name = match env::var("GIT_NOTES_REF") {
+      Ok(name) if !name.is_empty() => name,
+      _ => match self.config().get_str("core.notesref") {
+        Ok(Some(name)) => name.into(),
+        _ => DEFAULT_NOTES_REF.into(),
+      },
+    };
+    self.notes_ref(&name)
+  }
+
+  /// The notes in the notes ref `name`, like `git notes --ref {name}`.
+  /// Names that don't start with `refs/notes/` are put under it.
+  pub fn notes_ref(&self, name: &str) -> Notes<'_> {
+    Notes {
+      repo: self,
+      name: expand_notes_ref(name),
+    }
+  }
+}
+
+impl<'a> Notes<'a> {
+  /// The full name of the notes ref
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// The commit the notes ref is at, `None` if there aren't any notes yet
+  pub fn commit(&self) -> Result<Option<OID>, NotesError> {
+    Ok(self.repo.refs().resolve(&self.name)?)
+  }
+
+  /// The [`OID`] of the blob with the note for `oid`
+  pub fn find(&self, oid: &OID) -> Result<Option<OID>, NotesError> {
+    let commit = match self.commit()? {
+      Some(commit) => commit,
+      None => return Ok(None),
+    };
+    let odb = self.repo.odb();
+    let mut tree = odb.read_tree(odb.read_commit(&commit)?.tree())?;
+    let hex = oid.as_hex();
+    let mut rest = &hex[..];
+    // Every level of the fan-out takes another two digits off the name
+    loop {
+      if let Some(entry) = tree.get(rest).filter(|entry| entry.mode().is_blob()) {
+        return Ok(Some(*entry.oid()));
+      }
+      match tree.get(&rest[..2]).filter(|entry| entry.mode().is_tree()) {
+        Some(entry) if rest.len() > 2 => {
+          tree = odb.read_tree(entry.oid())?;
+          rest = &rest[2..];
+        }
+        _ => return Ok(None),
+      }
+    }
+  }
+
+  /// The note for `oid`, like `git notes show {oid}`
+  pub fn get(&self, oid: &OID) -> Result<Option<BString>, NotesError> {
+    match self.find(oid)? {
+      Some(blob) => Ok(Some(self.repo.odb().read_blob(&blob)?.contents().into())),
+      None => Ok(None),
+    }
+  }
+
+  /// Every object with a note along with the blob of its note, sorted by
+  /// the object, like `git notes list`
+  pub fn list(&self) -> Result<Vec<(OID, OID)>, NotesError> {
+    Ok(self.read(self.commit()?)?.notes.into_iter().collect())
+  }
+
+  fn read(&self, commit: Option<OID>) -> Result<NotesTree, NotesError> {
+    let mut tree = NotesTree::default();
+    if let Some(commit) = commit {
+      let odb = self.repo.odb();
+      tree.read(odb, odb.read_commit(&commit)?.tree(), "")?;
+    }
+    Ok(tree)
+  }
+
+  /// Make `message` the note of `oid`, like `git notes add -m {message}
+  /// {oid}`, returning the new commit of the notes ref. The message is
+  /// stored as given, so it should end with a newline like git's do. An
+  /// existing note is only replaced if `force` is set.
+  pub fn add(
+    &self,
+    oid: &OID,
+    message: impl AsRef<[u8]>,
+    force: bool,
+    signature: Signature,
+  ) -> Result<OID, NotesError> {
+    let commit = self.commit()?;
+    let mut tree = self.read(commit)?;
+    if !force && tree.notes.contains_key(oid) {
+      return Err(NotesError::Exists(*oid));
+    }
+    let blob = self.repo.odb().write_blob(&Blob::new(message.as_ref()))?;
+    tree.notes.insert(*oid, blob);
+    self.write(
+      commit.into_iter().collect(),
+      &tree,
+      signature,
+      "Notes added by 'git notes add'\n",
+    )
+  }
+
+  /// Add `message` to the end of the note of `oid` after a blank line,
+  /// like `git notes append`, or make it the note if there isn't one
+  pub fn append(
+    &self,
+    oid: &OID,
+    message: impl AsRef<[u8]>,
+    signature: Signature,
+  ) -> Result<OID, NotesError> {
+    let commit = self.commit()?;
+    let mut tree = self.read(commit)?;
+    let mut note = Vec::new();
+    if let Some(blob) = tree.notes.get(oid) {
+      note = self.repo.odb().read_blob(blob)?.contents().to_vec();
+      note.push(b'\n');
+    }
+    note.extend_from_slice(message.as_ref());
+    let blob = self.repo.odb().write_blob(&Blob::new(note))?;
+    tree.notes.insert(*oid, blob);
+    self.write(
+      commit.into_iter().collect(),
+      &tree,
+      signature,
+      "Notes added by 'git notes append'\n",
+    )
+  }
+
+  /// Remove the note of `oid`, like `git notes remove`, returning whether
+  /// there was one. Nothing is committed if there wasn't.
+  pub fn remove(&self, oid: &OID, signature: Signature) -> Result<bool, NotesError> {
+    let commit = self.commit()?;
+    let mut tree = self.read(commit)?;
+    if tree.notes.remove(oid).is_none() {
+      return Ok(false);
+    }
+    self.write(
+      commit.into_iter().collect(),
+      &tree,
+      signature,
+      "Notes removed by 'git notes remove'\n",
+    )?;
+    Ok(true)
+  }
+
+  /// Commit `tree` with `parents` and move the notes ref to it
+  fn write(
+    &self,
+    parents: Vec<OID>,
+    tree: &NotesTree,
+    signature: Signature,
+    message: &str,
+  ) -> Result<OID, NotesError> {
+    check_ref_name(self.name.as_bytes())
+      .map_err(|_| NotesError::InvalidName(self.name.clone().into()))?;
+    let odb = self.repo.odb();
+    let tree = tree.write(odb)?;
+    let commit = Commit::new(tree, parents, signature.clone(), signature, message);
+    let commit = odb.write_commit(&commit)?;
+    self.repo.refs().write(&self.name, &commit)?;
+    Ok(commit)
+  }
+
+  /// Merge the notes in the notes ref `other` into these, like `git notes
+  /// merge {other}`, returning the new commit of the notes ref. Notes only
+  /// one side changed since the two diverged are taken from that side,
+  /// and `strategy` decides between notes both changed. When one side has
+  /// everything the other has, the notes ref is fast-forwarded or left as
+  /// it is.
+  pub fn merge(
+    &self,
+    other: &str,
+    strategy: NotesMergeStrategy,
+    signature: Signature,
+  ) -> Result<OID, NotesError> {
+    let other = expand_notes_ref(other);
+    let theirs = self
+      .repo
+      .refs()
+      .resolve(&other)?
+      .ok_or_else(|| NotesError::NotFound(other.clone().into()))?;
+    let ours = match self.commit()? {
+      Some(ours) => ours,
+      None => {
+        self.repo.refs().write(&self.name, &theirs)?;
+        return Ok(theirs);
+      }
+    };
+    let base = merge_base(self.repo, &ours, &theirs)?;
+    if base == Some(theirs) {
+      return Ok(ours);
+    }
+    if base == Some(ours) {
+      self.repo.refs().write(&self.name, &theirs)?;
+      return Ok(theirs);
+    }
+
+    let odb = self.repo.odb();
+    let base_tree = self.read(base)?;
+    let mut merged = self.read(Some(ours))?;
+    let their_tree = self.read(Some(theirs))?;
+    let objects: HashSet<OID> = merged
+      .notes
+      .keys()
+      .chain(their_tree.notes.keys())
+      .chain(base_tree.notes.keys())
+      .copied()
+      .collect();
+    let mut conflicts = Vec::new();
+    for oid in objects {
+      let (base, ours, theirs) = (
+        base_tree.notes.get(&oid),
+        merged.notes.get(&oid).copied(),
+        their_tree.notes.get(&oid),
+      );
+      let note = if ours.as_ref() == theirs || theirs == base {
+        continue;
+      } else if ours.as_ref() == base {
+        theirs.copied()
+      } else {
+        match strategy {
+          NotesMergeStrategy::Manual => {
+            conflicts.push(oid);
+            continue;
+          }
+          NotesMergeStrategy::Ours => continue,
+          NotesMergeStrategy::Theirs => theirs.copied(),
+          NotesMergeStrategy::Union | NotesMergeStrategy::CatSortUniq => {
+            let read = |blob: Option<&OID>| -> Result<Vec<u8>, OdbError> {
+              Ok(match blob {
+                Some(blob) => odb.read_blob(blob)?.contents().to_vec(),
+                None => Vec::new(),
+              })
+            };
+            let (ours, theirs) = (read(ours.as_ref())?, read(theirs)?);
+            let note = match strategy {
+              NotesMergeStrategy::Union if ours.is_empty() || theirs.is_empty() => {
+                [ours, theirs].concat()
+              }
+              NotesMergeStrategy::Union => [&ours[..], b"\n", &theirs[..]].concat(),
+              _ => {
+                let mut lines: Vec<&[u8]> = ours.lines().chain(theirs.lines()).collect();
+                lines.sort();
+                lines.dedup();
+                lines
+                  .iter()
+                  .flat_map(|line| [*line, &b"\n"[..]])
+                  .collect::<Vec<_>>()
+                  .concat()
+              }
+            };
+            Some(odb.write_blob(&Blob::new(note))?)
+          }
+        }
+      };
+      match note {
+        Some(note) => merged.notes.insert(oid, note),
+        None => merged.notes.remove(&oid),
+      };
+    }
+    if !conflicts.is_empty() {
+      conflicts.sort();
+      return Err(NotesError::Conflict(conflicts));
+    }
+    let message = format!("notes: Merged notes from {} into {}\n", other, self.name);
+    self.write(vec![ours, theirs], &merged, signature, &message)
+  }
+}
+
+/// The newest commit both `ours` and `theirs` have in their history
+fn merge_base(repo: &Repository, ours: &OID, theirs: &OID) -> Result<Option<OID>, NotesError> {
+  let mut walk = repo.rev_walk();
+  walk.push(ours)?;
+  let ours: HashSet<OID> = walk.collect::<Result<_, _>>()?;
+  let mut walk = repo.rev_walk();
+  walk.push(theirs)?;
+  for oid in walk {
+    let oid = oid?;
+    if ours.contains(&oid) {
+      return Ok(Some(oid));
+    }
+  }
+  Ok(None)
+}
+
+/// The notes of a notes tree and what else is at the top of it, which is
+/// kept as it is
+#[derive(Default)]
+struct NotesTree {
+  notes: BTreeMap<OID, OID>,
+  others: Vec<TreeEntry>,
+}
+
+impl NotesTree {
+  /// Add the notes in `tree`, whose path in the fan-out is the hex digits
+  /// of `prefix`
+  fn read(&mut self, odb: &Odb, tree: &OID, prefix: &str) -> Result<(), NotesError> {
+    for entry in odb.read_tree(tree)?.entries() {
+      let name = entry.name().to_str().ok().filter(|name| {
+        name.len() + prefix.len() <= 40 && name.bytes().all(|c| c.is_ascii_hexdigit())
+      });
+      match name {
+        Some(name) if entry.mode().is_blob() && name.len() + prefix.len() == 40 => {
+          if let Ok(oid) = OID::from_hex(&[prefix, name].concat()) {
+            self.notes.insert(oid, *entry.oid());
+            continue;
+          }
+        }
+        Some(name) if entry.mode().is_tree() && name.len() == 2 => {
+          self.read(odb, entry.oid(), &[prefix, name].concat())?;
+          continue;
+        }
+        _ => {}
+      }
+      if prefix.is_empty() {
+        self.others.push(entry.clone());
+      }
+    }
+    Ok(())
+  }
+
+  /// Write the tree, fanned out one more level for every 256 times as many
+  /// notes there are than fit in one, which keeps each tree around 256
+  /// entries like git
+  fn write(&self, odb: &Odb) -> Result<OID, NotesError> {
+    let mut levels = 0;
+    let mut count = self.notes.len();
+    while count > 256 {
+      levels += 1;
+      count /= 256;
+    }
+    let notes: Vec<(String, OID)> = self
+      .notes
+      .iter()
+      .map(|(oid, blob)| (oid.as_hex(), *blob))
+      .collect();
+    let mut entries = self.others.clone();
+    entries.extend(write_level(odb, &notes, 0, levels)?);
+    Ok(odb.write_tree(&Tree::new(entries))?)
+  }
+}
+
+/// The entries for `notes`, sorted by their hex names, at `depth` digits
+/// into the names with `levels` of fan-out left
+fn write_level(
+  odb: &Odb,
+  notes: &[(String, OID)],
+  depth: usize,
+  levels: usize,
+) -> Result<Vec<TreeEntry>, NotesError> {
+  if levels == 0 {
+    return Ok(
+      notes
+        .iter()
+        .map(|(hex, blob)| TreeEntry::new(FileMode::NonExecutableFile, &hex[depth..], *blob))
+        .collect(),
+    );
+  }
+  let mut entries = Vec::new();
+  let mut rest = notes;
+  while let Some((hex, _)) = rest.first() {
+    let dir = &hex[depth..depth + 2];
+    let len = rest
+      .iter()
+      .take_while(|(hex, _)| &hex[depth..depth + 2] == dir)
+      .count();
+    let subtree = write_level(odb, &rest[..len], depth + 2, levels - 1)?;
+    let oid = odb.write_tree(&Tree::new(subtree))?;
+    entries.push(TreeEntry::new(FileMode::Tree, dir, oid));
+    rest = &rest[len..];
+  }
+  Ok(entries)
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`Notes`] type
+pub enum NotesError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  RevWalk(#[from] RevWalkError),
+  #[error("{0:?} is not a valid notes ref")]
+  InvalidName(BString),
+  #[error("notes ref {0} not found")]
+  NotFound(BString),
+  #[error("object {0} already has a note")]
+  Exists(OID),
+  #[error("unknown notes merge strategy {0:?}")]
+  UnknownStrategy(String),
+  #[error("the notes of {0:?} changed on both sides")]
+  Conflict(Vec<OID>),
+}
+
+#[test]
+fn notes() {
+  use crate::Time;
+  let tmp_dir = tempdir::TempDir::new("notes_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let odb = repo.odb();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let blobs: Vec<OID> = (0..260)
+    .map(|i| odb.write_blob(&Blob::new(i.to_string())).unwrap())
+    .collect();
+  let notes = repo.notes();
+  assert_eq!("refs/notes/commits", notes.name());
+  assert_eq!("refs/notes/review", repo.notes_ref("review").name());
+  assert_eq!(None, notes.get(&blobs[0]).unwrap());
+
+  notes
+    .add(&blobs[0], "first\n", false, signature.clone())
+    .unwrap();
+  assert!(matches!(
+    notes.add(&blobs[0], "again\n", false, signature.clone()),
+    Err(NotesError::Exists(_))
+  ));
+  notes
+    .append(&blobs[0], "more\n", signature.clone())
+    .unwrap();
+  assert_eq!(
+    Some("first\n\nmore\n".into()),
+    notes.get(&blobs[0]).unwrap()
+  );
+  assert!(notes.remove(&blobs[0], signature.clone()).unwrap());
+  assert!(!notes.remove(&blobs[0], signature.clone()).unwrap());
+  assert!(notes.list().unwrap().is_empty());
+
+  // Enough notes get fanned out, and both kinds of trees are read
+  for (i, blob) in blobs.iter().enumerate() {
+    notes
+      .add(blob, format!("note {}\n", i), false, signature.clone())
+      .unwrap();
+  }
+  let commit = odb.read_commit(&notes.commit().unwrap().unwrap()).unwrap();
+  let tree = odb.read_tree(commit.tree()).unwrap();
+  assert!(tree.entries().iter().all(|entry| entry.name().len() == 2));
+  assert_eq!(260, notes.list().unwrap().len());
+  assert_eq!(Some("note 7\n".into()), notes.get(&blobs[7]).unwrap());
+
+  // Git reads them the same way
+  if crate::transport::http::have_git() {
+    let output = std::process::Command::new("git")
+      .args(["notes", "show", &blobs[7].as_hex()])
+      .current_dir(tmp_dir.path())
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .output()
+      .unwrap();
+    assert!(output.status.success());
+    assert_eq!(b"note 7\n", &output.stdout[..]);
+  }
+
+  // Merging takes what only one side changed and resolves the rest with
+  // the strategy
+  let review = repo.notes_ref("review");
+  review
+    .merge("commits", NotesMergeStrategy::Manual, signature.clone())
+    .unwrap();
+  assert_eq!(notes.commit().unwrap(), review.commit().unwrap());
+  review
+    .add(&blobs[1], "reviewed\n", true, signature.clone())
+    .unwrap();
+  review
+    .add(&blobs[2], "theirs\n", true, signature.clone())
+    .unwrap();
+  notes
+    .add(&blobs[2], "ours\n", true, signature.clone())
+    .unwrap();
+  notes.remove(&blobs[3], signature.clone()).unwrap();
+  assert!(matches!(
+    notes.merge("review", NotesMergeStrategy::Manual, signature.clone()),
+    Err(NotesError::Conflict(_))
+  ));
+  let merged = notes
+    .merge("review", NotesMergeStrategy::Union, signature.clone())
+    .unwrap();
+  assert_eq!(2, odb.read_commit(&merged).unwrap().parents().len());
+  assert_eq!(Some("reviewed\n".into()), notes.get(&blobs[1]).unwrap());
+  assert_eq!(
+    Some("ours\n\ntheirs\n".into()),
+    notes.get(&blobs[2]).unwrap()
+  );
+  assert_eq!(None, notes.get(&blobs[3]).unwrap());
+  assert!(matches!(
+    "cat_sort_uniq".parse(),
+    Ok(NotesMergeStrategy::CatSortUniq)
+  ));
+}
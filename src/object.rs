@@ -0,0 +1,56 @@
+//! The [`Object`] trait shared by everything that can be stored in the
+//! [`Odb`][crate::Odb], so that working out its [`OID`][crate::OID] or its
+//! on disk representation is done the same way for all of them.
+
+use crate::ObjectKind;
+use std::io::{self, Write};
+
+/// A git object, like a [`Blob`][crate::Blob], [`Tree`][crate::Tree],
+/// [`Commit`][crate::Commit], or [`Tag`][crate::Tag]. Implementing it for
+/// another type lets [`OID::for_object`][crate::OID::for_object] hash it
+/// like git would.
+pub trait Object {
+  /// What kind of object this is
+  fn kind(&self) -> ObjectKind;
+
+  /// Write the contents of the object without the `{kind} {content_len}\0`
+  /// header
+  fn serialize_into(&self, writer: &mut impl Write) -> io::Result<()>;
+}
+
+/// The on disk representation of `object` before compression, in the form
+/// `{kind} {content_len}\0{content}`
+pub(crate) fn encode(object: &impl Object) -> Vec<u8> {
+  let mut content = Vec::new();
+  object
+    .serialize_into(&mut content)
+    .expect("writing to a Vec can't fail");
+  [
+    object.kind().as_str().as_bytes(),
+    b" ",
+    content.len().to_string().as_bytes(),
+    b"\0",
+    &content,
+  ]
+  .concat()
+}
+
+#[test]
+fn user_defined_object() {
+  struct Note(&'static str);
+  impl Object for Note {
+    fn kind(&self) -> ObjectKind {
+      ObjectKind::Blob
+    }
+
+    fn serialize_into(&self, writer: &mut impl Write) -> io::Result<()> {
+      writer.write_all(self.0.as_bytes())
+    }
+  }
+  let note = Note("this is a test");
+  assert_eq!(b"blob 14\0this is a test".to_vec(), encode(&note));
+  assert_eq!(
+    crate::Blob::new("this is a test".as_bytes()).id(),
+    crate::OID::for_object(&note)
+  );
+}
@@ -0,0 +1,245 @@
+//! Keeping objects that were read in memory, so walks and diffs that look
+//! at the same trees and commits over and over don't inflate them and
+//! apply their deltas every time. Objects never change once they have an
+//! [`OID`], so nothing in the cache ever goes stale.
+
+use crate::{pack, Config, ConfigError, RawObject, OID};
+use std::{
+  collections::{BTreeMap, HashMap},
+  fmt,
+  sync::Mutex,
+};
+
+/// How much an [`Odb`][crate::Odb] keeps of the objects it read. Small
+/// objects like trees and commits are kept apart from large ones, so
+/// reading a few big blobs doesn't push out everything a walk needs again.
+/// The least recently read objects are forgotten first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectCacheLimits {
+  /// How many bytes of small objects are kept. Defaults to 96 MiB, the
+  /// same as `core.deltaBaseCacheLimit` in git.
+  pub limit: usize,
+  /// How big an object has to be to count as large. Defaults to 1 MiB.
+  pub large_object: usize,
+  /// How many bytes of large objects are kept. Defaults to 16 MiB, a large
+  /// object that's bigger than this is never kept.
+  pub large_limit: usize,
+}
+
+impl Default for ObjectCacheLimits {
+  fn default() -> Self {
+    Self {
+      limit: 96 << 20,
+      large_object: 1 << 20,
+      large_limit: 16 << 20,
+    }
+  }
+}
+
+impl ObjectCacheLimits {
+  /// Keep no objects at all
+  pub fn none() -> Self {
+    Self {
+      limit: 0,
+      large_object: 0,
+      large_limit: 0,
+    }
+  }
+
+  /// Read how many bytes of small objects are kept from
+  /// `core.deltaBaseCacheLimit`, using the defaults for everything else
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let mut limits = Self::default();
+    if let Some(limit) = pack::size(config, "core.deltabasecachelimit")? {
+      limits.limit = limit;
+    }
+    Ok(limits)
+  }
+}
+
+/// How well the object cache of an [`Odb`][crate::Odb] is doing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ObjectCacheStats {
+  /// How many objects were read from the cache
+  pub hits: u64,
+  /// How many objects had to be read from the objects directory
+  pub misses: u64,
+  /// How many objects are kept right now
+  pub objects: usize,
+  /// How many bytes the objects kept take up
+  pub bytes: usize,
+}
+
+/// The objects kept under one limit, least recently read first
+#[derive(Default)]
+struct Lru {
+  objects: HashMap<OID, (RawObject, u64)>,
+  /// The objects by when they were last read
+  order: BTreeMap<u64, OID>,
+  bytes: usize,
+}
+
+impl Lru {
+  fn get(&mut self, oid: &OID, tick: u64) -> Option<RawObject> {
+    let (object, last) = self.objects.get_mut(oid)?;
+    self.order.remove(last);
+    self.order.insert(tick, *oid);
+    *last = tick;
+    Some(object.clone())
+  }
+
+  fn insert(&mut self, oid: OID, object: RawObject, tick: u64, limit: usize) {
+    let len = object.data.len();
+    if len > limit || self.objects.contains_key(&oid) {
+      return;
+    }
+    while self.bytes + len > limit {
+      // There's always something to forget while over the limit, since
+      // the object fits under it on its own
+      let (_, oldest) = self.order.pop_first().unwrap();
+      let (forgotten, _) = self.objects.remove(&oldest).unwrap();
+      self.bytes -= forgotten.data.len();
+    }
+    self.bytes += len;
+    self.order.insert(tick, oid);
+    self.objects.insert(oid, (object, tick));
+  }
+}
+
+#[derive(Default)]
+struct Cached {
+  small: Lru,
+  large: Lru,
+  tick: u64,
+  hits: u64,
+  misses: u64,
+}
+
+/// The objects an [`Odb`][crate::Odb] and its clones read last
+pub(crate) struct ObjectCache {
+  limits: ObjectCacheLimits,
+  cached: Mutex<Cached>,
+}
+
+impl ObjectCache {
+  pub(crate) fn new(limits: ObjectCacheLimits) -> Self {
+    Self {
+      limits,
+      cached: Mutex::default(),
+    }
+  }
+
+  pub(crate) fn limits(&self) -> ObjectCacheLimits {
+    self.limits
+  }
+
+  /// The object kept for `oid`, counting whether it was there
+  pub(crate) fn get(&self, oid: &OID) -> Option<RawObject> {
+    let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+    cached.tick += 1;
+    let tick = cached.tick;
+    let object = match cached.small.get(oid, tick) {
+      Some(object) => Some(object),
+      None => cached.large.get(oid, tick),
+    };
+    match object.is_some() {
+      true => cached.hits += 1,
+      false => cached.misses += 1,
+    }
+    object
+  }
+
+  /// Keep `object`, which was just read for `oid`, if it fits
+  pub(crate) fn insert(&self, oid: OID, object: &RawObject) {
+    let large = object.data.len() >= self.limits.large_object;
+    let limit = match large {
+      true => self.limits.large_limit,
+      false => self.limits.limit,
+    };
+    if object.data.len() > limit {
+      return;
+    }
+    let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+    cached.tick += 1;
+    let tick = cached.tick;
+    let lru = match large {
+      true => &mut cached.large,
+      false => &mut cached.small,
+    };
+    lru.insert(oid, object.clone(), tick, limit);
+  }
+
+  pub(crate) fn stats(&self) -> ObjectCacheStats {
+    let cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+    ObjectCacheStats {
+      hits: cached.hits,
+      misses: cached.misses,
+      objects: cached.small.objects.len() + cached.large.objects.len(),
+      bytes: cached.small.bytes + cached.large.bytes,
+    }
+  }
+}
+
+impl fmt::Debug for ObjectCache {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ObjectCache")
+      .field("limits", &self.limits)
+      .finish_non_exhaustive()
+  }
+}
+
+/// Two caches are the same if they keep objects the same way
+impl PartialEq for ObjectCache {
+  fn eq(&self, other: &Self) -> bool {
+    self.limits == other.limits
+  }
+}
+
+impl Eq for ObjectCache {}
+
+#[test]
+fn object_cache() {
+  use crate::{Blob, ObjectKind, Odb};
+  let tmp_dir = tempdir::TempDir::new("object_cache_test").unwrap();
+  let limits = ObjectCacheLimits {
+    limit: 100,
+    large_object: 50,
+    large_limit: 60,
+  };
+  let odb = Odb::new(tmp_dir.path()).with_object_cache(limits);
+  let small: Vec<OID> = (0..3)
+    .map(|i| odb.write_blob(&Blob::new(vec![b'a' + i; 40])).unwrap())
+    .collect();
+  let large = odb.write_blob(&Blob::new(vec![b'x'; 55])).unwrap();
+  let huge = odb.write_blob(&Blob::new(vec![b'y'; 70])).unwrap();
+
+  odb.read(&small[0]).unwrap();
+  odb.read(&small[1]).unwrap();
+  odb.read(&large).unwrap();
+  odb.read(&huge).unwrap();
+  // The objects are still read from the cache once their files are gone
+  for oid in [small[0], small[1], large, huge] {
+    let hex = oid.as_hex();
+    std::fs::remove_file(tmp_dir.path().join(&hex[..2]).join(&hex[2..])).unwrap();
+  }
+  assert_eq!(vec![b'a'; 40], odb.read(&small[0]).unwrap().data);
+  assert_eq!(ObjectKind::Blob, odb.read(&large).unwrap().kind);
+  // Too big to ever be kept
+  assert!(odb.read(&huge).is_err());
+  let stats = odb.object_cache_stats();
+  assert_eq!((2, 5), (stats.hits, stats.misses));
+  assert_eq!((3, 135), (stats.objects, stats.bytes));
+
+  // A third small object pushes out the one read longest ago, but leaves
+  // the large one alone
+  odb.read(&small[2]).unwrap();
+  assert!(odb.read(&small[1]).is_err());
+  assert!(odb.read(&small[0]).is_ok());
+  assert!(odb.read(&large).is_ok());
+  // Clones share the cache
+  assert_eq!(odb.object_cache_stats(), odb.clone().object_cache_stats());
+
+  let uncached = Odb::new(tmp_dir.path()).with_object_cache(ObjectCacheLimits::none());
+  uncached.read(&small[2]).unwrap();
+  assert_eq!(0, uncached.object_cache_stats().objects);
+}
@@ -0,0 +1,493 @@
+use crate::{
+  FileMode, ObjectKind, Odb, OdbError, Repository, RevWalk, RevWalkError, Tag, TreeEntry, OID,
+};
+use bstr::BString;
+use std::{
+  collections::{HashSet, VecDeque},
+  fmt,
+  str::FromStr,
+};
+
+/// What an [`ObjectWalk`] leaves out, like `git rev-list --filter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectFilter {
+  /// Leave out every blob, `blob:none`
+  BlobNone,
+  /// Leave out blobs of at least this many bytes, `blob:limit=<n>`
+  BlobLimit(u64),
+}
+
+impl ObjectFilter {
+  fn includes_blob(&self, size: impl FnOnce() -> Result<u64, OdbError>) -> Result<bool, OdbError> {
+    match self {
+      Self::BlobNone => Ok(false),
+      Self::BlobLimit(limit) => Ok(size()? < *limit),
+    }
+  }
+}
+
+impl FromStr for ObjectFilter {
+  type Err = RevWalkError;
+
+  /// Parse a filter the way git writes them, with an optional `k`, `m`, or
+  /// `g` after the limit
+  fn from_str(filter: &str) -> Result<Self, Self::Err> {
+    let invalid = || RevWalkError::InvalidFilter(filter.into());
+    if filter == "blob:none" {
+      return Ok(Self::BlobNone);
+    }
+    let limit = filter.strip_prefix("blob:limit=").ok_or_else(invalid)?;
+    let (digits, scale) = match limit.as_bytes().last() {
+      Some(b'k' | b'K') => (&limit[..limit.len() - 1], 1 << 10),
+      Some(b'm' | b'M') => (&limit[..limit.len() - 1], 1 << 20),
+      Some(b'g' | b'G') => (&limit[..limit.len() - 1], 1 << 30),
+      _ => (limit, 1),
+    };
+    digits
+      .parse::<u64>()
+      .ok()
+      .and_then(|limit| limit.checked_mul(scale))
+      .map(Self::BlobLimit)
+      .ok_or_else(invalid)
+  }
+}
+
+impl fmt::Display for ObjectFilter {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::BlobNone => write!(f, "blob:none"),
+      Self::BlobLimit(limit) => write!(f, "blob:limit={}", limit),
+    }
+  }
+}
+
+/// An object given by an [`ObjectWalk`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkedObject {
+  pub oid: OID,
+  pub kind: ObjectKind,
+  /// Where a tree or blob was found from the root of the tree of a commit,
+  /// which is empty for the root itself. Objects at the same path are
+  /// likely to be good bases for deltas of each other. Commits, tags, and
+  /// blobs given to [`ObjectWalk::push`] have none.
+  pub path: Option<BString>,
+}
+
+/// Walks every object reachable from some objects that isn't reachable from
+/// others, like `git rev-list --objects`, which is what goes in a pack sent
+/// to someone who has the hidden objects. The commits come first in the
+/// order of the [`RevWalk`] underneath, followed by the tags, trees, and
+/// blobs that were pushed, and then the trees and blobs of each commit with
+/// their paths.
+///
+/// Like git, only the trees of the hidden commits at the edge of what's
+/// walked are left out along with everything in them, not the trees of
+/// every hidden commit, since that is what the other side is most likely
+/// to have without walking all of history.
+#[derive(Debug)]
+pub struct ObjectWalk<'a> {
+  odb: &'a Odb,
+  walk: RevWalk<'a>,
+  filter: Option<ObjectFilter>,
+  /// Objects that were given or are known to be left out
+  seen: HashSet<OID>,
+  /// Tags, trees, and blobs that were pushed
+  pending: Vec<OID>,
+  /// Trees and blobs that were hidden
+  hidden: Vec<OID>,
+  /// Commits that were hidden
+  hidden_commits: Vec<OID>,
+  /// The commits, until they're walked
+  commits: Option<VecDeque<WalkedObject>>,
+  /// The trees of the commits left to walk
+  trees: VecDeque<OID>,
+  /// The entries left in each tree being walked, with the paths of the
+  /// trees
+  stack: Vec<(BString, std::vec::IntoIter<TreeEntry>)>,
+}
+
+impl<'a> ObjectWalk<'a> {
+  /// Create an [`ObjectWalk`] over the objects in `odb` that walks commits
+  /// with `walk`, which can have commits pushed and hidden already
+  pub fn new(odb: &'a Odb, walk: RevWalk<'a>) -> Self {
+    Self {
+      odb,
+      walk,
+      filter: None,
+      seen: HashSet::new(),
+      pending: Vec::new(),
+      hidden: Vec::new(),
+      hidden_commits: Vec::new(),
+      commits: None,
+      trees: VecDeque::new(),
+      stack: Vec::new(),
+    }
+  }
+
+  /// Leave out the objects a filter leaves out. Blobs given to
+  /// [`ObjectWalk::push`] are always walked.
+  pub fn filter_objects(&mut self, filter: ObjectFilter) -> &mut Self {
+    self.filter = Some(filter);
+    self
+  }
+
+  /// Walk `oid` and every object reachable from it. Tags are given along
+  /// with what they point at.
+  pub fn push(&mut self, oid: &OID) -> Result<&mut Self, RevWalkError> {
+    let mut oid = *oid;
+    loop {
+      match self.odb.object_kind(&oid)? {
+        ObjectKind::Commit => {
+          self.walk.push(&oid)?;
+          return Ok(self);
+        }
+        ObjectKind::Tag => {
+          self.pending.push(oid);
+          oid = *self.odb.read_tag(&oid)?.object();
+        }
+        _ => {
+          self.pending.push(oid);
+          return Ok(self);
+        }
+      }
+    }
+  }
+
+  /// Leave out `oid` and, for tags, trees, and blobs, everything reachable
+  /// from it. See [`ObjectWalk`] for which objects of hidden commits are
+  /// left out.
+  pub fn hide(&mut self, oid: &OID) -> Result<&mut Self, RevWalkError> {
+    let mut oid = *oid;
+    loop {
+      self.seen.insert(oid);
+      let object = self.odb.read(&oid)?;
+      match object.kind {
+        ObjectKind::Commit => {
+          self.walk.hide(&oid)?;
+          self.hidden_commits.push(oid);
+          return Ok(self);
+        }
+        ObjectKind::Tag => oid = *Tag::parse(object.data)?.object(),
+        _ => {
+          self.hidden.push(oid);
+          return Ok(self);
+        }
+      }
+    }
+  }
+
+  /// Walk every commit up front, which is needed to know which hidden
+  /// commits are at the edge of what's walked, and leave out the trees of
+  /// those
+  fn walk_commits(&mut self) -> Result<(), RevWalkError> {
+    let oids = self.walk.by_ref().collect::<Result<Vec<_>, _>>()?;
+    let walked: HashSet<OID> = oids.iter().copied().collect();
+    let mut commits = VecDeque::with_capacity(oids.len());
+    let mut edge: Vec<OID> = self.hidden_commits.clone();
+    for oid in oids {
+      let commit = self.odb.read_commit(&oid)?;
+      // Shallow commits have no parents as far as the walk goes
+      let parents = self.walk.parents(&oid);
+      edge.extend(parents.iter().filter(|parent| !walked.contains(parent)));
+      self.trees.push_back(*commit.tree());
+      commits.push_back(WalkedObject {
+        oid,
+        kind: ObjectKind::Commit,
+        path: None,
+      });
+      self.seen.insert(oid);
+    }
+    let mut hidden = std::mem::take(&mut self.hidden);
+    for oid in edge {
+      match self.odb.read_commit(&oid) {
+        Ok(commit) => hidden.push(*commit.tree()),
+        // What isn't here can't be sent anyway
+        Err(OdbError::NotFound(_)) => {}
+        Err(e) => return Err(e.into()),
+      }
+    }
+    self.hide_trees(hidden)?;
+    self.commits = Some(commits);
+    Ok(())
+  }
+
+  /// Mark every tree and blob reachable from `oids` as seen
+  fn hide_trees(&mut self, oids: Vec<OID>) -> Result<(), RevWalkError> {
+    let mut trees = Vec::new();
+    for oid in oids {
+      self.seen.insert(oid);
+      match self.odb.object_kind(&oid) {
+        Ok(ObjectKind::Tree) => trees.push(oid),
+        Ok(_) | Err(OdbError::NotFound(_)) => {}
+        Err(e) => return Err(e.into()),
+      }
+    }
+    let mut visited = HashSet::new();
+    while let Some(oid) = trees.pop() {
+      if !visited.insert(oid) {
+        continue;
+      }
+      self.seen.insert(oid);
+      for entry in self.odb.read_tree(&oid)?.entries() {
+        match entry.mode() {
+          FileMode::Tree => trees.push(*entry.oid()),
+          FileMode::GitLink => {}
+          _ => {
+            self.seen.insert(*entry.oid());
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// The next object of the trees of the commits, going through each tree
+  /// depth first in the order of its entries like git
+  fn next_in_trees(&mut self) -> Result<Option<WalkedObject>, RevWalkError> {
+    loop {
+      if self.odb.progress().is_cancelled() {
+        return Err(OdbError::Cancelled.into());
+      }
+      let (prefix, entries) = match self.stack.last_mut() {
+        Some(top) => top,
+        None => match self.trees.pop_front() {
+          Some(tree) => {
+            if let Some(object) = self.enter(tree, BString::from(""))? {
+              return Ok(Some(object));
+            }
+            continue;
+          }
+          None => return Ok(None),
+        },
+      };
+      let entry = match entries.next() {
+        Some(entry) => entry,
+        None => {
+          self.stack.pop();
+          continue;
+        }
+      };
+      let mut path = prefix.clone();
+      if !path.is_empty() {
+        path.push(b'/');
+      }
+      path.extend_from_slice(entry.name());
+      let oid = *entry.oid();
+      match entry.mode() {
+        FileMode::Tree => {
+          if let Some(object) = self.enter(oid, path)? {
+            return Ok(Some(object));
+          }
+        }
+        // Submodule commits are in another repository
+        FileMode::GitLink => {}
+        _ => {
+          if self.seen.contains(&oid) {
+            continue;
+          }
+          let odb = self.odb;
+          let included = match &self.filter {
+            Some(filter) => filter.includes_blob(|| Ok(odb.object_size(&oid)? as u64))?,
+            None => true,
+          };
+          if included {
+            self.seen.insert(oid);
+            return Ok(Some(WalkedObject {
+              oid,
+              kind: ObjectKind::Blob,
+              path: Some(path),
+            }));
+          }
+        }
+      }
+    }
+  }
+
+  /// Start walking a tree if it hasn't been seen yet, giving it
+  fn enter(&mut self, oid: OID, path: BString) -> Result<Option<WalkedObject>, RevWalkError> {
+    if !self.seen.insert(oid) {
+      return Ok(None);
+    }
+    let entries = self.odb.read_tree(&oid)?.entries().to_vec();
+    self.stack.push((path.clone(), entries.into_iter()));
+    Ok(Some(WalkedObject {
+      oid,
+      kind: ObjectKind::Tree,
+      path: Some(path),
+    }))
+  }
+
+  fn next_object(&mut self) -> Result<Option<WalkedObject>, RevWalkError> {
+    if self.commits.is_none() {
+      self.walk_commits()?;
+      // The pushed objects are walked before the trees of the commits
+      let mut trees = VecDeque::new();
+      for oid in std::mem::take(&mut self.pending) {
+        if self.seen.contains(&oid) {
+          continue;
+        }
+        match self.odb.object_kind(&oid)? {
+          ObjectKind::Tree => trees.push_back(oid),
+          kind => {
+            self.seen.insert(oid);
+            self.commits.as_mut().unwrap().push_back(WalkedObject {
+              oid,
+              kind,
+              path: None,
+            });
+          }
+        }
+      }
+      trees.extend(self.trees.drain(..));
+      self.trees = trees;
+    }
+    if let Some(object) = self.commits.as_mut().unwrap().pop_front() {
+      return Ok(Some(object));
+    }
+    self.next_in_trees()
+  }
+}
+
+impl Iterator for ObjectWalk<'_> {
+  type Item = Result<WalkedObject, RevWalkError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    match self.next_object() {
+      Ok(object) => object.map(Ok),
+      Err(e) => {
+        // Nothing more is given after an error
+        self.commits = Some(VecDeque::new());
+        self.trees.clear();
+        self.stack.clear();
+        Some(Err(e))
+      }
+    }
+  }
+}
+
+impl Repository {
+  /// Create an [`ObjectWalk`] over the objects of the repository, walking
+  /// commits with [`Repository::rev_walk`]
+  pub fn object_walk(&self) -> ObjectWalk<'_> {
+    ObjectWalk::new(self.odb(), self.rev_walk())
+  }
+}
+
+#[test]
+fn object_walk() {
+  use crate::{diff::write_tree, Commit, FileMode::NonExecutableFile, Signature, Time};
+  let tmp_dir = tempdir::TempDir::new("object_walk_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let odb = repo.odb();
+  let big = "x".repeat(2000);
+  let mut parents = Vec::new();
+  for (i, files) in [
+    vec![
+      ("a.txt", NonExecutableFile, "a\n"),
+      ("big.bin", NonExecutableFile, &big),
+      ("dir/b.txt", NonExecutableFile, "b\n"),
+    ],
+    vec![
+      ("a.txt", NonExecutableFile, "a2\n"),
+      ("big.bin", NonExecutableFile, &big),
+      ("dir/b.txt", NonExecutableFile, "b\n"),
+      ("dir/c.txt", NonExecutableFile, "c\n"),
+    ],
+  ]
+  .iter()
+  .enumerate()
+  {
+    let time = Time::new(1_600_000_000 + i as i64, 0);
+    let signature = Signature::new("A U Thor", "author@example.com", time);
+    let tree = write_tree(odb, files);
+    let commit = Commit::new(
+      tree,
+      parents.clone(),
+      signature.clone(),
+      signature,
+      "commit\n",
+    );
+    parents = vec![odb.write_commit(&commit).unwrap()];
+  }
+  let second = parents[0];
+  let first = odb.read_commit(&second).unwrap().parents()[0];
+  let signature = Signature::new("A U Thor", "author@example.com", Time::new(0, 0));
+  let tag = odb
+    .write_tag(&Tag::new(
+      second,
+      ObjectKind::Commit,
+      "v1.0",
+      signature,
+      "v1.0\n",
+    ))
+    .unwrap();
+
+  let list = |push: &[OID], hide: &[OID], filter: Option<&str>| -> String {
+    let mut walk = repo.object_walk();
+    for oid in push {
+      walk.push(oid).unwrap();
+    }
+    for oid in hide {
+      walk.hide(oid).unwrap();
+    }
+    if let Some(filter) = filter {
+      walk.filter_objects(filter.parse().unwrap());
+    }
+    walk
+      .map(|object| {
+        let object = object.unwrap();
+        match (object.kind, object.path) {
+          // Git names tags by their names
+          (ObjectKind::Tag, _) => format!("{} v1.0\n", object.oid),
+          (_, Some(path)) => format!("{} {}\n", object.oid, path),
+          (_, None) => format!("{}\n", object.oid),
+        }
+      })
+      .collect()
+  };
+  let everything = list(&[tag], &[], None);
+  assert_eq!(1 + 2 + 2 * 2 + 5, everything.lines().count());
+  // Only what changed since the first commit
+  let changed = list(&[second], &[first], None);
+  assert_eq!(5, changed.lines().count());
+  assert!(!changed.contains("big.bin") && !changed.contains("b.txt"));
+  let small = list(&[second], &[], Some("blob:limit=1k"));
+  assert!(!small.contains("big.bin") && small.contains("a.txt"));
+  let no_blobs = list(&[second], &[], Some("blob:none"));
+  assert_eq!(2 + 2 * 2, no_blobs.lines().count());
+  // Blobs that are pushed are never filtered
+  let blob = *odb
+    .read_tree(odb.read_commit(&first).unwrap().tree())
+    .unwrap()
+    .entries()[1]
+    .oid();
+  assert_eq!(format!("{}\n", blob), list(&[blob], &[], Some("blob:none")));
+
+  assert_eq!(
+    Ok(ObjectFilter::BlobLimit(1 << 20)),
+    "blob:limit=1m".parse().map_err(|_| ())
+  );
+  assert_eq!("blob:limit=1024", ObjectFilter::BlobLimit(1024).to_string());
+  assert!("tree:0".parse::<ObjectFilter>().is_err());
+
+  // Git lists the same objects in the same order
+  if crate::transport::http::have_git() {
+    let git = |args: &[&str]| {
+      let output = std::process::Command::new("git")
+        .arg("rev-list")
+        .arg("--objects")
+        .args(args)
+        .current_dir(tmp_dir.path())
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .output()
+        .unwrap();
+      assert!(output.status.success());
+      String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(git(&[&tag.to_string()]), everything);
+    assert_eq!(git(&[&format!("{}..{}", first, second)]), changed);
+    assert_eq!(git(&["--filter=blob:limit=1k", &second.to_string()]), small);
+    assert_eq!(git(&["--filter=blob:none", &second.to_string()]), no_blobs);
+  }
+}
@@ -0,0 +1,1100 @@
+use crate::{
+  alternates,
+  bitmap::BitmapIndex,
+  cleanup,
+  index_pack::{self, IndexOptions},
+  mmap::Mmap,
+  object,
+  object_cache::ObjectCache,
+  pack::PackSet,
+  progress::Reporter,
+  promisor::LazyFetch,
+  zlib::{self, ZlibError},
+  Blob, CancellationToken, Commit, CommitError, IndexPackOutcome, MemoryBudget, MemoryError,
+  MultiPackIndexError, Object, ObjectCacheLimits, ObjectCacheStats, PackError, PackLimits,
+  Progress, Promisor, PromisorError, Tag, TagError, Trace2, Tree, TreeError, OID,
+};
+use bstr::ByteSlice;
+use std::{
+  collections::{HashMap, HashSet},
+  convert::TryFrom,
+  fmt, fs,
+  io::{self, Read, Write},
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+use thiserror::Error;
+
+/// The kinds of objects that can be stored in the Object Database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectKind {
+  /// A [`Commit`]
+  Commit,
+  /// A [`Tree`]
+  Tree,
+  /// A [`Blob`]
+  Blob,
+  /// A [`Tag`]
+  Tag,
+}
+
+impl ObjectKind {
+  /// The name of the kind as used in object headers
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Commit => "commit",
+      Self::Tree => "tree",
+      Self::Blob => "blob",
+      Self::Tag => "tag",
+    }
+  }
+
+  /// Parse the name of a kind as used in object headers
+  pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+    match bytes {
+      b"commit" => Some(Self::Commit),
+      b"tree" => Some(Self::Tree),
+      b"blob" => Some(Self::Blob),
+      b"tag" => Some(Self::Tag),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for ObjectKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+/// An object read from the [`Odb`] before it has been parsed into a
+/// [`Blob`], [`Tree`], or [`Commit`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawObject {
+  /// What kind of object this is
+  pub kind: ObjectKind,
+  /// The contents of the object without the `{kind} {content_len}\0` header
+  pub data: Vec<u8>,
+}
+
+impl RawObject {
+  /// Create a new [`RawObject`]
+  pub fn new(kind: ObjectKind, data: impl Into<Vec<u8>>) -> Self {
+    Self {
+      kind,
+      data: data.into(),
+    }
+  }
+
+  /// The on disk representation of the object before compression, in the
+  /// form `{kind} {content_len}\0{content}`
+  pub fn as_bytes(&self) -> Vec<u8> {
+    object::encode(self)
+  }
+
+  /// Get the [`OID`] for the object
+  pub fn id(&self) -> OID {
+    OID::for_object(self)
+  }
+}
+
+impl Object for RawObject {
+  fn kind(&self) -> ObjectKind {
+    self.kind
+  }
+
+  fn serialize_into(&self, writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(&self.data)
+  }
+}
+
+/// The Object Database, the `.git/objects` directory where every [`Blob`],
+/// [`Tree`], and [`Commit`] of a repository is stored.
+///
+/// Loose objects are stored zlib compressed at `objects/{first two hex
+/// characters of the OID}/{remaining 38 hex characters}`. Packed objects
+/// are read from the pack files in `objects/pack`, with the `mmap` feature
+/// through memory mapped windows limited by [`PackLimits`].
+///
+/// The [`Odb`] of a partial clone has a [`Promisor`] to fetch the objects
+/// left out when they're read.
+///
+/// Objects that aren't in the objects directory are looked for in its
+/// alternates, the object directories listed in `info/alternates`, but
+/// they're only ever written to the objects directory itself.
+///
+/// The objects read last are kept in memory as [`ObjectCacheLimits`] allow,
+/// shared with the clones of the [`Odb`].
+///
+/// With a [`Progress`], storing and writing packs and checking out trees
+/// report how far along they are to it, and they stop along with walks if
+/// it or the [`CancellationToken`] of the [`Odb`] is cancelled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Odb {
+  path: PathBuf,
+  budget: MemoryBudget,
+  packs: Arc<PackSet>,
+  cache: Arc<ObjectCache>,
+  lazy: LazyFetch,
+  progress: Reporter,
+  alternates: Vec<Odb>,
+  replacements: Arc<HashMap<OID, OID>>,
+}
+
+/// How many replacements of replacements are followed, the same as git
+const MAX_REPLACE_DEPTH: usize = 5;
+/// How big a loose object has to be to be mapped into memory instead of
+/// read, small ones are quicker to read than to map
+const MAP_LOOSE: u64 = 64 << 10;
+
+impl Odb {
+  /// Create an [`Odb`] for the given objects directory that uses the
+  /// [`MemoryBudget::global`] budget, along with the alternates listed in
+  /// its `info/alternates`
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    let path = path.into();
+    let odb = Self::without_alternates(path, PackLimits::default(), MemoryBudget::global().clone());
+    let path = odb.path.clone();
+    odb.with_alternates_from(&path, alternates::listed(&path))
+  }
+
+  fn without_alternates(path: PathBuf, limits: PackLimits, budget: MemoryBudget) -> Self {
+    Self {
+      packs: Arc::new(PackSet::new(path.join("pack"), limits)),
+      cache: Arc::new(ObjectCache::new(ObjectCacheLimits::default())),
+      path,
+      budget,
+      lazy: LazyFetch::default(),
+      progress: Reporter::default(),
+      alternates: Vec::new(),
+      replacements: Arc::default(),
+    }
+  }
+
+  /// Also look for objects in the object directories `dirs` and their
+  /// alternates, like `GIT_ALTERNATE_OBJECT_DIRECTORIES` does. Ones that
+  /// aren't there or are already alternates are skipped.
+  pub fn with_alternates(self, dirs: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+    let dirs = dirs.into_iter().map(Into::into).collect();
+    self.with_alternates_from(Path::new(""), dirs)
+  }
+
+  fn with_alternates_from(mut self, base: &Path, dirs: Vec<PathBuf>) -> Self {
+    let mut found: Vec<PathBuf> = self.alternates.iter().map(|odb| odb.path.clone()).collect();
+    let known = found.len();
+    alternates::resolve(&self.path, base, dirs, &mut found);
+    for dir in found.drain(known..) {
+      let odb = Self::without_alternates(dir, self.packs.limits(), self.budget.clone());
+      self.alternates.push(odb);
+    }
+    self
+  }
+
+  /// Use `limits` for how much of the pack files are mapped into memory at
+  /// once instead of the defaults
+  pub fn with_pack_limits(mut self, limits: PackLimits) -> Self {
+    self.packs = Arc::new(PackSet::new(self.path.join("pack"), limits));
+    for alternate in &mut self.alternates {
+      alternate.packs = Arc::new(PackSet::new(alternate.path.join("pack"), limits));
+    }
+    self
+  }
+
+  /// Use `limits` for how much of the objects read is kept in memory
+  /// instead of the defaults. What was kept so far is forgotten.
+  pub fn with_object_cache(mut self, limits: ObjectCacheLimits) -> Self {
+    self.cache = Arc::new(ObjectCache::new(limits));
+    self
+  }
+
+  /// How many objects were read from memory instead of the objects
+  /// directory, and how much is kept
+  pub fn object_cache_stats(&self) -> ObjectCacheStats {
+    self.cache.stats()
+  }
+
+  /// Use `budget` to limit the memory used while reading objects. Reading
+  /// an object fails with [`OdbError::Memory`] if it and its compressed
+  /// form don't fit in what is left of the budget.
+  pub fn with_budget(mut self, budget: MemoryBudget) -> Self {
+    for alternate in &mut self.alternates {
+      alternate.budget = budget.clone();
+    }
+    self.budget = budget;
+    self
+  }
+
+  /// Fetch promised objects that aren't there with `promisor` when they're
+  /// read, which makes this the [`Odb`] of a partial clone
+  pub fn with_promisor(mut self, promisor: impl Promisor + 'static) -> Self {
+    self.lazy = LazyFetch::new(promisor);
+    self
+  }
+
+  /// Report to `progress` how far along storing and writing packs and
+  /// checking out trees are, and stop them when it's cancelled
+  pub fn with_progress(self, progress: impl Progress + 'static) -> Self {
+    self.with_shared_progress(Arc::new(progress))
+  }
+
+  pub(crate) fn with_shared_progress(mut self, progress: Arc<dyn Progress>) -> Self {
+    self.progress.set_progress(progress);
+    self
+  }
+
+  /// Stop walks, storing and writing packs, and checking out trees with
+  /// [`OdbError::Cancelled`] once `token` is cancelled
+  pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+    self.progress.set_token(token);
+    self
+  }
+
+  pub(crate) fn progress(&self) -> &Reporter {
+    &self.progress
+  }
+
+  /// Read the object a replacement is for instead of the original, with
+  /// the replacements by the [`OID`] of the original like the replace refs
+  /// of a [`crate::Repository`] ask for. The object is still read by the
+  /// [`OID`] of the original and replacements of replacements are followed.
+  pub fn with_replacements(mut self, replacements: HashMap<OID, OID>) -> Self {
+    self.replacements = Arc::new(replacements);
+    self
+  }
+
+  /// Read every object as it's stored, without replacing any
+  pub fn without_replacements(self) -> Self {
+    self.with_replacements(HashMap::new())
+  }
+
+  /// The objects read instead of others, by the [`OID`] of the original
+  pub fn replacements(&self) -> &HashMap<OID, OID> {
+    &self.replacements
+  }
+
+  /// The object read for `oid`, which is `oid` itself unless it's replaced
+  fn replaced(&self, oid: &OID) -> Result<OID, OdbError> {
+    let mut replaced = *oid;
+    let mut depth = 0;
+    while let Some(replacement) = self.replacements.get(&replaced) {
+      if depth == MAX_REPLACE_DEPTH {
+        return Err(OdbError::ReplaceDepth(*oid));
+      }
+      replaced = *replacement;
+      depth += 1;
+    }
+    Ok(replaced)
+  }
+
+  /// The [`MemoryBudget`] used while reading objects
+  pub fn budget(&self) -> &MemoryBudget {
+    &self.budget
+  }
+
+  /// The [`ObjectCacheLimits`] objects that were read are kept with
+  pub fn object_cache_limits(&self) -> ObjectCacheLimits {
+    self.cache.limits()
+  }
+
+  /// The [`PackLimits`] packs are read with
+  pub fn pack_limits(&self) -> PackLimits {
+    self.packs.limits()
+  }
+
+  /// The objects directory
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// The alternates objects are also looked for in, including their own
+  /// alternates, in the order they're looked in
+  pub fn alternates(&self) -> &[Odb] {
+    &self.alternates
+  }
+
+  /// The [`OID`] of every object stored in the objects directory, loose or
+  /// packed, in sorted order. What's only in the alternates is left out.
+  pub fn oids(&self) -> Result<Vec<OID>, OdbError> {
+    let mut oids = self.packs.oids()?;
+    self.loose_oids(&mut oids)?;
+    oids.sort();
+    oids.dedup();
+    Ok(oids)
+  }
+
+  fn loose_oids(&self, oids: &mut Vec<OID>) -> Result<(), OdbError> {
+    let dirs = match fs::read_dir(&self.path) {
+      Ok(dirs) => dirs,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(e.into()),
+    };
+    for dir in dirs {
+      let dir = dir?;
+      let prefix = dir.file_name();
+      let prefix = match prefix.to_str() {
+        Some(prefix) if prefix.len() == 2 && dir.file_type()?.is_dir() => prefix,
+        _ => continue,
+      };
+      for entry in fs::read_dir(dir.path())? {
+        let name = entry?.file_name();
+        // Temporary files and anything else that isn't an object is skipped
+        if let Some(oid) = name
+          .to_str()
+          .filter(|name| name.len() == 38)
+          .and_then(|name| OID::from_hex(&[prefix, name].concat()).ok())
+        {
+          oids.push(oid);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn loose_path(&self, oid: &OID) -> PathBuf {
+    let hex = oid.as_hex();
+    self.path.join(&hex[..2]).join(&hex[2..])
+  }
+
+  /// Read an object without parsing it, or the one replacing it. A
+  /// promised object that isn't there is fetched first.
+  pub fn read(&self, oid: &OID) -> Result<RawObject, OdbError> {
+    let _timer = Trace2::timer("odb", "read_object");
+    let oid = &self.replaced(oid)?;
+    if let Some(object) = self.cache.get(oid) {
+      return Ok(object);
+    }
+    let mut object = self.read_stored(oid)?;
+    if object.is_none() && self.lazy.fetch(self, &[*oid])? {
+      object = self.read_stored(oid)?;
+    }
+    let object = object.ok_or(OdbError::NotFound(*oid))?;
+    self.cache.insert(*oid, &object);
+    Ok(object)
+  }
+
+  /// Whether the object is stored in the [`Odb`], loose or packed, without
+  /// reading it. Promised objects that haven't been fetched aren't there.
+  pub fn contains(&self, oid: &OID) -> Result<bool, OdbError> {
+    if self.loose_path(oid).is_file() || self.packs.contains(oid)? {
+      return Ok(true);
+    }
+    for alternate in &self.alternates {
+      if alternate.contains(oid)? {
+        return Ok(true);
+      }
+    }
+    Ok(false)
+  }
+
+  /// The [`ObjectKind`] of an object, read from the header of a loose
+  /// object or the pack index and entry headers without inflating the
+  /// object, like `git cat-file -t`. A promised object that isn't there is
+  /// fetched first.
+  pub fn object_kind(&self, oid: &OID) -> Result<ObjectKind, OdbError> {
+    Ok(self.read_header(oid)?.0)
+  }
+
+  /// The size of an object in bytes, read the same way as
+  /// [`Odb::object_kind`], like `git cat-file -s`. For a delta in a pack
+  /// only the start of the delta is inflated.
+  pub fn object_size(&self, oid: &OID) -> Result<usize, OdbError> {
+    Ok(self.read_header(oid)?.1)
+  }
+
+  fn read_header(&self, oid: &OID) -> Result<(ObjectKind, usize), OdbError> {
+    let oid = &self.replaced(oid)?;
+    if let Some(header) = self.read_stored_header(oid)? {
+      return Ok(header);
+    }
+    if self.lazy.fetch(self, &[*oid])? {
+      if let Some(header) = self.read_stored_header(oid)? {
+        return Ok(header);
+      }
+    }
+    Err(OdbError::NotFound(*oid))
+  }
+
+  fn read_stored_header(&self, oid: &OID) -> Result<Option<(ObjectKind, usize)>, OdbError> {
+    let file = match fs::File::open(self.loose_path(oid)) {
+      Ok(file) => file,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        if let Some(header) = self.packs.header(oid)? {
+          return Ok(Some(header));
+        }
+        for alternate in &self.alternates {
+          if let Some(header) = alternate.read_stored_header(oid)? {
+            return Ok(Some(header));
+          }
+        }
+        return Ok(None);
+      }
+      Err(e) => return Err(e.into()),
+    };
+    // The header is at most a kind, 20 digits, a space, and the NUL, which
+    // comes out of a few hundred compressed bytes
+    let mut compressed = Vec::with_capacity(512);
+    file.take(512).read_to_end(&mut compressed)?;
+    let bytes = zlib::decompress_prefix(&compressed, 32)?;
+    let (kind, size, _) = parse_header(oid, &bytes)?;
+    Ok(Some((kind, size)))
+  }
+
+  pub(crate) fn read_stored(&self, oid: &OID) -> Result<Option<RawObject>, OdbError> {
+    if let Some(object) = self.read_loose(oid)? {
+      return Ok(Some(object));
+    }
+    if let Some(object) = self.packs.read(oid, &self.budget)? {
+      return Ok(Some(object));
+    }
+    for alternate in &self.alternates {
+      if let Some(object) = alternate.read_stored(oid)? {
+        return Ok(Some(object));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Fetch the promised objects of `oids` that aren't there in one go,
+  /// like before checking out a tree of a partial clone, instead of one
+  /// at a time as they're read
+  pub fn prefetch(&self, oids: &[OID]) -> Result<(), OdbError> {
+    let mut missing = Vec::new();
+    for oid in oids {
+      if self.read_stored(oid)?.is_none() && !missing.contains(oid) {
+        missing.push(*oid);
+      }
+    }
+    if !missing.is_empty() {
+      self.lazy.fetch(self, &missing)?;
+    }
+    Ok(())
+  }
+
+  /// The objects in promisor packs
+  pub(crate) fn promisor_oids(&self) -> Result<Vec<OID>, OdbError> {
+    Ok(self.packs.promisor_oids()?)
+  }
+
+  fn read_loose(&self, oid: &OID) -> Result<Option<RawObject>, OdbError> {
+    let mut file = match fs::File::open(self.loose_path(oid)) {
+      Ok(file) => file,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    if len >= MAP_LOOSE {
+      if let Ok(len) = usize::try_from(len) {
+        let compressed = Mmap::map(&file, 0, len)?;
+        return parse_loose(oid, &compressed, &self.budget).map(Some);
+      }
+    }
+    let mut compressed = Vec::with_capacity(len as usize);
+    file.read_to_end(&mut compressed)?;
+    parse_loose(oid, &compressed, &self.budget).map(Some)
+  }
+
+  fn read_kind(&self, oid: &OID, expected: ObjectKind) -> Result<Vec<u8>, OdbError> {
+    let object = self.read(oid)?;
+    if object.kind != expected {
+      return Err(OdbError::WrongKind {
+        oid: *oid,
+        expected,
+        found: object.kind,
+      });
+    }
+    Ok(object.data)
+  }
+
+  /// Read the [`Blob`] with the given [`OID`]
+  pub fn read_blob(&self, oid: &OID) -> Result<Blob, OdbError> {
+    Ok(Blob::new(self.read_kind(oid, ObjectKind::Blob)?))
+  }
+
+  /// Read the [`Tree`] with the given [`OID`]
+  pub fn read_tree(&self, oid: &OID) -> Result<Tree, OdbError> {
+    Ok(Tree::parse(self.read_kind(oid, ObjectKind::Tree)?)?)
+  }
+
+  /// Read the [`Commit`] with the given [`OID`]
+  pub fn read_commit(&self, oid: &OID) -> Result<Commit, OdbError> {
+    Ok(Commit::parse(self.read_kind(oid, ObjectKind::Commit)?)?)
+  }
+
+  /// Read the [`Tag`] with the given [`OID`]
+  pub fn read_tag(&self, oid: &OID) -> Result<Tag, OdbError> {
+    Ok(Tag::parse(self.read_kind(oid, ObjectKind::Tag)?)?)
+  }
+
+  /// Find the object whose [`OID`] starts with the given hex digits, like
+  /// git does for abbreviated object names. At least 4 digits are needed
+  /// and it's an error if more than one object matches.
+  pub fn find_prefix(&self, prefix: &str) -> Result<OID, OdbError> {
+    let invalid = || OdbError::InvalidPrefix(prefix.into());
+    if prefix.len() < 4 || prefix.len() > 40 || !prefix.bytes().all(|c| c.is_ascii_hexdigit()) {
+      return Err(invalid());
+    }
+    let prefix = prefix.to_ascii_lowercase();
+    if prefix.len() == 40 {
+      return OID::from_hex(&prefix).map_err(|_| invalid());
+    }
+    let mut found = HashSet::new();
+    self.prefix_matches(&prefix, &mut found)?;
+    for alternate in &self.alternates {
+      alternate.prefix_matches(&prefix, &mut found)?;
+    }
+    let mut found = found.into_iter();
+    match (found.next(), found.next()) {
+      (Some(oid), None) => Ok(oid),
+      (Some(_), Some(_)) => Err(OdbError::Ambiguous(prefix)),
+      (None, _) => Err(OdbError::PrefixNotFound(prefix)),
+    }
+  }
+
+  /// Add the objects stored in the objects directory whose hex form starts
+  /// with `prefix`, which is lowercase and at least 2 digits long
+  fn prefix_matches(&self, prefix: &str, found: &mut HashSet<OID>) -> Result<(), OdbError> {
+    found.extend(self.packs.find_prefix(prefix)?);
+    let entries = match fs::read_dir(self.path.join(&prefix[..2])) {
+      Ok(entries) => entries.collect::<Result<Vec<_>, _>>()?,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+      Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+      let name = entry.file_name();
+      let name = match name.to_str() {
+        Some(name) if name.len() == 38 && name.starts_with(&prefix[2..]) => name,
+        _ => continue,
+      };
+      if let Ok(oid) = OID::from_hex(&[&prefix[..2], name].concat()) {
+        found.insert(oid);
+      }
+    }
+    Ok(())
+  }
+
+  /// The shortest start of the hex form of `oid` that is at least `len`
+  /// digits long and doesn't also start the [`OID`] of another object, like
+  /// the abbreviated object names git shows. The object doesn't have to be
+  /// stored. With `None` the length starts at 7 and grows with the number of
+  /// packed objects, the same as git's `core.abbrev=auto`.
+  pub fn abbreviate(&self, oid: &OID, len: Option<usize>) -> Result<String, OdbError> {
+    let len = match len {
+      Some(len) => len,
+      None => {
+        // With around 2^bits objects a collision is expected at 2^(bits/2)
+        // and every hex digit holds 4 bits
+        let bits = (usize::BITS - self.packs.count()?.leading_zeros()) as usize;
+        bits.div_ceil(2).max(7)
+      }
+    };
+    let hex = oid.as_hex();
+    for len in len.clamp(4, 40)..40 {
+      match self.find_prefix(&hex[..len]) {
+        Ok(found) if found == *oid => return Ok(hex[..len].into()),
+        Err(OdbError::PrefixNotFound(_)) => return Ok(hex[..len].into()),
+        Ok(_) | Err(OdbError::Ambiguous(_)) => continue,
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(hex)
+  }
+
+  /// Write an object to the [`Odb`] returning its [`OID`]. Nothing is
+  /// written if the object is already stored, here or in an alternate. The
+  /// object is written to a
+  /// temporary file first and then moved into place so that other readers
+  /// never see a partially written object.
+  pub fn write(&self, object: &RawObject) -> Result<OID, OdbError> {
+    self.write_bytes(&object.as_bytes())
+  }
+
+  fn write_bytes(&self, bytes: &[u8]) -> Result<OID, OdbError> {
+    let oid = OID::hash(bytes);
+    let path = self.loose_path(&oid);
+    if path.exists() {
+      return Ok(oid);
+    }
+    for alternate in &self.alternates {
+      if alternate.contains(&oid)? {
+        return Ok(oid);
+      }
+    }
+    // The path always has a parent since it's inside of the objects dir
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    let tmp_path = dir.join(cleanup::temp_name("obj"));
+    let result = fs::File::create(&tmp_path)
+      .and_then(|mut file| file.write_all(&zlib::compress(bytes)))
+      .and_then(|_| fs::rename(&tmp_path, &path));
+    if let Err(e) = result {
+      let _ = fs::remove_file(&tmp_path);
+      // Another thread or process writing the same object at once can win
+      // the rename, which fails where files can't be renamed over others
+      if !path.exists() {
+        return Err(e.into());
+      }
+    }
+    Ok(oid)
+  }
+
+  /// Store a whole pack, like one received during a fetch, in `pack/`
+  /// along with an index for it, returning the [`OID`]s of the objects in
+  /// it. Every object is checked while indexing, so a pack that's corrupt or
+  /// thin, with deltas against objects outside of it, is never stored. The
+  /// index is moved into place last so other readers only see the pack once
+  /// it's complete.
+  pub fn write_pack(&self, bytes: &[u8]) -> Result<Vec<OID>, OdbError> {
+    Ok(self.index_pack_with(bytes, None, false)?.objects)
+  }
+
+  /// Store a pack fetched with a filter from a promisor remote like
+  /// [`Odb::write_pack`] does, marking it as a promisor pack with a
+  /// `.promisor` file. What its objects point at is promised.
+  pub fn write_promisor_pack(&self, bytes: &[u8]) -> Result<Vec<OID>, OdbError> {
+    let oids = self.index_pack_with(bytes, None, true)?.objects;
+    self.lazy.forget_promised();
+    Ok(oids)
+  }
+
+  /// Store a thin pack, like one pushed with deltas against objects the
+  /// repository already has, like [`Odb::write_pack`] does once the bases
+  /// it needs from `bases` are added to it
+  pub fn write_thin_pack(&self, bytes: &[u8], bases: &Odb) -> Result<Vec<OID>, OdbError> {
+    Ok(self.index_pack_with(bytes, Some(bases), false)?.objects)
+  }
+
+  /// Store the pack read from `pack`, like the stream of one from a fetch
+  /// or a pack file, in `pack/` along with an index for it, like
+  /// `git index-pack --stdin --fix-thin`. The pack is written out as it's
+  /// read, so only the objects along one chain of deltas are ever in
+  /// memory, and the checksum at the end of it is checked. A thin pack has
+  /// the bases of its deltas that aren't in it added from this [`Odb`].
+  pub fn index_pack(&self, pack: impl io::Read) -> Result<IndexPackOutcome, OdbError> {
+    self.index_pack_with(pack, Some(self), false)
+  }
+
+  fn index_pack_with(
+    &self,
+    pack: impl io::Read,
+    bases: Option<&Odb>,
+    promisor: bool,
+  ) -> Result<IndexPackOutcome, OdbError> {
+    let options = IndexOptions {
+      budget: &self.budget,
+      max_depth: self.pack_limits().max_delta_depth,
+      bases,
+      promisor,
+      progress: &self.progress,
+    };
+    index_pack::index_pack(&self.path.join("pack"), pack, &options)
+  }
+
+  /// The bitmap of the first pack that has one
+  pub(crate) fn bitmap(&self) -> Result<Option<Arc<BitmapIndex>>, OdbError> {
+    Ok(self.packs.bitmap()?)
+  }
+
+  /// Write a [`MultiPackIndex`][crate::MultiPackIndex] of every pack to
+  /// `objects/pack/multi-pack-index`, like `git multi-pack-index write`, so
+  /// that finding a packed object takes one lookup instead of one per pack.
+  /// Packs stored after this are looked through on their own until it's
+  /// written again.
+  pub fn write_midx(&self) -> Result<(), MultiPackIndexError> {
+    self.packs.write_midx()
+  }
+
+  /// Write a [`Blob`] to the [`Odb`]
+  pub fn write_blob(&self, blob: &Blob) -> Result<OID, OdbError> {
+    self.write_bytes(&blob.as_bytes())
+  }
+
+  /// Write a [`Tree`] to the [`Odb`]
+  pub fn write_tree(&self, tree: &Tree) -> Result<OID, OdbError> {
+    self.write_bytes(&tree.as_bytes())
+  }
+
+  /// Write a [`Commit`] to the [`Odb`]
+  pub fn write_commit(&self, commit: &Commit) -> Result<OID, OdbError> {
+    self.write_bytes(&commit.as_bytes())
+  }
+
+  /// Write a [`Tag`] to the [`Odb`]
+  pub fn write_tag(&self, tag: &Tag) -> Result<OID, OdbError> {
+    self.write_bytes(&tag.as_bytes())
+  }
+}
+
+/// The async versions of what waits on files the longest, for code running
+/// on tokio. Files are read and written with tokio, and what would block
+/// otherwise, like reading packs and resolving their deltas, runs on
+/// tokio's blocking threads.
+#[cfg(feature = "async")]
+impl Odb {
+  /// [`Odb::read`] without blocking on reading the object. A loose object
+  /// is read with tokio, anything else is read from the packs, the
+  /// alternates, or fetched with the [`Promisor`] on a blocking thread.
+  pub async fn read_async(&self, oid: &OID) -> Result<RawObject, OdbError> {
+    let oid = self.replaced(oid)?;
+    if let Some(object) = self.cache.get(&oid) {
+      return Ok(object);
+    }
+    let object = match tokio::fs::read(self.loose_path(&oid)).await {
+      Ok(compressed) => parse_loose(&oid, &compressed, &self.budget)?,
+      // Whatever isn't loose is in a pack or an alternate
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        let odb = self.clone();
+        blocking(move || {
+          let mut object = odb.read_stored(&oid)?;
+          if object.is_none() && odb.lazy.fetch(&odb, &[oid])? {
+            object = odb.read_stored(&oid)?;
+          }
+          object.ok_or(OdbError::NotFound(oid))
+        })
+        .await?
+      }
+      Err(e) => return Err(e.into()),
+    };
+    self.cache.insert(oid, &object);
+    Ok(object)
+  }
+
+  /// [`Odb::index_pack`] for a pack coming from an async stream. The pack
+  /// is written to a temporary file as it comes in, and stored from there
+  /// on a blocking thread once it's all there.
+  pub async fn index_pack_async(
+    &self,
+    mut pack: impl tokio::io::AsyncRead + Unpin,
+  ) -> Result<IndexPackOutcome, OdbError> {
+    use tokio::io::AsyncWriteExt;
+    tokio::fs::create_dir_all(&self.path).await?;
+    let path = self.path.join(cleanup::temp_name("pack"));
+    let received = async {
+      let mut file = tokio::fs::File::create(&path).await?;
+      tokio::io::copy(&mut pack, &mut file).await?;
+      file.flush().await
+    };
+    let result = match received.await {
+      Ok(()) => {
+        let (odb, path) = (self.clone(), path.clone());
+        blocking(move || odb.index_pack(io::BufReader::new(fs::File::open(path)?))).await
+      }
+      Err(e) => Err(e.into()),
+    };
+    let _ = tokio::fs::remove_file(&path).await;
+    result
+  }
+}
+
+/// Run `f` on one of tokio's blocking threads
+#[cfg(feature = "async")]
+async fn blocking<T: Send + 'static>(
+  f: impl FnOnce() -> Result<T, OdbError> + Send + 'static,
+) -> Result<T, OdbError> {
+  tokio::task::spawn_blocking(f)
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Inflate and parse the contents of the loose object file for `oid`
+pub(crate) fn parse_loose(
+  oid: &OID,
+  compressed: &[u8],
+  budget: &MemoryBudget,
+) -> Result<RawObject, OdbError> {
+  let _compressed = budget.try_reserve(compressed.len())?;
+  let limit = budget.available();
+  let (bytes, _) = match zlib::decompress_with_limit(compressed, limit) {
+    Ok(inflated) => inflated,
+    // Inflating stops as soon as the budget runs out so the real size
+    // isn't known, only that it's more than what is left
+    Err(ZlibError::TooLarge(_)) => {
+      return Err(
+        MemoryError::LimitExceeded {
+          requested: limit.saturating_add(1),
+          available: limit,
+        }
+        .into(),
+      )
+    }
+    Err(e) => return Err(e.into()),
+  };
+  let _inflated = budget.try_reserve(bytes.len())?;
+  let (kind, size, data) = parse_header(oid, &bytes)?;
+  let data = &bytes[data..];
+  if data.len() != size {
+    return Err(OdbError::Corrupt(
+      *oid,
+      "size in header does not match the contents",
+    ));
+  }
+  Ok(RawObject::new(kind, data))
+}
+
+/// Parse the `{kind} {size}\0` header of an inflated loose object,
+/// returning the kind, the size, and where the contents start
+fn parse_header(oid: &OID, bytes: &[u8]) -> Result<(ObjectKind, usize, usize), OdbError> {
+  let corrupt = |reason| OdbError::Corrupt(*oid, reason);
+  let nul = bytes
+    .find_byte(0)
+    .ok_or_else(|| corrupt("missing header"))?;
+  let header = &bytes[..nul];
+  let space = header
+    .find_byte(b' ')
+    .ok_or_else(|| corrupt("invalid header"))?;
+  let (kind, size) = (&header[..space], &header[space + 1..]);
+  let kind = ObjectKind::from_bytes(kind).ok_or_else(|| corrupt("unknown object kind"))?;
+  let size: usize = size
+    .to_str()
+    .ok()
+    .and_then(|s| s.parse().ok())
+    .ok_or_else(|| corrupt("invalid size in header"))?;
+  Ok((kind, size, nul + 1))
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`Odb`] type
+pub enum OdbError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("object {0} could not be found")]
+  NotFound(OID),
+  #[error("object {0} is corrupt: {1}")]
+  Corrupt(OID, &'static str),
+  #[error("object {oid} is a {found} not a {expected}")]
+  WrongKind {
+    oid: OID,
+    expected: ObjectKind,
+    found: ObjectKind,
+  },
+  #[error("{0}")]
+  Zlib(#[from] ZlibError),
+  #[error("{0}")]
+  Memory(#[from] MemoryError),
+  #[error("{0}")]
+  Pack(#[from] PackError),
+  #[error("{0}")]
+  Tree(#[from] TreeError),
+  #[error("{0}")]
+  Commit(#[from] CommitError),
+  #[error("{0}")]
+  Tag(#[from] TagError),
+  #[error("fetching {0} from the promisor remote failed: {1}")]
+  Promisor(OID, PromisorError),
+  #[error("{0:?} is not a valid abbreviated object name")]
+  InvalidPrefix(String),
+  #[error("no object starts with {0}")]
+  PrefixNotFound(String),
+  #[error("more than one object starts with {0}")]
+  Ambiguous(String),
+  #[error("replace depth too high for object {0}")]
+  ReplaceDepth(OID),
+  #[error("the operation was cancelled")]
+  Cancelled,
+}
+
+#[test]
+fn write_and_read() {
+  let tmp_dir = tempdir::TempDir::new("odb_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let blob = Blob::new("this is a test");
+  let oid = odb.write_blob(&blob).unwrap();
+  assert_eq!(blob.id(), oid);
+  assert!(tmp_dir
+    .path()
+    .join("a8/a940627d132695a9769df883f85992f0ff4a43")
+    .is_file());
+  assert_eq!(blob, odb.read_blob(&oid).unwrap());
+  // Writing it again is a no-op
+  assert_eq!(oid, odb.write_blob(&blob).unwrap());
+  assert_eq!(vec![oid], odb.oids().unwrap());
+
+  let tree = Tree::new(vec![crate::TreeEntry::new(
+    crate::FileMode::NonExecutableFile,
+    "a.txt",
+    oid,
+  )]);
+  let tree_oid = odb.write_tree(&tree).unwrap();
+  assert_eq!(tree, odb.read_tree(&tree_oid).unwrap());
+  assert!(matches!(
+    odb.read_tree(&oid),
+    Err(OdbError::WrongKind {
+      expected: ObjectKind::Tree,
+      found: ObjectKind::Blob,
+      ..
+    })
+  ));
+}
+
+#[test]
+fn not_found() {
+  let tmp_dir = tempdir::TempDir::new("odb_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let oid = Blob::new("missing").id();
+  assert!(matches!(odb.read(&oid), Err(OdbError::NotFound(o)) if o == oid));
+}
+
+#[test]
+fn memory_budget() {
+  let tmp_dir = tempdir::TempDir::new("odb_test").unwrap();
+  let budget = MemoryBudget::new(2048);
+  let odb = Odb::new(tmp_dir.path()).with_budget(budget.clone());
+  let oid = odb.write_blob(&Blob::new(vec![b'a'; 1000])).unwrap();
+  let held = budget.try_reserve(1500).unwrap();
+  assert!(matches!(
+    odb.read(&oid),
+    Err(OdbError::Memory(MemoryError::LimitExceeded { .. }))
+  ));
+  drop(held);
+  assert_eq!(1000, odb.read_blob(&oid).unwrap().size());
+  assert_eq!(0, budget.in_use());
+}
+
+#[test]
+fn corrupt() {
+  let tmp_dir = tempdir::TempDir::new("odb_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let oid = Blob::new("this is a test").id();
+  let path = odb.loose_path(&oid);
+  fs::create_dir_all(path.parent().unwrap()).unwrap();
+  fs::write(&path, zlib::compress(b"blob 3\0this is a test")).unwrap();
+  assert!(matches!(odb.read(&oid), Err(OdbError::Corrupt(..))));
+}
+
+#[test]
+fn find_prefix() {
+  let tmp_dir = tempdir::TempDir::new("odb_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let oid = odb.write_blob(&Blob::new("this is a test")).unwrap();
+  assert_eq!(oid, odb.find_prefix("a8a9").unwrap());
+  assert_eq!(oid, odb.find_prefix("A8A94062").unwrap());
+  assert!(matches!(
+    odb.find_prefix("a8a"),
+    Err(OdbError::InvalidPrefix(_))
+  ));
+  assert!(matches!(
+    odb.find_prefix("ffff"),
+    Err(OdbError::PrefixNotFound(_))
+  ));
+  // Make a second object that starts with a8a9 without finding a hash
+  // collision by copying the first one to a similar name
+  let path = odb.loose_path(&oid);
+  fs::copy(
+    &path,
+    path.with_file_name("a9000000000000000000000000000000000000"),
+  )
+  .unwrap();
+  assert!(matches!(
+    odb.find_prefix("a8a9"),
+    Err(OdbError::Ambiguous(_))
+  ));
+  assert_eq!(oid, odb.find_prefix(&oid.as_hex()[..39]).unwrap());
+}
+
+#[test]
+fn abbreviate() {
+  let tmp_dir = tempdir::TempDir::new("odb_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let oid = odb.write_blob(&Blob::new("this is a test")).unwrap();
+  assert_eq!("a8a9406", odb.abbreviate(&oid, None).unwrap());
+  assert_eq!("a8a9", odb.abbreviate(&oid, Some(2)).unwrap());
+  let missing = OID::from_bytes(&[0; 20]).unwrap();
+  assert_eq!("0000000", odb.abbreviate(&missing, None).unwrap());
+  // Another object sharing the first 9 digits makes the name longer
+  let path = odb.loose_path(&oid);
+  let similar = format!("a940627{}", "0".repeat(31));
+  fs::copy(&path, path.with_file_name(similar)).unwrap();
+  assert_eq!("a8a940627d", odb.abbreviate(&oid, None).unwrap());
+  assert_eq!(oid.as_hex(), odb.abbreviate(&oid, Some(40)).unwrap());
+}
+
+#[test]
+fn write_pack() {
+  use crate::pack;
+  let tmp_dir = tempdir::TempDir::new("odb_test").unwrap();
+  let base = RawObject::new(ObjectKind::Blob, "this is a test");
+  let delta = pack::test_delta(14, 17, (0, 10), b"a delta");
+  let oids = pack::write_test_pack(
+    &tmp_dir.path().join("source"),
+    "test",
+    &[
+      (None, base),
+      (Some(0), RawObject::new(ObjectKind::Blob, delta)),
+    ],
+  );
+  let bytes = fs::read(tmp_dir.path().join("source/pack-test.pack")).unwrap();
+
+  let odb = Odb::new(tmp_dir.path().join("objects"));
+  // Looked up once before the pack is there so it's found by a rescan
+  assert!(matches!(odb.read(&oids[1]), Err(OdbError::NotFound(_))));
+  assert_eq!(oids, odb.write_pack(&bytes).unwrap());
+  assert_eq!(b"this is a test"[..], odb.read(&oids[0]).unwrap().data[..]);
+  assert_eq!(
+    b"this is a a delta"[..],
+    odb.read(&oids[1]).unwrap().data[..]
+  );
+  let mut sorted = oids.clone();
+  sorted.sort();
+  assert_eq!(sorted, odb.oids().unwrap());
+  // Storing the same pack again changes nothing
+  assert_eq!(oids, odb.write_pack(&bytes).unwrap());
+  assert_eq!(2, fs::read_dir(odb.path().join("pack")).unwrap().count());
+
+  // A pack that doesn't check out isn't stored
+  let corrupt = Odb::new(tmp_dir.path().join("corrupt"));
+  let truncated = &bytes[..bytes.len() - 1];
+  assert!(matches!(
+    corrupt.write_pack(truncated),
+    Err(OdbError::Pack(_))
+  ));
+  assert!(!corrupt.path().join("pack").exists());
+}
+
+#[test]
+fn object_headers() {
+  use crate::pack;
+  let tmp_dir = tempdir::TempDir::new("odb_test").unwrap();
+  let base = RawObject::new(ObjectKind::Blob, "this is a test");
+  let delta = pack::test_delta(14, 17, (0, 10), b"a delta");
+  let chained = pack::test_delta(17, 117, (0, 17), &[b'x'; 100]);
+  let objects = pack::write_test_pack(
+    &tmp_dir.path().join("pack"),
+    "test",
+    &[
+      (None, base),
+      (Some(0), RawObject::new(ObjectKind::Blob, delta)),
+      (Some(1), RawObject::new(ObjectKind::Blob, chained)),
+    ],
+  );
+  // Nothing is inflated, so it all works without any memory to spare
+  let odb = Odb::new(tmp_dir.path()).with_budget(MemoryBudget::new(0));
+  let loose = Odb::new(tmp_dir.path())
+    .write_commit(&Commit::new(
+      OID::hash(b""),
+      vec![],
+      crate::Signature::new("A U Thor", "author@example.com", crate::Time::new(0, 0)),
+      crate::Signature::new("A U Thor", "author@example.com", crate::Time::new(0, 0)),
+      "loose\n",
+    ))
+    .unwrap();
+  assert!(odb.read(&objects[2]).is_err());
+  for (oid, kind, size) in [
+    (objects[0], ObjectKind::Blob, 14),
+    (objects[1], ObjectKind::Blob, 17),
+    (objects[2], ObjectKind::Blob, 117),
+  ] {
+    assert!(odb.contains(&oid).unwrap());
+    assert_eq!(kind, odb.object_kind(&oid).unwrap());
+    assert_eq!(size, odb.object_size(&oid).unwrap());
+  }
+  let commit = Odb::new(tmp_dir.path()).read(&loose).unwrap();
+  assert!(odb.contains(&loose).unwrap());
+  assert_eq!(ObjectKind::Commit, odb.object_kind(&loose).unwrap());
+  assert_eq!(commit.data.len(), odb.object_size(&loose).unwrap());
+
+  let missing = OID::hash(b"missing");
+  assert!(!odb.contains(&missing).unwrap());
+  assert!(matches!(
+    odb.object_size(&missing),
+    Err(OdbError::NotFound(_))
+  ));
+}
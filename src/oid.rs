@@ -1,15 +1,20 @@
-use crate::Blob;
+use crate::{object, Object};
 use sha1::{Digest, Sha1};
-use std::convert::TryInto;
+use std::{convert::TryInto, fmt};
 use thiserror::Error;
 
 /// An [`OID`] is the Object Identifier for a given git object which can be a
-/// [`Blob`][crate::Blob], a Tree, or a Commit. This is a Sha1 sum of the object
-/// that can be used to refer to the item in the Object Database.
-#[derive(Debug, PartialEq, Eq)]
+/// [`Blob`][crate::Blob], a Tree, a Commit, a Tag, or any other [`Object`].
+/// This is a Sha1 sum of the object that can be used to refer to the item in
+/// the Object Database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct OID([u8; 20]);
 
 impl OID {
+  /// The all zero [`OID`] git uses for an object that doesn't exist, like
+  /// the old side of an added file
+  pub(crate) const NULL: Self = Self([0; 20]);
+
   /// Get the Sha1 sum in a human readable hex format.
   pub fn as_hex(&self) -> String {
     hex::encode(self.0)
@@ -28,32 +33,39 @@ impl OID {
     // above for the length of 40
     Ok(Self(bytes.try_into().unwrap()))
   }
-}
 
-impl From<Blob> for OID {
-  fn from(blob: Blob) -> Self {
-    let bytes = blob.as_bytes();
-    let mut hasher = Sha1::new();
-    hasher.update(bytes);
-    Self(hasher.finalize().into())
+  /// Make an OID from the raw 20 bytes of a Sha1 sum as stored in Trees and
+  /// pack files. This function will fail if `bytes` is not 20 bytes long.
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, OIDError> {
+    bytes
+      .try_into()
+      .map(Self)
+      .map_err(|_| OIDError::InvalidLength(bytes.len()))
   }
-}
 
-impl From<&Blob> for OID {
-  fn from(blob: &Blob) -> Self {
-    let bytes = blob.as_bytes();
+  /// Get the raw 20 bytes of the Sha1 sum
+  pub fn as_bytes(&self) -> &[u8; 20] {
+    &self.0
+  }
+
+  /// Compute the [`OID`] of an object given its on disk representation
+  /// including the `{kind} {content_len}\0` header
+  pub(crate) fn hash(bytes: impl AsRef<[u8]>) -> Self {
     let mut hasher = Sha1::new();
-    hasher.update(bytes);
+    hasher.update(bytes.as_ref());
     Self(hasher.finalize().into())
   }
+
+  /// Compute the [`OID`] of any [`Object`], the Sha1 sum of its contents
+  /// along with the `{kind} {content_len}\0` header
+  pub fn for_object(object: &impl Object) -> Self {
+    Self::hash(object::encode(object))
+  }
 }
 
-impl From<&mut Blob> for OID {
-  fn from(blob: &mut Blob) -> Self {
-    let bytes = blob.as_bytes();
-    let mut hasher = Sha1::new();
-    hasher.update(bytes);
-    Self(hasher.finalize().into())
+impl fmt::Display for OID {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.as_hex())
   }
 }
 
@@ -62,6 +74,8 @@ impl From<&mut Blob> for OID {
 pub enum OIDError {
   #[error("invalid hex string used as input for OID. Reason was: {0}")]
   InvalidHex(HexErrorKind),
+  #[error("OID was {0} bytes long instead of 20")]
+  InvalidLength(usize),
 }
 
 #[derive(Error, Debug)]
@@ -75,7 +89,7 @@ pub enum HexErrorKind {
 
 #[test]
 fn as_hex() {
-  let oid = OID::from(&Blob::new("this is a test".as_bytes()));
+  let oid = OID::for_object(&crate::Blob::new("this is a test".as_bytes()));
   assert_eq!(&oid.as_hex(), "a8a940627d132695a9769df883f85992f0ff4a43");
 }
 
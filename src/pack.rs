@@ -0,0 +1,1562 @@
+use crate::{
+  bitmap::BitmapIndex,
+  delta::{self, apply_delta, delta_target_size, DeltaChain},
+  endian::{read_u32, read_u64, table_len},
+  midx::{self, IndexedPack},
+  mmap::{self, Mmap},
+  zlib::{self, ZlibError},
+  Config, ConfigError, MemoryBudget, MemoryError, MultiPackIndex, MultiPackIndexError, ObjectKind,
+  RawObject, Reservation, OID,
+};
+use std::{
+  collections::{HashMap, HashSet},
+  convert::TryFrom,
+  fmt, fs, io,
+  ops::Deref,
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex, OnceLock,
+  },
+};
+use thiserror::Error;
+
+const INDEX_SIGNATURE: &[u8; 4] = b"\xfftOc";
+pub(crate) const PACK_SIGNATURE: &[u8; 4] = b"PACK";
+/// Set on 4 byte offsets in a version 2 index that point into the table of
+/// 8 byte offsets instead
+const LARGE_OFFSET: u32 = 0x8000_0000;
+
+/// Limits on how much of the pack files of an [`Odb`][crate::Odb] are
+/// mapped into memory, the same as `core.packedGitWindowSize` and
+/// `core.packedGitLimit` in git. Packs are mapped one window at a time and
+/// the least recently used windows are unmapped to stay under the limit,
+/// so huge packs can be read on 32 bit targets or with little memory.
+/// Without the `mmap` feature nothing is mapped and each entry is read on
+/// its own instead, so only [`PackLimits::max_delta_depth`] matters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackLimits {
+  /// How many bytes of a pack are mapped at once. This is rounded down to
+  /// a multiple of the page size. Defaults to 1 GiB on 64 bit targets and
+  /// 32 MiB on 32 bit ones.
+  pub window_size: usize,
+  /// How many bytes of all packs can be mapped at once. This can be gone
+  /// over if every window is in use. Defaults to 8 GiB on 64 bit targets
+  /// and 256 MiB on 32 bit ones.
+  pub limit: usize,
+  /// How many deltas long a chain of them can be to read an object, or to
+  /// store a pack. Defaults to 10000, well past the 4095 git stops at.
+  pub max_delta_depth: usize,
+}
+
+impl Default for PackLimits {
+  fn default() -> Self {
+    if cfg!(target_pointer_width = "64") {
+      Self {
+        window_size: 1 << 30,
+        limit: (8u64 << 30) as usize,
+        max_delta_depth: delta::MAX_DEPTH,
+      }
+    } else {
+      Self {
+        window_size: 32 << 20,
+        limit: 256 << 20,
+        max_delta_depth: delta::MAX_DEPTH,
+      }
+    }
+  }
+}
+
+impl PackLimits {
+  /// Read the limits from `core.packedGitWindowSize` and
+  /// `core.packedGitLimit`, using the defaults for any not set
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let mut limits = Self::default();
+    if let Some(window_size) = size(config, "core.packedgitwindowsize")? {
+      limits.window_size = window_size;
+    }
+    if let Some(limit) = size(config, "core.packedgitlimit")? {
+      limits.limit = limit;
+    }
+    Ok(limits)
+  }
+}
+
+/// Read the size `key` is set to
+pub(crate) fn size(config: &Config, key: &str) -> Result<Option<usize>, ConfigError> {
+  config
+    .get_int(key)?
+    .map(|value| {
+      usize::try_from(value).map_err(|_| ConfigError::InvalidValue {
+        key: key.into(),
+        value: value.to_string().into(),
+        expected: "a size that fits in memory",
+      })
+    })
+    .transpose()
+}
+
+/// The pack files in `objects/pack` and the windows of them that are
+/// mapped
+pub(crate) struct PackSet {
+  dir: PathBuf,
+  limits: PackLimits,
+  packs: Mutex<Option<Loaded>>,
+  windows: Mutex<Windows>,
+}
+
+/// The packs that were found in the directory
+#[derive(Clone, Default)]
+struct Loaded {
+  packs: Vec<Arc<Pack>>,
+  /// The multi-pack-index and its packs in its order. One whose packs
+  /// aren't all there anymore is left out.
+  midx: Option<(Arc<MultiPackIndex>, Vec<Arc<Pack>>)>,
+  /// The packs that aren't in the multi-pack-index
+  uncovered: Vec<Arc<Pack>>,
+}
+
+#[derive(Default)]
+struct Windows {
+  mapped: usize,
+  tick: u64,
+  list: Vec<Window>,
+}
+
+struct Window {
+  pack: usize,
+  offset: u64,
+  map: Arc<Mmap>,
+  last_used: u64,
+}
+
+impl PackSet {
+  pub(crate) fn new(dir: PathBuf, limits: PackLimits) -> Self {
+    let page = mmap::page_size();
+    Self {
+      dir,
+      limits: PackLimits {
+        window_size: (limits.window_size / page * page).max(page),
+        ..limits
+      },
+      packs: Mutex::new(None),
+      windows: Mutex::new(Windows::default()),
+    }
+  }
+
+  /// The limits the windows are mapped with
+  pub(crate) fn limits(&self) -> PackLimits {
+    self.limits
+  }
+
+  /// The packs in the directory, loaded the first time they're needed or
+  /// looked for again when `rescan` is set in case new ones were added
+  fn packs(&self, rescan: bool) -> Result<Vec<Arc<Pack>>, PackError> {
+    Ok(self.loaded(rescan)?.packs)
+  }
+
+  fn loaded(&self, rescan: bool) -> Result<Loaded, PackError> {
+    let mut packs = self.packs.lock().unwrap_or_else(|e| e.into_inner());
+    if let (Some(packs), false) = (&*packs, rescan) {
+      return Ok(packs.clone());
+    }
+    let mut loaded = packs.take().unwrap_or_default().packs;
+    let entries = match fs::read_dir(&self.dir) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        *packs = Some(Loaded::default());
+        return Ok(Loaded::default());
+      }
+      Err(e) => return Err(e.into()),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+      let path = entry?.path();
+      if path.extension() == Some("idx".as_ref()) && path.with_extension("pack").is_file() {
+        paths.push(path.with_extension("pack"));
+      }
+    }
+    paths.sort();
+    // Packs that were deleted are dropped and ones already open are kept
+    loaded.retain(|pack| paths.contains(&pack.path));
+    for path in paths {
+      if !loaded.iter().any(|pack| pack.path == path) {
+        loaded.push(Arc::new(Pack::open(path)?));
+      }
+    }
+    // A multi-pack-index that can't be read is ignored like git does, the
+    // packs can still be looked through one by one
+    let midx = MultiPackIndex::open(&self.dir)
+      .ok()
+      .flatten()
+      .and_then(|midx| {
+        let covered = midx
+          .pack_names()
+          .iter()
+          .map(|name| {
+            let path = self.dir.join(name).with_extension("pack");
+            loaded.iter().find(|pack| pack.path == path).cloned()
+          })
+          .collect::<Option<Vec<_>>>()?;
+        Some((Arc::new(midx), covered))
+      });
+    let uncovered = loaded
+      .iter()
+      .filter(|pack| {
+        midx
+          .as_ref()
+          .is_none_or(|(_, covered)| !covered.iter().any(|c| Arc::ptr_eq(c, pack)))
+      })
+      .cloned()
+      .collect();
+    let loaded = Loaded {
+      packs: loaded,
+      midx,
+      uncovered,
+    };
+    *packs = Some(loaded.clone());
+    Ok(loaded)
+  }
+
+  /// The pack that has an object and the offset of its entry. The packs in
+  /// the multi-pack-index take one lookup in it, the others one each.
+  fn find(&self, oid: &OID) -> Result<Option<(Arc<Pack>, u64)>, PackError> {
+    for rescan in [false, true] {
+      let loaded = self.loaded(rescan)?;
+      if let Some((midx, covered)) = &loaded.midx {
+        if let Some((pack, offset)) = midx.find(oid) {
+          let pack = covered.get(pack as usize).ok_or(PackError::Malformed(
+            "pack in multi-pack-index out of bounds",
+          ))?;
+          return Ok(Some((pack.clone(), offset)));
+        }
+      }
+      for pack in loaded.uncovered {
+        if let Some(offset) = pack.index.offset_of(oid) {
+          return Ok(Some((pack, offset)));
+        }
+      }
+    }
+    Ok(None)
+  }
+
+  /// The bitmap of the first pack that has one. One that can't be read is
+  /// ignored like git does.
+  pub(crate) fn bitmap(&self) -> Result<Option<Arc<BitmapIndex>>, PackError> {
+    for pack in self.packs(false)? {
+      if let Some(bitmap) = pack.bitmap.get() {
+        return Ok(Some(bitmap.clone()));
+      }
+      let data = match fs::read(pack.path.with_extension("bitmap")) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(e.into()),
+      };
+      if let Ok(bitmap) = BitmapIndex::parse(&data, &pack.checksum(), pack.oids_in_pack_order()) {
+        return Ok(Some(pack.bitmap.get_or_init(|| Arc::new(bitmap)).clone()));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Write a multi-pack-index of every pack, see [`crate::Odb::write_midx`]
+  pub(crate) fn write_midx(&self) -> Result<(), MultiPackIndexError> {
+    let mut packs = Vec::new();
+    for pack in self.packs(true)? {
+      // Packs are only picked up when their index is there so the path
+      // always has a file name
+      let name = pack.path.with_extension("idx");
+      let name = name.file_name().unwrap().to_string_lossy().into_owned();
+      packs.push(IndexedPack {
+        name,
+        modified: fs::metadata(&pack.path)?.modified()?,
+        objects: (0..pack.index.count)
+          .map(|i| (pack.index.oid(i), pack.index.offset(i)))
+          .collect(),
+      });
+    }
+    fs::create_dir_all(&self.dir)?;
+    midx::write(&self.dir, &mut packs)?;
+    self.loaded(true)?;
+    Ok(())
+  }
+
+  /// Read an object from whichever pack has it
+  pub(crate) fn read(
+    &self,
+    oid: &OID,
+    budget: &MemoryBudget,
+  ) -> Result<Option<RawObject>, PackError> {
+    match self.find(oid)? {
+      Some((pack, offset)) => self.read_at(&pack, offset, budget).map(Some),
+      None => Ok(None),
+    }
+  }
+
+  /// Whether any pack has the object
+  pub(crate) fn contains(&self, oid: &OID) -> Result<bool, PackError> {
+    Ok(self.find(oid)?.is_some())
+  }
+
+  /// The kind and size of an object from whichever pack has it, reading
+  /// only the headers of its entry and the entries of its delta bases
+  pub(crate) fn header(&self, oid: &OID) -> Result<Option<(ObjectKind, usize)>, PackError> {
+    match self.find(oid)? {
+      Some((pack, offset)) => self.header_at(&pack, offset).map(Some),
+      None => Ok(None),
+    }
+  }
+
+  /// The [`OID`] of every object in every pack
+  pub(crate) fn oids(&self) -> Result<Vec<OID>, PackError> {
+    let mut oids = Vec::new();
+    for pack in self.packs(true)? {
+      oids.extend((0..pack.index.count).map(|i| pack.index.oid(i)));
+    }
+    Ok(oids)
+  }
+
+  /// The [`OID`] of every object in a promisor pack, one fetched with a
+  /// filter from a promisor remote, which is marked by a `.promisor` file
+  /// next to it
+  pub(crate) fn promisor_oids(&self) -> Result<Vec<OID>, PackError> {
+    let mut oids = Vec::new();
+    for pack in self.packs(true)? {
+      if pack.path.with_extension("promisor").is_file() {
+        oids.extend((0..pack.index.count).map(|i| pack.index.oid(i)));
+      }
+    }
+    Ok(oids)
+  }
+
+  /// How many objects are packed, counting ones in more than one pack once
+  /// for each pack
+  pub(crate) fn count(&self) -> Result<usize, PackError> {
+    Ok(
+      self
+        .packs(true)?
+        .iter()
+        .map(|pack| pack.index.count as usize)
+        .sum(),
+    )
+  }
+
+  /// Every packed object whose hex form starts with `prefix`
+  pub(crate) fn find_prefix(&self, prefix: &str) -> Result<HashSet<OID>, PackError> {
+    let first = u8::from_str_radix(&prefix[..2], 16).unwrap_or(0);
+    let mut found = HashSet::new();
+    for pack in self.packs(true)? {
+      let (start, end) = pack.index.bucket(first);
+      found.extend(
+        (start..end)
+          .map(|i| pack.index.oid(i))
+          .filter(|oid| oid.as_hex().starts_with(prefix)),
+      );
+    }
+    Ok(found)
+  }
+
+  /// How many bytes of packs are mapped right now
+  #[cfg(test)]
+  fn mapped(&self) -> usize {
+    self
+      .windows
+      .lock()
+      .unwrap_or_else(|e| e.into_inner())
+      .mapped
+  }
+
+  fn read_at(
+    &self,
+    pack: &Pack,
+    mut offset: u64,
+    budget: &MemoryBudget,
+  ) -> Result<RawObject, PackError> {
+    let mut deltas: Vec<(Vec<u8>, Reservation)> = Vec::new();
+    let mut chain = DeltaChain::new(offset, self.limits.max_delta_depth);
+    let (kind, mut data, mut reservation) = loop {
+      let end = pack.entry_end(offset)?;
+      let header = self.bytes(pack, offset, (end - offset).min(32) as usize)?;
+      let (header, header_len) = EntryHeader::parse(&header, offset)?;
+      let data = self.inflate(pack, offset + header_len as u64, end, header.size, budget)?;
+      offset = match header.kind {
+        EntryKind::Object(kind) => break (kind, data.0, data.1),
+        EntryKind::OfsDelta(base) => base,
+        EntryKind::RefDelta(base) => pack
+          .index
+          .offset_of(&base)
+          .ok_or(PackError::MissingBase(base))?,
+      };
+      chain.follow(offset)?;
+      deltas.push(data);
+    };
+    while let Some((delta, _delta_reservation)) = deltas.pop() {
+      let target = apply_delta(&data, &delta, budget)?;
+      data = target.0;
+      reservation = target.1;
+    }
+    drop(reservation);
+    Ok(RawObject::new(kind, data))
+  }
+
+  /// The kind and size of the object at `offset`. The size of a delta's
+  /// target is at the start of the delta, so only that much is inflated,
+  /// and the kind is that of the object at the end of the delta chain.
+  fn header_at(&self, pack: &Pack, mut offset: u64) -> Result<(ObjectKind, usize), PackError> {
+    let mut size = None;
+    let mut chain = DeltaChain::new(offset, self.limits.max_delta_depth);
+    loop {
+      let end = pack.entry_end(offset)?;
+      let header = self.bytes(pack, offset, (end - offset).min(32) as usize)?;
+      let (header, header_len) = EntryHeader::parse(&header, offset)?;
+      let base = match header.kind {
+        EntryKind::Object(kind) => return Ok((kind, size.unwrap_or(header.size))),
+        EntryKind::OfsDelta(base) => base,
+        EntryKind::RefDelta(base) => pack
+          .index
+          .offset_of(&base)
+          .ok_or(PackError::MissingBase(base))?,
+      };
+      if size.is_none() {
+        // The delta starts with the size of its base and then of its
+        // target, two varints of at most 10 bytes each, which never take
+        // more than a few hundred compressed bytes
+        let start = offset + header_len as u64;
+        let compressed = self.bytes(pack, start, (end - start).min(512) as usize)?;
+        let delta = zlib::decompress_prefix(&compressed, 20)?;
+        size = Some(delta_target_size(&delta)?);
+      }
+      chain.follow(base)?;
+      offset = base;
+    }
+  }
+
+  /// Inflate the data of an entry between `start` and `end`, which should
+  /// come out to `size` bytes
+  fn inflate(
+    &self,
+    pack: &Pack,
+    start: u64,
+    end: u64,
+    size: usize,
+    budget: &MemoryBudget,
+  ) -> Result<(Vec<u8>, Reservation), PackError> {
+    let reservation = budget.try_reserve(size)?;
+    let len = usize::try_from(end - start)
+      .map_err(|_| PackError::Malformed("entry is too large to read"))?;
+    let compressed = self.bytes(pack, start, len)?;
+    let (data, _) = zlib::decompress_with_limit(&compressed, size)?;
+    if data.len() != size {
+      return Err(PackError::Malformed("entry size does not match its data"));
+    }
+    Ok((data, reservation))
+  }
+
+  /// The bytes of a pack from `start` to `start + len`. They come straight
+  /// from a window if packs are mapped and they fit in one, and are read
+  /// into a buffer otherwise.
+  fn bytes(&self, pack: &Pack, start: u64, len: usize) -> Result<Bytes, PackError> {
+    let end = start + len as u64;
+    if end > pack.size {
+      return Err(PackError::Malformed("entry is past the end of the pack"));
+    }
+    let window_size = self.limits.window_size as u64;
+    let window_start = start / window_size * window_size;
+    if cfg!(feature = "mmap") && end <= window_start + window_size {
+      let map = self.window(pack, window_start)?;
+      let start = (start - window_start) as usize;
+      return Ok(Bytes::Window(map, start..start + len));
+    }
+    let mut buffer = vec![0; len];
+    mmap::read_exact_at(&pack.file, &mut buffer, start)?;
+    Ok(Bytes::Owned(buffer))
+  }
+
+  fn window(&self, pack: &Pack, offset: u64) -> Result<Arc<Mmap>, PackError> {
+    let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+    windows.tick += 1;
+    let tick = windows.tick;
+    if let Some(window) = windows
+      .list
+      .iter_mut()
+      .find(|window| window.pack == pack.id && window.offset == offset)
+    {
+      window.last_used = tick;
+      return Ok(window.map.clone());
+    }
+    let len = (pack.size - offset).min(self.limits.window_size as u64) as usize;
+    // Unmap the least recently used windows nothing is reading from until
+    // the new one fits
+    while windows.mapped + len > self.limits.limit {
+      let lru = windows
+        .list
+        .iter()
+        .enumerate()
+        .filter(|(_, window)| Arc::strong_count(&window.map) == 1)
+        .min_by_key(|(_, window)| window.last_used)
+        .map(|(i, _)| i);
+      match lru {
+        Some(i) => {
+          let window = windows.list.swap_remove(i);
+          windows.mapped -= window.map.len();
+        }
+        None => break,
+      }
+    }
+    let map = Arc::new(Mmap::map(&pack.file, offset, len)?);
+    windows.mapped += len;
+    windows.list.push(Window {
+      pack: pack.id,
+      offset,
+      map: map.clone(),
+      last_used: tick,
+    });
+    Ok(map)
+  }
+}
+
+impl fmt::Debug for PackSet {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("PackSet")
+      .field("dir", &self.dir)
+      .field("limits", &self.limits)
+      .finish()
+  }
+}
+
+/// Two sets are the same if they read the same directory the same way
+impl PartialEq for PackSet {
+  fn eq(&self, other: &Self) -> bool {
+    self.dir == other.dir && self.limits == other.limits
+  }
+}
+
+impl Eq for PackSet {}
+
+enum Bytes {
+  Window(Arc<Mmap>, std::ops::Range<usize>),
+  Owned(Vec<u8>),
+}
+
+impl Deref for Bytes {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match self {
+      Bytes::Window(map, range) => &map[range.clone()],
+      Bytes::Owned(buffer) => buffer,
+    }
+  }
+}
+
+/// A pack file and its index
+struct Pack {
+  /// Tells the windows of different packs apart
+  id: usize,
+  path: PathBuf,
+  file: fs::File,
+  size: u64,
+  index: PackIndex,
+  /// The offset of every entry in order, to find where an entry ends
+  offsets: OnceLock<Vec<u64>>,
+  /// The `.bitmap` of the pack once it's been read
+  bitmap: OnceLock<Arc<BitmapIndex>>,
+}
+
+impl Pack {
+  fn open(path: PathBuf) -> Result<Self, PackError> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let index = PackIndex::open(&path.with_extension("idx"))?;
+    let file = fs::File::open(&path)?;
+    let size = file.metadata()?.len();
+    if size < 12 + 20 {
+      return Err(PackError::Malformed("pack is too short"));
+    }
+    let mut header = [0; 12];
+    mmap::read_exact_at(&file, &mut header, 0)?;
+    if &header[..4] != PACK_SIGNATURE {
+      return Err(PackError::Malformed("missing PACK signature"));
+    }
+    let version = read_u32(&header[4..]);
+    if version != 2 && version != 3 {
+      return Err(PackError::UnsupportedVersion(version));
+    }
+    if read_u32(&header[8..]) != index.count {
+      return Err(PackError::Malformed(
+        "pack and index have a different number of objects",
+      ));
+    }
+    Ok(Self {
+      id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+      path,
+      file,
+      size,
+      index,
+      offsets: OnceLock::new(),
+      bitmap: OnceLock::new(),
+    })
+  }
+
+  /// The checksum at the end of the pack, which its index has a copy of
+  fn checksum(&self) -> OID {
+    let data = &self.index.data;
+    OID::from_bytes(&data[data.len() - 40..][..20]).unwrap()
+  }
+
+  /// The [`OID`] of every object in the order they're in the pack
+  fn oids_in_pack_order(&self) -> Vec<OID> {
+    let mut objects: Vec<(u64, OID)> = (0..self.index.count)
+      .map(|i| (self.index.offset(i), self.index.oid(i)))
+      .collect();
+    objects.sort_unstable();
+    objects.into_iter().map(|(_, oid)| oid).collect()
+  }
+
+  /// Where the entry at `offset` ends, which is where the next one starts
+  /// or the checksum at the end of the pack
+  fn entry_end(&self, offset: u64) -> Result<u64, PackError> {
+    let offsets = self.offsets.get_or_init(|| {
+      let mut offsets: Vec<u64> = (0..self.index.count)
+        .map(|i| self.index.offset(i))
+        .collect();
+      offsets.sort_unstable();
+      offsets
+    });
+    match offsets.binary_search(&offset) {
+      Ok(_) if offset >= self.size - 20 => {
+        Err(PackError::Malformed("entry is past the end of the pack"))
+      }
+      Ok(i) => Ok(offsets.get(i + 1).copied().unwrap_or(self.size - 20)),
+      Err(_) => Err(PackError::Malformed(
+        "delta base is not the start of an entry",
+      )),
+    }
+  }
+}
+
+/// A `.idx` file which maps the [`OID`] of every object in a pack to where
+/// it is in the pack. Versions 1 and 2 are supported.
+struct PackIndex {
+  data: Mmap,
+  count: u32,
+  /// Where the fanout table starts
+  fanout: usize,
+  /// Where the first [`OID`] is and how far apart they are
+  oids: usize,
+  stride: usize,
+  /// Where the 4 byte and 8 byte offsets of a version 2 index are
+  offsets: Option<(usize, usize)>,
+}
+
+impl PackIndex {
+  fn open(path: &Path) -> Result<Self, PackError> {
+    let file = fs::File::open(path)?;
+    let len = usize::try_from(file.metadata()?.len())
+      .map_err(|_| PackError::Malformed("index is too large to map"))?;
+    let data = Mmap::map(&file, 0, len)?;
+    if len < 8 + 256 * 4 + 40 {
+      return Err(PackError::Malformed("index is too short"));
+    }
+    let version2 = &data[..4] == INDEX_SIGNATURE;
+    if version2 && read_u32(&data[4..]) != 2 {
+      return Err(PackError::UnsupportedVersion(read_u32(&data[4..])));
+    }
+    let fanout = if version2 { 8 } else { 0 };
+    let count = read_u32(&data[fanout + 255 * 4..]);
+    // Every table has to be in the index, which also means none of the
+    // offsets into it below can overflow even on 32 bit targets
+    let entry_len = if version2 { 20 + 4 + 4 } else { 4 + 20 };
+    if u64::from(count) * entry_len + (fanout + 256 * 4 + 40) as u64 > len as u64 {
+      return Err(PackError::Malformed("index is too short"));
+    }
+    let n = count as usize;
+    let mut index = Self {
+      count,
+      fanout,
+      oids: fanout + 256 * 4,
+      stride: 20,
+      offsets: None,
+      data,
+    };
+    if version2 {
+      let small = index.oids + n * 24;
+      index.offsets = Some((small, small + n * 4));
+    } else {
+      // Version 1 entries are a 4 byte offset followed by the OID
+      index.oids += 4;
+      index.stride = 24;
+    }
+    Ok(index)
+  }
+
+  /// The range of entries whose [`OID`] starts with the byte `first`
+  fn bucket(&self, first: u8) -> (u32, u32) {
+    let fanout = |i: usize| read_u32(&self.data[self.fanout + i * 4..]);
+    let start = if first == 0 {
+      0
+    } else {
+      fanout(first as usize - 1)
+    };
+    (
+      start.min(self.count),
+      fanout(first as usize).min(self.count),
+    )
+  }
+
+  fn oid(&self, i: u32) -> OID {
+    OID::from_bytes(&self.data[self.oids + i as usize * self.stride..][..20]).unwrap()
+  }
+
+  fn find(&self, oid: &OID) -> Option<u32> {
+    let (mut low, mut high) = self.bucket(oid.as_bytes()[0]);
+    while low < high {
+      let mid = low + (high - low) / 2;
+      match self.oid(mid).cmp(oid) {
+        std::cmp::Ordering::Less => low = mid + 1,
+        std::cmp::Ordering::Greater => high = mid,
+        std::cmp::Ordering::Equal => return Some(mid),
+      }
+    }
+    None
+  }
+
+  fn offset(&self, i: u32) -> u64 {
+    let i = i as usize;
+    match self.offsets {
+      None => u64::from(read_u32(&self.data[self.oids - 4 + i * 24..])),
+      Some((small, large)) => {
+        let offset = read_u32(&self.data[small + i * 4..]);
+        if offset & LARGE_OFFSET == 0 {
+          return u64::from(offset);
+        }
+        // A large offset past the end of the index gives an offset past the
+        // end of the pack which is caught when it's read
+        table_len(offset & !LARGE_OFFSET, 8)
+          .and_then(|at: usize| at.checked_add(large))
+          .and_then(|at| self.data.get(at..at.checked_add(8)?))
+          .map_or(u64::MAX, read_u64)
+      }
+    }
+  }
+
+  fn offset_of(&self, oid: &OID) -> Option<u64> {
+    self.find(oid).map(|i| self.offset(i))
+  }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum EntryKind {
+  Object(ObjectKind),
+  /// A delta against the entry at this offset
+  OfsDelta(u64),
+  /// A delta against the object with this [`OID`]
+  RefDelta(OID),
+}
+
+pub(crate) struct EntryHeader {
+  pub(crate) kind: EntryKind,
+  /// The size of the object, or of the delta for deltas
+  pub(crate) size: usize,
+}
+
+const TRUNCATED: &str = "entry header is truncated";
+
+impl EntryHeader {
+  /// Parse the header of the entry at `offset`, returning it and how long it
+  /// is
+  pub(crate) fn parse(bytes: &[u8], offset: u64) -> Result<(Self, usize), PackError> {
+    let mut pos = 0;
+    let mut next = || {
+      let byte = bytes.get(pos).copied();
+      pos += 1;
+      byte
+    };
+    let mut byte = next().ok_or(PackError::Malformed(TRUNCATED))?;
+    let kind = (byte >> 4) & 7;
+    let mut size = u64::from(byte & 15);
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+      byte = next().ok_or(PackError::Malformed(TRUNCATED))?;
+      if shift > 57 {
+        return Err(PackError::Malformed("entry size is too large"));
+      }
+      size |= u64::from(byte & 0x7f) << shift;
+      shift += 7;
+    }
+    let kind = match kind {
+      1 => EntryKind::Object(ObjectKind::Commit),
+      2 => EntryKind::Object(ObjectKind::Tree),
+      3 => EntryKind::Object(ObjectKind::Blob),
+      4 => EntryKind::Object(ObjectKind::Tag),
+      6 => {
+        let mut byte = next().ok_or(PackError::Malformed(TRUNCATED))?;
+        let mut distance = u64::from(byte & 0x7f);
+        while byte & 0x80 != 0 {
+          byte = next().ok_or(PackError::Malformed(TRUNCATED))?;
+          distance = distance
+            .checked_add(1)
+            .and_then(|d| d.checked_mul(128))
+            .ok_or(PackError::Malformed("delta base offset is too large"))?
+            | u64::from(byte & 0x7f);
+        }
+        if distance == 0 || distance > offset {
+          return Err(PackError::Malformed("delta base offset is out of bounds"));
+        }
+        EntryKind::OfsDelta(offset - distance)
+      }
+      7 => {
+        let oid = bytes
+          .get(pos..pos + 20)
+          .ok_or(PackError::Malformed(TRUNCATED))?;
+        pos += 20;
+        EntryKind::RefDelta(OID::from_bytes(oid).unwrap())
+      }
+      _ => return Err(PackError::Malformed("unknown entry type")),
+    };
+    let size =
+      usize::try_from(size).map_err(|_| PackError::Malformed("entry size is too large"))?;
+    Ok((Self { kind, size }, pos))
+  }
+}
+
+/// Parse a whole pack file from `bytes` the way one is received during a
+/// fetch, without an index. Deltas are resolved against the other objects in
+/// the pack, so a thin pack fails with [`PackError::MissingBase`]. The
+/// objects are returned in the order they are in the pack.
+pub(crate) fn parse_pack(bytes: &[u8], budget: &MemoryBudget) -> Result<Vec<RawObject>, PackError> {
+  parse_thin_pack(bytes, budget, delta::MAX_DEPTH, |_| None)
+}
+
+/// [`parse_pack`] for a thin pack, with deltas against objects that aren't
+/// in it looked up with `find_base`, and chains of deltas no longer than
+/// `max_depth`
+pub(crate) fn parse_thin_pack(
+  bytes: &[u8],
+  budget: &MemoryBudget,
+  max_depth: usize,
+  mut find_base: impl FnMut(&OID) -> Option<RawObject>,
+) -> Result<Vec<RawObject>, PackError> {
+  use sha1::{Digest, Sha1};
+  if bytes.len() < 12 + 20 {
+    return Err(PackError::Malformed("pack is too short"));
+  }
+  let (content, checksum) = bytes.split_at(bytes.len() - 20);
+  if &content[..4] != PACK_SIGNATURE {
+    return Err(PackError::Malformed("missing PACK signature"));
+  }
+  let version = read_u32(&content[4..]);
+  if version != 2 && version != 3 {
+    return Err(PackError::UnsupportedVersion(version));
+  }
+  let count = read_u32(&content[8..]);
+
+  // Every entry takes up at least two bytes for its header and the shortest
+  // zlib stream, so a bogus count can't make this allocate much
+  let mut entries = Vec::with_capacity((count as usize).min(content.len() / 2));
+  let mut offsets = HashMap::new();
+  let mut pos = 12;
+  for i in 0..count as usize {
+    let rest = content
+      .get(pos..)
+      .filter(|rest| !rest.is_empty())
+      .ok_or(PackError::Malformed("pack has fewer entries than it says"))?;
+    let (header, header_len) = EntryHeader::parse(rest, pos as u64)?;
+    let reservation = budget.try_reserve(header.size)?;
+    let (data, len) = zlib::decompress_with_limit(&rest[header_len..], header.size)?;
+    if data.len() != header.size {
+      return Err(PackError::Malformed("entry size does not match its data"));
+    }
+    offsets.insert(pos as u64, i);
+    entries.push(PackEntry {
+      kind: header.kind,
+      data,
+      reservation,
+    });
+    pos += header_len + len;
+  }
+  if pos != content.len() {
+    return Err(PackError::Malformed("pack has data after the last entry"));
+  }
+  if Sha1::digest(content)[..] != *checksum {
+    return Err(PackError::Malformed("pack checksum does not match"));
+  }
+
+  // Resolve every delta once its base is, starting from the whole objects,
+  // so chains of any length and loops between ref deltas can't recurse
+  let mut by_offset: HashMap<usize, Vec<usize>> = HashMap::new();
+  let mut by_oid: HashMap<OID, Vec<usize>> = HashMap::new();
+  let mut resolved: Vec<Option<RawObject>> = Vec::with_capacity(entries.len());
+  let mut depths = vec![0; entries.len()];
+  let mut ready = Vec::new();
+  for (i, entry) in entries.iter_mut().enumerate() {
+    resolved.push(None);
+    match entry.kind {
+      EntryKind::Object(kind) => {
+        resolved[i] = Some(RawObject::new(kind, std::mem::take(&mut entry.data)));
+        ready.push(i);
+      }
+      EntryKind::OfsDelta(base) => {
+        let base = offsets.get(&base).ok_or(PackError::Malformed(
+          "delta base is not the start of an entry",
+        ))?;
+        by_offset.entry(*base).or_default().push(i);
+      }
+      EntryKind::RefDelta(base) => by_oid.entry(base).or_default().push(i),
+    }
+  }
+  loop {
+    while let Some(base) = ready.pop() {
+      let mut deltas = by_offset.remove(&base).unwrap_or_default();
+      let oid = resolved[base].as_ref().unwrap().id();
+      deltas.extend(by_oid.remove(&oid).unwrap_or_default());
+      for i in deltas {
+        depths[i] = depths[base] + 1;
+        if depths[i] > max_depth {
+          return Err(PackError::DeltaTooDeep(max_depth));
+        }
+        let base = resolved[base].as_ref().unwrap();
+        let (data, reservation) = apply_delta(&base.data, &entries[i].data, budget)?;
+        resolved[i] = Some(RawObject::new(base.kind, data));
+        // The delta isn't needed anymore, only what it made
+        entries[i].data = Vec::new();
+        entries[i].reservation = reservation;
+        ready.push(i);
+      }
+    }
+    // Bases from outside of the pack go after its entries, where they're
+    // only used to resolve deltas
+    let base = match by_oid.keys().next() {
+      Some(base) => *base,
+      None => break,
+    };
+    match find_base(&base) {
+      Some(object) if object.id() == base => {
+        resolved.push(Some(object));
+        depths.push(0);
+        ready.push(resolved.len() - 1);
+      }
+      _ => return Err(PackError::MissingBase(base)),
+    }
+  }
+  resolved.truncate(entries.len());
+  // Offset deltas always point back at an earlier entry, so once every ref
+  // delta found its base everything is resolved
+  resolved
+    .into_iter()
+    .map(|object| object.ok_or(PackError::Malformed("delta base can't be resolved")))
+    .collect()
+}
+
+/// Read one whole pack from `input` the way one comes in during a push,
+/// stopping right after its checksum so a client waiting for an answer
+/// doesn't have to hang up first. Entries are only inflated to find where
+/// the next one starts, checking them is left to [`Odb::index_pack`].
+///
+/// [`Odb::index_pack`]: crate::Odb::index_pack
+pub(crate) fn read_pack(mut input: impl io::Read) -> Result<Vec<u8>, PackError> {
+  let mut bytes = Vec::new();
+  let mut more = |bytes: &mut Vec<u8>| -> Result<(), PackError> {
+    let len = bytes.len();
+    bytes.resize(len + 64 * 1024, 0);
+    let read = loop {
+      match input.read(&mut bytes[len..]) {
+        Ok(read) => break read,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+        Err(e) => return Err(e.into()),
+      }
+    };
+    bytes.truncate(len + read);
+    match read {
+      0 => Err(PackError::Malformed("pack is truncated")),
+      _ => Ok(()),
+    }
+  };
+  while bytes.len() < 12 {
+    more(&mut bytes)?;
+  }
+  if &bytes[..4] != PACK_SIGNATURE {
+    return Err(PackError::Malformed("missing PACK signature"));
+  }
+  let mut pos = 12;
+  for _ in 0..read_u32(&bytes[8..]) {
+    loop {
+      let entry = EntryHeader::parse(&bytes[pos..], pos as u64).and_then(|(header, len)| {
+        let (_, compressed) = zlib::decompress_with_limit(&bytes[pos + len..], header.size)?;
+        Ok(len + compressed)
+      });
+      // Only more of the pack tells whether an entry that's cut off is bad
+      match entry {
+        Ok(len) => {
+          pos += len;
+          break;
+        }
+        Err(PackError::Malformed(TRUNCATED)) | Err(PackError::Zlib(ZlibError::UnexpectedEnd)) => {
+          more(&mut bytes)?
+        }
+        Err(e) => return Err(e),
+      }
+    }
+  }
+  while bytes.len() < pos + 20 {
+    more(&mut bytes)?;
+  }
+  if bytes.len() > pos + 20 {
+    return Err(PackError::Malformed("pack has data after the last entry"));
+  }
+  Ok(bytes)
+}
+
+/// Write a version 2 index of a pack for the objects with the given
+/// [`OID`]s, entry offsets, and CRC-32s in the order they are in the pack.
+/// Offsets past 2 GiB go in the table of 64 bit offsets at the end.
+pub(crate) fn write_index(oids: &[OID], offsets: &[u64], crcs: &[u32], checksum: &OID) -> Vec<u8> {
+  use sha1::{Digest, Sha1};
+  let mut sorted: Vec<usize> = (0..oids.len()).collect();
+  sorted.sort_by_key(|&i| oids[i]);
+  let mut index = [&INDEX_SIGNATURE[..], &2u32.to_be_bytes()].concat();
+  let mut count = 0;
+  for byte in 0..=255u8 {
+    while count < sorted.len() && oids[sorted[count]].as_bytes()[0] <= byte {
+      count += 1;
+    }
+    index.extend_from_slice(&(count as u32).to_be_bytes());
+  }
+  for &i in &sorted {
+    index.extend_from_slice(oids[i].as_bytes());
+  }
+  for &i in &sorted {
+    index.extend_from_slice(&crcs[i].to_be_bytes());
+  }
+  let mut large = Vec::new();
+  for &i in &sorted {
+    let offset = offsets[i];
+    if offset < 0x8000_0000 {
+      index.extend_from_slice(&(offset as u32).to_be_bytes());
+    } else {
+      index.extend_from_slice(&(0x8000_0000 | (large.len() / 8) as u32).to_be_bytes());
+      large.extend_from_slice(&offset.to_be_bytes());
+    }
+  }
+  index.extend(large);
+  index.extend_from_slice(checksum.as_bytes());
+  let index_checksum = Sha1::digest(&index);
+  index.extend_from_slice(&index_checksum);
+  index
+}
+
+/// An entry of a pack being parsed by [`parse_pack`]
+struct PackEntry {
+  kind: EntryKind,
+  data: Vec<u8>,
+  reservation: Reservation,
+}
+
+#[derive(Error, Debug)]
+/// Errors related to reading pack files
+pub enum PackError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Zlib(#[from] ZlibError),
+  #[error("{0}")]
+  Memory(#[from] MemoryError),
+  #[error("malformed pack: {0}")]
+  Malformed(&'static str),
+  #[error("pack version {0} is not supported")]
+  UnsupportedVersion(u32),
+  #[error("invalid delta: {0}")]
+  Delta(&'static str),
+  #[error("delta base {0} is not in the pack")]
+  MissingBase(OID),
+  #[error("delta chain is longer than {0}")]
+  DeltaTooDeep(usize),
+}
+
+/// An object to put in a pack written with [`PackWriter`]
+pub(crate) enum PackObject {
+  /// The whole object
+  Whole(RawObject),
+  /// A delta against `base`, which doesn't have to be in the pack, making
+  /// it a thin pack that only someone who has `base` can read
+  RefDelta { base: OID, delta: Vec<u8> },
+  /// A delta against the entry already written at `base`, an offset from
+  /// [`PackWriter::offset`]
+  OfsDelta { base: u64, delta: Vec<u8> },
+}
+
+/// Writes a version 2 pack one object at a time, so a pack never has to be
+/// in memory as a whole
+pub(crate) struct PackWriter<W> {
+  out: W,
+  hasher: sha1::Sha1,
+  remaining: u32,
+  written: u64,
+}
+
+impl<W: io::Write> PackWriter<W> {
+  /// Start a pack of `count` objects in `out`
+  pub(crate) fn new(out: W, count: u32) -> io::Result<Self> {
+    use sha1::Digest;
+    let mut writer = Self {
+      out,
+      hasher: sha1::Sha1::new(),
+      remaining: count,
+      written: 0,
+    };
+    writer.write(
+      &[
+        &PACK_SIGNATURE[..],
+        &2u32.to_be_bytes(),
+        &count.to_be_bytes(),
+      ]
+      .concat(),
+    )?;
+    Ok(writer)
+  }
+
+  fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+    use sha1::Digest;
+    self.hasher.update(bytes);
+    self.written += bytes.len() as u64;
+    self.out.write_all(bytes)
+  }
+
+  /// Where the next object starts, for deltas against it to refer to
+  pub(crate) fn offset(&self) -> u64 {
+    self.written
+  }
+
+  /// Write the next object, returning the CRC-32 of its entry for an index
+  /// of the pack
+  pub(crate) fn add(&mut self, object: &PackObject) -> io::Result<u32> {
+    if self.remaining == 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "more objects than the pack was started with",
+      ));
+    }
+    self.remaining -= 1;
+    let header = |kind: u8, size: usize| {
+      let mut bytes = vec![(kind << 4) | (size as u8 & 15)];
+      let mut size = size >> 4;
+      while size != 0 {
+        *bytes.last_mut().unwrap() |= 0x80;
+        bytes.push(size as u8 & 0x7f);
+        size >>= 7;
+      }
+      bytes
+    };
+    let entry = match object {
+      PackObject::Whole(object) => {
+        let kind = match object.kind {
+          ObjectKind::Commit => 1,
+          ObjectKind::Tree => 2,
+          ObjectKind::Blob => 3,
+          ObjectKind::Tag => 4,
+        };
+        let mut entry = header(kind, object.data.len());
+        entry.extend(zlib::compress(&object.data));
+        entry
+      }
+      PackObject::RefDelta { base, delta } => {
+        let mut entry = header(7, delta.len());
+        entry.extend_from_slice(base.as_bytes());
+        entry.extend(zlib::compress(delta));
+        entry
+      }
+      PackObject::OfsDelta { base, delta } => {
+        let mut entry = header(6, delta.len());
+        // Each byte after the first stands for one more than it says, so
+        // that no distance has two ways to be written
+        let mut distance = self.written - base;
+        let mut bytes = vec![distance as u8 & 0x7f];
+        distance >>= 7;
+        while distance != 0 {
+          distance -= 1;
+          bytes.push(0x80 | (distance as u8 & 0x7f));
+          distance >>= 7;
+        }
+        bytes.reverse();
+        entry.extend(bytes);
+        entry.extend(zlib::compress(delta));
+        entry
+      }
+    };
+    self.write(&entry)?;
+    Ok(zlib::crc32(&entry))
+  }
+
+  /// Copy `count` entries as they are in another pack from `entries`, to
+  /// the end of it
+  pub(crate) fn copy(&mut self, mut entries: impl io::Read, count: u32) -> io::Result<()> {
+    if count > self.remaining {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "more objects than the pack was started with",
+      ));
+    }
+    self.remaining -= count;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+      match entries.read(&mut buf) {
+        Ok(0) => return Ok(()),
+        Ok(read) => self.write(&buf[..read])?,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  /// Write the checksum that ends the pack, returning the output and the
+  /// checksum, which is what the pack is named after
+  pub(crate) fn finish(mut self) -> io::Result<(W, OID)> {
+    use sha1::Digest;
+    if self.remaining != 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "fewer objects than the pack was started with",
+      ));
+    }
+    let checksum = OID::from_bytes(&self.hasher.finalize()).unwrap();
+    self.out.write_all(checksum.as_bytes())?;
+    Ok((self.out, checksum))
+  }
+}
+
+/// Build a pack holding `objects` in the order given
+#[cfg(test)]
+pub(crate) fn build_pack(objects: &[PackObject]) -> Vec<u8> {
+  let mut writer = PackWriter::new(Vec::new(), objects.len() as u32).unwrap();
+  for object in objects {
+    writer.add(object).unwrap();
+  }
+  writer.finish().unwrap().0
+}
+
+/// Write a version 2 pack and index to `dir` holding `entries`, which are
+/// either whole objects or `(base index, delta)` pairs, using an offset
+/// delta for the previous entry and a ref delta otherwise
+#[cfg(test)]
+pub(crate) fn write_test_pack(
+  dir: &Path,
+  name: &str,
+  entries: &[(Option<usize>, RawObject)],
+) -> Vec<OID> {
+  use sha1::{Digest, Sha1};
+  fn header(kind: u8, size: usize) -> Vec<u8> {
+    let mut bytes = vec![(kind << 4) | (size as u8 & 15)];
+    let mut size = size >> 4;
+    while size != 0 {
+      *bytes.last_mut().unwrap() |= 0x80;
+      bytes.push(size as u8 & 0x7f);
+      size >>= 7;
+    }
+    bytes
+  }
+  let kind_number = |kind| match kind {
+    ObjectKind::Commit => 1,
+    ObjectKind::Tree => 2,
+    ObjectKind::Blob => 3,
+    ObjectKind::Tag => 4,
+  };
+  let mut pack = [
+    &PACK_SIGNATURE[..],
+    &2u32.to_be_bytes(),
+    &(entries.len() as u32).to_be_bytes(),
+  ]
+  .concat();
+  let mut objects: Vec<(OID, u64, RawObject)> = Vec::new();
+  for (base, object) in entries {
+    let offset = pack.len() as u64;
+    match base {
+      None => {
+        pack.extend(header(kind_number(object.kind), object.data.len()));
+        pack.extend(zlib::compress(&object.data));
+        objects.push((object.id(), offset, object.clone()));
+      }
+      Some(base) => {
+        // The object holds the delta, the result is worked out here
+        let (base_oid, base_offset, base_object) = objects[*base].clone();
+        let (data, _) =
+          apply_delta(&base_object.data, &object.data, &MemoryBudget::unlimited()).unwrap();
+        let target = RawObject::new(base_object.kind, data);
+        if *base == objects.len() - 1 {
+          pack.extend(header(6, object.data.len()));
+          let mut distance = offset - base_offset;
+          let mut encoded = vec![distance as u8 & 0x7f];
+          distance >>= 7;
+          while distance != 0 {
+            distance -= 1;
+            encoded.insert(0, 0x80 | (distance as u8 & 0x7f));
+            distance >>= 7;
+          }
+          pack.extend(encoded);
+        } else {
+          pack.extend(header(7, object.data.len()));
+          pack.extend_from_slice(base_oid.as_bytes());
+        }
+        pack.extend(zlib::compress(&object.data));
+        objects.push((target.id(), offset, target));
+      }
+    }
+  }
+  let checksum = Sha1::digest(&pack);
+  pack.extend_from_slice(&checksum);
+
+  let mut sorted: Vec<(OID, u64)> = objects
+    .iter()
+    .map(|(oid, offset, _)| (*oid, *offset))
+    .collect();
+  sorted.sort();
+  let mut index = [&INDEX_SIGNATURE[..], &2u32.to_be_bytes()].concat();
+  for byte in 0..=255u8 {
+    let count = sorted
+      .iter()
+      .filter(|(oid, _)| oid.as_bytes()[0] <= byte)
+      .count() as u32;
+    index.extend_from_slice(&count.to_be_bytes());
+  }
+  for (oid, _) in &sorted {
+    index.extend_from_slice(oid.as_bytes());
+  }
+  // CRCs aren't checked when reading
+  index.extend(vec![0; sorted.len() * 4]);
+  for (_, offset) in &sorted {
+    index.extend_from_slice(&(*offset as u32).to_be_bytes());
+  }
+  index.extend_from_slice(&checksum);
+  let index_checksum = Sha1::digest(&index);
+  index.extend_from_slice(&index_checksum);
+
+  fs::create_dir_all(dir).unwrap();
+  fs::write(dir.join(format!("pack-{}.pack", name)), pack).unwrap();
+  fs::write(dir.join(format!("pack-{}.idx", name)), index).unwrap();
+  objects.into_iter().map(|(oid, _, _)| oid).collect()
+}
+
+/// A delta that copies `base[offset..offset + len]` and then inserts `data`
+#[cfg(test)]
+pub(crate) fn test_delta(
+  base_len: usize,
+  target_len: usize,
+  copy: (u8, u8),
+  data: &[u8],
+) -> Vec<u8> {
+  let mut delta = Vec::new();
+  for mut size in [base_len, target_len] {
+    loop {
+      let byte = size as u8 & 0x7f;
+      size >>= 7;
+      if size == 0 {
+        delta.push(byte);
+        break;
+      }
+      delta.push(byte | 0x80);
+    }
+  }
+  delta.extend_from_slice(&[0x80 | 0x01 | 0x10, copy.0, copy.1]);
+  delta.push(data.len() as u8);
+  delta.extend_from_slice(data);
+  delta
+}
+
+#[test]
+fn read_packed_objects() {
+  let tmp_dir = tempdir::TempDir::new("pack_test").unwrap();
+  let base = b"hello world, this is the base of a delta".to_vec();
+  let entries = [
+    (None, RawObject::new(ObjectKind::Blob, base.clone())),
+    // "hello world" followed by "!" as an offset delta
+    (
+      Some(0),
+      RawObject::new(ObjectKind::Blob, test_delta(base.len(), 12, (0, 11), b"!")),
+    ),
+    (None, RawObject::new(ObjectKind::Tree, Vec::new())),
+    // A delta on top of the delta as a ref delta
+    (
+      Some(1),
+      RawObject::new(ObjectKind::Blob, test_delta(12, 9, (6, 5), b"!!!!")),
+    ),
+  ];
+  let oids = write_test_pack(tmp_dir.path(), "test", &entries);
+  let packs = PackSet::new(tmp_dir.path().into(), PackLimits::default());
+  let budget = MemoryBudget::unlimited();
+  let read = |oid: &OID| packs.read(oid, &budget).unwrap().unwrap();
+  assert_eq!(base, read(&oids[0]).data);
+  assert_eq!(b"hello world!".to_vec(), read(&oids[1]).data);
+  assert_eq!(ObjectKind::Tree, read(&oids[2]).kind);
+  assert_eq!(b"world!!!!".to_vec(), read(&oids[3]).data);
+  for oid in &oids {
+    assert_eq!(*oid, read(oid).id());
+  }
+  assert!(packs
+    .read(&OID::hash(b"missing"), &budget)
+    .unwrap()
+    .is_none());
+  let mut all = packs.oids().unwrap();
+  all.sort();
+  let mut expected = oids.clone();
+  expected.sort();
+  assert_eq!(expected, all);
+  assert_eq!(1, packs.find_prefix(&oids[3].as_hex()[..6]).unwrap().len());
+
+  // Reading a delta needs room in the budget for the base and the delta
+  let small = MemoryBudget::new(base.len() + 8);
+  assert!(matches!(
+    packs.read(&oids[3], &small),
+    Err(PackError::Memory(_))
+  ));
+}
+
+#[test]
+fn window_limits() {
+  let tmp_dir = tempdir::TempDir::new("pack_test").unwrap();
+  let page = mmap::page_size();
+  // Objects that don't compress well so the pack spans several pages
+  let mut entries = Vec::new();
+  let mut state = 1u32;
+  for _ in 0..8 {
+    let data: Vec<u8> = (0..page / 2)
+      .map(|_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) as u8
+      })
+      .collect();
+    entries.push((None, RawObject::new(ObjectKind::Blob, data)));
+  }
+  let oids = write_test_pack(tmp_dir.path(), "windows", &entries);
+  let packs = PackSet::new(
+    tmp_dir.path().into(),
+    PackLimits {
+      window_size: page,
+      limit: 2 * page,
+      ..PackLimits::default()
+    },
+  );
+  let budget = MemoryBudget::unlimited();
+  for _ in 0..2 {
+    for (oid, (_, object)) in oids.iter().zip(&entries) {
+      assert_eq!(object.data, packs.read(oid, &budget).unwrap().unwrap().data);
+      assert!(packs.mapped() <= 2 * page);
+    }
+  }
+}
+
+#[test]
+fn limits_from_config() {
+  let config =
+    Config::from_bytes("[core]\n\tpackedGitWindowSize = 64k\n\tpackedGitLimit = 1m\n").unwrap();
+  assert_eq!(
+    PackLimits {
+      window_size: 64 * 1024,
+      limit: 1024 * 1024,
+      ..PackLimits::default()
+    },
+    PackLimits::from_config(&config).unwrap()
+  );
+  let config = Config::from_bytes("[core]\n\tpackedGitLimit = -1\n").unwrap();
+  assert!(PackLimits::from_config(&config).is_err());
+}
+
+#[test]
+fn large_offsets() {
+  let tmp_dir = tempdir::TempDir::new("pack_test").unwrap();
+  let entries = [
+    (None, RawObject::new(ObjectKind::Blob, b"first".to_vec())),
+    (None, RawObject::new(ObjectKind::Blob, b"second".to_vec())),
+  ];
+  let oids = write_test_pack(tmp_dir.path(), "large", &entries);
+  let idx_path = tmp_dir.path().join("pack-large.idx");
+  let idx = fs::read(&idx_path).unwrap();
+  // Move both offsets into the table of 8 byte offsets
+  let small = 8 + 256 * 4 + 2 * 24;
+  let mut large = idx[..small].to_vec();
+  large.extend_from_slice(&LARGE_OFFSET.to_be_bytes());
+  large.extend_from_slice(&(LARGE_OFFSET | 1).to_be_bytes());
+  for i in 0..2 {
+    large.extend_from_slice(&u64::from(read_u32(&idx[small + i * 4..])).to_be_bytes());
+  }
+  large.extend_from_slice(&idx[small + 8..]);
+  fs::write(&idx_path, &large).unwrap();
+  let packs = PackSet::new(tmp_dir.path().into(), PackLimits::default());
+  let budget = MemoryBudget::unlimited();
+  for oid in &oids {
+    assert_eq!(*oid, packs.read(oid, &budget).unwrap().unwrap().id());
+  }
+
+  // An 8 byte offset far past the end of the table
+  large[small + 4..][..4].copy_from_slice(&(LARGE_OFFSET | 0x7fff_ffff).to_be_bytes());
+  fs::write(&idx_path, &large).unwrap();
+  let packs = PackSet::new(tmp_dir.path().into(), PackLimits::default());
+  for oid in &oids {
+    assert!(matches!(
+      packs.read(oid, &budget),
+      Err(PackError::Malformed(_))
+    ));
+  }
+
+  // A count that doesn't fit in the index is caught before anything is
+  // read from the tables
+  large[8 + 255 * 4..][..4].copy_from_slice(&u32::MAX.to_be_bytes());
+  fs::write(&idx_path, &large).unwrap();
+  let packs = PackSet::new(tmp_dir.path().into(), PackLimits::default());
+  assert!(matches!(
+    packs.read(&oids[0], &budget),
+    Err(PackError::Malformed("index is too short"))
+  ));
+}
+
+#[test]
+fn build_and_delta() {
+  use crate::delta::make_delta;
+  let budget = MemoryBudget::unlimited();
+  let big: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+  let mut edited = big.clone();
+  edited.splice(70_000..70_010, vec![7; 300]);
+  for (base, target) in [
+    (&b""[..], &b""[..]),
+    (b"hello world", b"hello there world"),
+    (b"same", b"same"),
+    (b"abc", b"xyz"),
+    (&big[..], &edited[..]),
+    (&big[..], &big[1..]),
+  ] {
+    let delta = make_delta(base, target);
+    assert_eq!(target, &apply_delta(base, &delta, &budget).unwrap().0[..]);
+  }
+  assert!(make_delta(&big, &edited).len() < 400);
+
+  let base = RawObject::new(ObjectKind::Blob, "hello world");
+  let target = RawObject::new(ObjectKind::Blob, "hello there world");
+  let delta = make_delta(&base.data, &target.data);
+  let whole = build_pack(&[
+    PackObject::Whole(base.clone()),
+    PackObject::RefDelta {
+      base: base.id(),
+      delta: delta.clone(),
+    },
+  ]);
+  let objects = parse_pack(&whole, &budget).unwrap();
+  assert_eq!(vec![base.clone(), target.clone()], objects);
+  let mut writer = PackWriter::new(Vec::new(), 2).unwrap();
+  let offset = writer.offset();
+  writer.add(&PackObject::Whole(base.clone())).unwrap();
+  writer
+    .add(&PackObject::OfsDelta {
+      base: offset,
+      delta: delta.clone(),
+    })
+    .unwrap();
+  let (ofs, _) = writer.finish().unwrap();
+  assert!(ofs.len() < whole.len());
+  assert_eq!(objects, parse_pack(&ofs, &budget).unwrap());
+  // Without its base the pack is thin and can't be read on its own
+  let thin = build_pack(&[PackObject::RefDelta {
+    base: base.id(),
+    delta,
+  }]);
+  assert!(matches!(
+    parse_pack(&thin, &budget),
+    Err(PackError::MissingBase(_))
+  ));
+}
@@ -0,0 +1,226 @@
+//! Packs kept on disk after they're made so they can be sent again, or the
+//! rest of one sent after a connection broke off. A clone of a big
+//! repository takes long enough to make and to send that starting over
+//! each time is a waste on both sides. A spooled pack is a plain file, so
+//! it can also be handed to a CDN or a static file server and sent to
+//! clients as a `packfile-uris` line of protocol v2 instead.
+
+use crate::{cleanup, OID};
+use std::{
+  fs,
+  io::{self, Read, Seek, SeekFrom, Write},
+  ops::Range,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime},
+};
+
+/// A directory of packs named by the id of what's in them, which
+/// [`UploadPack::spool_pack`][crate::UploadPack::spool_pack] works out
+/// from what was asked for. Packs are written to a temporary file and
+/// renamed into place, so a pack that's there is always whole and two
+/// connections asking for the same pack at once both end up with the
+/// same file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackSpool {
+  dir: PathBuf,
+}
+
+impl PackSpool {
+  /// Create a spool in `dir`, which is made when the first pack is written
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    Self { dir: dir.into() }
+  }
+
+  /// The directory the packs are in
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  fn path(&self, id: &OID) -> PathBuf {
+    self.dir.join(format!("{}.pack", id))
+  }
+
+  /// The pack spooled as `id`, if there is one
+  pub fn get(&self, id: &OID) -> io::Result<Option<SpooledPack>> {
+    match SpooledPack::open(*id, self.path(id)) {
+      Ok(pack) => Ok(Some(pack)),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e),
+    }
+  }
+
+  /// The pack spooled as `id`, written with `write` first if it isn't
+  /// there yet. Whatever `write` wrote is thrown away if it fails.
+  pub fn get_or_write<E>(
+    &self,
+    id: &OID,
+    write: impl FnOnce(&mut fs::File) -> Result<(), E>,
+  ) -> Result<SpooledPack, E>
+  where
+    E: From<io::Error>,
+  {
+    if let Some(pack) = self.get(id)? {
+      return Ok(pack);
+    }
+    fs::create_dir_all(&self.dir)?;
+    let tmp_path = self.dir.join(cleanup::temp_name("pack"));
+    let result = fs::File::create(&tmp_path)
+      .map_err(E::from)
+      .and_then(|mut file| {
+        write(&mut file)?;
+        file.sync_all()?;
+        Ok(())
+      })
+      .and_then(|_| Ok(fs::rename(&tmp_path, self.path(id))?));
+    if let Err(e) = result {
+      let _ = fs::remove_file(&tmp_path);
+      return Err(e);
+    }
+    Ok(SpooledPack::open(*id, self.path(id))?)
+  }
+
+  /// Remove the packs written more than `age` ago, and temporary files
+  /// left behind by writes that never finished, returning how many files
+  /// were removed. Someone still sending a removed pack keeps reading it
+  /// until they're done on unix.
+  pub fn remove_older_than(&self, age: Duration) -> io::Result<usize> {
+    let entries = match fs::read_dir(&self.dir) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+      Err(e) => return Err(e),
+    };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in entries {
+      let entry = entry?;
+      let modified = entry.metadata()?.modified()?;
+      if now.duration_since(modified).unwrap_or_default() <= age {
+        continue;
+      }
+      match fs::remove_file(entry.path()) {
+        Ok(()) => removed += 1,
+        // Someone else cleaned up at the same time
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(removed)
+  }
+}
+
+/// A pack in a [`PackSpool`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpooledPack {
+  id: OID,
+  path: PathBuf,
+  size: u64,
+  checksum: OID,
+}
+
+impl SpooledPack {
+  fn open(id: OID, path: PathBuf) -> io::Result<Self> {
+    let mut file = fs::File::open(&path)?;
+    let size = file.metadata()?.len();
+    // The checksum is the last 20 bytes of every pack
+    let mut checksum = [0; 20];
+    file.seek(SeekFrom::End(-20))?;
+    file.read_exact(&mut checksum)?;
+    Ok(Self {
+      id,
+      path,
+      size,
+      checksum: OID::from_bytes(&checksum).unwrap(),
+    })
+  }
+
+  /// The id the pack is spooled as
+  pub fn id(&self) -> &OID {
+    &self.id
+  }
+
+  /// The file the pack is in, for serving it some other way
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// How many bytes the pack is
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// The checksum at the end of the pack, which git names a pack after
+  /// and checks a pack it got from a `packfile-uris` line against
+  pub fn checksum(&self) -> &OID {
+    &self.checksum
+  }
+
+  /// Copy the bytes in `range` of the pack to `out`, returning how many
+  /// were copied. The range is cut off at the end of the pack.
+  pub fn write_range(&self, range: Range<u64>, out: &mut impl Write) -> io::Result<u64> {
+    let mut file = fs::File::open(&self.path)?;
+    let start = range.start.min(self.size);
+    file.seek(SeekFrom::Start(start))?;
+    io::copy(&mut file.take(range.end.saturating_sub(start)), out)
+  }
+
+  /// The `Content-Range` header of a response sending `range` of the pack
+  pub fn content_range(&self, range: &Range<u64>) -> String {
+    format!(
+      "bytes {}-{}/{}",
+      range.start,
+      range.end.saturating_sub(1),
+      self.size
+    )
+  }
+
+  /// The line of a `packfile-uris` section telling a client to get the
+  /// pack from `uri`
+  pub fn packfile_uri(&self, uri: &str) -> String {
+    format!("{} {}\n", self.checksum, uri)
+  }
+}
+
+/// The bytes of something `size` bytes long a `Range` header like
+/// `bytes=1000-` asks for, which is what picks up a download where it
+/// stopped. `None` if the header asks for anything but one range inside
+/// of it, which is answered with `416 Range Not Satisfiable`.
+pub fn parse_range(header: &str, size: u64) -> Option<Range<u64>> {
+  let spec = header.trim().strip_prefix("bytes=")?;
+  let (start, end) = spec.split_once('-')?;
+  let (start, end) = match (start.trim(), end.trim()) {
+    // The last `end` bytes
+    ("", end) => {
+      let len: u64 = end.parse().ok()?;
+      (size.saturating_sub(len), size)
+    }
+    (start, "") => (start.parse().ok()?, size),
+    (start, end) => {
+      let end: u64 = end.parse().ok()?;
+      (start.parse().ok()?, end.saturating_add(1).min(size))
+    }
+  };
+  if start >= end {
+    return None;
+  }
+  Some(start..end)
+}
+
+#[test]
+fn ranges() {
+  assert_eq!(Some(0..100), parse_range("bytes=0-", 100));
+  assert_eq!(Some(40..100), parse_range("bytes=40-", 100));
+  assert_eq!(Some(10..21), parse_range("bytes=10-20", 100));
+  assert_eq!(Some(90..100), parse_range("bytes=90-1000", 100));
+  assert_eq!(Some(70..100), parse_range("bytes=-30", 100));
+  assert_eq!(Some(0..100), parse_range("bytes=-300", 100));
+  for header in [
+    "bytes=100-",
+    "bytes=20-10",
+    "bytes=0-1,5-6",
+    "items=0-1",
+    "bytes=x-",
+    "bytes=-0",
+  ] {
+    assert_eq!(None, parse_range(header, 100), "{}", header);
+  }
+}
@@ -0,0 +1,671 @@
+use crate::{
+  diff_blobs, Blob, Change, ChangeKind, Config, ConfigError, DiffError, DiffFile, DiffOptions,
+  FileMode, Hunk, LineKind, Odb, Repository, OID,
+};
+use bstr::{BString, ByteSlice};
+
+/// Options controlling how [`format_patch`] writes a patch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchOptions {
+  /// How the contents of files are compared
+  pub diff: DiffOptions,
+  /// How many hex digits of object names to show on `index` lines at
+  /// least, as set by `core.abbrev`. `None` picks a length from the number
+  /// of objects. See [`Odb::abbreviate`].
+  pub abbrev: Option<usize>,
+  /// Whether bytes above `0x7f` in paths are escaped like control
+  /// characters are, as set by `core.quotePath`. Defaults to `true`.
+  pub quote_path: bool,
+}
+
+impl Default for PatchOptions {
+  fn default() -> Self {
+    Self {
+      diff: DiffOptions::default(),
+      abbrev: None,
+      quote_path: true,
+    }
+  }
+}
+
+impl PatchOptions {
+  /// Create [`PatchOptions`] from the `diff.*` settings, `core.abbrev`, and
+  /// `core.quotePath` in a [`Config`]
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let abbrev = match config.get_str("core.abbrev")? {
+      None => None,
+      Some(value) => match value.to_ascii_lowercase().as_str() {
+        "auto" => None,
+        "no" | "false" | "off" => Some(40),
+        _ => Some(
+          value
+            .parse::<usize>()
+            .ok()
+            .filter(|len| (4..=40).contains(len))
+            .ok_or_else(|| ConfigError::InvalidValue {
+              key: "core.abbrev".into(),
+              value: value.into(),
+              expected: "auto, no, or a length from 4 to 40",
+            })?,
+        ),
+      },
+    };
+    Ok(Self {
+      diff: DiffOptions::from_config(config)?,
+      abbrev,
+      quote_path: config.get_bool("core.quotepath")?.unwrap_or(true),
+    })
+  }
+}
+
+/// Write the [`Change`]s as a patch the same way `git diff` does, with a
+/// `diff --git` header for every file followed by its [`Hunk`]s. Files with
+/// a NUL byte in their first 8000 bytes are treated as binary and only
+/// said to differ, and changes between a file, a symbolic link, and a
+/// submodule are written as the old one being deleted and the new one
+/// being added. [`ChangeKind::Rewritten`] files are written as all of the
+/// old lines being removed and all of the new ones added.
+pub fn format_patch(
+  odb: &Odb,
+  changes: &[Change],
+  options: &PatchOptions,
+) -> Result<BString, DiffError> {
+  let mut patch = Vec::new();
+  for change in changes {
+    let (old, new) = (change.old_file(), change.new_file());
+    match change.kind() {
+      ChangeKind::TypeChanged => {
+        format_file(odb, old, None, ChangeKind::Deleted, options, &mut patch)?;
+        format_file(odb, None, new, ChangeKind::Added, options, &mut patch)?;
+      }
+      kind => format_file(odb, old, new, kind, options, &mut patch)?,
+    }
+  }
+  Ok(patch.into())
+}
+
+impl Repository {
+  /// Write the [`Change`]s as a patch using the [`PatchOptions`] from the
+  /// [`Config`] of the repository. See [`format_patch`].
+  pub fn format_patch(&self, changes: &[Change]) -> Result<BString, DiffError> {
+    format_patch(
+      self.odb(),
+      changes,
+      &PatchOptions::from_config(self.config())?,
+    )
+  }
+}
+
+/// Write [`Hunk`]s from [`diff_blobs`] in the unified diff format, where
+/// `old` is the old blob they were made from. Each `@@` line ends with the
+/// closest line before the hunk that starts with a letter, `_`, or `$`,
+/// which is how git shows the function a hunk is in when there's no
+/// `diff` attribute saying otherwise.
+pub fn format_hunks(old: impl AsRef<[u8]>, hunks: &[Hunk]) -> BString {
+  let lines: Vec<&[u8]> = old.as_ref().lines_with_terminator().collect();
+  let mut out = Vec::new();
+  // Like git the search for a function line stops where the search for the
+  // previous hunk started and keeps the line found then if there's none
+  let (mut function, mut searched): (&[u8], isize) = (b"", -1);
+  for hunk in hunks {
+    let start = if hunk.old_len > 0 {
+      hunk.old_start - 1
+    } else {
+      hunk.old_start
+    } as isize;
+    if let Some(line) = (searched + 1..start)
+      .rev()
+      .find_map(|idx| function_line(lines[idx as usize]))
+    {
+      function = line;
+    }
+    searched = start - 1;
+
+    out.extend_from_slice(b"@@ -");
+    range(hunk.old_start, hunk.old_len, &mut out);
+    out.extend_from_slice(b" +");
+    range(hunk.new_start, hunk.new_len, &mut out);
+    out.extend_from_slice(b" @@");
+    if !function.is_empty() {
+      out.push(b' ');
+      out.extend_from_slice(function);
+    }
+    out.push(b'\n');
+    for line in &hunk.lines {
+      out.push(match line.kind {
+        LineKind::Context => b' ',
+        LineKind::Removed => b'-',
+        LineKind::Added => b'+',
+      });
+      out.extend_from_slice(&line.content);
+      if !line.content.ends_with(b"\n") {
+        out.extend_from_slice(b"\n\\ No newline at end of file\n");
+      }
+    }
+  }
+  out.into()
+}
+
+/// The part of a line shown after a hunk header if it can start a function
+/// going by git's default rule, cut to 80 bytes without trailing whitespace
+fn function_line(line: &[u8]) -> Option<&[u8]> {
+  match line.first() {
+    Some(c) if c.is_ascii_alphabetic() || *c == b'_' || *c == b'$' => {
+      let line = &line[..line.len().min(80)];
+      let len = line
+        .iter()
+        .rposition(|c| !is_space(*c))
+        .map_or(0, |idx| idx + 1);
+      Some(&line[..len])
+    }
+    _ => None,
+  }
+}
+
+/// Whitespace the way C's `isspace` sees it
+fn is_space(c: u8) -> bool {
+  matches!(c, b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r')
+}
+
+/// A side of a hunk header, where the length is left off when it's 1
+fn range(start: usize, len: usize, out: &mut Vec<u8>) {
+  out.extend_from_slice(start.to_string().as_bytes());
+  if len != 1 {
+    out.extend_from_slice(format!(",{}", len).as_bytes());
+  }
+}
+
+/// Write the patch for one file, where a missing side means the file was
+/// added or deleted
+fn format_file(
+  odb: &Odb,
+  old: Option<&DiffFile>,
+  new: Option<&DiffFile>,
+  kind: ChangeKind,
+  options: &PatchOptions,
+  out: &mut Vec<u8>,
+) -> Result<(), DiffError> {
+  // Every change has at least one side
+  let old_path = &old.or(new).unwrap().path;
+  let new_path = &new.or(old).unwrap().path;
+  let quote = |prefix: &[u8], path: &[u8]| quote_path(prefix, path, options.quote_path);
+  let (a, b) = (quote(b"a/", old_path), quote(b"b/", new_path));
+  out.extend_from_slice(b"diff --git ");
+  out.extend_from_slice(&a);
+  out.push(b' ');
+  out.extend_from_slice(&b);
+  out.push(b'\n');
+
+  let mode = |file: &DiffFile| file.mode.as_bytes();
+  match (old, new) {
+    (None, Some(new)) => line(out, &[b"new file mode ", mode(new)]),
+    (Some(old), None) => line(out, &[b"deleted file mode ", mode(old)]),
+    (Some(old), Some(new)) if old.mode != new.mode => {
+      line(out, &[b"old mode ", mode(old)]);
+      line(out, &[b"new mode ", mode(new)]);
+    }
+    _ => {}
+  }
+  let mut moved = |similarity: u8, verb: &[u8]| {
+    line(
+      out,
+      &[format!("similarity index {}%", similarity).as_bytes()],
+    );
+    line(out, &[verb, b" from ", &quote(b"", old_path)]);
+    line(out, &[verb, b" to ", &quote(b"", new_path)]);
+  };
+  match kind {
+    ChangeKind::Renamed(similarity) => moved(similarity, b"rename"),
+    ChangeKind::Copied(similarity) => moved(similarity, b"copy"),
+    ChangeKind::Rewritten(dissimilarity) => line(
+      out,
+      &[format!("dissimilarity index {}%", dissimilarity).as_bytes()],
+    ),
+    _ => {}
+  }
+
+  let oid = |file: Option<&DiffFile>| file.map_or(OID::NULL, |file| file.oid);
+  let (old_oid, new_oid) = (oid(old), oid(new));
+  if old_oid == new_oid {
+    return Ok(());
+  }
+  let old_hex = odb.abbreviate(&old_oid, options.abbrev)?;
+  let new_hex = odb.abbreviate(&new_oid, options.abbrev)?;
+  out.extend_from_slice(format!("index {}..{}", old_hex, new_hex).as_bytes());
+  match (old, new) {
+    (Some(old), Some(new)) if old.mode == new.mode => line(out, &[b" ", mode(old)]),
+    _ => out.push(b'\n'),
+  }
+
+  // The other side of added and deleted files is shown as /dev/null
+  let label = |file: Option<&DiffFile>, quoted: Vec<u8>| match file {
+    Some(_) => quoted,
+    None => b"/dev/null".to_vec(),
+  };
+  let (a, b) = (label(old, a), label(new, b));
+  let (old, new) = (contents(odb, old)?, contents(odb, new)?);
+  if is_binary(old.contents()) || is_binary(new.contents()) {
+    line(out, &[b"Binary files ", &a, b" and ", &b, b" differ"]);
+    return Ok(());
+  }
+  // Names with spaces get a trailing tab so tools can tell where they end
+  let tab = |label: &[u8]| {
+    if label.contains(&b' ') {
+      &b"\t"[..]
+    } else {
+      b""
+    }
+  };
+  if let ChangeKind::Rewritten(_) = kind {
+    line(out, &[b"--- ", &a, tab(&a)]);
+    line(out, &[b"+++ ", &b, tab(&b)]);
+    format_rewrite(old.contents(), new.contents(), out);
+    return Ok(());
+  }
+  let hunks = diff_blobs(old.contents(), new.contents(), &options.diff);
+  if hunks.is_empty() {
+    return Ok(());
+  }
+  line(out, &[b"--- ", &a, tab(&a)]);
+  line(out, &[b"+++ ", &b, tab(&b)]);
+  out.extend_from_slice(&format_hunks(old.contents(), &hunks));
+  Ok(())
+}
+
+/// Write one hunk removing every line of `old` and adding every line of
+/// `new`, without looking for lines they have in common
+fn format_rewrite(old: &[u8], new: &[u8], out: &mut Vec<u8>) {
+  let old_lines: Vec<&[u8]> = old.lines_with_terminator().collect();
+  let new_lines: Vec<&[u8]> = new.lines_with_terminator().collect();
+  let count = |len: usize| match len {
+    0 => "0,0".to_string(),
+    1 => "1".to_string(),
+    len => format!("1,{}", len),
+  };
+  line(
+    out,
+    &[format!(
+      "@@ -{} +{} @@",
+      count(old_lines.len()),
+      count(new_lines.len())
+    )
+    .as_bytes()],
+  );
+  for (prefix, lines) in [(b'-', old_lines), (b'+', new_lines)] {
+    for content in lines {
+      out.push(prefix);
+      out.extend_from_slice(content);
+      if !content.ends_with(b"\n") {
+        out.extend_from_slice(b"\n\\ No newline at end of file\n");
+      }
+    }
+  }
+}
+
+fn line(out: &mut Vec<u8>, parts: &[&[u8]]) {
+  for part in parts {
+    out.extend_from_slice(part);
+  }
+  out.push(b'\n');
+}
+
+/// The contents of one side of a [`Change`], with a submodule showing up as
+/// the commit it points at like git does
+fn contents(odb: &Odb, file: Option<&DiffFile>) -> Result<Blob, DiffError> {
+  Ok(match file {
+    None => Blob::new(Vec::new()),
+    Some(file) if file.mode == FileMode::GitLink => {
+      Blob::new(format!("Subproject commit {}\n", file.oid.as_hex()))
+    }
+    Some(file) => odb.read_blob(&file.oid)?,
+  })
+}
+
+/// Whether git would treat the contents as binary, which it does when there
+/// is a NUL byte in the first 8000 bytes
+pub(crate) fn is_binary(contents: &[u8]) -> bool {
+  contents[..contents.len().min(8000)].contains(&0)
+}
+
+/// `prefix` followed by `path`, quoted and escaped like C strings the way
+/// git writes paths if `path` has control characters, `"`, or `\` in it,
+/// and also if it has bytes above `0x7f` when `quote_high` is set
+pub(crate) fn quote_path(prefix: &[u8], path: &[u8], quote_high: bool) -> Vec<u8> {
+  let needs_quote =
+    |c: u8| c < 0x20 || c == b'"' || c == b'\\' || c == 0x7f || (c >= 0x80 && quote_high);
+  if !path.iter().any(|c| needs_quote(*c)) {
+    return [prefix, path].concat();
+  }
+  let mut quoted = vec![b'"'];
+  quoted.extend_from_slice(prefix);
+  for &c in path {
+    if !needs_quote(c) {
+      quoted.push(c);
+      continue;
+    }
+    quoted.push(b'\\');
+    match c {
+      b'\x07' => quoted.push(b'a'),
+      b'\x08' => quoted.push(b'b'),
+      b'\t' => quoted.push(b't'),
+      b'\n' => quoted.push(b'n'),
+      b'\x0b' => quoted.push(b'v'),
+      b'\x0c' => quoted.push(b'f'),
+      b'\r' => quoted.push(b'r'),
+      b'"' | b'\\' => quoted.push(c),
+      _ => quoted.extend_from_slice(format!("{:03o}", c).as_bytes()),
+    }
+  }
+  quoted.push(b'"');
+  quoted
+}
+
+/// Undo [`quote_path`] for a quoted path at the start of `line`, returning
+/// the path and how many bytes of `line` it took up. `None` if `line`
+/// doesn't start with a properly quoted path.
+pub(crate) fn unquote_path(line: &[u8]) -> Option<(Vec<u8>, usize)> {
+  if line.first() != Some(&b'"') {
+    return None;
+  }
+  let mut path = Vec::new();
+  let mut pos = 1;
+  loop {
+    let c = *line.get(pos)?;
+    pos += 1;
+    match c {
+      b'"' => return Some((path, pos)),
+      b'\\' => {
+        let c = *line.get(pos)?;
+        pos += 1;
+        path.push(match c {
+          b'a' => b'\x07',
+          b'b' => b'\x08',
+          b't' => b'\t',
+          b'n' => b'\n',
+          b'v' => b'\x0b',
+          b'f' => b'\x0c',
+          b'r' => b'\r',
+          b'"' | b'\\' => c,
+          b'0'..=b'3' => {
+            let digits = line.get(pos..pos + 2)?;
+            pos += 2;
+            [c, digits[0], digits[1]]
+              .iter()
+              .try_fold(0u8, |value, &digit| match digit {
+                b'0'..=b'7' => Some(value * 8 + (digit - b'0')),
+                _ => None,
+              })?
+          }
+          _ => return None,
+        });
+      }
+      c => path.push(c),
+    }
+  }
+}
+
+#[test]
+fn patch() {
+  use crate::diff::write_tree;
+  use FileMode::*;
+  let tmp_dir = tempdir::TempDir::new("patch_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let function = "fn main() {\n  one();\n  two();\n  three();\n  four();\n}\n";
+  let old = write_tree(
+    &odb,
+    &[
+      ("README", NonExecutableFile, "a\nb\nc\n"),
+      ("bin", NonExecutableFile, "x\0y"),
+      ("del", NonExecutableFile, "gone\n"),
+      ("mode.sh", NonExecutableFile, "echo\n"),
+      ("sp ace", NonExecutableFile, "s\n"),
+      ("tést", NonExecutableFile, function),
+    ],
+  );
+  let new = write_tree(
+    &odb,
+    &[
+      ("README", NonExecutableFile, "a\nB\nc"),
+      ("bin", NonExecutableFile, "x\0z"),
+      ("mode.sh", ExecutableFile, "echo\n"),
+      ("new", NonExecutableFile, ""),
+      ("sp ace", NonExecutableFile, "s2\n"),
+      ("tést", NonExecutableFile, &function.replace("four", "FOUR")),
+    ],
+  );
+  let changes = crate::diff_trees(&odb, Some(&old), Some(&new)).unwrap();
+  let patch = format_patch(&odb, &changes, &PatchOptions::default()).unwrap();
+  // The same as `git diff` shows for these files
+  assert_eq!(
+    concat!(
+      "diff --git a/README b/README\n",
+      "index de98044..36ef1ba 100644\n",
+      "--- a/README\n",
+      "+++ b/README\n",
+      "@@ -1,3 +1,3 @@\n",
+      " a\n",
+      "-b\n",
+      "-c\n",
+      "+B\n",
+      "+c\n",
+      "\\ No newline at end of file\n",
+      "diff --git a/bin b/bin\n",
+      "index d5d0b8b..4a27031 100644\n",
+      "Binary files a/bin and b/bin differ\n",
+      "diff --git a/del b/del\n",
+      "deleted file mode 100644\n",
+      "index 286c5f5..0000000\n",
+      "--- a/del\n",
+      "+++ /dev/null\n",
+      "@@ -1 +0,0 @@\n",
+      "-gone\n",
+      "diff --git a/mode.sh b/mode.sh\n",
+      "old mode 100644\n",
+      "new mode 100755\n",
+      "diff --git a/new b/new\n",
+      "new file mode 100644\n",
+      "index 0000000..e69de29\n",
+      "diff --git a/sp ace b/sp ace\n",
+      "index b478595..5e28b27 100644\n",
+      "--- a/sp ace\t\n",
+      "+++ b/sp ace\t\n",
+      "@@ -1 +1 @@\n",
+      "-s\n",
+      "+s2\n",
+      "diff --git \"a/t\\303\\251st\" \"b/t\\303\\251st\"\n",
+      "index 14ca780..4530241 100644\n",
+      "--- \"a/t\\303\\251st\"\n",
+      "+++ \"b/t\\303\\251st\"\n",
+      "@@ -2,5 +2,5 @@ fn main() {\n",
+      "   one();\n",
+      "   two();\n",
+      "   three();\n",
+      "-  four();\n",
+      "+  FOUR();\n",
+      " }\n",
+    ),
+    patch
+  );
+  let unquoted = PatchOptions {
+    quote_path: false,
+    ..PatchOptions::default()
+  };
+  let patch = format_patch(&odb, &changes[changes.len() - 1..], &unquoted).unwrap();
+  assert!(patch.starts_with("diff --git a/tést b/tést\n".as_bytes()));
+}
+
+#[test]
+fn patch_renames_and_type_changes() {
+  let tmp_dir = tempdir::TempDir::new("patch_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let file = |path: &str, mode, contents: &str| DiffFile {
+    path: path.into(),
+    mode,
+    oid: odb.write_blob(&Blob::new(contents)).unwrap(),
+  };
+  let renamed = Change::between(
+    ChangeKind::Renamed(85),
+    file(
+      "ren2",
+      FileMode::NonExecutableFile,
+      "one\ntwo\nthree\nfour\nfive\nsix\n",
+    ),
+    file(
+      "rén 3",
+      FileMode::ExecutableFile,
+      "one\ntwo\nthree\nfour\nfive\nSIX\n",
+    ),
+  );
+  let link = Change::between(
+    ChangeKind::TypeChanged,
+    file("link", FileMode::NonExecutableFile, "x"),
+    file("link", FileMode::SymbolicLink, "f"),
+  );
+  let patch = format_patch(&odb, &[renamed, link], &PatchOptions::default()).unwrap();
+  assert_eq!(
+    concat!(
+      "diff --git a/ren2 \"b/r\\303\\251n 3\"\n",
+      "old mode 100644\n",
+      "new mode 100755\n",
+      "similarity index 85%\n",
+      "rename from ren2\n",
+      "rename to \"r\\303\\251n 3\"\n",
+      "index b566061..8767b06\n",
+      "--- a/ren2\n",
+      "+++ \"b/r\\303\\251n 3\"\t\n",
+      "@@ -3,4 +3,4 @@ two\n",
+      " three\n",
+      " four\n",
+      " five\n",
+      "-six\n",
+      "+SIX\n",
+      "diff --git a/link b/link\n",
+      "deleted file mode 100644\n",
+      "index c1b0730..0000000\n",
+      "--- a/link\n",
+      "+++ /dev/null\n",
+      "@@ -1 +0,0 @@\n",
+      "-x\n",
+      "\\ No newline at end of file\n",
+      "diff --git a/link b/link\n",
+      "new file mode 120000\n",
+      "index 0000000..4d1ae35\n",
+      "--- /dev/null\n",
+      "+++ b/link\n",
+      "@@ -0,0 +1 @@\n",
+      "+f\n",
+      "\\ No newline at end of file\n",
+    ),
+    patch
+  );
+}
+
+#[test]
+fn quoting() {
+  assert_eq!(b"a/plain".to_vec(), quote_path(b"a/", b"plain", true));
+  assert_eq!(
+    b"\"a/tab\\there \\\"q\\\" \\\\ \\001\\177\"".to_vec(),
+    quote_path(b"a/", b"tab\there \"q\" \\ \x01\x7f", true)
+  );
+  assert_eq!(
+    b"\"\\303\\251\"".to_vec(),
+    quote_path(b"", "é".as_bytes(), true)
+  );
+  assert_eq!("é".as_bytes(), &quote_path(b"", "é".as_bytes(), false)[..]);
+
+  let path = b"tab\there \"q\" \\ \x01\x7f\xc3\xa9";
+  let quoted = quote_path(b"", path, true);
+  assert_eq!(
+    Some((path.to_vec(), quoted.len())),
+    unquote_path(&[&quoted[..], b" rest"].concat())
+  );
+  assert_eq!(None, unquote_path(b"\"unterminated"));
+  assert_eq!(None, unquote_path(b"\"\\400\""));
+}
+
+#[test]
+fn patch_options_from_config() {
+  let config = Config::from_bytes("[core]\n\tabbrev = 12\n\tquotePath = false").unwrap();
+  let options = PatchOptions::from_config(&config).unwrap();
+  assert_eq!(Some(12), options.abbrev);
+  assert!(!options.quote_path);
+  let config = Config::from_bytes("[core]\n\tabbrev = no").unwrap();
+  assert_eq!(Some(40), PatchOptions::from_config(&config).unwrap().abbrev);
+  let config = Config::from_bytes("[core]\n\tabbrev = 3").unwrap();
+  assert!(PatchOptions::from_config(&config).is_err());
+}
+
+#[test]
+fn patch_copies_and_rewrites() {
+  use crate::{detect_renames, diff::write_tree, RenameDetection, RenameOptions, RewriteOptions};
+  use FileMode::*;
+  let tmp_dir = tempdir::TempDir::new("patch_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let big: String = (1..=40).map(|i| format!("old line {}\n", i)).collect();
+  let six = "one\ntwo\nthree\nfour\nfive\nsix\n";
+  let old = write_tree(
+    &odb,
+    &[
+      ("big", NonExecutableFile, &big),
+      ("f", NonExecutableFile, six),
+    ],
+  );
+  let new = write_tree(
+    &odb,
+    &[
+      ("big", NonExecutableFile, "new\nlast"),
+      ("cp", NonExecutableFile, &six.replace("six", "SIX")),
+      ("f", NonExecutableFile, &(six.to_string() + "seven\n")),
+    ],
+  );
+  let options = RenameOptions {
+    detection: RenameDetection::Copies,
+    rewrites: Some(RewriteOptions::default()),
+    ..RenameOptions::default()
+  };
+  let changes = crate::diff_trees(&odb, Some(&old), Some(&new)).unwrap();
+  let changes = detect_renames(&odb, changes, &options).unwrap();
+  let patch = format_patch(&odb, &changes, &PatchOptions::default()).unwrap();
+  let removed: String = big.lines().map(|line| format!("-{}\n", line)).collect();
+  // The same as `git diff -B -C` shows for these files
+  assert_eq!(
+    format!(
+      concat!(
+        "diff --git a/big b/big\n",
+        "dissimilarity index 100%\n",
+        "index 29468f8..e306a68 100644\n",
+        "--- a/big\n",
+        "+++ b/big\n",
+        "@@ -1,40 +1,2 @@\n",
+        "{}",
+        "+new\n",
+        "+last\n",
+        "\\ No newline at end of file\n",
+        "diff --git a/f b/cp\n",
+        "similarity index 85%\n",
+        "copy from f\n",
+        "copy to cp\n",
+        "index b566061..8767b06 100644\n",
+        "--- a/f\n",
+        "+++ b/cp\n",
+        "@@ -3,4 +3,4 @@ two\n",
+        " three\n",
+        " four\n",
+        " five\n",
+        "-six\n",
+        "+SIX\n",
+        "diff --git a/f b/f\n",
+        "index b566061..2019eda 100644\n",
+        "--- a/f\n",
+        "+++ b/f\n",
+        "@@ -4,3 +4,4 @@ three\n",
+        " four\n",
+        " five\n",
+        " six\n",
+        "+seven\n",
+      ),
+      removed
+    ),
+    patch
+  );
+}
@@ -0,0 +1,535 @@
+//! The pkt-line framing every git wire protocol is built on. Each packet
+//! starts with its length, including the length itself, as four hex digits.
+//! The lengths `0000`, `0001`, and `0002` can't be data packets and are used
+//! as markers instead.
+//!
+//! [`PktLineReader`] and [`PktLineWriter`] read and write packets on a
+//! stream, and with the `async` feature `AsyncPktLineReader` and
+//! `AsyncPktLineWriter` do the same on a tokio stream. [`PacketDecoder`]
+//! doesn't do any reading itself but is fed bytes as they come, so it works
+//! the same whatever they're read with, like another async runtime.
+
+use bstr::{BStr, BString};
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+/// One packet of a pkt-line stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Packet<'a> {
+  /// The bytes of a data packet without the length
+  Data(&'a BStr),
+  /// `0000`, the end of a message
+  Flush,
+  /// `0001`, the end of a section of a protocol v2 message
+  Delim,
+  /// `0002`, the end of a protocol v2 response for stateless connections
+  ResponseEnd,
+}
+
+impl<'a> Packet<'a> {
+  /// The most bytes a packet can take up, including the length
+  pub const MAX_LEN: usize = 65520;
+
+  /// Decode the packet at the start of `bytes`, returning it and how many
+  /// bytes it took up, or `None` if `bytes` doesn't hold all of it yet
+  pub fn decode(bytes: &'a [u8]) -> Result<Option<(Self, usize)>, PktLineError> {
+    let header = match bytes.get(..4) {
+      Some(header) => header,
+      None => return Ok(None),
+    };
+    let len = header.iter().try_fold(0, |len, &byte| {
+      let digit = (byte as char).to_digit(16)?;
+      Some(len << 4 | digit as usize)
+    });
+    let packet = match len {
+      Some(0) => Self::Flush,
+      Some(1) => Self::Delim,
+      Some(2) => Self::ResponseEnd,
+      Some(len @ 4..=Self::MAX_LEN) => match bytes.get(4..len) {
+        Some(data) => return Ok(Some((Self::Data(data.into()), len))),
+        None => return Ok(None),
+      },
+      Some(len) if len > Self::MAX_LEN => return Err(PktLineError::TooLong(len)),
+      _ => return Err(PktLineError::InvalidLength(header.into())),
+    };
+    Ok(Some((packet, 4)))
+  }
+
+  /// Append the packet with its length to `out`
+  pub fn encode(&self, out: &mut Vec<u8>) -> Result<(), PktLineError> {
+    match self {
+      Self::Data(data) => {
+        let len = data.len() + 4;
+        if len > Self::MAX_LEN {
+          return Err(PktLineError::TooLong(len));
+        }
+        out.extend_from_slice(format!("{:04x}", len).as_bytes());
+        out.extend_from_slice(data);
+      }
+      Self::Flush => out.extend_from_slice(b"0000"),
+      Self::Delim => out.extend_from_slice(b"0001"),
+      Self::ResponseEnd => out.extend_from_slice(b"0002"),
+    }
+    Ok(())
+  }
+}
+
+/// Reads packets from a stream one at a time, only reading as far as the
+/// end of each so whatever comes after the pkt-lines, like a pack, is left
+/// for the stream's next reader
+#[derive(Debug)]
+pub struct PktLineReader<R> {
+  inner: R,
+  buf: Vec<u8>,
+}
+
+impl<R: Read> PktLineReader<R> {
+  /// Read packets from `inner`
+  pub fn new(inner: R) -> Self {
+    Self {
+      inner,
+      buf: Vec::new(),
+    }
+  }
+
+  /// Read the next packet, or `None` if the stream ended before it started
+  pub fn read_packet(&mut self) -> Result<Option<Packet<'_>>, PktLineError> {
+    self.buf.resize(4, 0);
+    let mut read = 0;
+    while read < 4 {
+      match self.inner.read(&mut self.buf[read..]) {
+        Ok(0) if read == 0 => return Ok(None),
+        Ok(0) => return Err(PktLineError::Truncated),
+        Ok(n) => read += n,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+        Err(e) => return Err(e.into()),
+      }
+    }
+    if let Some(len) = data_len(&self.buf)? {
+      self.buf.resize(len, 0);
+      self
+        .inner
+        .read_exact(&mut self.buf[4..])
+        .map_err(truncated)?;
+    }
+    Ok(Packet::decode(&self.buf)?.map(|(packet, _)| packet))
+  }
+
+  /// The stream being read
+  pub fn get_ref(&self) -> &R {
+    &self.inner
+  }
+
+  /// The stream being read, to read what comes after the packets
+  pub fn into_inner(self) -> R {
+    self.inner
+  }
+}
+
+/// The length of the data packet whose four byte header is in `header`, or
+/// `None` if it's a marker that's all there already
+fn data_len(header: &[u8]) -> Result<Option<usize>, PktLineError> {
+  if Packet::decode(header)?.is_some() {
+    return Ok(None);
+  }
+  // Decoding checked the length already
+  let len = std::str::from_utf8(header).unwrap();
+  Ok(Some(usize::from_str_radix(len, 16).unwrap()))
+}
+
+/// A stream ending in the middle of a packet is [`PktLineError::Truncated`]
+fn truncated(e: io::Error) -> PktLineError {
+  if e.kind() == io::ErrorKind::UnexpectedEof {
+    PktLineError::Truncated
+  } else {
+    e.into()
+  }
+}
+
+/// Writes packets to a stream. Writing to it as an [`io::Write`] sends the
+/// bytes as data packets, split up where they're longer than a packet can
+/// be, which is how a pack goes out on a side-band.
+#[derive(Debug)]
+pub struct PktLineWriter<W> {
+  inner: W,
+}
+
+impl<W: Write> PktLineWriter<W> {
+  /// Write packets to `inner`
+  pub fn new(inner: W) -> Self {
+    Self { inner }
+  }
+
+  /// Write `packet`
+  pub fn write_packet(&mut self, packet: &Packet<'_>) -> Result<(), PktLineError> {
+    let mut bytes = Vec::new();
+    packet.encode(&mut bytes)?;
+    self.inner.write_all(&bytes)?;
+    Ok(())
+  }
+
+  /// The stream being written to
+  pub fn get_ref(&self) -> &W {
+    &self.inner
+  }
+
+  /// The stream being written to
+  pub fn into_inner(self) -> W {
+    self.inner
+  }
+}
+
+impl<W: Write> Write for PktLineWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let len = buf.len().min(Packet::MAX_LEN - 4);
+    if len == 0 {
+      return Ok(0);
+    }
+    self
+      .write_packet(&Packet::Data(buf[..len].into()))
+      .map_err(|e| match e {
+        PktLineError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidInput, e),
+      })?;
+    Ok(len)
+  }
+
+  /// Flushes the stream, which doesn't write a flush packet
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// [`PktLineReader`] for a tokio stream, which also only reads as far as the
+/// end of each packet
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncPktLineReader<R> {
+  inner: R,
+  buf: Vec<u8>,
+}
+
+#[cfg(feature = "async")]
+impl<R: tokio::io::AsyncRead + Unpin> AsyncPktLineReader<R> {
+  /// Read packets from `inner`
+  pub fn new(inner: R) -> Self {
+    Self {
+      inner,
+      buf: Vec::new(),
+    }
+  }
+
+  /// Read the next packet, or `None` if the stream ended before it started
+  pub async fn read_packet(&mut self) -> Result<Option<Packet<'_>>, PktLineError> {
+    use tokio::io::AsyncReadExt;
+    self.buf.resize(4, 0);
+    let mut read = 0;
+    while read < 4 {
+      match self.inner.read(&mut self.buf[read..]).await {
+        Ok(0) if read == 0 => return Ok(None),
+        Ok(0) => return Err(PktLineError::Truncated),
+        Ok(n) => read += n,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+        Err(e) => return Err(e.into()),
+      }
+    }
+    if let Some(len) = data_len(&self.buf)? {
+      self.buf.resize(len, 0);
+      self
+        .inner
+        .read_exact(&mut self.buf[4..])
+        .await
+        .map_err(truncated)?;
+    }
+    Ok(Packet::decode(&self.buf)?.map(|(packet, _)| packet))
+  }
+
+  /// The stream being read
+  pub fn get_ref(&self) -> &R {
+    &self.inner
+  }
+
+  /// The stream being read, to read what comes after the packets
+  pub fn into_inner(self) -> R {
+    self.inner
+  }
+}
+
+/// [`PktLineWriter`] for a tokio stream
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncPktLineWriter<W> {
+  inner: W,
+}
+
+#[cfg(feature = "async")]
+impl<W: tokio::io::AsyncWrite + Unpin> AsyncPktLineWriter<W> {
+  /// Write packets to `inner`
+  pub fn new(inner: W) -> Self {
+    Self { inner }
+  }
+
+  /// Write `packet`
+  pub async fn write_packet(&mut self, packet: &Packet<'_>) -> Result<(), PktLineError> {
+    use tokio::io::AsyncWriteExt;
+    let mut bytes = Vec::new();
+    packet.encode(&mut bytes)?;
+    self.inner.write_all(&bytes).await?;
+    Ok(())
+  }
+
+  /// Write `bytes` as data packets, split up where they're longer than a
+  /// packet can be, like [`PktLineWriter`] does when it's written to
+  pub async fn write_data(&mut self, bytes: &[u8]) -> Result<(), PktLineError> {
+    for chunk in bytes.chunks(Packet::MAX_LEN - 4) {
+      self.write_packet(&Packet::Data(chunk.into())).await?;
+    }
+    Ok(())
+  }
+
+  /// Flushes the stream, which doesn't write a flush packet
+  pub async fn flush(&mut self) -> Result<(), PktLineError> {
+    use tokio::io::AsyncWriteExt;
+    self.inner.flush().await?;
+    Ok(())
+  }
+
+  /// The stream being written to
+  pub fn get_ref(&self) -> &W {
+    &self.inner
+  }
+
+  /// The stream being written to
+  pub fn into_inner(self) -> W {
+    self.inner
+  }
+}
+
+/// Decodes packets from bytes handed to it as they arrive, holding on to a
+/// packet that's only partly there until the rest comes
+#[derive(Debug, Clone, Default)]
+pub struct PacketDecoder {
+  buf: Vec<u8>,
+  start: usize,
+}
+
+impl PacketDecoder {
+  /// A decoder without any bytes yet
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Add bytes that were read
+  pub fn feed(&mut self, bytes: &[u8]) {
+    // What was decoded already is dropped before the buffer grows
+    self.buf.drain(..self.start);
+    self.start = 0;
+    self.buf.extend_from_slice(bytes);
+  }
+
+  /// The next packet, or `None` if it isn't all there yet
+  pub fn next_packet(&mut self) -> Result<Option<Packet<'_>>, PktLineError> {
+    match Packet::decode(&self.buf[self.start..])? {
+      Some((packet, len)) => {
+        self.start += len;
+        Ok(Some(packet))
+      }
+      None => Ok(None),
+    }
+  }
+
+  /// The bytes fed that aren't part of a packet decoded yet, which are
+  /// what comes after the packets once they're done
+  pub fn remaining(&self) -> &[u8] {
+    &self.buf[self.start..]
+  }
+
+  /// Check that a stream that ended didn't end in the middle of a packet
+  pub fn finish(&self) -> Result<(), PktLineError> {
+    if self.remaining().is_empty() {
+      Ok(())
+    } else {
+      Err(PktLineError::Truncated)
+    }
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to reading and writing pkt-lines
+pub enum PktLineError {
+  #[error("invalid pkt-line length {0:?}")]
+  InvalidLength(BString),
+  #[error("pkt-line is {0} bytes long which is more than the limit of 65520")]
+  TooLong(usize),
+  #[error("pkt-line stream ended in the middle of a packet")]
+  Truncated,
+  #[error("{0}")]
+  Io(#[from] io::Error),
+}
+
+#[test]
+fn decode() {
+  let data = |bytes: &'static str| Packet::Data(bytes.into());
+  assert_eq!(
+    Some((data("want\n"), 9)),
+    Packet::decode(b"0009want\nrest").unwrap()
+  );
+  assert_eq!(Some((data(""), 4)), Packet::decode(b"0004").unwrap());
+  assert_eq!(Some((Packet::Flush, 4)), Packet::decode(b"0000").unwrap());
+  assert_eq!(Some((Packet::Delim, 4)), Packet::decode(b"0001").unwrap());
+  assert_eq!(
+    Some((Packet::ResponseEnd, 4)),
+    Packet::decode(b"0002").unwrap()
+  );
+  assert_eq!(Some((data("A"), 5)), Packet::decode(b"0005A").unwrap());
+  assert_eq!(Some((data("a"), 5)), Packet::decode(b"0005a").unwrap());
+  // Not all there yet
+  assert_eq!(None, Packet::decode(b"00").unwrap());
+  assert_eq!(None, Packet::decode(b"000ahello").unwrap());
+
+  assert!(matches!(
+    Packet::decode(b"0003"),
+    Err(PktLineError::InvalidLength(_))
+  ));
+  assert!(matches!(
+    Packet::decode(b"00x9want"),
+    Err(PktLineError::InvalidLength(_))
+  ));
+  assert!(matches!(
+    Packet::decode(b"fff1"),
+    Err(PktLineError::TooLong(0xfff1))
+  ));
+}
+
+#[test]
+fn encode() {
+  let mut out = Vec::new();
+  for packet in [
+    Packet::Data("want\n".into()),
+    Packet::Delim,
+    Packet::Data("".into()),
+    Packet::Flush,
+    Packet::ResponseEnd,
+  ] {
+    packet.encode(&mut out).unwrap();
+  }
+  assert_eq!(b"0009want\n0001000400000002"[..], out[..]);
+  let long = vec![b'a'; Packet::MAX_LEN - 4];
+  Packet::Data(long[..].into()).encode(&mut out).unwrap();
+  assert!(matches!(
+    Packet::Data(b"a".repeat(Packet::MAX_LEN - 3)[..].into()).encode(&mut out),
+    Err(PktLineError::TooLong(65521))
+  ));
+}
+
+#[test]
+fn streams() {
+  let data = |bytes: &'static str| Packet::Data(bytes.into());
+  let mut writer = PktLineWriter::new(Vec::new());
+  writer.write_packet(&data("want\n")).unwrap();
+  writer.write_packet(&Packet::Delim).unwrap();
+  writer.write_all(&vec![b'p'; Packet::MAX_LEN]).unwrap();
+  writer.write_packet(&Packet::Flush).unwrap();
+  let mut bytes = writer.into_inner();
+  // The last four bytes didn't fit in the first packet
+  assert_eq!(9 + 4 + Packet::MAX_LEN + 8 + 4, bytes.len());
+  bytes.extend_from_slice(b"PACK");
+
+  // Read a byte at a time to see packets put together across reads
+  struct Trickle<'a>(&'a [u8]);
+  impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      let n = self.0.len().min(buf.len()).min(1);
+      buf[..n].copy_from_slice(&self.0[..n]);
+      self.0 = &self.0[n..];
+      Ok(n)
+    }
+  }
+  let mut reader = PktLineReader::new(Trickle(&bytes));
+  assert_eq!(Some(data("want\n")), reader.read_packet().unwrap());
+  assert_eq!(Some(Packet::Delim), reader.read_packet().unwrap());
+  let long = reader.read_packet().unwrap().unwrap();
+  assert!(matches!(long, Packet::Data(data) if data.len() == Packet::MAX_LEN - 4));
+  assert_eq!(Some(data("pppp")), reader.read_packet().unwrap());
+  assert_eq!(Some(Packet::Flush), reader.read_packet().unwrap());
+  let mut rest = Vec::new();
+  reader.into_inner().read_to_end(&mut rest).unwrap();
+  assert_eq!(b"PACK"[..], rest[..]);
+
+  let mut reader = PktLineReader::new(&b"0009want\n"[..]);
+  reader.read_packet().unwrap();
+  assert_eq!(None, reader.read_packet().unwrap());
+  for truncated in [&b"00"[..], b"0009wa"] {
+    assert!(matches!(
+      PktLineReader::new(truncated).read_packet(),
+      Err(PktLineError::Truncated)
+    ));
+  }
+
+  let mut decoder = PacketDecoder::new();
+  let mut packets = 0;
+  let mut chunks = bytes.chunks(7);
+  'decode: for chunk in &mut chunks {
+    decoder.feed(chunk);
+    while let Some(packet) = decoder.next_packet().unwrap() {
+      packets += 1;
+      if packet == Packet::Flush {
+        break 'decode;
+      }
+    }
+  }
+  assert_eq!(5, packets);
+  // Whatever came after the flush in the same read is kept
+  let mut rest = decoder.remaining().to_vec();
+  chunks.for_each(|chunk| rest.extend_from_slice(chunk));
+  assert_eq!(b"PACK"[..], rest[..]);
+  decoder.feed(b"00");
+  assert!(matches!(decoder.finish(), Err(PktLineError::Truncated)));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_streams() {
+  use tokio::io::AsyncReadExt;
+  let data = |bytes: &'static str| Packet::Data(bytes.into());
+  let runtime = tokio::runtime::Builder::new_current_thread()
+    .build()
+    .unwrap();
+  runtime.block_on(async {
+    let mut writer = AsyncPktLineWriter::new(Vec::new());
+    writer.write_packet(&data("want\n")).await.unwrap();
+    writer.write_packet(&Packet::Delim).await.unwrap();
+    writer
+      .write_data(&vec![b'p'; Packet::MAX_LEN])
+      .await
+      .unwrap();
+    writer.write_packet(&Packet::Flush).await.unwrap();
+    writer.flush().await.unwrap();
+    let mut bytes = writer.into_inner();
+    assert_eq!(9 + 4 + Packet::MAX_LEN + 8 + 4, bytes.len());
+    bytes.extend_from_slice(b"PACK");
+
+    let mut reader = AsyncPktLineReader::new(&bytes[..]);
+    assert_eq!(Some(data("want\n")), reader.read_packet().await.unwrap());
+    assert_eq!(Some(Packet::Delim), reader.read_packet().await.unwrap());
+    let long = reader.read_packet().await.unwrap().unwrap();
+    assert!(matches!(long, Packet::Data(data) if data.len() == Packet::MAX_LEN - 4));
+    assert_eq!(Some(data("pppp")), reader.read_packet().await.unwrap());
+    assert_eq!(Some(Packet::Flush), reader.read_packet().await.unwrap());
+    let mut rest = Vec::new();
+    AsyncReadExt::read_to_end(&mut reader.into_inner(), &mut rest)
+      .await
+      .unwrap();
+    assert_eq!(b"PACK"[..], rest[..]);
+
+    let mut reader = AsyncPktLineReader::new(&b"0009want\n"[..]);
+    reader.read_packet().await.unwrap();
+    assert_eq!(None, reader.read_packet().await.unwrap());
+    for truncated in [&b"00"[..], b"0009wa"] {
+      assert!(matches!(
+        AsyncPktLineReader::new(truncated).read_packet().await,
+        Err(PktLineError::Truncated)
+      ));
+    }
+  });
+}
@@ -0,0 +1,327 @@
+//! Functions that do the same thing as git's plumbing commands, for code
+//! moving from running `git` to using this crate. Each takes the same
+//! inputs as the command and gives back what it would print, parsed, so
+//! `git rev-parse HEAD^{tree}` becomes `plumbing::rev_parse(&repo,
+//! "HEAD^{tree}")`. They are built on the rest of the crate, which has more
+//! control where it's needed.
+
+use crate::{
+  Commit, ConfigError, FileMode, IndexError, ObjectKind, OdbError, RawObject, RefError, RefTarget,
+  Repository, RevParseError, Signature, SignatureError, Tag, Time, Tree, OID,
+};
+use bstr::{BString, ByteSlice};
+use std::{env, fmt};
+use thiserror::Error;
+
+/// How many symbolic refs [`update_ref`] follows, the same as git
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// Compute the [`OID`] of `data` as an object of the given kind and write
+/// it to the object database if `write` is set, like `git hash-object -t
+/// {kind} [-w] --stdin`. Trees, commits, and tags have to parse.
+pub fn hash_object(
+  repo: &Repository,
+  kind: ObjectKind,
+  data: impl Into<Vec<u8>>,
+  write: bool,
+) -> Result<OID, PlumbingError> {
+  let object = RawObject::new(kind, data);
+  match kind {
+    ObjectKind::Blob => {}
+    ObjectKind::Tree => drop(Tree::parse(&object.data).map_err(OdbError::from)?),
+    ObjectKind::Commit => drop(Commit::parse(&object.data).map_err(OdbError::from)?),
+    ObjectKind::Tag => drop(Tag::parse(&object.data).map_err(OdbError::from)?),
+  }
+  if write {
+    Ok(repo.odb().write(&object)?)
+  } else {
+    Ok(object.id())
+  }
+}
+
+/// Read the object named by a revision, like `git cat-file {kind}
+/// {object}` where the kind is in the returned object
+pub fn cat_file(repo: &Repository, object: &str) -> Result<RawObject, PlumbingError> {
+  let oid = repo.rev_parse(object)?;
+  Ok(repo.odb().read(&oid)?)
+}
+
+/// Point a ref at `new`, like `git update-ref {name} {new} [{old}]`. A
+/// symbolic ref like `HEAD` updates the ref it points at. If `old` is given
+/// the ref has to point at it before, where the all zero [`OID`] means the
+/// ref must not exist yet.
+pub fn update_ref(
+  repo: &Repository,
+  name: &str,
+  new: &OID,
+  old: Option<&OID>,
+) -> Result<(), PlumbingError> {
+  let refs = repo.refs();
+  let mut name = BString::from(name);
+  let mut current = None;
+  for depth in 0.. {
+    if depth > MAX_SYMREF_DEPTH {
+      return Err(RefError::TooDeep(name).into());
+    }
+    match refs
+      .read(&name)?
+      .map(|reference| reference.target().clone())
+    {
+      Some(RefTarget::Symbolic(target)) => name = target,
+      Some(RefTarget::Direct(oid)) => {
+        current = Some(oid);
+        break;
+      }
+      None => break,
+    }
+  }
+  if let Some(old) = old {
+    let expected = Some(*old).filter(|old| old.as_bytes() != &[0; 20]);
+    if current != expected {
+      return Err(PlumbingError::RefChanged {
+        name,
+        expected: *old,
+        found: current,
+      });
+    }
+  }
+  Ok(refs.write(&name, new)?)
+}
+
+/// Write the [`Tree`] for the index of the repository, like `git
+/// write-tree`
+pub fn write_tree(repo: &Repository) -> Result<OID, PlumbingError> {
+  Ok(repo.index()?.write_tree(repo.odb())?)
+}
+
+/// Write a commit of `tree` with the given parents and message, like `git
+/// commit-tree {tree} -p {parent}... -m {message}`. The author and
+/// committer come from `GIT_AUTHOR_NAME`, `GIT_AUTHOR_EMAIL`, and
+/// `GIT_AUTHOR_DATE` and their `GIT_COMMITTER_*` counterparts, falling back
+/// to `user.name` and `user.email` in the config and the current time.
+/// Dates are only accepted in the `{seconds} {offset}` form.
+pub fn commit_tree(
+  repo: &Repository,
+  tree: &OID,
+  parents: &[OID],
+  message: &str,
+) -> Result<OID, PlumbingError> {
+  let author = ident(repo, "AUTHOR", "author")?;
+  let committer = ident(repo, "COMMITTER", "committer")?;
+  let commit = Commit::new(*tree, parents.to_vec(), author, committer, message);
+  Ok(repo.odb().write_commit(&commit)?)
+}
+
+/// The author or committer identity git would use, like `git var
+/// GIT_AUTHOR_IDENT`. `role` is `AUTHOR` or `COMMITTER` for the environment
+/// variables and `section` is the config section that overrides `user`.
+fn ident(repo: &Repository, role: &str, section: &'static str) -> Result<Signature, PlumbingError> {
+  let config = repo.config();
+  let lookup = |field: &str| -> Result<Option<String>, PlumbingError> {
+    if let Ok(value) = env::var(format!("GIT_{}_{}", role, field.to_uppercase())) {
+      return Ok(Some(value));
+    }
+    for section in [section, "user"] {
+      if let Some(value) = config.get_str(&format!("{}.{}", section, field))? {
+        return Ok(Some(value.to_string()));
+      }
+    }
+    Ok(None)
+  };
+  let name = lookup("name")?.ok_or(PlumbingError::UnknownIdentity(section))?;
+  let email = lookup("email")?.ok_or(PlumbingError::UnknownIdentity(section))?;
+  let time = match env::var(format!("GIT_{}_DATE", role)) {
+    Ok(date) => Time::parse(date)?,
+    Err(_) => Time::now(),
+  };
+  Ok(Signature::new(name, email, time))
+}
+
+/// One line of [`ls_tree`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LsTreeEntry {
+  pub mode: FileMode,
+  /// `tree` for directories, `commit` for submodules, and `blob` for the
+  /// rest
+  pub kind: ObjectKind,
+  pub oid: OID,
+  /// The path from the top of the tree that was listed
+  pub path: BString,
+}
+
+impl fmt::Display for LsTreeEntry {
+  /// The line as `git ls-tree` prints it, without quoting unusual
+  /// characters in the path
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{:0>6} {} {}\t{}",
+      self.mode.as_bytes().as_bstr(),
+      self.kind,
+      self.oid,
+      self.path
+    )
+  }
+}
+
+/// List the tree a revision names, like `git ls-tree [-r] {tree-ish}`. With
+/// `recursive` the files in every subdirectory are listed instead of the
+/// directories themselves.
+pub fn ls_tree(
+  repo: &Repository,
+  tree_ish: &str,
+  recursive: bool,
+) -> Result<Vec<LsTreeEntry>, PlumbingError> {
+  let tree = repo.rev_parse(&format!("{}^{{tree}}", tree_ish))?;
+  let mut entries = Vec::new();
+  list_tree(repo, &tree, b"", recursive, &mut entries)?;
+  Ok(entries)
+}
+
+fn list_tree(
+  repo: &Repository,
+  tree: &OID,
+  prefix: &[u8],
+  recursive: bool,
+  entries: &mut Vec<LsTreeEntry>,
+) -> Result<(), PlumbingError> {
+  for entry in repo.odb().read_tree(tree)?.entries() {
+    let path = [prefix, entry.name().as_bytes()].concat();
+    let kind = match entry.mode() {
+      FileMode::Tree if recursive => {
+        list_tree(
+          repo,
+          entry.oid(),
+          &[&path[..], b"/"].concat(),
+          true,
+          entries,
+        )?;
+        continue;
+      }
+      FileMode::Tree => ObjectKind::Tree,
+      FileMode::GitLink => ObjectKind::Commit,
+      _ => ObjectKind::Blob,
+    };
+    entries.push(LsTreeEntry {
+      mode: entry.mode(),
+      kind,
+      oid: *entry.oid(),
+      path: path.into(),
+    });
+  }
+  Ok(())
+}
+
+/// Resolve a revision to an [`OID`], like `git rev-parse {spec}`. See
+/// [`rev_parse`][crate::rev_parse] for what is supported.
+pub fn rev_parse(repo: &Repository, spec: &str) -> Result<OID, PlumbingError> {
+  Ok(repo.rev_parse(spec)?)
+}
+
+#[derive(Error, Debug)]
+/// Errors related to the functions in [`plumbing`][self]
+pub enum PlumbingError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  RevParse(#[from] RevParseError),
+  #[error("{0}")]
+  Index(#[from] IndexError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Signature(#[from] SignatureError),
+  #[error("ref {name} is not at {expected}")]
+  RefChanged {
+    name: BString,
+    expected: OID,
+    found: Option<OID>,
+  },
+  #[error("{0} identity unknown, set user.name and user.email")]
+  UnknownIdentity(&'static str),
+}
+
+#[test]
+fn plumbing() {
+  use std::fs;
+  let tmp_dir = tempdir::TempDir::new("plumbing_test").unwrap();
+  let mut repo = Repository::init(tmp_dir.path()).unwrap();
+  let config = repo.git_dir().join("config");
+  let mut contents = fs::read_to_string(&config).unwrap();
+  contents.push_str("[user]\n\tname = Jane Doe\n\temail = jane@example.com\n");
+  fs::write(&config, contents).unwrap();
+  repo.reload_config().unwrap();
+
+  let hello = hash_object(&repo, ObjectKind::Blob, "hello\n", false).unwrap();
+  assert_eq!("ce013625030ba8dba906f756967f9e9ca394464a", hello.as_hex());
+  assert!(cat_file(&repo, &hello.as_hex()).is_err());
+  assert_eq!(
+    hello,
+    hash_object(&repo, ObjectKind::Blob, "hello\n", true).unwrap()
+  );
+  assert_eq!(b"hello\n"[..], cat_file(&repo, "ce01362").unwrap().data[..]);
+  assert!(hash_object(&repo, ObjectKind::Commit, "not a commit", false).is_err());
+
+  let mut index = repo.index().unwrap_or_default();
+  for path in ["README", "src/lib.rs"] {
+    index.add(crate::IndexEntry::new(
+      path,
+      FileMode::NonExecutableFile,
+      hello,
+      Default::default(),
+    ));
+  }
+  index.write(repo.index_path()).unwrap();
+  let tree = write_tree(&repo).unwrap();
+
+  let commit = commit_tree(&repo, &tree, &[], "Initial commit\n").unwrap();
+  let parsed = repo.odb().read_commit(&commit).unwrap();
+  assert_eq!("Jane Doe", parsed.author().name());
+  assert_eq!("jane@example.com", parsed.committer().email());
+  assert_eq!(&tree, parsed.tree());
+
+  // HEAD points at master which doesn't exist yet
+  let zero = OID::from_bytes(&[0; 20]).unwrap();
+  assert!(update_ref(&repo, "HEAD", &commit, Some(&commit)).is_err());
+  update_ref(&repo, "HEAD", &commit, Some(&zero)).unwrap();
+  assert_eq!(commit, rev_parse(&repo, "master").unwrap());
+  let second = commit_tree(&repo, &tree, &[commit], "Second\n").unwrap();
+  assert!(matches!(
+    update_ref(&repo, "refs/heads/master", &second, Some(&zero)),
+    Err(PlumbingError::RefChanged { found: Some(found), .. }) if found == commit
+  ));
+  update_ref(&repo, "refs/heads/master", &second, Some(&commit)).unwrap();
+  assert_eq!(commit, rev_parse(&repo, "HEAD~1").unwrap());
+
+  let src = repo
+    .odb()
+    .read_tree(&tree)
+    .unwrap()
+    .get("src")
+    .unwrap()
+    .oid()
+    .as_hex();
+  let lines = |recursive| -> Vec<String> {
+    ls_tree(&repo, "HEAD", recursive)
+      .unwrap()
+      .iter()
+      .map(ToString::to_string)
+      .collect()
+  };
+  assert_eq!(
+    vec![
+      format!("100644 blob {}\tREADME", hello),
+      format!("040000 tree {}\tsrc", src),
+    ],
+    lines(false)
+  );
+  assert_eq!(
+    vec![
+      format!("100644 blob {}\tREADME", hello),
+      format!("100644 blob {}\tsrc/lib.rs", hello),
+    ],
+    lines(true)
+  );
+}
@@ -0,0 +1,192 @@
+use crate::{CheckoutOptions, ConfigError, ConfigFile};
+use std::{
+  fs, io,
+  path::{Path, PathBuf},
+  time::SystemTime,
+};
+
+/// What the filesystem a repository is on supports, found by trying it out
+/// the way `git init` does. The `core.*` settings that depend on it are
+/// written to the config of new repositories, so [`CheckoutOptions`] read
+/// from the config later match the filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FsCapabilities {
+  /// Whether symbolic links can be created, see
+  /// [`CheckoutOptions::symlinks`]
+  pub symlinks: bool,
+  /// Whether the executable bit of a file can be changed and is kept, see
+  /// [`CheckoutOptions::file_mode`]
+  pub file_mode: bool,
+  /// Whether names that only differ in case refer to the same file, see
+  /// [`CheckoutOptions::ignore_case`]
+  pub ignore_case: bool,
+  /// Whether a precomposed and a decomposed Unicode name refer to the same
+  /// file, see [`CheckoutOptions::precompose_unicode`]
+  pub precompose_unicode: bool,
+  /// Whether modification times are stored with more than whole seconds.
+  /// git has no setting for this since [`StatData`][crate::StatData] with
+  /// whole seconds still compares correctly, so it is only reported.
+  pub nanosecond_mtime: bool,
+}
+
+impl FsCapabilities {
+  /// Probe the filesystem `dir` is on by creating a few temporary files in
+  /// it, which are removed again afterwards
+  pub fn probe(dir: impl AsRef<Path>) -> io::Result<Self> {
+    let dir = dir.as_ref();
+    let probe = Probe::create(dir, "probe")?;
+    let file_mode = probe_file_mode(&probe.path);
+    let ignore_case = fs::symlink_metadata(dir.join(probe.name.to_uppercase())).is_ok();
+    let mut nanosecond_mtime = mtime_nanos(&probe.path) != 0;
+    drop(probe);
+
+    // An `ä` written as one code point and as an `a` with a combining
+    // diaeresis like git's `probe_utf8_pathname_composition`
+    let probe = Probe::create(dir, "probe-\u{e4}")?;
+    let decomposed = probe.name.replace('\u{e4}', "a\u{308}");
+    let precompose_unicode = fs::symlink_metadata(dir.join(decomposed)).is_ok();
+    nanosecond_mtime |= mtime_nanos(&probe.path) != 0;
+    drop(probe);
+
+    let probe = Probe::create(dir, "probe-link")?;
+    fs::remove_file(&probe.path)?;
+    let symlinks = create_symlink(&probe.path).is_ok()
+      && fs::symlink_metadata(&probe.path).is_ok_and(|metadata| metadata.file_type().is_symlink());
+    Ok(Self {
+      symlinks,
+      file_mode,
+      ignore_case,
+      precompose_unicode,
+      nanosecond_mtime,
+    })
+  }
+
+  /// Record the capabilities in the `core.*` settings of `config`.
+  /// `core.fileMode` is always written like git does, the others only when
+  /// they differ from [`CheckoutOptions::default`].
+  pub fn write_config(&self, config: &mut ConfigFile) -> Result<(), ConfigError> {
+    let default = CheckoutOptions::default();
+    config.set("core.filemode", self.file_mode.to_string())?;
+    let settings = [
+      ("core.symlinks", self.symlinks, default.symlinks),
+      ("core.ignorecase", self.ignore_case, default.ignore_case),
+      (
+        "core.precomposeunicode",
+        self.precompose_unicode,
+        default.precompose_unicode,
+      ),
+    ];
+    for (key, value, default) in settings {
+      if value != default {
+        config.set(key, value.to_string())?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A temporary file that is removed when dropped
+struct Probe {
+  name: String,
+  path: PathBuf,
+}
+
+impl Probe {
+  fn create(dir: &Path, prefix: &str) -> io::Result<Self> {
+    for n in 0.. {
+      let name = format!("{}-{}-{}", prefix, std::process::id(), n);
+      let path = dir.join(&name);
+      match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+      {
+        Ok(_) => return Ok(Self { name, path }),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+        Err(e) => return Err(e),
+      }
+    }
+    unreachable!()
+  }
+}
+
+impl Drop for Probe {
+  fn drop(&mut self) {
+    let _ = fs::remove_file(&self.path);
+  }
+}
+
+fn mtime_nanos(path: &Path) -> u32 {
+  fs::metadata(path)
+    .and_then(|metadata| metadata.modified())
+    .ok()
+    .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+    .map_or(0, |time| time.subsec_nanos())
+}
+
+/// Flip the executable bit of the file at `path` and check that it sticks
+#[cfg(unix)]
+fn probe_file_mode(path: &Path) -> bool {
+  use std::os::unix::fs::PermissionsExt;
+  let mode = match fs::symlink_metadata(path) {
+    Ok(metadata) => metadata.permissions().mode(),
+    Err(_) => return false,
+  };
+  let flipped = mode ^ 0o100;
+  fs::set_permissions(path, fs::Permissions::from_mode(flipped)).is_ok()
+    && fs::symlink_metadata(path).is_ok_and(|metadata| metadata.permissions().mode() == flipped)
+}
+
+#[cfg(not(unix))]
+fn probe_file_mode(_: &Path) -> bool {
+  false
+}
+
+#[cfg(unix)]
+fn create_symlink(path: &Path) -> io::Result<()> {
+  std::os::unix::fs::symlink("testing", path)
+}
+
+#[cfg(windows)]
+fn create_symlink(path: &Path) -> io::Result<()> {
+  std::os::windows::fs::symlink_file("testing", path)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn create_symlink(_: &Path) -> io::Result<()> {
+  Err(io::ErrorKind::Unsupported.into())
+}
+
+#[test]
+fn probe() {
+  use crate::{Config, ConfigLevel};
+  let tmp_dir = tempdir::TempDir::new("probe_test").unwrap();
+  let capabilities = FsCapabilities::probe(tmp_dir.path()).unwrap();
+  // Every probe cleans up after itself
+  assert_eq!(0, fs::read_dir(tmp_dir.path()).unwrap().count());
+  #[cfg(target_os = "linux")]
+  {
+    assert!(capabilities.symlinks);
+    assert!(capabilities.file_mode);
+    assert!(!capabilities.ignore_case);
+    assert!(!capabilities.precompose_unicode);
+  }
+
+  let mut file = ConfigFile::from_bytes("", ConfigLevel::Local).unwrap();
+  let unusual = FsCapabilities {
+    symlinks: false,
+    file_mode: false,
+    ignore_case: !CheckoutOptions::default().ignore_case,
+    ..capabilities
+  };
+  unusual.write_config(&mut file).unwrap();
+  let mut config = Config::new();
+  config.add(file);
+  let options = CheckoutOptions::from_config(&config).unwrap();
+  assert!(!options.symlinks && !options.file_mode);
+  assert_eq!(unusual.ignore_case, options.ignore_case);
+  assert_eq!(
+    CheckoutOptions::default().precompose_unicode,
+    options.precompose_unicode
+  );
+}
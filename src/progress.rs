@@ -0,0 +1,302 @@
+//! Telling whoever started a long operation, like a clone, how far along it
+//! is, and stopping it when they don't want to wait anymore. An
+//! [`Odb`][crate::Odb] with a [`Progress`] reports to it while storing
+//! packs, writing them and checking out trees, and one with a
+//! [`CancellationToken`] stops those and walks once it's cancelled. The
+//! ones made from it share both.
+
+use std::{
+  fmt,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+
+/// What an operation reporting to a [`Progress`] is busy with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProgressStage {
+  /// Objects of a pack that were read while storing it, out of how many
+  /// the pack says it has
+  ReceivingObjects,
+  /// Bytes of a pack that were read while storing it
+  ReceivingBytes,
+  /// Deltas of a pack whose objects were worked out, out of how many
+  /// deltas it has
+  ResolvingDeltas,
+  /// Objects written to a pack, like one that's pushed or repacked, out of
+  /// how many go in it
+  WritingObjects,
+  /// Files written to a working tree
+  CheckingOut,
+}
+
+impl ProgressStage {
+  /// What git calls the stage in its progress output
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::ReceivingObjects => "Receiving objects",
+      Self::ReceivingBytes => "Receiving bytes",
+      Self::ResolvingDeltas => "Resolving deltas",
+      Self::WritingObjects => "Writing objects",
+      Self::CheckingOut => "Updating files",
+    }
+  }
+}
+
+impl fmt::Display for ProgressStage {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+/// Gets told how far along an operation is. Updates come from whichever
+/// thread does the work and as often as every object that's read, so they
+/// should be quick, like storing the numbers for a UI to show later.
+///
+/// Any `Fn(ProgressStage, u64, Option<u64>)` is a progress that's never
+/// cancelled.
+pub trait Progress: Send + Sync {
+  /// `done` steps of `stage` are finished, out of `total` if it's known
+  /// how many there are
+  fn update(&self, stage: ProgressStage, done: u64, total: Option<u64>);
+
+  /// Whether to stop what's being done. This is asked between steps, and
+  /// the operation then fails with a `Cancelled` error after cleaning up
+  /// anything that isn't complete, like a pack that's only partly stored.
+  fn is_cancelled(&self) -> bool {
+    false
+  }
+}
+
+impl<F> Progress for F
+where
+  F: Fn(ProgressStage, u64, Option<u64>) + Send + Sync,
+{
+  fn update(&self, stage: ProgressStage, done: u64, total: Option<u64>) {
+    self(stage, done, total)
+  }
+}
+
+/// Stops the operations of the [`Odb`][crate::Odb]s and
+/// [`Repository`][crate::Repository]s it's given to once it's cancelled,
+/// from any thread. They check it between steps, like the commits of a walk
+/// or the objects of a pack, and fail with a `Cancelled` error after
+/// cleaning up what isn't complete, so the repository stays as it was
+/// before, apart from the files a checkout already wrote.
+///
+/// Clones of a token are the same token, cancelling one cancels all of
+/// them.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+  cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+  /// A token that isn't cancelled yet
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Stop everything this token was given to. It can't be undone.
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::Relaxed);
+  }
+
+  /// Whether [`CancellationToken::cancel`] was called
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::Relaxed)
+  }
+}
+
+/// Two tokens are the same if cancelling one cancels the other
+impl PartialEq for CancellationToken {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.cancelled, &other.cancelled)
+  }
+}
+
+impl Eq for CancellationToken {}
+
+/// The [`Progress`] and [`CancellationToken`] of an [`Odb`][crate::Odb],
+/// if it has them
+#[derive(Clone, Default)]
+pub(crate) struct Reporter {
+  progress: Option<Arc<dyn Progress>>,
+  token: Option<CancellationToken>,
+}
+
+impl Reporter {
+  pub(crate) fn set_progress(&mut self, progress: Arc<dyn Progress>) {
+    self.progress = Some(progress);
+  }
+
+  pub(crate) fn set_token(&mut self, token: CancellationToken) {
+    self.token = Some(token);
+  }
+
+  pub(crate) fn update(&self, stage: ProgressStage, done: u64, total: Option<u64>) {
+    if let Some(progress) = &self.progress {
+      progress.update(stage, done, total);
+    }
+  }
+
+  pub(crate) fn is_cancelled(&self) -> bool {
+    let token = self.token.as_ref();
+    let progress = self.progress.as_ref();
+    token.is_some_and(CancellationToken::is_cancelled)
+      || progress.is_some_and(|progress| progress.is_cancelled())
+  }
+}
+
+impl fmt::Debug for Reporter {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Reporter")
+      .field("progress", &self.progress.is_some())
+      .field("token", &self.token)
+      .finish()
+  }
+}
+
+impl PartialEq for Reporter {
+  fn eq(&self, other: &Self) -> bool {
+    let progress = match (&self.progress, &other.progress) {
+      (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+      (a, b) => a.is_none() && b.is_none(),
+    };
+    progress && self.token == other.token
+  }
+}
+
+impl Eq for Reporter {}
+
+#[test]
+fn clone_with_progress() {
+  use crate::{
+    Blob, Bundle, CloneOptions, Commit, FileMode, Repository, Signature, Time, Tree, TreeEntry,
+  };
+  use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+  };
+  struct Recorder {
+    updates: Mutex<Vec<(ProgressStage, u64, Option<u64>)>>,
+    cancel: AtomicBool,
+  }
+  impl Progress for Recorder {
+    fn update(&self, stage: ProgressStage, done: u64, total: Option<u64>) {
+      self.updates.lock().unwrap().push((stage, done, total));
+    }
+    fn is_cancelled(&self) -> bool {
+      self.cancel.load(Ordering::SeqCst)
+    }
+  }
+  impl Progress for Arc<Recorder> {
+    fn update(&self, stage: ProgressStage, done: u64, total: Option<u64>) {
+      (**self).update(stage, done, total)
+    }
+    fn is_cancelled(&self) -> bool {
+      (**self).is_cancelled()
+    }
+  }
+  impl Recorder {
+    fn last(&self, stage: ProgressStage) -> Option<(u64, Option<u64>)> {
+      let updates = self.updates.lock().unwrap();
+      let mut updates = updates.iter().filter(|update| update.0 == stage);
+      updates.next_back().map(|&(_, done, total)| (done, total))
+    }
+  }
+
+  let tmp_dir = tempdir::TempDir::new("progress_test").unwrap();
+  let source = Repository::init_bare(tmp_dir.path().join("source.git")).unwrap();
+  let odb = source.odb();
+  let entries = ["a.txt", "b.txt"].iter().map(|name| {
+    let blob = odb.write_blob(&Blob::new(*name)).unwrap();
+    TreeEntry::new(FileMode::NonExecutableFile, *name, blob)
+  });
+  let tree = odb.write_tree(&Tree::new(entries.collect())).unwrap();
+  let signature = Signature::new("A U Thor", "author@example.com", Time::new(0, 0));
+  let commit = Commit::new(tree, vec![], signature.clone(), signature, "c\n");
+  let commit = odb.write_commit(&commit).unwrap();
+  source.refs().write("refs/heads/master", &commit).unwrap();
+  source
+    .refs()
+    .write_symbolic("HEAD", "refs/heads/master")
+    .unwrap();
+  // Cloning a local repository hardlinks its objects, a bundle has a pack
+  let url = tmp_dir.path().join("source.bundle").display().to_string();
+  Bundle::create(&source, &["HEAD", "refs/heads/master"], &[])
+    .unwrap()
+    .save(&url)
+    .unwrap();
+
+  let recorder = Arc::new(Recorder {
+    updates: Mutex::default(),
+    cancel: AtomicBool::new(false),
+  });
+  let path = tmp_dir.path().join("clone");
+  let repo =
+    Repository::clone_with_progress(&url, &path, &CloneOptions::default(), recorder.clone())
+      .unwrap();
+  assert_eq!(
+    Some((4, Some(4))),
+    recorder.last(ProgressStage::ReceivingObjects)
+  );
+  assert!(recorder.last(ProgressStage::ReceivingBytes).unwrap().0 > 12);
+  assert_eq!(Some((2, None)), recorder.last(ProgressStage::CheckingOut));
+  // The repository keeps reporting to it
+  repo.repack_with_bitmap().unwrap();
+  assert_eq!(
+    Some((4, Some(4))),
+    recorder.last(ProgressStage::WritingObjects)
+  );
+
+  // Nothing is stored once it's cancelled
+  recorder.cancel.store(true, Ordering::SeqCst);
+  let path = tmp_dir.path().join("cancelled");
+  let e = Repository::clone_with_progress(&url, &path, &CloneOptions::default(), recorder.clone())
+    .unwrap_err();
+  assert_eq!("the operation was cancelled", e.to_string());
+  let packs = std::fs::read_dir(path.join(".git/objects/pack")).unwrap();
+  assert_eq!(0, packs.count());
+}
+
+#[test]
+fn cancellation_token() {
+  use crate::{
+    pack, Blob, Commit, ObjectKind, Odb, OdbError, RawObject, RevWalk, RevWalkError, Signature,
+    Time, Tree,
+  };
+  let tmp_dir = tempdir::TempDir::new("cancellation_test").unwrap();
+  let token = CancellationToken::new();
+  let odb = Odb::new(tmp_dir.path()).with_cancellation(token.clone());
+  let signature = Signature::new("A U Thor", "author@example.com", Time::new(0, 0));
+  let tree = odb.write_tree(&Tree::new(vec![])).unwrap();
+  let mut parents = vec![];
+  for _ in 0..3 {
+    let commit = Commit::new(tree, parents, signature.clone(), signature.clone(), "c\n");
+    parents = vec![odb.write_commit(&commit).unwrap()];
+  }
+  let mut walk = RevWalk::new(&odb);
+  walk.push(&parents[0]).unwrap();
+  assert!(walk.next().unwrap().is_ok());
+
+  token.clone().cancel();
+  assert!(token.is_cancelled());
+  assert!(matches!(
+    walk.next(),
+    Some(Err(RevWalkError::Odb(OdbError::Cancelled)))
+  ));
+  // A pack isn't stored, and nothing of it is left behind
+  let blob = Blob::new("blob\n");
+  let bytes = blob.as_bytes();
+  let header = bytes.iter().position(|&b| b == 0).unwrap();
+  let pack = pack::build_pack(&[pack::PackObject::Whole(RawObject::new(
+    ObjectKind::Blob,
+    &bytes[header + 1..],
+  ))]);
+  assert!(matches!(odb.write_pack(&pack), Err(OdbError::Cancelled)));
+  assert!(!tmp_dir.path().join("pack").exists());
+  // Only the Odbs given the token are cancelled
+  assert_eq!(1, Odb::new(tmp_dir.path()).write_pack(&pack).unwrap().len());
+}
@@ -0,0 +1,244 @@
+use crate::{
+  transport::{self, FetchOptions},
+  Commit, FileMode, ObjectKind, Odb, OdbError, Repository, Tag, Tree, OID,
+};
+use std::{
+  cell::Cell,
+  collections::HashSet,
+  error::Error,
+  fmt,
+  path::PathBuf,
+  sync::{Arc, Mutex},
+};
+
+/// What a [`Promisor`] fails with, which is whatever the fetch failed with
+pub type PromisorError = Box<dyn Error + Send + Sync>;
+
+/// Fetches the objects a partial clone left out once they're needed. The
+/// [`Odb`] of a partial clone asks for an object it doesn't have when the
+/// object is promised, meaning it's in a promisor pack or one of the
+/// objects there points at it, and reads it again afterwards.
+///
+/// Any `Fn(&[OID]) -> Result<(), PromisorError>` is a promisor, so objects
+/// can come from anywhere, like a cache shared between clones.
+/// [`RemotePromisor`] fetches them from a remote like git does.
+pub trait Promisor: Send + Sync {
+  /// Store `oids` in the repository's object database
+  fn fetch(&self, oids: &[OID]) -> Result<(), PromisorError>;
+}
+
+impl<F> Promisor for F
+where
+  F: Fn(&[OID]) -> Result<(), PromisorError> + Send + Sync,
+{
+  fn fetch(&self, oids: &[OID]) -> Result<(), PromisorError> {
+    self(oids)
+  }
+}
+
+/// Fetches the objects a partial clone left out from the remote it was
+/// cloned from, over the transport [`transport::connect`] picks. Nothing
+/// else is fetched, and the remote isn't told which commits are already
+/// there since the objects asked for are reachable from them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemotePromisor {
+  git_dir: PathBuf,
+  url: String,
+}
+
+impl RemotePromisor {
+  /// Fetch into the repository at `git_dir` from `url`
+  pub fn new(git_dir: impl Into<PathBuf>, url: impl Into<String>) -> Self {
+    Self {
+      git_dir: git_dir.into(),
+      url: url.into(),
+    }
+  }
+
+  /// The promisor of the remote `extensions.partialClone` names in the
+  /// config of `repo`, if it's a partial clone
+  pub fn from_config(repo: &Repository) -> Option<Self> {
+    let config = repo.config();
+    let remote = config.get("extensions.partialclone")?;
+    let url = config.get(&format!("remote.{}.url", remote))?;
+    Some(Self::new(repo.git_dir(), url.to_string()))
+  }
+}
+
+impl Promisor for RemotePromisor {
+  fn fetch(&self, oids: &[OID]) -> Result<(), PromisorError> {
+    let repo = Repository::open(&self.git_dir)?;
+    let mut transport = transport::connect(&self.url)?;
+    let options = FetchOptions {
+      no_haves: true,
+      ..FetchOptions::default()
+    };
+    transport.fetch_with_options(&repo, oids, &options)?;
+    Ok(())
+  }
+}
+
+thread_local! {
+  /// Set while a promisor is fetching, so objects it finds missing on the
+  /// way aren't fetched again from inside of the fetch
+  static FETCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The [`Promisor`] of an [`Odb`] along with the objects it promises,
+/// which are worked out the first time an object is missing
+#[derive(Clone, Default)]
+pub(crate) struct LazyFetch {
+  promisor: Option<Arc<dyn Promisor>>,
+  promised: Arc<Mutex<Option<HashSet<OID>>>>,
+}
+
+impl LazyFetch {
+  pub(crate) fn new(promisor: impl Promisor + 'static) -> Self {
+    Self {
+      promisor: Some(Arc::new(promisor)),
+      promised: Arc::default(),
+    }
+  }
+
+  /// Work out the promised objects again, after a promisor pack was added
+  pub(crate) fn forget_promised(&self) {
+    *self.promised.lock().unwrap_or_else(|e| e.into_inner()) = None;
+  }
+
+  /// Fetch the objects of `oids` that `odb` is promised, returning whether
+  /// any were fetched
+  pub(crate) fn fetch(&self, odb: &Odb, oids: &[OID]) -> Result<bool, OdbError> {
+    let promisor = match &self.promisor {
+      Some(promisor) if !FETCHING.with(Cell::get) => promisor,
+      _ => return Ok(false),
+    };
+    FETCHING.with(|fetching| fetching.set(true));
+    let result = self.promised(odb, oids).and_then(|promised| {
+      if promised.is_empty() {
+        return Ok(false);
+      }
+      promisor
+        .fetch(&promised)
+        .map_err(|e| OdbError::Promisor(promised[0], e))?;
+      Ok(true)
+    });
+    FETCHING.with(|fetching| fetching.set(false));
+    result
+  }
+
+  /// The objects of `oids` that are in a promisor pack or that an object
+  /// in one of them points at
+  fn promised(&self, odb: &Odb, oids: &[OID]) -> Result<Vec<OID>, OdbError> {
+    let mut promised = self.promised.lock().unwrap_or_else(|e| e.into_inner());
+    if promised.is_none() {
+      let mut set = HashSet::new();
+      for oid in odb.promisor_oids()? {
+        let object = odb.read(&oid)?;
+        match object.kind {
+          ObjectKind::Commit => {
+            let commit = Commit::parse(object.data)?;
+            set.insert(*commit.tree());
+            set.extend(commit.parents().iter().copied());
+          }
+          ObjectKind::Tree => {
+            let tree = Tree::parse(object.data)?;
+            let entries = tree.entries().iter();
+            // Submodule commits are in other repositories
+            let entries = entries.filter(|entry| entry.mode() != FileMode::GitLink);
+            set.extend(entries.map(|entry| *entry.oid()));
+          }
+          ObjectKind::Tag => {
+            set.insert(*Tag::parse(object.data)?.object());
+          }
+          ObjectKind::Blob => {}
+        }
+        set.insert(oid);
+      }
+      *promised = Some(set);
+    }
+    let set = promised.as_ref().unwrap();
+    Ok(
+      oids
+        .iter()
+        .filter(|oid| set.contains(oid))
+        .copied()
+        .collect(),
+    )
+  }
+}
+
+impl fmt::Debug for LazyFetch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("LazyFetch")
+      .field("promisor", &self.promisor.is_some())
+      .finish_non_exhaustive()
+  }
+}
+
+impl PartialEq for LazyFetch {
+  fn eq(&self, other: &Self) -> bool {
+    match (&self.promisor, &other.promisor) {
+      (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+      (a, b) => a.is_none() && b.is_none(),
+    }
+  }
+}
+
+impl Eq for LazyFetch {}
+
+#[test]
+fn lazy_fetch() {
+  use crate::{pack, Blob, RawObject, TreeEntry};
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  let tmp_dir = tempdir::TempDir::new("promisor_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let blob = Blob::new("left out\n");
+  let blob_oid = OID::hash(blob.as_bytes());
+  let tree = Tree::new(vec![TreeEntry::new(
+    FileMode::NonExecutableFile,
+    "file.txt",
+    blob_oid,
+  )]);
+  let bytes = tree.as_bytes();
+  let header = bytes.iter().position(|&b| b == 0).unwrap();
+  let pack = pack::build_pack(&[pack::PackObject::Whole(RawObject::new(
+    ObjectKind::Tree,
+    &bytes[header + 1..],
+  ))]);
+  odb.write_promisor_pack(&pack).unwrap();
+  let marker = std::fs::read_dir(tmp_dir.path().join("pack"))
+    .unwrap()
+    .any(|entry| entry.unwrap().path().extension() == Some("promisor".as_ref()));
+  assert!(marker);
+
+  let fetches = Arc::new(AtomicUsize::new(0));
+  let lazy = {
+    let (odb, fetches) = (odb.clone(), fetches.clone());
+    odb
+      .clone()
+      .with_promisor(move |oids: &[OID]| -> Result<(), PromisorError> {
+        fetches.fetch_add(1, Ordering::SeqCst);
+        assert_eq!([blob_oid], oids);
+        odb.write_blob(&blob)?;
+        Ok(())
+      })
+  };
+  assert!(matches!(odb.read(&blob_oid), Err(OdbError::NotFound(_))));
+  assert_eq!("left out\n", lazy.read_blob(&blob_oid).unwrap().contents());
+  assert_eq!(1, fetches.load(Ordering::SeqCst));
+
+  // Objects nothing promises are just not there
+  let missing = OID::hash("missing");
+  assert!(matches!(lazy.read(&missing), Err(OdbError::NotFound(_))));
+  lazy.prefetch(&[blob_oid, missing]).unwrap();
+  assert_eq!(1, fetches.load(Ordering::SeqCst));
+
+  let offline = Odb::new(tmp_dir.path().join("offline"));
+  offline.write_promisor_pack(&pack).unwrap();
+  let offline =
+    offline.with_promisor(|_: &[OID]| -> Result<(), PromisorError> { Err("offline".into()) });
+  assert!(matches!(
+    offline.read(&blob_oid),
+    Err(OdbError::Promisor(_, _))
+  ));
+}
@@ -0,0 +1,445 @@
+//! Limits on what a push can bring into a repository, checked before
+//! anything is stored so a hosting service can turn away a push that's too
+//! big instead of cleaning up after it.
+
+use crate::{
+  endian::read_u32,
+  pack::{self, PackError},
+  Config, ConfigError, FileMode, ObjectKind, OdbError, RawObject, Repository, Tree, TreeError, OID,
+};
+use bstr::{BString, ByteSlice};
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// The limits a push is held to. Every limit is off unless it's set, and
+/// each is checked as soon as what it limits is known, so a pack with more
+/// objects than allowed is turned away before any of it is inflated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ReceiveQuota {
+  /// The most bytes a pack can be
+  pub max_pack_size: Option<u64>,
+  /// The most objects a pack can have
+  pub max_objects: Option<u64>,
+  /// The most bytes a blob can be once it's inflated
+  pub max_blob_size: Option<u64>,
+  /// The most directories and the file in them a path can have, so `a/b`
+  /// is 2 deep
+  pub max_path_depth: Option<usize>,
+  /// The most bytes a path from the top of a tree can be
+  pub max_path_length: Option<usize>,
+  /// The most refs a push can create, update, or delete at once
+  pub max_ref_updates: Option<usize>,
+}
+
+impl ReceiveQuota {
+  /// No limits beyond `receive.maxInputSize` from `config`, which is the
+  /// one git has
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let max = config.get_int("receive.maxinputsize")?;
+    Ok(Self {
+      // Zero is no limit like it is for git
+      max_pack_size: max.filter(|&max| max > 0).map(|max| max as u64),
+      ..Self::default()
+    })
+  }
+
+  /// Check the number of refs a push updates
+  pub fn check_ref_updates(&self, count: usize) -> Result<(), QuotaError> {
+    match self.max_ref_updates {
+      Some(limit) if count > limit => Err(QuotaError::TooManyRefUpdates { count, limit }),
+      _ => Ok(()),
+    }
+  }
+
+  /// Check a pack pushed to `repo`, which can be thin with deltas against
+  /// objects `repo` has. Paths are checked from the root tree of every
+  /// commit in the pack, which takes trees `repo` already has into
+  /// account, so moving an old directory deeper down is caught too.
+  pub fn check_pack(&self, repo: &Repository, bytes: &[u8]) -> Result<(), QuotaError> {
+    if let Some(limit) = self.max_pack_size {
+      let size = bytes.len() as u64;
+      if size > limit {
+        return Err(QuotaError::PackTooBig { size, limit });
+      }
+    }
+    if let (Some(limit), Some(header)) = (self.max_objects, bytes.get(8..12)) {
+      let count = read_u32(header) as u64;
+      if count > limit {
+        return Err(QuotaError::TooManyObjects { count, limit });
+      }
+    }
+    if self.max_blob_size.is_none()
+      && self.max_path_depth.is_none()
+      && self.max_path_length.is_none()
+    {
+      return Ok(());
+    }
+    let odb = repo.odb();
+    let mut failed = None;
+    let max_depth = odb.pack_limits().max_delta_depth;
+    let objects =
+      pack::parse_thin_pack(bytes, odb.budget(), max_depth, |oid| match odb.read(oid) {
+        Ok(object) => Some(object),
+        Err(OdbError::NotFound(_)) => None,
+        Err(e) => {
+          failed = Some(e);
+          None
+        }
+      });
+    // A base that couldn't be read is better told by why
+    if let Some(e) = failed {
+      return Err(e.into());
+    }
+    let objects = objects?;
+    let mut trees = HashMap::new();
+    let mut commits = Vec::new();
+    for object in &objects {
+      match object.kind {
+        ObjectKind::Blob => {
+          if let Some(limit) = self.max_blob_size {
+            let size = object.data.len() as u64;
+            if size > limit {
+              return Err(QuotaError::BlobTooBig {
+                oid: object.id(),
+                size,
+                limit,
+              });
+            }
+          }
+        }
+        ObjectKind::Tree => {
+          trees.insert(object.id(), object);
+        }
+        ObjectKind::Commit => commits.push(object),
+        ObjectKind::Tag => {}
+      }
+    }
+    if self.max_path_depth.is_none() && self.max_path_length.is_none() {
+      return Ok(());
+    }
+    let mut paths = PathCheck {
+      quota: self,
+      repo,
+      new: trees,
+      stats: HashMap::new(),
+      path: Vec::new(),
+    };
+    for commit in commits {
+      let tree = commit_tree(commit)?;
+      let stats = paths.stats(&tree)?;
+      paths.check(&stats)?;
+    }
+    Ok(())
+  }
+}
+
+/// The tree of a commit from its first line, which is all that's needed
+fn commit_tree(commit: &RawObject) -> Result<OID, QuotaError> {
+  commit
+    .data
+    .lines()
+    .next()
+    .and_then(|line| line.strip_prefix(b"tree "))
+    .and_then(|hex| OID::from_hex(hex.to_str().ok()?).ok())
+    .ok_or(QuotaError::Pack(PackError::Malformed(
+      "commit without a tree",
+    )))
+}
+
+/// The deepest and longest path in a tree, relative to it
+#[derive(Debug, Clone, Default)]
+struct PathStats {
+  depth: usize,
+  deepest: BString,
+  longest: BString,
+}
+
+struct PathCheck<'a> {
+  quota: &'a ReceiveQuota,
+  repo: &'a Repository,
+  /// The trees in the pack
+  new: HashMap<OID, &'a RawObject>,
+  /// Trees are shared between commits and directories, so each is only
+  /// looked at once
+  stats: HashMap<OID, PathStats>,
+  /// The directories down to the tree being looked at
+  path: Vec<BString>,
+}
+
+impl PathCheck<'_> {
+  fn check(&self, stats: &PathStats) -> Result<(), QuotaError> {
+    if let Some(limit) = self.quota.max_path_depth {
+      if stats.depth > limit {
+        return Err(QuotaError::PathTooDeep {
+          path: stats.deepest.clone(),
+          depth: stats.depth,
+          limit,
+        });
+      }
+    }
+    if let Some(limit) = self.quota.max_path_length {
+      if stats.longest.len() > limit {
+        return Err(QuotaError::PathTooLong {
+          path: stats.longest.clone(),
+          length: stats.longest.len(),
+          limit,
+        });
+      }
+    }
+    Ok(())
+  }
+
+  fn stats(&mut self, oid: &OID) -> Result<PathStats, QuotaError> {
+    if let Some(stats) = self.stats.get(oid) {
+      return Ok(stats.clone());
+    }
+    // What's below doesn't matter once the directories down to here are
+    // too much on their own, which also keeps trees nested absurdly deep
+    // from going deeper than the limits
+    let path = BString::from(bstr::join("/", &self.path));
+    self.check(&PathStats {
+      depth: self.path.len(),
+      deepest: path.clone(),
+      longest: path,
+    })?;
+    let tree = match self.new.get(oid) {
+      Some(object) => Tree::parse(&object.data)?,
+      None => self.repo.odb().read_tree(oid)?,
+    };
+    let mut stats = PathStats::default();
+    for entry in tree.entries() {
+      let mut below = match entry.mode() {
+        FileMode::Tree => {
+          self.path.push(entry.name().to_owned());
+          let below = self.stats(entry.oid());
+          self.path.pop();
+          let mut below = below?;
+          below.deepest.insert(0, b'/');
+          below.longest.insert(0, b'/');
+          below
+        }
+        _ => PathStats::default(),
+      };
+      below.depth += 1;
+      for path in [&mut below.deepest, &mut below.longest] {
+        path.splice(0..0, entry.name().iter().copied());
+      }
+      if below.depth > stats.depth {
+        stats.depth = below.depth;
+        stats.deepest = below.deepest;
+      }
+      if below.longest.len() > stats.longest.len() {
+        stats.longest = below.longest;
+      }
+    }
+    self.stats.insert(*oid, stats.clone());
+    Ok(stats)
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to checking a push against a [`ReceiveQuota`]. The
+/// messages are meant to be shown to whoever pushed, like in an `ng`
+/// line of the report.
+pub enum QuotaError {
+  #[error("pack is {size} bytes, more than the limit of {limit}")]
+  PackTooBig { size: u64, limit: u64 },
+  #[error("pack has {count} objects, more than the limit of {limit}")]
+  TooManyObjects { count: u64, limit: u64 },
+  #[error("blob {oid} is {size} bytes, more than the limit of {limit}")]
+  BlobTooBig { oid: OID, size: u64, limit: u64 },
+  #[error("path {path} is {depth} deep, more than the limit of {limit}")]
+  PathTooDeep {
+    path: BString,
+    depth: usize,
+    limit: usize,
+  },
+  #[error("path {path} is {length} bytes, more than the limit of {limit}")]
+  PathTooLong {
+    path: BString,
+    length: usize,
+    limit: usize,
+  },
+  #[error("push updates {count} refs, more than the limit of {limit}")]
+  TooManyRefUpdates { count: usize, limit: usize },
+  #[error("{0}")]
+  Pack(#[from] PackError),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Tree(#[from] TreeError),
+}
+
+impl QuotaError {
+  /// Whether the push went over a limit, as opposed to not being readable
+  pub fn is_over_quota(&self) -> bool {
+    !matches!(self, Self::Pack(_) | Self::Odb(_) | Self::Tree(_))
+  }
+}
+
+#[test]
+fn quotas() {
+  use crate::{
+    delta::make_delta,
+    pack::{build_pack, PackObject},
+    Blob, Commit, Signature, Time, TreeEntry,
+  };
+  let tmp_dir = tempdir::TempDir::new("quota_test").unwrap();
+  let repo = Repository::init(tmp_dir.path().join("repo")).unwrap();
+  // Objects are made here and packed up to push to `repo`
+  let source = Repository::init(tmp_dir.path().join("source")).unwrap();
+  let odb = source.odb();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let tree = |entries: Vec<(&str, FileMode, OID)>| {
+    let entries = entries
+      .into_iter()
+      .map(|(name, mode, oid)| TreeEntry::new(mode, name, oid))
+      .collect();
+    odb.write_tree(&Tree::new(entries)).unwrap()
+  };
+  let commit = |tree: OID| {
+    let commit = Commit::new(
+      tree,
+      vec![],
+      signature.clone(),
+      signature.clone(),
+      "commit\n",
+    );
+    odb.write_commit(&commit).unwrap()
+  };
+  let pack = |oids: &[OID]| {
+    let objects: Vec<PackObject> = oids
+      .iter()
+      .map(|oid| PackObject::Whole(odb.read(oid).unwrap()))
+      .collect();
+    build_pack(&objects)
+  };
+  let file = FileMode::NonExecutableFile;
+  let big = odb.write_blob(&Blob::new(vec![b'x'; 1000])).unwrap();
+  let lib = tree(vec![("main.rs", file, big)]);
+  let src = tree(vec![("lib", FileMode::Tree, lib)]);
+  let root = tree(vec![("src", FileMode::Tree, src)]);
+  let first = commit(root);
+  let objects = [first, root, src, lib, big];
+  let bytes = pack(&objects);
+
+  assert!(ReceiveQuota::default().check_pack(&repo, &bytes).is_ok());
+  let check = |quota: ReceiveQuota, bytes: &[u8]| quota.check_pack(&repo, bytes).unwrap_err();
+  assert!(matches!(
+    check(
+      ReceiveQuota {
+        max_pack_size: Some(100),
+        ..Default::default()
+      },
+      &bytes
+    ),
+    QuotaError::PackTooBig { limit: 100, .. }
+  ));
+  assert!(matches!(
+    check(
+      ReceiveQuota {
+        max_objects: Some(4),
+        ..Default::default()
+      },
+      &bytes
+    ),
+    QuotaError::TooManyObjects { count: 5, limit: 4 }
+  ));
+  match check(
+    ReceiveQuota {
+      max_blob_size: Some(999),
+      ..Default::default()
+    },
+    &bytes,
+  ) {
+    QuotaError::BlobTooBig {
+      oid,
+      size: 1000,
+      limit: 999,
+    } => assert_eq!(big, oid),
+    other => panic!("{:?}", other),
+  }
+  let error = check(
+    ReceiveQuota {
+      max_path_depth: Some(2),
+      ..Default::default()
+    },
+    &bytes,
+  );
+  assert_eq!(
+    "path src/lib/main.rs is 3 deep, more than the limit of 2",
+    error.to_string()
+  );
+  assert!(error.is_over_quota());
+  assert!(matches!(
+    check(
+      ReceiveQuota {
+        max_path_length: Some(14),
+        ..Default::default()
+      },
+      &bytes
+    ),
+    QuotaError::PathTooLong {
+      length: 15,
+      limit: 14,
+      ..
+    }
+  ));
+  let quota = ReceiveQuota {
+    max_path_depth: Some(3),
+    max_path_length: Some(15),
+    max_ref_updates: Some(1),
+    ..Default::default()
+  };
+  quota.check_pack(&repo, &bytes).unwrap();
+  repo.odb().write_pack(&bytes).unwrap();
+  quota.check_ref_updates(1).unwrap();
+  assert!(matches!(
+    quota.check_ref_updates(2),
+    Err(QuotaError::TooManyRefUpdates { count: 2, limit: 1 })
+  ));
+
+  // An old directory moved deeper is caught even though only the trees
+  // above it are new, and thin packs have their bases looked up
+  let vendor = tree(vec![("src", FileMode::Tree, src)]);
+  let moved = commit(tree(vec![("vendor", FileMode::Tree, vendor)]));
+  let moved_root = *odb.read_commit(&moved).unwrap().tree();
+  let bytes = pack(&[moved, moved_root, vendor]);
+  assert!(matches!(
+    quota.check_pack(&repo, &bytes),
+    Err(QuotaError::PathTooDeep { depth: 4, .. })
+  ));
+  let mut edited = vec![b'x'; 1000];
+  edited[500] = b'y';
+  let edited = odb.write_blob(&Blob::new(edited)).unwrap();
+  let thin = build_pack(&[PackObject::RefDelta {
+    base: big,
+    delta: make_delta(
+      &odb.read(&big).unwrap().data,
+      &odb.read(&edited).unwrap().data,
+    ),
+  }]);
+  let quota = ReceiveQuota {
+    max_blob_size: Some(999),
+    ..Default::default()
+  };
+  assert!(matches!(
+    quota.check_pack(&repo, &thin),
+    Err(QuotaError::BlobTooBig { oid, .. }) if oid == edited
+  ));
+  let empty = Repository::init(tmp_dir.path().join("empty")).unwrap();
+  assert!(matches!(
+    quota.check_pack(&empty, &thin),
+    Err(QuotaError::Pack(PackError::MissingBase(base))) if base == big
+  ));
+
+  let config = Config::from_bytes("[receive]\n\tmaxInputSize = 2k\n").unwrap();
+  assert_eq!(
+    Some(2048),
+    ReceiveQuota::from_config(&config).unwrap().max_pack_size
+  );
+}
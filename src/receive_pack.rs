@@ -0,0 +1,903 @@
+//! The server side of pushing, what `git receive-pack` does. The objects
+//! pushed are kept in a quarantine directory until they're checked and the
+//! pre-receive hooks accepted them, so a push that's turned away leaves
+//! nothing behind.
+
+use crate::{
+  cleanup, pack,
+  refs::check_ref_name,
+  transport::{is_ancestor, TransportError},
+  upload_pack::SideBand,
+  AdvertisedRef, Commit, ConfigError, FileMode, HiddenRefs, ObjectKind, Odb, OdbError, PackError,
+  Packet, PktLineError, PktLineReader, QuotaError, ReceiveQuota, RefError, RefTarget, Repository,
+  Tag, Tree, OID,
+};
+use bstr::{BString, ByteSlice};
+use std::{
+  collections::HashSet,
+  fmt, fs,
+  io::{self, Write},
+  path::PathBuf,
+};
+use thiserror::Error;
+
+/// A ref update a push asked for, and what came of it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefCommand {
+  /// The full name of the ref
+  pub name: BString,
+  /// Where the client saw the ref, `None` if it's being created
+  pub old: Option<OID>,
+  /// Where the ref goes, `None` if it's being deleted
+  pub new: Option<OID>,
+  /// Why the ref wasn't updated, which is sent back to the client, or
+  /// `None` if it was or hasn't been turned away yet
+  pub error: Option<BString>,
+}
+
+type PreReceiveHook<'a> = Box<dyn FnMut(&Odb, &[RefCommand]) -> Result<(), BString> + 'a>;
+type UpdateHook<'a> = Box<dyn FnMut(&RefCommand) -> Result<(), BString> + 'a>;
+
+/// What [`ReceivePack::serve`] can do for a push, advertised before the
+/// agent
+const PUSH_CAPABILITIES: [&str; 6] = [
+  "report-status",
+  "report-status-v2",
+  "delete-refs",
+  "side-band-64k",
+  "atomic",
+  "ofs-delta",
+];
+
+/// The server side of a push for one connection
+pub struct ReceivePack<'a> {
+  repo: &'a Repository,
+  hidden: HiddenRefs,
+  capabilities: Vec<String>,
+  quota: Option<ReceiveQuota>,
+  pre_receive: Vec<PreReceiveHook<'a>>,
+  update: Vec<UpdateHook<'a>>,
+}
+
+impl fmt::Debug for ReceivePack<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ReceivePack")
+      .field("repo", &self.repo)
+      .field("hidden", &self.hidden)
+      .field("capabilities", &self.capabilities)
+      .field("quota", &self.quota)
+      .field("pre_receive", &self.pre_receive.len())
+      .field("update", &self.update.len())
+      .finish()
+  }
+}
+
+impl<'a> ReceivePack<'a> {
+  /// Take pushes to `repo`, hiding the refs its `transfer.hideRefs` and
+  /// `receivepack.hideRefs` config says to. Hidden refs can't be updated
+  /// either.
+  pub fn new(repo: &'a Repository) -> Self {
+    Self {
+      repo,
+      hidden: HiddenRefs::from_config(repo.config(), "receivepack"),
+      capabilities: PUSH_CAPABILITIES
+        .iter()
+        .map(|capability| capability.to_string())
+        .chain([format!("agent=libgit-rs/{}", env!("CARGO_PKG_VERSION"))])
+        .collect(),
+      quota: None,
+      pre_receive: Vec::new(),
+      update: Vec::new(),
+    }
+  }
+
+  /// The refs hidden from this connection, which more patterns can be
+  /// added to
+  pub fn hidden_refs(&mut self) -> &mut HiddenRefs {
+    &mut self.hidden
+  }
+
+  /// Hold pushes to `quota` instead of what [`ReceiveQuota::from_config`]
+  /// reads from the config of the repository
+  pub fn quota(&mut self, quota: ReceiveQuota) -> &mut Self {
+    self.quota = Some(quota);
+    self
+  }
+
+  /// Run `hook` once the objects pushed are checked and before any ref is
+  /// updated, like git's `pre-receive` hook. It's given the quarantine the
+  /// objects are in, which only has the objects pushed, and the updates
+  /// still to be made. An error turns away the whole push and is sent to
+  /// the client.
+  pub fn pre_receive(
+    &mut self,
+    hook: impl FnMut(&Odb, &[RefCommand]) -> Result<(), BString> + 'a,
+  ) -> &mut Self {
+    self.pre_receive.push(Box::new(hook));
+    self
+  }
+
+  /// Run `hook` for every ref right before it's updated, like git's
+  /// `update` hook, when the objects pushed are in the repository. An
+  /// error turns away that ref and is sent to the client.
+  pub fn update(&mut self, hook: impl FnMut(&RefCommand) -> Result<(), BString> + 'a) -> &mut Self {
+    self.update.push(Box::new(hook));
+    self
+  }
+
+  /// The refs this connection sees, sorted by name. Symbolic refs are
+  /// shown as the ref they point at, and `HEAD` isn't shown since it
+  /// isn't pushed to.
+  pub fn advertised_refs(&self) -> Result<Vec<AdvertisedRef>, ReceivePackError> {
+    let refs = self.repo.refs();
+    let mut advertised = Vec::new();
+    for reference in refs.list("refs/")? {
+      if self.hidden.is_hidden(reference.name()) {
+        continue;
+      }
+      let oid = match reference.target() {
+        RefTarget::Direct(oid) => *oid,
+        RefTarget::Symbolic(target) => match refs.resolve(target)? {
+          Some(oid) if !self.hidden.is_hidden(target) => oid,
+          _ => continue,
+        },
+      };
+      advertised.push(AdvertisedRef {
+        name: reference.name().to_owned(),
+        oid,
+        peeled: None,
+        symref_target: None,
+      });
+    }
+    Ok(advertised)
+  }
+
+  /// Write the protocol v0 ref advertisement for this connection, one
+  /// pkt-line per ref ending in a flush, with the capabilities after a NUL
+  /// on the first line. Without any refs a `capabilities^{}` line carries
+  /// them instead.
+  pub fn write_advertisement(&self, out: &mut impl io::Write) -> Result<(), ReceivePackError> {
+    let mut lines: Vec<Vec<u8>> = self
+      .advertised_refs()?
+      .iter()
+      .map(|reference| format!("{} {}", reference.oid, reference.name).into_bytes())
+      .collect();
+    if lines.is_empty() {
+      lines.push(format!("{} capabilities^{{}}", "0".repeat(40)).into_bytes());
+    }
+    lines[0].push(0);
+    lines[0].extend_from_slice(self.capabilities.join(" ").as_bytes());
+
+    let mut bytes = Vec::new();
+    for mut line in lines {
+      line.push(b'\n');
+      Packet::Data(line.as_bstr()).encode(&mut bytes)?;
+    }
+    Packet::Flush.encode(&mut bytes)?;
+    out.write_all(&bytes)?;
+    Ok(())
+  }
+
+  /// Write the advertisement the way smart HTTP sends it in response to
+  /// `GET info/refs?service=git-receive-pack`, with a `# service` line and
+  /// a flush in front of it
+  pub fn write_http_advertisement(&self, out: &mut impl io::Write) -> Result<(), ReceivePackError> {
+    let mut bytes = Vec::new();
+    Packet::Data(b"# service=git-receive-pack\n".as_bstr()).encode(&mut bytes)?;
+    Packet::Flush.encode(&mut bytes)?;
+    out.write_all(&bytes)?;
+    self.write_advertisement(out)
+  }
+
+  /// Serve a whole push over a connection that stays open, like `git
+  /// receive-pack` does over SSH: the advertisement goes out, then the
+  /// client sends the ref updates and a pack, and gets a report of what was
+  /// updated. A client that only wanted to see the refs hangs up after the
+  /// advertisement, which isn't an error.
+  ///
+  /// Returns the updates asked for along with what came of them. If the
+  /// pack couldn't be stored the client is told and that's the error.
+  pub fn serve(
+    &mut self,
+    input: impl io::Read,
+    mut output: impl io::Write,
+  ) -> Result<Vec<RefCommand>, ReceivePackError> {
+    self.write_advertisement(&mut output)?;
+    self.serve_stateless(input, output)
+  }
+
+  /// Serve the request of a stateless connection, like the body of a
+  /// `POST` to `git-receive-pack` over smart HTTP whose advertisement came
+  /// from [`ReceivePack::write_http_advertisement`] before. A push is only
+  /// ever one request, so this is [`ReceivePack::serve`] without the
+  /// advertisement.
+  pub fn serve_stateless(
+    &mut self,
+    input: impl io::Read,
+    mut output: impl io::Write,
+  ) -> Result<Vec<RefCommand>, ReceivePackError> {
+    let mut reader = PktLineReader::new(input);
+    let mut commands = Vec::new();
+    let mut capabilities: Vec<String> = Vec::new();
+    loop {
+      let line = match reader.read_packet()? {
+        // Nothing to push, the refs were all that was needed
+        None | Some(Packet::Flush) if commands.is_empty() => return Ok(commands),
+        Some(Packet::Flush) => break,
+        Some(Packet::Data(line)) => line,
+        other => return Err(unexpected(other)),
+      };
+      let line = line.strip_suffix(b"\n").unwrap_or(line);
+      // Capabilities come only on the first command
+      let line = match line.find_byte(0) {
+        Some(nul) if commands.is_empty() => {
+          capabilities = line[nul + 1..]
+            .split_str(" ")
+            .map(|word| word.to_str_lossy().into_owned())
+            .collect();
+          &line[..nul]
+        }
+        _ => line,
+      };
+      let mut words = line.splitn_str(3, " ");
+      let (old, new, name) = match (words.next(), words.next(), words.next()) {
+        (Some(old), Some(new), Some(name)) => (parse_oid(old)?, parse_oid(new)?, name),
+        _ => return Err(unexpected(Some(Packet::Data(line.as_bstr())))),
+      };
+      commands.push(RefCommand {
+        name: name.into(),
+        old,
+        new,
+        error: None,
+      });
+    }
+    let has = |capability: &str| capabilities.iter().any(|c| c == capability);
+    let (side_band, atomic) = (has("side-band-64k"), has("atomic"));
+    let quota = match self.quota {
+      Some(quota) => quota,
+      None => ReceiveQuota::from_config(self.repo.config())?,
+    };
+
+    // There's only a pack when something is created or updated
+    let quarantine = Quarantine::new(self.repo);
+    if commands.iter().any(|command| command.new.is_some()) {
+      let unpacked = pack::read_pack(reader.into_inner())
+        .map_err(ReceivePackError::from)
+        .and_then(|pack| {
+          quota.check_pack(self.repo, &pack)?;
+          quarantine.odb.write_thin_pack(&pack, self.repo.odb())?;
+          Ok(())
+        });
+      if let Err(e) = unpacked {
+        for command in &mut commands {
+          command.error = Some("unpacker error".into());
+        }
+        let report = report(&format!("unpack {}", e), &commands)?;
+        send(&mut output, side_band, &[], &report)?;
+        return Err(e);
+      }
+    }
+
+    let mut messages = Vec::new();
+    match quota.check_ref_updates(commands.len()) {
+      Ok(()) => self.update_refs(&mut commands, &quarantine, atomic, &mut messages)?,
+      Err(e) => {
+        for command in &mut commands {
+          command.error = Some(e.to_string().into());
+        }
+      }
+    }
+    let report = report("unpack ok", &commands)?;
+    send(&mut output, side_band, &messages, &report)?;
+    Ok(commands)
+  }
+
+  /// Update the refs of `commands` that pass every check and hook, setting
+  /// the error of the others. Messages from the hooks are added to
+  /// `messages`.
+  fn update_refs(
+    &mut self,
+    commands: &mut [RefCommand],
+    quarantine: &Quarantine,
+    atomic: bool,
+    messages: &mut Vec<BString>,
+  ) -> Result<(), ReceivePackError> {
+    let (repo, config) = (self.repo, self.repo.config());
+    let deny_deletes = config.get_bool("receive.denydeletes")?.unwrap_or(false);
+    let deny_non_fast_forwards = config
+      .get_bool("receive.denynonfastforwards")?
+      .unwrap_or(false);
+    // Updating the branch that's checked out would leave the working tree
+    // and the index behind it
+    let allowed = ["ignore", "warn", "false", "no", "off", "0"];
+    let current_branch = match config.get("receive.denycurrentbranch") {
+      _ if repo.is_bare() => None,
+      Some(value)
+        if allowed
+          .iter()
+          .any(|a| value.eq_ignore_ascii_case(a.as_bytes())) =>
+      {
+        None
+      }
+      _ => match repo.refs().read("HEAD")?.map(|head| head.target().clone()) {
+        Some(RefTarget::Symbolic(branch)) => Some(branch),
+        _ => None,
+      },
+    };
+    for command in commands.iter_mut() {
+      let error = if !command.name.starts_with(b"refs/") || check_ref_name(&command.name).is_err() {
+        Some("funny refname")
+      } else if self.hidden.is_hidden(&command.name) {
+        Some("deny updating a hidden ref")
+      } else if command.new.is_none() && deny_deletes {
+        Some("deletion prohibited")
+      } else if current_branch.as_ref() == Some(&command.name) {
+        Some("branch is currently checked out")
+      } else {
+        match command.new {
+          Some(new) if !is_connected(repo, &quarantine.odb, &new)? => {
+            Some("missing necessary objects")
+          }
+          _ => None,
+        }
+      };
+      command.error = error.map(BString::from);
+    }
+    if fail_atomic(commands, atomic) {
+      return Ok(());
+    }
+
+    let pending: Vec<RefCommand> = commands
+      .iter()
+      .filter(|command| command.error.is_none())
+      .cloned()
+      .collect();
+    if pending.is_empty() {
+      return Ok(());
+    }
+    for hook in &mut self.pre_receive {
+      if let Err(message) = hook(&quarantine.odb, &pending) {
+        messages.push(message);
+        for command in commands
+          .iter_mut()
+          .filter(|command| command.error.is_none())
+        {
+          command.error = Some("pre-receive hook declined".into());
+        }
+        return Ok(());
+      }
+    }
+    quarantine.migrate(repo)?;
+
+    for command in commands
+      .iter_mut()
+      .filter(|command| command.error.is_none())
+    {
+      if let (Some(old), Some(new), true) = (command.old, command.new, deny_non_fast_forwards) {
+        if !is_ancestor(repo, &old, &new)? {
+          command.error = Some("non-fast-forward".into());
+          continue;
+        }
+      }
+      for hook in &mut self.update {
+        if let Err(message) = hook(command) {
+          messages.push(message);
+          command.error = Some("hook declined".into());
+          break;
+        }
+      }
+    }
+    if fail_atomic(commands, atomic) {
+      return Ok(());
+    }
+    let pending = commands
+      .iter_mut()
+      .filter(|command| command.error.is_none());
+    match atomic {
+      true => {
+        let pending: Vec<&mut RefCommand> = pending.collect();
+        let mut transaction = repo.refs().transaction();
+        for command in &pending {
+          transaction.update(command.name.clone(), command.old, command.new);
+        }
+        if let Err(e) = transaction.commit() {
+          for command in pending {
+            command.error = Some(e.to_string().into());
+          }
+        }
+      }
+      false => {
+        for command in pending {
+          let mut transaction = repo.refs().transaction();
+          transaction.update(command.name.clone(), command.old, command.new);
+          if let Err(e) = transaction.commit() {
+            command.error = Some(e.to_string().into());
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Turn away every command of an atomic push once one of them is, returning
+/// whether they were
+fn fail_atomic(commands: &mut [RefCommand], atomic: bool) -> bool {
+  if !atomic || commands.iter().all(|command| command.error.is_none()) {
+    return false;
+  }
+  for command in commands
+    .iter_mut()
+    .filter(|command| command.error.is_none())
+  {
+    command.error = Some("atomic push failed".into());
+  }
+  true
+}
+
+/// Where the objects of a push wait until they're let into the repository.
+/// The directory is removed with everything left in it once it's dropped.
+struct Quarantine {
+  odb: Odb,
+}
+
+impl Quarantine {
+  fn new(repo: &Repository) -> Self {
+    let path = repo.odb().path().join(cleanup::temp_name("objdir"));
+    Self {
+      odb: Odb::new(path).with_budget(repo.odb().budget().clone()),
+    }
+  }
+
+  /// Move the packs into the repository, each index last so other readers
+  /// only see a pack once it's all there
+  fn migrate(&self, repo: &Repository) -> io::Result<()> {
+    let entries = match fs::read_dir(self.odb.path().join("pack")) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(e),
+    };
+    let mut paths = entries
+      .map(|entry| entry.map(|entry| entry.path()))
+      .collect::<io::Result<Vec<PathBuf>>>()?;
+    paths.sort_by_key(|path| path.extension() == Some("idx".as_ref()));
+    let dir = repo.odb().path().join("pack");
+    fs::create_dir_all(&dir)?;
+    for path in paths {
+      // The path always has a file name since it came from the directory
+      let dest = dir.join(path.file_name().unwrap());
+      if !dest.exists() {
+        fs::rename(&path, &dest)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Drop for Quarantine {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(self.odb.path());
+  }
+}
+
+/// Whether everything `oid` points at all the way down is either in the
+/// quarantine or already in the repository, where it's taken to be
+/// complete like git does
+fn is_connected(repo: &Repository, quarantine: &Odb, oid: &OID) -> Result<bool, OdbError> {
+  let mut seen = HashSet::new();
+  let mut pending = vec![*oid];
+  while let Some(oid) = pending.pop() {
+    if !seen.insert(oid) {
+      continue;
+    }
+    let object = match quarantine.read(&oid) {
+      Ok(object) => object,
+      Err(OdbError::NotFound(_)) => match repo.odb().read(&oid) {
+        Ok(_) => continue,
+        Err(OdbError::NotFound(_)) => return Ok(false),
+        Err(e) => return Err(e),
+      },
+      Err(e) => return Err(e),
+    };
+    match object.kind {
+      ObjectKind::Commit => {
+        let commit = Commit::parse(object.data)?;
+        pending.push(*commit.tree());
+        pending.extend(commit.parents().iter().copied());
+      }
+      ObjectKind::Tree => {
+        let tree = Tree::parse(object.data)?;
+        // Submodule commits are in other repositories
+        let entries = tree.entries().iter();
+        let entries = entries.filter(|entry| entry.mode() != FileMode::GitLink);
+        pending.extend(entries.map(|entry| *entry.oid()));
+      }
+      ObjectKind::Tag => pending.push(*Tag::parse(object.data)?.object()),
+      ObjectKind::Blob => {}
+    }
+  }
+  Ok(true)
+}
+
+/// The `report-status` of a push, which `report-status-v2` clients read
+/// the same way
+fn report(unpack: &str, commands: &[RefCommand]) -> Result<Vec<u8>, PktLineError> {
+  let mut bytes = Vec::new();
+  Packet::Data(format!("{}\n", unpack).as_bytes().as_bstr()).encode(&mut bytes)?;
+  for command in commands {
+    let line = match &command.error {
+      None => format!("ok {}\n", command.name),
+      Some(error) => format!("ng {} {}\n", command.name, error),
+    };
+    Packet::Data(line.as_bytes().as_bstr()).encode(&mut bytes)?;
+  }
+  Packet::Flush.encode(&mut bytes)?;
+  Ok(bytes)
+}
+
+/// Send `report` to the client, on band 1 of the side-band with the
+/// messages of hooks on band 2 before it if the client asked for one.
+/// Without it the messages have nowhere to go.
+fn send(
+  out: &mut impl io::Write,
+  side_band: bool,
+  messages: &[BString],
+  report: &[u8],
+) -> Result<(), ReceivePackError> {
+  if !side_band {
+    out.write_all(report)?;
+    return Ok(out.flush()?);
+  }
+  let len = Packet::MAX_LEN - 5;
+  let mut bytes = Vec::new();
+  for message in messages {
+    let message = [&message[..], b"\n"].concat();
+    for chunk in message.chunks(len) {
+      Packet::Data([&[2], chunk].concat().as_bstr()).encode(&mut bytes)?;
+    }
+  }
+  SideBand {
+    out: &mut bytes,
+    len,
+  }
+  .write_all(report)?;
+  Packet::Flush.encode(&mut bytes)?;
+  out.write_all(&bytes)?;
+  Ok(out.flush()?)
+}
+
+/// An object id of a ref update, where all zeros is no object
+fn parse_oid(hex: &[u8]) -> Result<Option<OID>, ReceivePackError> {
+  let oid = hex
+    .to_str()
+    .ok()
+    .and_then(|hex| OID::from_hex(hex).ok())
+    .ok_or_else(|| ReceivePackError::Protocol(format!("invalid object id {:?}", hex.as_bstr())))?;
+  Ok(Some(oid).filter(|oid| *oid.as_bytes() != [0; 20]))
+}
+
+fn unexpected(found: Option<Packet<'_>>) -> ReceivePackError {
+  let found = match found {
+    Some(Packet::Data(line)) => format!("{:?}", line),
+    Some(packet) => format!("{:?}", packet),
+    None => "the end of the request".into(),
+  };
+  ReceivePackError::Protocol(format!("expected a ref update, found {}", found))
+}
+
+#[derive(Error, Debug)]
+/// Errors related to taking pushes with [`ReceivePack`]
+pub enum ReceivePackError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  PktLine(#[from] PktLineError),
+  #[error("{0}")]
+  Pack(#[from] PackError),
+  #[error("{0}")]
+  Quota(#[from] QuotaError),
+  #[error("{0}")]
+  Transport(#[from] TransportError),
+  #[error("protocol error: {0}")]
+  Protocol(String),
+}
+
+#[test]
+fn serve() {
+  use crate::{
+    transport::{
+      http::{serve_smart_http, HttpTransport},
+      write_pack_for, PushStatus, PushUpdate, Transport,
+    },
+    Blob, Signature, Time, TreeEntry,
+  };
+  use std::cell::RefCell;
+  let tmp_dir = tempdir::TempDir::new("receive_pack_test").unwrap();
+  let server = Repository::init_bare(tmp_dir.path().join("server.git")).unwrap();
+  let client = Repository::init(tmp_dir.path().join("client")).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |contents: &str, parents: Vec<OID>| {
+    let odb = client.odb();
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(
+      tree,
+      parents,
+      signature.clone(),
+      signature.clone(),
+      "commit\n",
+    );
+    let oid = odb.write_commit(&commit).unwrap();
+    client.refs().write("refs/heads/master", &oid).unwrap();
+    oid
+  };
+  let first = commit("first\n", vec![]);
+  let second = commit("second\n", vec![first]);
+  let zero = "0".repeat(40);
+  let request = |commands: &[(&str, &str, &str)], capabilities: &str, haves: &[OID]| {
+    let mut bytes = Vec::new();
+    for (i, (old, new, name)) in commands.iter().enumerate() {
+      let mut line = format!("{} {} {}", old, new, name);
+      if i == 0 {
+        line = format!("{}\0{}", line, capabilities);
+      }
+      Packet::Data(format!("{}\n", line).as_bytes().as_bstr())
+        .encode(&mut bytes)
+        .unwrap();
+    }
+    Packet::Flush.encode(&mut bytes).unwrap();
+    let wants: Vec<OID> = commands
+      .iter()
+      .filter_map(|(_, new, _)| OID::from_hex(new).ok())
+      .filter(|oid| *oid.as_bytes() != [0; 20] && client.odb().read(oid).is_ok())
+      .collect();
+    if commands.iter().any(|(_, new, _)| *new != zero) {
+      bytes = write_pack_for(&client, &wants, haves, true, bytes)
+        .unwrap()
+        .0;
+    }
+    bytes
+  };
+  // What came on each band of the side-band
+  let bands = |mut bytes: &[u8]| {
+    let (mut report, mut messages) = (Vec::new(), Vec::new());
+    while let Some((packet, len)) = Packet::decode(bytes).unwrap() {
+      if let Packet::Data(data) = packet {
+        match data[0] {
+          1 => report.extend_from_slice(&data[1..]),
+          _ => messages.extend_from_slice(&data[1..]),
+        }
+      }
+      bytes = &bytes[len..];
+    }
+    let mut lines = Vec::new();
+    let mut report = &report[..];
+    while let Some((Packet::Data(line), len)) = Packet::decode(report).unwrap() {
+      lines.push(line.trim_end().to_str().unwrap().to_owned());
+      report = &report[len..];
+    }
+    (lines, messages.into())
+  };
+  let quarantined = || {
+    std::fs::read_dir(server.odb().path())
+      .unwrap()
+      .any(|entry| {
+        entry
+          .unwrap()
+          .file_name()
+          .to_str()
+          .unwrap()
+          .starts_with("tmp_")
+      })
+  };
+  let refs = server.refs();
+
+  let input = request(
+    &[
+      (&zero, &first.to_string(), "refs/heads/master"),
+      (&zero, &first.to_string(), "refs/tags/v1.0"),
+    ],
+    "report-status side-band-64k",
+    &[],
+  );
+  let mut output = Vec::new();
+  let commands = ReceivePack::new(&server)
+    .serve_stateless(&input[..], &mut output)
+    .unwrap();
+  assert!(commands.iter().all(|command| command.error.is_none()));
+  let (report, _): (_, BString) = bands(&output);
+  assert_eq!(
+    vec!["unpack ok", "ok refs/heads/master", "ok refs/tags/v1.0"],
+    report
+  );
+  assert_eq!(Some(first), refs.resolve("refs/heads/master").unwrap());
+  assert!(!quarantined());
+
+  // A pre-receive hook sees only the objects pushed and can turn the push
+  // away, which leaves none of them behind
+  let input = request(
+    &[
+      (&first.to_string(), &second.to_string(), "refs/heads/master"),
+      (&zero, &second.to_string(), "refs/heads/secret/plan"),
+    ],
+    "report-status side-band-64k",
+    &[first],
+  );
+  let mut output = Vec::new();
+  let seen = RefCell::new(Vec::new());
+  ReceivePack::new(&server)
+    .pre_receive(|odb, commands| {
+      assert!(odb.read(&second).is_ok());
+      assert!(matches!(odb.read(&first), Err(OdbError::NotFound(_))));
+      seen
+        .borrow_mut()
+        .extend(commands.iter().map(|c| c.name.clone()));
+      match commands
+        .iter()
+        .any(|c| c.name.starts_with(b"refs/heads/secret/"))
+      {
+        true => Err("no secrets".into()),
+        false => Ok(()),
+      }
+    })
+    .serve_stateless(&input[..], &mut output)
+    .unwrap();
+  assert_eq!(2, seen.borrow().len());
+  let (report, messages) = bands(&output);
+  assert_eq!(
+    vec![
+      "unpack ok",
+      "ng refs/heads/master pre-receive hook declined",
+      "ng refs/heads/secret/plan pre-receive hook declined",
+    ],
+    report
+  );
+  assert_eq!("no secrets\n", messages);
+  assert_eq!(Some(first), refs.resolve("refs/heads/master").unwrap());
+  assert!(matches!(
+    server.odb().read(&second),
+    Err(OdbError::NotFound(_))
+  ));
+  assert!(!quarantined());
+
+  // An update hook turns away one ref, which with atomic is all of them
+  let updates = [
+    (first.to_string(), second.to_string(), "refs/heads/master"),
+    (zero.clone(), second.to_string(), "refs/heads/topic"),
+  ];
+  let updates: Vec<(&str, &str, &str)> = updates
+    .iter()
+    .map(|(old, new, name)| (&old[..], &new[..], *name))
+    .collect();
+  for (capabilities, expected) in [
+    (
+      "report-status atomic",
+      [
+        "ng refs/heads/master atomic push failed",
+        "ng refs/heads/topic hook declined",
+      ],
+    ),
+    (
+      "report-status",
+      ["ok refs/heads/master", "ng refs/heads/topic hook declined"],
+    ),
+  ] {
+    let input = request(&updates, capabilities, &[first]);
+    let mut output = Vec::new();
+    ReceivePack::new(&server)
+      .update(|command| match command.name == "refs/heads/topic" {
+        true => Err("no topics".into()),
+        false => Ok(()),
+      })
+      .serve_stateless(&input[..], &mut output)
+      .unwrap();
+    let mut reader = crate::transport::PacketReader::new(&output);
+    assert_eq!("unpack ok", reader.read_line().unwrap().unwrap());
+    for line in &expected {
+      assert_eq!(*line, reader.read_line().unwrap().unwrap());
+    }
+  }
+  assert_eq!(Some(second), refs.resolve("refs/heads/master").unwrap());
+  assert_eq!(None, refs.resolve("refs/heads/topic").unwrap());
+
+  // Refs that moved, objects that never came, and names that aren't refs
+  let missing = OID::hash("missing");
+  let input = request(
+    &[
+      (&first.to_string(), &first.to_string(), "refs/heads/master"),
+      (&zero, &missing.to_string(), "refs/heads/missing"),
+      (&zero, &first.to_string(), "HEAD"),
+      (&first.to_string(), &zero, "refs/tags/v1.0"),
+    ],
+    "report-status",
+    &[],
+  );
+  let mut output = Vec::new();
+  let commands = ReceivePack::new(&server)
+    .serve_stateless(&input[..], &mut output)
+    .unwrap();
+  let errors: Vec<Option<&str>> = commands
+    .iter()
+    .map(|c| c.error.as_ref().map(|e| e.to_str().unwrap()))
+    .collect();
+  assert_eq!(
+    vec![
+      Some("ref refs/heads/master isn't where it was expected to be"),
+      Some("missing necessary objects"),
+      Some("funny refname"),
+      None
+    ],
+    errors
+  );
+  assert_eq!(None, refs.resolve("refs/tags/v1.0").unwrap());
+
+  let mut input = request(
+    &[(&zero, &second.to_string(), "refs/heads/broken")],
+    "report-status",
+    &[],
+  );
+  let len = input.len();
+  input[len - 1] ^= 1;
+  let mut output = Vec::new();
+  assert!(matches!(
+    ReceivePack::new(&server).serve_stateless(&input[..], &mut output),
+    Err(ReceivePackError::Odb(_))
+  ));
+  let mut reader = crate::transport::PacketReader::new(&output);
+  assert!(reader.read_line().unwrap().unwrap().starts_with(b"unpack "));
+  assert_eq!(
+    "ng refs/heads/broken unpacker error",
+    reader.read_line().unwrap().unwrap()
+  );
+  assert!(!quarantined());
+
+  // Enough of smart HTTP to take pushes from git and from this crate
+  let git_dir = server.git_dir().to_path_buf();
+  let url = serve_smart_http("receive-pack", move |advertise, body, response| {
+    let repo = Repository::open(&git_dir).unwrap();
+    let mut receive_pack = ReceivePack::new(&repo);
+    match advertise {
+      true => receive_pack.write_http_advertisement(response).unwrap(),
+      false => {
+        receive_pack.serve_stateless(body, response).unwrap();
+      }
+    }
+  }) + "/server.git";
+  let third = commit("third\n", vec![second]);
+  let mut transport = HttpTransport::new(&url).unwrap();
+  let outcome = transport
+    .push(&client, &[PushUpdate::new("refs/heads/master", third)])
+    .unwrap();
+  assert_eq!(PushStatus::Ok, outcome.refs[0].status);
+  assert_eq!(Some(third), refs.resolve("refs/heads/master").unwrap());
+  assert_eq!("third\n", {
+    let commit = server.odb().read_commit(&third).unwrap();
+    let tree = server.odb().read_tree(commit.tree()).unwrap();
+    let blob = server.odb().read_blob(tree.entries()[0].oid()).unwrap();
+    blob.contents().to_str().unwrap().to_owned()
+  });
+
+  if crate::transport::http::have_git() {
+    let fourth = commit("fourth\n", vec![third]);
+    let output = std::process::Command::new("git")
+      .args(["push", "--quiet", &url, "master:refs/heads/git"])
+      .current_dir(tmp_dir.path().join("client"))
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{}", output.stderr.as_bstr());
+    assert_eq!(Some(fourth), refs.resolve("refs/heads/git").unwrap());
+  }
+}
@@ -0,0 +1,180 @@
+use crate::{refs::check_ref_name, RefError, RefStore, Signature, OID};
+use bstr::{BString, ByteSlice};
+use std::{fs, io, io::Write, path::PathBuf};
+
+/// One change to a ref as recorded in its reflog under `logs/`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+  /// What the ref pointed at before, the all zero [`OID`] if it didn't
+  /// exist
+  pub old: OID,
+  /// What the ref points at after
+  pub new: OID,
+  /// Who changed the ref and when
+  pub committer: Signature,
+  /// Why the ref changed, like `commit: Fix the build`
+  pub message: BString,
+}
+
+impl ReflogEntry {
+  /// Create a new [`ReflogEntry`]. Line breaks in the message are turned
+  /// into spaces since every entry is a single line.
+  pub fn new(old: OID, new: OID, committer: Signature, message: impl Into<BString>) -> Self {
+    let mut message = message.into();
+    while message.last() == Some(&b'\n') {
+      message.pop();
+    }
+    for byte in message.iter_mut().filter(|byte| **byte == b'\n') {
+      *byte = b' ';
+    }
+    Self {
+      old,
+      new,
+      committer,
+      message,
+    }
+  }
+
+  /// Parse a line of a reflog without the line ending
+  pub fn parse(line: impl AsRef<[u8]>) -> Option<Self> {
+    let line = line.as_ref();
+    let oid = |hex: &[u8]| OID::from_hex(hex.to_str().ok()?).ok();
+    let old = oid(line.get(..40)?)?;
+    let new = oid(line.get(41..81)?)?;
+    let rest = line.get(82..)?;
+    let (committer, message) = match rest.find_byte(b'\t') {
+      Some(tab) => (&rest[..tab], &rest[tab + 1..]),
+      None => (rest, &b""[..]),
+    };
+    Some(Self {
+      old,
+      new,
+      committer: Signature::parse(committer).ok()?,
+      message: message.into(),
+    })
+  }
+
+  /// The entry as a line of a reflog, with the line ending
+  pub fn as_bytes(&self) -> Vec<u8> {
+    let mut line = format!("{} {} ", self.old, self.new).into_bytes();
+    line.extend(self.committer.as_bytes());
+    if !self.message.is_empty() {
+      line.push(b'\t');
+      line.extend_from_slice(&self.message);
+    }
+    line.push(b'\n');
+    line
+  }
+}
+
+impl RefStore {
+  fn reflog_path(&self, name: &[u8]) -> Result<PathBuf, RefError> {
+    check_ref_name(name)?;
+    let path = name
+      .to_path()
+      .map_err(|_| RefError::InvalidName(name.into()))?;
+    Ok(self.dir_of(name).join("logs").join(path))
+  }
+
+  /// Whether the ref `name` has a reflog
+  pub fn has_reflog(&self, name: impl AsRef<[u8]>) -> Result<bool, RefError> {
+    Ok(self.reflog_path(name.as_ref())?.is_file())
+  }
+
+  /// The reflog of the ref `name`, oldest entry first. A ref without a
+  /// reflog has no entries.
+  pub fn reflog(&self, name: impl AsRef<[u8]>) -> Result<Vec<ReflogEntry>, RefError> {
+    let name = name.as_ref();
+    let contents = match fs::read(self.reflog_path(name)?) {
+      Ok(contents) => contents,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(e) => return Err(e.into()),
+    };
+    contents
+      .lines()
+      .filter(|line| !line.is_empty())
+      .map(|line| ReflogEntry::parse(line).ok_or_else(|| RefError::Corrupt(name.into())))
+      .collect()
+  }
+
+  /// Add `entry` to the end of the reflog of the ref `name`, creating the
+  /// reflog if there isn't one yet
+  pub fn append_reflog(&self, name: impl AsRef<[u8]>, entry: &ReflogEntry) -> Result<(), RefError> {
+    let path = self.reflog_path(name.as_ref())?;
+    // The path always has a parent since it's inside of the git dir
+    fs::create_dir_all(path.parent().unwrap())?;
+    // A single write of a whole line in append mode is how git keeps
+    // concurrent writers from mixing up their entries
+    fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)?
+      .write_all(&entry.as_bytes())?;
+    Ok(())
+  }
+
+  /// Replace the reflog of the ref `name` with `entries`, oldest first,
+  /// or remove it if there are none. The new reflog is written next to the
+  /// old one and renamed over it, so readers see one or the other.
+  pub fn write_reflog(
+    &self,
+    name: impl AsRef<[u8]>,
+    entries: &[ReflogEntry],
+  ) -> Result<(), RefError> {
+    let path = self.reflog_path(name.as_ref())?;
+    if entries.is_empty() {
+      return match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+      };
+    }
+    fs::create_dir_all(path.parent().unwrap())?;
+    let mut lock_path = path.clone().into_os_string();
+    lock_path.push(".lock");
+    let contents: Vec<u8> = entries.iter().flat_map(ReflogEntry::as_bytes).collect();
+    let result = fs::write(&lock_path, contents).and_then(|_| fs::rename(&lock_path, &path));
+    if let Err(e) = result {
+      let _ = fs::remove_file(&lock_path);
+      return Err(e.into());
+    }
+    Ok(())
+  }
+}
+
+#[test]
+fn read_and_append() {
+  use crate::Time;
+  let tmp_dir = tempdir::TempDir::new("reflog_test").unwrap();
+  let refs = RefStore::new(tmp_dir.path());
+  let zero = OID::from_bytes(&[0; 20]).unwrap();
+  let oid = crate::Blob::new("this is a test").id();
+  let committer = Signature::new(
+    "C O Mitter",
+    "committer@example.com",
+    Time::new(1_234_567_890, 60),
+  );
+  assert_eq!(Vec::<ReflogEntry>::new(), refs.reflog("HEAD").unwrap());
+  assert!(!refs.has_reflog("HEAD").unwrap());
+
+  let first = ReflogEntry::new(zero, oid, committer.clone(), "commit (initial): first\n");
+  let second = ReflogEntry::new(oid, oid, committer, "checkout: moving\nfrom here\n");
+  assert_eq!("checkout: moving from here", second.message);
+  refs.append_reflog("HEAD", &first).unwrap();
+  refs.append_reflog("HEAD", &second).unwrap();
+  assert!(refs.has_reflog("HEAD").unwrap());
+  assert_eq!(vec![first, second], refs.reflog("HEAD").unwrap());
+  assert_eq!(
+    format!(
+      "{} {} C O Mitter <committer@example.com> 1234567890 +0100\tcommit (initial): first",
+      zero, oid
+    ),
+    fs::read_to_string(tmp_dir.path().join("logs/HEAD"))
+      .unwrap()
+      .lines()
+      .next()
+      .unwrap()
+  );
+
+  fs::write(tmp_dir.path().join("logs/HEAD"), "not a reflog\n").unwrap();
+  assert!(matches!(refs.reflog("HEAD"), Err(RefError::Corrupt(_))));
+}
@@ -0,0 +1,633 @@
+use crate::{OIDError, OID};
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  fs,
+  io::{self, Write},
+  path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// How many symbolic refs are followed before giving up, the same limit git
+/// has
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// What a [`Reference`] points at
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RefTarget {
+  /// The ref points straight at an object
+  Direct(OID),
+  /// The ref points at another ref, like `HEAD` pointing at
+  /// `refs/heads/master`
+  Symbolic(BString),
+}
+
+/// A named pointer into the history of a repository such as a branch,
+/// a tag, or `HEAD`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Reference {
+  name: BString,
+  target: RefTarget,
+}
+
+impl Reference {
+  /// Create a new [`Reference`]
+  pub fn new(name: impl Into<BString>, target: RefTarget) -> Self {
+    Self {
+      name: name.into(),
+      target,
+    }
+  }
+
+  /// The full name of the ref, like `refs/heads/master`
+  pub fn name(&self) -> &BStr {
+    self.name.as_bstr()
+  }
+
+  /// What the ref points at
+  pub fn target(&self) -> &RefTarget {
+    &self.target
+  }
+
+  /// The [`OID`] the ref points at if it is not symbolic
+  pub fn oid(&self) -> Option<&OID> {
+    match &self.target {
+      RefTarget::Direct(oid) => Some(oid),
+      RefTarget::Symbolic(_) => None,
+    }
+  }
+}
+
+/// The refs under `refs/` every linked worktree has its own of, on top of
+/// `HEAD` and the others outside of `refs/`
+const PER_WORKTREE: [&[u8]; 3] = [b"refs/worktree/", b"refs/bisect/", b"refs/rewritten/"];
+
+/// The refs of a repository, stored as loose files under the git directory
+/// and in `packed-refs`. A loose ref always takes priority over a packed
+/// ref of the same name. For a linked worktree only its own refs are in its
+/// git directory and the rest are shared with the rest of the repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefStore {
+  git_dir: PathBuf,
+  common_dir: PathBuf,
+}
+
+impl RefStore {
+  /// Create a [`RefStore`] for the given git directory
+  pub fn new(git_dir: impl Into<PathBuf>) -> Self {
+    let git_dir = git_dir.into();
+    Self {
+      common_dir: crate::repository::common_dir(&git_dir),
+      git_dir,
+    }
+  }
+
+  /// The directory the loose ref `name` is stored in, which is only
+  /// different from the shared one for the refs a linked worktree has its
+  /// own of
+  pub(crate) fn dir_of(&self, name: &[u8]) -> &Path {
+    match name != b"packed-refs" && is_per_worktree(name) {
+      true => &self.git_dir,
+      false => &self.common_dir,
+    }
+  }
+
+  /// Read a ref by its full name without following it if it is symbolic
+  pub fn read(&self, name: impl AsRef<[u8]>) -> Result<Option<Reference>, RefError> {
+    let name = name.as_ref();
+    check_ref_name(name)?;
+    let path = self
+      .dir_of(name)
+      .join(name.to_path().map_err(|_| invalid_name(name))?);
+    match fs::read(&path) {
+      Ok(contents) => return parse_loose(name, &contents).map(Some),
+      // A directory is in the way when reading `refs/heads` instead of a
+      // ref inside of it
+      Err(e) if e.kind() == io::ErrorKind::NotFound || path.is_dir() => {}
+      Err(e) => return Err(e.into()),
+    }
+    Ok(
+      self
+        .packed()?
+        .into_iter()
+        .find(|reference| reference.name == name),
+    )
+  }
+
+  /// Follow a ref through any symbolic refs to the ref that points at an
+  /// object. For `HEAD` on a branch this is the branch. A symbolic ref
+  /// pointing at a ref that doesn't exist yet, like `HEAD` in a new
+  /// repository, gives `None`.
+  pub fn follow(&self, name: impl AsRef<[u8]>) -> Result<Option<Reference>, RefError> {
+    let mut name = BString::from(name.as_ref());
+    for _ in 0..=MAX_SYMREF_DEPTH {
+      match self.read(&name)? {
+        Some(Reference {
+          target: RefTarget::Symbolic(target),
+          ..
+        }) => name = target,
+        reference => return Ok(reference),
+      }
+    }
+    Err(RefError::TooDeep(name))
+  }
+
+  /// The [`OID`] a ref points at after following symbolic refs
+  pub fn resolve(&self, name: impl AsRef<[u8]>) -> Result<Option<OID>, RefError> {
+    Ok(
+      self
+        .follow(name)?
+        .and_then(|reference| reference.oid().copied()),
+    )
+  }
+
+  /// Find a ref by a short name like `master`, `v1.0`, or `origin/main`
+  /// using the same rules as git: the name as is, then under `refs/`,
+  /// `refs/tags/`, `refs/heads/`, `refs/remotes/`, and finally
+  /// `refs/remotes/{name}/HEAD`.
+  pub fn find(&self, short: &str) -> Result<Option<Reference>, RefError> {
+    for candidate in [
+      short.to_string(),
+      format!("refs/{}", short),
+      format!("refs/tags/{}", short),
+      format!("refs/heads/{}", short),
+      format!("refs/remotes/{}", short),
+      format!("refs/remotes/{}/HEAD", short),
+    ] {
+      if check_ref_name(candidate.as_bytes()).is_err() {
+        continue;
+      }
+      // Only names like HEAD or FETCH_HEAD are looked up outside of refs/
+      if !candidate.starts_with("refs/") && !is_pseudo_ref(candidate.as_bytes()) {
+        continue;
+      }
+      if let Some(reference) = self.read(&candidate)? {
+        return Ok(Some(reference));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Every ref whose name starts with `prefix`, sorted by name. Use
+  /// `refs/` for all of them.
+  pub fn list(&self, prefix: impl AsRef<[u8]>) -> Result<Vec<Reference>, RefError> {
+    let prefix = prefix.as_ref();
+    let mut refs = Vec::new();
+    self.list_loose(&self.common_dir.join("refs"), b"refs/", &mut refs)?;
+    if self.git_dir != self.common_dir {
+      // What the main worktree has of its own is left out for the one of
+      // this worktree
+      refs.retain(|reference| !is_per_worktree(&reference.name));
+      for prefix in PER_WORKTREE.iter() {
+        let dir = self.git_dir.join(prefix.to_path_lossy());
+        self.list_loose(&dir, prefix, &mut refs)?;
+      }
+    }
+    for reference in self.packed()? {
+      if !refs.iter().any(|r: &Reference| r.name == reference.name) {
+        refs.push(reference);
+      }
+    }
+    refs.retain(|reference| reference.name.starts_with(prefix));
+    refs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(refs)
+  }
+
+  fn list_loose(
+    &self,
+    dir: &Path,
+    prefix: &[u8],
+    refs: &mut Vec<Reference>,
+  ) -> Result<(), RefError> {
+    let entries = match fs::read_dir(dir) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+      let entry = entry?;
+      let file_name = entry.file_name();
+      let file_name = match file_name.to_str() {
+        Some(name) => name,
+        None => continue,
+      };
+      let name = [prefix, file_name.as_bytes()].concat();
+      if entry.file_type()?.is_dir() {
+        self.list_loose(&entry.path(), &[&name[..], b"/"].concat(), refs)?;
+      } else if check_ref_name(&name).is_ok() {
+        refs.push(parse_loose(&name, &fs::read(entry.path())?)?);
+      }
+    }
+    Ok(())
+  }
+
+  fn packed(&self) -> Result<Vec<Reference>, RefError> {
+    let contents = match fs::read(self.common_dir.join("packed-refs")) {
+      Ok(contents) => contents,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(e) => return Err(e.into()),
+    };
+    let mut refs = Vec::new();
+    for line in contents.lines() {
+      // The header lists the traits of the file and `^` lines hold the
+      // object an annotated tag above them peels to
+      if line.is_empty() || line.starts_with(b"#") || line.starts_with(b"^") {
+        continue;
+      }
+      let corrupt = || RefError::Corrupt(BString::from("packed-refs"));
+      let space = line.find_byte(b' ').ok_or_else(corrupt)?;
+      let oid = parse_oid(&line[..space]).map_err(|_| corrupt())?;
+      refs.push(Reference::new(&line[space + 1..], RefTarget::Direct(oid)));
+    }
+    Ok(refs)
+  }
+
+  /// Point a ref at an [`OID`], creating it if it doesn't exist
+  pub fn write(&self, name: impl AsRef<[u8]>, oid: &OID) -> Result<(), RefError> {
+    self.write_loose(name.as_ref(), format!("{}\n", oid).as_bytes())
+  }
+
+  /// Point a ref at another ref, like `HEAD` at `refs/heads/master`
+  pub fn write_symbolic(
+    &self,
+    name: impl AsRef<[u8]>,
+    target: impl AsRef<[u8]>,
+  ) -> Result<(), RefError> {
+    let target = target.as_ref();
+    check_ref_name(target)?;
+    self.write_loose(name.as_ref(), &[b"ref: ", target, b"\n"].concat())
+  }
+
+  /// Delete a ref, both the loose file and its line in `packed-refs`. A
+  /// symbolic ref is deleted itself rather than the ref it points at.
+  /// Returns whether there was a ref to delete.
+  pub fn delete(&self, name: impl AsRef<[u8]>) -> Result<bool, RefError> {
+    let name = name.as_ref();
+    check_ref_name(name)?;
+    let path = self
+      .dir_of(name)
+      .join(name.to_path().map_err(|_| invalid_name(name))?);
+    let mut deleted = match fs::remove_file(&path) {
+      Ok(()) => true,
+      Err(e) if e.kind() == io::ErrorKind::NotFound || path.is_dir() => false,
+      Err(e) => return Err(e.into()),
+    };
+    // Directories left empty by the ref are removed like git does, which
+    // stops at the first one that still has something in it
+    let refs_dir = self.dir_of(name).join("refs");
+    let mut dir = path.parent();
+    while let Some(parent) = dir.filter(|dir| dir.starts_with(&refs_dir) && *dir != refs_dir) {
+      if fs::remove_dir(parent).is_err() {
+        break;
+      }
+      dir = parent.parent();
+    }
+
+    let packed_path = self.common_dir.join("packed-refs");
+    let contents = match fs::read(&packed_path) {
+      Ok(contents) => contents,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(deleted),
+      Err(e) => return Err(e.into()),
+    };
+    let mut kept = Vec::with_capacity(contents.len());
+    let mut skipping = false;
+    for line in contents.lines_with_terminator() {
+      // The `^` line after a ref belongs to it
+      if skipping && line.starts_with(b"^") {
+        continue;
+      }
+      skipping = line
+        .find_byte(b' ')
+        .is_some_and(|space| !line.starts_with(b"#") && line[space + 1..].trim_end() == name);
+      if skipping {
+        deleted = true;
+      } else {
+        kept.extend_from_slice(line);
+      }
+    }
+    if kept.len() != contents.len() {
+      // Rewritten under `packed-refs.lock` the same way loose refs are
+      self.write_loose(b"packed-refs", &kept)?;
+    }
+    Ok(deleted)
+  }
+
+  /// Start changing several refs at once
+  pub fn transaction(&self) -> RefTransaction<'_> {
+    RefTransaction {
+      refs: self,
+      updates: Vec::new(),
+    }
+  }
+
+  fn write_loose(&self, name: &[u8], contents: &[u8]) -> Result<(), RefError> {
+    let (path, lock_path, mut lock) = self.lock(name)?;
+    let result = lock
+      .write_all(contents)
+      .and_then(|_| fs::rename(&lock_path, &path));
+    if let Err(e) = result {
+      let _ = fs::remove_file(&lock_path);
+      return Err(e.into());
+    }
+    Ok(())
+  }
+
+  /// Take the lock of the ref `name` by creating `{name}.lock`, returning
+  /// the path of the ref, the path of the lock, and the lock itself
+  fn lock(&self, name: &[u8]) -> Result<(PathBuf, PathBuf, fs::File), RefError> {
+    check_ref_name(name)?;
+    let path = self
+      .dir_of(name)
+      .join(name.to_path().map_err(|_| invalid_name(name))?);
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock_path = PathBuf::from(lock_path);
+    // Deleting another ref can remove the directories it was in right
+    // after they're made, which is only worth trying again a few times
+    let mut attempts = 0;
+    loop {
+      // The path always has a parent since it's inside of the git dir
+      fs::create_dir_all(path.parent().unwrap())?;
+      match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock_path)
+      {
+        Ok(lock) => return Ok((path, lock_path, lock)),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+          return Err(RefError::Locked(lock_path))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound && attempts < 3 => attempts += 1,
+        Err(e) => return Err(e.into()),
+      }
+    }
+  }
+}
+
+/// Changes to several refs made together, like `git update-ref --stdin`
+/// does. Every ref is locked and checked to still be where it was
+/// expected to be before any of them are changed, so either all of them
+/// are or none are.
+#[derive(Debug)]
+pub struct RefTransaction<'a> {
+  refs: &'a RefStore,
+  updates: Vec<(BString, Option<OID>, Option<OID>)>,
+}
+
+impl RefTransaction<'_> {
+  /// Point the ref `name` at `new`, or delete it if that's `None`, as long
+  /// as it's at `old` until then. A ref expected at `None` must not exist.
+  pub fn update(
+    &mut self,
+    name: impl Into<BString>,
+    old: Option<OID>,
+    new: Option<OID>,
+  ) -> &mut Self {
+    self.updates.push((name.into(), old, new));
+    self
+  }
+
+  /// Make every change, or none of them if a ref is locked or isn't where
+  /// it was expected to be
+  pub fn commit(self) -> Result<(), RefError> {
+    let mut locks = Vec::new();
+    let mut prepare = || {
+      for (name, old, new) in &self.updates {
+        let (path, lock_path, mut lock) = self.refs.lock(name)?;
+        locks.push((path, lock_path));
+        if self.refs.resolve(name)? != *old {
+          return Err(RefError::Stale(name.clone()));
+        }
+        if let Some(new) = new {
+          lock.write_all(format!("{}\n", new).as_bytes())?;
+        }
+      }
+      Ok(())
+    };
+    if let Err(e) = prepare() {
+      for (_, lock_path) in &locks {
+        let _ = fs::remove_file(lock_path);
+      }
+      return Err(e);
+    }
+    // Nothing can stop the changes now short of the filesystem failing
+    let mut result = Ok(());
+    for ((name, _, new), (path, lock_path)) in self.updates.iter().zip(&locks) {
+      let changed = match new {
+        Some(_) => fs::rename(lock_path, path).map_err(RefError::from),
+        None => self.refs.delete(name).map(|_| ()),
+      };
+      let _ = fs::remove_file(lock_path);
+      if result.is_ok() {
+        result = changed;
+      }
+    }
+    result
+  }
+}
+
+fn parse_oid(hex: &[u8]) -> Result<OID, OIDError> {
+  OID::from_hex(hex.to_str().unwrap_or_default())
+}
+
+fn parse_loose(name: &[u8], contents: &[u8]) -> Result<Reference, RefError> {
+  let contents = contents.trim_end();
+  let target = match contents.strip_prefix(b"ref:") {
+    Some(target) => RefTarget::Symbolic(target.trim_start().into()),
+    None => RefTarget::Direct(parse_oid(contents).map_err(|_| RefError::Corrupt(name.into()))?),
+  };
+  Ok(Reference::new(name, target))
+}
+
+/// Names like `HEAD`, `FETCH_HEAD`, or `ORIG_HEAD` that live at the top of
+/// the git directory
+fn is_pseudo_ref(name: &[u8]) -> bool {
+  !name.is_empty() && name.iter().all(|&c| c.is_ascii_uppercase() || c == b'_')
+}
+
+/// Whether a linked worktree has its own ref `name` rather than sharing it
+fn is_per_worktree(name: &[u8]) -> bool {
+  !name.starts_with(b"refs/") || PER_WORKTREE.iter().any(|prefix| name.starts_with(prefix))
+}
+
+fn invalid_name(name: &[u8]) -> RefError {
+  RefError::InvalidName(name.into())
+}
+
+/// Check a ref name against the rules of `git check-ref-format`
+pub(crate) fn check_ref_name(name: &[u8]) -> Result<(), RefError> {
+  let valid_component = |c: &[u8]| !c.is_empty() && !c.starts_with(b".") && !c.ends_with(b".lock");
+  if name.is_empty()
+    || name.ends_with(b"/")
+    || name.ends_with(b".")
+    || name == b"@"
+    || name.find(b"..").is_some()
+    || name.find(b"@{").is_some()
+    || name
+      .iter()
+      .any(|&c| c < 0x20 || c == 0x7f || b" ~^:?*[\\".contains(&c))
+    || !name.split_str("/").all(valid_component)
+  {
+    return Err(invalid_name(name));
+  }
+  Ok(())
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`RefStore`] type
+pub enum RefError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0:?} is not a valid ref name")]
+  InvalidName(BString),
+  #[error("ref {0} is corrupt")]
+  Corrupt(BString),
+  #[error("ref {0} is a symbolic ref nested too deeply")]
+  TooDeep(BString),
+  #[error("the ref is locked by {0:?}")]
+  Locked(PathBuf),
+  #[error("ref {0} isn't where it was expected to be")]
+  Stale(BString),
+}
+
+#[test]
+fn read_and_write() {
+  let tmp_dir = tempdir::TempDir::new("refs_test").unwrap();
+  let refs = RefStore::new(tmp_dir.path());
+  let oid = crate::Blob::new("this is a test").id();
+  let other = crate::Blob::new("other").id();
+  assert_eq!(None, refs.read("refs/heads/master").unwrap());
+  refs.write_symbolic("HEAD", "refs/heads/master").unwrap();
+  assert_eq!(None, refs.resolve("HEAD").unwrap());
+  refs.write("refs/heads/master", &oid).unwrap();
+  assert_eq!(Some(oid), refs.resolve("HEAD").unwrap());
+  assert_eq!(
+    "refs/heads/master",
+    refs.follow("HEAD").unwrap().unwrap().name()
+  );
+
+  fs::write(
+    tmp_dir.path().join("packed-refs"),
+    format!(
+      "# pack-refs with: peeled fully-peeled sorted \n{0} refs/heads/master\n{1} refs/tags/v1.0\n^{0}\n",
+      other, oid
+    ),
+  )
+  .unwrap();
+  // The loose ref wins over the packed one
+  assert_eq!(Some(oid), refs.resolve("refs/heads/master").unwrap());
+  assert_eq!(Some(oid), refs.resolve("refs/tags/v1.0").unwrap());
+  let names: Vec<_> = refs
+    .list("refs/")
+    .unwrap()
+    .into_iter()
+    .map(|r| r.name().to_string())
+    .collect();
+  assert_eq!(vec!["refs/heads/master", "refs/tags/v1.0"], names);
+  assert_eq!("refs/tags/v1.0", refs.find("v1.0").unwrap().unwrap().name());
+  assert_eq!("HEAD", refs.find("HEAD").unwrap().unwrap().name());
+  assert_eq!(None, refs.find("config").unwrap());
+}
+
+#[test]
+fn delete() {
+  let tmp_dir = tempdir::TempDir::new("refs_test").unwrap();
+  let refs = RefStore::new(tmp_dir.path());
+  let oid = crate::Blob::new("this is a test").id();
+  let other = crate::Blob::new("other").id();
+  fs::create_dir(tmp_dir.path().join("refs")).unwrap();
+  refs.write("refs/heads/feature/x", &oid).unwrap();
+  refs.write("refs/heads/feature/y", &oid).unwrap();
+  assert!(refs.delete("refs/heads/feature/x").unwrap());
+  assert!(tmp_dir.path().join("refs/heads/feature").is_dir());
+  assert!(refs.delete("refs/heads/feature/y").unwrap());
+  assert!(!tmp_dir.path().join("refs/heads").exists());
+  assert!(!refs.delete("refs/heads/feature/y").unwrap());
+
+  fs::write(
+    tmp_dir.path().join("packed-refs"),
+    format!(
+      "# pack-refs with: peeled fully-peeled sorted \n{0} refs/heads/master\n{1} refs/tags/v1.0\n^{0}\n{0} refs/tags/v2.0\n",
+      other, oid
+    ),
+  )
+  .unwrap();
+  refs.write("refs/tags/v1.0", &other).unwrap();
+  assert!(refs.delete("refs/tags/v1.0").unwrap());
+  assert_eq!(None, refs.read("refs/tags/v1.0").unwrap());
+  assert_eq!(
+    format!(
+      "# pack-refs with: peeled fully-peeled sorted \n{0} refs/heads/master\n{0} refs/tags/v2.0\n",
+      other
+    ),
+    fs::read_to_string(tmp_dir.path().join("packed-refs")).unwrap()
+  );
+  assert_eq!(Some(other), refs.resolve("refs/tags/v2.0").unwrap());
+}
+
+#[test]
+fn symref_loop() {
+  let tmp_dir = tempdir::TempDir::new("refs_test").unwrap();
+  let refs = RefStore::new(tmp_dir.path());
+  refs.write_symbolic("refs/heads/a", "refs/heads/b").unwrap();
+  refs.write_symbolic("refs/heads/b", "refs/heads/a").unwrap();
+  assert!(matches!(
+    refs.resolve("refs/heads/a"),
+    Err(RefError::TooDeep(_))
+  ));
+}
+
+#[test]
+fn invalid_names() {
+  for name in [
+    "refs/heads/a..b",
+    "refs/heads/.hidden",
+    "refs/heads/x.lock",
+    "refs/heads/a b",
+    "refs/heads/",
+    "refs//x",
+    "refs/a@{1}",
+    "@",
+  ] {
+    assert!(check_ref_name(name.as_bytes()).is_err(), "{}", name);
+  }
+  assert!(check_ref_name(b"refs/heads/feature/x-1").is_ok());
+}
+
+#[test]
+fn transaction() {
+  let tmp_dir = tempdir::TempDir::new("refs_test").unwrap();
+  let refs = RefStore::new(tmp_dir.path());
+  let oid = crate::Blob::new("this is a test").id();
+  let other = crate::Blob::new("other").id();
+  refs.write("refs/heads/master", &oid).unwrap();
+  refs.write("refs/heads/gone", &oid).unwrap();
+
+  // One ref somewhere else stops all of them
+  let mut transaction = refs.transaction();
+  transaction
+    .update("refs/heads/new", None, Some(oid))
+    .update("refs/heads/master", Some(other), Some(oid));
+  assert!(
+    matches!(transaction.commit(), Err(RefError::Stale(name)) if name == "refs/heads/master")
+  );
+  assert_eq!(None, refs.resolve("refs/heads/new").unwrap());
+  let lock = tmp_dir.path().join("refs/heads/master.lock");
+  assert!(!lock.exists());
+
+  fs::write(&lock, "").unwrap();
+  let mut transaction = refs.transaction();
+  transaction.update("refs/heads/master", Some(oid), Some(other));
+  assert!(matches!(transaction.commit(), Err(RefError::Locked(_))));
+  fs::remove_file(&lock).unwrap();
+
+  let mut transaction = refs.transaction();
+  transaction
+    .update("refs/heads/new", None, Some(oid))
+    .update("refs/heads/master", Some(oid), Some(other))
+    .update("refs/heads/gone", Some(oid), None);
+  transaction.commit().unwrap();
+  assert_eq!(Some(oid), refs.resolve("refs/heads/new").unwrap());
+  assert_eq!(Some(other), refs.resolve("refs/heads/master").unwrap());
+  assert_eq!(None, refs.resolve("refs/heads/gone").unwrap());
+  assert!(!lock.exists());
+}
@@ -0,0 +1,296 @@
+use crate::refs::check_ref_name;
+use bstr::{BStr, BString, ByteSlice};
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+/// Which refs a fetch or push updates and where they go, like
+/// `+refs/heads/*:refs/remotes/origin/*` of `remote.{name}.fetch`:
+///
+/// - A leading `+` forces updates that aren't fast-forwards
+/// - The source before the `:` is the ref taken, on the remote for a fetch
+///   and here for a push, and the destination after it is the ref updated.
+///   Either can be left out: a fetch without a destination updates no ref
+///   and a push without a source deletes it.
+/// - A `*` in the source matches any part of a ref name, which takes the
+///   place of the `*` of the destination.
+/// - A leading `^` makes a negative refspec, which has only a source and
+///   keeps the refs it matches out of a fetch or push.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refspec {
+  force: bool,
+  negative: bool,
+  src: Option<BString>,
+  dst: Option<BString>,
+}
+
+impl Refspec {
+  /// Parse a refspec as it's written in the config or on the command line
+  pub fn parse(spec: impl AsRef<[u8]>) -> Result<Self, RefspecError> {
+    let spec = spec.as_ref();
+    let invalid = || RefspecError::Invalid(spec.into());
+    let (negative, rest) = match spec.strip_prefix(b"^") {
+      Some(rest) => (true, rest),
+      None => (false, spec),
+    };
+    let (force, rest) = match rest.strip_prefix(b"+") {
+      Some(rest) if !negative => (true, rest),
+      Some(_) => return Err(invalid()),
+      None => (false, rest),
+    };
+    let (src, dst) = match rest.find_byte(b':') {
+      Some(colon) => (&rest[..colon], Some(&rest[colon + 1..])),
+      None => (rest, None),
+    };
+    let side = |side: &[u8]| (!side.is_empty()).then(|| BString::from(side));
+    let refspec = Self {
+      force,
+      negative,
+      src: side(src),
+      dst: dst.and_then(side),
+    };
+
+    let wildcards = |side: &Option<BString>| side.as_ref().map_or(0, |s| s.find_iter("*").count());
+    let (src_wildcards, dst_wildcards) = (wildcards(&refspec.src), wildcards(&refspec.dst));
+    let valid = match (&refspec.src, &refspec.dst) {
+      // Negative refspecs only ever exclude something
+      (None, _) | (_, Some(_)) if negative => false,
+      (None, None) => dst.is_some(),
+      _ => {
+        src_wildcards <= 1
+          && dst_wildcards <= 1
+          && (refspec.dst.is_none() || src_wildcards == dst_wildcards)
+          && [&refspec.src, &refspec.dst]
+            .iter()
+            .filter_map(|side| side.as_ref())
+            .all(|side| is_valid_side(side))
+      }
+    };
+    match valid {
+      true => Ok(refspec),
+      false => Err(invalid()),
+    }
+  }
+
+  /// A refspec that updates `dst` with `src`
+  pub fn new(src: impl Into<BString>, dst: impl Into<BString>, force: bool) -> Self {
+    Self {
+      force,
+      negative: false,
+      src: Some(src.into()),
+      dst: Some(dst.into()),
+    }
+  }
+
+  /// Whether updates that aren't fast-forwards are made anyway
+  pub fn is_force(&self) -> bool {
+    self.force
+  }
+
+  /// Whether the refspec keeps the refs it matches out instead
+  pub fn is_negative(&self) -> bool {
+    self.negative
+  }
+
+  /// Whether the refspec has a `*` matching many refs
+  pub fn is_wildcard(&self) -> bool {
+    self.src.as_ref().is_some_and(|src| src.contains(&b'*'))
+  }
+
+  /// The refs taken, if any
+  pub fn src(&self) -> Option<&BStr> {
+    self.src.as_ref().map(|src| src.as_bstr())
+  }
+
+  /// The refs updated, if any
+  pub fn dst(&self) -> Option<&BStr> {
+    self.dst.as_ref().map(|dst| dst.as_bstr())
+  }
+
+  /// Whether `name` is one of the refs the source takes
+  pub fn matches(&self, name: impl AsRef<[u8]>) -> bool {
+    self
+      .src
+      .as_ref()
+      .is_some_and(|src| match_pattern(src, name.as_ref()).is_some())
+  }
+
+  /// Where the ref `name` the source takes goes, `None` if the refspec
+  /// doesn't take it or doesn't put it anywhere
+  pub fn transform(&self, name: impl AsRef<[u8]>) -> Option<BString> {
+    map_pattern(self.src.as_ref()?, self.dst.as_ref()?, name.as_ref())
+  }
+
+  /// Which ref the source takes to update the ref `name`, the other way
+  /// around from [`Refspec::transform`], like from a remote-tracking
+  /// branch to the branch on the remote
+  pub fn reverse_transform(&self, name: impl AsRef<[u8]>) -> Option<BString> {
+    map_pattern(self.dst.as_ref()?, self.src.as_ref()?, name.as_ref())
+  }
+
+  /// The same refspec with a destination starting with `prefix` starting
+  /// with `new_prefix` instead, to move where it puts refs
+  pub fn with_dst_prefix(&self, prefix: impl AsRef<[u8]>, new_prefix: impl AsRef<[u8]>) -> Self {
+    let mut refspec = self.clone();
+    if let Some(dst) = &mut refspec.dst {
+      if let Some(rest) = dst.strip_prefix(prefix.as_ref()) {
+        *dst = [new_prefix.as_ref(), rest].concat().into();
+      }
+    }
+    refspec
+  }
+}
+
+/// Where the ref `name` goes going by `refspecs`, along with whether the
+/// update is forced. The first refspec to map `name` somewhere wins, unless
+/// a negative refspec keeps it out.
+pub fn map_ref(refspecs: &[Refspec], name: impl AsRef<[u8]>) -> Option<(BString, bool)> {
+  let name = name.as_ref();
+  let (negative, positive): (Vec<_>, Vec<_>) = refspecs.iter().partition(|r| r.is_negative());
+  if negative.iter().any(|refspec| refspec.matches(name)) {
+    return None;
+  }
+  positive
+    .iter()
+    .find_map(|refspec| Some((refspec.transform(name)?, refspec.force)))
+}
+
+/// Sides are ref names, with at most one `*` anywhere in them, except for
+/// the sources of pushes which can also be `@` for `HEAD`.
+fn is_valid_side(side: &[u8]) -> bool {
+  side == b"@" || check_ref_name(&side.replace("*", "x")).is_ok()
+}
+
+/// What the `*` of `pattern` stands for in `name`, or all of it when
+/// `pattern` has no `*` and is `name`
+fn match_pattern<'a>(pattern: &[u8], name: &'a [u8]) -> Option<&'a [u8]> {
+  match pattern.find_byte(b'*') {
+    Some(star) => {
+      let (prefix, suffix) = (&pattern[..star], &pattern[star + 1..]);
+      (name.len() >= prefix.len() + suffix.len()
+        && name.starts_with(prefix)
+        && name.ends_with(suffix))
+      .then(|| &name[prefix.len()..name.len() - suffix.len()])
+    }
+    None => (pattern == name).then_some(name),
+  }
+}
+
+fn map_pattern(from: &[u8], to: &[u8], name: &[u8]) -> Option<BString> {
+  let matched = match_pattern(from, name)?;
+  Some(match to.find_byte(b'*') {
+    Some(star) if from.contains(&b'*') => [&to[..star], matched, &to[star + 1..]].concat().into(),
+    _ => to.into(),
+  })
+}
+
+impl fmt::Display for Refspec {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.negative {
+      f.write_str("^")?;
+    }
+    if self.force {
+      f.write_str("+")?;
+    }
+    if let Some(src) = &self.src {
+      write!(f, "{}", src)?;
+    }
+    if let Some(dst) = &self.dst {
+      write!(f, ":{}", dst)?;
+    } else if self.src.is_none() {
+      f.write_str(":")?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for Refspec {
+  type Err = RefspecError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Self::parse(s)
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to parsing a [`Refspec`]
+pub enum RefspecError {
+  #[error("{0:?} is not a valid refspec")]
+  Invalid(BString),
+}
+
+#[test]
+fn parse_and_map() {
+  let refspec = Refspec::parse("+refs/heads/*:refs/remotes/origin/*").unwrap();
+  assert!(refspec.is_force());
+  assert!(refspec.is_wildcard());
+  assert!(!refspec.is_negative());
+  assert_eq!(Some(b"refs/heads/*".as_bstr()), refspec.src());
+  assert_eq!(Some(b"refs/remotes/origin/*".as_bstr()), refspec.dst());
+  assert!(refspec.matches("refs/heads/topic/a"));
+  assert!(!refspec.matches("refs/tags/v1.0"));
+  assert_eq!(
+    Some("refs/remotes/origin/topic/a".into()),
+    refspec.transform("refs/heads/topic/a")
+  );
+  assert_eq!(None, refspec.transform("refs/tags/v1.0"));
+  assert_eq!(
+    Some("refs/heads/master".into()),
+    refspec.reverse_transform("refs/remotes/origin/master")
+  );
+  assert_eq!(
+    "+refs/heads/*:refs/remotes/upstream/*",
+    refspec
+      .with_dst_prefix("refs/remotes/origin/", "refs/remotes/upstream/")
+      .to_string()
+  );
+
+  let exact = Refspec::parse("refs/heads/master").unwrap();
+  assert_eq!(None, exact.dst());
+  assert!(exact.matches("refs/heads/master"));
+  assert_eq!(None, exact.transform("refs/heads/master"));
+  let delete = Refspec::parse(":refs/heads/gone").unwrap();
+  assert_eq!(None, delete.src());
+  assert_eq!(":refs/heads/gone", delete.to_string());
+  let infix = Refspec::parse("refs/heads/*-wip:refs/wip/*").unwrap();
+  assert_eq!(
+    Some("refs/wip/a".into()),
+    infix.transform("refs/heads/a-wip")
+  );
+  assert_eq!(None, infix.transform("refs/heads/-wi"));
+  assert!(Refspec::parse("@:refs/heads/master").is_ok());
+
+  for spec in [
+    "refs/heads/*:refs/remotes/origin/master",
+    "refs/heads/**:refs/a/*",
+    "^+refs/heads/a",
+    "^refs/heads/a:refs/heads/b",
+    "refs/heads/a..b",
+    "",
+  ]
+  .iter()
+  {
+    assert!(
+      matches!(Refspec::parse(spec), Err(RefspecError::Invalid(_))),
+      "{:?}",
+      spec
+    );
+  }
+
+  let refspecs: Vec<Refspec> = [
+    "^refs/heads/secret/*",
+    "refs/heads/master:refs/remotes/origin/main",
+    "+refs/heads/*:refs/remotes/origin/*",
+  ]
+  .iter()
+  .map(|spec| spec.parse().unwrap())
+  .collect();
+  assert_eq!(
+    Some(("refs/remotes/origin/main".into(), false)),
+    map_ref(&refspecs, "refs/heads/master")
+  );
+  assert_eq!(
+    Some(("refs/remotes/origin/topic".into(), true)),
+    map_ref(&refspecs, "refs/heads/topic")
+  );
+  assert_eq!(None, map_ref(&refspecs, "refs/heads/secret/plan"));
+  assert_eq!(None, map_ref(&refspecs, "refs/tags/v1.0"));
+}
@@ -0,0 +1,495 @@
+use crate::{
+  refs::check_ref_name, Config, ConfigError, ConfigFile, ConfigLevel, RefError, RefTarget, Refspec,
+  RefspecError, Repository, RepositoryError,
+};
+use bstr::{BStr, BString, ByteSlice};
+use thiserror::Error;
+
+/// A remote repository as set up in the `remote.{name}` section of the
+/// config, like the `origin` a clone comes from. A [`Remote`] is a copy
+/// of what the config says, changing it only changes the config once it's
+/// handed to [`Repository::save_remote`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Remote {
+  name: String,
+  url: Option<BString>,
+  push_urls: Vec<BString>,
+  fetch: Vec<Refspec>,
+  push: Vec<Refspec>,
+  mirror: bool,
+  prune: Option<bool>,
+  prune_tags: Option<bool>,
+}
+
+impl Remote {
+  /// Create a [`Remote`] called `name` for `url` that fetches every branch
+  /// into `refs/remotes/{name}/`, like `git remote add` sets up
+  pub fn new(name: impl Into<String>, url: impl Into<BString>) -> Self {
+    let name = name.into();
+    let fetch = Refspec::new("refs/heads/*", format!("refs/remotes/{}/*", name), true);
+    Self {
+      name,
+      url: Some(url.into()),
+      push_urls: Vec::new(),
+      fetch: vec![fetch],
+      push: Vec::new(),
+      mirror: false,
+      prune: None,
+      prune_tags: None,
+    }
+  }
+
+  /// Read the remote `name` from `config`, if there's anything set for it
+  pub fn from_config(config: &Config, name: &str) -> Result<Option<Self>, RemoteError> {
+    let is_remote = |subsection: Option<&BStr>| subsection == Some(name.as_bytes().as_bstr());
+    if !config
+      .entries()
+      .any(|entry| entry.section() == "remote" && is_remote(entry.subsection()))
+    {
+      return Ok(None);
+    }
+    let key = |setting: &str| format!("remote.{}.{}", name, setting);
+    let all = |setting: &str| config.get_all(&key(setting)).into_iter();
+    let refspecs = |setting: &str| all(setting).map(Refspec::parse).collect::<Result<_, _>>();
+    Ok(Some(Self {
+      name: name.into(),
+      url: config.get(&key("url")).map(BString::from),
+      push_urls: all("pushurl").map(BString::from).collect(),
+      fetch: refspecs("fetch")?,
+      push: refspecs("push")?,
+      mirror: config.get_bool(&key("mirror"))?.unwrap_or(false),
+      prune: config.get_bool(&key("prune"))?,
+      prune_tags: config.get_bool(&key("prunetags"))?,
+    }))
+  }
+
+  /// The name of the remote, like `origin`
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Where the remote is fetched from, `remote.{name}.url`
+  pub fn url(&self) -> Option<&BStr> {
+    self.url.as_ref().map(|url| url.as_bstr())
+  }
+
+  /// Set where the remote is fetched from
+  pub fn set_url(&mut self, url: impl Into<BString>) -> &mut Self {
+    self.url = Some(url.into());
+    self
+  }
+
+  /// Where the remote is pushed to, `remote.{name}.pushurl`, which is
+  /// [`Remote::url`] if there isn't one
+  pub fn push_urls(&self) -> Vec<&BStr> {
+    match (&self.push_urls[..], &self.url) {
+      ([], Some(url)) => vec![url.as_bstr()],
+      (urls, _) => urls.iter().map(|url| url.as_bstr()).collect(),
+    }
+  }
+
+  /// Push to `urls` instead of [`Remote::url`], or to it again without any
+  pub fn set_push_urls(&mut self, urls: Vec<BString>) -> &mut Self {
+    self.push_urls = urls;
+    self
+  }
+
+  /// The refspecs of what's fetched and where it goes,
+  /// `remote.{name}.fetch`
+  pub fn fetch_refspecs(&self) -> &[Refspec] {
+    &self.fetch
+  }
+
+  /// Fetch with `refspecs` instead
+  pub fn set_fetch_refspecs(&mut self, refspecs: Vec<Refspec>) -> &mut Self {
+    self.fetch = refspecs;
+    self
+  }
+
+  /// Fetch with `refspec` as well
+  pub fn add_fetch_refspec(&mut self, refspec: Refspec) -> &mut Self {
+    self.fetch.push(refspec);
+    self
+  }
+
+  /// The refspecs of what's pushed by default, `remote.{name}.push`
+  pub fn push_refspecs(&self) -> &[Refspec] {
+    &self.push
+  }
+
+  /// Push with `refspecs` instead
+  pub fn set_push_refspecs(&mut self, refspecs: Vec<Refspec>) -> &mut Self {
+    self.push = refspecs;
+    self
+  }
+
+  /// Push with `refspec` as well
+  pub fn add_push_refspec(&mut self, refspec: Refspec) -> &mut Self {
+    self.push.push(refspec);
+    self
+  }
+
+  /// Whether a push mirrors every ref to the remote, deleting the ones
+  /// that are gone here, `remote.{name}.mirror`
+  pub fn mirror(&self) -> bool {
+    self.mirror
+  }
+
+  /// Set whether a push mirrors every ref. A remote that's a mirror to
+  /// fetch from has `+refs/*:refs/*` as its fetch refspec instead.
+  pub fn set_mirror(&mut self, mirror: bool) -> &mut Self {
+    self.mirror = mirror;
+    self
+  }
+
+  /// Whether a fetch deletes remote-tracking refs whose ref on the remote
+  /// is gone, `remote.{name}.prune`. `None` leaves it to `fetch.prune`.
+  pub fn prune(&self) -> Option<bool> {
+    self.prune
+  }
+
+  /// Set whether a fetch prunes, `None` leaving it to `fetch.prune`
+  pub fn set_prune(&mut self, prune: Option<bool>) -> &mut Self {
+    self.prune = prune;
+    self
+  }
+
+  /// Whether a pruning fetch deletes tags too, `remote.{name}.pruneTags`.
+  /// `None` leaves it to `fetch.pruneTags`.
+  pub fn prune_tags(&self) -> Option<bool> {
+    self.prune_tags
+  }
+
+  /// Set whether a pruning fetch deletes tags, `None` leaving it to
+  /// `fetch.pruneTags`
+  pub fn set_prune_tags(&mut self, prune_tags: Option<bool>) -> &mut Self {
+    self.prune_tags = prune_tags;
+    self
+  }
+
+  /// Whether a fetch prunes going by `config` as well, which has
+  /// `fetch.prune` for remotes that don't say
+  pub fn prunes(&self, config: &Config) -> Result<bool, ConfigError> {
+    match self.prune {
+      Some(prune) => Ok(prune),
+      None => Ok(config.get_bool("fetch.prune")?.unwrap_or(false)),
+    }
+  }
+
+  /// Whether a pruning fetch deletes tags going by `config` as well, which
+  /// has `fetch.pruneTags` for remotes that don't say
+  pub fn prunes_tags(&self, config: &Config) -> Result<bool, ConfigError> {
+    match self.prune_tags {
+      Some(prune_tags) => Ok(prune_tags),
+      None => Ok(config.get_bool("fetch.prunetags")?.unwrap_or(false)),
+    }
+  }
+}
+
+/// Names have to make valid refs under `refs/remotes/`, the same check
+/// git makes
+fn check_remote_name(name: &str) -> Result<(), RemoteError> {
+  let invalid = || RemoteError::InvalidName(name.into());
+  if name.is_empty() {
+    return Err(invalid());
+  }
+  check_ref_name(format!("refs/remotes/{}/test", name).as_bytes()).map_err(|_| invalid())
+}
+
+impl Repository {
+  fn local_config(&self) -> Result<ConfigFile, ConfigError> {
+    ConfigFile::from_file(self.common_dir().join("config"), ConfigLevel::Local)
+  }
+
+  /// The names of the remotes in the config, in the order they first show
+  /// up
+  pub fn remote_names(&self) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for entry in self.config().entries() {
+      if let (true, Some(name)) = (entry.section() == "remote", entry.subsection()) {
+        let name = name.to_str_lossy();
+        if !names.iter().any(|known| *known == name) {
+          names.push(name.into_owned());
+        }
+      }
+    }
+    names
+  }
+
+  /// The remote `name` as the config has it
+  pub fn find_remote(&self, name: &str) -> Result<Option<Remote>, RemoteError> {
+    Remote::from_config(self.config(), name)
+  }
+
+  /// Write every setting of `remote` to the repository's config, like `git
+  /// remote set-url` and friends, keeping any others the section has
+  pub fn save_remote(&mut self, remote: &Remote) -> Result<(), RemoteError> {
+    check_remote_name(&remote.name)?;
+    let mut file = self.local_config()?;
+    let key = |setting: &str| format!("remote.{}.{}", remote.name, setting);
+    if let Some(url) = &remote.url {
+      file.set(&key("url"), url)?;
+    }
+    let to_string = |refspecs: &[Refspec]| refspecs.iter().map(|r| r.to_string().into()).collect();
+    let lists: [(&str, Vec<BString>); 3] = [
+      ("pushurl", remote.push_urls.clone()),
+      ("fetch", to_string(&remote.fetch)),
+      ("push", to_string(&remote.push)),
+    ];
+    for (setting, values) in lists.iter() {
+      file.unset_all(&key(setting))?;
+      for value in values {
+        file.add(&key(setting), value)?;
+      }
+    }
+    match remote.mirror {
+      true => file.set(&key("mirror"), "true")?,
+      false => {
+        file.unset_all(&key("mirror"))?;
+      }
+    }
+    let options = [("prune", remote.prune), ("prunetags", remote.prune_tags)];
+    for (setting, value) in options.iter() {
+      match value {
+        Some(value) => file.set(&key(setting), value.to_string())?,
+        None => {
+          file.unset_all(&key(setting))?;
+        }
+      }
+    }
+    file.save()?;
+    self.reload_config()?;
+    Ok(())
+  }
+
+  /// Add the remote `name` for `url` fetching every branch into
+  /// `refs/remotes/{name}/`, like `git remote add {name} {url}`
+  pub fn create_remote(
+    &mut self,
+    name: &str,
+    url: impl Into<BString>,
+  ) -> Result<Remote, RemoteError> {
+    check_remote_name(name)?;
+    if self.find_remote(name)?.is_some() {
+      return Err(RemoteError::Exists(name.into()));
+    }
+    let remote = Remote::new(name, url);
+    self.save_remote(&remote)?;
+    Ok(remote)
+  }
+
+  /// Rename the remote `name` to `new_name`, like `git remote rename`. Its
+  /// remote-tracking refs move to `refs/remotes/{new_name}/` along with
+  /// the fetch refspecs putting them there, and branches following it
+  /// follow it under the new name.
+  pub fn rename_remote(&mut self, name: &str, new_name: &str) -> Result<Remote, RemoteError> {
+    check_remote_name(new_name)?;
+    let mut remote = self
+      .find_remote(name)?
+      .ok_or_else(|| RemoteError::NotFound(name.into()))?;
+    if self.find_remote(new_name)?.is_some() {
+      return Err(RemoteError::Exists(new_name.into()));
+    }
+    let old_prefix = format!("refs/remotes/{}/", name);
+    let new_prefix = format!("refs/remotes/{}/", new_name);
+
+    let mut file = self.local_config()?;
+    file.rename_section(&format!("remote.{}", name), &format!("remote.{}", new_name))?;
+    for key in self.branch_remote_keys(name) {
+      file.set(&key, new_name)?;
+    }
+    if self.config().get("remote.pushdefault") == Some(name.as_bytes().as_bstr()) {
+      file.set("remote.pushdefault", new_name)?;
+    }
+    remote.name = new_name.into();
+    // Only where refspecs put refs moves, what they fetch stays
+    for refspec in &mut remote.fetch {
+      *refspec = refspec.with_dst_prefix(&old_prefix, &new_prefix);
+    }
+    file.save()?;
+    self.reload_config()?;
+    self.save_remote(&remote)?;
+
+    let refs = self.refs();
+    for reference in refs.list(&old_prefix)? {
+      let new_ref = [new_prefix.as_bytes(), &reference.name()[old_prefix.len()..]].concat();
+      match reference.target() {
+        RefTarget::Direct(oid) => refs.write(&new_ref, oid)?,
+        RefTarget::Symbolic(target) => {
+          let target = match target.strip_prefix(old_prefix.as_bytes()) {
+            Some(rest) => [new_prefix.as_bytes(), rest].concat(),
+            None => target.to_vec(),
+          };
+          refs.write_symbolic(&new_ref, target)?;
+        }
+      }
+      refs.delete(reference.name())?;
+    }
+    Ok(remote)
+  }
+
+  /// Delete the remote `name` with its remote-tracking refs, like `git
+  /// remote remove`. Branches that followed it don't follow anything
+  /// anymore.
+  pub fn delete_remote(&mut self, name: &str) -> Result<(), RemoteError> {
+    if self.find_remote(name)?.is_none() {
+      return Err(RemoteError::NotFound(name.into()));
+    }
+    let mut file = self.local_config()?;
+    for key in self.branch_remote_keys(name) {
+      file.unset_all(&key)?;
+      // The branch is `branch.{branch}`, before `.remote`
+      let branch = &key[..key.len() - ".remote".len()];
+      file.unset_all(&format!("{}.merge", branch))?;
+    }
+    file.remove_section(&format!("remote.{}", name))?;
+    file.save()?;
+    self.reload_config()?;
+    for reference in self.refs().list(format!("refs/remotes/{}/", name))? {
+      self.refs().delete(reference.name())?;
+    }
+    Ok(())
+  }
+
+  /// The `branch.{branch}.remote` keys set to `name`
+  fn branch_remote_keys(&self, name: &str) -> Vec<String> {
+    let mut keys: Vec<String> = self
+      .config()
+      .entries()
+      .filter(|entry| {
+        entry.section() == "branch"
+          && entry.subsection().is_some()
+          && entry.name() == "remote"
+          && entry.value() == Some(name.as_bytes().as_bstr())
+      })
+      .map(|entry| entry.key())
+      .collect();
+    keys.dedup();
+    keys
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to managing [`Remote`]s
+pub enum RemoteError {
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Repository(#[from] RepositoryError),
+  #[error("{0}")]
+  Refspec(#[from] RefspecError),
+  #[error("{0:?} is not a valid remote name")]
+  InvalidName(String),
+  #[error("remote {0} already exists")]
+  Exists(String),
+  #[error("no such remote: {0}")]
+  NotFound(String),
+}
+
+#[test]
+fn manage_remotes() {
+  use crate::OID;
+  let tmp_dir = tempdir::TempDir::new("remote_test").unwrap();
+  let mut repo = Repository::init(tmp_dir.path()).unwrap();
+  assert!(repo.remote_names().is_empty());
+  assert_eq!(None, repo.find_remote("origin").unwrap());
+  let remote = repo
+    .create_remote("origin", "https://example.com/repo.git")
+    .unwrap();
+  assert_eq!(Some(remote.clone()), repo.find_remote("origin").unwrap());
+  assert_eq!(vec!["https://example.com/repo.git"], remote.push_urls());
+  assert!(matches!(
+    repo.create_remote("origin", "elsewhere"),
+    Err(RemoteError::Exists(_))
+  ));
+  for name in ["", "a..b", "a//b", "a b"].iter() {
+    assert!(matches!(
+      repo.create_remote(name, "elsewhere"),
+      Err(RemoteError::InvalidName(_))
+    ));
+  }
+
+  let mut remote = repo.find_remote("origin").unwrap().unwrap();
+  remote
+    .set_push_urls(vec!["ssh://example.com/repo.git".into()])
+    .add_push_refspec(Refspec::parse("refs/heads/master").unwrap())
+    .set_mirror(true)
+    .set_prune(Some(true));
+  repo.save_remote(&remote).unwrap();
+  let saved = repo.find_remote("origin").unwrap().unwrap();
+  assert_eq!(remote, saved);
+  assert!(saved.prunes(repo.config()).unwrap());
+  assert!(!saved.prunes_tags(repo.config()).unwrap());
+  remote.set_mirror(false).set_prune(None);
+  repo.save_remote(&remote).unwrap();
+  let config = repo.config();
+  assert_eq!(None, config.get("remote.origin.mirror"));
+  assert_eq!(None, config.get("remote.origin.prune"));
+
+  let oid = OID::hash("a");
+  repo
+    .refs()
+    .write("refs/remotes/origin/master", &oid)
+    .unwrap();
+  repo
+    .refs()
+    .write_symbolic("refs/remotes/origin/HEAD", "refs/remotes/origin/master")
+    .unwrap();
+  repo.create_remote("other", "elsewhere").unwrap();
+  let mut file = repo.local_config().unwrap();
+  file.set("branch.master.remote", "origin").unwrap();
+  file
+    .set("branch.master.merge", "refs/heads/master")
+    .unwrap();
+  file.save().unwrap();
+  repo.reload_config().unwrap();
+  assert_eq!(vec!["origin", "other"], repo.remote_names());
+
+  assert!(matches!(
+    repo.rename_remote("origin", "other"),
+    Err(RemoteError::Exists(_))
+  ));
+  let renamed = repo.rename_remote("origin", "upstream").unwrap();
+  assert_eq!(
+    "+refs/heads/*:refs/remotes/upstream/*",
+    renamed.fetch_refspecs()[0].to_string()
+  );
+  assert_eq!(vec!["upstream", "other"], repo.remote_names());
+  assert_eq!(None, repo.find_remote("origin").unwrap());
+  assert_eq!(Some(renamed), repo.find_remote("upstream").unwrap());
+  let config = repo.config();
+  assert_eq!(
+    Some(b"upstream".as_bstr()),
+    config.get("branch.master.remote")
+  );
+  let refs = repo.refs();
+  assert!(refs.list("refs/remotes/origin/").unwrap().is_empty());
+  assert_eq!(
+    Some(oid),
+    refs.resolve("refs/remotes/upstream/HEAD").unwrap()
+  );
+  assert_eq!(
+    &RefTarget::Symbolic("refs/remotes/upstream/master".into()),
+    refs
+      .read("refs/remotes/upstream/HEAD")
+      .unwrap()
+      .unwrap()
+      .target()
+  );
+
+  repo.delete_remote("upstream").unwrap();
+  assert_eq!(vec!["other"], repo.remote_names());
+  assert!(repo
+    .refs()
+    .list("refs/remotes/upstream/")
+    .unwrap()
+    .is_empty());
+  assert_eq!(None, repo.config().get("branch.master.remote"));
+  assert_eq!(None, repo.config().get("branch.master.merge"));
+  assert!(matches!(
+    repo.delete_remote("upstream"),
+    Err(RemoteError::NotFound(_))
+  ));
+}
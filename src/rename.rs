@@ -0,0 +1,876 @@
+//! Finding files that were renamed or copied between two [`Tree`][crate::Tree]s,
+//! the same way git's diffcore does. Files are matched up by how much of
+//! their contents they share: the contents are cut into lines, or 64 byte
+//! pieces of long lines, and the number of bytes in pieces found on both
+//! sides is compared to the size of the larger file. Modifications that
+//! rewrote most of a file can be broken up into a deletion and an
+//! addition first, so that its old contents can be matched with a file it
+//! was moved to and its new contents with a file they came from.
+
+use crate::{
+  diff::kind_of, patch::is_binary, Change, ChangeKind, Config, ConfigError, DiffError, DiffFile,
+  FileMode, Odb, Trace2, OID,
+};
+use bstr::{BStr, ByteSlice};
+use std::{
+  cmp::Ordering,
+  collections::{hash_map::Entry, HashMap},
+  convert::TryFrom,
+};
+
+/// Similarity scores are out of this, like in git, so that percentages
+/// can be compared without rounding
+const MAX_SCORE: u64 = 60000;
+/// Files smaller than this are never broken up
+const MINIMUM_BREAK_SIZE: u64 = 400;
+/// How many of the most similar sources are kept for each destination
+const CANDIDATES: usize = 4;
+
+/// Which files [`detect_renames`] looks for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum RenameDetection {
+  /// Added and deleted files are left alone
+  No,
+  /// Deleted files are matched with added files that are similar enough
+  #[default]
+  Renames,
+  /// Modified files are also matched with added files, and a file can be
+  /// matched more than once, which is reported as it being copied. Like
+  /// `git diff -C`.
+  Copies,
+}
+
+/// When [`detect_renames`] breaks a modification up into a deletion and
+/// an addition, like `git diff -B`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RewriteOptions {
+  /// How much of a file has to change in percent for it to be broken up.
+  /// Both removed and added contents count towards this. Defaults to 50.
+  pub break_threshold: u8,
+  /// How much of the old contents has to be removed in percent for a
+  /// broken up file that wasn't matched with another to be reported as
+  /// [`ChangeKind::Rewritten`] rather than as modified. Defaults to 60.
+  pub merge_threshold: u8,
+}
+
+impl Default for RewriteOptions {
+  fn default() -> Self {
+    Self {
+      break_threshold: 50,
+      merge_threshold: 60,
+    }
+  }
+}
+
+/// Options controlling how [`detect_renames`] matches up files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenameOptions {
+  /// Whether to look for renames, copies, or neither
+  pub detection: RenameDetection,
+  /// How similar in percent two files have to be to count as one being
+  /// renamed or copied to the other. Files with the same name in a
+  /// different directory are matched up first if they're at least half
+  /// way between this and 100. At 100 only identical files are matched.
+  /// Defaults to 50.
+  pub threshold: u8,
+  /// Whether and how to break up rewritten files. Defaults to `None`.
+  pub rewrites: Option<RewriteOptions>,
+  /// How many files can be added and how many deleted before files that
+  /// aren't identical aren't compared anymore, since every pair of them
+  /// has to be. `0` means there's no limit. Defaults to 1000.
+  pub limit: usize,
+}
+
+impl Default for RenameOptions {
+  fn default() -> Self {
+    Self {
+      detection: RenameDetection::default(),
+      threshold: 50,
+      rewrites: None,
+      limit: 1000,
+    }
+  }
+}
+
+impl RenameOptions {
+  /// Read the options from `diff.renames` and `diff.renameLimit`, using the
+  /// defaults for any not set
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let mut options = Self::default();
+    if let Some(renames) = config.get_str("diff.renames")? {
+      options.detection = match renames.to_ascii_lowercase().as_str() {
+        "copy" | "copies" => RenameDetection::Copies,
+        _ => match config.get_bool("diff.renames")? {
+          Some(false) => RenameDetection::No,
+          _ => RenameDetection::Renames,
+        },
+      };
+    }
+    if let Some(limit) = config.get_int("diff.renamelimit")? {
+      // Like git a negative limit is no limit at all
+      options.limit = usize::try_from(limit).unwrap_or(0);
+    }
+    Ok(options)
+  }
+}
+
+/// Look for renamed and copied files in [`Change`]s from
+/// [`diff_trees`][crate::diff_trees] the way `git diff -M` does. A deleted
+/// and an added file that are identical or at least
+/// [`RenameOptions::threshold`] percent similar are reported as one
+/// [`ChangeKind::Renamed`] change in place of the added one. Identical
+/// files are matched up first, then ones with the same name, and then the
+/// most similar pairs of all the rest. Only files are compared by their
+/// contents, symbolic links and submodules have to be identical.
+///
+/// With [`RenameDetection::Copies`] an added file can also be matched with
+/// a modified one, or with a file that's already been matched, which
+/// makes it [`ChangeKind::Copied`]. When a deleted file is matched more
+/// than once the last match in path order is the rename.
+pub fn detect_renames(
+  odb: &Odb,
+  changes: Vec<Change>,
+  options: &RenameOptions,
+) -> Result<Vec<Change>, DiffError> {
+  if options.detection == RenameDetection::No && options.rewrites.is_none() {
+    return Ok(changes);
+  }
+  let _region = Trace2::region("diff", "detect_renames");
+  let mut blobs = Blobs {
+    odb,
+    spans: HashMap::new(),
+  };
+  let mut pairs = Vec::with_capacity(changes.len());
+  for change in changes {
+    let (kind, old, new) = change.into_sides();
+    let broken = match (&options.rewrites, &old, &new) {
+      (Some(rewrites), Some(old), Some(new)) if breakable(old, new) => {
+        should_break(&mut blobs, old, new, rewrites)?
+      }
+      _ => None,
+    };
+    match broken {
+      Some(score) => {
+        let pair = |old, new| Pair {
+          id: 0,
+          kind: ChangeKind::Modified,
+          old,
+          new,
+          score,
+          broken: true,
+          renamed: false,
+        };
+        pairs.push(pair(old, None));
+        pairs.push(pair(None, new));
+      }
+      None => pairs.push(Pair {
+        id: 0,
+        kind,
+        old,
+        new,
+        score: 0,
+        broken: false,
+        renamed: false,
+      }),
+    }
+  }
+  for (id, pair) in pairs.iter_mut().enumerate() {
+    pair.id = id;
+  }
+  // How often the old side of each pair is used by the end, a file that
+  // stays where it is counts as a use
+  let mut used = vec![0; pairs.len()];
+  if options.detection != RenameDetection::No {
+    pairs = find_renames(&mut blobs, pairs, &mut used, options)?;
+  }
+  if options.rewrites.is_some() {
+    pairs = merge_broken(pairs, &mut used);
+  }
+
+  let changes: Vec<Change> = pairs
+    .into_iter()
+    .filter_map(|pair| {
+      let kind = match (&pair.old, &pair.new) {
+        (None, _) => ChangeKind::Added,
+        (_, None) => ChangeKind::Deleted,
+        (Some(old), Some(new)) if kind_of(old.mode) != kind_of(new.mode) => ChangeKind::TypeChanged,
+        (Some(old), Some(new)) if pair.renamed && old.path != new.path => {
+          used[pair.id] -= 1;
+          if used[pair.id] > 0 {
+            ChangeKind::Copied(percent(pair.score))
+          } else {
+            ChangeKind::Renamed(percent(pair.score))
+          }
+        }
+        _ if pair.score > 0 => ChangeKind::Rewritten(percent(pair.score)),
+        _ => pair.kind,
+      };
+      Change::from_sides(kind, pair.old, pair.new)
+    })
+    .collect();
+  Trace2::data("diff", "changes", changes.len());
+  Ok(changes)
+}
+
+/// A [`Change`] while renames are being looked for
+struct Pair {
+  /// Which pair the old side first came from, to count its uses
+  id: usize,
+  /// The kind of change if it ends up being left alone
+  kind: ChangeKind,
+  old: Option<DiffFile>,
+  new: Option<DiffFile>,
+  /// How similar the sides of a rename are, or how much of a broken up
+  /// file was removed if it's to be reported as rewritten
+  score: u64,
+  /// Whether this is half of a modification that was broken up
+  broken: bool,
+  /// Whether the sides were matched up by rename detection
+  renamed: bool,
+}
+
+impl Pair {
+  fn path(&self) -> &BStr {
+    // Every pair has at least one side
+    self
+      .new
+      .as_ref()
+      .or(self.old.as_ref())
+      .unwrap()
+      .path
+      .as_bstr()
+  }
+}
+
+/// A possible match of a deleted or modified file with an added one
+#[derive(Clone, Copy)]
+struct Candidate {
+  score: u64,
+  same_name: bool,
+  /// Index in the destinations
+  dst: usize,
+  /// Index in the pairs
+  src: usize,
+}
+
+/// Better candidates sort first and empty slots last
+fn candidate_order(a: &Option<Candidate>, b: &Option<Candidate>) -> Ordering {
+  match (a, b) {
+    (None, None) => Ordering::Equal,
+    (None, Some(_)) => Ordering::Greater,
+    (Some(_), None) => Ordering::Less,
+    (Some(a), Some(b)) => b.score.cmp(&a.score).then(b.same_name.cmp(&a.same_name)),
+  }
+}
+
+/// Replace the worst candidate with `candidate` if it's better
+fn keep_if_better(candidates: &mut [Option<Candidate>], candidate: Candidate) {
+  let mut worst = 0;
+  for idx in 1..candidates.len() {
+    if candidate_order(&candidates[idx], &candidates[worst]) == Ordering::Greater {
+      worst = idx;
+    }
+  }
+  if candidate_order(&candidates[worst], &Some(candidate)) == Ordering::Greater {
+    candidates[worst] = Some(candidate);
+  }
+}
+
+fn find_renames(
+  blobs: &mut Blobs<'_>,
+  pairs: Vec<Pair>,
+  used: &mut [usize],
+  options: &RenameOptions,
+) -> Result<Vec<Pair>, DiffError> {
+  let copies = options.detection == RenameDetection::Copies;
+  let breaking = options.rewrites.is_some();
+  let minimum = score(options.threshold);
+  let (mut dsts, mut srcs) = (Vec::new(), Vec::new());
+  for (idx, pair) in pairs.iter().enumerate() {
+    match (&pair.old, &pair.new) {
+      (None, Some(_)) => dsts.push(idx),
+      (Some(_), None) => {
+        // Half of a file that's merged back together again stays
+        if pair.broken && pair.score == 0 {
+          used[pair.id] += 1;
+        }
+        srcs.push(idx);
+      }
+      (Some(_), Some(_)) if copies => {
+        used[pair.id] += 1;
+        srcs.push(idx);
+      }
+      _ => {}
+    }
+  }
+  let old = |src: usize| pairs[src].old.as_ref().unwrap();
+  let new = |dst: usize| pairs[dsts[dst]].new.as_ref().unwrap();
+  let mut matches: Vec<Option<(usize, u64)>> = vec![None; dsts.len()];
+  let record = |matches: &mut [_], used: &mut [usize], dst: usize, src: usize, score| {
+    matches[dst] = Some((src, score));
+    used[pairs[src].id] += 1;
+  };
+
+  // Identical files first, preferring ones that haven't been used and
+  // then ones with the same name
+  let mut by_oid: HashMap<OID, Vec<usize>> = HashMap::new();
+  for &src in &srcs {
+    by_oid.entry(old(src).oid).or_default().push(src);
+  }
+  for dst in 0..dsts.len() {
+    let target = new(dst);
+    let mut best: Option<(usize, usize)> = None;
+    let mut left = 100;
+    for &src in by_oid.get(&target.oid).into_iter().flatten() {
+      let source = old(src);
+      if (!is_file(source.mode) || !is_file(target.mode)) && source.mode != target.mode {
+        continue;
+      }
+      let unused = used[pairs[src].id] == 0;
+      if !unused && !copies {
+        continue;
+      }
+      let score = unused as usize + same_name(&source.path, &target.path) as usize;
+      if best.is_none_or(|(best, _)| score > best) {
+        best = Some((score, src));
+        if score == 2 {
+          break;
+        }
+      }
+      // Pick one when there are too many alternatives
+      left -= 1;
+      if left == 0 {
+        break;
+      }
+    }
+    if let Some((_, src)) = best {
+      record(&mut matches, used, dst, src, MAX_SCORE);
+    }
+  }
+
+  if minimum < MAX_SCORE {
+    // Sources that were renamed can't be used again. When neither copies
+    // nor rewrites are wanted, files whose name no other file on the same
+    // side has are compared with each other before everything else.
+    if !copies && !breaking {
+      srcs.retain(|&src| used[pairs[src].id] == 0);
+      let minimum = minimum + (MAX_SCORE - minimum) / 2;
+      let mut sources: HashMap<&[u8], Option<usize>> = HashMap::new();
+      for &src in &srcs {
+        sources
+          .entry(basename(&old(src).path))
+          .and_modify(|unique| *unique = None)
+          .or_insert(Some(src));
+      }
+      let mut targets: HashMap<&[u8], Option<usize>> = HashMap::new();
+      for dst in (0..dsts.len()).filter(|&dst| matches[dst].is_none()) {
+        targets
+          .entry(basename(&new(dst).path))
+          .and_modify(|unique| *unique = None)
+          .or_insert(Some(dst));
+      }
+      for (name, src) in sources {
+        if let (Some(src), Some(Some(dst))) = (src, targets.get(name)) {
+          let score = similarity(blobs, old(src), new(*dst), minimum)?;
+          if score >= minimum {
+            record(&mut matches, used, *dst, src, score);
+          }
+        }
+      }
+      srcs.retain(|&src| used[pairs[src].id] == 0);
+    }
+
+    let left = matches.iter().filter(|found| found.is_none()).count();
+    if left > 0
+      && !srcs.is_empty()
+      && (options.limit == 0 || left * srcs.len() <= options.limit * options.limit)
+    {
+      let mut candidates = Vec::with_capacity(left * CANDIDATES);
+      for dst in (0..dsts.len()).filter(|&dst| matches[dst].is_none()) {
+        let mut best = [None; CANDIDATES];
+        for &src in &srcs {
+          let (source, target) = (old(src), new(dst));
+          let candidate = Candidate {
+            score: similarity(blobs, source, target, minimum)?,
+            same_name: same_name(&source.path, &target.path),
+            dst,
+            src,
+          };
+          keep_if_better(&mut best, candidate);
+        }
+        candidates.extend_from_slice(&best);
+      }
+      candidates.sort_by(candidate_order);
+      // Renames of files that haven't been used yet go first so that a
+      // copy can't take the place of one
+      let passes: &[bool] = if copies { &[false, true] } else { &[false] };
+      for &copy in passes {
+        for candidate in &candidates {
+          let candidate = match candidate {
+            Some(candidate) if candidate.score >= minimum => candidate,
+            _ => break,
+          };
+          if matches[candidate.dst].is_some() || (!copy && used[pairs[candidate.src].id] > 0) {
+            continue;
+          }
+          record(
+            &mut matches,
+            used,
+            candidate.dst,
+            candidate.src,
+            candidate.score,
+          );
+        }
+      }
+    } else if left > 0 && !srcs.is_empty() {
+      Trace2::data("diff", "rename_limit_exceeded", left.max(srcs.len()));
+    }
+  }
+
+  // Added files that were matched become renames and deleted files are
+  // left out if they were used, or for broken up ones if their other
+  // half was replaced by a rename
+  let dst_of: HashMap<&BStr, usize> = dsts
+    .iter()
+    .enumerate()
+    .map(|(dst, &idx)| (pairs[idx].path(), dst))
+    .collect();
+  let mut out = Vec::with_capacity(pairs.len());
+  let mut next_dst = 0;
+  for pair in &pairs {
+    match (&pair.old, &pair.new) {
+      (None, Some(_)) => {
+        let dst = next_dst;
+        next_dst += 1;
+        if let Some((src, score)) = matches[dst] {
+          let source = &pairs[src];
+          let same_path = source.path() == pair.path();
+          out.push(Pair {
+            id: source.id,
+            kind: ChangeKind::Modified,
+            old: source.old.clone(),
+            new: pair.new.clone(),
+            score: if same_path { source.score } else { score },
+            broken: false,
+            renamed: true,
+          });
+          continue;
+        }
+      }
+      (Some(_), None) => {
+        let gone = if pair.broken {
+          dst_of
+            .get(pair.path())
+            .is_some_and(|&dst| matches[dst].is_some())
+        } else {
+          used[pair.id] > 0
+        };
+        if gone {
+          continue;
+        }
+      }
+      _ => {}
+    }
+    out.push(Pair {
+      old: pair.old.clone(),
+      new: pair.new.clone(),
+      ..*pair
+    });
+  }
+  Ok(out)
+}
+
+/// Put the halves of broken up files back together if neither was matched
+fn merge_broken(pairs: Vec<Pair>, used: &mut [usize]) -> Vec<Pair> {
+  let mut pairs: Vec<Option<Pair>> = pairs.into_iter().map(Some).collect();
+  let mut out = Vec::with_capacity(pairs.len());
+  for idx in 0..pairs.len() {
+    let pair = match pairs[idx].take() {
+      Some(pair) => pair,
+      None => continue,
+    };
+    if !pair.broken {
+      out.push(pair);
+      continue;
+    }
+    let peer = (idx + 1..pairs.len()).find(|&peer| {
+      pairs[peer]
+        .as_ref()
+        .is_some_and(|peer| peer.broken && peer.path() == pair.path())
+    });
+    let peer = match peer.and_then(|peer| pairs[peer].take()) {
+      Some(peer) => peer,
+      None => {
+        out.push(pair);
+        continue;
+      }
+    };
+    let (deleted, added) = if pair.old.is_some() {
+      (pair, peer)
+    } else {
+      (peer, pair)
+    };
+    used[deleted.id] += 1;
+    out.push(Pair {
+      id: deleted.id,
+      kind: ChangeKind::Modified,
+      old: deleted.old,
+      new: added.new,
+      score: deleted.score,
+      broken: false,
+      renamed: false,
+    });
+  }
+  out
+}
+
+/// Only modifications of blobs that stayed at the same path are broken up
+fn breakable(old: &DiffFile, new: &DiffFile) -> bool {
+  old.mode.is_blob() && new.mode.is_blob() && old.path == new.path
+}
+
+/// Whether a modification changed so much that it should be broken up,
+/// returning how much of the old contents was removed if so
+fn should_break(
+  blobs: &mut Blobs<'_>,
+  old: &DiffFile,
+  new: &DiffFile,
+  options: &RewriteOptions,
+) -> Result<Option<u64>, DiffError> {
+  if is_file(old.mode) != is_file(new.mode) {
+    return Ok(Some(MAX_SCORE));
+  }
+  if old.oid == new.oid {
+    return Ok(None);
+  }
+  let break_score = score(options.break_threshold);
+  let src_size = blobs.spans(&old.oid)?.size;
+  let dst_size = blobs.spans(&new.oid)?.size;
+  let max_size = src_size.max(dst_size);
+  if max_size < MINIMUM_BREAK_SIZE || src_size == 0 {
+    return Ok(None);
+  }
+  let (copied, added) = blobs.count_changes(&old.oid, &new.oid)?;
+  let copied = copied.min(src_size);
+  let added = if dst_size < added + copied {
+    dst_size.saturating_sub(copied)
+  } else {
+    added
+  };
+  let removed = src_size - copied;
+
+  // How much was removed decides whether the halves are merged back
+  // together later, both removed and added contents whether to break
+  let mut merge_score = removed * MAX_SCORE / src_size;
+  if merge_score <= break_score {
+    if (removed + added) * MAX_SCORE / max_size < break_score {
+      return Ok(None);
+    }
+    // Removing a lot without adding anything isn't a rewrite
+    if src_size * break_score < removed * MAX_SCORE && added * 20 < removed && added * 20 < copied {
+      return Ok(None);
+    }
+  }
+  if merge_score < score(options.merge_threshold) {
+    merge_score = 0;
+  }
+  Ok(Some(merge_score))
+}
+
+/// How similar `old` is to `new`, or 0 if it's certain to be less than
+/// `minimum` without looking at the contents
+fn similarity(
+  blobs: &mut Blobs<'_>,
+  old: &DiffFile,
+  new: &DiffFile,
+  minimum: u64,
+) -> Result<u64, DiffError> {
+  if !is_file(old.mode) || !is_file(new.mode) {
+    return Ok(0);
+  }
+  let src_size = blobs.spans(&old.oid)?.size;
+  let dst_size = blobs.spans(&new.oid)?.size;
+  let max_size = src_size.max(dst_size);
+  let delta_size = max_size - src_size.min(dst_size);
+  if max_size * (MAX_SCORE - minimum) < delta_size * MAX_SCORE || dst_size == 0 {
+    return Ok(0);
+  }
+  let (copied, _) = blobs.count_changes(&old.oid, &new.oid)?;
+  Ok(copied * MAX_SCORE / max_size)
+}
+
+/// The pieces of a blob's contents, see [`Spans::new`]
+struct Spans {
+  size: u64,
+  /// How many bytes are in pieces with each hash, sorted by hash
+  counts: Vec<(u32, u64)>,
+}
+
+impl Spans {
+  /// Cut the contents into lines, or 64 byte pieces of longer lines, and
+  /// hash them. Carriage returns before line feeds are left out of text.
+  /// Like in git a last line without a line feed isn't counted.
+  fn new(contents: &[u8]) -> Self {
+    // A prime between 2^16 and 2^17 that git picked for its hash table
+    const HASH_BASE: u32 = 107927;
+    let text = !is_binary(contents);
+    let mut counts: HashMap<u32, u64> = HashMap::new();
+    let (mut len, mut accum1, mut accum2) = (0, 0u32, 0u32);
+    let mut add = |len: u64, accum1: u32, accum2: u32| {
+      let hash = accum1.wrapping_add(accum2.wrapping_mul(0x61)) % HASH_BASE;
+      *counts.entry(hash).or_default() += len;
+    };
+    for (idx, &c) in contents.iter().enumerate() {
+      if text && c == b'\r' && contents.get(idx + 1) == Some(&b'\n') {
+        continue;
+      }
+      let old = accum1;
+      accum1 = (accum1 << 7) ^ (accum2 >> 25);
+      accum2 = (accum2 << 7) ^ (old >> 25);
+      accum1 = accum1.wrapping_add(c as u32);
+      len += 1;
+      if len < 64 && c != b'\n' {
+        continue;
+      }
+      add(len, accum1, accum2);
+      len = 0;
+      accum1 = 0;
+      accum2 = 0;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_unstable();
+    Self {
+      size: contents.len() as u64,
+      counts,
+    }
+  }
+}
+
+/// The [`Spans`] of every blob compared so far, so that each is only read
+/// and hashed once
+struct Blobs<'a> {
+  odb: &'a Odb,
+  spans: HashMap<OID, Spans>,
+}
+
+impl Blobs<'_> {
+  fn spans(&mut self, oid: &OID) -> Result<&Spans, DiffError> {
+    Ok(match self.spans.entry(*oid) {
+      Entry::Occupied(entry) => entry.into_mut(),
+      Entry::Vacant(entry) => entry.insert(Spans::new(self.odb.read_blob(oid)?.contents())),
+    })
+  }
+
+  /// How many bytes of `old` are in `new` and how many bytes of `new`
+  /// aren't in `old`
+  fn count_changes(&mut self, old: &OID, new: &OID) -> Result<(u64, u64), DiffError> {
+    self.spans(old)?;
+    self.spans(new)?;
+    let (old, new) = (&self.spans[old].counts, &self.spans[new].counts);
+    let (mut copied, mut added) = (0, 0);
+    let (mut old, mut new) = (old.iter().peekable(), new.iter().peekable());
+    loop {
+      let order = match (old.peek(), new.peek()) {
+        (None, None) => break,
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(a), Some(b)) => a.0.cmp(&b.0),
+      };
+      match order {
+        Ordering::Less => {
+          old.next();
+        }
+        Ordering::Greater => added += new.next().unwrap().1,
+        Ordering::Equal => {
+          let (src, dst) = (old.next().unwrap().1, new.next().unwrap().1);
+          copied += src.min(dst);
+          added += dst.saturating_sub(src);
+        }
+      }
+    }
+    Ok((copied, added))
+  }
+}
+
+fn is_file(mode: FileMode) -> bool {
+  matches!(mode, FileMode::NonExecutableFile | FileMode::ExecutableFile)
+}
+
+fn score(percent: u8) -> u64 {
+  u64::from(percent.min(100)) * MAX_SCORE / 100
+}
+
+fn percent(score: u64) -> u8 {
+  (score * 100 / MAX_SCORE) as u8
+}
+
+fn basename(path: &[u8]) -> &[u8] {
+  path.rsplit(|c| *c == b'/').next().unwrap()
+}
+
+fn same_name(a: &[u8], b: &[u8]) -> bool {
+  basename(a) == basename(b)
+}
+
+#[cfg(test)]
+fn summary(changes: &[Change]) -> Vec<String> {
+  changes.iter().map(ToString::to_string).collect()
+}
+
+#[test]
+fn renames() {
+  use crate::{diff::write_tree, diff_trees};
+  use FileMode::*;
+  let tmp_dir = tempdir::TempDir::new("rename_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let lines: String = (1..=20).map(|i| format!("line {}\n", i)).collect();
+  let lib: String = (1..=30)
+    .map(|i| format!("pub fn f{}() {{}}\n", i))
+    .collect();
+  let main = "fn main() {\n  run();\n}\n";
+  let old = write_tree(
+    &odb,
+    &[
+      ("a.txt", NonExecutableFile, &lines),
+      ("dir/moved.rs", NonExecutableFile, main),
+      ("gone", NonExecutableFile, "bye\n"),
+      ("link", SymbolicLink, "target"),
+      ("src/lib.rs", NonExecutableFile, &lib),
+    ],
+  );
+  let new = write_tree(
+    &odb,
+    &[
+      (
+        "b.txt",
+        NonExecutableFile,
+        &lines.replace("line 7", "LINE 7"),
+      ),
+      ("link2", SymbolicLink, "target"),
+      ("moved.rs", NonExecutableFile, main),
+      ("src/copy.rs", NonExecutableFile, &lib),
+      (
+        "src/lib.rs",
+        NonExecutableFile,
+        &(lib.clone() + "pub fn g() {}\n"),
+      ),
+    ],
+  );
+  let detect = |options: &RenameOptions| {
+    let changes = diff_trees(&odb, Some(&old), Some(&new)).unwrap();
+    summary(&detect_renames(&odb, changes, options).unwrap())
+  };
+
+  // The same as `git diff -M --name-status` shows
+  assert_eq!(
+    vec![
+      "R095\ta.txt\tb.txt",
+      "D\tgone",
+      "R100\tlink\tlink2",
+      "R100\tdir/moved.rs\tmoved.rs",
+      "A\tsrc/copy.rs",
+      "M\tsrc/lib.rs",
+    ],
+    detect(&RenameOptions::default())
+  );
+  let copies = RenameOptions {
+    detection: RenameDetection::Copies,
+    ..RenameOptions::default()
+  };
+  assert_eq!("C100\tsrc/lib.rs\tsrc/copy.rs", detect(&copies)[4]);
+
+  // Only identical files are matched above the threshold or the limit
+  let unrenamed = vec![
+    "D\ta.txt",
+    "A\tb.txt",
+    "D\tgone",
+    "R100\tlink\tlink2",
+    "R100\tdir/moved.rs\tmoved.rs",
+    "A\tsrc/copy.rs",
+    "M\tsrc/lib.rs",
+  ];
+  let strict = RenameOptions {
+    threshold: 96,
+    ..RenameOptions::default()
+  };
+  assert_eq!(unrenamed, detect(&strict));
+  let limited = RenameOptions {
+    limit: 1,
+    ..RenameOptions::default()
+  };
+  assert_eq!(unrenamed, detect(&limited));
+  let none = RenameOptions {
+    detection: RenameDetection::No,
+    ..RenameOptions::default()
+  };
+  assert_eq!(9, detect(&none).len());
+}
+
+#[test]
+fn rewrites() {
+  use crate::{diff::write_tree, diff_trees};
+  use FileMode::*;
+  let tmp_dir = tempdir::TempDir::new("rename_test").unwrap();
+  let odb = Odb::new(tmp_dir.path());
+  let lines =
+    |word: &str| -> String { (1..=40).map(|i| format!("{} line {}\n", word, i)).collect() };
+  let old = write_tree(
+    &odb,
+    &[
+      ("big", NonExecutableFile, &lines("old")),
+      ("x", NonExecutableFile, &lines("other")),
+      ("z", NonExecutableFile, &lines("zeta")),
+    ],
+  );
+  let new = write_tree(
+    &odb,
+    &[
+      ("big", NonExecutableFile, &lines("new")),
+      ("x", NonExecutableFile, &lines("zeta")),
+      ("y", NonExecutableFile, &lines("other")),
+    ],
+  );
+  let detect = |options: &RenameOptions| {
+    let changes = diff_trees(&odb, Some(&old), Some(&new)).unwrap();
+    summary(&detect_renames(&odb, changes, options).unwrap())
+  };
+  assert_eq!(
+    vec!["M\tbig", "M\tx", "A\ty", "D\tz"],
+    detect(&RenameOptions::default())
+  );
+  // Breaking up x lets its old contents be matched with y and its new
+  // ones with z, the same as `git diff -B -M`
+  let breaking = RenameOptions {
+    rewrites: Some(RewriteOptions::default()),
+    ..RenameOptions::default()
+  };
+  assert_eq!(
+    vec!["M100\tbig", "R100\tz\tx", "R100\tx\ty"],
+    detect(&breaking)
+  );
+  let only_breaking = RenameOptions {
+    detection: RenameDetection::No,
+    ..breaking
+  };
+  assert_eq!(
+    vec!["M100\tbig", "M100\tx", "A\ty", "D\tz"],
+    detect(&only_breaking)
+  );
+}
+
+#[test]
+fn rename_options_from_config() {
+  let config = Config::from_bytes("[diff]\n\trenames = copies\n\trenameLimit = 5").unwrap();
+  let options = RenameOptions::from_config(&config).unwrap();
+  assert_eq!(RenameDetection::Copies, options.detection);
+  assert_eq!(5, options.limit);
+  let config = Config::from_bytes("[diff]\n\trenames = false").unwrap();
+  let options = RenameOptions::from_config(&config).unwrap();
+  assert_eq!(RenameDetection::No, options.detection);
+  let config = Config::from_bytes("[diff]\n\trenames = maybe").unwrap();
+  assert!(RenameOptions::from_config(&config).is_err());
+  assert_eq!(
+    RenameOptions::default(),
+    RenameOptions::from_config(&Config::from_bytes("").unwrap()).unwrap()
+  );
+}
@@ -0,0 +1,182 @@
+use crate::{
+  Config, ConfigError, ObjectKind, OdbError, RefError, Repository, RepositoryError, OID,
+};
+use bstr::ByteSlice;
+use std::{collections::HashMap, env};
+use thiserror::Error;
+
+/// Where replace refs are kept unless `GIT_REPLACE_REF_BASE` says otherwise
+const REPLACE_REF_BASE: &str = "refs/replace/";
+
+/// The namespace replace refs are in, ending with a slash
+fn replace_ref_base() -> String {
+  match env::var("GIT_REPLACE_REF_BASE") {
+    Ok(base) if !base.is_empty() => match base.ends_with('/') {
+      true => base,
+      false => base + "/",
+    },
+    _ => REPLACE_REF_BASE.into(),
+  }
+}
+
+/// Whether objects are replaced in a repository with `config`, which they
+/// are unless `GIT_NO_REPLACE_OBJECTS` is set or `core.useReplaceRefs` is
+/// off, like git
+pub(crate) fn enabled(config: &Config) -> Result<bool, ConfigError> {
+  Ok(
+    env::var_os("GIT_NO_REPLACE_OBJECTS").is_none()
+      && config.get_bool("core.usereplacerefs")? != Some(false),
+  )
+}
+
+/// The replacements the replace refs of `repo` ask for, by the [`OID`] of
+/// the object being replaced. Refs that aren't named after an object are
+/// left out.
+pub(crate) fn read_replacements(repo: &Repository) -> Result<HashMap<OID, OID>, RefError> {
+  let mut replacements = HashMap::new();
+  let base = replace_ref_base();
+  for reference in repo.refs().list(&base)? {
+    let original = match OID::from_hex(&reference.name()[base.len()..].to_str_lossy()) {
+      Ok(original) => original,
+      Err(_) => continue,
+    };
+    if let Some(replacement) = repo.refs().resolve(reference.name())? {
+      replacements.insert(original, replacement);
+    }
+  }
+  Ok(replacements)
+}
+
+impl Repository {
+  /// Make reading the object `original` read `replacement` instead, like
+  /// `git replace {original} {replacement}`, by pointing
+  /// `refs/replace/{original}` at it. Both have to be there and be of the
+  /// same kind, and an object that's already replaced is only replaced
+  /// again if `force` is set. This is how `git replace --graft` rewrites
+  /// the parents of a commit without rewriting the commits after it.
+  pub fn replace(
+    &mut self,
+    original: &OID,
+    replacement: &OID,
+    force: bool,
+  ) -> Result<(), ReplaceError> {
+    if original == replacement {
+      return Err(ReplaceError::SameObject(*original));
+    }
+    let name = format!("{}{}", replace_ref_base(), original);
+    if !force && self.refs().read(&name)?.is_some() {
+      return Err(ReplaceError::Exists(*original));
+    }
+    let odb = self.odb().clone().without_replacements();
+    let (expected, found) = (odb.object_kind(original)?, odb.object_kind(replacement)?);
+    if expected != found {
+      return Err(ReplaceError::WrongKind {
+        oid: *replacement,
+        expected,
+        found,
+      });
+    }
+    self.refs().write(&name, replacement)?;
+    Ok(self.load_replacements()?)
+  }
+
+  /// Stop replacing the object `original`, like `git replace -d`,
+  /// returning whether it was replaced
+  pub fn remove_replacement(&mut self, original: &OID) -> Result<bool, ReplaceError> {
+    let removed = self
+      .refs()
+      .delete(format!("{}{}", replace_ref_base(), original))?;
+    self.load_replacements()?;
+    Ok(removed)
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to replacing objects
+pub enum ReplaceError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Repository(#[from] RepositoryError),
+  #[error("new object is the same as the old one: {0}")]
+  SameObject(OID),
+  #[error("replace ref for {0} already exists")]
+  Exists(OID),
+  #[error("object {oid} is a {found} not a {expected}")]
+  WrongKind {
+    oid: OID,
+    expected: ObjectKind,
+    found: ObjectKind,
+  },
+}
+
+#[test]
+fn replace() {
+  use crate::{Commit, Signature, Time, Tree};
+  let tmp_dir = tempdir::TempDir::new("replace_test").unwrap();
+  let mut repo = Repository::init(tmp_dir.path()).unwrap();
+  let odb = repo.odb().clone();
+  let tree = odb.write_tree(&Tree::new(Vec::new())).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |parents: Vec<OID>, message: &str| {
+    let commit = Commit::new(tree, parents, signature.clone(), signature.clone(), message);
+    odb.write_commit(&commit).unwrap()
+  };
+  let root = commit(vec![], "root\n");
+  let middle = commit(vec![root], "middle\n");
+  let tip = commit(vec![middle], "tip\n");
+  repo.refs().write("refs/heads/master", &tip).unwrap();
+
+  // Grafting the tip onto the root leaves the middle commit out of history
+  let graft = commit(vec![root], "tip\n");
+  repo.replace(&tip, &graft, false).unwrap();
+  assert!(matches!(
+    repo.replace(&tip, &graft, false),
+    Err(ReplaceError::Exists(_))
+  ));
+  assert!(matches!(
+    repo.replace(&root, &tree, true),
+    Err(ReplaceError::WrongKind { .. })
+  ));
+  assert_eq!(&[root], repo.odb().read_commit(&tip).unwrap().parents());
+  let history = |repo: &Repository| {
+    let mut walk = repo.rev_walk();
+    walk.push(&tip).unwrap();
+    walk.collect::<Result<Vec<_>, _>>().unwrap()
+  };
+  assert_eq!(vec![tip, root], history(&repo));
+  let reopened = Repository::open(tmp_dir.path()).unwrap();
+  assert_eq!(vec![tip, root], history(&reopened));
+
+  // Git sees the same history
+  if crate::transport::http::have_git() {
+    let output = std::process::Command::new("git")
+      .args(["rev-list", "master"])
+      .current_dir(tmp_dir.path())
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .output()
+      .unwrap();
+    assert!(output.status.success());
+    assert_eq!(format!("{}\n{}\n", tip, root), output.stdout.to_str_lossy());
+  }
+
+  // What's sent elsewhere is what's stored
+  let original = repo.original_objects();
+  assert_eq!(
+    &[middle],
+    original.odb().read_commit(&tip).unwrap().parents()
+  );
+  repo.set_replace_objects(false).unwrap();
+  assert_eq!(vec![tip, middle, root], history(&repo));
+  repo.set_replace_objects(true).unwrap();
+  assert!(repo.remove_replacement(&tip).unwrap());
+  assert!(!repo.remove_replacement(&tip).unwrap());
+  assert_eq!(vec![tip, middle, root], history(&repo));
+}
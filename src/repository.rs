@@ -0,0 +1,512 @@
+use crate::{
+  alternates, replace, CancellationToken, Config, ConfigError, ConfigFile, ConfigLevel,
+  FsCapabilities, Index, IndexError, MemoryBudget, ObjectCacheLimits, Odb, PackLimits, Progress,
+  Promisor, RefError, RefStore, RemotePromisor,
+};
+use bstr::ByteSlice;
+use std::{
+  borrow::Cow,
+  collections::HashMap,
+  fs,
+  io::{self, Write},
+  path::{Component, Path, PathBuf},
+  sync::Arc,
+};
+use thiserror::Error;
+
+/// A [`Repository`] ties together the git directory of a repository, its
+/// [`Odb`], its [`RefStore`], its [`Config`], and the working tree if there
+/// is one.
+///
+/// A [`Repository`] is `Send` and `Sync`, so a server can open it once and
+/// share it between threads. Everything that takes `&self` can be called
+/// from several threads at once: reading objects and refs, and writing
+/// objects, which are moved into place whole. Refs are changed under
+/// `.lock` files the same way git does, so two threads changing the same
+/// ref at once get [`RefError::Locked`] instead of clobbering each other,
+/// just like two processes would. What takes `&mut self`, like
+/// [`Repository::set_memory_budget`], needs the repository to itself, or a
+/// [`Clone`] of it, which shares the packs and object cache of its [`Odb`].
+#[derive(Debug, Clone)]
+pub struct Repository {
+  git_dir: PathBuf,
+  common_dir: PathBuf,
+  work_dir: Option<PathBuf>,
+  odb: Odb,
+  refs: RefStore,
+  config: Config,
+  replace_objects: bool,
+}
+
+// Sharing a repository between threads is part of its API, so it's
+// checked here rather than left to whatever its fields happen to be
+const _: fn() = || {
+  fn shared<T: Send + Sync>() {}
+  shared::<Repository>();
+  shared::<Odb>();
+  shared::<RefStore>();
+  shared::<Config>();
+};
+
+impl Repository {
+  /// Create a new repository with a working tree at `path`, storing git's
+  /// data in `path/.git`. Running this on an existing repository is safe and
+  /// only creates what is missing, like `git init`. The config of a new
+  /// repository records what the filesystem supports, see
+  /// [`FsCapabilities`].
+  pub fn init(path: impl AsRef<Path>) -> Result<Self, RepositoryError> {
+    let work_dir = path.as_ref();
+    let git_dir = work_dir.join(".git");
+    init_git_dir(&git_dir, false)?;
+    Self::from_parts(git_dir, Some(work_dir.into()))
+  }
+
+  /// Create a new bare repository, one without a working tree, at `path`
+  pub fn init_bare(path: impl AsRef<Path>) -> Result<Self, RepositoryError> {
+    let git_dir = path.as_ref();
+    init_git_dir(git_dir, true)?;
+    Self::from_parts(git_dir.into(), None)
+  }
+
+  /// Open an existing repository. `path` can either be the working tree of
+  /// a repository or the git directory itself, as is the case for bare
+  /// repositories. The `.git` of a working tree can also be a file pointing
+  /// at the git directory with a `gitdir: {path}` line, like the ones of
+  /// submodules.
+  pub fn open(path: impl AsRef<Path>) -> Result<Self, RepositoryError> {
+    let path = path.as_ref();
+    let dot_git = path.join(".git");
+    let git_dir = match is_git_dir(&dot_git) {
+      true => Some(dot_git),
+      false => read_git_file(&dot_git)?,
+    };
+    if let Some(git_dir) = git_dir {
+      let mut repo = Self::from_parts(git_dir, Some(path.into()))?;
+      // A repository that says it's bare has no working tree even if it's
+      // inside of one. Linked worktrees share the config but not that.
+      if repo.git_dir == repo.common_dir && repo.config.get_bool("core.bare")? == Some(true) {
+        repo.work_dir = None;
+      }
+      return Ok(repo);
+    }
+    if !is_git_dir(path) {
+      return Err(RepositoryError::NotFound(path.into()));
+    }
+    // The git directory of a linked worktree knows where the worktree is
+    if let Some(work_dir) = linked_work_dir(path)? {
+      return Self::from_parts(path.into(), Some(work_dir));
+    }
+    let config = Config::open(path)?;
+    let work_dir = match config.get_bool("core.bare")? {
+      Some(true) => None,
+      // Git directories kept away from their working tree, like the ones of
+      // submodules in `.git/modules`, say where it is
+      _ if config.get("core.worktree").is_some() => config
+        .get_path("core.worktree")?
+        .map(|dir| normalize(&path.join(dir))),
+      // A git directory opened directly is only bare if the config says so
+      // or if there isn't a working tree around it
+      _ => path
+        .parent()
+        .filter(|_| path.file_name() == Some(".git".as_ref()))
+        .map(Path::to_path_buf),
+    };
+    Self::from_parts(path.into(), work_dir)
+  }
+
+  fn from_parts(git_dir: PathBuf, work_dir: Option<PathBuf>) -> Result<Self, RepositoryError> {
+    let config = Config::open(&git_dir)?;
+    let common_dir = common_dir(&git_dir);
+    let mut repo = Self {
+      odb: Odb::new(common_dir.join("objects"))
+        .with_alternates(alternates::from_env())
+        .with_pack_limits(PackLimits::from_config(&config)?)
+        .with_object_cache(ObjectCacheLimits::from_config(&config)?),
+      refs: RefStore::new(&git_dir),
+      replace_objects: replace::enabled(&config)?,
+      config,
+      git_dir,
+      common_dir,
+      work_dir,
+    };
+    repo.load_replacements()?;
+    // A partial clone fetches what it left out from where it was cloned
+    if let Some(promisor) = RemotePromisor::from_config(&repo) {
+      repo.set_promisor(promisor);
+    }
+    Ok(repo)
+  }
+
+  /// The directory holding git's data, usually `.git`. For a linked
+  /// worktree this only holds its own `HEAD`, index, and other refs outside
+  /// of `refs/`, and everything else is in [`Repository::common_dir`].
+  pub fn git_dir(&self) -> &Path {
+    &self.git_dir
+  }
+
+  /// The directory holding what the worktrees of a repository share, like
+  /// the objects, most refs, and the config. This is the same as
+  /// [`Repository::git_dir`] unless the repository is a linked worktree.
+  pub fn common_dir(&self) -> &Path {
+    &self.common_dir
+  }
+
+  /// The working tree of the repository. This is `None` for bare
+  /// repositories.
+  pub fn work_dir(&self) -> Option<&Path> {
+    self.work_dir.as_deref()
+  }
+
+  /// Whether this repository has no working tree
+  pub fn is_bare(&self) -> bool {
+    self.work_dir.is_none()
+  }
+
+  /// The [`Odb`] of the repository
+  pub fn odb(&self) -> &Odb {
+    &self.odb
+  }
+
+  /// Limit the memory used by the [`Odb`] of the repository to `budget`,
+  /// for instance a [`MemoryBudget::child`] of the global budget per request
+  pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+    self.odb = self.odb.clone().with_budget(budget);
+  }
+
+  /// Fetch the objects a partial clone left out with `promisor` when
+  /// they're read, instead of the [`RemotePromisor`] for the remote in the
+  /// config
+  pub fn set_promisor(&mut self, promisor: impl Promisor + 'static) {
+    self.odb = self.odb.clone().with_promisor(promisor);
+  }
+
+  /// Report how far along fetching, checking out and repacking are to
+  /// `progress`, and stop them when it's cancelled
+  pub fn set_progress(&mut self, progress: impl Progress + 'static) {
+    self.set_shared_progress(Arc::new(progress));
+  }
+
+  pub(crate) fn set_shared_progress(&mut self, progress: Arc<dyn Progress>) {
+    self.odb = self.odb.clone().with_shared_progress(progress);
+  }
+
+  /// Stop walks, fetches, checkouts and repacks with
+  /// [`OdbError::Cancelled`][crate::OdbError::Cancelled] once `token` is
+  /// cancelled
+  pub fn set_cancellation(&mut self, token: CancellationToken) {
+    self.odb = self.odb.clone().with_cancellation(token);
+  }
+
+  /// Read the replace refs again, or forget them if replacing objects is
+  /// turned off
+  pub(crate) fn load_replacements(&mut self) -> Result<(), RepositoryError> {
+    let replacements = match self.replace_objects {
+      true => replace::read_replacements(self)?,
+      false => HashMap::new(),
+    };
+    self.odb = self.odb.clone().with_replacements(replacements);
+    Ok(())
+  }
+
+  /// Whether reading objects honors the replace refs, which it does
+  /// unless `GIT_NO_REPLACE_OBJECTS` is set or `core.useReplaceRefs` is
+  /// off when the repository is opened. Turning it off is like `git
+  /// --no-replace-objects`.
+  pub fn set_replace_objects(&mut self, replace: bool) -> Result<(), RepositoryError> {
+    self.replace_objects = replace;
+    self.load_replacements()
+  }
+
+  /// The repository with the objects as they're stored, which is what has
+  /// to be looked at when sending or indexing objects since replace refs
+  /// only change what's read here
+  pub(crate) fn original_objects(&self) -> Cow<'_, Repository> {
+    if self.odb.replacements().is_empty() {
+      return Cow::Borrowed(self);
+    }
+    let mut repo = self.clone();
+    repo.odb = repo.odb.without_replacements();
+    Cow::Owned(repo)
+  }
+
+  /// Borrow the objects of the object directory `objects`, like the
+  /// `objects` directory of another repository, by adding it to
+  /// `objects/info/alternates`. Objects that are there aren't fetched or
+  /// copied anymore, so that repository must not lose them, which pruning
+  /// it can do.
+  pub fn add_alternate(&mut self, objects: impl AsRef<Path>) -> Result<(), RepositoryError> {
+    let objects = fs::canonicalize(objects.as_ref())?;
+    if !objects.is_dir() {
+      return Err(io::Error::new(io::ErrorKind::NotFound, "not an object directory").into());
+    }
+    let info = self.odb.path().join("info");
+    fs::create_dir_all(&info)?;
+    if !alternates::listed(self.odb.path()).contains(&objects) {
+      let mut line = objects.as_os_str().to_string_lossy().into_owned();
+      line.push('\n');
+      fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(info.join("alternates"))?
+        .write_all(line.as_bytes())?;
+    }
+    self.odb = self.odb.clone().with_alternates(vec![objects]);
+    Ok(())
+  }
+
+  /// The [`RefStore`] of the repository
+  pub fn refs(&self) -> &RefStore {
+    &self.refs
+  }
+
+  /// The [`Config`] of the repository including the global and system
+  /// config
+  pub fn config(&self) -> &Config {
+    &self.config
+  }
+
+  /// The path of the [`Index`] file
+  pub fn index_path(&self) -> PathBuf {
+    self.git_dir.join("index")
+  }
+
+  /// Read the [`Index`] of the repository
+  pub fn index(&self) -> Result<Index, IndexError> {
+    Index::open_with_config(&self.index_path(), &self.config)
+  }
+
+  /// Read the config files again, for instance after they were changed
+  pub fn reload_config(&mut self) -> Result<(), RepositoryError> {
+    self.config = Config::open(&self.git_dir)?;
+    Ok(())
+  }
+}
+
+fn is_git_dir(path: &Path) -> bool {
+  path.join("HEAD").is_file() && common_dir(path).join("objects").is_dir()
+}
+
+/// The directory holding what's shared between worktrees for the git
+/// directory `git_dir`, which a linked worktree names in its `commondir`
+/// file relative to itself
+pub(crate) fn common_dir(git_dir: &Path) -> PathBuf {
+  match fs::read(git_dir.join("commondir")) {
+    Ok(contents) => match contents.trim_end().to_path() {
+      Ok(dir) => normalize(&git_dir.join(dir)),
+      Err(_) => git_dir.into(),
+    },
+    Err(_) => git_dir.into(),
+  }
+}
+
+/// The working tree of a linked worktree with the git directory `git_dir`,
+/// from its `gitdir` file pointing at the `.git` file of the worktree
+fn linked_work_dir(git_dir: &Path) -> Result<Option<PathBuf>, RepositoryError> {
+  if !git_dir.join("commondir").is_file() {
+    return Ok(None);
+  }
+  let contents = match fs::read(git_dir.join("gitdir")) {
+    Ok(contents) => contents,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e.into()),
+  };
+  let dot_git = contents
+    .trim_end()
+    .to_path()
+    .map_err(|_| RepositoryError::InvalidGitFile(git_dir.join("gitdir")))?;
+  Ok(
+    normalize(&git_dir.join(dot_git))
+      .parent()
+      .map(Path::to_path_buf),
+  )
+}
+
+/// Drop the `.` and `..` components of a path the way they read, without
+/// looking at what's on disk
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+  let mut normalized = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::CurDir => {}
+      Component::ParentDir if normalized.file_name().is_some() => {
+        normalized.pop();
+      }
+      component => normalized.push(component),
+    }
+  }
+  normalized
+}
+
+/// Read the git directory out of a `.git` file, which is `None` if `path`
+/// isn't a file. A relative git directory is relative to the file.
+pub(crate) fn read_git_file(path: &Path) -> Result<Option<PathBuf>, RepositoryError> {
+  if !path.is_file() {
+    return Ok(None);
+  }
+  let contents = fs::read(path)?;
+  let git_dir = contents
+    .strip_prefix(b"gitdir: ")
+    .map(|dir| dir.trim_end_with(|c| c == '\n' || c == '\r'))
+    .and_then(|dir| dir.to_path().ok())
+    .ok_or_else(|| RepositoryError::InvalidGitFile(path.into()))?;
+  // The file is always in a directory, the working tree
+  let git_dir = normalize(&path.parent().unwrap().join(git_dir));
+  match is_git_dir(&git_dir) {
+    true => Ok(Some(git_dir)),
+    false => Err(RepositoryError::NotFound(git_dir)),
+  }
+}
+
+fn init_git_dir(git_dir: &Path, bare: bool) -> Result<(), RepositoryError> {
+  for dir in ["objects/info", "objects/pack", "refs/heads", "refs/tags"] {
+    fs::create_dir_all(git_dir.join(dir))?;
+  }
+  let head = git_dir.join("HEAD");
+  if !head.exists() {
+    fs::write(head, "ref: refs/heads/master\n")?;
+  }
+  let config = git_dir.join("config");
+  if !config.exists() {
+    // Like git, what the filesystem supports is only probed for new
+    // repositories and recorded in their config
+    let capabilities = FsCapabilities::probe(git_dir)?;
+    fs::write(
+      &config,
+      format!(
+        "[core]\n\trepositoryformatversion = 0\n\tfilemode = {}\n\tbare = {}\n",
+        capabilities.file_mode, bare
+      ),
+    )?;
+    let mut file = ConfigFile::from_file(&config, ConfigLevel::Local)?;
+    capabilities.write_config(&mut file)?;
+    file.save()?;
+  }
+  Ok(())
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`Repository`] type
+pub enum RepositoryError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("no git repository found at {0:?}")]
+  NotFound(PathBuf),
+  #[error("{0:?} is not a valid .git file")]
+  InvalidGitFile(PathBuf),
+}
+
+#[test]
+fn init_and_open() {
+  let tmp_dir = tempdir::TempDir::new("repository_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  assert_eq!(tmp_dir.path().join(".git"), repo.git_dir());
+  assert_eq!(Some(tmp_dir.path()), repo.work_dir());
+  assert!(tmp_dir.path().join(".git/objects/pack").is_dir());
+  assert_eq!(Some(false), repo.config().get_bool("core.bare").unwrap());
+  let config = fs::read_to_string(tmp_dir.path().join(".git/config")).unwrap();
+  #[cfg(target_os = "linux")]
+  assert_eq!(
+    "[core]\n\trepositoryformatversion = 0\n\tfilemode = true\n\tbare = false\n",
+    config
+  );
+
+  let repo = Repository::open(tmp_dir.path()).unwrap();
+  assert!(!repo.is_bare());
+  let repo = Repository::open(tmp_dir.path().join(".git")).unwrap();
+  assert_eq!(Some(tmp_dir.path()), repo.work_dir());
+}
+
+#[test]
+fn init_bare() {
+  let tmp_dir = tempdir::TempDir::new("repository_test").unwrap();
+  Repository::init_bare(tmp_dir.path()).unwrap();
+  let repo = Repository::open(tmp_dir.path()).unwrap();
+  assert!(repo.is_bare());
+  assert_eq!(tmp_dir.path().join("objects"), repo.odb().path());
+}
+
+#[test]
+fn open_git_file() {
+  let tmp_dir = tempdir::TempDir::new("repository_test").unwrap();
+  let git_dir = tmp_dir.path().join("elsewhere.git");
+  Repository::init_bare(&git_dir).unwrap();
+  let mut config = ConfigFile::from_file(git_dir.join("config"), ConfigLevel::Local).unwrap();
+  config.set("core.bare", "false").unwrap();
+  config.save().unwrap();
+  let work_dir = tmp_dir.path().join("work");
+  fs::create_dir(&work_dir).unwrap();
+  fs::write(work_dir.join(".git"), "gitdir: ../elsewhere.git\n").unwrap();
+  let repo = Repository::open(&work_dir).unwrap();
+  assert_eq!(git_dir, repo.git_dir());
+  assert_eq!(Some(&*work_dir), repo.work_dir());
+
+  fs::write(work_dir.join(".git"), "not a git file\n").unwrap();
+  assert!(matches!(
+    Repository::open(&work_dir),
+    Err(RepositoryError::InvalidGitFile(_))
+  ));
+}
+
+#[test]
+fn open_missing() {
+  let tmp_dir = tempdir::TempDir::new("repository_test").unwrap();
+  assert!(matches!(
+    Repository::open(tmp_dir.path()),
+    Err(RepositoryError::NotFound(_))
+  ));
+}
+
+#[test]
+fn bare_errors() {
+  use crate::{CheckoutError, IndexError, StatusError};
+  let tmp_dir = tempdir::TempDir::new("repository_test").unwrap();
+  let repo = Repository::init_bare(tmp_dir.path().join("bare.git")).unwrap();
+  let tree = repo.odb().write_tree(&crate::Tree::new(vec![])).unwrap();
+  assert!(matches!(repo.status(), Err(StatusError::BareRepository)));
+  assert!(matches!(
+    repo.checkout_tree(&tree),
+    Err(CheckoutError::BareRepository)
+  ));
+  assert!(matches!(
+    repo.refresh_index(),
+    Err(IndexError::BareRepository)
+  ));
+  assert!(repo.index().unwrap().is_empty());
+  assert!(repo.attributes().is_ok());
+
+  // The config has the last word even for a `.git` inside of a working tree
+  let work_dir = tmp_dir.path().join("work");
+  let repo = Repository::init(&work_dir).unwrap();
+  let mut config =
+    ConfigFile::from_file(repo.git_dir().join("config"), ConfigLevel::Local).unwrap();
+  config.set("core.bare", "true").unwrap();
+  config.save().unwrap();
+  assert!(Repository::open(&work_dir).unwrap().is_bare());
+}
+
+#[test]
+fn shared_between_threads() {
+  use crate::Blob;
+  let tmp_dir = tempdir::TempDir::new("repository_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let blob = Blob::new("written by every thread\n");
+  std::thread::scope(|scope| {
+    for i in 0..8 {
+      let repo = &repo;
+      let blob = &blob;
+      scope.spawn(move || {
+        let oid = repo.odb().write_blob(blob).unwrap();
+        assert_eq!(*blob, repo.odb().read_blob(&oid).unwrap());
+        let name = format!("refs/heads/nested/thread-{}", i);
+        repo.refs().write(&name, &oid).unwrap();
+        assert!(repo.refs().delete(&name).unwrap());
+      });
+    }
+  });
+  assert_eq!(vec![blob.id()], repo.odb().oids().unwrap());
+  assert!(repo.refs().list("refs/heads/").unwrap().is_empty());
+}
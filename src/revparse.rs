@@ -0,0 +1,558 @@
+use crate::{
+  ConfigError, IndexError, ObjectKind, OdbError, RefError, RefTarget, Refspec, Repository, OID,
+};
+use std::collections::{hash_map::Entry, BinaryHeap, HashMap};
+use thiserror::Error;
+
+impl Repository {
+  /// Resolve a revision expression to an [`OID`] like `git rev-parse`. See
+  /// [`rev_parse`] for what is supported.
+  pub fn rev_parse(&self, spec: &str) -> Result<OID, RevParseError> {
+    rev_parse(self, spec)
+  }
+}
+
+/// Resolve a revision expression to an [`OID`] the same way as
+/// `git rev-parse`. The supported forms are:
+///
+/// - A full or abbreviated object name like `a8a9406`
+/// - A ref name like `HEAD`, `master`, `refs/tags/v1.0`, or `origin/main`,
+///   where `@` alone is `HEAD`
+/// - `branch@{upstream}` or `branch@{u}` for the branch a branch tracks,
+///   with the current branch if the name is left out
+/// - `rev~N` for the `N`th first parent and `rev^N` for the `N`th parent
+/// - `rev^{kind}` to peel tags and commits to a `commit`, `tree`, `blob`,
+///   or `tag`, and `rev^{}` to peel tags to what they point at
+/// - `rev^{/text}` and `:/text` for the newest commit reachable from `rev`
+///   or from any ref with a message containing `text`. Unlike git `text` is
+///   matched literally instead of as a regular expression. A leading `!-`
+///   matches commits that don't contain the text.
+/// - `rev:path` for a file or directory in the tree of a revision and
+///   `:path` or `:N:path` for a path in the index
+///
+/// Reflog expressions like `@{1}` or `@{yesterday}` are not supported yet.
+pub fn rev_parse(repo: &Repository, spec: &str) -> Result<OID, RevParseError> {
+  RevParser { repo, spec }.parse()
+}
+
+struct RevParser<'a> {
+  repo: &'a Repository,
+  /// The whole expression for error messages
+  spec: &'a str,
+}
+
+impl RevParser<'_> {
+  fn invalid(&self) -> RevParseError {
+    RevParseError::Invalid(self.spec.into())
+  }
+
+  fn parse(&self) -> Result<OID, RevParseError> {
+    let spec = self.spec;
+    if let Some(pattern) = spec.strip_prefix(":/") {
+      let tips = self.all_tips()?;
+      return self.search_message(tips, pattern);
+    }
+    if let Some(path) = spec.strip_prefix(':') {
+      return self.index_path(path);
+    }
+    match top_level_colon(spec) {
+      Some(idx) => {
+        let tree = self.peel(self.parse_rev(&spec[..idx])?, Some(ObjectKind::Tree))?;
+        self.tree_path(tree, &spec[idx + 1..])
+      }
+      None => self.parse_rev(spec),
+    }
+  }
+
+  /// Parse a revision without a `:path` at the end
+  fn parse_rev(&self, spec: &str) -> Result<OID, RevParseError> {
+    let mut depth = 0;
+    let base_end = spec
+      .char_indices()
+      .find(|&(_, c)| {
+        match c {
+          '{' => depth += 1,
+          '}' => depth -= 1,
+          _ => {}
+        }
+        depth == 0 && (c == '^' || c == '~')
+      })
+      .map_or(spec.len(), |(idx, _)| idx);
+    let mut oid = self.resolve_base(&spec[..base_end])?;
+
+    let mut rest = &spec[base_end..];
+    while let Some(c) = rest.chars().next() {
+      rest = &rest[1..];
+      if c == '^' && rest.starts_with('{') {
+        let end = rest.find('}').ok_or_else(|| self.invalid())?;
+        let inner = &rest[1..end];
+        rest = &rest[end + 1..];
+        oid = match inner {
+          "" => self.peel(oid, None)?,
+          "object" => {
+            self.repo.odb().read(&oid)?;
+            oid
+          }
+          "commit" => self.peel(oid, Some(ObjectKind::Commit))?,
+          "tree" => self.peel(oid, Some(ObjectKind::Tree))?,
+          "blob" => self.peel(oid, Some(ObjectKind::Blob))?,
+          "tag" => self.peel(oid, Some(ObjectKind::Tag))?,
+          inner => match inner.strip_prefix('/') {
+            Some(pattern) => {
+              let start = self.peel(oid, Some(ObjectKind::Commit))?;
+              self.search_message(vec![start], pattern)?
+            }
+            None => return Err(self.invalid()),
+          },
+        };
+        continue;
+      }
+      let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+      let n = match &rest[..digits] {
+        "" => 1,
+        n => n.parse().map_err(|_| self.invalid())?,
+      };
+      rest = &rest[digits..];
+      oid = match c {
+        '~' => (0..n).try_fold(oid, |oid, _| self.parent(oid, 1))?,
+        '^' if n == 0 => self.peel(oid, Some(ObjectKind::Commit))?,
+        '^' => self.parent(oid, n)?,
+        _ => return Err(self.invalid()),
+      };
+    }
+    Ok(oid)
+  }
+
+  /// Resolve the part of a revision before any `^` or `~`
+  fn resolve_base(&self, base: &str) -> Result<OID, RevParseError> {
+    if base.is_empty() {
+      return Err(self.invalid());
+    }
+    if let Some(at) = base.rfind("@{").filter(|_| base.ends_with('}')) {
+      let (name, modifier) = (&base[..at], &base[at + 2..base.len() - 1]);
+      return match modifier.to_ascii_lowercase().as_str() {
+        "upstream" | "u" => self.upstream(name),
+        _ => Err(RevParseError::Unsupported(format!(
+          "the @{{{}}} reflog expression",
+          modifier
+        ))),
+      };
+    }
+    let name = if base == "@" { "HEAD" } else { base };
+    if name.len() == 40 {
+      if let Ok(oid) = OID::from_hex(name) {
+        return Ok(oid);
+      }
+    }
+    let refs = self.repo.refs();
+    if let Some(reference) = refs.find(name)? {
+      return refs
+        .resolve(reference.name())?
+        .ok_or_else(|| RevParseError::NotFound(name.into()));
+    }
+    match self.repo.odb().find_prefix(name) {
+      Ok(oid) => Ok(oid),
+      Err(OdbError::Ambiguous(prefix)) => Err(RevParseError::Ambiguous(prefix)),
+      Err(OdbError::InvalidPrefix(_) | OdbError::PrefixNotFound(_)) => {
+        Err(RevParseError::NotFound(name.into()))
+      }
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// The tip of the branch that `name` tracks going by its
+  /// `branch.{name}.remote` and `branch.{name}.merge` config
+  fn upstream(&self, name: &str) -> Result<OID, RevParseError> {
+    let refs = self.repo.refs();
+    let branch = if name.is_empty() || name == "HEAD" || name == "@" {
+      match refs.read("HEAD")?.map(|head| head.target().clone()) {
+        Some(RefTarget::Symbolic(target)) => target.to_string(),
+        _ => return Err(RevParseError::NoUpstream("HEAD".into())),
+      }
+    } else {
+      name.into()
+    };
+    let branch = branch.strip_prefix("refs/heads/").unwrap_or(&branch);
+    let config = self.repo.config();
+    let no_upstream = || RevParseError::NoUpstream(branch.into());
+    let remote = config
+      .get_str(&format!("branch.{}.remote", branch))?
+      .ok_or_else(no_upstream)?;
+    let merge = config
+      .get_str(&format!("branch.{}.merge", branch))?
+      .ok_or_else(no_upstream)?;
+    let tracking = if remote == "." {
+      merge.to_string()
+    } else {
+      config
+        .get_all(&format!("remote.{}.fetch", remote))
+        .into_iter()
+        .filter_map(|refspec| Refspec::parse(refspec).ok()?.transform(merge))
+        .next()
+        .ok_or_else(no_upstream)?
+        .to_string()
+    };
+    refs
+      .resolve(&tracking)?
+      .ok_or(RevParseError::NotFound(tracking))
+  }
+
+  /// Peel tags, and commits when asking for a tree, until an object of the
+  /// given kind is found. With no kind tags are peeled to the first object
+  /// that is not a tag.
+  fn peel(&self, mut oid: OID, kind: Option<ObjectKind>) -> Result<OID, RevParseError> {
+    let odb = self.repo.odb();
+    loop {
+      let object = odb.read(&oid)?;
+      if Some(object.kind) == kind || (kind.is_none() && object.kind != ObjectKind::Tag) {
+        return Ok(oid);
+      }
+      match (object.kind, kind) {
+        (ObjectKind::Tag, _) => oid = *odb.read_tag(&oid)?.object(),
+        (ObjectKind::Commit, Some(ObjectKind::Tree)) => return Ok(*odb.read_commit(&oid)?.tree()),
+        (found, expected) => {
+          return Err(RevParseError::WrongKind {
+            oid,
+            // A kind is always given when peeling fails
+            expected: expected.unwrap_or(found),
+            found,
+          });
+        }
+      }
+    }
+  }
+
+  fn parent(&self, oid: OID, n: usize) -> Result<OID, RevParseError> {
+    let commit = self.peel(oid, Some(ObjectKind::Commit))?;
+    self
+      .repo
+      .odb()
+      .read_commit(&commit)?
+      .parents()
+      .get(n - 1)
+      .copied()
+      .ok_or(RevParseError::NoParent { oid: commit, n })
+  }
+
+  /// `HEAD` and every ref that points at a commit
+  fn all_tips(&self) -> Result<Vec<OID>, RevParseError> {
+    let refs = self.repo.refs();
+    let mut names = vec!["HEAD".into()];
+    names.extend(refs.list("refs/")?.into_iter().map(|r| r.name().to_owned()));
+    let mut tips = Vec::new();
+    for name in names {
+      let oid = match refs.resolve(&name)? {
+        Some(oid) => oid,
+        None => continue,
+      };
+      if let Ok(commit) = self.peel(oid, Some(ObjectKind::Commit)) {
+        tips.push(commit);
+      }
+    }
+    Ok(tips)
+  }
+
+  /// Find the newest commit reachable from `tips` whose message matches
+  fn search_message(&self, tips: Vec<OID>, pattern: &str) -> Result<OID, RevParseError> {
+    let (pattern, negate) = match pattern.strip_prefix('!') {
+      Some(rest) if rest.starts_with('!') => (rest, false),
+      Some(rest) => match rest.strip_prefix('-') {
+        Some(rest) => (rest, true),
+        None => return Err(self.invalid()),
+      },
+      None => (pattern, false),
+    };
+    let odb = self.repo.odb();
+    // Commits are looked at newest first going by their committer time
+    let mut commits = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    for oid in tips {
+      if let Entry::Vacant(entry) = commits.entry(oid) {
+        let commit = entry.insert(odb.read_commit(&oid)?);
+        queue.push((commit.committer().time.seconds, oid));
+      }
+    }
+    while let Some((_, oid)) = queue.pop() {
+      let commit = &commits[&oid];
+      if commit.message_lossy().contains(pattern) != negate {
+        return Ok(oid);
+      }
+      for parent in commit.parents().to_vec() {
+        if let Entry::Vacant(entry) = commits.entry(parent) {
+          let commit = entry.insert(odb.read_commit(&parent)?);
+          queue.push((commit.committer().time.seconds, parent));
+        }
+      }
+    }
+    Err(RevParseError::NoMatch(pattern.into()))
+  }
+
+  fn tree_path(&self, tree: OID, path: &str) -> Result<OID, RevParseError> {
+    let odb = self.repo.odb();
+    let mut oid = tree;
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+      let tree = odb
+        .read_tree(&oid)
+        .map_err(|_| RevParseError::PathNotFound(path.into()))?;
+      oid = *tree
+        .get(name)
+        .ok_or_else(|| RevParseError::PathNotFound(path.into()))?
+        .oid();
+    }
+    Ok(oid)
+  }
+
+  fn index_path(&self, path: &str) -> Result<OID, RevParseError> {
+    let (stage, path) = match path.as_bytes() {
+      [stage @ b'0'..=b'3', b':', ..] => (stage - b'0', &path[2..]),
+      _ => (0, path),
+    };
+    self
+      .repo
+      .index()?
+      .entries()
+      .iter()
+      .find(|entry| entry.stage == stage && entry.path == path)
+      .map(|entry| entry.oid)
+      .ok_or_else(|| RevParseError::PathNotFound(path.into()))
+  }
+}
+
+/// The first `:` that is not inside of an `@{...}` or `^{...}`
+fn top_level_colon(spec: &str) -> Option<usize> {
+  let mut depth = 0;
+  for (idx, c) in spec.char_indices() {
+    match c {
+      '{' => depth += 1,
+      '}' => depth -= 1,
+      ':' if depth == 0 => return Some(idx),
+      _ => {}
+    }
+  }
+  None
+}
+
+#[derive(Error, Debug)]
+/// Errors related to resolving revisions with [`rev_parse`]
+pub enum RevParseError {
+  #[error("invalid revision {0:?}")]
+  Invalid(String),
+  #[error("revision {0:?} not found")]
+  NotFound(String),
+  #[error("short object name {0} is ambiguous")]
+  Ambiguous(String),
+  #[error("commit {oid} has no parent {n}")]
+  NoParent { oid: OID, n: usize },
+  #[error("object {oid} is a {found} and can't be peeled to a {expected}")]
+  WrongKind {
+    oid: OID,
+    expected: ObjectKind,
+    found: ObjectKind,
+  },
+  #[error("branch {0:?} has no upstream")]
+  NoUpstream(String),
+  #[error("path {0:?} does not exist")]
+  PathNotFound(String),
+  #[error("no commit message matches {0:?}")]
+  NoMatch(String),
+  #[error("{0} is not supported")]
+  Unsupported(String),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Refs(#[from] RefError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Index(#[from] IndexError),
+}
+
+#[cfg(test)]
+struct TestRepo {
+  _dir: tempdir::TempDir,
+  repo: Repository,
+  commits: Vec<OID>,
+  tree: OID,
+  blob: OID,
+  tag: OID,
+}
+
+/// A repository with the history below where `C` is a merge of `B` and `D`
+/// and `master` points at `E`. `C` has the message `Merge side` and the
+/// others have their letter as the message.
+///
+/// ```text
+/// A - B - C - E
+///  \     /
+///   D ---
+/// ```
+#[cfg(test)]
+fn test_repo() -> TestRepo {
+  use crate::{Blob, Commit, FileMode, Signature, Tag, Time, Tree, TreeEntry};
+  let dir = tempdir::TempDir::new("revparse_test").unwrap();
+  let repo = Repository::init(dir.path()).unwrap();
+  let odb = repo.odb();
+  let blob = odb.write_blob(&Blob::new("this is a test")).unwrap();
+  let sub = odb
+    .write_tree(&Tree::new(vec![TreeEntry::new(
+      FileMode::NonExecutableFile,
+      "a.txt",
+      blob,
+    )]))
+    .unwrap();
+  let tree = odb
+    .write_tree(&Tree::new(vec![TreeEntry::new(FileMode::Tree, "dir", sub)]))
+    .unwrap();
+  let mut commits = Vec::new();
+  let mut commit = |parents: &[usize], message: &str| {
+    let time = Time::new(1_600_000_000 + commits.len() as i64, 0);
+    let signature = Signature::new("Jane Doe", "jane@example.com", time);
+    let parents = parents.iter().map(|&p| commits[p]).collect();
+    let commit = Commit::new(tree, parents, signature.clone(), signature, message);
+    commits.push(odb.write_commit(&commit).unwrap());
+  };
+  commit(&[], "A\n");
+  commit(&[0], "B\n");
+  commit(&[0], "D\n");
+  commit(&[1, 2], "Merge side\n");
+  commit(&[3], "E\n");
+  let (a, b, d, c, e) = (commits[0], commits[1], commits[2], commits[3], commits[4]);
+  let commits = vec![a, b, c, d, e];
+  let refs = repo.refs();
+  refs.write("refs/heads/master", &e).unwrap();
+  refs.write("refs/heads/side", &d).unwrap();
+  refs.write("refs/remotes/origin/master", &c).unwrap();
+  let signature = Signature::new("Jane Doe", "jane@example.com", Time::new(0, 0));
+  let tag = odb
+    .write_tag(&Tag::new(
+      b,
+      ObjectKind::Commit,
+      "v1.0",
+      signature,
+      "v1.0\n",
+    ))
+    .unwrap();
+  refs.write("refs/tags/v1.0", &tag).unwrap();
+  TestRepo {
+    _dir: dir,
+    repo,
+    commits,
+    tree,
+    blob,
+    tag,
+  }
+}
+
+#[test]
+fn parse() {
+  let test = test_repo();
+  let [a, b, c, d, e] = [0, 1, 2, 3, 4].map(|i| test.commits[i]);
+  let repo = &test.repo;
+  for (spec, expected) in [
+    ("HEAD", e),
+    ("@", e),
+    ("master", e),
+    ("refs/heads/master", e),
+    ("origin/master", c),
+    ("HEAD~1", c),
+    ("master~2", b),
+    ("HEAD^", c),
+    ("HEAD^^2", d),
+    ("HEAD~1^2~1", a),
+    ("HEAD^0", e),
+    ("v1.0", test.tag),
+    ("v1.0^{}", b),
+    ("v1.0^{commit}", b),
+    ("v1.0^0", b),
+    ("v1.0~1", a),
+    ("v1.0^{tree}", test.tree),
+    ("HEAD^{tree}", test.tree),
+    ("HEAD:dir/a.txt", test.blob),
+    ("HEAD:", test.tree),
+    (":/Merge", c),
+    (":/D", d),
+    ("side^{/A}", a),
+    (":/!-E", c),
+  ] {
+    assert_eq!(expected, repo.rev_parse(spec).unwrap(), "{}", spec);
+  }
+  assert_eq!(e, repo.rev_parse(&e.as_hex()).unwrap());
+  let short = &e.as_hex()[..7];
+  assert_eq!(e, repo.rev_parse(short).unwrap());
+  assert_eq!(c, repo.rev_parse(&format!("{}~1", short)).unwrap());
+}
+
+#[test]
+fn upstream() {
+  let test = test_repo();
+  let repo = &test.repo;
+  let mut config = std::fs::OpenOptions::new()
+    .append(true)
+    .open(repo.git_dir().join("config"))
+    .unwrap();
+  std::io::Write::write_all(
+    &mut config,
+    b"[remote \"origin\"]\n\tfetch = +refs/heads/*:refs/remotes/origin/*\n\
+      [branch \"master\"]\n\tremote = origin\n\tmerge = refs/heads/master\n\
+      [branch \"side\"]\n\tremote = .\n\tmerge = refs/heads/master\n",
+  )
+  .unwrap();
+  let repo = Repository::open(repo.work_dir().unwrap()).unwrap();
+  assert_eq!(test.commits[2], repo.rev_parse("@{u}").unwrap());
+  assert_eq!(
+    test.commits[2],
+    repo.rev_parse("master@{upstream}").unwrap()
+  );
+  assert_eq!(test.commits[1], repo.rev_parse("@{u}~1").unwrap());
+  assert_eq!(test.commits[4], repo.rev_parse("side@{u}").unwrap());
+  assert!(matches!(
+    repo.rev_parse("origin/master@{u}"),
+    Err(RevParseError::NoUpstream(_))
+  ));
+}
+
+#[test]
+fn errors() {
+  let test = test_repo();
+  let repo = &test.repo;
+  assert!(matches!(
+    repo.rev_parse("missing"),
+    Err(RevParseError::NotFound(_))
+  ));
+  assert!(matches!(
+    repo.rev_parse("HEAD~10"),
+    Err(RevParseError::NoParent { .. })
+  ));
+  assert!(matches!(
+    repo.rev_parse("HEAD^3"),
+    Err(RevParseError::NoParent { n: 3, .. })
+  ));
+  assert!(matches!(
+    repo.rev_parse("HEAD^{blob}"),
+    Err(RevParseError::WrongKind {
+      expected: ObjectKind::Blob,
+      found: ObjectKind::Commit,
+      ..
+    })
+  ));
+  assert!(matches!(
+    repo.rev_parse("HEAD:missing"),
+    Err(RevParseError::PathNotFound(_))
+  ));
+  assert!(matches!(
+    repo.rev_parse(":/no such message"),
+    Err(RevParseError::NoMatch(_))
+  ));
+  assert!(matches!(
+    repo.rev_parse("HEAD@{1}"),
+    Err(RevParseError::Unsupported(_))
+  ));
+  assert!(matches!(
+    repo.rev_parse("HEAD^{bogus}"),
+    Err(RevParseError::Invalid(_))
+  ));
+  assert!(matches!(
+    repo.rev_parse("HEAD~x"),
+    Err(RevParseError::Invalid(_))
+  ));
+}
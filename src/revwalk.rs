@@ -0,0 +1,524 @@
+use crate::{
+  Commit, CommitError, CommitGraph, ObjectKind, Odb, OdbError, Repository, Tag, TagError, Trace2,
+  OID,
+};
+use std::{
+  cmp::Reverse,
+  collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+};
+use thiserror::Error;
+
+/// Set once a commit has been queued so it's only walked once
+const SEEN: u8 = 1;
+/// Set for commits reachable from a hidden commit
+const UNINTERESTING: u8 = 2;
+/// How many more commits are looked at once only hidden commits are left to
+/// walk. Commits with a committer time older than their parents would
+/// otherwise make a commit look interesting when it isn't. This is the same
+/// amount git uses.
+const SLOP: usize = 5;
+
+/// The order a [`RevWalk`] gives commits in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sort {
+  /// Newest committer time first, like `git log`. A parent can come before
+  /// one of its children if their times are out of order.
+  #[default]
+  Date,
+  /// Children always come before their parents and each line of history is
+  /// shown as a whole before moving on to the next, like `git log
+  /// --topo-order`
+  Topological,
+}
+
+/// Walks the history of a repository starting from one or more commits and
+/// going through their parents, giving the [`OID`] of every commit reached
+/// once. Commits reachable from a hidden commit are left out, so pushing
+/// `main` and hiding `v1.0` gives the same commits as `git log v1.0..main`.
+///
+/// Tags given to [`RevWalk::push`] or [`RevWalk::hide`] are peeled to the
+/// commit they point at. Every commit has to be pushed or hidden before
+/// the walk is iterated.
+///
+/// Once the [`CancellationToken`][crate::CancellationToken] of the [`Odb`]
+/// is cancelled the next commit is [`OdbError::Cancelled`] instead.
+#[derive(Debug)]
+pub struct RevWalk<'a> {
+  odb: &'a Odb,
+  graph: Option<CommitGraph>,
+  sort: Sort,
+  reverse: bool,
+  commits: HashMap<OID, Node>,
+  queue: BinaryHeap<(i64, Reverse<usize>, OID)>,
+  queued: usize,
+  hidden: bool,
+  /// Commits whose parents are left out, like at the edge of a shallow
+  /// clone
+  shallow: HashSet<OID>,
+  /// The commits left to give when the whole walk has to be done up front
+  limited: Option<VecDeque<OID>>,
+}
+
+#[derive(Debug)]
+struct Node {
+  parents: Vec<OID>,
+  time: i64,
+  flags: u8,
+}
+
+impl<'a> RevWalk<'a> {
+  /// Create a [`RevWalk`] over the commits in `odb`. The parents and times
+  /// of commits are read from the [`CommitGraph`] when there is one, and
+  /// from the commits themselves otherwise.
+  pub fn new(odb: &'a Odb) -> Self {
+    Self {
+      odb,
+      // A commit-graph that can't be read is only slower to do without, and
+      // one can't know about replaced commits
+      graph: match odb.replacements().is_empty() {
+        true => CommitGraph::open(odb.path()).ok().flatten(),
+        false => None,
+      },
+      sort: Sort::default(),
+      reverse: false,
+      commits: HashMap::new(),
+      queue: BinaryHeap::new(),
+      queued: 0,
+      hidden: false,
+      shallow: HashSet::new(),
+      limited: None,
+    }
+  }
+
+  /// Set the order commits are given in
+  pub fn sort(&mut self, sort: Sort) -> &mut Self {
+    self.sort = sort;
+    self
+  }
+
+  /// Give commits oldest first instead, after sorting them
+  pub fn reverse(&mut self, reverse: bool) -> &mut Self {
+    self.reverse = reverse;
+    self
+  }
+
+  /// Walk as if `commits` had no parents, which is where the history of a
+  /// shallow clone ends since their parents aren't there
+  pub fn shallow(&mut self, commits: impl IntoIterator<Item = OID>) -> &mut Self {
+    self.shallow.extend(commits);
+    for (oid, node) in &mut self.commits {
+      if self.shallow.contains(oid) {
+        node.parents.clear();
+      }
+    }
+    self
+  }
+
+  /// The parents of a commit the walk has given, as far as the walk goes
+  pub(crate) fn parents(&self, oid: &OID) -> &[OID] {
+    self.commits.get(oid).map_or(&[], |node| &node.parents)
+  }
+
+  /// Start walking from `oid`
+  pub fn push(&mut self, oid: &OID) -> Result<&mut Self, RevWalkError> {
+    let oid = self.peel(*oid)?;
+    self.enqueue(oid);
+    Ok(self)
+  }
+
+  /// Leave out `oid` and every commit reachable from it
+  pub fn hide(&mut self, oid: &OID) -> Result<&mut Self, RevWalkError> {
+    let oid = self.peel(*oid)?;
+    self.hidden = true;
+    self.mark_uninteresting(oid);
+    self.enqueue(oid);
+    Ok(self)
+  }
+
+  /// Follow tags until a commit is reached and load it
+  fn peel(&mut self, mut oid: OID) -> Result<OID, RevWalkError> {
+    loop {
+      if self.commits.contains_key(&oid) || self.load_from_graph(oid) {
+        return Ok(oid);
+      }
+      let object = self.odb.read(&oid)?;
+      match object.kind {
+        ObjectKind::Tag => oid = *Tag::parse(object.data)?.object(),
+        ObjectKind::Commit => {
+          self.insert(oid, &Commit::parse(object.data)?);
+          return Ok(oid);
+        }
+        found => {
+          return Err(RevWalkError::NotACommit { oid, found });
+        }
+      }
+    }
+  }
+
+  fn load(&mut self, oid: OID) -> Result<(), RevWalkError> {
+    if !self.commits.contains_key(&oid) && !self.load_from_graph(oid) {
+      let commit = self.odb.read_commit(&oid)?;
+      self.insert(oid, &commit);
+    }
+    Ok(())
+  }
+
+  fn load_from_graph(&mut self, oid: OID) -> bool {
+    let commit = match self.graph.as_ref().map(|graph| graph.get(&oid)) {
+      Some(Ok(Some(commit))) => commit,
+      _ => return false,
+    };
+    let parents = match self.shallow.contains(&oid) {
+      true => Vec::new(),
+      false => commit.parents,
+    };
+    self.commits.insert(
+      oid,
+      Node {
+        parents,
+        time: commit.commit_time,
+        flags: 0,
+      },
+    );
+    true
+  }
+
+  fn insert(&mut self, oid: OID, commit: &Commit) {
+    let parents = match self.shallow.contains(&oid) {
+      true => Vec::new(),
+      false => commit.parents().to_vec(),
+    };
+    self.commits.insert(
+      oid,
+      Node {
+        parents,
+        time: commit.committer().time.seconds,
+        flags: 0,
+      },
+    );
+  }
+
+  /// Queue a loaded commit unless it's been queued before
+  fn enqueue(&mut self, oid: OID) {
+    let node = self.commits.get_mut(&oid).unwrap();
+    if node.flags & SEEN == 0 {
+      node.flags |= SEEN;
+      // Commits with the same time come out in the order they went in
+      self.queue.push((node.time, Reverse(self.queued), oid));
+      self.queued += 1;
+    }
+  }
+
+  /// Take the newest commit from the queue and queue its parents
+  fn pop(&mut self) -> Option<Result<OID, RevWalkError>> {
+    if self.odb.progress().is_cancelled() {
+      return Some(Err(OdbError::Cancelled.into()));
+    }
+    let (_, _, oid) = self.queue.pop()?;
+    let node = &self.commits[&oid];
+    let uninteresting = node.flags & UNINTERESTING != 0;
+    for parent in node.parents.clone() {
+      if let Err(e) = self.load(parent) {
+        return Some(Err(e));
+      }
+      if uninteresting {
+        self.mark_uninteresting(parent);
+      }
+      self.enqueue(parent);
+    }
+    Some(Ok(oid))
+  }
+
+  /// Mark a loaded commit as hidden along with every ancestor of it that
+  /// has been loaded so far. Ancestors that haven't been loaded yet are
+  /// marked when their child is popped.
+  fn mark_uninteresting(&mut self, oid: OID) {
+    let mut stack = vec![oid];
+    while let Some(oid) = stack.pop() {
+      if let Some(node) = self.commits.get_mut(&oid) {
+        if node.flags & UNINTERESTING == 0 {
+          node.flags |= UNINTERESTING;
+          stack.extend(node.parents.iter().copied());
+        }
+      }
+    }
+  }
+
+  fn is_uninteresting(&self, oid: &OID) -> bool {
+    self.commits[oid].flags & UNINTERESTING != 0
+  }
+
+  /// Walk everything up front, which is needed to hide commits, to sort
+  /// topologically, or to reverse the order
+  fn limit(&mut self) -> Result<VecDeque<OID>, RevWalkError> {
+    let _region = Trace2::region("revwalk", "limit");
+    let mut list = Vec::new();
+    let mut slop = SLOP;
+    while let Some(oid) = self.pop() {
+      let oid = oid?;
+      if !self.is_uninteresting(&oid) {
+        list.push(oid);
+      }
+      if self.hidden
+        && self
+          .queue
+          .iter()
+          .all(|(_, _, oid)| self.is_uninteresting(oid))
+      {
+        slop -= 1;
+        if slop == 0 {
+          break;
+        }
+      } else {
+        slop = SLOP;
+      }
+    }
+    // A commit can turn out to be hidden after it was walked
+    list.retain(|oid| !self.is_uninteresting(oid));
+
+    let mut list: VecDeque<OID> = match self.sort {
+      Sort::Date => list.into(),
+      Sort::Topological => self.topological(list),
+    };
+    if self.reverse {
+      list.make_contiguous().reverse();
+    }
+    Ok(list)
+  }
+
+  /// Order the commits so that no parent comes before any of its children
+  fn topological(&self, list: Vec<OID>) -> VecDeque<OID> {
+    let mut children: HashMap<OID, usize> = list.iter().map(|oid| (*oid, 0)).collect();
+    for oid in &list {
+      for parent in &self.commits[oid].parents {
+        if let Some(count) = children.get_mut(parent) {
+          *count += 1;
+        }
+      }
+    }
+    // The newest tips go on the top of the stack and each first parent is
+    // walked before the other parents so a line of history stays together
+    let mut stack: Vec<OID> = list
+      .iter()
+      .rev()
+      .filter(|oid| children[oid] == 0)
+      .copied()
+      .collect();
+    let mut sorted = VecDeque::with_capacity(list.len());
+    while let Some(oid) = stack.pop() {
+      sorted.push_back(oid);
+      for parent in self.commits[&oid].parents.iter().rev() {
+        if let Some(count) = children.get_mut(parent) {
+          *count -= 1;
+          if *count == 0 {
+            stack.push(*parent);
+          }
+        }
+      }
+    }
+    sorted
+  }
+}
+
+impl Iterator for RevWalk<'_> {
+  type Item = Result<OID, RevWalkError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.limited.is_none() && (self.hidden || self.reverse || self.sort != Sort::Date) {
+      match self.limit() {
+        Ok(list) => self.limited = Some(list),
+        Err(e) => {
+          self.limited = Some(VecDeque::new());
+          return Some(Err(e));
+        }
+      }
+    }
+    match &mut self.limited {
+      Some(list) => list.pop_front().map(Ok),
+      None => self.pop(),
+    }
+  }
+}
+
+impl Repository {
+  /// Create a [`RevWalk`] over the history of the repository, which stops
+  /// at the shallow commits of a shallow clone. A `.git/shallow` that
+  /// can't be read is left out, and the walk fails on the first parent
+  /// that isn't there instead.
+  pub fn rev_walk(&self) -> RevWalk<'_> {
+    let mut walk = RevWalk::new(self.odb());
+    walk.shallow(self.shallow_commits().unwrap_or_default());
+    walk
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`RevWalk`] type
+pub enum RevWalkError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Commit(#[from] CommitError),
+  #[error("{0}")]
+  Tag(#[from] TagError),
+  #[error("object {oid} is a {found} not a commit")]
+  NotACommit { oid: OID, found: ObjectKind },
+  #[error("{0:?} is not a supported object filter")]
+  InvalidFilter(String),
+}
+
+/// A repository with the history below where `M` merges `C` and `F`, every
+/// commit is one second newer than the one before it in the order they were
+/// made (`A`, `B`, `D`, `C`, `E`, `F`, `M`), and `v1.0` is an annotated tag
+/// of `B`.
+///
+/// ```text
+/// A - B - C ----- M
+///      \         /
+///       D - E - F
+/// ```
+#[cfg(test)]
+fn test_repo() -> (tempdir::TempDir, Repository, HashMap<char, OID>, OID) {
+  use crate::{Signature, Time, Tree};
+  let dir = tempdir::TempDir::new("revwalk_test").unwrap();
+  let repo = Repository::init(dir.path()).unwrap();
+  let odb = repo.odb();
+  let tree = odb.write_tree(&Tree::new(Vec::new())).unwrap();
+  let mut commits = HashMap::new();
+  for (i, (name, parents)) in [
+    ('A', ""),
+    ('B', "A"),
+    ('D', "B"),
+    ('C', "B"),
+    ('E', "D"),
+    ('F', "E"),
+    ('M', "CF"),
+  ]
+  .iter()
+  .enumerate()
+  {
+    let time = Time::new(1_600_000_000 + i as i64, 0);
+    let signature = Signature::new("Jane Doe", "jane@example.com", time);
+    let parents = parents.chars().map(|p| commits[&p]).collect();
+    let commit = Commit::new(
+      tree,
+      parents,
+      signature.clone(),
+      signature,
+      name.to_string(),
+    );
+    commits.insert(*name, odb.write_commit(&commit).unwrap());
+  }
+  let signature = Signature::new("Jane Doe", "jane@example.com", Time::new(0, 0));
+  let tag = odb
+    .write_tag(&Tag::new(
+      commits[&'B'],
+      ObjectKind::Commit,
+      "v1.0",
+      signature,
+      "v1.0\n",
+    ))
+    .unwrap();
+  (dir, repo, commits, tag)
+}
+
+#[cfg(test)]
+fn names(commits: &HashMap<char, OID>, walk: RevWalk<'_>) -> String {
+  walk
+    .map(|oid| {
+      let oid = oid.unwrap();
+      commits.iter().find(|(_, o)| **o == oid).unwrap().0
+    })
+    .collect()
+}
+
+#[test]
+fn walk_orders() {
+  let (_dir, repo, commits, _) = test_repo();
+  let mut walk = repo.rev_walk();
+  walk.push(&commits[&'M']).unwrap();
+  assert_eq!("MFECDBA", names(&commits, walk));
+
+  let mut walk = repo.rev_walk();
+  walk.push(&commits[&'M']).unwrap().sort(Sort::Topological);
+  assert_eq!("MCFEDBA", names(&commits, walk));
+
+  let mut walk = repo.rev_walk();
+  walk
+    .push(&commits[&'M'])
+    .unwrap()
+    .sort(Sort::Topological)
+    .reverse(true);
+  assert_eq!("ABDEFCM", names(&commits, walk));
+
+  // Pushing a commit twice or one reachable from another doesn't give it
+  // twice
+  let mut walk = repo.rev_walk();
+  walk.push(&commits[&'C']).unwrap();
+  walk.push(&commits[&'F']).unwrap();
+  walk.push(&commits[&'C']).unwrap();
+  assert_eq!("FECDBA", names(&commits, walk));
+}
+
+#[test]
+fn walk_with_commit_graph() {
+  let (_dir, repo, commits, tag) = test_repo();
+  CommitGraph::write(repo.odb()).unwrap();
+  // The walk doesn't need the commits at all now
+  for oid in commits.values() {
+    let hex = oid.as_hex();
+    std::fs::remove_file(repo.odb().path().join(&hex[..2]).join(&hex[2..])).unwrap();
+  }
+  let mut walk = repo.rev_walk();
+  walk.push(&commits[&'M']).unwrap();
+  assert_eq!("MFECDBA", names(&commits, walk));
+
+  // The tag isn't in the graph so it's still read to peel it
+  let mut walk = repo.rev_walk();
+  walk.push(&commits[&'M']).unwrap().hide(&tag).unwrap();
+  assert_eq!("MFECD", names(&commits, walk));
+}
+
+#[test]
+fn walk_hidden() {
+  let (_dir, repo, commits, tag) = test_repo();
+  let mut walk = repo.rev_walk();
+  walk.push(&commits[&'M']).unwrap().hide(&tag).unwrap();
+  assert_eq!("MFECD", names(&commits, walk));
+
+  let mut walk = repo.rev_walk();
+  walk.push(&commits[&'M']).unwrap();
+  walk.hide(&commits[&'C']).unwrap();
+  assert_eq!("MFED", names(&commits, walk));
+
+  let mut walk = repo.rev_walk();
+  walk.push(&commits[&'C']).unwrap();
+  walk.hide(&commits[&'M']).unwrap();
+  assert_eq!("", names(&commits, walk));
+
+  let mut walk = repo.rev_walk();
+  let tree = *repo.odb().read_commit(&commits[&'A']).unwrap().tree();
+  assert!(matches!(
+    walk.push(&tree),
+    Err(RevWalkError::NotACommit { .. })
+  ));
+}
+
+#[test]
+fn walk_shallow() {
+  let (_dir, repo, commits, _) = test_repo();
+  repo
+    .update_shallow(&[commits[&'B'], commits[&'F']], &[])
+    .unwrap();
+  // What's behind the shallow commits isn't there in a shallow clone
+  let hex = commits[&'A'].as_hex();
+  std::fs::remove_file(repo.odb().path().join(&hex[..2]).join(&hex[2..])).unwrap();
+  let mut walk = repo.rev_walk();
+  walk.push(&commits[&'M']).unwrap();
+  assert_eq!("MFCB", names(&commits, walk));
+
+  let mut walk = RevWalk::new(repo.odb());
+  walk.push(&commits[&'F']).unwrap();
+  walk.shallow([commits[&'F']]);
+  assert_eq!("F", names(&commits, walk));
+}
@@ -0,0 +1,119 @@
+use crate::{OIDError, Repository, OID};
+use std::{
+  fs,
+  io::{self, Write},
+  path::PathBuf,
+};
+use thiserror::Error;
+
+impl Repository {
+  fn shallow_path(&self) -> PathBuf {
+    self.common_dir().join("shallow")
+  }
+
+  /// Whether the repository is a shallow clone, one that's missing the
+  /// history behind some of its commits
+  pub fn is_shallow(&self) -> bool {
+    self.shallow_path().is_file()
+  }
+
+  /// The commits whose parents were left out of a shallow clone, as listed
+  /// in `.git/shallow`, sorted. A complete repository has none.
+  pub fn shallow_commits(&self) -> Result<Vec<OID>, ShallowError> {
+    let contents = match fs::read_to_string(self.shallow_path()) {
+      Ok(contents) => contents,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(e) => return Err(e.into()),
+    };
+    let mut commits = contents
+      .lines()
+      .filter(|line| !line.is_empty())
+      .map(OID::from_hex)
+      .collect::<Result<Vec<_>, _>>()?;
+    commits.sort();
+    commits.dedup();
+    Ok(commits)
+  }
+
+  /// Add `shallow` to the shallow commits and remove `unshallow`, as a
+  /// fetch reports them. `.git/shallow` is written under
+  /// `.git/shallow.lock` and removed once no commit is shallow anymore,
+  /// which makes the repository complete again.
+  pub fn update_shallow(&self, shallow: &[OID], unshallow: &[OID]) -> Result<(), ShallowError> {
+    if shallow.is_empty() && unshallow.is_empty() {
+      return Ok(());
+    }
+    let mut commits = self.shallow_commits()?;
+    commits.extend_from_slice(shallow);
+    commits.retain(|commit| !unshallow.contains(commit));
+    commits.sort();
+    commits.dedup();
+
+    let path = self.shallow_path();
+    let lock_path = self.common_dir().join("shallow.lock");
+    let mut lock = match fs::OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .open(&lock_path)
+    {
+      Ok(lock) => lock,
+      Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+        return Err(ShallowError::Locked(lock_path))
+      }
+      Err(e) => return Err(e.into()),
+    };
+    let contents: String = commits.iter().map(|oid| format!("{}\n", oid)).collect();
+    let result = match commits.is_empty() {
+      true => fs::remove_file(&path).or_else(|e| match e.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+      }),
+      false => lock
+        .write_all(contents.as_bytes())
+        .and_then(|_| fs::rename(&lock_path, &path)),
+    };
+    // The lock is only left behind by a rename that never happened
+    let _ = fs::remove_file(&lock_path);
+    Ok(result?)
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to the shallow commits of a [`Repository`]
+pub enum ShallowError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Oid(#[from] OIDError),
+  #[error("{0:?} exists, another process is changing the shallow commits")]
+  Locked(PathBuf),
+}
+
+#[test]
+fn shallow_commits() {
+  let tmp_dir = tempdir::TempDir::new("shallow_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  assert!(!repo.is_shallow());
+  assert!(repo.shallow_commits().unwrap().is_empty());
+  let (a, b) = (OID::hash("a"), OID::hash("b"));
+  repo.update_shallow(&[b, a, a], &[]).unwrap();
+  assert!(repo.is_shallow());
+  let mut expected = vec![a, b];
+  expected.sort();
+  assert_eq!(expected, repo.shallow_commits().unwrap());
+  assert_eq!(
+    format!("{}\n{}\n", expected[0], expected[1]),
+    fs::read_to_string(tmp_dir.path().join(".git/shallow")).unwrap()
+  );
+
+  fs::write(tmp_dir.path().join(".git/shallow.lock"), "").unwrap();
+  assert!(matches!(
+    repo.update_shallow(&[], &[a]),
+    Err(ShallowError::Locked(_))
+  ));
+  fs::remove_file(tmp_dir.path().join(".git/shallow.lock")).unwrap();
+  repo.update_shallow(&[], &[a]).unwrap();
+  assert_eq!(vec![b], repo.shallow_commits().unwrap());
+  repo.update_shallow(&[], &[b]).unwrap();
+  assert!(!repo.is_shallow());
+}
@@ -0,0 +1,206 @@
+use crate::Identity;
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  fmt,
+  time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+/// A [`Signature`] records who did something and when in a git object. It's
+/// used for the author and committer of a Commit and the tagger of a Tag and
+/// is stored in the form below where the offset is the timezone of the person
+/// as `+HHMM` or `-HHMM`:
+///
+/// ```text
+/// {name} <{email}> {seconds since the epoch} {offset}
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Signature {
+  /// The name of the person
+  pub name: BString,
+  /// The email of the person
+  pub email: BString,
+  /// When the action happened
+  pub time: Time,
+}
+
+/// A point in time with the timezone offset it was recorded in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Time {
+  /// Seconds since the Unix epoch
+  pub seconds: i64,
+  /// The offset from UTC in minutes
+  pub offset: i32,
+}
+
+impl Time {
+  /// Create a new [`Time`] given the seconds since the epoch and the offset
+  /// from UTC in minutes
+  pub fn new(seconds: i64, offset: i32) -> Self {
+    Self { seconds, offset }
+  }
+
+  /// Parse a time in git's internal `{seconds} {offset}` form, with an
+  /// optional leading `@`, the way `GIT_AUTHOR_DATE` accepts it
+  pub fn parse(time: impl AsRef<[u8]>) -> Result<Self, SignatureError> {
+    let time = time.as_ref();
+    let invalid = || SignatureError::InvalidTime(time.into());
+    let mut fields = time.fields();
+    let seconds = fields
+      .next()
+      .map(|seconds| seconds.strip_prefix(b"@").unwrap_or(seconds))
+      .and_then(|seconds| seconds.to_str().ok()?.parse().ok())
+      .ok_or_else(invalid)?;
+    let offset = fields
+      .next()
+      .ok_or_else(invalid)
+      .and_then(|offset| parse_offset(offset).map_err(|_| invalid()))?;
+    if fields.next().is_some() {
+      return Err(invalid());
+    }
+    Ok(Self::new(seconds, offset))
+  }
+
+  /// The current time in UTC
+  pub fn now() -> Self {
+    let seconds = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map_or(0, |d| d.as_secs() as i64);
+    Self::new(seconds, 0)
+  }
+}
+
+impl fmt::Display for Time {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let sign = if self.offset < 0 { '-' } else { '+' };
+    let offset = self.offset.abs();
+    write!(
+      f,
+      "{} {}{:02}{:02}",
+      self.seconds,
+      sign,
+      offset / 60,
+      offset % 60
+    )
+  }
+}
+
+impl Signature {
+  /// Create a new [`Signature`]
+  pub fn new(name: impl Into<BString>, email: impl Into<BString>, time: Time) -> Self {
+    Self {
+      name: name.into(),
+      email: email.into(),
+      time,
+    }
+  }
+
+  /// Parse a [`Signature`] as found in the header of a Commit or a Tag
+  pub fn parse(bytes: impl AsRef<[u8]>) -> Result<Self, SignatureError> {
+    let bytes = bytes.as_ref();
+    let invalid = || SignatureError::Invalid(bytes.into());
+    let start = bytes.find_byte(b'<').ok_or_else(invalid)?;
+    let end = bytes.rfind_byte(b'>').ok_or_else(invalid)?;
+    if end < start {
+      return Err(invalid());
+    }
+    let name = bytes[..start].trim_end();
+    let email = &bytes[start + 1..end];
+    let mut time = bytes[end + 1..].fields();
+    let seconds = time
+      .next()
+      .and_then(|s| s.to_str().ok()?.parse().ok())
+      .ok_or_else(invalid)?;
+    let offset = time
+      .next()
+      .map_or(Ok(0), parse_offset)
+      .map_err(|_| invalid())?;
+    Ok(Self::new(name, email, Time::new(seconds, offset)))
+  }
+
+  /// Turn the [`Signature`] into the form stored in an object header
+  pub fn as_bytes(&self) -> Vec<u8> {
+    [
+      self.name.as_bytes(),
+      b" <",
+      self.email.as_bytes(),
+      b"> ",
+      self.time.to_string().as_bytes(),
+    ]
+    .concat()
+  }
+
+  /// The name of the person as a [`BStr`]
+  pub fn name(&self) -> &BStr {
+    self.name.as_bstr()
+  }
+
+  /// The email of the person as a [`BStr`]
+  pub fn email(&self) -> &BStr {
+    self.email.as_bstr()
+  }
+
+  /// The name and email of the person without the time. This is useful for
+  /// looking the person up in a [`Mailmap`][crate::Mailmap].
+  pub fn identity(&self) -> Identity {
+    Identity::new(self.name.clone(), self.email.clone())
+  }
+}
+
+impl fmt::Display for Signature {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} <{}> {}", self.name, self.email, self.time)
+  }
+}
+
+fn parse_offset(offset: &[u8]) -> Result<i32, ()> {
+  let (sign, digits) = match offset.split_first() {
+    Some((b'+', digits)) => (1, digits),
+    Some((b'-', digits)) => (-1, digits),
+    _ => return Err(()),
+  };
+  if digits.len() != 4 || !digits.iter().all(u8::is_ascii_digit) {
+    return Err(());
+  }
+  let value = |d: &[u8]| d.iter().fold(0, |acc, b| acc * 10 + i32::from(b - b'0'));
+  Ok(sign * (value(&digits[..2]) * 60 + value(&digits[2..])))
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`Signature`] type
+pub enum SignatureError {
+  #[error("invalid signature {0:?}")]
+  Invalid(BString),
+  #[error("invalid time {0:?}")]
+  InvalidTime(BString),
+}
+
+#[test]
+fn parse() {
+  let sig = Signature::parse("Jane Doe <jane@example.com> 1600000000 -0130").unwrap();
+  assert_eq!("Jane Doe", sig.name());
+  assert_eq!("jane@example.com", sig.email());
+  assert_eq!(Time::new(1600000000, -90), sig.time);
+  assert!(Signature::parse("Jane Doe jane@example.com 1600000000").is_err());
+
+  assert_eq!(
+    Time::new(1600000000, 90),
+    Time::parse("1600000000 +0130").unwrap()
+  );
+  assert_eq!(
+    Time::new(1600000000, 0),
+    Time::parse("@1600000000 +0000").unwrap()
+  );
+  assert!(Time::parse("1600000000").is_err());
+  assert!(Time::parse("yesterday +0000").is_err());
+}
+
+#[test]
+fn as_bytes() {
+  let sig = Signature::new("Jane Doe", "jane@example.com", Time::new(1600000000, 120));
+  assert_eq!(
+    b"Jane Doe <jane@example.com> 1600000000 +0200".to_vec(),
+    sig.as_bytes()
+  );
+  assert_eq!(sig, Signature::parse(sig.as_bytes()).unwrap());
+}
@@ -0,0 +1,404 @@
+//! Signing commits and tags, and getting the signature back out to verify
+//! it. A commit keeps its signature in a `gpgsig` header and is signed
+//! without it, while a tag has its signature at the end of its message and
+//! is signed with everything before it.
+//!
+//! Anything that implements [`Signer`] can sign, including any
+//! `Fn(&[u8]) -> Result<BString, SigningError>`. [`CommandSigner`] runs
+//! `gpg`, `gpgsm`, or `ssh-keygen` going by `gpg.format` like git does.
+
+use crate::{Commit, Config, ConfigError, Signature, Tag, TagError};
+use bstr::{BString, ByteSlice};
+use std::{
+  env, fs,
+  io::{self, Write},
+  process::{Command, Stdio},
+  str::FromStr,
+  sync::atomic::{AtomicUsize, Ordering},
+};
+use thiserror::Error;
+
+/// The lines signatures of each [`SigningFormat`] start with
+const SIGNATURE_MARKERS: &[&str] = &[
+  "-----BEGIN PGP SIGNATURE-----",
+  "-----BEGIN PGP MESSAGE-----",
+  "-----BEGIN SIGNED MESSAGE-----",
+  "-----BEGIN SSH SIGNATURE-----",
+];
+
+/// Makes a detached signature for the bytes of a commit or tag
+pub trait Signer {
+  /// Sign `payload`, returning the armored signature
+  fn sign(&self, payload: &[u8]) -> Result<BString, SigningError>;
+}
+
+impl<F> Signer for F
+where
+  F: Fn(&[u8]) -> Result<BString, SigningError>,
+{
+  fn sign(&self, payload: &[u8]) -> Result<BString, SigningError> {
+    self(payload)
+  }
+}
+
+/// The kind of signature made, as set by `gpg.format`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SigningFormat {
+  /// OpenPGP signatures made with `gpg`
+  #[default]
+  OpenPgp,
+  /// X.509 signatures made with `gpgsm`
+  X509,
+  /// SSH signatures made with `ssh-keygen -Y sign`
+  Ssh,
+}
+
+impl SigningFormat {
+  /// The name `gpg.format` has for the format
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Self::OpenPgp => "openpgp",
+      Self::X509 => "x509",
+      Self::Ssh => "ssh",
+    }
+  }
+
+  /// The program that signs in the format unless `gpg.{format}.program`
+  /// says otherwise
+  fn default_program(self) -> &'static str {
+    match self {
+      Self::OpenPgp => "gpg",
+      Self::X509 => "gpgsm",
+      Self::Ssh => "ssh-keygen",
+    }
+  }
+}
+
+impl FromStr for SigningFormat {
+  type Err = SigningError;
+
+  fn from_str(format: &str) -> Result<Self, Self::Err> {
+    match format {
+      "openpgp" => Ok(Self::OpenPgp),
+      "x509" => Ok(Self::X509),
+      "ssh" => Ok(Self::Ssh),
+      _ => Err(SigningError::UnknownFormat(format.into())),
+    }
+  }
+}
+
+/// A [`Signer`] that runs a program to sign, the same ones with the same
+/// arguments as git: `gpg --status-fd=2 -bsau {key}` for OpenPGP and X.509,
+/// and `ssh-keygen -Y sign -n git -f {key}` for SSH.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandSigner {
+  format: SigningFormat,
+  program: String,
+  key: String,
+}
+
+/// Tells apart the key files written for SSH keys given in the config
+static KEY_FILES: AtomicUsize = AtomicUsize::new(0);
+
+impl CommandSigner {
+  /// Sign in `format` with `key`, which is the key id or user id for
+  /// `gpg` and `gpgsm`, and the path of the key for SSH or the public key
+  /// itself after `key::` if it's in the SSH agent
+  pub fn new(format: SigningFormat, key: impl Into<String>) -> Self {
+    Self {
+      format,
+      program: format.default_program().into(),
+      key: key.into(),
+    }
+  }
+
+  /// Run `program` instead of the default one for the format
+  pub fn with_program(mut self, program: impl Into<String>) -> Self {
+    self.program = program.into();
+    self
+  }
+
+  /// The [`CommandSigner`] `config` sets up, going by `gpg.format`,
+  /// `gpg.{format}.program` or `gpg.program`, and `user.signingKey`. Like
+  /// git, `gpg` and `gpgsm` sign with the key of `signer` when there's no
+  /// signing key, while SSH needs one.
+  pub fn from_config(config: &Config, signer: &Signature) -> Result<Self, SigningError> {
+    let format = match config.get_str("gpg.format")? {
+      Some(format) => format.parse()?,
+      None => SigningFormat::default(),
+    };
+    let key = match (format, config.get_str("user.signingkey")?) {
+      (SigningFormat::Ssh, Some(key)) if !key.starts_with("key::") => config
+        .get_path("user.signingkey")?
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_default(),
+      (_, Some(key)) => key.into(),
+      (SigningFormat::Ssh, None) => return Err(SigningError::NoKey),
+      (_, None) => format!("{} <{}>", signer.name, signer.email),
+    };
+    let mut command = Self::new(format, key);
+    let program = config
+      .get_str(&format!("gpg.{}.program", format.as_str()))?
+      .or(match format {
+        SigningFormat::OpenPgp => config.get_str("gpg.program")?,
+        _ => None,
+      });
+    if let Some(program) = program {
+      command.program = program.into();
+    }
+    Ok(command)
+  }
+
+  fn run(&self, args: &[&str], payload: &[u8]) -> Result<BString, SigningError> {
+    let mut child = Command::new(&self.program)
+      .args(args)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()?;
+    child.stdin.take().unwrap().write_all(payload)?;
+    let output = child.wait_with_output()?;
+    // gpg says it made a signature on the status fd, which is its stderr
+    let created = match self.format {
+      SigningFormat::Ssh => true,
+      _ => output.stderr.find(b"[GNUPG:] SIG_CREATED ").is_some(),
+    };
+    match output.status.success() && created && !output.stdout.is_empty() {
+      true => Ok(output.stdout.into()),
+      false => Err(SigningError::Failed(
+        self.program.clone(),
+        output.stderr.to_str_lossy().trim().into(),
+      )),
+    }
+  }
+}
+
+impl Signer for CommandSigner {
+  fn sign(&self, payload: &[u8]) -> Result<BString, SigningError> {
+    match self.format {
+      SigningFormat::OpenPgp | SigningFormat::X509 => {
+        self.run(&["--status-fd=2", "-bsau", &self.key], payload)
+      }
+      SigningFormat::Ssh => match self.key.strip_prefix("key::") {
+        None => self.run(&["-Y", "sign", "-n", "git", "-f", &self.key], payload),
+        Some(key) => {
+          // A key in the agent is named by its public key, which has to be
+          // given to ssh-keygen in a file
+          let path = env::temp_dir().join(format!(
+            ".git_signing_key_tmp{}_{}",
+            std::process::id(),
+            KEY_FILES.fetch_add(1, Ordering::Relaxed)
+          ));
+          fs::write(&path, format!("{}\n", key))?;
+          let path_str = path.to_string_lossy();
+          let signed = self.run(&["-Y", "sign", "-n", "git", "-f", &path_str, "-U"], payload);
+          let _ = fs::remove_file(&path);
+          signed
+        }
+      },
+    }
+  }
+}
+
+/// A signature taken out of a commit or tag along with what it signs
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SignedPayload {
+  /// The bytes that were signed
+  pub payload: BString,
+  /// The armored signature, ending with a newline
+  pub signature: BString,
+}
+
+impl Commit {
+  /// Sign the commit with `signer` and put the signature in its `gpgsig`
+  /// header, like `git commit -S`. A signature it already had is replaced.
+  pub fn sign(self, signer: &dyn Signer) -> Result<Self, SigningError> {
+    let mut signed = self.without_signature();
+    let signature = signer.sign(&signed.content())?;
+    // The signature goes after every other header, right before the blank
+    // line in front of the message
+    let signature = signature.trim_end_with(|c| c == '\n');
+    signed
+      .extra_headers_mut()
+      .push(("gpgsig".into(), signature.into()));
+    Ok(signed)
+  }
+
+  /// The signature in the `gpgsig` header and the commit it signed, which
+  /// is the commit without that header, or `None` if it isn't signed
+  pub fn extract_signature(&self) -> Option<SignedPayload> {
+    let (_, signature) = self
+      .extra_headers()
+      .iter()
+      .find(|(key, _)| key == "gpgsig")?;
+    let mut signature = signature.clone();
+    signature.push(b'\n');
+    Some(SignedPayload {
+      payload: self.without_signature().content().into(),
+      signature,
+    })
+  }
+
+  fn without_signature(&self) -> Commit {
+    let mut unsigned = self.clone();
+    unsigned
+      .extra_headers_mut()
+      .retain(|(key, _)| key != "gpgsig");
+    unsigned
+  }
+}
+
+impl Tag {
+  /// Sign the tag with `signer` and add the signature to the end of its
+  /// message, like `git tag -s`. A signature it already had is replaced.
+  pub fn sign(self, signer: &dyn Signer) -> Result<Self, SigningError> {
+    let content: Vec<u8> = match self.extract_signature() {
+      Some(signed) => signed.payload.into(),
+      None => self.content(),
+    };
+    let signature = signer.sign(&content)?;
+    Ok(Tag::parse([&content[..], &signature].concat())?)
+  }
+
+  /// The signature at the end of the message and the tag it signed, which
+  /// is everything before it, or `None` if it isn't signed
+  pub fn extract_signature(&self) -> Option<SignedPayload> {
+    let content = self.content();
+    let message = content.find(b"\n\n")? + 2;
+    let mut start = None;
+    let mut offset = message;
+    for line in content[message..].lines_with_terminator() {
+      if SIGNATURE_MARKERS
+        .iter()
+        .any(|marker| line.starts_with(marker.as_bytes()))
+      {
+        start = Some(offset);
+      }
+      offset += line.len();
+    }
+    let start = start?;
+    Some(SignedPayload {
+      payload: content[..start].into(),
+      signature: content[start..].into(),
+    })
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to signing commits and tags
+pub enum SigningError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Tag(#[from] TagError),
+  #[error("unknown signing format {0:?}")]
+  UnknownFormat(String),
+  #[error("user.signingKey has to be set to sign with SSH")]
+  NoKey,
+  #[error("{0} failed to sign the data: {1}")]
+  Failed(String, String),
+}
+
+#[test]
+fn sign_commits_and_tags() {
+  use crate::{ObjectKind, Repository, Time, Tree};
+  let tmp_dir = tempdir::TempDir::new("signing_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let odb = repo.odb();
+  let tree = odb.write_tree(&Tree::default()).unwrap();
+  let content = |bytes: Vec<u8>| bytes[bytes.find_byte(0).unwrap() + 1..].to_vec();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = Commit::new(
+    tree,
+    vec![],
+    signature.clone(),
+    signature.clone(),
+    "signed\n",
+  );
+  let fake = |payload: &[u8]| -> Result<BString, SigningError> {
+    let sum: u32 = payload.iter().map(|&b| u32::from(b)).sum();
+    Ok(
+      format!(
+        "-----BEGIN PGP SIGNATURE-----\n\n{}\n-----END PGP SIGNATURE-----\n",
+        sum
+      )
+      .into(),
+    )
+  };
+
+  let signed = commit.clone().sign(&fake).unwrap();
+  assert!(signed
+    .as_bytes()
+    .find(b"\ngpgsig -----BEGIN PGP SIGNATURE-----\n \n ")
+    .is_some());
+  let extracted = signed.extract_signature().unwrap();
+  assert_eq!(content(commit.as_bytes()), extracted.payload);
+  assert_eq!(fake(&extracted.payload).unwrap(), extracted.signature);
+  assert_eq!(signed, signed.clone().sign(&fake).unwrap());
+  assert_eq!(None, commit.extract_signature());
+
+  let tag = Tag::new(tree, ObjectKind::Tree, "v1.0", signature.clone(), "v1.0\n");
+  let signed_tag = tag.clone().sign(&fake).unwrap();
+  let extracted = signed_tag.extract_signature().unwrap();
+  assert_eq!(content(tag.as_bytes()), extracted.payload);
+  assert_eq!(fake(&extracted.payload).unwrap(), extracted.signature);
+  assert_eq!(signed_tag, signed_tag.clone().sign(&fake).unwrap());
+  assert_eq!(None, tag.extract_signature());
+
+  let config = Config::from_bytes("[gpg]\n\tformat = ssh").unwrap();
+  assert!(matches!(
+    CommandSigner::from_config(&config, &signature),
+    Err(SigningError::NoKey)
+  ));
+  let config = Config::from_bytes("[gpg]\n\tprogram = my-gpg").unwrap();
+  assert_eq!(
+    CommandSigner::new(SigningFormat::OpenPgp, "A U Thor <author@example.com>")
+      .with_program("my-gpg"),
+    CommandSigner::from_config(&config, &signature).unwrap()
+  );
+
+  // A signature made with ssh-keygen is one git verifies
+  let key = tmp_dir.path().join("key");
+  let generated = Command::new("ssh-keygen")
+    .args([
+      "-q",
+      "-t",
+      "ed25519",
+      "-N",
+      "",
+      "-C",
+      "author@example.com",
+      "-f",
+    ])
+    .arg(&key)
+    .status()
+    .is_ok_and(|status| status.success());
+  if generated && crate::transport::http::have_git() {
+    let signer = CommandSigner::new(SigningFormat::Ssh, key.to_string_lossy());
+    let signed = odb.write_commit(&commit.sign(&signer).unwrap()).unwrap();
+    let signed_tag = odb.write_tag(&tag.sign(&signer).unwrap()).unwrap();
+    let public = fs::read_to_string(key.with_extension("pub")).unwrap();
+    let allowed = tmp_dir.path().join("allowed_signers");
+    fs::write(&allowed, format!("author@example.com {}", public)).unwrap();
+    for (verify, oid) in [("verify-commit", signed), ("verify-tag", signed_tag)] {
+      let output = Command::new("git")
+        .args(["-c", "gpg.format=ssh", "-c"])
+        .arg(format!("gpg.ssh.allowedSignersFile={}", allowed.display()))
+        .args([verify, &oid.as_hex()])
+        .current_dir(tmp_dir.path())
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .output()
+        .unwrap();
+      let stderr = output.stderr.to_str_lossy();
+      assert!(output.status.success(), "{}", stderr);
+      assert!(stderr.contains("Good \"git\" signature for author@example.com"));
+    }
+  }
+}
@@ -0,0 +1,171 @@
+//! Byte strings that keep short contents inline instead of on the heap.
+//! Most blobs in a repository are small source files and most commit and
+//! tag messages are a line or two, so storing those without an allocation
+//! takes a lot of pressure off of the allocator when many of them are held
+//! at once. Longer contents are reference counted so that cloning never
+//! copies them, they're only copied when a shared clone is changed.
+
+use bstr::{BStr, ByteSlice};
+use std::{fmt, hash, ops::Deref, sync::Arc};
+
+/// How many bytes are stored inline. This keeps a [`SmallBytes`] at 32
+/// bytes on 64 bit targets.
+pub(crate) const INLINE_LEN: usize = 30;
+
+/// A byte string that is stored inline if it's at most [`INLINE_LEN`] bytes
+/// long and in a shared allocation on the heap otherwise
+#[derive(Clone)]
+pub(crate) enum SmallBytes {
+  Inline { len: u8, bytes: [u8; INLINE_LEN] },
+  Heap(Arc<Vec<u8>>),
+}
+
+impl SmallBytes {
+  /// The contents as a [`BStr`]
+  pub(crate) fn as_bstr(&self) -> &BStr {
+    self.deref().as_bstr()
+  }
+
+  /// Mutable access to the contents. They're moved to the heap if they were
+  /// inline and copied first if the allocation is shared with a clone, so
+  /// that the change is never seen through any other [`SmallBytes`].
+  pub(crate) fn make_mut(&mut self) -> &mut Vec<u8> {
+    if let Self::Inline { .. } = self {
+      *self = Self::Heap(Arc::new(self.to_vec()));
+    }
+    match self {
+      Self::Heap(bytes) => Arc::make_mut(bytes),
+      Self::Inline { .. } => unreachable!(),
+    }
+  }
+
+  /// Whether the contents are stored inline
+  #[cfg(test)]
+  pub(crate) fn is_inline(&self) -> bool {
+    matches!(self, Self::Inline { .. })
+  }
+
+  /// Whether both share the same allocation
+  #[cfg(test)]
+  pub(crate) fn is_shared_with(&self, other: &Self) -> bool {
+    match (self, other) {
+      (Self::Heap(a), Self::Heap(b)) => Arc::ptr_eq(a, b),
+      _ => false,
+    }
+  }
+}
+
+impl Default for SmallBytes {
+  fn default() -> Self {
+    Self::Inline {
+      len: 0,
+      bytes: [0; INLINE_LEN],
+    }
+  }
+}
+
+impl Deref for SmallBytes {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match self {
+      Self::Inline { len, bytes } => &bytes[..*len as usize],
+      Self::Heap(bytes) => bytes,
+    }
+  }
+}
+
+impl From<&[u8]> for SmallBytes {
+  fn from(slice: &[u8]) -> Self {
+    if slice.len() <= INLINE_LEN {
+      let mut bytes = [0; INLINE_LEN];
+      bytes[..slice.len()].copy_from_slice(slice);
+      Self::Inline {
+        len: slice.len() as u8,
+        bytes,
+      }
+    } else {
+      Self::Heap(Arc::new(slice.to_vec()))
+    }
+  }
+}
+
+/// Short contents are copied inline and the [`Vec`] is freed, longer ones
+/// reuse its allocation
+impl From<Vec<u8>> for SmallBytes {
+  fn from(vec: Vec<u8>) -> Self {
+    if vec.len() <= INLINE_LEN {
+      vec.as_slice().into()
+    } else {
+      Self::Heap(Arc::new(vec))
+    }
+  }
+}
+
+impl From<String> for SmallBytes {
+  fn from(string: String) -> Self {
+    string.into_bytes().into()
+  }
+}
+
+impl fmt::Debug for SmallBytes {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Debug::fmt(self.as_bstr(), f)
+  }
+}
+
+impl PartialEq for SmallBytes {
+  fn eq(&self, other: &Self) -> bool {
+    self.deref() == other.deref()
+  }
+}
+
+impl Eq for SmallBytes {}
+
+impl hash::Hash for SmallBytes {
+  fn hash<H: hash::Hasher>(&self, state: &mut H) {
+    self.deref().hash(state)
+  }
+}
+
+#[test]
+fn inline_and_heap() {
+  let short = SmallBytes::from(&b"hello"[..]);
+  assert!(short.is_inline());
+  assert_eq!(b"hello", &*short);
+  let exact = SmallBytes::from(vec![b'a'; INLINE_LEN]);
+  assert!(exact.is_inline());
+  assert_eq!(INLINE_LEN, exact.len());
+  let long = SmallBytes::from(vec![b'a'; INLINE_LEN + 1]);
+  assert!(!long.is_inline());
+  assert_eq!(INLINE_LEN + 1, long.len());
+  assert!(SmallBytes::default().is_empty());
+  // Where the bytes are stored doesn't change equality
+  assert_eq!(SmallBytes::Heap(Arc::new(b"hello".to_vec())), short);
+  assert_eq!("\"hello\"", format!("{:?}", short));
+  if cfg!(target_pointer_width = "64") {
+    assert_eq!(32, std::mem::size_of::<SmallBytes>());
+  }
+}
+
+#[test]
+fn copy_on_write() {
+  let mut long = SmallBytes::from(vec![b'a'; 100]);
+  let clone = long.clone();
+  assert!(long.is_shared_with(&clone));
+  long.make_mut().push(b'b');
+  assert!(!long.is_shared_with(&clone));
+  assert_eq!(101, long.len());
+  assert_eq!(100, clone.len());
+
+  // Changing contents that aren't shared doesn't copy them
+  let before = long.as_ptr();
+  long.make_mut()[0] = b'c';
+  assert_eq!(before, long.as_ptr());
+
+  let mut short = SmallBytes::from(&b"hello"[..]);
+  let clone = short.clone();
+  short.make_mut().extend_from_slice(b" world");
+  assert_eq!(b"hello world", &*short);
+  assert_eq!(b"hello", &*clone);
+}
@@ -0,0 +1,628 @@
+//! Sparse checkouts, which keep only some of the paths of the [`Index`] in
+//! the working tree. The rest get the skip-worktree bit so nothing expects
+//! their files to be there. Which paths are kept is read from
+//! `.git/info/sparse-checkout` when `core.sparseCheckout` is set, in one of
+//! two modes:
+//!
+//! - Cone mode, with `core.sparseCheckoutCone`, names directories to keep
+//!   in full. The files at the root and directly in the directories above
+//!   them are kept too, which is quick to check for huge repositories.
+//! - Pattern mode takes patterns like `.gitignore` files have. The last one
+//!   matching a path or one of its directories decides whether it's kept,
+//!   and ones starting with `!` leave paths out.
+//!
+//! The file for a cone is written with patterns that mean the same thing
+//! in pattern mode:
+//!
+//! ```text
+//! /*
+//! !/*/
+//! /docs/
+//! !/docs/*/
+//! /docs/api/
+//! ```
+
+use crate::{
+  checkout::{self, entry_path, prepare_dir},
+  index,
+  wildmatch::{self, wildmatch},
+  AttributesError, CheckoutError, CheckoutOptions, ConfigError, ConfigFile, ConfigLevel, FileMode,
+  FilterError, IndexError, Repository, RepositoryError, StatData,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::{collections::BTreeSet, fs, io};
+use thiserror::Error;
+
+/// The paths a sparse checkout keeps in the working tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseCheckout {
+  mode: Mode,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mode {
+  Cone {
+    /// The directories kept with everything in them
+    recursive: BTreeSet<BString>,
+    /// The directories above them, which only keep the files directly in
+    /// them
+    parents: BTreeSet<BString>,
+  },
+  Patterns(Vec<Pattern>),
+}
+
+/// A line of a sparse checkout file in pattern mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+  line: BString,
+  pattern: BString,
+  negated: bool,
+  dir_only: bool,
+  /// Patterns without a `/` match the name of the path in any directory
+  basename: bool,
+}
+
+impl Pattern {
+  fn parse(line: &[u8]) -> Option<Self> {
+    let line = match line.strip_suffix(b"\r") {
+      Some(line) => line,
+      None => line,
+    };
+    // Trailing spaces are dropped unless they're escaped
+    let mut end = line.len();
+    while end > 0 && line[end - 1] == b' ' && !(end > 1 && line[end - 2] == b'\\') {
+      end -= 1;
+    }
+    let line = &line[..end];
+    if line.is_empty() || line.starts_with(b"#") {
+      return None;
+    }
+    let (negated, mut pattern) = match line.strip_prefix(b"!") {
+      Some(pattern) => (true, pattern),
+      None => (false, line),
+    };
+    if pattern.starts_with(b"\\!") || pattern.starts_with(b"\\#") {
+      pattern = &pattern[1..];
+    }
+    let dir_only = pattern.len() > 1 && pattern.ends_with(b"/");
+    if dir_only {
+      pattern = &pattern[..pattern.len() - 1];
+    }
+    let basename = !pattern.contains(&b'/');
+    let pattern = pattern.strip_prefix(b"/").unwrap_or(pattern);
+    Some(Self {
+      line: line.into(),
+      pattern: pattern.into(),
+      negated,
+      dir_only,
+      basename,
+    })
+  }
+
+  fn matches(&self, path: &[u8], is_dir: bool) -> bool {
+    if self.dir_only && !is_dir {
+      return false;
+    }
+    match self.basename {
+      true => {
+        let name = path.rsplit_str("/").next().unwrap_or(path);
+        wildmatch(&self.pattern, name, 0)
+      }
+      false => wildmatch(&self.pattern, path, wildmatch::PATHNAME),
+    }
+  }
+}
+
+/// Escape the characters that are special in patterns the way git does
+/// when it writes the directories of a cone
+fn escape(dir: &[u8]) -> Vec<u8> {
+  let mut escaped = Vec::with_capacity(dir.len());
+  for &c in dir {
+    if b"*?[\\".contains(&c) {
+      escaped.push(b'\\');
+    }
+    escaped.push(c);
+  }
+  escaped
+}
+
+fn unescape(pattern: &[u8]) -> Option<BString> {
+  let mut unescaped = Vec::with_capacity(pattern.len());
+  let mut chars = pattern.iter();
+  while let Some(&c) = chars.next() {
+    match c {
+      b'\\' => unescaped.push(*chars.next()?),
+      // Anything else special means this isn't a directory of a cone
+      b'*' | b'?' | b'[' => return None,
+      c => unescaped.push(c),
+    }
+  }
+  Some(unescaped.into())
+}
+
+impl SparseCheckout {
+  /// A cone keeping everything in the given directories, like
+  /// `git sparse-checkout set --cone`. Directories inside ones that are
+  /// already kept are left out since they're kept anyway.
+  pub fn cone<I, S>(dirs: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: AsRef<[u8]>,
+  {
+    let dirs: BTreeSet<BString> = dirs
+      .into_iter()
+      .map(|dir| BString::from(dir.as_ref().trim_with(|c| c == '/')))
+      .filter(|dir| !dir.is_empty())
+      .collect();
+    Self::from_cone(dirs)
+  }
+
+  fn from_cone(dirs: BTreeSet<BString>) -> Self {
+    let kept_by_parent = |dir: &BString| {
+      dir
+        .find_iter("/")
+        .any(|slash| dirs.contains(dir[..slash].as_bstr()))
+    };
+    let recursive: BTreeSet<BString> = dirs
+      .iter()
+      .filter(|dir| !kept_by_parent(dir))
+      .cloned()
+      .collect();
+    let mut parents = BTreeSet::new();
+    for dir in &recursive {
+      for slash in dir.find_iter("/") {
+        parents.insert(BString::from(&dir[..slash]));
+      }
+    }
+    Self {
+      mode: Mode::Cone { recursive, parents },
+    }
+  }
+
+  /// The patterns of a sparse checkout file in pattern mode, one per line.
+  /// Empty lines and ones starting with `#` are skipped.
+  pub fn patterns(patterns: impl AsRef<[u8]>) -> Self {
+    let patterns = patterns
+      .as_ref()
+      .lines()
+      .filter_map(Pattern::parse)
+      .collect();
+    Self {
+      mode: Mode::Patterns(patterns),
+    }
+  }
+
+  /// Parse a sparse checkout file, in cone mode if `cone` is true. Like git
+  /// a file in cone mode with patterns a cone doesn't have is read in
+  /// pattern mode instead, which means the same for the ones it does have.
+  pub fn parse(bytes: impl AsRef<[u8]>, cone: bool) -> Self {
+    let bytes = bytes.as_ref();
+    if cone {
+      if let Some(dirs) = Self::parse_cone(bytes) {
+        return Self::from_cone(dirs);
+      }
+    }
+    Self::patterns(bytes)
+  }
+
+  /// The directories kept in full by a file in cone mode, or `None` if it
+  /// has other patterns
+  fn parse_cone(bytes: &[u8]) -> Option<BTreeSet<BString>> {
+    let (mut dirs, mut parents) = (BTreeSet::new(), BTreeSet::new());
+    for line in bytes.lines() {
+      let line = line.trim_end();
+      if line.is_empty() || line.starts_with(b"#") || line == b"/*" || line == b"!/*/" {
+        continue;
+      }
+      if let Some(dir) = line
+        .strip_prefix(b"!/")
+        .and_then(|l| l.strip_suffix(b"/*/"))
+      {
+        parents.insert(unescape(dir)?);
+      } else if let Some(dir) = line.strip_prefix(b"/").and_then(|l| l.strip_suffix(b"/")) {
+        dirs.insert(unescape(dir).filter(|dir| !dir.is_empty())?);
+      } else {
+        return None;
+      }
+    }
+    Some(dirs.difference(&parents).cloned().collect())
+  }
+
+  /// Whether this is a cone rather than a list of patterns
+  pub fn is_cone(&self) -> bool {
+    matches!(self.mode, Mode::Cone { .. })
+  }
+
+  /// What `git sparse-checkout list` shows: the directories of a cone, or
+  /// the patterns otherwise
+  pub fn list(&self) -> Vec<&BStr> {
+    match &self.mode {
+      Mode::Cone { recursive, .. } => recursive.iter().map(|dir| dir.as_bstr()).collect(),
+      Mode::Patterns(patterns) => patterns.iter().map(|p| p.line.as_bstr()).collect(),
+    }
+  }
+
+  /// Whether the file at `path`, relative to the root of the working tree,
+  /// is kept in the working tree
+  pub fn contains(&self, path: impl AsRef<[u8]>) -> bool {
+    let path = path.as_ref();
+    match &self.mode {
+      Mode::Cone { recursive, parents } => {
+        let dir = match path.rfind_byte(b'/') {
+          Some(slash) => &path[..slash],
+          None => return true,
+        };
+        parents.contains(dir.as_bstr())
+          || dir
+            .find_iter("/")
+            .map(|slash| &dir[..slash])
+            .chain(Some(dir))
+            .any(|dir| recursive.contains(dir.as_bstr()))
+      }
+      Mode::Patterns(patterns) => {
+        // The path is looked at first and then each directory above it
+        // until a pattern matches
+        let (mut end, mut is_dir) = (path.len(), false);
+        loop {
+          let candidate = &path[..end];
+          let last = patterns
+            .iter()
+            .rev()
+            .find(|pattern| pattern.matches(candidate, is_dir));
+          if let Some(pattern) = last {
+            return !pattern.negated;
+          }
+          match candidate.rfind_byte(b'/') {
+            Some(slash) => (end, is_dir) = (slash, true),
+            None => return false,
+          }
+        }
+      }
+    }
+  }
+
+  /// The contents of `.git/info/sparse-checkout` for this sparse checkout,
+  /// the same that `git sparse-checkout set` writes
+  pub fn as_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    match &self.mode {
+      Mode::Cone { recursive, parents } => {
+        bytes.extend_from_slice(b"/*\n!/*/\n");
+        for dir in parents {
+          let dir = escape(dir);
+          bytes.extend_from_slice(&[&b"/"[..], &dir, b"/\n!/", &dir, b"/*/\n"].concat());
+        }
+        for dir in recursive {
+          bytes.extend_from_slice(&[&b"/"[..], &escape(dir), b"/\n"].concat());
+        }
+      }
+      Mode::Patterns(patterns) => {
+        for pattern in patterns {
+          bytes.extend_from_slice(&pattern.line);
+          bytes.push(b'\n');
+        }
+      }
+    }
+    bytes
+  }
+}
+
+impl Repository {
+  /// The sparse checkout of the repository, or `None` if it doesn't have
+  /// one because `core.sparseCheckout` isn't set or there's no
+  /// `.git/info/sparse-checkout` file
+  pub fn sparse_checkout(&self) -> Result<Option<SparseCheckout>, ConfigError> {
+    if !self
+      .config()
+      .get_bool("core.sparsecheckout")?
+      .unwrap_or(false)
+    {
+      return Ok(None);
+    }
+    let cone = self
+      .config()
+      .get_bool("core.sparsecheckoutcone")?
+      .unwrap_or(false);
+    match fs::read(self.git_dir().join("info/sparse-checkout")) {
+      Ok(bytes) => Ok(Some(SparseCheckout::parse(bytes, cone))),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// Write `sparse` to `.git/info/sparse-checkout`, turn on
+  /// `core.sparseCheckout` and `core.sparseCheckoutCone` to match it, and
+  /// update the working tree, like `git sparse-checkout set`
+  pub fn set_sparse_checkout(&mut self, sparse: &SparseCheckout) -> Result<(), SparseError> {
+    self.work_dir().ok_or(SparseError::BareRepository)?;
+    let info = self.git_dir().join("info");
+    fs::create_dir_all(&info)?;
+    fs::write(info.join("sparse-checkout"), sparse.as_bytes())?;
+    self.set_sparse_config(true, sparse.is_cone())?;
+    self.apply_sparse_checkout()
+  }
+
+  /// Turn off the sparse checkout and write every file that it left out,
+  /// like `git sparse-checkout disable`. The sparse checkout file is kept.
+  pub fn disable_sparse_checkout(&mut self) -> Result<(), SparseError> {
+    self.work_dir().ok_or(SparseError::BareRepository)?;
+    self.set_sparse_config(false, false)?;
+    self.apply_sparse_checkout()
+  }
+
+  fn set_sparse_config(&mut self, enabled: bool, cone: bool) -> Result<(), SparseError> {
+    let path = self.common_dir().join("config");
+    let mut config = ConfigFile::from_file(path, ConfigLevel::Local)?;
+    config.set("core.sparseCheckout", enabled.to_string())?;
+    config.set("core.sparseCheckoutCone", cone.to_string())?;
+    config.save()?;
+    Ok(self.reload_config()?)
+  }
+
+  /// Make the working tree match the sparse checkout, like
+  /// `git sparse-checkout reapply`. Files it keeps that were left out are
+  /// written, and files it leaves out are deleted and get the skip-worktree
+  /// bit, along with the directories that leaves empty. Like git, files
+  /// with changes are never deleted, and neither are conflicts.
+  pub fn apply_sparse_checkout(&self) -> Result<(), SparseError> {
+    let work_dir = self.work_dir().ok_or(SparseError::BareRepository)?;
+    let sparse = self.sparse_checkout()?;
+    let mut options = CheckoutOptions::from_config(self.config())?;
+    options.set_attributes(self.attributes()?, Some(work_dir));
+    let mut index = self.index()?;
+    let mut entries = index.entries().to_vec();
+    for entry in entries.iter_mut().filter(|entry| entry.stage == 0) {
+      let kept = sparse
+        .as_ref()
+        .is_none_or(|sparse| sparse.contains(&entry.path));
+      // Entries already matching the sparse checkout are left alone
+      if kept != entry.skip_worktree {
+        continue;
+      }
+      let names: Vec<&[u8]> = entry.path.split_str("/").collect();
+      let mut path = work_dir.to_path_buf();
+      for name in &names {
+        path.push(entry_path(name.as_bstr(), &options)?);
+      }
+      let metadata = match fs::symlink_metadata(&path) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+      };
+      if kept {
+        // Something already there is kept and compared as usual
+        if metadata.is_none() {
+          let mut dir = work_dir.to_path_buf();
+          for name in &names[..names.len() - 1] {
+            dir.push(entry_path(name.as_bstr(), &options)?);
+            prepare_dir(&dir)?;
+          }
+          checkout::checkout_file(
+            self.odb(),
+            &path,
+            &entry.path,
+            entry.mode,
+            &entry.oid,
+            &options,
+          )?;
+          entry.stat = match entry.mode {
+            FileMode::GitLink => StatData::default(),
+            _ => StatData::from_metadata(&fs::symlink_metadata(&path)?),
+          };
+        }
+        entry.skip_worktree = false;
+        continue;
+      }
+      if let Some(metadata) = metadata {
+        match entry.mode {
+          // A submodule is only removed if there's nothing in it
+          FileMode::GitLink => {
+            if fs::remove_dir(&path).is_err() {
+              continue;
+            }
+          }
+          _ => {
+            let clean = (entry.is_stat_clean(&metadata, &options) && !index.is_racy(entry))
+              || (entry.worktree_mode(&metadata, &options) == entry.mode
+                && !metadata.is_dir()
+                && index::hash_file(&path, &metadata, &entry.path, &options)? == entry.oid);
+            if !clean {
+              continue;
+            }
+            checkout::remove_existing(&path)?;
+          }
+        }
+        let mut dir = path.parent();
+        while let Some(parent) = dir.filter(|&dir| dir != work_dir) {
+          if fs::remove_dir(parent).is_err() {
+            break;
+          }
+          dir = parent.parent();
+        }
+      }
+      entry.skip_worktree = true;
+      entry.stat = StatData::default();
+    }
+    // The entries are put back rather than making a new index so it's
+    // written the same way it was read
+    for entry in entries {
+      index.add(entry);
+    }
+    Ok(index.write(self.index_path())?)
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to sparse checkouts
+pub enum SparseError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Repository(#[from] RepositoryError),
+  #[error("{0}")]
+  Index(#[from] IndexError),
+  #[error("{0}")]
+  Attributes(#[from] AttributesError),
+  #[error("{0}")]
+  Filter(#[from] FilterError),
+  #[error("{0}")]
+  Checkout(#[from] CheckoutError),
+  #[error("a bare repository has no working tree to make sparse")]
+  BareRepository,
+}
+
+#[test]
+fn sparse_patterns() {
+  let cone = SparseCheckout::cone(["docs/api/", "docs/api/v1", "src"]);
+  assert!(cone.is_cone());
+  assert_eq!(vec!["docs/api", "src"], cone.list());
+  assert_eq!(
+    "/*\n!/*/\n/docs/\n!/docs/*/\n/docs/api/\n/src/\n",
+    cone.as_bytes().as_bstr()
+  );
+  assert_eq!(cone, SparseCheckout::parse(cone.as_bytes(), true));
+  for (path, kept) in [
+    ("README", true),
+    ("docs/index.md", true),
+    ("docs/api/v1/list.md", true),
+    ("docs/guide/intro.md", false),
+    ("src/main.rs", true),
+    ("tests/main.rs", false),
+  ] {
+    assert_eq!(kept, cone.contains(path), "{}", path);
+    // The file for a cone means the same thing in pattern mode
+    let patterns = SparseCheckout::parse(cone.as_bytes(), false);
+    assert_eq!(kept, patterns.contains(path), "{}", path);
+  }
+
+  let patterns = SparseCheckout::parse(
+    "# comment\n*.md\n/tools/\n!/tools/secret/\n!drafts/\n",
+    true,
+  );
+  assert!(!patterns.is_cone());
+  assert_eq!(
+    vec!["*.md", "/tools/", "!/tools/secret/", "!drafts/"],
+    patterns.list()
+  );
+  assert!(patterns.contains("docs/guide.md"));
+  // The file itself is matched before the directories it's in
+  assert!(patterns.contains("drafts/guide.md"));
+  assert!(!patterns.contains("drafts/notes.txt"));
+  assert!(patterns.contains("tools/build.sh"));
+  assert!(!patterns.contains("tools/secret/key"));
+  assert!(!patterns.contains("src/tools/build.sh"));
+  assert!(!patterns.contains("README"));
+}
+
+#[test]
+fn sparse_checkout() {
+  use crate::{diff::write_tree, Commit, FileMode::NonExecutableFile, Signature, Time};
+  use std::process::Command;
+  let tmp_dir = tempdir::TempDir::new("sparse_test").unwrap();
+  let mut repo = Repository::init(tmp_dir.path()).unwrap();
+  let work_dir = tmp_dir.path();
+  let files = [
+    ("top.txt", NonExecutableFile, "top\n"),
+    ("a/x.txt", NonExecutableFile, "x\n"),
+    ("a/b/c.txt", NonExecutableFile, "c\n"),
+    ("a/other/y.txt", NonExecutableFile, "y\n"),
+    ("d/e.txt", NonExecutableFile, "e\n"),
+  ];
+  let tree = write_tree(repo.odb(), &files);
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = Commit::new(tree, vec![], signature.clone(), signature, "root\n");
+  let commit = repo.odb().write_commit(&commit).unwrap();
+  repo.refs().write("refs/heads/master", &commit).unwrap();
+  repo.checkout_tree(&tree).unwrap();
+  assert_eq!(None, repo.sparse_checkout().unwrap());
+
+  let cone = SparseCheckout::cone(["a/b"]);
+  repo.set_sparse_checkout(&cone).unwrap();
+  assert_eq!(Some(&cone), repo.sparse_checkout().unwrap().as_ref());
+  let skipped = |repo: &Repository| {
+    let index = repo.index().unwrap();
+    let skipped: Vec<_> = index
+      .entries()
+      .iter()
+      .filter(|entry| entry.skip_worktree)
+      .map(|entry| entry.path.to_string())
+      .collect();
+    skipped
+  };
+  assert_eq!(vec!["a/other/y.txt", "d/e.txt"], skipped(&repo));
+  for (path, _, _) in &files {
+    assert_eq!(
+      cone.contains(path),
+      work_dir.join(path).exists(),
+      "{}",
+      path
+    );
+  }
+  assert!(!work_dir.join("d").exists());
+  assert!(repo.status().unwrap().is_empty());
+
+  // Changed files stay, and a new checkout only writes what's kept
+  fs::write(work_dir.join("top.txt"), "changed\n").unwrap();
+  repo
+    .set_sparse_checkout(&SparseCheckout::patterns("/top.txt\n/d/\n"))
+    .unwrap();
+  assert_eq!(
+    vec!["a/b/c.txt", "a/other/y.txt", "a/x.txt"],
+    skipped(&repo)
+  );
+  assert!(work_dir.join("d/e.txt").exists());
+  assert!(!work_dir.join("a").exists());
+  fs::remove_file(work_dir.join("d/e.txt")).unwrap();
+  repo.checkout_tree(&tree).unwrap();
+  assert_eq!(
+    "top\n",
+    fs::read_to_string(work_dir.join("top.txt")).unwrap()
+  );
+  assert!(work_dir.join("d/e.txt").exists());
+  assert!(!work_dir.join("a").exists());
+
+  // Git agrees on the file, the skip-worktree bits, and the status
+  if crate::transport::http::have_git() {
+    repo.set_sparse_checkout(&cone).unwrap();
+    let written = fs::read(repo.git_dir().join("info/sparse-checkout")).unwrap();
+    let git = |args: &[&str]| {
+      let output = Command::new("git")
+        .args(args)
+        .current_dir(work_dir)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .output()
+        .unwrap();
+      assert!(output.status.success(), "{}", output.stderr.as_bstr());
+      output.stdout
+    };
+    assert_eq!("", git(&["status", "--porcelain"]).as_bstr());
+    assert_eq!(
+      "H a/b/c.txt\nS a/other/y.txt\nH a/x.txt\nS d/e.txt\nH top.txt\n",
+      git(&["ls-files", "-t"]).as_bstr()
+    );
+    fs::remove_file(repo.git_dir().join("info/sparse-checkout")).unwrap();
+    git(&["sparse-checkout", "set", "--cone", "a/b"]);
+    assert_eq!(
+      written.as_bstr(),
+      fs::read(repo.git_dir().join("info/sparse-checkout"))
+        .unwrap()
+        .as_bstr()
+    );
+    git(&["sparse-checkout", "disable"]);
+    assert!(skipped(&repo).is_empty());
+  }
+
+  repo.disable_sparse_checkout().unwrap();
+  assert!(skipped(&repo).is_empty());
+  for (path, _, _) in &files {
+    assert!(work_dir.join(path).exists(), "{}", path);
+  }
+  assert!(repo.status().unwrap().is_empty());
+}
@@ -0,0 +1,573 @@
+//! Putting local changes aside and bringing them back later, like
+//! `git stash`. A stash is a commit whose tree is the working tree, with
+//! `HEAD` as its first parent, a commit of the [`Index`] as its second, and
+//! a commit of the untracked files as its third when they were stashed too.
+//! `refs/stash` points at the newest one and its reflog is the stack of all
+//! of them, so `stash@{0}` is the newest and the others follow.
+
+use crate::{
+  index, AttributesError, CheckoutError, CheckoutOptions, Commit, ConfigError, FileMode, HeadError,
+  Index, IndexEntry, IndexError, MergeError, MergeOptions, OdbError, RefError, ReflogEntry,
+  Repository, Signature, StatData, StatusError, StatusOptions, TreeConflict, UntrackedFiles,
+  WorktreeStatus, OID,
+};
+use bstr::{BString, ByteSlice};
+use std::{fs, io, path::Path};
+use thiserror::Error;
+
+/// The ref pointing at the newest stash
+const STASH_REF: &str = "refs/stash";
+
+/// Options controlling what [`Stash::push`] puts aside
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StashOptions {
+  /// Describe the stash with this instead of the commit `HEAD` is at
+  pub message: Option<String>,
+  /// Stash the untracked files too and remove them from the working tree,
+  /// like `--include-untracked`. `.gitignore` files aren't read, so this
+  /// includes ignored files.
+  pub include_untracked: bool,
+  /// Leave the changes in the [`Index`] in place, like `--keep-index`
+  pub keep_index: bool,
+}
+
+/// Options controlling how [`Stash::apply`] brings changes back
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StashApplyOptions {
+  /// Bring back the changes that were in the [`Index`] as staged changes,
+  /// like `--index`. Otherwise everything is left unstaged except for files
+  /// the stash added.
+  pub index: bool,
+}
+
+/// A stash in the stack
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StashEntry {
+  /// The commit of the working tree
+  pub commit: OID,
+  /// What the stash was described as, like `WIP on master: abc1234 Fix
+  /// the build`
+  pub message: BString,
+}
+
+/// The stashes of a [`Repository`], newest first
+#[derive(Debug, Clone, Copy)]
+pub struct Stash<'a> {
+  repo: &'a Repository,
+}
+
+impl Repository {
+  /// The stashes of the repository
+  pub fn stash(&self) -> Stash<'_> {
+    Stash { repo: self }
+  }
+}
+
+impl<'a> Stash<'a> {
+  /// Every stash, newest first like `git stash list`
+  pub fn list(&self) -> Result<Vec<StashEntry>, StashError> {
+    let mut entries: Vec<_> = self
+      .repo
+      .refs()
+      .reflog(STASH_REF)?
+      .into_iter()
+      .map(|entry| StashEntry {
+        commit: entry.new,
+        message: entry.message,
+      })
+      .collect();
+    entries.reverse();
+    Ok(entries)
+  }
+
+  /// The commit of `stash@{n}`
+  pub fn get(&self, n: usize) -> Result<OID, StashError> {
+    let entries = self.list()?;
+    entries
+      .get(n)
+      .map(|entry| entry.commit)
+      .ok_or(StashError::NotFound(n))
+  }
+
+  /// Put the changes in the [`Index`] and the working tree aside as a new
+  /// `stash@{0}` and reset them to `HEAD`, like `git stash push`, returning
+  /// the new stash or `None` if there was nothing to stash. `stasher` is
+  /// the author and committer of the stash commits and who the reflog
+  /// entry is by.
+  pub fn push(
+    &self,
+    stasher: &Signature,
+    options: &StashOptions,
+  ) -> Result<Option<OID>, StashError> {
+    let repo = self.repo;
+    let odb = repo.odb();
+    let work_dir = repo.work_dir().ok_or(StashError::BareRepository)?;
+    let head = *repo.head()?.oid().ok_or(StashError::Unborn)?;
+    let head_commit = odb.read_commit(&head)?;
+    let index = repo.index()?;
+    if let Some(entry) = index.entries().iter().find(|entry| entry.stage > 0) {
+      return Err(StashError::Unmerged(entry.path.clone()));
+    }
+    let mut status = StatusOptions::from_config(repo.config())?;
+    status
+      .checkout
+      .set_attributes(repo.attributes()?, Some(work_dir));
+    status.untracked = match options.include_untracked {
+      true => UntrackedFiles::All,
+      false => UntrackedFiles::No,
+    };
+    let changes = crate::worktree_status(&index, work_dir, &status)?;
+    let index_tree = index.write_tree(odb)?;
+    let untracked: Vec<&BString> = changes
+      .iter()
+      .filter(|change| change.status == WorktreeStatus::Untracked)
+      .map(|change| &change.path)
+      .collect();
+    if index_tree == *head_commit.tree() && changes.is_empty() {
+      return Ok(None);
+    }
+
+    let mut worktree = index.clone();
+    for change in &changes {
+      match change.status {
+        WorktreeStatus::Deleted => {
+          worktree.remove(&change.path);
+        }
+        WorktreeStatus::Modified | WorktreeStatus::TypeChanged => {
+          let existing = index.get(&change.path).map(|entry| entry.mode);
+          worktree.add(stash_file(
+            repo,
+            work_dir,
+            &change.path,
+            existing,
+            &status.checkout,
+          )?);
+        }
+        WorktreeStatus::Unmerged | WorktreeStatus::Untracked => {}
+      }
+    }
+    let worktree_tree = worktree.write_tree(odb)?;
+
+    let branch = match repo.current_branch()? {
+      Some(branch) => branch.to_string(),
+      None => "(no branch)".into(),
+    };
+    let head_message = head_commit.message_lossy();
+    let on = format!(
+      "{}: {} {}",
+      branch,
+      odb.abbreviate(&head, None)?,
+      head_message.lines().next().unwrap_or("")
+    );
+    let commit = |tree, parents, message: String| {
+      let commit = Commit::new(tree, parents, stasher.clone(), stasher.clone(), message);
+      odb.write_commit(&commit)
+    };
+    let mut parents = vec![
+      head,
+      commit(index_tree, vec![head], format!("index on {}\n", on))?,
+    ];
+    if !untracked.is_empty() {
+      let entries = untracked
+        .iter()
+        .map(|path| stash_file(repo, work_dir, path, None, &status.checkout))
+        .collect::<Result<_, _>>()?;
+      let tree = Index::new(entries).write_tree(odb)?;
+      parents.push(commit(
+        tree,
+        vec![],
+        format!("untracked files on {}\n", on),
+      )?);
+    }
+    let message = match &options.message {
+      Some(message) => format!("On {}: {}\n", branch, message),
+      None => format!("WIP on {}\n", on),
+    };
+    let stash = commit(worktree_tree, parents, message.clone())?;
+    let old = repo.refs().resolve(STASH_REF)?;
+    repo.refs().write(STASH_REF, &stash)?;
+    let zero = OID::from_bytes(&[0; 20]).unwrap();
+    let entry = ReflogEntry::new(old.unwrap_or(zero), stash, stasher.clone(), message);
+    repo.refs().append_reflog(STASH_REF, &entry)?;
+
+    let target = match options.keep_index {
+      true => index_tree,
+      false => *head_commit.tree(),
+    };
+    repo.switch_tree(&index, &target)?;
+    for path in untracked {
+      let mut path = work_dir.join(path.to_path().map_err(|_| invalid_path(path))?);
+      fs::remove_file(&path)?;
+      while path.pop() && path != work_dir && fs::remove_dir(&path).is_ok() {}
+    }
+    Ok(Some(stash))
+  }
+
+  /// Bring the changes of `stash@{n}` back on top of `HEAD` with a
+  /// three-way merge, like `git stash apply`, returning the paths that
+  /// couldn't be merged. The conflicts are left in the [`Index`] and the
+  /// working tree the same as for [`Repository::cherry_pick`].
+  ///
+  /// The tracked files have to match `HEAD` first, and untracked files
+  /// from the stash can't already be there.
+  pub fn apply(
+    &self,
+    n: usize,
+    options: &StashApplyOptions,
+  ) -> Result<Vec<TreeConflict>, StashError> {
+    let repo = self.repo;
+    let odb = repo.odb();
+    let work_dir = repo.work_dir().ok_or(StashError::BareRepository)?;
+    let oid = self.get(n)?;
+    let stash = odb.read_commit(&oid)?;
+    let (base, index, untracked) = match stash.parents() {
+      [base, index] => (base, index, None),
+      [base, index, untracked] => (base, index, Some(untracked)),
+      _ => return Err(StashError::NotAStash(oid)),
+    };
+    let head = *repo.head()?.oid().ok_or(StashError::Unborn)?;
+    let changed = repo.tracked_changes()?;
+    if !changed.is_empty() {
+      return Err(StashError::LocalChanges(changed));
+    }
+    let untracked = match untracked {
+      Some(untracked) => Some(*odb.read_commit(untracked)?.tree()),
+      None => None,
+    };
+    if let Some(tree) = &untracked {
+      for entry in Index::from_tree(odb, tree)?.entries() {
+        let path = entry
+          .path
+          .to_path()
+          .map_err(|_| invalid_path(&entry.path))?;
+        if fs::symlink_metadata(work_dir.join(path)).is_ok() {
+          return Err(StashError::UntrackedExists(entry.path.clone()));
+        }
+      }
+    }
+
+    let base_tree = *odb.read_commit(base)?.tree();
+    let head_tree = *odb.read_commit(&head)?.tree();
+    let mut merge_options = MergeOptions::from_config(repo.config())?;
+    merge_options.ours_label = Some("Updated upstream".into());
+    merge_options.ancestor_label = Some("Stash base".into());
+    merge_options.theirs_label = Some("Stashed changes".into());
+    let staged = match options.index {
+      true => {
+        let index_tree = *odb.read_commit(index)?.tree();
+        let merged = crate::merge_trees(
+          odb,
+          Some(&base_tree),
+          &head_tree,
+          &index_tree,
+          &merge_options,
+        )?;
+        if !merged.is_clean() {
+          return Err(StashError::IndexConflict(oid));
+        }
+        Some(merged.tree)
+      }
+      false => None,
+    };
+    let merged = crate::merge_trees(
+      odb,
+      Some(&base_tree),
+      &head_tree,
+      stash.tree(),
+      &merge_options,
+    )?;
+    repo.checkout_merged(&merged)?;
+
+    if merged.is_clean() {
+      // The merged files are only staged when asked to, except for the ones
+      // the stash added which would be untracked otherwise
+      let current = repo.index()?;
+      let head_index = Index::from_tree(odb, &head_tree)?;
+      let mut entries: Vec<IndexEntry> = Index::from_tree(odb, &staged.unwrap_or(head_tree))?
+        .entries()
+        .iter()
+        .map(|entry| match current.get(&entry.path) {
+          Some(current) if current.oid == entry.oid && current.mode == entry.mode => {
+            current.clone()
+          }
+          _ => entry.clone(),
+        })
+        .collect();
+      if staged.is_none() {
+        let added = current.entries().iter();
+        entries.extend(
+          added
+            .filter(|entry| head_index.get(&entry.path).is_none())
+            .cloned(),
+        );
+      }
+      Index::new(entries).write(repo.index_path())?;
+    }
+    if let Some(tree) = untracked {
+      let mut checkout = CheckoutOptions::from_config(repo.config())?;
+      checkout.set_attributes(repo.attributes()?, Some(work_dir));
+      crate::checkout_tree(odb, &tree, work_dir, &checkout)?;
+    }
+    Ok(merged.conflicts)
+  }
+
+  /// Apply `stash@{n}` like [`Stash::apply`] and drop it if there were no
+  /// conflicts, like `git stash pop`. A stash that conflicted is kept so it
+  /// isn't lost.
+  pub fn pop(
+    &self,
+    n: usize,
+    options: &StashApplyOptions,
+  ) -> Result<Vec<TreeConflict>, StashError> {
+    let conflicts = self.apply(n, options)?;
+    if conflicts.is_empty() {
+      self.drop(n)?;
+    }
+    Ok(conflicts)
+  }
+
+  /// Remove `stash@{n}` from the stack, like `git stash drop`, returning
+  /// its commit. The stashes after it move up by one.
+  pub fn drop(&self, n: usize) -> Result<OID, StashError> {
+    let refs = self.repo.refs();
+    let mut entries = refs.reflog(STASH_REF)?;
+    if n >= entries.len() {
+      return Err(StashError::NotFound(n));
+    }
+    let idx = entries.len() - 1 - n;
+    let dropped = entries.remove(idx);
+    // The stash after the dropped one now comes right after the one before
+    if idx < entries.len() {
+      entries[idx].old = match idx {
+        0 => OID::from_bytes(&[0; 20]).unwrap(),
+        _ => entries[idx - 1].new,
+      };
+    }
+    refs.write_reflog(STASH_REF, &entries)?;
+    match entries.last() {
+      Some(newest) => refs.write(STASH_REF, &newest.new)?,
+      None => {
+        refs.delete(STASH_REF)?;
+      }
+    }
+    Ok(dropped.new)
+  }
+
+  /// Drop every stash, like `git stash clear`
+  pub fn clear(&self) -> Result<(), StashError> {
+    self.repo.refs().write_reflog(STASH_REF, &[])?;
+    self.repo.refs().delete(STASH_REF)?;
+    Ok(())
+  }
+}
+
+/// Write the file at `path` in the working tree to the object database,
+/// returning its entry for the tree of a stash
+fn stash_file(
+  repo: &Repository,
+  work_dir: &Path,
+  path: &BString,
+  existing: Option<FileMode>,
+  options: &CheckoutOptions,
+) -> Result<IndexEntry, StashError> {
+  let full = work_dir.join(path.to_path().map_err(|_| invalid_path(path))?);
+  let metadata = fs::symlink_metadata(&full)?;
+  let blob = index::file_blob(&full, &metadata, path, options)?;
+  Ok(IndexEntry::new(
+    path.clone(),
+    index::worktree_mode(existing, &metadata, options),
+    repo.odb().write_blob(&blob)?,
+    StatData::default(),
+  ))
+}
+
+fn invalid_path(path: &BString) -> StashError {
+  StashError::Checkout(CheckoutError::InvalidPath(path.clone()))
+}
+
+#[derive(Error, Debug)]
+/// Errors related to stashing changes
+pub enum StashError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Head(#[from] HeadError),
+  #[error("{0}")]
+  Index(#[from] IndexError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Attributes(#[from] AttributesError),
+  #[error("{0}")]
+  Filter(#[from] crate::FilterError),
+  #[error("{0}")]
+  Status(#[from] StatusError),
+  #[error("{0}")]
+  Merge(#[from] MergeError),
+  #[error("{0}")]
+  Checkout(#[from] CheckoutError),
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("a bare repository has no working tree to stash")]
+  BareRepository,
+  #[error("HEAD is on a branch with no commits yet")]
+  Unborn,
+  #[error("{0:?} has a merge conflict")]
+  Unmerged(BString),
+  #[error("local changes would be overwritten: {0:?}")]
+  LocalChanges(Vec<BString>),
+  #[error("untracked file {0:?} already exists")]
+  UntrackedExists(BString),
+  #[error("stash@{{{0}}} does not exist")]
+  NotFound(usize),
+  #[error("{0} is not a stash commit")]
+  NotAStash(OID),
+  #[error("changes to the index of stash {0} conflict, try without restoring the index")]
+  IndexConflict(OID),
+}
+
+#[test]
+fn stash() {
+  use crate::{diff::write_tree, Blob, FileMode::NonExecutableFile, Time};
+  let tmp_dir = tempdir::TempDir::new("stash_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let work_dir = repo.work_dir().unwrap();
+  let odb = repo.odb().clone();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |parents: Vec<OID>, b: &str, message: &str| {
+    let files = [
+      ("a.txt", NonExecutableFile, "a\n"),
+      ("b.txt", NonExecutableFile, b),
+    ];
+    let tree = write_tree(&odb, &files);
+    let commit = Commit::new(tree, parents, signature.clone(), signature.clone(), message);
+    let commit = odb.write_commit(&commit).unwrap();
+    repo.refs().write("refs/heads/master", &commit).unwrap();
+    repo
+      .checkout_tree(odb.read_commit(&commit).unwrap().tree())
+      .unwrap();
+    commit
+  };
+  let root = commit(vec![], "b\n", "root\n");
+  let read = |path: &str| fs::read_to_string(work_dir.join(path)).unwrap();
+  let codes = || {
+    let status = repo.status().unwrap();
+    let codes: Vec<_> = status.iter().map(ToString::to_string).collect();
+    codes.join(",")
+  };
+  let stash = repo.stash();
+  let options = StashOptions::default();
+  assert_eq!(None, stash.push(&signature, &options).unwrap());
+
+  // A staged new file, a change to the working tree, and an untracked file
+  fs::write(work_dir.join("c.txt"), "c\n").unwrap();
+  let mut index = repo.index().unwrap();
+  let c = odb.write_blob(&Blob::new(b"c\n".to_vec())).unwrap();
+  index.add(IndexEntry::new(
+    "c.txt",
+    NonExecutableFile,
+    c,
+    StatData::default(),
+  ));
+  index.write(repo.index_path()).unwrap();
+  fs::write(work_dir.join("a.txt"), "changed\n").unwrap();
+  fs::create_dir(work_dir.join("new")).unwrap();
+  fs::write(work_dir.join("new/u.txt"), "u\n").unwrap();
+  let untracked = StashOptions {
+    include_untracked: true,
+    ..StashOptions::default()
+  };
+  let first = stash.push(&signature, &untracked).unwrap().unwrap();
+  assert_eq!("", codes());
+  assert_eq!("a\n", read("a.txt"));
+  assert!(!work_dir.join("c.txt").exists());
+  assert!(!work_dir.join("new").exists());
+  let first_commit = odb.read_commit(&first).unwrap();
+  assert_eq!(3, first_commit.parents().len());
+  assert_eq!(root, first_commit.parents()[0]);
+  let wip = format!(
+    "WIP on master: {} root",
+    odb.abbreviate(&root, None).unwrap()
+  );
+  assert_eq!(
+    vec![StashEntry {
+      commit: first,
+      message: wip.clone().into(),
+    }],
+    stash.list().unwrap()
+  );
+
+  fs::write(work_dir.join("b.txt"), "stashed\n").unwrap();
+  let named = StashOptions {
+    message: Some("second".into()),
+    ..StashOptions::default()
+  };
+  let second = stash.push(&signature, &named).unwrap().unwrap();
+  assert_eq!("b\n", read("b.txt"));
+  assert_eq!(vec![second, first], {
+    let list = stash.list().unwrap();
+    list.iter().map(|entry| entry.commit).collect::<Vec<_>>()
+  });
+  assert_eq!("On master: second", stash.list().unwrap()[0].message);
+
+  // Git reads the same stack
+  let git = |args: &[&str]| {
+    let output = std::process::Command::new("git")
+      .args(args)
+      .current_dir(work_dir)
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .output()
+      .unwrap();
+    assert!(output.status.success());
+    output.stdout.to_str_lossy().into_owned()
+  };
+  if crate::transport::http::have_git() {
+    assert_eq!(
+      format!("stash@{{0}}: On master: second\nstash@{{1}}: {}\n", wip),
+      git(&["stash", "list"])
+    );
+  }
+
+  // Popping the older stash brings everything back and leaves the newer
+  let restore = StashApplyOptions { index: true };
+  assert!(stash.pop(1, &restore).unwrap().is_empty());
+  assert_eq!("changed\n", read("a.txt"));
+  assert_eq!("u\n", read("new/u.txt"));
+  assert_eq!(" M a.txt,A  c.txt,?? new/", codes());
+  assert_eq!(vec![second], {
+    let list = stash.list().unwrap();
+    list.iter().map(|entry| entry.commit).collect::<Vec<_>>()
+  });
+  assert_eq!(Some(second), repo.refs().resolve(STASH_REF).unwrap());
+  assert!(matches!(
+    stash.apply(0, &StashApplyOptions::default()),
+    Err(StashError::LocalChanges(_))
+  ));
+  assert!(matches!(stash.drop(1), Err(StashError::NotFound(1))));
+
+  // A stash that conflicts with what was committed since stays on the stack
+  fs::remove_file(work_dir.join("c.txt")).unwrap();
+  fs::remove_dir_all(work_dir.join("new")).unwrap();
+  let index = Index::from_tree(&odb, odb.read_commit(&root).unwrap().tree()).unwrap();
+  index.write(repo.index_path()).unwrap();
+  commit(vec![root], "committed\n", "change b\n");
+  let conflicts = stash.pop(0, &StashApplyOptions::default()).unwrap();
+  assert_eq!(1, conflicts.len());
+  assert_eq!("b.txt", conflicts[0].path);
+  assert!(read("b.txt").starts_with("<<<<<<< Updated upstream\n"));
+  assert_eq!(1, stash.list().unwrap().len());
+  if crate::transport::http::have_git() {
+    assert_eq!("UU b.txt\n", git(&["status", "--porcelain"]));
+  }
+
+  assert_eq!(second, stash.drop(0).unwrap());
+  assert!(stash.list().unwrap().is_empty());
+  assert_eq!(None, repo.refs().resolve(STASH_REF).unwrap());
+}
@@ -0,0 +1,1023 @@
+//! Comparing the [`Index`] with the working tree, the part of `git status`
+//! that lists changes not staged for commit and untracked files, and with
+//! the tree of `HEAD` for the changes that are staged.
+//!
+//! Each directory is read once and its listing is merged with the entries of
+//! the [`Index`] under it, which are already sorted by path. Only tracked
+//! files are `stat`'d, relative to the directory that was just read, while
+//! untracked files and subdirectories are told apart by the file type the
+//! directory listing already has.
+
+use crate::{
+  index, AttributesError, CheckoutOptions, Config, ConfigError, FileMode, FilterError, Index,
+  IndexEntry, IndexError, Odb, OdbError, RefError, Repository, Trace2, OID,
+};
+use bstr::{BString, ByteSlice};
+use std::{
+  cmp::Ordering,
+  collections::BTreeMap,
+  fmt, fs, io,
+  path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// How untracked files are reported, as set by `status.showUntrackedFiles`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum UntrackedFiles {
+  /// Don't look for untracked files at all
+  No,
+  /// Report untracked files, but a directory without any tracked files in
+  /// it is reported once as `dir/` instead of listing what's inside
+  #[default]
+  Normal,
+  /// Report every untracked file on its own
+  All,
+}
+
+/// Options controlling how [`worktree_status`] compares the working tree
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatusOptions {
+  /// How files on disk are compared with the [`Index`], see
+  /// [`CheckoutOptions::file_mode`] and [`CheckoutOptions::symlinks`]
+  pub checkout: CheckoutOptions,
+  /// How untracked files are reported
+  pub untracked: UntrackedFiles,
+  /// How many threads to scan the top level directories of the working tree
+  /// with. `None` uses the amount of parallelism available on the machine.
+  /// This is only used when the `parallel` feature is enabled, otherwise
+  /// everything is scanned on the calling thread.
+  pub threads: Option<usize>,
+}
+
+impl StatusOptions {
+  /// Create [`StatusOptions`] from the `core.*` settings and
+  /// `status.showUntrackedFiles` in a [`Config`]
+  pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+    let untracked = match config.get_str("status.showuntrackedfiles")? {
+      None => UntrackedFiles::Normal,
+      Some(value) => match value.to_ascii_lowercase().as_str() {
+        "no" | "false" | "off" | "0" => UntrackedFiles::No,
+        "normal" | "true" | "yes" | "on" | "1" => UntrackedFiles::Normal,
+        "all" => UntrackedFiles::All,
+        _ => {
+          return Err(ConfigError::InvalidValue {
+            key: "status.showuntrackedfiles".into(),
+            value: value.into(),
+            expected: "no, normal, or all",
+          })
+        }
+      },
+    };
+    Ok(Self {
+      checkout: CheckoutOptions::from_config(config)?,
+      untracked,
+      threads: None,
+    })
+  }
+}
+
+/// How a path in the working tree differs from the [`Index`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WorktreeStatus {
+  /// The contents or the executable bit of the file changed
+  Modified,
+  /// The path changed between a file, a symbolic link, and a submodule
+  TypeChanged,
+  /// The file is in the [`Index`] but not in the working tree
+  Deleted,
+  /// The path has a merge conflict, so the [`Index`] has more than one
+  /// version of it and none is compared with the working tree
+  Unmerged,
+  /// The path is in the working tree but not in the [`Index`]
+  Untracked,
+}
+
+/// A path that differs between the [`Index`] and the working tree
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WorktreeChange {
+  /// How the path differs
+  pub status: WorktreeStatus,
+  /// The path from the root of the working tree, separated by `/`. Untracked
+  /// directories end with a `/`.
+  pub path: BString,
+}
+
+/// Compare every entry of the [`Index`] with the file at the same path in
+/// `work_dir` and look for untracked files, returning the paths that differ
+/// sorted by path.
+///
+/// Files whose metadata matches their [`StatData`][crate::StatData] are
+/// unchanged without being read, unless the [`Index`] was written too soon
+/// after they changed to tell (see [`Index::is_racy`]), and ones whose size
+/// is different are changed without being read, so only files that were
+/// touched without changing size get hashed. Submodules are only checked for being there,
+/// not for the commit they have checked out. Entries with the skip-worktree
+/// bit a sparse checkout sets are left out. `.gitignore` files aren't read,
+/// so ignored files are reported as untracked.
+pub fn worktree_status(
+  index: &Index,
+  work_dir: impl AsRef<Path>,
+  options: &StatusOptions,
+) -> Result<Vec<WorktreeChange>, StatusError> {
+  let _region = Trace2::region("status", "worktree");
+  let root = Scan {
+    path: work_dir.as_ref().to_owned(),
+    prefix: Vec::new(),
+    index,
+    entries: index.entries(),
+    untracked_only: false,
+  };
+  let mut changes = Vec::new();
+  let mut scans = Vec::new();
+  root.run(options, &mut changes, &mut scans)?;
+  Trace2::data("status", "top_level_dirs", scans.len());
+  scan_all(scans, options, &mut changes)?;
+  changes.sort_by(|a, b| a.path.cmp(&b.path));
+  Trace2::data("status", "changes", changes.len());
+  Ok(changes)
+}
+
+/// How a path differs between the tree of `HEAD` and the [`Index`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StagedStatus {
+  /// The path is in the [`Index`] but not in `HEAD`
+  Added,
+  /// The contents or the executable bit of the file changed
+  Modified,
+  /// The path changed between a file, a symbolic link, and a submodule
+  TypeChanged,
+  /// The path is in `HEAD` but not in the [`Index`]
+  Deleted,
+}
+
+/// The state of a path in the output of [`status`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PathStatus {
+  /// A tracked path that changed. At least one of the two is set, and the
+  /// working tree is only ever [`WorktreeStatus::Modified`],
+  /// [`WorktreeStatus::TypeChanged`], or [`WorktreeStatus::Deleted`].
+  Changed {
+    /// How the [`Index`] differs from `HEAD`
+    staged: Option<StagedStatus>,
+    /// How the working tree differs from the [`Index`]
+    worktree: Option<WorktreeStatus>,
+  },
+  /// The path has a merge conflict. These say which of the common ancestor
+  /// (stage 1), ours (stage 2), and theirs (stage 3) the [`Index`] has.
+  Conflicted {
+    ancestor: bool,
+    ours: bool,
+    theirs: bool,
+  },
+  /// The path is in the working tree but not in the [`Index`]
+  Untracked,
+}
+
+/// A path that differs between `HEAD`, the [`Index`], and the working tree
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StatusEntry {
+  /// The path from the root of the working tree, separated by `/`. Untracked
+  /// directories end with a `/`.
+  pub path: BString,
+  /// How the path differs
+  pub status: PathStatus,
+}
+
+impl StatusEntry {
+  /// The two letter code `git status --porcelain` shows for the path, the
+  /// staged change followed by the one in the working tree. Conflicts are
+  /// shown as `DD`, `AU`, `UD`, `UA`, `DU`, `AA`, or `UU` depending on which
+  /// sides added, deleted, or changed the path, and untracked paths as `??`.
+  pub fn code(&self) -> [u8; 2] {
+    match self.status {
+      PathStatus::Changed { staged, worktree } => {
+        let staged = match staged {
+          None => b' ',
+          Some(StagedStatus::Added) => b'A',
+          Some(StagedStatus::Modified) => b'M',
+          Some(StagedStatus::TypeChanged) => b'T',
+          Some(StagedStatus::Deleted) => b'D',
+        };
+        let worktree = match worktree {
+          Some(WorktreeStatus::Modified) => b'M',
+          Some(WorktreeStatus::TypeChanged) => b'T',
+          Some(WorktreeStatus::Deleted) => b'D',
+          _ => b' ',
+        };
+        [staged, worktree]
+      }
+      PathStatus::Conflicted {
+        ancestor,
+        ours,
+        theirs,
+      } => match (ancestor, ours, theirs) {
+        (true, false, false) => *b"DD",
+        (false, true, false) => *b"AU",
+        (true, true, false) => *b"UD",
+        (false, false, true) => *b"UA",
+        (true, false, true) => *b"DU",
+        (false, true, true) => *b"AA",
+        _ => *b"UU",
+      },
+      PathStatus::Untracked => *b"??",
+    }
+  }
+}
+
+impl fmt::Display for StatusEntry {
+  /// The line `git status --porcelain` shows for the path, without quoting
+  /// unusual characters in it
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} {}", self.code().as_bstr(), self.path)
+  }
+}
+
+/// Compare the tree `head` with the [`Index`] and the [`Index`] with the
+/// working tree in `work_dir`, like `git status`. The tracked paths that
+/// differ come first sorted by path and then the untracked ones, the same
+/// order `git status --porcelain` shows them in. `head` is `None` before the
+/// first commit, when everything in the [`Index`] is added.
+///
+/// The working tree is compared the same way as [`worktree_status`], so
+/// files whose [`StatData`][crate::StatData] still matches are not read.
+pub fn status(
+  odb: &Odb,
+  head: Option<&OID>,
+  index: &Index,
+  work_dir: impl AsRef<Path>,
+  options: &StatusOptions,
+) -> Result<Vec<StatusEntry>, StatusError> {
+  let mut paths = BTreeMap::new();
+  {
+    let _region = Trace2::region("status", "index");
+    let head = match head {
+      Some(tree) => Index::from_tree(odb, tree)?,
+      None => Index::default(),
+    };
+    let mut head: BTreeMap<&BString, &IndexEntry> = head
+      .entries()
+      .iter()
+      .map(|entry| (&entry.path, entry))
+      .collect();
+    let entries = index.entries();
+    let mut start = 0;
+    while start < entries.len() {
+      let path = &entries[start].path;
+      let stages = &entries[start..]
+        .iter()
+        .take_while(|entry| entry.path == *path)
+        .collect::<Vec<_>>();
+      start += stages.len();
+      let old = head.remove(path);
+      let status = if stages.iter().any(|entry| entry.stage != 0) {
+        let has = |stage| stages.iter().any(|entry| entry.stage == stage);
+        PathStatus::Conflicted {
+          ancestor: has(1),
+          ours: has(2),
+          theirs: has(3),
+        }
+      } else {
+        let staged = match old {
+          None => StagedStatus::Added,
+          Some(old) if kind(old.mode) != kind(stages[0].mode) => StagedStatus::TypeChanged,
+          Some(old) if old.mode != stages[0].mode || old.oid != stages[0].oid => {
+            StagedStatus::Modified
+          }
+          Some(_) => continue,
+        };
+        PathStatus::Changed {
+          staged: Some(staged),
+          worktree: None,
+        }
+      };
+      paths.insert(path.clone(), status);
+    }
+    for path in head.into_keys() {
+      let status = PathStatus::Changed {
+        staged: Some(StagedStatus::Deleted),
+        worktree: None,
+      };
+      paths.insert(path.clone(), status);
+    }
+    Trace2::data("status", "staged", paths.len());
+  }
+
+  // A file that was deleted from the index but is still there is both
+  // staged for deletion and untracked, so untracked paths are kept apart
+  let mut untracked = Vec::new();
+  for change in worktree_status(index, work_dir, options)? {
+    match change.status {
+      // Already found from the stages in the index
+      WorktreeStatus::Unmerged => {}
+      WorktreeStatus::Untracked => untracked.push(StatusEntry {
+        path: change.path,
+        status: PathStatus::Untracked,
+      }),
+      status => {
+        let changed = paths.entry(change.path).or_insert(PathStatus::Changed {
+          staged: None,
+          worktree: None,
+        });
+        if let PathStatus::Changed { worktree, .. } = changed {
+          *worktree = Some(status);
+        }
+      }
+    }
+  }
+  Ok(
+    paths
+      .into_iter()
+      .map(|(path, status)| StatusEntry { path, status })
+      .chain(untracked)
+      .collect(),
+  )
+}
+
+/// Files, symbolic links, and submodules are different kinds of entries, an
+/// executable and a non executable file are not
+fn kind(mode: FileMode) -> FileMode {
+  match mode {
+    FileMode::ExecutableFile => FileMode::NonExecutableFile,
+    mode => mode,
+  }
+}
+
+impl Repository {
+  /// Compare the [`Index`] of the repository with its working tree using the
+  /// [`StatusOptions`] from its [`Config`]. See [`worktree_status`].
+  pub fn worktree_status(&self) -> Result<Vec<WorktreeChange>, StatusError> {
+    let work_dir = self.work_dir().ok_or(StatusError::BareRepository)?;
+    let mut options = StatusOptions::from_config(self.config())?;
+    options
+      .checkout
+      .set_attributes(self.attributes()?, Some(work_dir));
+    worktree_status(&self.index()?, work_dir, &options)
+  }
+
+  /// The tracked paths whose [`Index`] entry or file in the working tree
+  /// differ from `HEAD`, leaving out untracked files. Operations that
+  /// rewrite the working tree look at these to not lose local changes.
+  pub(crate) fn tracked_changes(&self) -> Result<Vec<BString>, StatusError> {
+    Ok(
+      self
+        .status()?
+        .into_iter()
+        .filter(|entry| entry.status != PathStatus::Untracked)
+        .map(|entry| entry.path)
+        .collect(),
+    )
+  }
+
+  /// Compare the tree of `HEAD`, the [`Index`], and the working tree of the
+  /// repository using the [`StatusOptions`] from its [`Config`]. See
+  /// [`status`].
+  pub fn status(&self) -> Result<Vec<StatusEntry>, StatusError> {
+    let work_dir = self.work_dir().ok_or(StatusError::BareRepository)?;
+    let mut options = StatusOptions::from_config(self.config())?;
+    options
+      .checkout
+      .set_attributes(self.attributes()?, Some(work_dir));
+    let head = match self.refs().resolve("HEAD")? {
+      Some(commit) => Some(*self.odb().read_commit(&commit)?.tree()),
+      None => None,
+    };
+    status(
+      self.odb(),
+      head.as_ref(),
+      &self.index()?,
+      work_dir,
+      &options,
+    )
+  }
+}
+
+/// A directory of the working tree waiting to be read along with the entries
+/// of the [`Index`] under it
+struct Scan<'a> {
+  path: PathBuf,
+  /// The path of the directory from the root of the working tree with a
+  /// trailing `/`, or empty for the root itself
+  prefix: Vec<u8>,
+  index: &'a Index,
+  entries: &'a [IndexEntry],
+  /// Whether this is an untracked directory that only needs to be checked
+  /// for having files in it
+  untracked_only: bool,
+}
+
+/// Scan every directory in `scans` along with everything under them
+#[cfg(not(feature = "parallel"))]
+fn scan_all(
+  scans: Vec<Scan>,
+  options: &StatusOptions,
+  changes: &mut Vec<WorktreeChange>,
+) -> Result<(), StatusError> {
+  scan_tree(scans, options, changes)
+}
+
+/// Scan every directory in `scans` along with everything under them, with
+/// each top level directory being scanned on one thread
+#[cfg(feature = "parallel")]
+fn scan_all(
+  scans: Vec<Scan>,
+  options: &StatusOptions,
+  changes: &mut Vec<WorktreeChange>,
+) -> Result<(), StatusError> {
+  use std::{
+    sync::{
+      atomic::{AtomicUsize, Ordering},
+      Mutex,
+    },
+    thread,
+  };
+
+  let threads = options
+    .threads
+    .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+    .unwrap_or(1)
+    .min(scans.len());
+  if threads <= 1 {
+    return scan_tree(scans, options, changes);
+  }
+
+  // Directories are handed out one at a time as threads finish, since one
+  // large directory like `src` can hold most of the working tree
+  let next = AtomicUsize::new(0);
+  let scans: Vec<_> = scans
+    .into_iter()
+    .map(|scan| Mutex::new(Some(scan)))
+    .collect();
+  thread::scope(|scope| {
+    let workers: Vec<_> = (0..threads)
+      .map(|_| {
+        scope.spawn(|| {
+          let mut found = Vec::new();
+          while let Some(scan) = scans.get(next.fetch_add(1, Ordering::Relaxed)) {
+            let scan = scan
+              .lock()
+              .unwrap_or_else(|e| e.into_inner())
+              .take()
+              .unwrap();
+            scan_tree(vec![scan], options, &mut found)?;
+          }
+          Ok(found)
+        })
+      })
+      .collect();
+    for worker in workers {
+      let found: Result<Vec<WorktreeChange>, StatusError> = worker
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+      changes.extend(found?);
+    }
+    Ok(())
+  })
+}
+
+/// Scan the directories in `scans` and every directory under them on the
+/// calling thread
+fn scan_tree(
+  mut scans: Vec<Scan>,
+  options: &StatusOptions,
+  changes: &mut Vec<WorktreeChange>,
+) -> Result<(), StatusError> {
+  while let Some(scan) = scans.pop() {
+    scan.run(options, changes, &mut scans)?;
+  }
+  Ok(())
+}
+
+/// The entries of the [`Index`] for one name in a directory
+enum Tracked<'a> {
+  /// Every stage of a file, symbolic link, or submodule
+  File(&'a [IndexEntry]),
+  /// Everything in a subdirectory
+  Dir(&'a [IndexEntry]),
+}
+
+/// An item of a directory listing in the working tree
+struct Found {
+  name: Vec<u8>,
+  file_type: fs::FileType,
+  entry: fs::DirEntry,
+}
+
+impl<'a> Scan<'a> {
+  /// Read the directory and compare it with its entries, adding the
+  /// subdirectories that need to be read after it to `scans`
+  fn run(
+    self,
+    options: &StatusOptions,
+    changes: &mut Vec<WorktreeChange>,
+    scans: &mut Vec<Scan<'a>>,
+  ) -> Result<(), StatusError> {
+    if self.untracked_only {
+      if has_files(&self.path)? {
+        changes.push(change(WorktreeStatus::Untracked, &self.prefix, b""));
+      }
+      return Ok(());
+    }
+    let (mut found, mut tracked) = (
+      read_dir(&self.path)?.into_iter().peekable(),
+      self.group().into_iter().peekable(),
+    );
+    loop {
+      let order = match (found.peek(), tracked.peek()) {
+        (None, None) => return Ok(()),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(found), Some((name, _))) => found.name.as_slice().cmp(name),
+      };
+      match order {
+        Ordering::Less => self.untracked(found.next().unwrap(), options, changes, scans)?,
+        Ordering::Greater => deleted(tracked.next().unwrap().1, changes),
+        Ordering::Equal => {
+          let (found, (_, tracked)) = (found.next().unwrap(), tracked.next().unwrap());
+          self.tracked(found, tracked, options, changes, scans)?;
+        }
+      }
+    }
+  }
+
+  /// Compare something in the working tree with the entries of the
+  /// [`Index`] with the same name
+  fn tracked(
+    &self,
+    found: Found,
+    tracked: Tracked<'a>,
+    options: &StatusOptions,
+    changes: &mut Vec<WorktreeChange>,
+    scans: &mut Vec<Scan<'a>>,
+  ) -> Result<(), StatusError> {
+    match tracked {
+      Tracked::Dir(entries) if found.file_type.is_dir() => scans.push(Scan {
+        path: found.entry.path(),
+        prefix: [&self.prefix[..], &found.name, b"/"].concat(),
+        index: self.index,
+        entries,
+        untracked_only: false,
+      }),
+      Tracked::Dir(entries) => {
+        // A file where a directory was means everything in it is gone
+        deleted(Tracked::Dir(entries), changes);
+        self.untracked(found, options, changes, scans)?;
+      }
+      Tracked::File(entries) if entries.iter().any(|entry| entry.stage != 0) => {
+        changes.push(change(WorktreeStatus::Unmerged, &self.prefix, &found.name));
+      }
+      // Left out by a sparse checkout, so whatever is there isn't compared
+      Tracked::File(entries) if entries[0].skip_worktree => {}
+      Tracked::File(entries) => {
+        if let Some(status) = compare(self.index, &entries[0], &found, options)? {
+          changes.push(change(status, &self.prefix, &found.name));
+          if status == WorktreeStatus::Deleted {
+            // The file was replaced with a directory
+            self.untracked(found, options, changes, scans)?;
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Group the entries by the name of the file or subdirectory they are in,
+  /// sorted by that name. The entries under a subdirectory are already next
+  /// to each other since they share a prefix.
+  fn group(&self) -> Vec<(&'a [u8], Tracked<'a>)> {
+    let entries = self.entries;
+    let mut groups = Vec::new();
+    let mut start = 0;
+    while start < entries.len() {
+      let rest = &entries[start].path[self.prefix.len()..];
+      let (name, tracked) = match rest.find_byte(b'/') {
+        Some(slash) => {
+          let dir = &entries[start].path[..self.prefix.len() + slash + 1];
+          let len = entries[start..]
+            .iter()
+            .take_while(|entry| entry.path.starts_with(dir))
+            .count();
+          (&rest[..slash], Tracked::Dir(&entries[start..start + len]))
+        }
+        None => {
+          let path = &entries[start].path;
+          let len = entries[start..]
+            .iter()
+            .take_while(|entry| entry.path == *path)
+            .count();
+          (rest, Tracked::File(&entries[start..start + len]))
+        }
+      };
+      start += match tracked {
+        Tracked::File(entries) | Tracked::Dir(entries) => entries.len(),
+      };
+      groups.push((name, tracked));
+    }
+    // Paths only sort the same as names within a directory when there's no
+    // subdirectory, `a.txt` comes before `a/b` but after `a`
+    groups.sort_by(|a, b| a.0.cmp(b.0));
+    groups
+  }
+
+  /// Report something in the working tree that isn't in the [`Index`]
+  fn untracked(
+    &self,
+    found: Found,
+    options: &StatusOptions,
+    changes: &mut Vec<WorktreeChange>,
+    scans: &mut Vec<Scan<'a>>,
+  ) -> Result<(), StatusError> {
+    let file_type = found.file_type;
+    if options.untracked == UntrackedFiles::No {
+      return Ok(());
+    }
+    if file_type.is_file() || file_type.is_symlink() {
+      changes.push(change(WorktreeStatus::Untracked, &self.prefix, &found.name));
+    } else if file_type.is_dir() {
+      let path = found.entry.path();
+      let prefix = [&self.prefix[..], &found.name, b"/"].concat();
+      // Another repository inside of this one is always reported as a
+      // whole, like git does
+      if fs::symlink_metadata(path.join(".git")).is_ok() {
+        changes.push(change(WorktreeStatus::Untracked, &prefix, b""));
+        return Ok(());
+      }
+      scans.push(Scan {
+        path,
+        prefix,
+        index: self.index,
+        entries: &[],
+        untracked_only: options.untracked == UntrackedFiles::Normal,
+      });
+    }
+    Ok(())
+  }
+}
+
+/// Compare a tracked file with what's at its path in the working tree,
+/// returning `None` if it's unchanged
+fn compare(
+  index: &Index,
+  entry: &IndexEntry,
+  found: &Found,
+  options: &StatusOptions,
+) -> Result<Option<WorktreeStatus>, StatusError> {
+  let file_type = found.file_type;
+  if entry.mode == FileMode::GitLink {
+    return Ok((!file_type.is_dir()).then_some(WorktreeStatus::TypeChanged));
+  }
+  if file_type.is_dir() {
+    return Ok(Some(WorktreeStatus::Deleted));
+  }
+  let metadata = found.entry.metadata()?;
+  let mode = entry.worktree_mode(&metadata, &options.checkout);
+  if mode != entry.mode {
+    let executable = |mode| matches!(mode, FileMode::ExecutableFile | FileMode::NonExecutableFile);
+    return Ok(Some(if executable(mode) && executable(entry.mode) {
+      WorktreeStatus::Modified
+    } else {
+      WorktreeStatus::TypeChanged
+    }));
+  }
+  if entry.is_stat_clean(&metadata, &options.checkout) && !index.is_racy(entry) {
+    return Ok(None);
+  }
+  // The size is zero for entries with no stat data recorded, like ones made
+  // from a tree, so it only decides anything when it was recorded
+  if entry.stat.size != 0 && entry.stat.size != metadata.len() as u32 {
+    return Ok(Some(WorktreeStatus::Modified));
+  }
+  let oid = index::hash_file(
+    &found.entry.path(),
+    &metadata,
+    &entry.path,
+    &options.checkout,
+  )?;
+  Ok((oid != entry.oid).then_some(WorktreeStatus::Modified))
+}
+
+/// Report everything that was tracked under a name as deleted
+fn deleted(tracked: Tracked, changes: &mut Vec<WorktreeChange>) {
+  let entries = match tracked {
+    Tracked::File(entries) if entries[0].skip_worktree && entries.len() == 1 => return,
+    Tracked::File(entries) => {
+      let status = if entries.iter().any(|entry| entry.stage != 0) {
+        WorktreeStatus::Unmerged
+      } else {
+        WorktreeStatus::Deleted
+      };
+      changes.push(change(status, &entries[0].path, b""));
+      return;
+    }
+    Tracked::Dir(entries) => entries,
+  };
+  let mut start = 0;
+  while start < entries.len() {
+    let path = &entries[start].path;
+    let stages = &entries[start..]
+      .iter()
+      .take_while(|entry| entry.path == *path)
+      .count();
+    deleted(Tracked::File(&entries[start..start + stages]), changes);
+    start += stages;
+  }
+}
+
+fn change(status: WorktreeStatus, prefix: &[u8], name: &[u8]) -> WorktreeChange {
+  WorktreeChange {
+    status,
+    path: [prefix, name].concat().into(),
+  }
+}
+
+/// Every item of a directory other than `.git`, sorted by name. Only the
+/// directory itself is read, nothing is `stat`'d.
+fn read_dir(path: &Path) -> Result<Vec<Found>, StatusError> {
+  let mut listing = Vec::new();
+  for entry in fs::read_dir(path)? {
+    let entry = entry?;
+    let file_name = entry.file_name();
+    if file_name == ".git" {
+      continue;
+    }
+    let name = <[u8]>::from_os_str(&file_name)
+      .ok_or_else(|| StatusError::InvalidFileName(entry.path()))?
+      .to_vec();
+    listing.push(Found {
+      name,
+      file_type: entry.file_type()?,
+      entry,
+    });
+  }
+  listing.sort_by(|a, b| a.name.cmp(&b.name));
+  Ok(listing)
+}
+
+/// Whether there is a file anywhere under the directory, stopping at the
+/// first one
+fn has_files(path: &Path) -> Result<bool, StatusError> {
+  for entry in fs::read_dir(path)? {
+    let entry = entry?;
+    let file_type = entry.file_type()?;
+    if file_type.is_file() || file_type.is_symlink() {
+      return Ok(true);
+    }
+    if file_type.is_dir() && has_files(&entry.path())? {
+      return Ok(true);
+    }
+  }
+  Ok(false)
+}
+
+#[derive(Error, Debug)]
+/// Errors related to comparing the [`Index`] with the working tree
+pub enum StatusError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Index(#[from] IndexError),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Attributes(#[from] AttributesError),
+  #[error("{0}")]
+  Filter(#[from] FilterError),
+  #[error("the file name of {0:?} can't be stored in git")]
+  InvalidFileName(PathBuf),
+  #[error("a bare repository has no working tree to compare")]
+  BareRepository,
+}
+
+#[cfg(test)]
+fn summary(changes: &[WorktreeChange]) -> Vec<(WorktreeStatus, String)> {
+  changes
+    .iter()
+    .map(|change| (change.status, change.path.to_string()))
+    .collect()
+}
+
+#[test]
+fn worktree_changes() {
+  use crate::diff::write_tree;
+  use FileMode::*;
+  use WorktreeStatus::*;
+  let tmp_dir = tempdir::TempDir::new("status_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let tree = write_tree(
+    repo.odb(),
+    &[
+      ("README", NonExecutableFile, "readme"),
+      ("a.txt", NonExecutableFile, "a"),
+      ("a/b.txt", NonExecutableFile, "b"),
+      ("run.sh", NonExecutableFile, "make"),
+      ("same/file", NonExecutableFile, "same"),
+      ("gone/file", NonExecutableFile, "gone"),
+      ("touched", NonExecutableFile, "touched"),
+      ("replaced", NonExecutableFile, "replaced"),
+    ],
+  );
+  repo.checkout_tree(&tree).unwrap();
+  assert!(repo.worktree_status().unwrap().is_empty());
+
+  let work_dir = tmp_dir.path();
+  fs::write(work_dir.join("README"), "changed").unwrap();
+  fs::write(work_dir.join("a/b.txt"), "c").unwrap();
+  fs::remove_dir_all(work_dir.join("gone")).unwrap();
+  fs::remove_file(work_dir.join("replaced")).unwrap();
+  fs::create_dir(work_dir.join("replaced")).unwrap();
+  fs::write(work_dir.join("replaced/file"), "").unwrap();
+  fs::create_dir_all(work_dir.join("new/empty")).unwrap();
+  fs::write(work_dir.join("new/file"), "").unwrap();
+  fs::create_dir_all(work_dir.join("only_empty/dirs")).unwrap();
+  fs::write(work_dir.join("same/untracked"), "").unwrap();
+  // Same size and contents, only the times change
+  let touched = work_dir.join("touched");
+  fs::remove_file(&touched).unwrap();
+  fs::write(&touched, "touched").unwrap();
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(work_dir.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+  }
+
+  let mut expected = vec![
+    (Modified, "README".to_string()),
+    (Modified, "a/b.txt".into()),
+    (Deleted, "gone/file".into()),
+    (Untracked, "new/".into()),
+    (Deleted, "replaced".into()),
+    (Untracked, "replaced/".into()),
+    (Untracked, "same/untracked".into()),
+  ];
+  if cfg!(unix) {
+    expected.insert(6, (Modified, "run.sh".into()));
+  }
+  assert_eq!(expected, summary(&repo.worktree_status().unwrap()));
+
+  let index = repo.index().unwrap();
+  let options = StatusOptions {
+    untracked: UntrackedFiles::All,
+    checkout: CheckoutOptions {
+      file_mode: false,
+      ..CheckoutOptions::default()
+    },
+    ..StatusOptions::default()
+  };
+  let all = summary(&worktree_status(&index, work_dir, &options).unwrap());
+  assert_eq!(
+    vec![
+      (Modified, "README".to_string()),
+      (Modified, "a/b.txt".into()),
+      (Deleted, "gone/file".into()),
+      (Untracked, "new/file".into()),
+      (Deleted, "replaced".into()),
+      (Untracked, "replaced/file".into()),
+      (Untracked, "same/untracked".into()),
+    ],
+    all
+  );
+  let options = StatusOptions {
+    untracked: UntrackedFiles::No,
+    ..StatusOptions::default()
+  };
+  let tracked = summary(&worktree_status(&index, work_dir, &options).unwrap());
+  assert!(tracked.iter().all(|(status, _)| *status != Untracked));
+}
+
+#[test]
+fn worktree_without_stat_data() {
+  use WorktreeStatus::*;
+  let tmp_dir = tempdir::TempDir::new("status_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let tree = crate::diff::write_tree(
+    repo.odb(),
+    &[
+      ("same", FileMode::NonExecutableFile, "same"),
+      ("changed", FileMode::NonExecutableFile, "changed"),
+    ],
+  );
+  repo.checkout_tree(&tree).unwrap();
+  fs::write(tmp_dir.path().join("changed"), "CHANGED").unwrap();
+  // An index made from a tree has no stat data so every file is hashed
+  let index = Index::from_tree(repo.odb(), &tree).unwrap();
+  let changes = worktree_status(&index, tmp_dir.path(), &StatusOptions::default()).unwrap();
+  assert_eq!(vec![(Modified, "changed".to_string())], summary(&changes));
+
+  let mut conflicted = index.clone();
+  let mut theirs = index.get("same").unwrap().clone();
+  theirs.stage = 3;
+  conflicted.add(theirs);
+  let changes = worktree_status(&conflicted, tmp_dir.path(), &StatusOptions::default()).unwrap();
+  assert_eq!(
+    vec![(Modified, "changed".to_string()), (Unmerged, "same".into())],
+    summary(&changes)
+  );
+}
+
+#[test]
+fn status_options_from_config() {
+  let config = Config::from_bytes("[status]\n\tshowUntrackedFiles = all").unwrap();
+  let options = StatusOptions::from_config(&config).unwrap();
+  assert_eq!(UntrackedFiles::All, options.untracked);
+  let config = Config::from_bytes("[status]\n\tshowUntrackedFiles = false").unwrap();
+  let options = StatusOptions::from_config(&config).unwrap();
+  assert_eq!(UntrackedFiles::No, options.untracked);
+  let config = Config::from_bytes("[status]\n\tshowUntrackedFiles = some").unwrap();
+  assert!(StatusOptions::from_config(&config).is_err());
+}
+
+#[test]
+fn head_index_and_worktree() {
+  use crate::{Blob, Commit, IndexEntry, Signature, StatData, Time};
+  use FileMode::*;
+  let tmp_dir = tempdir::TempDir::new("status_test").unwrap();
+  let work_dir = tmp_dir.path();
+  let repo = Repository::init(work_dir).unwrap();
+  let tree = crate::diff::write_tree(
+    repo.odb(),
+    &[
+      ("both", NonExecutableFile, "both"),
+      ("conflict", NonExecutableFile, "conflict"),
+      ("deleted", NonExecutableFile, "deleted"),
+      ("dir/kept", NonExecutableFile, "kept"),
+      ("link", NonExecutableFile, "link"),
+      ("staged", NonExecutableFile, "staged"),
+      ("unchanged", NonExecutableFile, "unchanged"),
+      ("unstaged", NonExecutableFile, "unstaged"),
+    ],
+  );
+  repo.checkout_tree(&tree).unwrap();
+  let codes = |repo: &Repository| -> Vec<String> {
+    repo
+      .status()
+      .unwrap()
+      .iter()
+      .map(ToString::to_string)
+      .collect()
+  };
+  // Before the first commit everything is added
+  let unborn = codes(&repo);
+  assert_eq!(8, unborn.len());
+  assert_eq!("A  both", unborn[0]);
+
+  let signature = Signature::new("Jane Doe", "jane@example.com", Time::new(0, 0));
+  let commit = Commit::new(tree, vec![], signature.clone(), signature, "A\n");
+  let commit = repo.odb().write_commit(&commit).unwrap();
+  repo.refs().write("refs/heads/master", &commit).unwrap();
+  assert!(repo.status().unwrap().is_empty());
+
+  let mut index = repo.index().unwrap();
+  let mut stage = |path: &str, contents: &str| {
+    let file = work_dir.join(path);
+    fs::write(&file, contents).unwrap();
+    let oid = repo.odb().write_blob(&Blob::new(contents)).unwrap();
+    let stat = StatData::from_metadata(&fs::metadata(&file).unwrap());
+    index.add(IndexEntry::new(path, NonExecutableFile, oid, stat));
+  };
+  stage("staged", "STAGED");
+  stage("both", "BOTH");
+  stage("new", "new");
+  fs::write(work_dir.join("both"), "B0TH").unwrap();
+  fs::write(work_dir.join("unstaged"), "UNSTAGED").unwrap();
+  fs::write(work_dir.join("untracked"), "").unwrap();
+  fs::remove_file(work_dir.join("deleted")).unwrap();
+  index.remove("deleted");
+  // Only removed from the index, the file is still there and untracked
+  index.remove("dir/kept");
+  let link = index.get("link").unwrap().clone();
+  index.add(IndexEntry::new("link", SymbolicLink, link.oid, link.stat));
+  let conflict = index.get("conflict").unwrap().clone();
+  index.remove("conflict");
+  for stage in 1..=3 {
+    index.add(IndexEntry {
+      stage,
+      ..conflict.clone()
+    });
+  }
+  index.add(IndexEntry {
+    stage: 2,
+    ..IndexEntry::new("ours", NonExecutableFile, conflict.oid, conflict.stat)
+  });
+  index.write(repo.index_path()).unwrap();
+  fs::write(work_dir.join("ours"), "conflict").unwrap();
+
+  assert_eq!(
+    vec![
+      "MM both",
+      "UU conflict",
+      "D  deleted",
+      "D  dir/kept",
+      "TT link",
+      "A  new",
+      "AU ours",
+      "M  staged",
+      " M unstaged",
+      "?? dir/",
+      "?? untracked",
+    ],
+    codes(&repo)
+  );
+}
@@ -0,0 +1,652 @@
+//! Submodules, repositories checked out inside of the working tree of
+//! another one. The tree of the outer repository only records the commit
+//! a submodule is at as a [`FileMode::GitLink`], while `.gitmodules` says
+//! where to get it from:
+//!
+//! ```text
+//! [submodule "lib"]
+//!     path = vendor/lib
+//!     url = ../lib.git
+//! ```
+//!
+//! A submodule is initialized once its url is copied into the config of the
+//! outer repository, and its own git directory is kept in `.git/modules`
+//! with a `.git` file in its working tree pointing there.
+
+use crate::{
+  transport::{self, TransportError},
+  CheckoutError, CloneError, CloneOptions, Config, ConfigError, ConfigFile, ConfigLevel, FileMode,
+  Index, IndexEntry, IndexError, Odb, OdbError, RefError, Repository, RepositoryError, StatData,
+  OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::{fs, io, path::Path};
+use thiserror::Error;
+
+/// A submodule as described by `.gitmodules`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submodule {
+  name: BString,
+  path: BString,
+  url: Option<BString>,
+  branch: Option<BString>,
+}
+
+impl Submodule {
+  /// Parse the submodules in a `.gitmodules` file, in the order their
+  /// sections first show up. Like git, ones without a path are skipped, as
+  /// are ones with a name or path that could point outside of where they
+  /// belong.
+  pub fn parse_all(bytes: impl AsRef<[u8]>) -> Result<Vec<Self>, SubmoduleError> {
+    let config = Config::from_bytes(bytes)?;
+    let mut names: Vec<&BStr> = Vec::new();
+    for entry in config.entries() {
+      if let (true, Some(name)) = (entry.section() == "submodule", entry.subsection()) {
+        if !names.contains(&name) {
+          names.push(name);
+        }
+      }
+    }
+    let submodules = names
+      .into_iter()
+      .filter(|name| is_safe_path(name))
+      .filter_map(|name| {
+        let get = |setting: &str| {
+          let key = format!("submodule.{}.{}", name, setting);
+          config.get(&key).map(BStr::to_owned)
+        };
+        let path = get("path").filter(|path| is_safe_path(path.as_bstr()))?;
+        Some(Self {
+          name: name.to_owned(),
+          path: path.trim_end_with(|c| c == '/').into(),
+          url: get("url"),
+          branch: get("branch"),
+        })
+      })
+      .collect();
+    Ok(submodules)
+  }
+
+  /// The name of the submodule, which is what its settings in the config
+  /// and its git directory in `.git/modules` are named after. It's usually
+  /// the same as the path it was first added at.
+  pub fn name(&self) -> &BStr {
+    self.name.as_bstr()
+  }
+
+  /// Where the submodule is in the working tree
+  pub fn path(&self) -> &BStr {
+    self.path.as_bstr()
+  }
+
+  /// Where the submodule is cloned from. Urls starting with `./` or `../`
+  /// are relative to the url of the outer repository.
+  pub fn url(&self) -> Option<&BStr> {
+    self.url.as_ref().map(|url| url.as_bstr())
+  }
+
+  /// The branch of the remote the submodule follows, if it's set
+  pub fn branch(&self) -> Option<&BStr> {
+    self.branch.as_ref().map(|branch| branch.as_bstr())
+  }
+}
+
+/// How a submodule in the index compares with its checkout, see
+/// [`Repository::submodule_status`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubmoduleStatus {
+  /// The path of the submodule
+  pub path: BString,
+  /// The name of the submodule, which is its path if it isn't in
+  /// `.gitmodules`
+  pub name: BString,
+  /// The commit the index records for the submodule
+  pub recorded: OID,
+  /// The commit the submodule has checked out, which is `None` if it isn't
+  /// cloned or hasn't got a commit yet
+  pub head: Option<OID>,
+  /// Whether the url of the submodule is in the config
+  pub initialized: bool,
+}
+
+impl SubmoduleStatus {
+  /// Whether the submodule is checked out at a different commit than the
+  /// one recorded
+  pub fn is_modified(&self) -> bool {
+    self.head.is_some_and(|head| head != self.recorded)
+  }
+
+  /// The character `git submodule status` prints in front of the
+  /// submodule: `-` if it isn't checked out, `+` if it's at a different
+  /// commit than the one recorded, and a space otherwise
+  pub fn code(&self) -> char {
+    match self.head {
+      None => '-',
+      Some(_) if self.is_modified() => '+',
+      Some(_) => ' ',
+    }
+  }
+}
+
+impl Repository {
+  /// The submodules in `.gitmodules`, read from the working tree or, if it
+  /// isn't there, from the index
+  pub fn submodules(&self) -> Result<Vec<Submodule>, SubmoduleError> {
+    let on_disk = self.work_dir().map(|dir| dir.join(".gitmodules"));
+    let contents = match on_disk.filter(|path| path.is_file()) {
+      Some(path) => fs::read(path)?,
+      None => match self.index()?.get(".gitmodules") {
+        Some(entry) => self.odb().read_blob(&entry.oid)?.contents().to_vec(),
+        None => return Ok(Vec::new()),
+      },
+    };
+    Submodule::parse_all(contents)
+  }
+
+  /// The [`SubmoduleStatus`] of every submodule in the index, like
+  /// `git submodule status`
+  pub fn submodule_status(&self) -> Result<Vec<SubmoduleStatus>, SubmoduleError> {
+    let submodules = self.submodules()?;
+    let mut statuses = Vec::new();
+    for entry in self.index()?.entries() {
+      if entry.mode != FileMode::GitLink || entry.stage != 0 {
+        continue;
+      }
+      let name = submodules
+        .iter()
+        .find(|submodule| submodule.path == entry.path)
+        .map_or(&entry.path, |submodule| &submodule.name);
+      let head = match self.work_dir() {
+        Some(dir) => checked_out_commit(&dir.join(entry.path.to_path_lossy())),
+        None => None,
+      };
+      statuses.push(SubmoduleStatus {
+        path: entry.path.clone(),
+        name: name.clone(),
+        recorded: entry.oid,
+        head,
+        initialized: self.config().get(&url_key(name)).is_some(),
+      });
+    }
+    Ok(statuses)
+  }
+
+  /// Open the submodule at `path` in the working tree
+  pub fn open_submodule(&self, path: impl AsRef<[u8]>) -> Result<Repository, SubmoduleError> {
+    let work_dir = self.work_dir().ok_or(SubmoduleError::BareRepository)?;
+    let path = path.as_ref();
+    Ok(Repository::open(work_dir.join(path.to_path_lossy()))?)
+  }
+
+  /// Copy the urls of the submodules in the index from `.gitmodules` into
+  /// the config, like `git submodule init`. Relative urls are resolved
+  /// against the url of `origin`, or the working tree if there's no such
+  /// remote. Submodules that are already initialized are left alone.
+  /// Returns the submodules that were initialized.
+  pub fn init_submodules(&mut self) -> Result<Vec<Submodule>, SubmoduleError> {
+    let index = self.index()?;
+    let mut file = ConfigFile::from_file(self.common_dir().join("config"), ConfigLevel::Local)?;
+    let mut initialized = Vec::new();
+    for submodule in self.submodules()? {
+      let in_index = index
+        .get(&submodule.path)
+        .is_some_and(|entry| entry.mode == FileMode::GitLink);
+      let key = url_key(&submodule.name);
+      if !in_index || self.config().get(&key).is_some() {
+        continue;
+      }
+      if let Some(url) = &submodule.url {
+        file.set(&key, self.resolve_submodule_url(&url.to_str_lossy())?)?;
+        initialized.push(submodule);
+      }
+    }
+    if !initialized.is_empty() {
+      file.save()?;
+      self.reload_config()?;
+    }
+    Ok(initialized)
+  }
+
+  /// Clone the initialized submodules that aren't there yet and check out
+  /// the commit the index records for each of them, fetching it if the
+  /// submodule doesn't have it, like `git submodule update`. The commit is
+  /// checked out with a detached `HEAD` using
+  /// [`Repository::checkout_tree`]. Returns the paths of the submodules that
+  /// were updated.
+  pub fn update_submodules(&self) -> Result<Vec<BString>, SubmoduleError> {
+    let mut updated = Vec::new();
+    for status in self.submodule_status()? {
+      let url = match self.config().get_str(&url_key(&status.name))? {
+        Some(url) => url.to_owned(),
+        None => continue,
+      };
+      if status.head == Some(status.recorded) {
+        continue;
+      }
+      let submodule = match self.open_submodule(&status.path) {
+        Ok(submodule) => submodule,
+        Err(SubmoduleError::Repository(RepositoryError::NotFound(_))) => self.clone_submodule(
+          status.name.as_bstr(),
+          status.path.as_bstr(),
+          &url,
+          &CloneOptions::default(),
+        )?,
+        Err(e) => return Err(e),
+      };
+      match submodule.odb().read_commit(&status.recorded) {
+        Ok(_) => {}
+        Err(OdbError::NotFound(_)) => {
+          transport::connect(&url)?.fetch(&submodule, &[status.recorded])?;
+        }
+        Err(e) => return Err(e.into()),
+      }
+      let commit = submodule.odb().read_commit(&status.recorded)?;
+      submodule.checkout_tree(commit.tree())?;
+      submodule.refs().write("HEAD", &status.recorded)?;
+      updated.push(status.path);
+    }
+    Ok(updated)
+  }
+
+  /// Add the repository at `url` as a submodule at `path`, like
+  /// `git submodule add {url} {path}`. It's cloned with `options`, added to
+  /// `.gitmodules` and initialized, and both the submodule and
+  /// `.gitmodules` are staged in the index.
+  pub fn add_submodule(
+    &mut self,
+    url: &str,
+    path: impl AsRef<[u8]>,
+    options: &CloneOptions,
+  ) -> Result<Repository, SubmoduleError> {
+    let work_dir = self
+      .work_dir()
+      .ok_or(SubmoduleError::BareRepository)?
+      .to_path_buf();
+    let path = path.as_ref().trim_end_with(|c| c == '/');
+    if !is_safe_path(path.as_bstr()) {
+      return Err(SubmoduleError::InvalidPath(path.into()));
+    }
+    let mut index = self.index()?;
+    if index.get(path).is_some() {
+      return Err(SubmoduleError::Exists(path.into()));
+    }
+    let name = path.as_bstr();
+    let resolved = self.resolve_submodule_url(url)?;
+    let submodule = self.clone_submodule(name, name, &resolved, options)?;
+
+    let gitmodules = work_dir.join(".gitmodules");
+    if !gitmodules.exists() {
+      fs::write(&gitmodules, "")?;
+    }
+    let mut file = ConfigFile::from_file(&gitmodules, ConfigLevel::Local)?;
+    file.set(&format!("submodule.{}.path", name), path)?;
+    file.set(&url_key(name), url)?;
+    file.save()?;
+    let mut config = ConfigFile::from_file(self.common_dir().join("config"), ConfigLevel::Local)?;
+    config.set(&url_key(name), &resolved)?;
+    config.save()?;
+    self.reload_config()?;
+
+    let contents = fs::read(&gitmodules)?;
+    let oid = self.odb().write_blob(&crate::Blob::new(contents))?;
+    let stat = StatData::from_metadata(&fs::metadata(&gitmodules)?);
+    index.add(IndexEntry::new(
+      ".gitmodules",
+      FileMode::NonExecutableFile,
+      oid,
+      stat,
+    ));
+    index.write(self.index_path())?;
+    self.stage_submodule(path)?;
+    Ok(submodule)
+  }
+
+  /// Record the commit the submodule at `path` has checked out in the
+  /// index, like `git add {path}` does for a submodule, and return it
+  pub fn stage_submodule(&self, path: impl AsRef<[u8]>) -> Result<OID, SubmoduleError> {
+    let path = path.as_ref();
+    let head = self
+      .open_submodule(path)?
+      .refs()
+      .resolve("HEAD")?
+      .ok_or_else(|| SubmoduleError::NoCommit(path.into()))?;
+    let mut index = self.index()?;
+    remove_under(&mut index, path);
+    index.add(IndexEntry::new(
+      path,
+      FileMode::GitLink,
+      head,
+      StatData::default(),
+    ));
+    index.write(self.index_path())?;
+    Ok(head)
+  }
+
+  /// Clone `url` into `path` keeping its git directory in
+  /// `.git/modules/{name}`
+  fn clone_submodule(
+    &self,
+    name: &BStr,
+    path: &BStr,
+    url: &str,
+    options: &CloneOptions,
+  ) -> Result<Repository, SubmoduleError> {
+    let work_dir = self.work_dir().ok_or(SubmoduleError::BareRepository)?;
+    if !is_safe_path(name) {
+      return Err(SubmoduleError::InvalidName(name.into()));
+    }
+    let sub_dir = work_dir.join(path.to_path_lossy());
+    let module_dir = self.common_dir().join("modules").join(name.to_path_lossy());
+    if module_dir.exists() {
+      return Err(SubmoduleError::Exists(name.into()));
+    }
+    Repository::clone(url, &sub_dir, options)?;
+    // The module directory always has a parent, `.git/modules` at least
+    fs::create_dir_all(module_dir.parent().unwrap())?;
+    fs::rename(sub_dir.join(".git"), &module_dir)?;
+
+    // Like git the two point at each other with relative paths, so the
+    // whole working tree can be moved, unless the git directory of the
+    // outer repository is somewhere else entirely
+    let depth = |path: &BStr| path.split_str("/").count();
+    let (git_dir, work_tree) = match self.git_dir() == work_dir.join(".git") {
+      true => (
+        format!("{}.git/modules/{}", "../".repeat(depth(path)), name),
+        format!("{}{}", "../".repeat(depth(name) + 2), path),
+      ),
+      false => (
+        module_dir.display().to_string(),
+        sub_dir.display().to_string(),
+      ),
+    };
+    fs::write(sub_dir.join(".git"), format!("gitdir: {}\n", git_dir))?;
+    let mut config = ConfigFile::from_file(module_dir.join("config"), ConfigLevel::Local)?;
+    config.set("core.worktree", work_tree)?;
+    config.save()?;
+    Ok(Repository::open(sub_dir)?)
+  }
+
+  /// Resolve a submodule url relative to the url of `origin`, or the
+  /// working tree if there's no such remote
+  fn resolve_submodule_url(&self, url: &str) -> Result<String, SubmoduleError> {
+    let base = match self.config().get_str("remote.origin.url")? {
+      Some(base) => base.to_owned(),
+      None => self
+        .work_dir()
+        .unwrap_or_else(|| self.git_dir())
+        .display()
+        .to_string(),
+    };
+    Ok(resolve_url(&base, url))
+  }
+}
+
+/// Set the submodule at `path` in the [`Tree`][crate::Tree] with the given
+/// [`OID`] to `commit`, or remove it if `commit` is `None`, and return the
+/// new tree. Anything else at `path` is replaced.
+pub fn set_tree_submodule(
+  odb: &Odb,
+  tree: &OID,
+  path: impl AsRef<[u8]>,
+  commit: Option<&OID>,
+) -> Result<OID, SubmoduleError> {
+  let path = path.as_ref();
+  let mut index = Index::from_tree(odb, tree)?;
+  remove_under(&mut index, path);
+  match commit {
+    Some(commit) => index.add(IndexEntry::new(
+      path,
+      FileMode::GitLink,
+      *commit,
+      StatData::default(),
+    )),
+    None => {
+      index.remove(path);
+    }
+  }
+  Ok(index.write_tree(odb)?)
+}
+
+/// The commit the repository at `path` has checked out, if it's a
+/// repository and has one
+pub(crate) fn checked_out_commit(path: &Path) -> Option<OID> {
+  Repository::open(path).ok()?.refs().resolve("HEAD").ok()?
+}
+
+/// Remove whatever is at `path` in the index, be it an entry or a directory
+/// of them
+fn remove_under(index: &mut Index, path: &[u8]) {
+  let under: Vec<BString> = index
+    .entries()
+    .iter()
+    .filter(|entry| {
+      entry.path == path
+        || entry
+          .path
+          .strip_prefix(path)
+          .is_some_and(|rest| rest.starts_with(b"/"))
+    })
+    .map(|entry| entry.path.clone())
+    .collect();
+  for path in under {
+    while index.remove(&path) {}
+  }
+}
+
+fn url_key(name: &[u8]) -> String {
+  format!("submodule.{}.url", name.as_bstr())
+}
+
+/// Whether a submodule name or path stays where it belongs, being relative
+/// and without any `..` components
+fn is_safe_path(path: &BStr) -> bool {
+  !path.is_empty()
+    && !path.starts_with(b"/")
+    && path
+      .split(|&b| b == b'/' || b == b'\\')
+      .all(|component| component != b"..")
+}
+
+/// Resolve a url starting with `./` or `../` against `base` like git does,
+/// with every `../` dropping the last component of `base`. Urls that aren't
+/// relative are returned as they are.
+fn resolve_url(base: &str, url: &str) -> String {
+  if !url.starts_with("./") && !url.starts_with("../") {
+    return url.into();
+  }
+  let mut base = base.to_owned();
+  let mut url = url;
+  loop {
+    if let Some(rest) = url.strip_prefix("./") {
+      url = rest;
+    } else if let Some(rest) = url.strip_prefix("../") {
+      let trimmed = base.trim_end_matches('/').len();
+      base.truncate(trimmed);
+      // scp-like urls such as `host:repo` have their path after the colon
+      match base.rfind(['/', ':']) {
+        Some(idx) => base.truncate(idx + 1),
+        None => base.clear(),
+      }
+      url = rest;
+    } else {
+      break;
+    }
+  }
+  if base.is_empty() || base.ends_with('/') || base.ends_with(':') {
+    base + url
+  } else {
+    format!("{}/{}", base, url)
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to submodules
+pub enum SubmoduleError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Index(#[from] IndexError),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Repository(#[from] RepositoryError),
+  #[error("{0}")]
+  Clone(#[from] CloneError),
+  #[error("{0}")]
+  Transport(#[from] TransportError),
+  #[error("{0}")]
+  Checkout(#[from] CheckoutError),
+  #[error("the repository has no working tree for submodules")]
+  BareRepository,
+  #[error("submodule path {0:?} is outside of the working tree")]
+  InvalidPath(BString),
+  #[error("submodule name {0:?} is outside of .git/modules")]
+  InvalidName(BString),
+  #[error("{0:?} already exists")]
+  Exists(BString),
+  #[error("submodule {0:?} has no commit checked out")]
+  NoCommit(BString),
+}
+
+#[test]
+fn submodules() {
+  use crate::{Blob, Commit, Signature, Time, Tree, TreeEntry};
+  let tmp_dir = tempdir::TempDir::new("submodule_test").unwrap();
+  let source = Repository::init_bare(tmp_dir.path().join("lib.git")).unwrap();
+  let url = tmp_dir.path().join("lib.git").display().to_string();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |contents: &str| {
+    let odb = source.odb();
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "lib.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(tree, vec![], signature.clone(), signature.clone(), "lib\n");
+    let oid = odb.write_commit(&commit).unwrap();
+    source.refs().write("refs/heads/master", &oid).unwrap();
+    oid
+  };
+  let first = commit("one\n");
+
+  // Adding clones the submodule with its git directory in `.git/modules`
+  let super_dir = tmp_dir.path().join("super");
+  let mut repo = Repository::init(&super_dir).unwrap();
+  let lib = repo
+    .add_submodule(&url, "vendor/lib", &CloneOptions::default())
+    .unwrap();
+  assert_eq!(super_dir.join(".git/modules/vendor/lib"), lib.git_dir());
+  assert_eq!(
+    "gitdir: ../../.git/modules/vendor/lib\n",
+    fs::read_to_string(super_dir.join("vendor/lib/.git")).unwrap()
+  );
+  let lib = Repository::open(super_dir.join(".git/modules/vendor/lib")).unwrap();
+  assert_eq!(Some(&*super_dir.join("vendor/lib")), lib.work_dir());
+  let submodules = repo.submodules().unwrap();
+  assert_eq!(1, submodules.len());
+  assert_eq!("vendor/lib", submodules[0].name());
+  assert_eq!("vendor/lib", submodules[0].path());
+  assert_eq!(Some(url.as_bytes().as_bstr()), submodules[0].url());
+  let status = repo.submodule_status().unwrap();
+  assert_eq!(1, status.len());
+  assert_eq!(
+    (first, Some(first), ' '),
+    (status[0].recorded, status[0].head, status[0].code())
+  );
+  assert!(status[0].initialized);
+  let index = repo.index().unwrap();
+  assert!(index.get(".gitmodules").is_some());
+  let tree = index.write_tree(repo.odb()).unwrap();
+  assert_eq!(tree, Tree::from_dir(&super_dir).unwrap().id());
+
+  // Recording a newer commit leaves the checkout behind until it's updated,
+  // which has to fetch it
+  let second = commit("two\n");
+  let tree = set_tree_submodule(repo.odb(), &tree, "vendor/lib", Some(&second)).unwrap();
+  Index::from_tree(repo.odb(), &tree)
+    .unwrap()
+    .write(repo.index_path())
+    .unwrap();
+  assert_eq!('+', repo.submodule_status().unwrap()[0].code());
+  assert_eq!(
+    vec![BString::from("vendor/lib")],
+    repo.update_submodules().unwrap()
+  );
+  assert_eq!(' ', repo.submodule_status().unwrap()[0].code());
+  assert_eq!(
+    "two\n",
+    fs::read_to_string(super_dir.join("vendor/lib/lib.txt")).unwrap()
+  );
+  if crate::transport::http::have_git() {
+    let output = std::process::Command::new("git")
+      .args(["submodule", "status"])
+      .current_dir(&super_dir)
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .output()
+      .unwrap();
+    // What git describes the commit as after the path depends on its refs
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with(&format!(" {} vendor/lib ", second)));
+  }
+
+  // A fresh checkout of the outer repository knows nothing of the
+  // submodule until it's initialized
+  let other_dir = tmp_dir.path().join("other");
+  let mut other = Repository::init(&other_dir).unwrap();
+  fs::copy(super_dir.join(".gitmodules"), other_dir.join(".gitmodules")).unwrap();
+  fs::create_dir_all(other_dir.join("vendor/lib")).unwrap();
+  Index::from_tree(repo.odb(), &tree)
+    .unwrap()
+    .write(other.index_path())
+    .unwrap();
+  let status = &other.submodule_status().unwrap()[0];
+  assert_eq!(
+    (None, '-', false),
+    (status.head, status.code(), status.initialized)
+  );
+  assert!(other.update_submodules().unwrap().is_empty());
+  assert_eq!(1, other.init_submodules().unwrap().len());
+  assert!(other.init_submodules().unwrap().is_empty());
+  assert_eq!(
+    vec![BString::from("vendor/lib")],
+    other.update_submodules().unwrap()
+  );
+  assert_eq!(Some(second), other.submodule_status().unwrap()[0].head);
+
+  let tree = set_tree_submodule(repo.odb(), &tree, "vendor/lib", None).unwrap();
+  let tree = repo.odb().read_tree(&tree).unwrap();
+  assert!(tree.get("vendor").is_none());
+
+  let parsed = Submodule::parse_all(
+    "[submodule \"../evil\"]\n\tpath = evil\n[submodule \"nopath\"]\n\turl = x\n\
+     [submodule \"a\"]\n\tpath = a/\n\tbranch = main\n",
+  )
+  .unwrap();
+  assert_eq!(1, parsed.len());
+  assert_eq!(
+    ("a", Some("main".into())),
+    (parsed[0].path().to_str().unwrap(), parsed[0].branch())
+  );
+
+  assert_eq!(
+    "https://example.com/org/lib.git",
+    resolve_url("https://example.com/org/super.git", "../lib.git")
+  );
+  assert_eq!(
+    "https://example.com/org/super.git/lib",
+    resolve_url("https://example.com/org/super.git/", "./lib")
+  );
+  assert_eq!(
+    "git@host:org/lib",
+    resolve_url("git@host:org/super", "../lib")
+  );
+  assert_eq!("host:lib", resolve_url("host:super", "../lib"));
+  assert_eq!("/abs/lib", resolve_url("/tmp/x", "/abs/lib"));
+}
@@ -0,0 +1,219 @@
+use crate::{
+  object, small::SmallBytes, OIDError, Object, ObjectKind, Signature, SignatureError, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::io::{self, Write};
+use thiserror::Error;
+
+/// A [`Tag`] is an annotated tag, a git object that gives another object a
+/// name along with who made the tag, when, and a message. Lightweight tags
+/// are only a ref and have no object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+  object: OID,
+  kind: ObjectKind,
+  name: BString,
+  tagger: Option<Signature>,
+  extra_headers: Vec<(BString, BString)>,
+  message: SmallBytes,
+}
+
+impl Tag {
+  /// Create a new [`Tag`] named `name` pointing at `object`
+  pub fn new(
+    object: OID,
+    kind: ObjectKind,
+    name: impl Into<BString>,
+    tagger: Signature,
+    message: impl Into<BString>,
+  ) -> Self {
+    Self {
+      object,
+      kind,
+      name: name.into(),
+      tagger: Some(tagger),
+      extra_headers: Vec::new(),
+      message: Vec::from(message.into()).into(),
+    }
+  }
+
+  /// Parse the contents of a [`Tag`] as stored in the Object Database,
+  /// without the `tag {content_len}\0` prefix. Old tags without a `tagger`
+  /// header are accepted.
+  pub fn parse(bytes: impl AsRef<[u8]>) -> Result<Self, TagError> {
+    let bytes = bytes.as_ref();
+    let (headers, message) = match bytes.find(b"\n\n") {
+      Some(idx) => (&bytes[..idx], &bytes[idx + 2..]),
+      None => (bytes, &b""[..]),
+    };
+
+    let mut object = None;
+    let mut kind = None;
+    let mut name = None;
+    let mut tagger = None;
+    let mut extra_headers: Vec<(BString, BString)> = Vec::new();
+    for line in headers.lines() {
+      if let Some(continuation) = line.strip_prefix(b" ") {
+        let (_, value) = extra_headers
+          .last_mut()
+          .ok_or_else(|| TagError::InvalidHeader(line.into()))?;
+        value.push(b'\n');
+        value.extend_from_slice(continuation);
+        continue;
+      }
+      let (key, value) = match line.find_byte(b' ') {
+        Some(idx) => (&line[..idx], &line[idx + 1..]),
+        None => return Err(TagError::InvalidHeader(line.into())),
+      };
+      match key {
+        b"object" if object.is_none() => {
+          let hex = value
+            .to_str()
+            .map_err(|_| TagError::InvalidHeader(line.into()))?;
+          object = Some(OID::from_hex(hex)?);
+        }
+        b"type" if kind.is_none() => {
+          kind =
+            Some(ObjectKind::from_bytes(value).ok_or_else(|| TagError::InvalidHeader(line.into()))?)
+        }
+        b"tag" if name.is_none() => name = Some(value.into()),
+        b"tagger" if tagger.is_none() => tagger = Some(Signature::parse(value)?),
+        _ => extra_headers.push((key.into(), value.into())),
+      }
+    }
+
+    Ok(Self {
+      object: object.ok_or(TagError::MissingHeader("object"))?,
+      kind: kind.ok_or(TagError::MissingHeader("type"))?,
+      name: name.ok_or(TagError::MissingHeader("tag"))?,
+      tagger,
+      extra_headers,
+      message: message.into(),
+    })
+  }
+
+  /// Turn the [`Tag`] into the on disk representation stored in Object
+  /// Database, which is in the form below:
+  ///
+  /// ```text
+  /// tag {content_len}\0object {object}
+  /// type {kind}
+  /// tag {name}
+  /// tagger {tagger}
+  ///
+  /// {message}
+  /// ```
+  pub fn as_bytes(&self) -> Vec<u8> {
+    object::encode(self)
+  }
+
+  pub(crate) fn content(&self) -> Vec<u8> {
+    let mut content = Vec::new();
+    content.extend_from_slice(b"object ");
+    content.extend_from_slice(self.object.as_hex().as_bytes());
+    content.extend_from_slice(b"\ntype ");
+    content.extend_from_slice(self.kind.as_str().as_bytes());
+    content.extend_from_slice(b"\ntag ");
+    content.extend_from_slice(&self.name);
+    content.push(b'\n');
+    if let Some(tagger) = &self.tagger {
+      content.extend_from_slice(b"tagger ");
+      content.extend_from_slice(&tagger.as_bytes());
+      content.push(b'\n');
+    }
+    for (key, value) in &self.extra_headers {
+      content.extend_from_slice(key);
+      content.push(b' ');
+      content.extend_from_slice(&value.replace("\n", "\n "));
+      content.push(b'\n');
+    }
+    content.push(b'\n');
+    content.extend_from_slice(&self.message);
+    content
+  }
+
+  /// Get the [`OID`] for the [`Tag`]
+  pub fn id(&self) -> OID {
+    OID::for_object(self)
+  }
+
+  /// The [`OID`] of the object that is tagged
+  pub fn object(&self) -> &OID {
+    &self.object
+  }
+
+  /// What kind of object is tagged
+  pub fn kind(&self) -> ObjectKind {
+    self.kind
+  }
+
+  /// The name of the tag, without `refs/tags/`
+  pub fn name(&self) -> &BStr {
+    self.name.as_bstr()
+  }
+
+  /// Who made the tag. Some very old tags don't record this.
+  pub fn tagger(&self) -> Option<&Signature> {
+    self.tagger.as_ref()
+  }
+
+  /// The message of the tag, including a signature if it was signed
+  pub fn message(&self) -> &BStr {
+    self.message.as_bstr()
+  }
+}
+
+impl Object for Tag {
+  fn kind(&self) -> ObjectKind {
+    ObjectKind::Tag
+  }
+
+  fn serialize_into(&self, writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(&self.content())
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`Tag`] type
+pub enum TagError {
+  #[error("tag is missing the {0} header")]
+  MissingHeader(&'static str),
+  #[error("invalid tag header {0:?}")]
+  InvalidHeader(BString),
+  #[error("invalid OID in tag header: {0}")]
+  InvalidOID(#[from] OIDError),
+  #[error("{0}")]
+  InvalidSignature(#[from] SignatureError),
+}
+
+#[cfg(test)]
+const TEST_TAG: &[u8] = b"object 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+type tree
+tag v1.0
+tagger Jane Doe <jane@example.com> 1600000000 +0000
+
+Release 1.0
+";
+
+#[test]
+fn parse() {
+  let tag = Tag::parse(TEST_TAG).unwrap();
+  assert_eq!(
+    "4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+    tag.object().as_hex()
+  );
+  assert_eq!(ObjectKind::Tree, tag.kind());
+  assert_eq!("v1.0", tag.name());
+  assert_eq!("Jane Doe", tag.tagger().unwrap().name());
+  assert_eq!("Release 1.0\n", tag.message());
+  assert!(matches!(
+    Tag::parse(b"type tree\ntag v1.0\n"),
+    Err(TagError::MissingHeader("object"))
+  ));
+}
+
+#[test]
+fn round_trip() {
+  let tag = Tag::parse(TEST_TAG).unwrap();
+  assert_eq!([b"tag 132\0", TEST_TAG].concat(), tag.as_bytes());
+}
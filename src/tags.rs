@@ -0,0 +1,210 @@
+use crate::{
+  refs::check_ref_name, wildmatch::wildmatch, ObjectKind, Odb, OdbError, RefError, Repository,
+  Signature, Tag, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use thiserror::Error;
+
+/// The tags of a [`Repository`], kept as refs under `refs/tags/`. Names are
+/// given without that prefix, like `v1.0`.
+#[derive(Debug, Clone, Copy)]
+pub struct Tags<'a> {
+  repo: &'a Repository,
+}
+
+impl Repository {
+  /// The tags of the repository
+  pub fn tags(&self) -> Tags<'_> {
+    Tags { repo: self }
+  }
+}
+
+impl<'a> Tags<'a> {
+  fn ref_name(name: &str) -> Result<String, TagsError> {
+    let full = format!("refs/tags/{}", name);
+    check_ref_name(full.as_bytes()).map_err(|_| TagsError::InvalidName(name.into()))?;
+    Ok(full)
+  }
+
+  fn check_free(&self, full: &str, name: &str, force: bool) -> Result<(), TagsError> {
+    if !force && self.repo.refs().read(full)?.is_some() {
+      return Err(TagsError::Exists(name.into()));
+    }
+    Ok(())
+  }
+
+  /// The [`OID`] the tag `name` points at, which is the [`Tag`] object for
+  /// annotated tags
+  pub fn get(&self, name: &str) -> Result<Option<OID>, TagsError> {
+    Ok(self.repo.refs().resolve(Self::ref_name(name)?)?)
+  }
+
+  /// Create the lightweight tag `name` pointing straight at `target`, like
+  /// `git tag {name} {target}`. An existing tag of the same name is only
+  /// replaced if `force` is set.
+  pub fn create(&self, name: &str, target: &OID, force: bool) -> Result<(), TagsError> {
+    let full = Self::ref_name(name)?;
+    self.check_free(&full, name, force)?;
+    self.repo.odb().read(target)?;
+    Ok(self.repo.refs().write(&full, target)?)
+  }
+
+  /// Write a [`Tag`] object for `target` and create the tag `name` pointing
+  /// at it, like `git tag -a {name} -m {message} {target}`. The message is
+  /// stored as given, so it should end with a newline like git's do. An
+  /// existing tag of the same name is only replaced if `force` is set.
+  pub fn create_annotated(
+    &self,
+    name: &str,
+    target: &OID,
+    tagger: Signature,
+    message: impl Into<BString>,
+    force: bool,
+  ) -> Result<OID, TagsError> {
+    let full = Self::ref_name(name)?;
+    self.check_free(&full, name, force)?;
+    let kind = self.repo.odb().read(target)?.kind;
+    let tag = Tag::new(*target, kind, name, tagger, message);
+    let oid = self.repo.odb().write_tag(&tag)?;
+    self.repo.refs().write(&full, &oid)?;
+    Ok(oid)
+  }
+
+  /// Delete the tag `name`, like `git tag -d {name}`, returning what it
+  /// pointed at. Only the ref goes away, a [`Tag`] object stays in the
+  /// object database.
+  pub fn delete(&self, name: &str) -> Result<OID, TagsError> {
+    let full = Self::ref_name(name)?;
+    let oid = self
+      .repo
+      .refs()
+      .resolve(&full)?
+      .ok_or_else(|| TagsError::NotFound(name.into()))?;
+    self.repo.refs().delete(&full)?;
+    Ok(oid)
+  }
+
+  /// The names of the tags matching any of `patterns`, or every tag if
+  /// there are none, sorted by name. The patterns are globs matched
+  /// against the whole name like `git tag --list` does, where `*` also
+  /// matches `/`.
+  pub fn list(&self, patterns: &[&str]) -> Result<Vec<BString>, TagsError> {
+    let mut names = Vec::new();
+    for reference in self.repo.refs().list("refs/tags/")? {
+      let name: &BStr = reference.name()[b"refs/tags/".len()..].as_bstr();
+      if patterns.is_empty()
+        || patterns
+          .iter()
+          .any(|pattern| wildmatch(pattern.as_bytes(), name, 0))
+      {
+        names.push(name.into());
+      }
+    }
+    Ok(names)
+  }
+
+  /// Follow the tag `name` through any chain of [`Tag`] objects to the
+  /// object at the end of it, like `git rev-parse {name}^{}`
+  pub fn peel(&self, name: &str) -> Result<OID, TagsError> {
+    let oid = self
+      .get(name)?
+      .ok_or_else(|| TagsError::NotFound(name.into()))?;
+    Ok(peel_tag(self.repo.odb(), &oid)?.0)
+  }
+}
+
+/// Follow [`Tag`] objects starting at `oid` until an object that isn't a
+/// tag is reached, returning it and its kind. An `oid` that isn't a tag is
+/// returned as is.
+pub fn peel_tag(odb: &Odb, oid: &OID) -> Result<(OID, ObjectKind), OdbError> {
+  let mut oid = *oid;
+  loop {
+    let object = odb.read(&oid)?;
+    if object.kind != ObjectKind::Tag {
+      return Ok((oid, object.kind));
+    }
+    oid = *Tag::parse(&object.data)?.object();
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`Tags`] type
+pub enum TagsError {
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0:?} is not a valid tag name")]
+  InvalidName(BString),
+  #[error("tag {0} already exists")]
+  Exists(BString),
+  #[error("tag {0} not found")]
+  NotFound(BString),
+}
+
+#[test]
+fn create_list_and_delete() {
+  use crate::{Commit, Time, Tree};
+  let tmp_dir = tempdir::TempDir::new("tags_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let odb = repo.odb();
+  let tree = odb.write_tree(&Tree::default()).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = Commit::new(
+    tree,
+    vec![],
+    signature.clone(),
+    signature.clone(),
+    "first\n",
+  );
+  let commit = odb.write_commit(&commit).unwrap();
+  let tags = repo.tags();
+
+  tags.create("light", &commit, false).unwrap();
+  assert_eq!(Some(commit), tags.get("light").unwrap());
+  assert!(matches!(
+    tags.create("light", &tree, false),
+    Err(TagsError::Exists(_))
+  ));
+  tags.create("light", &tree, true).unwrap();
+  assert_eq!(tree, tags.peel("light").unwrap());
+  assert!(matches!(
+    tags.create("bad..name", &commit, false),
+    Err(TagsError::InvalidName(_))
+  ));
+
+  let v1 = tags
+    .create_annotated("v1.0", &commit, signature.clone(), "Release 1.0\n", false)
+    .unwrap();
+  let tag = odb.read_tag(&v1).unwrap();
+  assert_eq!(ObjectKind::Commit, tag.kind());
+  assert_eq!("v1.0", tag.name());
+  // A tag of a tag peels all the way to the commit
+  let nested = tags
+    .create_annotated("release/v1.0", &v1, signature, "Again\n", false)
+    .unwrap();
+  assert_eq!(ObjectKind::Tag, odb.read_tag(&nested).unwrap().kind());
+  assert_eq!(commit, tags.peel("release/v1.0").unwrap());
+  assert_eq!(
+    (commit, ObjectKind::Commit),
+    peel_tag(odb, &nested).unwrap()
+  );
+  assert_eq!(commit, repo.rev_parse("release/v1.0^{}").unwrap());
+
+  assert_eq!(
+    vec!["light", "release/v1.0", "v1.0"],
+    tags.list(&[]).unwrap()
+  );
+  assert_eq!(vec!["release/v1.0", "v1.0"], tags.list(&["*v1*"]).unwrap());
+  assert_eq!(vec!["light"], tags.list(&["l?ght", "nope"]).unwrap());
+
+  assert_eq!(v1, tags.delete("v1.0").unwrap());
+  assert!(matches!(tags.delete("v1.0"), Err(TagsError::NotFound(_))));
+  assert_eq!(vec!["light", "release/v1.0"], tags.list(&[]).unwrap());
+  // The object of a deleted tag is still there for the tags pointing at it
+  assert_eq!(commit, tags.peel("release/v1.0").unwrap());
+}
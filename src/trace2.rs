@@ -0,0 +1,457 @@
+use std::{
+  cell::Cell,
+  collections::BTreeMap,
+  env,
+  fmt::Write as _,
+  fs,
+  io::{self, Write},
+  panic::Location,
+  process,
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Mutex,
+  },
+  thread,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+static CHILD_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+  static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+struct State {
+  writer: Box<dyn Write + Send>,
+  sid: String,
+  start: Instant,
+  timers: BTreeMap<(&'static str, &'static str), TimerStats>,
+}
+
+#[derive(Default)]
+struct TimerStats {
+  intervals: u64,
+  total: Duration,
+  min: Duration,
+  max: Duration,
+}
+
+/// Performance events written as JSON lines in the same format as git's
+/// trace2 event target, so existing tooling for `GIT_TRACE2_EVENT` output
+/// can be used to look at where time goes inside of the library.
+///
+/// Tracing is off until [`Trace2::enable`] or [`Trace2::enable_from_env`] is
+/// called and costs only an atomic load per event while off.
+pub struct Trace2;
+
+impl Trace2 {
+  /// Write events to `writer` from now on, replacing any earlier writer
+  #[track_caller]
+  pub fn enable(writer: impl Write + Send + 'static) {
+    let now = SystemTime::now();
+    let micros = now
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_micros();
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    *state = Some(State {
+      writer: Box::new(writer),
+      sid: format!("{}-{}", micros, process::id()),
+      start: Instant::now(),
+      timers: BTreeMap::new(),
+    });
+    ENABLED.store(true, Ordering::Release);
+    drop(state);
+    emit(
+      "version",
+      Location::caller(),
+      &format!(
+        r#","evt":"3","exe":"{} {}""#,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+      ),
+    );
+  }
+
+  /// Enable tracing if `GIT_TRACE2_EVENT` is set, the same as git. It can
+  /// be `1` or `2` for stdout or stderr, or an absolute path to append to.
+  /// Returns whether tracing was enabled.
+  pub fn enable_from_env() -> io::Result<bool> {
+    let target = match env::var_os("GIT_TRACE2_EVENT") {
+      Some(target) => target,
+      None => return Ok(false),
+    };
+    match target.to_str() {
+      Some("" | "0" | "false") => return Ok(false),
+      Some("1" | "true") => Self::enable(io::stdout()),
+      Some("2") => Self::enable(io::stderr()),
+      _ => Self::enable(
+        fs::OpenOptions::new()
+          .create(true)
+          .append(true)
+          .open(target)?,
+      ),
+    }
+    Ok(true)
+  }
+
+  /// Write the totals of every [`TraceTimer`] as `timer` events and stop
+  /// tracing
+  #[track_caller]
+  pub fn disable() {
+    if !ENABLED.load(Ordering::Acquire) {
+      return;
+    }
+    let timers = match &mut *STATE.lock().unwrap_or_else(|e| e.into_inner()) {
+      Some(state) => std::mem::take(&mut state.timers),
+      None => BTreeMap::new(),
+    };
+    for ((category, name), stats) in timers {
+      emit(
+        "timer",
+        Location::caller(),
+        &format!(
+          r#","category":"{}","name":"{}","intervals":{},"t_total":{:.6},"t_min":{:.6},"t_max":{:.6}"#,
+          escape(category),
+          escape(name),
+          stats.intervals,
+          stats.total.as_secs_f64(),
+          stats.min.as_secs_f64(),
+          stats.max.as_secs_f64()
+        ),
+      );
+    }
+    ENABLED.store(false, Ordering::Release);
+    if let Some(mut state) = STATE.lock().unwrap_or_else(|e| e.into_inner()).take() {
+      let _ = state.writer.flush();
+    }
+  }
+
+  /// Whether events are being written
+  pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+  }
+
+  /// Start a region, which ends when the returned [`TraceRegion`] is
+  /// dropped. Regions nested on the same thread are indented by tooling.
+  #[track_caller]
+  pub fn region(category: &'static str, label: &'static str) -> TraceRegion {
+    if !Self::is_enabled() {
+      return TraceRegion { start: None };
+    }
+    let location = Location::caller();
+    let nesting = NESTING.with(|n| {
+      n.set(n.get() + 1);
+      n.get()
+    });
+    emit(
+      "region_enter",
+      location,
+      &region_fields(nesting, category, label),
+    );
+    TraceRegion {
+      start: Some((Instant::now(), category, label, location)),
+    }
+  }
+
+  /// Record a value, like how many entries an index has
+  #[track_caller]
+  pub fn data(category: &'static str, key: &'static str, value: impl std::fmt::Display) {
+    if !Self::is_enabled() {
+      return;
+    }
+    let t_abs = match &*STATE.lock().unwrap_or_else(|e| e.into_inner()) {
+      Some(state) => state.start.elapsed(),
+      None => return,
+    };
+    emit(
+      "data",
+      Location::caller(),
+      &format!(
+        r#","t_abs":{:.6},"nesting":{},"category":"{}","key":"{}","value":"{}""#,
+        t_abs.as_secs_f64(),
+        NESTING.with(Cell::get),
+        escape(category),
+        escape(key),
+        escape(&value.to_string())
+      ),
+    );
+  }
+
+  /// Start timing something that happens many times, like reading an
+  /// object. Nothing is written per interval, instead the totals are
+  /// written as one event by [`Trace2::disable`].
+  pub fn timer(category: &'static str, name: &'static str) -> TraceTimer {
+    TraceTimer {
+      start: Self::is_enabled().then(|| (Instant::now(), category, name)),
+    }
+  }
+
+  /// Record that a child process is being started with `argv`. Call
+  /// [`TraceChild::exit`] once it's finished.
+  #[track_caller]
+  pub fn child_start<S: AsRef<str>>(argv: &[S]) -> TraceChild {
+    if !Self::is_enabled() {
+      return TraceChild { start: None };
+    }
+    let id = CHILD_ID.fetch_add(1, Ordering::Relaxed);
+    let argv = argv
+      .iter()
+      .map(|arg| format!(r#""{}""#, escape(arg.as_ref())))
+      .collect::<Vec<_>>()
+      .join(",");
+    emit(
+      "child_start",
+      Location::caller(),
+      &format!(
+        r#","child_id":{},"child_class":"?","use_shell":false,"argv":[{}]"#,
+        id, argv
+      ),
+    );
+    TraceChild {
+      start: Some((Instant::now(), id)),
+    }
+  }
+}
+
+/// A region started by [`Trace2::region`] that ends when dropped
+#[must_use = "the region ends as soon as this is dropped"]
+pub struct TraceRegion {
+  start: Option<(
+    Instant,
+    &'static str,
+    &'static str,
+    &'static Location<'static>,
+  )>,
+}
+
+impl Drop for TraceRegion {
+  fn drop(&mut self) {
+    if let Some((start, category, label, location)) = self.start {
+      let nesting = NESTING.with(|n| {
+        let nesting = n.get();
+        n.set(nesting.saturating_sub(1));
+        nesting
+      });
+      emit(
+        "region_leave",
+        location,
+        &format!(
+          r#","t_rel":{:.6}{}"#,
+          start.elapsed().as_secs_f64(),
+          region_fields(nesting, category, label)
+        ),
+      );
+    }
+  }
+}
+
+/// An interval started by [`Trace2::timer`] that is added to its timer's
+/// totals when dropped
+#[must_use = "the interval ends as soon as this is dropped"]
+pub struct TraceTimer {
+  start: Option<(Instant, &'static str, &'static str)>,
+}
+
+impl Drop for TraceTimer {
+  fn drop(&mut self) {
+    if let Some((start, category, name)) = self.start {
+      let elapsed = start.elapsed();
+      if let Some(state) = &mut *STATE.lock().unwrap_or_else(|e| e.into_inner()) {
+        let stats = state.timers.entry((category, name)).or_default();
+        if stats.intervals == 0 || elapsed < stats.min {
+          stats.min = elapsed;
+        }
+        stats.max = stats.max.max(elapsed);
+        stats.total += elapsed;
+        stats.intervals += 1;
+      }
+    }
+  }
+}
+
+/// A child process started with [`Trace2::child_start`]
+pub struct TraceChild {
+  start: Option<(Instant, usize)>,
+}
+
+impl TraceChild {
+  /// Record that the child process `pid` exited with `code`
+  #[track_caller]
+  pub fn exit(self, pid: u32, code: i32) {
+    if let Some((start, id)) = self.start {
+      emit(
+        "child_exit",
+        Location::caller(),
+        &format!(
+          r#","child_id":{},"pid":{},"code":{},"t_rel":{:.6}"#,
+          id,
+          pid,
+          code,
+          start.elapsed().as_secs_f64()
+        ),
+      );
+    }
+  }
+}
+
+fn region_fields(nesting: usize, category: &str, label: &str) -> String {
+  format!(
+    r#","nesting":{},"category":"{}","label":"{}""#,
+    nesting,
+    escape(category),
+    escape(label)
+  )
+}
+
+/// Write one event with the fields every event has followed by `fields`,
+/// which starts with a comma
+fn emit(event: &str, location: &Location<'_>, fields: &str) {
+  let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+  let state = match &mut *state {
+    Some(state) => state,
+    None => return,
+  };
+  let thread = thread::current();
+  let line = format!(
+    r#"{{"event":"{}","sid":"{}","thread":"{}","time":"{}","file":"{}","line":{}{}}}"#,
+    event,
+    state.sid,
+    escape(thread.name().unwrap_or("thread")),
+    format_time(SystemTime::now()),
+    escape(location.file()),
+    location.line(),
+    fields
+  );
+  // Tracing is best effort and never makes an operation fail
+  let _ = writeln!(state.writer, "{}", line);
+}
+
+fn escape(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '"' => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      '\n' => escaped.push_str("\\n"),
+      '\r' => escaped.push_str("\\r"),
+      '\t' => escaped.push_str("\\t"),
+      c if (c as u32) < 0x20 => {
+        let _ = write!(escaped, "\\u{:04x}", c as u32);
+      }
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+/// Format a time as UTC in the form `2020-09-13T12:26:40.000000Z`
+fn format_time(time: SystemTime) -> String {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let secs = since_epoch.as_secs();
+  let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+  // Convert days since the epoch into a date in the proleptic Gregorian
+  // calendar, see http://howardhinnant.github.io/date_algorithms.html
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z.rem_euclid(146_097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + i64::from(month <= 2);
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+    year,
+    month,
+    day,
+    secs_of_day / 3600,
+    secs_of_day / 60 % 60,
+    secs_of_day % 60,
+    since_epoch.subsec_micros()
+  )
+}
+
+#[test]
+fn events() {
+  use std::sync::Arc;
+
+  #[derive(Clone, Default)]
+  struct Buffer(Arc<Mutex<Vec<u8>>>);
+  impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  let buffer = Buffer::default();
+  Trace2::enable(buffer.clone());
+  {
+    let _outer = Trace2::region("trace_test", "outer");
+    let _inner = Trace2::region("trace_test", "inner \"quoted\"");
+    Trace2::data("trace_test", "count", 3);
+  }
+  for _ in 0..2 {
+    let _timer = Trace2::timer("trace_test", "loop");
+  }
+  Trace2::child_start(&["git", "status"]).exit(42, 0);
+  Trace2::disable();
+  assert!(!Trace2::is_enabled());
+  let _ignored = Trace2::region("trace_test", "after");
+
+  let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+  // Other tests running at the same time can write events too so only
+  // ours are checked
+  let lines: Vec<&str> = output
+    .lines()
+    .filter(|line| {
+      line.contains(r#""category":"trace_test""#)
+        || line.contains(r#""event":"version""#)
+        || line.contains(r#""event":"child_"#)
+    })
+    .collect();
+  let events: Vec<&str> = lines
+    .iter()
+    .map(|line| line.split('"').nth(3).unwrap())
+    .collect();
+  assert_eq!(
+    vec![
+      "version",
+      "region_enter",
+      "region_enter",
+      "data",
+      "region_leave",
+      "region_leave",
+      "child_start",
+      "child_exit",
+      "timer",
+    ],
+    events
+  );
+  assert!(lines[2].contains(r#""nesting":2,"category":"trace_test","label":"inner \"quoted\"""#));
+  assert!(lines[3].contains(r#""key":"count","value":"3""#));
+  assert!(lines[6].contains(r#""argv":["git","status"]"#));
+  assert!(lines[8].contains(r#""name":"loop","intervals":2"#));
+  assert!(lines
+    .iter()
+    .all(|line| line.contains(r#""file":"src/trace2.rs""#)));
+}
+
+#[test]
+fn time_format() {
+  assert_eq!(
+    "2020-09-13T12:26:40.000000Z",
+    format_time(UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+  );
+  assert_eq!("1970-01-01T00:00:00.000000Z", format_time(UNIX_EPOCH));
+  assert_eq!(
+    "2000-02-29T23:59:59.000001Z",
+    format_time(UNIX_EPOCH + Duration::new(951_868_799, 1000))
+  );
+}
@@ -0,0 +1,318 @@
+//! Trailers are the `Token: value` lines at the end of a commit message,
+//! like `Signed-off-by:` or `Co-authored-by:`, found the way
+//! `git interpret-trailers` finds them.
+//!
+//! Only the last paragraph of a message can hold trailers, and never the
+//! first one since that's the subject. The paragraph is a trailer block if
+//! every line in it is a trailer, or if at least a quarter of them are and
+//! one starts with something git writes itself like `Signed-off-by: `, so
+//! a `(cherry picked from commit ...)` line doesn't hide the trailers around
+//! it. Lines starting with whitespace continue the line before them.
+
+use crate::{Commit, CommitError};
+use std::fmt;
+
+/// Lines git adds to messages itself, which make a paragraph a trailer
+/// block even when it has other lines in it
+const GIT_GENERATED_PREFIXES: &[&str] = &["Signed-off-by: ", "(cherry picked from commit "];
+
+/// One `Token: value` line at the end of a commit message
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Trailer {
+  /// What the trailer is, like `Signed-off-by`
+  pub token: String,
+  /// What comes after the `:`, with continuation lines joined by spaces
+  pub value: String,
+}
+
+impl Trailer {
+  /// Create a new [`Trailer`]
+  pub fn new(token: impl Into<String>, value: impl Into<String>) -> Self {
+    Self {
+      token: token.into(),
+      value: value.into(),
+    }
+  }
+
+  /// Tokens are compared ignoring case, like git does
+  fn same_token(&self, other: &Self) -> bool {
+    self.token.eq_ignore_ascii_case(&other.token)
+  }
+
+  /// Whether `other` is the same trailer, ignoring case like git does
+  fn same(&self, other: &Self) -> bool {
+    self.same_token(other) && self.value.eq_ignore_ascii_case(&other.value)
+  }
+}
+
+impl fmt::Display for Trailer {
+  /// The trailer as it's written in a message, without a line ending
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}: {}", self.token, self.value)
+  }
+}
+
+/// What [`add_trailer`] does when the message already has a trailer with
+/// the same token, like `trailer.ifExists`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TrailerIfExists {
+  /// Add it unless the last trailer is the same one
+  #[default]
+  AddIfDifferentNeighbor,
+  /// Add it unless the same trailer is anywhere in the message
+  AddIfDifferent,
+  /// Always add it
+  Add,
+  /// Remove the last trailer with the same token and add it
+  Replace,
+  /// Leave the message as it is
+  DoNothing,
+}
+
+/// A line of the trailer block along with the lines continuing it
+#[derive(Debug)]
+struct BlockLine<'a> {
+  text: &'a str,
+  trailer: Option<Trailer>,
+}
+
+/// Split `line` into a trailer if it has a token made of letters, digits,
+/// and `-` followed by a `:`, optionally with whitespace before the `:`
+fn parse_line(line: &str) -> Option<Trailer> {
+  let colon = line.find(':')?;
+  let token = line[..colon].trim_end_matches([' ', '\t']);
+  if token.is_empty()
+    || !token
+      .bytes()
+      .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+  {
+    return None;
+  }
+  Some(Trailer::new(token, line[colon + 1..].trim()))
+}
+
+/// The byte range of the trailer block of `message` and its lines, if it
+/// has one
+fn find_block(message: &str) -> Option<(usize, usize, Vec<BlockLine<'_>>)> {
+  let end = message.trim_end().len();
+  let body = &message[..end];
+  // Without a blank line there's only the subject, which is never part of
+  // the trailer block
+  let start = body.rfind("\n\n")? + 2;
+
+  let mut lines: Vec<BlockLine<'_>> = Vec::new();
+  let (mut trailers, mut others, mut recognized) = (0, 0, false);
+  let mut offset = start;
+  for line in body[start..].split('\n') {
+    let len = line.len();
+    if line.starts_with([' ', '\t']) && !lines.is_empty() {
+      let last = lines.last_mut().unwrap();
+      last.text = &body[offset - last.text.len() - 1..offset + len];
+      if let Some(trailer) = &mut last.trailer {
+        trailer.value.push(' ');
+        trailer.value.push_str(line.trim());
+      }
+    } else {
+      let trailer = parse_line(line);
+      match trailer {
+        Some(_) => trailers += 1,
+        None => others += 1,
+      }
+      recognized |= GIT_GENERATED_PREFIXES
+        .iter()
+        .any(|prefix| line.starts_with(prefix));
+      lines.push(BlockLine {
+        text: &body[offset..offset + len],
+        trailer,
+      });
+    }
+    offset += len + 1;
+  }
+  let is_block = trailers > 0 && (others == 0 || (recognized && trailers * 3 >= others));
+  is_block.then_some((start, end, lines))
+}
+
+/// The trailers at the end of `message`, in the order they appear, like
+/// `git interpret-trailers --parse`
+pub fn parse_trailers(message: &str) -> Vec<Trailer> {
+  match find_block(message) {
+    Some((_, _, lines)) => lines.into_iter().filter_map(|line| line.trailer).collect(),
+    None => Vec::new(),
+  }
+}
+
+/// Add `trailer` to the end of the trailers of `message`, like
+/// `git interpret-trailers --trailer`, starting a new trailer block after a
+/// blank line if there isn't one yet. `if_exists` says what happens when
+/// there's already a trailer with the same token.
+pub fn add_trailer(message: &str, trailer: &Trailer, if_exists: TrailerIfExists) -> String {
+  let (start, end, mut lines) = match find_block(message) {
+    Some(block) => block,
+    None => {
+      let message = message.trim_end();
+      return match message.is_empty() {
+        true => format!("{}\n", trailer),
+        false => format!("{}\n\n{}\n", message, trailer),
+      };
+    }
+  };
+  let existing = |lines: &[BlockLine<'_>], matches: &dyn Fn(&Trailer) -> bool| {
+    lines
+      .iter()
+      .rposition(|line| line.trailer.as_ref().is_some_and(matches))
+  };
+  match if_exists {
+    TrailerIfExists::Add => {}
+    TrailerIfExists::AddIfDifferentNeighbor => {
+      let last = lines.iter().rev().find_map(|line| line.trailer.as_ref());
+      if last.is_some_and(|last| last.same(trailer)) {
+        return message.into();
+      }
+    }
+    TrailerIfExists::AddIfDifferent => {
+      if existing(&lines, &|other| other.same(trailer)).is_some() {
+        return message.into();
+      }
+    }
+    TrailerIfExists::Replace => {
+      if let Some(idx) = existing(&lines, &|other| other.same_token(trailer)) {
+        lines.remove(idx);
+      }
+    }
+    TrailerIfExists::DoNothing => {
+      if existing(&lines, &|other| other.same_token(trailer)).is_some() {
+        return message.into();
+      }
+    }
+  }
+  let mut edited = message[..start].to_string();
+  for line in &lines {
+    edited.push_str(line.text);
+    edited.push('\n');
+  }
+  edited.push_str(&format!("{}\n", trailer));
+  edited.push_str(message[end..].trim_start_matches('\n'));
+  edited
+}
+
+impl Commit {
+  /// The trailers at the end of the message, see [`parse_trailers`]
+  pub fn trailers(&self) -> Vec<Trailer> {
+    parse_trailers(&self.message_lossy())
+  }
+
+  /// Add `trailer` to the message, see [`add_trailer`]. The message stays
+  /// in the [`Encoding`][crate::Encoding] it was in.
+  pub fn with_trailer(
+    self,
+    trailer: &Trailer,
+    if_exists: TrailerIfExists,
+  ) -> Result<Self, CommitError> {
+    let message = add_trailer(&self.message()?, trailer, if_exists);
+    self.with_message(&message)
+  }
+}
+
+#[test]
+fn trailers() {
+  let signed = Trailer::new("Signed-off-by", "A U Thor <author@example.com>");
+  let message = "Fix the build\n\nIt was broken.\n\n\
+                 Signed-off-by: A U Thor <author@example.com>\n\
+                 Co-authored-by: C O Mitter\n  <committer@example.com>\n";
+  assert_eq!(
+    vec![
+      signed.clone(),
+      Trailer::new("Co-authored-by", "C O Mitter <committer@example.com>"),
+    ],
+    parse_trailers(message)
+  );
+  // The subject is never a trailer
+  assert!(parse_trailers("Fixes: the build\n").is_empty());
+  // Paragraphs with other lines need enough trailers and one git wrote
+  assert!(parse_trailers("Subject\n\nNot a trailer\nReviewed-by: Someone\n").is_empty());
+  let picked =
+    "Subject\n\n(cherry picked from commit abc)\nSigned-off-by: A U Thor <author@example.com>\n";
+  assert_eq!(vec![signed.clone()], parse_trailers(picked));
+
+  assert_eq!(
+    "Subject\n\nSigned-off-by: A U Thor <author@example.com>\n",
+    add_trailer("Subject\n", &signed, TrailerIfExists::default())
+  );
+  assert_eq!(
+    message,
+    add_trailer(message, &signed, TrailerIfExists::AddIfDifferent)
+  );
+  let reviewed = Trailer::new("Reviewed-by", "Someone");
+  let added = add_trailer(message, &reviewed, TrailerIfExists::default());
+  assert_eq!(format!("{}Reviewed-by: Someone\n", message), added);
+  assert_eq!(
+    added,
+    add_trailer(&added, &reviewed, TrailerIfExists::default())
+  );
+  let replaced = add_trailer(
+    &added,
+    &Trailer::new("reviewed-by", "Someone Else"),
+    TrailerIfExists::Replace,
+  );
+  assert_eq!(format!("{}reviewed-by: Someone Else\n", message), replaced);
+
+  let signature = crate::Signature::new(
+    "A U Thor",
+    "author@example.com",
+    crate::Time::new(1_234_567_890, 0),
+  );
+  let tree = crate::Tree::new(Vec::new()).id();
+  let commit = Commit::new(tree, vec![], signature.clone(), signature, "Subject\n")
+    .with_trailer(&signed, TrailerIfExists::default())
+    .unwrap();
+  assert_eq!(vec![signed.clone()], commit.trailers());
+
+  // Git finds and adds the same trailers
+  if crate::transport::http::have_git() {
+    use std::io::Write;
+    let git = |args: &[&str], input: &str| {
+      let mut child = std::process::Command::new("git")
+        .arg("interpret-trailers")
+        .args(args)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+      child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+      let output = child.wait_with_output().unwrap();
+      assert!(output.status.success());
+      String::from_utf8(output.stdout).unwrap()
+    };
+    for message in [
+      message,
+      picked,
+      "Subject\n\nNot a trailer\nReviewed-by: Someone\n",
+    ] {
+      let parsed: String = parse_trailers(message)
+        .iter()
+        .map(|trailer| format!("{}\n", trailer))
+        .collect();
+      assert_eq!(git(&["--parse"], message), parsed);
+    }
+    assert_eq!(git(&["--trailer", "Reviewed-by: Someone"], message), added);
+    assert_eq!(
+      git(
+        &[
+          "--if-exists",
+          "replace",
+          "--trailer",
+          "reviewed-by: Someone Else"
+        ],
+        &added
+      ),
+      replaced
+    );
+  }
+}
@@ -0,0 +1,1807 @@
+//! Fetching from and pushing to other repositories over git's wire
+//! protocols. What's said is the same whatever carries it, so the messages
+//! of the protocols are built and parsed here and the transports in the
+//! submodules only move the bytes.
+
+#[cfg(feature = "async")]
+pub mod async_http;
+pub mod bundle;
+mod capture;
+pub mod http;
+pub mod local;
+mod negotiate;
+pub mod ssh;
+mod throttle;
+
+pub use capture::*;
+pub use negotiate::*;
+pub use throttle::*;
+
+use crate::{
+  bitmap,
+  delta::{self, DeltaCandidate},
+  pack::{PackObject, PackWriter},
+  AdvertisedRef, BundleError, ConfigError, CredentialError, DeltaOptions, FileMode, ObjectKind,
+  OdbError, Packet, PktLineError, PktLineReader, ProgressStage, RefError, Repository,
+  RepositoryError, RevWalkError, ShallowError, Tag, UploadPackError, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  collections::{HashMap, HashSet},
+  fmt, io,
+};
+use thiserror::Error;
+
+/// How many commits a fetch tells the remote it already has. Stateless
+/// transports send them all in one request, so there's no going back for
+/// more once the remote knows what's in common.
+const MAX_HAVES: usize = 256;
+
+/// The versions of the wire protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProtocolVersion {
+  /// The original protocol where the remote advertises every ref as soon
+  /// as a client connects. It's also called version 0, the only
+  /// difference being a `version 1` line at the start.
+  V1,
+  /// The protocol where the client sends commands, like `ls-refs` to list
+  /// only the refs it asks for
+  V2,
+}
+
+/// What a remote said it has and can do when a connection was made
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Advertisement {
+  /// The version of the protocol the remote is speaking
+  pub version: ProtocolVersion,
+  /// The refs of the remote
+  pub refs: Vec<AdvertisedRef>,
+  /// The capabilities of the remote, like `ofs-delta`, with their values
+  /// after an `=`. For protocol v2 these are the lines of the capability
+  /// advertisement, like `fetch=shallow filter`.
+  pub capabilities: Vec<BString>,
+}
+
+impl Advertisement {
+  /// Whether the remote has the capability `name`, with or without a
+  /// value
+  pub fn has_capability(&self, name: &str) -> bool {
+    self.capability(name).is_some()
+  }
+
+  /// The value of the capability `name`, which is empty if the remote has
+  /// it without one
+  pub fn capability(&self, name: &str) -> Option<&BStr> {
+    self.capabilities.iter().find_map(|capability| {
+      let rest = capability.strip_prefix(name.as_bytes())?;
+      match rest.first() {
+        None => Some(b"".as_bstr()),
+        Some(b'=') => Some(rest[1..].as_bstr()),
+        Some(_) => None,
+      }
+    })
+  }
+
+  /// Whether the protocol v2 command `command` takes the argument
+  /// `feature`, like `shallow` for `fetch`
+  fn command_has(&self, command: &str, feature: &str) -> bool {
+    self
+      .capability(command)
+      .is_some_and(|features| features.fields().any(|field| field == feature.as_bytes()))
+  }
+
+  /// The ref `name`, like `HEAD` or `refs/heads/master`
+  pub fn get(&self, name: impl AsRef<[u8]>) -> Option<&AdvertisedRef> {
+    let name = name.as_ref();
+    self.refs.iter().find(|reference| reference.name == name)
+  }
+}
+
+/// What a fetch got from the remote
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FetchOutcome {
+  /// The objects that were received and stored, in the order they were in
+  /// the pack. Nothing is asked for when every object wanted is already
+  /// there, in which case this is empty.
+  pub objects: Vec<OID>,
+  /// Progress messages the remote sent along with the pack, like
+  /// `Counting objects`, which never change the outcome
+  pub progress: BString,
+  /// The commits of a shallow fetch whose parents were left out, which the
+  /// repository has to remember as shallow
+  pub shallow: Vec<OID>,
+  /// Commits that were shallow before and got their parents this time
+  pub unshallow: Vec<OID>,
+}
+
+/// What to leave out of a fetch, which needs a remote that has the
+/// feature. The default fetches everything, and filtering needs protocol
+/// v2.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FetchOptions {
+  /// Only fetch this many commits of history from each want. A shallow
+  /// repository is deepened or made shallower to that depth, and
+  /// [`FetchOptions::INFINITE_DEPTH`] makes it complete again.
+  pub depth: Option<u32>,
+  /// Count the depth from the shallow commits the repository has instead
+  /// of from the wants, like `git fetch --deepen`
+  pub deepen_relative: bool,
+  /// Only fetch commits made after this time, in seconds since the epoch
+  pub deepen_since: Option<i64>,
+  /// Leave out the commits reachable from these refs
+  pub deepen_not: Vec<BString>,
+  /// Leave out objects like a partial clone does, with a filter like
+  /// `blob:none` or `tree:0`. The pack is kept as a promisor pack, so
+  /// what it leaves out can be fetched later.
+  pub filter: Option<String>,
+  /// Don't tell the remote which commits are already there, which is how
+  /// the objects a partial clone left out are fetched since they're
+  /// reachable from them
+  pub no_haves: bool,
+  /// How to pick the commits the remote is told about, or what
+  /// `fetch.negotiationAlgorithm` picks
+  pub negotiation: Option<Negotiation>,
+}
+
+impl FetchOptions {
+  /// The depth that fetches all of history, which is what `git fetch
+  /// --unshallow` asks for
+  pub const INFINITE_DEPTH: u32 = 0x7fff_ffff;
+
+  /// The negotiation algorithm to pick haves for `repo` with
+  pub(crate) fn negotiation(&self, repo: &Repository) -> Negotiation {
+    match (self.no_haves, self.negotiation) {
+      (true, _) => Negotiation::Noop,
+      (false, Some(negotiation)) => negotiation,
+      (false, None) => Negotiation::from_config(repo),
+    }
+  }
+
+  fn deepens(&self) -> bool {
+    self.depth.is_some() || self.deepen_since.is_some() || !self.deepen_not.is_empty()
+  }
+}
+
+/// Reads the packets from a buffer of a whole response
+pub(crate) struct PacketReader<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> PacketReader<'a> {
+  pub(crate) fn new(bytes: &'a [u8]) -> Self {
+    Self { bytes }
+  }
+
+  /// The next packet, failing if the response ends before one
+  pub(crate) fn read(&mut self) -> Result<Packet<'a>, TransportError> {
+    let bytes = self.bytes;
+    match Packet::decode(bytes)? {
+      Some((packet, len)) => {
+        self.bytes = &bytes[len..];
+        Ok(packet)
+      }
+      None => Err(PktLineError::Truncated.into()),
+    }
+  }
+
+  /// The next packet if it's a data packet, with the line ending it might
+  /// have removed, or `None` for any other packet. `ERR` packets, which a
+  /// remote can send instead of anything, are turned into errors.
+  pub(crate) fn read_line(&mut self) -> Result<Option<&'a BStr>, TransportError> {
+    match self.read()? {
+      Packet::Data(data) => data_line(data).map(Some),
+      _ => Ok(None),
+    }
+  }
+
+  /// Whatever is left unread
+  pub(crate) fn rest(&self) -> &'a [u8] {
+    self.bytes
+  }
+}
+
+/// The line in the data of a packet without its line ending, or the error
+/// if it's an `ERR` packet
+fn data_line(data: &[u8]) -> Result<&BStr, TransportError> {
+  let line = data.strip_suffix(b"\n").unwrap_or(data);
+  match line.strip_prefix(b"ERR ") {
+    Some(message) => Err(TransportError::Remote(message.into())),
+    None => Ok(line.as_bstr()),
+  }
+}
+
+/// Build a pkt-line message from `lines`, where `None` is a delimiter, and
+/// end it with a flush
+pub(crate) fn encode_lines<'a>(
+  lines: impl IntoIterator<Item = Option<&'a [u8]>>,
+) -> Result<Vec<u8>, TransportError> {
+  let mut out = Vec::new();
+  for line in lines {
+    match line {
+      Some(line) => Packet::Data(line.as_bstr()).encode(&mut out)?,
+      None => Packet::Delim.encode(&mut out)?,
+    }
+  }
+  Packet::Flush.encode(&mut out)?;
+  Ok(out)
+}
+
+/// Read pkt-lines from a connection up to and including the next flush,
+/// returning their bytes. Connections that stay open, like a process over
+/// SSH, only tell where a message ends by the flush.
+pub(crate) fn read_until_flush(reader: &mut impl io::Read) -> Result<Vec<u8>, TransportError> {
+  let mut reader = PktLineReader::new(reader);
+  let mut bytes = Vec::new();
+  loop {
+    match reader.read_packet()? {
+      Some(packet) => {
+        packet.encode(&mut bytes)?;
+        if packet == Packet::Flush {
+          return Ok(bytes);
+        }
+      }
+      None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+    }
+  }
+}
+
+fn parse_oid(hex: &[u8]) -> Result<OID, TransportError> {
+  hex
+    .to_str()
+    .ok()
+    .and_then(|hex| OID::from_hex(hex).ok())
+    .ok_or_else(|| TransportError::Protocol(format!("invalid object name {:?}", hex.as_bstr())))
+}
+
+/// Parse what a remote says first. Protocol v1 lists the refs right away,
+/// while protocol v2 only lists capabilities and the refs have to be asked
+/// for with [`ls_refs_request`].
+pub(crate) fn parse_advertisement(
+  reader: &mut PacketReader<'_>,
+) -> Result<Advertisement, TransportError> {
+  let mut advertisement = Advertisement {
+    version: ProtocolVersion::V1,
+    refs: Vec::new(),
+    capabilities: Vec::new(),
+  };
+  let mut first = true;
+  while let Some(line) = reader.read_line()? {
+    if first && line == "version 2" {
+      advertisement.version = ProtocolVersion::V2;
+      while let Some(line) = reader.read_line()? {
+        advertisement.capabilities.push(line.into());
+      }
+      return Ok(advertisement);
+    }
+    if first && line == "version 1" {
+      continue;
+    }
+    let line = match line.find_byte(0) {
+      Some(nul) if first => {
+        let capabilities = line[nul + 1..].fields().map(BString::from);
+        advertisement.capabilities.extend(capabilities);
+        &line[..nul]
+      }
+      _ => line,
+    };
+    first = false;
+    let space = line
+      .find_byte(b' ')
+      .ok_or_else(|| TransportError::Protocol(format!("invalid ref line {:?}", line.as_bstr())))?;
+    let (oid, name) = (parse_oid(&line[..space])?, &line[space + 1..]);
+    if name == "capabilities^{}" {
+      continue;
+    }
+    match name.strip_suffix(b"^{}") {
+      Some(name) => match advertisement.refs.last_mut() {
+        Some(last) if last.name == name => last.peeled = Some(oid),
+        _ => {
+          return Err(TransportError::Protocol(
+            "peeled ref without its ref".into(),
+          ))
+        }
+      },
+      None => advertisement.refs.push(AdvertisedRef {
+        name: name.into(),
+        oid,
+        peeled: None,
+        symref_target: None,
+      }),
+    }
+  }
+  let symrefs: Vec<BString> = advertisement
+    .capabilities
+    .iter()
+    .filter_map(|capability| capability.strip_prefix(b"symref="))
+    .map(BString::from)
+    .collect();
+  for symref in symrefs {
+    if let Some(colon) = symref.find_byte(b':') {
+      let (name, target) = (&symref[..colon], &symref[colon + 1..]);
+      if let Some(reference) = advertisement.refs.iter_mut().find(|r| r.name == name) {
+        reference.symref_target = Some(target.into());
+      }
+    }
+  }
+  Ok(advertisement)
+}
+
+/// Parse the advertisement in `bytes`, which smart HTTP starts with a line
+/// naming the service
+pub(crate) fn read_advertisement(bytes: &[u8]) -> Result<Advertisement, TransportError> {
+  let mut reader = PacketReader::new(bytes);
+  if bytes.get(4..5) == Some(b"#") {
+    reader.read_line()?;
+    if reader.read()? != Packet::Flush {
+      return Err(TransportError::Protocol(
+        "expected a flush after the service".into(),
+      ));
+    }
+  }
+  parse_advertisement(&mut reader)
+}
+
+/// The capabilities a client says it has in its first protocol v2 command
+fn v2_command(command: &str, advertisement: &Advertisement) -> Vec<Vec<u8>> {
+  let mut lines = vec![
+    format!("command={}", command).into_bytes(),
+    format!("agent=libgit-rs/{}", env!("CARGO_PKG_VERSION")).into_bytes(),
+  ];
+  if advertisement.has_capability("object-format") {
+    lines.push(b"object-format=sha1".to_vec());
+  }
+  lines
+}
+
+/// The protocol v2 `ls-refs` command for the refs starting with one of
+/// `prefixes`, or every ref without any, with symbolic refs and peeled
+/// tags
+pub(crate) fn ls_refs_request(
+  advertisement: &Advertisement,
+  prefixes: &[BString],
+) -> Result<Vec<u8>, TransportError> {
+  let command = v2_command("ls-refs", advertisement);
+  let mut arguments = vec![b"peel".to_vec(), b"symrefs".to_vec()];
+  arguments.extend(
+    prefixes
+      .iter()
+      .map(|prefix| [&b"ref-prefix "[..], prefix].concat()),
+  );
+  encode_lines(
+    command
+      .iter()
+      .map(|line| Some(&line[..]))
+      .chain([None])
+      .chain(arguments.iter().map(|line| Some(&line[..]))),
+  )
+}
+
+/// Drop the refs of `advertisement` that don't start with one of
+/// `prefixes`, if there are any. Protocol v1 remotes always list every
+/// ref, and protocol v2 ones are allowed to send more than was asked for.
+pub(crate) fn retain_prefixed(advertisement: &mut Advertisement, prefixes: &[BString]) {
+  if !prefixes.is_empty() {
+    advertisement.refs.retain(|reference| {
+      prefixes
+        .iter()
+        .any(|prefix| reference.name.starts_with(prefix))
+    });
+  }
+}
+
+/// Parse the response to [`ls_refs_request`] into the refs of
+/// `advertisement`. Unborn refs aren't listed since they don't point at
+/// anything.
+pub(crate) fn parse_ls_refs(
+  reader: &mut PacketReader<'_>,
+  advertisement: &mut Advertisement,
+) -> Result<(), TransportError> {
+  while let Some(line) = reader.read_line()? {
+    let mut fields = line.split_str(" ");
+    let oid = fields.next().unwrap_or_default();
+    let name = fields
+      .next()
+      .ok_or_else(|| TransportError::Protocol(format!("invalid ref line {:?}", line)))?;
+    if oid == b"unborn" {
+      continue;
+    }
+    let mut reference = AdvertisedRef {
+      name: name.into(),
+      oid: parse_oid(oid)?,
+      peeled: None,
+      symref_target: None,
+    };
+    for attribute in fields {
+      if let Some(target) = attribute.strip_prefix(b"symref-target:") {
+        reference.symref_target = Some(target.into());
+      } else if let Some(peeled) = attribute.strip_prefix(b"peeled:") {
+        reference.peeled = Some(parse_oid(peeled)?);
+      }
+    }
+    advertisement.refs.push(reference);
+  }
+  Ok(())
+}
+
+/// The objects in `wants` that aren't in `repo` yet
+pub(crate) fn missing_wants(repo: &Repository, wants: &[OID]) -> Result<Vec<OID>, TransportError> {
+  let mut missing = Vec::new();
+  for want in wants {
+    if !repo.odb().contains(want)? && !missing.contains(want) {
+      missing.push(*want);
+    }
+  }
+  Ok(missing)
+}
+
+/// The wants of a fetch with `options`, which are the ones `repo` doesn't
+/// have yet unless the fetch deepens, since the history behind commits
+/// already there is what a deepening fetch is for
+pub(crate) fn fetch_wants(
+  repo: &Repository,
+  wants: &[OID],
+  options: &FetchOptions,
+) -> Result<Vec<OID>, TransportError> {
+  match options.deepens() {
+    true => Ok(wants.to_vec()),
+    false => missing_wants(repo, wants),
+  }
+}
+
+/// The capabilities a protocol v1 fetch asks for out of what the remote
+/// has. The pack comes multiplexed with progress messages when the remote
+/// can do that.
+fn v1_fetch_capabilities(advertisement: &Advertisement) -> Vec<&'static str> {
+  let mut capabilities = Vec::new();
+  for capability in ["side-band-64k", "ofs-delta"] {
+    if advertisement.has_capability(capability) {
+      capabilities.push(capability);
+    }
+  }
+  if !capabilities.contains(&"side-band-64k") && advertisement.has_capability("side-band") {
+    capabilities.push("side-band");
+  }
+  capabilities
+}
+
+/// The lines telling a remote about the `shallow` commits of the client
+/// and how far `options` deepen it, which are the same in both protocol
+/// versions but for `deepen-relative`. `has` says whether the remote can
+/// do something, like `deepen-since`.
+fn shallow_arguments(
+  shallow: &[OID],
+  options: &FetchOptions,
+  has: impl Fn(&str) -> bool,
+) -> Result<Vec<Vec<u8>>, TransportError> {
+  let mut arguments = Vec::new();
+  if !has("shallow") {
+    // Without shallow commits the remote never has to know of them
+    return match options.deepens() {
+      true => Err(TransportError::Unsupported(
+        "the remote can't do shallow fetches".into(),
+      )),
+      false => Ok(arguments),
+    };
+  }
+  arguments.extend(
+    shallow
+      .iter()
+      .map(|oid| format!("shallow {}", oid).into_bytes()),
+  );
+  let require = |feature: &str| match has(feature) {
+    true => Ok(()),
+    false => Err(TransportError::Unsupported(format!(
+      "the remote can't do {}",
+      feature
+    ))),
+  };
+  if let Some(depth) = options.depth {
+    arguments.push(format!("deepen {}", depth).into_bytes());
+  }
+  if let Some(since) = options.deepen_since {
+    require("deepen-since")?;
+    arguments.push(format!("deepen-since {}", since).into_bytes());
+  }
+  for name in &options.deepen_not {
+    require("deepen-not")?;
+    arguments.push([&b"deepen-not "[..], name].concat());
+  }
+  Ok(arguments)
+}
+
+/// A protocol v1 request for a pack of `wants` without what's reachable
+/// from `haves`, sent in one go and ending with `done` for stateless
+/// transports. A repository with `shallow` commits says so, and `options`
+/// can deepen it.
+pub(crate) fn v1_fetch_request(
+  advertisement: &Advertisement,
+  wants: &[OID],
+  haves: &[OID],
+  shallow: &[OID],
+  options: &FetchOptions,
+) -> Result<Vec<u8>, TransportError> {
+  if options.filter.is_some() {
+    return Err(TransportError::Unsupported(
+      "filtered fetches need protocol v2".into(),
+    ));
+  }
+  let shallow_lines = shallow_arguments(shallow, options, |feature| {
+    advertisement.has_capability(feature)
+  })?;
+  let mut capabilities = v1_fetch_capabilities(advertisement);
+  if !shallow_lines.is_empty() {
+    capabilities.push("shallow");
+  }
+  // Which is only a capability here, not a line of its own
+  if options.depth.is_some() && options.deepen_relative {
+    if !advertisement.has_capability("deepen-relative") {
+      return Err(TransportError::Unsupported(
+        "the remote can't do deepen-relative".into(),
+      ));
+    }
+    capabilities.push("deepen-relative");
+  }
+  if options.deepen_since.is_some() {
+    capabilities.push("deepen-since");
+  }
+  if !options.deepen_not.is_empty() {
+    capabilities.push("deepen-not");
+  }
+  let mut capabilities = capabilities.join(" ");
+  capabilities.push_str(&format!(" agent=libgit-rs/{}", env!("CARGO_PKG_VERSION")));
+  let mut out = Vec::new();
+  for (i, want) in wants.iter().enumerate() {
+    let line = match i {
+      0 => format!("want {} {}\n", want, capabilities.trim_start()),
+      _ => format!("want {}\n", want),
+    };
+    Packet::Data(line.as_bytes().as_bstr()).encode(&mut out)?;
+  }
+  // The shallow commits and how far to deepen come after the wants
+  for line in &shallow_lines {
+    let line = [line, &b"\n"[..]].concat();
+    Packet::Data(line.as_bstr()).encode(&mut out)?;
+  }
+  Packet::Flush.encode(&mut out)?;
+  for have in haves {
+    let line = format!("have {}\n", have);
+    Packet::Data(line.as_bytes().as_bstr()).encode(&mut out)?;
+  }
+  Packet::Data(b"done\n".as_bstr()).encode(&mut out)?;
+  Ok(out)
+}
+
+/// A protocol v2 `fetch` command for a pack of `wants` without what's
+/// reachable from `haves` and what `options` leave out, ending with `done`
+/// so the remote sends the pack right away. A repository with `shallow`
+/// commits says so.
+pub(crate) fn v2_fetch_request(
+  advertisement: &Advertisement,
+  wants: &[OID],
+  haves: &[OID],
+  shallow: &[OID],
+  options: &FetchOptions,
+) -> Result<Vec<u8>, TransportError> {
+  let command = v2_command("fetch", advertisement);
+  let mut arguments = vec![b"ofs-delta".to_vec()];
+  if advertisement.command_has("fetch", "sideband-all") {
+    arguments.push(b"sideband-all".to_vec());
+  }
+  // Every way of deepening comes with the `shallow` feature
+  arguments.extend(shallow_arguments(shallow, options, |_| {
+    advertisement.command_has("fetch", "shallow")
+  })?);
+  if options.depth.is_some() && options.deepen_relative {
+    arguments.push(b"deepen-relative".to_vec());
+  }
+  if let Some(filter) = &options.filter {
+    if !advertisement.command_has("fetch", "filter") {
+      return Err(TransportError::Unsupported(
+        "the remote can't filter what it sends".into(),
+      ));
+    }
+    arguments.push(format!("filter {}", filter).into_bytes());
+  }
+  arguments.extend(
+    wants
+      .iter()
+      .map(|want| format!("want {}", want).into_bytes()),
+  );
+  arguments.extend(
+    haves
+      .iter()
+      .map(|have| format!("have {}", have).into_bytes()),
+  );
+  arguments.push(b"done".to_vec());
+  encode_lines(
+    command
+      .iter()
+      .map(|line| Some(&line[..]))
+      .chain([None])
+      .chain(arguments.iter().map(|line| Some(&line[..]))),
+  )
+}
+
+/// Split the packets of a side-band stream into the pack, which is band 1,
+/// and progress messages, which are band 2, until a flush. Band 3 is an
+/// error from the remote.
+fn demux(
+  reader: &mut PacketReader<'_>,
+  pack: &mut Vec<u8>,
+  progress: &mut BString,
+) -> Result<(), TransportError> {
+  loop {
+    match reader.read()? {
+      Packet::Data(data) => match data.split_first() {
+        Some((1, bytes)) => pack.extend_from_slice(bytes),
+        Some((2, message)) => progress.extend_from_slice(message),
+        Some((3, message)) => {
+          let message = message.strip_suffix(b"\n").unwrap_or(message);
+          return Err(TransportError::Remote(message.into()));
+        }
+        _ => return Err(TransportError::Protocol("invalid side-band packet".into())),
+      },
+      Packet::Flush | Packet::ResponseEnd => return Ok(()),
+      Packet::Delim => return Err(TransportError::Protocol("unexpected delimiter".into())),
+    }
+  }
+}
+
+/// Store a fetched `pack` in `repo`, as a promisor pack if `options`
+/// filtered it
+fn store_pack(
+  repo: &Repository,
+  pack: &[u8],
+  options: &FetchOptions,
+) -> Result<Vec<OID>, TransportError> {
+  Ok(match options.filter {
+    Some(_) => repo.odb().write_promisor_pack(pack)?,
+    None => repo.odb().write_pack(pack)?,
+  })
+}
+
+/// Read the pack out of the response to [`v1_fetch_request`], after the
+/// `ACK` or `NAK` for the common commits, and store it in `repo`. A
+/// request that deepens gets the shallow commits first, up to a flush.
+pub(crate) fn receive_v1_pack(
+  repo: &Repository,
+  advertisement: &Advertisement,
+  response: &[u8],
+  options: &FetchOptions,
+) -> Result<FetchOutcome, TransportError> {
+  let mut reader = PacketReader::new(response);
+  let mut outcome = FetchOutcome::default();
+  if options.deepens() {
+    while let Some(line) = reader.read_line()? {
+      parse_shallow_line(line, &mut outcome)?;
+    }
+  }
+  match reader.read_line()? {
+    Some(line) if line == "NAK" || line.starts_with(b"ACK ") => {}
+    other => {
+      let found = other.map_or("a special packet".into(), |line| format!("{:?}", line));
+      return Err(TransportError::Protocol(format!(
+        "expected ACK or NAK, found {}",
+        found
+      )));
+    }
+  }
+  // A remote can acknowledge more than one of the haves before the pack
+  while let Ok(Some((Packet::Data(line), _))) = Packet::decode(reader.rest()) {
+    if !line.starts_with(b"ACK ") {
+      break;
+    }
+    reader.read()?;
+  }
+  let capabilities = v1_fetch_capabilities(advertisement);
+  let pack = if capabilities.iter().any(|c| c.starts_with("side-band")) {
+    let mut pack = Vec::new();
+    demux(&mut reader, &mut pack, &mut outcome.progress)?;
+    pack
+  } else {
+    reader.rest().to_vec()
+  };
+  outcome.objects = store_pack(repo, &pack, options)?;
+  Ok(outcome)
+}
+
+/// Add the commit of a `shallow` or `unshallow` line to `outcome`
+fn parse_shallow_line(line: &BStr, outcome: &mut FetchOutcome) -> Result<(), TransportError> {
+  if let Some(oid) = line.strip_prefix(b"shallow ") {
+    outcome.shallow.push(parse_oid(oid)?);
+  } else if let Some(oid) = line.strip_prefix(b"unshallow ") {
+    outcome.unshallow.push(parse_oid(oid)?);
+  } else {
+    return Err(TransportError::Protocol(format!(
+      "invalid shallow line {:?}",
+      line
+    )));
+  }
+  Ok(())
+}
+
+/// The next line of a section of a protocol v2 response, which with
+/// `sideband-all` comes on band 1 between progress messages on band 2
+fn read_section_line<'a>(
+  reader: &mut PacketReader<'a>,
+  sideband_all: bool,
+  progress: &mut BString,
+) -> Result<Option<&'a BStr>, TransportError> {
+  if !sideband_all {
+    return reader.read_line();
+  }
+  loop {
+    match reader.read()? {
+      Packet::Data(data) => match data.split_first() {
+        Some((1, line)) => return data_line(line).map(Some),
+        // Empty ones are only there to keep the connection alive
+        Some((2, message)) => progress.extend_from_slice(message),
+        Some((3, message)) => {
+          let message = message.strip_suffix(b"\n").unwrap_or(message);
+          return Err(TransportError::Remote(message.into()));
+        }
+        _ => return Err(TransportError::Protocol("invalid side-band packet".into())),
+      },
+      _ => return Ok(None),
+    }
+  }
+}
+
+/// Read the `packfile` section out of the response to
+/// [`v2_fetch_request`] and store the pack in `repo`, along with the
+/// commits the `shallow-info` section says are shallow now, skipping over
+/// the other sections
+pub(crate) fn receive_v2_pack(
+  repo: &Repository,
+  advertisement: &Advertisement,
+  response: &[u8],
+  options: &FetchOptions,
+) -> Result<FetchOutcome, TransportError> {
+  let sideband_all = advertisement.command_has("fetch", "sideband-all");
+  let mut reader = PacketReader::new(response);
+  let mut outcome = FetchOutcome::default();
+  loop {
+    match read_section_line(&mut reader, sideband_all, &mut outcome.progress)? {
+      Some(header) if header == "packfile" => break,
+      Some(header) if header == "shallow-info" => {
+        while let Some(line) = read_section_line(&mut reader, sideband_all, &mut outcome.progress)?
+        {
+          parse_shallow_line(line, &mut outcome)?;
+        }
+      }
+      // The lines of sections like `acknowledgments` or `wanted-refs`
+      // only matter to options that aren't used
+      Some(_) => {
+        while read_section_line(&mut reader, sideband_all, &mut outcome.progress)?.is_some() {}
+      }
+      None => return Err(TransportError::Protocol("response has no packfile".into())),
+    }
+  }
+  let mut pack = Vec::new();
+  demux(&mut reader, &mut pack, &mut outcome.progress)?;
+  outcome.objects = store_pack(repo, &pack, options)?;
+  Ok(outcome)
+}
+
+/// The protocol v2 `object-info` command asking for the sizes of `oids`
+pub(crate) fn object_info_request(
+  advertisement: &Advertisement,
+  oids: &[OID],
+) -> Result<Vec<u8>, TransportError> {
+  if !advertisement.has_capability("object-info") {
+    return Err(TransportError::Unsupported(
+      "the remote can't tell the sizes of objects".into(),
+    ));
+  }
+  let command = v2_command("object-info", advertisement);
+  let mut arguments = vec![b"size".to_vec()];
+  arguments.extend(oids.iter().map(|oid| format!("oid {}", oid).into_bytes()));
+  encode_lines(
+    command
+      .iter()
+      .map(|line| Some(&line[..]))
+      .chain([None])
+      .chain(arguments.iter().map(|line| Some(&line[..]))),
+  )
+}
+
+/// Parse the response to [`object_info_request`], which has the size of
+/// every object asked for that the remote has
+pub(crate) fn parse_object_info(
+  response: &[u8],
+) -> Result<Vec<(OID, Option<u64>)>, TransportError> {
+  let mut reader = PacketReader::new(response);
+  if reader.read_line()? != Some(b"size".as_bstr()) {
+    return Err(TransportError::Protocol(
+      "object-info response doesn't start with size".into(),
+    ));
+  }
+  let mut sizes = Vec::new();
+  while let Some(line) = reader.read_line()? {
+    let mut fields = line.splitn_str(2, " ");
+    let oid = parse_oid(fields.next().unwrap_or_default())?;
+    // Objects the remote doesn't have come without a size
+    let size = match fields.next().unwrap_or_default() {
+      b"" => None,
+      size => Some(
+        size
+          .to_str()
+          .ok()
+          .and_then(|size| size.parse().ok())
+          .ok_or_else(|| TransportError::Protocol(format!("invalid object size {:?}", line)))?,
+      ),
+    };
+    sizes.push((oid, size));
+  }
+  Ok(sizes)
+}
+
+/// A change to a ref of a remote to ask for when pushing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushUpdate {
+  /// The full name of the ref on the remote, like `refs/heads/master`
+  pub name: BString,
+  /// What the ref should point at, or `None` to delete it
+  pub new: Option<OID>,
+  /// Whether to update the ref even if it would lose commits the remote
+  /// has, like `git push --force`
+  pub force: bool,
+}
+
+impl PushUpdate {
+  /// Point the ref `name` at `new`
+  pub fn new(name: impl Into<BString>, new: OID) -> Self {
+    Self {
+      name: name.into(),
+      new: Some(new),
+      force: false,
+    }
+  }
+
+  /// Delete the ref `name`
+  pub fn delete(name: impl Into<BString>) -> Self {
+    Self {
+      name: name.into(),
+      new: None,
+      force: false,
+    }
+  }
+
+  /// Set whether the update is forced
+  pub fn force(mut self, force: bool) -> Self {
+    self.force = force;
+    self
+  }
+}
+
+/// What became of a ref that was pushed
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PushStatus {
+  /// The remote updated the ref
+  Ok,
+  /// The ref already pointed at the new value so it wasn't sent
+  UpToDate,
+  /// The ref wasn't sent since the remote would lose commits and the
+  /// update wasn't forced
+  NonFastForward,
+  /// The ref wasn't sent since what it points at on the remote isn't here,
+  /// so there's no telling if commits would be lost. Fetching it first
+  /// sorts that out.
+  FetchFirst,
+  /// The ref was refused with a reason, like `hook declined`
+  Rejected(BString),
+}
+
+/// A ref that was pushed and what became of it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PushedRef {
+  /// The full name of the ref on the remote
+  pub name: BString,
+  /// What the ref pointed at on the remote before, `None` if it didn't
+  /// exist
+  pub old: Option<OID>,
+  /// What the ref was to point at, `None` to delete it
+  pub new: Option<OID>,
+  /// Whether it happened
+  pub status: PushStatus,
+}
+
+/// What a push did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PushOutcome {
+  /// The refs in the order they were given
+  pub refs: Vec<PushedRef>,
+  /// Progress messages the remote sent while storing what was pushed
+  pub progress: BString,
+}
+
+impl PushOutcome {
+  /// Whether every ref was updated or already up to date
+  pub fn is_ok(&self) -> bool {
+    self
+      .refs
+      .iter()
+      .all(|r| matches!(r.status, PushStatus::Ok | PushStatus::UpToDate))
+  }
+}
+
+/// Whether `ancestor` is `commit` or reachable from it
+pub(crate) fn is_ancestor(
+  repo: &Repository,
+  ancestor: &OID,
+  commit: &OID,
+) -> Result<bool, TransportError> {
+  let mut walk = repo.rev_walk();
+  match walk.push(ancestor).and_then(|walk| walk.hide(commit)) {
+    Ok(_) => {}
+    Err(RevWalkError::NotACommit { .. }) => return Ok(false),
+    Err(e) => return Err(e.into()),
+  }
+  match walk.next() {
+    Some(Err(e)) => Err(e.into()),
+    found => Ok(found.is_none()),
+  }
+}
+
+/// Every tree and blob in `trees` recursively that isn't in `skip`, with
+/// its path and whether it's a tree
+fn walk_trees(
+  repo: &Repository,
+  trees: impl IntoIterator<Item = OID>,
+  skip: &HashSet<OID>,
+  mut visit: impl FnMut(OID, &[u8], bool) -> bool,
+) -> Result<(), TransportError> {
+  let mut stack: Vec<(OID, BString)> = trees.into_iter().map(|oid| (oid, "".into())).collect();
+  while let Some((oid, prefix)) = stack.pop() {
+    if skip.contains(&oid) || !visit(oid, &prefix, true) {
+      continue;
+    }
+    for entry in repo.odb().read_tree(&oid)?.entries() {
+      let mut path = prefix.clone();
+      if !path.is_empty() {
+        path.push(b'/');
+      }
+      path.extend_from_slice(entry.name());
+      match entry.mode() {
+        FileMode::Tree => stack.push((*entry.oid(), path)),
+        // Submodule commits are in another repository
+        FileMode::GitLink => {}
+        _ if skip.contains(entry.oid()) => {}
+        _ => {
+          visit(*entry.oid(), &path, false);
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+/// An object to send, with its path and the object they have to make a
+/// delta of it against
+type ToSend = (OID, Option<BString>, Option<OID>);
+
+/// The objects someone who has `haves` needs to get `wants`, leaving out
+/// what's reachable from the `haves` that are here and the parents of the
+/// commits being sent. Trees and blobs come with their paths, and each
+/// blob with the blob at the same path in what they have, if there is one,
+/// to make a delta against.
+pub(crate) fn objects_to_send(
+  repo: &Repository,
+  wants: &[OID],
+  haves: &[OID],
+) -> Result<Vec<ToSend>, TransportError> {
+  let odb = repo.odb();
+  // A pack bitmap answers this without walking history, but doesn't say
+  // which paths the blobs are at to find bases for deltas
+  if let Some(objects) = bitmap::objects_to_send(odb, wants, haves)? {
+    return Ok(objects.into_iter().map(|oid| (oid, None, None)).collect());
+  }
+  let mut walk = repo.rev_walk();
+  let mut sent = Vec::new();
+  let mut seen: HashSet<OID> = haves.iter().copied().collect();
+  let mut roots = Vec::new();
+  for want in wants {
+    let mut oid = *want;
+    while !seen.contains(&oid) {
+      let object = odb.read(&oid)?;
+      match object.kind {
+        ObjectKind::Tag => {
+          seen.insert(oid);
+          sent.push((oid, None, None));
+          oid = *Tag::parse(&object.data).map_err(OdbError::from)?.object();
+        }
+        ObjectKind::Commit => {
+          walk.push(&oid)?;
+          break;
+        }
+        ObjectKind::Tree | ObjectKind::Blob => {
+          roots.push(oid);
+          break;
+        }
+      }
+    }
+  }
+  let mut have_commits = Vec::new();
+  for have in haves {
+    match walk.hide(have) {
+      Ok(_) => have_commits.push(*have),
+      // What isn't here, or isn't history, has nothing to leave out
+      Err(RevWalkError::Odb(OdbError::NotFound(_)) | RevWalkError::NotACommit { .. }) => {}
+      Err(e) => return Err(e.into()),
+    }
+  }
+  let commits = walk.collect::<Result<Vec<OID>, _>>()?;
+  let commit_set: HashSet<OID> = commits.iter().copied().collect();
+
+  // Everything in the trees of the commits they have is left out, and the
+  // blobs are remembered by path as bases for deltas
+  let mut boundary = Vec::new();
+  for oid in &commits {
+    let commit = odb.read_commit(oid)?;
+    sent.push((*oid, None, None));
+    roots.push(*commit.tree());
+    boundary.extend(
+      commit
+        .parents()
+        .iter()
+        .filter(|p| !commit_set.contains(p))
+        .copied(),
+    );
+  }
+  let mut had = HashSet::new();
+  let mut bases = HashMap::new();
+  let mut boundary_trees = Vec::new();
+  for oid in have_commits.iter().chain(&boundary) {
+    match odb.read_commit(oid) {
+      Ok(commit) => boundary_trees.push(*commit.tree()),
+      Err(OdbError::NotFound(_) | OdbError::WrongKind { .. }) => {}
+      Err(e) => return Err(e.into()),
+    }
+  }
+  walk_trees(repo, boundary_trees, &HashSet::new(), |oid, path, tree| {
+    if !tree {
+      bases.entry(BString::from(path)).or_insert(oid);
+    }
+    had.insert(oid)
+  })?;
+
+  walk_trees(repo, roots, &had, |oid, path, tree| {
+    if !seen.insert(oid) {
+      return false;
+    }
+    let base = match tree {
+      true => None,
+      false => bases.get(path.as_bstr()).copied(),
+    };
+    sent.push((oid, Some(path.into()), base));
+    true
+  })?;
+  Ok(sent)
+}
+
+/// Write a pack to `out` of everything reachable from `wants` that isn't
+/// from `haves`, returning `out` and the checksum of the pack. Objects are
+/// stored as deltas against others in the pack where that's smaller, as
+/// many and as deep as `pack.window` and `pack.depth` allow. A thin pack
+/// also has blobs as deltas against the blobs at the same paths in the
+/// commits the other side has when that's much smaller, without it the
+/// pack can be read on its own. The objects written are reported to the
+/// [`Progress`][crate::Progress] of the [`Odb`][crate::Odb].
+pub(crate) fn write_pack_for<W: io::Write>(
+  repo: &Repository,
+  wants: &[OID],
+  haves: &[OID],
+  thin: bool,
+  out: W,
+) -> Result<(W, OID), TransportError> {
+  // What's sent is what's stored, whatever replaces it here
+  let original = repo.original_objects();
+  let repo = &*original;
+  let objects = objects_to_send(repo, wants, haves)?;
+  let candidates: Vec<DeltaCandidate<'_>> = objects
+    .iter()
+    .map(|(oid, path, _)| DeltaCandidate {
+      oid: *oid,
+      path: path.as_ref().map(|path| path.as_bytes()),
+    })
+    .collect();
+  let options = DeltaOptions::from_config(repo.config())?;
+  let mut deltas = delta::find_deltas(repo.odb(), &candidates, &options)?;
+  let mut offsets = vec![None; objects.len()];
+  let mut writer = PackWriter::new(out, objects.len() as u32)?;
+  let progress = repo.odb().progress();
+  let mut written = 0;
+  for i in 0..objects.len() {
+    if progress.is_cancelled() {
+      return Err(OdbError::Cancelled.into());
+    }
+    // The base of a delta has to be written before it to have an offset,
+    // and so does the base of that one
+    let mut chain = vec![i];
+    while let Some((base, _)) = &deltas[*chain.last().unwrap()] {
+      if offsets[*base].is_some() {
+        break;
+      }
+      chain.push(*base);
+    }
+    for idx in chain.into_iter().rev() {
+      if offsets[idx].is_some() {
+        continue;
+      }
+      offsets[idx] = Some(writer.offset());
+      written += 1;
+      progress.update(
+        ProgressStage::WritingObjects,
+        written,
+        Some(objects.len() as u64),
+      );
+      if let Some((base, delta)) = deltas[idx].take() {
+        let base = offsets[base].unwrap();
+        writer.add(&PackObject::OfsDelta { base, delta })?;
+        continue;
+      }
+      let (oid, _, base) = &objects[idx];
+      let object = repo.odb().read(oid)?;
+      if let Some(base) = base.filter(|_| thin) {
+        let delta = delta::make_delta(&repo.odb().read(&base)?.data, &object.data);
+        if delta.len() < object.data.len() / 2 {
+          writer.add(&PackObject::RefDelta { base, delta })?;
+          continue;
+        }
+      }
+      writer.add(&PackObject::Whole(object))?;
+    }
+  }
+  Ok(writer.finish()?)
+}
+
+/// Decide what can be done about `updates` before asking the remote that
+/// sent `advertisement`, returning the refs as they stand and which of
+/// them are left for the remote. Refs that are up to date or can't be
+/// pushed without losing commits get their status right away.
+pub(crate) fn plan_push(
+  repo: &Repository,
+  advertisement: &Advertisement,
+  updates: &[PushUpdate],
+) -> Result<(Vec<PushedRef>, Vec<usize>), TransportError> {
+  let mut refs = Vec::new();
+  let mut pending = Vec::new();
+  for update in updates {
+    let old = advertisement.get(&update.name).map(|r| r.oid);
+    let status = match (old, update.new) {
+      _ if old == update.new => Some(PushStatus::UpToDate),
+      (_, None) if !advertisement.has_capability("delete-refs") => Some(PushStatus::Rejected(
+        "the remote doesn't allow deleting refs".into(),
+      )),
+      (Some(old), Some(new)) if !update.force => match repo.odb().read(&old) {
+        Err(OdbError::NotFound(_)) => Some(PushStatus::FetchFirst),
+        Err(e) => return Err(e.into()),
+        Ok(_) if !is_ancestor(repo, &old, &new)? => Some(PushStatus::NonFastForward),
+        Ok(_) => None,
+      },
+      _ => None,
+    };
+    if status.is_none() {
+      pending.push(refs.len());
+    }
+    refs.push(PushedRef {
+      name: update.name.clone(),
+      old,
+      new: update.new,
+      // The status of a ref that's sent comes from the report, so it stays
+      // like this only if the remote doesn't say
+      status: status.unwrap_or_else(|| PushStatus::Rejected("no status reported".into())),
+    });
+  }
+  Ok((refs, pending))
+}
+
+/// Build the request that pushes `updates` to the remote that sent
+/// `advertisement`, along with the refs as they stand before it's sent.
+/// Only the refs [`plan_push`] leaves for the remote are in the request,
+/// which is `None` if that leaves nothing to send.
+pub(crate) fn push_request(
+  repo: &Repository,
+  advertisement: &Advertisement,
+  updates: &[PushUpdate],
+) -> Result<(Option<Vec<u8>>, Vec<PushedRef>), TransportError> {
+  let (refs, pending) = plan_push(repo, advertisement, updates)?;
+  if pending.is_empty() {
+    return Ok((None, refs));
+  }
+
+  let mut capabilities = Vec::new();
+  for capability in ["report-status-v2", "side-band-64k"] {
+    if advertisement.has_capability(capability) {
+      capabilities.push(capability.to_string());
+    }
+  }
+  if !capabilities.iter().any(|c| c.starts_with("report-status")) {
+    capabilities.push("report-status".into());
+  }
+  capabilities.push(format!("agent=libgit-rs/{}", env!("CARGO_PKG_VERSION")));
+  let zero = OID::from_bytes(&[0; 20]).unwrap();
+  let mut request = Vec::new();
+  for (i, reference) in pending.iter().map(|&i| &refs[i]).enumerate() {
+    let mut line = format!(
+      "{} {} {}",
+      reference.old.unwrap_or(zero),
+      reference.new.unwrap_or(zero),
+      reference.name
+    )
+    .into_bytes();
+    if i == 0 {
+      line.push(0);
+      line.extend_from_slice(capabilities.join(" ").as_bytes());
+    }
+    line.push(b'\n');
+    Packet::Data(line.as_bstr()).encode(&mut request)?;
+  }
+  Packet::Flush.encode(&mut request)?;
+
+  // A pack is only sent when something is being created or updated
+  let wants: Vec<OID> = pending.iter().filter_map(|&i| refs[i].new).collect();
+  if !wants.is_empty() {
+    let haves: Vec<OID> = advertisement
+      .refs
+      .iter()
+      .flat_map(|r| [Some(r.oid), r.peeled])
+      .flatten()
+      .collect();
+    request = write_pack_for(repo, &wants, &haves, true, request)?.0;
+  }
+  Ok((Some(request), refs))
+}
+
+/// Read the `report-status` or `report-status-v2` the remote sent back
+/// for a [`push_request`] into the statuses of `refs`. If the remote
+/// couldn't store the pack nothing was updated and that's an error.
+fn receive_push_report(
+  advertisement: &Advertisement,
+  response: &[u8],
+  refs: &mut [PushedRef],
+) -> Result<BString, TransportError> {
+  let mut progress = BString::from("");
+  let mut report = Vec::new();
+  let report = if advertisement.has_capability("side-band-64k") {
+    demux(&mut PacketReader::new(response), &mut report, &mut progress)?;
+    &report[..]
+  } else {
+    response
+  };
+  let mut reader = PacketReader::new(report);
+  match reader.read_line()? {
+    Some(line) if line == "unpack ok" => {}
+    Some(line) => match line.strip_prefix(b"unpack ") {
+      Some(reason) => {
+        return Err(TransportError::Remote(
+          format!("failed to store the pack: {}", reason.as_bstr()).into(),
+        ))
+      }
+      None => {
+        return Err(TransportError::Protocol(format!(
+          "invalid report {:?}",
+          line
+        )))
+      }
+    },
+    None => return Err(TransportError::Protocol("empty report".into())),
+  }
+  let mut last = None;
+  while let Some(line) = reader.read_line()? {
+    let (status, rest) = line.split_at(line.find_byte(b' ').unwrap_or(line.len()));
+    let rest = rest.get(1..).unwrap_or_default();
+    match status {
+      b"ok" | b"ng" => {
+        let (name, reason) = match rest.find_byte(b' ') {
+          Some(space) => (&rest[..space], &rest[space + 1..]),
+          None => (rest, &b""[..]),
+        };
+        last = refs.iter().position(|r| r.name == name);
+        if let Some(i) = last {
+          refs[i].status = match status {
+            b"ok" => PushStatus::Ok,
+            _ => PushStatus::Rejected(reason.into()),
+          };
+        }
+      }
+      // Options of report-status-v2 tell what a ref was really changed to
+      // when the remote rewrote it, like one taking pushes for review
+      b"option" => {
+        let (Some(i), Some(space)) = (last, rest.find_byte(b' ')) else {
+          continue;
+        };
+        let (key, value) = (&rest[..space], &rest[space + 1..]);
+        match key {
+          b"refname" => refs[i].name = value.into(),
+          b"old-oid" => refs[i].old = Some(parse_oid(value)?),
+          b"new-oid" => refs[i].new = Some(parse_oid(value)?),
+          _ => {}
+        }
+      }
+      _ => {
+        return Err(TransportError::Protocol(format!(
+          "invalid report {:?}",
+          line
+        )))
+      }
+    }
+  }
+  Ok(progress)
+}
+
+/// A way to talk to another repository. [`http::HttpTransport`],
+/// [`ssh::SshTransport`] and [`local::LocalTransport`] are built in, and
+/// anything else, like a proprietary protocol or an in-process server, can
+/// be plugged in by implementing this and registering it in a
+/// [`TransportRegistry`].
+pub trait Transport {
+  /// The refs and capabilities of the repository, which are requested the
+  /// first time and kept for the fetches after that
+  fn list_refs(&mut self) -> Result<&Advertisement, TransportError>;
+
+  /// The refs and capabilities of the repository as it shows them to
+  /// someone pushing, which can differ from what fetches see. They're
+  /// requested the first time and kept until the next push.
+  fn list_push_refs(&mut self) -> Result<&Advertisement, TransportError>;
+
+  /// Fetch `wants` and everything reachable from them into `repo`, leaving
+  /// out what's reachable from the commits the refs of `repo` are at. The
+  /// objects are stored as a pack, and updating refs to point at them is
+  /// left to the caller.
+  fn fetch(&mut self, repo: &Repository, wants: &[OID]) -> Result<FetchOutcome, TransportError>;
+
+  /// [`Transport::fetch`] leaving out what `options` say to. Transports
+  /// that can't leave anything out only do fetches with the default
+  /// options.
+  fn fetch_with_options(
+    &mut self,
+    repo: &Repository,
+    wants: &[OID],
+    options: &FetchOptions,
+  ) -> Result<FetchOutcome, TransportError> {
+    if *options != FetchOptions::default() {
+      return Err(TransportError::Unsupported(
+        "the transport can't do shallow or filtered fetches".into(),
+      ));
+    }
+    self.fetch(repo, wants)
+  }
+
+  /// The sizes of `oids` without fetching them, from the protocol v2
+  /// `object-info` command, or `None` for those the remote doesn't have
+  fn object_info(&mut self, oids: &[OID]) -> Result<Vec<(OID, Option<u64>)>, TransportError> {
+    let _ = oids;
+    Err(TransportError::Unsupported(
+      "the transport can't ask for object sizes".into(),
+    ))
+  }
+
+  /// Push `updates` from `repo`, like `git push`, sending the objects the
+  /// remote needs as a thin pack. Updates that would lose commits on the
+  /// remote without being forced aren't sent, and what became of each ref
+  /// is in the outcome. Failing as a whole only happens when the remote
+  /// can't be talked to or can't store the pack.
+  fn push(
+    &mut self,
+    repo: &Repository,
+    updates: &[PushUpdate],
+  ) -> Result<PushOutcome, TransportError>;
+}
+
+type TransportFactory =
+  Box<dyn Fn(&str) -> Result<Box<dyn Transport>, TransportError> + Send + Sync>;
+
+/// Which [`Transport`] to use for a URL, picked by its scheme. URLs like
+/// `host:path` that `scp` takes have the scheme `ssh`, and paths without a
+/// scheme have the scheme `file`.
+pub struct TransportRegistry {
+  factories: HashMap<String, TransportFactory>,
+}
+
+impl TransportRegistry {
+  /// A registry without any transports
+  pub fn empty() -> Self {
+    Self {
+      factories: HashMap::new(),
+    }
+  }
+
+  /// Use `factory` to make transports for URLs with `scheme`, instead of
+  /// whatever was used for it before
+  pub fn register(
+    &mut self,
+    scheme: impl Into<String>,
+    factory: impl Fn(&str) -> Result<Box<dyn Transport>, TransportError> + Send + Sync + 'static,
+  ) -> &mut Self {
+    self
+      .factories
+      .insert(scheme.into().to_ascii_lowercase(), Box::new(factory));
+    self
+  }
+
+  /// Whether there's a transport for `scheme`
+  pub fn has_scheme(&self, scheme: &str) -> bool {
+    self.factories.contains_key(&scheme.to_ascii_lowercase())
+  }
+
+  /// Make a transport for `url`. Nothing is sent until it's used.
+  pub fn connect(&self, url: &str) -> Result<Box<dyn Transport>, TransportError> {
+    match self.factories.get(&url_scheme(url).to_ascii_lowercase()) {
+      Some(factory) => factory(url),
+      None => Err(TransportError::UnsupportedUrl(url.into())),
+    }
+  }
+}
+
+impl Default for TransportRegistry {
+  /// A registry with the transports built in
+  fn default() -> Self {
+    let mut registry = Self::empty();
+    for scheme in ["http", "https"] {
+      registry.register(scheme, |url| Ok(Box::new(http::HttpTransport::new(url)?)));
+    }
+    for scheme in ["ssh", "git+ssh", "ssh+git"] {
+      registry.register(scheme, |url| Ok(Box::new(ssh::SshTransport::new(url)?)));
+    }
+    registry.register("file", |url| {
+      let path = url.strip_prefix("file://").unwrap_or(url);
+      match crate::bundle::is_bundle(path) {
+        true => Ok(Box::new(bundle::BundleTransport::new(url)?)),
+        false => Ok(Box::new(local::LocalTransport::new(url)?)),
+      }
+    });
+    registry
+  }
+}
+
+impl fmt::Debug for TransportRegistry {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut schemes: Vec<&String> = self.factories.keys().collect();
+    schemes.sort();
+    f.debug_struct("TransportRegistry")
+      .field("schemes", &schemes)
+      .finish()
+  }
+}
+
+/// Make a transport for `url` with one of the transports built in
+pub fn connect(url: &str) -> Result<Box<dyn Transport>, TransportError> {
+  TransportRegistry::default().connect(url)
+}
+
+/// The scheme of `url`, which is `ssh` for URLs like `host:path` and
+/// `file` for paths
+fn url_scheme(url: &str) -> &str {
+  if let Some((scheme, _)) = url.split_once("://") {
+    let valid = scheme
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    if !scheme.is_empty() && valid {
+      return scheme;
+    }
+  }
+  if ssh::SshUrl::parse(url).is_some() {
+    "ssh"
+  } else {
+    "file"
+  }
+}
+
+/// Fetch `wants`, which [`fetch_wants`] has already been through, into
+/// `repo` from the remote that sent `advertisement`, with `send` sending
+/// the request to the remote's upload-pack and returning the response.
+/// The shallow commits of `repo` are updated with what the remote says.
+pub(crate) fn fetch_pack(
+  repo: &Repository,
+  advertisement: &Advertisement,
+  wants: &[OID],
+  options: &FetchOptions,
+  send: impl FnOnce(Vec<u8>) -> Result<Vec<u8>, TransportError>,
+) -> Result<FetchOutcome, TransportError> {
+  let request = fetch_request(repo, advertisement, wants, options)?;
+  receive_fetch_response(repo, advertisement, &send(request)?, options)
+}
+
+/// The request [`fetch_pack`] sends, in the version of the protocol the
+/// remote spoke in `advertisement`
+pub(crate) fn fetch_request(
+  repo: &Repository,
+  advertisement: &Advertisement,
+  wants: &[OID],
+  options: &FetchOptions,
+) -> Result<Vec<u8>, TransportError> {
+  let haves = local_haves(repo, options.negotiation(repo))?;
+  let shallow = repo.shallow_commits()?;
+  match advertisement.version {
+    ProtocolVersion::V1 => v1_fetch_request(advertisement, wants, &haves, &shallow, options),
+    ProtocolVersion::V2 => v2_fetch_request(advertisement, wants, &haves, &shallow, options),
+  }
+}
+
+/// Store the pack in the `response` to a [`fetch_request`] in `repo` and
+/// update its shallow commits with what the remote says
+pub(crate) fn receive_fetch_response(
+  repo: &Repository,
+  advertisement: &Advertisement,
+  response: &[u8],
+  options: &FetchOptions,
+) -> Result<FetchOutcome, TransportError> {
+  let outcome = match advertisement.version {
+    ProtocolVersion::V1 => receive_v1_pack(repo, advertisement, response, options)?,
+    ProtocolVersion::V2 => receive_v2_pack(repo, advertisement, response, options)?,
+  };
+  repo.update_shallow(&outcome.shallow, &outcome.unshallow)?;
+  Ok(outcome)
+}
+
+/// Push `updates` from `repo` to the remote that sent `advertisement`,
+/// with `send` sending the request to the remote's receive-pack and
+/// returning the response. Nothing is sent if every ref was decided
+/// without asking the remote.
+pub(crate) fn push_pack(
+  repo: &Repository,
+  advertisement: &Advertisement,
+  updates: &[PushUpdate],
+  send: impl FnOnce(Vec<u8>) -> Result<Vec<u8>, TransportError>,
+) -> Result<PushOutcome, TransportError> {
+  let (request, refs) = push_request(repo, advertisement, updates)?;
+  let response = request.map(send).transpose()?;
+  receive_push_response(advertisement, response.as_deref(), refs)
+}
+
+/// What became of the refs of a push from the remote's `response` to the
+/// [`push_request`] for them, if there was anything to send
+pub(crate) fn receive_push_response(
+  advertisement: &Advertisement,
+  response: Option<&[u8]>,
+  mut refs: Vec<PushedRef>,
+) -> Result<PushOutcome, TransportError> {
+  let mut outcome = PushOutcome::default();
+  if let Some(response) = response {
+    outcome.progress = receive_push_report(advertisement, response, &mut refs)?;
+  }
+  outcome.refs = refs;
+  Ok(outcome)
+}
+
+#[derive(Error, Debug)]
+/// Errors related to talking to other repositories
+pub enum TransportError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("HTTP {status} from {url}")]
+  Http { status: u16, url: String },
+  #[error("protocol error: {0}")]
+  Protocol(String),
+  #[error("remote error: {0}")]
+  Remote(BString),
+  #[error("{0}")]
+  PktLine(#[from] PktLineError),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  RevWalk(#[from] RevWalkError),
+  #[error("{0}")]
+  Repository(#[from] RepositoryError),
+  #[error("{0}")]
+  UploadPack(#[from] Box<UploadPackError>),
+  #[error("{0}")]
+  Bundle(#[from] Box<BundleError>),
+  #[error("{0}")]
+  Shallow(#[from] ShallowError),
+  #[error("{0}")]
+  Credential(#[from] CredentialError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0:?} is not a URL this transport can use")]
+  UnsupportedUrl(String),
+  #[error("{0}")]
+  Unsupported(String),
+}
+
+#[test]
+fn parse_advertisements() {
+  let oid = |byte: u8| OID::from_bytes(&[byte; 20]).unwrap();
+  let lines = [
+    format!(
+      "{} HEAD\0multi_ack side-band-64k symref=HEAD:refs/heads/main agent=git/2\n",
+      oid(1)
+    ),
+    format!("{} refs/heads/main\n", oid(1)),
+    format!("{} refs/tags/v1\n", oid(2)),
+    format!("{} refs/tags/v1^{{}}\n", oid(1)),
+  ];
+  let response = encode_lines(lines.iter().map(|line| Some(line.as_bytes()))).unwrap();
+  let advertisement = parse_advertisement(&mut PacketReader::new(&response)).unwrap();
+  assert_eq!(ProtocolVersion::V1, advertisement.version);
+  assert_eq!(3, advertisement.refs.len());
+  let head = advertisement.get("HEAD").unwrap();
+  assert_eq!(Some("refs/heads/main".into()), head.symref_target);
+  assert_eq!(
+    Some(oid(1)),
+    advertisement.get("refs/tags/v1").unwrap().peeled
+  );
+  assert!(advertisement.has_capability("side-band-64k"));
+  assert!(!advertisement.has_capability("side-band"));
+  assert_eq!(Some("git/2".into()), advertisement.capability("agent"));
+
+  let response = encode_lines([
+    Some(&b"version 2\n"[..]),
+    Some(b"ls-refs=unborn\n"),
+    Some(b"fetch=shallow sideband-all\n"),
+  ])
+  .unwrap();
+  let mut advertisement = parse_advertisement(&mut PacketReader::new(&response)).unwrap();
+  assert_eq!(ProtocolVersion::V2, advertisement.version);
+  assert!(advertisement.command_has("fetch", "sideband-all"));
+  assert!(!advertisement.command_has("fetch", "filter"));
+  let lines = [
+    format!("{} HEAD symref-target:refs/heads/main\n", oid(1)),
+    "unborn refs/heads/new\n".into(),
+    format!("{} refs/tags/v1 peeled:{}\n", oid(2), oid(1)),
+  ];
+  let response = encode_lines(lines.iter().map(|line| Some(line.as_bytes()))).unwrap();
+  parse_ls_refs(&mut PacketReader::new(&response), &mut advertisement).unwrap();
+  assert_eq!(
+    vec![
+      AdvertisedRef {
+        name: "HEAD".into(),
+        oid: oid(1),
+        peeled: None,
+        symref_target: Some("refs/heads/main".into()),
+      },
+      AdvertisedRef {
+        name: "refs/tags/v1".into(),
+        oid: oid(2),
+        peeled: Some(oid(1)),
+        symref_target: None,
+      },
+    ],
+    advertisement.refs
+  );
+
+  let response = encode_lines([Some(&b"ERR access denied\n"[..])]).unwrap();
+  assert!(matches!(
+    parse_advertisement(&mut PacketReader::new(&response)),
+    Err(TransportError::Remote(message)) if message == "access denied"
+  ));
+}
+
+#[test]
+fn v2_commands() {
+  let oid = |byte: u8| OID::from_bytes(&[byte; 20]).unwrap();
+  let advertisement = Advertisement {
+    version: ProtocolVersion::V2,
+    refs: Vec::new(),
+    capabilities: vec!["ls-refs".into(), "fetch=shallow sideband-all".into()],
+  };
+  let request = ls_refs_request(&advertisement, &["refs/heads/".into()]).unwrap();
+  assert!(request.ends_with(b"001aref-prefix refs/heads/0000"));
+  let mut listed = advertisement.clone();
+  listed.refs = ["HEAD", "refs/heads/main", "refs/tags/v1"]
+    .iter()
+    .map(|name| AdvertisedRef {
+      name: (*name).into(),
+      oid: oid(1),
+      peeled: None,
+      symref_target: None,
+    })
+    .collect();
+  retain_prefixed(&mut listed, &["refs/heads/".into(), "HEAD".into()]);
+  assert_eq!(2, listed.refs.len());
+
+  let options = FetchOptions {
+    depth: Some(1),
+    deepen_not: vec!["refs/heads/old".into()],
+    ..FetchOptions::default()
+  };
+  let request = v2_fetch_request(&advertisement, &[oid(1)], &[], &[oid(2)], &options).unwrap();
+  let request = request.as_bstr();
+  assert!(request.contains_str("sideband-all"));
+  assert!(request.contains_str("deepen 1"));
+  assert!(request.contains_str("deepen-not refs/heads/old"));
+  assert!(request.contains_str(format!("shallow {}", oid(2))));
+  let filtered = FetchOptions {
+    filter: Some("blob:none".into()),
+    ..FetchOptions::default()
+  };
+  assert!(matches!(
+    v2_fetch_request(&advertisement, &[oid(1)], &[], &[], &filtered),
+    Err(TransportError::Unsupported(_))
+  ));
+
+  // With sideband-all every section comes on a band, with progress and
+  // keepalives in between
+  let tmp_dir = tempdir::TempDir::new("transport_test").unwrap();
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let (pack, _) = PackWriter::new(Vec::new(), 0).unwrap().finish().unwrap();
+  let shallow = format!("\x01shallow {}\n", oid(1));
+  let response = encode_lines([
+    Some(&b"\x01shallow-info\n"[..]),
+    Some(shallow.as_bytes()),
+    Some(b"\x02"),
+    None,
+    Some(b"\x01packfile\n"),
+    Some(b"\x02Counting objects\n"),
+    Some(&[&b"\x01"[..], &pack].concat()),
+  ])
+  .unwrap();
+  let outcome = receive_v2_pack(&repo, &advertisement, &response, &options).unwrap();
+  assert_eq!(vec![oid(1)], outcome.shallow);
+  assert_eq!("Counting objects\n", outcome.progress);
+  assert!(outcome.objects.is_empty());
+
+  assert!(matches!(
+    object_info_request(&advertisement, &[oid(1)]),
+    Err(TransportError::Unsupported(_))
+  ));
+  let lines = [
+    "size\n".to_string(),
+    format!("{} 12\n", oid(1)),
+    format!("{} \n", oid(2)),
+  ];
+  let response = encode_lines(lines.iter().map(|line| Some(line.as_bytes()))).unwrap();
+  assert_eq!(
+    vec![(oid(1), Some(12)), (oid(2), None)],
+    parse_object_info(&response).unwrap()
+  );
+}
+
+#[test]
+fn registry() {
+  struct Static(Advertisement);
+  impl Transport for Static {
+    fn list_refs(&mut self) -> Result<&Advertisement, TransportError> {
+      Ok(&self.0)
+    }
+    fn list_push_refs(&mut self) -> Result<&Advertisement, TransportError> {
+      Ok(&self.0)
+    }
+    fn fetch(&mut self, _: &Repository, _: &[OID]) -> Result<FetchOutcome, TransportError> {
+      Ok(FetchOutcome::default())
+    }
+    fn push(&mut self, _: &Repository, _: &[PushUpdate]) -> Result<PushOutcome, TransportError> {
+      Ok(PushOutcome::default())
+    }
+  }
+
+  assert_eq!("https", url_scheme("https://example.com/repo"));
+  assert_eq!("git+ssh", url_scheme("git+ssh://host/repo"));
+  assert_eq!("ssh", url_scheme("git@example.com:repo.git"));
+  assert_eq!("file", url_scheme("/srv/repo.git"));
+  assert_eq!("file", url_scheme("./dir:with/colon"));
+  assert_eq!("file", url_scheme("file:///srv/repo.git"));
+
+  let mut registry = TransportRegistry::default();
+  assert!(registry.connect("https://example.com/repo").is_ok());
+  assert!(registry.connect("git@example.com:repo.git").is_ok());
+  assert!(registry.connect("/srv/repo.git").is_ok());
+  assert!(matches!(
+    registry.connect("mem://repo").err(),
+    Some(TransportError::UnsupportedUrl(_))
+  ));
+  registry.register("MEM", |url| {
+    let name = url.trim_start_matches("mem://");
+    Ok(Box::new(Static(Advertisement {
+      version: ProtocolVersion::V2,
+      refs: vec![AdvertisedRef {
+        name: format!("refs/heads/{}", name).into(),
+        oid: OID::hash(name),
+        peeled: None,
+        symref_target: None,
+      }],
+      capabilities: Vec::new(),
+    })))
+  });
+  assert!(registry.has_scheme("mem"));
+  let mut transport = registry.connect("mem://main").unwrap();
+  let advertisement = transport.list_refs().unwrap();
+  assert_eq!(
+    OID::hash("main"),
+    advertisement.get("refs/heads/main").unwrap().oid
+  );
+  assert!(!TransportRegistry::empty().has_scheme("https"));
+}
@@ -0,0 +1,298 @@
+//! The smart HTTP transport of [`super::http`] for async code running on
+//! tokio. Requests are made by an [`AsyncHttpClient`] and awaited, and
+//! everything else, building the requests and storing what comes back, is
+//! the same as for [`HttpTransport`][super::http::HttpTransport].
+//!
+//! Credentials aren't asked for since the credential helpers are blocking
+//! processes, a client for a server that wants them sends them itself.
+
+use super::{
+  fetch_request, fetch_wants,
+  http::{
+    add_git_headers, check_advertisement, check_status, parse_response, plain_request,
+    post_request, refs_request, HttpRequest, HttpResponse,
+  },
+  ls_refs_request, parse_ls_refs, push_request, read_advertisement, receive_fetch_response,
+  receive_push_response, retain_prefixed, Advertisement, FetchOptions, FetchOutcome, PacketReader,
+  ProtocolVersion, PushOutcome, PushUpdate, TransportError,
+};
+use crate::{Repository, OID};
+use bstr::BString;
+use std::{future::Future, io};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::TcpStream,
+};
+
+/// Makes the requests of an [`AsyncHttpTransport`]
+pub trait AsyncHttpClient {
+  /// Make `request` and return the response whatever its status is, only
+  /// failing if there isn't one
+  fn send(&self, request: &HttpRequest) -> impl Future<Output = io::Result<HttpResponse>> + Send;
+}
+
+/// The [`AsyncHttpClient`] used unless another one is given. Only plain
+/// `http` URLs are requested, over a new connection each time, `https`
+/// needs a client that brings its own TLS. Redirects aren't followed.
+#[derive(Debug, Clone, Default)]
+pub struct TokioHttpClient;
+
+impl AsyncHttpClient for TokioHttpClient {
+  async fn send(&self, request: &HttpRequest) -> io::Result<HttpResponse> {
+    let (address, head) = plain_request(request)?;
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&request.body).await?;
+    // The connection is closed by the server after the response
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    parse_response(&response, true)
+  }
+}
+
+/// Fetches from and pushes to a repository over smart HTTP without
+/// blocking on the network
+#[derive(Debug)]
+pub struct AsyncHttpTransport<C = TokioHttpClient> {
+  url: String,
+  client: C,
+  version: ProtocolVersion,
+  advertisement: Option<Advertisement>,
+  push_advertisement: Option<Advertisement>,
+  ref_prefixes: Vec<BString>,
+}
+
+impl AsyncHttpTransport {
+  /// Talk to the repository at `url`, which has to be an `http` or `https`
+  /// URL, using protocol v2 if the server has it. Nothing is requested
+  /// until the refs are needed.
+  pub fn new(url: impl Into<String>) -> Result<Self, TransportError> {
+    let mut url = url.into();
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+      return Err(TransportError::UnsupportedUrl(url));
+    }
+    while url.ends_with('/') {
+      url.pop();
+    }
+    Ok(Self {
+      url,
+      client: TokioHttpClient,
+      version: ProtocolVersion::V2,
+      advertisement: None,
+      push_advertisement: None,
+      ref_prefixes: Vec::new(),
+    })
+  }
+}
+
+impl<C: AsyncHttpClient> AsyncHttpTransport<C> {
+  /// Make requests with `client` instead of [`TokioHttpClient`]
+  pub fn with_client<D: AsyncHttpClient>(self, client: D) -> AsyncHttpTransport<D> {
+    AsyncHttpTransport {
+      url: self.url,
+      client,
+      version: self.version,
+      advertisement: self.advertisement,
+      push_advertisement: self.push_advertisement,
+      ref_prefixes: self.ref_prefixes,
+    }
+  }
+
+  /// Ask for `version` of the protocol. Servers that don't have protocol v2
+  /// answer with protocol v1 anyway.
+  pub fn with_protocol(mut self, version: ProtocolVersion) -> Self {
+    self.version = version;
+    self
+  }
+
+  /// Only list the refs starting with one of `prefixes`, like
+  /// [`HttpTransport::with_ref_prefixes`][super::http::HttpTransport::with_ref_prefixes]
+  pub fn with_ref_prefixes<P: Into<BString>>(
+    mut self,
+    prefixes: impl IntoIterator<Item = P>,
+  ) -> Self {
+    self.ref_prefixes = prefixes.into_iter().map(Into::into).collect();
+    self
+  }
+
+  /// The URL of the repository
+  pub fn url(&self) -> &str {
+    &self.url
+  }
+
+  async fn send(
+    &self,
+    mut request: HttpRequest,
+    service: &str,
+  ) -> Result<HttpResponse, TransportError> {
+    add_git_headers(&mut request, self.version, service);
+    let response = self.client.send(&request).await?;
+    check_status(response, request.url)
+  }
+
+  /// `POST` `body` to `service`
+  async fn post(&self, service: &str, body: Vec<u8>) -> Result<Vec<u8>, TransportError> {
+    let request = post_request(&self.url, service, body);
+    Ok(self.send(request, service).await?.body)
+  }
+
+  async fn request_refs(&self, service: &str) -> Result<Advertisement, TransportError> {
+    let request = refs_request(&self.url, service);
+    let url = request.url.clone();
+    let response = self.send(request, service).await?;
+    check_advertisement(&response, &url, service)?;
+    let mut advertisement = read_advertisement(&response.body)?;
+    if service == "git-upload-pack" {
+      if advertisement.version == ProtocolVersion::V2 {
+        let request = ls_refs_request(&advertisement, &self.ref_prefixes)?;
+        let response = self.post(service, request).await?;
+        parse_ls_refs(&mut PacketReader::new(&response), &mut advertisement)?;
+      }
+      retain_prefixed(&mut advertisement, &self.ref_prefixes);
+    }
+    Ok(advertisement)
+  }
+
+  /// The refs and capabilities of the repository, like
+  /// [`Transport::list_refs`][super::Transport::list_refs]
+  pub async fn list_refs(&mut self) -> Result<&Advertisement, TransportError> {
+    if self.advertisement.is_none() {
+      let advertisement = self.request_refs("git-upload-pack").await?;
+      self.advertisement = Some(advertisement);
+    }
+    Ok(self.advertisement.as_ref().unwrap())
+  }
+
+  /// The refs and capabilities of the repository as it shows them to
+  /// someone pushing, like
+  /// [`Transport::list_push_refs`][super::Transport::list_push_refs]
+  pub async fn list_push_refs(&mut self) -> Result<&Advertisement, TransportError> {
+    if self.push_advertisement.is_none() {
+      let advertisement = self.request_refs("git-receive-pack").await?;
+      self.push_advertisement = Some(advertisement);
+    }
+    Ok(self.push_advertisement.as_ref().unwrap())
+  }
+
+  /// Fetch `wants` and everything reachable from them into `repo`, like
+  /// [`Transport::fetch`][super::Transport::fetch]
+  pub async fn fetch(
+    &mut self,
+    repo: &Repository,
+    wants: &[OID],
+  ) -> Result<FetchOutcome, TransportError> {
+    self
+      .fetch_with_options(repo, wants, &FetchOptions::default())
+      .await
+  }
+
+  /// [`AsyncHttpTransport::fetch`] leaving out what `options` say to
+  pub async fn fetch_with_options(
+    &mut self,
+    repo: &Repository,
+    wants: &[OID],
+    options: &FetchOptions,
+  ) -> Result<FetchOutcome, TransportError> {
+    let wants = fetch_wants(repo, wants, options)?;
+    if wants.is_empty() {
+      return Ok(FetchOutcome::default());
+    }
+    let advertisement = self.list_refs().await?.clone();
+    let request = fetch_request(repo, &advertisement, &wants, options)?;
+    let response = self.post("git-upload-pack", request).await?;
+    receive_fetch_response(repo, &advertisement, &response, options)
+  }
+
+  /// Push `updates` from `repo`, like
+  /// [`Transport::push`][super::Transport::push]
+  pub async fn push(
+    &mut self,
+    repo: &Repository,
+    updates: &[PushUpdate],
+  ) -> Result<PushOutcome, TransportError> {
+    let advertisement = self.list_push_refs().await?.clone();
+    let (request, refs) = push_request(repo, &advertisement, updates)?;
+    let response = match request {
+      Some(request) => Some(self.post("git-receive-pack", request).await?),
+      None => None,
+    };
+    // What's there now is only known for the refs that were updated, so
+    // the rest is asked for again next time
+    self.push_advertisement = None;
+    receive_push_response(&advertisement, response.as_deref(), refs)
+  }
+}
+
+#[test]
+fn fetch_and_push() {
+  use super::http::{have_git, serve_http_backend};
+  use crate::{Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  if !have_git() {
+    return;
+  }
+  let tmp_dir = tempdir::TempDir::new("async_http_test").unwrap();
+  let server = Repository::init_bare(tmp_dir.path().join("server.git")).unwrap();
+  let signature = Signature::new("A U Thor", "author@example.com", Time::new(0, 0));
+  let commit = |repo: &Repository, contents: &str, parents: Vec<OID>| {
+    let odb = repo.odb();
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(tree, parents, signature.clone(), signature.clone(), "c\n");
+    odb.write_commit(&commit).unwrap()
+  };
+  let first = commit(&server, "first\n", vec![]);
+  server.refs().write("refs/heads/master", &first).unwrap();
+  let url = format!("{}/server.git", serve_http_backend(tmp_dir.path()));
+
+  let client = Repository::init(tmp_dir.path().join("client")).unwrap();
+  let runtime = tokio::runtime::Builder::new_current_thread()
+    .enable_io()
+    .build()
+    .unwrap();
+  // Fetching has to be something a multi-threaded runtime can spawn
+  fn spawnable<T: Send>(_: &T) {}
+  spawnable(
+    &AsyncHttpTransport::new(&url)
+      .unwrap()
+      .fetch(&client, &[first]),
+  );
+  runtime.block_on(async {
+    let mut transport = AsyncHttpTransport::new(&url).unwrap();
+    let head = transport
+      .list_refs()
+      .await
+      .unwrap()
+      .get("HEAD")
+      .unwrap()
+      .oid;
+    assert_eq!(first, head);
+    let outcome = transport.fetch(&client, &[first]).await.unwrap();
+    assert_eq!(3, outcome.objects.len());
+    let read = client.odb().read_async(&first).await.unwrap();
+    assert_eq!(client.odb().read(&first).unwrap(), read);
+    let pack_dir = client.odb().path().join("pack");
+    let pack = std::fs::read_dir(&pack_dir)
+      .unwrap()
+      .map(|entry| entry.unwrap().path())
+      .find(|path| path.extension() == Some("pack".as_ref()))
+      .unwrap();
+    let copy = crate::Odb::new(tmp_dir.path().join("copy"));
+    let stored = copy
+      .index_pack_async(&std::fs::read(pack).unwrap()[..])
+      .await
+      .unwrap();
+    assert_eq!(outcome.objects, stored.objects);
+
+    let second = commit(&client, "second\n", vec![first]);
+    let outcome = transport
+      .push(&client, &[PushUpdate::new("refs/heads/master", second)])
+      .await
+      .unwrap();
+    assert!(outcome.is_ok());
+    assert_eq!(
+      Some(second),
+      server.refs().resolve("refs/heads/master").unwrap()
+    );
+  });
+}
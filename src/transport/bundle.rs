@@ -0,0 +1,185 @@
+//! Fetching from a [`Bundle`] file, named by a path or a `file://` URL
+//! like a repository on the same machine is. The refs of the bundle are
+//! what's advertised, and a fetch stores the whole pack of the bundle
+//! once the repository has its prerequisites.
+
+use super::{
+  missing_wants, Advertisement, FetchOutcome, ProtocolVersion, PushOutcome, PushUpdate, Transport,
+  TransportError,
+};
+use crate::{AdvertisedRef, Bundle, OdbError, Repository, OID};
+use std::path::{Path, PathBuf};
+
+/// Fetches from a bundle file
+#[derive(Debug)]
+pub struct BundleTransport {
+  path: PathBuf,
+  bundle: Option<Bundle>,
+  advertisement: Option<Advertisement>,
+}
+
+impl BundleTransport {
+  /// Fetch from the bundle at `url`, which is either a path or a `file://`
+  /// URL. The bundle isn't read until the refs are needed.
+  pub fn new(url: impl Into<String>) -> Result<Self, TransportError> {
+    let url = url.into();
+    let path = match url.strip_prefix("file://") {
+      Some(path) => path.to_string(),
+      None if url.contains("://") => return Err(TransportError::UnsupportedUrl(url)),
+      None => url,
+    };
+    if path.is_empty() {
+      return Err(TransportError::UnsupportedUrl(path));
+    }
+    Ok(Self {
+      path: path.into(),
+      bundle: None,
+      advertisement: None,
+    })
+  }
+
+  /// The path of the bundle
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  fn bundle(&mut self) -> Result<&Bundle, TransportError> {
+    if self.bundle.is_none() {
+      self.bundle = Some(Bundle::open(&self.path).map_err(Box::new)?);
+    }
+    Ok(self.bundle.as_ref().unwrap())
+  }
+}
+
+impl Transport for BundleTransport {
+  fn list_refs(&mut self) -> Result<&Advertisement, TransportError> {
+    if self.advertisement.is_none() {
+      let bundle = self.bundle()?;
+      let mut refs: Vec<AdvertisedRef> = bundle
+        .refs()
+        .iter()
+        .map(|(name, oid)| AdvertisedRef {
+          name: name.clone(),
+          oid: *oid,
+          peeled: None,
+          symref_target: None,
+        })
+        .collect();
+      // Bundles don't say what HEAD points at, so like `git clone` it's
+      // taken to be the first branch at the same commit
+      if let Some(head) = refs.iter().position(|r| r.name == "HEAD") {
+        refs[head].symref_target = refs
+          .iter()
+          .find(|r| r.oid == refs[head].oid && r.name.starts_with(b"refs/heads/"))
+          .map(|r| r.name.clone());
+      }
+      self.advertisement = Some(Advertisement {
+        version: ProtocolVersion::V1,
+        refs,
+        capabilities: Vec::new(),
+      });
+    }
+    Ok(self.advertisement.as_ref().unwrap())
+  }
+
+  fn list_push_refs(&mut self) -> Result<&Advertisement, TransportError> {
+    Err(TransportError::Unsupported(
+      "bundles can't be pushed to".into(),
+    ))
+  }
+
+  fn fetch(&mut self, repo: &Repository, wants: &[OID]) -> Result<FetchOutcome, TransportError> {
+    let wants = missing_wants(repo, wants)?;
+    if wants.is_empty() {
+      return Ok(FetchOutcome::default());
+    }
+    let objects = self.bundle()?.unbundle(repo).map_err(Box::new)?;
+    for want in &wants {
+      match repo.odb().read(want) {
+        Ok(_) => {}
+        Err(OdbError::NotFound(_)) => {
+          return Err(TransportError::Remote(
+            format!("{} is not in the bundle", want).into(),
+          ))
+        }
+        Err(e) => return Err(e.into()),
+      }
+    }
+    Ok(FetchOutcome {
+      objects,
+      ..FetchOutcome::default()
+    })
+  }
+
+  fn push(
+    &mut self,
+    _repo: &Repository,
+    _updates: &[PushUpdate],
+  ) -> Result<PushOutcome, TransportError> {
+    Err(TransportError::Unsupported(
+      "bundles can't be pushed to".into(),
+    ))
+  }
+}
+
+#[test]
+fn clone_from_bundle() {
+  use crate::{transport, Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  let tmp_dir = tempdir::TempDir::new("bundle_transport_test").unwrap();
+  let source = Repository::init_bare(tmp_dir.path().join("source.git")).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let odb = source.odb();
+  let blob = odb.write_blob(&Blob::new("contents\n")).unwrap();
+  let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+  let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+  let commit = Commit::new(tree, vec![], signature.clone(), signature, "commit\n");
+  let oid = odb.write_commit(&commit).unwrap();
+  source.refs().write("refs/heads/main", &oid).unwrap();
+  source.refs().write("refs/heads/other", &oid).unwrap();
+  source
+    .refs()
+    .write_symbolic("HEAD", "refs/heads/main")
+    .unwrap();
+  let bundle = Bundle::create(&source, &["HEAD", "refs/heads/main"], &[]).unwrap();
+  let path = tmp_dir.path().join("repo.bundle");
+  bundle.save(&path).unwrap();
+
+  // Paths to bundles get a bundle transport, which can't be pushed to
+  let url = format!("file://{}", path.display());
+  let mut transport = transport::connect(&url).unwrap();
+  let head = transport.list_refs().unwrap().get("HEAD").unwrap().clone();
+  assert_eq!(
+    (oid, Some("refs/heads/main".into())),
+    (head.oid, head.symref_target)
+  );
+  assert!(matches!(
+    transport.list_push_refs(),
+    Err(TransportError::Unsupported(_))
+  ));
+  let clone = Repository::init_bare(tmp_dir.path().join("fetched.git")).unwrap();
+  assert_eq!(3, transport.fetch(&clone, &[oid]).unwrap().objects.len());
+  assert!(transport.fetch(&clone, &[oid]).unwrap().objects.is_empty());
+  let missing = OID::from_bytes(&[1; 20]).unwrap();
+  assert!(matches!(
+    BundleTransport::new(&url)
+      .unwrap()
+      .fetch(&clone, &[missing]),
+    Err(TransportError::Remote(_))
+  ));
+
+  let clone = Repository::clone(
+    path.to_str().unwrap(),
+    tmp_dir.path().join("clone"),
+    &Default::default(),
+  )
+  .unwrap();
+  assert_eq!(Some(oid), clone.refs().resolve("refs/heads/main").unwrap());
+  assert_eq!(
+    "contents\n",
+    std::fs::read_to_string(tmp_dir.path().join("clone/file.txt")).unwrap()
+  );
+}
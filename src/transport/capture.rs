@@ -0,0 +1,450 @@
+//! Recording what a transport and a remote say to each other, and playing
+//! it back. A recording of a fetch or push that went wrong somewhere else
+//! can be replayed through the same code here, with the remote's answers
+//! coming from the recording, to see what the client made of them.
+//!
+//! A recording is a file of messages, each a line like
+//! `request git-upload-pack 123` followed by the 123 bytes that were sent
+//! and a newline. Since the messages are pkt-lines, most of it can be read
+//! in a pager.
+
+use super::{
+  fetch_pack, fetch_wants, ls_refs_request, object_info_request, parse_ls_refs, parse_object_info,
+  push_pack, read_advertisement, retain_prefixed, Advertisement, FetchOptions, FetchOutcome,
+  PacketReader, ProtocolVersion, PushOutcome, PushUpdate, Transport, TransportError,
+};
+use crate::{Packet, Repository, OID};
+use bstr::{BString, ByteSlice};
+use std::{
+  collections::VecDeque,
+  fmt, fs,
+  io::{self, Write},
+  path::Path,
+  sync::{Arc, Mutex},
+};
+
+/// What a [`Message`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+  /// What the remote said first, which for smart HTTP is the response to
+  /// `GET info/refs`
+  Advertisement,
+  /// What was sent to the remote
+  Request,
+  /// What the remote answered a request with
+  Response,
+}
+
+impl MessageKind {
+  fn name(self) -> &'static str {
+    match self {
+      MessageKind::Advertisement => "advertisement",
+      MessageKind::Request => "request",
+      MessageKind::Response => "response",
+    }
+  }
+}
+
+/// One message of a [`Recording`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+  /// Which way the message went
+  pub kind: MessageKind,
+  /// The service it was said to or by, `git-upload-pack` or
+  /// `git-receive-pack`
+  pub service: String,
+  /// The bytes of the message, as they were sent
+  pub bytes: Vec<u8>,
+}
+
+/// Writes the messages of a transport as they're sent and received. A
+/// recorder can be cloned to record several transports into the same
+/// place.
+#[derive(Clone)]
+pub struct Recorder {
+  out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl fmt::Debug for Recorder {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Recorder").finish_non_exhaustive()
+  }
+}
+
+impl Recorder {
+  /// Record to `out`
+  pub fn new(out: impl Write + Send + 'static) -> Self {
+    Self {
+      out: Arc::new(Mutex::new(Box::new(out))),
+    }
+  }
+
+  /// Record to the file at `path`, replacing what's there
+  pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+    Ok(Self::new(fs::File::create(path)?))
+  }
+
+  /// Write a message, flushing it right away so a process that goes down
+  /// halfway leaves everything up to there behind
+  pub(crate) fn record(&self, kind: MessageKind, service: &str, bytes: &[u8]) -> io::Result<()> {
+    let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+    writeln!(out, "{} {} {}", kind.name(), service, bytes.len())?;
+    out.write_all(bytes)?;
+    out.write_all(b"\n")?;
+    out.flush()
+  }
+}
+
+/// The messages a [`Recorder`] wrote
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+  messages: Vec<Message>,
+}
+
+impl Recording {
+  /// Read the recording in the file at `path`
+  pub fn open(path: impl AsRef<Path>) -> Result<Self, TransportError> {
+    Self::parse(&fs::read(path)?)
+  }
+
+  /// Parse a recording. A message cut off at the end, like the last one a
+  /// process was writing when it went down, is left out.
+  pub fn parse(mut bytes: &[u8]) -> Result<Self, TransportError> {
+    let invalid =
+      |message: &str| TransportError::Protocol(format!("invalid recording: {}", message));
+    let mut messages = Vec::new();
+    while !bytes.is_empty() {
+      let Some(end) = bytes.find_byte(b'\n') else {
+        break;
+      };
+      let header = bytes[..end]
+        .to_str()
+        .map_err(|_| invalid("header isn't UTF-8"))?;
+      let mut fields = header.split(' ');
+      let kind = match fields.next() {
+        Some("advertisement") => MessageKind::Advertisement,
+        Some("request") => MessageKind::Request,
+        Some("response") => MessageKind::Response,
+        _ => return Err(invalid(header)),
+      };
+      let (service, len) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(service), Some(len), None) => (service, len),
+        _ => return Err(invalid(header)),
+      };
+      let len: usize = len.parse().map_err(|_| invalid(header))?;
+      let rest = &bytes[end + 1..];
+      if rest.len() <= len {
+        break;
+      }
+      if rest[len] != b'\n' {
+        return Err(invalid("message doesn't end where its length says"));
+      }
+      messages.push(Message {
+        kind,
+        service: service.into(),
+        bytes: rest[..len].to_vec(),
+      });
+      bytes = &rest[len + 1..];
+    }
+    Ok(Self { messages })
+  }
+
+  /// The messages in the order they were said
+  pub fn messages(&self) -> &[Message] {
+    &self.messages
+  }
+}
+
+/// A transport that plays back a [`Recording`], answering whatever the
+/// code here sends with the responses the remote sent back then. It can
+/// replay a recording of any transport, since they all say the same
+/// things.
+///
+/// The requests made are checked against the ones recorded, so a replay
+/// tells when the client doesn't do what it did then, like when the
+/// repository being fetched into isn't in the same state. That can be
+/// turned off with [`ReplayTransport::check_requests`] to see what comes of
+/// the recorded responses anyway.
+#[derive(Debug)]
+pub struct ReplayTransport {
+  messages: VecDeque<Message>,
+  check_requests: bool,
+  advertisement: Option<Advertisement>,
+  push_advertisement: Option<Advertisement>,
+}
+
+impl ReplayTransport {
+  /// Play back `recording` from the start
+  pub fn new(recording: Recording) -> Self {
+    Self {
+      messages: recording.messages.into(),
+      check_requests: true,
+      advertisement: None,
+      push_advertisement: None,
+    }
+  }
+
+  /// Whether a request that isn't the one recorded is an error, which it
+  /// is by default
+  pub fn check_requests(mut self, check: bool) -> Self {
+    self.check_requests = check;
+    self
+  }
+
+  /// Whether the messages left in the recording start with an
+  /// advertisement from `service`, which is a new connection
+  fn at_advertisement(&self, service: &str) -> bool {
+    self.messages.front().is_some_and(|message| {
+      message.kind == MessageKind::Advertisement && message.service == service
+    })
+  }
+
+  fn take(&mut self, kind: MessageKind, service: &str) -> Result<Vec<u8>, TransportError> {
+    match self.messages.pop_front() {
+      Some(message) if message.kind == kind && message.service == service => Ok(message.bytes),
+      Some(message) => Err(TransportError::Protocol(format!(
+        "expected the {} of {} in the recording, not the {} of {}",
+        kind.name(),
+        service,
+        message.kind.name(),
+        message.service
+      ))),
+      None => Err(TransportError::Protocol(format!(
+        "expected the {} of {} in the recording, but it ended",
+        kind.name(),
+        service
+      ))),
+    }
+  }
+
+  fn exchange(&mut self, service: &str, request: &[u8]) -> Result<Vec<u8>, TransportError> {
+    let recorded = self.take(MessageKind::Request, service)?;
+    if self.check_requests && recorded != request {
+      return Err(TransportError::Protocol(format!(
+        "the request to {} isn't the one recorded",
+        service
+      )));
+    }
+    self.take(MessageKind::Response, service)
+  }
+
+  /// Take the advertisement from `service`, and with protocol v2 the
+  /// refs asked for after it. Transports that connect for every fetch,
+  /// like SSH, don't ask for the refs again when fetching.
+  fn advertise(&mut self, service: &str) -> Result<Advertisement, TransportError> {
+    let bytes = self.take(MessageKind::Advertisement, service)?;
+    let mut advertisement = read_advertisement(&bytes)?;
+    let ls_refs = self.messages.front().is_some_and(|message| {
+      message.kind == MessageKind::Request
+        && message
+          .bytes
+          .get(4..)
+          .unwrap_or_default()
+          .starts_with(b"command=ls-refs")
+    });
+    if advertisement.version == ProtocolVersion::V2 && ls_refs {
+      // The refs asked for are the ones that were asked for then
+      let mut prefixes = Vec::new();
+      let mut reader = PacketReader::new(&self.messages[0].bytes);
+      while let Ok(packet) = reader.read() {
+        if let Packet::Data(data) = packet {
+          let line = data.strip_suffix(b"\n").unwrap_or(data);
+          if let Some(prefix) = line.strip_prefix(b"ref-prefix ") {
+            prefixes.push(BString::from(prefix));
+          }
+        }
+      }
+      let response = self.exchange(service, &ls_refs_request(&advertisement, &prefixes)?)?;
+      parse_ls_refs(&mut PacketReader::new(&response), &mut advertisement)?;
+      retain_prefixed(&mut advertisement, &prefixes);
+    }
+    Ok(advertisement)
+  }
+}
+
+impl Transport for ReplayTransport {
+  fn list_refs(&mut self) -> Result<&Advertisement, TransportError> {
+    if self.advertisement.is_none() {
+      self.advertisement = Some(self.advertise("git-upload-pack")?);
+    }
+    Ok(self.advertisement.as_ref().unwrap())
+  }
+
+  fn list_push_refs(&mut self) -> Result<&Advertisement, TransportError> {
+    if self.push_advertisement.is_none() {
+      self.push_advertisement = Some(self.advertise("git-receive-pack")?);
+    }
+    Ok(self.push_advertisement.as_ref().unwrap())
+  }
+
+  fn fetch(&mut self, repo: &Repository, wants: &[OID]) -> Result<FetchOutcome, TransportError> {
+    self.fetch_with_options(repo, wants, &FetchOptions::default())
+  }
+
+  fn fetch_with_options(
+    &mut self,
+    repo: &Repository,
+    wants: &[OID],
+    options: &FetchOptions,
+  ) -> Result<FetchOutcome, TransportError> {
+    let wants = fetch_wants(repo, wants, options)?;
+    if wants.is_empty() {
+      return Ok(FetchOutcome::default());
+    }
+    // Transports that connect for every fetch got a new advertisement
+    // first, and the others use the one they already have
+    let advertisement = if self.at_advertisement("git-upload-pack") {
+      self.advertise("git-upload-pack")?
+    } else {
+      self.list_refs()?.clone()
+    };
+    fetch_pack(repo, &advertisement, &wants, options, |request| {
+      self.exchange("git-upload-pack", &request)
+    })
+  }
+
+  fn object_info(&mut self, oids: &[OID]) -> Result<Vec<(OID, Option<u64>)>, TransportError> {
+    let advertisement = if self.at_advertisement("git-upload-pack") {
+      self.advertise("git-upload-pack")?
+    } else {
+      self.list_refs()?.clone()
+    };
+    let request = object_info_request(&advertisement, oids)?;
+    parse_object_info(&self.exchange("git-upload-pack", &request)?)
+  }
+
+  fn push(
+    &mut self,
+    repo: &Repository,
+    updates: &[PushUpdate],
+  ) -> Result<PushOutcome, TransportError> {
+    let advertisement = if self.at_advertisement("git-receive-pack") {
+      self.advertise("git-receive-pack")?
+    } else {
+      self.list_push_refs()?.clone()
+    };
+    let outcome = push_pack(repo, &advertisement, updates, |request| {
+      self.exchange("git-receive-pack", &request)
+    })?;
+    self.push_advertisement = None;
+    Ok(outcome)
+  }
+}
+
+#[test]
+fn record_and_replay() {
+  use super::{http::HttpTransport, PushStatus};
+  use crate::{Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  let invalid = Recording::parse(b"request git-upload-pack 4\n0000x");
+  assert!(matches!(invalid, Err(TransportError::Protocol(_))));
+  let cut_off =
+    Recording::parse(b"request git-upload-pack 4\n0000\nresponse git-upload-pack 8\n00");
+  assert_eq!(
+    &[Message {
+      kind: MessageKind::Request,
+      service: "git-upload-pack".into(),
+      bytes: b"0000".to_vec(),
+    }][..],
+    cut_off.unwrap().messages()
+  );
+  if !super::http::have_git() {
+    return;
+  }
+
+  let tmp_dir = tempdir::TempDir::new("capture_test").unwrap();
+  let server = Repository::init_bare(tmp_dir.path().join("server.git")).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |repo: &Repository, contents: &str, parents: Vec<OID>| {
+    let odb = repo.odb();
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(
+      tree,
+      parents,
+      signature.clone(),
+      signature.clone(),
+      "commit\n",
+    );
+    odb.write_commit(&commit).unwrap()
+  };
+  let first = commit(&server, "first\n", vec![]);
+  server.refs().write("refs/heads/master", &first).unwrap();
+  let url = format!(
+    "{}/server.git",
+    super::http::serve_http_backend(tmp_dir.path())
+  );
+
+  // Fetch and push for real, recording it all
+  let path = tmp_dir.path().join("recording");
+  let client = Repository::init(tmp_dir.path().join("client")).unwrap();
+  let mut transport = HttpTransport::new(&url)
+    .unwrap()
+    .with_recorder(Recorder::create(&path).unwrap());
+  let refs = transport.list_refs().unwrap().clone();
+  assert_eq!(3, transport.fetch(&client, &[first]).unwrap().objects.len());
+  client.refs().write("refs/heads/master", &first).unwrap();
+  let second = commit(&client, "second\n", vec![first]);
+  let update = PushUpdate::new("refs/heads/master", second);
+  let outcome = transport
+    .push(&client, std::slice::from_ref(&update))
+    .unwrap();
+  assert!(outcome.is_ok());
+  let recording = Recording::open(&path).unwrap();
+  let kinds: Vec<(MessageKind, &str)> = recording
+    .messages()
+    .iter()
+    .map(|message| (message.kind, message.service.as_str()))
+    .collect();
+  use MessageKind::*;
+  assert_eq!(
+    vec![
+      (Advertisement, "git-upload-pack"),
+      (Request, "git-upload-pack"),
+      (Response, "git-upload-pack"),
+      (Request, "git-upload-pack"),
+      (Response, "git-upload-pack"),
+      (Advertisement, "git-receive-pack"),
+      (Request, "git-receive-pack"),
+      (Response, "git-receive-pack"),
+    ],
+    kinds
+  );
+
+  // The same again into a repository like the first one was, without the
+  // server
+  let replayed = Repository::init(tmp_dir.path().join("replayed")).unwrap();
+  let mut replay = ReplayTransport::new(recording.clone());
+  assert_eq!(&refs, replay.list_refs().unwrap());
+  let outcome = replay.fetch(&replayed, &[first]).unwrap();
+  assert_eq!(3, outcome.objects.len());
+  replayed.refs().write("refs/heads/master", &first).unwrap();
+  assert_eq!(second, commit(&replayed, "second\n", vec![first]));
+  let outcome = replay
+    .push(&replayed, std::slice::from_ref(&update))
+    .unwrap();
+  assert_eq!(PushStatus::Ok, outcome.refs[0].status);
+  assert!(matches!(
+    replay.fetch(
+      &Repository::init(tmp_dir.path().join("more")).unwrap(),
+      &[first]
+    ),
+    Err(TransportError::Protocol(_))
+  ));
+
+  // Asking for something else isn't what was recorded, unless that's not
+  // checked
+  let other = Repository::init(tmp_dir.path().join("other")).unwrap();
+  let mut replay = ReplayTransport::new(recording.clone());
+  assert!(matches!(
+    replay.fetch(&other, &[second]),
+    Err(TransportError::Protocol(_))
+  ));
+  let mut replay = ReplayTransport::new(recording).check_requests(false);
+  let outcome = replay.fetch(&other, &[second]).unwrap();
+  assert!(outcome.objects.contains(&first));
+}
@@ -0,0 +1,1423 @@
+//! The smart HTTP transport, where every message of the protocol is its own
+//! request. The refs come from `GET {url}/info/refs?service=...` and each
+//! command after that is a `POST` to `{url}/{service}`. Since nothing is
+//! kept between requests, a fetch or push sends everything it has to say
+//! in one go.
+//!
+//! The requests are made by an [`HttpClient`], so anything from proxies to
+//! other kinds of authentication can be handled by bringing one.
+//! [`DefaultHttpClient`] does plain HTTP itself and leaves HTTPS to the
+//! `curl` command. Servers that answer `401` get basic auth with
+//! credentials from [`Credentials`], which are the credential helpers of
+//! the global config unless others are given.
+
+use super::{
+  fetch_pack, fetch_wants, ls_refs_request, object_info_request, parse_ls_refs, parse_object_info,
+  push_pack, read_advertisement, retain_prefixed, Advertisement, Direction, FetchOptions,
+  FetchOutcome, MessageKind, PacketReader, ProtocolVersion, PushOutcome, PushUpdate, Recorder,
+  Throttle, Throttled, Transport, TransportError,
+};
+use crate::{cleanup, Config, Credential, CredentialHelpers, Credentials, Repository, OID};
+use bstr::{BString, ByteSlice};
+use std::{
+  cell::RefCell,
+  fmt, fs,
+  io::{self, Read, Write},
+  net::TcpStream,
+  path::PathBuf,
+  process::{Command, Stdio},
+  sync::Arc,
+  thread,
+  time::Duration,
+};
+
+/// A request for an [`HttpClient`] to make
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+  /// `GET` or `POST`
+  pub method: &'static str,
+  /// The whole URL, with the query
+  pub url: String,
+  /// The headers to send besides the ones the request has to have, like
+  /// `Host` and `Content-Length`
+  pub headers: Vec<(String, String)>,
+  /// What to send, empty for a `GET`
+  pub body: Vec<u8>,
+}
+
+/// What an [`HttpClient`] got back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+  /// The status code, like 200
+  pub status: u16,
+  /// The headers, with the names as they were sent
+  pub headers: Vec<(String, String)>,
+  /// The body, with any transfer encoding undone
+  pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+  /// The value of the header `name`, which is matched ignoring case
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self
+      .headers
+      .iter()
+      .find(|(header, _)| header.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_str())
+  }
+}
+
+/// Makes the requests of an [`HttpTransport`]
+pub trait HttpClient {
+  /// Make `request` and return the response whatever its status is, only
+  /// failing if there isn't one
+  fn send(&self, request: &HttpRequest) -> io::Result<HttpResponse>;
+}
+
+/// The [`HttpClient`] used unless another one is given. Plain `http` URLs
+/// are requested over a new connection each time, and `https` ones by
+/// running `curl`, which has to be installed. Redirects aren't followed.
+#[derive(Clone, Default)]
+pub struct DefaultHttpClient {
+  throttle: Option<Arc<dyn Throttle>>,
+}
+
+impl DefaultHttpClient {
+  /// Create a client that goes as fast as it can
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Slow requests and responses down with `throttle`, which goes for the
+  /// bytes going to and coming from `curl` for `https` URLs
+  pub fn with_throttle(mut self, throttle: impl Throttle + 'static) -> Self {
+    self.throttle = Some(Arc::new(throttle));
+    self
+  }
+
+  fn throttle(&self) -> &dyn Throttle {
+    match &self.throttle {
+      Some(throttle) => &**throttle,
+      None => &UNTHROTTLED,
+    }
+  }
+}
+
+impl fmt::Debug for DefaultHttpClient {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("DefaultHttpClient")
+      .field("throttled", &self.throttle.is_some())
+      .finish()
+  }
+}
+
+/// What a [`DefaultHttpClient`] without a throttle waits, which is never
+static UNTHROTTLED: fn(Direction, usize) -> Duration = |_, _| Duration::ZERO;
+
+impl HttpClient for DefaultHttpClient {
+  fn send(&self, request: &HttpRequest) -> io::Result<HttpResponse> {
+    if request.url.starts_with("https://") {
+      return send_with_curl(request, self.throttle());
+    }
+    let (address, head) = plain_request(request)?;
+    let mut stream = Throttled::new(TcpStream::connect(address)?, self.throttle());
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&request.body)?;
+    // The connection is closed by the server after the response
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    parse_response(&response, true)
+  }
+}
+
+/// The address to connect to for a plain `http` request and the head of
+/// the request to send before its body
+pub(crate) fn plain_request(request: &HttpRequest) -> io::Result<(String, String)> {
+  let rest = request
+    .url
+    .strip_prefix("http://")
+    .ok_or_else(|| invalid_data(format!("unsupported URL {}", request.url)))?;
+  let (authority, path) = match rest.find('/') {
+    Some(slash) => rest.split_at(slash),
+    None => (rest, "/"),
+  };
+  // Credentials in the URL are left to clients that handle them
+  let host = authority.rsplit('@').next().unwrap_or(authority);
+  let address = match host.rfind(':') {
+    Some(colon) if !host.ends_with(']') && host[colon + 1..].parse::<u16>().is_ok() => {
+      host.to_string()
+    }
+    _ => format!("{}:80", host),
+  };
+  let mut head = format!(
+    "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+    request.method, path, host
+  );
+  for (name, value) in &request.headers {
+    head.push_str(&format!("{}: {}\r\n", name, value));
+  }
+  if request.method != "GET" {
+    head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+  }
+  head.push_str("\r\n");
+  Ok((address, head))
+}
+
+fn invalid_data(message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Make `request` by running `curl`, which prints the response headers
+/// before the body with `--include`
+fn send_with_curl(request: &HttpRequest, throttle: &dyn Throttle) -> io::Result<HttpResponse> {
+  // The headers can hold credentials, which anyone can read from the
+  // arguments of a process, so curl reads them from a file only we can read
+  let headers = HeaderFile::new(&request.headers)?;
+  let mut command = Command::new("curl");
+  command
+    .args(["--silent", "--show-error", "--include", "--request"])
+    .arg(request.method)
+    // Waiting for a `100 Continue` before sending a body only slows
+    // things down
+    .args(["--header", "Expect:"])
+    .arg("--header")
+    .arg({
+      let mut arg = std::ffi::OsString::from("@");
+      arg.push(&headers.path);
+      arg
+    });
+  if request.method != "GET" {
+    command.args(["--data-binary", "@-"]);
+  }
+  let mut child = command
+    .arg("--")
+    .arg(&request.url)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()?;
+  // The request is written from another thread so a big request and a big
+  // response can't each wait for the other. Reading stderr after stdout
+  // is fine since curl only writes a line or two to it.
+  let (stdin, mut stdout) = (child.stdin.take().unwrap(), child.stdout.take().unwrap());
+  let mut response = Vec::new();
+  let written = thread::scope(|scope| {
+    // Moved in so it's closed once the request is written, which is how
+    // curl knows the body is done
+    let writer = scope.spawn(move || Throttled::new(stdin, throttle).write_all(&request.body));
+    let read = Throttled::new(&mut stdout, throttle).read_to_end(&mut response);
+    let written = writer.join().unwrap();
+    read.and(written)
+  });
+  let output = child.wait_with_output()?;
+  written?;
+  if !output.status.success() {
+    let stderr = output.stderr.to_str_lossy();
+    return Err(io::Error::other(format!("curl failed: {}", stderr.trim())));
+  }
+  // curl undoes the transfer encoding itself
+  parse_response(&response, false)
+}
+
+/// The headers of a request in a temporary file that only the current user
+/// can read, removed once it's dropped
+struct HeaderFile {
+  path: PathBuf,
+}
+
+impl HeaderFile {
+  fn new(headers: &[(String, String)]) -> io::Result<Self> {
+    let path = std::env::temp_dir().join(cleanup::temp_name("headers"));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut handle = options.open(&path)?;
+    // Made right away so the file is removed if writing it fails
+    let file = Self { path };
+    for (name, value) in headers {
+      writeln!(handle, "{}: {}", name, value)?;
+    }
+    Ok(file)
+  }
+}
+
+impl Drop for HeaderFile {
+  fn drop(&mut self) {
+    let _ = fs::remove_file(&self.path);
+  }
+}
+/// Parse a whole HTTP/1.x response, skipping any `100 Continue` before it.
+/// A chunked body is only put back together when `dechunk` is set.
+pub(crate) fn parse_response(mut bytes: &[u8], dechunk: bool) -> io::Result<HttpResponse> {
+  loop {
+    let end = bytes
+      .find(b"\r\n\r\n")
+      .ok_or_else(|| invalid_data("truncated HTTP response".into()))?;
+    let head = bytes[..end].to_str_lossy();
+    let body = &bytes[end + 4..];
+    let mut lines = head.split("\r\n");
+    let status = lines
+      .next()
+      .and_then(|line| line.split(' ').nth(1))
+      .and_then(|status| status.parse::<u16>().ok())
+      .ok_or_else(|| invalid_data("invalid HTTP status line".into()))?;
+    if (100..200).contains(&status) {
+      bytes = body;
+      continue;
+    }
+    let headers = lines
+      .filter_map(|line| line.split_once(':'))
+      .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+      .collect();
+    let mut response = HttpResponse {
+      status,
+      headers,
+      body: Vec::new(),
+    };
+    let chunked = response
+      .header("transfer-encoding")
+      .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+    let length = response
+      .header("content-length")
+      .and_then(|length| length.parse::<usize>().ok());
+    response.body = match (chunked && dechunk, length) {
+      (true, _) => dechunk_body(body)?,
+      (false, Some(length)) if length <= body.len() => body[..length].to_vec(),
+      (false, Some(_)) => return Err(invalid_data("truncated HTTP response".into())),
+      (false, None) => body.to_vec(),
+    };
+    return Ok(response);
+  }
+}
+
+/// Put a body sent with `Transfer-Encoding: chunked` back together
+fn dechunk_body(mut bytes: &[u8]) -> io::Result<Vec<u8>> {
+  let truncated = || invalid_data("truncated chunked HTTP body".into());
+  let mut body = Vec::new();
+  loop {
+    let end = bytes.find(b"\r\n").ok_or_else(truncated)?;
+    // Chunk extensions after a `;` carry nothing needed here
+    let size = bytes[..end].split_str(";").next().unwrap_or_default();
+    let size = usize::from_str_radix(size.to_str_lossy().trim(), 16)
+      .map_err(|_| invalid_data("invalid HTTP chunk size".into()))?;
+    if size == 0 {
+      return Ok(body);
+    }
+    let chunk = bytes.get(end + 2..end + 2 + size).ok_or_else(truncated)?;
+    body.extend_from_slice(chunk);
+    bytes = bytes.get(end + 4 + size..).ok_or_else(truncated)?;
+  }
+}
+
+/// Fetches from and pushes to a repository over smart HTTP
+pub struct HttpTransport {
+  url: String,
+  client: Box<dyn HttpClient>,
+  version: ProtocolVersion,
+  advertisement: Option<Advertisement>,
+  push_advertisement: Option<Advertisement>,
+  ref_prefixes: Vec<BString>,
+  recorder: Option<Recorder>,
+  credentials: Option<Arc<dyn Credentials>>,
+  /// The credential that worked, sent with every request after it
+  credential: RefCell<Option<Credential>>,
+}
+
+impl fmt::Debug for HttpTransport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("HttpTransport")
+      .field("url", &self.url)
+      .field("version", &self.version)
+      .field("advertisement", &self.advertisement)
+      .field("push_advertisement", &self.push_advertisement)
+      .field("ref_prefixes", &self.ref_prefixes)
+      .field("recorder", &self.recorder)
+      .field("credential", &self.credential.borrow())
+      .finish_non_exhaustive()
+  }
+}
+
+impl HttpTransport {
+  /// Talk to the repository at `url`, which has to be an `http` or `https`
+  /// URL, using protocol v2 if the server has it. Nothing is requested
+  /// until the refs are needed.
+  pub fn new(url: impl Into<String>) -> Result<Self, TransportError> {
+    let mut url = url.into();
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+      return Err(TransportError::UnsupportedUrl(url));
+    }
+    while url.ends_with('/') {
+      url.pop();
+    }
+    Ok(Self {
+      url,
+      client: Box::new(DefaultHttpClient::new()),
+      version: ProtocolVersion::V2,
+      advertisement: None,
+      push_advertisement: None,
+      ref_prefixes: Vec::new(),
+      recorder: None,
+      credentials: None,
+      credential: RefCell::new(None),
+    })
+  }
+
+  /// Make requests with `client` instead of [`DefaultHttpClient`]
+  pub fn with_client(mut self, client: impl HttpClient + 'static) -> Self {
+    self.client = Box::new(client);
+    self
+  }
+
+  /// Ask for `version` of the protocol. Servers that don't have protocol v2
+  /// answer with protocol v1 anyway.
+  pub fn with_protocol(mut self, version: ProtocolVersion) -> Self {
+    self.version = version;
+    self
+  }
+
+  /// Only list the refs starting with one of `prefixes`, like
+  /// `refs/heads/`, which protocol v2 servers leave the rest out of the
+  /// response for. `HEAD` is only listed if it's one of them.
+  pub fn with_ref_prefixes<P: Into<BString>>(
+    mut self,
+    prefixes: impl IntoIterator<Item = P>,
+  ) -> Self {
+    self.ref_prefixes = prefixes.into_iter().map(Into::into).collect();
+    self
+  }
+
+  /// Write everything said either way to `recorder`, for replaying it with
+  /// a [`ReplayTransport`][super::ReplayTransport]
+  pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+    self.recorder = Some(recorder);
+    self
+  }
+
+  /// Get credentials from `credentials` instead of the credential helpers
+  /// of the global config, like [`CredentialHelpers::from_config`] with
+  /// the config of a repository
+  pub fn with_credentials(mut self, credentials: impl Credentials + 'static) -> Self {
+    self.credentials = Some(Arc::new(credentials));
+    self
+  }
+
+  /// The URL of the repository
+  pub fn url(&self) -> &str {
+    &self.url
+  }
+
+  fn send(&self, mut request: HttpRequest, service: &str) -> Result<HttpResponse, TransportError> {
+    add_git_headers(&mut request, self.version, service);
+    if let Some(auth) = self
+      .credential
+      .borrow()
+      .as_ref()
+      .and_then(Credential::basic_auth)
+    {
+      request.headers.push(("Authorization".into(), auth));
+    }
+    let mut response = self.client.send(&request)?;
+    if response.status == 401 && self.credential.borrow().is_none() {
+      response = self.authenticate(&mut request)?;
+    }
+    check_status(response, request.url)
+  }
+
+  /// Make `request` again with credentials after the server asked for
+  /// them, telling the credentials whether they worked: approved for a
+  /// `2xx` response and rejected for a `401` or `403`
+  fn authenticate(&self, request: &mut HttpRequest) -> Result<HttpResponse, TransportError> {
+    let credentials = match &self.credentials {
+      Some(credentials) => credentials.clone(),
+      None => {
+        let config = Config::open_global().unwrap_or_default();
+        Arc::new(CredentialHelpers::from_config(&config, &self.url))
+      }
+    };
+    let mut credential = Credential::from_url(&self.url);
+    credentials.fill(&mut credential)?;
+    if let Some(auth) = credential.basic_auth() {
+      request.headers.push(("Authorization".into(), auth));
+    }
+    let response = self.client.send(request)?;
+    // Like git, a response that says nothing about the credentials, like a
+    // server error, leaves them alone
+    match response.status {
+      200..=299 => {
+        credentials.approve(&credential)?;
+        *self.credential.borrow_mut() = Some(credential);
+      }
+      401 | 403 => credentials.reject(&credential)?,
+      _ => {}
+    }
+    Ok(response)
+  }
+
+  /// `POST` `body` to `service`
+  fn post(&self, service: &str, body: Vec<u8>) -> Result<Vec<u8>, TransportError> {
+    let request = post_request(&self.url, service, body);
+    self.record(MessageKind::Request, service, &request.body)?;
+    let response = self.send(request, service)?.body;
+    self.record(MessageKind::Response, service, &response)?;
+    Ok(response)
+  }
+
+  fn record(&self, kind: MessageKind, service: &str, bytes: &[u8]) -> io::Result<()> {
+    match &self.recorder {
+      Some(recorder) => recorder.record(kind, service, bytes),
+      None => Ok(()),
+    }
+  }
+
+  fn request_refs(&self, service: &str) -> Result<Advertisement, TransportError> {
+    let request = refs_request(&self.url, service);
+    let url = request.url.clone();
+    let response = self.send(request, service)?;
+    check_advertisement(&response, &url, service)?;
+    self.record(MessageKind::Advertisement, service, &response.body)?;
+    let mut advertisement = read_advertisement(&response.body)?;
+    if service == "git-upload-pack" {
+      if advertisement.version == ProtocolVersion::V2 {
+        let request = ls_refs_request(&advertisement, &self.ref_prefixes)?;
+        let response = self.post(service, request)?;
+        parse_ls_refs(&mut PacketReader::new(&response), &mut advertisement)?;
+      }
+      retain_prefixed(&mut advertisement, &self.ref_prefixes);
+    }
+    Ok(advertisement)
+  }
+}
+
+/// Add the headers every request to `service` has to `request`
+pub(crate) fn add_git_headers(request: &mut HttpRequest, version: ProtocolVersion, service: &str) {
+  // Some servers, GitHub's among them, only speak the smart protocol to
+  // clients that look like git
+  request.headers.push((
+    "User-Agent".into(),
+    format!("git/2.0 (libgit-rs/{})", env!("CARGO_PKG_VERSION")),
+  ));
+  // Pushing is only done with protocol v1
+  if version == ProtocolVersion::V2 && service == "git-upload-pack" {
+    request
+      .headers
+      .push(("Git-Protocol".into(), "version=2".into()));
+  }
+}
+
+/// `response` to a request for `url`, unless it isn't a success
+pub(crate) fn check_status(
+  response: HttpResponse,
+  url: String,
+) -> Result<HttpResponse, TransportError> {
+  if response.status != 200 {
+    return Err(TransportError::Http {
+      status: response.status,
+      url,
+    });
+  }
+  Ok(response)
+}
+
+/// The `POST` of `body` to `service` of the repository at `url`
+pub(crate) fn post_request(url: &str, service: &str, body: Vec<u8>) -> HttpRequest {
+  HttpRequest {
+    method: "POST",
+    url: format!("{}/{}", url, service),
+    headers: vec![
+      (
+        "Content-Type".into(),
+        format!("application/x-{}-request", service),
+      ),
+      ("Accept".into(), format!("application/x-{}-result", service)),
+    ],
+    body,
+  }
+}
+
+/// The `GET` of the refs `service` advertises for the repository at `url`
+pub(crate) fn refs_request(url: &str, service: &str) -> HttpRequest {
+  HttpRequest {
+    method: "GET",
+    url: format!("{}/info/refs?service={}", url, service),
+    headers: Vec::new(),
+    body: Vec::new(),
+  }
+}
+
+/// Make sure the `response` to a [`refs_request`] for `url` came from a
+/// smart HTTP server
+pub(crate) fn check_advertisement(
+  response: &HttpResponse,
+  url: &str,
+  service: &str,
+) -> Result<(), TransportError> {
+  // A dumb server hands out the file as plain text instead
+  let content_type = format!("application/x-{}-advertisement", service);
+  if response.header("content-type") != Some(&content_type) {
+    return Err(TransportError::Protocol(format!(
+      "{} is not a smart HTTP server",
+      url
+    )));
+  }
+  Ok(())
+}
+
+impl Transport for HttpTransport {
+  fn list_refs(&mut self) -> Result<&Advertisement, TransportError> {
+    if self.advertisement.is_none() {
+      let advertisement = self.request_refs("git-upload-pack")?;
+      self.advertisement = Some(advertisement);
+    }
+    Ok(self.advertisement.as_ref().unwrap())
+  }
+
+  fn list_push_refs(&mut self) -> Result<&Advertisement, TransportError> {
+    if self.push_advertisement.is_none() {
+      let advertisement = self.request_refs("git-receive-pack")?;
+      self.push_advertisement = Some(advertisement);
+    }
+    Ok(self.push_advertisement.as_ref().unwrap())
+  }
+
+  fn fetch(&mut self, repo: &Repository, wants: &[OID]) -> Result<FetchOutcome, TransportError> {
+    self.fetch_with_options(repo, wants, &FetchOptions::default())
+  }
+
+  fn fetch_with_options(
+    &mut self,
+    repo: &Repository,
+    wants: &[OID],
+    options: &FetchOptions,
+  ) -> Result<FetchOutcome, TransportError> {
+    let wants = fetch_wants(repo, wants, options)?;
+    if wants.is_empty() {
+      return Ok(FetchOutcome::default());
+    }
+    let advertisement = self.list_refs()?.clone();
+    fetch_pack(repo, &advertisement, &wants, options, |request| {
+      self.post("git-upload-pack", request)
+    })
+  }
+
+  fn object_info(&mut self, oids: &[OID]) -> Result<Vec<(OID, Option<u64>)>, TransportError> {
+    let request = object_info_request(self.list_refs()?, oids)?;
+    parse_object_info(&self.post("git-upload-pack", request)?)
+  }
+
+  fn push(
+    &mut self,
+    repo: &Repository,
+    updates: &[PushUpdate],
+  ) -> Result<PushOutcome, TransportError> {
+    let advertisement = self.list_push_refs()?.clone();
+    let outcome = push_pack(repo, &advertisement, updates, |request| {
+      self.post("git-receive-pack", request)
+    })?;
+    // What's there now is only known for the refs that were updated, so
+    // the rest is asked for again next time
+    self.push_advertisement = None;
+    Ok(outcome)
+  }
+}
+
+/// Serve the repositories under `root` over HTTP on a local port by
+/// running `git http-backend` for each request, returning the URL of
+/// `root`. Responses to `POST`s are chunked so both ways of sending a body
+/// get used.
+#[cfg(test)]
+pub(crate) fn serve_http_backend(root: &std::path::Path) -> String {
+  use std::{io::BufRead, net::TcpListener};
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}", listener.local_addr().unwrap());
+  let root = root.to_path_buf();
+  let handle = move |stream: TcpStream| -> io::Result<()> {
+    let mut reader = io::BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split(' ');
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let mut headers = Vec::new();
+    loop {
+      line.clear();
+      reader.read_line(&mut line)?;
+      match line.trim_end().split_once(':') {
+        Some((name, value)) => headers.push((name.to_lowercase(), value.trim().to_string())),
+        None => break,
+      }
+    }
+    let header = |name: &str| {
+      headers
+        .iter()
+        .find(|(header, _)| header == name)
+        .map_or("", |(_, value)| value.as_str())
+    };
+    let mut body = vec![0; header("content-length").parse().unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+    let output = Command::new("git")
+      .arg("http-backend")
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .env("GIT_PROJECT_ROOT", &root)
+      .env("GIT_HTTP_EXPORT_ALL", "1")
+      .env("REQUEST_METHOD", &method)
+      .env("PATH_INFO", path)
+      .env("QUERY_STRING", query)
+      .env("CONTENT_TYPE", header("content-type"))
+      .env("CONTENT_LENGTH", body.len().to_string())
+      .env("GIT_PROTOCOL", header("git-protocol"))
+      .env("REMOTE_ADDR", "127.0.0.1")
+      // Pushing is only allowed for someone who logged in
+      .env("REMOTE_USER", "tester")
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::null())
+      .spawn()
+      .and_then(|mut child| {
+        child.stdin.take().unwrap().write_all(&body)?;
+        child.wait_with_output()
+      })?;
+    let cgi = output.stdout;
+    let end = cgi.find(b"\r\n\r\n").unwrap_or(cgi.len());
+    let mut status = "200 OK".to_string();
+    let mut response = Vec::new();
+    for line in ByteSlice::lines(&cgi[..end]) {
+      match line.strip_prefix(b"Status: ") {
+        Some(line) => status = line.to_str_lossy().into_owned(),
+        None => response.extend_from_slice(&[line, b"\r\n"].concat()),
+      }
+    }
+    let body = cgi.get(end + 4..).unwrap_or_default();
+    let mut stream = stream;
+    write!(stream, "HTTP/1.1 {}\r\n", status)?;
+    stream.write_all(&response)?;
+    if method == "POST" {
+      stream.write_all(b"Transfer-Encoding: chunked\r\n\r\n")?;
+      for chunk in body.chunks(1000) {
+        write!(stream, "{:x}\r\n", chunk.len())?;
+        stream.write_all(&[chunk, b"\r\n"].concat())?;
+      }
+      stream.write_all(b"0\r\n\r\n")
+    } else {
+      write!(stream, "Content-Length: {}\r\n\r\n", body.len())?;
+      stream.write_all(body)
+    }
+  };
+  std::thread::spawn(move || {
+    for stream in listener.incoming().flatten() {
+      let _ = handle(stream);
+    }
+  });
+  url
+}
+
+/// Serve enough of smart HTTP on a local port for git and this crate to
+/// talk to `git-{service}`, returning the URL of the server. `respond`
+/// writes the advertisement for a `GET`, when it's given `true`, and the
+/// result of the request body for a `POST`. Each connection takes one
+/// request.
+#[cfg(test)]
+pub(crate) fn serve_smart_http(
+  service: &'static str,
+  respond: impl Fn(bool, &[u8], &mut Vec<u8>) + Send + 'static,
+) -> String {
+  use std::{io::BufRead, net::TcpListener};
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}", listener.local_addr().unwrap());
+  std::thread::spawn(move || {
+    for stream in listener.incoming() {
+      let stream = stream.unwrap();
+      let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+      let mut head = String::new();
+      let mut length = 0;
+      while reader.read_line(&mut head).unwrap() > 2 {
+        let line = head.lines().last().unwrap().to_lowercase();
+        if let Some(len) = line.strip_prefix("content-length: ") {
+          length = len.parse().unwrap();
+        }
+      }
+      let mut body = vec![0; length];
+      reader.read_exact(&mut body).unwrap();
+      let advertise = head.starts_with("GET");
+      let mut response = Vec::new();
+      respond(advertise, &body, &mut response);
+      let content_type = match advertise {
+        true => "advertisement",
+        false => "result",
+      };
+      let mut stream = stream;
+      write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/x-git-{}-{}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        service,
+        content_type,
+        response.len()
+      )
+      .unwrap();
+      stream.write_all(&response).unwrap();
+    }
+  });
+  url
+}
+
+/// Whether `git` is there to run, so tests against it can be skipped
+/// where it isn't
+#[cfg(test)]
+pub(crate) fn have_git() -> bool {
+  Command::new("git")
+    .arg("--version")
+    .output()
+    .is_ok_and(|output| output.status.success())
+}
+
+#[test]
+fn parse_responses() {
+  let response = parse_response(
+    b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 404 Not Found\r\nContent-Length: 4\r\n\r\nnope and more",
+    true,
+  )
+  .unwrap();
+  assert_eq!(404, response.status);
+  assert_eq!(Some("4"), response.header("CONTENT-LENGTH"));
+  assert_eq!(b"nope"[..], response.body[..]);
+  let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4;x=y\r\nthis\r\n5\r\n is a\r\n0\r\n\r\n";
+  assert_eq!(
+    b"this is a"[..],
+    parse_response(chunked, true).unwrap().body[..]
+  );
+  assert!(parse_response(&chunked[..chunked.len() - 12], true).is_err());
+  assert!(matches!(
+    HttpTransport::new("ssh://example.com/repo"),
+    Err(TransportError::UnsupportedUrl(_))
+  ));
+}
+
+#[test]
+fn fetch() {
+  use crate::{Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  if !have_git() {
+    return;
+  }
+  let tmp_dir = tempdir::TempDir::new("http_test").unwrap();
+  let server = Repository::init_bare(tmp_dir.path().join("server.git")).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |contents: &str, parents: Vec<OID>| {
+    let odb = server.odb();
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(
+      tree,
+      parents,
+      signature.clone(),
+      signature.clone(),
+      "commit\n",
+    );
+    let oid = odb.write_commit(&commit).unwrap();
+    server.refs().write("refs/heads/master", &oid).unwrap();
+    oid
+  };
+  let first = commit("first\n", vec![]);
+  let tag = server
+    .tags()
+    .create_annotated("v1", &first, signature.clone(), "v1\n", false)
+    .unwrap();
+  let root = serve_http_backend(tmp_dir.path());
+  let url = format!("{}/server.git", root);
+
+  for (i, &version) in [ProtocolVersion::V1, ProtocolVersion::V2]
+    .iter()
+    .enumerate()
+  {
+    let client = Repository::init(tmp_dir.path().join(format!("client{}", i))).unwrap();
+    let mut transport = HttpTransport::new(format!("{}/", url))
+      .unwrap()
+      .with_protocol(version);
+    let advertisement = transport.list_refs().unwrap();
+    assert_eq!(version, advertisement.version);
+    let head = advertisement.get("HEAD").unwrap();
+    assert_eq!(Some("refs/heads/master".into()), head.symref_target);
+    let v1 = advertisement.get("refs/tags/v1").unwrap();
+    assert_eq!((tag, Some(first)), (v1.oid, v1.peeled));
+
+    let outcome = transport.fetch(&client, &[first, tag]).unwrap();
+    assert_eq!(4, outcome.objects.len());
+    assert_eq!(first, *client.odb().read_tag(&tag).unwrap().object());
+    client
+      .refs()
+      .write("refs/remotes/origin/master", &first)
+      .unwrap();
+    // Nothing is asked for when everything is already there
+    assert_eq!(
+      FetchOutcome::default(),
+      transport.fetch(&client, &[first]).unwrap()
+    );
+
+    // Only what's new comes the second time around
+    let second = commit(&format!("second {}\n", i), vec![first]);
+    let mut transport = HttpTransport::new(&url).unwrap().with_protocol(version);
+    let head = transport.list_refs().unwrap().get("HEAD").unwrap().oid;
+    assert_eq!(second, head);
+    let outcome = transport.fetch(&client, &[second]).unwrap();
+    assert_eq!(3, outcome.objects.len());
+    assert!(outcome.objects.contains(&second));
+    assert!(!outcome.objects.contains(&first));
+    let commit = client.odb().read_commit(&second).unwrap();
+    assert_eq!(&[first][..], commit.parents());
+  }
+
+  // What's sent for https URLs goes through curl, which can be tried out
+  // over plain HTTP
+  let request = HttpRequest {
+    method: "POST",
+    url: format!("{}/git-upload-pack", url),
+    headers: vec![(
+      "Content-Type".into(),
+      "application/x-git-upload-pack-request".into(),
+    )],
+    body: b"0000".to_vec(),
+  };
+  let have_curl = Command::new("curl").arg("--version").output().is_ok();
+  if have_curl {
+    let headers = HeaderFile::new(&request.headers).unwrap();
+    let path = headers.path.clone();
+    assert_eq!(
+      "Content-Type: application/x-git-upload-pack-request\n",
+      fs::read_to_string(&path).unwrap()
+    );
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = fs::metadata(&path).unwrap().permissions().mode();
+      assert_eq!(0o600, mode & 0o777);
+    }
+    drop(headers);
+    assert!(!path.exists());
+    let response = send_with_curl(&request, &UNTHROTTLED).unwrap();
+    assert_eq!(DefaultHttpClient::new().send(&request).unwrap(), response);
+    assert_eq!(200, response.status);
+  }
+
+  // Every byte either way goes through the throttle
+  let counts = Arc::new(std::sync::Mutex::new((0, 0)));
+  let throttle = {
+    let counts = counts.clone();
+    move |direction, len| {
+      let mut counts = counts.lock().unwrap();
+      match direction {
+        Direction::Sent => counts.0 += len,
+        Direction::Received => counts.1 += len,
+      }
+      Duration::ZERO
+    }
+  };
+  let client = DefaultHttpClient::new().with_throttle(throttle);
+  let response = client.send(&request).unwrap();
+  let (sent, received) = *counts.lock().unwrap();
+  assert!(sent > request.body.len());
+  assert!(received > response.body.len());
+
+  let mut missing = HttpTransport::new(format!("{}/missing.git", root)).unwrap();
+  assert!(matches!(
+    missing.list_refs(),
+    Err(TransportError::Http { status: 404, .. })
+  ));
+}
+
+#[test]
+fn push() {
+  use super::{push_request, PushStatus};
+  use crate::{Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  use std::os::unix::fs::PermissionsExt;
+  if !have_git() {
+    return;
+  }
+  let tmp_dir = tempdir::TempDir::new("http_test").unwrap();
+  let server_path = tmp_dir.path().join("server.git");
+  let server = Repository::init_bare(&server_path).unwrap();
+  std::fs::create_dir(server_path.join("hooks")).unwrap();
+  let hook = server_path.join("hooks").join("update");
+  std::fs::write(&hook, "#!/bin/sh\ntest \"$1\" != refs/heads/locked\n").unwrap();
+  std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+  let client = Repository::init(tmp_dir.path().join("client")).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |contents: &str, parents: Vec<OID>| {
+    let odb = client.odb();
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(
+      tree,
+      parents,
+      signature.clone(),
+      signature.clone(),
+      "commit\n",
+    );
+    odb.write_commit(&commit).unwrap()
+  };
+  // Enough that doesn't compress well for a delta to be worth it
+  let mut contents: String = (0..2000)
+    .map(|i| format!("{}\n", OID::hash(i.to_string())))
+    .collect();
+  let first = commit(&contents, vec![]);
+  let url = format!("{}/server.git", serve_http_backend(tmp_dir.path()));
+  let mut transport = HttpTransport::new(&url).unwrap();
+  let statuses = |outcome: &PushOutcome| -> Vec<PushStatus> {
+    outcome.refs.iter().map(|r| r.status.clone()).collect()
+  };
+
+  let outcome = transport
+    .push(
+      &client,
+      &[
+        PushUpdate::new("refs/heads/master", first),
+        PushUpdate::new("refs/heads/locked", first),
+      ],
+    )
+    .unwrap();
+  assert_eq!(
+    vec![PushStatus::Ok, PushStatus::Rejected("hook declined".into())],
+    statuses(&outcome)
+  );
+  assert!(!outcome.is_ok());
+  assert_eq!(None, outcome.refs[0].old);
+  assert_eq!(
+    Some(first),
+    server.refs().resolve("refs/heads/master").unwrap()
+  );
+  assert_eq!(None, server.refs().resolve("refs/heads/locked").unwrap());
+  let tree = *server.odb().read_commit(&first).unwrap().tree();
+  let blob = *server.odb().read_tree(&tree).unwrap().entries()[0].oid();
+  assert_eq!(contents, server.odb().read_blob(&blob).unwrap().contents());
+
+  // Only the changed blob goes the second time, as a delta against the
+  // one the remote has
+  contents.replace_range(100..110, "changed!!!");
+  let second = commit(&contents, vec![first]);
+  let updates = [PushUpdate::new("refs/heads/master", second)];
+  let (request, _) = push_request(&client, transport.list_push_refs().unwrap(), &updates).unwrap();
+  assert!(request.unwrap().len() < contents.len() / 4);
+  let outcome = transport.push(&client, &updates).unwrap();
+  assert!(outcome.is_ok());
+  assert_eq!(Some(first), outcome.refs[0].old);
+  assert_eq!(
+    Some(second),
+    server.refs().resolve("refs/heads/master").unwrap()
+  );
+  server.odb().read_commit(&second).unwrap();
+  assert_eq!(
+    vec![PushStatus::UpToDate],
+    statuses(&transport.push(&client, &updates).unwrap())
+  );
+
+  // Going back to the first commit loses one unless it's forced
+  let rewind = PushUpdate::new("refs/heads/master", first);
+  assert_eq!(
+    vec![PushStatus::NonFastForward],
+    statuses(
+      &transport
+        .push(&client, std::slice::from_ref(&rewind))
+        .unwrap()
+    )
+  );
+  assert_eq!(
+    Some(second),
+    server.refs().resolve("refs/heads/master").unwrap()
+  );
+  let outcome = transport.push(&client, &[rewind.force(true)]).unwrap();
+  assert!(outcome.is_ok());
+  assert_eq!(
+    Some(first),
+    server.refs().resolve("refs/heads/master").unwrap()
+  );
+
+  // A ref pointing at something that isn't here needs a fetch first
+  let other = Repository::init(tmp_dir.path().join("other")).unwrap();
+  other.odb().write_blob(&Blob::new("other\n")).unwrap();
+  let (_, refs) = push_request(
+    &other,
+    transport.list_push_refs().unwrap(),
+    &[PushUpdate::new("refs/heads/master", OID::hash("other"))],
+  )
+  .unwrap();
+  assert_eq!(PushStatus::FetchFirst, refs[0].status);
+
+  transport
+    .push(&client, &[PushUpdate::new("refs/heads/topic", second)])
+    .unwrap();
+  let outcome = transport
+    .push(&client, &[PushUpdate::delete("refs/heads/topic")])
+    .unwrap();
+  assert!(outcome.is_ok());
+  assert_eq!(Some(second), outcome.refs[0].old);
+  assert_eq!(None, server.refs().resolve("refs/heads/topic").unwrap());
+}
+
+#[test]
+fn v2_commands() {
+  use crate::{Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  if !have_git() {
+    return;
+  }
+  let tmp_dir = tempdir::TempDir::new("http_test").unwrap();
+  let server_path = tmp_dir.path().join("server.git");
+  let server = Repository::init_bare(&server_path).unwrap();
+  let mut config = std::fs::OpenOptions::new()
+    .append(true)
+    .open(server_path.join("config"))
+    .unwrap();
+  writeln!(
+    config,
+    "[uploadpack]\n\tallowFilter = true\n[transfer]\n\tadvertiseObjectInfo = true"
+  )
+  .unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let odb = server.odb();
+  let mut parents = Vec::new();
+  let mut blobs = Vec::new();
+  for contents in ["first\n", "second\n"] {
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(
+      tree,
+      parents,
+      signature.clone(),
+      signature.clone(),
+      "commit\n",
+    );
+    parents = vec![odb.write_commit(&commit).unwrap()];
+    blobs.push(blob);
+  }
+  let second = parents[0];
+  server.refs().write("refs/heads/master", &second).unwrap();
+  server.refs().write("refs/tags/v2", &second).unwrap();
+  let url = format!("{}/server.git", serve_http_backend(tmp_dir.path()));
+
+  // Only the branches are listed when only they are asked for
+  let mut transport = HttpTransport::new(&url)
+    .unwrap()
+    .with_ref_prefixes(["refs/heads/"]);
+  let names: Vec<_> = transport
+    .list_refs()
+    .unwrap()
+    .refs
+    .iter()
+    .map(|reference| reference.name.clone())
+    .collect();
+  assert_eq!(vec![BString::from("refs/heads/master")], names);
+
+  // A fetch of depth 1 only has the last commit
+  let shallow = Repository::init(tmp_dir.path().join("shallow")).unwrap();
+  let options = FetchOptions {
+    depth: Some(1),
+    ..FetchOptions::default()
+  };
+  let outcome = transport
+    .fetch_with_options(&shallow, &[second], &options)
+    .unwrap();
+  assert_eq!(3, outcome.objects.len());
+  assert_eq!(vec![second], outcome.shallow);
+
+  // A filtered fetch leaves out every blob
+  let partial = Repository::init(tmp_dir.path().join("partial")).unwrap();
+  let options = FetchOptions {
+    filter: Some("blob:none".into()),
+    ..FetchOptions::default()
+  };
+  let outcome = transport
+    .fetch_with_options(&partial, &[second], &options)
+    .unwrap();
+  assert_eq!(4, outcome.objects.len());
+  assert!(!outcome.objects.iter().any(|oid| blobs.contains(oid)));
+
+  let missing = OID::hash("missing");
+  assert_eq!(
+    vec![(blobs[1], Some(7)), (missing, None)],
+    transport.object_info(&[blobs[1], missing]).unwrap()
+  );
+
+  // Protocol v1 has none of it
+  let mut v1 = HttpTransport::new(&url)
+    .unwrap()
+    .with_protocol(ProtocolVersion::V1);
+  let client = Repository::init(tmp_dir.path().join("v1")).unwrap();
+  assert!(matches!(
+    v1.fetch_with_options(&client, &[second], &options),
+    Err(TransportError::Unsupported(_))
+  ));
+}
+
+#[test]
+fn shallow_fetches() {
+  use crate::{Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  if !have_git() {
+    return;
+  }
+  let tmp_dir = tempdir::TempDir::new("http_test").unwrap();
+  let server = Repository::init_bare(tmp_dir.path().join("server.git")).unwrap();
+  let odb = server.odb();
+  let mut commits: Vec<OID> = Vec::new();
+  for i in 0..4 {
+    let signature = Signature::new("A U Thor", "author@example.com", Time::new(i, 0));
+    let blob = odb.write_blob(&Blob::new(format!("{}\n", i))).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let parents = commits.last().copied().into_iter().collect();
+    let commit = Commit::new(tree, parents, signature.clone(), signature, "commit\n");
+    commits.push(odb.write_commit(&commit).unwrap());
+  }
+  let tip = commits[3];
+  server.refs().write("refs/heads/master", &tip).unwrap();
+  let url = format!("{}/server.git", serve_http_backend(tmp_dir.path()));
+
+  for version in [ProtocolVersion::V1, ProtocolVersion::V2].iter() {
+    let path = tmp_dir.path().join(format!("{:?}", version));
+    let client = Repository::init(&path).unwrap();
+    let mut transport = HttpTransport::new(&url).unwrap().with_protocol(*version);
+    let walk = |client: &Repository| {
+      let mut walk = client.rev_walk();
+      walk.push(&tip).unwrap();
+      walk.map(Result::unwrap).collect::<Vec<_>>()
+    };
+    let fetch = |transport: &mut HttpTransport, depth, deepen_relative| {
+      let options = FetchOptions {
+        depth: Some(depth),
+        deepen_relative,
+        ..FetchOptions::default()
+      };
+      transport
+        .fetch_with_options(&client, &[tip], &options)
+        .unwrap()
+    };
+
+    let outcome = fetch(&mut transport, 1, false);
+    assert_eq!(vec![tip], outcome.shallow, "{:?}", version);
+    assert_eq!(vec![tip], client.shallow_commits().unwrap());
+    client.refs().write("refs/heads/master", &tip).unwrap();
+    assert_eq!(vec![tip], walk(&client));
+
+    // Two more commits from where the history ends now
+    let outcome = fetch(&mut transport, 2, true);
+    assert_eq!(vec![commits[1]], outcome.shallow, "{:?}", version);
+    assert_eq!(vec![tip], outcome.unshallow);
+    assert_eq!(vec![commits[1]], client.shallow_commits().unwrap());
+    assert_eq!(3, walk(&client).len());
+
+    // And the rest of it
+    fetch(&mut transport, FetchOptions::INFINITE_DEPTH, false);
+    assert!(!client.is_shallow());
+    assert_eq!(4, walk(&client).len());
+  }
+
+  let options = crate::CloneOptions {
+    depth: Some(1),
+    ..crate::CloneOptions::default()
+  };
+  let path = tmp_dir.path().join("clone");
+  let clone = Repository::clone(&url, &path, &options).unwrap();
+  assert_eq!(vec![tip], clone.shallow_commits().unwrap());
+  assert_eq!(
+    "3\n",
+    std::fs::read_to_string(path.join("file.txt")).unwrap()
+  );
+}
+
+#[test]
+fn partial_clone() {
+  use crate::{Blob, CloneOptions, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  if !have_git() {
+    return;
+  }
+  let tmp_dir = tempdir::TempDir::new("http_test").unwrap();
+  let server_path = tmp_dir.path().join("server.git");
+  let server = Repository::init_bare(&server_path).unwrap();
+  let mut config = std::fs::OpenOptions::new()
+    .append(true)
+    .open(server_path.join("config"))
+    .unwrap();
+  writeln!(config, "[uploadpack]\n\tallowFilter = true").unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let odb = server.odb();
+  let mut parents = Vec::new();
+  let mut blobs = Vec::new();
+  for contents in ["first\n", "second\n"] {
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(
+      tree,
+      parents,
+      signature.clone(),
+      signature.clone(),
+      "commit\n",
+    );
+    parents = vec![odb.write_commit(&commit).unwrap()];
+    blobs.push(blob);
+  }
+  server
+    .refs()
+    .write("refs/heads/master", &parents[0])
+    .unwrap();
+  let url = format!("{}/server.git", serve_http_backend(tmp_dir.path()));
+
+  let options = CloneOptions {
+    filter: Some("blob:none".into()),
+    ..CloneOptions::default()
+  };
+  let path = tmp_dir.path().join("clone");
+  let clone = Repository::clone(&url, &path, &options).unwrap();
+  let config = clone.config();
+  assert_eq!(
+    Some("origin"),
+    config.get_str("extensions.partialclone").unwrap()
+  );
+  assert_eq!(
+    Some(true),
+    config.get_bool("remote.origin.promisor").unwrap()
+  );
+  // The blob checked out was fetched, the older one only once it's read
+  assert_eq!(
+    "second\n",
+    std::fs::read_to_string(path.join("file.txt")).unwrap()
+  );
+  let reopened = Repository::open(&path).unwrap();
+  let oids = reopened.odb().oids().unwrap();
+  assert!(oids.contains(&blobs[1]));
+  assert!(!oids.contains(&blobs[0]));
+  assert_eq!(
+    "first\n",
+    reopened.odb().read_blob(&blobs[0]).unwrap().contents()
+  );
+  assert!(reopened.odb().oids().unwrap().contains(&blobs[0]));
+}
+
+#[test]
+fn authentication() {
+  use crate::CredentialError;
+  use std::sync::Mutex;
+  if !have_git() {
+    return;
+  }
+  let tmp_dir = tempdir::TempDir::new("http_test").unwrap();
+  Repository::init_bare(tmp_dir.path().join("server.git")).unwrap();
+  let url = format!("{}/server.git", serve_http_backend(tmp_dir.path()));
+
+  /// Only lets requests with the right basic auth through to the server
+  struct Guarded(Arc<Mutex<Vec<Option<String>>>>);
+  impl HttpClient for Guarded {
+    fn send(&self, request: &HttpRequest) -> io::Result<HttpResponse> {
+      let auth = request
+        .headers
+        .iter()
+        .find(|(name, _)| name == "Authorization")
+        .map(|(_, value)| value.clone());
+      self.0.lock().unwrap().push(auth.clone());
+      let status = |status| HttpResponse {
+        status,
+        headers: Vec::new(),
+        body: Vec::new(),
+      };
+      match auth.as_deref() {
+        Some("Basic amFuZTpodW50ZXIy") => DefaultHttpClient::new().send(request),
+        Some("Basic amFuZTpmb3JiaWRkZW4=") => Ok(status(403)),
+        Some("Basic amFuZTpicm9rZW4=") => Ok(status(500)),
+        _ => Ok(HttpResponse {
+          status: 401,
+          headers: vec![("WWW-Authenticate".into(), "Basic realm=\"git\"".into())],
+          body: Vec::new(),
+        }),
+      }
+    }
+  }
+
+  let sent = Arc::new(Mutex::new(Vec::new()));
+  let verdicts = Arc::new(Mutex::new(Vec::new()));
+  struct Recording(Arc<Mutex<Vec<&'static str>>>, &'static str);
+  impl Credentials for Recording {
+    fn fill(&self, credential: &mut Credential) -> Result<(), CredentialError> {
+      assert!(credential.host.as_ref().unwrap().starts_with("127.0.0.1:"));
+      credential.username = Some("jane".into());
+      credential.password = Some(self.1.into());
+      Ok(())
+    }
+    fn approve(&self, _: &Credential) -> Result<(), CredentialError> {
+      self.0.lock().unwrap().push("approve");
+      Ok(())
+    }
+    fn reject(&self, _: &Credential) -> Result<(), CredentialError> {
+      self.0.lock().unwrap().push("reject");
+      Ok(())
+    }
+  }
+
+  let mut transport = HttpTransport::new(&url)
+    .unwrap()
+    .with_client(Guarded(sent.clone()))
+    .with_credentials(Recording(verdicts.clone(), "hunter2"));
+  assert!(transport.list_refs().unwrap().refs.is_empty());
+  transport.list_push_refs().unwrap();
+  // Once the credentials worked they're sent right away
+  let auth = Some("Basic amFuZTpodW50ZXIy".to_string());
+  assert_eq!(
+    vec![None, auth.clone(), auth.clone(), auth],
+    *sent.lock().unwrap()
+  );
+  assert_eq!(vec!["approve"], *verdicts.lock().unwrap());
+
+  verdicts.lock().unwrap().clear();
+  let mut transport = HttpTransport::new(&url)
+    .unwrap()
+    .with_client(Guarded(sent))
+    .with_credentials(Recording(verdicts.clone(), "wrong"));
+  assert!(matches!(
+    transport.list_refs(),
+    Err(TransportError::Http { status: 401, .. })
+  ));
+  assert_eq!(vec!["reject"], *verdicts.lock().unwrap());
+
+  // Forbidden credentials are rejected too, and a server error says
+  // nothing about them
+  for (password, status, verdict) in [("forbidden", 403, vec!["reject"]), ("broken", 500, vec![])] {
+    verdicts.lock().unwrap().clear();
+    let mut transport = HttpTransport::new(&url)
+      .unwrap()
+      .with_client(Guarded(Arc::default()))
+      .with_credentials(Recording(verdicts.clone(), password));
+    assert!(matches!(
+      transport.list_refs(),
+      Err(TransportError::Http { status: s, .. }) if s == status
+    ));
+    assert_eq!(verdict, *verdicts.lock().unwrap());
+  }
+
+  let mut transport = HttpTransport::new(&url)
+    .unwrap()
+    .with_client(Guarded(Arc::default()))
+    .with_credentials(|_: &mut Credential| -> Result<(), CredentialError> {
+      Err(CredentialError::Missing("nobody".into()))
+    });
+  assert!(matches!(
+    transport.list_refs(),
+    Err(TransportError::Credential(CredentialError::Missing(_)))
+  ));
+}
@@ -0,0 +1,310 @@
+//! Fetching from and pushing to a repository on the same machine, named
+//! by a path or a `file://` URL. Both repositories are opened right here,
+//! so nothing goes through the wire protocol: a fetch writes the pack
+//! straight from the other repository's objects, and a clone into an empty
+//! repository hardlinks its packs and loose objects like `git clone` does
+//! for local paths.
+
+use super::{
+  missing_wants, plan_push, write_pack_for, Advertisement, FetchOutcome, Negotiation,
+  ProtocolVersion, PushOutcome, PushStatus, PushUpdate, Transport, TransportError,
+};
+use crate::{HiddenRefs, Repository, UploadPack, OID};
+use std::{
+  fmt, fs, io,
+  path::{Path, PathBuf},
+};
+
+/// Fetches from and pushes to a repository on the same machine
+pub struct LocalTransport {
+  path: PathBuf,
+  hardlinks: bool,
+  remote: Option<Repository>,
+  advertisement: Option<Advertisement>,
+  push_advertisement: Option<Advertisement>,
+}
+
+impl fmt::Debug for LocalTransport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("LocalTransport")
+      .field("path", &self.path)
+      .field("hardlinks", &self.hardlinks)
+      .field("advertisement", &self.advertisement)
+      .field("push_advertisement", &self.push_advertisement)
+      .finish()
+  }
+}
+
+impl LocalTransport {
+  /// Talk to the repository at `url`, which is either a path or a
+  /// `file://` URL. The repository isn't opened until the refs are needed.
+  pub fn new(url: impl Into<String>) -> Result<Self, TransportError> {
+    let url = url.into();
+    let path = match url.strip_prefix("file://") {
+      Some(path) => path.to_string(),
+      None if url.contains("://") => return Err(TransportError::UnsupportedUrl(url)),
+      None => url,
+    };
+    if path.is_empty() {
+      return Err(TransportError::UnsupportedUrl(path));
+    }
+    Ok(Self {
+      path: path.into(),
+      hardlinks: true,
+      remote: None,
+      advertisement: None,
+      push_advertisement: None,
+    })
+  }
+
+  /// Whether a fetch into a repository without any objects hardlinks the
+  /// objects of the other repository, which is on by default. Files are
+  /// copied instead when they're on different file systems. Without it
+  /// only what's reachable from the refs fetched is written, in a pack.
+  pub fn with_hardlinks(mut self, hardlinks: bool) -> Self {
+    self.hardlinks = hardlinks;
+    self
+  }
+
+  /// The path of the repository
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  fn remote(&mut self) -> Result<&Repository, TransportError> {
+    if self.remote.is_none() {
+      self.remote = Some(Repository::open(&self.path)?);
+    }
+    Ok(self.remote.as_ref().unwrap())
+  }
+
+  /// What the other repository would advertise, hiding the refs its config
+  /// says to for `section`, which is `uploadpack` or `receivepack`
+  fn advertise(&mut self, section: &str) -> Result<Advertisement, TransportError> {
+    let remote = self.remote()?;
+    let mut upload_pack = UploadPack::new(remote);
+    *upload_pack.hidden_refs() = HiddenRefs::from_config(remote.config(), section);
+    let refs = upload_pack.advertised_refs().map_err(Box::new)?;
+    Ok(Advertisement {
+      version: ProtocolVersion::V1,
+      refs,
+      capabilities: vec!["delete-refs".into()],
+    })
+  }
+}
+
+/// Link the objects in `from` into `to`, copying them if they can't be
+/// linked, and skipping those already there
+fn link_objects(from: &Path, to: &Path) -> io::Result<()> {
+  for entry in fs::read_dir(from)? {
+    let entry = entry?;
+    let name = entry.file_name();
+    let target = to.join(&name);
+    if entry.file_type()?.is_dir() {
+      // Only the directories of loose objects and packs hold objects
+      let is_objects = name == "pack" || name.to_str().is_some_and(|name| name.len() == 2);
+      if is_objects {
+        fs::create_dir_all(&target)?;
+        link_objects(&entry.path(), &target)?;
+      }
+      continue;
+    }
+    // Half written files of the other repository are left out
+    if name.to_str().is_some_and(|name| name.starts_with("tmp_")) || target.exists() {
+      continue;
+    }
+    if fs::hard_link(entry.path(), &target).is_err() {
+      fs::copy(entry.path(), &target)?;
+    }
+  }
+  Ok(())
+}
+
+impl Transport for LocalTransport {
+  fn list_refs(&mut self) -> Result<&Advertisement, TransportError> {
+    if self.advertisement.is_none() {
+      self.advertisement = Some(self.advertise("uploadpack")?);
+    }
+    Ok(self.advertisement.as_ref().unwrap())
+  }
+
+  fn list_push_refs(&mut self) -> Result<&Advertisement, TransportError> {
+    if self.push_advertisement.is_none() {
+      self.push_advertisement = Some(self.advertise("receivepack")?);
+    }
+    Ok(self.push_advertisement.as_ref().unwrap())
+  }
+
+  fn fetch(&mut self, repo: &Repository, wants: &[OID]) -> Result<FetchOutcome, TransportError> {
+    let wants = missing_wants(repo, wants)?;
+    if wants.is_empty() {
+      return Ok(FetchOutcome::default());
+    }
+    let hardlinks = self.hardlinks;
+    let remote = self.remote()?;
+    for want in &wants {
+      if !remote.odb().contains(want)? {
+        return Err(TransportError::Remote(
+          format!("{} is not there", want).into(),
+        ));
+      }
+    }
+    // Objects borrowed from alternates aren't copied again
+    if hardlinks && repo.odb().alternates().is_empty() && repo.odb().oids()?.is_empty() {
+      fs::create_dir_all(repo.odb().path())?;
+      link_objects(remote.odb().path(), repo.odb().path())?;
+      return Ok(FetchOutcome {
+        objects: repo.odb().oids()?,
+        ..FetchOutcome::default()
+      });
+    }
+    // Only commits the other repository has tell it anything
+    let mut haves = Vec::new();
+    for have in super::local_haves(repo, Negotiation::from_config(repo))? {
+      if remote.odb().contains(&have)? {
+        haves.push(have);
+      }
+    }
+    let (pack, _) = write_pack_for(remote, &wants, &haves, false, Vec::new())?;
+    Ok(FetchOutcome {
+      objects: repo.odb().write_pack(&pack)?,
+      ..FetchOutcome::default()
+    })
+  }
+
+  fn push(
+    &mut self,
+    repo: &Repository,
+    updates: &[PushUpdate],
+  ) -> Result<PushOutcome, TransportError> {
+    let advertisement = self.list_push_refs()?.clone();
+    // Whatever happens, what's there now is asked for again next time
+    self.push_advertisement = None;
+    let (mut refs, pending) = plan_push(repo, &advertisement, updates)?;
+    let remote = self.remote()?;
+    let wants: Vec<OID> = pending.iter().filter_map(|&i| refs[i].new).collect();
+    if !wants.is_empty() {
+      let haves: Vec<OID> = advertisement
+        .refs
+        .iter()
+        .map(|r| r.oid)
+        .filter(|oid| repo.odb().read(oid).is_ok())
+        .collect();
+      let (pack, _) = write_pack_for(repo, &wants, &haves, false, Vec::new())?;
+      remote.odb().write_pack(&pack)?;
+    }
+    // Nothing like receive-pack's hooks is run, the refs are only checked
+    // to still be where they were when the push was planned
+    for i in pending {
+      let reference = &mut refs[i];
+      if remote.refs().resolve(&reference.name)? != reference.old {
+        reference.status = PushStatus::Rejected("stale info".into());
+        continue;
+      }
+      match reference.new {
+        Some(new) => remote.refs().write(&reference.name, &new)?,
+        None => {
+          remote.refs().delete(&reference.name)?;
+        }
+      }
+      reference.status = PushStatus::Ok;
+    }
+    Ok(PushOutcome {
+      refs,
+      progress: "".into(),
+    })
+  }
+}
+
+#[test]
+fn clone_fetch_and_push() {
+  use crate::{Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  let tmp_dir = tempdir::TempDir::new("local_test").unwrap();
+  let source = Repository::init_bare(tmp_dir.path().join("source.git")).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |repo: &Repository, contents: &str, parents: Vec<OID>| {
+    let odb = repo.odb();
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(
+      tree,
+      parents,
+      signature.clone(),
+      signature.clone(),
+      "commit\n",
+    );
+    let oid = odb.write_commit(&commit).unwrap();
+    repo.refs().write("refs/heads/master", &oid).unwrap();
+    oid
+  };
+  let first = commit(&source, "first\n", vec![]);
+  let url = format!("file://{}", tmp_dir.path().join("source.git").display());
+  assert!(LocalTransport::new("https://example.com/repo").is_err());
+
+  // A clone links every object over
+  let clone = Repository::init(tmp_dir.path().join("clone")).unwrap();
+  let mut transport = LocalTransport::new(&url).unwrap();
+  let head = transport.list_refs().unwrap().get("HEAD").unwrap().clone();
+  assert_eq!(
+    (first, Some("refs/heads/master".into())),
+    (head.oid, head.symref_target)
+  );
+  assert_eq!(3, transport.fetch(&clone, &[first]).unwrap().objects.len());
+  clone.odb().read_commit(&first).unwrap();
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::MetadataExt;
+    let hex = first.as_hex();
+    let path = format!("{}/{}", &hex[..2], &hex[2..]);
+    let linked = fs::metadata(clone.odb().path().join(&path)).unwrap();
+    assert_eq!(2, linked.nlink());
+  }
+
+  // After that only what's new comes, in a pack
+  let second = commit(&source, "second\n", vec![first]);
+  clone
+    .refs()
+    .write("refs/remotes/origin/master", &first)
+    .unwrap();
+  let mut transport = LocalTransport::new(tmp_dir.path().join("source.git").to_str().unwrap())
+    .unwrap()
+    .with_hardlinks(false);
+  let outcome = transport.fetch(&clone, &[second]).unwrap();
+  assert_eq!(3, outcome.objects.len());
+  assert!(!outcome.objects.contains(&first));
+  assert!(matches!(
+    transport.fetch(&clone, &[OID::hash("missing")]),
+    Err(TransportError::Remote(_))
+  ));
+
+  // Pushing writes the objects and moves the refs of the other side
+  let third = commit(&clone, "third\n", vec![second]);
+  let outcome = transport
+    .push(
+      &clone,
+      &[
+        PushUpdate::new("refs/heads/master", third),
+        PushUpdate::new("refs/heads/old", first),
+      ],
+    )
+    .unwrap();
+  assert!(outcome.is_ok());
+  assert_eq!(
+    Some(third),
+    source.refs().resolve("refs/heads/master").unwrap()
+  );
+  source.odb().read_commit(&third).unwrap();
+  let rewind = PushUpdate::new("refs/heads/master", first);
+  let outcome = transport.push(&clone, &[rewind]).unwrap();
+  assert_eq!(PushStatus::NonFastForward, outcome.refs[0].status);
+  let outcome = transport
+    .push(&clone, &[PushUpdate::delete("refs/heads/old")])
+    .unwrap();
+  assert!(outcome.is_ok());
+  assert_eq!(None, source.refs().resolve("refs/heads/old").unwrap());
+}
@@ -0,0 +1,187 @@
+use super::{TransportError, MAX_HAVES};
+use crate::{OdbError, RefStore, Repository, RevWalk, RevWalkError, OID};
+use std::{collections::HashMap, str::FromStr};
+
+/// How a fetch picks the commits it tells the remote it has, which the
+/// remote leaves out of the pack along with their history. Only so many
+/// are sent, so on a long history the choice decides how much of what's
+/// already here comes again.
+///
+/// `fetch.negotiationAlgorithm` picks one when [`FetchOptions`] don't,
+/// and names git doesn't know are [`Negotiation::Consecutive`] like there.
+///
+/// [`FetchOptions`]: super::FetchOptions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Negotiation {
+  /// Every commit newest first starting from the refs, which finds the
+  /// newest common commit when it's close to the tips
+  #[default]
+  Consecutive,
+  /// Skip more and more commits the further back the walk goes, so older
+  /// history is still covered when the refs moved on a lot since the
+  /// remote was last fetched from. The common commit found can be older
+  /// than the newest one, which makes the pack a little bigger.
+  Skipping,
+  /// Tell the remote nothing, like [`FetchOptions::no_haves`]
+  ///
+  /// [`FetchOptions::no_haves`]: super::FetchOptions::no_haves
+  Noop,
+}
+
+impl Negotiation {
+  /// The algorithm the config of `repo` picks
+  pub fn from_config(repo: &Repository) -> Self {
+    repo
+      .config()
+      .get_str("fetch.negotiationalgorithm")
+      .ok()
+      .flatten()
+      .and_then(|name| name.parse().ok())
+      .unwrap_or_default()
+  }
+}
+
+impl FromStr for Negotiation {
+  type Err = TransportError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "consecutive" | "default" => Ok(Self::Consecutive),
+      "skipping" => Ok(Self::Skipping),
+      "noop" => Ok(Self::Noop),
+      _ => Err(TransportError::Unsupported(format!(
+        "negotiation algorithm {}",
+        s
+      ))),
+    }
+  }
+}
+
+/// The commits to tell a remote `repo` has as `negotiation` picks them,
+/// starting from every ref, so the remote can leave out what's reachable
+/// from them. The refs of the repositories whose objects are alternates of
+/// `repo` count too, like git's alternate ref tips.
+pub(crate) fn local_haves(
+  repo: &Repository,
+  negotiation: Negotiation,
+) -> Result<Vec<OID>, TransportError> {
+  if negotiation == Negotiation::Noop {
+    return Ok(Vec::new());
+  }
+  let mut walk = repo.rev_walk();
+  push_tips(&mut walk, repo.refs())?;
+  for alternate in repo.odb().alternates() {
+    let path = alternate.path();
+    if let (Some(git_dir), Some("objects")) = (
+      path.parent(),
+      path.file_name().and_then(|name| name.to_str()),
+    ) {
+      push_tips(&mut walk, &RefStore::new(git_dir))?;
+    }
+  }
+  match negotiation {
+    Negotiation::Skipping => skipping(walk),
+    _ => walk.take(MAX_HAVES).map(|oid| Ok(oid?)).collect(),
+  }
+}
+
+/// Push what `HEAD` and the refs of `refs` point at
+fn push_tips(walk: &mut RevWalk<'_>, refs: &RefStore) -> Result<(), TransportError> {
+  for reference in refs.read("HEAD")?.into_iter().chain(refs.list("refs/")?) {
+    let oid = match refs.resolve(reference.name())? {
+      Some(oid) => oid,
+      None => continue,
+    };
+    // Refs to trees, blobs, or missing objects have no history to share
+    match walk.push(&oid) {
+      Ok(_) | Err(RevWalkError::Odb(OdbError::NotFound(_))) => {}
+      Err(RevWalkError::NotACommit { .. }) => {}
+      Err(e) => return Err(e.into()),
+    }
+  }
+  Ok(())
+}
+
+/// Git's skipping negotiator: after a commit is sent, the next `skip`
+/// commits behind it are skipped, which grows by half each time. The
+/// walk still goes through every commit in between, newest first, so
+/// merges are skipped no differently than the rest and the commit-graph
+/// keeps it quick.
+fn skipping(mut walk: RevWalk<'_>) -> Result<Vec<OID>, TransportError> {
+  // How many more commits to skip, and how many were skipped after the
+  // last commit sent on the way here
+  let mut skips: HashMap<OID, (u32, u32)> = HashMap::new();
+  let mut haves = Vec::new();
+  while haves.len() < MAX_HAVES {
+    let oid = match walk.next() {
+      Some(oid) => oid?,
+      None => break,
+    };
+    let (left, skipped) = skips.remove(&oid).unwrap_or((0, 0));
+    let next = match left {
+      0 => {
+        haves.push(oid);
+        let skip = (skipped * 3 / 2).max(skipped + 1);
+        (skip, skip)
+      }
+      left => (left - 1, skipped),
+    };
+    // A commit reached from more than one child skips the least
+    for parent in walk.parents(&oid) {
+      let entry = skips.entry(*parent).or_insert(next);
+      *entry = (*entry).min(next);
+    }
+  }
+  Ok(haves)
+}
+
+#[test]
+fn negotiation_algorithms() {
+  use crate::{Commit, ConfigFile, ConfigLevel, Signature, Time, Tree};
+  let dir = tempdir::TempDir::new("negotiate_test").unwrap();
+  let mut repo = Repository::init(dir.path()).unwrap();
+  let odb = repo.odb();
+  let tree = odb.write_tree(&Tree::new(Vec::new())).unwrap();
+  let mut history: Vec<OID> = Vec::new();
+  for i in 0..1000 {
+    let time = Time::new(1_600_000_000 + i, 0);
+    let signature = Signature::new("Jane Doe", "jane@example.com", time);
+    let parents = history.last().copied().into_iter().collect();
+    let commit = Commit::new(tree, parents, signature.clone(), signature, i.to_string());
+    history.push(odb.write_commit(&commit).unwrap());
+  }
+  repo
+    .refs()
+    .write("refs/heads/master", history.last().unwrap())
+    .unwrap();
+  history.reverse();
+
+  let consecutive = local_haves(&repo, Negotiation::Consecutive).unwrap();
+  assert_eq!(&history[..MAX_HAVES], &consecutive[..]);
+  assert!(local_haves(&repo, Negotiation::Noop).unwrap().is_empty());
+
+  // Every commit sent is further from the last one than that was from the
+  // one before, going back much further with far fewer haves
+  let skipping = local_haves(&repo, Negotiation::Skipping).unwrap();
+  let depths: Vec<usize> = skipping
+    .iter()
+    .map(|oid| history.iter().position(|c| c == oid).unwrap())
+    .collect();
+  assert_eq!(&[0, 2, 5, 9, 14][..], &depths[..5]);
+  assert!(depths.windows(3).all(|d| d[2] - d[1] > d[1] - d[0]));
+  assert!(skipping.len() < 50);
+  assert!(*depths.last().unwrap() > 900);
+
+  assert_eq!(Negotiation::Consecutive, Negotiation::from_config(&repo));
+  let mut config =
+    ConfigFile::from_file(dir.path().join(".git/config"), ConfigLevel::Local).unwrap();
+  config
+    .set("fetch.negotiationAlgorithm", "skipping")
+    .unwrap();
+  config.save().unwrap();
+  repo.reload_config().unwrap();
+  assert_eq!(Negotiation::Skipping, Negotiation::from_config(&repo));
+  assert!(matches!(
+    "fancy".parse::<Negotiation>(),
+    Err(TransportError::Unsupported(_))
+  ));
+}
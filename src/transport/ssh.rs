@@ -0,0 +1,522 @@
+//! Fetching and pushing over SSH by running the system's `ssh`, which
+//! takes care of keys, agents, known hosts, and its config like git has it
+//! do. The remote runs `git-upload-pack` or `git-receive-pack` and the two
+//! sides talk over its standard input and output, a new connection for
+//! every fetch and push.
+
+use super::{
+  fetch_pack, fetch_wants, ls_refs_request, object_info_request, parse_ls_refs, parse_object_info,
+  push_pack, read_advertisement, read_until_flush, retain_prefixed, Advertisement, FetchOptions,
+  FetchOutcome, MessageKind, PacketReader, ProtocolVersion, PushOutcome, PushUpdate, Recorder,
+  Transport, TransportError,
+};
+use crate::{Repository, OID};
+use bstr::{BString, ByteSlice};
+use std::{
+  env,
+  io::{self, Read, Write},
+  process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+  thread,
+};
+
+/// Where an SSH URL points, from either `ssh://[user@]host[:port]/path`
+/// or the shorter `[user@]host:path` that `scp` takes
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SshUrl {
+  /// Who to log in as, or whoever the config of `ssh` says
+  pub user: Option<String>,
+  /// The host to connect to
+  pub host: String,
+  /// The port to connect to, or whatever the config of `ssh` says
+  pub port: Option<u16>,
+  /// The path of the repository on the host, relative to the home
+  /// directory of the user unless it starts with `/`
+  pub path: String,
+}
+
+impl SshUrl {
+  /// Parse `url`, `None` if it isn't an SSH URL
+  pub fn parse(url: &str) -> Option<Self> {
+    let rest = ["ssh://", "git+ssh://", "ssh+git://"]
+      .iter()
+      .find_map(|scheme| url.strip_prefix(scheme));
+    let (authority, path) = match rest {
+      Some(rest) => {
+        let (authority, path) = rest.split_at(rest.find('/')?);
+        // `ssh://host/~user/repo` is `~user/repo` like for scp
+        (
+          authority,
+          path
+            .strip_prefix("/~")
+            .map_or(path.to_string(), |rest| format!("~{}", rest)),
+        )
+      }
+      None => {
+        // Anything with a slash before the colon is a local path, as is
+        // anything with a scheme
+        let host_end = if url.starts_with('[') {
+          url.find(']')?
+        } else {
+          0
+        };
+        let colon = host_end + url[host_end..].find(':')?;
+        if url.contains("://") || url[..colon].contains('/') {
+          return None;
+        }
+        (&url[..colon], url[colon + 1..].to_string())
+      }
+    };
+    let (user, host) = match authority.rsplit_once('@') {
+      Some((user, host)) => (Some(user.to_string()), host),
+      None => (None, authority),
+    };
+    // IPv6 addresses are in brackets so their colons aren't taken for a
+    // port, and the port only comes in the long form
+    let (host, port) = match host.strip_prefix('[') {
+      Some(host) => {
+        let (host, rest) = host.split_once(']')?;
+        (host, rest.strip_prefix(':'))
+      }
+      None if rest.is_some() => match host.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (host, None),
+      },
+      None => (host, None),
+    };
+    let port = match port {
+      Some(port) => Some(port.parse().ok()?),
+      None => None,
+    };
+    if host.is_empty() || path.is_empty() {
+      return None;
+    }
+    Some(Self {
+      user,
+      host: host.to_string(),
+      port,
+      path,
+    })
+  }
+}
+
+/// Fetches from and pushes to a repository over SSH
+#[derive(Debug)]
+pub struct SshTransport {
+  url: SshUrl,
+  command: String,
+  version: ProtocolVersion,
+  advertisement: Option<Advertisement>,
+  push_advertisement: Option<Advertisement>,
+  ref_prefixes: Vec<BString>,
+  recorder: Option<Recorder>,
+}
+
+impl SshTransport {
+  /// Talk to the repository at `url`, which has to be an SSH URL, using
+  /// protocol v2 if the server has it. `ssh` is run with the command in
+  /// `GIT_SSH_COMMAND` if it's set like git does. Nothing is run until the
+  /// refs are needed.
+  pub fn new(url: impl Into<String>) -> Result<Self, TransportError> {
+    let url = url.into();
+    let url = SshUrl::parse(&url).ok_or(TransportError::UnsupportedUrl(url))?;
+    Ok(Self {
+      url,
+      command: env::var("GIT_SSH_COMMAND").unwrap_or_else(|_| "ssh".into()),
+      version: ProtocolVersion::V2,
+      advertisement: None,
+      push_advertisement: None,
+      ref_prefixes: Vec::new(),
+      recorder: None,
+    })
+  }
+
+  /// Run `command` instead of `ssh`, which like `GIT_SSH_COMMAND` is run
+  /// by the shell so it can have arguments of its own
+  pub fn with_command(mut self, command: impl Into<String>) -> Self {
+    self.command = command.into();
+    self
+  }
+
+  /// Ask for `version` of the protocol. It's sent in an environment
+  /// variable that servers have to let through, and those that don't or
+  /// don't have protocol v2 answer with protocol v1 anyway.
+  pub fn with_protocol(mut self, version: ProtocolVersion) -> Self {
+    self.version = version;
+    self
+  }
+
+  /// Only list the refs starting with one of `prefixes`, like
+  /// `refs/heads/`, which protocol v2 servers leave the rest out of the
+  /// response for. `HEAD` is only listed if it's one of them.
+  pub fn with_ref_prefixes<P: Into<BString>>(
+    mut self,
+    prefixes: impl IntoIterator<Item = P>,
+  ) -> Self {
+    self.ref_prefixes = prefixes.into_iter().map(Into::into).collect();
+    self
+  }
+
+  /// Write everything said either way to `recorder`, for replaying it with
+  /// a [`ReplayTransport`][super::ReplayTransport]. The flushes that only
+  /// hang up on the remote aren't written.
+  pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+    self.recorder = Some(recorder);
+    self
+  }
+
+  /// Where the repository is
+  pub fn url(&self) -> &SshUrl {
+    &self.url
+  }
+
+  fn record(&self, kind: MessageKind, service: &str, bytes: &[u8]) -> io::Result<()> {
+    match &self.recorder {
+      Some(recorder) => recorder.record(kind, service, bytes),
+      None => Ok(()),
+    }
+  }
+
+  /// Send `request` to `service` on `connection` and read the response
+  fn finish(
+    &self,
+    connection: Connection,
+    service: &str,
+    request: &[u8],
+  ) -> Result<Vec<u8>, TransportError> {
+    self.record(MessageKind::Request, service, request)?;
+    let response = connection.finish(request)?;
+    self.record(MessageKind::Response, service, &response)?;
+    Ok(response)
+  }
+
+  /// Run `service` on the remote for the repository and read what it
+  /// says first
+  fn connect(&self, service: &str) -> Result<(Connection, Advertisement), TransportError> {
+    let mut args = Vec::new();
+    if let Some(port) = self.url.port {
+      args.extend(["-p".to_string(), port.to_string()]);
+    }
+    // Pushing is only done with protocol v1
+    let v2 = self.version == ProtocolVersion::V2 && service == "git-upload-pack";
+    if v2 {
+      args.extend(["-o".to_string(), "SendEnv=GIT_PROTOCOL".to_string()]);
+    }
+    args.push(match &self.url.user {
+      Some(user) => format!("{}@{}", user, self.url.host),
+      None => self.url.host.clone(),
+    });
+    // The remote runs the command with its shell
+    args.push(format!(
+      "{} '{}'",
+      service,
+      self.url.path.replace('\'', r"'\''")
+    ));
+    let mut command = Command::new("sh");
+    command
+      .arg("-c")
+      .arg(format!("{} \"$@\"", self.command))
+      .arg(&self.command)
+      .args(args)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped());
+    if v2 {
+      command.env("GIT_PROTOCOL", "version=2");
+    }
+    let mut child = command.spawn()?;
+    let stderr = child.stderr.take().unwrap();
+    let mut connection = Connection {
+      stdin: child.stdin.take(),
+      stdout: child.stdout.take().unwrap(),
+      // Read as it comes so the remote never waits on a full pipe
+      stderr: Some(thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let _ = { stderr }.read_to_end(&mut bytes);
+        bytes
+      })),
+      child,
+    };
+    match read_until_flush(&mut connection.stdout) {
+      Ok(bytes) => {
+        self.record(MessageKind::Advertisement, service, &bytes)?;
+        Ok((connection, read_advertisement(&bytes)?))
+      }
+      // Not being able to log in or a repository that isn't there ends
+      // the connection before anything is said
+      Err(TransportError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+        Err(connection.failed())
+      }
+      Err(e) => Err(e),
+    }
+  }
+}
+
+impl Transport for SshTransport {
+  fn list_refs(&mut self) -> Result<&Advertisement, TransportError> {
+    if self.advertisement.is_none() {
+      let (connection, mut advertisement) = self.connect("git-upload-pack")?;
+      match advertisement.version {
+        ProtocolVersion::V1 => {
+          connection.finish(b"0000")?;
+        }
+        ProtocolVersion::V2 => {
+          let request = ls_refs_request(&advertisement, &self.ref_prefixes)?;
+          let response = self.finish(connection, "git-upload-pack", &request)?;
+          parse_ls_refs(&mut PacketReader::new(&response), &mut advertisement)?;
+        }
+      }
+      retain_prefixed(&mut advertisement, &self.ref_prefixes);
+      self.advertisement = Some(advertisement);
+    }
+    Ok(self.advertisement.as_ref().unwrap())
+  }
+
+  fn list_push_refs(&mut self) -> Result<&Advertisement, TransportError> {
+    if self.push_advertisement.is_none() {
+      let (connection, advertisement) = self.connect("git-receive-pack")?;
+      connection.finish(b"0000")?;
+      self.push_advertisement = Some(advertisement);
+    }
+    Ok(self.push_advertisement.as_ref().unwrap())
+  }
+
+  fn fetch(&mut self, repo: &Repository, wants: &[OID]) -> Result<FetchOutcome, TransportError> {
+    self.fetch_with_options(repo, wants, &FetchOptions::default())
+  }
+
+  fn fetch_with_options(
+    &mut self,
+    repo: &Repository,
+    wants: &[OID],
+    options: &FetchOptions,
+  ) -> Result<FetchOutcome, TransportError> {
+    let wants = fetch_wants(repo, wants, options)?;
+    if wants.is_empty() {
+      return Ok(FetchOutcome::default());
+    }
+    let (connection, advertisement) = self.connect("git-upload-pack")?;
+    fetch_pack(repo, &advertisement, &wants, options, |request| {
+      self.finish(connection, "git-upload-pack", &request)
+    })
+  }
+
+  fn object_info(&mut self, oids: &[OID]) -> Result<Vec<(OID, Option<u64>)>, TransportError> {
+    let (connection, advertisement) = self.connect("git-upload-pack")?;
+    let request = object_info_request(&advertisement, oids)?;
+    parse_object_info(&self.finish(connection, "git-upload-pack", &request)?)
+  }
+
+  fn push(
+    &mut self,
+    repo: &Repository,
+    updates: &[PushUpdate],
+  ) -> Result<PushOutcome, TransportError> {
+    let (connection, advertisement) = self.connect("git-receive-pack")?;
+    let mut connection = Some(connection);
+    let outcome = push_pack(repo, &advertisement, updates, |request| {
+      self.finish(connection.take().unwrap(), "git-receive-pack", &request)
+    })?;
+    // A flush tells the remote there's nothing to do when nothing was sent
+    if let Some(connection) = connection {
+      connection.finish(b"0000")?;
+    }
+    self.push_advertisement = None;
+    Ok(outcome)
+  }
+}
+
+/// A remote command being run over SSH
+struct Connection {
+  child: Child,
+  stdin: Option<ChildStdin>,
+  stdout: ChildStdout,
+  stderr: Option<thread::JoinHandle<Vec<u8>>>,
+}
+
+impl Connection {
+  /// Send the last of what there is to say and read everything the remote
+  /// says until it's done
+  fn finish(mut self, request: &[u8]) -> Result<Vec<u8>, TransportError> {
+    let mut response = Vec::new();
+    let stdin = self.stdin.take().unwrap();
+    let (written, read) = thread::scope(|scope| {
+      // Written from another thread so a remote that talks while it reads
+      // doesn't wait on us forever. Dropping the pipe at the end tells the
+      // remote that's all.
+      let writer = scope.spawn(move || { stdin }.write_all(request));
+      let read = self.stdout.read_to_end(&mut response);
+      (writer.join().unwrap(), read)
+    });
+    // The remote hangs up before reading it all when it fails, which is
+    // better told by what it said
+    if !self.child.wait()?.success() {
+      return Err(self.failed());
+    }
+    written?;
+    read?;
+    Ok(response)
+  }
+
+  /// The error for a connection that ended badly, with what the remote or
+  /// `ssh` said on its way out
+  fn failed(mut self) -> TransportError {
+    drop(self.stdin.take());
+    let status = self.child.wait();
+    let stderr = self.stderr.take().unwrap().join().unwrap_or_default();
+    let message = stderr.trim();
+    match status {
+      Ok(status) if message.is_empty() => {
+        TransportError::Remote(format!("ssh exited with {}", status).into())
+      }
+      _ => TransportError::Remote(BString::from(message)),
+    }
+  }
+}
+
+impl Drop for Connection {
+  fn drop(&mut self) {
+    // A remote left waiting for more is done once its input closes
+    drop(self.stdin.take());
+    let _ = self.child.wait();
+  }
+}
+
+#[test]
+fn parse_urls() {
+  let url = |user: Option<&str>, host: &str, port, path: &str| {
+    Some(SshUrl {
+      user: user.map(Into::into),
+      host: host.into(),
+      port,
+      path: path.into(),
+    })
+  };
+  for (expected, parsed) in [
+    (
+      url(Some("git"), "github.com", None, "user/repo.git"),
+      "git@github.com:user/repo.git",
+    ),
+    (url(None, "host", None, "/srv/repo"), "host:/srv/repo"),
+    (url(None, "host", None, "~/repo"), "host:~/repo"),
+    (
+      url(Some("git"), "host", Some(2222), "/srv/repo"),
+      "ssh://git@host:2222/srv/repo",
+    ),
+    (
+      url(None, "host", None, "~alice/repo"),
+      "ssh://host/~alice/repo",
+    ),
+    (
+      url(None, "::1", Some(22), "/repo"),
+      "git+ssh://[::1]:22/repo",
+    ),
+    (url(None, "::1", None, "repo"), "[::1]:repo"),
+    (None, "./dir:with/colon"),
+    (None, "/local/path"),
+    (None, "https://github.com/user/repo"),
+    (None, "ssh://host"),
+    (None, "ssh://host:port/repo"),
+    (None, "host:"),
+  ] {
+    assert_eq!(expected, SshUrl::parse(parsed), "{}", parsed);
+  }
+}
+
+#[test]
+fn fetch_and_push() {
+  use super::{http::have_git, PushStatus};
+  use crate::{Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  use std::os::unix::fs::PermissionsExt;
+  if !have_git() {
+    return;
+  }
+  let tmp_dir = tempdir::TempDir::new("ssh_test").unwrap();
+  // Stands in for `ssh` by running the remote command right here, with
+  // GIT_PROTOCOL passed along like `SendEnv` would
+  let ssh = tmp_dir.path().join("ssh");
+  std::fs::write(
+    &ssh,
+    "#!/bin/sh\nfor last; do :; done\nexec sh -c \"$last\"\n",
+  )
+  .unwrap();
+  std::fs::set_permissions(&ssh, std::fs::Permissions::from_mode(0o755)).unwrap();
+  let command = format!(
+    "GIT_CONFIG_NOSYSTEM=1 GIT_CONFIG_GLOBAL=/dev/null {}",
+    ssh.display()
+  );
+  let server_path = tmp_dir.path().join("server.git");
+  let server = Repository::init_bare(&server_path).unwrap();
+  let client = Repository::init(tmp_dir.path().join("client")).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |contents: &str, parents: Vec<OID>| {
+    let odb = client.odb();
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(
+      tree,
+      parents,
+      signature.clone(),
+      signature.clone(),
+      "commit\n",
+    );
+    odb.write_commit(&commit).unwrap()
+  };
+  let first = commit("first\n", vec![]);
+  let url = format!("git@example.com:{}", server_path.display());
+  let mut transport = SshTransport::new(&url).unwrap().with_command(&command);
+  let outcome = transport
+    .push(&client, &[PushUpdate::new("refs/heads/master", first)])
+    .unwrap();
+  assert_eq!(PushStatus::Ok, outcome.refs[0].status);
+  assert_eq!(
+    Some(first),
+    server.refs().resolve("refs/heads/master").unwrap()
+  );
+  let update = PushUpdate::new("refs/heads/master", first);
+  assert_eq!(
+    PushStatus::UpToDate,
+    transport.push(&client, &[update]).unwrap().refs[0].status
+  );
+  let second = commit("second\n", vec![first]);
+  transport
+    .push(&client, &[PushUpdate::new("refs/heads/master", second)])
+    .unwrap();
+  assert_eq!(
+    Some(second),
+    server.refs().resolve("refs/heads/master").unwrap()
+  );
+
+  for (i, &version) in [ProtocolVersion::V1, ProtocolVersion::V2]
+    .iter()
+    .enumerate()
+  {
+    let fetcher = Repository::init(tmp_dir.path().join(format!("fetcher{}", i))).unwrap();
+    let mut transport = SshTransport::new(format!("ssh://localhost{}", server_path.display()))
+      .unwrap()
+      .with_command(&command)
+      .with_protocol(version);
+    let advertisement = transport.list_refs().unwrap();
+    assert_eq!(version, advertisement.version);
+    assert_eq!(second, advertisement.get("refs/heads/master").unwrap().oid);
+    let outcome = transport.fetch(&fetcher, &[second]).unwrap();
+    assert_eq!(6, outcome.objects.len());
+    let commit = fetcher.odb().read_commit(&second).unwrap();
+    assert_eq!(&[first][..], commit.parents());
+  }
+
+  // What the remote says on its way out is the error
+  let mut missing = SshTransport::new(format!("host:{}/missing.git", tmp_dir.path().display()))
+    .unwrap()
+    .with_command(&command);
+  match missing.list_refs() {
+    Err(TransportError::Remote(message)) => {
+      assert!(message.contains_str("does not appear to be a git repository"))
+    }
+    other => panic!("{:?}", other),
+  }
+}
@@ -0,0 +1,280 @@
+use std::{
+  fmt,
+  io::{self, Read, Write},
+  sync::{Arc, Mutex},
+  thread,
+  time::{Duration, Instant},
+};
+
+/// The most bytes read or written at once by [`Throttled`], so a throttle
+/// gets asked often enough to keep the rate even
+const CHUNK_SIZE: usize = 16 << 10;
+
+/// Which way bytes are going, from the side doing the throttling
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+  /// Going out, like a pack being pushed or served
+  Sent,
+  /// Coming in, like a pack being fetched or received
+  Received,
+}
+
+/// Decides how fast a transfer may go. It's asked about every chunk of
+/// bytes sent or received and answers how long to wait, which for bytes
+/// received is after they were read so the wait holds back the next read
+/// and with it the sender.
+///
+/// Any `Fn(Direction, usize) -> Duration` is a throttle, so the policy can
+/// come from anywhere, like a token bucket kept in another service.
+/// [`RateLimit`] is a token bucket to use in the process.
+pub trait Throttle: Send + Sync {
+  /// How long to wait for `len` bytes going `direction`
+  fn delay(&self, direction: Direction, len: usize) -> Duration;
+}
+
+impl<F> Throttle for F
+where
+  F: Fn(Direction, usize) -> Duration + Send + Sync,
+{
+  fn delay(&self, direction: Direction, len: usize) -> Duration {
+    self(direction, len)
+  }
+}
+
+/// A token bucket capping the bytes per second going both ways. The bucket
+/// holds up to a second of bytes to start with, so short bursts go through
+/// at full speed, and transfers that take more than there is wait until
+/// enough has built up again.
+///
+/// Clones share the same bucket, so giving every connection of a tenant a
+/// clone caps all of them together. A limit made with [`RateLimit::child`]
+/// also counts against its parent for a cap on everything on top of the
+/// per tenant ones.
+#[derive(Clone)]
+pub struct RateLimit {
+  inner: Arc<Inner>,
+}
+
+struct Inner {
+  bucket: Mutex<Bucket>,
+  parent: Option<RateLimit>,
+}
+
+struct Bucket {
+  rate: u64,
+  burst: u64,
+  /// What's left, below zero when transfers are ahead of the rate
+  tokens: f64,
+  filled: Instant,
+}
+
+impl RateLimit {
+  /// Cap transfers at `bytes_per_second`
+  pub fn new(bytes_per_second: u64) -> Self {
+    Self::with_parent(bytes_per_second, None)
+  }
+
+  /// Create a limit of `bytes_per_second` whose transfers also count
+  /// against this one
+  pub fn child(&self, bytes_per_second: u64) -> Self {
+    Self::with_parent(bytes_per_second, Some(self.clone()))
+  }
+
+  fn with_parent(bytes_per_second: u64, parent: Option<RateLimit>) -> Self {
+    let rate = bytes_per_second.max(1);
+    Self {
+      inner: Arc::new(Inner {
+        bucket: Mutex::new(Bucket {
+          rate,
+          burst: rate,
+          tokens: rate as f64,
+          filled: Instant::now(),
+        }),
+        parent,
+      }),
+    }
+  }
+
+  /// Let bursts of up to `bytes` through at full speed instead of a
+  /// second's worth
+  pub fn with_burst(self, bytes: u64) -> Self {
+    {
+      let mut bucket = self.inner.bucket.lock().unwrap_or_else(|e| e.into_inner());
+      bucket.burst = bytes.max(1);
+      bucket.tokens = bucket.tokens.min(bucket.burst as f64);
+    }
+    self
+  }
+
+  /// Change the cap, which transfers already going pick up with their next
+  /// chunk
+  pub fn set_rate(&self, bytes_per_second: u64) {
+    let mut bucket = self.inner.bucket.lock().unwrap_or_else(|e| e.into_inner());
+    bucket.refill(Instant::now());
+    bucket.rate = bytes_per_second.max(1);
+  }
+
+  /// The cap in bytes per second
+  pub fn rate(&self) -> u64 {
+    self
+      .inner
+      .bucket
+      .lock()
+      .unwrap_or_else(|e| e.into_inner())
+      .rate
+  }
+
+  /// Take `len` bytes out of the bucket at `now`, returning how long until
+  /// the bucket is back at zero
+  fn take_at(&self, len: usize, now: Instant) -> Duration {
+    let own = {
+      let mut bucket = self.inner.bucket.lock().unwrap_or_else(|e| e.into_inner());
+      bucket.refill(now);
+      bucket.tokens -= len as f64;
+      if bucket.tokens < 0.0 {
+        Duration::from_secs_f64(-bucket.tokens / bucket.rate as f64)
+      } else {
+        Duration::ZERO
+      }
+    };
+    match &self.inner.parent {
+      Some(parent) => own.max(parent.take_at(len, now)),
+      None => own,
+    }
+  }
+}
+
+impl Bucket {
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.filled).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+    self.filled = self.filled.max(now);
+  }
+}
+
+impl Throttle for RateLimit {
+  fn delay(&self, _: Direction, len: usize) -> Duration {
+    self.take_at(len, Instant::now())
+  }
+}
+
+impl fmt::Debug for RateLimit {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let bucket = self.inner.bucket.lock().unwrap_or_else(|e| e.into_inner());
+    f.debug_struct("RateLimit")
+      .field("rate", &bucket.rate)
+      .field("burst", &bucket.burst)
+      .field("parent", &self.inner.parent)
+      .finish()
+  }
+}
+
+/// A reader or writer, like a socket or the pipe to a process, slowed down
+/// by a [`Throttle`]. Reads and writes are split into chunks and the
+/// throttle is asked how long to wait for each one.
+pub struct Throttled<'a, S> {
+  inner: S,
+  throttle: &'a dyn Throttle,
+}
+
+impl<'a, S> Throttled<'a, S> {
+  /// Throttle `inner` with `throttle`
+  pub fn new(inner: S, throttle: &'a dyn Throttle) -> Self {
+    Self { inner, throttle }
+  }
+
+  /// The reader or writer being throttled
+  pub fn into_inner(self) -> S {
+    self.inner
+  }
+}
+
+impl<S: fmt::Debug> fmt::Debug for Throttled<'_, S> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Throttled")
+      .field("inner", &self.inner)
+      .finish()
+  }
+}
+
+impl<S: Read> Read for Throttled<'_, S> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let len = buf.len().min(CHUNK_SIZE);
+    let read = self.inner.read(&mut buf[..len])?;
+    if read != 0 {
+      thread::sleep(self.throttle.delay(Direction::Received, read));
+    }
+    Ok(read)
+  }
+}
+
+impl<S: Write> Write for Throttled<'_, S> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let len = buf.len().min(CHUNK_SIZE);
+    if len != 0 {
+      thread::sleep(self.throttle.delay(Direction::Sent, len));
+    }
+    // Whatever doesn't get written now is asked about again with the next
+    // write
+    self.inner.write(&buf[..len])
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+#[test]
+fn rate_limit() {
+  let limit = RateLimit::new(1000).with_burst(500);
+  let global = RateLimit::new(1000);
+  let tenant = global.child(100);
+  // Made after the buckets so no time before their start is lost
+  let start = Instant::now();
+  let at = |millis| start + Duration::from_millis(millis);
+  // The burst goes through right away, then there's a wait for the rest
+  assert_eq!(Duration::ZERO, limit.take_at(500, at(0)));
+  assert_eq!(Duration::from_millis(250), limit.take_at(250, at(0)));
+  // Half a second later the debt is paid and 250 bytes built up
+  assert_eq!(Duration::ZERO, limit.take_at(250, at(500)));
+  // The bucket never holds more than the burst
+  assert_eq!(Duration::from_millis(100), limit.take_at(600, at(10_000)));
+
+  // Clones share a bucket and children count against their parent's too
+  let other = tenant.clone();
+  assert_eq!(Duration::ZERO, tenant.take_at(100, at(0)));
+  assert_eq!(Duration::from_millis(500), other.take_at(50, at(0)));
+  assert_eq!(Duration::from_millis(50), global.take_at(900, at(0)));
+  // A bit of time has passed by the time the rate changes
+  global.set_rate(50);
+  assert_eq!(50, global.rate());
+  let wait = tenant.take_at(0, at(0));
+  assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+}
+
+#[test]
+fn throttled() {
+  let seen = Mutex::new(Vec::new());
+  let throttle = |direction, len| {
+    seen.lock().unwrap().push((direction, len));
+    Duration::ZERO
+  };
+  let data = vec![7; CHUNK_SIZE + 10];
+  let mut writer = Throttled::new(Vec::new(), &throttle);
+  writer.write_all(&data).unwrap();
+  assert_eq!(data, writer.into_inner());
+  let mut read = vec![0; data.len()];
+  Throttled::new(&data[..], &throttle)
+    .read_exact(&mut read)
+    .unwrap();
+  assert_eq!(data, read);
+  assert_eq!(
+    vec![
+      (Direction::Sent, CHUNK_SIZE),
+      (Direction::Sent, 10),
+      (Direction::Received, CHUNK_SIZE),
+      (Direction::Received, 10),
+    ],
+    *seen.lock().unwrap()
+  );
+}
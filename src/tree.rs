@@ -0,0 +1,523 @@
+use crate::{object, small::SmallBytes, Blob, OIDError, Object, ObjectKind, OID};
+use bstr::{BStr, BString, ByteSlice};
+use std::{
+  cmp::Ordering,
+  fs,
+  io::{self, Write},
+  path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// A [`Tree`] is a git object that represents a directory in a git
+/// repository. It holds a sorted list of [`TreeEntry`] where each entry is
+/// the name, [`FileMode`], and [`OID`] of a [`Blob`] or of another [`Tree`]
+/// for subdirectories.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tree {
+  entries: Vec<TreeEntry>,
+}
+
+/// A single item in a [`Tree`]. Names are stored the same way as the
+/// contents of a [`Blob`], so cloning entries doesn't copy them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeEntry {
+  mode: FileMode,
+  name: SmallBytes,
+  oid: OID,
+}
+
+/// The kind of item a [`TreeEntry`] points at. Git only stores a handful of
+/// modes rather than the full set of permission bits of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileMode {
+  /// A subdirectory, stored as `40000`
+  Tree,
+  /// A regular file, stored as `100644`
+  NonExecutableFile,
+  /// A file with the executable bit set, stored as `100755`
+  ExecutableFile,
+  /// A symbolic link, stored as `120000`. The [`Blob`] holds the link target
+  SymbolicLink,
+  /// A commit in another repository, used for submodules, stored as `160000`
+  GitLink,
+}
+
+/// Options controlling how [`Tree::from_dir_with_options`] builds a [`Tree`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeOptions {
+  /// How many threads to hash files with. `None` uses the amount of
+  /// parallelism available on the machine. This is only used when the
+  /// `parallel` feature is enabled, otherwise files are always hashed on the
+  /// calling thread.
+  pub threads: Option<usize>,
+}
+
+impl FileMode {
+  /// The mode as it is written in a [`Tree`]
+  pub fn as_bytes(&self) -> &'static [u8] {
+    match self {
+      Self::Tree => b"40000",
+      Self::NonExecutableFile => b"100644",
+      Self::ExecutableFile => b"100755",
+      Self::SymbolicLink => b"120000",
+      Self::GitLink => b"160000",
+    }
+  }
+
+  /// Parse the mode of a [`TreeEntry`] as written in a [`Tree`]
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, TreeError> {
+    match bytes {
+      b"40000" => Ok(Self::Tree),
+      b"100644" => Ok(Self::NonExecutableFile),
+      b"100755" => Ok(Self::ExecutableFile),
+      b"120000" => Ok(Self::SymbolicLink),
+      b"160000" => Ok(Self::GitLink),
+      _ => Err(TreeError::InvalidMode(bytes.into())),
+    }
+  }
+
+  /// Whether this is the mode for a subdirectory
+  pub fn is_tree(&self) -> bool {
+    *self == Self::Tree
+  }
+
+  /// Whether the [`OID`] of an entry with this mode points at a [`Blob`]
+  pub fn is_blob(&self) -> bool {
+    matches!(
+      self,
+      Self::NonExecutableFile | Self::ExecutableFile | Self::SymbolicLink
+    )
+  }
+
+  #[cfg(unix)]
+  pub(crate) fn from_metadata(metadata: &fs::Metadata) -> Self {
+    use std::os::unix::fs::PermissionsExt;
+    if metadata.file_type().is_symlink() {
+      Self::SymbolicLink
+    } else if metadata.is_dir() {
+      Self::Tree
+    } else if metadata.permissions().mode() & 0o111 != 0 {
+      Self::ExecutableFile
+    } else {
+      Self::NonExecutableFile
+    }
+  }
+
+  #[cfg(not(unix))]
+  pub(crate) fn from_metadata(metadata: &fs::Metadata) -> Self {
+    if metadata.file_type().is_symlink() {
+      Self::SymbolicLink
+    } else if metadata.is_dir() {
+      Self::Tree
+    } else {
+      Self::NonExecutableFile
+    }
+  }
+}
+
+impl TreeEntry {
+  /// Create a new [`TreeEntry`]
+  pub fn new(mode: FileMode, name: impl Into<BString>, oid: OID) -> Self {
+    Self {
+      mode,
+      name: Vec::from(name.into()).into(),
+      oid,
+    }
+  }
+
+  /// The [`FileMode`] of the entry
+  pub fn mode(&self) -> FileMode {
+    self.mode
+  }
+
+  /// The file or directory name of the entry
+  pub fn name(&self) -> &BStr {
+    self.name.as_bstr()
+  }
+
+  /// The [`OID`] of the [`Blob`] or [`Tree`] the entry points at
+  pub fn oid(&self) -> &OID {
+    &self.oid
+  }
+}
+
+impl Tree {
+  /// Create a [`Tree`] from a set of entries. The entries are sorted into the
+  /// order git expects them to be stored in.
+  pub fn new(mut entries: Vec<TreeEntry>) -> Self {
+    entries.sort_by(entry_order);
+    Self { entries }
+  }
+
+  /// Parse the contents of a [`Tree`] as stored in the Object Database,
+  /// without the `tree {content_len}\0` prefix
+  pub fn parse(bytes: impl AsRef<[u8]>) -> Result<Self, TreeError> {
+    let mut bytes = bytes.as_ref();
+    let mut entries = Vec::new();
+    while !bytes.is_empty() {
+      let space = bytes.find_byte(b' ').ok_or(TreeError::Malformed)?;
+      let mode = FileMode::from_bytes(&bytes[..space])?;
+      let nul = space + bytes[space..].find_byte(0).ok_or(TreeError::Malformed)?;
+      let name = &bytes[space + 1..nul];
+      let oid = bytes.get(nul + 1..nul + 21).ok_or(TreeError::Malformed)?;
+      entries.push(TreeEntry::new(mode, name, OID::from_bytes(oid)?));
+      bytes = &bytes[nul + 21..];
+    }
+    Ok(Self { entries })
+  }
+
+  /// Turn the [`Tree`] into the on disk representation stored in Object
+  /// Database, which is in the form below where:
+  /// - {content_len} is the length of all of the entries as a string
+  /// - {mode} is the [`FileMode`] of an entry as an ASCII string
+  /// - {name} is the name of an entry
+  /// - {oid} is the raw 20 bytes of the [`OID`] of an entry, not hex
+  ///
+  /// ```text
+  /// tree {content_len}\0{mode} {name}\0{oid}{mode} {name}\0{oid}...
+  /// ```
+  pub fn as_bytes(&self) -> Vec<u8> {
+    object::encode(self)
+  }
+
+  /// Get the [`OID`] for the [`Tree`]
+  pub fn id(&self) -> OID {
+    OID::for_object(self)
+  }
+
+  /// The entries of the [`Tree`] in sorted order
+  pub fn entries(&self) -> &[TreeEntry] {
+    &self.entries
+  }
+
+  /// Find the entry with the given name
+  pub fn get(&self, name: impl AsRef<[u8]>) -> Option<&TreeEntry> {
+    let name = name.as_ref();
+    self.entries.iter().find(|entry| *entry.name == *name)
+  }
+
+  /// Whether the [`Tree`] has no entries. This is only valid for the root
+  /// [`Tree`] of an empty repository as git never stores empty directories.
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// Create a [`Tree`] from a directory on disk, hashing every file in it as
+  /// a [`Blob`] and every subdirectory as a [`Tree`]. The `.git` directory is
+  /// skipped as are empty directories, since git can't store them. A
+  /// subdirectory that's a repository of its own is a [`FileMode::GitLink`]
+  /// to the commit it has checked out, and is skipped if there isn't one.
+  pub fn from_dir(path: impl AsRef<Path>) -> Result<Self, TreeError> {
+    Self::from_dir_with_options(path, &TreeOptions::default())
+  }
+
+  /// The same as [`Tree::from_dir`] but with [`TreeOptions`] to control how
+  /// the work is done. Only the hashing of files is spread across threads,
+  /// the [`Tree`] is always assembled in the same order so the result does
+  /// not depend on the options used.
+  pub fn from_dir_with_options(
+    path: impl AsRef<Path>,
+    options: &TreeOptions,
+  ) -> Result<Self, TreeError> {
+    let mut sources = Vec::new();
+    let dir = scan(path.as_ref(), &mut sources)?;
+    let oids = hash_sources(&sources, options)?;
+    Ok(assemble(dir, &oids))
+  }
+}
+
+/// Git sorts entries by name but compares directories as if their name had a
+/// trailing `/`
+pub(crate) fn entry_order(a: &TreeEntry, b: &TreeEntry) -> Ordering {
+  let common = a.name.len().min(b.name.len());
+  a.name[..common].cmp(&b.name[..common]).then_with(|| {
+    let next = |entry: &TreeEntry| {
+      entry
+        .name
+        .get(common)
+        .copied()
+        .or_else(|| entry.mode.is_tree().then_some(b'/'))
+    };
+    next(a).cmp(&next(b))
+  })
+}
+
+/// A directory found while scanning the file system, waiting on the [`OID`]s
+/// of its files to be turned into a [`Tree`]
+struct Dir {
+  entries: Vec<(BString, Node)>,
+}
+
+enum Node {
+  /// A file or symbolic link and the index of its [`Source`]
+  Blob(FileMode, usize),
+  Tree(Dir),
+  /// A nested repository and the commit it has checked out
+  Link(OID),
+}
+
+/// Something on disk that needs to be hashed as a [`Blob`]
+struct Source {
+  path: PathBuf,
+  symlink: bool,
+}
+
+fn scan(path: &Path, sources: &mut Vec<Source>) -> Result<Dir, TreeError> {
+  let mut entries = Vec::new();
+  for dir_entry in fs::read_dir(path)? {
+    let dir_entry = dir_entry?;
+    let file_name = dir_entry.file_name();
+    if file_name == ".git" {
+      continue;
+    }
+    let path = dir_entry.path();
+    let name = <[u8]>::from_os_str(&file_name)
+      .ok_or_else(|| TreeError::InvalidFileName(path.clone()))?
+      .into();
+    let metadata = fs::symlink_metadata(&path)?;
+    let node = match FileMode::from_metadata(&metadata) {
+      FileMode::Tree if path.join(".git").exists() => {
+        match crate::submodule::checked_out_commit(&path) {
+          Some(oid) => Node::Link(oid),
+          None => continue,
+        }
+      }
+      FileMode::Tree => Node::Tree(scan(&path, sources)?),
+      mode => {
+        sources.push(Source {
+          path,
+          symlink: mode == FileMode::SymbolicLink,
+        });
+        Node::Blob(mode, sources.len() - 1)
+      }
+    };
+    entries.push((name, node));
+  }
+  Ok(Dir { entries })
+}
+
+fn hash_source(source: &Source) -> Result<OID, TreeError> {
+  let blob = if source.symlink {
+    let target = fs::read_link(&source.path)?;
+    let target =
+      <[u8]>::from_path(&target).ok_or_else(|| TreeError::InvalidFileName(target.clone()))?;
+    Blob::new(target)
+  } else {
+    Blob::from_file(&source.path)?
+  };
+  Ok(blob.id())
+}
+
+#[cfg(not(feature = "parallel"))]
+fn hash_sources(sources: &[Source], _: &TreeOptions) -> Result<Vec<OID>, TreeError> {
+  sources.iter().map(hash_source).collect()
+}
+
+#[cfg(feature = "parallel")]
+fn hash_sources(sources: &[Source], options: &TreeOptions) -> Result<Vec<OID>, TreeError> {
+  use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+  };
+
+  let threads = options
+    .threads
+    .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+    .unwrap_or(1)
+    .min(sources.len());
+  if threads <= 1 {
+    return sources.iter().map(hash_source).collect();
+  }
+
+  // Threads take the next file off of the list as they finish so that a few
+  // large files don't leave the other threads idle
+  let next = AtomicUsize::new(0);
+  let mut oids = vec![None; sources.len()];
+  thread::scope(|scope| {
+    let workers: Vec<_> = (0..threads)
+      .map(|_| {
+        scope.spawn(|| {
+          let mut hashed = Vec::new();
+          loop {
+            let idx = next.fetch_add(1, Ordering::Relaxed);
+            match sources.get(idx) {
+              Some(source) => hashed.push((idx, hash_source(source)?)),
+              None => return Ok(hashed),
+            }
+          }
+        })
+      })
+      .collect();
+    for worker in workers {
+      let hashed: Result<Vec<(usize, OID)>, TreeError> = worker
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+      for (idx, oid) in hashed? {
+        oids[idx] = Some(oid);
+      }
+    }
+    Ok::<_, TreeError>(())
+  })?;
+  // Every index was handed out to a worker and all of them succeeded
+  Ok(oids.into_iter().map(Option::unwrap).collect())
+}
+
+fn assemble(dir: Dir, oids: &[OID]) -> Tree {
+  let entries = dir
+    .entries
+    .into_iter()
+    .filter_map(|(name, node)| match node {
+      Node::Blob(mode, idx) => Some(TreeEntry::new(mode, name, oids[idx])),
+      Node::Tree(dir) => {
+        let tree = assemble(dir, oids);
+        (!tree.is_empty()).then(|| TreeEntry::new(FileMode::Tree, name, tree.id()))
+      }
+      Node::Link(oid) => Some(TreeEntry::new(FileMode::GitLink, name, oid)),
+    })
+    .collect();
+  Tree::new(entries)
+}
+
+impl Object for Tree {
+  fn kind(&self) -> ObjectKind {
+    ObjectKind::Tree
+  }
+
+  fn serialize_into(&self, writer: &mut impl Write) -> io::Result<()> {
+    for entry in &self.entries {
+      writer.write_all(entry.mode.as_bytes())?;
+      writer.write_all(b" ")?;
+      writer.write_all(&entry.name)?;
+      writer.write_all(b"\0")?;
+      writer.write_all(entry.oid.as_bytes())?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to operations done with the [`Tree`] type
+pub enum TreeError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("tree entry had an invalid mode {0:?}")]
+  InvalidMode(BString),
+  #[error("tree entry was cut off or missing its name")]
+  Malformed,
+  #[error("invalid OID in tree entry: {0}")]
+  InvalidOID(#[from] OIDError),
+  #[error("the file name of {0:?} can't be stored in git")]
+  InvalidFileName(PathBuf),
+}
+
+#[cfg(test)]
+fn test_dir() -> tempdir::TempDir {
+  let tmp_dir = tempdir::TempDir::new("tree_test").unwrap();
+  let root = tmp_dir.path();
+  fs::write(root.join("a.txt"), "this is a test").unwrap();
+  fs::create_dir_all(root.join("sub/deeper")).unwrap();
+  fs::create_dir_all(root.join("empty")).unwrap();
+  fs::create_dir_all(root.join(".git")).unwrap();
+  fs::write(root.join(".git/HEAD"), "ref: refs/heads/main\n").unwrap();
+  fs::write(root.join("sub/b.txt"), "hello").unwrap();
+  fs::write(root.join("sub/deeper/c"), "x").unwrap();
+  tmp_dir
+}
+
+#[test]
+fn empty() {
+  assert_eq!(
+    OID::from_hex("4b825dc642cb6eb9a060e54bf8d69288fbee4904").unwrap(),
+    Tree::default().id()
+  );
+}
+
+#[test]
+fn sorting() {
+  let oid = Blob::new("").id();
+  let tree = Tree::new(vec![
+    TreeEntry::new(FileMode::NonExecutableFile, "foo.txt", oid),
+    TreeEntry::new(FileMode::Tree, "foo", oid),
+    TreeEntry::new(FileMode::NonExecutableFile, "foo-bar", oid),
+    TreeEntry::new(FileMode::NonExecutableFile, "bar", oid),
+  ]);
+  let names: Vec<_> = tree
+    .entries()
+    .iter()
+    .map(|e| e.name().to_string())
+    .collect();
+  assert_eq!(vec!["bar", "foo-bar", "foo.txt", "foo"], names);
+}
+
+#[test]
+fn parse() {
+  let oid = Blob::new("this is a test").id();
+  let tree = Tree::new(vec![
+    TreeEntry::new(FileMode::NonExecutableFile, "a.txt", oid),
+    TreeEntry::new(FileMode::ExecutableFile, b"\xffbin".as_bstr(), oid),
+  ]);
+  let bytes = tree.as_bytes();
+  let content = &bytes[bytes.find_byte(0).unwrap() + 1..];
+  assert_eq!(tree, Tree::parse(content).unwrap());
+  assert!(matches!(
+    Tree::parse(&content[..content.len() - 1]),
+    Err(TreeError::Malformed)
+  ));
+}
+
+#[test]
+fn from_dir() {
+  let tmp_dir = test_dir();
+  let tree = Tree::from_dir(tmp_dir.path()).unwrap();
+  assert_eq!(2, tree.entries().len());
+  assert!(tree.get("empty").is_none());
+  assert!(tree.get(".git").is_none());
+  let sub = tree.get("sub").unwrap();
+  assert_eq!(FileMode::Tree, sub.mode());
+  assert_eq!(
+    OID::from_hex("6a0b829158e8281d9d9e45991712d7e9afd9e398").unwrap(),
+    *sub.oid()
+  );
+}
+
+#[cfg(unix)]
+#[test]
+fn from_dir_modes() {
+  use std::os::unix::{ffi::OsStrExt, fs::PermissionsExt};
+  let tmp_dir = test_dir();
+  let root = tmp_dir.path();
+  fs::write(root.join("run.sh"), "#!/bin/sh\n").unwrap();
+  fs::set_permissions(root.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+  std::os::unix::fs::symlink("a.txt", root.join("link")).unwrap();
+  let tree = Tree::from_dir(root).unwrap();
+  assert_eq!(FileMode::SymbolicLink, tree.get("link").unwrap().mode());
+  assert_eq!(FileMode::ExecutableFile, tree.get("run.sh").unwrap().mode());
+  assert_eq!(
+    OID::from_hex("eda2ef3c1798b326d3075d8c9b17ac3619fb9394").unwrap(),
+    tree.id()
+  );
+
+  // Names are bytes like they are to git, whether or not they're UTF-8
+  let name = std::ffi::OsStr::from_bytes(b"\xff.txt");
+  fs::write(root.join("sub").join(name), "latin1").unwrap();
+  let tree = Tree::from_dir(root).unwrap();
+  let sub = Tree::from_dir(root.join("sub")).unwrap();
+  assert_eq!(tree.get("sub").unwrap().oid(), &sub.id());
+  let entry = sub.get(b"\xff.txt").unwrap();
+  assert_eq!(FileMode::NonExecutableFile, entry.mode());
+}
+
+#[test]
+fn from_dir_with_options() {
+  let tmp_dir = test_dir();
+  for threads in 1..4 {
+    let tree = Tree::from_dir_with_options(
+      tmp_dir.path(),
+      &TreeOptions {
+        threads: Some(threads),
+      },
+    )
+    .unwrap();
+    assert_eq!(Tree::from_dir(tmp_dir.path()).unwrap(), tree);
+  }
+}
@@ -0,0 +1,983 @@
+//! The server side of fetching, what `git upload-pack` does. A new
+//! [`UploadPack`] is made for every connection so what it advertises can
+//! depend on who is connecting.
+
+use crate::{
+  peel_tag,
+  transport::{write_pack_for, Throttle, Throttled, TransportError},
+  CacheKey, Config, ConfigError, OdbError, PackSpool, Packet, PktLineError, PktLineReader,
+  RefError, RefTarget, Repository, SpooledPack, OID,
+};
+use bstr::{BStr, BString, ByteSlice};
+use std::{fmt, io, io::Write, ops::Range};
+use thiserror::Error;
+
+/// A ref as it's shown to a client
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AdvertisedRef {
+  /// The full name of the ref, or `HEAD`
+  pub name: BString,
+  /// The object the ref points at
+  pub oid: OID,
+  /// What an annotated tag points at once every tag is peeled, sent as a
+  /// `{name}^{}` line after it
+  pub peeled: Option<OID>,
+  /// The ref a symbolic ref points at, sent as a `symref` capability
+  pub symref_target: Option<BString>,
+}
+
+/// Which refs are kept from clients, with the same rules as git's
+/// `transfer.hideRefs`. A pattern hides the ref of that name and every ref
+/// under it, so `refs/pull` hides `refs/pull/1/head`, and a pattern
+/// starting with `!` shows refs again that an earlier one hid. The last
+/// pattern matching a ref decides. A leading `^` is allowed and ignored
+/// since refs are always matched by their full name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HiddenRefs {
+  patterns: Vec<BString>,
+}
+
+impl HiddenRefs {
+  /// Hide the refs matching `patterns`
+  pub fn new<P: Into<BString>>(patterns: impl IntoIterator<Item = P>) -> Self {
+    Self {
+      patterns: patterns.into_iter().map(Into::into).collect(),
+    }
+  }
+
+  /// The patterns in `transfer.hideRefs` followed by the ones in
+  /// `{section}.hideRefs`, where the section is `uploadpack` or
+  /// `receivepack`
+  pub fn from_config(config: &Config, section: &str) -> Self {
+    let mut patterns: Vec<BString> = config
+      .get_all("transfer.hiderefs")
+      .into_iter()
+      .map(BStr::to_owned)
+      .collect();
+    patterns.extend(
+      config
+        .get_all(&format!("{}.hiderefs", section))
+        .into_iter()
+        .map(BStr::to_owned),
+    );
+    Self { patterns }
+  }
+
+  /// Add a pattern after the others, so it decides over them
+  pub fn push(&mut self, pattern: impl Into<BString>) {
+    self.patterns.push(pattern.into());
+  }
+
+  /// Whether the ref `name` is hidden
+  pub fn is_hidden(&self, name: impl AsRef<[u8]>) -> bool {
+    let name = name.as_ref();
+    for pattern in self.patterns.iter().rev() {
+      let (negated, pattern) = match pattern.strip_prefix(b"!") {
+        Some(pattern) => (true, pattern),
+        None => (false, &pattern[..]),
+      };
+      let pattern = pattern.strip_prefix(b"^").unwrap_or(pattern);
+      let pattern = pattern.strip_suffix(b"/").unwrap_or(pattern);
+      let matches = name
+        .strip_prefix(pattern)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(b"/"));
+      if matches {
+        return !negated;
+      }
+    }
+    false
+  }
+}
+
+type RefFilter<'a> = Box<dyn FnMut(&mut Vec<AdvertisedRef>) + 'a>;
+
+/// What [`UploadPack::serve`] can do for a fetch, advertised before the
+/// agent. Progress is never sent, so `no-progress` is all the same.
+const FETCH_CAPABILITIES: [&str; 5] = [
+  "thin-pack",
+  "side-band",
+  "side-band-64k",
+  "ofs-delta",
+  "no-progress",
+];
+
+/// The server side of a fetch for one connection
+pub struct UploadPack<'a> {
+  repo: &'a Repository,
+  hidden: HiddenRefs,
+  filters: Vec<RefFilter<'a>>,
+  capabilities: Vec<String>,
+  throttle: Option<Box<dyn Throttle + 'a>>,
+}
+
+impl fmt::Debug for UploadPack<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("UploadPack")
+      .field("repo", &self.repo)
+      .field("hidden", &self.hidden)
+      .field("filters", &self.filters.len())
+      .field("capabilities", &self.capabilities)
+      .field("throttled", &self.throttle.is_some())
+      .finish()
+  }
+}
+
+impl<'a> UploadPack<'a> {
+  /// Serve `repo`, hiding the refs its `transfer.hideRefs` and
+  /// `uploadpack.hideRefs` config says to
+  pub fn new(repo: &'a Repository) -> Self {
+    Self {
+      repo,
+      hidden: HiddenRefs::from_config(repo.config(), "uploadpack"),
+      filters: Vec::new(),
+      capabilities: FETCH_CAPABILITIES
+        .iter()
+        .map(|capability| capability.to_string())
+        .chain([format!("agent=libgit-rs/{}", env!("CARGO_PKG_VERSION"))])
+        .collect(),
+      throttle: None,
+    }
+  }
+
+  /// Slow everything sent to this connection down with `throttle`, for
+  /// example a clone of the [`RateLimit`][crate::transport::RateLimit] of
+  /// whoever is connecting
+  pub fn throttle(&mut self, throttle: impl Throttle + 'a) -> &mut Self {
+    self.throttle = Some(Box::new(throttle));
+    self
+  }
+
+  /// Write `bytes` to `out` going through the throttle if there is one
+  fn send(&self, out: &mut impl io::Write, bytes: &[u8]) -> io::Result<()> {
+    match &self.throttle {
+      Some(throttle) => Throttled::new(out, &**throttle).write_all(bytes),
+      None => out.write_all(bytes),
+    }
+  }
+
+  /// The refs hidden from this connection, which more patterns can be
+  /// added to, for example the private refs of other users
+  pub fn hidden_refs(&mut self) -> &mut HiddenRefs {
+    &mut self.hidden
+  }
+
+  /// Change the refs advertised to this connection after hidden ones are
+  /// left out. `filter` can remove refs, add ones that aren't in the
+  /// repository, or point refs somewhere else. Filters run in the order
+  /// they were added.
+  pub fn filter_refs(&mut self, filter: impl FnMut(&mut Vec<AdvertisedRef>) + 'a) -> &mut Self {
+    self.filters.push(Box::new(filter));
+    self
+  }
+
+  /// The refs this connection sees, `HEAD` first if it points at anything
+  /// and the rest sorted by name
+  pub fn advertised_refs(&mut self) -> Result<Vec<AdvertisedRef>, UploadPackError> {
+    let refs = self.repo.refs();
+    let mut advertised = Vec::new();
+    let head = refs.read("HEAD")?.into_iter();
+    for reference in head.chain(refs.list("refs/")?) {
+      if self.hidden.is_hidden(reference.name()) {
+        continue;
+      }
+      let (oid, symref_target) = match reference.target() {
+        RefTarget::Direct(oid) => (*oid, None),
+        RefTarget::Symbolic(target) => match refs.resolve(target)? {
+          // A symbolic ref to a hidden ref would tell where it points
+          Some(oid) if !self.hidden.is_hidden(target) => (oid, Some(target.clone())),
+          _ => continue,
+        },
+      };
+      let peeled = match peel_tag(self.repo.odb(), &oid) {
+        Ok((peeled, _)) => Some(peeled).filter(|peeled| *peeled != oid),
+        // Refs to missing objects are advertised anyway like git does
+        Err(OdbError::NotFound(_)) => None,
+        Err(e) => return Err(e.into()),
+      };
+      advertised.push(AdvertisedRef {
+        name: reference.name().to_owned(),
+        oid,
+        peeled,
+        symref_target,
+      });
+    }
+    for filter in &mut self.filters {
+      filter(&mut advertised);
+    }
+    Ok(advertised)
+  }
+
+  /// Write the protocol v0 ref advertisement for this connection, one
+  /// pkt-line per ref and its peeled tag ending in a flush, with the
+  /// capabilities after a NUL on the first line. Without any refs a
+  /// `capabilities^{}` line carries them instead.
+  pub fn write_advertisement(&mut self, out: &mut impl io::Write) -> Result<(), UploadPackError> {
+    let refs = self.advertised_refs()?;
+    let mut capabilities = self.capabilities.clone();
+    for reference in &refs {
+      if let Some(target) = &reference.symref_target {
+        capabilities.push(format!("symref={}:{}", reference.name, target));
+      }
+    }
+    let mut lines = Vec::new();
+    for reference in &refs {
+      lines.push(format!("{} {}", reference.oid, reference.name).into_bytes());
+      if let Some(peeled) = reference.peeled {
+        lines.push(format!("{} {}^{{}}", peeled, reference.name).into_bytes());
+      }
+    }
+    if lines.is_empty() {
+      lines.push(format!("{} capabilities^{{}}", "0".repeat(40)).into_bytes());
+    }
+    lines[0].push(0);
+    lines[0].extend_from_slice(capabilities.join(" ").as_bytes());
+
+    let mut bytes = Vec::new();
+    for mut line in lines {
+      line.push(b'\n');
+      Packet::Data(line.as_bstr()).encode(&mut bytes)?;
+    }
+    Packet::Flush.encode(&mut bytes)?;
+    self.send(out, &bytes)?;
+    Ok(())
+  }
+
+  /// Write the advertisement the way smart HTTP sends it in response to
+  /// `GET info/refs?service=git-upload-pack`, with a `# service` line and
+  /// a flush in front of it
+  pub fn write_http_advertisement(
+    &mut self,
+    out: &mut impl io::Write,
+  ) -> Result<(), UploadPackError> {
+    let mut bytes = Vec::new();
+    Packet::Data(b"# service=git-upload-pack\n".as_bstr()).encode(&mut bytes)?;
+    Packet::Flush.encode(&mut bytes)?;
+    self.send(out, &bytes)?;
+    self.write_advertisement(out)
+  }
+
+  /// Serve a whole fetch over a connection that stays open, like `git
+  /// upload-pack` does over SSH: the advertisement goes out, then the
+  /// client says what it wants, haves are acknowledged until it's `done`,
+  /// and the pack follows on the side-band if the client asked for one. A
+  /// client that only wanted to see the refs hangs up after the
+  /// advertisement, which isn't an error.
+  ///
+  /// The first have in common is acknowledged and no others, like git
+  /// without `multi_ack`, and everything reachable from the haves in
+  /// common is left out of the pack.
+  pub fn serve(
+    &mut self,
+    input: impl io::Read,
+    mut output: impl io::Write,
+  ) -> Result<(), UploadPackError> {
+    self.write_advertisement(&mut output)?;
+    self.serve_request(input, output, false)
+  }
+
+  /// Serve one request of a stateless connection, like the body of a
+  /// `POST` to `git-upload-pack` over smart HTTP whose advertisement came
+  /// from [`UploadPack::write_http_advertisement`] before. A request
+  /// without `done` is only answered with whether a have was in common,
+  /// and the client sends another one with the wants again and more haves.
+  pub fn serve_stateless(
+    &mut self,
+    input: impl io::Read,
+    output: impl io::Write,
+  ) -> Result<(), UploadPackError> {
+    self.serve_request(input, output, true)
+  }
+
+  fn serve_request(
+    &mut self,
+    input: impl io::Read,
+    mut output: impl io::Write,
+    stateless: bool,
+  ) -> Result<(), UploadPackError> {
+    let mut reader = PktLineReader::new(input);
+    let mut wants = Vec::new();
+    let mut capabilities: Vec<String> = Vec::new();
+    loop {
+      let line = match reader.read_packet()? {
+        // Nothing wanted, the refs were all that was needed
+        None | Some(Packet::Flush) if wants.is_empty() => return Ok(()),
+        Some(Packet::Flush) => break,
+        Some(Packet::Data(line)) => line,
+        other => return Err(unexpected("a want", other)),
+      };
+      let line = line.strip_suffix(b"\n").unwrap_or(line);
+      let want = line
+        .strip_prefix(b"want ")
+        .ok_or_else(|| unexpected("a want", Some(Packet::Data(line.as_bstr()))))?;
+      let mut words = want.split_str(" ");
+      let hex = words.next().unwrap_or_default();
+      wants.push(parse_oid(hex)?);
+      // Capabilities come only on the first want
+      if capabilities.is_empty() {
+        capabilities = words.map(|word| word.to_str_lossy().into_owned()).collect();
+      }
+    }
+    self.check_wants(&wants)?;
+
+    let mut common = Vec::new();
+    let mut acks = Vec::new();
+    loop {
+      match reader.read_packet()? {
+        Some(Packet::Data(line)) if line.strip_suffix(b"\n").unwrap_or(line) == b"done" => break,
+        Some(Packet::Data(line)) => {
+          let line = line.strip_suffix(b"\n").unwrap_or(line);
+          let have = line
+            .strip_prefix(b"have ")
+            .ok_or_else(|| unexpected("a have", Some(Packet::Data(line.as_bstr()))))?;
+          let have = parse_oid(have)?;
+          match self.repo.odb().read(&have) {
+            Ok(_) => {
+              if common.is_empty() {
+                Packet::Data(format!("ACK {}\n", have).as_bytes().as_bstr()).encode(&mut acks)?;
+              }
+              common.push(have);
+            }
+            Err(OdbError::NotFound(_)) => {}
+            Err(e) => return Err(e.into()),
+          }
+        }
+        Some(Packet::Flush) => {
+          if common.is_empty() {
+            Packet::Data(b"NAK\n".as_bstr()).encode(&mut acks)?;
+          }
+          self.send(&mut output, &acks)?;
+          acks.clear();
+          // The client makes another request with more haves
+          if stateless {
+            return Ok(output.flush()?);
+          }
+        }
+        // A client that gave up on the fetch
+        None => return Ok(()),
+        other => return Err(unexpected("a have", other)),
+      }
+    }
+    if common.is_empty() {
+      Packet::Data(b"NAK\n".as_bstr()).encode(&mut acks)?;
+    }
+    self.send(&mut output, &acks)?;
+
+    let has = |capability: &str| capabilities.iter().any(|c| c == capability);
+    let thin = has("thin-pack");
+    // Packets on a side-band have the band in front of the data
+    let band_len = match (has("side-band-64k"), has("side-band")) {
+      (true, _) => Some(Packet::MAX_LEN - 5),
+      (false, true) => Some(1000 - 5),
+      (false, false) => None,
+    };
+    let mut throttled;
+    let out: &mut dyn io::Write = match &self.throttle {
+      Some(throttle) => {
+        throttled = Throttled::new(&mut output, &**throttle);
+        &mut throttled
+      }
+      None => &mut output,
+    };
+    match band_len {
+      Some(len) => {
+        let band = io::BufWriter::with_capacity(
+          len,
+          SideBand {
+            out: &mut *out,
+            len,
+          },
+        );
+        write_pack_for(self.repo, &wants, &common, thin, band)?
+          .0
+          .flush()?;
+        let mut flush = Vec::new();
+        Packet::Flush.encode(&mut flush)?;
+        out.write_all(&flush)?;
+      }
+      None => {
+        write_pack_for(self.repo, &wants, &common, thin, &mut *out)?;
+      }
+    }
+    Ok(out.flush()?)
+  }
+
+  /// Check that every want was advertised to this connection
+  fn check_wants(&mut self, wants: &[OID]) -> Result<(), UploadPackError> {
+    let advertised = self.advertised_refs()?;
+    for want in wants {
+      let found = advertised
+        .iter()
+        .any(|r| r.oid == *want || r.peeled == Some(*want));
+      if !found {
+        return Err(UploadPackError::NotAdvertised(*want));
+      }
+    }
+    Ok(())
+  }
+
+  /// The pack of everything reachable from `wants` that isn't from
+  /// `haves`, made the first time it's asked for and kept in `spool` for
+  /// the next. Only what this connection was advertised can be wanted.
+  ///
+  /// What's in the pack is decided by the objects alone, so the same pack
+  /// is found again by a client resuming, by other clients cloning the
+  /// same refs, and even for forks sharing objects with the same spool.
+  /// Haves this repository doesn't have are ignored like the ones a
+  /// negotiation finds aren't in common. A thin pack can only be read by
+  /// clients that have the haves, a pack to be sent as a `packfile-uris`
+  /// line shouldn't be one.
+  pub fn spool_pack(
+    &mut self,
+    spool: &PackSpool,
+    wants: &[OID],
+    haves: &[OID],
+    thin: bool,
+  ) -> Result<SpooledPack, UploadPackError> {
+    self.check_wants(wants)?;
+    let mut common = Vec::new();
+    for have in haves {
+      if self.repo.odb().contains(have)? {
+        common.push(*have);
+      }
+    }
+    let mut haves = common;
+    let mut wants = wants.to_vec();
+    for oids in [&mut wants, &mut haves] {
+      oids.sort();
+      oids.dedup();
+    }
+    let key = wants
+      .iter()
+      .fold(CacheKey::new("upload-pack", 1), |key, oid| key.object(oid))
+      .param(if thin { "thin haves" } else { "haves" });
+    let key = haves.iter().fold(key, |key, oid| key.object(oid));
+    spool.get_or_write(&key.id(), |file| {
+      write_pack_for(self.repo, &wants, &haves, thin, io::BufWriter::new(file))?
+        .0
+        .flush()?;
+      Ok::<_, UploadPackError>(())
+    })
+  }
+
+  /// Send the bytes in `range` of a spooled pack, going through the
+  /// throttle if there is one, and return how many were sent. The range
+  /// from [`parse_range`][crate::parse_range] lets a client pick up where
+  /// a broken off download stopped.
+  pub fn send_spooled(
+    &self,
+    pack: &SpooledPack,
+    range: Range<u64>,
+    out: &mut impl io::Write,
+  ) -> Result<u64, UploadPackError> {
+    let sent = match &self.throttle {
+      Some(throttle) => pack.write_range(range, &mut Throttled::new(out, &**throttle))?,
+      None => pack.write_range(range, out)?,
+    };
+    Ok(sent)
+  }
+
+  /// Write the `packfile-uris` section of a protocol v2 fetch response,
+  /// ending in the delimiter before the `packfile` section. The client
+  /// downloads each pack from its URI, like the file of a spooled pack
+  /// put on static file servers, and only what isn't in them comes in the
+  /// response.
+  pub fn write_packfile_uris(
+    &self,
+    out: &mut impl io::Write,
+    packs: &[(&SpooledPack, &str)],
+  ) -> Result<(), UploadPackError> {
+    let mut bytes = Vec::new();
+    Packet::Data(b"packfile-uris\n".as_bstr()).encode(&mut bytes)?;
+    for (pack, uri) in packs {
+      Packet::Data(pack.packfile_uri(uri).as_bytes().as_bstr()).encode(&mut bytes)?;
+    }
+    Packet::Delim.encode(&mut bytes)?;
+    self.send(out, &bytes)?;
+    Ok(())
+  }
+}
+
+/// Sends the bytes written to it as data packets on band 1 of a side-band,
+/// up to `len` bytes each
+pub(crate) struct SideBand<W> {
+  pub(crate) out: W,
+  pub(crate) len: usize,
+}
+
+impl<W: io::Write> io::Write for SideBand<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let len = buf.len().min(self.len);
+    let mut bytes = Vec::with_capacity(len + 5);
+    let packet = [&[1], &buf[..len]].concat();
+    Packet::Data(packet.as_bstr())
+      .encode(&mut bytes)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    self.out.write_all(&bytes)?;
+    Ok(len)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.out.flush()
+  }
+}
+
+fn parse_oid(hex: &[u8]) -> Result<OID, UploadPackError> {
+  hex
+    .to_str()
+    .ok()
+    .and_then(|hex| OID::from_hex(hex).ok())
+    .ok_or_else(|| UploadPackError::Protocol(format!("invalid object id {:?}", hex.as_bstr())))
+}
+
+fn unexpected(expected: &str, found: Option<Packet<'_>>) -> UploadPackError {
+  let found = match found {
+    Some(Packet::Data(line)) => format!("{:?}", line),
+    Some(packet) => format!("{:?}", packet),
+    None => "the end of the request".into(),
+  };
+  UploadPackError::Protocol(format!("expected {}, found {}", expected, found))
+}
+
+#[derive(Error, Debug)]
+/// Errors related to serving fetches with [`UploadPack`]
+pub enum UploadPackError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  PktLine(#[from] PktLineError),
+  #[error("{0}")]
+  Transport(#[from] TransportError),
+  #[error("{0} was not advertised")]
+  NotAdvertised(OID),
+  #[error("protocol error: {0}")]
+  Protocol(String),
+}
+
+#[test]
+fn hidden_refs() {
+  let hidden = HiddenRefs::new([
+    "refs/pull",
+    "refs/internal/",
+    "!refs/internal/public",
+    "^HEAD",
+  ]);
+  assert!(hidden.is_hidden("refs/pull/1/head"));
+  assert!(hidden.is_hidden("refs/pull"));
+  assert!(!hidden.is_hidden("refs/pullrequests"));
+  assert!(hidden.is_hidden("refs/internal/secret"));
+  assert!(!hidden.is_hidden("refs/internal/public"));
+  assert!(hidden.is_hidden("refs/internal/public-not"));
+  assert!(hidden.is_hidden("HEAD"));
+  assert!(!hidden.is_hidden("refs/heads/master"));
+}
+
+#[test]
+fn advertisement() {
+  use crate::{Commit, Signature, Time, Tree};
+  let tmp_dir = tempdir::TempDir::new("upload_pack_test").unwrap();
+  let config = tmp_dir.path().join(".git/config");
+  let repo = Repository::init(tmp_dir.path()).unwrap();
+  let mut advertised = Vec::new();
+  UploadPack::new(&repo)
+    .write_advertisement(&mut advertised)
+    .unwrap();
+  let (packet, len) = Packet::decode(&advertised).unwrap().unwrap();
+  assert_eq!(
+    Packet::Data(
+      format!(
+        "{} capabilities^{{}}\0thin-pack side-band side-band-64k ofs-delta no-progress agent=libgit-rs/{}\n",
+        "0".repeat(40),
+        env!("CARGO_PKG_VERSION")
+      )
+      .as_bytes()
+      .into()
+    ),
+    packet
+  );
+  assert_eq!(b"0000"[..], advertised[len..]);
+
+  let odb = repo.odb();
+  let tree = odb.write_tree(&Tree::default()).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = Commit::new(
+    tree,
+    vec![],
+    signature.clone(),
+    signature.clone(),
+    "first\n",
+  );
+  let commit = odb.write_commit(&commit).unwrap();
+  let refs = repo.refs();
+  for name in [
+    "refs/heads/master",
+    "refs/pull/1/head",
+    "refs/users/jane/wip",
+    "refs/users/joe/wip",
+  ] {
+    refs.write(name, &commit).unwrap();
+  }
+  let tag = repo
+    .tags()
+    .create_annotated("v1.0", &commit, signature, "Release\n", false)
+    .unwrap();
+  std::fs::write(
+    &config,
+    std::fs::read_to_string(&config).unwrap()
+      + "[transfer]\n\thideRefs = refs/pull\n[uploadpack]\n\thideRefs = refs/users\n",
+  )
+  .unwrap();
+  let repo = Repository::open(tmp_dir.path()).unwrap();
+
+  // Jane sees her own refs but nobody else's
+  let sent = std::sync::atomic::AtomicUsize::new(0);
+  let mut upload_pack = UploadPack::new(&repo);
+  upload_pack.hidden_refs().push("!refs/users/jane");
+  upload_pack.filter_refs(|refs| {
+    refs.push(AdvertisedRef {
+      name: "refs/heads/virtual".into(),
+      oid: commit,
+      peeled: None,
+      symref_target: None,
+    })
+  });
+  let names: Vec<String> = upload_pack
+    .advertised_refs()
+    .unwrap()
+    .iter()
+    .map(|reference| reference.name.to_string())
+    .collect();
+  assert_eq!(
+    vec![
+      "HEAD",
+      "refs/heads/master",
+      "refs/tags/v1.0",
+      "refs/users/jane/wip",
+      "refs/heads/virtual"
+    ],
+    names
+  );
+
+  // What gets sent goes through the throttle
+  upload_pack.throttle(|_, len| {
+    sent.fetch_add(len, std::sync::atomic::Ordering::SeqCst);
+    std::time::Duration::ZERO
+  });
+  let mut advertised = Vec::new();
+  upload_pack.write_advertisement(&mut advertised).unwrap();
+  assert_eq!(
+    advertised.len(),
+    sent.load(std::sync::atomic::Ordering::SeqCst)
+  );
+  let mut lines = Vec::new();
+  let mut rest = &advertised[..];
+  while let Some((packet, len)) = Packet::decode(rest).unwrap() {
+    lines.push(match packet {
+      Packet::Data(data) => data.to_string(),
+      _ => "0000".into(),
+    });
+    rest = &rest[len..];
+  }
+  assert_eq!(
+    vec![
+      format!(
+        "{} HEAD\0thin-pack side-band side-band-64k ofs-delta no-progress agent=libgit-rs/{} \
+         symref=HEAD:refs/heads/master\n",
+        commit,
+        env!("CARGO_PKG_VERSION")
+      ),
+      format!("{} refs/heads/master\n", commit),
+      format!("{} refs/tags/v1.0\n", tag),
+      format!("{} refs/tags/v1.0^{{}}\n", commit),
+      format!("{} refs/users/jane/wip\n", commit),
+      format!("{} refs/heads/virtual\n", commit),
+      "0000".into(),
+    ],
+    lines
+  );
+
+  // Hiding the branch HEAD is on hides HEAD too
+  let mut upload_pack = UploadPack::new(&repo);
+  upload_pack.hidden_refs().push("refs/heads");
+  let refs = upload_pack.advertised_refs().unwrap();
+  assert_eq!(
+    vec!["refs/tags/v1.0"],
+    refs.iter().map(|r| r.name.to_string()).collect::<Vec<_>>()
+  );
+}
+
+#[test]
+fn spool() {
+  use crate::{parse_range, Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry};
+  let tmp_dir = tempdir::TempDir::new("upload_pack_test").unwrap();
+  let repo = Repository::init(tmp_dir.path().join("server")).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |contents: &str, parents: Vec<OID>| {
+    let odb = repo.odb();
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(
+      tree,
+      parents,
+      signature.clone(),
+      signature.clone(),
+      "commit\n",
+    );
+    let oid = odb.write_commit(&commit).unwrap();
+    repo.refs().write("refs/heads/master", &oid).unwrap();
+    oid
+  };
+  let first = commit("first\n", vec![]);
+  let second = commit("second\n", vec![first]);
+  let spool = PackSpool::new(tmp_dir.path().join("spool"));
+  let mut upload_pack = UploadPack::new(&repo);
+  assert!(matches!(
+    upload_pack.spool_pack(&spool, &[first], &[], false),
+    Err(UploadPackError::NotAdvertised(oid)) if oid == first
+  ));
+
+  // The same pack is found again, whatever order it's asked for in
+  let missing = OID::hash("missing");
+  let pack = upload_pack
+    .spool_pack(&spool, &[second, second], &[], false)
+    .unwrap();
+  assert_eq!(
+    pack,
+    upload_pack
+      .spool_pack(&spool, &[second], &[missing], false)
+      .unwrap()
+  );
+  assert_eq!(Some(pack.clone()), spool.get(pack.id()).unwrap());
+  let bytes = std::fs::read(pack.path()).unwrap();
+  assert_eq!(bytes.len() as u64, pack.size());
+  assert_eq!(&bytes[bytes.len() - 20..], pack.checksum().as_bytes());
+
+  // A download broken off halfway picks up where it stopped
+  let mut download = Vec::new();
+  let half = pack.size() / 2;
+  assert_eq!(
+    half,
+    upload_pack
+      .send_spooled(&pack, 0..half, &mut download)
+      .unwrap()
+  );
+  let range = parse_range(&format!("bytes={}-", download.len()), pack.size()).unwrap();
+  assert_eq!(
+    format!("bytes {}-{}/{}", half, pack.size() - 1, pack.size()),
+    pack.content_range(&range)
+  );
+  upload_pack
+    .send_spooled(&pack, range, &mut download)
+    .unwrap();
+  assert_eq!(bytes, download);
+  let client = Repository::init(tmp_dir.path().join("client")).unwrap();
+  assert_eq!(6, client.odb().write_pack(&download).unwrap().len());
+  assert_eq!(
+    &[first][..],
+    client.odb().read_commit(&second).unwrap().parents()
+  );
+
+  // Only what's new is in a pack for someone who has the first commit
+  let fetch = upload_pack
+    .spool_pack(&spool, &[second], &[first], true)
+    .unwrap();
+  assert_ne!(pack.id(), fetch.id());
+  assert!(fetch.size() < pack.size());
+
+  let mut section = Vec::new();
+  upload_pack
+    .write_packfile_uris(&mut section, &[(&pack, "https://cdn.example.com/a.pack")])
+    .unwrap();
+  let line = format!("{} https://cdn.example.com/a.pack\n", pack.checksum());
+  assert_eq!(
+    format!("0012packfile-uris\n{:04x}{}0001", line.len() + 4, line),
+    String::from_utf8(section).unwrap()
+  );
+
+  assert_eq!(
+    0,
+    spool
+      .remove_older_than(std::time::Duration::from_secs(60))
+      .unwrap()
+  );
+  std::thread::sleep(std::time::Duration::from_millis(20));
+  assert_eq!(
+    2,
+    spool.remove_older_than(std::time::Duration::ZERO).unwrap()
+  );
+  assert_eq!(None, spool.get(pack.id()).unwrap());
+}
+
+#[test]
+fn serve() {
+  use crate::{
+    transport::{
+      http::{serve_smart_http, HttpTransport},
+      ProtocolVersion, Transport,
+    },
+    Blob, Commit, FileMode, Signature, Time, Tree, TreeEntry,
+  };
+  let tmp_dir = tempdir::TempDir::new("upload_pack_test").unwrap();
+  let repo = Repository::init(tmp_dir.path().join("server")).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let commit = |contents: &str, parents: Vec<OID>| {
+    let odb = repo.odb();
+    let blob = odb.write_blob(&Blob::new(contents)).unwrap();
+    let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+    let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+    let commit = Commit::new(
+      tree,
+      parents,
+      signature.clone(),
+      signature.clone(),
+      "commit\n",
+    );
+    let oid = odb.write_commit(&commit).unwrap();
+    repo.refs().write("refs/heads/master", &oid).unwrap();
+    oid
+  };
+  let first = commit("first\n", vec![]);
+  let second = commit("second\n", vec![first]);
+  let lines = |lines: &[&str]| {
+    let mut bytes = Vec::new();
+    for line in lines {
+      match *line {
+        "0000" => Packet::Flush.encode(&mut bytes).unwrap(),
+        line => Packet::Data(format!("{}\n", line).as_bytes().as_bstr())
+          .encode(&mut bytes)
+          .unwrap(),
+      }
+    }
+    bytes
+  };
+  let read_lines = |mut bytes: &[u8]| {
+    let mut lines = Vec::new();
+    while let Some((packet, len)) = Packet::decode(bytes).unwrap() {
+      lines.push(match packet {
+        Packet::Data(data) => data.to_vec(),
+        _ => b"0000".to_vec(),
+      });
+      bytes = &bytes[len..];
+    }
+    lines
+  };
+
+  // Over a connection that stays open the first have in common is
+  // acknowledged right away and the pack comes on the side-band
+  let request = lines(&[
+    &format!("want {} side-band-64k", second),
+    "0000",
+    &format!("have {}", OID::hash("missing")),
+    "0000",
+    &format!("have {}", first),
+    &format!("have {}", first),
+    "0000",
+    "done",
+  ]);
+  let mut response = Vec::new();
+  UploadPack::new(&repo)
+    .serve(&request[..], &mut response)
+    .unwrap();
+  let mut advertisement = Vec::new();
+  UploadPack::new(&repo)
+    .write_advertisement(&mut advertisement)
+    .unwrap();
+  assert!(response.starts_with(&advertisement));
+  let lines_sent = read_lines(&response[advertisement.len()..]);
+  assert_eq!(
+    vec![b"NAK\n".to_vec(), format!("ACK {}\n", first).into_bytes()],
+    lines_sent[..2]
+  );
+  assert_eq!(b"0000", &lines_sent.last().unwrap()[..]);
+  let pack: Vec<u8> = lines_sent[2..lines_sent.len() - 1]
+    .iter()
+    .flat_map(|line| {
+      assert_eq!(1, line[0]);
+      line[1..].to_vec()
+    })
+    .collect();
+  let client = Repository::init(tmp_dir.path().join("client")).unwrap();
+  assert_eq!(3, client.odb().write_pack(&pack).unwrap().len());
+
+  // A stateless request without done only hears about the haves
+  let mut response = Vec::new();
+  let request = lines(&[
+    &format!("want {}", second),
+    "0000",
+    &format!("have {}", first),
+    "0000",
+  ]);
+  UploadPack::new(&repo)
+    .serve_stateless(&request[..], &mut response)
+    .unwrap();
+  assert_eq!(
+    vec![format!("ACK {}\n", first).into_bytes()],
+    read_lines(&response)
+  );
+  let request = lines(&[&format!("want {}", OID::hash("secret")), "0000", "done"]);
+  assert!(matches!(
+    UploadPack::new(&repo).serve_stateless(&request[..], &mut Vec::new()),
+    Err(UploadPackError::NotAdvertised(_))
+  ));
+  assert!(matches!(
+    UploadPack::new(&repo).serve_stateless(&b"0009have\n"[..], &mut Vec::new()),
+    Err(UploadPackError::Protocol(_))
+  ));
+
+  // Enough of smart HTTP to host the repository for git and for this crate
+  let git_dir = repo.git_dir().to_path_buf();
+  let url = serve_smart_http("upload-pack", move |advertise, body, response| {
+    let repo = Repository::open(&git_dir).unwrap();
+    let mut upload_pack = UploadPack::new(&repo);
+    match advertise {
+      true => upload_pack.write_http_advertisement(response).unwrap(),
+      false => upload_pack.serve_stateless(body, response).unwrap(),
+    }
+  }) + "/server.git";
+  let fetched = Repository::init(tmp_dir.path().join("fetched")).unwrap();
+  let mut transport = HttpTransport::new(&url)
+    .unwrap()
+    .with_protocol(ProtocolVersion::V1);
+  assert_eq!(
+    second,
+    transport.list_refs().unwrap().get("HEAD").unwrap().oid
+  );
+  transport.fetch(&fetched, &[second]).unwrap();
+  assert_eq!(
+    &[first][..],
+    fetched.odb().read_commit(&second).unwrap().parents()
+  );
+
+  if crate::transport::http::have_git() {
+    let output = std::process::Command::new("git")
+      .args(["clone", "--quiet", &url])
+      .arg(tmp_dir.path().join("cloned"))
+      .env("GIT_CONFIG_NOSYSTEM", "1")
+      .env("GIT_CONFIG_GLOBAL", "/dev/null")
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{}", output.stderr.as_bstr());
+    assert_eq!(
+      "second\n",
+      std::fs::read_to_string(tmp_dir.path().join("cloned/file.txt")).unwrap()
+    );
+  }
+}
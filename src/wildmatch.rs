@@ -0,0 +1,219 @@
+/// `*` and `?` don't match `/`, and `**` only crosses directories when it
+/// makes up a whole path component
+pub(crate) const PATHNAME: u8 = 1;
+/// Letters match regardless of case
+pub(crate) const CASEFOLD: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MatchResult {
+  Match,
+  NoMatch,
+  /// Nothing later in the text can match either
+  AbortAll,
+  /// Only a `**` earlier in the pattern can still make this match
+  AbortToStarStar,
+}
+
+/// Whether `text` matches the glob `pattern` the same way as git's
+/// `wildmatch.c`
+pub(crate) fn wildmatch(pattern: &[u8], text: &[u8], flags: u8) -> bool {
+  dowild(pattern, text, flags) == MatchResult::Match
+}
+
+fn dowild(pattern: &[u8], text: &[u8], flags: u8) -> MatchResult {
+  let pathname = flags & PATHNAME != 0;
+  let fold = |c: u8| {
+    if flags & CASEFOLD != 0 {
+      c.to_ascii_lowercase()
+    } else {
+      c
+    }
+  };
+  let (mut p, mut t) = (0, 0);
+  while p < pattern.len() {
+    let c = pattern[p];
+    if t == text.len() && c != b'*' {
+      return MatchResult::AbortAll;
+    }
+    match c {
+      b'\\' if p + 1 < pattern.len() => {
+        p += 1;
+        if fold(pattern[p]) != fold(text[t]) {
+          return MatchResult::NoMatch;
+        }
+      }
+      b'?' => {
+        if pathname && text[t] == b'/' {
+          return MatchResult::NoMatch;
+        }
+      }
+      b'*' => {
+        let start = p;
+        while p < pattern.len() && pattern[p] == b'*' {
+          p += 1;
+        }
+        let match_slash = if p - start < 2 {
+          !pathname
+        } else if !pathname {
+          true
+        } else if (start == 0 || pattern[start - 1] == b'/')
+          && (p == pattern.len() || pattern[p] == b'/')
+        {
+          // `/**/` also matches a single `/`, so try skipping it entirely
+          if p < pattern.len() && dowild(&pattern[p + 1..], &text[t..], flags) == MatchResult::Match
+          {
+            return MatchResult::Match;
+          }
+          true
+        } else {
+          // `**` in the middle of a component is the same as `*`
+          false
+        };
+        if p == pattern.len() {
+          if !match_slash && text[t..].contains(&b'/') {
+            return MatchResult::AbortToStarStar;
+          }
+          return MatchResult::Match;
+        }
+        loop {
+          let result = dowild(&pattern[p..], &text[t..], flags);
+          if result != MatchResult::NoMatch
+            && (!match_slash || result != MatchResult::AbortToStarStar)
+          {
+            return result;
+          }
+          if t == text.len() {
+            return MatchResult::AbortAll;
+          }
+          if !match_slash && text[t] == b'/' {
+            return MatchResult::AbortToStarStar;
+          }
+          t += 1;
+        }
+      }
+      b'[' => match match_class(&pattern[p + 1..], text[t], flags) {
+        Some((true, len)) => p += len,
+        Some((false, _)) => return MatchResult::NoMatch,
+        // An unterminated class never matches anything
+        None => return MatchResult::AbortAll,
+      },
+      c => {
+        if fold(c) != fold(text[t]) {
+          return MatchResult::NoMatch;
+        }
+      }
+    }
+    p += 1;
+    t += 1;
+  }
+  if t == text.len() {
+    MatchResult::Match
+  } else {
+    MatchResult::NoMatch
+  }
+}
+
+/// Match a character against the bracket expression starting right after
+/// its `[`, returning whether it matched and how many bytes of the pattern
+/// the expression took up, not counting the `[`
+fn match_class(pattern: &[u8], c: u8, flags: u8) -> Option<(bool, usize)> {
+  let fold = flags & CASEFOLD != 0;
+  let mut p = 0;
+  let negated = matches!(pattern.first(), Some(b'!' | b'^'));
+  if negated {
+    p += 1;
+  }
+  let mut matched = false;
+  let mut first = true;
+  loop {
+    let mut start = *pattern.get(p)?;
+    if start == b']' && !first {
+      break;
+    }
+    first = false;
+    if start == b'[' && pattern.get(p + 1) == Some(&b':') {
+      let end = pattern[p + 2..].windows(2).position(|w| w == b":]")?;
+      let name = &pattern[p + 2..p + 2 + end];
+      matched |= match name {
+        b"alnum" => c.is_ascii_alphanumeric(),
+        b"alpha" => c.is_ascii_alphabetic(),
+        b"blank" => c == b' ' || c == b'\t',
+        b"cntrl" => c.is_ascii_control(),
+        b"digit" => c.is_ascii_digit(),
+        b"graph" => c.is_ascii_graphic(),
+        b"lower" => c.is_ascii_lowercase() || (fold && c.is_ascii_uppercase()),
+        b"print" => c.is_ascii_graphic() || c == b' ',
+        b"punct" => c.is_ascii_punctuation(),
+        b"space" => c.is_ascii_whitespace() || c == 0x0b,
+        b"upper" => c.is_ascii_uppercase() || (fold && c.is_ascii_lowercase()),
+        b"xdigit" => c.is_ascii_hexdigit(),
+        _ => return None,
+      };
+      p += 2 + end + 2;
+      continue;
+    }
+    if start == b'\\' {
+      p += 1;
+      start = *pattern.get(p)?;
+    }
+    if pattern.get(p + 1) == Some(&b'-') && pattern.get(p + 2).is_some_and(|&e| e != b']') {
+      let mut end = pattern[p + 2];
+      p += 2;
+      if end == b'\\' {
+        p += 1;
+        end = *pattern.get(p)?;
+      }
+      let in_range = |c: u8| start <= c && c <= end;
+      matched |= in_range(c)
+        || (fold && (in_range(c.to_ascii_lowercase()) || in_range(c.to_ascii_uppercase())));
+    } else {
+      matched |= start == c || (fold && start.eq_ignore_ascii_case(&c));
+    }
+    p += 1;
+  }
+  if flags & PATHNAME != 0 && c == b'/' {
+    matched = negated;
+  }
+  Some((matched != negated, p + 1))
+}
+
+#[test]
+fn matching() {
+  for (pattern, text, flags, expected) in [
+    ("foo", "foo", 0, true),
+    ("foo", "Foo", CASEFOLD, true),
+    ("f?o", "f/o", 0, true),
+    ("f?o", "f/o", PATHNAME, false),
+    ("*.txt", "a/b.txt", 0, true),
+    ("*.txt", "a/b.txt", PATHNAME, false),
+    ("**/b.txt", "a/b.txt", PATHNAME, true),
+    ("**/b.txt", "b.txt", PATHNAME, true),
+    ("a/**/b", "a/b", PATHNAME, true),
+    ("a/**/b", "a/x/y/b", PATHNAME, true),
+    ("a/**", "a/x/y/b", PATHNAME, true),
+    ("a**b", "a/b", PATHNAME, false),
+    (
+      "/home/*/work/**",
+      "/home/jane/work/repo/.git",
+      PATHNAME,
+      true,
+    ),
+    ("[a-c]at", "bat", 0, true),
+    ("[!a-c]at", "bat", 0, false),
+    ("[[:digit:]]x", "1x", 0, true),
+    ("[]]", "]", 0, true),
+    ("[A-Z]", "q", CASEFOLD, true),
+    ("a[/]b", "a/b", PATHNAME, false),
+    ("\\*", "*", 0, true),
+    ("\\*", "x", 0, false),
+    ("[abc", "a", 0, false),
+  ] {
+    assert_eq!(
+      expected,
+      wildmatch(pattern.as_bytes(), text.as_bytes(), flags),
+      "{} {}",
+      pattern,
+      text
+    );
+  }
+}
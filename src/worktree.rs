@@ -0,0 +1,495 @@
+//! Linked worktrees, more working trees for the same repository like
+//! `git worktree add` makes. Each one has its own git directory in
+//! `.git/worktrees/{name}` holding its `HEAD`, index, and the other refs
+//! only it has, with a `commondir` file pointing back at the git directory
+//! of the repository for everything else and a `gitdir` file pointing at
+//! the `.git` file of the worktree, which points back in turn.
+
+use crate::{
+  repository::normalize, CheckoutError, ConfigError, OdbError, RefError, RefStore, RefTarget,
+  Repository, RepositoryError, StatusError,
+};
+use bstr::{BString, ByteSlice};
+use std::{
+  env, fs, io,
+  path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// A linked worktree of a repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Worktree {
+  name: String,
+  git_dir: PathBuf,
+  path: Option<PathBuf>,
+  lock_reason: Option<String>,
+}
+
+impl Worktree {
+  fn read(name: String, git_dir: PathBuf) -> Result<Self, WorktreeError> {
+    let path = match fs::read(git_dir.join("gitdir")) {
+      Ok(contents) => contents.trim_end().to_path().ok().and_then(|dot_git| {
+        normalize(&git_dir.join(dot_git))
+          .parent()
+          .map(Path::to_path_buf)
+      }),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+      Err(e) => return Err(e.into()),
+    };
+    let lock_reason = match fs::read_to_string(git_dir.join("locked")) {
+      Ok(reason) => Some(reason.trim_end().to_owned()),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+      Err(e) => return Err(e.into()),
+    };
+    Ok(Self {
+      name,
+      git_dir,
+      path,
+      lock_reason,
+    })
+  }
+
+  /// The name of the worktree, which its git directory is named after
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// The git directory of the worktree, in `.git/worktrees`
+  pub fn git_dir(&self) -> &Path {
+    &self.git_dir
+  }
+
+  /// Where the working tree is, if its git directory still knows
+  pub fn path(&self) -> Option<&Path> {
+    self.path.as_deref()
+  }
+
+  /// Whether the worktree is locked, which keeps it from being pruned or
+  /// removed. Worktrees on removable drives are usually locked.
+  pub fn is_locked(&self) -> bool {
+    self.lock_reason.is_some()
+  }
+
+  /// Why the worktree is locked, which is empty if no reason was given
+  pub fn lock_reason(&self) -> Option<&str> {
+    self.lock_reason.as_deref()
+  }
+
+  /// Whether the working tree is gone, so the worktree can be pruned
+  /// unless it's locked
+  pub fn is_prunable(&self) -> bool {
+    !self.is_locked()
+      && self
+        .path
+        .as_ref()
+        .is_none_or(|path| !path.join(".git").exists())
+  }
+
+  /// Open the worktree as a [`Repository`]
+  pub fn open(&self) -> Result<Repository, WorktreeError> {
+    Ok(Repository::open(&self.git_dir)?)
+  }
+}
+
+/// Options for [`Repository::add_worktree`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorktreeOptions {
+  /// The name of the worktree instead of the last component of its path.
+  /// Either way a number is added to it if it's taken.
+  pub name: Option<String>,
+  /// The branch to check out, which is made at the commit `HEAD` is at if
+  /// it doesn't exist. Without one a new branch named after the worktree is
+  /// made, like `git worktree add` does.
+  pub branch: Option<String>,
+  /// Check out the commit `HEAD` is at with a detached `HEAD` instead of a
+  /// branch, like `git worktree add --detach`
+  pub detach: bool,
+  /// Lock the worktree with this reason, like `git worktree add --lock`
+  pub lock: Option<String>,
+}
+
+impl Repository {
+  /// The linked worktrees of the repository, sorted by name. The main
+  /// working tree is the repository itself and isn't included.
+  pub fn worktrees(&self) -> Result<Vec<Worktree>, WorktreeError> {
+    let entries = match fs::read_dir(self.common_dir().join("worktrees")) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(e) => return Err(e.into()),
+    };
+    let mut worktrees = Vec::new();
+    for entry in entries {
+      let entry = entry?;
+      if entry.file_type()?.is_dir() && entry.path().join("commondir").is_file() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        worktrees.push(Worktree::read(name, entry.path())?);
+      }
+    }
+    worktrees.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(worktrees)
+  }
+
+  /// Find a linked worktree by name
+  pub fn find_worktree(&self, name: &str) -> Result<Worktree, WorktreeError> {
+    let git_dir = self.common_dir().join("worktrees").join(name);
+    match check_name(name).is_ok() && git_dir.join("commondir").is_file() {
+      true => Worktree::read(name.into(), git_dir),
+      false => Err(WorktreeError::NotFound(name.into())),
+    }
+  }
+
+  /// Add a linked worktree at `path`, which can't exist yet or has to be an
+  /// empty directory, and check out the commit `HEAD` is at there, like
+  /// `git worktree add {path}`. A branch can only be checked out in one
+  /// worktree at a time.
+  pub fn add_worktree(
+    &self,
+    path: impl AsRef<Path>,
+    options: &WorktreeOptions,
+  ) -> Result<Repository, WorktreeError> {
+    let path = path.as_ref();
+    let path = normalize(&match path.is_absolute() {
+      true => path.to_path_buf(),
+      false => env::current_dir()?.join(path),
+    });
+    if path.exists() && fs::read_dir(&path)?.next().is_some() {
+      return Err(WorktreeError::Exists(path));
+    }
+    let base = match &options.name {
+      Some(name) => name.clone(),
+      None => path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| WorktreeError::InvalidName(path.display().to_string()))?,
+    };
+    check_name(&base)?;
+    let worktrees = self.common_dir().join("worktrees");
+    let mut name = base.clone();
+    let mut n = 1;
+    while worktrees.join(&name).exists() {
+      name = format!("{}{}", base, n);
+      n += 1;
+    }
+
+    let head = self.refs().resolve("HEAD")?;
+    let (branch, commit) = match options.detach {
+      true => (None, head.ok_or(WorktreeError::NoCommit)?),
+      false => {
+        let short = options.branch.as_deref().unwrap_or(&name);
+        let branch = format!("refs/heads/{}", short);
+        if self.checked_out_branches()?.iter().any(|b| *b == branch) {
+          return Err(WorktreeError::BranchCheckedOut(short.into()));
+        }
+        match self.refs().resolve(&branch)? {
+          Some(oid) => (Some(branch), oid),
+          None => {
+            let oid = head.ok_or(WorktreeError::NoCommit)?;
+            self.refs().write(&branch, &oid)?;
+            (Some(branch), oid)
+          }
+        }
+      }
+    };
+
+    let git_dir = worktrees.join(&name);
+    fs::create_dir_all(&git_dir)?;
+    if let Some(reason) = &options.lock {
+      fs::write(git_dir.join("locked"), format!("{}\n", reason))?;
+    }
+    fs::write(git_dir.join("commondir"), "../..\n")?;
+    fs::write(
+      git_dir.join("gitdir"),
+      format!("{}\n", path.join(".git").display()),
+    )?;
+    let refs = RefStore::new(&git_dir);
+    match &branch {
+      Some(branch) => refs.write_symbolic("HEAD", branch)?,
+      None => refs.write("HEAD", &commit)?,
+    }
+    fs::create_dir_all(&path)?;
+    fs::write(
+      path.join(".git"),
+      format!("gitdir: {}\n", git_dir.display()),
+    )?;
+
+    let worktree = Repository::open(&path)?;
+    let tree = *worktree.odb().read_commit(&commit)?.tree();
+    worktree.checkout_tree(&tree)?;
+    Ok(worktree)
+  }
+
+  /// Remove the linked worktree `name` along with its working tree, like
+  /// `git worktree remove`. Unless `force` is set, locked worktrees and
+  /// ones with changes or untracked files are left alone.
+  pub fn remove_worktree(&self, name: &str, force: bool) -> Result<(), WorktreeError> {
+    let worktree = self.find_worktree(name)?;
+    if worktree.is_locked() && !force {
+      return Err(WorktreeError::Locked(name.into()));
+    }
+    if let Some(path) = worktree.path().filter(|path| path.join(".git").exists()) {
+      if !force && !worktree.open()?.status()?.is_empty() {
+        return Err(WorktreeError::Dirty(name.into()));
+      }
+      fs::remove_dir_all(path)?;
+    }
+    fs::remove_dir_all(worktree.git_dir())?;
+    Ok(())
+  }
+
+  /// Remove what's left of the linked worktrees whose working tree is gone
+  /// and that aren't locked, like `git worktree prune`. Returns the names
+  /// of the worktrees that were pruned.
+  pub fn prune_worktrees(&self) -> Result<Vec<String>, WorktreeError> {
+    let mut pruned = Vec::new();
+    for worktree in self.worktrees()? {
+      if worktree.is_prunable() {
+        fs::remove_dir_all(worktree.git_dir())?;
+        pruned.push(worktree.name);
+      }
+    }
+    Ok(pruned)
+  }
+
+  /// Lock the linked worktree `name` so it isn't pruned or removed, like
+  /// `git worktree lock`
+  pub fn lock_worktree(&self, name: &str, reason: &str) -> Result<(), WorktreeError> {
+    let worktree = self.find_worktree(name)?;
+    if worktree.is_locked() {
+      return Err(WorktreeError::Locked(name.into()));
+    }
+    let contents = match reason.is_empty() {
+      true => String::new(),
+      false => format!("{}\n", reason),
+    };
+    fs::write(worktree.git_dir().join("locked"), contents)?;
+    Ok(())
+  }
+
+  /// Unlock the linked worktree `name`, returning whether it was locked
+  pub fn unlock_worktree(&self, name: &str) -> Result<bool, WorktreeError> {
+    let worktree = self.find_worktree(name)?;
+    match fs::remove_file(worktree.git_dir().join("locked")) {
+      Ok(()) => Ok(true),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// The branches `HEAD` is on in the main working tree, unless the
+  /// repository is bare, and every linked worktree
+  fn checked_out_branches(&self) -> Result<Vec<BString>, WorktreeError> {
+    let mut git_dirs = Vec::new();
+    if self.config().get_bool("core.bare")? != Some(true) {
+      git_dirs.push(self.common_dir().to_path_buf());
+    }
+    git_dirs.extend(
+      self
+        .worktrees()?
+        .into_iter()
+        .map(|worktree| worktree.git_dir),
+    );
+    let mut branches = Vec::new();
+    for git_dir in git_dirs {
+      if let Some(RefTarget::Symbolic(branch)) = RefStore::new(git_dir)
+        .read("HEAD")?
+        .map(|head| head.target().clone())
+      {
+        branches.push(branch);
+      }
+    }
+    Ok(branches)
+  }
+}
+
+/// Check that a worktree name is a single path component that's also
+/// valid in a ref name, as git requires
+fn check_name(name: &str) -> Result<(), WorktreeError> {
+  let invalid = name.is_empty()
+    || name.starts_with('.')
+    || name.ends_with(".lock")
+    || crate::refs::check_ref_name(format!("refs/{}", name).as_bytes()).is_err()
+    || name.contains('/');
+  match invalid {
+    true => Err(WorktreeError::InvalidName(name.into())),
+    false => Ok(()),
+  }
+}
+
+#[derive(Error, Debug)]
+/// Errors related to linked worktrees
+pub enum WorktreeError {
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Config(#[from] ConfigError),
+  #[error("{0}")]
+  Repository(#[from] RepositoryError),
+  #[error("{0}")]
+  Ref(#[from] RefError),
+  #[error("{0}")]
+  Odb(#[from] OdbError),
+  #[error("{0}")]
+  Checkout(#[from] CheckoutError),
+  #[error("{0}")]
+  Status(#[from] StatusError),
+  #[error("no worktree named {0:?}")]
+  NotFound(String),
+  #[error("{0:?} already exists")]
+  Exists(PathBuf),
+  #[error("{0:?} is not a valid worktree name")]
+  InvalidName(String),
+  #[error("branch {0:?} is already checked out in a worktree")]
+  BranchCheckedOut(String),
+  #[error("HEAD has no commit to check out")]
+  NoCommit,
+  #[error("worktree {0:?} is locked")]
+  Locked(String),
+  #[error("worktree {0:?} has changes, use force to remove it anyway")]
+  Dirty(String),
+}
+
+#[test]
+fn worktrees() {
+  use crate::{Blob, Commit, FileMode, Head, Signature, Time, Tree, TreeEntry};
+  let tmp_dir = tempdir::TempDir::new("worktree_test").unwrap();
+  let main_dir = tmp_dir.path().join("main");
+  let repo = Repository::init(&main_dir).unwrap();
+  let signature = Signature::new(
+    "A U Thor",
+    "author@example.com",
+    Time::new(1_234_567_890, 0),
+  );
+  let odb = repo.odb();
+  let blob = odb.write_blob(&Blob::new("hello\n")).unwrap();
+  let entry = TreeEntry::new(FileMode::NonExecutableFile, "file.txt", blob);
+  let tree = odb.write_tree(&Tree::new(vec![entry])).unwrap();
+  let commit = Commit::new(tree, vec![], signature.clone(), signature, "commit\n");
+  let commit = odb.write_commit(&commit).unwrap();
+  repo.refs().write("refs/heads/master", &commit).unwrap();
+  repo.checkout_tree(&tree).unwrap();
+
+  // A new worktree gets a branch of its own, with the objects and every
+  // other ref shared
+  let wt_dir = tmp_dir.path().join("wt");
+  let wt = repo
+    .add_worktree(&wt_dir, &WorktreeOptions::default())
+    .unwrap();
+  assert_eq!(main_dir.join(".git/worktrees/wt"), wt.git_dir());
+  assert_eq!(main_dir.join(".git"), wt.common_dir());
+  assert_eq!(Some(&*wt_dir), wt.work_dir());
+  assert_eq!(main_dir.join(".git/worktrees/wt/index"), wt.index_path());
+  assert_eq!(
+    "hello\n",
+    fs::read_to_string(wt_dir.join("file.txt")).unwrap()
+  );
+  assert_eq!(
+    Head::Branch {
+      name: "refs/heads/wt".into(),
+      oid: commit
+    },
+    wt.head().unwrap()
+  );
+  assert_eq!(
+    Some("refs/heads/master".into()),
+    repo.head().unwrap().branch().map(|b| b.to_owned())
+  );
+  assert!(wt.status().unwrap().is_empty());
+  wt.refs().write("refs/heads/from-wt", &commit).unwrap();
+  wt.refs().write("refs/bisect/bad", &commit).unwrap();
+  assert_eq!(
+    Some(commit),
+    repo.refs().resolve("refs/heads/from-wt").unwrap()
+  );
+  assert_eq!(None, repo.refs().resolve("refs/bisect/bad").unwrap());
+  assert!(wt
+    .refs()
+    .list("refs/")
+    .unwrap()
+    .iter()
+    .any(|r| r.name() == "refs/bisect/bad"));
+  assert!(Repository::open(wt.git_dir()).unwrap().work_dir() == Some(&*wt_dir));
+
+  if crate::transport::http::have_git() {
+    let git = |dir: &Path, args: &[&str]| {
+      let output = std::process::Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .output()
+        .unwrap();
+      assert!(output.status.success(), "{:?}", output);
+      String::from_utf8(output.stdout).unwrap()
+    };
+    let list = git(&main_dir, &["worktree", "list", "--porcelain"]);
+    assert!(list.contains(&format!("worktree {}\n", wt_dir.display())));
+    assert!(list.contains("branch refs/heads/wt\n"));
+    assert_eq!("", git(&wt_dir, &["status", "--porcelain"]));
+    assert_eq!(
+      format!("{}\n", commit),
+      git(&wt_dir, &["rev-parse", "HEAD"])
+    );
+  }
+
+  // Branches are only checked out once
+  let options = WorktreeOptions {
+    branch: Some("master".into()),
+    ..WorktreeOptions::default()
+  };
+  assert!(matches!(
+    repo.add_worktree(tmp_dir.path().join("other"), &options),
+    Err(WorktreeError::BranchCheckedOut(_))
+  ));
+  let options = WorktreeOptions {
+    name: Some("wt".into()),
+    detach: true,
+    lock: Some("on a usb stick".into()),
+    ..WorktreeOptions::default()
+  };
+  let detached = repo
+    .add_worktree(tmp_dir.path().join("detached"), &options)
+    .unwrap();
+  assert_eq!(Head::Detached(commit), detached.head().unwrap());
+
+  let worktrees = repo.worktrees().unwrap();
+  let names: Vec<_> = worktrees.iter().map(Worktree::name).collect();
+  assert_eq!(vec!["wt", "wt1"], names);
+  assert_eq!(Some("on a usb stick"), worktrees[1].lock_reason());
+
+  // Locked worktrees are kept even once they're gone
+  fs::remove_dir_all(tmp_dir.path().join("detached")).unwrap();
+  assert!(repo.prune_worktrees().unwrap().is_empty());
+  assert!(repo.unlock_worktree("wt1").unwrap());
+  assert_eq!(vec!["wt1".to_string()], repo.prune_worktrees().unwrap());
+
+  fs::write(wt_dir.join("file.txt"), "changed\n").unwrap();
+  assert!(matches!(
+    repo.remove_worktree("wt", false),
+    Err(WorktreeError::Dirty(_))
+  ));
+  repo.remove_worktree("wt", true).unwrap();
+  assert!(!wt_dir.exists());
+  assert!(repo.worktrees().unwrap().is_empty());
+  for name in ["../wt", ".."] {
+    assert!(matches!(
+      repo.find_worktree(name),
+      Err(WorktreeError::NotFound(_))
+    ));
+  }
+
+  // Worktrees of a bare repository have a working tree even though it
+  // doesn't, and can check out the branch its `HEAD` is on
+  let bare = Repository::init_bare(tmp_dir.path().join("bare.git")).unwrap();
+  fs::remove_dir_all(bare.odb().path()).unwrap();
+  fs::rename(main_dir.join(".git/objects"), bare.odb().path()).unwrap();
+  bare.refs().write("refs/heads/master", &commit).unwrap();
+  let options = WorktreeOptions {
+    branch: Some("master".into()),
+    ..WorktreeOptions::default()
+  };
+  let wt = bare
+    .add_worktree(tmp_dir.path().join("bare-wt"), &options)
+    .unwrap();
+  assert!(!wt.is_bare());
+  assert!(wt.status().unwrap().is_empty());
+}
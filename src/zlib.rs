@@ -0,0 +1,258 @@
+//! The zlib format (RFC 1950) wrapping deflate (RFC 1951), which git
+//! compresses every loose object and every object in a pack file with. The
+//! compressing and decompressing is done by miniz_oxide, this only fits it
+//! to how objects are laid out and limited.
+
+use miniz_oxide::{
+  deflate::{compress_to_vec, compress_to_vec_zlib},
+  inflate::{
+    core::{
+      decompress as inflate,
+      inflate_flags::{
+        TINFL_FLAG_COMPUTE_ADLER32, TINFL_FLAG_PARSE_ZLIB_HEADER,
+        TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+      },
+      DecompressorOxide,
+    },
+    TINFLStatus,
+  },
+};
+use thiserror::Error;
+
+/// The compression level git uses unless `core.compression` says otherwise
+const LEVEL: u8 = 6;
+
+/// [`decompress_with_limit`] without a limit
+#[cfg(test)]
+pub(crate) fn decompress(input: &[u8]) -> Result<(Vec<u8>, usize), ZlibError> {
+  decompress_with_limit(input, usize::MAX)
+}
+
+/// Decompress a zlib stream from the start of `input`, returning the data and
+/// how many bytes of `input` the stream took up. Anything after the end of
+/// the stream is left alone, which is how objects are laid out back to back
+/// in pack files. This fails with [`ZlibError::TooLarge`] as soon as the
+/// output would grow past `limit` bytes.
+pub(crate) fn decompress_with_limit(
+  input: &[u8],
+  limit: usize,
+) -> Result<(Vec<u8>, usize), ZlibError> {
+  check_header(input)?;
+  let flags = TINFL_FLAG_PARSE_ZLIB_HEADER | TINFL_FLAG_COMPUTE_ADLER32;
+  match run(input, limit, flags)? {
+    (output, consumed, true) => Ok((output, consumed)),
+    (_, _, false) => Err(ZlibError::TooLarge(limit)),
+  }
+}
+
+/// Decompress only the first `len` bytes of a zlib stream at the start of
+/// `input`, or all of it if it's shorter, to read the header of an object
+/// without the rest of it. `input` can stop anywhere after those bytes and
+/// the checksum isn't checked, since it covers data that isn't there.
+pub(crate) fn decompress_prefix(input: &[u8], len: usize) -> Result<Vec<u8>, ZlibError> {
+  check_header(input)?;
+  let mut decompressor = DecompressorOxide::new();
+  let mut output = vec![0; len];
+  let flags = TINFL_FLAG_PARSE_ZLIB_HEADER | TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+  let (status, _, written) = inflate(&mut decompressor, input, &mut output, 0, flags);
+  match status {
+    TINFLStatus::Done | TINFLStatus::HasMoreOutput => {}
+    // The stream goes on past what's there, but what's wanted was read
+    TINFLStatus::FailedCannotMakeProgress | TINFLStatus::NeedsMoreInput if written == len => {}
+    status => return Err(status.into()),
+  }
+  output.truncate(written);
+  Ok(output)
+}
+
+/// Inflate `input` into at most `limit` bytes, returning them, how much of
+/// `input` was used, and whether the stream ended before the limit was hit
+fn run(input: &[u8], limit: usize, flags: u32) -> Result<(Vec<u8>, usize, bool), ZlibError> {
+  let flags = flags | TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+  let mut decompressor = DecompressorOxide::new();
+  let mut output = vec![0; input.len().saturating_mul(2).clamp(64, 1 << 20).min(limit)];
+  let (mut read, mut written) = (0, 0);
+  loop {
+    let (status, consumed, produced) = inflate(
+      &mut decompressor,
+      &input[read..],
+      &mut output,
+      written,
+      flags,
+    );
+    read += consumed;
+    written += produced;
+    match status {
+      TINFLStatus::Done => {
+        output.truncate(written);
+        return Ok((output, read, true));
+      }
+      TINFLStatus::HasMoreOutput if output.len() >= limit => {
+        output.truncate(written);
+        return Ok((output, read, false));
+      }
+      TINFLStatus::HasMoreOutput => {
+        let len = output.len().saturating_mul(2).min(limit);
+        output.resize(len, 0);
+      }
+      status => return Err(status.into()),
+    }
+  }
+}
+
+/// Check the two byte zlib header, which miniz_oxide only says is wrong
+/// the same way as any other corruption
+fn check_header(input: &[u8]) -> Result<(), ZlibError> {
+  let (cmf, flg) = match input {
+    [cmf, flg, ..] => (*cmf, *flg),
+    _ => return Err(ZlibError::UnexpectedEnd),
+  };
+  if cmf & 0x0f != 8 || cmf >> 4 > 7 || (u16::from(cmf) << 8 | u16::from(flg)) % 31 != 0 {
+    return Err(ZlibError::InvalidHeader);
+  }
+  if flg & 0x20 != 0 {
+    return Err(ZlibError::PresetDictionary);
+  }
+  Ok(())
+}
+
+/// Compress `input` into a zlib stream
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+  compress_to_vec_zlib(input, LEVEL)
+}
+
+/// Compress `input` into a raw deflate stream without the zlib header and
+/// checksum, as zip archives store it
+pub(crate) fn deflate(input: &[u8]) -> Vec<u8> {
+  compress_to_vec(input, LEVEL)
+}
+
+/// The CRC-32 of `data` as zlib computes it, which pack indexes store for
+/// every entry
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+  const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+      let mut crc = i as u32;
+      let mut bit = 0;
+      while bit < 8 {
+        crc = if crc & 1 != 0 {
+          0xedb8_8320 ^ (crc >> 1)
+        } else {
+          crc >> 1
+        };
+        bit += 1;
+      }
+      table[i] = crc;
+      i += 1;
+    }
+    table
+  };
+  !data.iter().fold(!0, |crc, &byte| {
+    TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+  })
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+/// Errors related to decompressing zlib data
+pub enum ZlibError {
+  #[error("the zlib stream ended early")]
+  UnexpectedEnd,
+  #[error("invalid zlib header")]
+  InvalidHeader,
+  #[error("zlib streams with a preset dictionary are not supported")]
+  PresetDictionary,
+  #[error("the deflate stream is corrupt")]
+  Corrupt,
+  #[error("zlib checksum did not match the decompressed data")]
+  ChecksumMismatch,
+  #[error("decompressed data is larger than the limit of {0} bytes")]
+  TooLarge(usize),
+}
+
+impl From<TINFLStatus> for ZlibError {
+  fn from(status: TINFLStatus) -> Self {
+    match status {
+      TINFLStatus::FailedCannotMakeProgress | TINFLStatus::NeedsMoreInput => Self::UnexpectedEnd,
+      TINFLStatus::Adler32Mismatch => Self::ChecksumMismatch,
+      _ => Self::Corrupt,
+    }
+  }
+}
+
+#[test]
+fn decompress_fixed() {
+  let compressed = [
+    120, 218, 203, 72, 205, 201, 201, 87, 200, 192, 32, 203, 243, 139, 114, 82, 0, 163, 138, 10,
+    249, 0xFF,
+  ];
+  let (data, consumed) = decompress(&compressed).unwrap();
+  assert_eq!(b"hello hello hello hello world".to_vec(), data);
+  assert_eq!(compressed.len() - 1, consumed);
+}
+
+#[test]
+fn decompress_dynamic() {
+  let compressed = hex::decode(
+    "78dab58d4b1683201004afd2b980e7c8320b2f003a20093291af707ae7e5e50aaeabba7ade084771cb073a72\
+     0b307ce25df66f02578ac882bd1a1d2bdb09f36df24b89b77768919acb1b8cab246850807747e1285b9b1e78\
+     7243a5d305ebfb3fbf2a93314847957e07d305ce6f4c91",
+  )
+  .unwrap();
+  let (data, _) = decompress(&compressed).unwrap();
+  let expected = [
+    &b"The quick brown fox jumps over the lazy dog. ".repeat(3)[..],
+    b"Pack my box with five dozen liquor jugs! How vexingly quick daft zebras jump.",
+  ]
+  .concat();
+  assert_eq!(expected, data);
+}
+
+#[test]
+fn round_trip() {
+  let inputs: Vec<Vec<u8>> = vec![
+    Vec::new(),
+    b"a".to_vec(),
+    b"blob 14\0this is a test".to_vec(),
+    (0..100_000u32).map(|i| (i % 251) as u8).collect(),
+    (0..5000u32).map(|i| (i * 7 % 13) as u8).collect(),
+  ];
+  for input in inputs {
+    let compressed = compress(&input);
+    assert_eq!(
+      (input.clone(), compressed.len()),
+      decompress(&compressed).unwrap()
+    );
+    let prefix = &compressed[..compressed.len().min(512)];
+    assert_eq!(
+      &input[..input.len().min(20)],
+      &decompress_prefix(prefix, 20).unwrap()[..]
+    );
+  }
+  assert!(compress(&[0; 10_000]).len() < 200);
+}
+
+#[test]
+fn checksums() {
+  assert_eq!(0, crc32(b""));
+  assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+  assert_eq!(0x0d1e_e7ea, crc32(b"this is a test"));
+}
+
+#[test]
+fn errors() {
+  assert_eq!(Err(ZlibError::InvalidHeader), decompress(&[0, 0, 0]));
+  let mut compressed = compress(b"this is a test");
+  let last = compressed.len() - 1;
+  compressed[last] ^= 1;
+  assert_eq!(Err(ZlibError::ChecksumMismatch), decompress(&compressed));
+  assert_eq!(
+    Err(ZlibError::UnexpectedEnd),
+    decompress(&compressed[..compressed.len() - 6])
+  );
+  assert_eq!(
+    Err(ZlibError::TooLarge(4)),
+    decompress_with_limit(&compress(b"this is a test"), 4)
+  );
+}
@@ -0,0 +1,74 @@
+//! Drive the `lgit` binary through a small workflow, checking the library
+//! holds up when used the way a command line tool would
+
+#![cfg(feature = "cli")]
+
+use std::{fs, path::Path, process::Command};
+
+fn lgit(dir: &Path, args: &[&str]) -> String {
+  let output = Command::new(env!("CARGO_BIN_EXE_lgit"))
+    .args(args)
+    .current_dir(dir)
+    .env("GIT_AUTHOR_NAME", "Jane Doe")
+    .env("GIT_AUTHOR_EMAIL", "jane@example.com")
+    .env("GIT_AUTHOR_DATE", "1600000000 +0200")
+    .env("GIT_COMMITTER_NAME", "Jane Doe")
+    .env("GIT_COMMITTER_EMAIL", "jane@example.com")
+    .env("GIT_COMMITTER_DATE", "1600000000 +0200")
+    .output()
+    .unwrap();
+  assert!(
+    output.status.success(),
+    "lgit {:?} failed: {}",
+    args,
+    String::from_utf8_lossy(&output.stderr)
+  );
+  String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn workflow() {
+  let tmp_dir = tempdir::TempDir::new("cli_test").unwrap();
+  let root = tmp_dir.path();
+  lgit(root, &["init", "origin"]);
+  let origin = root.join("origin");
+  fs::write(origin.join("README"), "one\ntwo\nthree\n").unwrap();
+  fs::create_dir(origin.join("src")).unwrap();
+  fs::write(origin.join("src/lib.rs"), "fn main() {}\n").unwrap();
+  lgit(&origin, &["add", "."]);
+  assert_eq!("A  README\nA  src/lib.rs\n", lgit(&origin, &["status"]));
+  // The same commit git makes from the same tree, identity, and date
+  assert_eq!(
+    "[master (root-commit) e275e19] Initial commit\n",
+    lgit(&origin, &["commit", "-m", "Initial commit"])
+  );
+
+  fs::write(origin.join("README"), "one\nTWO\nthree\n").unwrap();
+  assert_eq!(" M README\n", lgit(&origin, &["status"]));
+  let diff = lgit(&origin, &["diff"]);
+  assert!(diff.starts_with("diff --git a/README b/README\n"));
+  assert!(diff.ends_with("@@ -1,3 +1,3 @@\n one\n-two\n+TWO\n three\n"));
+  assert_eq!("", lgit(&origin, &["diff", "--cached"]));
+  lgit(&origin, &["add", "README"]);
+  assert_eq!(diff, lgit(&origin, &["diff", "--cached"]));
+  lgit(&origin, &["commit", "-m", "Shout"]);
+  assert_eq!(
+    "commit 5c9ea1cc0782202901fd7271e3551ecc5656d9c3\n\
+     Author: Jane Doe <jane@example.com>\n\
+     Date:   Sun Sep 13 14:26:40 2020 +0200\n\
+     \n    Shout",
+    lgit(&origin, &["log", "HEAD"])
+      .split("\n\ncommit")
+      .next()
+      .unwrap()
+  );
+
+  lgit(root, &["clone", "origin", "copy"]);
+  let copy = root.join("copy");
+  assert_eq!("", lgit(&copy, &["status"]));
+  assert_eq!(
+    "one\nTWO\nthree\n",
+    fs::read_to_string(copy.join("README")).unwrap()
+  );
+  assert_eq!(lgit(&origin, &["log"]), lgit(&copy, &["log"]));
+}